@@ -0,0 +1,121 @@
+//! Dumps the dependency graph of loaded crates in DOT or JSON format,
+//! or summarizes the dependents of a single crate to assess whether it can be unloaded or swapped.
+//!
+//! See the `dep_graph` module of `mod_mgmt`.
+
+#![no_std]
+
+extern crate alloc;
+#[macro_use] extern crate app_io;
+
+use alloc::{string::String, vec::Vec};
+use getopts::Options;
+use mod_mgmt::dep_graph::CrateGraph;
+
+pub fn main(args: Vec<String>) -> isize {
+    let mut opts = Options::new();
+    opts.optflag("h", "help", "print this help menu");
+    opts.optflag("j", "json", "dump the graph as JSON instead of DOT");
+    opts.optflag("r", "recursive", "include crates in recursive namespaces, e.g., kernel crates for applications");
+    opts.optflag("s", "serial", "write the graph to the serial port instead of the terminal");
+    opts.optopt("c", "crate", "only include the given crate and its direct dependents and dependencies", "CRATE");
+    opts.optflag("i", "info", "with crate, print a summary of the crate's dependents instead of the graph");
+
+    let matches = match opts.parse(&args) {
+        Ok(m) => m,
+        Err(e) => {
+            println!("{}", e);
+            print_usage(opts);
+            return -1;
+        }
+    };
+
+    if matches.opt_present("h") {
+        print_usage(opts);
+        return 0;
+    }
+
+    match run(&matches) {
+        Ok(()) => 0,
+        Err(e) => {
+            println!("Error: {}", e);
+            -1
+        }
+    }
+}
+
+fn run(matches: &getopts::Matches) -> Result<(), String> {
+    let namespace = task::with_current_task(|t| t.get_namespace().clone())
+        .map_err(|_| String::from("couldn't get the current task"))?;
+    let mut graph = namespace.dependency_graph(matches.opt_present("r"));
+
+    if let Some(prefix) = matches.opt_str("c") {
+        let crate_name = find_crate(&graph, &prefix)?;
+        if matches.opt_present("i") {
+            print_info(&graph, &crate_name);
+            return Ok(());
+        }
+        graph = graph.neighborhood(&crate_name);
+    } else if matches.opt_present("i") {
+        return Err(String::from("--info requires --crate"));
+    }
+
+    let output = if matches.opt_present("j") { graph.to_json() } else { graph.to_dot() };
+    if matches.opt_present("s") {
+        logger_x86_64::write_str(&output).map_err(|_| String::from("couldn't write the graph to the serial port"))?;
+        println!("Wrote a graph of {} crates and {} dependencies to the serial port.", graph.nodes.len(), graph.edges.len());
+    } else {
+        print!("{}", output);
+    }
+    Ok(())
+}
+
+/// Returns the name of the crate in the `graph` whose name is `prefix`,
+/// or otherwise the only crate whose name starts with `prefix`.
+fn find_crate(graph: &CrateGraph, prefix: &str) -> Result<String, String> {
+    if let Some(node) = graph.nodes.iter().find(|node| node.name == prefix) {
+        return Ok(node.name.clone());
+    }
+    let matches: Vec<&str> = graph.nodes.iter()
+        .filter(|node| node.name.starts_with(prefix))
+        .map(|node| node.name.as_str())
+        .collect();
+    match matches.as_slice() {
+        [name] => Ok(String::from(*name)),
+        [] => Err(alloc::format!("couldn't find a crate matching {:?}", prefix)),
+        names => Err(alloc::format!("{:?} matches multiple crates: {:?}", prefix, names)),
+    }
+}
+
+fn print_info(graph: &CrateGraph, crate_name: &str) {
+    if let Some(node) = graph.nodes.iter().find(|node| node.name == crate_name) {
+        println!("Crate {} in namespace {:?} has {} sections, {} of which are TLS sections.",
+            node.name, node.namespace, node.num_sections, node.tls_sections.len(),
+        );
+        for tls_section in &node.tls_sections {
+            println!("    TLS section {}", tls_section);
+        }
+    }
+    println!("Depends on {} crates.", graph.dependencies_of(crate_name).len());
+
+    let dependents: Vec<_> = graph.edges.iter().filter(|edge| edge.dependency == crate_name).collect();
+    if dependents.is_empty() {
+        println!("No loaded crates depend on it, so it can be unloaded or swapped on its own.");
+        return;
+    }
+    println!("{} crates depend on it, which must be unloaded or swapped along with it:", dependents.len());
+    for edge in dependents {
+        println!("    {} ({} relocations)", edge.dependent, edge.num_relocations);
+        for reference in &edge.tls_references {
+            println!("        {} references TLS section {}", reference.section, reference.tls_section);
+        }
+    }
+}
+
+fn print_usage(opts: Options) {
+    println!("{}", opts.usage(USAGE));
+}
+
+const USAGE: &str = "Usage: crate_graph [OPTION]
+Dumps the dependency graph of the crates loaded into the current namespace, in Graphviz DOT format by default.
+Crates with TLS sections and dependencies on TLS sections are highlighted.";
@@ -0,0 +1,147 @@
+//! Prints or clears the kernel log buffer, and adjusts log levels at runtime.
+//!
+//! All logged records are retained in the kernel log buffer, whereas only those
+//! at or above the console log level are also written to the serial port.
+
+#![no_std]
+
+extern crate alloc;
+
+use alloc::{string::String, vec::Vec};
+use app_io::println;
+use getopts::Options;
+use log::{Level, LevelFilter};
+use logger_x86_64 as logger;
+
+pub fn main(args: Vec<String>) -> isize {
+    let mut opts = Options::new();
+    opts.optflag("h", "help", "print this help menu");
+    opts.optflag("c", "read-clear", "clear the log buffer after printing it");
+    opts.optflag("C", "clear", "clear the log buffer without printing it");
+    opts.optopt("l", "level", "only print records at or above LEVEL", "LEVEL");
+    opts.optopt("n", "console-level", "set the level at or above which records are written to the serial port", "LEVEL");
+    opts.optopt("g", "global-level", "set the log level of crates that don't have their own log level", "LEVEL");
+    opts.optmulti("s", "set", "set the log level of a crate or module, or remove it with LEVEL `default`", "CRATE=LEVEL");
+    opts.optflag("L", "levels", "print the current log levels");
+
+    let matches = match opts.parse(&args) {
+        Ok(matches) => matches,
+        Err(e) => {
+            println!("{}", e);
+            print_usage(opts);
+            return -1;
+        }
+    };
+    if matches.opt_present("h") {
+        print_usage(opts);
+        return 0;
+    }
+
+    let mut adjusted_levels = false;
+    if let Some(level) = matches.opt_str("n") {
+        let Some(level) = parse_level_filter(&level) else { return -1 };
+        logger::set_console_log_level(level);
+        adjusted_levels = true;
+    }
+    if let Some(level) = matches.opt_str("g") {
+        let Some(level) = parse_level_filter(&level) else { return -1 };
+        match level.to_level() {
+            Some(level) => logger::set_log_level(level),
+            None => {
+                println!("dmesg: the global log level can't be `off`");
+                return -1;
+            }
+        }
+        adjusted_levels = true;
+    }
+    for setting in matches.opt_strs("s") {
+        let Some((name, level)) = setting.split_once('=') else {
+            println!("dmesg: invalid setting {:?}, expected CRATE=LEVEL", setting);
+            return -1;
+        };
+        let level = if level.eq_ignore_ascii_case("default") {
+            None
+        } else {
+            let Some(level) = parse_level_filter(level) else { return -1 };
+            Some(level)
+        };
+        if let Err(e) = logger::set_crate_log_level(name, level) {
+            println!("dmesg: couldn't set the log level of {}: {}", name, e);
+            return -1;
+        }
+        adjusted_levels = true;
+    }
+
+    if matches.opt_present("L") {
+        print_levels();
+        return 0;
+    }
+    if matches.opt_present("C") {
+        logger::clear_log_records();
+        return 0;
+    }
+    if adjusted_levels && !matches.opt_present("c") && !matches.opt_present("l") {
+        return 0;
+    }
+
+    let min_level = match matches.opt_str("l") {
+        Some(level) => match level.parse::<Level>() {
+            Ok(level) => level,
+            Err(_) => {
+                println!("dmesg: invalid log level {:?}", level);
+                return -1;
+            }
+        },
+        None => Level::Trace,
+    };
+    for record in logger::log_records().iter().filter(|record| record.level <= min_level) {
+        println!(
+            "[{:>5}.{:06}] [{}] [{}] {}:{}: {}",
+            record.timestamp.as_secs(),
+            record.timestamp.subsec_micros(),
+            record.cpu,
+            level_char(record.level),
+            record.file,
+            record.line,
+            record.message,
+        );
+    }
+    if matches.opt_present("c") {
+        logger::clear_log_records();
+    }
+    0
+}
+
+fn print_levels() {
+    println!("console: {}", logger::console_log_level());
+    println!("global:  {}", logger::log_level());
+    for (name, level) in logger::crate_log_levels() {
+        println!("{}: {}", name, level);
+    }
+}
+
+fn parse_level_filter(level: &str) -> Option<LevelFilter> {
+    let parsed = level.parse::<LevelFilter>().ok();
+    if parsed.is_none() {
+        println!("dmesg: invalid log level {:?}", level);
+    }
+    parsed
+}
+
+fn level_char(level: Level) -> char {
+    match level {
+        Level::Error => 'E',
+        Level::Warn => 'W',
+        Level::Info => 'I',
+        Level::Debug => 'D',
+        Level::Trace => 'T',
+    }
+}
+
+fn print_usage(opts: Options) {
+    println!("{}", opts.usage(BRIEF));
+}
+
+const BRIEF: &str = "Usage: dmesg [OPTIONS]\n
+Prints the records in the kernel log buffer, oldest first.
+LEVEL is one of `off`, `error`, `warn`, `info`, `debug` or `trace`.";
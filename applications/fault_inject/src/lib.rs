@@ -0,0 +1,128 @@
+//! Injects faults into kernel subsystems on demand, in order to exercise their error paths.
+//!
+//! See the `fault_injection` crate, which must be enabled via `THESEUS_CONFIG=fault_injection`.
+
+#![no_std]
+
+extern crate alloc;
+#[macro_use] extern crate app_io;
+
+use alloc::{string::String, vec::Vec};
+use apic::LapicIpiDestination;
+use fault_injection::{FaultPoint, Trigger};
+use getopts::Options;
+
+/// The number of spin iterations between consecutive interrupts of a storm,
+/// such that each interrupt is delivered instead of being coalesced with the previous one.
+const STORM_SPACING: usize = 1000;
+
+pub fn main(args: Vec<String>) -> isize {
+    let mut opts = Options::new();
+    opts.optflag("h", "help", "print this help menu");
+    opts.optopt("c", "cpu", "with storm, the CPU to send interrupts to (default: the current CPU)", "CPU");
+    opts.optopt("v", "vector", "with storm, the interrupt vector to send (default: the local APIC timer)", "VECTOR");
+
+    let matches = match opts.parse(&args) {
+        Ok(m) => m,
+        Err(e) => {
+            println!("{}", e);
+            print_usage(opts);
+            return -1;
+        }
+    };
+
+    if matches.opt_present("h") {
+        print_usage(opts);
+        return 0;
+    }
+    if !fault_injection::is_enabled() {
+        println!("Fault injection is disabled; rebuild Theseus with THESEUS_CONFIG=fault_injection.");
+        return -1;
+    }
+
+    let free: Vec<&str> = matches.free.iter().map(String::as_str).collect();
+    let result = match free.as_slice() {
+        [] => {
+            list();
+            Ok(())
+        }
+        ["storm", count] => match (count.parse(), parse_opt(matches.opt_str("c")), parse_opt(matches.opt_str("v"))) {
+            (Ok(count), Ok(cpu), Ok(vector)) => storm(count, cpu, vector.unwrap_or(apic::LOCAL_APIC_LVT_IRQ)),
+            _ => Err("invalid storm COUNT, CPU, or VECTOR"),
+        },
+        [point, trigger @ ..] => match (point.parse::<FaultPoint>(), parse_trigger(trigger)) {
+            (Ok(point), Ok(trigger)) => fault_injection::set_trigger(point, trigger)
+                .map(|_| println!("Set fault point {} to {}.", point, trigger)),
+            (Err(e), _) | (_, Err(e)) => Err(e),
+        },
+    };
+
+    match result {
+        Ok(()) => 0,
+        Err(e) => {
+            println!("Error: {}", e);
+            -1
+        }
+    }
+}
+
+fn list() {
+    println!("{0:<8}  {1:<12}  {2:>10}", "POINT", "TRIGGER", "INJECTED");
+    for point in FaultPoint::ALL {
+        // `Trigger` doesn't support padding, so format it first.
+        let trigger = alloc::format!("{}", fault_injection::trigger(point));
+        println!("{0:<8}  {1:<12}  {2:>10}", point, trigger, fault_injection::injected_count(point));
+    }
+}
+
+fn parse_trigger(args: &[&str]) -> Result<Trigger, &'static str> {
+    let count = |n: &str| n.parse::<u32>().map_err(|_| "invalid trigger count");
+    match args {
+        ["off"] => Ok(Trigger::Off),
+        ["next", n] => Ok(Trigger::Next(count(n)?)),
+        ["every", n] => Ok(Trigger::EveryNth(count(n)?)),
+        _ => Err("the trigger must be `off`, `next N`, or `every N`"),
+    }
+}
+
+fn parse_opt<T: core::str::FromStr>(opt: Option<String>) -> Result<Option<T>, ()> {
+    opt.map(|s| s.parse().map_err(|_| ())).transpose()
+}
+
+/// Sends `count` interrupts with the given `vector` to the given CPU, or to the current CPU if `None`.
+fn storm(count: usize, cpu: Option<u8>, vector: u8) -> Result<(), &'static str> {
+    let destination = || match cpu {
+        Some(cpu) => LapicIpiDestination::One(cpu),
+        None => LapicIpiDestination::Me,
+    };
+    for _ in 0..count {
+        apic::get_my_apic()
+            .ok_or("couldn't get the current CPU's local APIC")?
+            .write()
+            .send_ipi(vector, destination());
+        for _ in 0..STORM_SPACING {
+            core::hint::spin_loop();
+        }
+    }
+    println!("Sent {} interrupts with vector {:#X}.", count, vector);
+    Ok(())
+}
+
+fn print_usage(opts: Options) {
+    println!("{}", opts.usage(USAGE));
+}
+
+const USAGE: &str = "Usage: fault_inject [OPTION] [COMMAND]
+Injects faults into kernel subsystems to exercise their error paths.
+
+Commands:
+    (none)                list each fault point with its trigger and the number of faults injected there.
+    POINT off             stop injecting faults at POINT.
+    POINT next N          make the next N checks of POINT fail.
+    POINT every N         make every N-th check of POINT fail.
+    storm COUNT           send COUNT interrupts in quick succession to a CPU.
+
+Fault points:
+    alloc     page allocation, which also fails stack creation, memory mapping, and heap growth.
+    tls       adding dynamic TLS sections, which aborts crate loading.
+    reloc     writing relocations, which aborts crate loading and swapping.";
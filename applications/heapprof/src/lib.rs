@@ -0,0 +1,158 @@
+//! Profiles heap allocations by task or application crate, which helps pinpoint memory leaks.
+//!
+//! See the `profiling` module of the `heap` crate and the `memory_accounting` crate.
+
+#![no_std]
+
+extern crate alloc;
+#[macro_use] extern crate app_io;
+
+use alloc::{string::String, vec::Vec};
+use getopts::Options;
+use memory_accounting::HeapProfileOwner;
+
+/// The default maximum number of outstanding allocations printed by the `leaks` command.
+const DEFAULT_MAX_LEAKS: usize = 32;
+
+pub fn main(args: Vec<String>) -> isize {
+    let mut opts = Options::new();
+    opts.optflag("h", "help", "print this help menu");
+    opts.optflag("c", "crates", "with start, attribute allocations to application crates instead of tasks");
+    opts.optopt("n", "", "with leaks, the maximum number of allocations to print", "NUM");
+
+    let matches = match opts.parse(&args) {
+        Ok(m) => m,
+        Err(e) => {
+            println!("{}", e);
+            print_usage(opts);
+            return -1;
+        }
+    };
+
+    if matches.opt_present("h") {
+        print_usage(opts);
+        return 0;
+    }
+
+    let result = match matches.free.first().map(String::as_str) {
+        Some("start") => {
+            let owner = if matches.opt_present("c") { HeapProfileOwner::AppCrate } else { HeapProfileOwner::Task };
+            memory_accounting::enable_heap_profiling(owner)
+                .map(|_| println!("Started heap profiling by {}.", owner_kind_name(owner)))
+        }
+        Some("stop") => {
+            memory_accounting::disable_heap_profiling();
+            println!("Stopped heap profiling.");
+            Ok(())
+        }
+        Some("report") => report(),
+        Some("leaks") => {
+            let max = match matches.opt_str("n").map(|n| n.parse::<usize>()) {
+                Some(Ok(max)) => max,
+                Some(Err(_)) => {
+                    println!("Invalid number of allocations.");
+                    return -1;
+                }
+                None => DEFAULT_MAX_LEAKS,
+            };
+            match matches.free.get(1).and_then(|owner| parse_owner(owner)) {
+                Some(owner) => leaks(owner, max),
+                None => Err("leaks requires a valid OWNER argument"),
+            }
+        }
+        _ => {
+            print_usage(opts);
+            return -1;
+        }
+    };
+
+    match result {
+        Ok(()) => 0,
+        Err(e) => {
+            println!("Error: {}", e);
+            -1
+        }
+    }
+}
+
+fn report() -> Result<(), &'static str> {
+    let owner_kind = memory_accounting::heap_profile_owner().ok_or("heap profiling is not enabled")?;
+    let (profiles, dropped) = memory_accounting::heap_profiles().ok_or("heap profiling is not enabled")?;
+    println!("{0:<18}  {1:>10}  {2:>12}  {3:>10}  {4:>12}  {5}",
+        "OWNER", "ALLOCS", "BYTES", "LIVE", "LIVE BYTES", "NAME"
+    );
+    for p in &profiles {
+        let name = p.name.as_deref().unwrap_or(match owner_kind {
+            HeapProfileOwner::Task => "<exited>",
+            HeapProfileOwner::AppCrate => "<unloaded>",
+        });
+        println!("{0:<18}  {1:>10}  {2:>12}  {3:>10}  {4:>12}  {5}",
+            format_owner(owner_kind, p.owner),
+            p.profile.allocations, p.profile.bytes_allocated,
+            p.profile.outstanding_allocations, p.profile.outstanding_bytes,
+            name,
+        );
+    }
+    println!("Total live: {} bytes across {} {}s.",
+        profiles.iter().map(|p| p.profile.outstanding_bytes).sum::<usize>(),
+        profiles.len(),
+        owner_kind_name(owner_kind),
+    );
+    if dropped > 0 {
+        println!("Warning: {} allocations were not recorded because the profiler's tables were full.", dropped);
+    }
+    Ok(())
+}
+
+fn leaks(owner: usize, max: usize) -> Result<(), &'static str> {
+    let allocations = heap::outstanding_allocations(owner).ok_or("heap profiling is not enabled")?;
+    println!("{0:<18}  {1:>10}", "ADDRESS", "SIZE");
+    for (addr, size) in allocations.iter().take(max) {
+        println!("{:<#18X}  {:>10}", addr, size);
+    }
+    if allocations.len() > max {
+        println!("... and {} more.", allocations.len() - max);
+    }
+    println!("{} outstanding allocations totaling {} bytes.",
+        allocations.len(),
+        allocations.iter().map(|(_, size)| size).sum::<usize>(),
+    );
+    Ok(())
+}
+
+fn owner_kind_name(owner: HeapProfileOwner) -> &'static str {
+    match owner {
+        HeapProfileOwner::Task => "task",
+        HeapProfileOwner::AppCrate => "application crate",
+    }
+}
+
+/// Task owners are printed as decimal task IDs, while crate owners are printed as hexadecimal addresses.
+fn format_owner(owner_kind: HeapProfileOwner, owner: usize) -> String {
+    match owner_kind {
+        HeapProfileOwner::Task => alloc::format!("{}", owner),
+        HeapProfileOwner::AppCrate => alloc::format!("{:#X}", owner),
+    }
+}
+
+fn parse_owner(owner: &str) -> Option<usize> {
+    match owner.strip_prefix("0x").or_else(|| owner.strip_prefix("0X")) {
+        Some(hex) => usize::from_str_radix(hex, 16).ok(),
+        None => owner.parse().ok(),
+    }
+}
+
+fn print_usage(opts: Options) {
+    println!("{}", opts.usage(USAGE));
+}
+
+const USAGE: &str = "Usage: heapprof [OPTION] COMMAND
+Profiles heap allocations to find memory leaks.
+
+Commands:
+    start          start recording each heap allocation and the task (or application crate, with -c) that made it.
+    stop           stop recording heap allocations and discard all records.
+    report         print the allocations made and still outstanding for each task or application crate.
+    leaks OWNER    print the outstanding allocations of the given OWNER, as listed by report.
+
+Outstanding allocations of exited tasks or unloaded crates are likely memory leaks.";
@@ -0,0 +1,80 @@
+//! Takes CPUs offline, brings them back online, and lists their states.
+//!
+//! Taking a CPU offline migrates its tasks to the other online CPUs and then parks it,
+//! which can be used to measure power usage or to isolate a misbehaving CPU.
+
+#![no_std]
+
+extern crate alloc;
+
+use alloc::{string::String, vec::Vec};
+use app_io::println;
+use getopts::Options;
+
+pub fn main(args: Vec<String>) -> isize {
+    let mut opts = Options::new();
+    opts.optflag("h", "help", "print this help menu");
+    opts.optopt("d", "offline", "take CPU offline", "CPU");
+    opts.optopt("u", "online", "bring CPU back online", "CPU");
+
+    let matches = match opts.parse(&args) {
+        Ok(matches) => matches,
+        Err(e) => {
+            println!("{}", e);
+            print_usage(opts);
+            return -1;
+        }
+    };
+    if matches.opt_present("h") {
+        print_usage(opts);
+        return 0;
+    }
+
+    let (operation, cpu): (fn(u8) -> Result<(), &'static str>, _) = match (matches.opt_str("d"), matches.opt_str("u")) {
+        (None, None) => {
+            print_cpus();
+            return 0;
+        }
+        (Some(cpu), None) => (cpu_hotplug::offline, cpu),
+        (None, Some(cpu)) => (cpu_hotplug::online, cpu),
+        (Some(_), Some(_)) => {
+            println!("hotplug: --offline and --online can't be used together");
+            return -1;
+        }
+    };
+    let Ok(cpu) = cpu.parse::<u8>() else {
+        println!("hotplug: invalid CPU {:?}", cpu);
+        return -1;
+    };
+    match operation(cpu) {
+        Ok(()) => 0,
+        Err(e) => {
+            println!("hotplug: CPU {}: {}", cpu, e);
+            -1
+        }
+    }
+}
+
+fn print_cpus() {
+    let mut cpus: Vec<u8> = apic::get_lapics().iter().map(|(&cpu, _)| cpu).collect();
+    cpus.sort_unstable();
+    for cpu in cpus {
+        let state = if cpu_hotplug::is_parked(cpu) {
+            "offline"
+        } else if cpu::is_offline(cpu) {
+            "going offline"
+        } else {
+            "online"
+        };
+        let bootstrap = if apic::bootstrap_cpu() == Some(cpu) { " (bootstrap)" } else { "" };
+        println!("CPU {}: {}{}", cpu, state, bootstrap);
+    }
+}
+
+fn print_usage(opts: Options) {
+    println!("{}", opts.usage(BRIEF));
+}
+
+const BRIEF: &str = "Usage: hotplug [OPTIONS]\n
+Lists the state of each CPU, or takes a CPU offline or brings it back online.
+The bootstrap CPU and CPUs that a task is pinned to can't be taken offline.";
@@ -61,6 +61,7 @@ impl Job {
                         KillReason::Requested => 130,
                         KillReason::Panic(_) => 1,
                         KillReason::Exception(num) => num.into(),
+                        KillReason::LoadFailure(_) => 127,
                     },
                 };
                 part.state = State::Complete(exit_value);
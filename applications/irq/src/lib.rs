@@ -0,0 +1,78 @@
+//! Lists the MSI and MSI-X interrupts of PCI devices and changes which CPUs they are routed to.
+
+#![no_std]
+
+extern crate alloc;
+
+use alloc::{format, string::{String, ToString}, vec::Vec};
+use app_io::println;
+use getopts::Options;
+
+pub fn main(args: Vec<String>) -> isize {
+    let mut opts = Options::new();
+    opts.optflag("h", "help", "print this help menu");
+    opts.optopt("i", "irq", "the interrupt to route, used with --cpu", "IRQ");
+    opts.optopt("c", "cpu", "route the interrupt to CPU", "CPU");
+    opts.optflag("r", "rebalance", "spread all interrupts evenly across the online CPUs");
+
+    let matches = match opts.parse(&args) {
+        Ok(matches) => matches,
+        Err(e) => {
+            println!("{}", e);
+            print_usage(opts);
+            return -1;
+        }
+    };
+    if matches.opt_present("h") {
+        print_usage(opts);
+        return 0;
+    }
+
+    if matches.opt_present("r") {
+        if let Err(e) = msi::rebalance() {
+            println!("irq: {}", e);
+            return -1;
+        }
+    }
+
+    match (matches.opt_str("i"), matches.opt_str("c")) {
+        (None, None) => {}
+        (Some(irq), Some(cpu)) => {
+            let (Ok(irq), Ok(cpu)) = (irq.parse::<u8>(), cpu.parse::<u8>()) else {
+                println!("irq: invalid IRQ {:?} or CPU {:?}", irq, cpu);
+                return -1;
+            };
+            if let Err(e) = msi::set_affinity(irq, cpu) {
+                println!("irq: IRQ {}: {}", irq, e);
+                return -1;
+            }
+        }
+        _ => {
+            println!("irq: --irq and --cpu must be used together");
+            return -1;
+        }
+    }
+
+    print_routes();
+    0
+}
+
+fn print_routes() {
+    println!("{:>4} {:>4}  {:<12} VECTOR", "IRQ", "CPU", "DEVICE");
+    for route in msi::routes() {
+        let vector = match route.msix_vector {
+            Some(vector) => format!("MSI-X {}", vector),
+            None => String::from("MSI"),
+        };
+        let device = route.device.to_string();
+        println!("{:>4} {:>4}  {:<12} {}", route.interrupt_num, route.cpu, device, vector);
+    }
+}
+
+fn print_usage(opts: Options) {
+    println!("{}", opts.usage(BRIEF));
+}
+
+const BRIEF: &str = "Usage: irq [OPTIONS]\n
+Lists the MSI and MSI-X interrupts of PCI devices and the CPU that each one is routed to,
+after optionally rebalancing them or routing one of them to a different CPU.";
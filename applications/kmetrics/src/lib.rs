@@ -0,0 +1,147 @@
+//! Prints the kernel's registered metrics, or serves them over HTTP,
+//! in the Prometheus text exposition format.
+//!
+//! When serving, `GET /metrics` (or `GET /`) on the given port returns the current metrics,
+//! such that a Prometheus server can scrape them directly.
+
+#![no_std]
+
+extern crate alloc;
+
+use alloc::{format, string::String, vec::Vec};
+use app_io::{print, println};
+use dreadnought::{block_on, task::spawn_async};
+use getopts::Options;
+use net::{TcpListener, TcpOptions, TcpStream};
+
+/// The port on which metrics are served by default, which is that of the Prometheus node exporter.
+const DEFAULT_PORT: u16 = 9100;
+/// The number of connections that may wait to be accepted at a time.
+const BACKLOG: usize = 4;
+/// The maximum length of a request's header, beyond which the request is rejected.
+const MAX_REQUEST_LEN: usize = 4096;
+
+pub fn main(args: Vec<String>) -> isize {
+    let mut opts = Options::new();
+    opts.optflag("h", "help", "print this help menu");
+    opts.optflagopt("s", "serve", "serve the metrics over HTTP on PORT, which defaults to 9100", "PORT");
+
+    let matches = match opts.parse(&args) {
+        Ok(matches) => matches,
+        Err(e) => {
+            println!("{}", e);
+            print_usage(opts);
+            return -1;
+        }
+    };
+    if matches.opt_present("h") {
+        print_usage(opts);
+        return 0;
+    }
+    if !matches.opt_present("s") {
+        print!("{}", metrics::to_text());
+        return 0;
+    }
+
+    let port = match matches.opt_str("s").map(|port| port.parse::<u16>()) {
+        None => DEFAULT_PORT,
+        Some(Ok(port)) => port,
+        Some(Err(_)) => {
+            println!("kmetrics: invalid port");
+            return -1;
+        }
+    };
+    serve(port)
+}
+
+/// Serves the metrics over HTTP on the given `port` until accepting a connection fails.
+fn serve(port: u16) -> isize {
+    let Some(interface) = net::get_default_interface() else {
+        println!("kmetrics: no network interface is available");
+        return -1;
+    };
+    let listener = match TcpListener::bind(interface, port, BACKLOG, TcpOptions::default()) {
+        Ok(listener) => listener,
+        Err(e) => {
+            println!("kmetrics: couldn't listen on port {}: {}", port, e);
+            return -1;
+        }
+    };
+    println!("kmetrics: serving metrics on port {}", port);
+
+    block_on(async {
+        loop {
+            match listener.accept().await {
+                Ok(stream) => {
+                    if let Err(e) = spawn_async(respond(stream)) {
+                        println!("kmetrics: couldn't spawn connection task: {}", e);
+                    }
+                }
+                Err(e) => {
+                    println!("kmetrics: couldn't accept connection: {}", e);
+                    return -1;
+                }
+            }
+        }
+    })
+}
+
+/// Reads a single HTTP request from the given `stream` and responds to it,
+/// after which the connection is closed.
+async fn respond(stream: TcpStream) {
+    let Some(request) = read_request(&stream).await else {
+        return;
+    };
+    let mut request_line = request.split(|&b| b == b'\r' || b == b'\n')
+        .next()
+        .unwrap_or_default()
+        .split(|&b| b == b' ');
+    let method = request_line.next().unwrap_or_default();
+    let path = request_line.next().unwrap_or_default();
+
+    let (status, body) = match (method, path) {
+        (b"GET", b"/metrics" | b"/") => ("200 OK", metrics::to_text()),
+        (b"GET", _) => ("404 Not Found", String::from("not found\n")),
+        _ => ("405 Method Not Allowed", String::from("method not allowed\n")),
+    };
+    let header = format!(
+        "HTTP/1.0 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        body.len(),
+    );
+    let result = async {
+        stream.write_all(header.as_bytes()).await?;
+        stream.write_all(body.as_bytes()).await?;
+        stream.flush().await
+    }.await;
+    if let Err(e) = result {
+        println!("kmetrics: couldn't write response: {}", e);
+    }
+}
+
+/// Reads from the given `stream` until the end of a request's header.
+///
+/// Returns `None` if the peer closed the connection or the header is too long.
+async fn read_request(stream: &TcpStream) -> Option<Vec<u8>> {
+    let mut request = Vec::new();
+    let mut buf = [0; 512];
+    while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+        if request.len() > MAX_REQUEST_LEN {
+            return None;
+        }
+        let len = stream.read(&mut buf).await.ok()?;
+        if len == 0 {
+            return None;
+        }
+        request.extend_from_slice(&buf[..len]);
+    }
+    Some(request)
+}
+
+fn print_usage(opts: Options) {
+    println!("{}", opts.usage(BRIEF));
+}
+
+const BRIEF: &str = "Usage: kmetrics [OPTIONS]\n
+Prints the kernel's metrics in the Prometheus text format.
+To serve them on another port, pass it as `--serve=PORT` or `-sPORT`.";
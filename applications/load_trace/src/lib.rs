@@ -0,0 +1,103 @@
+//! Records crate loading and TLS layout decisions into a trace that can be replayed on a later boot.
+//!
+//! See the `load_trace` module of `mod_mgmt`.
+//! To record from the very start of a boot, rebuild Theseus with `THESEUS_CONFIG=record_load_trace`.
+
+#![no_std]
+
+extern crate alloc;
+#[macro_use] extern crate app_io;
+
+use alloc::{string::String, vec::Vec};
+use getopts::Options;
+use mod_mgmt::load_trace;
+
+pub fn main(args: Vec<String>) -> isize {
+    let mut opts = Options::new();
+    opts.optflag("h", "help", "print this help menu");
+    opts.optflag("s", "serial", "with dump or stop, write the trace to the serial port instead of the terminal");
+
+    let matches = match opts.parse(&args) {
+        Ok(m) => m,
+        Err(e) => {
+            println!("{}", e);
+            print_usage(opts);
+            return -1;
+        }
+    };
+
+    if matches.opt_present("h") {
+        print_usage(opts);
+        return 0;
+    }
+
+    let to_serial = matches.opt_present("s");
+    let free: Vec<&str> = matches.free.iter().map(String::as_str).collect();
+    let result = match free.as_slice() {
+        [] | ["status"] => {
+            status();
+            Ok(())
+        }
+        ["start"] => {
+            load_trace::start_recording();
+            println!("Started recording a new load trace.");
+            Ok(())
+        }
+        ["dump"] => dump(&load_trace::recorded_events(), to_serial),
+        ["stop"] => dump(&load_trace::stop_recording(), to_serial),
+        ["stop-replay"] => match load_trace::stop_replay() {
+            Some(divergences) => {
+                println!("Stopped replaying the load trace after {} divergences.", divergences);
+                Ok(())
+            }
+            None => Err("no load trace is being replayed"),
+        },
+        _ => Err("unknown command"),
+    };
+
+    match result {
+        Ok(()) => 0,
+        Err(e) => {
+            println!("Error: {}", e);
+            -1
+        }
+    }
+}
+
+fn status() {
+    println!("Recording: {}, {} events recorded.",
+        if load_trace::is_recording() { "yes" } else { "no" },
+        load_trace::recorded_events().len(),
+    );
+    match load_trace::divergences() {
+        Some(divergences) => println!("Replaying: yes, {} divergences from the trace so far.", divergences),
+        None => println!("Replaying: no"),
+    }
+}
+
+fn dump(events: &[load_trace::LoadEvent], to_serial: bool) -> Result<(), &'static str> {
+    let trace = load_trace::serialize(events);
+    if to_serial {
+        logger_x86_64::write_str(&trace).map_err(|_| "couldn't write the trace to the serial port")?;
+        println!("Wrote {} events to the serial port.", events.len());
+    } else {
+        print!("{}", trace);
+    }
+    Ok(())
+}
+
+fn print_usage(opts: Options) {
+    println!("{}", opts.usage(USAGE));
+}
+
+const USAGE: &str = "Usage: load_trace [OPTION] [COMMAND]
+Records crate loads, symbol resolutions, and TLS offset assignments into a replayable trace.
+
+Commands:
+    status (or none)      show whether a trace is being recorded or replayed.
+    start                 start recording a new trace, discarding the current one.
+    dump                  print the trace recorded so far.
+    stop                  stop recording and print the recorded trace.
+    stop-replay           stop replaying the trace given at boot.
+
+To replay a trace on a later boot, save it as `extra_files/load_trace` and rebuild Theseus.";
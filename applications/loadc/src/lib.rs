@@ -15,6 +15,7 @@ extern crate memory;
 extern crate rustc_demangle;
 extern crate mod_mgmt;
 extern crate task;
+extern crate spawn;
 extern crate xmas_elf;
 extern crate libc; // for basic C types/typedefs used in libc
 
@@ -25,9 +26,10 @@ use core::{
 use alloc::{collections::BTreeSet, string::{String, ToString}, sync::Arc, vec::Vec};
 use getopts::{Matches, Options};
 use memory::{Page, MappedPages, VirtualAddress, PteFlagsArch, PteFlags};
-use mod_mgmt::{CrateNamespace, StrongDependency, find_symbol_table, RelocationEntry, write_relocation};
+use mod_mgmt::{CrateNamespace, StrongDependency, find_symbol_table, RelocationEntry, write_relocation, THESEUS_TLS_MODULE_ID};
 use rustc_demangle::demangle;
 use path::Path;
+use task::ExitValue;
 use xmas_elf::{
     ElfFile,
     program::SegmentData,
@@ -82,13 +84,37 @@ fn rmain(matches: Matches) -> Result<c_int, String> {
     let byte_slice: &[u8] = file_mp.as_slice(0, file.len())?;
 
     let (mut segments, entry_point, _vaddr_offset, elf_file) = parse_and_load_elf_executable(byte_slice)?;
-    debug!("Parsed ELF executable, moving on to overwriting relocations.");
-    
+    debug!("Parsed ELF executable, moving on to loading its TLS segment.");
+
+    // Add the executable's TLS block to the TLS areas of tasks created from now on.
+    // Its TLS accesses are relocated below, because the offset chosen by the static linker
+    // is already occupied in Theseus's TLS areas.
+    let executable_tls = namespace.load_executable_tls_segment(&*file, &mmi)?;
+    let tls_block_offset = executable_tls.as_ref().map(|(offset, _)| *offset);
+
+    let result = relocate_and_run(&namespace, &mut segments, entry_point, &elf_file, &mmi, tls_block_offset);
+
+    if let Some((_, tls_section)) = executable_tls {
+        namespace.remove_tls_section(&tls_section)?;
+    }
+    result
+}
+
+/// Rewrites the relocations of the loaded executable `segments`, remaps them with their proper flags,
+/// and runs the executable in a new task until it returns.
+fn relocate_and_run(
+    namespace: &Arc<CrateNamespace>,
+    segments: &mut Vec<LoadedSegment>,
+    entry_point: VirtualAddress,
+    elf_file: &ElfFile,
+    mmi: &memory::MmiRef,
+    tls_block_offset: Option<usize>,
+) -> Result<c_int, String> {
     // Now, overwrite (recalculate) the relocations that refer to symbols that already exist in Theseus,
     // most important of which are static data sections, 
     // as it is logically incorrect to have duplicates of data that are supposed to be global system-wide singletons.
     // We should throw a warning here if there are no relocations in the file, as it was probably built/linked with the wrong arguments.
-    overwrite_relocations(&namespace, &mut segments, &elf_file, &mmi, false)?;
+    overwrite_relocations(namespace, segments, elf_file, mmi, tls_block_offset, false)?;
 
     // Remap each segment's mapped pages using the correct flags; they were previously mapped as always writable.
     {
@@ -102,20 +128,39 @@ fn rmain(matches: Matches) -> Result<c_int, String> {
 
     segments.iter().enumerate().for_each(|(i, seg)| debug!("Segment {} needed {} relocations to be rewritten.", i, seg.sections_i_depend_on.len()) );
 
-    let _executable = LoadedExecutable { segments, entry_point }; // must persist through the entire executable's runtime.
+    let _executable = LoadedExecutable { segments: core::mem::take(segments), entry_point }; // must persist through the entire executable's runtime.
     
-    debug!("Jumping to entry point {:#X}", entry_point);
+    // The executable runs in a new task, because only TLS areas created after its TLS block
+    // was loaded include it.
+    debug!("Spawning task to jump to entry point {:#X}", entry_point);
+    let task = spawn::new_task_builder(run_entry_point, entry_point)
+        .block()
+        .spawn()?;
+    if let Ok(streams) = app_io::streams() {
+        app_io::insert_child_streams(task.id, streams);
+    }
+    task.unblock().map_err(|_| "couldn't unblock the executable's task")?;
+    let exit_value = task.join();
+    app_io::remove_child_streams(task.id);
+
+    let c_retval = match exit_value? {
+        ExitValue::Completed(value) => *value.downcast_ref::<c_int>()
+            .ok_or("executable's task returned an unexpected value")?,
+        ExitValue::Killed(reason) => return Err(format!("executable's task was killed: {reason:?}")),
+    };
+    debug!("C _start entry point returned value {}({:#X})", c_retval, c_retval);
+
+    Ok(c_retval)
+}
 
+/// The entry function of the task that runs the executable.
+fn run_entry_point(entry_point: VirtualAddress) -> c_int {
     let dummy_args = ["hello", "world"];
     let dummy_env = ["USER=root", "PWD=/"];
 
     // TODO: FIXME: use `MappedPages::as_func()` instead of `transmute()`.
     let start_fn: StartFunction = unsafe { core::mem::transmute(entry_point.value()) };
-    let c_retval = start_fn(&dummy_args, &dummy_env);
-
-    debug!("C _start entry point returned value {}({:#X})", c_retval, c_retval);
-
-    Ok(c_retval)
+    start_fn(&dummy_args, &dummy_env)
 }
 
 /// Corresponds to C function:  `int foo()`
@@ -341,11 +386,15 @@ fn parse_and_load_elf_executable(
 /// This is necessary to ensure that the newly-loaded ELF executable depends on and references 
 /// the real singleton instances of each data sections (aka `OBJECT`s in ELF terminology) 
 /// rather than using the duplicate instance of those data sections in the executable itself. 
+///
+/// It also rewrites the accesses to the executable's own TLS variables to use the given `tls_block_offset`,
+/// i.e., the offset of the executable's TLS block in Theseus's TLS areas.
 fn overwrite_relocations(
     namespace: &Arc<CrateNamespace>,
     segments: &mut [LoadedSegment],
     elf_file: &ElfFile,
     mmi: &memory::MmiRef,
+    tls_block_offset: Option<usize>,
     verbose_log: bool
 ) -> Result<(), String> {
     let symtab = find_symbol_table(elf_file)?;
@@ -396,6 +445,8 @@ fn overwrite_relocations(
             })?;
         
         let mut target_segment_dependencies: Vec<StrongDependency> = Vec::new();
+        // The addresses of calls to `__tls_get_addr()` that were removed by relaxing a TLS code sequence.
+        let mut relaxed_tls_calls: BTreeSet<usize> = BTreeSet::new();
         let target_segment_start_addr = target_segment.bounds.start;
         let target_segment_slice: &mut [u8] = target_segment.mp.as_slice_mut(
             0,
@@ -416,6 +467,38 @@ fn overwrite_relocations(
                 trace!("      Rela64 entry has offset: {:#X}, addend: {:#X}, symtab_index: {}, type: {:#X}", 
                     rela_entry.get_offset(), rela_entry.get_addend(), rela_entry.get_symbol_table_index(), rela_entry.get_type());
             }
+            if relaxed_tls_calls.contains(&(rela_entry.get_offset() as usize)) {
+                continue;
+            }
+
+            // An access to one of the executable's own TLS variables, whose value is its offset within the TLS block.
+            if let (Ok(Type::Tls), Some(tls_block_offset)) = (source_sec_entry.get_type(), tls_block_offset) {
+                if source_sec_entry.shndx() != 0 { // i.e., not `SHN_UNDEF`
+                    let mut relocation_entry = RelocationEntry::from_elf_relocation(rela_entry);
+                    relaxed_tls_calls.extend(relocation_entry.relaxed_tls_call_offset());
+                    let offset_into_target_segment = relocation_entry.offset.checked_sub(target_segment_start_addr.value())
+                        .ok_or_else(|| format!("TLS relocation offset {:#X} was before its target segment", relocation_entry.offset))?;
+                    let source_value = if relocation_entry.is_tls_module_id() {
+                        THESEUS_TLS_MODULE_ID
+                    } else {
+                        tls_block_offset
+                            .wrapping_add(source_sec_entry.value() as usize)
+                            .wrapping_add(relocation_entry.addend)
+                    };
+                    // Like in `write_relocation()` below, the offset into the target segment replaces the entry's offset,
+                    // and the addend is already included in the source value.
+                    relocation_entry.offset = 0;
+                    relocation_entry.addend = 0;
+                    write_relocation(
+                        relocation_entry,
+                        target_segment_slice,
+                        offset_into_target_segment,
+                        VirtualAddress::new_canonical(source_value),
+                        verbose_log
+                    )?;
+                    continue;
+                }
+            }
 
             let source_sec_shndx = source_sec_entry.shndx() as usize; 
             let source_sec_name = match source_sec_entry.get_name(elf_file) {
@@ -0,0 +1,104 @@
+//! Shows the memory attributed to each task or crate, as reported by the `memory_accounting` crate.
+
+#![no_std]
+
+extern crate alloc;
+#[macro_use] extern crate app_io;
+
+use alloc::{string::String, sync::Arc, vec::Vec};
+use getopts::Options;
+use mod_mgmt::CrateNamespace;
+
+pub fn main(args: Vec<String>) -> isize {
+    let mut opts = Options::new();
+    opts.optflag("h", "help", "print this help menu");
+    opts.optflag("c", "crates", "print the memory attributed to each crate instead of each task");
+    opts.optflag("r", "recursive", "with -c, also include crates in recursive namespaces");
+    opts.optflag("f", "filesystems", "print the memory attributed to each tmpfs instance instead of each task");
+
+    let matches = match opts.parse(&args) {
+        Ok(m) => m,
+        Err(e) => {
+            println!("{}", e);
+            print_usage(opts);
+            return -1;
+        }
+    };
+
+    if matches.opt_present("h") {
+        print_usage(opts);
+        return 0;
+    }
+
+    if matches.opt_present("c") {
+        let Some(namespace) = current_namespace() else {
+            println!("Couldn't get the current task's namespace.");
+            return -1;
+        };
+        print_crates(&namespace, matches.opt_present("r"));
+    } else if matches.opt_present("f") {
+        print_tmpfs();
+    } else {
+        print_tasks();
+    }
+    0
+}
+
+fn print_tasks() {
+    let usage = memory_accounting::all_tasks_usage();
+    println!("{0:<5}  {1:>10}  {2:>10}  {3:>12}  {4:>12}  {5}", "ID", "STACK", "TLS", "HEAP", "TOTAL", "NAME");
+    for task in &usage {
+        println!("{0:<5}  {1:>10}  {2:>10}  {3:>12}  {4:>12}  {5}",
+            task.id, task.kstack_bytes, task.tls_bytes, task.heap_bytes_live(), task.total_bytes(), task.name
+        );
+    }
+    println!("Total across {} tasks: {} bytes",
+        usage.len(), usage.iter().map(|task| task.total_bytes()).sum::<u64>()
+    );
+}
+
+fn print_crates(namespace: &CrateNamespace, recursive: bool) {
+    let usage = memory_accounting::all_crates_usage(namespace, recursive);
+    println!("{0:>10}  {1:>10}  {2:>10}  {3:>10}  {4:>10}  {5}", "TEXT", "RODATA", "DATA", "TOTAL", "TLS/TASK", "NAME");
+    for krate in &usage {
+        println!("{0:>10}  {1:>10}  {2:>10}  {3:>10}  {4:>10}  {5}",
+            krate.text_bytes, krate.rodata_bytes, krate.data_bytes, krate.mapped_bytes(), krate.tls_bytes_per_task, krate.name
+        );
+    }
+    println!("Total across {} crates: {} bytes mapped, {} bytes of TLS per task",
+        usage.len(),
+        usage.iter().map(|krate| krate.mapped_bytes()).sum::<usize>(),
+        usage.iter().map(|krate| krate.tls_bytes_per_task).sum::<usize>(),
+    );
+}
+
+fn print_tmpfs() {
+    let usage = memory_accounting::all_tmpfs_usage();
+    println!("{0:>12}  {1:>12}  {2}", "USED", "LIMIT", "NAME");
+    for fs in &usage {
+        println!("{0:>12}  {1:>12}  {2}", fs.used_bytes, fs.size_limit, fs.name);
+    }
+    println!("Total across {} tmpfs instances: {} bytes",
+        usage.len(), usage.iter().map(|fs| fs.used_bytes).sum::<usize>()
+    );
+}
+
+fn current_namespace() -> Option<Arc<CrateNamespace>> {
+    task::with_current_task(|t| t.get_namespace().clone())
+        .ok()
+        .or_else(|| mod_mgmt::get_initial_kernel_namespace().cloned())
+}
+
+fn print_usage(opts: Options) {
+    println!("{}", opts.usage(USAGE));
+}
+
+const USAGE: &str = "Usage: memstat [OPTION]
+Shows the memory attributed to each task, to each crate with -c, or to each tmpfs instance with -f.
+
+    STACK:     the size in bytes of the task's kernel stack.
+    TLS:       the size in bytes of the task's TLS area.
+    HEAP:      the heap bytes allocated but not yet freed by the task.
+    TEXT, RODATA, DATA:  the size in bytes of the pages holding the crate's sections.
+    TLS/TASK:  the size in bytes of the crate's TLS sections, included in every task's TLS area.
+    USED, LIMIT:  the size in bytes of the pages holding the tmpfs instance's files, and its size limit.";
@@ -33,7 +33,7 @@ pub fn main(args: Vec<String>) -> isize {
     opts.optflag("h", "help", "print this help menu");
     opts.optflag("r", "recursive", "include recursive namespaces");
     opts.optflag("f", "files", "lists crate object files available in this namespace rather than currently-loaded crates");
-    opts.optopt("", "load", "load a crate into the current namespace. Ignores all other arguments.", "CRATE_OBJ_FILE_PATH");
+    opts.optopt("", "load", "load a crate, or all crates in an `ar` archive like an .rlib, into the current namespace. Ignores all other arguments.", "CRATE_OBJ_FILE_PATH");
 
     let matches = match opts.parse(&args) {
         Ok(m) => m,
@@ -72,7 +72,11 @@ fn rmain(matches: Matches) -> Result<(), String> {
         let file = path.get_file(&curr_wd).ok_or_else(||
             format!("Couldn't resolve path to crate object file at {path:?}")
         )?;
-        load_crate(&mut output, file, &namespace)?;
+        if matches!(path.extension(), Some("rlib" | "a")) {
+            load_crate_archive(&mut output, file, &namespace)?;
+        } else {
+            load_crate(&mut output, file, &namespace)?;
+        }
     } else if matches.opt_present("f") {
         print_files(&mut output, 0, namespace.deref(), recursive)
             .map_err(|_e| String::from("String formatting error"))?;
@@ -99,6 +103,22 @@ fn load_crate(output: &mut String, crate_file_ref: FileRef, namespace: &Arc<Crat
 }
 
 
+fn load_crate_archive(output: &mut String, archive_file_ref: FileRef, namespace: &Arc<CrateNamespace>) -> Result<(), String> {
+    let kernel_mmi_ref = memory::get_kernel_mmi_ref().ok_or_else(|| "Cannot get kernel_mmi_ref".to_string())?;
+    let new_crates = namespace.load_crate_archive(
+        &archive_file_ref,
+        None,
+        kernel_mmi_ref,
+        false
+    ).map_err(String::from)?;
+    writeln!(output, "Loaded {} crates from {}", new_crates.len(), archive_file_ref.lock().get_absolute_path()).unwrap();
+    for new_crate in new_crates {
+        writeln!(output, "    {}", new_crate.lock_as_ref().crate_name).unwrap();
+    }
+    Ok(())
+}
+
+
 fn print_files(output: &mut String, indent: usize, namespace: &CrateNamespace, recursive: bool) -> core::fmt::Result {
     writeln!(output, "\n{:indent$}{} CrateNamespace has crate object files:", "", namespace.name(), indent = indent)?;
     let mut files = namespace.dir().lock().list();
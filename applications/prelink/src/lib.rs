@@ -0,0 +1,101 @@
+//! Records the relocation results of loaded crates into a prelink cache that accelerates later boots.
+//!
+//! See the `prelink` module of `mod_mgmt`.
+//! To record the crates loaded during boot, rebuild Theseus with `THESEUS_CONFIG=record_prelink_cache`.
+
+#![no_std]
+
+extern crate alloc;
+#[macro_use] extern crate app_io;
+
+use alloc::{string::String, vec::Vec};
+use getopts::Options;
+use mod_mgmt::prelink;
+
+pub fn main(args: Vec<String>) -> isize {
+    let mut opts = Options::new();
+    opts.optflag("h", "help", "print this help menu");
+    opts.optflag("s", "serial", "with dump or stop, write the cache to the serial port instead of the terminal");
+
+    let matches = match opts.parse(&args) {
+        Ok(m) => m,
+        Err(e) => {
+            println!("{}", e);
+            print_usage(opts);
+            return -1;
+        }
+    };
+
+    if matches.opt_present("h") {
+        print_usage(opts);
+        return 0;
+    }
+
+    let to_serial = matches.opt_present("s");
+    let free: Vec<&str> = matches.free.iter().map(String::as_str).collect();
+    let result = match free.as_slice() {
+        [] | ["status"] => {
+            status();
+            Ok(())
+        }
+        ["start"] => {
+            prelink::start_recording();
+            println!("Started recording a new prelink cache.");
+            Ok(())
+        }
+        ["dump"] => dump(&prelink::recorded_crates(), to_serial),
+        ["stop"] => dump(&prelink::stop_recording(), to_serial),
+        ["clear"] => {
+            prelink::clear_cache();
+            println!("Cleared the prelink cache; crates loaded from now on will be fully relocated.");
+            Ok(())
+        }
+        _ => Err("unknown command"),
+    };
+
+    match result {
+        Ok(()) => 0,
+        Err(e) => {
+            println!("Error: {}", e);
+            -1
+        }
+    }
+}
+
+fn status() {
+    println!("Recording: {}, {} crates recorded.",
+        if prelink::is_recording() { "yes" } else { "no" },
+        prelink::recorded_crates().len(),
+    );
+    let stats = prelink::stats();
+    println!("Cache: {} crates used, {} crates invalid and fully relocated, {} crates not yet loaded.",
+        stats.hits, stats.misses, stats.pending,
+    );
+}
+
+fn dump(crates: &[prelink::PrelinkedCrate], to_serial: bool) -> Result<(), &'static str> {
+    let cache = prelink::serialize(crates);
+    if to_serial {
+        logger_x86_64::write_str(&cache).map_err(|_| "couldn't write the cache to the serial port")?;
+        println!("Wrote {} crates to the serial port.", crates.len());
+    } else {
+        print!("{}", cache);
+    }
+    Ok(())
+}
+
+fn print_usage(opts: Options) {
+    println!("{}", opts.usage(USAGE));
+}
+
+const USAGE: &str = "Usage: prelink [OPTION] [COMMAND]
+Records the relocation results of loaded crates into a prelink cache for later boots.
+
+Commands:
+    status (or none)      show whether crates are being recorded and how the cache was used at boot.
+    start                 start recording a new cache, discarding the current one.
+    dump                  print the cache recorded so far.
+    stop                  stop recording and print the recorded cache.
+    clear                 stop using the cache given at boot for crates loaded from now on.
+
+To use a cache on later boots, save it as `extra_files/prelink_cache` and rebuild Theseus.";
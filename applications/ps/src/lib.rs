@@ -16,6 +16,7 @@ pub fn main(args: Vec<String>) -> isize {
     let mut opts = Options::new();
     opts.optflag("h", "help", "print this help menu");
     opts.optflag("b", "brief", "print only task id and name");
+    opts.optflag("m", "memory", "print the base address and size of each task's stack and TLS area");
 
     let matches = match opts.parse(&args) {
         Ok(m) => m,
@@ -30,7 +31,10 @@ pub fn main(args: Vec<String>) -> isize {
     }
 
     // Print headers
-    if matches.opt_present("b") {
+    if matches.opt_present("m") {
+        println!("{0:<5}  {1:<18}  {2:<8}  {3:<18}  {4:<8}  {5}", "ID", "STACK", "STACKSZ", "TLS", "TLSSZ", "NAME");
+    }
+    else if matches.opt_present("b") {
         println!("{0:<5}  {1}", "ID", "NAME");
     }
     else {
@@ -47,7 +51,15 @@ pub fn main(args: Vec<String>) -> isize {
     let mut task_string = String::new();
     for (id, task) in TASKLIST.lock().iter() {
         num_tasks += 1;
-        if matches.opt_present("b") {
+        if matches.opt_present("m") {
+            let (stack_bottom, stack_size) = task.with_kstack(|kstack|
+                (kstack.bottom().value(), kstack.top_unusable().value() - kstack.bottom().value())
+            );
+            writeln!(task_string, "{0:<5}  {1:<#18X}  {2:<8}  {3:<#18X}  {4:<8}  {5}",
+                id, stack_bottom, stack_size, task.tls_area_base(), task.tls_area_size(), task.name
+            ).expect("Failed to write to task_string.");
+        }
+        else if matches.opt_present("b") {
             writeln!(task_string, "{0:<5}  {1}", id, task.name).expect("Failed to write to task_string.");
         }
         else {
@@ -88,6 +100,10 @@ const BRIEF: &str = "Usage: ps [options]\n
     CPU:       the cpu core the task is currently running on.
     PIN:       the core the task is pinned on, if any.
     RUNSTATE:  runnability status of this task, e.g., whether it can be scheduled in.
+    STACK:     the bottom address of the task's kernel stack (with -m).
+    STACKSZ:   the size in bytes of the task's kernel stack (with -m).
+    TLS:       the base address of the task's TLS area (with -m).
+    TLSSZ:     the size in bytes of the task's TLS area (with -m).
     ID:        the unique identifier for this task.
     NAME:      the name of the task.";
     
\ No newline at end of file
@@ -0,0 +1,35 @@
+//! Reboots the machine.
+
+#![no_std]
+
+extern crate alloc;
+
+use alloc::{string::String, vec::Vec};
+use app_io::println;
+use getopts::Options;
+
+pub fn main(args: Vec<String>) -> isize {
+    let mut opts = Options::new();
+    opts.optflag("h", "help", "print this help menu");
+
+    let matches = match opts.parse(&args) {
+        Ok(matches) => matches,
+        Err(e) => {
+            println!("{}", e);
+            print_usage(opts);
+            return -1;
+        }
+    };
+    if matches.opt_present("h") {
+        print_usage(opts);
+        return 0;
+    }
+    power::reboot()
+}
+
+fn print_usage(opts: Options) {
+    println!("{}", opts.usage(BRIEF));
+}
+
+const BRIEF: &str = "Usage: reboot [OPTIONS]\n
+Reboots the machine via the ACPI reset register or the keyboard controller.";
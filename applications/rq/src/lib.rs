@@ -6,6 +6,7 @@ extern crate apic;
 extern crate getopts;
 extern crate task;
 extern crate runqueue;
+extern crate scheduler;
 
 use getopts::Options;
 use alloc::{
@@ -21,6 +22,8 @@ use apic::get_lapics;
 pub fn main(args: Vec<String>) -> isize {
     let mut opts = Options::new();
     opts.optflag("h", "help", "print this help menu");
+    opts.optopt("p", "policy", "select the scheduling POLICY for the CPU given by -c, or for all CPUs", "POLICY");
+    opts.optopt("c", "cpu", "with -p, the CPU whose scheduling policy is selected", "CPU");
 
     let matches = match opts.parse(&args) {
         Ok(m) => { m }
@@ -32,6 +35,18 @@ pub fn main(args: Vec<String>) -> isize {
         return print_usage(opts)
     }
 
+    if let Some(policy) = matches.opt_str("p") {
+        let cpu = match matches.opt_str("c").map(|c| c.parse::<u8>()) {
+            Some(Ok(cpu)) => Some(cpu),
+            Some(Err(_)) => {
+                println!("Invalid CPU: {:?}", matches.opt_str("c"));
+                return -1;
+            }
+            None => None,
+        };
+        return select_policy(&policy, cpu);
+    }
+
     let all_lapics = get_lapics();
     for lapic in all_lapics.iter() {
         let lapic = lapic.1;
@@ -41,14 +56,15 @@ pub fn main(args: Vec<String>) -> isize {
         let core_type = if is_bootstrap_cpu {"BSP Core"}
                         else {"AP Core"};
 
-        println!("\n{} (apic: {}, proc: {})", core_type, apic_id, processor); 
+        println!("\n{} (apic: {}, proc: {}), policy: {}", core_type, apic_id, processor, scheduler::policy_name(apic_id));
         
         if let Some(runqueue) = runqueue::get_runqueue(apic_id).map(|rq| rq.read()) {
             let mut runqueue_contents = String::new();
             for task in runqueue.iter() {
-                writeln!(runqueue_contents, "{} ({}) {}", 
+                writeln!(runqueue_contents, "{} ({}) [prio {}] {}", 
                     task.name, 
                     task.id,
+                    task.effective_priority(),
                     if task.is_running() { "*" } else { "" }
                 )
                 .expect("Failed to write to runqueue_contents");
@@ -65,10 +81,42 @@ pub fn main(args: Vec<String>) -> isize {
     0
 }
 
+/// Selects the scheduling policy with the given name for the given CPU, or for all CPUs if `None`.
+fn select_policy(name: &str, cpu: Option<u8>) -> isize {
+    let cpus: Vec<u8> = match cpu {
+        Some(cpu) => core::iter::once(cpu).collect(),
+        None => get_lapics().iter().map(|(apic_id, _)| *apic_id).collect(),
+    };
+    for cpu in cpus {
+        let result = if name == "default" {
+            scheduler::reset_policy(cpu);
+            Ok(())
+        } else {
+            match scheduler::policy_by_name(name) {
+                Some(policy) => scheduler::set_policy(cpu, policy),
+                None => {
+                    println!("Unknown scheduling policy {:?}", name);
+                    return -1;
+                }
+            }
+        };
+        match result {
+            Ok(()) => println!("CPU {} now uses the {} scheduling policy.", cpu, scheduler::policy_name(cpu)),
+            Err(e) => {
+                println!("Couldn't select a scheduling policy for CPU {}: {}", cpu, e);
+                return -1;
+            }
+        }
+    }
+    0
+}
+
 fn print_usage(opts: Options) -> isize {
     let mut brief = "Usage: rq \n \n".to_string();
 
-    brief.push_str("Prints each CPU's ID, the tasks on its runqueue ('*' identifies the currently running task), and whether it is the boot CPU or not");
+    brief.push_str("Prints each CPU's ID, its scheduling policy, the tasks on its runqueue with their priority \
+        ('*' identifies the currently running task), and whether it is the boot CPU or not.\n\
+        With -p, selects the scheduling policy of one or all CPUs instead: 'default', 'round_robin', or 'priority'.");
 
     println!("{} \n", opts.usage(&brief));
 
@@ -0,0 +1,76 @@
+//! A TCP echo server, which echoes back all data it receives on each connection.
+//!
+//! Usage: `tcp_echo [PORT]`, where `PORT` defaults to 7.
+//! Each accepted connection is served by its own task until the peer closes it.
+
+#![no_std]
+
+extern crate alloc;
+
+use alloc::{string::String, vec::Vec};
+use app_io::println;
+use dreadnought::{block_on, task::spawn_async};
+use net::{TcpListener, TcpOptions, TcpStream};
+
+/// The port of the echo protocol.
+const DEFAULT_PORT: u16 = 7;
+/// The number of connections that may wait to be accepted at a time.
+const BACKLOG: usize = 4;
+
+pub fn main(args: Vec<String>) -> isize {
+    let port = match args.first().map(|arg| arg.parse::<u16>()) {
+        None => DEFAULT_PORT,
+        Some(Ok(port)) => port,
+        Some(Err(_)) => {
+            println!("Usage: tcp_echo [PORT]");
+            return -1;
+        }
+    };
+    let Some(interface) = net::get_default_interface() else {
+        println!("tcp_echo: no network interface is available");
+        return -1;
+    };
+    let listener = match TcpListener::bind(interface, port, BACKLOG, TcpOptions::default()) {
+        Ok(listener) => listener,
+        Err(e) => {
+            println!("tcp_echo: couldn't listen on port {}: {}", port, e);
+            return -1;
+        }
+    };
+    println!("tcp_echo: listening on port {}", port);
+
+    block_on(async {
+        loop {
+            match listener.accept().await {
+                Ok(stream) => {
+                    if let Err(e) = spawn_async(echo(stream)) {
+                        println!("tcp_echo: couldn't spawn connection task: {}", e);
+                    }
+                }
+                Err(e) => {
+                    println!("tcp_echo: couldn't accept connection: {}", e);
+                    return -1;
+                }
+            }
+        }
+    })
+}
+
+/// Echoes back all data received on the given `stream` until the peer closes it.
+async fn echo(stream: TcpStream) {
+    let mut buf = [0; 1024];
+    loop {
+        let len = match stream.read(&mut buf).await {
+            Ok(0) => break,
+            Ok(len) => len,
+            Err(e) => {
+                println!("tcp_echo: couldn't read from connection: {}", e);
+                break;
+            }
+        };
+        if let Err(e) = stream.write_all(&buf[..len]).await {
+            println!("tcp_echo: couldn't write to connection: {}", e);
+            break;
+        }
+    }
+}
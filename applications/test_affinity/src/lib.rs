@@ -0,0 +1,110 @@
+//! Tests that changing a task's CPU affinity at runtime migrates it to an allowed CPU.
+//!
+//! This spawns a task pinned to one CPU, waits for it to start running there,
+//! and then changes its affinity to only allow a different CPU.
+//! The task must then be migrated to that CPU, with its TLS area still intact.
+
+#![no_std]
+
+extern crate alloc;
+
+use alloc::{string::String, vec::Vec};
+use core::sync::atomic::{AtomicBool, Ordering};
+use app_io::println;
+use cpu::CpuSet;
+use task::ExitValue;
+
+/// The maximum number of times the migrating task yields while waiting to be migrated.
+const MAX_YIELDS: usize = 1_000_000;
+
+/// Set by the migrating task once it has started running on its first CPU.
+static STARTED: AtomicBool = AtomicBool::new(false);
+
+/// The result of the migrating task.
+#[derive(Debug)]
+struct Migrated {
+    /// The CPU that the task first ran on.
+    first_cpu: u8,
+    /// The CPU that the task ran on when it finished, or `None` if it was never migrated.
+    final_cpu: Option<u8>,
+    /// Whether the task's TLS area still identified the task after it was migrated.
+    tls_intact: bool,
+}
+
+pub fn main(_args: Vec<String>) -> isize {
+    match run() {
+        Ok(()) => {
+            println!("test_affinity: all tests passed.");
+            0
+        }
+        Err(e) => {
+            println!("test_affinity: test failed: {}", e);
+            -1
+        }
+    }
+}
+
+fn run() -> Result<(), &'static str> {
+    if cpu::cpu_count() < 2 {
+        println!("test_affinity: skipped, as migration requires at least two CPUs.");
+        return Ok(());
+    }
+    let current = cpu::current_cpu();
+    let (from_cpu, to_cpu) = {
+        let mut others = (0..=u8::MAX).filter(|&c| c != current && runqueue::get_runqueue(c).is_some());
+        let from = others.next().ok_or("couldn't find another CPU")?;
+        (from, others.next().unwrap_or(current))
+    };
+
+    let task = spawn::new_task_builder(wait_for_migration, to_cpu)
+        .name(String::from("test_affinity_migrating"))
+        .pin_to(from_cpu)
+        .spawn()?;
+    if task.pinned_core() != Some(from_cpu) {
+        return Err("spawned task was not pinned to the requested CPU");
+    }
+    while !STARTED.load(Ordering::Acquire) {
+        scheduler::schedule();
+    }
+
+    println!("test_affinity: moving task {} from CPU {} to CPU {}.", task.id, from_cpu, to_cpu);
+    scheduler::set_affinity(&task, CpuSet::single(to_cpu))?;
+    if task.affinity() != CpuSet::single(to_cpu) {
+        return Err("task's affinity was not updated");
+    }
+    if scheduler::set_affinity(&task, CpuSet::empty()).is_ok() {
+        return Err("setting an empty affinity should have failed");
+    }
+
+    let result = match task.join()? {
+        ExitValue::Completed(value) => value.downcast_ref::<Migrated>()
+            .map(|m| (m.first_cpu, m.final_cpu, m.tls_intact))
+            .ok_or("migrating task returned an unexpected value")?,
+        ExitValue::Killed(_) => return Err("migrating task was killed"),
+    };
+    match result {
+        (first, _, _) if first != from_cpu => Err("task didn't first run on the CPU it was pinned to"),
+        (_, None, _) => Err("task was never migrated to the newly-allowed CPU"),
+        (_, Some(last), _) if last != to_cpu => Err("task was migrated to a disallowed CPU"),
+        (_, _, false) => Err("task's TLS area was corrupted by the migration"),
+        _ => Ok(()),
+    }
+}
+
+fn wait_for_migration(to_cpu: u8) -> Migrated {
+    let id = task::get_my_current_task_id();
+    let first_cpu = cpu::current_cpu();
+    STARTED.store(true, Ordering::Release);
+
+    let mut final_cpu = None;
+    for _ in 0..MAX_YIELDS {
+        if cpu::current_cpu() == to_cpu {
+            final_cpu = Some(to_cpu);
+            break;
+        }
+        scheduler::schedule();
+    }
+    let tls_intact = task::get_my_current_task_id() == id
+        && task::with_current_task(|t| t.id == id).unwrap_or(false);
+    Migrated { first_cpu, final_cpu, tls_intact }
+}
@@ -6,6 +6,7 @@ use alloc::{string::String, vec::Vec};
 use app_io::println;
 use dreadnought::{
     block_on, select_biased,
+    runtime::{Handle, Runtime},
     task::spawn_async,
     time::{sleep, Duration},
     FutureExt,
@@ -30,10 +31,39 @@ pub fn main(_: Vec<String>) -> isize {
         // handle_2.abort();
         // assert!(matches!(handle_2.await, Err(Error::Cancelled)));
 
+        test_runtime();
         0
     })
 }
 
+dreadnought::task_local! {
+    static JOB_ID: usize;
+}
+
+/// Runs many sleeping futures on a multi-worker runtime,
+/// checking that each keeps its task-local value across `.await` points.
+fn test_runtime() {
+    let runtime = Runtime::new(4).expect("failed to create runtime");
+    let handles: Vec<_> = (0..32)
+        .map(|i| runtime.spawn(JOB_ID.scope(i, async move {
+            sleep(Duration::from_millis(10 * (i as u64 % 4))).await;
+            assert_eq!(JOB_ID.get(), i);
+            // Spawn a nested future onto the same runtime.
+            Handle::current().spawn(async move { i * 2 }).await
+        })))
+        .collect();
+    let sum: usize = runtime.block_on(async {
+        let mut sum = 0;
+        for handle in handles {
+            sum += handle.await;
+        }
+        sum
+    });
+    assert_eq!(sum, (0..32).map(|i| i * 2).sum());
+    println!("runtime with {} workers ran all futures", runtime.handle().num_workers());
+    runtime.shutdown();
+}
+
 async fn foo() -> u8 {
     println!("called foo");
     sleep(Duration::from_secs(2)).await;
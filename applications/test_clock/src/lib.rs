@@ -0,0 +1,93 @@
+//! Tests the monotonic and wall clocks from the `clock` crate.
+//!
+//! This reads the monotonic clock on every CPU in turn and checks that it never goes backwards,
+//! reports each CPU's TSC calibration offset, and checks that the wall clock is sane.
+
+#![no_std]
+
+extern crate alloc;
+
+use alloc::{string::String, vec::Vec};
+use core::sync::atomic::{AtomicU64, Ordering};
+use app_io::println;
+use clock::WallClockSource;
+use task::ExitValue;
+use time::{now, Duration, Monotonic, WallTime};
+
+/// The number of times the monotonic clock is read on each CPU.
+const NUM_READS: usize = 1000;
+/// The Unix time of January 1st 2020, before which the RTC-based wall clock cannot be.
+const JANUARY_1ST_2020: Duration = Duration::from_secs(1_577_836_800);
+
+/// The latest monotonic time read by any CPU during the test.
+static LATEST: AtomicU64 = AtomicU64::new(0);
+
+pub fn main(_args: Vec<String>) -> isize {
+    match run() {
+        Ok(()) => {
+            println!("test_clock: all tests passed.");
+            0
+        }
+        Err(e) => {
+            println!("test_clock: test failed: {}", e);
+            -1
+        }
+    }
+}
+
+fn run() -> Result<(), &'static str> {
+    println!("test_clock: the monotonic clock is {}based on the TSC.", if clock::is_tsc_based() { "" } else { "not " });
+    LATEST.store(clock::monotonic_nanos(), Ordering::Release);
+
+    let cpus: Vec<u8> = apic::get_lapics().iter().map(|(apic_id, _)| *apic_id).collect();
+    for cpu in cpus {
+        let task = spawn::new_task_builder(read_monotonic, ())
+            .name(alloc::format!("test_clock_{}", cpu))
+            .pin_to(cpu)
+            .spawn()?;
+        let monotonic = match task.join()? {
+            ExitValue::Completed(value) => *value.downcast_ref::<bool>().ok_or("reading task returned an unexpected value")?,
+            ExitValue::Killed(_) => return Err("reading task was killed"),
+        };
+        if !monotonic {
+            println!("test_clock: CPU {} read an earlier time than a previous CPU.", cpu);
+            return Err("the monotonic clock went backwards across CPUs");
+        }
+        println!("test_clock: CPU {} is monotonic, TSC offset: {:?} ns.", cpu, clock::tsc_offset(cpu));
+    }
+
+    // The `time` crate must use the clocks from the `clock` crate.
+    let before = clock::monotonic_nanos();
+    let instant = now::<Monotonic>();
+    let after = clock::monotonic_nanos();
+    let nanos = instant.duration_since(time::Instant::ZERO).as_nanos() as u64;
+    if nanos < before || nanos > after {
+        return Err("time::now::<Monotonic>() doesn't use the calibrated monotonic clock");
+    }
+
+    let wall_time = now::<WallTime>();
+    println!("test_clock: wall time is {:?} since the Unix epoch, set from {:?}.", wall_time, clock::wall_clock_source());
+    if clock::wall_clock_source() == WallClockSource::Rtc && wall_time < JANUARY_1ST_2020 {
+        return Err("the wall clock is earlier than the year 2020");
+    }
+    let later = now::<WallTime>();
+    if later < wall_time {
+        return Err("the wall clock went backwards");
+    }
+    Ok(())
+}
+
+/// Reads the monotonic clock repeatedly on the current CPU, returning whether it never went backwards,
+/// including relative to the readings of the CPUs that ran before this one.
+fn read_monotonic(_: ()) -> bool {
+    let mut previous = LATEST.load(Ordering::Acquire);
+    for _ in 0..NUM_READS {
+        let nanos = clock::monotonic_nanos();
+        if nanos < previous {
+            return false;
+        }
+        previous = nanos;
+    }
+    LATEST.store(previous, Ordering::Release);
+    true
+}
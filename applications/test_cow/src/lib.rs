@@ -0,0 +1,192 @@
+//! Tests copy-on-write mappings created by [`MappedPages::share_copy_on_write()`].
+//!
+//! This checks that shared mappings initially have the same contents,
+//! that writes to either mapping are not visible through the other,
+//! and that many tasks on different CPUs can concurrently fault on the same copy-on-write pages
+//! without losing writes or leaking shared frames.
+
+#![no_std]
+
+extern crate alloc;
+
+use alloc::{string::String, sync::Arc, vec::Vec};
+use core::{ops::Deref, sync::atomic::{AtomicUsize, Ordering}};
+use app_io::println;
+use memory::{MappedPages, PteFlags, PAGE_SIZE};
+
+/// The number of pages in each mapping.
+const NUM_PAGES: usize = 16;
+/// The number of `u64` words in each mapping.
+const NUM_WORDS: usize = NUM_PAGES * PAGE_SIZE / core::mem::size_of::<u64>();
+/// The number of copy-on-write copies faulted on concurrently.
+const NUM_COPIES: usize = 4;
+/// The number of tasks that concurrently write to each copy.
+const WRITERS_PER_COPY: usize = 4;
+
+pub fn main(_args: Vec<String>) -> isize {
+    match run() {
+        Ok(()) => {
+            println!("test_cow: all tests passed.");
+            0
+        }
+        Err(e) => {
+            println!("test_cow: test failed: {}", e);
+            -1
+        }
+    }
+}
+
+fn run() -> Result<(), &'static str> {
+    let shared_frames_before = memory::num_shared_frames();
+
+    test_isolation()?;
+    println!("test_cow: copy-on-write isolation test passed.");
+    test_concurrent_faults()?;
+    println!("test_cow: concurrent copy-on-write fault test passed.");
+
+    if memory::num_shared_frames() != shared_frames_before {
+        return Err("shared frames were not freed after all copy-on-write mappings were dropped");
+    }
+    Ok(())
+}
+
+/// Shares the given `original` mapping as copy-on-write at newly-allocated pages.
+fn share(original: &mut MappedPages) -> Result<MappedPages, &'static str> {
+    let pages = memory::allocate_pages(original.size_in_pages()).ok_or("couldn't allocate pages")?;
+    let kernel_mmi_ref = memory::get_kernel_mmi_ref().ok_or("KERNEL_MMI was not yet initialized")?;
+    // The lock must be released before writing to either mapping.
+    let mut kernel_mmi = kernel_mmi_ref.lock();
+    original.share_copy_on_write(&mut kernel_mmi.page_table, pages)
+}
+
+/// The value stored at word `index` of a mapping filled with the given `seed`.
+fn value(seed: usize, index: usize) -> u64 {
+    ((seed as u64) << 32) | index as u64
+}
+
+fn fill(mp: &mut MappedPages, seed: usize) -> Result<(), &'static str> {
+    for (i, word) in mp.as_slice_mut::<u64>(0, NUM_WORDS)?.iter_mut().enumerate() {
+        *word = value(seed, i);
+    }
+    Ok(())
+}
+
+fn check(mp: &MappedPages, seed: usize) -> Result<(), &'static str> {
+    let mismatch = mp.as_slice::<u64>(0, NUM_WORDS)?
+        .iter()
+        .enumerate()
+        .any(|(i, word)| *word != value(seed, i));
+    if mismatch {
+        return Err("mapping did not contain the expected contents");
+    }
+    Ok(())
+}
+
+/// Returns the physical address that each page of the given mapping is mapped to.
+fn frames_of(mp: &MappedPages) -> Result<Vec<usize>, &'static str> {
+    mp.deref().clone()
+        .into_iter()
+        .map(|page| memory::translate(page.start_address())
+            .map(|paddr| paddr.value())
+            .ok_or("page was not mapped"))
+        .collect()
+}
+
+fn test_isolation() -> Result<(), &'static str> {
+    let mut original = memory::create_mapping(NUM_PAGES * PAGE_SIZE, PteFlags::new().writable(true))?;
+    fill(&mut original, 1)?;
+
+    let mut copy = share(&mut original)?;
+    check(&original, 1)?;
+    check(&copy, 1)?;
+    if frames_of(&original)? != frames_of(&copy)? {
+        return Err("copy-on-write mappings did not initially share their frames");
+    }
+    let first_frame = memory::Frame::containing_address(memory::PhysicalAddress::new_canonical(frames_of(&copy)?[0]));
+    if memory::shared_frame_mappings(first_frame) != 2 {
+        return Err("shared frame did not have exactly two mappings");
+    }
+
+    fill(&mut copy, 2)?;
+    check(&original, 1)?;
+    check(&copy, 2)?;
+    if frames_of(&original)?.iter().zip(frames_of(&copy)?).any(|(a, b)| *a == b) {
+        return Err("written copy-on-write pages still shared a frame");
+    }
+
+    fill(&mut original, 3)?;
+    check(&original, 3)?;
+    check(&copy, 2)?;
+    Ok(())
+}
+
+/// The arguments of a task that writes to a subset of the words of a copy-on-write mapping.
+struct WriterArgs {
+    /// The starting virtual address of the mapping.
+    start: usize,
+    /// The seed of the values written by this task.
+    seed: usize,
+    /// This task writes to every word whose index modulo `WRITERS_PER_COPY` equals `writer`.
+    writer: usize,
+    /// The number of writer tasks that have not yet started.
+    not_ready: Arc<AtomicUsize>,
+}
+
+fn writer(args: WriterArgs) {
+    // Wait for all writers to start, such that as many faults as possible happen concurrently.
+    args.not_ready.fetch_sub(1, Ordering::AcqRel);
+    while args.not_ready.load(Ordering::Acquire) != 0 {
+        core::hint::spin_loop();
+    }
+    let words = args.start as *mut u64;
+    for i in (args.writer .. NUM_WORDS).step_by(WRITERS_PER_COPY) {
+        // SAFETY: the mapping outlives this task and each word is only written by one task.
+        unsafe { words.add(i).write_volatile(value(args.seed, i)) };
+    }
+}
+
+fn test_concurrent_faults() -> Result<(), &'static str> {
+    let mut original = memory::create_mapping(NUM_PAGES * PAGE_SIZE, PteFlags::new().writable(true))?;
+    fill(&mut original, 10)?;
+
+    let mut copies = Vec::with_capacity(NUM_COPIES);
+    for _ in 0 .. NUM_COPIES {
+        copies.push(share(&mut original)?);
+    }
+
+    let cpu_count = cpu::cpu_count() as usize;
+    let not_ready = Arc::new(AtomicUsize::new(NUM_COPIES * WRITERS_PER_COPY));
+    let mut tasks = Vec::new();
+    for (c, copy) in copies.iter().enumerate() {
+        for w in 0 .. WRITERS_PER_COPY {
+            let args = WriterArgs {
+                start: copy.start_address().value(),
+                seed: 20 + c,
+                writer: w,
+                not_ready: not_ready.clone(),
+            };
+            let task = spawn::new_task_builder(writer, args)
+                .name(alloc::format!("test_cow_writer_{}_{}", c, w))
+                .pin_on_core(((c * WRITERS_PER_COPY + w) % cpu_count) as u8)
+                .spawn()?;
+            tasks.push(task);
+        }
+    }
+    for task in tasks {
+        task.join()?;
+    }
+
+    check(&original, 10)?;
+    let mut frames = frames_of(&original)?;
+    for (c, copy) in copies.iter().enumerate() {
+        check(copy, 20 + c)?;
+        frames.extend(frames_of(copy)?);
+    }
+    let num_frames = frames.len();
+    frames.sort_unstable();
+    frames.dedup();
+    if frames.len() != num_frames {
+        return Err("written copy-on-write pages still shared a frame");
+    }
+    Ok(())
+}
@@ -0,0 +1,60 @@
+//! Tests capturing a structured crash dump via the `crash_dump` crate.
+//!
+//! Since a real unrecoverable fault would halt the system, this captures a dump on request,
+//! then reads it back from the reserved region and checks that it contains every section.
+
+#![no_std]
+
+extern crate alloc;
+
+use alloc::{string::String, vec::Vec};
+use app_io::println;
+use crash_dump::{FaultInfo, DUMP_FOOTER, DUMP_HEADER};
+
+/// The sections that every dump captured from a task must contain.
+const SECTIONS: [&str; 5] = ["[registers]", "[backtrace]", "[tls_area]", "[stack]", "[crates]"];
+
+pub fn main(_args: Vec<String>) -> isize {
+    match run() {
+        Ok(()) => {
+            println!("test_crash_dump: all tests passed.");
+            0
+        }
+        Err(e) => {
+            println!("test_crash_dump: test failed: {}", e);
+            -1
+        }
+    }
+}
+
+fn run() -> Result<(), &'static str> {
+    let stack_variable = 0u64;
+    let fault = FaultInfo {
+        instruction_pointer: run as usize,
+        stack_pointer: &stack_variable as *const u64 as usize,
+        ..Default::default()
+    };
+    let stored = crash_dump::capture(&fault);
+    if stored == 0 {
+        return Err("no crash dump region was reserved");
+    }
+
+    let dump = crash_dump::last_dump().ok_or("couldn't read back the crash dump")?;
+    if dump.len() != stored {
+        return Err("the stored crash dump has the wrong length");
+    }
+    if !dump.contains(DUMP_HEADER) || !dump.contains(DUMP_FOOTER) {
+        return Err("the crash dump is incomplete or was truncated");
+    }
+    for section in SECTIONS {
+        if !dump.contains(section) {
+            println!("test_crash_dump: missing section {}", section);
+            return Err("the crash dump is missing a section");
+        }
+    }
+    if !dump.contains("test_crash_dump") {
+        return Err("the crash dump doesn't list the faulting task or its crate");
+    }
+    println!("test_crash_dump: captured a {}-byte crash dump.", stored);
+    Ok(())
+}
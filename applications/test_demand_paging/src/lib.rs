@@ -0,0 +1,121 @@
+//! Tests demand-paged mappings created by [`memory::create_mapping_on_demand()`].
+//!
+//! This checks that pages are only backed by frames once they are accessed,
+//! that newly-backed pages are zeroed, that pages can be populated ahead of time,
+//! and that many tasks on different CPUs can concurrently fault on the same unbacked pages.
+
+#![no_std]
+
+extern crate alloc;
+
+use alloc::{string::String, vec::Vec};
+use core::ops::Deref;
+use app_io::println;
+use memory::{MappedPages, PteFlags, PAGE_SIZE};
+
+/// The number of pages in each mapping.
+const NUM_PAGES: usize = 32;
+/// The number of `u64` words in each page.
+const WORDS_PER_PAGE: usize = PAGE_SIZE / core::mem::size_of::<u64>();
+/// The number of tasks that concurrently access the same mapping.
+const NUM_TASKS: usize = 8;
+
+pub fn main(_args: Vec<String>) -> isize {
+    match run() {
+        Ok(()) => {
+            println!("test_demand_paging: all tests passed.");
+            0
+        }
+        Err(e) => {
+            println!("test_demand_paging: test failed: {}", e);
+            -1
+        }
+    }
+}
+
+fn run() -> Result<(), &'static str> {
+    test_lazy_backing()?;
+    println!("test_demand_paging: lazy backing test passed.");
+    test_populate()?;
+    println!("test_demand_paging: populate test passed.");
+    test_concurrent_faults()?;
+    println!("test_demand_paging: concurrent fault test passed.");
+    Ok(())
+}
+
+/// Returns the number of pages of the given mapping that are currently backed by a frame.
+fn backed_pages(mp: &MappedPages) -> usize {
+    mp.deref().clone()
+        .into_iter()
+        .filter(|page| memory::translate(page.start_address()).is_some())
+        .count()
+}
+
+fn test_lazy_backing() -> Result<(), &'static str> {
+    let mut mp = memory::create_mapping_on_demand(NUM_PAGES * PAGE_SIZE, PteFlags::new().writable(true))?;
+    if backed_pages(&mp) != 0 {
+        return Err("demand-paged mapping was backed before being accessed");
+    }
+
+    let words = mp.as_slice_mut::<u64>(0, NUM_PAGES * WORDS_PER_PAGE)?;
+    if words[3 * WORDS_PER_PAGE .. 4 * WORDS_PER_PAGE].iter().any(|w| *w != 0) {
+        return Err("newly-backed page was not zeroed");
+    }
+    words[7 * WORDS_PER_PAGE + 1] = 0xDEADBEEF;
+    if words[7 * WORDS_PER_PAGE + 1] != 0xDEADBEEF {
+        return Err("write to newly-backed page was lost");
+    }
+    if backed_pages(&mp) != 2 {
+        return Err("accessing two pages did not back exactly two pages");
+    }
+    Ok(())
+}
+
+fn test_populate() -> Result<(), &'static str> {
+    let mp = memory::create_mapping_on_demand(NUM_PAGES * PAGE_SIZE, PteFlags::new().writable(true))?;
+    {
+        let kernel_mmi_ref = memory::get_kernel_mmi_ref().ok_or("KERNEL_MMI was not yet initialized")?;
+        mp.populate(&mut kernel_mmi_ref.lock().page_table)?;
+    }
+    if backed_pages(&mp) != NUM_PAGES {
+        return Err("populated mapping was not fully backed");
+    }
+    if mp.as_slice::<u64>(0, NUM_PAGES * WORDS_PER_PAGE)?.iter().any(|w| *w != 0) {
+        return Err("populated pages were not zeroed");
+    }
+    Ok(())
+}
+
+/// Writes the given task's index into its own word of every page of the mapping starting at `start`.
+fn toucher((start, index): (usize, usize)) {
+    for page in 0 .. NUM_PAGES {
+        let word = (start + page * PAGE_SIZE) as *mut u64;
+        // SAFETY: the mapping outlives this task and each word is only written by one task.
+        unsafe { word.add(index).write_volatile(index as u64 + 1) };
+    }
+}
+
+fn test_concurrent_faults() -> Result<(), &'static str> {
+    let mp = memory::create_mapping_on_demand(NUM_PAGES * PAGE_SIZE, PteFlags::new().writable(true))?;
+    let cpu_count = cpu::cpu_count() as usize;
+    let mut tasks = Vec::with_capacity(NUM_TASKS);
+    for index in 0 .. NUM_TASKS {
+        let task = spawn::new_task_builder(toucher, (mp.start_address().value(), index))
+            .name(alloc::format!("test_demand_paging_{}", index))
+            .pin_on_core((index % cpu_count) as u8)
+            .spawn()?;
+        tasks.push(task);
+    }
+    for task in tasks {
+        task.join()?;
+    }
+
+    let words = mp.as_slice::<u64>(0, NUM_PAGES * WORDS_PER_PAGE)?;
+    for page in words.chunks(WORDS_PER_PAGE) {
+        let expected = (1 ..= NUM_TASKS as u64).chain(core::iter::repeat(0));
+        if page.iter().zip(expected).any(|(w, e)| *w != e) {
+            return Err("concurrent first accesses to a demand-paged page lost a write");
+        }
+    }
+    Ok(())
+}
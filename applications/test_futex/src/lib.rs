@@ -0,0 +1,89 @@
+//! Tests blocking on and waking up tasks via the `futex` crate.
+//!
+//! This spawns several tasks that wait for a flag to be set,
+//! then sets the flag and wakes them up, first one and then all of the rest.
+
+#![no_std]
+
+extern crate alloc;
+
+use alloc::{string::String, vec::Vec};
+use core::sync::atomic::{AtomicU32, Ordering};
+use app_io::println;
+
+/// The number of waiting tasks to spawn.
+const NUM_WAITERS: u32 = 4;
+/// The maximum number of times the test yields while waiting for tasks to wait or wake up.
+const MAX_YIELDS: usize = 100_000;
+
+/// The value that waiting tasks wait on, which is `0` until they are allowed to proceed.
+static FLAG: AtomicU32 = AtomicU32::new(0);
+/// The number of waiting tasks that have been woken up.
+static WOKEN: AtomicU32 = AtomicU32::new(0);
+
+pub fn main(_args: Vec<String>) -> isize {
+    match run() {
+        Ok(()) => {
+            println!("test_futex: all tests passed.");
+            0
+        }
+        Err(e) => {
+            println!("test_futex: test failed: {}", e);
+            -1
+        }
+    }
+}
+
+fn run() -> Result<(), &'static str> {
+    if futex::wait_on(&FLAG, 1).map_err(|_| "wait_on failed")? {
+        return Err("wait_on slept even though the value didn't match");
+    }
+
+    let mut tasks = Vec::new();
+    for i in 0..NUM_WAITERS {
+        tasks.push(spawn::new_task_builder(waiter, ())
+            .name(alloc::format!("test_futex_waiter_{}", i))
+            .spawn()?);
+    }
+    wait_for(|| futex::num_waiters(&FLAG) == NUM_WAITERS as usize)
+        .ok_or("not every task started waiting")?;
+
+    FLAG.store(1, Ordering::Release);
+    if futex::wake(&FLAG, 1) != 1 {
+        return Err("wake(1) didn't wake up exactly one task");
+    }
+    wait_for(|| WOKEN.load(Ordering::Acquire) == 1)
+        .ok_or("the woken task didn't run")?;
+    if futex::num_waiters(&FLAG) != NUM_WAITERS as usize - 1 {
+        return Err("wake(1) woke up more than one task");
+    }
+
+    if futex::wake_all(&FLAG) != NUM_WAITERS as usize - 1 {
+        return Err("wake_all didn't wake up all remaining tasks");
+    }
+    for task in &tasks {
+        task.join()?;
+    }
+    if WOKEN.load(Ordering::Acquire) != NUM_WAITERS {
+        return Err("not every waiting task was woken up");
+    }
+    Ok(())
+}
+
+/// Yields until the given `condition` holds, returning `None` if it never did.
+fn wait_for(condition: impl Fn() -> bool) -> Option<()> {
+    for _ in 0..MAX_YIELDS {
+        if condition() {
+            return Some(());
+        }
+        scheduler::schedule();
+    }
+    None
+}
+
+fn waiter(_: ()) {
+    while FLAG.load(Ordering::Acquire) == 0 {
+        let _ = futex::wait_on(&FLAG, 0);
+    }
+    WOKEN.fetch_add(1, Ordering::AcqRel);
+}
@@ -0,0 +1,155 @@
+//! Tests mappings backed by huge pages, created by [`memory::create_huge_mapping()`].
+//!
+//! This checks that each huge page is mapped to physically-contiguous frames,
+//! that remapping or unmapping only part of a huge page transparently splits it
+//! without changing its contents or frames, and that the rest of the mapping remains usable.
+
+#![no_std]
+
+extern crate alloc;
+
+use alloc::{string::String, vec::Vec};
+use core::ops::Deref;
+use app_io::println;
+use memory::{HugePageSize, MappedPages, PageRange, PteFlags, PAGE_SIZE};
+
+/// The size of huge pages used by this test.
+const SIZE: HugePageSize = HugePageSize::Size2MiB;
+/// The number of huge pages in the mapping.
+const NUM_HUGE_PAGES: usize = 2;
+/// The number of 4KiB pages in the mapping.
+const NUM_PAGES: usize = NUM_HUGE_PAGES * SIZE.size_in_pages();
+/// The number of `u64` words in each 4KiB page.
+const WORDS_PER_PAGE: usize = PAGE_SIZE / core::mem::size_of::<u64>();
+
+pub fn main(_args: Vec<String>) -> isize {
+    match run() {
+        Ok(()) => {
+            println!("test_huge_pages: all tests passed.");
+            0
+        }
+        Err(e) => {
+            println!("test_huge_pages: test failed: {}", e);
+            -1
+        }
+    }
+}
+
+fn run() -> Result<(), &'static str> {
+    test_mapping()?;
+    println!("test_huge_pages: huge page mapping test passed.");
+    test_split_on_remap()?;
+    println!("test_huge_pages: split on remap test passed.");
+    test_split_on_unmap()?;
+    println!("test_huge_pages: split on unmap test passed.");
+    Ok(())
+}
+
+/// The value stored at word `index` of a mapping.
+fn value(index: usize) -> u64 {
+    0xC0FFEE_0000_0000 | index as u64
+}
+
+fn fill(mp: &mut MappedPages) -> Result<(), &'static str> {
+    let num_words = mp.size_in_pages() * WORDS_PER_PAGE;
+    for (i, word) in mp.as_slice_mut::<u64>(0, num_words)?.iter_mut().enumerate() {
+        *word = value(i);
+    }
+    Ok(())
+}
+
+fn check(mp: &MappedPages) -> Result<(), &'static str> {
+    let num_words = mp.size_in_pages() * WORDS_PER_PAGE;
+    let mismatch = mp.as_slice::<u64>(0, num_words)?
+        .iter()
+        .enumerate()
+        .any(|(i, word)| *word != value(i));
+    if mismatch {
+        return Err("mapping did not contain the expected contents");
+    }
+    Ok(())
+}
+
+/// Returns the physical address that each page of the given mapping is mapped to.
+fn frames_of(mp: &MappedPages) -> Result<Vec<usize>, &'static str> {
+    mp.deref().clone()
+        .into_iter()
+        .map(|page| memory::translate(page.start_address())
+            .map(|paddr| paddr.value())
+            .ok_or("page was not mapped"))
+        .collect()
+}
+
+/// Creates a filled huge page mapping, returning it and the physical address of each of its pages.
+fn create() -> Result<(MappedPages, Vec<usize>), &'static str> {
+    let mut mp = memory::create_huge_mapping(NUM_PAGES * PAGE_SIZE, PteFlags::new().writable(true), SIZE)?;
+    if mp.size_in_pages() != NUM_PAGES {
+        return Err("huge page mapping had an unexpected size");
+    }
+    if mp.start_address().value() % SIZE.size_in_bytes() != 0 {
+        return Err("huge page mapping was not aligned to the huge page size");
+    }
+    fill(&mut mp)?;
+    let frames = frames_of(&mp)?;
+    Ok((mp, frames))
+}
+
+fn test_mapping() -> Result<(), &'static str> {
+    let (mp, frames) = create()?;
+    check(&mp)?;
+    for huge_page in frames.chunks(SIZE.size_in_pages()) {
+        if huge_page[0] % SIZE.size_in_bytes() != 0 {
+            return Err("huge page was not mapped to aligned frames");
+        }
+        if huge_page.windows(2).any(|w| w[1] != w[0] + PAGE_SIZE) {
+            return Err("huge page was not mapped to contiguous frames");
+        }
+    }
+    Ok(())
+}
+
+fn test_split_on_remap() -> Result<(), &'static str> {
+    let (mut mp, frames) = create()?;
+    // Remap a single page in the middle of the first huge page, which must split it.
+    let page = *mp.start() + SIZE.size_in_pages() / 2;
+    let kernel_mmi_ref = memory::get_kernel_mmi_ref().ok_or("KERNEL_MMI was not yet initialized")?;
+    mp.remap_range(&mut kernel_mmi_ref.lock().page_table, PageRange::new(page, page), PteFlags::new())?;
+
+    check(&mp)?;
+    if frames_of(&mp)? != frames {
+        return Err("splitting a huge page changed the frames it was mapped to");
+    }
+    // All other pages must still be writable.
+    fill_except(&mut mp, page.number() - mp.start().number())?;
+    check(&mp)?;
+
+    mp.remap_range(&mut kernel_mmi_ref.lock().page_table, PageRange::new(page, page), PteFlags::new().writable(true))?;
+    fill(&mut mp)?;
+    check(&mp)
+}
+
+/// Writes to every page of the given mapping except for the page at `skipped_index`.
+fn fill_except(mp: &mut MappedPages, skipped_index: usize) -> Result<(), &'static str> {
+    let num_words = mp.size_in_pages() * WORDS_PER_PAGE;
+    let words = mp.as_slice_mut::<u64>(0, num_words)?;
+    for (i, word) in words.iter_mut().enumerate() {
+        if i / WORDS_PER_PAGE != skipped_index {
+            *word = value(i);
+        }
+    }
+    Ok(())
+}
+
+fn test_split_on_unmap() -> Result<(), &'static str> {
+    let (mp, frames) = create()?;
+    // Unmapping the second half of the first huge page (and all of the second) must split the first.
+    let at_page = *mp.start() + SIZE.size_in_pages() / 2;
+    let (first, second) = mp.split(at_page).map_err(|_| "couldn't split huge page mapping")?;
+    drop(second);
+
+    check(&first)?;
+    if frames_of(&first)?[..] != frames[.. first.size_in_pages()] {
+        return Err("splitting a huge page changed the frames it was mapped to");
+    }
+    Ok(())
+}
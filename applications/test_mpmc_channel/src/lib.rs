@@ -0,0 +1,153 @@
+//! Tests the lock-free MPMC channel from the `mpmc_channel` crate.
+//!
+//! Several sender tasks send a known set of messages through a small channel,
+//! while blocking and async receiver tasks drain it concurrently.
+//! This checks that every message is received exactly once
+//! and that receivers observe the disconnection once all senders are dropped.
+
+#![no_std]
+
+extern crate alloc;
+
+use alloc::{string::String, vec::Vec};
+use app_io::println;
+use mpmc_channel::{Receiver, RecvError, Sender, TryRecvError, TrySendError};
+use task::ExitValue;
+
+const NUM_SENDERS: usize = 2;
+const NUM_BLOCKING_RECEIVERS: usize = 2;
+const NUM_ASYNC_RECEIVERS: usize = 1;
+/// The number of messages sent by each sender.
+const NUM_MESSAGES: usize = 10_000;
+/// The capacity of the channel, which is small such that senders often find it full.
+const CAPACITY: usize = 8;
+
+pub fn main(_args: Vec<String>) -> isize {
+    match run() {
+        Ok(()) => {
+            println!("test_mpmc_channel: all tests passed.");
+            0
+        }
+        Err(e) => {
+            println!("test_mpmc_channel: test failed: {}", e);
+            -1
+        }
+    }
+}
+
+fn run() -> Result<(), &'static str> {
+    test_try()?;
+
+    let (sender, receiver) = mpmc_channel::new_channel::<usize>(CAPACITY);
+    let mut receivers = Vec::new();
+    for i in 0..NUM_BLOCKING_RECEIVERS {
+        receivers.push(
+            spawn::new_task_builder(receive_blocking, receiver.clone())
+                .name(alloc::format!("test_mpmc_channel_receiver_{}", i))
+                .spawn()?
+        );
+    }
+    for i in 0..NUM_ASYNC_RECEIVERS {
+        receivers.push(
+            spawn::new_task_builder(receive_async, receiver.clone())
+                .name(alloc::format!("test_mpmc_channel_async_receiver_{}", i))
+                .spawn()?
+        );
+    }
+    drop(receiver);
+
+    let mut senders = Vec::new();
+    for i in 0..NUM_SENDERS {
+        senders.push(
+            spawn::new_task_builder(send, (i, sender.clone()))
+                .name(alloc::format!("test_mpmc_channel_sender_{}", i))
+                .spawn()?
+        );
+    }
+    drop(sender);
+    for sender in senders {
+        sender.join()?;
+    }
+
+    let mut received = Vec::new();
+    for receiver in receivers {
+        match receiver.join()? {
+            ExitValue::Completed(value) => received.extend(
+                value.downcast_ref::<Vec<usize>>().ok_or("receiver returned an unexpected value")?.iter().copied()
+            ),
+            ExitValue::Killed(_) => return Err("receiver task was killed"),
+        }
+    }
+    received.sort_unstable();
+    let expected: Vec<usize> = (0..NUM_SENDERS * NUM_MESSAGES).collect();
+    println!("test_mpmc_channel: received {} of {} messages.", received.len(), expected.len());
+    if received != expected {
+        return Err("messages were lost or received more than once");
+    }
+    Ok(())
+}
+
+/// Tests the non-blocking functions on a single task.
+fn test_try() -> Result<(), &'static str> {
+    let (sender, receiver) = mpmc_channel::new_channel::<usize>(3);
+    if sender.capacity() != 4 {
+        return Err("capacity wasn't rounded up to a power of two");
+    }
+    if receiver.try_recv() != Err(TryRecvError::Empty) {
+        return Err("try_recv() on an empty channel didn't return Empty");
+    }
+    for i in 0..4 {
+        sender.try_send(i).map_err(|_| "try_send() failed on a channel with space")?;
+    }
+    if sender.try_send(4) != Err(TrySendError::Full(4)) {
+        return Err("try_send() on a full channel didn't return Full");
+    }
+    for i in 0..4 {
+        if receiver.try_recv() != Ok(i) {
+            return Err("try_recv() didn't receive messages in order");
+        }
+    }
+    sender.try_send(5).map_err(|_| "try_send() failed after the channel was drained")?;
+    drop(sender);
+    if receiver.try_recv() != Ok(5) {
+        return Err("a message sent before the sender was dropped was lost");
+    }
+    if receiver.recv() != Err(RecvError::Disconnected) {
+        return Err("recv() on a disconnected channel didn't return Disconnected");
+    }
+    Ok(())
+}
+
+fn send((index, sender): (usize, Sender<usize>)) {
+    for i in 0..NUM_MESSAGES {
+        let mut msg = index * NUM_MESSAGES + i;
+        loop {
+            match sender.try_send(msg) {
+                Ok(()) => break,
+                Err(TrySendError::Full(returned)) => {
+                    msg = returned;
+                    scheduler::schedule();
+                }
+                Err(TrySendError::Disconnected(_)) => return,
+            }
+        }
+    }
+}
+
+fn receive_blocking(receiver: Receiver<usize>) -> Vec<usize> {
+    let mut received = Vec::new();
+    while let Ok(msg) = receiver.recv() {
+        received.push(msg);
+    }
+    received
+}
+
+fn receive_async(receiver: Receiver<usize>) -> Vec<usize> {
+    dreadnought::block_on(async {
+        let mut received = Vec::new();
+        while let Ok(msg) = receiver.recv_async().await {
+            received.push(msg);
+        }
+        received
+    })
+}
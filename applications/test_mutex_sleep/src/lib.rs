@@ -9,18 +9,21 @@ extern crate mutex_sleep;
 extern crate cpu;
 
 use core::ops::Deref;
+use core::sync::atomic::{AtomicBool, Ordering};
 
 use alloc::{
     vec::Vec,
     string::String,
     sync::Arc,
 };
-use mutex_sleep::MutexSleep;
+use mutex_sleep::{MutexSleep, MutexSleepPi};
+use task::ExitValue;
 
 
 pub fn main(_args: Vec<String>) -> isize {    
     let res = match _args.get(0).map(|s| &**s) {
         Some("-c") => test_contention(),
+        Some("-p") => test_priority_inheritance(),
         _          => test_lockstep(),
     };
     match res {
@@ -155,3 +158,86 @@ fn lockstep_task((lock, remainder): (Arc<MutexSleep<usize>>, usize)) -> Result<(
     warn!("{} finished loop.", curr_task);
     Ok(())
 }
+
+
+/// The priority of the task that holds the lock in the priority inheritance test.
+const LOW_PRIORITY: u8 = 5;
+/// The priority of the task that waits for the lock in the priority inheritance test.
+const HIGH_PRIORITY: u8 = 35;
+/// The maximum number of times the lock holder yields while waiting to inherit a priority.
+const MAX_YIELDS: usize = 100_000;
+
+/// Set once the low-priority task holds the lock.
+static PI_LOCK_HELD: AtomicBool = AtomicBool::new(false);
+
+/// A test that a low-priority task holding a `MutexSleepPi` inherits the priority
+/// of a high-priority task that blocks on it, and reverts to its own priority upon releasing it.
+fn test_priority_inheritance() -> Result<(), &'static str> {
+    let my_cpu = cpu::current_cpu();
+
+    let shared_lock = Arc::new(MutexSleepPi::new(0usize));
+
+    let low = spawn::new_task_builder(pi_low_task, shared_lock.clone())
+        .name(String::from("mutex_sleep_pi_low"))
+        .pin_on_core(my_cpu)
+        .block()
+        .spawn()?;
+
+    let high = spawn::new_task_builder(pi_high_task, shared_lock.clone())
+        .name(String::from("mutex_sleep_pi_high"))
+        .pin_on_core(my_cpu)
+        .block()
+        .spawn()?;
+
+    low.set_priority(LOW_PRIORITY);
+    high.set_priority(HIGH_PRIORITY);
+    low.unblock().unwrap();
+    high.unblock().unwrap();
+
+    high.join()?;
+    let (inherited, after_release) = match low.join()? {
+        ExitValue::Completed(value) => value.downcast_ref::<(u8, u8)>()
+            .copied()
+            .ok_or("lock holder returned an unexpected value")?,
+        ExitValue::Killed(_) => return Err("lock holder was killed"),
+    };
+    warn!("Lock holder inherited priority {} while holding the lock, and {} after releasing it.", inherited, after_release);
+
+    if inherited != HIGH_PRIORITY {
+        return Err("lock holder didn't inherit the priority of the waiting task");
+    }
+    if after_release != 0 {
+        return Err("lock holder kept its inherited priority after releasing the lock");
+    }
+    if *shared_lock.lock()? != 2 {
+        return Err("the shared value wasn't incremented by both tasks");
+    }
+    Ok(())
+}
+
+/// Holds the lock until it inherits a priority, and returns the inherited priority
+/// while holding the lock and after releasing it.
+fn pi_low_task(lock: Arc<MutexSleepPi<usize>>) -> (u8, u8) {
+    let curr_task = task::get_my_current_task().expect("couldn't get current task");
+    let inherited = {
+        let mut locked = lock.lock().expect("couldn't acquire lock");
+        PI_LOCK_HELD.store(true, Ordering::Release);
+        for _i in 0..MAX_YIELDS {
+            if curr_task.inherited_priority() != 0 {
+                break;
+            }
+            scheduler::schedule(); // give the high-priority task a chance to block on the lock
+        }
+        *locked += 1;
+        curr_task.inherited_priority()
+    };
+    (inherited, curr_task.inherited_priority())
+}
+
+fn pi_high_task(lock: Arc<MutexSleepPi<usize>>) -> Result<(), &'static str> {
+    while !PI_LOCK_HELD.load(Ordering::Acquire) {
+        scheduler::schedule();
+    }
+    *lock.lock()? += 1;
+    Ok(())
+}
@@ -0,0 +1,123 @@
+//! Tests read-copy-update synchronization from the `rcu` crate.
+//!
+//! Reader tasks repeatedly check that an RCU-protected value is internally consistent
+//! while the test concurrently replaces and updates it, and the test then checks that
+//! every replaced value was dropped only after a grace period.
+
+#![no_std]
+
+extern crate alloc;
+
+use alloc::{string::String, vec, vec::Vec};
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use app_io::println;
+use rcu::Rcu;
+use task::ExitValue;
+
+/// The number of reader tasks.
+const NUM_READERS: usize = 4;
+/// The number of times the value is updated.
+const NUM_UPDATES: usize = 1000;
+/// The length of each version of the value.
+const LEN: usize = 16;
+
+/// The number of versions of the value that have been dropped.
+static DROPPED: AtomicUsize = AtomicUsize::new(0);
+/// Tells the reader tasks to exit.
+static STOP: AtomicBool = AtomicBool::new(false);
+
+/// A version of the value, whose elements are all equal to its version number
+/// until it is dropped, at which point they are overwritten.
+struct Version(Vec<usize>);
+
+impl Version {
+    fn new(version: usize) -> Version {
+        Version(vec![version; LEN])
+    }
+
+    fn is_consistent(&self) -> bool {
+        self.0.iter().all(|&v| v == self.0[0] && v != usize::MAX)
+    }
+}
+
+impl Drop for Version {
+    fn drop(&mut self) {
+        // Poison the value such that a reader that still uses it notices.
+        self.0.iter_mut().for_each(|v| *v = usize::MAX);
+        DROPPED.fetch_add(1, Ordering::AcqRel);
+    }
+}
+
+pub fn main(_args: Vec<String>) -> isize {
+    match run() {
+        Ok(()) => {
+            println!("test_rcu: all tests passed.");
+            0
+        }
+        Err(e) => {
+            println!("test_rcu: test failed: {}", e);
+            -1
+        }
+    }
+}
+
+fn run() -> Result<(), &'static str> {
+    let value: &'static Rcu<Version> = alloc::boxed::Box::leak(alloc::boxed::Box::new(Rcu::new(Version::new(0))));
+
+    let mut readers = Vec::with_capacity(NUM_READERS);
+    for i in 0..NUM_READERS {
+        readers.push(
+            spawn::new_task_builder(read, value)
+                .name(alloc::format!("test_rcu_reader_{}", i))
+                .spawn()?
+        );
+    }
+
+    for version in 1..=NUM_UPDATES {
+        if version % 2 == 0 {
+            value.replace(Version::new(version));
+        } else {
+            value.update(|_| Version::new(version));
+        }
+    }
+    rcu::synchronize()?;
+    STOP.store(true, Ordering::Release);
+
+    let mut inconsistent = 0;
+    for reader in readers {
+        match reader.join()? {
+            ExitValue::Completed(value) => inconsistent += *value.downcast_ref::<usize>().ok_or("reader returned an unexpected value")?,
+            ExitValue::Killed(_) => return Err("reader task was killed"),
+        }
+    }
+    if inconsistent != 0 {
+        println!("test_rcu: readers observed {} inconsistent values.", inconsistent);
+        return Err("a reader observed a value that was dropped or being modified");
+    }
+
+    // Each update defers dropping the version it replaced.
+    rcu::synchronize()?;
+    let dropped = DROPPED.load(Ordering::Acquire);
+    println!("test_rcu: {} replaced versions were dropped, {} callbacks pending.", dropped, rcu::num_pending_callbacks());
+    if dropped != NUM_UPDATES {
+        return Err("not every replaced version was dropped after a grace period");
+    }
+    if value.read_lock().0[0] != NUM_UPDATES {
+        return Err("the latest update wasn't published");
+    }
+    Ok(())
+}
+
+/// Reads the value until told to stop, returning the number of inconsistent values it observed.
+fn read(value: &'static Rcu<Version>) -> usize {
+    let mut inconsistent = 0;
+    while !STOP.load(Ordering::Acquire) {
+        let guard = rcu::read_lock();
+        if !value.read(&guard).is_consistent() {
+            inconsistent += 1;
+        }
+        drop(guard);
+        core::hint::spin_loop();
+    }
+    inconsistent
+}
@@ -0,0 +1,97 @@
+//! Tests selecting the priority scheduling policy for a CPU at runtime.
+//!
+//! This selects the [`scheduler::PriorityPolicy`] for the current CPU,
+//! then makes a low-priority and a high-priority task runnable on that CPU at the same time.
+//! The high-priority task must run first, and both must have received a priority boost upon being unblocked.
+
+#![no_std]
+
+extern crate alloc;
+
+use alloc::{string::String, sync::Arc, vec::Vec};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use app_io::println;
+use cpu::CpuSet;
+use scheduler::PriorityPolicy;
+
+const LOW_PRIORITY: u8 = 30;
+const HIGH_PRIORITY: u8 = 38;
+
+/// The number of test tasks that have run so far.
+static NUM_RUN: AtomicUsize = AtomicUsize::new(0);
+
+pub fn main(_args: Vec<String>) -> isize {
+    match run() {
+        Ok(()) => {
+            println!("test_scheduler_policy: all tests passed.");
+            0
+        }
+        Err(e) => {
+            println!("test_scheduler_policy: test failed: {}", e);
+            -1
+        }
+    }
+}
+
+fn run() -> Result<(), &'static str> {
+    let current_task = task::get_my_current_task().ok_or("couldn't get current task")?;
+    // Stay on this CPU, such that the test tasks compete with each other on the same runqueue.
+    let cpu = cpu::current_cpu();
+    let previous_affinity = current_task.affinity();
+    scheduler::set_affinity(&current_task, CpuSet::single(cpu))?;
+    scheduler::set_policy(cpu, Arc::new(PriorityPolicy))?;
+    println!("test_scheduler_policy: CPU {} now uses the {} policy.", cpu, scheduler::policy_name(cpu));
+
+    let result = test_priority_order(cpu);
+
+    scheduler::reset_policy(cpu);
+    scheduler::set_affinity(&current_task, previous_affinity)?;
+    result
+}
+
+fn test_priority_order(cpu: u8) -> Result<(), &'static str> {
+    let low = spawn::new_task_builder(record_order, ())
+        .name(String::from("test_scheduler_policy_low"))
+        .pin_to(cpu)
+        .block()
+        .spawn()?;
+    let high = spawn::new_task_builder(record_order, ())
+        .name(String::from("test_scheduler_policy_high"))
+        .pin_to(cpu)
+        .block()
+        .spawn()?;
+    scheduler::set_priority(&low, LOW_PRIORITY)?;
+    scheduler::set_priority(&high, HIGH_PRIORITY)?;
+
+    // Make both tasks runnable before this CPU can run the scheduler again.
+    let boosts = {
+        let _preemption_guard = preemption::hold_preemption();
+        low.unblock().map_err(|_| "couldn't unblock low-priority task")?;
+        high.unblock().map_err(|_| "couldn't unblock high-priority task")?;
+        (low.priority_boost(), high.priority_boost())
+    };
+    if boosts != (task::WAKE_PRIORITY_BOOST, task::WAKE_PRIORITY_BOOST) {
+        return Err("unblocked tasks did not receive a priority boost");
+    }
+
+    let high_order = join_order(high)?;
+    let low_order = join_order(low)?;
+    if high_order > low_order {
+        return Err("the low-priority task ran before the high-priority task");
+    }
+    Ok(())
+}
+
+/// Waits for the given task to exit, returning the order in which it ran.
+fn join_order(task: task::JoinableTaskRef) -> Result<usize, &'static str> {
+    match task.join()? {
+        task::ExitValue::Completed(value) => value.downcast_ref::<usize>()
+            .copied()
+            .ok_or("test task returned an unexpected value"),
+        task::ExitValue::Killed(_) => Err("test task was killed"),
+    }
+}
+
+fn record_order(_: ()) -> usize {
+    NUM_RUN.fetch_add(1, Ordering::SeqCst)
+}
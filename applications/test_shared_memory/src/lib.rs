@@ -0,0 +1,133 @@
+//! Tests shared memory regions from the `shared_memory` crate.
+//!
+//! The current task creates a named region and grants another task read-only
+//! and then read-write rights to it, checking that the other task can only access
+//! the region as its rights allow, and not at all once they are revoked.
+//! It also checks that a region is destroyed when its owning task exits.
+
+#![no_std]
+
+extern crate alloc;
+
+use alloc::{string::String, vec::Vec};
+use app_io::println;
+use shared_memory::{RegionId, Rights};
+use task::ExitValue;
+
+const REGION_NAME: &str = "test_shared_memory";
+const REGION_SIZE: usize = 8192;
+
+pub fn main(_args: Vec<String>) -> isize {
+    match run() {
+        Ok(()) => {
+            println!("test_shared_memory: all tests passed.");
+            0
+        }
+        Err(e) => {
+            println!("test_shared_memory: test failed: {}", e);
+            -1
+        }
+    }
+}
+
+/// What the other task does with the region.
+#[derive(Clone, Copy)]
+enum Access {
+    Read,
+    Write,
+}
+
+fn run() -> Result<(), &'static str> {
+    let region = shared_memory::create(Some(REGION_NAME), REGION_SIZE)?;
+    if shared_memory::lookup(REGION_NAME) != Some(region.id()) {
+        return Err("couldn't look up the region by name");
+    }
+    if shared_memory::create(Some(REGION_NAME), REGION_SIZE).is_ok() {
+        return Err("created two regions with the same name");
+    }
+    region.write(|bytes| bytes[..4].copy_from_slice(b"ping"))?;
+
+    // Without any rights, the other task can't attach.
+    if access(None, Access::Read)?.is_ok() {
+        return Err("a task without rights attached to the region");
+    }
+    // With read-only rights, it can read but not write.
+    match access(Some((&region, Rights::ReadOnly)), Access::Read)? {
+        Ok(bytes) if &bytes[..] == b"ping" => {}
+        Ok(_) => return Err("a reader saw the wrong contents"),
+        Err(e) => return Err(e),
+    }
+    if access(Some((&region, Rights::ReadOnly)), Access::Write)?.is_ok() {
+        return Err("a task with read-only rights wrote to the region");
+    }
+    // With read-write rights, it can write.
+    access(Some((&region, Rights::ReadWrite)), Access::Write)?
+        .map_err(|_| "a task with read-write rights couldn't write to the region")?;
+    if region.read(|bytes| bytes[..4] != *b"pong")? {
+        return Err("the owner didn't see the other task's write");
+    }
+
+    // The owner's region is destroyed when the owning task exits.
+    let owner = spawn::new_task_builder(create_and_exit, ()).name(String::from("test_shared_memory_owner")).spawn()?;
+    let id = match owner.join()? {
+        ExitValue::Completed(value) => (*value.downcast_ref::<Result<RegionId, &'static str>>()
+            .ok_or("owner task returned an unexpected value")?)?,
+        ExitValue::Killed(_) => return Err("owner task was killed"),
+    };
+    if shared_memory::attach(id).is_ok() {
+        return Err("a region outlived its owning task");
+    }
+    Ok(())
+}
+
+/// Spawns a task that attaches to the region and accesses it, after granting it the given rights if any.
+///
+/// Returns what the task read or the error it encountered, after checking that
+/// it can no longer access the region once its rights are revoked.
+fn access(
+    grant: Option<(&shared_memory::SharedRegion, Rights)>,
+    access: Access,
+) -> Result<Result<Vec<u8>, &'static str>, &'static str> {
+    let task = spawn::new_task_builder(attach_and_access, access)
+        .name(String::from("test_shared_memory_accessor"))
+        .block()
+        .spawn()?;
+    if let Some((region, rights)) = grant {
+        region.grant(&task, rights)?;
+    }
+    task.unblock().map_err(|_| "couldn't unblock accessor task")?;
+    let result = match task.join()? {
+        ExitValue::Completed(value) => value.downcast_ref::<Result<Vec<u8>, &'static str>>()
+            .ok_or("accessor task returned an unexpected value")?
+            .clone(),
+        ExitValue::Killed(_) => return Err("accessor task was killed"),
+    };
+    if let Some((region, _)) = grant {
+        // The exited task's rights were revoked when it exited.
+        if region.rights_of(&task).is_some() && result.is_ok() {
+            return Err("an exited task's rights weren't revoked");
+        }
+        region.revoke(&task);
+    }
+    Ok(result)
+}
+
+fn attach_and_access(access: Access) -> Result<Vec<u8>, &'static str> {
+    let id = shared_memory::lookup(REGION_NAME).ok_or("couldn't find the region")?;
+    let attachment = shared_memory::attach(id)?;
+    match access {
+        Access::Read => attachment.read(|bytes| bytes[..4].to_vec()),
+        Access::Write => attachment.write(|bytes| {
+            bytes[..4].copy_from_slice(b"pong");
+            bytes[..4].to_vec()
+        }),
+    }
+}
+
+fn create_and_exit(_: ()) -> Result<RegionId, &'static str> {
+    let region = shared_memory::create(None, REGION_SIZE)?;
+    let id = region.id();
+    // Leak the owning handle, such that only the task's exit can destroy the region.
+    core::mem::forget(region);
+    Ok(id)
+}
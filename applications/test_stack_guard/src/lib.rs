@@ -0,0 +1,60 @@
+//! Tests that every spawned task's stack has an unmapped guard page beneath it.
+//!
+//! This spawns a task that accesses the guard page beneath its own stack,
+//! which must cause a page fault that kills only that task.
+//! The page fault handler should report it as a stack overflow in that task.
+
+#![no_std]
+
+extern crate alloc;
+
+use alloc::{string::String, vec::Vec};
+use app_io::println;
+use task::{ExitValue, KillReason};
+
+/// The exception number of a page fault.
+const PAGE_FAULT: u8 = 0xE;
+
+pub fn main(_args: Vec<String>) -> isize {
+    match run() {
+        Ok(()) => {
+            println!("test_stack_guard: all tests passed.");
+            0
+        }
+        Err(e) => {
+            println!("test_stack_guard: test failed: {}", e);
+            -1
+        }
+    }
+}
+
+fn run() -> Result<(), &'static str> {
+    let guard_is_unmapped = task::with_current_task(|t| t.with_kstack(|kstack|
+        kstack.guard_page().size_in_pages() >= 1
+            && (*kstack.guard_page().end() + 1) == *kstack.start()
+    )).map_err(|_| "couldn't get current task")?;
+    if !guard_is_unmapped {
+        return Err("the current task's stack did not have a guard page directly beneath it");
+    }
+
+    let task = spawn::new_task_builder(touch_guard_page, ())
+        .name(String::from("test_stack_guard_overflow"))
+        .spawn()?;
+    let task_id = task.id;
+    println!("test_stack_guard: task {} will access its stack guard page, \
+        which should be reported as a stack overflow in that task.", task_id);
+
+    match task.join()? {
+        ExitValue::Killed(KillReason::Exception(PAGE_FAULT)) => Ok(()),
+        ExitValue::Killed(_) => Err("task was killed for a reason other than a page fault"),
+        ExitValue::Completed(_) => Err("task accessed its stack guard page without faulting"),
+    }
+}
+
+fn touch_guard_page(_: ()) {
+    let guard_page_addr = task::with_current_task(|t| t.with_kstack(|kstack| kstack.bottom().value() - 1))
+        .expect("couldn't get current task");
+    // SAFETY: this is expected to fault, as the guard page beneath every stack is unmapped.
+    let value = unsafe { core::ptr::read_volatile(guard_page_addr as *const u8) };
+    println!("test_stack_guard: unexpectedly read {:#X} from the stack guard page", value);
+}
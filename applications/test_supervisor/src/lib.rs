@@ -0,0 +1,139 @@
+//! Tests restart policies of restartable tasks from the `spawn` crate
+//! and supervisors from the `supervisor` crate.
+//!
+//! This checks that a task with a limited restart policy is restarted only after failing,
+//! at most as often as its policy allows, and that its cleanup and escalation functions run,
+//! and that a supervisor restarts a task that escalates until the supervisor gives up on it.
+
+#![no_std]
+
+extern crate alloc;
+
+use alloc::{string::String, vec::Vec};
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use app_io::println;
+use supervisor::Supervisor;
+use task::RestartPolicy;
+
+/// The maximum number of times the test yields while waiting for tasks to be restarted.
+const MAX_YIELDS: usize = 1_000_000;
+
+static FLAKY_RUNS: AtomicUsize = AtomicUsize::new(0);
+static FLAKY_CLEANUPS: AtomicUsize = AtomicUsize::new(0);
+static FLAKY_SUCCEEDED: AtomicBool = AtomicBool::new(false);
+
+static FAILING_RUNS: AtomicUsize = AtomicUsize::new(0);
+static FAILING_CLEANUPS: AtomicUsize = AtomicUsize::new(0);
+static FAILING_ESCALATED: AtomicBool = AtomicBool::new(false);
+
+static DRIVER_RUNS: AtomicUsize = AtomicUsize::new(0);
+static DRIVER_GAVE_UP: AtomicBool = AtomicBool::new(false);
+
+pub fn main(_args: Vec<String>) -> isize {
+    match run() {
+        Ok(()) => {
+            println!("test_supervisor: all tests passed.");
+            0
+        }
+        Err(e) => {
+            println!("test_supervisor: test failed: {}", e);
+            -1
+        }
+    }
+}
+
+fn run() -> Result<(), &'static str> {
+    test_restart_until_success()?;
+    test_escalation()?;
+    test_supervisor()
+}
+
+fn wait_for(condition: impl Fn() -> bool) -> Result<(), &'static str> {
+    for _ in 0..MAX_YIELDS {
+        if condition() {
+            return Ok(());
+        }
+        scheduler::schedule();
+    }
+    Err("timed out waiting for restarted tasks")
+}
+
+/// Panics the first two times it runs, then succeeds.
+fn flaky_task(_: ()) {
+    if FLAKY_RUNS.fetch_add(1, Ordering::AcqRel) < 2 {
+        panic!("test_supervisor: flaky task failed on purpose");
+    }
+    FLAKY_SUCCEEDED.store(true, Ordering::Release);
+}
+
+fn failing_task(_: ()) {
+    FAILING_RUNS.fetch_add(1, Ordering::AcqRel);
+    panic!("test_supervisor: failing task failed on purpose");
+}
+
+fn failing_driver(_: ()) {
+    DRIVER_RUNS.fetch_add(1, Ordering::AcqRel);
+    panic!("test_supervisor: failing driver failed on purpose");
+}
+
+fn test_restart_until_success() -> Result<(), &'static str> {
+    let policy = RestartPolicy::on_failure(3)
+        .cleanup(|_| { FLAKY_CLEANUPS.fetch_add(1, Ordering::AcqRel); })
+        .escalate(|_| panic!("test_supervisor: flaky task escalated"));
+    spawn::new_task_builder(flaky_task, ())
+        .name(String::from("test_supervisor_flaky"))
+        .restart_policy(policy)
+        .spawn_restartable(None)?;
+    wait_for(|| FLAKY_SUCCEEDED.load(Ordering::Acquire))?;
+
+    // Give the task a chance to be wrongly restarted after succeeding.
+    for _ in 0..100 {
+        scheduler::schedule();
+    }
+    if FLAKY_RUNS.load(Ordering::Acquire) != 3 || FLAKY_CLEANUPS.load(Ordering::Acquire) != 2 {
+        return Err("the flaky task wasn't run exactly three times with two cleanups");
+    }
+    Ok(())
+}
+
+fn test_escalation() -> Result<(), &'static str> {
+    let policy = RestartPolicy::on_failure(2)
+        .cleanup(|_| { FAILING_CLEANUPS.fetch_add(1, Ordering::AcqRel); })
+        .escalate(|_| FAILING_ESCALATED.store(true, Ordering::Release));
+    spawn::new_task_builder(failing_task, ())
+        .name(String::from("test_supervisor_failing"))
+        .restart_policy(policy)
+        .spawn_restartable(None)?;
+    wait_for(|| FAILING_ESCALATED.load(Ordering::Acquire))?;
+
+    if FAILING_RUNS.load(Ordering::Acquire) != 3 || FAILING_CLEANUPS.load(Ordering::Acquire) != 3 {
+        return Err("the failing task wasn't run and cleaned up exactly three times before escalating");
+    }
+    Ok(())
+}
+
+fn test_supervisor() -> Result<(), &'static str> {
+    let supervisor = Supervisor::new(String::from("test_supervisor"), 1);
+    let policy = RestartPolicy::on_failure(1)
+        .escalate(|_| DRIVER_GAVE_UP.store(true, Ordering::Release));
+    supervisor.supervise(String::from("driver"), failing_driver, (), policy)?;
+    wait_for(|| DRIVER_GAVE_UP.load(Ordering::Acquire))?;
+
+    // Each instance runs twice (once plus one restart), and the supervisor restarts it once.
+    if DRIVER_RUNS.load(Ordering::Acquire) != 4 {
+        return Err("the supervised task wasn't run exactly four times");
+    }
+    let status = supervisor.status("driver").ok_or("the supervisor lost track of its task")?;
+    println!("test_supervisor: supervised task status: {:?}", status);
+    if !status.failed || status.escalations != 2 || status.last_failure.is_none() {
+        return Err("the supervisor didn't give up on its failing task");
+    }
+
+    DRIVER_GAVE_UP.store(false, Ordering::Release);
+    supervisor.restart("driver")?;
+    wait_for(|| DRIVER_GAVE_UP.load(Ordering::Acquire))?;
+    if DRIVER_RUNS.load(Ordering::Acquire) != 8 {
+        return Err("the explicitly restarted task wasn't run exactly four more times");
+    }
+    Ok(())
+}
@@ -0,0 +1,128 @@
+//! Tests asynchronous event delivery to tasks from the `task_events` crate.
+//!
+//! This checks that posted events can be polled for and are coalesced,
+//! that a task blocked in `wait()` is woken by a posted event,
+//! that registered handlers run in the task's own context,
+//! and that a timer can post an event when it expires.
+
+#![no_std]
+
+extern crate alloc;
+
+use alloc::{boxed::Box, string::String, vec::Vec};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use app_io::println;
+use task::ExitValue;
+use task_events::{Event, EventSet, MAX_CUSTOM_EVENTS};
+use time::Duration;
+use timer::TimerAction;
+
+/// The delay of the timer that posts an event to the current task.
+const TIMER_DELAY: Duration = Duration::from_millis(1);
+
+static HANDLER_RUNS: AtomicUsize = AtomicUsize::new(0);
+
+pub fn main(_args: Vec<String>) -> isize {
+    match run() {
+        Ok(()) => {
+            println!("test_task_events: all tests passed.");
+            0
+        }
+        Err(e) => {
+            println!("test_task_events: test failed: {}", e);
+            -1
+        }
+    }
+}
+
+fn run() -> Result<(), &'static str> {
+    test_poll()?;
+    test_wait()?;
+    test_handler()?;
+    test_timer()
+}
+
+fn test_poll() -> Result<(), &'static str> {
+    let current = task::get_my_current_task().ok_or("couldn't get the current task")?;
+    if !task_events::poll().is_empty() {
+        return Err("events were pending before any were posted");
+    }
+    task_events::post(&current, Event::IoReady)?;
+    task_events::post(&current, Event::IoReady)?;
+    task_events::post(&current, Event::Custom(0))?;
+    let polled = task_events::poll();
+    if polled != EventSet::of(Event::IoReady).with(Event::Custom(0)) {
+        return Err("polling didn't return exactly the posted events");
+    }
+    if !task_events::poll().is_empty() {
+        return Err("polling didn't clear the pending events");
+    }
+    if task_events::post(&current, Event::Custom(MAX_CUSTOM_EVENTS)).is_ok() {
+        return Err("posted an out-of-range custom event");
+    }
+    println!("test_task_events: polled {:?}.", polled);
+    Ok(())
+}
+
+fn wait_for_custom_event(_: ()) -> EventSet {
+    task_events::wait(EventSet::of(Event::Custom(1))).unwrap_or_default()
+}
+
+fn test_wait() -> Result<(), &'static str> {
+    let waiter = spawn::new_task_builder(wait_for_custom_event, ())
+        .name(String::from("test_task_events_waiter"))
+        .spawn()?;
+    // Give the waiter a chance to block before posting the event it's waiting for.
+    for _ in 0..10 {
+        scheduler::schedule();
+    }
+    task_events::post(&waiter, Event::IoReady)?;
+    task_events::post(&waiter, Event::Custom(1))?;
+    match waiter.join()? {
+        ExitValue::Completed(value) => match value.downcast_ref::<EventSet>() {
+            Some(events) if *events == EventSet::of(Event::Custom(1)) => Ok(()),
+            _ => Err("the waiting task didn't receive the event it waited for"),
+        },
+        ExitValue::Killed(_) => Err("the waiting task was killed"),
+    }
+}
+
+fn test_handler() -> Result<(), &'static str> {
+    let current = task::get_my_current_task().ok_or("couldn't get the current task")?;
+    task_events::register_handler(Event::Custom(2), Box::new(|event| {
+        assert_eq!(event, Event::Custom(2));
+        HANDLER_RUNS.fetch_add(1, Ordering::AcqRel);
+    }))?;
+    task_events::post(&current, Event::Custom(2))?;
+    if !task_events::poll().is_empty() {
+        return Err("polling returned an event that has a handler");
+    }
+    task_events::deliver_pending();
+    if HANDLER_RUNS.load(Ordering::Acquire) != 1 {
+        return Err("the handler didn't run exactly once");
+    }
+
+    if task_events::unregister_handler(Event::Custom(2)).is_none() {
+        return Err("couldn't unregister the handler");
+    }
+    task_events::post(&current, Event::Custom(2))?;
+    task_events::deliver_pending();
+    if HANDLER_RUNS.load(Ordering::Acquire) != 1 {
+        return Err("an unregistered handler ran");
+    }
+    if !task_events::poll().contains(Event::Custom(2)) {
+        return Err("an event whose handler was unregistered couldn't be polled");
+    }
+    Ok(())
+}
+
+fn test_timer() -> Result<(), &'static str> {
+    let current = task::get_my_current_task().ok_or("couldn't get the current task")?;
+    timer::start_one_shot_after(TIMER_DELAY, TimerAction::PostEvent(current));
+    let events = task_events::wait(EventSet::of(Event::TimerExpired))?;
+    if !events.contains(Event::TimerExpired) {
+        return Err("waiting for a timer returned the wrong events");
+    }
+    println!("test_task_events: timer of {:?} posted {:?}.", TIMER_DELAY, events);
+    Ok(())
+}
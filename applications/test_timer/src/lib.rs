@@ -0,0 +1,119 @@
+//! Tests high-resolution one-shot and periodic timers from the `timer` crate.
+//!
+//! This checks that one-shot timers fire no earlier than their deadline,
+//! that periodic timers fire repeatedly until cancelled,
+//! and reports how late each kind of timer fired.
+
+#![no_std]
+
+extern crate alloc;
+
+use alloc::{boxed::Box, string::String, vec::Vec};
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use app_io::println;
+use time::{now, Duration, Instant, Monotonic};
+use timer::TimerAction;
+
+/// The delay of the one-shot timer, which is shorter than a scheduler tick.
+const ONE_SHOT_DELAY: Duration = Duration::from_micros(500);
+/// The period of the periodic timer.
+const PERIOD: Duration = Duration::from_millis(2);
+/// The number of times the periodic timer must fire.
+const NUM_PERIODS: usize = 5;
+/// The maximum number of times the test yields while waiting for a timer to fire.
+const MAX_YIELDS: usize = 1_000_000;
+
+static ONE_SHOT_FIRED: AtomicBool = AtomicBool::new(false);
+/// The time at which the one-shot timer fired, in nanoseconds of the monotonic clock.
+static ONE_SHOT_FIRED_AT: AtomicU64 = AtomicU64::new(0);
+static PERIODIC_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+pub fn main(_args: Vec<String>) -> isize {
+    match run() {
+        Ok(()) => {
+            println!("test_timer: all tests passed.");
+            0
+        }
+        Err(e) => {
+            println!("test_timer: test failed: {}", e);
+            -1
+        }
+    }
+}
+
+fn run() -> Result<(), &'static str> {
+    test_one_shot()?;
+    test_periodic()?;
+    test_sleep()
+}
+
+fn nanos_now() -> u64 {
+    now::<Monotonic>().duration_since(Instant::ZERO).as_nanos() as u64
+}
+
+fn wait_for(condition: impl Fn() -> bool) -> Result<(), &'static str> {
+    for _ in 0..MAX_YIELDS {
+        if condition() {
+            return Ok(());
+        }
+        scheduler::schedule();
+    }
+    Err("timed out waiting for a timer to fire")
+}
+
+fn test_one_shot() -> Result<(), &'static str> {
+    let start = nanos_now();
+    timer::start_one_shot_after(ONE_SHOT_DELAY, TimerAction::Callback(Box::new(|| {
+        ONE_SHOT_FIRED_AT.store(nanos_now(), Ordering::Relaxed);
+        ONE_SHOT_FIRED.store(true, Ordering::Release);
+    })));
+    wait_for(|| ONE_SHOT_FIRED.load(Ordering::Acquire))?;
+
+    let elapsed = Duration::from_nanos(ONE_SHOT_FIRED_AT.load(Ordering::Relaxed) - start);
+    println!("test_timer: one-shot timer of {:?} fired after {:?}.", ONE_SHOT_DELAY, elapsed);
+    if elapsed < ONE_SHOT_DELAY {
+        return Err("one-shot timer fired before its deadline");
+    }
+
+    let cancelled = timer::start_one_shot_after(ONE_SHOT_DELAY, TimerAction::Callback(Box::new(|| {
+        panic!("test_timer: cancelled timer fired");
+    })));
+    if !timer::cancel(cancelled) {
+        return Err("couldn't cancel a pending one-shot timer");
+    }
+    if timer::cancel(cancelled) {
+        return Err("cancelled the same timer twice");
+    }
+    Ok(())
+}
+
+fn test_periodic() -> Result<(), &'static str> {
+    let start = nanos_now();
+    let id = timer::start_periodic(PERIOD, TimerAction::Callback(Box::new(|| {
+        PERIODIC_COUNT.fetch_add(1, Ordering::AcqRel);
+    })))?;
+    wait_for(|| PERIODIC_COUNT.load(Ordering::Acquire) >= NUM_PERIODS)?;
+    let elapsed = Duration::from_nanos(nanos_now() - start);
+    if !timer::cancel(id) {
+        return Err("couldn't cancel the periodic timer");
+    }
+    println!("test_timer: periodic timer of {:?} fired {} times in {:?}.", PERIOD, NUM_PERIODS, elapsed);
+    if elapsed < PERIOD * NUM_PERIODS as u32 {
+        return Err("periodic timer fired more often than its period");
+    }
+    if timer::start_periodic(Duration::ZERO, TimerAction::Callback(Box::new(|| {}))).is_ok() {
+        return Err("started a periodic timer with a zero period");
+    }
+    Ok(())
+}
+
+fn test_sleep() -> Result<(), &'static str> {
+    let start = now::<Monotonic>();
+    sleep::sleep(ONE_SHOT_DELAY).map_err(|_| "couldn't sleep")?;
+    let elapsed = now::<Monotonic>() - start;
+    println!("test_timer: sleep of {:?} lasted {:?}.", ONE_SHOT_DELAY, elapsed);
+    if elapsed < ONE_SHOT_DELAY {
+        return Err("sleep returned before its duration elapsed");
+    }
+    Ok(())
+}
@@ -0,0 +1,32 @@
+#!/bin/bash
+# Regenerates the object files used by `test_tls_relocations`,
+# which contain the TLS code sequences and relocations that LLVM emits under each TLS model.
+#
+# Requires `llc` and `llvm-mc` (LLVM 14 or newer), which can be overridden with the `LLC` and `LLVM_MC` variables.
+set -e
+
+FIXTURES_DIR="$(dirname "$(readlink -f "$0")")"
+LLC="${LLC:-llc}"
+LLVM_MC="${LLVM_MC:-llvm-mc}"
+
+## Compiles `tls_var.ll` for the given target and TLS model, with any extra `llc` arguments.
+compile() {
+	local arch=$1 model=$2 name=$3
+	shift 3
+	sed "s/TLS_MODEL/$model/" "$FIXTURES_DIR/tls_var.ll" | "$LLC" - -O2 \
+		-mtriple="$arch-unknown-none-elf" -relocation-model=pic -filetype=obj \
+		"$@" -o "$FIXTURES_DIR/$arch-$name.o"
+}
+
+for arch in x86_64 aarch64; do
+	compile $arch "thread_local(localexec)"    local-exec
+	compile $arch "thread_local(initialexec)"  initial-exec
+	compile $arch "thread_local(localdynamic)" local-dynamic
+	compile $arch "thread_local"               general-dynamic
+done
+
+# aarch64 local-exec code depends on the maximum size of the TLS area.
+compile aarch64 "thread_local(localexec)" local-exec-tls-size-12 -tls-size=12
+compile aarch64 "thread_local(localexec)" local-exec-tls-size-32 -tls-size=32
+
+"$LLVM_MC" -triple=x86_64-unknown-none-elf -filetype=obj "$FIXTURES_DIR/x86_64-data.s" -o "$FIXTURES_DIR/x86_64-data.o"
@@ -0,0 +1,13 @@
+; A thread-local variable that is accessed by address and by value.
+; `generate.sh` compiles this under each TLS model by replacing `TLS_MODEL`.
+
+@TLS_VAR = TLS_MODEL global i64 0, align 8
+
+define i64* @tls_var_address() nounwind {
+  ret i64* @TLS_VAR
+}
+
+define i64 @tls_var_load() nounwind {
+  %value = load i64, i64* @TLS_VAR, align 8
+  ret i64 %value
+}
@@ -0,0 +1,10 @@
+# TLS offsets stored as data, e.g., in a `.data.rel.ro` section or a GOT entry.
+	.section .data.rel.ro,"aw"
+	.globl	tls_offsets
+tls_offsets:
+	.quad	TLS_VAR@tpoff + 8
+	.quad	TLS_VAR@dtpoff + 8
+	.size	tls_offsets, 16
+	.section .tbss,"awT",@nobits
+TLS_VAR:
+	.zero	8
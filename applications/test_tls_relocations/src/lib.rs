@@ -0,0 +1,379 @@
+//! Tests writing the TLS relocations that compilers emit under each TLS model
+//! (local-exec, initial-exec, local-dynamic, general-dynamic, and TLS descriptors),
+//! checking that the loader relaxes each code sequence into the expected local-exec code
+//! and that re-writing a relocation (e.g., when swapping crates) yields the same code.
+//!
+//! Most code sequences come from the object files in the `fixtures` directory,
+//! which are emitted by LLVM for `x86_64` and `aarch64` under each TLS model (see `fixtures/generate.sh`).
+//! A few encodings that LLVM doesn't emit for those fixtures are tested with hand-written code sequences,
+//! with the relocated fields left zeroed as they appear in an object file.
+//! They're all tested regardless of the current architecture, as writing them only modifies bytes.
+//!
+//! Usage: `test_tls_relocations`
+
+#![no_std]
+
+extern crate alloc;
+
+use alloc::{collections::BTreeMap, format, string::String, vec::Vec};
+use app_io::println;
+use memory::VirtualAddress;
+use mod_mgmt::{find_symbol_table, write_relocation, RelocationEntry};
+use xmas_elf::{
+    sections::{SectionData, ShType},
+    symbol_table::Entry,
+    ElfFile,
+};
+
+const R_X86_64_DTPMOD64: u32 = 16;
+const R_X86_64_GOTTPOFF: u32 = 22;
+const R_AARCH64_TLSLE_ADD_TPREL_HI12:       u32 = 549;
+const R_AARCH64_TLSLE_ADD_TPREL_LO12_NC:    u32 = 551;
+const R_AARCH64_TLSLE_LDST64_TPREL_LO12_NC: u32 = 559;
+const R_AARCH64_TLS_DTPMOD64:               u32 = 1028;
+const R_AARCH64_TLS_TPREL64:                u32 = 1030;
+
+/// The TLS offset used for `x86_64`, where static TLS sections are below the TLS self pointer.
+/// In little-endian code, this is `0xf0, 0xff, 0xff, 0xff`.
+const X86_64_OFFSET: isize = -0x10;
+/// The TLS offset used for `aarch64`, which spans both of the 16-bit halves used by `movz`/`movk`.
+const AARCH64_OFFSET: isize = 0x12340;
+/// The TLS offset used for `aarch64` code that can only access the first 4KiB of the TLS area.
+const AARCH64_SMALL_OFFSET: isize = 0x120;
+const MODULE_ID: isize = 1;
+
+/// Includes an object file from the `fixtures` directory,
+/// aligned such that its ELF headers can be parsed in place.
+macro_rules! fixture {
+    ($name:literal) => {{
+        #[repr(C, align(8))]
+        struct Aligned<T: ?Sized>(T);
+        static OBJECT: &Aligned<[u8]> = &Aligned(*include_bytes!(concat!("../fixtures/", $name, ".o")));
+        &OBJECT.0
+    }};
+}
+
+/// An object file whose relocations are all written, after which its symbols must contain the expected bytes.
+struct Fixture {
+    name: &'static str,
+    object: &'static [u8],
+    /// The TLS offset of the section containing the TLS variable.
+    source: isize,
+    /// The name and expected contents of each symbol.
+    expected: Vec<(&'static str, Vec<u8>)>,
+}
+
+/// A code sequence with its relocations, and the code expected after writing them.
+struct TestCase {
+    name: &'static str,
+    code: Vec<u8>,
+    /// The relocation type, addend, and offset into `code`.
+    relocations: &'static [(u32, isize, usize)],
+    /// The source value of each relocation: a TLS offset, or the TLS module ID.
+    source: isize,
+    expected: Vec<u8>,
+}
+
+pub fn main(_args: Vec<String>) -> isize {
+    let mut failures = 0;
+    let fixture_results = fixtures().into_iter().map(|fixture| (fixture.name, run_fixture(&fixture)));
+    let case_results = test_cases().into_iter().map(|case| (case.name, run(&case)));
+    for (name, result) in fixture_results.chain(case_results) {
+        match result {
+            Ok(()) => println!("passed: {}", name),
+            Err(e) => {
+                println!("FAILED: {}: {}", name, e);
+                failures += 1;
+            }
+        }
+    }
+    if failures == 0 {
+        println!("test_tls_relocations: all tests passed");
+        0
+    } else {
+        println!("test_tls_relocations: {} tests failed", failures);
+        -1
+    }
+}
+
+fn run_fixture(fixture: &Fixture) -> Result<(), String> {
+    let elf_file = ElfFile::new(fixture.object)?;
+    let symtab = find_symbol_table(&elf_file)?;
+    let rela_sections: Vec<_> = elf_file.section_iter()
+        .filter(|sec| sec.get_type() == Ok(ShType::Rela))
+        .collect();
+
+    // A copy of the contents of each section that relocations are written into, by section index.
+    let mut target_sections = BTreeMap::new();
+    for rela_sec in &rela_sections {
+        let target_sec_shndx = rela_sec.info() as u16;
+        let target_sec = elf_file.section_header(target_sec_shndx)?;
+        target_sections.insert(target_sec_shndx, target_sec.raw_data(&elf_file).to_vec());
+    }
+
+    // Write every relocation twice, as the second write must not change the relaxed code.
+    for pass in 0..2 {
+        for rela_sec in &rela_sections {
+            let Ok(SectionData::Rela64(rela_array)) = rela_sec.get_data(&elf_file) else {
+                return Err(format!("couldn't parse Rela section {:?}", rela_sec.get_name(&elf_file)));
+            };
+            let target_sec_data = target_sections.get_mut(&(rela_sec.info() as u16))
+                .ok_or("BUG: target section wasn't copied")?;
+
+            // The offset of a call to `__tls_get_addr()` that was removed by relaxing a TLS code sequence,
+            // whose relocation entry must be skipped, just like when loading a crate.
+            let mut relaxed_tls_call_offset: Option<usize> = None;
+            for rela_entry in rela_array {
+                if relaxed_tls_call_offset.take() == Some(rela_entry.get_offset() as usize) {
+                    continue;
+                }
+                let relocation_entry = RelocationEntry::from_elf_relocation(rela_entry);
+                let symbol_value = symtab[rela_entry.get_symbol_table_index() as usize].value() as usize;
+                let source = if relocation_entry.is_tls_module_id() {
+                    MODULE_ID as usize
+                } else if relocation_entry.is_tls() {
+                    (fixture.source as usize).wrapping_add(symbol_value)
+                } else {
+                    return Err(format!("unexpected non-TLS relocation {:?}", relocation_entry));
+                };
+                write_relocation(
+                    relocation_entry,
+                    target_sec_data,
+                    0,
+                    VirtualAddress::new_canonical(source),
+                    false,
+                )?;
+                relaxed_tls_call_offset = relocation_entry.relaxed_tls_call_offset();
+            }
+        }
+
+        for (symbol_name, expected) in &fixture.expected {
+            let symbol = symtab.iter()
+                .find(|symbol| symbol.get_name(&elf_file) == Ok(*symbol_name))
+                .ok_or_else(|| format!("couldn't find symbol {symbol_name:?}"))?;
+            let start = symbol.value() as usize;
+            let contents = target_sections.get(&symbol.shndx())
+                .and_then(|data| data.get(start .. start + symbol.size() as usize))
+                .ok_or_else(|| format!("symbol {symbol_name:?} isn't in a relocated section"))?;
+            if contents != expected.as_slice() {
+                return Err(format!("pass {pass}: {symbol_name}: expected {:02X?}, got {:02X?}", expected, contents));
+            }
+        }
+    }
+    Ok(())
+}
+
+fn run(case: &TestCase) -> Result<(), String> {
+    let mut code = case.code.clone();
+    // Write every relocation twice, as the second write must not change the relaxed code.
+    for pass in 0..2 {
+        for &(typ, addend, offset) in case.relocations {
+            let relocation_entry = RelocationEntry { typ, addend: addend as usize, offset };
+            write_relocation(
+                relocation_entry,
+                &mut code,
+                0,
+                VirtualAddress::new_canonical(case.source as usize),
+                false,
+            )?;
+        }
+        if code != case.expected {
+            return Err(format!("pass {pass}: expected {:02X?}, got {:02X?}", case.expected, code));
+        }
+    }
+    Ok(())
+}
+
+/// Returns the little-endian bytes of the given aarch64 instructions.
+fn insns(insns: &[u32]) -> Vec<u8> {
+    insns.iter().flat_map(|insn| insn.to_le_bytes()).collect()
+}
+
+/// Returns the fixtures, in which `tls_var_address()` returns the address of `TLS_VAR`
+/// and `tls_var_load()` returns its value.
+fn fixtures() -> Vec<Fixture> {
+    Vec::from([
+        // `movq %fs:0, %rax; leaq x@tpoff(%rax), %rax` and `movq %fs:0, %rax; movq x@tpoff(%rax), %rax`
+        Fixture {
+            name: "x86_64 local-exec (TPOFF32)",
+            object: fixture!("x86_64-local-exec"),
+            source: X86_64_OFFSET,
+            expected: Vec::from([
+                ("tls_var_address", [0x64, 0x48, 0x8b, 0x04, 0x25, 0, 0, 0, 0, 0x48, 0x8d, 0x80, 0xf0, 0xff, 0xff, 0xff, 0xc3].to_vec()),
+                ("tls_var_load",    [0x64, 0x48, 0x8b, 0x04, 0x25, 0, 0, 0, 0, 0x48, 0x8b, 0x80, 0xf0, 0xff, 0xff, 0xff, 0xc3].to_vec()),
+            ]),
+        },
+        // `addq x@gottpoff(%rip), %rax` becomes `addq $x@tpoff, %rax`,
+        // and `movq x@gottpoff(%rip), %rax` becomes `movq $x@tpoff, %rax`.
+        Fixture {
+            name: "x86_64 initial-exec (GOTTPOFF)",
+            object: fixture!("x86_64-initial-exec"),
+            source: X86_64_OFFSET,
+            expected: Vec::from([
+                ("tls_var_address", [0x64, 0x48, 0x8b, 0x04, 0x25, 0, 0, 0, 0, 0x48, 0x81, 0xc0, 0xf0, 0xff, 0xff, 0xff, 0xc3].to_vec()),
+                ("tls_var_load",    [0x48, 0xc7, 0xc0, 0xf0, 0xff, 0xff, 0xff, 0x64, 0x48, 0x8b, 0x0c, 0x25, 0, 0, 0, 0, 0x48, 0x8b, 0x04, 0x01, 0xc3].to_vec()),
+            ]),
+        },
+        // `leaq x@tlsld(%rip), %rdi; call __tls_get_addr@PLT` becomes `data16 data16 data16 movq %fs:0, %rax`,
+        // after which the `x@dtpoff(%rax)` operand is used as is.
+        Fixture {
+            name: "x86_64 local-dynamic (TLSLD, DTPOFF32)",
+            object: fixture!("x86_64-local-dynamic"),
+            source: X86_64_OFFSET,
+            expected: Vec::from([
+                ("tls_var_address", [0x50, 0x66, 0x66, 0x66, 0x64, 0x48, 0x8b, 0x04, 0x25, 0, 0, 0, 0, 0x48, 0x8d, 0x80, 0xf0, 0xff, 0xff, 0xff, 0x59, 0xc3].to_vec()),
+                ("tls_var_load",    [0x50, 0x66, 0x66, 0x66, 0x64, 0x48, 0x8b, 0x04, 0x25, 0, 0, 0, 0, 0x48, 0x8b, 0x80, 0xf0, 0xff, 0xff, 0xff, 0x59, 0xc3].to_vec()),
+            ]),
+        },
+        // `data16 leaq x@tlsgd(%rip), %rdi; data16 data16 rex64 call __tls_get_addr@PLT`
+        // becomes `movq %fs:0, %rax; leaq x@tpoff(%rax), %rax`.
+        Fixture {
+            name: "x86_64 general-dynamic (TLSGD)",
+            object: fixture!("x86_64-general-dynamic"),
+            source: X86_64_OFFSET,
+            expected: Vec::from([
+                ("tls_var_address", [0x50, 0x64, 0x48, 0x8b, 0x04, 0x25, 0, 0, 0, 0, 0x48, 0x8d, 0x80, 0xf0, 0xff, 0xff, 0xff, 0x59, 0xc3].to_vec()),
+                ("tls_var_load",    [0x50, 0x64, 0x48, 0x8b, 0x04, 0x25, 0, 0, 0, 0, 0x48, 0x8d, 0x80, 0xf0, 0xff, 0xff, 0xff, 0x48, 0x8b, 0x00, 0x59, 0xc3].to_vec()),
+            ]),
+        },
+        // `.quad x@tpoff + 8; .quad x@dtpoff + 8`
+        Fixture {
+            name: "x86_64 TPOFF64 and DTPOFF64",
+            object: fixture!("x86_64-data"),
+            source: X86_64_OFFSET,
+            expected: Vec::from([
+                ("tls_offsets", [(X86_64_OFFSET as i64 + 8).to_le_bytes(); 2].concat()),
+            ]),
+        },
+        // `mrs x8, tpidr_el0; add x8, x8, #:tprel_hi12:x, lsl #12; add x0, x8, #:tprel_lo12_nc:x`
+        Fixture {
+            name: "aarch64 local-exec add (TLSLE_ADD_TPREL)",
+            object: fixture!("aarch64-local-exec"),
+            source: AARCH64_OFFSET,
+            expected: Vec::from([
+                ("tls_var_address", insns(&[0xd53b_d048, 0x9140_4908, 0x910d_0100, 0xd65f_03c0])),
+                ("tls_var_load",    insns(&[0xd53b_d048, 0x9140_4908, 0x910d_0108, 0xf940_0100, 0xd65f_03c0])),
+            ]),
+        },
+        // `mrs x8, tpidr_el0; add x0, x8, #:tprel_lo12:x`
+        Fixture {
+            name: "aarch64 local-exec add, 4KiB TLS area (TLSLE_ADD_TPREL_LO12)",
+            object: fixture!("aarch64-local-exec-tls-size-12"),
+            source: AARCH64_SMALL_OFFSET,
+            expected: Vec::from([
+                ("tls_var_address", insns(&[0xd53b_d048, 0x9104_8100, 0xd65f_03c0])),
+                ("tls_var_load",    insns(&[0xd53b_d048, 0x9104_8108, 0xf940_0100, 0xd65f_03c0])),
+            ]),
+        },
+        // `movn x8, #:tprel_g1:x; mrs x9, tpidr_el0; movk x8, #:tprel_g0_nc:x`,
+        // in which the `movn` becomes a `movz` for a positive offset.
+        Fixture {
+            name: "aarch64 local-exec movz/movk (TLSLE_MOVW_TPREL)",
+            object: fixture!("aarch64-local-exec-tls-size-32"),
+            source: AARCH64_OFFSET,
+            expected: Vec::from([
+                ("tls_var_address", insns(&[0xd2a0_0028, 0xd53b_d049, 0xf284_6808, 0x8b08_0120, 0xd65f_03c0])),
+                ("tls_var_load",    insns(&[0xd2a0_0028, 0xd53b_d049, 0xf284_6808, 0xf868_6920, 0xd65f_03c0])),
+            ]),
+        },
+        // `adrp x8, :gottprel:x; ldr x8, [x8, :gottprel_lo12:x]`
+        // becomes `movz x8, #:tprel_g1:x, lsl #16; movk x8, #:tprel_g0_nc:x`.
+        Fixture {
+            name: "aarch64 initial-exec (TLSIE_GOTTPREL)",
+            object: fixture!("aarch64-initial-exec"),
+            source: AARCH64_OFFSET,
+            expected: Vec::from([
+                ("tls_var_address", insns(&[0xd2a0_0028, 0xf284_6808, 0xd53b_d049, 0x8b08_0120, 0xd65f_03c0])),
+                ("tls_var_load",    insns(&[0xd2a0_0028, 0xf284_6808, 0xd53b_d049, 0xf868_6920, 0xd65f_03c0])),
+            ]),
+        },
+        // A negative offset must be relaxed into a `movn` instead of a `movz`.
+        Fixture {
+            name: "aarch64 initial-exec, negative offset (TLSIE_GOTTPREL)",
+            object: fixture!("aarch64-initial-exec"),
+            source: X86_64_OFFSET,
+            expected: Vec::from([
+                ("tls_var_address", insns(&[0x92a0_0008, 0xf29f_fe08, 0xd53b_d049, 0x8b08_0120, 0xd65f_03c0])),
+                ("tls_var_load",    insns(&[0x92a0_0008, 0xf29f_fe08, 0xd53b_d049, 0xf868_6920, 0xd65f_03c0])),
+            ]),
+        },
+        // LLVM uses TLS descriptors for both local-dynamic and general-dynamic accesses:
+        // `adrp x0, :tlsdesc:x; ldr x1, [x0, :tlsdesc_lo12:x]; add x0, x0, :tlsdesc_lo12:x; blr x1`
+        // becomes `movz x0, #:tprel_g1:x, lsl #16; movk x0, #:tprel_g0_nc:x; nop; nop`.
+        Fixture {
+            name: "aarch64 local-dynamic (TLSDESC)",
+            object: fixture!("aarch64-local-dynamic"),
+            source: AARCH64_OFFSET,
+            expected: Vec::from([
+                ("tls_var_address", insns(&[0xf81f_0ffe, 0xd2a0_0020, 0xf284_6800, 0xd503_201f, 0xd503_201f, 0xd53b_d048, 0x8b00_0100, 0xf841_07fe, 0xd65f_03c0])),
+                ("tls_var_load",    insns(&[0xf81f_0ffe, 0xd2a0_0020, 0xf284_6800, 0xd503_201f, 0xd503_201f, 0xd53b_d048, 0xf860_6900, 0xf841_07fe, 0xd65f_03c0])),
+            ]),
+        },
+        Fixture {
+            name: "aarch64 general-dynamic (TLSDESC)",
+            object: fixture!("aarch64-general-dynamic"),
+            source: AARCH64_OFFSET,
+            expected: Vec::from([
+                ("tls_var_address", insns(&[0xf81f_0ffe, 0xd2a0_0020, 0xf284_6800, 0xd503_201f, 0xd503_201f, 0xd53b_d048, 0x8b00_0100, 0xf841_07fe, 0xd65f_03c0])),
+                ("tls_var_load",    insns(&[0xf81f_0ffe, 0xd2a0_0020, 0xf284_6800, 0xd503_201f, 0xd503_201f, 0xd53b_d048, 0xf860_6900, 0xf841_07fe, 0xd65f_03c0])),
+            ]),
+        },
+    ])
+}
+
+/// Returns the hand-written test cases, which cover encodings that don't appear in the fixtures.
+fn test_cases() -> Vec<TestCase> {
+    let x86_64_offset = (X86_64_OFFSET as i32).to_le_bytes();
+    let mut cases = Vec::new();
+
+    // x86_64 initial-exec with an extended register:
+    // `addq x@gottpoff(%rip), %r12` becomes `addq $x@tpoff, %r12`.
+    let mut expected = [0x49, 0x81, 0xc4, 0, 0, 0, 0].to_vec();
+    expected[3..].copy_from_slice(&x86_64_offset);
+    cases.push(TestCase {
+        name: "x86_64 initial-exec addq %r12 (GOTTPOFF)",
+        code: [0x4c, 0x03, 0x25, 0, 0, 0, 0].to_vec(),
+        relocations: &[(R_X86_64_GOTTPOFF, -4, 3)],
+        source: X86_64_OFFSET,
+        expected,
+    });
+
+    cases.push(TestCase {
+        name: "x86_64 DTPMOD64",
+        code: [0; 8].to_vec(),
+        relocations: &[(R_X86_64_DTPMOD64, 0, 0)],
+        source: MODULE_ID,
+        expected: (MODULE_ID as u64).to_ne_bytes().to_vec(),
+    });
+
+    // aarch64 local-exec: `add x8, x8, #:tprel_hi12:x, lsl #12; ldr x0, [x8, #:tprel_lo12_nc:x]`
+    // and `add x8, x8, #:tprel_lo12_nc:x`.
+    cases.push(TestCase {
+        name: "aarch64 local-exec add/ldr (TLSLE_ADD_TPREL, TLSLE_LDST64_TPREL)",
+        code: insns(&[0x9140_0108, 0xf940_0100, 0x9100_0108]),
+        relocations: &[
+            (R_AARCH64_TLSLE_ADD_TPREL_HI12, 0, 0),
+            (R_AARCH64_TLSLE_LDST64_TPREL_LO12_NC, 0, 4),
+            (R_AARCH64_TLSLE_ADD_TPREL_LO12_NC, 0, 8),
+        ],
+        source: AARCH64_OFFSET,
+        expected: insns(&[0x9140_4908, 0xf941_a100, 0x910d_0108]),
+    });
+
+    cases.push(TestCase {
+        name: "aarch64 TLS_TPREL64",
+        code: [0; 8].to_vec(),
+        relocations: &[(R_AARCH64_TLS_TPREL64, 8, 0)],
+        source: AARCH64_OFFSET,
+        expected: (AARCH64_OFFSET as i64 + 8).to_ne_bytes().to_vec(),
+    });
+    cases.push(TestCase {
+        name: "aarch64 TLS_DTPMOD64",
+        code: [0; 8].to_vec(),
+        relocations: &[(R_AARCH64_TLS_DTPMOD64, 0, 0)],
+        source: MODULE_ID,
+        expected: (MODULE_ID as u64).to_ne_bytes().to_vec(),
+    });
+
+    cases
+}
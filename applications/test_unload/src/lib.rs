@@ -0,0 +1,81 @@
+//! Tests repeatedly loading and unloading a crate that contains TLS sections,
+//! checking that each unload removes the crate, its symbols, and its TLS sections.
+//!
+//! Usage: `test_unload [CRATE_FILE_PREFIX] [NUM_CYCLES]`
+
+#![no_std]
+
+extern crate alloc;
+
+use alloc::{format, string::String, sync::Arc, vec::Vec};
+use app_io::println;
+use mod_mgmt::{CrateNamespace, TlsAllocHint};
+
+/// A crate that contains both `.tdata` and `.tbss` sections.
+const DEFAULT_CRATE_PREFIX: &str = "test_thread_local-";
+const DEFAULT_NUM_CYCLES: usize = 5;
+
+pub fn main(args: Vec<String>) -> isize {
+    let prefix = args.first().map(String::as_str).unwrap_or(DEFAULT_CRATE_PREFIX);
+    let num_cycles = args.get(1).and_then(|n| n.parse().ok()).unwrap_or(DEFAULT_NUM_CYCLES);
+    match rmain(prefix, num_cycles) {
+        Ok(()) => {
+            println!("test_unload: completed {} load/unload cycles of {:?}", num_cycles, prefix);
+            0
+        }
+        Err(e) => {
+            println!("test_unload: error: {}", e);
+            -1
+        }
+    }
+}
+
+fn rmain(prefix: &str, num_cycles: usize) -> Result<(), String> {
+    let kernel_mmi_ref = memory::get_kernel_mmi_ref().ok_or("couldn't get kernel MMI")?;
+    let kernel_namespace = mod_mgmt::get_initial_kernel_namespace()
+        .ok_or("couldn't get initial kernel namespace")?;
+    let crate_file = kernel_namespace.dir().get_file_starting_with(prefix)
+        .ok_or_else(|| format!("couldn't find a single crate object file starting with {prefix:?}"))?;
+
+    // Load the crate into a separate namespace so that unloading it doesn't affect the kernel.
+    let namespace = CrateNamespace::new(
+        String::from("test_unload"),
+        kernel_namespace.dir().clone(),
+        Some(Arc::clone(kernel_namespace)),
+    );
+
+    for cycle in 0..num_cycles {
+        let tls_size_before = namespace.get_tls_initializer_data(TlsAllocHint::Any).size();
+
+        let (crate_ref, num_symbols) = namespace.load_crate(&crate_file, None, kernel_mmi_ref, false)?;
+        let crate_name = crate_ref.lock_as_ref().crate_name.clone();
+        let symbol_prefix = crate_ref.lock_as_ref().crate_name_as_prefix();
+        // Drop our reference such that the crate isn't considered to be in use.
+        drop(crate_ref);
+
+        let tls_size_loaded = namespace.get_tls_initializer_data(TlsAllocHint::Any).size();
+        if tls_size_loaded <= tls_size_before {
+            return Err(format!("cycle {cycle}: loading {crate_name} didn't add any TLS sections"));
+        }
+        if namespace.symbol_map().lock().iter_prefix(symbol_prefix.as_bytes()).count() == 0 {
+            return Err(format!("cycle {cycle}: loading {crate_name} didn't add any of its {num_symbols} symbols"));
+        }
+
+        namespace.unload_crate(&crate_name, false)?;
+
+        if namespace.crate_tree().lock().get(crate_name.as_bytes()).is_some() {
+            return Err(format!("cycle {cycle}: {crate_name} was still in the namespace after unloading it"));
+        }
+        if namespace.symbol_map().lock().iter_prefix(symbol_prefix.as_bytes()).count() != 0 {
+            return Err(format!("cycle {cycle}: {crate_name}'s symbols remained after unloading it"));
+        }
+        let tls_size_after = namespace.get_tls_initializer_data(TlsAllocHint::Any).size();
+        if tls_size_after != tls_size_before {
+            return Err(format!(
+                "cycle {cycle}: TLS area size was {tls_size_before} before loading {crate_name}, but {tls_size_after} after unloading it"
+            ));
+        }
+        println!("cycle {}: loaded and unloaded {} ({} TLS bytes)", cycle, crate_name, tls_size_loaded - tls_size_before);
+    }
+    Ok(())
+}
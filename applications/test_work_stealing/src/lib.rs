@@ -0,0 +1,95 @@
+//! Tests that idle CPUs steal tasks from the runqueue of a CPU with a burst of newly-spawned tasks.
+//!
+//! This spawns a burst of busy tasks that all start on the current CPU's runqueue,
+//! and then checks that some of them ran on other CPUs and that stolen tasks were counted.
+
+#![no_std]
+
+extern crate alloc;
+
+use alloc::{string::String, vec::Vec};
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use app_io::println;
+use cpu::CpuSet;
+use cpu_stats::Stat;
+
+/// The number of tasks spawned in the burst.
+const NUM_TASKS: usize = 16;
+/// The maximum number of times the test yields while waiting for tasks to be stolen.
+const MAX_YIELDS: usize = 1_000_000;
+
+/// A bitmap of the CPUs that the burst tasks have run on, modulo 64.
+static CPUS_USED: AtomicU64 = AtomicU64::new(0);
+/// Tells the burst tasks to exit.
+static STOP: AtomicBool = AtomicBool::new(false);
+
+pub fn main(_args: Vec<String>) -> isize {
+    match run() {
+        Ok(()) => {
+            println!("test_work_stealing: all tests passed.");
+            0
+        }
+        Err(e) => {
+            println!("test_work_stealing: test failed: {}", e);
+            -1
+        }
+    }
+}
+
+fn run() -> Result<(), &'static str> {
+    if cpu::cpu_count() < 2 {
+        println!("test_work_stealing: skipped, as stealing requires at least two CPUs.");
+        return Ok(());
+    }
+    if !scheduler::is_work_stealing_enabled() {
+        return Err("work stealing is disabled");
+    }
+    let current = cpu::current_cpu();
+    let stolen_before = cpu_stats::aggregate().total(Stat::TasksStolen);
+
+    // Spawn the whole burst onto the current CPU's runqueue, and only then allow the tasks to run anywhere,
+    // such that other CPUs can only get them by stealing.
+    let mut tasks = Vec::with_capacity(NUM_TASKS);
+    for i in 0..NUM_TASKS {
+        let task = spawn::new_task_builder(spin, ())
+            .name(alloc::format!("test_work_stealing_{}", i))
+            .pin_to(current)
+            .block()
+            .spawn()?;
+        task.set_affinity(CpuSet::all());
+        tasks.push(task);
+    }
+    for task in &tasks {
+        task.unblock().map_err(|_| "couldn't unblock burst task")?;
+    }
+
+    let mut spread = false;
+    for _ in 0..MAX_YIELDS {
+        if CPUS_USED.load(Ordering::Acquire) & !(1 << (current % 64)) != 0 {
+            spread = true;
+            break;
+        }
+        scheduler::schedule();
+    }
+    STOP.store(true, Ordering::Release);
+    for task in &tasks {
+        task.join()?;
+    }
+
+    let stolen = cpu_stats::aggregate().total(Stat::TasksStolen) - stolen_before;
+    println!("test_work_stealing: {} tasks were stolen, CPUs used: {:#X}.", stolen, CPUS_USED.load(Ordering::Relaxed));
+    if !spread {
+        return Err("no burst task ran on another CPU");
+    }
+    if stolen == 0 {
+        return Err("no stolen tasks were counted");
+    }
+    Ok(())
+}
+
+fn spin(_: ()) {
+    while !STOP.load(Ordering::Acquire) {
+        CPUS_USED.fetch_or(1 << (cpu::current_cpu() % 64), Ordering::AcqRel);
+        core::hint::spin_loop();
+    }
+}
@@ -0,0 +1,114 @@
+//! Prints the current TLS layout, each task's TLS area, and the memory occupied by TLS data images.
+//!
+//! See [`mod_mgmt::TlsLayoutInfo`] and [`mod_mgmt::tls_image_usage()`].
+
+#![no_std]
+
+extern crate alloc;
+#[macro_use] extern crate app_io;
+
+use alloc::{string::String, vec::Vec};
+use getopts::Options;
+use mod_mgmt::TlsLayoutInfo;
+use task::TASKLIST;
+
+pub fn main(args: Vec<String>) -> isize {
+    let mut opts = Options::new();
+    opts.optflag("h", "help", "print this help menu");
+    opts.optflag("s", "sections", "only print the TLS layout and its sections");
+    opts.optflag("t", "tasks", "only print each task's TLS area");
+    opts.optflag("u", "usage", "only print the memory occupied by TLS data images");
+    opts.optopt("c", "crate", "only print the TLS sections of crates whose names start with PREFIX", "PREFIX");
+
+    let matches = match opts.parse(&args) {
+        Ok(m) => m,
+        Err(e) => {
+            println!("{}", e);
+            print_usage(opts);
+            return -1;
+        }
+    };
+
+    if matches.opt_present("h") {
+        print_usage(opts);
+        return 0;
+    }
+
+    let Ok(namespace) = task::with_current_task(|t| t.get_namespace().clone()) else {
+        println!("Error: couldn't get the current task");
+        return -1;
+    };
+    let layout = namespace.tls_layout_info();
+
+    let all = !(matches.opt_present("s") || matches.opt_present("t") || matches.opt_present("u"));
+    if all || matches.opt_present("s") {
+        print_layout(&layout, namespace.tls_layout_hash(), matches.opt_str("c").as_deref());
+    }
+    if all || matches.opt_present("t") {
+        if all { println!(); }
+        print_tasks(layout.generation);
+    }
+    if all || matches.opt_present("u") {
+        if all { println!(); }
+        print_usage_stats();
+    }
+    0
+}
+
+fn print_layout(layout: &TlsLayoutInfo, hash: u64, crate_prefix: Option<&str>) {
+    println!("TLS layout generation {}, hash {:#018X}", layout.generation, hash);
+    println!("TCB layout: {:?}, deterministic: {}", layout.tcb_layout, layout.deterministic);
+    println!("Image size: {} bytes, self pointer at offset {:#X}", layout.image_size, layout.self_ptr_offset);
+    println!();
+    println!("{0:>8}  {1:>8}  {2:<6}  {3:<7}  {4:<24}  {5}", "OFFSET", "SIZE", "TYPE", "KIND", "CRATE", "SECTION");
+    for description in &layout.sections {
+        let crate_name = description.crate_name().unwrap_or_else(|| String::from("-"));
+        if crate_prefix.map_or(false, |prefix| !crate_name.starts_with(prefix)) {
+            continue;
+        }
+        // Print negative offsets with an explicit sign, since hex formatting ignores it.
+        let offset = if description.offset < 0 {
+            alloc::format!("-{:#X}", description.offset.unsigned_abs())
+        } else {
+            alloc::format!("{:#X}", description.offset)
+        };
+        println!("{0:>8}  {1:>8}  {2:<6}  {3:<7}  {4:<24}  {5}",
+            offset,
+            description.section.size,
+            if description.is_bss() { ".tbss" } else { ".tdata" },
+            if description.is_static() { "static" } else { "dynamic" },
+            crate_name,
+            description.section.name,
+        );
+    }
+}
+
+fn print_tasks(current_generation: u64) {
+    // Gather each task's details first, to avoid holding the task list lock while printing.
+    let tasks: Vec<_> = TASKLIST.lock().iter()
+        .map(|(id, task)| (*id, task.tls_area_base(), task.tls_area_size(), task.tls_area_generation(), task.name.clone()))
+        .collect();
+    println!("{0:<5}  {1:<18}  {2:>8}  {3:>10}  {4}", "ID", "TLS BASE", "SIZE", "GENERATION", "NAME");
+    for (id, base, size, generation, name) in tasks {
+        let stale = if generation < current_generation { "*" } else { " " };
+        println!("{0:<5}  {1:<#18X}  {2:>8}  {3:>9}{4}  {5}", id, base, size, generation, stale, name);
+    }
+    println!("(* = generated from an older TLS layout)");
+}
+
+fn print_usage_stats() {
+    let usage = mod_mgmt::tls_image_usage();
+    println!("Live TLS data images: {}", usage.images);
+    println!("Total size:           {} bytes", usage.bytes);
+    if usage.images > 0 {
+        println!("Average size:         {} bytes", usage.bytes / usage.images);
+    }
+}
+
+fn print_usage(opts: Options) {
+    println!("{}", opts.usage(USAGE));
+}
+
+const USAGE: &str = "Usage: tlsinfo [OPTION]
+Prints the current TLS layout and its sections, each task's TLS area,
+and the memory occupied by TLS data images. By default, all of these are printed.";
@@ -0,0 +1,177 @@
+//! Shows system-wide statistics, such as the number of interrupts and context switches,
+//! aggregated from every CPU's per-CPU statistics block.
+//!
+//! In live mode, it instead periodically shows each CPU's load and each task's
+//! CPU usage, memory usage, and state, measured over the preceding refresh interval.
+
+#![no_std]
+
+extern crate alloc;
+#[macro_use] extern crate app_io;
+
+use alloc::{collections::BTreeMap, format, string::String, vec::Vec};
+use core::time::Duration;
+use getopts::Options;
+use memory_accounting::TaskMemoryUsage;
+
+/// The default interval between refreshes in live mode.
+const DEFAULT_DELAY: Duration = Duration::from_secs(2);
+
+pub fn main(args: Vec<String>) -> isize {
+    let mut opts = Options::new();
+    opts.optflag("h", "help", "print this help menu");
+    opts.optflag("t", "tasks", "also print the profiling counters summed across all tasks");
+    opts.optflag("l", "live", "periodically show per-CPU load and per-task CPU usage, memory, and state");
+    opts.optopt("d", "delay", "with live, the number of seconds between refreshes (default: 2)", "SECONDS");
+    opts.optopt("n", "iterations", "with live, stop after the given number of refreshes (default: never)", "N");
+
+    let matches = match opts.parse(&args) {
+        Ok(m) => m,
+        Err(e) => {
+            println!("{}", e);
+            print_usage(opts);
+            return -1;
+        }
+    };
+
+    if matches.opt_present("h") {
+        print_usage(opts);
+        return 0;
+    }
+
+    if matches.opt_present("l") {
+        let delay = matches.opt_str("d").map(|d| d.parse::<u64>().map(Duration::from_secs));
+        let iterations = matches.opt_str("n").map(|n| n.parse::<usize>());
+        return match (delay.transpose(), iterations.transpose()) {
+            (Ok(Some(delay)), _) if delay.is_zero() => {
+                println!("Error: the delay must be at least one second");
+                -1
+            }
+            (Ok(delay), Ok(iterations)) => live(delay.unwrap_or(DEFAULT_DELAY), iterations),
+            _ => {
+                println!("Error: invalid SECONDS or N");
+                -1
+            }
+        };
+    }
+
+    let report = cpu_stats::aggregate();
+    if report.per_cpu.is_empty() {
+        println!("No CPU has a per-CPU statistics block.");
+        return -1;
+    }
+    print!("{}", report);
+
+    if matches.opt_present("t") {
+        println!();
+        print!("{}", task::aggregate_tls_counters());
+    }
+    0
+}
+
+/// The state of a task at one point in time.
+struct TaskSample {
+    cpu_time: Duration,
+    runstate: String,
+    cpu: Option<u8>,
+    idle_on: Option<u8>,
+    memory: TaskMemoryUsage,
+}
+
+/// Returns a sample of every task, keyed by task ID.
+fn sample_tasks() -> BTreeMap<usize, TaskSample> {
+    // Collect the tasks first, such that the task list isn't locked while inspecting each task.
+    let tasks: Vec<task::TaskRef> = task::TASKLIST.lock().values().cloned().collect();
+    tasks.iter().map(|task| {
+        let sample = TaskSample {
+            cpu_time: task.cpu_time(),
+            runstate: format!("{:?}", task.runstate()),
+            cpu: task.running_on_cpu(),
+            idle_on: task.is_an_idle_task.then(|| task.pinned_core()).flatten(),
+            memory: memory_accounting::task_usage(task),
+        };
+        (task.id, sample)
+    }).collect()
+}
+
+/// Repeatedly prints per-CPU load and per-task usage, measured over each `delay`,
+/// until the given number of `iterations` has been printed, if any.
+fn live(delay: Duration, iterations: Option<usize>) -> isize {
+    let mut previous = sample_tasks();
+    let mut previous_time = time::now::<time::Monotonic>();
+    let mut count = 0;
+    while iterations.map_or(true, |iterations| count < iterations) {
+        if sleep::sleep(delay).is_err() {
+            println!("Error: couldn't sleep");
+            return -1;
+        }
+        let current = sample_tasks();
+        let current_time = time::now::<time::Monotonic>();
+        let elapsed = current_time.duration_since(previous_time);
+        print_live(&previous, &current, elapsed);
+        previous = current;
+        previous_time = current_time;
+        count += 1;
+    }
+    0
+}
+
+/// Returns the given `time` as a percentage of `elapsed`.
+fn percent(time: Duration, elapsed: Duration) -> f64 {
+    if elapsed.is_zero() {
+        0.0
+    } else {
+        100.0 * time.as_secs_f64() / elapsed.as_secs_f64()
+    }
+}
+
+fn print_live(previous: &BTreeMap<usize, TaskSample>, current: &BTreeMap<usize, TaskSample>, elapsed: Duration) {
+    // The CPU time that each task used during this interval; new tasks are charged for all of their CPU time.
+    let usage = |id: &usize, sample: &TaskSample| {
+        let before = previous.get(id).map_or(Duration::ZERO, |prev| prev.cpu_time);
+        sample.cpu_time.saturating_sub(before)
+    };
+
+    println!("\n---------- {} tasks, interval {} ms ----------", current.len(), elapsed.as_millis());
+
+    // Each CPU is busy whenever its idle task isn't running.
+    println!("{0:<5}  {1:>6}", "CPU", "LOAD");
+    for (id, sample) in current {
+        if let Some(cpu) = sample.idle_on {
+            let load = 100.0 - percent(usage(id, sample), elapsed);
+            println!("{0:<5}  {1:>5.1}%", cpu, load.clamp(0.0, 100.0));
+        }
+    }
+    println!();
+
+    let mut rows: Vec<(&usize, &TaskSample, Duration)> = current.iter()
+        .map(|(id, sample)| (id, sample, usage(id, sample)))
+        .collect();
+    rows.sort_by(|a, b| b.2.cmp(&a.2).then(a.0.cmp(b.0)));
+
+    println!("{0:<5}  {1:<10}  {2:<4}  {3:>6}  {4:>10}  {5:>10}  {6:>8}  {7:>10}  {8}",
+        "ID", "RUNSTATE", "CPU", "%CPU", "STACK", "HEAP", "TLS", "TIME(ms)", "NAME");
+    for (id, sample, cpu_usage) in rows {
+        let cpu = sample.cpu.map(|cpu| format!("{cpu}")).unwrap_or_else(|| String::from("-"));
+        println!("{0:<5}  {1:<10}  {2:<4}  {3:>5.1}%  {4:>10}  {5:>10}  {6:>8}  {7:>10}  {8}",
+            id,
+            sample.runstate,
+            cpu,
+            percent(cpu_usage, elapsed),
+            sample.memory.kstack_bytes,
+            sample.memory.heap_bytes_live(),
+            sample.memory.tls_bytes,
+            sample.cpu_time.as_millis(),
+            sample.memory.name,
+        );
+    }
+}
+
+fn print_usage(opts: Options) {
+    println!("{}", opts.usage(USAGE));
+}
+
+const USAGE: &str = "Usage: top [OPTION]
+Shows the statistics counters of each CPU and their totals across all CPUs.
+With --live, periodically shows each CPU's load and each task's CPU usage, memory usage in bytes,
+and state, sorted by CPU usage over the preceding interval.";
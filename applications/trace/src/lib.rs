@@ -0,0 +1,152 @@
+//! Enables and disables static tracepoints and dumps the events they recorded.
+//!
+//! See the `tracepoint` crate.
+
+#![no_std]
+
+extern crate alloc;
+#[macro_use] extern crate app_io;
+
+use alloc::{string::String, vec::Vec};
+use getopts::Options;
+use tracepoint::{Event, Tracepoint};
+
+pub fn main(args: Vec<String>) -> isize {
+    let mut opts = Options::new();
+    opts.optflag("h", "help", "print this help menu");
+    opts.optopt("c", "cpu", "with dump, only show events recorded on the given CPU", "CPU");
+    opts.optopt("n", "last", "with dump, only show the last N events", "N");
+
+    let matches = match opts.parse(&args) {
+        Ok(m) => m,
+        Err(e) => {
+            println!("{}", e);
+            print_usage(opts);
+            return -1;
+        }
+    };
+
+    if matches.opt_present("h") {
+        print_usage(opts);
+        return 0;
+    }
+
+    let free: Vec<&str> = matches.free.iter().map(String::as_str).collect();
+    let result = match free.as_slice() {
+        [] => {
+            list();
+            Ok(())
+        }
+        ["enable", points @ ..] => parse_points(points).map(|points| set_enabled(&points, true)),
+        ["disable", points @ ..] => parse_points(points).map(|points| set_enabled(&points, false)),
+        ["dump"] => match (parse_opt(matches.opt_str("c")), parse_opt(matches.opt_str("n"))) {
+            (Ok(cpu), Ok(last)) => {
+                dump(cpu, last);
+                Ok(())
+            }
+            _ => Err("invalid CPU or N"),
+        },
+        ["clear"] => {
+            tracepoint::clear();
+            println!("Cleared all recorded events.");
+            Ok(())
+        }
+        _ => Err("unknown command"),
+    };
+
+    match result {
+        Ok(()) => 0,
+        Err(e) => {
+            println!("Error: {}", e);
+            -1
+        }
+    }
+}
+
+fn list() {
+    let events = tracepoint::all_events();
+    println!("{0:<10}  {1:<8}  {2:>8}", "POINT", "STATE", "EVENTS");
+    for point in Tracepoint::ALL {
+        let state = if tracepoint::is_enabled(point) { "enabled" } else { "disabled" };
+        let count = events.iter().filter(|event| event.point == point).count();
+        println!("{0:<10}  {1:<8}  {2:>8}", point, state, count);
+    }
+}
+
+fn parse_points(args: &[&str]) -> Result<Vec<Tracepoint>, &'static str> {
+    match args {
+        [] => Err("no tracepoints were given"),
+        ["all"] => Ok(Tracepoint::ALL.to_vec()),
+        points => points.iter().map(|point| point.parse()).collect(),
+    }
+}
+
+fn set_enabled(points: &[Tracepoint], enabled: bool) {
+    for &point in points {
+        tracepoint::set_enabled(point, enabled);
+        println!("{} tracepoint {}.", if enabled { "Enabled" } else { "Disabled" }, point);
+    }
+}
+
+fn parse_opt<T: core::str::FromStr>(opt: Option<String>) -> Result<Option<T>, ()> {
+    opt.map(|s| s.parse().map_err(|_| ())).transpose()
+}
+
+/// Prints the recorded events, optionally only those of the given `cpu` and only the `last` N of them.
+///
+/// Timestamps are printed in TSC ticks relative to the first printed event.
+fn dump(cpu: Option<u8>, last: Option<usize>) {
+    let mut events: Vec<Event> = match cpu {
+        Some(cpu) => tracepoint::events(cpu),
+        None => tracepoint::all_events(),
+    };
+    if let Some(last) = last {
+        events.drain(.. events.len().saturating_sub(last));
+    }
+    let Some(first) = events.first() else {
+        println!("No events have been recorded.");
+        return;
+    };
+    let base = first.timestamp;
+    println!("{0:>16}  {1:>3}  {2:<10}  ARGUMENTS", "TICKS", "CPU", "POINT");
+    for event in &events {
+        let [name0, name1] = event.point.arg_names();
+        println!("{0:>16}  {1:>3}  {2:<10}  {3}={4:#X} {5}={6:#X}",
+            event.timestamp.wrapping_sub(base),
+            event.cpu,
+            event.point,
+            name0, event.args[0],
+            name1, event.args[1],
+        );
+    }
+
+    let cpus: Vec<u8> = match cpu {
+        Some(cpu) => Vec::from([cpu]),
+        None => (0 ..= u8::MAX).collect(),
+    };
+    let overwritten: u64 = cpus.into_iter().map(tracepoint::overwritten).sum();
+    if overwritten > 0 {
+        println!("({} older events were overwritten)", overwritten);
+    }
+}
+
+fn print_usage(opts: Options) {
+    println!("{}", opts.usage(USAGE));
+}
+
+const USAGE: &str = "Usage: trace [OPTION] [COMMAND]
+Enables and disables static tracepoints and dumps the events they recorded into per-CPU ring buffers.
+
+Commands:
+    (none)                list each tracepoint with its state and the number of recorded events.
+    enable POINT...       start recording events at the given tracepoints, or `all` of them.
+    disable POINT...      stop recording events at the given tracepoints, or `all` of them.
+    dump                  print the recorded events of all CPUs, ordered by time.
+    clear                 discard all recorded events.
+
+Tracepoints:
+    spawn       a task was spawned; records its ID and how many ticks spawning it took.
+    switch      a context switch; records the IDs of the previous and next tasks.
+    tls         a TLS data image was re-generated; records its generation and size.
+    pagefault   a page fault; records the accessed address and the error code.
+    crate       a crate was loaded; records its number of sections and how many ticks loading it took.";
@@ -59,6 +59,7 @@
 //! * `spawn`: Functions and wrappers for spawning new Tasks.
 //! * `task`: Task types and structure definitions, a Task is a thread of execution.
 //! * `time`: Abstractions to interact with hardware clocks. 
+//! * `tmpfs`: an in-memory filesystem with a size limit per instance, mounted at `/tmp` by default.
 //! * `tsc`: TSC (TimeStamp Counter) support for performance counters on x86. Basically a wrapper around rdtsc.
 //! * `tss`: TSS (Task State Segment support (x86 only) for Theseus.
 //! * `vfs_node`: contains the structs VFSDirectory and VFSFile, which are the most basic, generic implementers of the traits Directory and File
@@ -35,9 +35,12 @@ pub fn acpi_table_handler(
         rsdt::RSDT_SIGNATURE |
         rsdt::XSDT_SIGNATURE => rsdt::handle(acpi_tables, signature, length, phys_addr),
         fadt::FADT_SIGNATURE => fadt::handle(acpi_tables, signature, length, phys_addr),
+        dsdt::DSDT_SIGNATURE => dsdt::handle(acpi_tables, signature, length, phys_addr),
         hpet::HPET_SIGNATURE => hpet::handle(acpi_tables, signature, length, phys_addr),
         madt::MADT_SIGNATURE => madt::handle(acpi_tables, signature, length, phys_addr),
         dmar::DMAR_SIGNATURE => dmar::handle(acpi_tables, signature, length, phys_addr),
+        mcfg::MCFG_SIGNATURE => mcfg::handle(acpi_tables, signature, length, phys_addr),
+        srat::SRAT_SIGNATURE => srat::handle(acpi_tables, signature, length, phys_addr),
         _ => {
             warn!("Skipping unsupported ACPI table {:?}", core::str::from_utf8(&signature).unwrap_or("Unknown Signature"));
             Ok(())
@@ -0,0 +1,91 @@
+//! Definitions for the DSDT, the Differentiated System Description Table.
+//!
+//! The DSDT consists of AML bytecode that describes the platform.
+//! Theseus doesn't include an AML interpreter, so this crate only scans the bytecode
+//! for the few simple objects needed for power management, e.g., the `\_S5` sleep type.
+
+#![no_std]
+
+use core::mem::size_of;
+use memory::PhysicalAddress;
+use sdt::Sdt;
+use acpi_table::{AcpiSignature, AcpiTables};
+
+
+pub const DSDT_SIGNATURE: &[u8; 4] = b"DSDT";
+
+/// The AML opcode that defines a named object.
+const NAME_OP: u8 = 0x08;
+/// The AML opcode that begins a package.
+const PACKAGE_OP: u8 = 0x12;
+/// The AML prefix of a byte constant.
+const BYTE_PREFIX: u8 = 0x0A;
+/// The AML prefix of a name that begins at the root of the namespace.
+const ROOT_CHAR: u8 = b'\\';
+
+
+/// The handler for parsing the DSDT table and adding it to the ACPI tables list.
+pub fn handle(
+    acpi_tables: &mut AcpiTables,
+    signature: AcpiSignature,
+    length: usize,
+    phys_addr: PhysicalAddress
+) -> Result<(), &'static str> {
+    let aml_length = length.checked_sub(size_of::<Sdt>()).ok_or("DSDT is shorter than its header")?;
+    let slice_start_paddr = phys_addr + size_of::<Sdt>();
+    acpi_tables.add_table_location(signature, phys_addr, Some((slice_start_paddr, aml_length)))
+}
+
+
+/// The values to write to the `SLP_TYP` fields of the PM1a and PM1b control registers
+/// to enter a given sleep state.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SleepType {
+    pub slp_typ_a: u8,
+    pub slp_typ_b: u8,
+}
+
+/// A wrapper around the DSDT, which gives access to its AML bytecode.
+pub struct Dsdt<'t> {
+    aml: &'t [u8],
+}
+
+impl<'t> Dsdt<'t> {
+    /// Finds the DSDT in the given `AcpiTables` and returns a wrapper around it.
+    pub fn get(acpi_tables: &'t AcpiTables) -> Option<Dsdt<'t>> {
+        Some(Dsdt { aml: acpi_tables.table_slice(DSDT_SIGNATURE).ok()? })
+    }
+
+    /// Returns the AML bytecode of the DSDT.
+    pub fn aml(&self) -> &'t [u8] {
+        self.aml
+    }
+
+    /// Returns the sleep type of the given sleep `state`, e.g., `5` for `\_S5` (soft off),
+    /// if the DSDT defines it as a package of constants.
+    pub fn sleep_type(&self, state: u8) -> Option<SleepType> {
+        if state > 5 {
+            return None;
+        }
+        let name = [b'_', b'S', b'0' + state, b'_'];
+        let aml = self.aml;
+        let start = aml.windows(name.len()).enumerate().find_map(|(i, window)| {
+            let is_definition = window == name
+                && ((i >= 1 && aml[i - 1] == NAME_OP) || (i >= 2 && aml[i - 2] == NAME_OP && aml[i - 1] == ROOT_CHAR))
+                && aml.get(i + name.len()) == Some(&PACKAGE_OP);
+            is_definition.then_some(i + name.len() + 1)
+        })?;
+
+        // The package length is encoded in one to four bytes,
+        // where the top two bits of the first byte give the number of following bytes.
+        let package_length_bytes = 1 + (*aml.get(start)? >> 6) as usize;
+        // Skip the package length and the number of elements.
+        let mut elements = aml.get(start + package_length_bytes + 1 ..)?.iter();
+        let mut next_constant = || match *elements.next()? {
+            BYTE_PREFIX => elements.next().copied(),
+            // `ZeroOp` and `OneOp` are encoded as the values themselves.
+            value => Some(value),
+        };
+        Some(SleepType { slp_typ_a: next_constant()?, slp_typ_b: next_constant()? })
+    }
+}
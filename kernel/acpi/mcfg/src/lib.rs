@@ -0,0 +1,98 @@
+//! Definitions for the MCFG, the PCI Express Memory-mapped Configuration table.
+//!
+//! The MCFG lists the regions of physical memory through which the configuration space
+//! of each PCI segment group can be accessed, known as the Enhanced Configuration Access Mechanism (ECAM).
+//! See section 4.1.2 of the PCI Firmware specification 3.0.
+
+#![no_std]
+
+use core::mem::size_of;
+use memory::PhysicalAddress;
+use sdt::Sdt;
+use acpi_table::{AcpiSignature, AcpiTables};
+use zerocopy::FromBytes;
+
+
+pub const MCFG_SIGNATURE: &[u8; 4] = b"MCFG";
+
+
+/// The handler for parsing the MCFG table and adding it to the ACPI tables list.
+pub fn handle(
+    acpi_tables: &mut AcpiTables,
+    signature: AcpiSignature,
+    length: usize,
+    phys_addr: PhysicalAddress
+) -> Result<(), &'static str> {
+    // The MCFG is followed by an array of entries, one per contiguous range of buses.
+    let num_entries = length.checked_sub(size_of::<McfgHeader>()).ok_or("MCFG is shorter than its header")?
+        / size_of::<McfgEntry>();
+    let slice_start_paddr = phys_addr + size_of::<McfgHeader>();
+    acpi_tables.add_table_location(signature, phys_addr, Some((slice_start_paddr, num_entries)))
+}
+
+
+/// The fixed-size part of the MCFG table.
+#[derive(Clone, Copy, Debug, FromBytes)]
+#[repr(C, packed)]
+struct McfgHeader {
+    header: Sdt,
+    _reserved: u64,
+}
+const _: () = assert!(core::mem::size_of::<McfgHeader>() == 44);
+const _: () = assert!(core::mem::align_of::<McfgHeader>() == 1);
+
+
+/// An entry in the MCFG, which describes the memory-mapped configuration space
+/// of a range of buses in a single PCI segment group.
+#[derive(Clone, Copy, Debug, FromBytes)]
+#[repr(C, packed)]
+pub struct McfgEntry {
+    /// The physical address of the configuration space of bus 0 in this segment group,
+    /// even if `start_bus` is greater than 0.
+    base_address: u64,
+    segment_group: u16,
+    start_bus: u8,
+    end_bus: u8,
+    _reserved: u32,
+}
+const _: () = assert!(core::mem::size_of::<McfgEntry>() == 16);
+const _: () = assert!(core::mem::align_of::<McfgEntry>() == 1);
+
+impl McfgEntry {
+    /// Returns the physical address of the configuration space of bus 0 in this entry's segment group.
+    pub fn base_address(&self) -> u64 {
+        self.base_address
+    }
+
+    pub fn segment_group(&self) -> u16 {
+        self.segment_group
+    }
+
+    /// Returns the first bus whose configuration space is described by this entry.
+    pub fn start_bus(&self) -> u8 {
+        self.start_bus
+    }
+
+    /// Returns the last bus (inclusive) whose configuration space is described by this entry.
+    pub fn end_bus(&self) -> u8 {
+        self.end_bus
+    }
+}
+
+
+/// A wrapper around the MCFG ACPI table, which gives access to its entries.
+pub struct Mcfg<'t> {
+    entries: &'t [McfgEntry],
+}
+
+impl<'t> Mcfg<'t> {
+    /// Finds the MCFG in the given `AcpiTables` and returns a wrapper around it.
+    pub fn get(acpi_tables: &'t AcpiTables) -> Option<Mcfg<'t>> {
+        Some(Mcfg { entries: acpi_tables.table_slice(MCFG_SIGNATURE).ok()? })
+    }
+
+    /// Returns an iterator over the entries in the MCFG.
+    pub fn iter(&self) -> impl Iterator<Item = &'t McfgEntry> {
+        self.entries.iter()
+    }
+}
@@ -57,11 +57,27 @@ pub fn init(rsdp_address: Option<PhysicalAddress>, page_table: &mut PageTable) -
     }
 
     // FADT is mandatory, and contains the address of the DSDT
-    {
+    let dsdt_phys_addr = {
         let acpi_tables = ACPI_TABLES.lock();
-        let _fadt = fadt::Fadt::get(&acpi_tables).ok_or("The required FADT APIC table wasn't found (signature 'FACP')")?;
-        // here: do something with the DSDT here, when needed.
-        // debug!("DSDT physical address: {:#X}", {_fadt.dsdt});
+        let fadt = fadt::Fadt::get(&acpi_tables).ok_or("The required FADT APIC table wasn't found (signature 'FACP')")?;
+        // The 64-bit address takes precedence if it is present.
+        let dsdt = if fadt.x_dsdt != 0 { fadt.x_dsdt as usize } else { fadt.dsdt as usize };
+        PhysicalAddress::new(dsdt).filter(|paddr| paddr.value() != 0)
+    };
+
+    // The DSDT describes how to put the machine into sleep states, e.g., to shut it down.
+    if let Some(dsdt_phys_addr) = dsdt_phys_addr {
+        debug!("DSDT physical address: {:#X}", dsdt_phys_addr);
+        let mut acpi_tables = ACPI_TABLES.lock();
+        let result = acpi_tables.map_new_table(dsdt_phys_addr, page_table)
+            .and_then(|(sdt_signature, sdt_total_length)|
+                acpi_table_handler(&mut acpi_tables, sdt_signature, sdt_total_length, dsdt_phys_addr)
+            );
+        if let Err(e) = result {
+            warn!("Couldn't map the DSDT, so ACPI shutdown won't be possible: {}", e);
+        }
+    } else {
+        warn!("The FADT has no DSDT address, so ACPI shutdown won't be possible.");
     }
     
     // HPET is optional, but usually present.
@@ -83,6 +99,59 @@ pub fn init(rsdp_address: Option<PhysicalAddress>, page_table: &mut PageTable) -
         madt.bsp_init(page_table)?;
     }
 
+    // MCFG is optional; without it, the PCI config space is accessed via legacy I/O ports.
+    {
+        let acpi_tables = ACPI_TABLES.lock();
+        if let Some(mcfg) = mcfg::Mcfg::get(&acpi_tables) {
+            for entry in mcfg.iter() {
+                debug!("Found MCFG entry: segment_group: {}, buses: {}..={}, base_address: {:#X}",
+                    entry.segment_group(), entry.start_bus(), entry.end_bus(), entry.base_address(),
+                );
+                // Theseus only supports the first PCI segment group.
+                if entry.segment_group() != 0 {
+                    continue;
+                }
+                let base_address = PhysicalAddress::new(entry.base_address() as usize)
+                    .ok_or("MCFG base_address was invalid")?;
+                if let Err(e) = pci::init_ecam(base_address, entry.start_bus(), entry.end_bus(), page_table) {
+                    warn!("Couldn't map the PCI config space, falling back to I/O ports: {}", e);
+                }
+                break;
+            }
+        } else {
+            warn!("This machine has no MCFG, so the PCI extended config space is inaccessible.");
+        }
+    }
+
+    // SRAT is optional; without it, the NUMA topology is unknown.
+    {
+        let acpi_tables = ACPI_TABLES.lock();
+        if let Some(srat) = srat::Srat::get(&acpi_tables) {
+            let cpus = srat.iter().filter_map(|entry| match entry {
+                srat::SratEntry::LocalApicAffinity(cpu) if cpu.enabled() => {
+                    Some((cpu.apic_id, cpu.proximity_domain() as usize))
+                }
+                srat::SratEntry::X2ApicAffinity(cpu) if cpu.enabled() => {
+                    u8::try_from(cpu.x2apic_id).ok().map(|apic_id| (apic_id, cpu.proximity_domain() as usize))
+                }
+                _ => None,
+            });
+            let memory_ranges = srat.iter().filter_map(|entry| match entry {
+                srat::SratEntry::MemoryAffinity(mem) if mem.enabled() => Some((
+                    mem.proximity_domain() as usize,
+                    PhysicalAddress::new(mem.base_address() as usize)?,
+                    mem.length() as usize,
+                )),
+                _ => None,
+            });
+            if let Err(e) = numa::init(cpus, memory_ranges) {
+                warn!("Couldn't initialize the NUMA topology: {}", e);
+            }
+        } else {
+            debug!("This machine has no SRAT, so its NUMA topology is unknown.");
+        }
+    }
+
     // If we have a DMAR table, use it to obtain IOMMU info. 
     {
         let acpi_tables = ACPI_TABLES.lock();
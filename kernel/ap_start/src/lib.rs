@@ -15,6 +15,10 @@ extern crate scheduler;
 extern crate kernel_config;
 extern crate apic;
 extern crate no_drop;
+extern crate mod_mgmt;
+extern crate cpu_stats;
+extern crate tracepoint;
+extern crate clock;
 
 use alloc::collections::BTreeMap;
 use core::sync::atomic::{AtomicBool, Ordering};
@@ -50,6 +54,19 @@ pub fn kstart_ap(
     nmi_lint: u8,
     nmi_flags: u16,
 ) -> ! {
+    // Allow fault handlers to identify this CPU without relying on the TLS register.
+    mod_mgmt::init_hardened_cpu_id(apic_id);
+    cpu_stats::init(apic_id).expect("kstart_ap(): failed to initialize per-CPU statistics");
+    tracepoint::init(apic_id);
+    metrics::init(apic_id);
+    // Set up a TLS area from the current TLS layout before anything on this AP can access TLS variables.
+    // This includes the TLS sections of crates loaded after boot, which matters for CPUs that are hot-added.
+    // This is replaced by the bootstrap task's own TLS area in `spawn::init()` below.
+    let bootstrap_tls = mod_mgmt::get_initial_kernel_namespace()
+        .expect("kstart_ap(): initial kernel namespace was None")
+        .init_online_cpu_tls(apic_id)
+        .expect("kstart_ap(): failed to initialize bootstrap TLS area");
+
     info!("Booting AP: proc: {}, apic: {}, stack: {:#X} to {:#X}, nmi_lint: {}, nmi_flags: {:#X}",
         processor_id, apic_id, _stack_start, _stack_end, nmi_lint, nmi_flags
     );
@@ -87,9 +104,19 @@ pub fn kstart_ap(
         nmi_flags,
     ).unwrap();
 
+    // Calibrate this CPU's TSC against the reference clock, as it may not be synchronized with other CPUs' TSCs.
+    if let Err(e) = clock::init_ap() {
+        warn!("kstart_ap(): failed to calibrate the TSC of CPU {}: {}", apic_id, e);
+    }
+
     // Now that the Local APIC has been initialized for this CPU, we can initialize the
     // task management subsystem and create the idle task for this CPU.
     let bootstrap_task = spawn::init(kernel_mmi_ref.clone(), apic_id, this_ap_stack).unwrap();
+    // Give interrupt handlers on this CPU their own TLS area, separate from the interrupted task's.
+    mod_mgmt::get_initial_kernel_namespace()
+        .expect("kstart_ap(): initial kernel namespace was None")
+        .init_interrupt_tls(apic_id)
+        .expect("kstart_ap(): failed to initialize interrupt TLS area");
     spawn::create_idle_task().unwrap();
 
     // The PAT must be initialized explicitly on every CPU,
@@ -101,7 +128,7 @@ pub fn kstart_ap(
     info!("Initialization complete on AP core {}. Enabling interrupts...", apic_id);
     // The following final initialization steps are important, and order matters:
     // 1. Drop any other local stack variables that still exist.
-    // (currently nothing else needs to be dropped)
+    drop(bootstrap_tls);
     // 2. "Finish" this bootstrap task, indicating it has exited and no longer needs to run.
     bootstrap_task.finish();
     // 3. Enable interrupts such that other tasks can be scheduled in.
@@ -14,7 +14,7 @@ use atomic_linked_list::atomic_map::AtomicMap;
 use crossbeam_utils::atomic::AtomicCell;
 use pit_clock_basic::pit_wait;
 use bit_field::BitField;
-use log::{error, info, debug, trace};
+use log::{error, warn, info, debug, trace};
 
 /// A unique identifier for a CPU core.
 pub type CpuId = u8;
@@ -33,7 +33,7 @@ pub const APIC_SPURIOUS_INTERRUPT_IRQ: u8  = 0xFF;
 const APIC_TIMER_DISABLE:              u32 = 1 << 16;
 const _APIC_TIMER_MODE_ONESHOT:        u32 = 0b00 << 17;
 const APIC_TIMER_MODE_PERIODIC:        u32 = 0b01 << 17;
-const _APIC_TIMER_MODE_TSC_DEADLINE:   u32 = 0b10 << 17;
+const APIC_TIMER_MODE_TSC_DEADLINE:    u32 = 0b10 << 17;
 /// The IRQ number reserved for Local APIC timer interrupts in the IDT.
 pub const LOCAL_APIC_LVT_IRQ:          u8  = 0x22;
 
@@ -88,6 +88,23 @@ pub fn has_x2apic() -> bool {
     *res // because call_once returns a reference to the cached IS_X2APIC value
 }
 
+/// Returns true if the local APIC timer supports TSC-deadline mode,
+/// in which it fires a one-shot interrupt when the TSC reaches a given value.
+pub fn has_tsc_deadline() -> bool {
+    static HAS_TSC_DEADLINE: Once<bool> = Once::new(); // cache the result
+    *HAS_TSC_DEADLINE.call_once(||
+        X86CpuIdInstr::new()
+            .get_feature_info()
+            .map_or(false, |info| info.has_tsc_deadline())
+    )
+}
+
+/// Returns the current value of the TSC.
+fn rdtsc() -> u64 {
+    // SAFE: just reading the TSC value
+    unsafe { core::arch::x86_64::_rdtsc() }
+}
+
 /// Returns a reference to the list of LocalApics, one per CPU core.
 pub fn get_lapics() -> &'static AtomicMap<CpuId, RwLockIrqSafe<LocalApic>> {
 	&LOCAL_APICS
@@ -399,6 +416,22 @@ pub struct LocalApic {
     /// The value that should be written to the APIC timer's initial count register
     /// when enabling the LVT timer.
     initial_timer_count: u32,
+    /// The state of the LVT timer if it operates in TSC-deadline mode rather than periodic mode.
+    tsc_deadline: Option<TscDeadlineTimer>,
+}
+
+/// The state of a LVT timer in TSC-deadline mode, which multiplexes the periodic scheduler tick
+/// with one-shot timer interrupts requested via [`LocalApic::request_timer_interrupt_after()`].
+#[derive(Debug)]
+struct TscDeadlineTimer {
+    /// The frequency of the TSC, in ticks per second.
+    tsc_frequency: u64,
+    /// The number of TSC ticks between scheduler ticks.
+    tick_period: u64,
+    /// The TSC value at which the next scheduler tick is due.
+    next_tick: u64,
+    /// The TSC value at which the earliest requested one-shot interrupt is due, or `u64::MAX` if none.
+    next_deadline: u64,
 }
 impl fmt::Debug for LocalApic {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
@@ -407,6 +440,7 @@ impl fmt::Debug for LocalApic {
             .field("apic_id", &self.apic_id)
             .field("processor_id", &self.processor_id)
             .field("is_bootstrap_cpu", &self.is_bootstrap_cpu)
+            .field("tsc_deadline", &self.tsc_deadline.is_some())
             .finish_non_exhaustive()
     }
 }
@@ -497,6 +531,7 @@ impl LocalApic {
             apic_id: CpuId::MAX, // placeholder, is replaced below.
             is_bootstrap_cpu,
             initial_timer_count: 0, // set in `calibrate_lapic_timer()`
+            tsc_deadline: None, // set in `init_lvt_timer()`
         };
 
         // Now that the APIC hardware is enabled, we can safely obtain this Local APIC's ID.
@@ -631,7 +666,41 @@ impl LocalApic {
     }
 
     /// After this lapic has been enabled, initialize its LVT timer.
+    ///
+    /// The timer uses TSC-deadline mode if it is supported, which allows one-shot timer interrupts
+    /// to be requested in between scheduler ticks; otherwise, it uses periodic mode.
     fn init_lvt_timer(&mut self) {
+        if !cfg!(apic_timer_fixed) && has_tsc_deadline() {
+            match tsc::get_tsc_frequency() {
+                Ok(freq) => {
+                    let tsc_frequency = freq as u64;
+                    let tick_period = (freq * CONFIG_TIMESLICE_PERIOD_MICROSECONDS as u128 / 1_000_000) as u64;
+                    trace!("LocalApic {}, TSC-deadline timer period: {} TSC ticks", self.apic_id, tick_period);
+                    self.tsc_deadline = Some(TscDeadlineTimer {
+                        tsc_frequency,
+                        tick_period,
+                        next_tick: rdtsc() + tick_period,
+                        next_deadline: u64::MAX,
+                    });
+                    match &mut self.inner {
+                        LapicType::X2Apic => unsafe {
+                            wrmsr(IA32_X2APIC_LVT_TIMER, LOCAL_APIC_LVT_IRQ as u64 | APIC_TIMER_MODE_TSC_DEADLINE as u64);
+                            wrmsr(IA32_X2APIC_LVT_THERMAL, 0);
+                            wrmsr(IA32_X2APIC_ESR, 0);
+                        }
+                        LapicType::XApic(regs) => {
+                            regs.lvt_timer.write(LOCAL_APIC_LVT_IRQ as u32 | APIC_TIMER_MODE_TSC_DEADLINE);
+                            regs.lvt_thermal.write(0);
+                            regs.lvt_error.write(0);
+                        }
+                    }
+                    self.arm_tsc_deadline();
+                    return;
+                }
+                Err(e) => warn!("LocalApic {}: couldn't use TSC-deadline timer mode: {}", self.apic_id, e),
+            }
+        }
+
         let apic_period = if cfg!(apic_timer_fixed) {
             info!("apic_timer_fixed config: overriding LocalAPIC LVT timer period to {}", 0x10000);
             0x10000 // for bochs, which doesn't do apic periods right
@@ -678,7 +747,19 @@ impl LocalApic {
         //   To start the timer, it is necessary to write to the initial-count register.
         //
         // Thus, when enabling the timer, we must immeditely write the initial count again.
-        if enable {
+        if enable && self.tsc_deadline.is_some() {
+            let timer_enable = LOCAL_APIC_LVT_IRQ as u32 | APIC_TIMER_MODE_TSC_DEADLINE;
+            match &mut self.inner {
+                LapicType::X2Apic => unsafe {
+                    wrmsr(IA32_X2APIC_LVT_TIMER, timer_enable as u64);
+                }
+                LapicType::XApic(regs) => {
+                    regs.lvt_timer.write(timer_enable);
+                }
+            }
+            // A deadline that passed while the timer was masked fires immediately.
+            self.arm_tsc_deadline();
+        } else if enable {
             let timer_enable = LOCAL_APIC_LVT_IRQ as u32 | APIC_TIMER_MODE_PERIODIC;
             match &mut self.inner {
                 LapicType::X2Apic => unsafe {
@@ -703,6 +784,63 @@ impl LocalApic {
         }
     }
 
+    /// Returns `true` if this lapic's LVT timer operates in TSC-deadline mode,
+    /// i.e., it supports [`LocalApic::request_timer_interrupt_after()`].
+    pub fn has_tsc_deadline_timer(&self) -> bool {
+        self.tsc_deadline.is_some()
+    }
+
+    /// Requests a one-shot LVT timer interrupt on this lapic after the given number of `nanoseconds`,
+    /// in addition to the periodic scheduler ticks.
+    ///
+    /// If an earlier interrupt was already requested, that one is kept;
+    /// the interrupt handler should then request the next one as needed.
+    ///
+    /// Returns `false` if the LVT timer doesn't operate in TSC-deadline mode,
+    /// in which case timer interrupts only occur upon scheduler ticks.
+    pub fn request_timer_interrupt_after(&mut self, nanoseconds: u64) -> bool {
+        let Some(timer) = self.tsc_deadline.as_mut() else { return false };
+        let ticks = (nanoseconds as u128 * timer.tsc_frequency as u128 / 1_000_000_000) as u64;
+        let deadline = rdtsc().saturating_add(ticks);
+        if deadline < timer.next_deadline {
+            timer.next_deadline = deadline;
+            self.arm_tsc_deadline();
+        }
+        true
+    }
+
+    /// Handles an LVT timer interrupt on this lapic, re-arming the timer if it is in TSC-deadline mode.
+    ///
+    /// Returns `true` if this interrupt is a periodic scheduler tick,
+    /// or `false` if it only serves a requested one-shot interrupt.
+    pub fn handle_lvt_timer_interrupt(&mut self) -> bool {
+        let Some(timer) = self.tsc_deadline.as_mut() else { return true };
+        let now = rdtsc();
+        if now >= timer.next_deadline {
+            timer.next_deadline = u64::MAX;
+        }
+        let is_tick = now >= timer.next_tick;
+        if is_tick {
+            timer.next_tick += timer.tick_period;
+            // Don't fire a burst of ticks to make up for ones that were missed.
+            if timer.next_tick <= now {
+                timer.next_tick = now + timer.tick_period;
+            }
+        }
+        self.arm_tsc_deadline();
+        is_tick
+    }
+
+    /// Programs the TSC deadline to the earlier of the next scheduler tick and the next requested interrupt.
+    fn arm_tsc_deadline(&self) {
+        if let Some(timer) = &self.tsc_deadline {
+            // The Intel SDM requires a fence between writing the LVT timer in xAPIC mode,
+            // which is a memory-mapped register, and writing the TSC deadline MSR.
+            core::sync::atomic::fence(Ordering::SeqCst);
+            unsafe { wrmsr(IA32_TSC_DEADLINE, core::cmp::min(timer.next_tick, timer.next_deadline)); }
+        }
+    }
+
     /// Returns the ID of this Local APIC (fast).
     /// 
     /// Unlike [`LocalApic::read_apic_id()`], this does not read any hardware registers.
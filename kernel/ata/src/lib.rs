@@ -3,6 +3,8 @@
 //! The primary struct of interest is [`AtaDrive`].
 //! 
 //! Support for DMA is not yet implemented, but the slower port-based I/O is fully supported.
+//! While waiting for a drive to have data ready, the current task blocks
+//! until the bus raises an interrupt instead of polling the drive's status.
 
 #![no_std]
 #![feature(abi_x86_interrupt)]
@@ -17,12 +19,15 @@ use alloc::{
 	boxed::Box, 
 	format, 
 	string::{String, ToString}, 
-	sync::Arc
+	sync::Arc,
+	vec,
+	vec::Vec,
 };
 use port_io::{Port, PortReadOnly, PortWriteOnly};
 use pci::PciDevice;
-use storage_device::{StorageDevice, StorageDeviceRef, StorageController};
+use storage_device::{BlockDevice, BlockRequest, StorageDevice, StorageDeviceRef, StorageController};
 use io::{BlockIo, BlockReader, BlockWriter, IoError, KnownLength};
+use io_wait::PendingIo;
 use x86_64::structures::idt::InterruptStackFrame;
 
 
@@ -237,11 +242,14 @@ struct AtaBus {
 	/// `DEVADDRESS`, located at `BAR1 + 3`. 
 	/// Not sure what this is used for.
 	_drive_address: Port<u8>,
+
+	/// The pending I/O request that this bus's interrupt handler completes.
+	pending_io: &'static PendingIo,
 }
 
 impl AtaBus {
 	/// Creates and sets up a new ATA bus at the location specified by the given data and control BARs.
-	fn new(data_bar: u16, control_bar: u16) -> AtaBus {
+	fn new(data_bar: u16, control_bar: u16, pending_io: &'static PendingIo) -> AtaBus {
 		let data_bar = data_bar & PCI_BAR_PORT_MASK;
 		let control_bar = control_bar & PCI_BAR_PORT_MASK;
 		AtaBus { 
@@ -259,6 +267,7 @@ impl AtaBus {
 			alternate_status: PortReadOnly::new(control_bar + 2),
 			control: PortWriteOnly::new(control_bar + 2),
 			_drive_address: Port::new(control_bar + 3),
+			pending_io,
 		}
 	}
 
@@ -456,30 +465,28 @@ impl AtaBus {
 	/// Waits until the bus is ready to transfer data (either read or write).
 	/// This is intended to be used **after** commands have been issued.
 	/// 
-	/// This performs a blocking poll that reads the bus's status 
-	/// until it is no longer busy and data is ready to be transferred
+	/// This reads the bus's status until it is no longer busy and data is ready to be transferred
 	/// (`AtaStatus::BUSY` is `0` and `AtaStatus::DATA_REQUEST_READY` is `1`).
+	/// The drive raises an interrupt once data is ready, so the current task blocks between status reads;
+	/// see [`io_wait::block_until()`].
 	/// 
 	/// Returns an error if the `status` port indicates an error. 
 	/// Invoke [`error()`](#method.error) to obtain more details on what kind of error occurred.
 	fn wait_for_data_ready(&self) -> Result<(), ()> {
-		let mut _loop_counter = 0;
-		loop {
+		io_wait::block_until(self.pending_io, || {
+			// Reading the `status` port also acknowledges the drive's interrupt.
 			let status = self.status();
-			_loop_counter += 1;
 			if status.intersects(AtaStatus::ERROR | AtaStatus::DRIVE_WRITE_FAULT) {
-				return Err(());
+				return Some(Err(()));
 			}
 			if status.intersects(AtaStatus::BUSY) { 
-				if _loop_counter % 1_000_000 == 0 {
-					warn!("AtaBus::wait_for_data_ready() has been busy waiting for a long time... is there a device/driver problem? (status: {:?})", status);
-				}
-				continue;
+				return None;
 			}
 			if status.intersects(AtaStatus::DATA_REQUEST_READY) {
-				return Ok(()); // ready to go!
+				return Some(Ok(())); // ready to go!
 			}
-		}
+			None
+		})
 	}
 
 	/// Waits until this bus is finished transferring data (either read or write),
@@ -684,6 +691,8 @@ impl StorageDevice for AtaDrive {
 			self.identify_data.max_48_bit_lba as usize
 		}
 	}
+
+	fn as_block_device_mut(&mut self) -> Option<&mut dyn BlockDevice> { Some(self) }
 }
 impl BlockIo for AtaDrive {
 	fn block_size(&self) -> usize { SECTOR_SIZE_IN_BYTES }
@@ -705,6 +714,33 @@ impl BlockWriter for AtaDrive {
 
 	fn flush(&mut self) -> Result<(), IoError> { Ok(()) }
 }
+/// ATA drives only support port I/O, so each request is performed synchronously
+/// and the returned `BlockRequest` has already completed.
+impl BlockDevice for AtaDrive {
+	fn max_blocks_per_request(&self) -> usize {
+		self.identify_data.max_blocks_per_transfer as usize
+	}
+
+	fn submit_read(&mut self, block_offset: usize, num_blocks: usize) -> Result<BlockRequest, IoError> {
+		let mut buffer = vec![0; num_blocks * SECTOR_SIZE_IN_BYTES];
+		self.read_blocks(&mut buffer, block_offset)?;
+		Ok(BlockRequest::completed(Ok(buffer)))
+	}
+
+	fn submit_write(&mut self, buffer: Vec<u8>, block_offset: usize) -> Result<BlockRequest, IoError> {
+		self.write_blocks(&buffer, block_offset)?;
+		Ok(BlockRequest::completed(Ok(buffer)))
+	}
+
+	fn submit_flush(&mut self) -> Result<BlockRequest, IoError> {
+		BlockWriter::flush(self)?;
+		Ok(BlockRequest::completed(Ok(Vec::new())))
+	}
+
+	fn submit_discard(&mut self, _block_offset: usize, _num_blocks: usize) -> Result<BlockRequest, IoError> {
+		Err(IoError::Other("ATA drives do not support discarding blocks"))
+	}
+}
 
 pub type AtaDriveRef = Arc<Mutex<AtaDrive>>;
 
@@ -754,8 +790,8 @@ impl IdeController {
 		// TODO: use the BAR4 for DMA in the future
 		let _bus_master_base = pci_device.bars[4]; 
 
-		// Register interrupt handlers for the primary and secondary ATA buses.
-		// They're not yet used for anything but will determine when a DMA transfer has completed.
+		// Register interrupt handlers for the primary and secondary ATA buses,
+		// which wake up the task waiting for data on that bus.
 		interrupts::register_interrupt(ATA_PRIMARY_IRQ, primary_ata_handler).map_err(|e| {
 			error!("ATA Primary Bus IRQ {:#X} was already in use by handler {:#X}! Sharing IRQs is currently unsupported.", 
 				ATA_PRIMARY_IRQ, e,
@@ -769,8 +805,8 @@ impl IdeController {
 			"ATA Secondary Bus IRQ was already in use! Sharing IRQs is currently unsupported."
 		})?;
 
-		let primary_bus = Arc::new(Mutex::new(AtaBus::new(primary_bus_data_port, primary_bus_control_port)));
-		let secondary_bus = Arc::new(Mutex::new(AtaBus::new(secondary_bus_data_port, secondary_bus_control_port)));
+		let primary_bus = Arc::new(Mutex::new(AtaBus::new(primary_bus_data_port, primary_bus_control_port, &PRIMARY_PENDING_IO)));
+		let secondary_bus = Arc::new(Mutex::new(AtaBus::new(secondary_bus_data_port, secondary_bus_control_port, &SECONDARY_PENDING_IO)));
 
 		let primary_master   = AtaDrive::new(Arc::clone(&primary_bus), BusDriveSelect::Master);
 		let primary_slave    = AtaDrive::new(primary_bus, BusDriveSelect::Slave);
@@ -891,15 +927,20 @@ const ATA_PRIMARY_IRQ:   u8 = interrupts::IRQ_BASE_OFFSET + 0xE;
 /// Because we perform the typical PIC remapping, the remapped IRQ vector number is 0x2F.
 const ATA_SECONDARY_IRQ: u8 = interrupts::IRQ_BASE_OFFSET + 0xF;
 
-/// The primary ATA interrupt handler. Not yet used for anything, but useful for DMA.
+/// The pending I/O request on the primary ATA bus, completed by its interrupt handler.
+static PRIMARY_PENDING_IO: PendingIo = PendingIo::new();
+/// The pending I/O request on the secondary ATA bus, completed by its interrupt handler.
+static SECONDARY_PENDING_IO: PendingIo = PendingIo::new();
+
+/// The primary ATA interrupt handler, which wakes up the task waiting for data on the primary bus.
 extern "x86-interrupt" fn primary_ata_handler(_stack_frame: InterruptStackFrame ) {
-    info!("Primary ATA Interrupt ({:#X})", ATA_PRIMARY_IRQ);
+    PRIMARY_PENDING_IO.complete(Ok(0));
     interrupts::eoi(Some(ATA_PRIMARY_IRQ));
 }
 
-/// The primary ATA interrupt handler. Not yet used for anything, but useful for DMA.
+/// The secondary ATA interrupt handler, which wakes up the task waiting for data on the secondary bus.
 extern "x86-interrupt" fn secondary_ata_handler(_stack_frame: InterruptStackFrame ) {
-    info!("Secondary ATA Interrupt ({:#X})", ATA_SECONDARY_IRQ);
+    SECONDARY_PENDING_IO.complete(Ok(0));
     interrupts::eoi(Some(ATA_SECONDARY_IRQ));
 }
 
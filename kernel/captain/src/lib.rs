@@ -19,8 +19,11 @@
 
 extern crate alloc;
 
-use core::ops::DerefMut;
-use log::{error, info};
+use alloc::{string::String, sync::Arc, vec::Vec};
+use core::{ops::DerefMut, sync::atomic::{AtomicUsize, Ordering}, time::Duration};
+use spin::Once;
+use task::{RunState, TaskRef};
+use log::{error, info, warn};
 use memory::{EarlyIdentityMappedPages, MmiRef, PhysicalAddress, VirtualAddress};
 use kernel_config::memory::KERNEL_STACK_SIZE_IN_PAGES;
 use irq_safety::enable_interrupts;
@@ -56,6 +59,157 @@ impl DropAfterInit {
 }
 
 
+/// The size of the memory region reserved for crash dumps.
+const CRASH_DUMP_REGION_SIZE: usize = 64 * 1024;
+
+/// The task that rebalances the per-core heaps, which is woken up by [`wake_heap_rebalancer()`].
+static HEAP_REBALANCER: Once<TaskRef> = Once::new();
+
+/// A system task that rebalances the empty pages across the per-core heaps
+/// whenever one of them runs low on empty pages.
+fn heap_rebalancer(_: ()) {
+    let Some(current) = task::get_my_current_task() else {
+        error!("heap_rebalancer: couldn't get current task, exiting.");
+        return;
+    };
+    loop {
+        // Block before taking the request, such that a request cannot be lost
+        // by arriving before the current task has blocked.
+        let _ = current.block();
+        if !multiple_heaps::take_rebalance_request() {
+            scheduler::schedule();
+            continue;
+        }
+        // The rebalance notifier may have already unblocked the current task.
+        if current.runstate() == RunState::Blocked {
+            let _ = current.unblock();
+        }
+        let moved = multiple_heaps::rebalance_heaps();
+        if moved > 0 {
+            log::trace!("heap_rebalancer: moved {} empty heap pages between per-core heaps", moved);
+        }
+    }
+}
+
+/// The rebalance notifier for the per-core heaps, which is invoked from within the allocator.
+fn wake_heap_rebalancer() {
+    if let Some(rebalancer) = HEAP_REBALANCER.get() {
+        // Only unblock the rebalancer if it's blocked, since it checks for requests before blocking again.
+        if rebalancer.runstate() == RunState::Blocked {
+            let _ = rebalancer.unblock();
+        }
+    }
+}
+
+/// Refreshes the TLS areas of existing tasks after lazy binding has loaded a crate with TLS sections.
+fn refresh_tls_after_lazy_binding() -> Result<(), &'static str> {
+    task::refresh_tls_areas(&[])?;
+    let namespace = mod_mgmt::get_initial_kernel_namespace()
+        .ok_or("couldn't get the initial kernel namespace")?;
+    for (cpu, _) in apic::get_lapics().iter() {
+        if mod_mgmt::has_interrupt_tls(*cpu) {
+            namespace.init_interrupt_tls(*cpu)?;
+        }
+    }
+    let _ = task_events::broadcast(task_events::Event::TlsLayoutChanged);
+    Ok(())
+}
+
+/// Kills the current task because a function it called through a lazy binding stub couldn't be loaded.
+fn kill_after_lazy_binding_failure(description: String) -> ! {
+    let cause = task::KillReason::LoadFailure(description);
+    if let Some(kill_handler) = task::take_kill_handler() {
+        kill_handler(&cause);
+    }
+    match task::with_current_task(|t| t.kill(cause)) {
+        Ok(Ok(())) => { }
+        Ok(Err(e)) => error!("couldn't kill the task whose lazily-bound call failed: {}", e),
+        Err(_) => error!("couldn't get the task whose lazily-bound call failed"),
+    }
+    // A killed task is never scheduled in again.
+    loop {
+        scheduler::schedule();
+    }
+}
+
+/// Runs the crate loading jobs of `mod_mgmt`'s parallel pipeline on all CPUs.
+///
+/// One worker task is spawned on each CPU other than the current one,
+/// and the current task also runs jobs until there are none left.
+/// This doesn't return until every worker has exited and stopped running.
+fn run_parallel_jobs(num_jobs: usize, job: &(dyn Fn(usize) + Sync)) {
+    fn run_jobs((next_job, num_jobs, job): (Arc<AtomicUsize>, usize, &'static (dyn Fn(usize) + Sync))) {
+        loop {
+            let i = next_job.fetch_add(1, Ordering::Relaxed);
+            if i >= num_jobs {
+                break;
+            }
+            job(i);
+        }
+    }
+
+    // SAFETY: before this function returns, it waits until every worker task has exited
+    //         and stopped running, even if joining it failed, so no worker can use `job`
+    //         after it is no longer valid. Tasks that failed to spawn never run.
+    let job: &'static (dyn Fn(usize) + Sync) = unsafe { core::mem::transmute(job) };
+    let next_job = Arc::new(AtomicUsize::new(0));
+    let current_cpu = cpu::current_cpu();
+    let mut workers = Vec::new();
+    for (cpu, _) in apic::get_lapics().iter() {
+        if *cpu == current_cpu || workers.len() + 1 >= num_jobs {
+            continue;
+        }
+        let worker = spawn::new_task_builder(run_jobs, (next_job.clone(), num_jobs, job))
+            .name(alloc::format!("crate_loader_{}", cpu))
+            .pin_to(*cpu)
+            .spawn();
+        match worker {
+            Ok(worker) => workers.push(worker),
+            Err(e) => error!("couldn't spawn crate loading worker on CPU {}: {}", cpu, e),
+        }
+    }
+    run_jobs((next_job, num_jobs, job));
+    for worker in workers {
+        if let Err(e) = worker.join() {
+            error!("couldn't join crate loading worker: {}", e);
+        }
+        // An exited task never runs again, so it can no longer use `job`.
+        while !worker.has_exited() || worker.is_running() {
+            core::hint::spin_loop();
+        }
+    }
+}
+
+/// Loads the crates listed in the preload list on all CPUs and logs how long it took.
+fn preload_crates(kernel_mmi_ref: &MmiRef) -> Result<(), &'static str> {
+    let namespace = mod_mgmt::get_initial_kernel_namespace()
+        .ok_or("BUG: couldn't get the initial kernel namespace")?;
+    let start = clock::monotonic_nanos();
+    let num_crates = mod_mgmt::parallel::preload(namespace, kernel_mmi_ref)?;
+    if let Some(stats) = mod_mgmt::parallel::last_stats().filter(|_| num_crates > 0) {
+        let elapsed = Duration::from_nanos(clock::monotonic_nanos() - start);
+        info!("Preloaded {} crates in {:?}; loading their sections took {} ticks on all CPUs vs. {} ticks serially ({} ticks for symbols, {} ticks for relocations)",
+            num_crates, elapsed, stats.section_loading, stats.section_loading_serial, stats.symbols, stats.relocations,
+        );
+    }
+    Ok(())
+}
+
+/// A task that runs all in-kernel tests instead of the first application
+/// and then exits QEMU with the overall result.
+#[cfg(ktest)]
+fn ktest_runner(_: ()) {
+    let Some(namespace) = mod_mgmt::get_initial_kernel_namespace() else {
+        error!("ktest_runner: couldn't get the initial kernel namespace");
+        ktest::exit_qemu(false);
+        return;
+    };
+    let summary = ktest::run(namespace, None);
+    info!("ktest_runner: {} tests passed, {} tests failed.", summary.passed(), summary.failed());
+    ktest::exit_qemu(summary.success());
+}
+
+
 /// Initialize the Captain, which is the main crate that "steers the ship" of Theseus. 
 /// 
 /// This does the rest of the initialization procedures so that the OS 
@@ -94,6 +248,9 @@ pub fn init(
     // now we initialize early driver stuff, like APIC/ACPI
     device_manager::early_init(rsdp_address, kernel_mmi_ref.lock().deref_mut())?;
 
+    // calibrate the TSC-based monotonic clock against the HPET, and set the wall clock from the RTC
+    clock::init()?;
+
     // initialize the rest of the BSP's interrupt stuff, including TSS & GDT
     let (double_fault_stack, privilege_stack) = {
         let mut kernel_mmi = kernel_mmi_ref.lock();
@@ -108,12 +265,31 @@ pub fn init(
     
     // get BSP's apic id
     let bsp_apic_id = cpu::bootstrap_cpu().ok_or("captain::init(): couldn't get ID of bootstrap CPU!")?;
+    // Allow fault handlers to identify this CPU without relying on the TLS register.
+    mod_mgmt::init_hardened_cpu_id(bsp_apic_id);
+    // Allow the memory subsystem to record which CPU holds a page table lock, using that same ID.
+    memory::set_current_cpu_cb(mod_mgmt::hardened_cpu_id);
+    cpu_stats::init(bsp_apic_id)?;
+    tracepoint::init(bsp_apic_id);
+    metrics::init(bsp_apic_id);
 
     // create the initial `Task`, which is bootstrapped from this execution context.
     let bootstrap_task = spawn::init(kernel_mmi_ref.clone(), bsp_apic_id, bsp_initial_stack)?;
+    // give interrupt handlers on this CPU their own TLS area, separate from the interrupted task's
+    mod_mgmt::get_initial_kernel_namespace()
+        .ok_or("captain::init(): initial kernel namespace was None")?
+        .init_interrupt_tls(bsp_apic_id)?;
+    // deliver asynchronous events to tasks whenever they resume at a safe point
+    task_events::init();
+    // refresh existing tasks' TLS areas whenever lazy binding loads a crate with TLS sections
+    mod_mgmt::lazy_binding::set_lazy_binding_tls_hook(refresh_tls_after_lazy_binding)?;
+    // kill only the calling task if a lazily-bound call's crate can't be loaded
+    mod_mgmt::lazy_binding::set_lazy_binding_failure_hook(kill_after_lazy_binding_failure)?;
     info!("Created initial bootstrap task: {:?}", bootstrap_task);
 
-    // after we've initialized the task subsystem, we can use better exception handlers
+    // after we've initialized the task subsystem, we can use better exception handlers,
+    // which capture a crash dump into a region reserved up front when a fault is unrecoverable
+    crash_dump::init(CRASH_DUMP_REGION_SIZE)?;
     exceptions_full::init(idt);
     
     // boot up the other cores (APs)
@@ -142,6 +318,16 @@ pub fn init(
     multiple_heaps::switch_to_multiple_heaps()?;
     info!("Initialized per-core heaps");
 
+    // In sanitizer builds, surround every heap allocation made from now on with poisoned redzones.
+    #[cfg(kasan)] {
+        heap::enable_sanitizer(task::get_my_current_task_id)?;
+        info!("Enabled heap redzones");
+    }
+
+    // Now that all CPUs are up, load the crates that will be needed soon in parallel.
+    mod_mgmt::parallel::set_parallel_executor(run_parallel_jobs)?;
+    preload_crates(&kernel_mmi_ref)?;
+
     #[cfg(feature = "uefi")] {
         log::error!("uefi boot cannot proceed as it is not fully implemented");
         loop {}
@@ -159,6 +345,7 @@ pub fn init(
     // initialize the rest of our drivers
     device_manager::init(key_producer, mouse_producer)?;
     task_fs::init()?;
+    tmpfs::init()?;
 
     // create a SIMD personality
     #[cfg(simd_personality)] {
@@ -178,7 +365,23 @@ pub fn init(
     // 3. Start the first application(s).
     drop_after_init.drop_all();
     console::start_connection_detection()?;
+    if let Err(e) = console::start_remote_shell() {
+        warn!("couldn't start the remote shell service: {}", e);
+    }
+    let heap_rebalancer = spawn::new_task_builder(heap_rebalancer, ())
+        .name(String::from("heap_rebalancer"))
+        .spawn()?;
+    HEAP_REBALANCER.call_once(|| (*heap_rebalancer).clone());
+    multiple_heaps::set_rebalance_notifier(wake_heap_rebalancer);
+    if let Err(e) = watchdog::start(watchdog::WatchdogConfig::default()) {
+        warn!("couldn't start the watchdog: {}", e);
+    }
+    #[cfg(not(ktest))]
     first_application::start()?;
+    #[cfg(ktest)]
+    spawn::new_task_builder(ktest_runner, ())
+        .name(String::from("ktest_runner"))
+        .spawn()?;
 
     info!("captain::init(): initialization done! Spawning an idle task on BSP core {} and enabling interrupts...", bsp_apic_id);
     // The following final initialization steps are important, and order matters:
@@ -7,7 +7,6 @@ extern crate alloc;
 extern crate task;
 
 use core::mem::ManuallyDrop;
-use alloc::boxed::Box;
 use task::KillReason;
 
 /// Invokes the given closure `f`, catching a panic as it is unwinding the stack.
@@ -53,10 +52,12 @@ pub fn catch_unwind_with_arg<F, A, R>(f: F, arg: A) -> Result<R, KillReason>
 /// * a pointer to the 
 /// * a pointer to the arbitrary object passed around during the unwinding process,
 ///   which in Theseus is a pointer to the `UnwindingContext`. 
-fn panic_callback<F, A, R>(data_ptr: *mut u8, exception_object: *mut u8) where F: FnOnce(A) -> R {
+fn panic_callback<F, A, R>(data_ptr: *mut u8, _exception_object: *mut u8) where F: FnOnce(A) -> R {
     let data = unsafe { &mut *(data_ptr as *mut TryIntrinsicArg<F, A, R>) };
-    let unwinding_context_boxed = unsafe { Box::from_raw(exception_object as *mut unwind::UnwindingContext) };
-    let unwinding_context = *unwinding_context_boxed;
+    // The unwinding context is owned by the current task's TLS slot,
+    // which holds the same pointer as the given `exception_object`.
+    let unwinding_context = unwind::take_unwinding_context()
+        .expect("BUG: panic_callback(): current task had no unwinding context");
     let (_stack_frame_iter, cause, _taskref) = unwinding_context.into();
     data.ret = ManuallyDrop::new(Err(cause));
 }
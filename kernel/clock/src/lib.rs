@@ -0,0 +1,294 @@
+//! A unified clock API: a monotonic nanosecond clock that is consistent across CPUs,
+//! and a wall clock that tracks Unix time.
+//!
+//! The monotonic clock reads the TSC, which is far cheaper than reading the HPET,
+//! but the TSCs of different CPUs are not necessarily synchronized.
+//! Thus, each CPU's TSC is calibrated against the reference monotonic clock source
+//! (e.g., the HPET) when that CPU boots, which yields a per-CPU offset that is added
+//! to every reading on that CPU. Readings are also clamped to never go backwards,
+//! even when a task migrates to a CPU whose calibration is slightly behind.
+//! If the TSC doesn't tick at a constant rate, the reference clock is used instead.
+//!
+//! The wall clock is derived from the monotonic clock plus an offset,
+//! which is initially set from the RTC and may be corrected later
+//! by a more precise source, e.g., a PTP or NTP client, via [`set_wall_time()`].
+//!
+//! Once [`init()`] has run, both clocks are registered with the `time` crate,
+//! so `time::now::<Monotonic>()` and `time::now::<WallTime>()` use them.
+
+#![no_std]
+
+use core::{
+    arch::x86_64::__rdtscp,
+    sync::atomic::{AtomicBool, AtomicI64, AtomicU64, AtomicU8, Ordering},
+};
+use irq_safety::hold_interrupts;
+use log::{info, warn};
+use spin::Once;
+use time::{ClockSource, ClockType, Duration, Instant, Monotonic, Period, WallTime};
+
+/// The maximum number of CPUs, one for each possible CPU ID.
+const MAX_CPUS: usize = u8::MAX as usize + 1;
+/// The number of samples taken when calibrating a CPU's TSC, of which the most precise one is used.
+const CALIBRATION_SAMPLES: usize = 16;
+/// The period of the clocks in this crate, which count nanoseconds.
+const NANOSECOND_PERIOD: u64 = 1_000_000; // in femtoseconds
+const FEMTOS_PER_NANO: u128 = 1_000_000;
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+
+/// The reference monotonic clock source that the TSCs are calibrated against.
+struct Reference {
+    now: fn() -> Instant,
+    /// The period of the reference clock in femtoseconds.
+    period: u64,
+}
+
+static REFERENCE: Once<Reference> = Once::new();
+/// The TSC frequency in ticks per second, or `0` if the TSC isn't used.
+static TSC_FREQUENCY: AtomicU64 = AtomicU64::new(0);
+/// The offset in nanoseconds that is added to each CPU's TSC-derived time.
+static TSC_OFFSETS: [AtomicI64; MAX_CPUS] = {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const ZERO: AtomicI64 = AtomicI64::new(0);
+    [ZERO; MAX_CPUS]
+};
+/// Whether each CPU's TSC has been calibrated.
+static CALIBRATED: [AtomicBool; MAX_CPUS] = {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const FALSE: AtomicBool = AtomicBool::new(false);
+    [FALSE; MAX_CPUS]
+};
+/// The latest monotonic time returned on any CPU, which later readings are clamped to.
+static LAST_NANOS: AtomicU64 = AtomicU64::new(0);
+
+/// The offset in nanoseconds that is added to the monotonic time to obtain Unix time.
+static WALL_OFFSET: AtomicI64 = AtomicI64::new(0);
+/// The source that the wall clock was last set from, as a `WallClockSource`.
+static WALL_SOURCE: AtomicU8 = AtomicU8::new(WallClockSource::None as u8);
+
+/// The source that the wall clock was last set from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum WallClockSource {
+    /// The wall clock hasn't been set; it counts from the Unix epoch at boot.
+    None = 0,
+    /// The real-time clock, which has a resolution of one second.
+    Rtc = 1,
+    /// The Precision Time Protocol.
+    Ptp = 2,
+    /// The Network Time Protocol.
+    Ntp = 3,
+    /// Set explicitly, e.g., by a user.
+    Manual = 4,
+}
+
+impl WallClockSource {
+    fn from_u8(value: u8) -> WallClockSource {
+        match value {
+            1 => WallClockSource::Rtc,
+            2 => WallClockSource::Ptp,
+            3 => WallClockSource::Ntp,
+            4 => WallClockSource::Manual,
+            _ => WallClockSource::None,
+        }
+    }
+}
+
+/// The calibrated TSC as a monotonic clock source with nanosecond resolution.
+struct CalibratedTsc;
+
+impl ClockSource for CalibratedTsc {
+    type ClockType = Monotonic;
+
+    fn now() -> Instant {
+        Instant::new(monotonic_nanos())
+    }
+}
+
+/// The wall clock as a clock source with nanosecond resolution.
+struct WallClock;
+
+impl ClockSource for WallClock {
+    type ClockType = WallTime;
+
+    fn now() -> Duration {
+        wall_time()
+    }
+}
+
+/// Initializes the clocks on the bootstrap CPU and registers them with the `time` crate.
+///
+/// A monotonic clock source, e.g., the HPET, must already be registered,
+/// as it is used as the reference that every CPU's TSC is calibrated against.
+/// The wall clock is initially set from the RTC.
+pub fn init() -> Result<(), &'static str> {
+    let period = time::period::<Monotonic>().ok_or("no monotonic clock source has been registered")?;
+    REFERENCE.call_once(|| Reference {
+        now: Monotonic::now_fn().load(),
+        period: period.into(),
+    });
+
+    if has_invariant_tsc() {
+        TSC_FREQUENCY.store(tsc::get_tsc_frequency()? as u64, Ordering::Release);
+        calibrate_current_cpu()?;
+    } else {
+        warn!("The TSC doesn't tick at a constant rate, so the monotonic clock will use the slower reference clock.");
+    }
+    time::register_clock_source::<CalibratedTsc>(Period::new(NANOSECOND_PERIOD));
+
+    let rtc_time = rtc::read_rtc();
+    set_wall_time(Duration::from_secs(unix_time_of(&rtc_time)), WallClockSource::Rtc);
+    time::register_clock_source::<WallClock>(Period::new(NANOSECOND_PERIOD));
+    info!("Initialized clocks: TSC-based: {}, wall time from {}", is_tsc_based(), rtc_time);
+    Ok(())
+}
+
+/// Calibrates the TSC of the current CPU, which must be invoked on every CPU other than
+/// the bootstrap CPU when it boots, after its local APIC has been initialized.
+///
+/// Until then, the monotonic clock uses the slower reference clock on that CPU.
+pub fn init_ap() -> Result<(), &'static str> {
+    if is_tsc_based() {
+        calibrate_current_cpu()?;
+    }
+    Ok(())
+}
+
+/// Returns whether the monotonic clock is based on the TSC rather than the reference clock.
+pub fn is_tsc_based() -> bool {
+    TSC_FREQUENCY.load(Ordering::Relaxed) != 0
+}
+
+/// Returns the offset in nanoseconds added to the given CPU's TSC-derived time,
+/// or `None` if that CPU's TSC hasn't been calibrated.
+///
+/// The differences between CPUs' offsets show how far apart their TSCs are.
+pub fn tsc_offset(cpu: u8) -> Option<i64> {
+    CALIBRATED[cpu as usize].load(Ordering::Acquire)
+        .then(|| TSC_OFFSETS[cpu as usize].load(Ordering::Relaxed))
+}
+
+/// Re-calibrates the TSC of the current CPU against the reference clock,
+/// which corrects any drift that has accumulated since it was last calibrated.
+pub fn calibrate_current_cpu() -> Result<(), &'static str> {
+    let freq = TSC_FREQUENCY.load(Ordering::Acquire);
+    if freq == 0 {
+        return Err("the monotonic clock doesn't use the TSC");
+    }
+    let _held_interrupts = hold_interrupts();
+
+    // Bracket each reference reading between two TSC readings and use the tightest bracket.
+    let mut best: Option<(u64, u64, u32)> = None; // (TSC window, reference nanos, CPU ID)
+    let mut cpu = 0;
+    let mut best_tsc = 0;
+    for _ in 0..CALIBRATION_SAMPLES {
+        let before = unsafe { __rdtscp(&mut cpu) };
+        let reference = reference_nanos();
+        let after = unsafe { __rdtscp(&mut cpu) };
+        let window = after.saturating_sub(before);
+        if best.map_or(true, |(best_window, ..)| window < best_window) {
+            best = Some((window, reference, cpu));
+            best_tsc = before + window / 2;
+        }
+    }
+    let (_, reference, cpu) = best.ok_or("couldn't sample the TSC")?;
+    let offset = reference as i64 - tsc_to_nanos(best_tsc, freq) as i64;
+
+    let cpu = cpu as u8 as usize;
+    TSC_OFFSETS[cpu].store(offset, Ordering::Relaxed);
+    CALIBRATED[cpu].store(true, Ordering::Release);
+    Ok(())
+}
+
+/// Returns the number of nanoseconds since an arbitrary point in time around boot.
+///
+/// The returned value never decreases, even across CPUs.
+pub fn monotonic_nanos() -> u64 {
+    let freq = TSC_FREQUENCY.load(Ordering::Relaxed);
+    let nanos = if freq == 0 {
+        reference_nanos()
+    } else {
+        // Theseus stores the current CPU's ID in `IA32_TSC_AUX`, which `rdtscp` reads atomically with the TSC.
+        let mut cpu = 0;
+        let tsc = unsafe { __rdtscp(&mut cpu) };
+        let cpu = cpu as u8 as usize;
+        if CALIBRATED[cpu].load(Ordering::Acquire) {
+            (tsc_to_nanos(tsc, freq) as i64).saturating_add(TSC_OFFSETS[cpu].load(Ordering::Relaxed)) as u64
+        } else {
+            reference_nanos()
+        }
+    };
+    let previous = LAST_NANOS.fetch_max(nanos, Ordering::AcqRel);
+    core::cmp::max(previous, nanos)
+}
+
+/// Returns the current wall-clock time as the duration since the Unix epoch.
+pub fn wall_time() -> Duration {
+    let nanos = (monotonic_nanos() as i64).saturating_add(WALL_OFFSET.load(Ordering::Relaxed));
+    Duration::from_nanos(nanos.max(0) as u64)
+}
+
+/// Sets the wall clock to the given Unix time, as obtained from the given `source`.
+///
+/// This doesn't affect the monotonic clock.
+pub fn set_wall_time(unix_time: Duration, source: WallClockSource) {
+    let offset = unix_time.as_nanos() as i64 - monotonic_nanos() as i64;
+    WALL_OFFSET.store(offset, Ordering::Relaxed);
+    WALL_SOURCE.store(source as u8, Ordering::Relaxed);
+}
+
+/// Adjusts the wall clock by the given number of nanoseconds, e.g., to apply a correction
+/// computed by a PTP or NTP client, and records that `source` as the wall clock's source.
+pub fn adjust_wall_time(delta_nanos: i64, source: WallClockSource) {
+    WALL_OFFSET.fetch_add(delta_nanos, Ordering::Relaxed);
+    WALL_SOURCE.store(source as u8, Ordering::Relaxed);
+}
+
+/// Returns the source that the wall clock was last set from.
+pub fn wall_clock_source() -> WallClockSource {
+    WallClockSource::from_u8(WALL_SOURCE.load(Ordering::Relaxed))
+}
+
+/// Returns the time of the reference clock in nanoseconds,
+/// or the time of the current monotonic clock source if the reference hasn't been captured yet.
+fn reference_nanos() -> u64 {
+    let Some(reference) = REFERENCE.get() else {
+        return time::now::<Monotonic>().duration_since(Instant::ZERO).as_nanos() as u64;
+    };
+    // `duration_since()` scales by the period of the current clock source, which is this crate's
+    // once it has been registered, so recover the reference counter and scale it by the reference's period.
+    let current_period: u128 = time::period::<Monotonic>().map_or(reference.period as u128, u128::from);
+    let nanos = (reference.now)().duration_since(Instant::ZERO).as_nanos();
+    let counter = nanos * FEMTOS_PER_NANO / current_period;
+    (counter * reference.period as u128 / FEMTOS_PER_NANO) as u64
+}
+
+fn tsc_to_nanos(tsc: u64, freq: u64) -> u64 {
+    (tsc as u128 * NANOS_PER_SEC as u128 / freq as u128) as u64
+}
+
+/// Returns whether the TSC ticks at a constant rate regardless of the CPU's power state.
+fn has_invariant_tsc() -> bool {
+    raw_cpuid::CpuId::new()
+        .get_advanced_power_mgmt_info()
+        .map_or(false, |info| info.has_invariant_tsc())
+}
+
+/// Converts the given RTC time, whose year is within the 21st century, to Unix time in seconds.
+fn unix_time_of(rtc_time: &rtc::RtcTime) -> u64 {
+    let year = 2000 + rtc_time.years as i64;
+    let days = days_from_civil(year, rtc_time.months as i64, rtc_time.days as i64);
+    let seconds = rtc_time.hours as i64 * 3600 + rtc_time.minutes as i64 * 60 + rtc_time.seconds as i64;
+    (days * 86400 + seconds).max(0) as u64
+}
+
+/// Returns the number of days between the Unix epoch and the given date in the Gregorian calendar.
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    // Count years from March, such that the leap day is at the end of the year.
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
@@ -4,7 +4,11 @@
 
 extern crate alloc;
 
-use alloc::{format, sync::Arc};
+mod remote;
+
+pub use remote::{start_remote_shell, start_remote_shell_with_key, DEFAULT_REMOTE_SHELL_PORT};
+
+use alloc::{format, string::String, sync::Arc};
 use async_channel::Receiver;
 use core::sync::atomic::{AtomicU16, Ordering};
 use core2::io::Write;
@@ -109,6 +113,28 @@ fn shell_loop(
         .name(format!("{address:?}_to_tty"))
         .spawn()?;
 
+    let task = spawn_shell(&tty, format!("{address:?}_shell"))?;
+    task.join()?;
+
+    reader_task.kill(KillReason::Requested).unwrap();
+    writer_task.kill(KillReason::Requested).unwrap();
+
+    // Flush the tty in case the reader task didn't run between the last time the
+    // shell wrote something to the slave end and us killing the task.
+    let mut data = [0; 256];
+    if let Ok(len) = tty.master().try_read(&mut data) {
+        port.lock()
+            .write(&data[..len])
+            .map_err(|_| "couldn't write to serial port")?;
+    };
+
+    // TODO: Close port?
+
+    Ok(())
+}
+
+/// Spawns a shell whose standard streams are the slave end of the given `tty`.
+pub(crate) fn spawn_shell(tty: &tty::Tty, name: String) -> Result<JoinableTaskRef, &'static str> {
     let new_app_ns = mod_mgmt::create_application_namespace(None)?;
 
     let (app_file, _ns) =
@@ -117,7 +143,7 @@ fn shell_loop(
 
     let path = path::Path::new(app_file.lock().get_absolute_path());
     let task = spawn::new_application_task_builder(path, Some(new_app_ns))?
-        .name(format!("{address:?}_shell"))
+        .name(name)
         .block()
         .spawn()?;
 
@@ -134,23 +160,7 @@ fn shell_loop(
     );
 
     task.unblock().map_err(|_| "couldn't unblock shell task")?;
-    task.join()?;
-
-    reader_task.kill(KillReason::Requested).unwrap();
-    writer_task.kill(KillReason::Requested).unwrap();
-
-    // Flush the tty in case the reader task didn't run between the last time the
-    // shell wrote something to the slave end and us killing the task.
-    let mut data = [0; 256];
-    if let Ok(len) = tty.master().try_read(&mut data) {
-        port.lock()
-            .write(&data[..len])
-            .map_err(|_| "couldn't write to serial port")?;
-    };
-
-    // TODO: Close port?
-
-    Ok(())
+    Ok(task)
 }
 
 fn tty_to_port_loop((port, master): (Arc<MutexIrqSafe<SerialPort>>, tty::Master)) {
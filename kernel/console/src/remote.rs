@@ -0,0 +1,282 @@
+//! A remote shell service, which lets headless machines be driven over TCP
+//! without a serial connection.
+//!
+//! The service only runs if a preshared key was given in the
+//! `THESEUS_REMOTE_SHELL_KEY` environment variable at build time. Upon
+//! connecting, a client must send the key followed by a newline. The
+//! connection is then attached to a new shell through a [`tty::Tty`], just like
+//! a serial port console, so clients should put their terminal into raw mode:
+//! ```sh
+//! socat -,raw,echo=0 tcp:<address>:2323
+//! ```
+//!
+//! With QEMU's user networking, the guest isn't reachable from the host unless
+//! the port is forwarded, e.g. with `hostfwd=tcp::2323-:2323`; `net=tap` needs
+//! no forwarding.
+//!
+//! The connection isn't encrypted, so this should only be used on trusted
+//! networks, e.g. to drive test machines.
+
+use crate::spawn_shell;
+use alloc::{format, string::String, sync::Arc, vec::Vec};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use dreadnought::{
+    block_on, select_biased,
+    time::{sleep, Duration},
+    FutureExt,
+};
+use log::{error, info, warn};
+use net::{TcpListener, TcpOptions, TcpStream};
+use task::JoinableTaskRef;
+
+/// The port on which the remote shell service listens by default.
+pub const DEFAULT_REMOTE_SHELL_PORT: u16 = 2323;
+
+/// The preshared key that clients must send to be given a shell.
+const BUILD_KEY: Option<&str> = option_env!("THESEUS_REMOTE_SHELL_KEY");
+
+/// The number of connections that may wait to be accepted at a time.
+const BACKLOG: usize = 2;
+/// The maximum number of concurrent sessions, including unauthenticated ones.
+const MAX_SESSIONS: usize = 4;
+/// The number of wrong keys a client may send before it's disconnected.
+const MAX_AUTH_ATTEMPTS: usize = 3;
+/// The maximum length of a key sent by a client.
+const MAX_KEY_LEN: usize = 256;
+/// How long a client has to authenticate before it's disconnected.
+const AUTH_TIMEOUT: Duration = Duration::from_secs(30);
+/// How long to wait before responding to a wrong key, to slow down guessing.
+const AUTH_FAILURE_DELAY: Duration = Duration::from_secs(1);
+/// How often the shell's output is forwarded to the client while the client is
+/// idle.
+const OUTPUT_INTERVAL: Duration = Duration::from_millis(10);
+
+/// The input sent to the shell once its client disconnects, which interrupts
+/// the foreground job, if any, and then exits the shell.
+const HANGUP: &[u8] = b"\x03exit\n";
+
+/// The number of sessions that are currently open.
+static SESSIONS: AtomicUsize = AtomicUsize::new(0);
+
+/// Starts the remote shell service on [`DEFAULT_REMOTE_SHELL_PORT`] if a key
+/// was given at build time.
+///
+/// Returns the newly-spawned listener task, or `None` if no key was given.
+pub fn start_remote_shell() -> Result<Option<JoinableTaskRef>, &'static str> {
+    match BUILD_KEY {
+        Some(key) if !key.is_empty() => {
+            start_remote_shell_with_key(DEFAULT_REMOTE_SHELL_PORT, key).map(Some)
+        }
+        _ => Ok(None),
+    }
+}
+
+/// Starts the remote shell service on the given `port` of the default network
+/// interface, which only gives shells to clients that send the given `key`.
+///
+/// Returns the newly-spawned listener task.
+pub fn start_remote_shell_with_key(port: u16, key: &str) -> Result<JoinableTaskRef, &'static str> {
+    let interface = net::get_default_interface().ok_or("no network interface is available")?;
+    let listener = TcpListener::bind(interface, port, BACKLOG, TcpOptions::default())
+        .map_err(|_| "couldn't listen on the remote shell port")?;
+    info!("remote shell listening on port {port}");
+
+    spawn::new_task_builder(listener_loop, (listener, Arc::<str>::from(key)))
+        .name(String::from("remote_shell_listener"))
+        .spawn()
+}
+
+/// The entry point for the remote shell listener task.
+fn listener_loop((listener, key): (TcpListener, Arc<str>)) -> Result<(), &'static str> {
+    block_on(async {
+        let mut next_id = 0;
+        loop {
+            let stream = listener.accept().await.map_err(|e| {
+                error!("couldn't accept remote shell connection: {e}");
+                "couldn't accept remote shell connection"
+            })?;
+
+            let Some(slot) = SessionSlot::acquire() else {
+                warn!("refusing remote shell connection: too many sessions");
+                let _ = stream.write_all(b"too many sessions\r\n").await;
+                continue;
+            };
+
+            let id = next_id;
+            next_id += 1;
+            if let Err(e) = spawn::new_task_builder(session, (stream, key.clone(), id, slot))
+                .name(format!("remote_shell_{id}_manager"))
+                .spawn()
+            {
+                error!("failed to spawn manager for remote shell session {id}: {e}");
+            }
+        }
+    })
+}
+
+/// The entry point for the task managing a remote shell session.
+fn session(
+    (stream, key, id, _slot): (TcpStream, Arc<str>, usize, SessionSlot),
+) -> Result<(), &'static str> {
+    let authenticated = block_on(async {
+        select_biased! {
+            result = authenticate(&stream, &key).fuse() => result,
+            _ = sleep(AUTH_TIMEOUT).fuse() => Ok(None),
+        }
+    });
+    let pending = match authenticated {
+        Ok(Some(pending)) => pending,
+        Ok(None) => {
+            warn!("remote shell session {id} failed to authenticate");
+            return Ok(());
+        }
+        Err(e) => {
+            warn!("remote shell session {id} disconnected during authentication: {e}");
+            return Ok(());
+        }
+    };
+    info!("creating new tty for remote shell session {id}");
+
+    let tty = tty::Tty::new();
+    let master = tty.master();
+    let shell = spawn_shell(&tty, format!("remote_shell_{id}"))?;
+    if !pending.is_empty() {
+        let _ = master.write(&pending);
+    }
+
+    block_on(forward(&stream, &master, &shell));
+    shell.join()?;
+
+    // Forward the output the shell wrote before exiting. The connection is
+    // closed once the stream is dropped.
+    let mut data = [0; 256];
+    while let Ok(len) = master.try_read(&mut data) {
+        if block_on(stream.write_all(&data[..len])).is_err() {
+            break;
+        }
+    }
+    info!("remote shell session {id} closed");
+    Ok(())
+}
+
+/// Prompts the client for the preshared key.
+///
+/// Returns the data the client sent after the key, which is meant for the
+/// shell, or `None` if the client failed to authenticate.
+async fn authenticate(stream: &TcpStream, key: &str) -> net::Result<Option<Vec<u8>>> {
+    let mut pending = Vec::new();
+    let mut attempts = 0;
+    stream.write_all(b"key: ").await?;
+    while attempts < MAX_AUTH_ATTEMPTS {
+        let Some(line) = read_line(stream, &mut pending).await? else {
+            return Ok(None);
+        };
+        // Ignore the empty line caused by a `\r\n` split across reads.
+        if line.is_empty() {
+            continue;
+        }
+        if keys_match(&line, key.as_bytes()) {
+            stream.write_all(b"\r\n").await?;
+            return Ok(Some(pending));
+        }
+        attempts += 1;
+        sleep(AUTH_FAILURE_DELAY).await;
+        stream.write_all(b"\r\ninvalid key\r\n").await?;
+        if attempts < MAX_AUTH_ATTEMPTS {
+            stream.write_all(b"key: ").await?;
+        }
+    }
+    Ok(None)
+}
+
+/// Reads a line terminated by `\r`, `\n` or `\r\n` from the client, keeping any
+/// data after it in `pending`.
+///
+/// Returns `None` if the client disconnected or the line is too long.
+async fn read_line(stream: &TcpStream, pending: &mut Vec<u8>) -> net::Result<Option<Vec<u8>>> {
+    loop {
+        if let Some(end) = pending.iter().position(|&b| b == b'\r' || b == b'\n') {
+            let line = pending[..end].to_vec();
+            let mut rest = end + 1;
+            if pending[end] == b'\r' && pending.get(rest) == Some(&b'\n') {
+                rest += 1;
+            }
+            pending.drain(..rest);
+            return Ok(Some(line));
+        }
+        if pending.len() > MAX_KEY_LEN {
+            return Ok(None);
+        }
+
+        let mut data = [0; 64];
+        let len = stream.read(&mut data).await?;
+        if len == 0 {
+            return Ok(None);
+        }
+        pending.extend_from_slice(&data[..len]);
+    }
+}
+
+/// Compares the given keys in constant time with respect to their contents.
+fn keys_match(given: &[u8], key: &[u8]) -> bool {
+    given.len() == key.len() && given.iter().zip(key).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+/// Forwards data between the client and the shell until the shell exits or the
+/// client disconnects.
+///
+/// The tty only supports blocking reads, so the shell's output is polled
+/// whenever the client sends data and at least every [`OUTPUT_INTERVAL`].
+async fn forward(stream: &TcpStream, master: &tty::Master, shell: &JoinableTaskRef) {
+    let mut input = [0; 256];
+    let mut output = [0; 256];
+    loop {
+        while let Ok(len) = master.try_read(&mut output) {
+            if stream.write_all(&output[..len]).await.is_err() {
+                let _ = master.write(HANGUP);
+                return;
+            }
+        }
+        if shell.has_exited() {
+            return;
+        }
+
+        let read = select_biased! {
+            read = stream.read(&mut input).fuse() => Some(read),
+            _ = sleep(OUTPUT_INTERVAL).fuse() => None,
+        };
+        match read {
+            Some(Ok(0)) | Some(Err(_)) => {
+                let _ = master.write(HANGUP);
+                return;
+            }
+            Some(Ok(len)) => {
+                if let Err(e) = master.write(&input[..len]) {
+                    error!("couldn't write to master: {e}");
+                }
+            }
+            None => {}
+        }
+    }
+}
+
+/// A reservation of one of the [`MAX_SESSIONS`] sessions, which is released
+/// when dropped.
+struct SessionSlot;
+
+impl SessionSlot {
+    fn acquire() -> Option<Self> {
+        SESSIONS
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |sessions| {
+                (sessions < MAX_SESSIONS).then_some(sessions + 1)
+            })
+            .ok()
+            .map(|_| Self)
+    }
+}
+
+impl Drop for SessionSlot {
+    fn drop(&mut self) {
+        SESSIONS.fetch_sub(1, Ordering::AcqRel);
+    }
+}
@@ -0,0 +1,100 @@
+//! A set of CPUs, used to express which CPUs a task is allowed to run on.
+
+use core::fmt;
+
+/// The number of possible CPU IDs.
+pub(crate) const MAX_CPUS: usize = u8::MAX as usize + 1;
+const BITS_PER_WORD: usize = u64::BITS as usize;
+const NUM_WORDS: usize = MAX_CPUS / BITS_PER_WORD;
+
+/// A set of CPUs, represented as a bitmap indexed by CPU ID.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct CpuSet {
+    bits: [u64; NUM_WORDS],
+}
+
+impl CpuSet {
+    /// Returns a set that contains no CPUs.
+    pub const fn empty() -> CpuSet {
+        CpuSet { bits: [0; NUM_WORDS] }
+    }
+
+    /// Returns a set that contains every possible CPU.
+    pub const fn all() -> CpuSet {
+        CpuSet { bits: [u64::MAX; NUM_WORDS] }
+    }
+
+    /// Returns a set that contains only the given CPU.
+    pub const fn single(cpu: u8) -> CpuSet {
+        let mut set = CpuSet::empty();
+        set.bits[cpu as usize / BITS_PER_WORD] = 1 << (cpu as usize % BITS_PER_WORD);
+        set
+    }
+
+    /// Adds the given CPU to this set.
+    pub fn insert(&mut self, cpu: u8) {
+        self.bits[cpu as usize / BITS_PER_WORD] |= 1 << (cpu as usize % BITS_PER_WORD);
+    }
+
+    /// Removes the given CPU from this set.
+    pub fn remove(&mut self, cpu: u8) {
+        self.bits[cpu as usize / BITS_PER_WORD] &= !(1 << (cpu as usize % BITS_PER_WORD));
+    }
+
+    /// Returns whether this set contains the given CPU.
+    pub const fn contains(&self, cpu: u8) -> bool {
+        self.bits[cpu as usize / BITS_PER_WORD] & (1 << (cpu as usize % BITS_PER_WORD)) != 0
+    }
+
+    /// Returns whether this set contains no CPUs.
+    pub fn is_empty(&self) -> bool {
+        self.bits.iter().all(|&word| word == 0)
+    }
+
+    /// Returns whether this set contains every possible CPU.
+    pub fn is_all(&self) -> bool {
+        self.bits.iter().all(|&word| word == u64::MAX)
+    }
+
+    /// Returns the number of CPUs in this set.
+    pub fn len(&self) -> usize {
+        self.bits.iter().map(|word| word.count_ones() as usize).sum()
+    }
+
+    /// Returns the only CPU in this set, or `None` if it contains zero or multiple CPUs.
+    pub fn single_cpu(&self) -> Option<u8> {
+        if self.len() == 1 { self.iter().next() } else { None }
+    }
+
+    /// Returns an iterator over the CPUs in this set, in increasing order.
+    pub fn iter(&self) -> impl Iterator<Item = u8> + '_ {
+        (0..MAX_CPUS).map(|cpu| cpu as u8).filter(|&cpu| self.contains(cpu))
+    }
+}
+
+impl Default for CpuSet {
+    /// By default, a set contains every possible CPU.
+    fn default() -> Self {
+        CpuSet::all()
+    }
+}
+
+impl FromIterator<u8> for CpuSet {
+    fn from_iter<I: IntoIterator<Item = u8>>(iter: I) -> Self {
+        let mut set = CpuSet::empty();
+        for cpu in iter {
+            set.insert(cpu);
+        }
+        set
+    }
+}
+
+impl fmt::Debug for CpuSet {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.is_all() {
+            write!(f, "all")
+        } else {
+            f.debug_set().entries(self.iter()).finish()
+        }
+    }
+}
@@ -1,11 +1,17 @@
 //! An abstraction for querying about CPUs (cores) in an SMP multicore system.
 //!
-//! This crate contains no extra functionality.
+//! Aside from [`CpuSet`] and tracking which CPUs are offline, this crate contains no extra functionality.
 //! Currently it just re-exports types and functions from:
 //! * [`apic`] on x86_64
 
 #![no_std]
 
+mod cpu_set;
+mod offline;
+
+pub use cpu_set::CpuSet;
+pub use offline::{is_offline, offline_cpus, set_offline};
+
 #[cfg(target_arch = "x86_64")]
 pub use apic::{
     CpuId,
@@ -0,0 +1,37 @@
+//! Tracking which CPUs have been taken offline.
+//!
+//! An offline CPU keeps its runqueue and idle task, but no other tasks may be placed on it
+//! or run on it, regardless of their affinity. Once its runqueue has been drained,
+//! the CPU parks itself until it is brought back online.
+//! See the `cpu_hotplug` crate for how CPUs are taken offline and brought back online.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+use crate::{CpuSet, cpu_set::MAX_CPUS};
+
+/// Whether each CPU is offline.
+static OFFLINE: [AtomicBool; MAX_CPUS] = {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const FALSE: AtomicBool = AtomicBool::new(false);
+    [FALSE; MAX_CPUS]
+};
+
+/// Returns whether the given CPU has been taken offline.
+pub fn is_offline(cpu: u8) -> bool {
+    OFFLINE[cpu as usize].load(Ordering::Acquire)
+}
+
+/// Returns the set of CPUs that have been taken offline.
+pub fn offline_cpus() -> CpuSet {
+    (0..MAX_CPUS)
+        .map(|cpu| cpu as u8)
+        .filter(|&cpu| is_offline(cpu))
+        .collect()
+}
+
+/// Records whether the given CPU is offline.
+///
+/// This only records the new state; it neither drains nor parks the CPU.
+/// Use the `cpu_hotplug` crate to take a CPU offline or bring it back online.
+pub fn set_offline(cpu: u8, offline: bool) {
+    OFFLINE[cpu as usize].store(offline, Ordering::Release);
+}
@@ -0,0 +1,215 @@
+//! Taking application processors (APs) offline and bringing them back online at runtime.
+//!
+//! Taking a CPU offline via [`offline()`] works as follows:
+//! 1. The CPU is marked as offline, after which no new tasks are placed on it,
+//!    and no task other than its idle task is allowed to run on it.
+//!    The MSI and MSI-X interrupts routed to it are moved to other online CPUs.
+//! 2. The CPU is asked to migrate the tasks on its runqueue to other online CPUs,
+//!    which it does the next time it runs the scheduler, along with their TLS bases.
+//!    The task that is running on it is migrated once it has been switched away from.
+//! 3. Once only its idle task remains, the CPU parks itself in [`park_if_offline()`]:
+//!    it stops participating in RCU grace periods and halts with interrupts disabled.
+//!
+//! Bringing a CPU back online via [`online()`] marks it as online and wakes it with an NMI.
+//! Before enabling interrupts again, it regenerates its per-CPU interrupt TLS image
+//! from the current TLS layout, which includes the TLS sections of crates loaded while it was offline.
+//!
+//! The bootstrap CPU cannot be taken offline, as legacy device interrupts are routed to it.
+//! A CPU cannot be taken offline if a task is pinned to it, i.e., if that task
+//! isn't allowed to run on any other online CPU.
+
+#![no_std]
+
+use apic::LapicIpiDestination;
+use core::sync::atomic::{AtomicBool, Ordering};
+use log::{error, info, warn};
+use time::{Duration, Monotonic};
+
+/// The maximum number of CPUs, one for each possible CPU ID.
+const MAX_CPUS: usize = u8::MAX as usize + 1;
+
+/// How long to wait for a CPU to drain its runqueue and park itself, or to wake up.
+const TRANSITION_TIMEOUT: Duration = Duration::from_secs(1);
+/// How often to check whether a CPU has parked itself or woken up.
+const POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+/// Whether each CPU is parked, i.e., halted in [`park_if_offline()`].
+static PARKED: [AtomicBool; MAX_CPUS] = {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const FALSE: AtomicBool = AtomicBool::new(false);
+    [FALSE; MAX_CPUS]
+};
+
+/// Set while a CPU is being taken offline or brought online, which serializes those operations.
+static IN_PROGRESS: AtomicBool = AtomicBool::new(false);
+
+/// Takes the given CPU offline, blocking until it has drained its runqueue and parked itself.
+///
+/// If the CPU doesn't park itself in time, e.g., because a task running on it
+/// doesn't yield, it is brought back online and an error is returned.
+pub fn offline(cpu: u8) -> Result<(), &'static str> {
+    let _in_progress = InProgress::enter()?;
+    if runqueue::get_runqueue(cpu).is_none() {
+        return Err("the given CPU doesn't exist");
+    }
+    if apic::bootstrap_cpu() == Some(cpu) {
+        return Err("the bootstrap CPU cannot be taken offline");
+    }
+    if cpu::is_offline(cpu) {
+        return Err("the given CPU is already offline");
+    }
+    check_no_pinned_tasks(cpu)?;
+
+    cpu::set_offline(cpu, true);
+    // A parked CPU doesn't handle interrupts, so route its device interrupts elsewhere.
+    if let Err(e) = msi::migrate_from(cpu) {
+        warn!("couldn't move all device interrupts away from CPU {}: {}", cpu, e);
+    }
+    scheduler::request_migration(cpu);
+    if wait_until(|| PARKED[cpu as usize].load(Ordering::Acquire)) {
+        info!("CPU {} is now offline", cpu);
+        return Ok(());
+    }
+
+    error!("CPU {} didn't drain its runqueue in time, bringing it back online", cpu);
+    cpu::set_offline(cpu, false);
+    if !wake(cpu) {
+        error!("CPU {} didn't wake up after failing to go offline", cpu);
+    }
+    Err("timed out waiting for the CPU to drain its runqueue")
+}
+
+/// Brings the given CPU back online, blocking until it has woken up.
+pub fn online(cpu: u8) -> Result<(), &'static str> {
+    let _in_progress = InProgress::enter()?;
+    if !cpu::is_offline(cpu) {
+        return Err("the given CPU is already online");
+    }
+    cpu::set_offline(cpu, false);
+    if !wake(cpu) {
+        return Err("timed out waiting for the CPU to wake up");
+    }
+    info!("CPU {} is now online", cpu);
+    Ok(())
+}
+
+/// Returns whether the given CPU is parked, i.e., it is offline and has stopped running tasks.
+pub fn is_parked(cpu: u8) -> bool {
+    PARKED[cpu as usize].load(Ordering::Acquire)
+}
+
+/// Parks the current CPU if it has been taken offline and only its idle task remains on its runqueue,
+/// returning once it has been brought back online.
+///
+/// This must only be invoked by the idle task of the given CPU, which must be the current CPU.
+pub fn park_if_offline(cpu: u8) {
+    if !cpu::is_offline(cpu) || !is_drained(cpu) {
+        return;
+    }
+
+    let held_interrupts = irq_safety::hold_interrupts();
+    // The idle task holds no references to RCU-protected data.
+    rcu::note_cpu_offline(cpu);
+    PARKED[cpu as usize].store(true, Ordering::Release);
+
+    // Only an NMI can wake this CPU while interrupts are disabled,
+    // so any NMI that arrives while parked is handled by `handle_nmi()`.
+    while cpu::is_offline(cpu) {
+        x86_64::instructions::hlt();
+    }
+
+    rcu::note_quiescent_state(cpu);
+    if tls_initializer::has_interrupt_tls(cpu) {
+        let result = mod_mgmt::get_initial_kernel_namespace()
+            .ok_or("the initial kernel namespace doesn't exist")
+            .and_then(|namespace| namespace.init_interrupt_tls(cpu));
+        if let Err(e) = result {
+            warn!("CPU {} couldn't regenerate its interrupt TLS image: {}", cpu, e);
+        }
+    }
+    PARKED[cpu as usize].store(false, Ordering::Release);
+    drop(held_interrupts);
+}
+
+/// Returns whether the current CPU is parked, in which case an NMI is expected,
+/// as it is used to wake the CPU up.
+///
+/// This is invoked by the NMI handler, so it doesn't take any locks.
+pub fn handle_nmi() -> bool {
+    tls_initializer::hardened_cpu_id().map_or(false, is_parked)
+}
+
+/// Returns whether the given CPU's runqueue contains no tasks other than its idle task.
+fn is_drained(cpu: u8) -> bool {
+    runqueue::get_runqueue(cpu)
+        .map_or(true, |rq| rq.read().iter().all(|t| t.is_an_idle_task))
+}
+
+/// Returns an error if any task that hasn't exited is allowed to run on the given CPU
+/// but not on any other online CPU.
+fn check_no_pinned_tasks(cpu: u8) -> Result<(), &'static str> {
+    let tasklist = task::TASKLIST.lock();
+    let pinned = tasklist.values().find(|t| {
+        !t.is_an_idle_task
+            && !t.has_exited()
+            && t.is_allowed_on(cpu)
+            && !t.affinity().iter().any(|other| other != cpu
+                && !cpu::is_offline(other)
+                && runqueue::get_runqueue(other).is_some()
+            )
+    });
+    match pinned {
+        Some(t) => {
+            warn!("Task {:?} (id {}) is pinned to CPU {}", t.name, t.id, cpu);
+            Err("a task is pinned to the given CPU")
+        }
+        None => Ok(()),
+    }
+}
+
+/// Wakes the given CPU with an NMI, and returns whether it woke up in time.
+///
+/// The NMI is resent while waiting, in case the CPU hadn't halted yet when the first one arrived.
+fn wake(cpu: u8) -> bool {
+    wait_until(|| {
+        if !is_parked(cpu) {
+            return true;
+        }
+        match apic::get_my_apic() {
+            Some(lapic) => lapic.write().send_nmi_ipi(LapicIpiDestination::One(cpu)),
+            None => error!("couldn't get the current CPU's local APIC to wake CPU {}", cpu),
+        }
+        false
+    })
+}
+
+/// Sleeps until the given `condition` holds or the transition timeout elapses,
+/// and returns whether it held.
+fn wait_until(mut condition: impl FnMut() -> bool) -> bool {
+    let deadline = time::now::<Monotonic>() + TRANSITION_TIMEOUT;
+    loop {
+        if condition() {
+            return true;
+        }
+        if time::now::<Monotonic>() >= deadline || sleep::sleep(POLL_INTERVAL).is_err() {
+            return condition();
+        }
+    }
+}
+
+/// A guard that marks a hotplug operation as in progress until it is dropped.
+struct InProgress;
+
+impl InProgress {
+    fn enter() -> Result<InProgress, &'static str> {
+        IN_PROGRESS.compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .map(|_| InProgress)
+            .map_err(|_| "another CPU is already being taken offline or brought online")
+    }
+}
+
+impl Drop for InProgress {
+    fn drop(&mut self) {
+        IN_PROGRESS.store(false, Ordering::Release);
+    }
+}
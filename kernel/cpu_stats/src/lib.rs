@@ -0,0 +1,246 @@
+//! Cheap statistics counters stored in each CPU's per-CPU area.
+//!
+//! Each CPU's per-CPU area is laid out by a [`tp_area::AreaBuilder`],
+//! whose reserved region begins with a block of counters (see [`STATS_OFFSET`]).
+//! On x86_64, the per-CPU area is installed as the CPU's kernel `GS` base,
+//! so [`incr()`] and [`add()`] update a counter with a single `GS`-relative instruction,
+//! which cannot be torn by an interrupt on the same CPU.
+//! Updates on a CPU whose per-CPU area hasn't been installed yet, e.g., from an early interrupt,
+//! are dropped; see [`tls_initializer::gs::has_kernel_gs_base()`].
+//! On aarch64, `TPIDR_EL1` holds the hardened CPU ID (see [`tls_initializer::hardened_cpu_id()`]),
+//! so the per-CPU area is found by indexing a per-CPU array with that ID instead.
+//! Each counter is only updated by the CPU that owns it, so no locks are needed,
+//! but counters are atomic such that other CPUs can read them at any time.
+//!
+//! The counters of all CPUs can be combined into a system-wide [`CpuStatsReport`] via [`aggregate()`].
+
+#![no_std]
+
+extern crate alloc;
+
+use alloc::{boxed::Box, vec::Vec};
+use core::{
+    fmt,
+    ptr,
+    sync::atomic::{AtomicPtr, AtomicU64, Ordering},
+};
+use tp_area::{AreaBuilder, AreaVariant};
+
+/// The maximum number of CPUs, one for each possible CPU ID.
+const MAX_CPUS: usize = u8::MAX as usize + 1;
+
+/// The number of counters in the statistics block of each per-CPU area.
+pub const NUM_CPU_STATS: usize = 8;
+
+/// The offset of the statistics block from the start of each per-CPU area.
+pub const STATS_OFFSET: usize = 0;
+
+/// The size of the reserved region at the start of each per-CPU area.
+const RESERVED_AREA_SIZE: usize = STATS_OFFSET + NUM_CPU_STATS * core::mem::size_of::<u64>();
+
+/// The start of each CPU's per-CPU area, or null if it hasn't been initialized.
+static PER_CPU_AREAS: [AtomicPtr<AtomicU64>; MAX_CPUS] = {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const NULL: AtomicPtr<AtomicU64> = AtomicPtr::new(ptr::null_mut());
+    [NULL; MAX_CPUS]
+};
+
+/// The events counted for each CPU.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(usize)]
+pub enum Stat {
+    /// The number of interrupts handled.
+    Interrupts = 0,
+    /// The number of context switches.
+    ContextSwitches = 1,
+    /// The number of attempts to steal a task from another CPU's runqueue.
+    StealAttempts = 2,
+    /// The number of tasks on this CPU's runqueue that were stolen by other CPUs.
+    TasksStolen = 3,
+    /// The number of scheduler ticks, which the watchdog uses to detect stalled CPUs.
+    Heartbeats = 4,
+}
+
+impl Stat {
+    /// All counters, in order of their index into the statistics block.
+    pub const ALL: [Stat; 5] = [
+        Stat::Interrupts,
+        Stat::ContextSwitches,
+        Stat::StealAttempts,
+        Stat::TasksStolen,
+        Stat::Heartbeats,
+    ];
+
+    /// Returns the name of this counter.
+    pub fn name(self) -> &'static str {
+        match self {
+            Stat::Interrupts      => "Interrupts",
+            Stat::ContextSwitches => "ContextSwitches",
+            Stat::StealAttempts   => "StealAttempts",
+            Stat::TasksStolen     => "TasksStolen",
+            Stat::Heartbeats      => "Heartbeats",
+        }
+    }
+}
+
+const _: () = assert!(Stat::ALL.len() <= NUM_CPU_STATS);
+
+/// Creates the per-CPU area of the CPU with the given `cpu_id`, with all counters at zero,
+/// and installs it on the current CPU, which must be the CPU with the given `cpu_id`.
+///
+/// This must be invoked once on every CPU while it boots up, after its hardened CPU ID
+/// has been initialized via [`tls_initializer::init_hardened_cpu_id()`].
+/// Counter updates on that CPU before then are dropped.
+/// If that CPU already has a per-CPU area, e.g., because it was previously brought online,
+/// its existing counters are kept and that area is re-installed.
+pub fn init(cpu_id: u8) -> Result<(), &'static str> {
+    let area = match PER_CPU_AREAS[cpu_id as usize].load(Ordering::Acquire) {
+        area if area.is_null() => create_area(cpu_id)?,
+        area => area,
+    };
+    #[cfg(target_arch = "x86_64")]
+    tls_initializer::gs::init_kernel_gs_base(cpu_id, area as usize)?;
+    #[cfg(not(target_arch = "x86_64"))]
+    let _ = area;
+    Ok(())
+}
+
+/// Creates a new per-CPU area for the CPU with the given `cpu_id` and returns its area pointer.
+fn create_area(cpu_id: u8) -> Result<*mut AtomicU64, &'static str> {
+    let slot = &PER_CPU_AREAS[cpu_id as usize];
+    // The per-CPU area has no sections yet, only the reserved region with the statistics block.
+    let builder = AreaBuilder::<()>::new(AreaVariant::PointerAtStart, RESERVED_AREA_SIZE);
+    let image = builder.build_image(|_, _| { });
+    if image.len() < RESERVED_AREA_SIZE {
+        return Err("BUG: per-CPU area image is smaller than its reserved region");
+    }
+    // Copy the image into word-aligned storage, such that each counter can be accessed atomically.
+    let area: Box<[AtomicU64]> = image.chunks(core::mem::size_of::<u64>())
+        .map(|chunk| {
+            let mut bytes = [0u8; core::mem::size_of::<u64>()];
+            bytes[.. chunk.len()].copy_from_slice(chunk);
+            AtomicU64::new(u64::from_ne_bytes(bytes))
+        })
+        .collect();
+    let len = area.len();
+    // Per-CPU areas are never freed, because their address is held in a CPU register.
+    let area = Box::leak(area).as_mut_ptr();
+    match slot.compare_exchange(ptr::null_mut(), area, Ordering::AcqRel, Ordering::Acquire) {
+        Ok(_) => Ok(area),
+        Err(existing) => {
+            // SAFETY: `area` was leaked above and never shared, because another CPU won the race.
+            drop(unsafe { Box::from_raw(ptr::slice_from_raw_parts_mut(area, len)) });
+            Ok(existing)
+        }
+    }
+}
+
+/// Returns the given `stat` counter of the CPU with the given `cpu_id`, if that CPU has a per-CPU area.
+fn counter(cpu_id: u8, stat: Stat) -> Option<&'static AtomicU64> {
+    let area = PER_CPU_AREAS[cpu_id as usize].load(Ordering::Acquire);
+    // SAFETY: per-CPU areas are never freed, and every area contains the statistics block.
+    (!area.is_null()).then(|| unsafe { &*area.add(STATS_OFFSET / core::mem::size_of::<u64>() + stat as usize) })
+}
+
+/// Increments the given `stat` counter of the current CPU by one.
+#[inline(always)]
+pub fn incr(stat: Stat) {
+    add(stat, 1);
+}
+
+/// Adds `value` to the given `stat` counter of the current CPU.
+///
+/// This doesn't access the TLS register, so it can be used in any context,
+/// including interrupt handlers and the context switch path.
+/// It does nothing if [`init()`] hasn't yet been invoked on the current CPU.
+#[inline(always)]
+pub fn add(stat: Stat, value: u64) {
+    #[cfg(target_arch = "x86_64")] {
+        if !tls_initializer::gs::has_kernel_gs_base() {
+            return;
+        }
+        let offset = STATS_OFFSET + stat as usize * core::mem::size_of::<u64>();
+        // SAFETY: `init()` installed this CPU's per-CPU area as its `GS` base, as checked above,
+        //         and every per-CPU area contains the statistics block.
+        //         Only this CPU writes to its counters, and a single instruction cannot be torn by an interrupt.
+        unsafe {
+            core::arch::asm!(
+                "add qword ptr gs:[{offset}], {value}",
+                offset = in(reg) offset,
+                value = in(reg) value,
+                options(nostack),
+            );
+        }
+    }
+    #[cfg(not(target_arch = "x86_64"))] {
+        if let Some(counter) = tls_initializer::hardened_cpu_id().and_then(|cpu_id| counter(cpu_id, stat)) {
+            // Only the current CPU writes to its counters, so a separate load and store is sufficient.
+            counter.store(counter.load(Ordering::Relaxed).wrapping_add(value), Ordering::Relaxed);
+        }
+    }
+}
+
+/// Returns the value of the given `stat` counter of the CPU with the given `cpu_id`,
+/// or `None` if that CPU has no per-CPU area.
+pub fn get(cpu_id: u8, stat: Stat) -> Option<u64> {
+    counter(cpu_id, stat).map(|counter| counter.load(Ordering::Relaxed))
+}
+
+/// The counters of each CPU and their sums across all CPUs.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CpuStatsReport {
+    /// The ID and counters of each CPU that has a per-CPU area, in order of CPU ID.
+    pub per_cpu: Vec<(u8, [u64; NUM_CPU_STATS])>,
+    totals: [u64; NUM_CPU_STATS],
+}
+
+impl CpuStatsReport {
+    /// Returns the total value of the given `stat` counter across all CPUs.
+    pub fn total(&self, stat: Stat) -> u64 {
+        self.totals[stat as usize]
+    }
+}
+
+impl fmt::Display for CpuStatsReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:<5}", "CPU")?;
+        for stat in Stat::ALL {
+            write!(f, "  {:>16}", stat.name())?;
+        }
+        writeln!(f)?;
+        for (cpu_id, counters) in &self.per_cpu {
+            write!(f, "{:<5}", cpu_id)?;
+            for stat in Stat::ALL {
+                write!(f, "  {:>16}", counters[stat as usize])?;
+            }
+            writeln!(f)?;
+        }
+        write!(f, "{:<5}", "ALL")?;
+        for stat in Stat::ALL {
+            write!(f, "  {:>16}", self.total(stat))?;
+        }
+        writeln!(f)
+    }
+}
+
+/// Reads the counters in every CPU's per-CPU area and sums them into a system-wide report.
+///
+/// Counters of other CPUs are read while they may be updated,
+/// so the report is a best-effort snapshot.
+pub fn aggregate() -> CpuStatsReport {
+    let mut report = CpuStatsReport::default();
+    for cpu_id in 0 ..= u8::MAX {
+        if PER_CPU_AREAS[cpu_id as usize].load(Ordering::Acquire).is_null() {
+            continue;
+        }
+        let mut counters = [0; NUM_CPU_STATS];
+        for stat in Stat::ALL {
+            counters[stat as usize] = get(cpu_id, stat).unwrap_or(0);
+        }
+        for (total, value) in report.totals.iter_mut().zip(counters) {
+            *total = total.wrapping_add(value);
+        }
+        report.per_cpu.push((cpu_id, counters));
+    }
+    report
+}
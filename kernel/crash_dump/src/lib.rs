@@ -0,0 +1,261 @@
+//! Structured crash dumps, captured when a fault cannot be recovered from.
+//!
+//! A crash dump records the faulting CPU's registers, a backtrace obtained via the unwinder,
+//! the faulting task's TLS area and the used part of its stack,
+//! and the list of loaded crates with the addresses of their text sections,
+//! such that a crash can be analyzed post mortem without a live debugger.
+//!
+//! Each dump is written as text to the serial log and, once [`init()`] has reserved a memory region,
+//! to that region, from which the most recent dump can be read via [`last_dump()`].
+//! The region is allocated up front because the heap may be unusable after a fault.
+
+#![no_std]
+
+extern crate alloc;
+
+use alloc::string::String;
+use core::{fmt::{self, Write}, slice};
+use irq_safety::MutexIrqSafe;
+use log::{error, info};
+use memory::{MappedPages, PteFlags, VirtualAddress};
+use task::TaskRef;
+use x86_64::{
+    registers::control::{Cr0, Cr2, Cr3, Cr4},
+    structures::idt::InterruptStackFrame,
+};
+
+/// The first line of every crash dump, which includes the format version.
+pub const DUMP_HEADER: &str = "=== THESEUS CRASH DUMP v1 ===";
+/// The last line of every complete crash dump.
+pub const DUMP_FOOTER: &str = "=== END OF CRASH DUMP ===";
+
+/// The maximum number of bytes of the faulting task's stack included in a dump.
+const MAX_STACK_BYTES: usize = 2048;
+/// The maximum number of bytes of the faulting task's TLS area included in a dump.
+const MAX_TLS_BYTES: usize = 1024;
+/// The maximum number of stack frames included in a dump's backtrace.
+const MAX_BACKTRACE_FRAMES: usize = 64;
+
+/// The reserved memory region that crash dumps are written into, and the length of the most recent dump.
+static DUMP_REGION: MutexIrqSafe<Option<(MappedPages, usize)>> = MutexIrqSafe::new(None);
+
+/// Reserves a memory region of at least `size_in_bytes` into which crash dumps are written.
+///
+/// Dumps that exceed the region's size are truncated there, but are always logged in full.
+pub fn init(size_in_bytes: usize) -> Result<(), &'static str> {
+    let pages = memory::create_mapping(size_in_bytes, PteFlags::new().valid(true).writable(true))?;
+    info!("Reserved {} bytes for crash dumps at {:#X}", pages.size_in_bytes(), pages.start_address().value());
+    *DUMP_REGION.lock() = Some((pages, 0));
+    Ok(())
+}
+
+/// The machine state at the time of a fault.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct FaultInfo {
+    /// The number of the exception that caused the fault,
+    /// or `None` if the dump was requested by software.
+    pub exception_number: Option<u8>,
+    /// The error code pushed by the CPU for the exception, if any.
+    pub error_code: Option<u64>,
+    /// The address whose access caused the fault, e.g., for a page fault.
+    pub accessed_address: Option<usize>,
+    pub instruction_pointer: usize,
+    pub stack_pointer: usize,
+    pub rflags: u64,
+    pub code_segment: u64,
+    pub stack_segment: u64,
+}
+
+impl FaultInfo {
+    /// Creates a `FaultInfo` from the given exception and the stack frame pushed by the CPU.
+    pub fn from_exception(
+        exception_number: u8,
+        stack_frame: &InterruptStackFrame,
+        error_code: Option<u64>,
+        accessed_address: Option<usize>,
+    ) -> FaultInfo {
+        FaultInfo {
+            exception_number: Some(exception_number),
+            error_code,
+            accessed_address,
+            instruction_pointer: stack_frame.instruction_pointer.as_u64() as usize,
+            stack_pointer: stack_frame.stack_pointer.as_u64() as usize,
+            rflags: stack_frame.cpu_flags,
+            code_segment: stack_frame.code_segment,
+            stack_segment: stack_frame.stack_segment,
+        }
+    }
+}
+
+/// Captures a crash dump for the given fault, which occurred on the current CPU in the current task.
+///
+/// Returns the number of bytes of the dump that were stored in the reserved region,
+/// which is `0` if no region was reserved or if it is in use by a concurrent dump.
+pub fn capture(fault: &FaultInfo) -> usize {
+    // Log the fault first, in case capturing the rest of the dump faults again.
+    error!("Capturing crash dump for unrecoverable fault: {:X?}", fault);
+
+    // Don't wait for the region, as a fault may have occurred while it was locked.
+    let mut region = DUMP_REGION.try_lock();
+    let (buffer, last_len) = match region.as_deref_mut() {
+        Some(Some((pages, last_len))) => {
+            let size = pages.size_in_bytes();
+            (pages.as_slice_mut::<u8>(0, size).ok(), Some(last_len))
+        }
+        _ => (None, None),
+    };
+    let mut writer = DumpWriter { buffer, len: 0 };
+    // Writing to a `DumpWriter` never fails, it only truncates.
+    let _ = write_dump(&mut writer, fault);
+    if let Some(last_len) = last_len {
+        *last_len = writer.len;
+    }
+    writer.len
+}
+
+/// Returns the most recent crash dump stored in the reserved region, if any.
+pub fn last_dump() -> Option<String> {
+    let region = DUMP_REGION.lock();
+    let (pages, len) = region.as_ref()?;
+    if *len == 0 {
+        return None;
+    }
+    let bytes = pages.as_slice::<u8>(0, *len).ok()?;
+    Some(String::from_utf8_lossy(bytes).into_owned())
+}
+
+/// Writes a dump to the reserved region (if any) and to the serial log.
+struct DumpWriter<'b> {
+    buffer: Option<&'b mut [u8]>,
+    len: usize,
+}
+
+impl Write for DumpWriter<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        if let Some(buffer) = self.buffer.as_deref_mut() {
+            let end = buffer.len().min(self.len + s.len());
+            buffer[self.len..end].copy_from_slice(&s.as_bytes()[..end - self.len]);
+            self.len = end;
+        }
+        let _ = logger_x86_64::write_str(s);
+        Ok(())
+    }
+}
+
+fn write_dump(w: &mut DumpWriter, fault: &FaultInfo) -> fmt::Result {
+    let current_task = task::get_my_current_task();
+
+    writeln!(w, "\n{}", DUMP_HEADER)?;
+    match fault.exception_number {
+        Some(num) => writeln!(w, "exception: {:#X}", num)?,
+        None => writeln!(w, "exception: none (requested)")?,
+    }
+    if let Some(error_code) = fault.error_code {
+        writeln!(w, "error_code: {:#X}", error_code)?;
+    }
+    if let Some(addr) = fault.accessed_address {
+        writeln!(w, "accessed_address: {:#018X}", addr)?;
+    }
+    writeln!(w, "cpu: {}", cpu::current_cpu())?;
+    match &current_task {
+        Some(task) => writeln!(w, "task: {} {:?}", task.id, task.name)?,
+        None => writeln!(w, "task: none")?,
+    }
+
+    writeln!(w, "[registers]")?;
+    writeln!(w, "  rip:    {:#018X}", fault.instruction_pointer)?;
+    writeln!(w, "  rsp:    {:#018X}", fault.stack_pointer)?;
+    writeln!(w, "  rflags: {:#018X}", fault.rflags)?;
+    writeln!(w, "  cs:     {:#06X}", fault.code_segment)?;
+    writeln!(w, "  ss:     {:#06X}", fault.stack_segment)?;
+    writeln!(w, "  cr0:    {:#018X}", Cr0::read_raw())?;
+    writeln!(w, "  cr2:    {:#018X}", Cr2::read_raw())?;
+    writeln!(w, "  cr3:    {:#018X}", Cr3::read().0.start_address().as_u64())?;
+    writeln!(w, "  cr4:    {:#018X}", Cr4::read_raw())?;
+
+    write_backtrace(w)?;
+    if let Some(task) = &current_task {
+        write_tls_area(w, task)?;
+        write_stack(w, task, fault.stack_pointer)?;
+    }
+    write_crates(w, current_task.as_ref())?;
+    writeln!(w, "{}", DUMP_FOOTER)
+}
+
+fn write_backtrace(w: &mut DumpWriter) -> fmt::Result {
+    writeln!(w, "[backtrace]")?;
+    let mut result = Ok(());
+    let trace = stack_trace::stack_trace(
+        &mut |stack_frame, stack_frame_iter| {
+            let call_site = stack_frame.call_site_address() as usize;
+            let symbol = stack_frame_iter.namespace()
+                .get_section_containing_address(VirtualAddress::new_canonical(call_site), false);
+            result = match symbol {
+                Some((section, offset)) => writeln!(w, "  {:#018X} {} + {:#X}", call_site, section.name, offset),
+                None => writeln!(w, "  {:#018X} ??", call_site),
+            };
+            result.is_ok()
+        },
+        Some(MAX_BACKTRACE_FRAMES),
+    );
+    result?;
+    if let Err(e) = trace {
+        writeln!(w, "  incomplete: {}", e)?;
+    }
+    Ok(())
+}
+
+fn write_tls_area(w: &mut DumpWriter, task: &TaskRef) -> fmt::Result {
+    let (base, size) = (task.tls_area_base(), task.tls_area_size());
+    writeln!(w, "[tls_area] base: {:#018X}, size: {:#X}", base, size)?;
+    if base != 0 {
+        // SAFE: a task's TLS area remains mapped for as long as the task exists.
+        let bytes = unsafe { slice::from_raw_parts(base as *const u8, size.min(MAX_TLS_BYTES)) };
+        write_hex(w, base, bytes)?;
+    }
+    Ok(())
+}
+
+fn write_stack(w: &mut DumpWriter, task: &TaskRef, stack_pointer: usize) -> fmt::Result {
+    let (bottom, top) = task.with_kstack(|kstack| (kstack.bottom().value(), kstack.top_usable().value()));
+    writeln!(w, "[stack] bottom: {:#018X}, top: {:#018X}", bottom, top)?;
+    if stack_pointer < bottom || stack_pointer > top {
+        return writeln!(w, "  rsp is outside of the task's stack");
+    }
+    let len = (top - stack_pointer).min(MAX_STACK_BYTES);
+    // SAFE: the range lies within the task's stack, which is mapped.
+    let bytes = unsafe { slice::from_raw_parts(stack_pointer as *const u8, len) };
+    write_hex(w, stack_pointer, bytes)
+}
+
+fn write_crates(w: &mut DumpWriter, task: Option<&TaskRef>) -> fmt::Result {
+    writeln!(w, "[crates]")?;
+    let namespace = match task {
+        Some(task) => task.get_namespace(),
+        None => match mod_mgmt::get_initial_kernel_namespace() {
+            Some(namespace) => namespace,
+            None => return writeln!(w, "  no namespace"),
+        },
+    };
+    let mut result = Ok(());
+    namespace.for_each_crate(true, |name, crate_ref| {
+        result = match crate_ref.lock_as_ref().text_pages.as_ref() {
+            Some((_, range)) => writeln!(w, "  {:#018X}..{:#018X} {}", range.start.value(), range.end.value(), name),
+            None => writeln!(w, "  {:18}  {:18} {}", "-", "-", name),
+        };
+        result.is_ok()
+    });
+    result
+}
+
+/// Writes the given `bytes` as hexadecimal, 16 per line, prefixed with their address.
+fn write_hex(w: &mut DumpWriter, start_address: usize, bytes: &[u8]) -> fmt::Result {
+    for (i, line) in bytes.chunks(16).enumerate() {
+        write!(w, "  {:#018X}:", start_address + i * 16)?;
+        for byte in line {
+            write!(w, " {:02X}", byte)?;
+        }
+        writeln!(w)?;
+    }
+    Ok(())
+}
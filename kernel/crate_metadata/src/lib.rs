@@ -45,7 +45,7 @@
 
 extern crate alloc;
 
-use core::{convert::TryFrom, mem::size_of};
+use core::mem::size_of;
 use core::fmt;
 use core::ops::Range;
 use log::{error, debug, trace};
@@ -66,6 +66,8 @@ use fs_node::{FileRef, WeakFileRef};
 use hashbrown::HashMap;
 use goblin::elf::reloc::*;
 
+mod tls_relocation;
+
 pub use str_ref::StrRef;
 pub use crate_metadata_serde::{
     SectionType,
@@ -307,6 +309,13 @@ impl LoadedCrate {
             .filter_map(move |shndx| self.sections.get(shndx))
     }
 
+    /// A convenience function to iterate over only the TLS (`.tdata` and `.tbss`) sections in this crate.
+    pub fn tls_sections_iter(&self) -> impl Iterator<Item = &StrongSectionRef> {
+        self.tls_sections
+            .iter()
+            .filter_map(move |shndx| self.sections.get(shndx))
+    }
+
     /// A convenience function to iterate over only the global (public) sections in this crate.
     pub fn global_sections_iter(&self) -> impl Iterator<Item = &StrongSectionRef> {
         self.global_sections
@@ -997,6 +1006,12 @@ impl RelocationEntry {
     pub fn is_absolute(&self) -> bool {
         matches!(self.typ, R_X86_64_32 | R_X86_64_64)
     }
+
+    /// Returns true if this relocation is for a direct call to a function,
+    /// which can be redirected through a stub without changing the call's semantics.
+    pub fn is_function_call(&self) -> bool {
+        self.typ == R_X86_64_PLT32
+    }
 }
 
 
@@ -1037,6 +1052,10 @@ pub fn write_relocation(
     source_sec_vaddr: VirtualAddress,
     verbose_log: bool
 ) -> Result<(), &'static str> {
+    if fault_injection::should_fail(fault_injection::FaultPoint::Relocation) {
+        return Err(fault_injection::FaultPoint::Relocation.error());
+    }
+
     // Calculate exactly where we should write the relocation data to.
     let target_sec_offset = target_sec_offset + relocation_entry.offset;
 
@@ -1073,34 +1092,19 @@ pub fn write_relocation(
             if verbose_log { trace!("                    target_ptr: {:p}, source_val: {:#X} (from source_sec_vaddr {:#X})", target_ref.as_ptr(), source_val, source_sec_vaddr); }
             target_ref.copy_from_slice(&source_val.to_ne_bytes());
         }
-        R_X86_64_TPOFF32 => {
-            let target_range = target_sec_offset .. (target_sec_offset + size_of::<i32>());
-            let target_ref = &mut target_sec_slice[target_range];
-            // Here we treat the `source_sec_vaddr` value as a signed value 
-            // by casting its bit value directly, i.e., `usize as isize`.
-            let offset_val = source_sec_vaddr.value() as isize;
-            // Now we must check that the signed `offset_val` fits in `i32`
-            let source_val = i32::try_from(offset_val)
-                .map_err(|_| "BUG: TLS relocation (R_X86_64_TPOFF32) source section value (TLS offset) cannot fit in a `i32`")?;
-            if verbose_log { trace!("                    target_ptr: {:p}, source_val: {:#X} (from source_sec_vaddr {:#X})", target_ref.as_ptr(), source_val, source_sec_vaddr); }
-            target_ref.copy_from_slice(&source_val.to_ne_bytes());
+        _ if relocation_entry.is_tls() => {
+            tls_relocation::write_tls_relocation(relocation_entry, target_sec_slice, target_sec_offset, source_sec_vaddr, verbose_log)?;
         }
-        // R_X86_64_GOTTPOFF => {
-        //     // 32-bit signed PC-relative offset to the GOT entry for the IE (Initial Exec(utable) TLS model))
-        //     debug!("R_X86_64_GOTTPOFF: {:#X?}", relocation_entry);
-        //     debug!("R_X86_64_GOTTPOFF: target: {:#X}, source: {:#X}", target_sec_slice.as_ptr() as usize + target_sec_offset, source_sec_vaddr);
-        //     unimplemented!()
-        // }
         // R_X86_64_GOTPCREL => { 
         //     unimplemented!(); // if we stop using the large code model, we need to create a Global Offset Table
         // }
         _ => {
             error!("found unsupported relocation type {}\n    \
-                --> Are you compiling crates with 'code-model=large' and 'tls-model=local-exec'?",
+                --> Are you compiling crates with 'code-model=large'?",
                 relocation_entry.typ
             );
             return Err("found unsupported relocation type. \
-                Are you compiling crates with 'code-model=large' and 'tls-model=local-exec'?"
+                Are you compiling crates with 'code-model=large'?"
             );
         }
     }
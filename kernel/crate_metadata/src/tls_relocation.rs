@@ -0,0 +1,309 @@
+//! Support for writing thread-local storage (TLS) relocations under every TLS access model.
+//!
+//! Theseus places all TLS sections into a single TLS area at fixed offsets from the TLS self pointer,
+//! and doesn't create a Global Offset Table (GOT) or TLS descriptors for dynamically-loaded crates.
+//! Thus, like a static linker, we "relax" the code sequences emitted for the general-dynamic,
+//! local-dynamic, initial-exec, and TLS descriptor models into equivalent local-exec sequences,
+//! in which the TLS variable's offset is an immediate value.
+//! Relaxing a code sequence is idempotent, so these relocations can be safely re-written,
+//! e.g., when swapping crates.
+//!
+//! The source value of a TLS relocation is the TLS variable's offset from the TLS self pointer,
+//! except for module ID relocations (see [`RelocationEntry::is_tls_module_id()`]),
+//! whose source value is the TLS module ID.
+//! Because the entire TLS area is a single module based at the TLS self pointer,
+//! module-relative offsets (`DTPOFF`/`DTPREL`) are the same as the local-exec offsets.
+
+use core::{convert::{TryFrom, TryInto}, mem::size_of};
+use goblin::elf::reloc::*;
+use log::trace;
+use memory::VirtualAddress;
+use super::RelocationEntry;
+
+// AArch64 TLS relocation types, as defined by the "ELF for the Arm 64-bit Architecture" ABI.
+const R_AARCH64_TLSIE_ADR_GOTTPREL_PAGE21:   u32 = 541;
+const R_AARCH64_TLSIE_LD64_GOTTPREL_LO12_NC: u32 = 542;
+const R_AARCH64_TLSLE_MOVW_TPREL_G2:         u32 = 544;
+const R_AARCH64_TLSLE_MOVW_TPREL_G1:         u32 = 545;
+const R_AARCH64_TLSLE_MOVW_TPREL_G1_NC:      u32 = 546;
+const R_AARCH64_TLSLE_MOVW_TPREL_G0:         u32 = 547;
+const R_AARCH64_TLSLE_MOVW_TPREL_G0_NC:      u32 = 548;
+const R_AARCH64_TLSLE_ADD_TPREL_HI12:        u32 = 549;
+const R_AARCH64_TLSLE_ADD_TPREL_LO12:        u32 = 550;
+const R_AARCH64_TLSLE_ADD_TPREL_LO12_NC:     u32 = 551;
+const R_AARCH64_TLSLE_LDST8_TPREL_LO12:      u32 = 552;
+const R_AARCH64_TLSLE_LDST8_TPREL_LO12_NC:   u32 = 553;
+const R_AARCH64_TLSLE_LDST16_TPREL_LO12:     u32 = 554;
+const R_AARCH64_TLSLE_LDST16_TPREL_LO12_NC:  u32 = 555;
+const R_AARCH64_TLSLE_LDST32_TPREL_LO12:     u32 = 556;
+const R_AARCH64_TLSLE_LDST32_TPREL_LO12_NC:  u32 = 557;
+const R_AARCH64_TLSLE_LDST64_TPREL_LO12:     u32 = 558;
+const R_AARCH64_TLSLE_LDST64_TPREL_LO12_NC:  u32 = 559;
+const R_AARCH64_TLSDESC_ADR_PAGE21:          u32 = 562;
+const R_AARCH64_TLSDESC_LD64_LO12:           u32 = 563;
+const R_AARCH64_TLSDESC_ADD_LO12:            u32 = 564;
+const R_AARCH64_TLSDESC_CALL:                u32 = 569;
+const R_AARCH64_TLSLE_LDST128_TPREL_LO12:    u32 = 570;
+const R_AARCH64_TLSLE_LDST128_TPREL_LO12_NC: u32 = 571;
+const R_AARCH64_TLS_DTPMOD64:                u32 = 1028;
+const R_AARCH64_TLS_DTPREL64:                u32 = 1029;
+const R_AARCH64_TLS_TPREL64:                 u32 = 1030;
+
+/// The relaxed local-exec replacement for the x86_64 general-dynamic code sequence:
+/// `movq %fs:0, %rax; leaq <offset>(%rax), %rax`, with the 32-bit offset at index 12.
+const X86_64_GD_TO_LE: [u8; 16] = [0x64, 0x48, 0x8b, 0x04, 0x25, 0, 0, 0, 0, 0x48, 0x8d, 0x80, 0, 0, 0, 0];
+/// The relaxed local-exec replacement for the x86_64 local-dynamic code sequence:
+/// `data16 data16 data16 movq %fs:0, %rax`.
+const X86_64_LD_TO_LE: [u8; 12] = [0x66, 0x66, 0x66, 0x64, 0x48, 0x8b, 0x04, 0x25, 0, 0, 0, 0];
+
+const AARCH64_NOP:  u32 = 0xd503_201f;
+/// `movz xd, #imm16` (64-bit).
+const AARCH64_MOVZ: u32 = 0xd280_0000;
+/// `movn xd, #imm16` (64-bit).
+const AARCH64_MOVN: u32 = 0x9280_0000;
+/// `movk xd, #imm16` (64-bit).
+const AARCH64_MOVK: u32 = 0xf280_0000;
+/// The bits that select between `movn`, `movz`, and `movk`.
+const AARCH64_MOVW_OPC_MASK: u32 = 0x6000_0000;
+/// The `hw` field value of a move wide instruction that shifts its immediate left by 16 bits.
+const AARCH64_MOVW_LSL_16: u32 = 1 << 21;
+const AARCH64_IMM16_MASK: u32 = 0xffff << 5;
+const AARCH64_IMM12_MASK: u32 = 0xfff << 10;
+const AARCH64_RD_MASK: u32 = 0x1f;
+
+impl RelocationEntry {
+    /// Returns true if this is a TLS relocation whose source value is the TLS module ID
+    /// rather than the offset of a TLS variable.
+    pub fn is_tls_module_id(&self) -> bool {
+        matches!(self.typ, R_X86_64_DTPMOD64 | R_AARCH64_TLS_DTPMOD64)
+    }
+
+    /// Returns true if this is a TLS relocation of any TLS access model.
+    pub fn is_tls(&self) -> bool {
+        matches!(self.typ,
+            R_X86_64_DTPMOD64 | R_X86_64_DTPOFF64 | R_X86_64_TPOFF64 | R_X86_64_TLSGD |
+            R_X86_64_TLSLD | R_X86_64_DTPOFF32 | R_X86_64_GOTTPOFF | R_X86_64_TPOFF32 |
+            R_AARCH64_TLSIE_ADR_GOTTPREL_PAGE21 ..= R_AARCH64_TLSIE_LD64_GOTTPREL_LO12_NC |
+            R_AARCH64_TLSLE_MOVW_TPREL_G2 ..= R_AARCH64_TLSLE_LDST64_TPREL_LO12_NC |
+            R_AARCH64_TLSDESC_ADR_PAGE21 ..= R_AARCH64_TLSDESC_ADD_LO12 | R_AARCH64_TLSDESC_CALL |
+            R_AARCH64_TLSLE_LDST128_TPREL_LO12 | R_AARCH64_TLSLE_LDST128_TPREL_LO12_NC |
+            R_AARCH64_TLS_DTPMOD64 ..= R_AARCH64_TLS_TPREL64
+        )
+    }
+
+    /// Returns the offset (from the start of the target section) of the call to `__tls_get_addr()`
+    /// that is removed when the code sequence containing this relocation is relaxed, if any.
+    ///
+    /// The relocation entry for that call must be skipped, as it would overwrite the relaxed code.
+    pub fn relaxed_tls_call_offset(&self) -> Option<usize> {
+        match self.typ {
+            // `leaq x@tlsgd(%rip), %rdi` is followed by 4 bytes of prefixes and the call opcode.
+            R_X86_64_TLSGD => Some(self.offset + 8),
+            // `leaq x@tlsld(%rip), %rdi` is directly followed by the call opcode.
+            R_X86_64_TLSLD => Some(self.offset + 5),
+            _ => None,
+        }
+    }
+}
+
+/// Writes the given TLS relocation, which must be one for which [`RelocationEntry::is_tls()`] is true,
+/// relaxing its code sequence into a local-exec one if necessary.
+///
+/// The arguments are the same as [`write_relocation()`](super::write_relocation),
+/// except that the `target_sec_offset` already includes the relocation entry's offset.
+pub(crate) fn write_tls_relocation(
+    relocation_entry: RelocationEntry,
+    target_sec_slice: &mut [u8],
+    target_sec_offset: usize,
+    source_sec_vaddr: VirtualAddress,
+    verbose_log: bool,
+) -> Result<(), &'static str> {
+    let source = source_sec_vaddr.value();
+    // The offset of the TLS variable, including the addend. This is negative for static TLS sections.
+    let offset = source.wrapping_add(relocation_entry.addend) as isize as i64;
+    // The code sequences relaxed below use PC-relative addends that aren't part of the variable's offset.
+    let pc_relative_offset = || i32::try_from(source as isize)
+        .map_err(|_| "BUG: TLS relocation source value (TLS offset) cannot fit in a `i32`");
+    if verbose_log { trace!("                    TLS relocation {:?}, source_sec_vaddr: {:#X}", relocation_entry, source_sec_vaddr); }
+
+    match relocation_entry.typ {
+        R_X86_64_DTPMOD64 | R_AARCH64_TLS_DTPMOD64 => {
+            write_bytes(target_sec_slice, target_sec_offset, &(source as u64).to_ne_bytes())
+        }
+        R_X86_64_TPOFF64 | R_X86_64_DTPOFF64 | R_AARCH64_TLS_TPREL64 | R_AARCH64_TLS_DTPREL64 => {
+            write_bytes(target_sec_slice, target_sec_offset, &offset.to_ne_bytes())
+        }
+        R_X86_64_DTPOFF32 => {
+            let value = i32::try_from(offset)
+                .map_err(|_| "BUG: TLS relocation (R_X86_64_DTPOFF32) TLS offset cannot fit in a `i32`")?;
+            write_bytes(target_sec_slice, target_sec_offset, &value.to_ne_bytes())
+        }
+        R_X86_64_TPOFF32 => {
+            // Here we treat the `source_sec_vaddr` value as a signed value
+            // by casting its bit value directly, i.e., `usize as isize`.
+            let value = i32::try_from(source as isize)
+                .map_err(|_| "BUG: TLS relocation (R_X86_64_TPOFF32) source section value (TLS offset) cannot fit in a `i32`")?;
+            write_bytes(target_sec_slice, target_sec_offset, &value.to_ne_bytes())
+        }
+        R_X86_64_GOTTPOFF => relax_x86_64_initial_exec(target_sec_slice, target_sec_offset, pc_relative_offset()?),
+        R_X86_64_TLSGD => {
+            // The relocation refers to the 32-bit displacement of the `leaq`, which starts 4 bytes into the sequence.
+            let start = target_sec_offset.checked_sub(4).ok_or("R_X86_64_TLSGD relocation is out of bounds")?;
+            const ORIGINAL_LEAQ: [u8; 4] = [0x66, 0x48, 0x8d, 0x3d];
+            const ORIGINAL_CALL: [u8; 4] = [0x66, 0x66, 0x48, 0xe8];
+            let sequence = target_sec_slice.get_mut(start .. start + X86_64_GD_TO_LE.len())
+                .ok_or("R_X86_64_TLSGD relocation is out of bounds")?;
+            let is_original = sequence[..4] == ORIGINAL_LEAQ && sequence[8..12] == ORIGINAL_CALL;
+            if !is_original && sequence[..12] != X86_64_GD_TO_LE[..12] {
+                return Err("unsupported code sequence for R_X86_64_TLSGD relocation");
+            }
+            sequence.copy_from_slice(&X86_64_GD_TO_LE);
+            sequence[12..].copy_from_slice(&pc_relative_offset()?.to_ne_bytes());
+            Ok(())
+        }
+        R_X86_64_TLSLD => {
+            // The relocation refers to the 32-bit displacement of the `leaq`, which starts 3 bytes into the sequence.
+            let start = target_sec_offset.checked_sub(3).ok_or("R_X86_64_TLSLD relocation is out of bounds")?;
+            let sequence = target_sec_slice.get_mut(start .. start + X86_64_LD_TO_LE.len())
+                .ok_or("R_X86_64_TLSLD relocation is out of bounds")?;
+            let is_original = sequence[..3] == [0x48, 0x8d, 0x3d] && sequence[7] == 0xe8;
+            if !is_original && *sequence != X86_64_LD_TO_LE {
+                return Err("unsupported code sequence for R_X86_64_TLSLD relocation");
+            }
+            sequence.copy_from_slice(&X86_64_LD_TO_LE);
+            Ok(())
+        }
+
+        R_AARCH64_TLSLE_MOVW_TPREL_G2    => update_insn(target_sec_slice, target_sec_offset, |insn| movw(insn, offset, 32, true)),
+        R_AARCH64_TLSLE_MOVW_TPREL_G1    => update_insn(target_sec_slice, target_sec_offset, |insn| movw(insn, offset, 16, true)),
+        R_AARCH64_TLSLE_MOVW_TPREL_G1_NC => update_insn(target_sec_slice, target_sec_offset, |insn| movw(insn, offset, 16, false)),
+        R_AARCH64_TLSLE_MOVW_TPREL_G0    => update_insn(target_sec_slice, target_sec_offset, |insn| movw(insn, offset, 0, true)),
+        R_AARCH64_TLSLE_MOVW_TPREL_G0_NC => update_insn(target_sec_slice, target_sec_offset, |insn| movw(insn, offset, 0, false)),
+        R_AARCH64_TLSLE_ADD_TPREL_HI12 => update_insn(target_sec_slice, target_sec_offset, |insn| {
+            if !(0 .. 1 << 24).contains(&offset) {
+                return Err("TLS offset is out of range for R_AARCH64_TLSLE_ADD_TPREL_HI12 relocation");
+            }
+            Ok(set_imm12(insn, (offset >> 12) as u32))
+        }),
+        R_AARCH64_TLSLE_ADD_TPREL_LO12 | R_AARCH64_TLSLE_ADD_TPREL_LO12_NC => update_insn(target_sec_slice, target_sec_offset, |insn| {
+            if relocation_entry.typ == R_AARCH64_TLSLE_ADD_TPREL_LO12 && !(0 .. 1 << 12).contains(&offset) {
+                return Err("TLS offset is out of range for R_AARCH64_TLSLE_ADD_TPREL_LO12 relocation");
+            }
+            Ok(set_imm12(insn, offset as u32))
+        }),
+        R_AARCH64_TLSLE_LDST8_TPREL_LO12   ..= R_AARCH64_TLSLE_LDST64_TPREL_LO12_NC |
+        R_AARCH64_TLSLE_LDST128_TPREL_LO12 |   R_AARCH64_TLSLE_LDST128_TPREL_LO12_NC => {
+            let (scale, checked) = match relocation_entry.typ {
+                R_AARCH64_TLSLE_LDST128_TPREL_LO12 => (4, true),
+                R_AARCH64_TLSLE_LDST128_TPREL_LO12_NC => (4, false),
+                typ => ((typ - R_AARCH64_TLSLE_LDST8_TPREL_LO12) / 2, (typ - R_AARCH64_TLSLE_LDST8_TPREL_LO12) % 2 == 0),
+            };
+            update_insn(target_sec_slice, target_sec_offset, |insn| {
+                if checked && !(0 .. 1 << 12).contains(&offset) {
+                    return Err("TLS offset is out of range for R_AARCH64_TLSLE_LDST*_TPREL_LO12 relocation");
+                }
+                if offset & ((1 << scale) - 1) != 0 {
+                    return Err("TLS offset is misaligned for R_AARCH64_TLSLE_LDST*_TPREL_LO12 relocation");
+                }
+                Ok(set_imm12(insn, (offset & 0xfff) as u32 >> scale))
+            })
+        }
+
+        // Initial-exec: `adrp xd, :gottprel:x; ldr xd, [xd, :gottprel_lo12:x]`
+        // is relaxed into `movz/movn xd, #:tprel_g1:x, lsl #16; movk xd, #:tprel_g0_nc:x`.
+        // TLS descriptors: `adrp x0, :tlsdesc:x; ldr x1, [x0, :tlsdesc_lo12:x]; add x0, x0, :tlsdesc_lo12:x; blr x1`
+        // is relaxed into `movz/movn x0, #:tprel_g1:x, lsl #16; movk x0, #:tprel_g0_nc:x; nop; nop`.
+        R_AARCH64_TLSIE_ADR_GOTTPREL_PAGE21 | R_AARCH64_TLSDESC_ADR_PAGE21 => {
+            let offset = i32::try_from(offset).map_err(|_| "TLS offset cannot fit in a `i32` for relaxed aarch64 TLS relocation")?;
+            update_insn(target_sec_slice, target_sec_offset, |insn| {
+                let rd = insn & AARCH64_RD_MASK;
+                Ok(if offset < 0 {
+                    AARCH64_MOVN | AARCH64_MOVW_LSL_16 | ((!offset as u32 >> 16) << 5) | rd
+                } else {
+                    AARCH64_MOVZ | AARCH64_MOVW_LSL_16 | ((offset as u32 >> 16) << 5) | rd
+                })
+            })
+        }
+        R_AARCH64_TLSIE_LD64_GOTTPREL_LO12_NC | R_AARCH64_TLSDESC_LD64_LO12 => update_insn(target_sec_slice, target_sec_offset, |insn| {
+            let is_movk = insn & 0xffe0_0000 == AARCH64_MOVK;
+            // The descriptor is loaded into `x1` from `[x0, ...]`, but the offset must end up in `x0`.
+            let rd = if relocation_entry.typ == R_AARCH64_TLSDESC_LD64_LO12 && !is_movk {
+                (insn >> 5) & AARCH64_RD_MASK
+            } else {
+                insn & AARCH64_RD_MASK
+            };
+            Ok(AARCH64_MOVK | (((offset as u32) & 0xffff) << 5) | rd)
+        }),
+        R_AARCH64_TLSDESC_ADD_LO12 | R_AARCH64_TLSDESC_CALL => {
+            update_insn(target_sec_slice, target_sec_offset, |_| Ok(AARCH64_NOP))
+        }
+
+        _ => Err("BUG: write_tls_relocation(): not a TLS relocation"),
+    }
+}
+
+/// Relaxes the x86_64 initial-exec instruction that loads the TLS offset from the GOT,
+/// `movq x@gottpoff(%rip), %reg` or `addq x@gottpoff(%rip), %reg`,
+/// into `movq $offset, %reg` or `addq $offset, %reg`, respectively.
+fn relax_x86_64_initial_exec(target_sec_slice: &mut [u8], target_sec_offset: usize, offset: i32) -> Result<(), &'static str> {
+    // The relocation refers to the 32-bit displacement after the REX prefix, opcode, and ModRM bytes.
+    let start = target_sec_offset.checked_sub(3).ok_or("R_X86_64_GOTTPOFF relocation is out of bounds")?;
+    let insn = target_sec_slice.get_mut(start .. start + 7).ok_or("R_X86_64_GOTTPOFF relocation is out of bounds")?;
+    let (rex, opcode, modrm) = (insn[0], insn[1], insn[2]);
+    if rex & 0xfa != 0x48 {
+        return Err("unsupported code sequence for R_X86_64_GOTTPOFF relocation");
+    }
+    // The register moves from the ModRM `reg` field (extended by REX.R) to the `rm` field (extended by REX.B).
+    let (new_opcode, reg, rex_b) = match opcode {
+        0x8b | 0x03 if modrm & 0xc7 == 0x05 => (if opcode == 0x8b { 0xc7 } else { 0x81 }, (modrm >> 3) & 0x7, (rex >> 2) & 0x1),
+        0xc7 | 0x81 if modrm & 0xf8 == 0xc0 => (opcode, modrm & 0x7, rex & 0x1),
+        _ => return Err("unsupported code sequence for R_X86_64_GOTTPOFF relocation"),
+    };
+    insn[0] = 0x48 | rex_b;
+    insn[1] = new_opcode;
+    insn[2] = 0xc0 | reg;
+    insn[3..].copy_from_slice(&offset.to_ne_bytes());
+    Ok(())
+}
+
+/// Returns the given aarch64 move wide instruction with its immediate set to
+/// the 16 bits of the given `value` starting at bit `shift`.
+///
+/// If `checked`, the instruction is converted into a `movz` or a `movn` (for negative values),
+/// and the `value` must fit into the bits up to and including those 16 bits.
+fn movw(insn: u32, value: i64, shift: u32, checked: bool) -> Result<u32, &'static str> {
+    if !checked {
+        return Ok((insn & !AARCH64_IMM16_MASK) | ((((value >> shift) as u32) & 0xffff) << 5));
+    }
+    let limit = 1i64 << (shift + 16);
+    if !(-limit .. limit).contains(&value) {
+        return Err("TLS offset is out of range for R_AARCH64_TLSLE_MOVW_TPREL relocation");
+    }
+    let (opc, imm) = if value < 0 { (0, !value) } else { (0x4000_0000, value) };
+    Ok((insn & !(AARCH64_MOVW_OPC_MASK | AARCH64_IMM16_MASK)) | opc | ((((imm >> shift) as u32) & 0xffff) << 5))
+}
+
+/// Returns the given aarch64 instruction with its 12-bit immediate set to the low 12 bits of `imm`.
+fn set_imm12(insn: u32, imm: u32) -> u32 {
+    (insn & !AARCH64_IMM12_MASK) | ((imm & 0xfff) << 10)
+}
+
+/// Replaces the aarch64 instruction at the given offset with the result of `f`.
+fn update_insn(
+    target_sec_slice: &mut [u8],
+    target_sec_offset: usize,
+    f: impl FnOnce(u32) -> Result<u32, &'static str>,
+) -> Result<(), &'static str> {
+    let target_ref = target_sec_slice.get_mut(target_sec_offset .. target_sec_offset + size_of::<u32>())
+        .ok_or("aarch64 TLS relocation is out of bounds")?;
+    let insn = u32::from_le_bytes(target_ref.try_into().unwrap());
+    target_ref.copy_from_slice(&f(insn)?.to_le_bytes());
+    Ok(())
+}
+
+fn write_bytes(target_sec_slice: &mut [u8], target_sec_offset: usize, bytes: &[u8]) -> Result<(), &'static str> {
+    target_sec_slice.get_mut(target_sec_offset .. target_sec_offset + bytes.len())
+        .ok_or("TLS relocation is out of bounds")?
+        .copy_from_slice(bytes);
+    Ok(())
+}
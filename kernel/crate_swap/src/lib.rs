@@ -15,6 +15,8 @@ extern crate fs_node;
 extern crate qp_trie;
 extern crate path;
 extern crate by_address;
+extern crate task;
+extern crate task_events;
 
 #[cfg(loscd_eval)]
 extern crate hpet;
@@ -40,13 +42,18 @@ use mod_mgmt::{
     IntoCrateObjectFile,
     write_relocation,
     crate_name_from_path,
+    CRATE_HASH_DELIMITER,
     replace_containing_crate_name,
     StrongSectionRef,
+    TlsSectionReplacement,
     WeakDependent, StrRef,
 };
 use path::Path;
 use by_address::ByAddress;
 
+pub mod static_tls;
+pub mod transfer;
+
 
 lazy_static! {
     /// The set of crates that have been previously unloaded (e.g., swapped out) from a `CrateNamespace`.
@@ -84,6 +91,19 @@ pub type StateTransferFunction = fn(&Arc<CrateNamespace>, &CrateNamespace) -> Re
 /// 4) Remove crate `C` and clean it up, e.g., removing its entries from the symbol map.
 ///    Save the removed crate (and its symbol subtrie) in a cache for later use to expedite future swapping operations.
 /// 
+/// If crate `C` contains TLS sections (`.tdata`/`.tbss`), each TLS section in `C2` is placed at the same offset
+/// as the corresponding TLS section in `C` if they're the same size, such that every task's live value
+/// is used by `C2` as-is. If the TLS layout changes otherwise, e.g., because `C2` has other TLS sections,
+/// every task's TLS area must be replaced, carrying over the values of any corresponding TLS sections from `C`.
+/// Since a task that has started running may hold references into its TLS area, such a swap fails
+/// before any old crate is modified unless every task has either not yet run or already exited;
+/// see [`task::ensure_tls_areas_replaceable()`].
+/// Crates with TLS sections are never saved in the cache of swapped-out crates.
+/// 
+/// Before any old crate is modified, the state transfer functions registered for the old crates
+/// via [`transfer::register_transfer_function()`] migrate their state to the new crates,
+/// and the swap is aborted if any of them fails; see the [`transfer`] module for more.
+/// 
 /// The given `CrateNamespace` is used as the backup namespace for resolving unknown symbols,
 /// in adddition to any recursive namespaces on which this namespace depends.
 /// 
@@ -144,6 +164,14 @@ pub fn swap_crates(
     let hpet = hpet::get_hpet().ok_or("couldn't get HPET timer")?;
     #[cfg(loscd_eval)]
     let hpet_start_swap = hpet.get_counter();
+
+    // The TLS sections of the old crates, which should be replaced in place by the new crates' TLS sections.
+    let tls_replacements = tls_section_replacements(&swap_requests);
+    // Old crates' TLS sections are removed from the TLS initializer, so they cannot be swapped back in from the cache.
+    let cache_old_crates = cache_old_crates && tls_replacements.is_empty();
+
+    // Let tasks that registered for it know that crates are about to be swapped.
+    let _ = task_events::broadcast(task_events::Event::CrateSwapPending);
     
     let (namespace_of_new_crates, is_optimized) = {
         #[cfg(not(loscd_eval))] {
@@ -167,6 +195,7 @@ pub fn swap_crates(
                         .and_then(|ocn| swap_req.old_namespace.get_crate(ocn))
                         .map(|_old_loaded_crate| swap_req.new_crate_object_file.deref())
                 });
+                nn.replace_tls_sections_on_load(tls_replacements);
                 nn.load_crates(crate_file_iter, Some(this_namespace), kernel_mmi_ref, verbose_log)?;
                 (nn, false)
             }
@@ -184,6 +213,7 @@ pub fn swap_crates(
                     .and_then(|ocn| swap_req.old_namespace.get_crate(ocn))
                     .map(|_old_loaded_crate| swap_req.new_crate_object_file.deref())
             });
+            nn.replace_tls_sections_on_load(tls_replacements);
             nn.load_crates(crate_file_iter, Some(this_namespace), kernel_mmi_ref, verbose_log)?;
             (nn, false)
         }
@@ -221,6 +251,28 @@ pub fn swap_crates(
     let mut new_crate_names: Vec<String> = Vec::with_capacity(swap_requests.len());
     // Whether the old crate was actually loaded into the old namespace. There is one entry per swap request.
     let mut old_crates_are_loaded: Vec<bool> = Vec::with_capacity(swap_requests.len());
+    // Pairs of corresponding TLS sections from the old crates and the new crates, whose values should be carried over.
+    let mut replaced_tls_sections: Vec<(StrongSectionRef, StrongSectionRef)> = Vec::new();
+    // The old crates' TLS sections that weren't replaced in place, which must be removed from the TLS initializer.
+    let mut old_tls_sections_to_remove: Vec<StrongSectionRef> = Vec::new();
+    // Whether any TLS sections were added or removed, which requires refreshing every task's TLS area.
+    let mut tls_layout_changed = false;
+
+    // Moving a task's TLS area would leave dangling any references to its TLS variables that the task holds,
+    // so a swap that changes the TLS layout is only permitted if no task's TLS area would need to be moved.
+    let (tls_layout_will_change, relocated_tls_sections) = new_tls_layout(&swap_requests, &namespace_of_new_crates);
+    if tls_layout_will_change {
+        if let Err(e) = task::ensure_tls_areas_replaceable() {
+            for new_sec in &relocated_tls_sections {
+                let _ = this_namespace.remove_tls_section(new_sec);
+            }
+            return Err(e);
+        }
+    }
+
+    // Run the registered state transfer functions of the old crates before anything in the old namespace is modified,
+    // such that the swap can still be aborted and the transfers rolled back if any of them fails.
+    let transfers = transfer::prepare_transfers(this_namespace, &namespace_of_new_crates, &swap_requests)?;
 
     // Now that we have loaded all of the new modules into the new namepsace in isolation,
     // we simply need to fix up all of the relocations `WeakDependents` for each of the existing sections
@@ -287,6 +339,10 @@ pub fn swap_crates(
             // Go through all the `.data` and `.bss` sections and copy over the old_sec into the new source_sec,
             // as they represent static variables that would otherwise result in a loss of data.
             for old_sec in old_crate.data_sections_iter() {
+                // Skip the sections that a registered state transfer function has already migrated.
+                if transfers.was_handled(old_sec) {
+                    continue;
+                }
                 let old_sec_name_without_hash = old_sec.name_without_hash();
                 // get the section from the new crate that corresponds to the `old_sec`
                 let prefix = if crates_have_same_name {
@@ -313,6 +369,33 @@ pub fn swap_crates(
                 hpet_total_bss_transfer += hpet_end_bss_transfer - hpet_start_bss_transfer;
             }
 
+            // Pair each of the old crate's TLS sections with the corresponding TLS section in the new crate.
+            // If the new TLS section was placed at the same offset, every task's TLS area already holds its value;
+            // otherwise, the value will be copied into the new TLS section when each task's TLS area is refreshed.
+            for old_sec in old_crate.tls_sections_iter() {
+                let new_sec_name = corresponding_section_name(old_sec.name_without_hash(), &old_crate_name_without_hash, &new_crate_name_without_hash);
+                let new_sec = {
+                    let mut iter = new_crate.tls_sections_iter().filter(|sec| sec.typ == old_sec.typ && sec.name.starts_with(&*new_sec_name));
+                    iter.next().filter(|_| iter.next().is_none())
+                };
+                let replaced_in_place = match new_sec {
+                    Some(new_sec) if new_sec.size == old_sec.size => {
+                        replaced_tls_sections.push((Arc::clone(old_sec), Arc::clone(new_sec)));
+                        new_sec.virt_addr == old_sec.virt_addr
+                    }
+                    _ => {
+                        warn!("swap_crates(): couldn't transfer the values of old TLS section {:?} to the new crate", old_sec.name);
+                        false
+                    }
+                };
+                if !replaced_in_place {
+                    old_tls_sections_to_remove.push(Arc::clone(old_sec));
+                    tls_layout_changed = true;
+                }
+            }
+            tls_layout_changed |= new_crate.tls_sections_iter()
+                .any(|new_sec| !replaced_tls_sections.iter().any(|(old, new)| Arc::ptr_eq(new, new_sec) && new.virt_addr == old.virt_addr));
+
             // We need to find all of the "weak dependents" (sections that depend on the sections in the old crate)
             // and replace them by rewriting their relocation entries to point to the corresponding new section in the new_crate.
             //
@@ -363,6 +446,17 @@ pub fn swap_crates(
                         error!("swap_crates(): couldn't find section in the new crate that corresponds to a match of the old section {:?}", old_sec.name);
                         "couldn't find section in the new crate that corresponds to a match of the old section"
                     })?;
+
+                    // The existing dependents of the old section must not be bound to an incompatible new definition.
+                    let old_version = old_sec_ns.symbol_version(&old_sec.name);
+                    let new_version = namespace_of_new_crates.symbol_version(&new_crate_source_sec.name);
+                    if let (Some(old_version), Some(new_version)) = (old_version, new_version) {
+                        new_version.check_replaces(&old_version).map_err(|e| {
+                            error!("swap_crates(): new section {:?} {:?} is incompatible with old section {:?} {:?}: {}",
+                                new_crate_source_sec.name, new_version, old_sec.name, old_version, e);
+                            e
+                        })?;
+                    }
                     #[cfg(not(loscd_eval))]
                     debug!("swap_crates(): found match for old source_sec {:?}, new source_sec: {:?}", old_sec, &*new_crate_source_sec);
 
@@ -431,13 +525,16 @@ pub fn swap_crates(
                             target_sec_mapped_pages.remap(&mut kernel_mmi_ref.lock().page_table, target_sec_initial_flags.writable(true))?;
                         }
 
-                        write_relocation(
-                            relocation_entry, 
-                            target_sec_mapped_pages.as_slice_mut(0, target_sec.mapped_pages_offset + target_sec.size)?,
-                            target_sec.mapped_pages_offset, 
-                            new_source_sec.virt_addr,
-                            verbose_log
-                        )?;
+                        // The TLS module ID never changes, so module ID relocations needn't be rewritten.
+                        if !relocation_entry.is_tls_module_id() {
+                            write_relocation(
+                                relocation_entry, 
+                                target_sec_mapped_pages.as_slice_mut(0, target_sec.mapped_pages_offset + target_sec.size)?,
+                                target_sec.mapped_pages_offset, 
+                                new_source_sec.virt_addr,
+                                verbose_log
+                            )?;
+                        }
 
                         #[cfg(loscd_eval)] {
                             let end_rewriting_relocations = hpet.get_counter();
@@ -520,6 +617,20 @@ pub fn swap_crates(
         return Err("BUG: swap_crates(): didn't properly populate the list of `new_crate_names` and/or `old_crates_are_loaded`.");
     }
 
+    // Now that nothing depends on the old crates' TLS sections, remove the ones that weren't replaced in place,
+    // and carry over every task's TLS values into a TLS area that includes the new crates' TLS sections.
+    for old_tls_sec in &old_tls_sections_to_remove {
+        if this_namespace.remove_tls_section(old_tls_sec).is_err() {
+            warn!("swap_crates(): old TLS section {:?} was not in the TLS initializer", old_tls_sec.name);
+        }
+    }
+    if tls_layout_changed {
+        task::refresh_tls_areas(&replaced_tls_sections)?;
+        let _ = task_events::broadcast(task_events::Event::TlsLayoutChanged);
+    }
+    // Every task's TLS area now includes the new crates' TLS sections, into which the migrated values can be written.
+    transfers.commit();
+
     // Remove all of the old crates now that we're fully done using them.
     // This doesn't mean each crate will be immediately dropped -- they still might be in use by other crates or tasks.
     for ((req, new_crate_name), is_old_crate_loaded) in swap_requests.iter().zip(new_crate_names.iter()).zip(old_crates_are_loaded.iter()) {
@@ -774,6 +885,74 @@ pub fn swap_crates(
 }
 
 
+/// Returns the name (without hash) of the section in the new crate that corresponds to
+/// the section with the given name (without hash) in the old crate.
+fn corresponding_section_name<'s>(
+    old_sec_name_without_hash: &'s str,
+    old_crate_name_without_hash: &str,
+    new_crate_name_without_hash: &str,
+) -> Cow<'s, str> {
+    if old_crate_name_without_hash == new_crate_name_without_hash {
+        Cow::from(old_sec_name_without_hash)
+    } else if let Some(s) = replace_containing_crate_name(old_sec_name_without_hash, old_crate_name_without_hash, new_crate_name_without_hash) {
+        Cow::from(s)
+    } else {
+        Cow::from(old_sec_name_without_hash)
+    }
+}
+
+
+/// Returns the TLS sections of the currently-loaded old crates in the given `swap_requests`,
+/// each of which should be replaced in place by the corresponding TLS section in its new crate.
+fn tls_section_replacements(swap_requests: &SwapRequestList) -> Vec<TlsSectionReplacement> {
+    let mut replacements = Vec::new();
+    for req in swap_requests.iter() {
+        let old_crate_ref = match req.old_crate_name.as_deref().and_then(|ocn| CrateNamespace::get_crate_and_namespace(&req.old_namespace, ocn)) {
+            Some((ocr, _ns)) => ocr,
+            _ => continue,
+        };
+        let new_crate_name = crate_name_from_path(&Path::new(req.new_crate_object_file.lock().get_name())).to_string();
+        let new_crate_name_without_hash = new_crate_name.split(CRATE_HASH_DELIMITER).next().unwrap_or(&new_crate_name);
+        let old_crate = old_crate_ref.lock_as_ref();
+        for old_sec in old_crate.tls_sections_iter() {
+            let new_sec_name = corresponding_section_name(old_sec.name_without_hash(), old_crate.crate_name_without_hash(), new_crate_name_without_hash);
+            replacements.push(TlsSectionReplacement {
+                new_crate_name: new_crate_name.clone(),
+                new_section_name_without_hash: new_sec_name.into_owned(),
+                old_section: Arc::clone(old_sec),
+            });
+        }
+    }
+    replacements
+}
+
+
+/// Returns whether swapping in the new crates in the given `namespace_of_new_crates` will change the TLS layout,
+/// i.e., whether their TLS sections don't occupy exactly the offsets of the old crates' TLS sections,
+/// along with the new crates' TLS sections that were placed at offsets not occupied by an old TLS section.
+fn new_tls_layout(swap_requests: &SwapRequestList, namespace_of_new_crates: &CrateNamespace) -> (bool, Vec<StrongSectionRef>) {
+    let mut old_tls_offsets = Vec::new();
+    for req in swap_requests.iter() {
+        if let Some((old_crate_ref, _ns)) = req.old_crate_name.as_deref().and_then(|ocn| CrateNamespace::get_crate_and_namespace(&req.old_namespace, ocn)) {
+            old_tls_offsets.extend(old_crate_ref.lock_as_ref().tls_sections_iter().map(|sec| (sec.virt_addr, sec.size)));
+        }
+    }
+    let mut num_replaced_in_place = 0;
+    let mut relocated = Vec::new();
+    namespace_of_new_crates.for_each_crate(false, |_crate_name, new_crate_ref| {
+        for new_sec in new_crate_ref.lock_as_ref().tls_sections_iter() {
+            if old_tls_offsets.contains(&(new_sec.virt_addr, new_sec.size)) {
+                num_replaced_in_place += 1;
+            } else {
+                relocated.push(Arc::clone(new_sec));
+            }
+        }
+        true
+    });
+    (num_replaced_in_place != old_tls_offsets.len() || !relocated.is_empty(), relocated)
+}
+
+
 /// Convenience function that removes the given `file` from its parent directory 
 /// and inserts it into the given destination directory. 
 /// 
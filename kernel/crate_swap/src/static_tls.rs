@@ -0,0 +1,39 @@
+//! Live updates of the statically-linked TLS layout of the base kernel image (the nano_core).
+//!
+//! This is the counterpart to [`swap_crates()`](super::swap_crates) for the nano_core's static TLS sections,
+//! which cannot be swapped like a crate's sections because their offsets are fixed by the linker.
+//! See the [`mod_mgmt::static_tls`] module for how a new nano_core's static TLS offsets are reconciled.
+
+use alloc::vec::Vec;
+use memory::MmiRef;
+use mod_mgmt::{CrateNamespace, LoadedSection};
+
+/// Replaces the static TLS sections of the given `namespace` with the given TLS sections
+/// of a new nano_core, each along with its offset as determined by the linker,
+/// and rebases the TLS area of every task accordingly.
+///
+/// If any task's TLS area cannot be rebased, e.g., because a task has started running
+/// and may hold references into its TLS area (see [`task::rebase_tls_areas()`]), the update is rolled back,
+/// such that the old static TLS sections and all existing TLS areas remain in use.
+///
+/// Returns the number of tasks whose TLS area was rebased.
+pub fn replace_static_tls_sections(
+    namespace: &CrateNamespace,
+    new_sections: Vec<(LoadedSection, usize)>,
+    total_static_tls_size: usize,
+    kernel_mmi_ref: &MmiRef,
+) -> Result<usize, &'static str> {
+    let update = namespace.replace_static_tls_sections(new_sections, total_static_tls_size, kernel_mmi_ref)?;
+    match task::rebase_tls_areas(update.remappings()) {
+        Ok(num_rebased) => {
+            update.commit();
+            Ok(num_rebased)
+        }
+        Err(e) => {
+            if let Err(rollback_err) = update.rollback() {
+                error!("couldn't roll back the static TLS update: {}", rollback_err);
+            }
+            Err(e)
+        }
+    }
+}
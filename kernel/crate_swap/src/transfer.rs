@@ -0,0 +1,328 @@
+//! A state transfer framework for evolving a crate's state when it is swapped for a new version.
+//!
+//! Transfer functions are registered for an old crate by its name without the hash
+//! via [`register_transfer_function()`], e.g., by the new version of that crate.
+//! When [`swap_crates()`](super::swap_crates) replaces that old crate, each of its transfer functions
+//! is invoked with a [`StateTransfer`] after the new crates are loaded,
+//! but before anything in the old namespace has been modified.
+//! A transfer function can migrate:
+//! * static state, via [`StateTransfer::transfer_static()`] and [`StateTransfer::write_static()`].
+//!   The old crate's `.data`/`.bss` sections that it migrates are not copied verbatim afterwards.
+//! * every task's value of a TLS variable, via [`StateTransfer::transfer_tls()`].
+//!   The converted values are written into each task's TLS area once the swap is complete
+//!   and the new crate's TLS sections are part of it.
+//! * heap objects reachable from the old crate's state, by moving or converting them directly
+//!   and registering how to undo that via [`StateTransfer::on_rollback()`].
+//!
+//! If any transfer function fails, all transfers that were already performed are rolled back
+//! in reverse order and the swap is aborted, so the old crates remain in use with their original state.
+
+use alloc::{
+    boxed::Box,
+    string::String,
+    sync::Arc,
+    vec::Vec,
+};
+use spin::Mutex;
+use mod_mgmt::{
+    CrateNamespace,
+    StrongCrateRef,
+    StrongSectionRef,
+    crate_name_from_path,
+    symbol_version,
+};
+use path::Path;
+use task::TaskRef;
+use super::SwapRequestList;
+
+/// A function that migrates the state of an old crate to the new crate that replaces it.
+pub type TransferFunction = fn(&mut StateTransfer) -> Result<(), &'static str>;
+
+/// A function that converts the value of a TLS variable in the old crate, the first argument,
+/// into its value in the new crate, the second argument, which is initially zeroed.
+pub type TlsConversion = fn(&[u8], &mut [u8]) -> Result<(), &'static str>;
+
+/// The registered transfer functions, keyed by the name of the old crate without its hash.
+static TRANSFER_FUNCTIONS: Mutex<Vec<(String, TransferFunction)>> = Mutex::new(Vec::new());
+
+/// Registers a function that migrates the state of the crate named `old_crate_name_without_hash`
+/// whenever that crate is swapped.
+///
+/// Transfer functions for the same crate are invoked in the order they were registered.
+/// Returns an error if the same function was already registered for that crate.
+pub fn register_transfer_function(old_crate_name_without_hash: &str, function: TransferFunction) -> Result<(), &'static str> {
+    let mut functions = TRANSFER_FUNCTIONS.lock();
+    if functions.iter().any(|(name, f)| name == old_crate_name_without_hash && *f as usize == function as usize) {
+        return Err("this transfer function was already registered for that crate");
+    }
+    functions.push((String::from(old_crate_name_without_hash), function));
+    Ok(())
+}
+
+/// Removes all transfer functions registered for the crate named `old_crate_name_without_hash`,
+/// returning how many were removed.
+pub fn unregister_transfer_functions(old_crate_name_without_hash: &str) -> usize {
+    let mut functions = TRANSFER_FUNCTIONS.lock();
+    let count = functions.len();
+    functions.retain(|(name, _)| name != old_crate_name_without_hash);
+    count - functions.len()
+}
+
+/// A single action that restores the state that a transfer modified.
+enum Undo {
+    /// Restore the given bytes at the given offset into a section.
+    Bytes(StrongSectionRef, usize, Vec<u8>),
+    /// Invoke a function registered via [`StateTransfer::on_rollback()`].
+    Function(Box<dyn FnOnce() + Send>),
+}
+
+/// A TLS value that will be written into a task's TLS area once the swap is complete.
+struct StagedTlsValue {
+    task: TaskRef,
+    offset: isize,
+    bytes: Vec<u8>,
+}
+
+/// The context in which a [`TransferFunction`] migrates the state of one old crate to its new crate.
+pub struct StateTransfer<'t> {
+    old_namespace: &'t Arc<CrateNamespace>,
+    new_namespace: &'t CrateNamespace,
+    old_crate: StrongCrateRef,
+    new_crate: StrongCrateRef,
+    transferred: &'t mut PreparedTransfers,
+}
+
+impl<'t> StateTransfer<'t> {
+    /// Returns the namespace that currently contains the old crate.
+    pub fn old_namespace(&self) -> &Arc<CrateNamespace> {
+        self.old_namespace
+    }
+
+    /// Returns the namespace that contains the newly-loaded crates, which are not yet in use.
+    pub fn new_namespace(&self) -> &CrateNamespace {
+        self.new_namespace
+    }
+
+    /// Returns the old crate being swapped out.
+    pub fn old_crate(&self) -> &StrongCrateRef {
+        &self.old_crate
+    }
+
+    /// Returns the new crate that replaces the old crate.
+    pub fn new_crate(&self) -> &StrongCrateRef {
+        &self.new_crate
+    }
+
+    /// Returns the old crate's section with the given name, excluding its hash,
+    /// e.g., `my_crate::COUNTER`.
+    pub fn old_section(&self, name_without_hash: &str) -> Result<StrongSectionRef, &'static str> {
+        find_section(&self.old_crate, name_without_hash).ok_or("couldn't find a single matching section in the old crate")
+    }
+
+    /// Returns the new crate's section with the given name, excluding its hash.
+    pub fn new_section(&self, name_without_hash: &str) -> Result<StrongSectionRef, &'static str> {
+        find_section(&self.new_crate, name_without_hash).ok_or("couldn't find a single matching section in the new crate")
+    }
+
+    /// Migrates the static variable in the old crate's section `old_name` to the new crate's section `new_name`
+    /// by invoking `convert` with the old section's contents and the new section's contents.
+    ///
+    /// The old section is then excluded from the verbatim copy of the old crate's `.data` and `.bss` sections.
+    pub fn transfer_static<F>(&mut self, old_name: &str, new_name: &str, convert: F) -> Result<(), &'static str>
+        where F: FnOnce(&[u8], &mut [u8]) -> Result<(), &'static str>
+    {
+        let old_sec = self.old_section(old_name)?;
+        let new_sec = self.new_section(new_name)?;
+        let old_value = section_bytes(&old_sec)?;
+        let mut new_value = section_bytes(&new_sec)?;
+        convert(&old_value, &mut new_value)?;
+        self.write_static(&new_sec, 0, &new_value)?;
+        self.transferred.handled_sections.push(old_sec);
+        Ok(())
+    }
+
+    /// Overwrites the bytes at the given `offset` into the given `.data` or `.bss` section,
+    /// which may belong to the old crate, the new crate, or any other crate.
+    ///
+    /// The section's previous contents are restored if the swap is rolled back.
+    pub fn write_static(&mut self, section: &StrongSectionRef, offset: usize, bytes: &[u8]) -> Result<(), &'static str> {
+        if !matches!(section.typ, mod_mgmt::SectionType::Data | mod_mgmt::SectionType::Bss) {
+            return Err("write_static(): the section isn't a .data or .bss section");
+        }
+        let end = offset.checked_add(bytes.len()).filter(|&end| end <= section.size)
+            .ok_or("write_static(): the bytes don't fit within the section")?;
+        let mut mp = section.mapped_pages.lock();
+        let data: &mut [u8] = mp.as_slice_mut(section.mapped_pages_offset + offset, end - offset)?;
+        self.transferred.undo_log.push(Undo::Bytes(Arc::clone(section), offset, data.to_vec()));
+        data.copy_from_slice(bytes);
+        Ok(())
+    }
+
+    /// Migrates every task's value of the TLS variable in the old crate's section `old_name`
+    /// to the new crate's section `new_name` by invoking `convert` with each task's old value.
+    ///
+    /// The converted values are written once the swap is complete, so the old crate keeps using its own values until then.
+    /// Tasks that are running during the swap keep their existing TLS area, so their values cannot be migrated.
+    pub fn transfer_tls(&mut self, old_name: &str, new_name: &str, convert: TlsConversion) -> Result<(), &'static str> {
+        let old_sec = self.old_section(old_name)?;
+        let new_sec = self.new_section(new_name)?;
+        if !matches!(old_sec.typ, mod_mgmt::SectionType::TlsData | mod_mgmt::SectionType::TlsBss)
+            || !matches!(new_sec.typ, mod_mgmt::SectionType::TlsData | mod_mgmt::SectionType::TlsBss)
+        {
+            return Err("transfer_tls(): the sections aren't TLS sections");
+        }
+        // A TLS section's virtual address is its offset from the TLS self pointer.
+        let old_offset = old_sec.virt_addr.value() as isize;
+        let new_offset = new_sec.virt_addr.value() as isize;
+        let tasks: Vec<TaskRef> = task::TASKLIST.lock().values().cloned().collect();
+        for taskref in tasks {
+            let was_suspended = taskref.is_suspended();
+            taskref.suspend();
+            let mut old_value = vec![0u8; old_sec.size];
+            let result = if taskref.is_running() {
+                warn!("transfer_tls(): couldn't migrate {:?} of running task {:?}", old_sec.name, taskref);
+                Ok(false)
+            } else {
+                // SAFETY: the task isn't running and cannot be scheduled in while it's suspended.
+                unsafe { taskref.read_tls_area(old_offset, &mut old_value) }.map(|_| true)
+            };
+            if !was_suspended {
+                taskref.unsuspend();
+            }
+            if !result? {
+                continue;
+            }
+            let mut bytes = vec![0u8; new_sec.size];
+            convert(&old_value, &mut bytes)?;
+            self.transferred.staged_tls_values.push(StagedTlsValue { task: taskref, offset: new_offset, bytes });
+        }
+        Ok(())
+    }
+
+    /// Registers a function that undoes a change made by this transfer, e.g., moving heap objects
+    /// that are reachable from the old crate's state, which is invoked if the swap is rolled back.
+    pub fn on_rollback<F>(&mut self, undo: F) where F: FnOnce() + Send + 'static {
+        self.transferred.undo_log.push(Undo::Function(Box::new(undo)));
+    }
+}
+
+/// The state transfers that were performed for a crate swap but not yet finalized.
+#[derive(Default)]
+pub(crate) struct PreparedTransfers {
+    undo_log: Vec<Undo>,
+    handled_sections: Vec<StrongSectionRef>,
+    staged_tls_values: Vec<StagedTlsValue>,
+}
+
+impl PreparedTransfers {
+    /// Returns whether the given old `.data` or `.bss` section was migrated by a transfer function,
+    /// in which case it must not be copied verbatim into the new crate.
+    pub(crate) fn was_handled(&self, old_section: &StrongSectionRef) -> bool {
+        self.handled_sections.iter().any(|sec| Arc::ptr_eq(sec, old_section))
+    }
+
+    /// Undoes all transfers in reverse order and discards the staged TLS values.
+    pub(crate) fn rollback(self) {
+        for undo in self.undo_log.into_iter().rev() {
+            match undo {
+                Undo::Bytes(section, offset, bytes) => {
+                    let mut mp = section.mapped_pages.lock();
+                    match mp.as_slice_mut::<u8>(section.mapped_pages_offset + offset, bytes.len()) {
+                        Ok(data) => data.copy_from_slice(&bytes),
+                        Err(e) => error!("couldn't roll back state transfer into section {:?}: {}", section.name, e),
+                    }
+                }
+                Undo::Function(function) => function(),
+            }
+        }
+    }
+
+    /// Writes the staged TLS values into each task's TLS area,
+    /// which must already include the new crates' TLS sections.
+    pub(crate) fn commit(self) {
+        for StagedTlsValue { task: taskref, offset, bytes } in self.staged_tls_values {
+            let was_suspended = taskref.is_suspended();
+            taskref.suspend();
+            let result = if taskref.is_running() {
+                Err("task was running")
+            } else {
+                // SAFETY: the task isn't running and cannot be scheduled in while it's suspended,
+                //         and `bytes` was produced by the transfer function for this TLS variable.
+                unsafe { taskref.write_existing_tls_area(offset, &bytes) }
+            };
+            if !was_suspended {
+                taskref.unsuspend();
+            }
+            if let Err(e) = result {
+                warn!("couldn't write migrated TLS value at offset {:#X} into task {:?}: {}", offset, taskref, e);
+            }
+        }
+    }
+}
+
+/// Invokes the registered transfer functions of each old crate in the given `swap_requests`.
+///
+/// If any transfer function fails, all transfers are rolled back and the error is returned.
+pub(crate) fn prepare_transfers(
+    this_namespace: &Arc<CrateNamespace>,
+    namespace_of_new_crates: &CrateNamespace,
+    swap_requests: &SwapRequestList,
+) -> Result<PreparedTransfers, &'static str> {
+    let mut transferred = PreparedTransfers::default();
+    if TRANSFER_FUNCTIONS.lock().is_empty() {
+        return Ok(transferred);
+    }
+    for req in swap_requests {
+        let old_crate = match req.old_crate_name.as_deref().and_then(|ocn| CrateNamespace::get_crate_and_namespace(&req.old_namespace, ocn)) {
+            Some((old_crate, _ns)) => old_crate,
+            None => continue,
+        };
+        let old_crate_name_without_hash = String::from(old_crate.lock_as_ref().crate_name_without_hash());
+        let functions: Vec<TransferFunction> = TRANSFER_FUNCTIONS.lock().iter()
+            .filter(|(name, _)| *name == old_crate_name_without_hash)
+            .map(|(_, f)| *f)
+            .collect();
+        if functions.is_empty() {
+            continue;
+        }
+        let new_crate_name = String::from(crate_name_from_path(&Path::new(req.new_crate_object_file.lock().get_name())));
+        let new_crate = match namespace_of_new_crates.get_crate(&new_crate_name) {
+            Some(new_crate) => new_crate,
+            None => {
+                transferred.rollback();
+                return Err("BUG: couldn't get the new crate for state transfer");
+            }
+        };
+        let mut state_transfer = StateTransfer {
+            old_namespace: this_namespace,
+            new_namespace: namespace_of_new_crates,
+            old_crate,
+            new_crate,
+            transferred: &mut transferred,
+        };
+        for function in functions {
+            if let Err(e) = function(&mut state_transfer) {
+                error!("state transfer from old crate {:?} failed: {}. Rolling back all state transfers.", old_crate_name_without_hash, e);
+                drop(state_transfer);
+                transferred.rollback();
+                return Err(e);
+            }
+        }
+    }
+    Ok(transferred)
+}
+
+/// Returns the single section in the given crate with the given name, excluding its hash.
+fn find_section(krate: &StrongCrateRef, name_without_hash: &str) -> Option<StrongSectionRef> {
+    let krate = krate.lock_as_ref();
+    let mut iter = krate.sections.values()
+        .filter(|sec| symbol_version::name_without_hash(&sec.name) == name_without_hash);
+    iter.next().filter(|_| iter.next().is_none()).cloned()
+}
+
+/// Returns a copy of the contents of the given section.
+fn section_bytes(section: &StrongSectionRef) -> Result<Vec<u8>, &'static str> {
+    let mp = section.mapped_pages.lock();
+    let bytes: &[u8] = mp.as_slice(section.mapped_pages_offset, section.size)?;
+    Ok(bytes.to_vec())
+}
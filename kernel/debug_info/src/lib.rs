@@ -19,6 +19,7 @@ extern crate mod_mgmt;
 extern crate hashbrown;
 extern crate by_address;
 extern crate rustc_demangle;
+extern crate spin;
 
 use core::{
     ops::{Deref, Range},
@@ -60,6 +61,8 @@ use by_address::ByAddress;
 use crate_metadata::{StrongCrateRef, StrongSectionRef, RelocationEntry, write_relocation};
 use mod_mgmt::{CrateNamespace, find_symbol_table};
 
+pub mod registry;
+
 
 /// The set of debug sections that we need to use from a crate object file.
 /// 
@@ -470,6 +473,26 @@ impl DebugSections {
     }
 
 
+    /// Returns these debug sections as a `gimli::Dwarf` object, which is needed to parse units.
+    fn dwarf(&self) -> gimli::Result<gimli::Dwarf<EndianSlice<NativeEndian>>> {
+        let load_section = |section_id| {
+            let slice_opt = match section_id {
+                gimli::SectionId::DebugInfo =>     Some(self.debug_info.0.deref()),
+                gimli::SectionId::DebugLine =>     Some(self.debug_line.0.deref()),
+                gimli::SectionId::DebugLoc =>      self.debug_loc.as_ref().map(|loc| loc.0.deref()),
+                gimli::SectionId::DebugPubNames => Some(self.debug_pubnames.0.deref()),
+                gimli::SectionId::DebugPubTypes => Some(self.debug_pubtypes.0.deref()),
+                gimli::SectionId::DebugAbbrev =>   Some(self.debug_abbrev.0.deref()),
+                gimli::SectionId::DebugRanges =>   Some(self.debug_ranges.0.deref()),
+                gimli::SectionId::DebugStr =>      Some(self.debug_str.0.deref()),
+                // Other debug sections aren't retained, so they're treated as empty.
+                _ => None,
+            };
+            Ok(gimli::EndianSlice::new(slice_opt.unwrap_or_default(), NativeEndian))
+        };
+        gimli::Dwarf::load(load_section)
+    }
+
     /// Finds the subprogram that contains the given instruction pointer. 
     /// 
     /// A *subprogram* is DWARF's term for an executable function/method/closure/subroutine,
@@ -486,24 +509,7 @@ impl DebugSections {
 
         warn!("TARGET INSTRUCTION POINTER: {:#X}", instruction_pointer);
 
-        let load_section = |section_id| {
-            let slice_opt = match section_id {
-                gimli::SectionId::DebugInfo =>     Some(self.debug_info.0.deref()),
-                gimli::SectionId::DebugLine =>     Some(self.debug_line.0.deref()),
-                gimli::SectionId::DebugLoc =>      self.debug_loc.as_ref().map(|loc| loc.0.deref()),
-                gimli::SectionId::DebugPubNames => Some(self.debug_pubnames.0.deref()),
-                gimli::SectionId::DebugPubTypes => Some(self.debug_pubtypes.0.deref()),
-                gimli::SectionId::DebugAbbrev =>   Some(self.debug_abbrev.0.deref()),
-                gimli::SectionId::DebugRanges =>   Some(self.debug_ranges.0.deref()),
-                gimli::SectionId::DebugStr =>      Some(self.debug_str.0.deref()),
-                _ => {
-                    error!("Unsupported debug section: {:?}", section_id.name());
-                    None
-                }
-            };
-            Ok(gimli::EndianSlice::new(slice_opt.unwrap_or_default(), NativeEndian))
-        };
-        let dwarf = gimli::Dwarf::load(load_section)?;
+        let dwarf = self.dwarf()?;
         
         let debug_info_sec = self.debug_info();
         let debug_abbrev_sec = self.debug_abbrev();
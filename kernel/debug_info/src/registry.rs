@@ -0,0 +1,509 @@
+//! A registry of the DWARF debug information of loaded crates,
+//! which answers line-number and variable-location queries by address,
+//! e.g., for printing better backtraces or for a debugger stub.
+//!
+//! The debug sections of the crate that contains a queried address are loaded on demand
+//! from that crate's object file via [`DebugSymbols::load()`].
+//! By default, they're unloaded again after each query, which frees their memory
+//! and drops their dependencies on other crates' sections.
+//! If retention is enabled via [`set_retain()`], or if a crate was explicitly [`register()`]ed,
+//! its debug sections are kept until it is [`unregister()`]ed.
+//! Retained debug sections make later queries cheap, and they can be queried via
+//! [`cached_source_location()`] in contexts that cannot load anything, e.g., exception handlers.
+//! Note that retained debug sections keep their crate alive, so a crate should be unregistered
+//! before it is unloaded or swapped out.
+//!
+//! The locations of thread-local variables, which are given by `DW_OP_form_tls_address`
+//! (or `DW_OP_GNU_push_tls_address`) expressions, are resolved against the offsets
+//! that the namespace's `TlsInitializer` assigned to their TLS sections; see [`VariableLocation::Tls`].
+
+use core::{
+    fmt,
+    ops::Range,
+    sync::atomic::{AtomicBool, Ordering},
+};
+use alloc::{
+    string::String,
+    vec::Vec,
+};
+use spin::Mutex;
+use gimli::{
+    AttributeValue,
+    EndianSlice,
+    NativeEndian,
+    EvaluationResult,
+    read::{EntriesTreeNode, LineProgramHeader, LineRow},
+};
+use memory::VirtualAddress;
+use crate_metadata::{StrongCrateRef, StrongSectionRef, WeakCrateRef};
+use mod_mgmt::CrateNamespace;
+use super::{DebugSections, DebugSymbols};
+
+type Slice<'a> = EndianSlice<'a, NativeEndian>;
+
+/// Whether the debug sections of every queried crate are retained.
+static RETAIN: AtomicBool = AtomicBool::new(false);
+
+/// The crates whose debug sections are currently loaded.
+static REGISTRY: Mutex<Vec<RegisteredCrate>> = Mutex::new(Vec::new());
+
+/// A crate in the registry.
+struct RegisteredCrate {
+    crate_ref: WeakCrateRef,
+    /// The range of virtual addresses covered by the crate's executable sections, if any.
+    text_range: Option<Range<VirtualAddress>>,
+    debug_symbols: DebugSymbols,
+}
+
+impl RegisteredCrate {
+    fn is(&self, crate_ref: &StrongCrateRef) -> bool {
+        self.crate_ref.upgrade().map_or(false, |c| c.ptr_eq(crate_ref))
+    }
+}
+
+/// A location in the source code, as given by a line-number program.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SourceLocation {
+    /// The path of the source file, including its directory if known.
+    pub file: String,
+    /// The line number, starting at 1, or `0` if it's unknown.
+    pub line: u64,
+    /// The column number, starting at 1, or `0` for the left edge of the line.
+    pub column: u64,
+}
+
+impl fmt::Display for SourceLocation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.file, self.line)?;
+        if self.column != 0 {
+            write!(f, ":{}", self.column)?;
+        }
+        Ok(())
+    }
+}
+
+/// Where a variable's value resides.
+#[derive(Debug, Clone)]
+pub enum VariableLocation {
+    /// In the DWARF register with the given number.
+    Register(u16),
+    /// At the given offset from the enclosing function's frame base (its `DW_AT_frame_base`).
+    FrameBaseOffset(i64),
+    /// At the given address, e.g., a static variable.
+    Address(VirtualAddress),
+    /// In the TLS area, at the given `offset` from the TLS self pointer.
+    Tls {
+        /// The offset from the TLS self pointer, which is negative for static TLS sections.
+        offset: isize,
+        /// The TLS section that contains the `offset` according to the namespace's `TlsInitializer`, if any.
+        section: Option<StrongSectionRef>,
+        /// The address of the variable in the TLS area with the TLS self pointer given to the query, if any.
+        address: Option<VirtualAddress>,
+    },
+    /// The variable has been optimized out at the queried address.
+    OptimizedOut,
+    /// The location expression needs information that isn't available, e.g., the contents of memory.
+    Unsupported,
+}
+
+/// A variable along with its location.
+#[derive(Debug, Clone)]
+pub struct Variable {
+    /// The variable's name as written in the source code.
+    pub name: String,
+    /// Whether this is a function parameter rather than a local or static variable.
+    pub is_parameter: bool,
+    pub location: VariableLocation,
+}
+
+/// Sets whether the debug sections of every queried crate are retained,
+/// rather than being unloaded after each query.
+///
+/// Disabling retention unloads the debug sections of all crates, including explicitly-registered ones.
+pub fn set_retain(retain: bool) {
+    RETAIN.store(retain, Ordering::Release);
+    if !retain {
+        REGISTRY.lock().clear();
+    }
+}
+
+/// Returns whether the debug sections of every queried crate are retained.
+pub fn is_retaining() -> bool {
+    RETAIN.load(Ordering::Acquire)
+}
+
+/// Loads and retains the debug sections of the given crate, regardless of whether retention is enabled.
+pub fn register(crate_ref: &StrongCrateRef, namespace: &CrateNamespace) -> Result<(), &'static str> {
+    with_debug_sections(crate_ref, namespace, true, |_| ())
+}
+
+/// Unloads the retained debug sections of the given crate, if any.
+///
+/// Returns `true` if the crate's debug sections were retained.
+pub fn unregister(crate_ref: &StrongCrateRef) -> bool {
+    let mut registry = REGISTRY.lock();
+    let num_registered = registry.len();
+    registry.retain(|registered| !registered.is(crate_ref));
+    registry.len() != num_registered
+}
+
+/// Returns the source location of the instruction at the given `address`,
+/// which must be in a crate loaded into the given `namespace` or its recursive namespace.
+///
+/// Returns `Ok(None)` if the crate's line-number program doesn't cover the `address`.
+pub fn source_location(namespace: &CrateNamespace, address: VirtualAddress) -> Result<Option<SourceLocation>, &'static str> {
+    let crate_ref = namespace.get_crate_containing_address(address, false)
+        .ok_or("no loaded crate contains the given address")?;
+    with_debug_sections(&crate_ref, namespace, false, |debug_sections| debug_sections.source_location(address))?
+        .map_err(|_| "couldn't parse the crate's line-number program")
+}
+
+/// Returns the source location of the instruction at the given `address`
+/// using only debug sections that are already retained, without loading anything.
+///
+/// This doesn't block, so it can be used in exception handlers;
+/// it returns `None` if the registry is locked.
+pub fn cached_source_location(address: VirtualAddress) -> Option<SourceLocation> {
+    let registry = REGISTRY.try_lock()?;
+    registry.iter()
+        .filter(|registered| registered.text_range.as_ref().map_or(false, |range| range.contains(&address)))
+        .find_map(|registered| registered.debug_symbols.get_loaded()?.source_location(address).ok().flatten())
+}
+
+/// Returns the parameters and local variables that are in scope at the instruction at the given `address`,
+/// which must be in a crate loaded into the given `namespace` or its recursive namespace,
+/// along with the location of each at that `address`.
+///
+/// If a `tls_self_pointer` is given, the addresses of thread-local variables in that TLS area are included.
+pub fn variables_at(
+    namespace: &CrateNamespace,
+    address: VirtualAddress,
+    tls_self_pointer: Option<usize>,
+) -> Result<Vec<Variable>, &'static str> {
+    let crate_ref = namespace.get_crate_containing_address(address, false)
+        .ok_or("no loaded crate contains the given address")?;
+    let mut variables = with_debug_sections(&crate_ref, namespace, false, |debug_sections|
+        debug_sections.variables_at(address, tls_self_pointer)
+    )?.map_err(|_| "couldn't parse the crate's debug information entries")?;
+    resolve_tls_sections(namespace, &mut variables);
+    Ok(variables)
+}
+
+/// Returns the static and thread-local variables defined by the given crate,
+/// along with the location of each.
+///
+/// If a `tls_self_pointer` is given, the addresses of thread-local variables in that TLS area are included.
+pub fn static_variables(
+    crate_ref: &StrongCrateRef,
+    namespace: &CrateNamespace,
+    tls_self_pointer: Option<usize>,
+) -> Result<Vec<Variable>, &'static str> {
+    let mut variables = with_debug_sections(crate_ref, namespace, false, |debug_sections|
+        debug_sections.static_variables(tls_self_pointer)
+    )?.map_err(|_| "couldn't parse the crate's debug information entries")?;
+    resolve_tls_sections(namespace, &mut variables);
+    Ok(variables)
+}
+
+/// Invokes `f` on the loaded debug sections of the given crate,
+/// loading them first if necessary and unloading them afterwards unless they're retained.
+fn with_debug_sections<T>(
+    crate_ref: &StrongCrateRef,
+    namespace: &CrateNamespace,
+    retain: bool,
+    f: impl FnOnce(&DebugSections) -> T,
+) -> Result<T, &'static str> {
+    let retain = retain || is_retaining();
+    let mut registry = REGISTRY.lock();
+    let (index, newly_added) = match registry.iter().position(|registered| registered.is(crate_ref)) {
+        Some(index) => (index, false),
+        None => {
+            let krate = crate_ref.lock_as_ref();
+            let registered = RegisteredCrate {
+                crate_ref: StrongCrateRef::downgrade(crate_ref),
+                text_range: krate.text_pages.as_ref().map(|(_, range)| range.clone()),
+                debug_symbols: DebugSymbols::Unloaded(krate.debug_symbols_file.clone()),
+            };
+            drop(krate);
+            registry.push(registered);
+            (registry.len() - 1, true)
+        }
+    };
+    let result = registry[index].debug_symbols.load(crate_ref, namespace).map(f);
+    if newly_added && (!retain || result.is_err()) {
+        registry.remove(index);
+    }
+    result
+}
+
+/// Sets the TLS section of each thread-local variable based on the given namespace's current TLS layout.
+fn resolve_tls_sections(namespace: &CrateNamespace, variables: &mut [Variable]) {
+    if !variables.iter().any(|v| matches!(v.location, VariableLocation::Tls { .. })) {
+        return;
+    }
+    let layout = namespace.tls_layout_info();
+    for variable in variables.iter_mut() {
+        if let VariableLocation::Tls { offset, ref mut section, .. } = variable.location {
+            *section = layout.sections.iter()
+                .find(|sec| sec.offset <= offset && offset < sec.offset + sec.section.size as isize)
+                .map(|sec| sec.section.clone());
+        }
+    }
+}
+
+
+impl DebugSections {
+    /// Returns the source location of the instruction at the given `address`,
+    /// or `None` if no line-number program covers it.
+    pub fn source_location(&self, address: VirtualAddress) -> gimli::Result<Option<SourceLocation>> {
+        let address = address.value() as u64;
+        let dwarf = self.dwarf()?;
+        let mut units = dwarf.units();
+        while let Some(header) = units.next()? {
+            let unit = dwarf.unit(header)?;
+            let program = match unit.line_program.clone() {
+                Some(program) => program,
+                None => continue,
+            };
+            // Each row starts a range of addresses that extends up to the next row's address,
+            // unless it ends a sequence of rows.
+            let mut rows = program.rows();
+            let mut previous: Option<LineRow> = None;
+            while let Some((header, row)) = rows.next_row()? {
+                if let Some(prev) = previous {
+                    if prev.address() <= address && address < row.address() {
+                        return row_location(&dwarf, &unit, header, &prev).map(Some);
+                    }
+                }
+                previous = if row.end_sequence() { None } else { Some(*row) };
+            }
+        }
+        Ok(None)
+    }
+
+    /// Returns the parameters and local variables that are in scope at the instruction at the given `address`,
+    /// along with the location of each at that `address`.
+    ///
+    /// The TLS section of a thread-local variable is not resolved, as that requires a `CrateNamespace`.
+    pub fn variables_at(&self, address: VirtualAddress, tls_self_pointer: Option<usize>) -> gimli::Result<Vec<Variable>> {
+        let dwarf = self.dwarf()?;
+        let mut variables = Vec::new();
+        let mut units = dwarf.units();
+        while let Some(header) = units.next()? {
+            let unit = dwarf.unit(header)?;
+            let mut tree = unit.entries_tree(None)?;
+            let context = LocationContext { dwarf: &dwarf, unit: &unit, address: address.value() as u64, tls_self_pointer };
+            if collect_local_variables(&context, tree.root()?, false, &mut variables)? {
+                break;
+            }
+        }
+        Ok(variables)
+    }
+
+    /// Returns the static and thread-local variables in these debug sections, along with the location of each.
+    ///
+    /// The TLS section of a thread-local variable is not resolved, as that requires a `CrateNamespace`.
+    pub fn static_variables(&self, tls_self_pointer: Option<usize>) -> gimli::Result<Vec<Variable>> {
+        let dwarf = self.dwarf()?;
+        let mut variables = Vec::new();
+        let mut units = dwarf.units();
+        while let Some(header) = units.next()? {
+            let unit = dwarf.unit(header)?;
+            let mut tree = unit.entries_tree(None)?;
+            let context = LocationContext { dwarf: &dwarf, unit: &unit, address: 0, tls_self_pointer };
+            collect_static_variables(&context, tree.root()?, &mut variables)?;
+        }
+        Ok(variables)
+    }
+}
+
+
+/// The contextual info needed to evaluate the locations of variables in a single unit.
+struct LocationContext<'a, 'd> {
+    dwarf: &'a gimli::Dwarf<Slice<'d>>,
+    unit: &'a gimli::Unit<Slice<'d>>,
+    /// The address of the instruction at which variable locations are evaluated.
+    address: u64,
+    tls_self_pointer: Option<usize>,
+}
+
+/// Returns the source location described by the given row of a line-number program.
+fn row_location(
+    dwarf: &gimli::Dwarf<Slice>,
+    unit: &gimli::Unit<Slice>,
+    header: &LineProgramHeader<Slice>,
+    row: &LineRow,
+) -> gimli::Result<SourceLocation> {
+    let mut file = String::new();
+    if let Some(entry) = row.file(header) {
+        if let Some(directory) = entry.directory(header) {
+            file.push_str(&dwarf.attr_string(unit, directory)?.to_string()?);
+            file.push('/');
+        }
+        file.push_str(&dwarf.attr_string(unit, entry.path_name())?.to_string()?);
+    }
+    let column = match row.column() {
+        gimli::ColumnType::LeftEdge => 0,
+        gimli::ColumnType::Column(column) => column,
+    };
+    Ok(SourceLocation { file, line: row.line().unwrap_or(0), column })
+}
+
+/// Recursively collects the variables of the subprogram and nested scopes in the given `node`
+/// that contain the context's address.
+///
+/// Returns whether a subprogram that contains the address was found.
+fn collect_local_variables(
+    context: &LocationContext,
+    node: EntriesTreeNode<Slice>,
+    in_subprogram: bool,
+    variables: &mut Vec<Variable>,
+) -> gimli::Result<bool> {
+    let entry = node.entry();
+    let tag = entry.tag();
+    if tag == gimli::DW_TAG_subprogram || tag == gimli::DW_TAG_lexical_block || tag == gimli::DW_TAG_inlined_subroutine {
+        if !die_contains(context, entry)? {
+            return Ok(false);
+        }
+    } else if tag == gimli::DW_TAG_variable || tag == gimli::DW_TAG_formal_parameter {
+        if in_subprogram {
+            if let Some(name) = die_name(context, entry)? {
+                variables.push(Variable {
+                    name,
+                    is_parameter: tag == gimli::DW_TAG_formal_parameter,
+                    location: variable_location(context, entry)?,
+                });
+            }
+        }
+        return Ok(false);
+    }
+
+    let in_subprogram = in_subprogram || tag == gimli::DW_TAG_subprogram;
+    let mut found = tag == gimli::DW_TAG_subprogram;
+    let mut children = node.children();
+    while let Some(child) = children.next()? {
+        found |= collect_local_variables(context, child, in_subprogram, variables)?;
+    }
+    Ok(found)
+}
+
+/// Recursively collects the variables in the given `node` that aren't within a subprogram.
+fn collect_static_variables(
+    context: &LocationContext,
+    node: EntriesTreeNode<Slice>,
+    variables: &mut Vec<Variable>,
+) -> gimli::Result<()> {
+    let entry = node.entry();
+    let tag = entry.tag();
+    if tag == gimli::DW_TAG_subprogram {
+        return Ok(());
+    }
+    if tag == gimli::DW_TAG_variable {
+        // Declarations without a location are defined elsewhere, e.g., in another crate.
+        if entry.attr_value(gimli::DW_AT_location)?.is_some() {
+            if let Some(name) = die_name(context, entry)? {
+                variables.push(Variable { name, is_parameter: false, location: variable_location(context, entry)? });
+            }
+        }
+        return Ok(());
+    }
+    let mut children = node.children();
+    while let Some(child) = children.next()? {
+        collect_static_variables(context, child, variables)?;
+    }
+    Ok(())
+}
+
+/// Returns whether any of the address ranges of the given entry contain the context's address.
+fn die_contains(context: &LocationContext, entry: &gimli::DebuggingInformationEntry<Slice>) -> gimli::Result<bool> {
+    let mut ranges = context.dwarf.die_ranges(context.unit, entry)?;
+    while let Some(range) = ranges.next()? {
+        if range.begin <= context.address && context.address < range.end {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Returns the name of the given entry, which for an inlined variable is that of its abstract origin.
+fn die_name(context: &LocationContext, entry: &gimli::DebuggingInformationEntry<Slice>) -> gimli::Result<Option<String>> {
+    let name = match entry.attr_value(gimli::DW_AT_name)? {
+        Some(name) => Some(name),
+        None => match entry.attr_value(gimli::DW_AT_abstract_origin)? {
+            Some(AttributeValue::UnitRef(offset)) => context.unit.entry(offset)?.attr_value(gimli::DW_AT_name)?,
+            _ => None,
+        },
+    };
+    match name {
+        Some(name) => Ok(Some(String::from(context.dwarf.attr_string(context.unit, name)?.to_string()?))),
+        None => Ok(None),
+    }
+}
+
+/// Returns the location of the given variable entry at the context's address.
+fn variable_location(context: &LocationContext, entry: &gimli::DebuggingInformationEntry<Slice>) -> gimli::Result<VariableLocation> {
+    let expression = match entry.attr_value(gimli::DW_AT_location)? {
+        None => return Ok(VariableLocation::OptimizedOut),
+        Some(AttributeValue::Exprloc(expression)) => expression,
+        Some(AttributeValue::LocationListsRef(offset)) => {
+            let mut locations = context.dwarf.locations(context.unit, offset)?;
+            let mut found = None;
+            while let Some(location) = locations.next()? {
+                if location.range.begin <= context.address && context.address < location.range.end {
+                    found = Some(location.data);
+                    break;
+                }
+            }
+            match found {
+                Some(expression) => expression,
+                None => return Ok(VariableLocation::OptimizedOut),
+            }
+        }
+        Some(_) => return Ok(VariableLocation::Unsupported),
+    };
+
+    let mut evaluation = expression.evaluation(context.unit.encoding());
+    let mut frame_base_relative = false;
+    let mut thread_local = false;
+    let mut result = evaluation.evaluate()?;
+    loop {
+        result = match result {
+            EvaluationResult::Complete => break,
+            EvaluationResult::RequiresFrameBase => {
+                // Evaluating against a frame base of zero yields the offset from the actual frame base.
+                frame_base_relative = true;
+                evaluation.resume_with_frame_base(0)?
+            }
+            EvaluationResult::RequiresTls(offset) => {
+                // In Theseus, a relocation against a TLS section in a debug section resolves to
+                // that section's offset from the TLS self pointer, as assigned by the `TlsInitializer`.
+                thread_local = true;
+                let tls_self_pointer = context.tls_self_pointer.unwrap_or(0) as u64;
+                evaluation.resume_with_tls(tls_self_pointer.wrapping_add(offset))?
+            }
+            EvaluationResult::RequiresRelocatedAddress(address) => {
+                // Debug sections are relocated when they're loaded.
+                evaluation.resume_with_relocated_address(address)?
+            }
+            _ => return Ok(VariableLocation::Unsupported),
+        };
+    }
+
+    let pieces = evaluation.result();
+    Ok(match pieces.first().map(|piece| &piece.location) {
+        Some(gimli::Location::Register { register }) => VariableLocation::Register(register.0),
+        Some(&gimli::Location::Address { address }) if thread_local => {
+            let tls_self_pointer = context.tls_self_pointer.unwrap_or(0);
+            VariableLocation::Tls {
+                offset: (address as usize).wrapping_sub(tls_self_pointer) as isize,
+                section: None,
+                address: context.tls_self_pointer.and_then(|_| VirtualAddress::new(address as usize)),
+            }
+        }
+        Some(&gimli::Location::Address { address }) if frame_base_relative => VariableLocation::FrameBaseOffset(address as i64),
+        Some(&gimli::Location::Address { address }) => VirtualAddress::new(address as usize)
+            .map_or(VariableLocation::Unsupported, VariableLocation::Address),
+        Some(gimli::Location::Empty) | None => VariableLocation::OptimizedOut,
+        Some(_) => VariableLocation::Unsupported,
+    })
+}
@@ -130,6 +130,25 @@ pub fn register_interrupt_handler<DIA, Arg, Success, Failure, S>(
             }
         })?;
 
+    spawn_deferred_task(deferred_interrupt_action, deferred_action_argument, deferred_task_name)
+        .map_err(InterruptRegistrationError::SpawnError)
+}
+
+
+/// Spawns a "deferred task" without registering an interrupt handler for it.
+///
+/// This is useful for devices whose interrupt handlers are registered separately,
+/// e.g., a device with multiple MSI or MSI-X vectors that all wake up the same deferred task.
+/// The arguments and returned task are the same as in [`register_interrupt_handler()`].
+pub fn spawn_deferred_task<DIA, Arg, Success, Failure, S>(
+    deferred_interrupt_action: DIA,
+    deferred_action_argument: Arg,
+    deferred_task_name: Option<S>,
+) -> Result<JoinableTaskRef, &'static str>
+    where DIA: Fn(&Arg) -> Result<Success, Failure> + Send + 'static,
+          Arg: Send + 'static,
+          S: Into<String>,
+{
     // Spawn the deferred task, which should be initially blocked from running.
     // It will be unblocked by the interrupt handler whenever it needs to run.
     let mut tb = spawn::new_task_builder(
@@ -139,7 +158,7 @@ pub fn register_interrupt_handler<DIA, Arg, Success, Failure, S>(
     if let Some(name) = deferred_task_name {
         tb = tb.name(name.into());
     }
-    tb.spawn().map_err(InterruptRegistrationError::SpawnError)
+    tb.spawn()
 }
 
 
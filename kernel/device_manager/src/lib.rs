@@ -19,13 +19,17 @@ extern crate network_manager;
 extern crate ethernet_smoltcp_device;
 extern crate mpmc;
 extern crate ixgbe;
-extern crate alloc;
-extern crate fatfs;
-extern crate io;
-extern crate core2;
-#[macro_use] extern crate derive_more;
+#[macro_use] extern crate alloc;
+extern crate fat_fs;
+extern crate ext2;
+extern crate root;
+extern crate fs_node;
+extern crate path;
+extern crate vfs;
 extern crate mlx5;
 extern crate net;
+extern crate virtio_net;
+extern crate iommu;
 
 use core::convert::TryFrom;
 use mpmc::Queue;
@@ -34,10 +38,10 @@ use memory::MemoryManagementInfo;
 use ethernet_smoltcp_device::EthernetNetworkInterface;
 use network_manager::add_to_network_interfaces;
 use alloc::vec::Vec;
-use io::{ByteReaderWriterWrapper, LockableIo, ReaderWriter};
 use serial_port::{SerialPortAddress, take_serial_port_basic};
-use storage_manager::StorageDevice;
 use memory::PhysicalAddress;
+use fs_node::DirRef;
+use path::Path;
 
 /// A randomly chosen IP address that must be outside of the DHCP range.
 /// TODO: use DHCP to acquire an IP address.
@@ -110,6 +114,13 @@ pub fn init(key_producer: Queue<Event>, mouse_producer: Queue<Event>) -> Result<
         debug!("Found pci device: {:X?}", dev);
     } 
 
+    // Devices bypass IOMMU translation until their drivers isolate them in their own DMA domains.
+    if iommu::iommu_present() {
+        if let Err(e) = iommu::enable_translation(pci::pci_device_iter().map(|dev| dev.location)) {
+            error!("Failed to enable IOMMU translation, so DMA won't be isolated: {}", e);
+        }
+    }
+
     // store all the initialized ixgbe NICs here to be added to the network interface list
     let mut ixgbe_devs = Vec::new();
 
@@ -184,6 +195,20 @@ pub fn init(key_producer: Queue<Event>, mouse_producer: Queue<Event>) -> Result<
                 continue;
             }
 
+            if dev.vendor_id == virtio_net::VIRTIO_VENDOR_ID
+                && (dev.device_id == virtio_net::VIRTIO_NET_DEV_ID || dev.device_id == virtio_net::VIRTIO_NET_TRANSITIONAL_DEV_ID)
+            {
+                info!("virtio-net PCI device found at: {:?}", dev.location);
+                let nic = virtio_net::VirtioNetNic::init(dev)?;
+                let interface = net::register_device(nic);
+                nic.lock().init_interrupts(interface)?;
+
+                let virtio_net_interface = EthernetNetworkInterface::new_ipv4_interface(nic, DEFAULT_LOCAL_IP, &DEFAULT_GATEWAY_IP)?;
+                add_to_network_interfaces(virtio_net_interface);
+
+                continue;
+            }
+
             // here: check for and initialize other ethernet cards
         }
 
@@ -207,106 +232,33 @@ pub fn init(key_producer: Queue<Event>, mouse_producer: Queue<Event>) -> Result<
         warn!("Note: no network devices found on this system.");
     }
 
-    // Discover filesystems from each storage device on the storage controllers initialized above
+    // Discover FAT and ext2 filesystems on each storage device initialized above
     // and mount each filesystem to the root directory by default.
-    if false {
-        for storage_device in storage_manager::storage_devices() {
-            let disk = FatFsAdapter(
-                ReaderWriter::new(
-                    ByteReaderWriterWrapper::from(
-                        LockableIo::<dyn StorageDevice + Send, spin::Mutex<_>, _>::from(storage_device)
-                    )
-                ),
-            );
-
-            if let Ok(filesystem) = fatfs::FileSystem::new(disk, fatfs::FsOptions::new()) {
-                debug!("FATFS data:
-                    fat_type: {:?},
-                    volume_id: {:X?},
-                    volume_label: {:?},
-                    cluster_size: {:?},
-                    status_flags: {:?},
-                    stats: {:?}",
-                    filesystem.fat_type(),
-                    filesystem.volume_id(),
-                    filesystem.volume_label(),
-                    filesystem.cluster_size(),
-                    filesystem.read_status_flags(),
-                    filesystem.stats(),
-                );
-
-                let root = filesystem.root_dir();
-                debug!("Root directory contents:");
-                for f in root.iter() {
-                    debug!("\t {:X?}", f.map(|entry| (entry.file_name(), entry.attributes(), entry.len())));
-                }
+    for (i, storage_device) in storage_manager::storage_devices().enumerate() {
+        let name = format!("fat{i}");
+        match fat_fs::mount(storage_device.clone(), name.clone(), root::get_root()) {
+            Ok(fs_root) => {
+                register_mount(&name, fs_root, "fat");
+                continue;
             }
+            Err(e) => debug!("Storage device {} does not contain a mountable FAT filesystem: {}", i, e),
+        }
+        let name = format!("ext2{i}");
+        match ext2::mount(storage_device, name.clone(), root::get_root()) {
+            Ok(fs_root) => register_mount(&name, fs_root, "ext2"),
+            Err(e) => debug!("Storage device {} does not contain a mountable ext2 filesystem: {}", i, e),
         }
     }
 
     Ok(())
 }
 
-// TODO: move the following `FatFsAdapter` stuff into a separate crate. 
-
-/// An adapter (wrapper type) that implements traits required by the [`fatfs`] crate
-/// for any I/O device that wants to be usable by [`fatfs`].
-///
-/// To meet [`fatfs`]'s requirements, the underlying I/O stream must be able to 
-/// read, write, and seek while tracking its current offset. 
-/// We use traits from the [`core2`] crate to meet these requirements, 
-/// thus, the given `IO` parameter must implement those [`core2`] traits.
-///
-/// For example, this allows one to access a FAT filesystem 
-/// by reading from or writing to a storage device.
-pub struct FatFsAdapter<IO>(IO);
-impl<IO> FatFsAdapter<IO> {
-    pub fn new(io: IO) -> FatFsAdapter<IO> { FatFsAdapter(io) }
-}
-/// This tells the `fatfs` crate that our read/write/seek functions
-/// may return errors of the type [`FatFsIoErrorAdapter`],
-/// which is a simple wrapper around [`core2::io::Error`].
-impl<IO> fatfs::IoBase for FatFsAdapter<IO> {
-    type Error = FatFsIoErrorAdapter;
-}
-impl<IO> fatfs::Read for FatFsAdapter<IO> where IO: core2::io::Read {
-    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
-        self.0.read(buf).map_err(Into::into)
-    }
-}
-impl<IO> fatfs::Write for FatFsAdapter<IO> where IO: core2::io::Write {
-    fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
-        self.0.write(buf).map_err(Into::into)
-    }
-    fn flush(&mut self) -> Result<(), Self::Error> {
-        self.0.flush().map_err(Into::into)
-    }
-}
-impl<IO> fatfs::Seek for FatFsAdapter<IO> where IO: core2::io::Seek {
-    fn seek(&mut self, pos: fatfs::SeekFrom) -> Result<u64, Self::Error> {
-        let core2_pos = match pos {
-            fatfs::SeekFrom::Start(s)   => core2::io::SeekFrom::Start(s),
-            fatfs::SeekFrom::Current(c) => core2::io::SeekFrom::Current(c),
-            fatfs::SeekFrom::End(e)     => core2::io::SeekFrom::End(e),
-        };
-        self.0.seek(core2_pos).map_err(Into::into)
-    }
-}
-
-/// This struct exists to enable us to implement the [`fatfs::IoError`] trait
-/// for the [`core2::io::Error`] trait.
-/// 
-/// This is required because Rust prevents implementing foreign traits for foreign types.
-#[derive(Debug, From, Into)]
-pub struct FatFsIoErrorAdapter(core2::io::Error);
-impl fatfs::IoError for FatFsIoErrorAdapter {
-    fn is_interrupted(&self) -> bool {
-        self.0.kind() == core2::io::ErrorKind::Interrupted
-    }
-    fn new_unexpected_eof_error() -> Self {
-        FatFsIoErrorAdapter(core2::io::ErrorKind::UnexpectedEof.into())
-    }
-    fn new_write_zero_error() -> Self {
-        FatFsIoErrorAdapter(core2::io::ErrorKind::WriteZero.into())
+/// Records a filesystem that was mounted as the given `name` in the root directory
+/// in the VFS mount table.
+fn register_mount(name: &str, fs_root: DirRef, fs_type: &'static str) {
+    let path = Path::new(format!("/{name}"));
+    match vfs::mount(&path, fs_root, fs_type) {
+        Ok(()) => info!("Mounted {} filesystem at {}", fs_type, path),
+        Err(e) => error!("Failed to register {} filesystem at {} in the mount table: {}", fs_type, path, e),
     }
 }
@@ -11,6 +11,14 @@
 //! task, and the executor loops around, polling the future again. It will
 //! continue doing so until the future returns ready.
 //!
+//! Futures can carry their own task-local values across `.await` points;
+//! see the [`task_local`] module.
+//!
+//! To run many futures on a fixed set of OS tasks instead of one task per future,
+//! use a multi-worker [`runtime::Runtime`], whose workers steal work from each other.
+//!
+//! All wakers created by this crate are IRQ-safe, i.e., they can be woken from interrupt handlers.
+//!
 //! The crate is named after the [Executor-class Start
 //! Dreadnought][dreadnought] (`super_star_destroyer` was a bit too on the
 //! nose).
@@ -24,13 +32,15 @@ extern crate alloc;
 use alloc::{sync::Arc, task::Wake};
 use core::{
     future::Future,
+    sync::atomic::{AtomicBool, Ordering},
     task::{Context, Poll},
 };
-use mutex_sleep::MutexSleep as Mutex;
 
 pub use futures::{future, pin_mut, select_biased, FutureExt};
 
+pub mod runtime;
 pub mod task;
+pub mod task_local;
 pub mod time;
 
 /// Executes a future to completion.
@@ -43,7 +53,7 @@ where
 {
     // Pin the future onto the stack. This works because we don't send it anywhere.
     pin_mut!(future);
-    let activated = Arc::new(Mutex::new(false));
+    let activated = Arc::new(AtomicBool::new(false));
     let task = ::task::get_my_current_task().expect("failed to get current task");
     let waker = core::task::Waker::from(Arc::new(Waker {
         activated: activated.clone(),
@@ -55,13 +65,12 @@ where
         match future.as_mut().poll(&mut context) {
             Poll::Ready(output) => return output,
             Poll::Pending => {
-                let mut activated = activated.lock().expect("failed to lock mutex");
-                if *activated {
-                    *activated = false;
-                    drop(activated);
+                // Block before checking whether the waker was activated,
+                // such that an activation between the check and the block cannot be lost.
+                let _ = task.block();
+                if activated.swap(false, Ordering::AcqRel) {
+                    let _ = task.unblock();
                 } else {
-                    let _ = task.block();
-                    drop(activated);
                     scheduler::schedule();
                 }
             }
@@ -74,18 +83,20 @@ where
 struct Waker {
     /// Whether the waker has been activated.
     ///
-    /// This field ensures `execute` detects if the waker was activated prior to
-    /// `execute` blocking the task. The field cannot be an atomic as the lock
-    /// must be held while blocking or unblocking the task.
-    activated: Arc<Mutex<bool>>,
+    /// This field ensures `block_on` detects if the waker was activated prior to
+    /// `block_on` blocking the task. It is an atomic rather than a lock,
+    /// such that the waker can be activated from interrupt context.
+    activated: Arc<AtomicBool>,
     task: ::task::TaskRef,
 }
 
 impl Wake for Waker {
     fn wake(self: Arc<Self>) {
-        let mut activated = self.activated.lock().expect("failed to lock mutex");
-        *activated = true;
+        self.wake_by_ref();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.activated.store(true, Ordering::Release);
         let _ = self.task.unblock();
-        drop(activated);
     }
 }
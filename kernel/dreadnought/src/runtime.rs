@@ -0,0 +1,425 @@
+//! A multi-worker runtime that runs many futures on a fixed set of OS tasks.
+//!
+//! Each worker is an OS task with its own local queue of runnable jobs, i.e., spawned futures.
+//! A worker runs jobs from its local queue first, then from the runtime's global queue,
+//! and finally steals half of the jobs from another worker's local queue.
+//! A worker with no jobs to run blocks until a job is woken.
+//!
+//! Waking a job only pushes it onto a queue and unblocks an idle worker,
+//! both of which are safe in interrupt context, so drivers can wake jobs from interrupt handlers.
+//! A job that is woken on one of the runtime's workers is pushed onto that worker's local queue;
+//! otherwise, it's pushed onto the global queue.
+//!
+//! Task-local values set via [`LocalKey::scope()`](crate::task_local::LocalKey::scope)
+//! follow a job from worker to worker, and the runtime that is running the current job
+//! can be obtained via [`Handle::current()`].
+//!
+//! If a job panics, the worker running it dies and its local jobs are lost.
+
+use alloc::{
+    boxed::Box,
+    collections::VecDeque,
+    format,
+    sync::Arc,
+    task::Wake,
+    vec::Vec,
+};
+use core::{
+    cell::UnsafeCell,
+    fmt,
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicBool, AtomicU8, AtomicUsize, Ordering},
+    task::{Context, Poll, Waker},
+};
+use irq_safety::MutexIrqSafe;
+use task::{JoinableTaskRef, TaskRef};
+
+crate::task_local! {
+    /// The worker that is running the current job.
+    static CURRENT_WORKER: WorkerContext;
+}
+
+/// The job is neither queued nor running.
+const IDLE: u8 = 0;
+/// The job is in a queue, waiting to be run.
+const SCHEDULED: u8 = 1;
+/// The job is being polled by a worker.
+const RUNNING: u8 = 2;
+/// The job was woken while being polled, so it must be polled again.
+const NOTIFIED: u8 = 3;
+/// The job's future has completed.
+const COMPLETE: u8 = 4;
+
+/// A spawned future along with its scheduling state.
+struct Job {
+    state: AtomicU8,
+    /// Only accessed by the worker that moved `state` from `SCHEDULED` to `RUNNING`.
+    future: UnsafeCell<Option<Pin<Box<dyn Future<Output = ()> + Send>>>>,
+    shared: Arc<Shared>,
+}
+
+// SAFETY: the `future` is only accessed by one worker at a time, as guaranteed by the job's `state`.
+unsafe impl Sync for Job {}
+
+impl Wake for Job {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        let mut state = self.state.load(Ordering::Acquire);
+        loop {
+            let new_state = match state {
+                IDLE => SCHEDULED,
+                RUNNING => NOTIFIED,
+                _ => return,
+            };
+            match self.state.compare_exchange_weak(state, new_state, Ordering::AcqRel, Ordering::Acquire) {
+                Ok(_) if new_state == SCHEDULED => return self.shared.schedule(self.clone()),
+                Ok(_) => return,
+                Err(actual) => state = actual,
+            }
+        }
+    }
+}
+
+/// The state of a runtime that is shared by all of its workers, jobs, and handles.
+struct Shared {
+    /// The queue of jobs that were woken or spawned outside of a worker.
+    global: MutexIrqSafe<VecDeque<Arc<Job>>>,
+    workers: Vec<Worker>,
+    /// Incremented for each job that is spawned, and decremented when it completes.
+    live_jobs: AtomicUsize,
+    shutdown: AtomicBool,
+}
+
+/// The per-worker state of a runtime.
+struct Worker {
+    local: MutexIrqSafe<VecDeque<Arc<Job>>>,
+    /// Whether this worker is blocked, or about to block, waiting for jobs.
+    idle: AtomicBool,
+    /// The OS task of this worker, set once it starts running.
+    task: MutexIrqSafe<Option<TaskRef>>,
+}
+
+/// The value of [`CURRENT_WORKER`] for each worker.
+struct WorkerContext {
+    shared: Arc<Shared>,
+    index: usize,
+}
+
+impl Shared {
+    /// Pushes a runnable job onto a queue and wakes up an idle worker to run it.
+    fn schedule(self: &Arc<Self>, job: Arc<Job>) {
+        let local = CURRENT_WORKER.try_with(|worker| {
+            Arc::ptr_eq(&worker.shared, self).then_some(worker.index)
+        }).ok().flatten();
+        match local {
+            Some(index) => self.workers[index].local.lock().push_back(job),
+            None => self.global.lock().push_back(job),
+        }
+        self.wake_idle_worker();
+    }
+
+    fn wake_idle_worker(&self) {
+        for worker in &self.workers {
+            if worker.idle.swap(false, Ordering::AcqRel) {
+                if let Some(task) = worker.task.lock().as_ref() {
+                    let _ = task.unblock();
+                }
+                return;
+            }
+        }
+    }
+
+    fn wake_all_workers(&self) {
+        for worker in &self.workers {
+            worker.idle.store(false, Ordering::Release);
+            if let Some(task) = worker.task.lock().as_ref() {
+                let _ = task.unblock();
+            }
+        }
+    }
+
+    /// Finds the next job for the worker at `index` to run.
+    fn find_job(&self, index: usize) -> Option<Arc<Job>> {
+        if let Some(job) = self.workers[index].local.lock().pop_front() {
+            return Some(job);
+        }
+        if let Some(job) = self.global.lock().pop_front() {
+            return Some(job);
+        }
+        self.steal(index)
+    }
+
+    /// Steals half of the jobs from the first other worker that has any,
+    /// keeping all but one in the local queue of the worker at `index`.
+    fn steal(&self, index: usize) -> Option<Arc<Job>> {
+        let count = self.workers.len();
+        for victim in (1..count).map(|offset| (index + offset) % count) {
+            let mut stolen = {
+                let mut victim_queue = self.workers[victim].local.lock();
+                let keep = victim_queue.len() / 2;
+                victim_queue.split_off(keep)
+            };
+            if let Some(job) = stolen.pop_front() {
+                self.workers[index].local.lock().append(&mut stolen);
+                return Some(job);
+            }
+        }
+        None
+    }
+}
+
+/// Polls a job once, rescheduling it if it was woken while being polled.
+fn run_job(job: Arc<Job>) {
+    job.state.store(RUNNING, Ordering::Release);
+    let waker = Waker::from(job.clone());
+    let mut context = Context::from_waker(&waker);
+
+    // SAFETY: this worker moved the job's state to `RUNNING`, so it has exclusive access to the future.
+    let future = unsafe { &mut *job.future.get() };
+    let Some(inner) = future.as_mut() else { return };
+    if inner.as_mut().poll(&mut context).is_ready() {
+        *future = None;
+        job.state.store(COMPLETE, Ordering::Release);
+        job.shared.live_jobs.fetch_sub(1, Ordering::AcqRel);
+        return;
+    }
+    if job.state.compare_exchange(RUNNING, IDLE, Ordering::AcqRel, Ordering::Acquire).is_err() {
+        // The job was woken while it was being polled.
+        job.state.store(SCHEDULED, Ordering::Release);
+        let shared = job.shared.clone();
+        shared.schedule(job);
+    }
+}
+
+/// The entry point of each worker task.
+fn worker_loop((shared, index): (Arc<Shared>, usize)) {
+    let current = task::get_my_current_task().expect("runtime worker has no current task");
+    *shared.workers[index].task.lock() = Some(current.clone());
+    let context = WorkerContext { shared: shared.clone(), index };
+
+    CURRENT_WORKER.sync_scope(context, || loop {
+        if let Some(job) = shared.find_job(index) {
+            run_job(job);
+            continue;
+        }
+        if shared.shutdown.load(Ordering::Acquire) {
+            return;
+        }
+        // Mark this worker as idle and block before checking for jobs again,
+        // such that a job woken in between cannot be missed.
+        let worker = &shared.workers[index];
+        worker.idle.store(true, Ordering::Release);
+        let _ = current.block();
+        if let Some(job) = shared.find_job(index) {
+            worker.idle.store(false, Ordering::Release);
+            let _ = current.unblock();
+            run_job(job);
+            continue;
+        }
+        if shared.shutdown.load(Ordering::Acquire) {
+            let _ = current.unblock();
+            return;
+        }
+        scheduler::schedule();
+        worker.idle.store(false, Ordering::Release);
+    });
+}
+
+/// A runtime that runs futures on a fixed set of worker tasks.
+///
+/// Dropping the runtime shuts it down; see [`Runtime::shutdown()`].
+pub struct Runtime {
+    handle: Handle,
+    worker_tasks: Vec<JoinableTaskRef>,
+}
+
+impl Runtime {
+    /// Creates a new runtime and spawns its `num_workers` worker tasks.
+    pub fn new(num_workers: usize) -> Result<Runtime, &'static str> {
+        if num_workers == 0 {
+            return Err("a runtime must have at least one worker");
+        }
+        let shared = Arc::new(Shared {
+            global: MutexIrqSafe::new(VecDeque::new()),
+            workers: (0..num_workers).map(|_| Worker {
+                local: MutexIrqSafe::new(VecDeque::new()),
+                idle: AtomicBool::new(false),
+                task: MutexIrqSafe::new(None),
+            }).collect(),
+            live_jobs: AtomicUsize::new(0),
+            shutdown: AtomicBool::new(false),
+        });
+        let handle = Handle { shared };
+        let mut runtime = Runtime { handle, worker_tasks: Vec::with_capacity(num_workers) };
+        for index in 0..num_workers {
+            let task = spawn::new_task_builder(worker_loop, (runtime.handle.shared.clone(), index))
+                .name(format!("dreadnought_worker_{index}"))
+                .spawn()?;
+            runtime.worker_tasks.push(task);
+        }
+        Ok(runtime)
+    }
+
+    /// Returns a handle to this runtime, which can be used to spawn futures onto it.
+    pub fn handle(&self) -> &Handle {
+        &self.handle
+    }
+
+    /// Spawns a future onto this runtime; see [`Handle::spawn()`].
+    pub fn spawn<F>(&self, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        self.handle.spawn(future)
+    }
+
+    /// Runs the given future to completion on the current task,
+    /// while the runtime's workers run the futures spawned onto it.
+    pub fn block_on<F: Future>(&self, future: F) -> F::Output {
+        crate::block_on(future)
+    }
+
+    /// Shuts down this runtime, waiting for all of its workers to exit.
+    ///
+    /// Jobs that haven't completed are dropped without being polled again.
+    pub fn shutdown(mut self) {
+        self.shutdown_inner();
+    }
+
+    fn shutdown_inner(&mut self) {
+        let shared = &self.handle.shared;
+        shared.shutdown.store(true, Ordering::Release);
+        shared.global.lock().clear();
+        for worker in &shared.workers {
+            worker.local.lock().clear();
+        }
+        shared.wake_all_workers();
+        for task in self.worker_tasks.drain(..) {
+            let _ = task.join();
+        }
+    }
+}
+
+impl Drop for Runtime {
+    fn drop(&mut self) {
+        self.shutdown_inner();
+    }
+}
+
+impl fmt::Debug for Runtime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Runtime")
+            .field("workers", &self.handle.shared.workers.len())
+            .field("live_jobs", &self.handle.shared.live_jobs.load(Ordering::Relaxed))
+            .finish()
+    }
+}
+
+/// A cloneable reference to a [`Runtime`], which can be used to spawn futures onto it.
+#[derive(Clone)]
+pub struct Handle {
+    shared: Arc<Shared>,
+}
+
+impl Handle {
+    /// Returns a handle to the runtime that is running the current job.
+    ///
+    /// # Panics
+    /// Panics if the current task isn't one of a runtime's workers.
+    pub fn current() -> Handle {
+        Self::try_current().expect("not running on a dreadnought runtime")
+    }
+
+    /// Returns a handle to the runtime that is running the current job, if any.
+    pub fn try_current() -> Option<Handle> {
+        CURRENT_WORKER.try_with(|worker| Handle { shared: worker.shared.clone() }).ok()
+    }
+
+    /// Spawns a future onto the runtime, returning a [`JoinHandle`] for its output.
+    ///
+    /// The future begins running in the background immediately;
+    /// the returned handle need not be polled.
+    pub fn spawn<F>(&self, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        let output = Arc::new(JoinState { value: MutexIrqSafe::new(None), waker: MutexIrqSafe::new(None) });
+        let sender = output.clone();
+        let wrapped = async move {
+            let value = future.await;
+            *sender.value.lock() = Some(value);
+            if let Some(waker) = sender.waker.lock().take() {
+                waker.wake();
+            }
+        };
+        let job = Arc::new(Job {
+            state: AtomicU8::new(SCHEDULED),
+            future: UnsafeCell::new(Some(Box::pin(wrapped))),
+            shared: self.shared.clone(),
+        });
+        self.shared.live_jobs.fetch_add(1, Ordering::AcqRel);
+        self.shared.schedule(job);
+        JoinHandle { state: output }
+    }
+
+    /// Returns the number of this runtime's worker tasks.
+    pub fn num_workers(&self) -> usize {
+        self.shared.workers.len()
+    }
+
+    /// Returns the number of spawned futures that haven't yet completed.
+    pub fn live_jobs(&self) -> usize {
+        self.shared.live_jobs.load(Ordering::Acquire)
+    }
+}
+
+impl fmt::Debug for Handle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Handle").field("workers", &self.shared.workers.len()).finish_non_exhaustive()
+    }
+}
+
+/// The output of a spawned future, shared between its job and its [`JoinHandle`].
+struct JoinState<T> {
+    value: MutexIrqSafe<Option<T>>,
+    waker: MutexIrqSafe<Option<Waker>>,
+}
+
+/// A future that resolves to the output of a future spawned onto a [`Runtime`].
+///
+/// Dropping this handle detaches the spawned future, which keeps running.
+pub struct JoinHandle<T> {
+    state: Arc<JoinState<T>>,
+}
+
+impl<T> JoinHandle<T> {
+    /// Returns whether the spawned future has completed.
+    pub fn is_finished(&self) -> bool {
+        self.state.value.lock().is_some()
+    }
+}
+
+impl<T> Future for JoinHandle<T> {
+    type Output = T;
+
+    fn poll(self: Pin<&mut Self>, context: &mut Context<'_>) -> Poll<Self::Output> {
+        // Register the waker before checking for the value, such that a completion in between isn't missed.
+        *self.state.waker.lock() = Some(context.waker().clone());
+        match self.state.value.lock().take() {
+            Some(value) => Poll::Ready(value),
+            None => Poll::Pending,
+        }
+    }
+}
+
+impl<T> fmt::Debug for JoinHandle<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("JoinHandle").field("finished", &self.is_finished()).finish()
+    }
+}
@@ -1,6 +1,6 @@
 //! Asynchronous tasks based on Theseus's native OS [task] subsystem.
 
-use alloc::boxed::Box;
+use alloc::{boxed::Box, string::String};
 use core::{
     future::Future,
     marker::PhantomData,
@@ -74,6 +74,7 @@ where
                         KillReason::Requested => Err(Error::Cancelled),
                         KillReason::Panic(info) => Err(Error::Panic(info)),
                         KillReason::Exception(num) => Err(Error::Exception(num)),
+                        KillReason::LoadFailure(err) => Err(Error::LoadFailure(err)),
                     },
                 },
                 Err(s) => Err(Error::Join(s)),
@@ -94,4 +95,5 @@ pub enum Error {
     /// A `Join` error should not occur; this indicates a BUG in Theseus's task mgmt.
     Join(&'static str),
     Exception(u8),
+    LoadFailure(String),
 }
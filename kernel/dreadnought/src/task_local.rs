@@ -0,0 +1,173 @@
+//! Task-local storage for asynchronous tasks.
+//!
+//! A task-local value is bound to a future via [`LocalKey::scope()`], and is accessible
+//! from within that future (and any futures it awaits) via [`LocalKey::with()`].
+//! Unlike a thread-local value, a task-local value follows its future across `.await` points,
+//! regardless of which OS task (thread) ends up polling the future.
+//!
+//! This is similar to `tokio::task_local!`:
+//! ```ignore
+//! dreadnought::task_local! {
+//!     static REQUEST_ID: u64;
+//! }
+//!
+//! REQUEST_ID.scope(42, async {
+//!     assert_eq!(REQUEST_ID.get(), 42);
+//! }).await;
+//! ```
+//!
+//! The values that are currently in scope form a linked list of frames on the stack of
+//! whichever OS task is polling the future, the head of which is stored in a reserved slot
+//! in that task's TLS area (see [`tls_initializer::task_local_root()`]).
+//! Polling a [`TaskLocalFuture`] pushes a frame before polling its inner future
+//! and pops that frame afterwards, so values never leak into unrelated futures.
+
+use core::{
+    fmt,
+    future::Future,
+    marker::PhantomData,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+/// Declares one or more task-local keys of type [`LocalKey`].
+#[macro_export]
+macro_rules! task_local {
+    ($($(#[$attr:meta])* $vis:vis static $name:ident: $t:ty;)+) => {
+        $(
+            $(#[$attr])*
+            $vis static $name: $crate::task_local::LocalKey<$t> = $crate::task_local::LocalKey::new();
+        )+
+    };
+}
+
+/// A key for a task-local value of type `T`, declared via [`task_local!`].
+pub struct LocalKey<T: 'static> {
+    /// Ensures that each key has a unique address, which identifies it.
+    _unique: u8,
+    _phantom: PhantomData<fn() -> T>,
+}
+
+// SAFETY: a key holds no data; it only identifies a value.
+unsafe impl<T: 'static> Sync for LocalKey<T> { }
+
+/// The error returned when accessing a task-local value that isn't in scope.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AccessError;
+
+impl fmt::Display for AccessError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("task-local value not set")
+    }
+}
+
+/// A frame in the linked list of task-local values that are currently in scope.
+struct Frame {
+    key: *const (),
+    value: *const (),
+    parent: usize,
+}
+
+/// Pops a [`Frame`] when dropped, even if the scoped code panics.
+struct FrameGuard {
+    parent: usize,
+}
+
+impl Drop for FrameGuard {
+    fn drop(&mut self) {
+        tls_initializer::set_task_local_root(self.parent);
+    }
+}
+
+impl<T: 'static> LocalKey<T> {
+    #[doc(hidden)]
+    pub const fn new() -> LocalKey<T> {
+        LocalKey { _unique: 0, _phantom: PhantomData }
+    }
+
+    fn id(&'static self) -> *const () {
+        self as *const Self as *const ()
+    }
+
+    /// Runs the given function `f` with `value` in scope for this key.
+    fn with_frame<R>(&'static self, value: &T, f: impl FnOnce() -> R) -> R {
+        let parent = tls_initializer::task_local_root();
+        let frame = Frame {
+            key: self.id(),
+            value: value as *const T as *const (),
+            parent,
+        };
+        tls_initializer::set_task_local_root(&frame as *const Frame as usize);
+        let _guard = FrameGuard { parent };
+        f()
+    }
+
+    /// Sets this key to `value` while the given `future` runs, including across `.await` points.
+    pub fn scope<F: Future>(&'static self, value: T, future: F) -> TaskLocalFuture<T, F> {
+        TaskLocalFuture { key: self, value, future }
+    }
+
+    /// Sets this key to `value` while the given synchronous function `f` runs.
+    pub fn sync_scope<F: FnOnce() -> R, R>(&'static self, value: T, f: F) -> R {
+        self.with_frame(&value, f)
+    }
+
+    /// Invokes `f` with a reference to the value of this key in the current scope.
+    ///
+    /// # Panics
+    /// Panics if this key isn't set in the current scope.
+    pub fn with<F: FnOnce(&T) -> R, R>(&'static self, f: F) -> R {
+        self.try_with(f).expect("task-local value not set in the current scope")
+    }
+
+    /// Invokes `f` with a reference to the value of this key in the current scope,
+    /// or returns an error if it isn't set.
+    pub fn try_with<F: FnOnce(&T) -> R, R>(&'static self, f: F) -> Result<R, AccessError> {
+        let mut frame_ptr = tls_initializer::task_local_root();
+        while frame_ptr != 0 {
+            // SAFETY: every frame in the list lives on the stack below the current one,
+            //         and is popped before it goes out of scope.
+            let frame = unsafe { &*(frame_ptr as *const Frame) };
+            if frame.key == self.id() {
+                // SAFETY: the frame was pushed by this key, so its value has type `T`.
+                return Ok(f(unsafe { &*(frame.value as *const T) }));
+            }
+            frame_ptr = frame.parent;
+        }
+        Err(AccessError)
+    }
+
+    /// Returns a copy of the value of this key in the current scope.
+    ///
+    /// # Panics
+    /// Panics if this key isn't set in the current scope.
+    pub fn get(&'static self) -> T where T: Clone {
+        self.with(T::clone)
+    }
+}
+
+impl<T: 'static> fmt::Debug for LocalKey<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad("LocalKey { .. }")
+    }
+}
+
+/// A future that sets a task-local value while its inner future is polled.
+///
+/// This is returned by [`LocalKey::scope()`].
+pub struct TaskLocalFuture<T: 'static, F> {
+    key: &'static LocalKey<T>,
+    value: T,
+    future: F,
+}
+
+impl<T: 'static, F: Future> Future for TaskLocalFuture<T, F> {
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, context: &mut Context<'_>) -> Poll<Self::Output> {
+        // SAFETY: we never move `value` or `future` out of the pinned `self`.
+        let this = unsafe { self.get_unchecked_mut() };
+        let future = unsafe { Pin::new_unchecked(&mut this.future) };
+        this.key.with_frame(&this.value, || future.poll(context))
+    }
+}
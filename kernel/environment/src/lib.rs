@@ -4,7 +4,7 @@ extern crate alloc;
 
 use alloc::{string::String, sync::Arc};
 use core::fmt;
-use fs_node::{DirRef, FileOrDir};
+use fs_node::DirRef;
 use hashbrown::HashMap;
 use path::Path;
 
@@ -29,15 +29,17 @@ impl Environment {
     }
 
     /// Changes the current working directory.
+    ///
+    /// The `path` is resolved by the VFS, so it may traverse mount points and symbolic links.
     #[doc(alias("change"))]
     pub fn chdir(&mut self, path: &Path) -> Result<()> {
-        match path.get(&self.working_dir) {
-            Some(FileOrDir::Dir(dir_ref)) => {
+        match vfs::resolve_dir(path, &self.working_dir) {
+            Ok(dir_ref) => {
                 self.working_dir = dir_ref;
                 Ok(())
             }
-            Some(FileOrDir::File(_)) => Err(Error::NotADirectory),
-            None => Err(Error::NotFound),
+            Err(vfs::Error::NotADirectory) => Err(Error::NotADirectory),
+            Err(_) => Err(Error::NotFound),
         }
     }
 
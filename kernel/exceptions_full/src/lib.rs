@@ -6,7 +6,7 @@
 #![feature(abi_x86_interrupt)]
 
 use log::{warn, debug, trace};
-use memory::{VirtualAddress, Page};
+use memory::VirtualAddress;
 use signal_handler::{Signal, SignalContext, ErrorCode};
 use x86_64::{
     registers::control::Cr2,
@@ -17,8 +17,15 @@ use x86_64::{
 };
 use locked_idt::LockedIdt;
 use fault_log::log_exception;
+use crash_dump::FaultInfo;
+use tls_initializer::gs::KernelGsGuard;
+use tracepoint::Tracepoint;
+use metrics::Counter;
 
 
+/// The number of page faults that occurred, including those that were resolved.
+static PAGE_FAULTS: Counter = Counter::new("page_faults_total", "Number of page faults, including resolved ones");
+
 /// Initialize the given `idt` with fully-featured exception handlers.
 /// 
 /// This only sets the exception `Entry`s in the `IDT`, i.e.,
@@ -61,6 +68,10 @@ pub fn init(idt_ref: &'static LockedIdt) {
     }
 
     idt_ref.load();
+
+    if let Err(e) = PAGE_FAULTS.register() {
+        warn!("Failed to register page fault metrics: {}", e);
+    }
 }
 
 
@@ -98,13 +109,21 @@ fn kill_and_halt(
     print_stack_trace: bool
 ) {
     // First, log the exception that merits a kill operation.
-    {
+    let fault = {
         let (err, addr) = match error_code {
             Some(ErrorCode::PageFaultError {accessed_address, pf_error}) => (Some(pf_error.bits()), Some(accessed_address)),
             Some(ErrorCode::Other(e)) => (Some(e), None),
             None => (None, None),
         };
         log_exception(exception_number, stack_frame.instruction_pointer.as_u64() as usize, err, addr);
+        FaultInfo::from_exception(exception_number, stack_frame, err, addr)
+    };
+
+    // Capture a crash dump right away if killing the current task can't recover from this exception.
+    let mut dump_captured = false;
+    if is_unrecoverable(exception_number) {
+        crash_dump::capture(&fault);
+        dump_captured = true;
     }
 
 
@@ -151,6 +170,11 @@ fn kill_and_halt(
                     ).map(|(sec, offset)| (sec.name.clone(), offset));
                     if let Some((symbol_name, offset)) = symbol_offset {
                         println_both!("  {:>#018X} in {} + {:#X}", stack_frame.call_site_address(), symbol_name, offset);
+                        // Only debug info that's already retained can be used here, as loading it may fault again.
+                        let call_site = VirtualAddress::new_canonical(stack_frame.call_site_address() as usize);
+                        if let Some(location) = debug_info::registry::cached_source_location(call_site) {
+                            println_both!("      at {}", location);
+                        }
                     } else {
                         println_both!("  {:>#018X} in ??", stack_frame.call_site_address());
                     }
@@ -233,16 +257,30 @@ fn kill_and_halt(
     // But in general, this task should have already been marked as killed and thus no longer schedulable,
     // so it should not reach this point. 
     // Only exceptions during the early OS initialization process will get here, meaning that the OS will basically stop.
+    // Without `unwind_exceptions`, a successfully-killed task also gets here, but that's not a crash.
+    let killed = task::with_current_task(|t| t.has_exited()).unwrap_or(false);
+    if !dump_captured && !killed {
+        crash_dump::capture(&fault);
+    }
     loop { }
 }
 
 
-/// Checks whether the given `vaddr` falls within a stack guard page, indicating stack overflow. 
-fn is_stack_overflow(vaddr: VirtualAddress) -> bool {
-    let page = Page::containing_address(vaddr);
+/// Returns whether the given exception cannot be recovered from by killing the current task,
+/// either because it indicates a machine-level problem or because there is no current task to kill.
+fn is_unrecoverable(exception_number: u8) -> bool {
+    // 0x8 is a double fault and 0x12 is a machine check.
+    matches!(exception_number, 0x8 | 0x12) || task::get_my_current_task().is_none()
+}
+
+
+/// Checks whether the given `vaddr` falls within the current task's stack guard page, indicating stack overflow.
+///
+/// Returns the ID of the current task if so.
+fn stack_overflow_task_id(vaddr: VirtualAddress) -> Option<usize> {
     task::with_current_task(|t|
-        t.with_kstack(|kstack| kstack.guard_page().contains(&page))
-    ).unwrap_or(false)
+        t.with_kstack(|kstack| kstack.is_guard_page_address(vaddr)).then_some(t.id)
+    ).ok().flatten()
 }
 
 /// Converts the given `exception_number` into a [`Signal`] category, if relevant.
@@ -265,11 +303,23 @@ extern "x86-interrupt" fn divide_error_handler(stack_frame: InterruptStackFrame)
 
 /// exception 0x01
 extern "x86-interrupt" fn debug_handler(stack_frame: InterruptStackFrame) {
+    let _gs_guard = KernelGsGuard::enter_paranoid();
+    // A data watchpoint traps after the offending instruction, so the instruction pointer is just past it.
+    for watchpoint in watchpoint::take_triggered() {
+        let next_instruction = VirtualAddress::new_canonical(stack_frame.instruction_pointer.as_u64() as usize);
+        let symbolized = task::with_current_task(|t| t.get_namespace().symbolize_address(next_instruction))
+            .ok()
+            .flatten();
+        match symbolized {
+            Some(s) => println_both!("\nEXCEPTION: DEBUG EXCEPTION: {} hit by the instruction before {}", watchpoint, s),
+            None => println_both!("\nEXCEPTION: DEBUG EXCEPTION: {} hit by the instruction before {:#X}", watchpoint, next_instruction),
+        }
+    }
     println_both!("\nEXCEPTION: DEBUG EXCEPTION\n{:#X?}", stack_frame);
     // don't halt here, this isn't a fatal/permanent failure, just a brief pause.
 }
 
-/// exception 0x02, also used for TLB Shootdown IPIs and sampling interrupts.
+/// exception 0x02, also used for TLB Shootdown IPIs, sampling interrupts and the watchdog.
 ///
 /// # Important Note
 /// Acquiring ANY locks in this function, even irq-safe ones, could cause a deadlock
@@ -277,6 +327,8 @@ extern "x86-interrupt" fn debug_handler(stack_frame: InterruptStackFrame) {
 /// another regular interrupt. 
 /// This includes printing to the log (e.g., `debug!()`) or the screen.
 extern "x86-interrupt" fn nmi_handler(stack_frame: InterruptStackFrame) {
+    // An NMI may interrupt a kernel entry/exit path before or after its `swapgs`.
+    let _gs_guard = KernelGsGuard::enter_paranoid();
     let mut expected_nmi = false;
 
     // currently we're using NMIs to send TLB shootdown IPIs
@@ -302,6 +354,19 @@ extern "x86-interrupt" fn nmi_handler(stack_frame: InterruptStackFrame) {
         }
     }
 
+    // The watchdog uses NMIs to capture a backtrace of a stalled CPU, or to halt all CPUs.
+    if watchdog::handle_nmi(
+        stack_frame.instruction_pointer.as_u64() as usize,
+        stack_frame.stack_pointer.as_u64() as usize,
+    ) {
+        expected_nmi = true;
+    }
+
+    // An offline CPU is parked until an NMI wakes it up to come back online.
+    if cpu_hotplug::handle_nmi() {
+        expected_nmi = true;
+    }
+
     if expected_nmi {
         return;
     }
@@ -351,13 +416,20 @@ extern "x86-interrupt" fn device_not_available_handler(stack_frame: InterruptSta
 
 /// exception 0x08
 extern "x86-interrupt" fn double_fault_handler(stack_frame: InterruptStackFrame, error_code: u64) -> ! {
+    // A double fault may be caused by a clobbered TLS register, which everything below relies on.
+    // This handler never returns to the interrupted context, so it's safe to repair it.
+    if let Ok(true) = tls_initializer::repair_tls_register() {
+        println_both!("\nNote: the TLS register was clobbered and has been restored.");
+    }
     let accessed_vaddr = Cr2::read_raw();
     println_both!("\nEXCEPTION: DOUBLE FAULT\n{:#X?}\nTried to access {:#X}
         Note: double faults in Theseus are typically caused by stack overflow, is the stack large enough?",
         stack_frame, accessed_vaddr,
     );
-    if is_stack_overflow(VirtualAddress::new_canonical(accessed_vaddr as usize)) {
-        println_both!("--> This double fault was definitely caused by stack overflow, tried to access {:#X}.\n", accessed_vaddr);
+    if let Some(task_id) = stack_overflow_task_id(VirtualAddress::new_canonical(accessed_vaddr as usize)) {
+        println_both!("--> This double fault was definitely caused by stack overflow in task {}, tried to access {:#X}.\n",
+            task_id, accessed_vaddr,
+        );
     }
     
     kill_and_halt(0x8, &stack_frame, Some(error_code.into()), false);
@@ -384,23 +456,55 @@ extern "x86-interrupt" fn stack_segment_fault_handler(stack_frame: InterruptStac
 
 /// exception 0x0D
 extern "x86-interrupt" fn general_protection_fault_handler(stack_frame: InterruptStackFrame, error_code: u64) {
+    let _gs_guard = KernelGsGuard::enter(stack_frame.code_segment);
     println_both!("\nEXCEPTION: GENERAL PROTECTION FAULT\n{:#X?}\nError code: {:#b}", stack_frame, error_code);
     kill_and_halt(0xD, &stack_frame, Some(error_code.into()), true)
 }
 
 /// exception 0x0E
 extern "x86-interrupt" fn page_fault_handler(stack_frame: InterruptStackFrame, error_code: PageFaultErrorCode) {
+    let _gs_guard = KernelGsGuard::enter(stack_frame.code_segment);
     let accessed_vaddr = Cr2::read_raw() as usize;
+    tracepoint::trace(Tracepoint::PageFault, accessed_vaddr as u64, error_code.bits());
+    PAGE_FAULTS.inc();
+
+    // An access to a stack's guard page is never resolvable, so check for that first,
+    // which also avoids acquiring any page table locks below after a stack overflow.
+    let stack_overflow_task = stack_overflow_task_id(VirtualAddress::new_canonical(accessed_vaddr));
+
+    if stack_overflow_task.is_none() {
+        // A write to a present, read-only page may be a write to a copy-on-write page,
+        // which is resolved by giving that page its own copy, after which the write is retried.
+        if error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION | PageFaultErrorCode::CAUSED_BY_WRITE)
+            && memory::handle_copy_on_write_fault(VirtualAddress::new_canonical(accessed_vaddr))
+        {
+            return;
+        }
+        // An access to a page that is not present may be the first access to a demand-paged page,
+        // which is resolved by backing that page with a new frame, after which the access is retried.
+        if !error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION)
+            && memory::handle_demand_paging_fault(VirtualAddress::new_canonical(accessed_vaddr))
+        {
+            return;
+        }
+    }
 
     #[cfg(not(downtime_eval))] {
-        println_both!("\nEXCEPTION: PAGE FAULT while accessing {:#x}\n\
-            error code: {:?}\n{:#X?}",
-            accessed_vaddr,
-            error_code,
-            stack_frame
-        );
-        if is_stack_overflow(VirtualAddress::new_canonical(accessed_vaddr)) {
-            println_both!("--> Page fault was caused by stack overflow, tried to access {:#X}\n.", accessed_vaddr);
+        if let Some(task_id) = stack_overflow_task {
+            println_both!("\nEXCEPTION: PAGE FAULT: stack overflow in task {}, tried to access guard page at {:#x}\n\
+                error code: {:?}\n{:#X?}",
+                task_id,
+                accessed_vaddr,
+                error_code,
+                stack_frame
+            );
+        } else {
+            println_both!("\nEXCEPTION: PAGE FAULT while accessing {:#x}\n\
+                error code: {:?}\n{:#X?}",
+                accessed_vaddr,
+                error_code,
+                stack_frame
+            );
         }
     }
     
@@ -422,6 +526,7 @@ extern "x86-interrupt" fn alignment_check_handler(stack_frame: InterruptStackFra
 
 /// exception 0x12
 extern "x86-interrupt" fn machine_check_handler(stack_frame: InterruptStackFrame) -> ! {
+    let _gs_guard = KernelGsGuard::enter_paranoid();
     println_both!("\nEXCEPTION: MACHINE CHECK\n{:#X?}", stack_frame);
     kill_and_halt(0x12, &stack_frame, None, true);
     loop {}
@@ -0,0 +1,219 @@
+//! The on-disk structures of an ext2 filesystem, all of which are little-endian.
+//!
+//! Each structure keeps its raw bytes, such that fields this crate doesn't use
+//! are preserved when the structure is written back.
+
+use core::mem::size_of;
+
+/// The byte offset of the superblock from the start of the device.
+pub const SUPERBLOCK_OFFSET: usize = 1024;
+pub const SUPERBLOCK_SIZE: usize = 1024;
+pub const EXT2_MAGIC: u16 = 0xEF53;
+
+/// Superblock state: the filesystem was cleanly unmounted.
+pub const STATE_VALID: u16 = 1;
+
+/// The inode number of the root directory.
+pub const ROOT_INODE: u32 = 2;
+/// The first non-reserved inode number in revision 0 filesystems.
+pub const GOOD_OLD_FIRST_INODE: u32 = 11;
+pub const GOOD_OLD_INODE_SIZE: usize = 128;
+
+/// Directory entries contain a file type.
+pub const INCOMPAT_FILETYPE: u32 = 0x0002;
+/// Backup superblocks are only stored in some block groups.
+pub const RO_COMPAT_SPARSE_SUPER: u32 = 0x0001;
+/// Regular files may be larger than 4 GiB.
+pub const RO_COMPAT_LARGE_FILE: u32 = 0x0002;
+
+pub const GROUP_DESCRIPTOR_SIZE: usize = 32;
+
+/// The number of block pointers in an inode that point directly at data blocks.
+pub const DIRECT_BLOCKS: usize = 12;
+/// The indices of the singly, doubly, and triply indirect block pointers in an inode.
+pub const INDIRECT_BLOCK: usize = 12;
+pub const DOUBLY_INDIRECT_BLOCK: usize = 13;
+pub const TRIPLY_INDIRECT_BLOCK: usize = 14;
+
+/// The file type bits of an inode's mode.
+pub const S_IFMT:  u16 = 0xF000;
+pub const S_IFREG: u16 = 0x8000;
+pub const S_IFDIR: u16 = 0x4000;
+
+/// Directory entry file types.
+pub const FT_UNKNOWN:  u8 = 0;
+pub const FT_REG_FILE: u8 = 1;
+pub const FT_DIR:      u8 = 2;
+
+/// Generates a getter, and optionally a setter, for each little-endian field at the given byte offset.
+///
+/// Getters are generated even for fields that are only ever written.
+macro_rules! le_fields {
+    ($($getter:ident $(, $setter:ident)?: $ty:ty = $offset:expr;)*) => {
+        $(
+            #[allow(dead_code)]
+            pub fn $getter(&self) -> $ty {
+                let mut bytes = [0; size_of::<$ty>()];
+                bytes.copy_from_slice(&self.raw[$offset .. $offset + size_of::<$ty>()]);
+                <$ty>::from_le_bytes(bytes)
+            }
+            $(
+                pub fn $setter(&mut self, value: $ty) {
+                    self.raw[$offset .. $offset + size_of::<$ty>()].copy_from_slice(&value.to_le_bytes());
+                }
+            )?
+        )*
+    };
+}
+
+/// The superblock, which describes the filesystem as a whole.
+pub struct Superblock {
+    pub raw: [u8; SUPERBLOCK_SIZE],
+}
+
+impl Superblock {
+    le_fields! {
+        inodes_count: u32 = 0;
+        blocks_count: u32 = 4;
+        free_blocks_count, set_free_blocks_count: u32 = 12;
+        free_inodes_count, set_free_inodes_count: u32 = 16;
+        first_data_block: u32 = 20;
+        log_block_size: u32 = 24;
+        blocks_per_group: u32 = 32;
+        inodes_per_group: u32 = 40;
+        mount_time, set_mount_time: u32 = 44;
+        write_time, set_write_time: u32 = 48;
+        mount_count, set_mount_count: u16 = 52;
+        magic: u16 = 56;
+        state, set_state: u16 = 58;
+        rev_level: u32 = 76;
+        first_ino: u32 = 84;
+        inode_size: u16 = 88;
+        feature_incompat: u32 = 96;
+        feature_ro_compat: u32 = 100;
+    }
+}
+
+/// A block group descriptor, which locates a block group's bitmaps and inode table.
+pub struct GroupDescriptor {
+    pub raw: [u8; GROUP_DESCRIPTOR_SIZE],
+}
+
+impl GroupDescriptor {
+    le_fields! {
+        block_bitmap: u32 = 0;
+        inode_bitmap: u32 = 4;
+        inode_table: u32 = 8;
+        free_blocks_count, set_free_blocks_count: u16 = 12;
+        free_inodes_count, set_free_inodes_count: u16 = 14;
+        used_dirs_count, set_used_dirs_count: u16 = 16;
+    }
+}
+
+/// The first 128 bytes of an inode, which are common to all revisions.
+#[derive(Clone)]
+pub struct Inode {
+    pub raw: [u8; GOOD_OLD_INODE_SIZE],
+}
+
+impl Inode {
+    /// Returns a new inode with the given mode and all timestamps set to `time`.
+    pub fn new(mode: u16, time: u32) -> Inode {
+        let mut inode = Inode { raw: [0; GOOD_OLD_INODE_SIZE] };
+        inode.set_mode(mode);
+        inode.set_access_time(time);
+        inode.set_change_time(time);
+        inode.set_modification_time(time);
+        inode
+    }
+
+    le_fields! {
+        mode, set_mode: u16 = 0;
+        size_low, set_size_low: u32 = 4;
+        access_time, set_access_time: u32 = 8;
+        change_time, set_change_time: u32 = 12;
+        modification_time, set_modification_time: u32 = 16;
+        deletion_time, set_deletion_time: u32 = 20;
+        links_count, set_links_count: u16 = 26;
+        sectors, set_sectors: u32 = 28;
+        size_high, set_size_high: u32 = 108;
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.mode() & S_IFMT == S_IFDIR
+    }
+
+    pub fn is_regular_file(&self) -> bool {
+        self.mode() & S_IFMT == S_IFREG
+    }
+
+    /// Returns the size in bytes of this inode's contents.
+    ///
+    /// The upper 32 bits of the size are only used by regular files,
+    /// as that field holds the directory ACL for directories.
+    pub fn size(&self) -> u64 {
+        if self.is_regular_file() {
+            (self.size_high() as u64) << 32 | self.size_low() as u64
+        } else {
+            self.size_low() as u64
+        }
+    }
+
+    pub fn set_size(&mut self, size: u64) {
+        self.set_size_low(size as u32);
+        if self.is_regular_file() {
+            self.set_size_high((size >> 32) as u32);
+        }
+    }
+
+    /// Returns the block pointer at the given index, where a zero pointer denotes a hole.
+    pub fn block(&self, index: usize) -> u32 {
+        let offset = 40 + index * 4;
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(&self.raw[offset .. offset + 4]);
+        u32::from_le_bytes(bytes)
+    }
+
+    pub fn set_block(&mut self, index: usize, block: u32) {
+        let offset = 40 + index * 4;
+        self.raw[offset .. offset + 4].copy_from_slice(&block.to_le_bytes());
+    }
+}
+
+/// The fixed-size header of a directory entry, which is followed by the entry's name.
+pub const DIR_ENTRY_HEADER_SIZE: usize = 8;
+
+/// Returns the number of bytes occupied by a directory entry with a name of the given length,
+/// which is rounded up to a multiple of four bytes.
+pub fn dir_entry_size(name_len: usize) -> usize {
+    (DIR_ENTRY_HEADER_SIZE + name_len + 3) & !3
+}
+
+/// The header of a directory entry within a directory block.
+#[derive(Clone, Copy)]
+pub struct DirEntryHeader {
+    /// The entry's inode number, or zero if this entry is unused.
+    pub inode: u32,
+    /// The distance in bytes from the start of this entry to the start of the next one.
+    pub rec_len: u16,
+    pub name_len: u8,
+    pub file_type: u8,
+}
+
+impl DirEntryHeader {
+    pub fn read(bytes: &[u8]) -> DirEntryHeader {
+        DirEntryHeader {
+            inode: u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]),
+            rec_len: u16::from_le_bytes([bytes[4], bytes[5]]),
+            name_len: bytes[6],
+            file_type: bytes[7],
+        }
+    }
+
+    pub fn write(&self, bytes: &mut [u8]) {
+        bytes[0..4].copy_from_slice(&self.inode.to_le_bytes());
+        bytes[4..6].copy_from_slice(&self.rec_len.to_le_bytes());
+        bytes[6] = self.name_len;
+        bytes[7] = self.file_type;
+    }
+}
@@ -0,0 +1,569 @@
+//! The core of an ext2 filesystem: block groups, allocation bitmaps, inodes, and block mapping.
+
+mod dir;
+
+use alloc::{sync::Arc, vec, vec::Vec};
+use core::cmp::min;
+use io::IoError;
+use log::{error, warn};
+use page_cache::PageCache;
+use spin::Mutex;
+use storage_device::StorageDeviceRef;
+use time::{now, WallTime};
+use crate::disk::*;
+
+/// Returns the current wall-clock time as an ext2 timestamp.
+fn timestamp() -> u32 {
+    now::<WallTime>().as_secs() as u32
+}
+
+/// Reads exactly `buffer.len()` bytes from the given byte `offset` of the cached device.
+fn read_exact(cache: &PageCache, buffer: &mut [u8], offset: u64) -> Result<(), IoError> {
+    if cache.read_at(buffer, offset as usize)? != buffer.len() {
+        return Err(IoError::Other("ext2: attempted to read past the end of the device"));
+    }
+    Ok(())
+}
+
+/// The metadata of a mounted filesystem that changes as blocks and inodes are allocated.
+struct FsState {
+    superblock: Superblock,
+    groups: Vec<GroupDescriptor>,
+}
+
+/// A mounted ext2 filesystem.
+///
+/// All accesses to the device go through a [`PageCache`],
+/// and all operations on the filesystem are serialized by a single lock.
+pub struct Ext2Fs {
+    cache: Arc<PageCache>,
+    block_size: usize,
+    inode_size: usize,
+    inodes_per_group: u32,
+    blocks_per_group: u32,
+    first_data_block: u32,
+    blocks_count: u32,
+    /// The first inode number that isn't reserved.
+    first_ino: u32,
+    /// Whether directory entries contain the type of the file they refer to.
+    filetype: bool,
+    /// Whether regular files may be larger than 4 GiB.
+    large_file: bool,
+    read_only: bool,
+    state: Mutex<FsState>,
+}
+
+impl Ext2Fs {
+    /// Mounts the ext2 filesystem on the given storage device.
+    ///
+    /// Filesystems that require unsupported features are rejected,
+    /// and filesystems with unsupported read-only-compatible features are mounted read-only.
+    pub fn mount(device: StorageDeviceRef) -> Result<Arc<Ext2Fs>, &'static str> {
+        let cache = PageCache::new(device)?;
+        let mut superblock = Superblock { raw: [0; SUPERBLOCK_SIZE] };
+        read_exact(&cache, &mut superblock.raw, SUPERBLOCK_OFFSET as u64)?;
+        if superblock.magic() != EXT2_MAGIC {
+            return Err("storage device does not contain an ext2 filesystem");
+        }
+        if superblock.log_block_size() > 2 {
+            return Err("ext2 block sizes larger than 4 KiB are not supported");
+        }
+        let unsupported_incompat = superblock.feature_incompat() & !INCOMPAT_FILETYPE;
+        if unsupported_incompat != 0 {
+            error!("ext2: filesystem requires unsupported features {:#X}", unsupported_incompat);
+            return Err("ext2 filesystem requires unsupported features");
+        }
+        let unsupported_ro_compat = superblock.feature_ro_compat() & !(RO_COMPAT_SPARSE_SUPER | RO_COMPAT_LARGE_FILE);
+        let read_only = unsupported_ro_compat != 0;
+        if read_only {
+            warn!("ext2: mounting read-only because of unsupported features {:#X}", unsupported_ro_compat);
+        }
+        let (inode_size, first_ino) = if superblock.rev_level() == 0 {
+            (GOOD_OLD_INODE_SIZE, GOOD_OLD_FIRST_INODE)
+        } else {
+            (superblock.inode_size() as usize, superblock.first_ino())
+        };
+        let blocks_per_group = superblock.blocks_per_group();
+        let first_data_block = superblock.first_data_block();
+        let blocks_count = superblock.blocks_count();
+        if inode_size < GOOD_OLD_INODE_SIZE
+            || blocks_per_group == 0
+            || superblock.inodes_per_group() == 0
+            || blocks_count <= first_data_block
+        {
+            return Err("ext2 superblock is corrupted");
+        }
+
+        let block_size = 1024 << superblock.log_block_size();
+        let num_groups = ((blocks_count - first_data_block) + blocks_per_group - 1) / blocks_per_group;
+        let table_offset = (first_data_block as u64 + 1) * block_size as u64;
+        let mut groups = Vec::with_capacity(num_groups as usize);
+        for group in 0..num_groups as u64 {
+            let mut descriptor = GroupDescriptor { raw: [0; GROUP_DESCRIPTOR_SIZE] };
+            read_exact(&cache, &mut descriptor.raw, table_offset + group * GROUP_DESCRIPTOR_SIZE as u64)?;
+            groups.push(descriptor);
+        }
+
+        let fs = Arc::new(Ext2Fs {
+            cache,
+            block_size,
+            inode_size,
+            inodes_per_group: superblock.inodes_per_group(),
+            blocks_per_group,
+            first_data_block,
+            blocks_count,
+            first_ino,
+            filetype: superblock.feature_incompat() & INCOMPAT_FILETYPE != 0,
+            large_file: superblock.feature_ro_compat() & RO_COMPAT_LARGE_FILE != 0,
+            read_only,
+            state: Mutex::new(FsState { superblock, groups }),
+        });
+
+        if !read_only {
+            let mut state = fs.state.lock();
+            let superblock = &mut state.superblock;
+            if superblock.state() & STATE_VALID == 0 {
+                warn!("ext2: filesystem was not cleanly unmounted and may contain errors");
+            }
+            // The filesystem is marked as not clean until it's unmounted.
+            superblock.set_state(superblock.state() & !STATE_VALID);
+            superblock.set_mount_count(superblock.mount_count().wrapping_add(1));
+            superblock.set_mount_time(timestamp());
+            fs.write_superblock(&mut state)?;
+            drop(state);
+            fs.sync()?;
+        }
+        Ok(fs)
+    }
+
+    /// Returns whether this filesystem was mounted read-only.
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// Writes back all modified data and metadata to the storage device.
+    pub fn sync(&self) -> Result<(), IoError> {
+        self.cache.flush()
+    }
+
+    /// Ensures that all prior writes reach the device before any subsequent writes,
+    /// which orders metadata updates such that a crash cannot leave dangling references.
+    fn barrier(&self) -> Result<(), IoError> {
+        self.sync()
+    }
+
+    fn check_writable(&self) -> Result<(), IoError> {
+        if self.read_only {
+            Err(IoError::Other("ext2 filesystem is mounted read-only"))
+        } else {
+            Ok(())
+        }
+    }
+
+    fn block_offset(&self, block: u32) -> u64 {
+        block as u64 * self.block_size as u64
+    }
+
+    fn write_bytes(&self, buffer: &[u8], offset: u64) -> Result<(), IoError> {
+        self.cache.write_at(buffer, offset as usize).map(|_| ())
+    }
+
+    fn write_superblock(&self, state: &mut FsState) -> Result<(), IoError> {
+        state.superblock.set_write_time(timestamp());
+        self.write_bytes(&state.superblock.raw, SUPERBLOCK_OFFSET as u64)
+    }
+
+    fn write_group(&self, state: &FsState, group: usize) -> Result<(), IoError> {
+        let offset = self.block_offset(self.first_data_block + 1) + (group * GROUP_DESCRIPTOR_SIZE) as u64;
+        self.write_bytes(&state.groups[group].raw, offset)
+    }
+
+    fn group_of_inode(&self, ino: u32) -> usize {
+        ((ino - 1) / self.inodes_per_group) as usize
+    }
+
+    fn sectors_per_block(&self) -> u32 {
+        (self.block_size / 512) as u32
+    }
+
+    fn pointers_per_block(&self) -> u64 {
+        (self.block_size / 4) as u64
+    }
+
+    fn inode_offset(&self, state: &FsState, ino: u32) -> Result<u64, IoError> {
+        if ino == 0 || ino > state.superblock.inodes_count() {
+            return Err(IoError::Other("ext2: invalid inode number"));
+        }
+        let table = state.groups.get(self.group_of_inode(ino))
+            .ok_or(IoError::Other("ext2: inode is beyond the last block group"))?
+            .inode_table();
+        let index = ((ino - 1) % self.inodes_per_group) as u64;
+        Ok(self.block_offset(table) + index * self.inode_size as u64)
+    }
+
+    fn read_inode(&self, state: &FsState, ino: u32) -> Result<Inode, IoError> {
+        let mut inode = Inode { raw: [0; GOOD_OLD_INODE_SIZE] };
+        read_exact(&self.cache, &mut inode.raw, self.inode_offset(state, ino)?)?;
+        Ok(inode)
+    }
+
+    fn write_inode(&self, state: &FsState, ino: u32, inode: &Inode) -> Result<(), IoError> {
+        self.write_bytes(&inode.raw, self.inode_offset(state, ino)?)
+    }
+
+    /// Finds a clear bit within the given range of bits of a bitmap block, sets it, and returns its index.
+    fn allocate_bit(&self, bitmap_block: u32, start: usize, count: usize) -> Result<Option<usize>, IoError> {
+        let mut bitmap = vec![0u8; self.block_size];
+        let offset = self.block_offset(bitmap_block);
+        read_exact(&self.cache, &mut bitmap, offset)?;
+        let end = min(count, self.block_size * 8);
+        let Some(bit) = (start..end).find(|bit| bitmap[bit / 8] & (1 << (bit % 8)) == 0) else {
+            return Ok(None);
+        };
+        self.write_bytes(&[bitmap[bit / 8] | (1 << (bit % 8))], offset + (bit / 8) as u64)?;
+        Ok(Some(bit))
+    }
+
+    /// Clears the given bit of a bitmap block, which must be set.
+    fn clear_bit(&self, bitmap_block: u32, bit: usize) -> Result<(), IoError> {
+        let offset = self.block_offset(bitmap_block) + (bit / 8) as u64;
+        let mut byte = [0u8];
+        read_exact(&self.cache, &mut byte, offset)?;
+        if byte[0] & (1 << (bit % 8)) == 0 {
+            return Err(IoError::Other("ext2: attempted to free a block or inode that is already free"));
+        }
+        self.write_bytes(&[byte[0] & !(1 << (bit % 8))], offset)
+    }
+
+    /// Allocates a zeroed block, preferably in the given block group.
+    fn allocate_block(&self, state: &mut FsState, preferred_group: usize) -> Result<u32, IoError> {
+        let num_groups = state.groups.len();
+        for group in (preferred_group..num_groups).chain(0..preferred_group) {
+            if state.groups[group].free_blocks_count() == 0 {
+                continue;
+            }
+            let first_block = self.first_data_block as usize + group * self.blocks_per_group as usize;
+            let blocks_in_group = min(self.blocks_per_group as usize, self.blocks_count as usize - first_block);
+            let Some(bit) = self.allocate_bit(state.groups[group].block_bitmap(), 0, blocks_in_group)? else {
+                continue;
+            };
+            let descriptor = &mut state.groups[group];
+            descriptor.set_free_blocks_count(descriptor.free_blocks_count().saturating_sub(1));
+            self.write_group(state, group)?;
+            let superblock = &mut state.superblock;
+            superblock.set_free_blocks_count(superblock.free_blocks_count().saturating_sub(1));
+            self.write_superblock(state)?;
+
+            let block = (first_block + bit) as u32;
+            // Zero the new block, such that holes and unwritten parts of it read as zeros.
+            self.write_bytes(&vec![0; self.block_size], self.block_offset(block))?;
+            return Ok(block);
+        }
+        Err(IoError::Other("ext2: no free blocks remain"))
+    }
+
+    fn free_blocks(&self, state: &mut FsState, blocks: &[u32]) -> Result<(), IoError> {
+        for &block in blocks {
+            if block < self.first_data_block || block >= self.blocks_count {
+                return Err(IoError::Other("ext2: attempted to free an invalid block"));
+            }
+            let group = ((block - self.first_data_block) / self.blocks_per_group) as usize;
+            let bit = ((block - self.first_data_block) % self.blocks_per_group) as usize;
+            self.clear_bit(state.groups[group].block_bitmap(), bit)?;
+            let descriptor = &mut state.groups[group];
+            descriptor.set_free_blocks_count(descriptor.free_blocks_count() + 1);
+            self.write_group(state, group)?;
+        }
+        let superblock = &mut state.superblock;
+        superblock.set_free_blocks_count(superblock.free_blocks_count() + blocks.len() as u32);
+        self.write_superblock(state)
+    }
+
+    /// Allocates an inode, preferably in the given block group, and zeroes its on-disk contents.
+    fn allocate_inode(&self, state: &mut FsState, preferred_group: usize, is_dir: bool) -> Result<u32, IoError> {
+        let num_groups = state.groups.len();
+        for group in (preferred_group..num_groups).chain(0..preferred_group) {
+            if state.groups[group].free_inodes_count() == 0 {
+                continue;
+            }
+            // Never allocate the reserved inodes at the start of the first group.
+            let start = if group == 0 { self.first_ino as usize - 1 } else { 0 };
+            let bitmap = state.groups[group].inode_bitmap();
+            let Some(bit) = self.allocate_bit(bitmap, start, self.inodes_per_group as usize)? else {
+                continue;
+            };
+            let descriptor = &mut state.groups[group];
+            descriptor.set_free_inodes_count(descriptor.free_inodes_count().saturating_sub(1));
+            if is_dir {
+                descriptor.set_used_dirs_count(descriptor.used_dirs_count() + 1);
+            }
+            self.write_group(state, group)?;
+            let superblock = &mut state.superblock;
+            superblock.set_free_inodes_count(superblock.free_inodes_count().saturating_sub(1));
+            self.write_superblock(state)?;
+
+            let ino = (group * self.inodes_per_group as usize + bit + 1) as u32;
+            self.write_bytes(&vec![0; self.inode_size], self.inode_offset(state, ino)?)?;
+            return Ok(ino);
+        }
+        Err(IoError::Other("ext2: no free inodes remain"))
+    }
+
+    fn free_inode(&self, state: &mut FsState, ino: u32, is_dir: bool) -> Result<(), IoError> {
+        let group = self.group_of_inode(ino);
+        let bit = ((ino - 1) % self.inodes_per_group) as usize;
+        self.clear_bit(state.groups[group].inode_bitmap(), bit)?;
+        let descriptor = &mut state.groups[group];
+        descriptor.set_free_inodes_count(descriptor.free_inodes_count() + 1);
+        if is_dir {
+            descriptor.set_used_dirs_count(descriptor.used_dirs_count().saturating_sub(1));
+        }
+        self.write_group(state, group)?;
+        let superblock = &mut state.superblock;
+        superblock.set_free_inodes_count(superblock.free_inodes_count() + 1);
+        self.write_superblock(state)
+    }
+
+    /// Returns the index of the inode's block pointer and the indices within each level
+    /// of indirect blocks that lead to the given file block, along with the number of levels.
+    fn block_path(&self, file_block: u64) -> Result<(usize, [usize; 3], usize), IoError> {
+        let p = self.pointers_per_block();
+        if file_block < DIRECT_BLOCKS as u64 {
+            return Ok((file_block as usize, [0; 3], 0));
+        }
+        let index = file_block - DIRECT_BLOCKS as u64;
+        if index < p {
+            return Ok((INDIRECT_BLOCK, [index as usize, 0, 0], 1));
+        }
+        let index = index - p;
+        if index < p * p {
+            return Ok((DOUBLY_INDIRECT_BLOCK, [(index / p) as usize, (index % p) as usize, 0], 2));
+        }
+        let index = index - p * p;
+        if index < p * p * p {
+            return Ok((TRIPLY_INDIRECT_BLOCK, [(index / (p * p)) as usize, ((index / p) % p) as usize, (index % p) as usize], 3));
+        }
+        Err(IoError::Other("ext2: file offset exceeds the maximum file size"))
+    }
+
+    /// Returns the block that holds the given file block of an inode, or zero if it is a hole.
+    ///
+    /// If `allocate` is `true`, a hole is filled with a newly-allocated block,
+    /// along with any missing indirect blocks that lead to it.
+    /// Each new block is zeroed before it is referenced by its parent.
+    fn map_block(&self, state: &mut FsState, ino: u32, inode: &mut Inode, file_block: u64, allocate: bool) -> Result<u32, IoError> {
+        let (slot, indices, levels) = self.block_path(file_block)?;
+        let group = self.group_of_inode(ino);
+        let mut block = inode.block(slot);
+        if block == 0 {
+            if !allocate {
+                return Ok(0);
+            }
+            block = self.allocate_block(state, group)?;
+            inode.set_block(slot, block);
+            inode.set_sectors(inode.sectors() + self.sectors_per_block());
+        }
+        for &index in &indices[..levels] {
+            let pointer_offset = self.block_offset(block) + index as u64 * 4;
+            let mut pointer = [0u8; 4];
+            read_exact(&self.cache, &mut pointer, pointer_offset)?;
+            let mut next = u32::from_le_bytes(pointer);
+            if next == 0 {
+                if !allocate {
+                    return Ok(0);
+                }
+                next = self.allocate_block(state, group)?;
+                self.write_bytes(&next.to_le_bytes(), pointer_offset)?;
+                inode.set_sectors(inode.sectors() + self.sectors_per_block());
+            }
+            block = next;
+        }
+        Ok(block)
+    }
+
+    /// Unlinks all blocks of the inode that map file blocks at or beyond `keep`,
+    /// along with any indirect blocks that no longer map anything.
+    ///
+    /// The unlinked blocks are returned rather than freed,
+    /// such that they can be freed after the inode no longer refers to them.
+    fn truncate_blocks(&self, inode: &mut Inode, keep: u64) -> Result<Vec<u32>, IoError> {
+        let mut unlinked = Vec::new();
+        for slot in min(keep, DIRECT_BLOCKS as u64) as usize .. DIRECT_BLOCKS {
+            let block = inode.block(slot);
+            if block != 0 {
+                unlinked.push(block);
+                inode.set_block(slot, 0);
+            }
+        }
+        let mut base = DIRECT_BLOCKS as u64;
+        let mut span = self.pointers_per_block();
+        for (levels, slot) in [(1, INDIRECT_BLOCK), (2, DOUBLY_INDIRECT_BLOCK), (3, TRIPLY_INDIRECT_BLOCK)] {
+            let block = inode.block(slot);
+            if block != 0 && keep < base + span && self.truncate_indirect(block, levels, base, keep, &mut unlinked)? {
+                unlinked.push(block);
+                inode.set_block(slot, 0);
+            }
+            base += span;
+            span *= self.pointers_per_block();
+        }
+        inode.set_sectors(inode.sectors().saturating_sub(unlinked.len() as u32 * self.sectors_per_block()));
+        Ok(unlinked)
+    }
+
+    /// Unlinks the blocks that map file blocks at or beyond `keep` within the given indirect block,
+    /// which maps the file blocks starting at `base` through the given number of `levels` of indirection.
+    ///
+    /// Returns whether the indirect block no longer maps anything, in which case it should be unlinked too.
+    fn truncate_indirect(&self, block: u32, levels: u32, base: u64, keep: u64, unlinked: &mut Vec<u32>) -> Result<bool, IoError> {
+        let mut pointers = vec![0u8; self.block_size];
+        read_exact(&self.cache, &mut pointers, self.block_offset(block))?;
+        let span = self.pointers_per_block().pow(levels - 1);
+        let mut modified = false;
+        let mut empty = true;
+        for (i, pointer) in pointers.chunks_exact_mut(4).enumerate() {
+            let child = u32::from_le_bytes([pointer[0], pointer[1], pointer[2], pointer[3]]);
+            if child == 0 {
+                continue;
+            }
+            let child_base = base + i as u64 * span;
+            let unlink = if child_base + span <= keep {
+                false
+            } else if levels == 1 {
+                true
+            } else {
+                self.truncate_indirect(child, levels - 1, child_base, keep, unlinked)?
+            };
+            if unlink {
+                unlinked.push(child);
+                pointer.fill(0);
+                modified = true;
+            } else {
+                empty = false;
+            }
+        }
+        if modified && !empty {
+            self.write_bytes(&pointers, self.block_offset(block))?;
+        }
+        Ok(empty)
+    }
+
+    /// Returns the size in bytes of the given inode's contents.
+    pub(crate) fn len(&self, ino: u32) -> Result<u64, IoError> {
+        let state = self.state.lock();
+        Ok(self.read_inode(&state, ino)?.size())
+    }
+
+    /// Reads from the given inode's contents at the given byte `offset`,
+    /// returning the number of bytes read.
+    pub(crate) fn read(&self, ino: u32, buffer: &mut [u8], offset: u64) -> Result<usize, IoError> {
+        let mut state = self.state.lock();
+        let mut inode = self.read_inode(&state, ino)?;
+        let size = inode.size();
+        if offset > size {
+            return Err(IoError::InvalidInput);
+        }
+        let end = min(offset + buffer.len() as u64, size);
+        let mut pos = offset;
+        while pos < end {
+            let in_block = (pos % self.block_size as u64) as usize;
+            let count = min((self.block_size - in_block) as u64, end - pos) as usize;
+            let dest = &mut buffer[(pos - offset) as usize ..][..count];
+            match self.map_block(&mut state, ino, &mut inode, pos / self.block_size as u64, false)? {
+                0 => dest.fill(0),
+                block => read_exact(&self.cache, dest, self.block_offset(block) + in_block as u64)?,
+            }
+            pos += count as u64;
+        }
+        Ok((end - offset) as usize)
+    }
+
+    /// Writes to the given inode's contents at the given byte `offset`,
+    /// allocating blocks and extending its size as necessary.
+    pub(crate) fn write(&self, ino: u32, buffer: &[u8], offset: u64) -> Result<usize, IoError> {
+        self.check_writable()?;
+        let end = offset.checked_add(buffer.len() as u64).ok_or(IoError::InvalidInput)?;
+        if !self.large_file && end > u32::MAX as u64 {
+            return Err(IoError::Other("ext2: files larger than 4 GiB require the large_file feature"));
+        }
+        let mut state = self.state.lock();
+        let mut inode = self.read_inode(&state, ino)?;
+        let sectors_before = inode.sectors();
+        let mut result = Ok(buffer.len());
+        let mut pos = offset;
+        while pos < end {
+            let in_block = (pos % self.block_size as u64) as usize;
+            let count = min((self.block_size - in_block) as u64, end - pos) as usize;
+            let src = &buffer[(pos - offset) as usize ..][..count];
+            let written = self.map_block(&mut state, ino, &mut inode, pos / self.block_size as u64, true)
+                .and_then(|block| self.write_bytes(src, self.block_offset(block) + in_block as u64));
+            if let Err(e) = written {
+                result = Err(e);
+                break;
+            }
+            pos += count as u64;
+        }
+        // Even upon failure, the inode must be written such that it owns any blocks that were allocated.
+        if inode.sectors() != sectors_before {
+            // New blocks and their contents must reach the device before the inode refers to them.
+            self.barrier()?;
+        }
+        if pos > inode.size() {
+            inode.set_size(pos);
+        }
+        let time = timestamp();
+        inode.set_modification_time(time);
+        inode.set_change_time(time);
+        self.write_inode(&state, ino, &inode)?;
+        result
+    }
+
+    /// Sets the size of the given inode's contents, freeing any blocks beyond the new size.
+    pub(crate) fn set_len(&self, ino: u32, len: u64) -> Result<(), IoError> {
+        self.check_writable()?;
+        if !self.large_file && len > u32::MAX as u64 {
+            return Err(IoError::Other("ext2: files larger than 4 GiB require the large_file feature"));
+        }
+        let mut state = self.state.lock();
+        let mut inode = self.read_inode(&state, ino)?;
+        let block_size = self.block_size as u64;
+        let mut unlinked = Vec::new();
+        if len < inode.size() {
+            unlinked = self.truncate_blocks(&mut inode, (len + block_size - 1) / block_size)?;
+            // Zero the rest of the new last block, such that it reads as zeros if the file is extended again.
+            let tail = (len % block_size) as usize;
+            if tail != 0 {
+                let block = self.map_block(&mut state, ino, &mut inode, len / block_size, false)?;
+                if block != 0 {
+                    self.write_bytes(&vec![0; self.block_size - tail], self.block_offset(block) + tail as u64)?;
+                }
+            }
+        }
+        inode.set_size(len);
+        let time = timestamp();
+        inode.set_modification_time(time);
+        inode.set_change_time(time);
+        self.write_inode(&state, ino, &inode)?;
+        if !unlinked.is_empty() {
+            // The inode must no longer refer to the unlinked blocks before they can be reused.
+            self.barrier()?;
+            self.free_blocks(&mut state, &unlinked)?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Ext2Fs {
+    /// Marks the filesystem as cleanly unmounted and writes back all of its contents.
+    fn drop(&mut self) {
+        if self.read_only {
+            return;
+        }
+        let mut state = self.state.lock();
+        state.superblock.set_state(state.superblock.state() | STATE_VALID);
+        let result = self.write_superblock(&mut state)
+            .and_then(|_| self.sync());
+        if let Err(e) = result {
+            error!("ext2: failed to cleanly unmount filesystem: {:?}", e);
+        }
+    }
+}
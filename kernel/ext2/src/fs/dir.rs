@@ -0,0 +1,312 @@
+//! Directory management: looking up, listing, creating, and removing directory entries.
+//!
+//! Each directory block is a sequence of variable-length records that exactly fills the block.
+//! Removing an entry merges its record into the preceding one,
+//! and adding an entry reuses the slack space at the end of any record large enough to hold it.
+
+use alloc::{string::String, vec, vec::Vec};
+use io::IoError;
+use super::{read_exact, timestamp, Ext2Fs, FsState};
+use crate::disk::*;
+
+const CORRUPTED_DIRECTORY: IoError = IoError::Other("ext2: directory is corrupted");
+
+/// Parses the records within a directory block, returning the offset and header of each one.
+fn records(data: &[u8]) -> Result<Vec<(usize, DirEntryHeader)>, IoError> {
+    let mut records = Vec::new();
+    let mut offset = 0;
+    while offset < data.len() {
+        if data.len() - offset < DIR_ENTRY_HEADER_SIZE {
+            return Err(CORRUPTED_DIRECTORY);
+        }
+        let header = DirEntryHeader::read(&data[offset..]);
+        let rec_len = header.rec_len as usize;
+        if rec_len < DIR_ENTRY_HEADER_SIZE
+            || rec_len % 4 != 0
+            || offset + rec_len > data.len()
+            || DIR_ENTRY_HEADER_SIZE + header.name_len as usize > rec_len
+        {
+            return Err(CORRUPTED_DIRECTORY);
+        }
+        records.push((offset, header));
+        offset += rec_len;
+    }
+    Ok(records)
+}
+
+/// Returns the name of the directory entry at the given offset.
+fn entry_name<'d>(data: &'d [u8], offset: usize, header: &DirEntryHeader) -> &'d [u8] {
+    &data[offset + DIR_ENTRY_HEADER_SIZE ..][..header.name_len as usize]
+}
+
+/// Writes a directory entry with the given header and name at the given offset.
+fn write_entry(data: &mut [u8], offset: usize, header: DirEntryHeader, name: &[u8]) {
+    header.write(&mut data[offset..]);
+    data[offset + DIR_ENTRY_HEADER_SIZE ..][..name.len()].copy_from_slice(name);
+}
+
+impl Ext2Fs {
+    fn file_type(&self, is_dir: bool) -> u8 {
+        match (self.filetype, is_dir) {
+            (false, _) => FT_UNKNOWN,
+            (true, true) => FT_DIR,
+            (true, false) => FT_REG_FILE,
+        }
+    }
+
+    fn read_dir_inode(&self, state: &FsState, ino: u32) -> Result<Inode, IoError> {
+        let inode = self.read_inode(state, ino)?;
+        if !inode.is_dir() {
+            return Err(IoError::Other("ext2: inode is not a directory"));
+        }
+        Ok(inode)
+    }
+
+    /// Returns the number of blocks in the given directory.
+    fn dir_blocks(&self, inode: &Inode) -> u64 {
+        inode.size() / self.block_size as u64
+    }
+
+    /// Reads the given block of a directory, returning its block number and contents.
+    fn read_dir_block(&self, state: &mut FsState, ino: u32, inode: &mut Inode, index: u64) -> Result<(u32, Vec<u8>), IoError> {
+        let block = self.map_block(state, ino, inode, index, false)?;
+        if block == 0 {
+            // Directories never contain holes.
+            return Err(CORRUPTED_DIRECTORY);
+        }
+        let mut data = vec![0u8; self.block_size];
+        read_exact(&self.cache, &mut data, self.block_offset(block))?;
+        Ok((block, data))
+    }
+
+    /// Finds the entry with the given name in a directory, returning its inode number.
+    fn find_entry(&self, state: &mut FsState, ino: u32, inode: &mut Inode, name: &[u8]) -> Result<Option<u32>, IoError> {
+        for index in 0..self.dir_blocks(inode) {
+            let (_, data) = self.read_dir_block(state, ino, inode, index)?;
+            for (offset, header) in records(&data)? {
+                if header.inode != 0 && entry_name(&data, offset, &header) == name {
+                    return Ok(Some(header.inode));
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// Adds an entry to a directory, appending a new block to it if no existing block has room.
+    ///
+    /// The caller must write back the directory's inode, as its size may have changed.
+    fn add_entry(&self, state: &mut FsState, ino: u32, inode: &mut Inode, name: &[u8], child: u32, file_type: u8) -> Result<(), IoError> {
+        let needed = dir_entry_size(name.len());
+        let new_header = |rec_len: usize| DirEntryHeader {
+            inode: child,
+            rec_len: rec_len as u16,
+            name_len: name.len() as u8,
+            file_type,
+        };
+        let num_blocks = self.dir_blocks(inode);
+        for index in 0..num_blocks {
+            let (block, mut data) = self.read_dir_block(state, ino, inode, index)?;
+            for (offset, mut header) in records(&data)? {
+                let rec_len = header.rec_len as usize;
+                if header.inode == 0 && rec_len >= needed {
+                    write_entry(&mut data, offset, new_header(rec_len), name);
+                } else if header.inode != 0 && rec_len - dir_entry_size(header.name_len as usize) >= needed {
+                    // Split the slack space off the end of the existing record.
+                    let used = dir_entry_size(header.name_len as usize);
+                    header.rec_len = used as u16;
+                    header.write(&mut data[offset..]);
+                    write_entry(&mut data, offset + used, new_header(rec_len - used), name);
+                } else {
+                    continue;
+                }
+                return self.write_bytes(&data, self.block_offset(block));
+            }
+        }
+
+        let block = self.map_block(state, ino, inode, num_blocks, true)?;
+        let mut data = vec![0u8; self.block_size];
+        write_entry(&mut data, 0, new_header(self.block_size), name);
+        self.write_bytes(&data, self.block_offset(block))?;
+        inode.set_size(inode.size() + self.block_size as u64);
+        Ok(())
+    }
+
+    /// Removes the entry with the given name from a directory, returning its inode number.
+    fn remove_entry(&self, state: &mut FsState, ino: u32, inode: &mut Inode, name: &[u8]) -> Result<Option<u32>, IoError> {
+        for index in 0..self.dir_blocks(inode) {
+            let (block, mut data) = self.read_dir_block(state, ino, inode, index)?;
+            let records = records(&data)?;
+            let Some(position) = records.iter().position(|(offset, header)|
+                header.inode != 0 && entry_name(&data, *offset, header) == name
+            ) else {
+                continue;
+            };
+            let (offset, mut header) = records[position];
+            let removed = header.inode;
+            if position > 0 {
+                // Merge the removed record into the preceding one.
+                let (prev_offset, mut prev) = records[position - 1];
+                prev.rec_len += header.rec_len;
+                prev.write(&mut data[prev_offset..]);
+            } else {
+                // The first record of a block cannot be merged, so it is marked as unused instead.
+                header.inode = 0;
+                header.write(&mut data[offset..]);
+            }
+            self.write_bytes(&data, self.block_offset(block))?;
+            return Ok(Some(removed));
+        }
+        Ok(None)
+    }
+
+    /// Returns whether a directory contains no entries other than `.` and `..`.
+    fn is_empty_dir(&self, state: &mut FsState, ino: u32, inode: &mut Inode) -> Result<bool, IoError> {
+        for index in 0..self.dir_blocks(inode) {
+            let (_, data) = self.read_dir_block(state, ino, inode, index)?;
+            for (offset, header) in records(&data)? {
+                let name = entry_name(&data, offset, &header);
+                if header.inode != 0 && name != b"." && name != b".." {
+                    return Ok(false);
+                }
+            }
+        }
+        Ok(true)
+    }
+
+    /// Returns whether the given inode is a directory.
+    pub(crate) fn is_dir(&self, ino: u32) -> Result<bool, IoError> {
+        let state = self.state.lock();
+        Ok(self.read_inode(&state, ino)?.is_dir())
+    }
+
+    /// Finds the entry with the given name in a directory,
+    /// returning its inode number and whether it's a directory.
+    pub(crate) fn lookup(&self, dir: u32, name: &str) -> Result<Option<(u32, bool)>, IoError> {
+        let mut state = self.state.lock();
+        let mut inode = self.read_dir_inode(&state, dir)?;
+        let Some(child) = self.find_entry(&mut state, dir, &mut inode, name.as_bytes())? else {
+            return Ok(None);
+        };
+        Ok(Some((child, self.read_inode(&state, child)?.is_dir())))
+    }
+
+    /// Returns the name and whether it's a directory of each entry in a directory,
+    /// excluding the `.` and `..` entries.
+    pub(crate) fn list(&self, dir: u32) -> Result<Vec<(String, bool)>, IoError> {
+        let mut state = self.state.lock();
+        let mut inode = self.read_dir_inode(&state, dir)?;
+        let mut entries = Vec::new();
+        for index in 0..self.dir_blocks(&inode) {
+            let (_, data) = self.read_dir_block(&mut state, dir, &mut inode, index)?;
+            for (offset, header) in records(&data)? {
+                let name = entry_name(&data, offset, &header);
+                if header.inode == 0 || name == b"." || name == b".." {
+                    continue;
+                }
+                let is_dir = match header.file_type {
+                    FT_DIR => true,
+                    FT_UNKNOWN => self.read_inode(&state, header.inode)?.is_dir(),
+                    _ => false,
+                };
+                entries.push((String::from_utf8_lossy(name).into_owned(), is_dir));
+            }
+        }
+        Ok(entries)
+    }
+
+    /// Creates a new, empty file or directory with the given name in a directory,
+    /// returning its inode number.
+    pub(crate) fn create(&self, dir: u32, name: &str, is_dir: bool) -> Result<u32, IoError> {
+        self.check_writable()?;
+        if name.is_empty() || name.len() > 255 || name.contains(['/', '\0']) || name == "." || name == ".." {
+            return Err(IoError::Other("ext2: invalid file name"));
+        }
+        let mut state = self.state.lock();
+        let mut dir_inode = self.read_dir_inode(&state, dir)?;
+        if self.find_entry(&mut state, dir, &mut dir_inode, name.as_bytes())?.is_some() {
+            return Err(IoError::Other("a file or directory with that name already exists"));
+        }
+
+        let ino = self.allocate_inode(&mut state, self.group_of_inode(dir), is_dir)?;
+        let time = timestamp();
+        let mut inode;
+        if is_dir {
+            inode = Inode::new(S_IFDIR | 0o755, time);
+            let block = self.map_block(&mut state, ino, &mut inode, 0, true)?;
+            let mut data = vec![0u8; self.block_size];
+            let dot_len = dir_entry_size(1);
+            let file_type = self.file_type(true);
+            write_entry(&mut data, 0, DirEntryHeader { inode: ino, rec_len: dot_len as u16, name_len: 1, file_type }, b".");
+            write_entry(
+                &mut data,
+                dot_len,
+                DirEntryHeader { inode: dir, rec_len: (self.block_size - dot_len) as u16, name_len: 2, file_type },
+                b"..",
+            );
+            self.write_bytes(&data, self.block_offset(block))?;
+            inode.set_size(self.block_size as u64);
+            inode.set_links_count(2);
+        } else {
+            inode = Inode::new(S_IFREG | 0o644, time);
+            inode.set_links_count(1);
+        }
+        self.write_inode(&state, ino, &inode)?;
+        // The new inode must be initialized before any directory entry refers to it.
+        self.barrier()?;
+
+        self.add_entry(&mut state, dir, &mut dir_inode, name.as_bytes(), ino, self.file_type(is_dir))?;
+        if is_dir {
+            // The new directory's `..` entry refers to its parent.
+            dir_inode.set_links_count(dir_inode.links_count() + 1);
+        }
+        dir_inode.set_modification_time(time);
+        dir_inode.set_change_time(time);
+        // Any block appended to the parent must be written before the parent's size covers it.
+        self.barrier()?;
+        self.write_inode(&state, dir, &dir_inode)?;
+        Ok(ino)
+    }
+
+    /// Removes the entry with the given name from a directory,
+    /// and frees its inode and blocks if no other entries refer to it.
+    ///
+    /// Directories can only be removed if they are empty.
+    pub(crate) fn unlink(&self, dir: u32, name: &str) -> Result<(), IoError> {
+        self.check_writable()?;
+        let mut state = self.state.lock();
+        let mut dir_inode = self.read_dir_inode(&state, dir)?;
+        let ino = self.find_entry(&mut state, dir, &mut dir_inode, name.as_bytes())?
+            .ok_or(IoError::Other("file or directory not found"))?;
+        let mut inode = self.read_inode(&state, ino)?;
+        let is_dir = inode.is_dir();
+        if is_dir && !self.is_empty_dir(&mut state, ino, &mut inode)? {
+            return Err(IoError::Other("directory is not empty"));
+        }
+
+        self.remove_entry(&mut state, dir, &mut dir_inode, name.as_bytes())?;
+        let time = timestamp();
+        if is_dir {
+            dir_inode.set_links_count(dir_inode.links_count().saturating_sub(1));
+        }
+        dir_inode.set_modification_time(time);
+        dir_inode.set_change_time(time);
+        self.write_inode(&state, dir, &dir_inode)?;
+        // The entry must be gone before its inode can be freed and reused.
+        self.barrier()?;
+
+        // A directory's only other link is its own `.` entry.
+        let links = if is_dir { 0 } else { inode.links_count().saturating_sub(1) };
+        inode.set_links_count(links);
+        inode.set_change_time(time);
+        if links > 0 {
+            return self.write_inode(&state, ino, &inode);
+        }
+        let unlinked = self.truncate_blocks(&mut inode, 0)?;
+        inode.set_size(0);
+        inode.set_deletion_time(time);
+        self.write_inode(&state, ino, &inode)?;
+        self.barrier()?;
+        self.free_blocks(&mut state, &unlinked)?;
+        self.free_inode(&mut state, ino, is_dir)
+    }
+}
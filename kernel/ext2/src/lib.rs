@@ -0,0 +1,284 @@
+//! Exposes ext2 filesystems on storage devices as files and directories in the VFS.
+//!
+//! [`mount()`] opens the ext2 filesystem on a storage device
+//! and inserts its root directory into a VFS directory as an [`Ext2Directory`].
+//! Its files can then be read, written, appended to, and truncated via [`Ext2File`],
+//! and files and directories can be created and removed.
+//! Files may be sparse, and use direct, singly, doubly, and triply indirect blocks.
+//!
+//! All device accesses go through a [`page_cache::PageCache`],
+//! and all nodes of a filesystem share a single [`Ext2Fs`], which serializes operations on it.
+//! Each node refers to its inode by number, so multiple nodes for the same file
+//! always observe the same contents.
+//!
+//! # Crash consistency
+//! Without a journal, modifications are ordered such that a crash partway through an operation
+//! can leak blocks or inodes, but never leaves a reference to uninitialized or reused metadata:
+//! * New blocks are zeroed and new inodes are initialized before anything refers to them.
+//! * A directory entry is removed before its inode is freed,
+//!   and an inode stops referring to blocks before they are freed.
+//! * The filesystem is marked as not cleanly unmounted while it is mounted,
+//!   such that `e2fsck` checks it after a crash.
+
+#![no_std]
+
+extern crate alloc;
+
+mod disk;
+mod fs;
+
+pub use fs::Ext2Fs;
+
+use alloc::{
+    string::String,
+    sync::{Arc, Weak},
+    vec,
+    vec::Vec,
+};
+use disk::ROOT_INODE;
+use fs_node::{DirRef, Directory, File, FileOrDir, FileRef, FsNode, WeakDirRef};
+use io::{ByteReader, ByteWriter, IoError, KnownLength};
+use log::error;
+use memory::MappedPages;
+use spin::Mutex;
+use storage_device::StorageDeviceRef;
+
+/// The size of the buffer used when copying a file into an ext2 directory.
+const CHUNK_SIZE: usize = 4096;
+
+/// Mounts the ext2 filesystem on the given storage `device` as a directory with the given `name`
+/// within the given `parent` directory.
+///
+/// Returns the mounted root directory of the filesystem,
+/// or an error if the device doesn't contain a supported ext2 filesystem.
+pub fn mount(device: StorageDeviceRef, name: String, parent: &DirRef) -> Result<DirRef, &'static str> {
+    let fs = Ext2Fs::mount(device)?;
+    if !fs.is_dir(ROOT_INODE)? {
+        return Err("ext2 root inode is not a directory");
+    }
+    let root = Ext2Directory::new_ref(fs, ROOT_INODE, name, Arc::downgrade(parent));
+    parent.lock().insert(FileOrDir::Dir(root.clone()))?;
+    Ok(root)
+}
+
+/// A directory within a mounted ext2 filesystem.
+pub struct Ext2Directory {
+    fs: Arc<Ext2Fs>,
+    ino: u32,
+    name: String,
+    parent: WeakDirRef,
+    /// A reference to this directory, which becomes the parent of the nodes obtained from it.
+    self_ref: Weak<Mutex<Ext2Directory>>,
+}
+
+impl Ext2Directory {
+    fn new_ref(fs: Arc<Ext2Fs>, ino: u32, name: String, parent: WeakDirRef) -> DirRef {
+        Arc::new_cyclic(|self_ref| Mutex::new(Ext2Directory {
+            fs,
+            ino,
+            name,
+            parent,
+            self_ref: self_ref.clone(),
+        })) as DirRef
+    }
+
+    /// Creates a new, empty file with the given `name` in this directory.
+    pub fn create_file(&self, name: &str) -> Result<FileRef, IoError> {
+        let ino = self.fs.create(self.ino, name, false)?;
+        Ok(self.file_node(ino, String::from(name)))
+    }
+
+    /// Creates a new, empty directory with the given `name` in this directory.
+    pub fn create_dir(&self, name: &str) -> Result<DirRef, IoError> {
+        let ino = self.fs.create(self.ino, name, true)?;
+        Ok(self.dir_node(ino, String::from(name)))
+    }
+
+    fn file_node(&self, ino: u32, name: String) -> FileRef {
+        Arc::new(Mutex::new(Ext2File {
+            fs: self.fs.clone(),
+            ino,
+            name,
+            parent: self.self_ref.clone() as WeakDirRef,
+        })) as FileRef
+    }
+
+    fn dir_node(&self, ino: u32, name: String) -> DirRef {
+        Ext2Directory::new_ref(self.fs.clone(), ino, name, self.self_ref.clone() as WeakDirRef)
+    }
+
+    /// Copies the given node from another filesystem into this directory,
+    /// including all of its contents if it is a directory.
+    fn copy_in(&self, node: &FileOrDir) -> Result<(), IoError> {
+        let name = node.get_name();
+        match node {
+            FileOrDir::File(src) => {
+                let dest = self.create_file(&name)?;
+                let mut src = src.lock();
+                let mut dest = dest.lock();
+                let mut buffer = vec![0u8; CHUNK_SIZE];
+                let mut offset = 0;
+                while offset < src.len() {
+                    let bytes_read = src.read_at(&mut buffer, offset)?;
+                    if bytes_read == 0 {
+                        break;
+                    }
+                    dest.write_at(&buffer[..bytes_read], offset)?;
+                    offset += bytes_read;
+                }
+            }
+            FileOrDir::Dir(src) => {
+                let dest = self.create_dir(&name)?;
+                let children: Vec<FileOrDir> = {
+                    let src = src.lock();
+                    src.list().iter().filter_map(|child| src.get(child)).collect()
+                };
+                for child in children {
+                    dest.lock().insert(child)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Directory for Ext2Directory {
+    /// Copies the given node into this directory, as nodes from other filesystems
+    /// cannot be stored in an ext2 filesystem directly.
+    /// The given node itself remains unchanged; obtain the copy via [`Directory::get()`].
+    ///
+    /// If an existing node has the same name, it is removed first and then returned.
+    fn insert(&mut self, node: FileOrDir) -> Result<Option<FileOrDir>, &'static str> {
+        let name = node.get_name();
+        let old_node = self.get(&name);
+        if old_node.is_some() {
+            self.fs.unlink(self.ino, &name)?;
+        }
+        self.copy_in(&node)?;
+        Ok(old_node.map(|mut old_node| {
+            old_node.set_parent_dir(Weak::<Mutex<Ext2Directory>>::new());
+            old_node
+        }))
+    }
+
+    fn get(&self, name: &str) -> Option<FileOrDir> {
+        let (ino, is_dir) = self.fs.lookup(self.ino, name)
+            .map_err(|e| error!("Ext2Directory::get(): failed to read directory {:?}: {:?}", self.name, e))
+            .ok()??;
+        Some(if is_dir {
+            FileOrDir::Dir(self.dir_node(ino, String::from(name)))
+        } else {
+            FileOrDir::File(self.file_node(ino, String::from(name)))
+        })
+    }
+
+    fn list(&self) -> Vec<String> {
+        match self.fs.list(self.ino) {
+            Ok(entries) => entries.into_iter().map(|(name, _)| name).collect(),
+            Err(e) => {
+                error!("Ext2Directory::list(): failed to read directory {:?}: {:?}", self.name, e);
+                Vec::new()
+            }
+        }
+    }
+
+    fn remove(&mut self, node: &FileOrDir) -> Option<FileOrDir> {
+        let name = node.get_name();
+        match self.fs.unlink(self.ino, &name) {
+            Ok(()) => {
+                let mut node = node.clone();
+                node.set_parent_dir(Weak::<Mutex<Ext2Directory>>::new());
+                Some(node)
+            }
+            Err(e) => {
+                error!("Ext2Directory::remove(): failed to remove {:?}: {:?}", name, e);
+                None
+            }
+        }
+    }
+}
+
+impl FsNode for Ext2Directory {
+    fn get_name(&self) -> String {
+        self.name.clone()
+    }
+
+    fn get_parent_dir(&self) -> Option<DirRef> {
+        self.parent.upgrade()
+    }
+
+    fn set_parent_dir(&mut self, new_parent: WeakDirRef) {
+        self.parent = new_parent;
+    }
+}
+
+/// A regular file within a mounted ext2 filesystem.
+pub struct Ext2File {
+    fs: Arc<Ext2Fs>,
+    ino: u32,
+    name: String,
+    parent: WeakDirRef,
+}
+
+impl Ext2File {
+    /// Appends the given `buffer` to the end of this file.
+    pub fn append(&mut self, buffer: &[u8]) -> Result<usize, IoError> {
+        let len = self.len();
+        self.write_at(buffer, len)
+    }
+}
+
+impl ByteReader for Ext2File {
+    fn read_at(&mut self, buffer: &mut [u8], offset: usize) -> Result<usize, IoError> {
+        self.fs.read(self.ino, buffer, offset as u64)
+    }
+}
+
+impl ByteWriter for Ext2File {
+    /// Writes the given `buffer` at the given `offset`, extending the file if necessary.
+    /// Any gap between the previous end of the file and `offset` becomes a hole, which reads as zeros.
+    fn write_at(&mut self, buffer: &[u8], offset: usize) -> Result<usize, IoError> {
+        self.fs.write(self.ino, buffer, offset as u64)
+    }
+
+    fn flush(&mut self) -> Result<(), IoError> {
+        self.fs.sync()
+    }
+}
+
+impl KnownLength for Ext2File {
+    fn len(&self) -> usize {
+        match self.fs.len(self.ino) {
+            Ok(len) => len as usize,
+            Err(e) => {
+                error!("Ext2File::len(): failed to read inode {}: {:?}", self.ino, e);
+                0
+            }
+        }
+    }
+}
+
+impl File for Ext2File {
+    fn as_mapping(&self) -> Result<&MappedPages, &'static str> {
+        Err("files on an ext2 filesystem cannot be memory mapped")
+    }
+
+    /// Sets the length of this file, either truncating it or extending it with a hole.
+    fn set_len(&mut self, len: usize) -> Result<(), IoError> {
+        self.fs.set_len(self.ino, len as u64)
+    }
+}
+
+impl FsNode for Ext2File {
+    fn get_name(&self) -> String {
+        self.name.clone()
+    }
+
+    fn get_parent_dir(&self) -> Option<DirRef> {
+        self.parent.upgrade()
+    }
+
+    fn set_parent_dir(&mut self, new_parent: WeakDirRef) {
+        self.parent = new_parent;
+    }
+}
@@ -0,0 +1,437 @@
+//! Exposes FAT filesystems on storage devices as files and directories in the VFS.
+//!
+//! [`mount()`] opens the FAT12, FAT16, or FAT32 filesystem on a storage device
+//! and inserts its root directory into a VFS directory as a [`FatDirectory`].
+//! Its files can then be read, written, appended to, and truncated via [`FatFile`],
+//! and files and directories can be created and removed, including those with long names,
+//! which are stored as VFAT long file name entries.
+//! The on-disk format itself is handled by the [`fatfs`] crate.
+//!
+//! All nodes of a filesystem share a single lock on it, and each node reopens its
+//! file or directory by path upon every operation, so multiple nodes for the same file
+//! always observe the same contents.
+//!
+//! # Crash consistency
+//! Modifications are ordered such that a crash partway through an operation
+//! does not leave a file's directory entry describing data that was never written:
+//! * A file's data is written before its directory entry is updated with the new size.
+//! * Each modifying operation flushes the storage device before it returns,
+//!   so its effects reach the device before those of any subsequent operation.
+//! * When the last node of a filesystem is dropped, the filesystem is unmounted,
+//!   which writes back its FSInfo sector and clears its dirty flag.
+
+#![no_std]
+
+extern crate alloc;
+
+mod adapter;
+
+pub use adapter::{FatFsAdapter, FatFsIoErrorAdapter};
+
+use alloc::{
+    format,
+    string::String,
+    sync::{Arc, Weak},
+    vec,
+    vec::Vec,
+};
+use fatfs::{FileSystem, FsOptions, Read, Seek, SeekFrom, Write};
+use fs_node::{DirRef, Directory, File, FileOrDir, FileRef, FsNode, WeakDirRef};
+use io::{
+    BlockWriter, ByteReader, ByteReaderWriterWrapper, ByteWriter, IoError, KnownLength,
+    LockableIo, ReaderWriter,
+};
+use log::error;
+use memory::MappedPages;
+use spin::Mutex;
+use storage_device::{StorageDevice, StorageDeviceRef};
+
+/// A storage device wrapped such that it can be accessed by the [`fatfs`] crate.
+pub type FatDisk = FatFsAdapter<ReaderWriter<ByteReaderWriterWrapper<
+    LockableIo<'static, dyn StorageDevice + Send, Mutex<dyn StorageDevice + Send>, StorageDeviceRef>
+>>>;
+
+/// A FAT filesystem on a storage device.
+pub type FatFileSystem = FileSystem<FatDisk>;
+
+/// The size of the buffer used when filling a file with zeros or copying a file into a FAT directory.
+const CHUNK_SIZE: usize = 4096;
+
+/// A mounted FAT filesystem, which is shared by all of its files and directories.
+struct Volume {
+    fs: Mutex<FatFileSystem>,
+    /// The storage device containing the filesystem, which is flushed after each modification.
+    device: StorageDeviceRef,
+}
+
+impl Volume {
+    /// Flushes the underlying storage device, such that all prior writes have reached it.
+    fn sync(&self) -> Result<(), IoError> {
+        self.device.lock().flush()
+    }
+}
+
+/// Mounts the FAT filesystem on the given storage `device` as a directory with the given `name`
+/// within the given `parent` directory.
+///
+/// Returns the mounted root directory of the filesystem,
+/// or an error if the device doesn't contain a FAT filesystem.
+pub fn mount(device: StorageDeviceRef, name: String, parent: &DirRef) -> Result<DirRef, &'static str> {
+    let disk = FatFsAdapter::new(ReaderWriter::new(ByteReaderWriterWrapper::from(
+        LockableIo::<dyn StorageDevice + Send, Mutex<_>, _>::from(device.clone())
+    )));
+    let fs = FileSystem::new(disk, FsOptions::new())
+        .map_err(|_| "storage device does not contain a FAT filesystem")?;
+    let volume = Arc::new(Volume { fs: Mutex::new(fs), device });
+    let root = FatDirectory::new_ref(volume, String::new(), name, Arc::downgrade(parent));
+    parent.lock().insert(FileOrDir::Dir(root.clone()))?;
+    Ok(root)
+}
+
+/// Converts an error from the [`fatfs`] crate into an [`IoError`].
+fn fat_error(error: fatfs::Error<FatFsIoErrorAdapter>) -> IoError {
+    match error {
+        fatfs::Error::Io(_)               => IoError::Other("I/O error on the FAT filesystem's storage device"),
+        fatfs::Error::InvalidInput        => IoError::InvalidInput,
+        fatfs::Error::NotFound            => IoError::Other("file or directory not found"),
+        fatfs::Error::AlreadyExists       => IoError::Other("a file or directory with that name already exists"),
+        fatfs::Error::DirectoryIsNotEmpty => IoError::Other("directory is not empty"),
+        fatfs::Error::NotEnoughSpace      => IoError::Other("not enough free space on the FAT filesystem"),
+        fatfs::Error::CorruptedFileSystem => IoError::Other("the FAT filesystem is corrupted"),
+        _                                 => IoError::Other("FAT filesystem error"),
+    }
+}
+
+/// Writes `count` zero bytes to the given file at its current position.
+fn write_zeros<W: Write>(file: &mut W, mut count: usize) -> Result<(), W::Error> {
+    let zeros = [0u8; CHUNK_SIZE];
+    while count > 0 {
+        let chunk = core::cmp::min(count, CHUNK_SIZE);
+        file.write_all(&zeros[..chunk])?;
+        count -= chunk;
+    }
+    Ok(())
+}
+
+/// A directory within a mounted FAT filesystem.
+pub struct FatDirectory {
+    volume: Arc<Volume>,
+    /// The path of this directory relative to the root of its filesystem,
+    /// which is empty for the root directory.
+    path: String,
+    name: String,
+    parent: WeakDirRef,
+    /// A reference to this directory, which becomes the parent of the nodes obtained from it.
+    self_ref: Weak<Mutex<FatDirectory>>,
+}
+
+impl FatDirectory {
+    fn new_ref(volume: Arc<Volume>, path: String, name: String, parent: WeakDirRef) -> DirRef {
+        Arc::new_cyclic(|self_ref| Mutex::new(FatDirectory {
+            volume,
+            path,
+            name,
+            parent,
+            self_ref: self_ref.clone(),
+        })) as DirRef
+    }
+
+    /// Creates a new, empty file with the given `name` in this directory.
+    pub fn create_file(&self, name: &str) -> Result<FileRef, IoError> {
+        if self.find(name)?.is_some() {
+            return Err(IoError::Other("a file or directory with that name already exists"));
+        }
+        {
+            let fs = self.volume.fs.lock();
+            let mut file = fs.root_dir().create_file(&self.child_path(name)).map_err(fat_error)?;
+            file.flush().map_err(fat_error)?;
+        }
+        self.volume.sync()?;
+        Ok(self.file_node(String::from(name)))
+    }
+
+    /// Creates a new, empty directory with the given `name` in this directory.
+    pub fn create_dir(&self, name: &str) -> Result<DirRef, IoError> {
+        if self.find(name)?.is_some() {
+            return Err(IoError::Other("a file or directory with that name already exists"));
+        }
+        self.volume.fs.lock().root_dir().create_dir(&self.child_path(name)).map_err(fat_error)?;
+        self.volume.sync()?;
+        Ok(self.dir_node(String::from(name)))
+    }
+
+    fn child_path(&self, name: &str) -> String {
+        if self.path.is_empty() {
+            String::from(name)
+        } else {
+            format!("{}/{}", self.path, name)
+        }
+    }
+
+    fn file_node(&self, name: String) -> FileRef {
+        Arc::new(Mutex::new(FatFile {
+            volume: self.volume.clone(),
+            path: self.child_path(&name),
+            name,
+            parent: self.self_ref.clone() as WeakDirRef,
+        })) as FileRef
+    }
+
+    fn dir_node(&self, name: String) -> DirRef {
+        FatDirectory::new_ref(self.volume.clone(), self.child_path(&name), name, self.self_ref.clone() as WeakDirRef)
+    }
+
+    /// Returns the name and whether it's a directory of each entry in this directory,
+    /// excluding the `.` and `..` entries.
+    fn entries(&self) -> Result<Vec<(String, bool)>, IoError> {
+        let fs = self.volume.fs.lock();
+        let root = fs.root_dir();
+        let dir = if self.path.is_empty() {
+            root
+        } else {
+            root.open_dir(&self.path).map_err(fat_error)?
+        };
+        let mut entries = Vec::new();
+        for entry in dir.iter() {
+            let entry = entry.map_err(fat_error)?;
+            let name = entry.file_name();
+            if name != "." && name != ".." {
+                entries.push((name, entry.is_dir()));
+            }
+        }
+        Ok(entries)
+    }
+
+    /// Finds the entry with the given `name`, ignoring case as FAT does,
+    /// and returns its actual name and whether it's a directory.
+    fn find(&self, name: &str) -> Result<Option<(String, bool)>, IoError> {
+        Ok(self.entries()?.into_iter().find(|(entry_name, _)| entry_name.eq_ignore_ascii_case(name)))
+    }
+
+    /// Removes the entry with the given `name`, which must be empty if it is a directory.
+    fn remove_entry(&self, name: &str) -> Result<(), IoError> {
+        self.volume.fs.lock().root_dir().remove(&self.child_path(name)).map_err(fat_error)?;
+        self.volume.sync()
+    }
+
+    /// Copies the given node from another filesystem into this directory,
+    /// including all of its contents if it is a directory.
+    fn copy_in(&self, node: &FileOrDir) -> Result<(), IoError> {
+        let name = node.get_name();
+        match node {
+            FileOrDir::File(src) => {
+                let dest = self.create_file(&name)?;
+                let mut src = src.lock();
+                let mut dest = dest.lock();
+                let mut buffer = vec![0u8; CHUNK_SIZE];
+                let mut offset = 0;
+                while offset < src.len() {
+                    let bytes_read = src.read_at(&mut buffer, offset)?;
+                    if bytes_read == 0 {
+                        break;
+                    }
+                    dest.write_at(&buffer[..bytes_read], offset)?;
+                    offset += bytes_read;
+                }
+            }
+            FileOrDir::Dir(src) => {
+                let dest = self.create_dir(&name)?;
+                let children: Vec<FileOrDir> = {
+                    let src = src.lock();
+                    src.list().iter().filter_map(|child| src.get(child)).collect()
+                };
+                for child in children {
+                    dest.lock().insert(child)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Directory for FatDirectory {
+    /// Copies the given node into this directory, as nodes from other filesystems
+    /// cannot be stored in a FAT filesystem directly.
+    /// The given node itself remains unchanged; obtain the copy via [`Directory::get()`].
+    ///
+    /// If an existing node has the same name, it is removed first and then returned.
+    fn insert(&mut self, node: FileOrDir) -> Result<Option<FileOrDir>, &'static str> {
+        let name = node.get_name();
+        let old_node = self.get(&name);
+        if let Some(old_node) = &old_node {
+            self.remove_entry(&old_node.get_name())?;
+        }
+        self.copy_in(&node)?;
+        Ok(old_node.map(|mut old_node| {
+            old_node.set_parent_dir(Weak::<Mutex<FatDirectory>>::new());
+            old_node
+        }))
+    }
+
+    fn get(&self, name: &str) -> Option<FileOrDir> {
+        let (name, is_dir) = self.find(name)
+            .map_err(|e| error!("FatDirectory::get(): failed to read directory {:?}: {:?}", self.path, e))
+            .ok()??;
+        Some(if is_dir {
+            FileOrDir::Dir(self.dir_node(name))
+        } else {
+            FileOrDir::File(self.file_node(name))
+        })
+    }
+
+    fn list(&self) -> Vec<String> {
+        match self.entries() {
+            Ok(entries) => entries.into_iter().map(|(name, _)| name).collect(),
+            Err(e) => {
+                error!("FatDirectory::list(): failed to read directory {:?}: {:?}", self.path, e);
+                Vec::new()
+            }
+        }
+    }
+
+    fn remove(&mut self, node: &FileOrDir) -> Option<FileOrDir> {
+        let name = node.get_name();
+        match self.remove_entry(&name) {
+            Ok(()) => {
+                let mut node = node.clone();
+                node.set_parent_dir(Weak::<Mutex<FatDirectory>>::new());
+                Some(node)
+            }
+            Err(e) => {
+                error!("FatDirectory::remove(): failed to remove {:?}: {:?}", name, e);
+                None
+            }
+        }
+    }
+}
+
+impl FsNode for FatDirectory {
+    fn get_name(&self) -> String {
+        self.name.clone()
+    }
+
+    fn get_parent_dir(&self) -> Option<DirRef> {
+        self.parent.upgrade()
+    }
+
+    fn set_parent_dir(&mut self, new_parent: WeakDirRef) {
+        self.parent = new_parent;
+    }
+}
+
+/// A file within a mounted FAT filesystem.
+pub struct FatFile {
+    volume: Arc<Volume>,
+    /// The path of this file relative to the root of its filesystem.
+    path: String,
+    name: String,
+    parent: WeakDirRef,
+}
+
+impl FatFile {
+    /// Appends the given `buffer` to the end of this file.
+    pub fn append(&mut self, buffer: &[u8]) -> Result<usize, IoError> {
+        let len = self.len();
+        self.write_at(buffer, len)
+    }
+}
+
+impl ByteReader for FatFile {
+    fn read_at(&mut self, buffer: &mut [u8], offset: usize) -> Result<usize, IoError> {
+        let fs = self.volume.fs.lock();
+        let mut file = fs.root_dir().open_file(&self.path).map_err(fat_error)?;
+        let len = file.seek(SeekFrom::End(0)).map_err(fat_error)? as usize;
+        if offset > len {
+            return Err(IoError::InvalidInput);
+        }
+        file.seek(SeekFrom::Start(offset as u64)).map_err(fat_error)?;
+        let mut total_read = 0;
+        while total_read < buffer.len() {
+            let bytes_read = file.read(&mut buffer[total_read..]).map_err(fat_error)?;
+            if bytes_read == 0 {
+                break;
+            }
+            total_read += bytes_read;
+        }
+        Ok(total_read)
+    }
+}
+
+impl ByteWriter for FatFile {
+    /// Writes the given `buffer` at the given `offset`, extending the file if necessary.
+    /// Any gap between the previous end of the file and `offset` is filled with zeros.
+    fn write_at(&mut self, buffer: &[u8], offset: usize) -> Result<usize, IoError> {
+        {
+            let fs = self.volume.fs.lock();
+            let mut file = fs.root_dir().open_file(&self.path).map_err(fat_error)?;
+            let len = file.seek(SeekFrom::End(0)).map_err(fat_error)? as usize;
+            if offset > len {
+                write_zeros(&mut file, offset - len).map_err(fat_error)?;
+            } else {
+                file.seek(SeekFrom::Start(offset as u64)).map_err(fat_error)?;
+            }
+            file.write_all(buffer).map_err(fat_error)?;
+            // Only update the directory entry after all of the data has been written.
+            file.flush().map_err(fat_error)?;
+        }
+        self.volume.sync()?;
+        Ok(buffer.len())
+    }
+
+    fn flush(&mut self) -> Result<(), IoError> {
+        self.volume.sync()
+    }
+}
+
+impl KnownLength for FatFile {
+    fn len(&self) -> usize {
+        let fs = self.volume.fs.lock();
+        let len = fs.root_dir()
+            .open_file(&self.path)
+            .and_then(|mut file| file.seek(SeekFrom::End(0)));
+        match len {
+            Ok(len) => len as usize,
+            Err(e) => {
+                error!("FatFile::len(): failed to open {:?}: {:?}", self.path, fat_error(e));
+                0
+            }
+        }
+    }
+}
+
+impl File for FatFile {
+    fn as_mapping(&self) -> Result<&MappedPages, &'static str> {
+        Err("files on a FAT filesystem cannot be memory mapped")
+    }
+
+    /// Sets the length of this file, either truncating it or extending it with zeros.
+    fn set_len(&mut self, len: usize) -> Result<(), IoError> {
+        {
+            let fs = self.volume.fs.lock();
+            let mut file = fs.root_dir().open_file(&self.path).map_err(fat_error)?;
+            let current_len = file.seek(SeekFrom::End(0)).map_err(fat_error)? as usize;
+            if len < current_len {
+                file.seek(SeekFrom::Start(len as u64)).map_err(fat_error)?;
+                file.truncate().map_err(fat_error)?;
+            } else {
+                write_zeros(&mut file, len - current_len).map_err(fat_error)?;
+            }
+            file.flush().map_err(fat_error)?;
+        }
+        self.volume.sync()
+    }
+}
+
+impl FsNode for FatFile {
+    fn get_name(&self) -> String {
+        self.name.clone()
+    }
+
+    fn get_parent_dir(&self) -> Option<DirRef> {
+        self.parent.upgrade()
+    }
+
+    fn set_parent_dir(&mut self, new_parent: WeakDirRef) {
+        self.parent = new_parent;
+    }
+}
@@ -0,0 +1,213 @@
+//! Fault injection for kernel subsystems, which forces failures on demand
+//! such that rarely-taken error paths, e.g., failed spawns and aborted crate loads, actually get exercised.
+//!
+//! Each [`FaultPoint`] is a place in a subsystem that asks [`should_fail()`] whether to fail,
+//! and each can be configured with a [`Trigger`] that determines when it does, e.g., via the `fault_inject` command.
+//!
+//! Fault injection is only compiled in if Theseus is built with the `fault_injection` cfg option,
+//! e.g., `make run THESEUS_CONFIG=fault_injection`.
+//! Otherwise, [`should_fail()`] always returns `false` and configuring a trigger returns an error,
+//! so fault points cost nothing in regular builds.
+//!
+//! This crate has no dependencies, such that even the lowest-level subsystems can contain fault points.
+
+#![no_std]
+
+use core::{fmt, str::FromStr};
+#[cfg(fault_injection)]
+use core::sync::atomic::{AtomicU32, AtomicU8, AtomicUsize, Ordering};
+
+/// A place in a kernel subsystem where a fault can be injected.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum FaultPoint {
+    /// Allocating virtual pages fails, which also causes mapping memory, creating stacks,
+    /// and growing the heap to fail.
+    PageAllocation,
+    /// Adding a dynamic TLS section to a `TlsInitializer` fails, which aborts loading the crate that contains it.
+    TlsInitializer,
+    /// Writing a relocation entry fails, which aborts loading or swapping the crate that contains it.
+    Relocation,
+}
+
+impl FaultPoint {
+    /// All fault points.
+    pub const ALL: [FaultPoint; 3] = [FaultPoint::PageAllocation, FaultPoint::TlsInitializer, FaultPoint::Relocation];
+
+    /// Returns the short name of this fault point, which is accepted by [`FromStr`].
+    pub fn name(self) -> &'static str {
+        match self {
+            FaultPoint::PageAllocation => "alloc",
+            FaultPoint::TlsInitializer => "tls",
+            FaultPoint::Relocation => "reloc",
+        }
+    }
+
+    /// Returns the error message that a fault point should return when a fault is injected.
+    pub fn error(self) -> &'static str {
+        match self {
+            FaultPoint::PageAllocation => "injected fault: page allocation failed",
+            FaultPoint::TlsInitializer => "injected fault: TLS initializer failed",
+            FaultPoint::Relocation => "injected fault: relocation failed",
+        }
+    }
+
+    #[cfg(fault_injection)]
+    fn state(self) -> &'static PointState {
+        &STATES[self as usize]
+    }
+}
+
+impl fmt::Display for FaultPoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for FaultPoint {
+    type Err = &'static str;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        FaultPoint::ALL.into_iter()
+            .find(|point| point.name() == s)
+            .ok_or("unknown fault point")
+    }
+}
+
+/// Determines when a [`FaultPoint`] fails.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Trigger {
+    /// The fault point never fails.
+    Off,
+    /// The next given number of checks of the fault point fail, after which it no longer fails.
+    Next(u32),
+    /// Every n-th check of the fault point fails, starting from when this trigger was set.
+    EveryNth(u32),
+}
+
+impl fmt::Display for Trigger {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Trigger::Off => write!(f, "off"),
+            Trigger::Next(n) => write!(f, "next {}", n),
+            Trigger::EveryNth(n) => write!(f, "every {}", n),
+        }
+    }
+}
+
+#[cfg(fault_injection)]
+const MODE_OFF: u8 = 0;
+#[cfg(fault_injection)]
+const MODE_NEXT: u8 = 1;
+#[cfg(fault_injection)]
+const MODE_EVERY_NTH: u8 = 2;
+
+/// The lock-free state of a fault point, such that it can be checked from any context, e.g., within allocators.
+#[cfg(fault_injection)]
+struct PointState {
+    mode: AtomicU8,
+    /// The remaining number of failures for `Next`, or the period for `EveryNth`.
+    param: AtomicU32,
+    /// The number of checks since the trigger was set, used for `EveryNth`.
+    checks: AtomicU32,
+    /// The total number of injected faults.
+    injected: AtomicUsize,
+}
+
+#[cfg(fault_injection)]
+#[allow(clippy::declare_interior_mutable_const)]
+const INITIAL_STATE: PointState = PointState {
+    mode: AtomicU8::new(MODE_OFF),
+    param: AtomicU32::new(0),
+    checks: AtomicU32::new(0),
+    injected: AtomicUsize::new(0),
+};
+
+#[cfg(fault_injection)]
+static STATES: [PointState; FaultPoint::ALL.len()] = [INITIAL_STATE; FaultPoint::ALL.len()];
+
+/// Returns whether fault injection was compiled into this build of Theseus.
+pub const fn is_enabled() -> bool {
+    cfg!(fault_injection)
+}
+
+/// Returns whether the given fault `point` should fail now, according to its [`Trigger`].
+///
+/// This never blocks or allocates, so it can be invoked from any context.
+#[inline(always)]
+pub fn should_fail(point: FaultPoint) -> bool {
+    #[cfg(fault_injection)] {
+        let state = point.state();
+        let fail = match state.mode.load(Ordering::Acquire) {
+            MODE_NEXT => state.param
+                .fetch_update(Ordering::AcqRel, Ordering::Acquire, |remaining| remaining.checked_sub(1))
+                .is_ok(),
+            MODE_EVERY_NTH => {
+                let period = state.param.load(Ordering::Acquire).max(1);
+                (state.checks.fetch_add(1, Ordering::AcqRel) + 1) % period == 0
+            }
+            _ => false,
+        };
+        if fail {
+            state.injected.fetch_add(1, Ordering::Relaxed);
+        }
+        fail
+    }
+    #[cfg(not(fault_injection))] {
+        let _ = point;
+        false
+    }
+}
+
+/// Sets the trigger that determines when the given fault `point` fails.
+///
+/// Returns an error if fault injection was not compiled into this build of Theseus.
+pub fn set_trigger(point: FaultPoint, trigger: Trigger) -> Result<(), &'static str> {
+    #[cfg(fault_injection)] {
+        let state = point.state();
+        // Turn the point off while changing its parameters, such that checks never see a partial update.
+        state.mode.store(MODE_OFF, Ordering::Release);
+        let (mode, param) = match trigger {
+            Trigger::Off => return Ok(()),
+            Trigger::Next(count) => (MODE_NEXT, count),
+            Trigger::EveryNth(0) => return Err("the period of an `EveryNth` trigger must be non-zero"),
+            Trigger::EveryNth(period) => (MODE_EVERY_NTH, period),
+        };
+        state.param.store(param, Ordering::Release);
+        state.checks.store(0, Ordering::Release);
+        state.mode.store(mode, Ordering::Release);
+        Ok(())
+    }
+    #[cfg(not(fault_injection))] {
+        let _ = (point, trigger);
+        Err("fault injection is disabled; rebuild Theseus with THESEUS_CONFIG=fault_injection")
+    }
+}
+
+/// Returns the current trigger of the given fault `point`.
+///
+/// A `Next` trigger whose failures have all been injected is reported as `Next(0)`.
+pub fn trigger(point: FaultPoint) -> Trigger {
+    #[cfg(fault_injection)] {
+        let state = point.state();
+        let param = state.param.load(Ordering::Acquire);
+        match state.mode.load(Ordering::Acquire) {
+            MODE_NEXT => Trigger::Next(param),
+            MODE_EVERY_NTH => Trigger::EveryNth(param),
+            _ => Trigger::Off,
+        }
+    }
+    #[cfg(not(fault_injection))] {
+        let _ = point;
+        Trigger::Off
+    }
+}
+
+/// Returns the total number of faults injected at the given fault `point`.
+pub fn injected_count(point: FaultPoint) -> usize {
+    #[cfg(fault_injection)] {
+        point.state().injected.load(Ordering::Relaxed)
+    }
+    #[cfg(not(fault_injection))] {
+        let _ = point;
+        0
+    }
+}
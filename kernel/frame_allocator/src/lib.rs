@@ -757,6 +757,46 @@ fn find_any_chunk(
 
 
 
+/// Searches the given `list` for a free chunk that holds at least `num_frames` frames within the given `range`.
+fn find_chunk_in_range(
+    list: &mut StaticArrayRBTree<Chunk>,
+    num_frames: usize,
+    range: &FrameRange,
+) -> Result<(AllocatedFrames, DeferredAllocAction<'static>), AllocationError> {
+    // Returns the first frame of the part of `chunk` that lies within `range`, if that part is large enough.
+    let start_within_range = |chunk: &Chunk| -> Option<Frame> {
+        if chunk.typ != MemoryRegionType::Free {
+            return None;
+        }
+        let overlap = chunk.frames.overlap(range)?;
+        (overlap.size_in_frames() >= num_frames).then(|| *overlap.start())
+    };
+
+    match list.0 {
+        Inner::Array(ref mut arr) => {
+            for elem in arr.iter_mut() {
+                if let Some(chunk) = elem {
+                    if let Some(start_frame) = start_within_range(chunk) {
+                        return Ok(allocate_from_chosen_chunk(start_frame, num_frames, &chunk.clone(), ValueRefMut::Array(elem)));
+                    }
+                }
+            }
+        }
+        Inner::RBTree(ref mut tree) => {
+            let mut cursor = tree.upper_bound_mut(Bound::<&Chunk>::Unbounded);
+            while let Some(chunk) = cursor.get().map(|w| w.deref()) {
+                if let Some(start_frame) = start_within_range(chunk) {
+                    return Ok(allocate_from_chosen_chunk(start_frame, num_frames, &chunk.clone(), ValueRefMut::RBTree(cursor)));
+                }
+                cursor.move_prev();
+            }
+        }
+    }
+
+    Err(AllocationError::OutOfAddressSpace(num_frames))
+}
+
+
 /// The final part of the main allocation routine that splits the given chosen chunk
 /// into multiple smaller chunks, thereby "allocating" frames from it.
 ///
@@ -1028,6 +1068,44 @@ pub fn allocate_frames(num_frames: usize) -> Option<AllocatedFrames> {
 }
 
 
+/// Allocates the given number of frames, starting at a physical address that is aligned
+/// to a multiple of `alignment_in_frames` frames, e.g., `512` for a 2MiB-aligned huge page.
+///
+/// This works by allocating a larger range of frames and then freeing the unaligned parts
+/// before and after the aligned range, so it temporarily reserves up to `alignment_in_frames - 1` extra frames.
+/// 
+/// See [`allocate_frames_deferred()`](fn.allocate_frames_deferred.html) for more details. 
+pub fn allocate_frames_aligned(num_frames: usize, alignment_in_frames: usize) -> Option<AllocatedFrames> {
+    if alignment_in_frames <= 1 {
+        return allocate_frames(num_frames);
+    }
+    let padded = allocate_frames(num_frames.checked_add(alignment_in_frames - 1)?)?;
+    let misalignment = padded.start().number() % alignment_in_frames;
+    let aligned_start = if misalignment == 0 {
+        *padded.start()
+    } else {
+        *padded.start() + (alignment_in_frames - misalignment)
+    };
+    // The unaligned parts before and after the aligned range are freed when dropped here.
+    let (_before, rest) = padded.split(aligned_start).ok()?;
+    let (aligned, _after) = rest.split(aligned_start + num_frames).ok()?;
+    Some(aligned)
+}
+
+
+/// Allocates the given number of frames from the general-purpose free frames
+/// that lie entirely within the given `range`, e.g., the memory local to a specific NUMA node.
+/// 
+/// Returns `None` if no contiguous free chunk of that size exists within the `range`.
+pub fn allocate_frames_in_range(num_frames: usize, range: &FrameRange) -> Option<AllocatedFrames> {
+    if num_frames == 0 {
+        return None;
+    }
+    let result = find_chunk_in_range(&mut FREE_GENERAL_FRAMES_LIST.lock(), num_frames, range);
+    result.map(|(af, _action)| af).ok()
+}
+
+
 /// Allocates frames with no constraints on the starting physical address, 
 /// with a size given by the number of bytes. 
 /// 
@@ -21,8 +21,9 @@ use alloc::string::String;
 use alloc::vec::Vec;
 use spin::Mutex;
 use alloc::sync::{Arc, Weak};
-use memory::MappedPages;
-use io::{ByteReader, ByteWriter, KnownLength};
+use core::cmp::min;
+use memory::{MappedPages, PteFlags, PAGE_SIZE};
+use io::{ByteReader, ByteWriter, IoError, KnownLength};
 
 
 /// A reference to any type that implements the [`File`] trait,
@@ -73,6 +74,79 @@ pub trait FsNode {
 pub trait File : FsNode + ByteReader + ByteWriter + KnownLength {
     /// Returns a view of this file as an immutable memory-mapped region.
     fn as_mapping(&self) -> Result<&MappedPages, &'static str>;
+
+    /// Returns the path that this file points to if it is a symbolic link,
+    /// or `None` if it is a regular file.
+    ///
+    /// Symbolic links are followed during path resolution by the `vfs` crate.
+    fn symlink_target(&self) -> Option<String> {
+        None
+    }
+
+    /// Sets the length of this file, either truncating it or extending it with zeros.
+    ///
+    /// Returns an error by default, for files that cannot be resized.
+    fn set_len(&mut self, _len: usize) -> Result<(), IoError> {
+        Err(IoError::Other("this file cannot be resized"))
+    }
+
+    /// Returns a new, writable memory mapping of `len` bytes of this file's contents starting at `offset`,
+    /// which must be a multiple of the page size.
+    ///
+    /// The mapping is private: writing to it doesn't modify this file.
+    /// The part of the mapping beyond the end of this file is zeroed.
+    /// See `vfs::mmap()` for mappings whose changes are written back to the file.
+    ///
+    /// By default, the contents are copied into newly-allocated pages via [`ByteReader::read_at()`],
+    /// which for on-disk filesystems are served from their page cache.
+    /// Files whose contents are already stored in `MappedPages` can instead share their frames
+    /// copy-on-write; see [`map_copy_on_write()`].
+    fn mmap(&mut self, offset: usize, len: usize) -> Result<MappedPages, IoError> {
+        map_by_copying(self, offset, len)
+    }
+}
+
+/// Maps `len` bytes of the given `file` starting at `offset` into newly-allocated pages,
+/// by reading its contents into them. This is the default implementation of [`File::mmap()`].
+pub fn map_by_copying<F: File + ?Sized>(file: &mut F, offset: usize, len: usize) -> Result<MappedPages, IoError> {
+    if len == 0 || offset % PAGE_SIZE != 0 {
+        return Err(IoError::InvalidInput);
+    }
+    let mut mp = memory::create_mapping(len, PteFlags::new().valid(true).writable(true))?;
+    let size = mp.size_in_bytes();
+    let dest = mp.as_slice_mut::<u8>(0, size)?;
+    let count = min(len, file.len().saturating_sub(offset));
+    let mut bytes_read = 0;
+    while bytes_read < count {
+        match file.read_at(&mut dest[bytes_read..count], offset + bytes_read)? {
+            0 => break,
+            n => bytes_read += n,
+        }
+    }
+    dest[bytes_read..].fill(0);
+    Ok(mp)
+}
+
+/// Maps `len` bytes of the given file contents `mp` starting at `offset` into new pages,
+/// sharing the underlying frames copy-on-write, as a zero-copy alternative to [`map_by_copying()`].
+///
+/// Afterwards, the first write to a shared page, through either mapping, gives that page its own copy,
+/// so the file and the new mapping appear to be independent of one another.
+/// `offset` must be a multiple of the page size, and the range must lie within `mp`, which must be writable.
+pub fn map_copy_on_write(mp: &mut MappedPages, offset: usize, len: usize) -> Result<MappedPages, &'static str> {
+    let end = offset.checked_add(len)
+        .filter(|&end| len > 0 && offset % PAGE_SIZE == 0 && end <= mp.size_in_bytes())
+        .ok_or("map_copy_on_write(): range must be page-aligned and within the given mapping")?;
+    let kernel_mmi_ref = memory::get_kernel_mmi_ref().ok_or("map_copy_on_write(): KERNEL_MMI was not yet initialized!")?;
+    let new_pages = memory::allocate_pages(mp.size_in_pages()).ok_or("map_copy_on_write(): couldn't allocate pages")?;
+    let shared = mp.share_copy_on_write(&mut kernel_mmi_ref.lock().page_table, new_pages)?;
+
+    // Only keep the requested range; the pages before and after it are unmapped upon being dropped.
+    let first_page = *shared.start() + offset / PAGE_SIZE;
+    let end_page = *shared.start() + (end + PAGE_SIZE - 1) / PAGE_SIZE;
+    let (_before, rest) = shared.split(first_page).map_err(|_| "map_copy_on_write(): couldn't split mapping")?;
+    let (mapping, _after) = rest.split(end_page).map_err(|_| "map_copy_on_write(): couldn't split mapping")?;
+    Ok(mapping)
 }
 
 /// Trait for directories, implementors of Directory must also implement FsNode
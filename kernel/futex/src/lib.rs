@@ -0,0 +1,126 @@
+//! Futex-style wait queues keyed on memory addresses.
+//!
+//! A task calls [`wait_on()`] to sleep until the value at a given address changes,
+//! and another task calls [`wake()`] after changing that value to wake up the tasks waiting on it.
+//! No wait queue needs to be allocated or shared ahead of time: any 32-bit atomic value can be waited on,
+//! which allows higher-level synchronization primitives (e.g., mutexes, condition variables,
+//! or thread parking) to be built as a single atomic word that only enters the kernel under contention,
+//! rather than spinning while they wait.
+//!
+//! Waiting tasks are kept in a fixed set of buckets chosen by hashing the address.
+//! Checking the value and going to sleep happen atomically with respect to waking up tasks
+//! on the same address, so a wakeup cannot be missed between the two.
+
+#![no_std]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU32, Ordering};
+use irq_safety::MutexIrqSafe;
+use log::warn;
+use task::TaskRef;
+pub use wait_queue::WaitError;
+
+/// The number of buckets that waiting tasks are spread across.
+const NUM_BUCKETS: usize = 64;
+
+/// A task waiting on an address.
+struct Waiter {
+    addr: usize,
+    task: TaskRef,
+}
+
+/// The waiting tasks of every address that hashes to a given bucket, in the order they started waiting.
+static BUCKETS: [MutexIrqSafe<Vec<Waiter>>; NUM_BUCKETS] = {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const EMPTY: MutexIrqSafe<Vec<Waiter>> = MutexIrqSafe::new(Vec::new());
+    [EMPTY; NUM_BUCKETS]
+};
+
+/// Returns the bucket that holds the tasks waiting on the given address.
+fn bucket_of(addr: usize) -> &'static MutexIrqSafe<Vec<Waiter>> {
+    // The lowest two bits are always zero for a 32-bit value.
+    let hash = (addr >> 2).wrapping_mul(0x9E37_79B9_7F4A_7C15);
+    &BUCKETS[(hash >> 32) % NUM_BUCKETS]
+}
+
+/// Puts the current task to sleep until it is woken up by [`wake()`] on the same `addr`,
+/// but only if `addr` still holds the `expected` value.
+///
+/// The value is compared atomically with respect to [`wake()`]:
+/// if another task changes the value and then invokes [`wake()`],
+/// the current task either observes the new value here or is woken up.
+/// As with any futex, the caller should re-check the value after this returns,
+/// since the value may have changed again by the time the current task runs.
+///
+/// ## Return
+/// * `Ok(true)` if the current task slept and was then woken up.
+/// * `Ok(false)` if `addr` didn't hold the `expected` value, so the current task didn't sleep.
+/// * `Err` if the current task couldn't be put to sleep.
+pub fn wait_on(addr: &AtomicU32, expected: u32) -> Result<bool, WaitError> {
+    let key = addr as *const AtomicU32 as usize;
+    let bucket = bucket_of(key);
+    let current = task::get_my_current_task().ok_or(WaitError::NoCurrentTask)?;
+    {
+        let mut waiters = bucket.lock();
+        if addr.load(Ordering::Acquire) != expected {
+            return Ok(false);
+        }
+        current.block().map_err(|_| WaitError::CantBlockCurrentTask)?;
+        waiters.push(Waiter { addr: key, task: current.clone() });
+    }
+    loop {
+        scheduler::schedule();
+
+        // A task that was woken up by `wake()` has already been removed from the bucket.
+        // If it's still there, something else unblocked it, so put it back to sleep.
+        let waiters = bucket.lock();
+        if !waiters.iter().any(|w| w.addr == key && w.task == current) {
+            return Ok(true);
+        }
+        warn!("futex::wait_on(): task was unblocked while still waiting (spurious wakeup?). {:?}", current);
+        current.block().map_err(|_| WaitError::CantBlockCurrentTask)?;
+    }
+}
+
+/// Wakes up to `max_tasks` of the tasks waiting on the given `addr` via [`wait_on()`],
+/// in the order in which they started waiting.
+///
+/// The caller should change the value at `addr` before invoking this,
+/// such that tasks that are about to wait on it observe the new value.
+///
+/// Returns the number of tasks that were woken up.
+pub fn wake(addr: &AtomicU32, max_tasks: usize) -> usize {
+    let key = addr as *const AtomicU32 as usize;
+    let mut waiters = bucket_of(key).lock();
+    let mut woken = 0;
+    let mut i = 0;
+    while woken < max_tasks && i < waiters.len() {
+        if waiters[i].addr != key {
+            i += 1;
+            continue;
+        }
+        let waiter = waiters.remove(i);
+        if waiter.task.unblock().is_err() {
+            warn!("futex::wake(): failed to unblock {:?}", waiter.task);
+        }
+        woken += 1;
+    }
+    woken
+}
+
+/// Wakes up all tasks waiting on the given `addr` via [`wait_on()`].
+///
+/// Returns the number of tasks that were woken up.
+pub fn wake_all(addr: &AtomicU32) -> usize {
+    wake(addr, usize::MAX)
+}
+
+/// Returns the number of tasks currently waiting on the given `addr`.
+///
+/// This is only a hint, as tasks may start or stop waiting right after it is counted.
+pub fn num_waiters(addr: &AtomicU32) -> usize {
+    let key = addr as *const AtomicU32 as usize;
+    bucket_of(key).lock().iter().filter(|w| w.addr == key).count()
+}
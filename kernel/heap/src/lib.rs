@@ -1,6 +1,16 @@
 //! The global allocator for the system. 
 //! It starts off as a single fixed size allocator.
 //! When a more complex heap is set up, it is set as the default allocator.
+//!
+//! Once the default allocator is set, small allocations are first served from
+//! a per-task cache of free blocks in the task's TLS area; see the `tls_cache` module.
+//!
+//! Each allocation and deallocation is charged to the current task's profiling counters
+//! (see the `tls_counters` crate), which allows attributing heap usage to tasks.
+//! For finer-grained leak tracking, the optional heap profiler records each outstanding allocation
+//! and its owner; see the `profiling` module.
+//! To catch buffer overflows, the optional heap sanitizer surrounds each allocation
+//! with poisoned redzones; see the `sanitizer` module.
 
 #![feature(allocator_api)]
 #![no_std]
@@ -11,6 +21,23 @@ extern crate spin;
 extern crate memory;
 extern crate kernel_config;
 extern crate block_allocator;
+extern crate tls_initializer;
+extern crate tls_counters;
+extern crate zerocopy;
+
+mod tls_cache;
+mod profiling;
+mod sanitizer;
+
+pub use profiling::{
+    enable_profiling, disable_profiling, is_profiling_enabled,
+    profiling_report, outstanding_allocations,
+    OwnerProfile, ProfilingReport,
+};
+pub use sanitizer::{
+    enable_sanitizer, disable_sanitizer, is_sanitizer_enabled, check_access,
+    HeapViolation, HeapViolationKind, HEAP_POISON_BYTE,
+};
 
 use alloc::alloc::{GlobalAlloc, Layout};
 use memory::PteFlags;
@@ -19,6 +46,7 @@ use irq_safety::MutexIrqSafe;
 use spin::Once;
 use alloc::boxed::Box;
 use block_allocator::FixedSizeBlockAllocator;
+use tls_counters::Counter;
 
 
 #[global_allocator]
@@ -53,8 +81,11 @@ pub fn init_single_heap(start_virt_addr: usize, size_in_bytes: usize) {
 
 
 /// Sets a new default allocator to be used by the global heap. It will start being used after this function is called.
+///
+/// This also enables the per-task heap allocation caches, which are backed by the new default allocator.
 pub fn set_allocator(allocator: Box<dyn GlobalAlloc + Send + Sync>) {
     DEFAULT_ALLOCATOR.call_once(|| allocator);
+    let _ = tls_initializer::set_heap_cache_hooks(tls_cache::HOOKS);
 }
 
 
@@ -73,14 +104,15 @@ impl Heap {
             initial_allocator: MutexIrqSafe::new(FixedSizeBlockAllocator::new()),
         }
     }
-}
-
-unsafe impl GlobalAlloc for Heap {
 
-    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+    /// Allocates a block with the given layout from the appropriate allocator.
+    ///
+    /// The `tls_self_pointer` is that of the current task, if the TLS register has been set.
+    unsafe fn alloc_block(&self, layout: Layout, tls_self_pointer: Option<usize>) -> *mut u8 {
         match DEFAULT_ALLOCATOR.get() {
             Some(allocator) => {
-                allocator.alloc(layout)
+                tls_cache::alloc(allocator.as_ref(), layout, tls_self_pointer)
+                    .unwrap_or_else(|| allocator.alloc(layout))
             }
             None => {       
                 self.initial_allocator.lock().allocate(layout)
@@ -88,15 +120,61 @@ unsafe impl GlobalAlloc for Heap {
         }
     }
 
-    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+    /// Returns a block allocated by `alloc_block()` to the allocator it came from.
+    unsafe fn dealloc_block(&self, ptr: *mut u8, layout: Layout, tls_self_pointer: Option<usize>) {
         if (ptr as usize) < INITIAL_HEAP_END_ADDR {
             self.initial_allocator.lock().deallocate(ptr, layout);
         }
         else {
-            DEFAULT_ALLOCATOR.get()
-                .expect("Ptr passed to dealloc is not within the initial allocator's range, and another allocator has not been set up")
-                .dealloc(ptr, layout);
+            let allocator = DEFAULT_ALLOCATOR.get()
+                .expect("Ptr passed to dealloc is not within the initial allocator's range, and another allocator has not been set up");
+            if !tls_cache::dealloc(allocator.as_ref(), ptr, layout, tls_self_pointer) {
+                allocator.dealloc(ptr, layout);
+            }
+        }
+    }
+}
+
+/// Adds the given values to the profiling counters of the current task,
+/// whose TLS self pointer is `tls_self_pointer`.
+///
+/// Interrupts are held such that an interrupt handler's allocations on this CPU
+/// cannot interleave with this task's updates to its own counters.
+fn add_to_counters(tls_self_pointer: Option<usize>, values: &[(Counter, u64)]) {
+    if let Some(tls_self_pointer) = tls_self_pointer {
+        let _held_interrupts = irq_safety::hold_interrupts();
+        for &(counter, value) in values {
+            // SAFE: the TLS self pointer is that of the current task, and interrupts are held.
+            unsafe { tls_counters::add_at(tls_self_pointer, counter, value); }
         }
     }
+}
+
+unsafe impl GlobalAlloc for Heap {
+
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let tls_self_pointer = tls_initializer::try_current_tls_self_pointer();
+        let ptr = sanitizer::alloc(
+            layout,
+            |padded| self.alloc_block(padded, tls_self_pointer),
+            |block, padded| self.dealloc_block(block, padded, tls_self_pointer),
+        ).unwrap_or_else(|| self.alloc_block(layout, tls_self_pointer));
+        if !ptr.is_null() {
+            add_to_counters(tls_self_pointer, &[
+                (Counter::Allocations, 1),
+                (Counter::HeapBytesAllocated, layout.size() as u64),
+            ]);
+            profiling::on_alloc(ptr, layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let tls_self_pointer = tls_initializer::try_current_tls_self_pointer();
+        add_to_counters(tls_self_pointer, &[(Counter::HeapBytesFreed, layout.size() as u64)]);
+        profiling::on_dealloc(ptr);
+        let (block, layout) = sanitizer::dealloc(ptr, layout);
+        self.dealloc_block(block, layout, tls_self_pointer);
+    }
 
 }
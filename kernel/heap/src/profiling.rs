@@ -0,0 +1,262 @@
+//! Optional heap profiling, which tracks every outstanding allocation and the owner that made it.
+//!
+//! Profiling is disabled by default and costs only a single atomic load per allocation when disabled.
+//! Once enabled via [`enable_profiling()`], every allocation is recorded along with its size
+//! and its owner, which is an opaque ID returned by the owner function given to [`enable_profiling()`],
+//! e.g., the ID of the current task or the address of the current application crate.
+//! Each owner's allocation counts and bytes are accumulated, and the allocations that it hasn't yet freed
+//! can be listed, which helps pinpoint memory leaks.
+//!
+//! The profiler must not allocate from the heap it is profiling,
+//! so all of its records are kept in fixed-size tables that are mapped up front when profiling is enabled.
+//! If those tables fill up, further allocations are not recorded but are counted as dropped.
+
+use alloc::vec::Vec;
+use core::{mem::size_of, sync::atomic::{AtomicBool, Ordering}};
+use irq_safety::MutexIrqSafe;
+use memory::MappedPages;
+use zerocopy::FromBytes;
+use crate::HEAP_FLAGS;
+
+/// The maximum number of outstanding allocations that can be recorded at once.
+const MAX_RECORDS: usize = 64 * 1024;
+/// The maximum number of distinct owners that can be recorded.
+const MAX_OWNERS: usize = 128;
+
+/// Whether profiling is enabled, which is checked before taking the `PROFILER` lock.
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static PROFILER: MutexIrqSafe<Option<Profiler>> = MutexIrqSafe::new(None);
+
+/// A single outstanding allocation. An `addr` of zero denotes an empty slot.
+#[derive(Clone, Copy, FromBytes)]
+#[repr(C)]
+struct Record {
+    addr: usize,
+    size: usize,
+    /// The index of the owner in `Profiler::owners`.
+    owner_index: usize,
+}
+
+/// The heap usage of a single owner, accumulated since profiling was enabled.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct OwnerProfile {
+    /// The opaque ID of the owner, as returned by the owner function.
+    pub owner: usize,
+    /// The total number of allocations made by this owner.
+    pub allocations: usize,
+    /// The total number of bytes allocated by this owner.
+    pub bytes_allocated: usize,
+    /// The number of this owner's allocations that have not yet been freed.
+    pub outstanding_allocations: usize,
+    /// The number of bytes in this owner's allocations that have not yet been freed.
+    pub outstanding_bytes: usize,
+}
+
+/// A snapshot of the heap profile of every owner.
+#[derive(Debug, Clone)]
+pub struct ProfilingReport {
+    /// The profile of each owner that has allocated memory since profiling was enabled.
+    pub owners: Vec<OwnerProfile>,
+    /// The number of allocations that could not be recorded because the profiler's tables were full.
+    pub dropped: usize,
+}
+
+struct Profiler {
+    /// The function that returns the owner of the current allocation.
+    owner_fn: fn() -> usize,
+    /// The open-addressed hash table of outstanding allocations, keyed by address.
+    records: MappedPages,
+    owners: [OwnerProfile; MAX_OWNERS],
+    num_owners: usize,
+    dropped: usize,
+}
+
+impl Profiler {
+    fn records(&mut self) -> &mut [Record] {
+        self.records.as_slice_mut(0, MAX_RECORDS)
+            .expect("BUG: heap profiler's records mapping was too small")
+    }
+
+    /// Returns the index in `self.owners` of the given `owner`, adding it if necessary.
+    fn owner_index(&mut self, owner: usize) -> Option<usize> {
+        if let Some(index) = self.owners[..self.num_owners].iter().position(|o| o.owner == owner) {
+            return Some(index);
+        }
+        if self.num_owners == MAX_OWNERS {
+            return None;
+        }
+        self.owners[self.num_owners] = OwnerProfile { owner, ..Default::default() };
+        self.num_owners += 1;
+        Some(self.num_owners - 1)
+    }
+
+    fn record_alloc(&mut self, addr: usize, size: usize) {
+        let owner = (self.owner_fn)();
+        let Some(owner_index) = self.owner_index(owner) else {
+            self.dropped += 1;
+            return;
+        };
+        let records = self.records();
+        let start = slot_of(addr);
+        let free_slot = (0..MAX_RECORDS)
+            .map(|i| (start + i) % MAX_RECORDS)
+            .find(|&slot| records[slot].addr == 0);
+        let Some(slot) = free_slot else {
+            self.dropped += 1;
+            return;
+        };
+        records[slot] = Record { addr, size, owner_index };
+
+        let profile = &mut self.owners[owner_index];
+        profile.allocations += 1;
+        profile.bytes_allocated += size;
+        profile.outstanding_allocations += 1;
+        profile.outstanding_bytes += size;
+    }
+
+    fn record_dealloc(&mut self, addr: usize) {
+        let records = self.records();
+        let start = slot_of(addr);
+        let mut slot = start;
+        // Allocations made before profiling was enabled or that were dropped won't be found.
+        loop {
+            if records[slot].addr == addr {
+                break;
+            }
+            if records[slot].addr == 0 {
+                return;
+            }
+            slot = (slot + 1) % MAX_RECORDS;
+            if slot == start {
+                return;
+            }
+        }
+        let removed = records[slot];
+
+        // Backward-shift deletion: move later records in the same probe sequence into the hole,
+        // such that lookups never stop early at an empty slot.
+        let mut hole = slot;
+        let mut next = (hole + 1) % MAX_RECORDS;
+        while records[next].addr != 0 {
+            let home = slot_of(records[next].addr);
+            let distance_to_hole = (hole + MAX_RECORDS - home) % MAX_RECORDS;
+            let distance_to_next = (next + MAX_RECORDS - home) % MAX_RECORDS;
+            if distance_to_hole < distance_to_next {
+                records[hole] = records[next];
+                hole = next;
+            }
+            next = (next + 1) % MAX_RECORDS;
+        }
+        records[hole].addr = 0;
+
+        let profile = &mut self.owners[removed.owner_index];
+        profile.outstanding_allocations -= 1;
+        profile.outstanding_bytes -= removed.size;
+    }
+}
+
+/// Returns the slot in the records table at which the lookup for the given address begins.
+fn slot_of(addr: usize) -> usize {
+    // Heap blocks are at least 8-byte aligned, so the lowest bits are useless for hashing.
+    (addr >> 3).wrapping_mul(0x9E37_79B9_7F4A_7C15) % MAX_RECORDS
+}
+
+/// Starts recording every heap allocation along with the owner returned by `owner_fn`.
+///
+/// The `owner_fn` is invoked on every allocation while holding the profiler's lock,
+/// so it must not allocate from the heap, take any locks, or be unloaded while profiling is enabled.
+///
+/// Returns an error if profiling is already enabled or the profiler's tables couldn't be mapped.
+pub fn enable_profiling(owner_fn: fn() -> usize) -> Result<(), &'static str> {
+    if ENABLED.load(Ordering::Acquire) {
+        return Err("heap profiling is already enabled");
+    }
+    // Map the records table before taking the lock, since mapping it may allocate from the heap.
+    let mut records = memory::create_mapping(MAX_RECORDS * size_of::<Record>(), HEAP_FLAGS)?;
+    for record in records.as_slice_mut::<Record>(0, MAX_RECORDS)? {
+        record.addr = 0;
+    }
+    let mut profiler = PROFILER.lock();
+    if profiler.is_some() {
+        return Err("heap profiling is already enabled");
+    }
+    *profiler = Some(Profiler {
+        owner_fn,
+        records,
+        owners: [OwnerProfile::default(); MAX_OWNERS],
+        num_owners: 0,
+        dropped: 0,
+    });
+    ENABLED.store(true, Ordering::Release);
+    Ok(())
+}
+
+/// Stops recording heap allocations and discards all recorded profiles.
+pub fn disable_profiling() {
+    ENABLED.store(false, Ordering::Release);
+    let profiler = PROFILER.lock().take();
+    // Unmapping the records table may deallocate heap memory, so it's dropped after the lock is released.
+    drop(profiler);
+}
+
+/// Returns whether heap profiling is currently enabled.
+pub fn is_profiling_enabled() -> bool {
+    ENABLED.load(Ordering::Acquire)
+}
+
+/// Returns the heap profile of every owner recorded since profiling was enabled,
+/// or `None` if profiling is not enabled.
+pub fn profiling_report() -> Option<ProfilingReport> {
+    // Copy the profiles out first, as creating the `Vec` while holding the lock would deadlock.
+    let (owners, num_owners, dropped) = {
+        let profiler = PROFILER.lock();
+        let profiler = profiler.as_ref()?;
+        (profiler.owners, profiler.num_owners, profiler.dropped)
+    };
+    Some(ProfilingReport {
+        owners: owners[..num_owners].to_vec(),
+        dropped,
+    })
+}
+
+/// Returns the address and size of each allocation made by the given `owner` that hasn't yet been freed,
+/// or `None` if profiling is not enabled.
+///
+/// Allocations made while this function is running may not be included.
+pub fn outstanding_allocations(owner: usize) -> Option<Vec<(usize, usize)>> {
+    let expected = profiling_report()?.owners.iter()
+        .find(|o| o.owner == owner)
+        .map_or(0, |o| o.outstanding_allocations);
+    // Reserve space up front, since the `Vec` must not grow while holding the lock.
+    let mut allocations = Vec::with_capacity(expected + expected / 8 + 16);
+    let mut profiler = PROFILER.lock();
+    let profiler = profiler.as_mut()?;
+    let Some(owner_index) = profiler.owners[..profiler.num_owners].iter().position(|o| o.owner == owner) else {
+        return Some(allocations);
+    };
+    for record in profiler.records().iter().filter(|r| r.addr != 0 && r.owner_index == owner_index) {
+        if allocations.len() == allocations.capacity() {
+            break;
+        }
+        allocations.push((record.addr, record.size));
+    }
+    Some(allocations)
+}
+
+/// Records a new allocation, if profiling is enabled.
+pub(crate) fn on_alloc(ptr: *mut u8, size: usize) {
+    if ENABLED.load(Ordering::Relaxed) {
+        if let Some(profiler) = PROFILER.lock().as_mut() {
+            profiler.record_alloc(ptr as usize, size);
+        }
+    }
+}
+
+/// Removes the record of a freed allocation, if profiling is enabled.
+pub(crate) fn on_dealloc(ptr: *mut u8) {
+    if ENABLED.load(Ordering::Relaxed) {
+        if let Some(profiler) = PROFILER.lock().as_mut() {
+            profiler.record_dealloc(ptr as usize);
+        }
+    }
+}
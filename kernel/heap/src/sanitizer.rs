@@ -0,0 +1,357 @@
+//! Optional poisoned redzones around heap allocations, a lightweight kernel address sanitizer.
+//!
+//! The sanitizer is disabled by default. Once enabled via [`enable_sanitizer()`],
+//! every allocation is surrounded by redzones of at least `REDZONE_SIZE` bytes,
+//! which are filled with [`HEAP_POISON_BYTE`] and marked as poisoned in a shadow bitmap
+//! that holds one bit per `GRANULE` bytes of the heap.
+//! The front redzone also holds a small header with the allocation's size and owner,
+//! which is the opaque ID returned by the owner function given to [`enable_sanitizer()`],
+//! e.g., the ID of the current task.
+//!
+//! Redzones are checked in two ways:
+//! * When an allocation is freed, all of its redzone bytes must still hold the poison value;
+//!   otherwise, the allocation was overflowed or underflowed, which is reported immediately via a panic.
+//! * Slow-path accessors can check that a range of memory doesn't touch a redzone
+//!   via [`check_access()`], which attributes a bad access to the allocation it overflowed.
+//!
+//! Only allocations within the first `SHADOW_COVERAGE` bytes of the heap get redzones.
+//! Allocations made before the sanitizer was enabled are unaffected, and allocations made while it was enabled
+//! keep their redzones until they're freed, even if the sanitizer has since been disabled.
+
+use core::{
+    alloc::Layout,
+    cmp::max,
+    fmt,
+    mem::size_of,
+    sync::atomic::{AtomicBool, Ordering},
+};
+use irq_safety::MutexIrqSafe;
+use kernel_config::memory::KERNEL_HEAP_START;
+use memory::MappedPages;
+use crate::HEAP_FLAGS;
+
+/// The number of bytes tracked by each bit of the shadow bitmap.
+const GRANULE: usize = 16;
+/// The minimum size of the redzones before and after each allocation, which must be a multiple of `GRANULE`.
+const REDZONE_SIZE: usize = 32;
+/// The number of bytes at the start of the heap that the shadow bitmap covers.
+const SHADOW_COVERAGE: usize = 1 << 30;
+/// The number of `u64` words in the shadow bitmap.
+const SHADOW_WORDS: usize = SHADOW_COVERAGE / GRANULE / 64;
+/// The farthest distance that [`check_access()`] scans to attribute a bad access to an allocation.
+const MAX_ATTRIBUTION_DISTANCE: usize = 64 * 1024;
+/// Identifies a valid `Header`, XOR-ed with the address of its allocation.
+const HEADER_MAGIC: usize = 0x5245_445A_4F4E_4521;
+
+/// The value of every redzone byte.
+pub const HEAP_POISON_BYTE: u8 = 0xFB;
+
+/// Whether new allocations get redzones, which is checked before taking the `SANITIZER` lock.
+static ENABLED: AtomicBool = AtomicBool::new(false);
+/// Whether the shadow bitmap exists, i.e., whether any allocation may have redzones.
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+static SANITIZER: MutexIrqSafe<Option<Sanitizer>> = MutexIrqSafe::new(None);
+
+/// The metadata stored right before the start of each allocation that has redzones.
+#[repr(C)]
+struct Header {
+    magic: usize,
+    size: usize,
+    owner: usize,
+}
+
+struct Sanitizer {
+    /// The function that returns the owner of the current allocation.
+    owner_fn: fn() -> usize,
+    /// The shadow bitmap, in which a set bit denotes a poisoned granule.
+    shadow: MappedPages,
+}
+
+impl Sanitizer {
+    fn shadow(&mut self) -> &mut [u64] {
+        self.shadow.as_slice_mut(0, SHADOW_WORDS)
+            .expect("BUG: heap sanitizer's shadow mapping was too small")
+    }
+
+    fn is_poisoned(&mut self, addr: usize) -> bool {
+        let granule = (addr - KERNEL_HEAP_START) / GRANULE;
+        self.shadow()[granule / 64] & (1 << (granule % 64)) != 0
+    }
+
+    /// Sets the poisoned state of every granule in the given range, which must be granule-aligned.
+    fn set_poisoned(&mut self, start: usize, end: usize, poisoned: bool) {
+        let shadow = self.shadow();
+        for granule in ((start - KERNEL_HEAP_START) / GRANULE) .. ((end - KERNEL_HEAP_START) / GRANULE) {
+            if poisoned {
+                shadow[granule / 64] |= 1 << (granule % 64);
+            } else {
+                shadow[granule / 64] &= !(1 << (granule % 64));
+            }
+        }
+    }
+
+    /// Returns the size and owner of the allocation that starts at `addr`, if it has redzones.
+    ///
+    /// # Safety
+    /// The granule before `addr` must be poisoned, such that the header location is mapped.
+    unsafe fn header_of(&self, addr: usize) -> Option<(usize, usize)> {
+        let header = &*((addr - size_of::<Header>()) as *const Header);
+        (header.magic == HEADER_MAGIC ^ addr).then_some((header.size, header.owner))
+    }
+
+    /// Attributes a bad access of the given poisoned address to the allocation whose redzone it's in.
+    fn attribute(&mut self, addr: usize) -> HeapViolation {
+        let granule_start = addr & !(GRANULE - 1);
+        let heap_end = KERNEL_HEAP_START + SHADOW_COVERAGE;
+
+        // First, look for a preceding allocation whose rear redzone contains the address.
+        let mut g = granule_start;
+        let limit = granule_start.saturating_sub(MAX_ATTRIBUTION_DISTANCE).max(KERNEL_HEAP_START + GRANULE);
+        while g > limit && self.is_poisoned(g) {
+            g -= GRANULE;
+        }
+        if g > limit {
+            // `g` is the last granule of the preceding allocation, so find its first granule.
+            while g > limit && !self.is_poisoned(g - GRANULE) {
+                g -= GRANULE;
+            }
+            if self.is_poisoned(g - GRANULE) {
+                // SAFETY: the granule before `g` is poisoned.
+                if let Some((size, owner)) = unsafe { self.header_of(g) } {
+                    if addr >= g + size && addr < g + round_up(size) + REDZONE_SIZE {
+                        return HeapViolation { kind: HeapViolationKind::Overflow, address: addr, allocation: g, size, owner };
+                    }
+                }
+            }
+        }
+
+        // Otherwise, look for a following allocation whose front redzone contains the address.
+        let mut g = granule_start;
+        let limit = (granule_start + MAX_ATTRIBUTION_DISTANCE).min(heap_end);
+        while g < limit && self.is_poisoned(g) {
+            g += GRANULE;
+        }
+        if g < limit {
+            // SAFETY: the granule before `g` is poisoned.
+            if let Some((size, owner)) = unsafe { self.header_of(g) } {
+                return HeapViolation { kind: HeapViolationKind::Underflow, address: addr, allocation: g, size, owner };
+            }
+        }
+        HeapViolation { kind: HeapViolationKind::Unattributed, address: addr, allocation: 0, size: 0, owner: 0 }
+    }
+}
+
+/// The kind of a [`HeapViolation`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HeapViolationKind {
+    /// A byte after the end of an allocation was accessed.
+    Overflow,
+    /// A byte before the start of an allocation was accessed.
+    Underflow,
+    /// An allocation was freed with a different size than it was allocated with.
+    SizeMismatch,
+    /// A redzone byte was accessed, but the allocation it belongs to couldn't be determined.
+    Unattributed,
+}
+
+/// A bad access of a heap redzone, along with the allocation whose redzone it is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeapViolation {
+    pub kind: HeapViolationKind,
+    /// The address of the bad access.
+    pub address: usize,
+    /// The starting address of the allocation, or `0` if it is unattributed.
+    pub allocation: usize,
+    /// The size of the allocation.
+    pub size: usize,
+    /// The owner that made the allocation, as returned by the sanitizer's owner function.
+    pub owner: usize,
+}
+
+impl fmt::Display for HeapViolation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.kind {
+            HeapViolationKind::Overflow => write!(f,
+                "heap buffer overflow at {:#X}, {} bytes past the end of the {}-byte allocation at {:#X} made by owner {}",
+                self.address, self.address - (self.allocation + self.size), self.size, self.allocation, self.owner,
+            ),
+            HeapViolationKind::Underflow => write!(f,
+                "heap buffer underflow at {:#X}, {} bytes before the {}-byte allocation at {:#X} made by owner {}",
+                self.address, self.allocation - self.address, self.size, self.allocation, self.owner,
+            ),
+            HeapViolationKind::SizeMismatch => write!(f,
+                "the {}-byte allocation at {:#X} made by owner {} was freed with a size of {} bytes",
+                self.size, self.allocation, self.owner, self.address,
+            ),
+            HeapViolationKind::Unattributed => write!(f, "access of a heap redzone at {:#X}", self.address),
+        }
+    }
+}
+
+fn round_up(size: usize) -> usize {
+    (size + GRANULE - 1) & !(GRANULE - 1)
+}
+
+/// Returns the size of the front redzone for an allocation with the given layout,
+/// which also keeps the allocation itself aligned.
+fn front_redzone_size(layout: Layout) -> usize {
+    max(REDZONE_SIZE, layout.align())
+}
+
+/// Returns the layout of the whole block for an allocation with the given layout, including both redzones.
+fn padded_layout(layout: Layout) -> Option<Layout> {
+    let size = front_redzone_size(layout)
+        .checked_add(round_up(layout.size()))?
+        .checked_add(REDZONE_SIZE)?;
+    Layout::from_size_align(size, max(layout.align(), GRANULE)).ok()
+}
+
+/// Returns whether the given range of addresses is covered by the shadow bitmap.
+fn is_covered(start: usize, end: usize) -> bool {
+    start >= KERNEL_HEAP_START && end <= KERNEL_HEAP_START + SHADOW_COVERAGE
+}
+
+/// Starts adding redzones to every new heap allocation, recording the owner returned by `owner_fn` for each.
+///
+/// The `owner_fn` is invoked on every allocation without holding any lock,
+/// but it must not allocate from the heap or be unloaded while the sanitizer is enabled.
+/// If the sanitizer was previously enabled, the given `owner_fn` replaces the previous one.
+///
+/// Returns an error if the shadow bitmap couldn't be mapped.
+pub fn enable_sanitizer(owner_fn: fn() -> usize) -> Result<(), &'static str> {
+    if !ACTIVE.load(Ordering::Acquire) {
+        // Map the shadow bitmap before taking the lock, since mapping it may allocate from the heap.
+        let mut shadow = memory::create_mapping(SHADOW_WORDS * size_of::<u64>(), HEAP_FLAGS)?;
+        shadow.as_slice_mut::<u64>(0, SHADOW_WORDS)?.fill(0);
+        let mut sanitizer = SANITIZER.lock();
+        if sanitizer.is_none() {
+            *sanitizer = Some(Sanitizer { owner_fn, shadow });
+            ACTIVE.store(true, Ordering::Release);
+        }
+    }
+    if let Some(sanitizer) = SANITIZER.lock().as_mut() {
+        sanitizer.owner_fn = owner_fn;
+    }
+    ENABLED.store(true, Ordering::Release);
+    Ok(())
+}
+
+/// Stops adding redzones to new heap allocations.
+///
+/// Existing allocations with redzones are still checked when they're freed.
+pub fn disable_sanitizer() {
+    ENABLED.store(false, Ordering::Release);
+}
+
+/// Returns whether new heap allocations get redzones.
+pub fn is_sanitizer_enabled() -> bool {
+    ENABLED.load(Ordering::Acquire)
+}
+
+/// Checks that the `len` bytes at the given address don't touch a heap redzone,
+/// e.g., before a slow-path accessor copies them.
+///
+/// This checks whole granules, so it can miss a bad access of the few bytes
+/// between the end of an allocation and the end of its last granule;
+/// those bytes are still checked when the allocation is freed.
+pub fn check_access(addr: usize, len: usize) -> Result<(), HeapViolation> {
+    if !ACTIVE.load(Ordering::Relaxed) || len == 0 {
+        return Ok(());
+    }
+    let Some(end) = addr.checked_add(len) else { return Ok(()) };
+    let start = max(addr, KERNEL_HEAP_START);
+    let end = end.min(KERNEL_HEAP_START + SHADOW_COVERAGE);
+    let mut sanitizer = SANITIZER.lock();
+    let Some(sanitizer) = sanitizer.as_mut() else { return Ok(()) };
+    let mut g = start & !(GRANULE - 1);
+    while g < end {
+        if sanitizer.is_poisoned(g) {
+            return Err(sanitizer.attribute(max(g, start)));
+        }
+        g += GRANULE;
+    }
+    Ok(())
+}
+
+/// Allocates a block with redzones via the given `alloc` function, if the sanitizer is enabled.
+///
+/// Returns `None` if the allocation should be made without redzones instead.
+pub(crate) unsafe fn alloc(layout: Layout, alloc: impl Fn(Layout) -> *mut u8, dealloc: impl FnOnce(*mut u8, Layout)) -> Option<*mut u8> {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return None;
+    }
+    let padded = padded_layout(layout)?;
+    let owner_fn = SANITIZER.lock().as_ref()?.owner_fn;
+    let owner = owner_fn();
+
+    let base = alloc(padded);
+    if base.is_null() {
+        return Some(base);
+    }
+    let base_addr = base as usize;
+    if !is_covered(base_addr, base_addr + padded.size()) {
+        dealloc(base, padded);
+        return None;
+    }
+    let front = front_redzone_size(layout);
+    let addr = base_addr + front;
+    let body_end = addr + round_up(layout.size());
+
+    core::ptr::write_bytes(base, HEAP_POISON_BYTE, front);
+    core::ptr::write_bytes((addr + layout.size()) as *mut u8, HEAP_POISON_BYTE, padded.size() - front - layout.size());
+    ((addr - size_of::<Header>()) as *mut Header).write(Header { magic: HEADER_MAGIC ^ addr, size: layout.size(), owner });
+
+    let mut sanitizer = SANITIZER.lock();
+    let sanitizer = sanitizer.as_mut().expect("BUG: heap sanitizer was enabled without a shadow bitmap");
+    sanitizer.set_poisoned(base_addr, addr, true);
+    sanitizer.set_poisoned(addr, body_end, false);
+    sanitizer.set_poisoned(body_end, base_addr + padded.size(), true);
+    Some(addr as *mut u8)
+}
+
+/// Checks the redzones of the allocation at `ptr` that is being freed, if it has any,
+/// and returns the block and layout that must actually be freed.
+///
+/// # Panics
+/// Panics if the allocation's redzones were overwritten or if it's freed with the wrong size.
+pub(crate) unsafe fn dealloc(ptr: *mut u8, layout: Layout) -> (*mut u8, Layout) {
+    if !ACTIVE.load(Ordering::Relaxed) {
+        return (ptr, layout);
+    }
+    let addr = ptr as usize;
+    if !is_covered(addr.saturating_sub(GRANULE), addr) {
+        return (ptr, layout);
+    }
+    let mut sanitizer = SANITIZER.lock();
+    let Some(sanitizer) = sanitizer.as_mut() else { return (ptr, layout) };
+    if !sanitizer.is_poisoned(addr - GRANULE) {
+        return (ptr, layout);
+    }
+    // SAFETY: the granule before `addr` is poisoned.
+    let Some((size, owner)) = sanitizer.header_of(addr) else { return (ptr, layout) };
+    let violation = |kind, address| HeapViolation { kind, address, allocation: addr, size, owner };
+    if size != layout.size() {
+        let v = violation(HeapViolationKind::SizeMismatch, layout.size());
+        drop(sanitizer);
+        panic!("heap sanitizer: {}", v);
+    }
+
+    let padded = padded_layout(layout).expect("BUG: heap sanitizer couldn't recompute a padded layout");
+    let base_addr = addr - front_redzone_size(layout);
+    let header_start = addr - size_of::<Header>();
+    let front = base_addr .. header_start;
+    let rear = (addr + size) .. (base_addr + padded.size());
+    let corrupted = front.clone().chain(rear.clone())
+        .find(|&a| *(a as *const u8) != HEAP_POISON_BYTE);
+    if let Some(bad) = corrupted {
+        let kind = if bad < addr { HeapViolationKind::Underflow } else { HeapViolationKind::Overflow };
+        let v = violation(kind, bad);
+        drop(sanitizer);
+        panic!("heap sanitizer: {}", v);
+    }
+
+    sanitizer.set_poisoned(base_addr, base_addr + padded.size(), false);
+    // Invalidate the header, such that a double free isn't mistaken for an allocation with redzones.
+    (header_start as *mut Header).write(Header { magic: 0, size: 0, owner: 0 });
+    (base_addr as *mut u8, padded)
+}
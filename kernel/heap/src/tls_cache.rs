@@ -0,0 +1,151 @@
+//! A small per-task cache ("magazine") of free heap blocks that resides in the task's TLS area.
+//!
+//! Small allocations are served from and returned to the current task's magazine
+//! without taking any locks. When a size class's magazine is empty or full,
+//! half of it is refilled from or flushed to the default allocator in a single batch.
+//!
+//! Every small allocation is made with the layout of its size class, even when it bypasses the magazine,
+//! so that any small block can be cached by any task and later handed out for any layout in its class.
+//!
+//! The TLS region that holds each task's magazine is reserved by the `tls_initializer` crate,
+//! which also invokes our [`init`] and [`teardown`] hooks when a task starts and exits.
+
+use core::{alloc::{GlobalAlloc, Layout}, mem::size_of};
+use tls_initializer::{HeapCacheHooks, HEAP_CACHE_SIZE};
+
+/// The block sizes of each size class, which are also the alignment of each block.
+const SIZE_CLASSES: [usize; NUM_SIZE_CLASSES] = [16, 32, 64, 128, 256, 512];
+const NUM_SIZE_CLASSES: usize = 6;
+/// The maximum number of free blocks cached per size class.
+const MAGAZINE_CAPACITY: usize = 8;
+/// The number of blocks that are refilled or flushed at once.
+const BATCH_SIZE: usize = MAGAZINE_CAPACITY / 2;
+
+// A magazine's state is initially zero, meaning it's not yet usable, e.g., the task hasn't started running yet.
+/// The magazine is initialized and can be used.
+const STATE_ACTIVE: usize = 1;
+/// The magazine has been torn down because the task is exiting.
+const STATE_TORN_DOWN: usize = 2;
+
+/// The contents of the per-task heap cache region in the TLS area.
+#[repr(C)]
+struct Magazine {
+    state: usize,
+    counts: [usize; NUM_SIZE_CLASSES],
+    blocks: [[*mut u8; MAGAZINE_CAPACITY]; NUM_SIZE_CLASSES],
+}
+
+const _: () = assert!(size_of::<Magazine>() <= HEAP_CACHE_SIZE);
+
+/// The hooks that are registered with the `tls_initializer` crate.
+pub(crate) const HOOKS: HeapCacheHooks = HeapCacheHooks { init, teardown };
+
+/// Returns the index of the size class that can hold an allocation with the given `layout`.
+fn size_class_of(layout: Layout) -> Option<usize> {
+    SIZE_CLASSES.iter().position(|&size| layout.size() <= size && layout.align() <= size)
+}
+
+/// Returns the layout used to allocate blocks of the given size class from the default allocator.
+fn block_layout(class: usize) -> Layout {
+    // SAFE: all size classes are nonzero powers of two.
+    unsafe { Layout::from_size_align_unchecked(SIZE_CLASSES[class], SIZE_CLASSES[class]) }
+}
+
+/// Returns the magazine in the TLS area with the given TLS self pointer, if it is active.
+///
+/// Interrupts must be disabled while using the returned magazine,
+/// because an interrupt handler may also use the heap.
+fn magazine_at(tls_self_pointer: usize) -> Option<&'static mut Magazine> {
+    let region = tls_initializer::heap_cache_location_at(tls_self_pointer);
+    // SAFE: the TLS area always reserves a zero-initialized, pointer-aligned region for the magazine.
+    let magazine = unsafe { &mut *(region as *mut Magazine) };
+    (magazine.state == STATE_ACTIVE).then_some(magazine)
+}
+
+fn init(region: *mut u8) {
+    let magazine = unsafe { &mut *(region as *mut Magazine) };
+    magazine.counts = [0; NUM_SIZE_CLASSES];
+    magazine.state = STATE_ACTIVE;
+}
+
+fn teardown(region: *mut u8) {
+    let _held_interrupts = irq_safety::hold_interrupts();
+    let magazine = unsafe { &mut *(region as *mut Magazine) };
+    if magazine.state != STATE_ACTIVE {
+        return;
+    }
+    // Prevent any further deallocations from this task from being cached.
+    magazine.state = STATE_TORN_DOWN;
+    if let Some(allocator) = super::DEFAULT_ALLOCATOR.get() {
+        for class in 0..NUM_SIZE_CLASSES {
+            flush(magazine, allocator.as_ref(), class, magazine.counts[class]);
+        }
+    }
+}
+
+/// Returns `count` blocks of the given size class from the `magazine` to the `allocator`.
+fn flush(magazine: &mut Magazine, allocator: &(dyn GlobalAlloc + Send + Sync), class: usize, count: usize) {
+    for _ in 0..count {
+        magazine.counts[class] -= 1;
+        let block = magazine.blocks[class][magazine.counts[class]];
+        unsafe { allocator.dealloc(block, block_layout(class)); }
+    }
+}
+
+/// Allocates a block that fits the given `layout` from the magazine of the current task,
+/// whose TLS self pointer is `tls_self_pointer`, refilling it from the `allocator` if needed.
+/// If the magazine is not active, a block of the same size class is allocated directly from the `allocator`.
+///
+/// Returns `None` if the `layout` is too large for any size class,
+/// in which case the caller should allocate directly from the `allocator`.
+pub(crate) fn alloc(allocator: &(dyn GlobalAlloc + Send + Sync), layout: Layout, tls_self_pointer: Option<usize>) -> Option<*mut u8> {
+    let class = size_class_of(layout)?;
+    let _held_interrupts = irq_safety::hold_interrupts();
+    let magazine = match tls_self_pointer.and_then(magazine_at) {
+        Some(magazine) => magazine,
+        None => return Some(unsafe { allocator.alloc(block_layout(class)) }),
+    };
+    if magazine.counts[class] == 0 {
+        for _ in 0..BATCH_SIZE {
+            let block = unsafe { allocator.alloc(block_layout(class)) };
+            if block.is_null() {
+                break;
+            }
+            magazine.blocks[class][magazine.counts[class]] = block;
+            magazine.counts[class] += 1;
+        }
+        if magazine.counts[class] == 0 {
+            return Some(core::ptr::null_mut());
+        }
+    }
+    magazine.counts[class] -= 1;
+    Some(magazine.blocks[class][magazine.counts[class]])
+}
+
+/// Returns the given block to the magazine of the current task, whose TLS self pointer is `tls_self_pointer`,
+/// flushing part of the magazine to the `allocator` if it is full.
+///
+/// If the magazine is not active, the block is deallocated directly to the `allocator` with its size class's layout.
+///
+/// Returns `false` if the `layout` is too large for any size class,
+/// in which case the caller should deallocate directly to the `allocator`.
+pub(crate) fn dealloc(allocator: &(dyn GlobalAlloc + Send + Sync), ptr: *mut u8, layout: Layout, tls_self_pointer: Option<usize>) -> bool {
+    let class = match size_class_of(layout) {
+        Some(class) => class,
+        None => return false,
+    };
+    let _held_interrupts = irq_safety::hold_interrupts();
+    let magazine = match tls_self_pointer.and_then(magazine_at) {
+        Some(magazine) => magazine,
+        None => {
+            unsafe { allocator.dealloc(ptr, block_layout(class)); }
+            return true;
+        }
+    };
+    if magazine.counts[class] == MAGAZINE_CAPACITY {
+        flush(magazine, allocator, class, BATCH_SIZE);
+    }
+    magazine.blocks[class][magazine.counts[class]] = ptr;
+    magazine.counts[class] += 1;
+    true
+}
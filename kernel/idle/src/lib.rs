@@ -0,0 +1,89 @@
+//! Puts the current CPU into a low-power state until the next interrupt.
+//!
+//! This is used by each CPU's idle task instead of busy-waiting, such that idle CPUs
+//! consume less power and leave more resources to their hyperthread siblings.
+//! An idle CPU is woken up by its timer interrupt or by an IPI, after which
+//! the scheduler can switch to any task that has become runnable in the meantime.
+//!
+//! On x86_64, this uses `MWAIT` if the CPU supports it, or `HLT` otherwise.
+//! On aarch64, this uses `WFI`.
+
+#![no_std]
+
+use core::sync::atomic::{AtomicU8, Ordering};
+use spin::Once;
+
+/// The `MWAIT` hint that selects the shallowest C-state, C1, which has the lowest wakeup latency.
+pub const MWAIT_HINT_C1: u8 = 0x00;
+
+/// The hint passed to `MWAIT`, which selects the target C-state.
+static MWAIT_HINT: AtomicU8 = AtomicU8::new(MWAIT_HINT_C1);
+
+/// Sets the hint passed to `MWAIT`, which selects the C-state that idle CPUs enter.
+///
+/// Deeper C-states save more power but take longer to wake up from.
+/// The valid hints are model-specific and given by `CPUID` leaf 5.
+pub fn set_mwait_hint(hint: u8) {
+    MWAIT_HINT.store(hint, Ordering::Relaxed);
+}
+
+/// Returns the hint passed to `MWAIT`.
+pub fn mwait_hint() -> u8 {
+    MWAIT_HINT.load(Ordering::Relaxed)
+}
+
+/// Returns whether this CPU supports the `MONITOR` and `MWAIT` instructions.
+pub fn has_mwait() -> bool {
+    static HAS_MWAIT: Once<bool> = Once::new();
+    *HAS_MWAIT.call_once(|| {
+        #[cfg(target_arch = "x86_64")] {
+            raw_cpuid::CpuId::new()
+                .get_feature_info()
+                .map_or(false, |info| info.has_monitor_mwait())
+        }
+        #[cfg(not(target_arch = "x86_64"))] {
+            false
+        }
+    })
+}
+
+/// Puts the current CPU into a low-power state until the next interrupt occurs.
+///
+/// Interrupts must be enabled, otherwise this returns immediately,
+/// because the CPU could never be woken up.
+#[inline]
+pub fn idle() {
+    if !irq_safety::interrupts_enabled() {
+        core::hint::spin_loop();
+        return;
+    }
+    #[cfg(target_arch = "x86_64")] {
+        if has_mwait() {
+            /// The cache line monitored by `MWAIT`, which is never written;
+            /// the CPU is only woken up by interrupts.
+            static MONITORED: AtomicU8 = AtomicU8::new(0);
+            // SAFETY: `MONITOR` only arms address monitoring, and `MWAIT` returns upon the next interrupt.
+            unsafe {
+                core::arch::asm!(
+                    "monitor",
+                    in("rax") &MONITORED as *const AtomicU8,
+                    in("ecx") 0,
+                    in("edx") 0,
+                    options(nostack, preserves_flags),
+                );
+                core::arch::asm!(
+                    "mwait",
+                    in("eax") mwait_hint() as u32,
+                    in("ecx") 0,
+                    options(nostack, preserves_flags),
+                );
+            }
+        } else {
+            // SAFETY: `HLT` returns upon the next interrupt, as interrupts are enabled.
+            unsafe { core::arch::asm!("hlt", options(nomem, nostack, preserves_flags)) };
+        }
+    }
+    #[cfg(target_arch = "aarch64")]
+    // SAFETY: `WFI` returns upon the next interrupt, as interrupts are enabled.
+    unsafe { core::arch::asm!("wfi", options(nomem, nostack, preserves_flags)) };
+}
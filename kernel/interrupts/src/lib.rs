@@ -15,6 +15,7 @@ use memory::VirtualAddress;
 use apic::{INTERRUPT_CHIP, InterruptChip};
 use locked_idt::LockedIdt;
 use log::{error, warn, info, debug};
+use tls_initializer::InterruptTlsGuard;
 use vga_buffer::println_raw;
 
 
@@ -145,6 +146,9 @@ pub fn init(
     // Use the APIC instead of the old PIC
     disable_pic();
 
+    // Allow high-resolution timers to fire in between scheduler ticks.
+    timer::register_hardware_timer(request_lapic_timer_interrupt);
+
     Ok(&IDT)
 }
 
@@ -271,7 +275,10 @@ pub fn deregister_interrupt(interrupt_num: u8, func: HandlerFunc) -> Result<(),
 ///
 /// The `irq` argument is only used if the `PIC` chip is active,
 /// but it doesn't hurt to always provide it.
+///
+/// This also counts the handled interrupt in the current CPU's statistics.
 pub fn eoi(irq: Option<u8>) {
+    cpu_stats::incr(cpu_stats::Stat::Interrupts);
     match INTERRUPT_CHIP.load() {
         InterruptChip::APIC | InterruptChip::X2APIC => {
             if let Some(my_apic) = apic::get_my_apic() {
@@ -295,29 +302,59 @@ pub fn eoi(irq: Option<u8>) {
 }
 
 
+/// Installs the current CPU's interrupt TLS area, if it has one, until the returned guard is dropped.
+///
+/// The guard must be dropped before the handler switches to another task.
+fn enter_interrupt_tls() -> Option<InterruptTlsGuard> {
+    tls_initializer::hardened_cpu_id().map(tls_initializer::enter_interrupt_tls)
+}
+
+
 pub static APIC_TIMER_TICKS: AtomicUsize = AtomicUsize::new(0);
 /// 0x22
 extern "x86-interrupt" fn lapic_timer_handler(_stack_frame: InterruptStackFrame) {
+    let interrupt_tls = enter_interrupt_tls();
+
+    // In TSC-deadline mode, the lapic timer also fires for high-resolution timers in between scheduler ticks.
+    let is_tick = apic::get_my_apic()
+        .map_or(true, |lapic| lapic.write().handle_lvt_timer_interrupt());
+
+    // Fire any high-resolution timers whose deadline has passed,
+    // which includes unblocking tasks whose sleeping time is over.
+    timer::process_expired_timers();
+
+    if !is_tick {
+        eoi(None);
+        return;
+    }
     let _ticks = APIC_TIMER_TICKS.fetch_add(1, Ordering::Relaxed);
+    cpu_stats::incr(cpu_stats::Stat::Heartbeats);
     // info!(" ({}) APIC TIMER HANDLER! TICKS = {}", apic::current_cpu(), _ticks);
-
-    // Callback to the sleep API to unblock tasks whose waiting time is over
-    // and alert to update the number of ticks elapsed
-    sleep::unblock_sleeping_tasks();
     
     // we must acknowledge the interrupt first before handling it because we switch tasks here, which doesn't return
     eoi(None); // None, because 0x22 IRQ cannot possibly be a PIC interrupt
     
+    // The scheduler relies on the current task's TLS area.
+    drop(interrupt_tls);
     scheduler::schedule();
 }
 
+/// Requests a one-shot lapic timer interrupt on the current CPU, for high-resolution timers.
+fn request_lapic_timer_interrupt(after: time::Duration) {
+    if let Some(lapic) = apic::get_my_apic() {
+        lapic.write().request_timer_interrupt_after(after.as_nanos() as u64);
+    }
+}
+
 extern "x86-interrupt" fn apic_spurious_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    let _interrupt_tls = enter_interrupt_tls();
     warn!("APIC SPURIOUS INTERRUPT HANDLER!");
 
     eoi(None);
 }
 
 extern "x86-interrupt" fn unimplemented_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    let _interrupt_tls = enter_interrupt_tls();
     println_raw!("\nUnimplemented interrupt handler: {:#?}", _stack_frame);
 	match apic::INTERRUPT_CHIP.load() {
         apic::InterruptChip::PIC => {
@@ -353,6 +390,7 @@ extern "x86-interrupt" fn unimplemented_interrupt_handler(_stack_frame: Interrup
 /// See here for more: https://mailman.linuxchix.org/pipermail/techtalk/2002-August/012697.html.
 /// We handle it according to this advice: https://wiki.osdev.org/8259_PIC#Spurious_IRQs
 extern "x86-interrupt" fn pic_spurious_interrupt_handler(_stack_frame: InterruptStackFrame ) {
+    let _interrupt_tls = enter_interrupt_tls();
     if let Some(pic) = PIC.get() {
         let irq_regs = pic.read_isr_irr();
         // check if this was a real IRQ7 (parallel port) (bit 7 will be set)
@@ -399,7 +399,7 @@ impl<RW> ByteWriter for ByteReaderWriterWrapper<RW> where RW: BlockReader + Bloc
             // If the transfer is block-aligned on both sides, then we can write it directly 
             // from the `buffer` to the underlying block writer without reading any bytes first.
             if bytes_in_block_range.start % self.block_size() == 0 && bytes_in_block_range.end % self.block_size() == 0 {
-                let _blocks_written = self.write_blocks(&buffer[buffer_range], block_range.start);
+                let _blocks_written = self.write_blocks(&buffer[buffer_range], block_range.start)?;
             } 
             // Otherwise, to transfer only *part* of a block (a sub-range of its bytes), we must:
             // 1. Read that whole block into a temporary buffer,
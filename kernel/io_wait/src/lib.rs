@@ -0,0 +1,189 @@
+//! Blocking waits for device I/O completions.
+//!
+//! Each task has its own I/O context, which is created lazily in the task's TLS area
+//! and holds the receiving end of a lock-free [`mpmc_channel`] of completions.
+//! To wait for a device, a task starts an [`IoRequest`] and hands its [`IoCompleter`] to the driver,
+//! typically by arming the device's [`PendingIo`] slot before issuing a command to the device.
+//! The driver's interrupt handler then completes the request, which sends the completion
+//! over the channel without taking any locks and thus unblocks the waiting task.
+//!
+//! Most drivers can simply use [`block_until()`], which replaces a polling loop
+//! over a device's status with one that blocks the current task between polls.
+//! Before tasking and interrupts are enabled, [`block_until()`] falls back to polling.
+
+#![no_std]
+
+use core::cell::Cell;
+use io::IoError;
+use irq_safety::MutexIrqSafe;
+use mpmc_channel::{Receiver, Sender};
+use thread_local_macro::thread_local;
+
+/// The number of completions that can be buffered in a task's I/O context.
+const COMPLETION_CAPACITY: usize = 16;
+
+/// The result of a device I/O operation, e.g., the number of bytes transferred.
+pub type IoStatus = Result<usize, IoError>;
+
+/// A completion sent by an [`IoCompleter`] for the request with the given ID.
+type Completion = (u64, IoStatus);
+
+thread_local! {
+    /// The current task's I/O context.
+    static CONTEXT: IoContext = IoContext::new();
+}
+
+/// The per-task state used to wait for I/O completions.
+struct IoContext {
+    sender: Sender<Completion>,
+    receiver: Receiver<Completion>,
+    /// The ID of the next request started by this task.
+    next_id: Cell<u64>,
+}
+
+impl IoContext {
+    fn new() -> IoContext {
+        let (sender, receiver) = mpmc_channel::new_channel(COMPLETION_CAPACITY);
+        IoContext { sender, receiver, next_id: Cell::new(0) }
+    }
+}
+
+/// An I/O operation started by the current task, which it can wait for.
+///
+/// This must be waited for by the same task that started it.
+#[derive(Debug)]
+pub struct IoRequest {
+    id: u64,
+}
+
+impl IoRequest {
+    /// Starts a new I/O request for the current task.
+    pub fn new() -> IoRequest {
+        CONTEXT.with(|context| {
+            let id = context.next_id.get();
+            context.next_id.set(id + 1);
+            IoRequest { id }
+        })
+    }
+
+    /// Returns a completer for this request, which can be handed to a driver.
+    pub fn completer(&self) -> IoCompleter {
+        CONTEXT.with(|context| IoCompleter { id: self.id, sender: context.sender.clone() })
+    }
+
+    /// Blocks the current task until this request is completed, and returns its status.
+    ///
+    /// Completions of earlier requests that the current task stopped waiting for are discarded.
+    pub fn wait(self) -> IoStatus {
+        CONTEXT.with(|context| loop {
+            match context.receiver.recv() {
+                Ok((id, status)) if id == self.id => return status,
+                Ok(_stale) => continue,
+                Err(mpmc_channel::RecvError::NoCurrentTask) => return Err(IoError::Other("no current task to block")),
+                // The context holds a sender, so its channel can never be disconnected.
+                Err(mpmc_channel::RecvError::Disconnected) => unreachable!("I/O context channel was disconnected"),
+            }
+        })
+    }
+}
+
+impl Default for IoRequest {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The means for a driver to complete an [`IoRequest`], which can be used in interrupt context.
+pub struct IoCompleter {
+    id: u64,
+    sender: Sender<Completion>,
+}
+
+impl IoCompleter {
+    /// Completes the request with the given status, unblocking the task that waits for it.
+    ///
+    /// Returns `false` if the waiting task's I/O context is full or no longer exists.
+    pub fn complete(self, status: IoStatus) -> bool {
+        self.sender.try_send((self.id, status)).is_ok()
+    }
+}
+
+impl core::fmt::Debug for IoCompleter {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        f.debug_struct("IoCompleter").field("id", &self.id).finish_non_exhaustive()
+    }
+}
+
+/// A slot that holds the completer of the request that a device is currently serving,
+/// which the device's interrupt handler completes.
+#[derive(Debug)]
+pub struct PendingIo {
+    completer: MutexIrqSafe<Option<IoCompleter>>,
+}
+
+impl PendingIo {
+    /// Creates an empty slot.
+    pub const fn new() -> PendingIo {
+        PendingIo { completer: MutexIrqSafe::new(None) }
+    }
+
+    /// Sets the completer to be completed by the next call to [`PendingIo::complete()`],
+    /// replacing any previous completer.
+    pub fn arm(&self, completer: IoCompleter) {
+        *self.completer.lock() = Some(completer);
+    }
+
+    /// Removes the completer from this slot without completing it.
+    pub fn disarm(&self) -> Option<IoCompleter> {
+        self.completer.lock().take()
+    }
+
+    /// Completes the armed request with the given status, if any.
+    ///
+    /// Returns `true` if a request was completed.
+    pub fn complete(&self, status: IoStatus) -> bool {
+        let completer = self.completer.lock().take();
+        completer.map_or(false, |c| c.complete(status))
+    }
+}
+
+impl Default for PendingIo {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Returns whether the current task can block to wait for I/O,
+/// which requires that tasking and interrupts are enabled.
+pub fn can_block() -> bool {
+    irq_safety::interrupts_enabled() && task::get_my_current_task().is_some()
+}
+
+/// Blocks the current task until `poll` returns `Some`, which is returned.
+///
+/// The device is expected to complete the request armed in `pending` once `poll` may succeed,
+/// e.g., by raising an interrupt once it has data ready.
+/// The task re-checks `poll` after each completion, so spurious completions are harmless.
+///
+/// If the current task cannot block (see [`can_block()`]), this polls instead.
+pub fn block_until<T>(pending: &PendingIo, mut poll: impl FnMut() -> Option<T>) -> T {
+    if !can_block() {
+        loop {
+            if let Some(value) = poll() {
+                return value;
+            }
+            core::hint::spin_loop();
+        }
+    }
+    loop {
+        let request = IoRequest::new();
+        // Arm the slot before polling, such that a completion cannot be missed
+        // by arriving between the poll and the wait.
+        pending.arm(request.completer());
+        if let Some(value) = poll() {
+            pending.disarm();
+            return value;
+        }
+        let _ = request.wait();
+    }
+}
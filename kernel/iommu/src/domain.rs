@@ -0,0 +1,113 @@
+//! Second-level page tables, which translate the DMA addresses of the devices in a domain.
+//!
+//! Devices use physical addresses for DMA, so every granted frame is identity-mapped.
+
+use alloc::collections::BTreeMap;
+use memory::{create_contiguous_mapping, MappedPages, PhysicalAddress, PteFlags};
+
+/// The size of a page, and of each page table.
+pub const PAGE_SIZE: usize = 4096;
+/// The number of entries in each page table.
+const ENTRIES: usize = 512;
+
+/// Page table entry: the device may read from the page, or the next-level table is present.
+const READ:  u64 = 1 << 0;
+/// Page table entry: the device may write to the page.
+const WRITE: u64 = 1 << 1;
+/// Page table entry: the bits that hold the address of the page or next-level table.
+const ADDRESS_MASK: u64 = 0x000F_FFFF_FFFF_F000;
+
+/// A single page table and the next-level tables that its entries point to.
+struct Table {
+    mp: MappedPages,
+    phys: PhysicalAddress,
+    children: BTreeMap<usize, Table>,
+}
+
+impl Table {
+    fn new() -> Result<Table, &'static str> {
+        let flags = PteFlags::new().valid(true).writable(true);
+        let (mut mp, phys) = create_contiguous_mapping(PAGE_SIZE, flags)?;
+        mp.as_slice_mut::<u64>(0, ENTRIES)?.fill(0);
+        Ok(Table { mp, phys, children: BTreeMap::new() })
+    }
+
+    fn set_entry(&mut self, index: usize, value: u64, coherent: bool) {
+        let entries = self.mp.as_slice_mut::<u64>(0, ENTRIES)
+            .expect("BUG: IOMMU page table wasn't mapped");
+        entries[index] = value;
+        if !coherent {
+            crate::flush_cache_line(&entries[index]);
+        }
+    }
+}
+
+/// A DMA domain: the set of frames that the devices attached to it may access.
+pub struct Domain {
+    pub id: u16,
+    /// The number of levels of page tables, i.e., 3 for 39-bit or 4 for 48-bit addresses.
+    levels: u32,
+    /// Whether the IOMMU snoops the CPU caches when walking the page tables.
+    coherent: bool,
+    root: Table,
+}
+
+impl Domain {
+    pub fn new(id: u16, levels: u32, coherent: bool) -> Result<Domain, &'static str> {
+        Ok(Domain { id, levels, coherent, root: Table::new()? })
+    }
+
+    /// Returns the physical address of the top-level page table.
+    pub fn root_address(&self) -> PhysicalAddress {
+        self.root.phys
+    }
+
+    /// Returns the index of the entry that translates `addr` in a page table at the given level,
+    /// where level 0 holds the page table entries.
+    fn index(addr: usize, level: u32) -> usize {
+        (addr >> (12 + 9 * level)) % ENTRIES
+    }
+
+    /// Identity-maps the page at the given address, allowing devices to write to it if `writable`.
+    pub fn map(&mut self, addr: usize, writable: bool) -> Result<(), &'static str> {
+        if addr >> (12 + 9 * self.levels) != 0 {
+            return Err("IOMMU: address is beyond the domain's address width");
+        }
+        let coherent = self.coherent;
+        let mut table = &mut self.root;
+        for level in (1..self.levels).rev() {
+            let index = Self::index(addr, level);
+            if !table.children.contains_key(&index) {
+                let child = Table::new()?;
+                table.set_entry(index, child.phys.value() as u64 | READ | WRITE, coherent);
+                table.children.insert(index, child);
+            }
+            table = table.children.get_mut(&index).unwrap();
+        }
+        let permissions = if writable { READ | WRITE } else { READ };
+        table.set_entry(Self::index(addr, 0), (addr as u64 & ADDRESS_MASK) | permissions, coherent);
+        Ok(())
+    }
+
+    /// Unmaps the page at the given address, returning whether it was mapped.
+    ///
+    /// Page tables are never freed, since they are likely to be reused by later mappings.
+    pub fn unmap(&mut self, addr: usize) -> bool {
+        let coherent = self.coherent;
+        let mut table = &mut self.root;
+        for level in (1..self.levels).rev() {
+            match table.children.get_mut(&Self::index(addr, level)) {
+                Some(child) => table = child,
+                None => return false,
+            }
+        }
+        let index = Self::index(addr, 0);
+        let was_mapped = table.mp.as_slice::<u64>(0, ENTRIES)
+            .map(|entries| entries[index] & (READ | WRITE) != 0)
+            .unwrap_or(false);
+        if was_mapped {
+            table.set_entry(index, 0, coherent);
+        }
+        was_mapped
+    }
+}
@@ -1,25 +1,60 @@
 //! Intel VT-d (IOMMU) implementation.
 //!
+//! The IOMMU translates the addresses of DMA requests, such that a device can only access the memory
+//! that has been explicitly granted to the DMA domain it belongs to.
+//! This protects the rest of the system, e.g., task stacks and TLS images, from buggy or malicious devices
+//! and from bugs in their drivers, which may be swapped at runtime.
+//!
+//! A driver isolates its device by creating a [`DmaDomain`] for it via [`create_domain()`],
+//! after which it must [grant](DmaDomain::grant) the device access to every buffer
+//! before passing that buffer's physical address to the device, and [revoke](DmaDomain::revoke) it afterwards.
+//! Domains are owned by the IOMMU rather than by the driver, so a driver that is swapped
+//! obtains the same domain, and thus keeps the same grants, via [`domain_of()`].
+//!
+//! Devices whose drivers don't create a domain bypass translation ("pass-through"),
+//! once translation has been enabled via [`enable_translation()`].
+//!
+//! Only Intel VT-d is currently supported, and only register-based invalidation is used.
+//!
 //! [Specification](https://software.intel.com/content/dam/develop/external/us/en/documents-tps/vt-directed-io-spec.pdf)
 
 #![allow(dead_code)]
 #![no_std]
 
+extern crate alloc;
 extern crate irq_safety;
 #[macro_use] extern crate log;
 extern crate memory;
+extern crate pci;
 extern crate spin;
 extern crate volatile;
 extern crate zerocopy;
 extern crate bitflags;
 
+use alloc::collections::BTreeMap;
+use core::arch::asm;
 use spin::Once;
 use irq_safety::MutexIrqSafe;
-use memory::{PageTable, PteFlags, PhysicalAddress, allocate_frames_at, allocate_pages, BorrowedMappedPages, Mutable};
+use memory::{PageTable, PteFlags, PhysicalAddress, allocate_frames, allocate_frames_at, allocate_pages, BorrowedMappedPages, Mutable, MappedPages, create_contiguous_mapping};
+use pci::PciLocation;
 
+mod domain;
 mod regs;
+use domain::{Domain, PAGE_SIZE};
 use regs::*;
 
+/// Root and context entries: the entry is present.
+const ENTRY_PRESENT: u64 = 1 << 0;
+/// Context entries: the device bypasses translation (translation type `10b`).
+const CONTEXT_PASSTHROUGH: u64 = 0b10 << 2;
+/// Context entries: the location in the upper 64 bits where the domain ID is written.
+const CONTEXT_DID_SHIFT: u64 = 8;
+/// The number of 64-bit words in each root table or context table (256 entries of 128 bits each).
+const TABLE_WORDS: usize = 512;
+
+/// The domain ID of devices that bypass translation.
+const PASSTHROUGH_DOMAIN_ID: u16 = 1;
+
 /// Struct representing IOMMU (TODO: rename since this is specific to Intel VT-d)
 pub struct IntelIommu {
     /// Width of host addresses available for DMA
@@ -30,6 +65,26 @@ pub struct IntelIommu {
     register_base_address: PhysicalAddress,
     /// Memory mapped control registers
     regs: BorrowedMappedPages<IntelIommuRegisters, Mutable>,
+    /// Whether the IOMMU snoops the CPU caches when walking its tables.
+    coherent: bool,
+    /// The adjusted guest address width used by DMA domains, as encoded in context entries.
+    address_width: u64,
+    /// The number of levels of page tables in each DMA domain.
+    levels: u32,
+    /// The largest adjusted guest address width, which is required for pass-through context entries.
+    max_address_width: u64,
+    /// The root table, which points to the context table of each bus.
+    root_table: MappedPages,
+    /// The context table of each bus, which points to the DMA domain of each device on that bus.
+    context_tables: BTreeMap<u8, MappedPages>,
+    /// All DMA domains, indexed by domain ID.
+    domains: BTreeMap<u16, Domain>,
+    /// The DMA domain of each isolated device, indexed by source ID.
+    device_domains: BTreeMap<u16, u16>,
+    /// The number of domain IDs supported by the IOMMU.
+    max_domains: u32,
+    next_domain_id: u16,
+    translation_enabled: bool,
 }
 
 /// Singleton representing IOMMU (TODO: could there be more than one IOMMU?)
@@ -37,8 +92,7 @@ static IOMMU: Once<MutexIrqSafe<IntelIommu>> = Once::new();
 
 /// Initialize the IOMMU hardware.
 ///
-/// Currently this just sets up basic structures and prints out information about the IOMMU;
-/// it doesn't actually create any I/O device page tables.
+/// This sets up an empty root table, but doesn't enable translation; see [`enable_translation()`].
 ///
 /// # Arguments
 /// * `host_address_width`: number of address bits available for DMA
@@ -73,10 +127,9 @@ pub fn init(host_address_width: u8,
     }
 
     // check IOMMU capabilities/extended capabilities
+    let c = Capability(regs.cap.read());
+    let ec = ExtendedCapability(regs.ecap.read());
     {
-        let c = Capability(regs.cap.read());
-        let ec = ExtendedCapability(regs.ecap.read());
-
         // List capabilities and extended capabilities
         info!("IOMMU Capabilities: {:?}", c);
         info!("IOMMU Extended Capabilities: {:?}", ec);
@@ -88,22 +141,60 @@ pub fn init(host_address_width: u8,
         info!("IOMMU Global Status: {:?}", status);
     }
 
+    // Prefer 4-level (48-bit) page tables over 3-level (39-bit) ones.
+    let (address_width, levels) = if c.sagaw() & (1 << 2) != 0 {
+        (2, 4)
+    } else if c.sagaw() & (1 << 1) != 0 {
+        (1, 3)
+    } else {
+        return Err("IOMMU supports neither 3-level nor 4-level page tables");
+    };
+    if iotlb_offset(&ec) < REMAINING_OFFSET || iotlb_offset(&ec) + 8 > 4096 {
+        return Err("IOMMU's IOTLB registers are outside of its first page of registers");
+    }
+
+    // The kernel's page table is already locked by our caller, so map the root table with it directly.
+    let (mut root_table, root_table_phys) = {
+        let frames = allocate_frames(1).ok_or("Unable to allocate frame for IOMMU root table")?;
+        let root_table_phys = frames.start_address();
+        let pages = allocate_pages(1).ok_or("Unable to find virtual page!")?;
+        (page_table.map_allocated_pages_to(pages, frames, PteFlags::new().valid(true).writable(true))?, root_table_phys)
+    };
+    root_table.as_slice_mut::<u64>(0, TABLE_WORDS)?.fill(0);
+    let coherent = ec.c();
+    if !coherent {
+        flush_cache(root_table.as_slice::<u64>(0, TABLE_WORDS)?);
+    }
+
     // create the "iommu" object
-    let iommu = IntelIommu {
+    let mut iommu = IntelIommu {
         host_address_width,
         pci_segment_number,
         register_base_address,
         regs,
+        coherent,
+        address_width,
+        levels,
+        max_address_width: 63 - c.sagaw().leading_zeros() as u64,
+        root_table,
+        context_tables: BTreeMap::new(),
+        domains: BTreeMap::new(),
+        device_domains: BTreeMap::new(),
+        max_domains: 1 << (4 + 2 * c.nd()),
+        next_domain_id: PASSTHROUGH_DOMAIN_ID + 1,
+        translation_enabled: false,
     };
 
+    // Ensure translation is disabled while the root table is being set.
+    iommu.set_command_bit(GlobalCommand::TE, false, |x: GlobalStatus| { ! x.intersects(GlobalStatus::TES) });
+    iommu.regs.rtaddr.write(root_table_phys.value() as u64);
+    iommu.set_command_bit(GlobalCommand::Srtp, true, |x: GlobalStatus| { x.intersects(GlobalStatus::RTPS) });
+    iommu.invalidate_context_cache(CCMD_CIRG_GLOBAL);
+    iommu.invalidate_iotlb(IOTLB_IIRG_GLOBAL);
+
     // initialize the iommu singleton with this object
     IOMMU.call_once(|| {MutexIrqSafe::new(iommu)});
 
-    // Ensure translation is disabled.
-    //
-    // TODO: This can be removed, its only purpose is to test that set_command_bit works
-    set_command_bit(GlobalCommand::TE, false, |x: GlobalStatus| { ! x.intersects(GlobalStatus::TES) })?;
-
     info!("IOMMU Init stage 1 complete.");
 
     Ok(())
@@ -114,31 +205,266 @@ pub fn iommu_present() -> bool {
     IOMMU.is_completed()
 }
 
-/// This function writes a command to the IOMMU Global Command register using
-/// the algorithm described in the Intel documentation:
-/// 1. Read global status register into temporary variable.
-/// 2. Clear all bits in temporary variable that have no effect on command register.
-/// 3. Set or clear the corresponding command bit depending on `x`.
-/// 4. Write the variable to the command register.
-/// 5. Wait until `condition` is met, where `condition` is a function that 
-///    can test the value of the status register.
+/// Returns `true` if the IOMMU is translating DMA requests.
+pub fn translation_enabled() -> bool {
+    IOMMU.get().map_or(false, |iommu| iommu.lock().translation_enabled)
+}
+
+/// Enables translation of DMA requests.
 ///
-/// # Arguments:
-/// * `command`: command bit to set/clear
-/// * `bit_value`: value to set command bit to
-/// * `condition`: function which interprets status register and returns true when
-///    command has completed.
-fn set_command_bit(
-    command: GlobalCommand, 
-    bit_value: bool,
-    condition: impl Fn(GlobalStatus) -> bool
-) -> Result<(), &'static str> {
+/// Each of the given devices that doesn't yet have a DMA domain bypasses translation,
+/// such that drivers that don't use the IOMMU keep working.
+/// Any other device, e.g., one that is hotplugged later, is blocked from performing DMA
+/// until its driver creates a domain for it.
+pub fn enable_translation(devices: impl IntoIterator<Item = PciLocation>) -> Result<(), &'static str> {
     let iommu = &mut IOMMU.get().ok_or("IOMMU not initialized!")?.lock();
-    let tmp = iommu.regs.gstatus.read();
-    let tmp = tmp & 0x96ffffff;
-    let bits = command as u32;
-    let cmd = if bit_value { tmp | bits } else { tmp & (!bits) };
-    iommu.regs.gcommand.write(cmd);
-    while !condition(GlobalStatus::from_bits_truncate(iommu.regs.gstatus.read())) {}
+    if iommu.translation_enabled {
+        return Ok(());
+    }
+    if !ExtendedCapability(iommu.regs.ecap.read()).pt() {
+        return Err("IOMMU doesn't support pass-through, which is required by devices without a DMA domain");
+    }
+    let high = iommu.max_address_width | ((PASSTHROUGH_DOMAIN_ID as u64) << CONTEXT_DID_SHIFT);
+    for location in devices {
+        let source_id = source_id(&location);
+        if !iommu.device_domains.contains_key(&source_id) {
+            iommu.set_context_entry(source_id, ENTRY_PRESENT | CONTEXT_PASSTHROUGH, high)?;
+        }
+    }
+    iommu.invalidate_context_cache(CCMD_CIRG_GLOBAL);
+    iommu.invalidate_iotlb(IOTLB_IIRG_GLOBAL);
+    iommu.set_command_bit(GlobalCommand::TE, true, |x: GlobalStatus| { x.intersects(GlobalStatus::TES) });
+    iommu.translation_enabled = true;
+    info!("IOMMU translation enabled, {} device(s) isolated", iommu.device_domains.len());
     Ok(())
 }
+
+/// Creates a DMA domain for the given device, to which no memory has been granted,
+/// and attaches the device to it.
+///
+/// If the device already has a DMA domain, e.g., because its driver was swapped, that domain is returned.
+pub fn create_domain(location: PciLocation) -> Result<DmaDomain, &'static str> {
+    let iommu = &mut IOMMU.get().ok_or("IOMMU not initialized!")?.lock();
+    let source_id = source_id(&location);
+    if let Some(&id) = iommu.device_domains.get(&source_id) {
+        return Ok(DmaDomain { id });
+    }
+    if iommu.next_domain_id as u32 >= iommu.max_domains {
+        return Err("IOMMU has no more domain IDs");
+    }
+    let id = iommu.next_domain_id;
+    let domain = Domain::new(id, iommu.levels, iommu.coherent)?;
+    let low = domain.root_address().value() as u64 | ENTRY_PRESENT;
+    let high = iommu.address_width | ((id as u64) << CONTEXT_DID_SHIFT);
+    iommu.set_context_entry(source_id, low, high)?;
+    iommu.next_domain_id += 1;
+    iommu.domains.insert(id, domain);
+    iommu.device_domains.insert(source_id, id);
+    info!("IOMMU: isolated device {} in DMA domain {}", location, id);
+    Ok(DmaDomain { id })
+}
+
+/// Returns the DMA domain of the given device, if it has one.
+pub fn domain_of(location: PciLocation) -> Option<DmaDomain> {
+    let iommu = IOMMU.get()?.lock();
+    iommu.device_domains.get(&source_id(&location)).map(|&id| DmaDomain { id })
+}
+
+/// A DMA domain, which contains the memory that the devices attached to it may access.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DmaDomain {
+    id: u16,
+}
+
+impl DmaDomain {
+    pub fn id(&self) -> u16 {
+        self.id
+    }
+
+    /// Allows the devices in this domain to access the given physical memory region,
+    /// which is rounded out to whole pages.
+    ///
+    /// The devices may only write to the region if `writable` is `true`.
+    pub fn grant(&self, start: PhysicalAddress, size_in_bytes: usize, writable: bool) -> Result<(), &'static str> {
+        let iommu = &mut IOMMU.get().ok_or("IOMMU not initialized!")?.lock();
+        iommu.grant(self.id, start, size_in_bytes, writable)
+    }
+
+    /// Revokes access to the given physical memory region, which is rounded out to whole pages,
+    /// waiting until the devices in this domain can no longer access it.
+    pub fn revoke(&self, start: PhysicalAddress, size_in_bytes: usize) -> Result<(), &'static str> {
+        let iommu = &mut IOMMU.get().ok_or("IOMMU not initialized!")?.lock();
+        iommu.revoke(self.id, start, size_in_bytes)
+    }
+}
+
+impl IntelIommu {
+    fn grant(&mut self, id: u16, start: PhysicalAddress, size_in_bytes: usize, writable: bool) -> Result<(), &'static str> {
+        let domain = self.domains.get_mut(&id).ok_or("IOMMU: DMA domain doesn't exist")?;
+        for addr in page_range(start, size_in_bytes) {
+            if let Err(e) = domain.map(addr, writable) {
+                for mapped in page_range(start, size_in_bytes).take_while(|&mapped| mapped < addr) {
+                    domain.unmap(mapped);
+                }
+                return Err(e);
+            }
+        }
+        // In caching mode, the IOMMU may cache not-present entries, e.g., when it's emulated.
+        if Capability(self.regs.cap.read()).cm() {
+            self.invalidate_iotlb(IOTLB_IIRG_DOMAIN | ((id as u64) << IOTLB_DID_SHIFT));
+        }
+        self.flush_write_buffer();
+        Ok(())
+    }
+
+    fn revoke(&mut self, id: u16, start: PhysicalAddress, size_in_bytes: usize) -> Result<(), &'static str> {
+        let domain = self.domains.get_mut(&id).ok_or("IOMMU: DMA domain doesn't exist")?;
+        let mut unmapped_any = false;
+        for addr in page_range(start, size_in_bytes) {
+            unmapped_any |= domain.unmap(addr);
+        }
+        if unmapped_any {
+            self.flush_write_buffer();
+            self.invalidate_iotlb(IOTLB_IIRG_DOMAIN | IOTLB_DRAIN | ((id as u64) << IOTLB_DID_SHIFT));
+        }
+        Ok(())
+    }
+
+    /// Replaces the context entry of the given device, creating the context table of its bus if needed.
+    ///
+    /// If the device already had a present context entry, it's cleared and invalidated first.
+    fn set_context_entry(&mut self, source_id: u16, low: u64, high: u64) -> Result<(), &'static str> {
+        let bus = (source_id >> 8) as u8;
+        let index = 2 * (source_id & 0xFF) as usize;
+        let coherent = self.coherent;
+
+        if !self.context_tables.contains_key(&bus) {
+            let (mut table, table_phys) = create_contiguous_mapping(PAGE_SIZE, PteFlags::new().valid(true).writable(true))?;
+            table.as_slice_mut::<u64>(0, TABLE_WORDS)?.fill(0);
+            if !coherent {
+                flush_cache(table.as_slice::<u64>(0, TABLE_WORDS)?);
+            }
+            self.context_tables.insert(bus, table);
+            let root_entries = self.root_table.as_slice_mut::<u64>(0, TABLE_WORDS)?;
+            root_entries[2 * bus as usize] = table_phys.value() as u64 | ENTRY_PRESENT;
+            if !coherent {
+                flush_cache(&root_entries[2 * bus as usize..][..2]);
+            }
+        }
+
+        let entries = self.context_tables.get_mut(&bus).unwrap().as_slice_mut::<u64>(0, TABLE_WORDS)?;
+        let old_high = entries[index + 1];
+        let was_present = entries[index] & ENTRY_PRESENT != 0;
+        if was_present {
+            entries[index] = 0;
+            if !coherent {
+                flush_cache(&entries[index..][..2]);
+            }
+        }
+        // The present bit in the lower half must be written last.
+        entries[index + 1] = high;
+        entries[index] = low;
+        if !coherent {
+            flush_cache(&entries[index..][..2]);
+        }
+
+        if was_present {
+            let old_id = (old_high >> CONTEXT_DID_SHIFT) & 0xFFFF;
+            self.invalidate_context_cache(CCMD_CIRG_DEVICE | ((source_id as u64) << CCMD_SID_SHIFT) | old_id);
+            self.invalidate_iotlb(IOTLB_IIRG_DOMAIN | IOTLB_DRAIN | (old_id << IOTLB_DID_SHIFT));
+        } else {
+            self.invalidate_context_cache(CCMD_CIRG_DEVICE | ((source_id as u64) << CCMD_SID_SHIFT) | ((high >> CONTEXT_DID_SHIFT) & 0xFFFF));
+        }
+        self.flush_write_buffer();
+        Ok(())
+    }
+
+    /// Invalidates the context cache with the given granularity, and waits for the invalidation to complete.
+    fn invalidate_context_cache(&mut self, command: u64) {
+        self.regs.ccmd.write(CCMD_ICC | command);
+        while self.regs.ccmd.read() & CCMD_ICC != 0 {
+            core::hint::spin_loop();
+        }
+    }
+
+    /// Invalidates the IOTLB with the given granularity, and waits for the invalidation to complete.
+    fn invalidate_iotlb(&mut self, command: u64) {
+        let offset = iotlb_offset(&ExtendedCapability(self.regs.ecap.read()));
+        let iotlb = &mut self.regs.remaining[(offset - REMAINING_OFFSET) / 8];
+        iotlb.write(IOTLB_IVT | command);
+        while iotlb.read() & IOTLB_IVT != 0 {
+            core::hint::spin_loop();
+        }
+    }
+
+    /// Flushes the IOMMU's internal write buffers, if it requires it after the tables have been updated.
+    fn flush_write_buffer(&mut self) {
+        if Capability(self.regs.cap.read()).rwbf() {
+            self.set_command_bit(GlobalCommand::Wbf, true, |x: GlobalStatus| { ! x.intersects(GlobalStatus::WBFS) });
+        }
+    }
+
+    /// This function writes a command to the IOMMU Global Command register using
+    /// the algorithm described in the Intel documentation:
+    /// 1. Read global status register into temporary variable.
+    /// 2. Clear all bits in temporary variable that have no effect on command register.
+    /// 3. Set or clear the corresponding command bit depending on `x`.
+    /// 4. Write the variable to the command register.
+    /// 5. Wait until `condition` is met, where `condition` is a function that
+    ///    can test the value of the status register.
+    ///
+    /// # Arguments:
+    /// * `command`: command bit to set/clear
+    /// * `bit_value`: value to set command bit to
+    /// * `condition`: function which interprets status register and returns true when
+    ///    command has completed.
+    fn set_command_bit(
+        &mut self,
+        command: GlobalCommand,
+        bit_value: bool,
+        condition: impl Fn(GlobalStatus) -> bool
+    ) {
+        let tmp = self.regs.gstatus.read();
+        let tmp = tmp & 0x96ffffff;
+        let bits = command as u32;
+        let cmd = if bit_value { tmp | bits } else { tmp & (!bits) };
+        self.regs.gcommand.write(cmd);
+        while !condition(GlobalStatus::from_bits_truncate(self.regs.gstatus.read())) {}
+    }
+}
+
+/// Returns the source ID of the given device, which identifies it in DMA requests.
+fn source_id(location: &PciLocation) -> u16 {
+    ((location.bus() as u16) << 8) | ((location.slot() as u16) << 3) | location.function() as u16
+}
+
+/// Returns the offset of the IOTLB invalidate register.
+fn iotlb_offset(ec: &ExtendedCapability) -> usize {
+    ec.iro() as usize * 16 + 8
+}
+
+/// Returns the addresses of the pages that contain the given physical memory region.
+fn page_range(start: PhysicalAddress, size_in_bytes: usize) -> core::iter::StepBy<core::ops::Range<usize>> {
+    let first = start.value() & !(PAGE_SIZE - 1);
+    let end = start.value() + size_in_bytes;
+    (first..end).step_by(PAGE_SIZE)
+}
+
+/// Writes back the given table entries from the CPU caches to memory,
+/// which is required if the IOMMU doesn't snoop the caches when walking its tables.
+fn flush_cache(entries: &[u64]) {
+    for entry in entries.iter().step_by(8) {
+        flush_cache_line(entry);
+    }
+    if let Some(last) = entries.last() {
+        flush_cache_line(last);
+    }
+}
+
+/// Writes back the cache line that contains the given value to memory.
+fn flush_cache_line<T>(value: &T) {
+    unsafe {
+        asm!("clflush [{}]", in(reg) value as *const T, options(nostack, preserves_flags));
+    }
+    core::sync::atomic::fence(core::sync::atomic::Ordering::SeqCst);
+}
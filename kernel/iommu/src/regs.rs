@@ -1,7 +1,7 @@
 //! Structures needed for interacting with the IOMMU.
 
 use zerocopy::FromBytes;
-use volatile::{ReadOnly, WriteOnly};
+use volatile::{ReadOnly, Volatile, WriteOnly};
 use bitflags::bitflags;
 use core::fmt;
 
@@ -21,9 +21,21 @@ pub struct IntelIommuRegisters {
     pub gcommand:           WriteOnly<u32>,    // 0x18
     /// Global status register
     pub gstatus:            ReadOnly<u32>,     // 0x1c
-    /// Unimplemented (may be architecturally defined)
-    _unimplemented:         [u8; 4096-0x20],   // 0x20-0xFFF
+    /// Root table address register
+    pub rtaddr:             Volatile<u64>,     // 0x20
+    /// Context command register
+    pub ccmd:               Volatile<u64>,     // 0x28
+    /// Reserved
+    _reserved1:             [u8; 4],           // 0x30 - 0x33
+    /// Fault status register
+    pub fsts:               Volatile<u32>,     // 0x34
+    /// Remaining registers, some of which are located at offsets given by the capability registers,
+    /// e.g., the IOTLB registers.
+    pub remaining:          [Volatile<u64>; (4096 - 0x38) / 8], // 0x38-0xFFF
 }
+
+/// The offset of the first register in [`IntelIommuRegisters::remaining`].
+pub const REMAINING_OFFSET: usize = 0x38;
 // TODO: Hardware may use more than 4kB, which means the registers may occupy
 //       more than one contiguous page.
 //       Currently we assume the IOMMU registers occupy only a single page.
@@ -47,13 +59,13 @@ impl Capability {
     fn fro(&self)     -> u64  { (self.0 >> 24) & 0x3ff }
     fn zlr(&self)     -> bool { (self.0) & (1 << 22) != 0 }
     fn mgaw(&self)    -> u64  { ((self.0 >> 16) & 0x3f) + 1 }
-    fn sagaw(&self)   -> u64  { (self.0 >> 8) & 0x1f }
-    fn cm(&self)      -> bool { (self.0) & (1 << 7) != 0 }
+    pub fn sagaw(&self)   -> u64  { (self.0 >> 8) & 0x1f }
+    pub fn cm(&self)      -> bool { (self.0) & (1 << 7) != 0 }
     fn phmr(&self)    -> bool { (self.0) & (1 << 6) != 0 }
     fn plmr(&self)    -> bool { (self.0) & (1 << 5) != 0 }
-    fn rwbf(&self)    -> bool { (self.0) & (1 << 4) != 0 }
+    pub fn rwbf(&self)    -> bool { (self.0) & (1 << 4) != 0 }
     fn afl(&self)     -> bool { (self.0) & (1 << 3) != 0 }
-    fn nd(&self)      -> u64  { self.0 & 0x7 }
+    pub fn nd(&self)      -> u64  { self.0 & 0x7 }
 }
 
 impl fmt::Debug for Capability {
@@ -109,14 +121,14 @@ impl ExtendedCapability {
     fn nest(&self)    -> bool { (self.0) & (1 << 26) != 0 }
     fn mts(&self)     -> bool { (self.0) & (1 << 25) != 0 }
     fn mhmv(&self)    -> u64  { (self.0 >> 20) & 0xf }
-    fn iro(&self)     -> u64  { (self.0 >> 8) & 0x3ff }
+    pub fn iro(&self)     -> u64  { (self.0 >> 8) & 0x3ff }
     fn sc(&self)      -> bool { (self.0) & (1 << 7) != 0 }
-    fn pt(&self)      -> bool { (self.0) & (1 << 6) != 0 }
+    pub fn pt(&self)      -> bool { (self.0) & (1 << 6) != 0 }
     fn eim(&self)     -> bool { (self.0) & (1 << 4) != 0 }
     fn ir(&self)      -> bool { (self.0) & (1 << 3) != 0 }
     fn dt(&self)      -> bool { (self.0) & (1 << 2) != 0 }
     fn qi(&self)      -> bool { (self.0) & (1 << 1) != 0 }
-    fn c(&self)       -> bool { (self.0) & (1 << 0) != 0 }
+    pub fn c(&self)       -> bool { (self.0) & (1 << 0) != 0 }
 }
 
 impl fmt::Debug for ExtendedCapability {
@@ -155,6 +167,26 @@ impl fmt::Debug for ExtendedCapability {
     }
 }
 
+/// Context command register: invalidates the context cache.
+pub const CCMD_ICC:             u64 = 1 << 63;
+/// Context command register: global invalidation.
+pub const CCMD_CIRG_GLOBAL:     u64 = 0b01 << 61;
+/// Context command register: device-selective invalidation.
+pub const CCMD_CIRG_DEVICE:     u64 = 0b11 << 61;
+/// The location in the context command register where the source ID is written.
+pub const CCMD_SID_SHIFT:       u64 = 16;
+
+/// IOTLB invalidate register: invalidates the IOTLB.
+pub const IOTLB_IVT:            u64 = 1 << 63;
+/// IOTLB invalidate register: global invalidation.
+pub const IOTLB_IIRG_GLOBAL:    u64 = 0b01 << 60;
+/// IOTLB invalidate register: domain-selective invalidation.
+pub const IOTLB_IIRG_DOMAIN:    u64 = 0b10 << 60;
+/// IOTLB invalidate register: drain pending reads and writes before completing the invalidation.
+pub const IOTLB_DRAIN:          u64 = 0b11 << 48;
+/// The location in the IOTLB invalidate register where the domain ID is written.
+pub const IOTLB_DID_SHIFT:      u64 = 32;
+
 /// Bits corresponding to commands in the Global Command register.
 #[repr(u32)]
 pub enum GlobalCommand {
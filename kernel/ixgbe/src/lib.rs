@@ -55,7 +55,7 @@ use alloc::{
 };
 use irq_safety::MutexIrqSafe;
 use memory::{PhysicalAddress, MappedPages, Mutable, BorrowedSliceMappedPages, BorrowedMappedPages};
-use pci::{PciDevice, MSIX_CAPABILITY, PciConfigSpaceAccessMechanism, PciLocation};
+use pci::{PciDevice, PciConfigSpaceAccessMechanism, PciLocation};
 use bit_field::BitField;
 use interrupts::register_msi_interrupt;
 use x86_64::structures::idt::HandlerFunc;
@@ -546,16 +546,10 @@ impl IxgbeNic {
 
     /// Returns the memory mapped msix vector table
     pub fn mem_map_msix(dev: &PciDevice) -> Result<BorrowedMappedPages<MsixVectorTable, Mutable>, &'static str> {
-        // retreive the address in the pci config space for the msi-x capability
-        let cap_addr = dev.find_pci_capability(MSIX_CAPABILITY).ok_or("ixgbe: device does not have MSI-X capability")?;
-        // find the BAR used for msi-x
-        let vector_table_offset = 4;
-        let table_offset = dev.pci_read_32(cap_addr + vector_table_offset);
-        let bar = table_offset & 0x7;
-        let offset = table_offset >> 3;
+        // find the BAR and offset of the msi-x vector table
+        let table = dev.msix().ok_or("ixgbe: device does not have MSI-X capability")?.table();
         // find the memory base address and size of the area for the vector table
-        let mem_base = PhysicalAddress::new((dev.bars[bar as usize] + offset) as usize)
-            .ok_or("ixgbe: the mem_base physical address specified in the BAR was invalid")?;
+        let mem_base = dev.determine_mem_base(table.bar)? + table.offset;
         let mem_size_in_bytes = core::mem::size_of::<MsixVectorEntry>() * IXGBE_MAX_MSIX_VECTORS;
 
         // debug!("msi-x vector table bar: {}, base_address: {:#X} and size: {} bytes", bar, mem_base, mem_size_in_bytes);
@@ -0,0 +1,182 @@
+//! An in-kernel test harness that runs every `#[ktest]` function and reports the results over the serial port.
+//!
+//! Kernel crates mark test functions with the [`ktest`] attribute, which compiles them
+//! only when the `ktest` cfg option is enabled, i.e., via `make ktest`.
+//! Tests don't have to be registered anywhere: the harness discovers them at runtime
+//! by searching the symbol map of a [`CrateNamespace`] for [`SYMBOL_PREFIX`].
+//! Low-level crates that `mod_mgmt` depends on (e.g., `tls_initializer`) can't depend on this crate,
+//! so they should use the attribute from the `ktest_macros` crate directly.
+//!
+//! Each test runs in its own task, such that a test that panics or causes an exception
+//! only fails that test rather than the whole run.
+//! The results are written directly to the serial port, one line per event,
+//! which is easy to parse by a script on the host:
+//! ```text
+//! KTEST BEGIN count=2
+//! KTEST RUN tls_initializer::ktests::dynamic_sections_are_aligned_and_disjoint
+//! KTEST PASS tls_initializer::ktests::dynamic_sections_are_aligned_and_disjoint time_us=41
+//! KTEST RUN tls_initializer::ktests::removed_range_is_reused
+//! KTEST FAIL tls_initializer::ktests::removed_range_is_reused time_us=12 reason=the removed section's range wasn't reused
+//! KTEST END passed=1 failed=1
+//! ```
+//!
+//! After running the tests, [`exit_qemu()`] can be used to report the overall result
+//! to the host through QEMU's `isa-debug-exit` device.
+
+#![no_std]
+
+extern crate alloc;
+
+use alloc::{
+    format,
+    string::{String, ToString},
+    vec::Vec,
+};
+use core::fmt;
+use log::{error, warn};
+use mod_mgmt::CrateNamespace;
+use port_io::PortWriteOnly;
+use task::ExitValue;
+use time::{Duration, Monotonic};
+
+pub use ktest_macros::ktest;
+
+/// The prefix of the symbol name of every test function emitted by the [`ktest`] attribute.
+pub const SYMBOL_PREFIX: &str = "__ktest::";
+
+/// The signature of every test function emitted by the [`ktest`] attribute.
+pub type KtestFn = fn() -> Result<(), &'static str>;
+
+/// The I/O port of QEMU's `isa-debug-exit` device, as configured by `make ktest`.
+const QEMU_EXIT_PORT: u16 = 0xf4;
+/// The value written to [`QEMU_EXIT_PORT`] if all tests passed.
+/// QEMU exits with the code `(value << 1) | 1`, i.e., 33.
+const QEMU_EXIT_SUCCESS: u32 = 0x10;
+/// The value written to [`QEMU_EXIT_PORT`] if any test failed, which makes QEMU exit with code 35.
+const QEMU_EXIT_FAILURE: u32 = 0x11;
+
+/// A single test discovered in a [`CrateNamespace`].
+pub struct Ktest {
+    /// The fully-qualified path of the test function, without the [`SYMBOL_PREFIX`].
+    pub name: String,
+    func: KtestFn,
+}
+
+/// The outcome of a single test.
+#[derive(Debug)]
+pub enum Outcome {
+    Passed,
+    /// The test returned an error or was killed, e.g., because it panicked.
+    Failed(String),
+}
+
+/// The results of a run of the test harness.
+#[derive(Debug, Default)]
+pub struct Summary {
+    /// The name of each test that was run, along with its outcome and how long it took.
+    pub results: Vec<(String, Outcome, Duration)>,
+}
+
+impl Summary {
+    /// Returns the number of tests that passed.
+    pub fn passed(&self) -> usize {
+        self.results.iter().filter(|(_, outcome, _)| matches!(outcome, Outcome::Passed)).count()
+    }
+
+    /// Returns the number of tests that failed.
+    pub fn failed(&self) -> usize {
+        self.results.len() - self.passed()
+    }
+
+    /// Returns `true` if no test failed.
+    pub fn success(&self) -> bool {
+        self.failed() == 0
+    }
+}
+
+/// Returns all tests in the given `namespace` (and its recursive namespaces)
+/// whose name contains the given `filter`, sorted by name.
+///
+/// If `filter` is `None`, all tests are returned.
+pub fn discover(namespace: &CrateNamespace, filter: Option<&str>) -> Vec<Ktest> {
+    let mut tests: Vec<Ktest> = Vec::new();
+    for (symbol, weak_sec) in namespace.find_symbols_starting_with(SYMBOL_PREFIX) {
+        let name = &symbol[SYMBOL_PREFIX.len() ..];
+        if filter.map_or(false, |f| !name.contains(f)) {
+            continue;
+        }
+        let Some(sec) = weak_sec.upgrade() else {
+            warn!("ktest: the section for test {:?} was dropped", name);
+            continue;
+        };
+        // SAFETY: every symbol with this prefix is emitted by the `#[ktest]` attribute,
+        // which gives it the signature of `KtestFn`.
+        match unsafe { sec.as_func::<KtestFn>() } {
+            Ok(func) => tests.push(Ktest { name: name.to_string(), func: *func }),
+            Err(e) => error!("ktest: couldn't get the function for test {:?}: {}", name, e),
+        }
+    }
+    tests.sort_by(|a, b| a.name.cmp(&b.name));
+    // A test in a crate that is shared with a recursive namespace would otherwise be found twice.
+    tests.dedup_by(|a, b| a.name == b.name);
+    tests
+}
+
+/// Runs all tests in the given `namespace` whose name contains the given `filter`, one after another,
+/// and reports the results over the serial port.
+pub fn run(namespace: &CrateNamespace, filter: Option<&str>) -> Summary {
+    let tests = discover(namespace, filter);
+    emit(format_args!("BEGIN count={}", tests.len()));
+    let mut summary = Summary::default();
+    for test in tests {
+        emit(format_args!("RUN {}", test.name));
+        let start = time::now::<Monotonic>();
+        let outcome = run_one(&test);
+        let elapsed = time::now::<Monotonic>().duration_since(start);
+        match &outcome {
+            Outcome::Passed => emit(format_args!("PASS {} time_us={}", test.name, elapsed.as_micros())),
+            Outcome::Failed(reason) => emit(format_args!(
+                "FAIL {} time_us={} reason={}", test.name, elapsed.as_micros(), reason.replace('\n', " ")
+            )),
+        }
+        summary.results.push((test.name, outcome, elapsed));
+    }
+    emit(format_args!("END passed={} failed={}", summary.passed(), summary.failed()));
+    summary
+}
+
+/// Runs the given `test` in a new task and waits for it to exit.
+fn run_one(test: &Ktest) -> Outcome {
+    let func = test.func;
+    let task = match spawn::new_task_builder(move |_: ()| func(), ())
+        .name(format!("ktest {}", test.name))
+        .spawn()
+    {
+        Ok(task) => task,
+        Err(e) => return Outcome::Failed(format!("couldn't spawn test task: {}", e)),
+    };
+    match task.join() {
+        Ok(ExitValue::Completed(value)) => match value.downcast_ref::<Result<(), &'static str>>() {
+            Some(Ok(())) => Outcome::Passed,
+            Some(Err(e)) => Outcome::Failed(e.to_string()),
+            None => Outcome::Failed("test task returned an unexpected value".to_string()),
+        },
+        Ok(ExitValue::Killed(reason)) => Outcome::Failed(format!("killed: {}", reason)),
+        Err(e) => Outcome::Failed(format!("couldn't join test task: {}", e)),
+    }
+}
+
+/// Writes a single line of structured output directly to the serial port, bypassing the log formatter.
+fn emit(args: fmt::Arguments) {
+    let _ = logger_x86_64::write_str(&format!("KTEST {}\n", args));
+}
+
+/// Exits QEMU with an exit code that tells the host whether all tests passed.
+///
+/// This only has an effect if QEMU was started with an `isa-debug-exit` device at I/O port `0xf4`,
+/// as `make ktest` does; otherwise, this returns and the system keeps running.
+pub fn exit_qemu(success: bool) {
+    let value = if success { QEMU_EXIT_SUCCESS } else { QEMU_EXIT_FAILURE };
+    // SAFETY: writing to an unused port has no effect.
+    unsafe { PortWriteOnly::<u32>::new(QEMU_EXIT_PORT).write(value) };
+}
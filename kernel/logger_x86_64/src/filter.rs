@@ -0,0 +1,140 @@
+//! Runtime-adjustable log levels.
+//!
+//! A record is logged if its level is at or above the log level of the crate (or module) it came from,
+//! which defaults to the global log level.
+//! Once the full logger has been initialized, logged records are retained in the log buffers,
+//! but only those at or above the console log level are also written to the logger's writers,
+//! e.g., a serial port, which is far slower.
+
+use alloc::{boxed::Box, string::String, vec::Vec};
+use core::sync::atomic::{AtomicBool, Ordering};
+use crossbeam_utils::atomic::AtomicCell;
+use irq_safety::MutexIrqSafe;
+use log::LevelFilter;
+
+/// By default, only records at `Info` and above are written to the logger's writers
+/// once the full logger has been initialized.
+pub const DEFAULT_CONSOLE_LOG_LEVEL: LevelFilter = LevelFilter::Info;
+
+/// The maximum number of crates or modules that can have their own log level.
+pub const MAX_CRATE_LOG_LEVELS: usize = 32;
+
+/// The log level of crates that don't have their own log level.
+static GLOBAL_LEVEL: AtomicCell<LevelFilter> = AtomicCell::new(LevelFilter::Trace);
+/// The minimum level of records that are written to the logger's writers.
+static CONSOLE_LEVEL: AtomicCell<LevelFilter> = AtomicCell::new(DEFAULT_CONSOLE_LOG_LEVEL);
+const _: () = assert!(AtomicCell::<LevelFilter>::is_lock_free());
+
+/// Whether any crate has its own log level, which allows skipping the lock on [`CRATE_LEVELS`].
+static HAS_CRATE_LEVELS: AtomicBool = AtomicBool::new(false);
+/// The crates or modules that have their own log level.
+///
+/// This is a fixed-size array rather than a `Vec`, such that it can be modified without allocating
+/// while it is locked, as the heap may itself issue log statements.
+static CRATE_LEVELS: MutexIrqSafe<[Option<(&'static str, LevelFilter)>; MAX_CRATE_LOG_LEVELS]> =
+    MutexIrqSafe::new([None; MAX_CRATE_LOG_LEVELS]);
+
+/// Returns the log level that applies to records with the given `target`, i.e., module path.
+pub(crate) fn level_for(target: &str) -> LevelFilter {
+    let global = GLOBAL_LEVEL.load();
+    if !HAS_CRATE_LEVELS.load(Ordering::Acquire) {
+        return global;
+    }
+    // The most specific crate or module path wins.
+    CRATE_LEVELS.lock().iter()
+        .flatten()
+        .filter(|(name, _)| is_within(target, name))
+        .max_by_key(|(name, _)| name.len())
+        .map(|(_, level)| *level)
+        .unwrap_or(global)
+}
+
+/// Returns `true` if `target` is the crate or module `name`, or a module within it.
+fn is_within(target: &str, name: &str) -> bool {
+    target.strip_prefix(name)
+        .map_or(false, |rest| rest.is_empty() || rest.starts_with("::"))
+}
+
+pub(crate) fn set_global_level(level: LevelFilter) {
+    GLOBAL_LEVEL.store(level);
+    update_max_level();
+}
+
+pub(crate) fn global_level() -> LevelFilter {
+    GLOBAL_LEVEL.load()
+}
+
+pub(crate) fn set_console_level(level: LevelFilter) {
+    CONSOLE_LEVEL.store(level);
+}
+
+pub(crate) fn console_level() -> LevelFilter {
+    CONSOLE_LEVEL.load()
+}
+
+pub(crate) fn set_crate_level(name: &str, level: Option<LevelFilter>) -> Result<(), &'static str> {
+    if update_crate_level(name, level) {
+        return Ok(());
+    }
+    let Some(level) = level else {
+        // The crate didn't have its own log level to begin with.
+        return Ok(());
+    };
+
+    // Copy the name to the heap without holding the lock. Names are never freed,
+    // but existing entries are updated in place, so each distinct name is only leaked once.
+    let name: &'static str = Box::leak(String::from(name).into_boxed_str());
+    if update_crate_level(name, Some(level)) {
+        // Another task added the same name in the meantime.
+        return Ok(());
+    }
+    let mut crate_levels = CRATE_LEVELS.lock();
+    let entry = crate_levels.iter_mut()
+        .find(|entry| entry.is_none())
+        .ok_or("too many crates have their own log level")?;
+    *entry = Some((name, level));
+    crate_levels_changed(&*crate_levels);
+    Ok(())
+}
+
+/// Sets or removes the log level of the crate with the given `name`, if it already has its own log level.
+///
+/// Returns `false` if the crate doesn't have its own log level.
+fn update_crate_level(name: &str, level: Option<LevelFilter>) -> bool {
+    let mut crate_levels = CRATE_LEVELS.lock();
+    let Some(entry) = crate_levels.iter_mut().find(|entry| matches!(entry, Some((n, _)) if *n == name)) else {
+        return false;
+    };
+    *entry = match (*entry, level) {
+        (Some((name, _)), Some(level)) => Some((name, level)),
+        _ => None,
+    };
+    crate_levels_changed(&*crate_levels);
+    true
+}
+
+/// Updates the state that depends on the crate log levels after they were modified.
+fn crate_levels_changed(crate_levels: &[Option<(&'static str, LevelFilter)>]) {
+    HAS_CRATE_LEVELS.store(crate_levels.iter().any(Option::is_some), Ordering::Release);
+    update_max_level_with(crate_levels);
+}
+
+pub(crate) fn crate_levels() -> Vec<(&'static str, LevelFilter)> {
+    let mut levels = Vec::with_capacity(MAX_CRATE_LOG_LEVELS);
+    levels.extend(CRATE_LEVELS.lock().iter().flatten().copied());
+    levels
+}
+
+/// Sets the `log` crate's maximum level, which cheaply filters out log statements in the logging macros,
+/// to the most verbose level of any crate.
+fn update_max_level() {
+    update_max_level_with(&*CRATE_LEVELS.lock());
+}
+
+fn update_max_level_with(crate_levels: &[Option<(&'static str, LevelFilter)>]) {
+    let max = crate_levels.iter()
+        .flatten()
+        .map(|(_, level)| *level)
+        .fold(GLOBAL_LEVEL.load(), core::cmp::max);
+    log::set_max_level(max);
+}
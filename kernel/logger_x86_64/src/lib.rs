@@ -5,6 +5,12 @@
 //!
 //! Currently, log statements are written to one or more **writers**, 
 //! which are objects that implement the [`core::fmt::Write`] trait.
+//!
+//! Once the full logger has been initialized, log records are also retained in per-CPU ring buffers,
+//! which can be read back with [`log_records()`], e.g., by the `dmesg` application.
+//! Only records at or above the [console log level](set_console_log_level) are then written to the writers,
+//! so verbose logging can stay enabled without slowing down the system by writing everything to a serial port.
+//! The log level of individual crates can be adjusted at runtime with [`set_crate_log_level()`].
 
 #![no_std]
 #![feature(trait_alias)]
@@ -14,8 +20,14 @@ extern crate crossbeam_utils;
 extern crate log;
 extern crate irq_safety;
 extern crate serial_port_basic;
+extern crate apic;
+extern crate spin;
+extern crate time;
+
+mod filter;
+mod ring;
 
-use log::{Record, Level, SetLoggerError, Metadata, Log};
+use log::{Record, Level, LevelFilter, SetLoggerError, Metadata, Log};
 use core::{borrow::Borrow, fmt::{self, Write}, ops::Deref};
 use irq_safety::MutexIrqSafe;
 use serial_port_basic::SerialPort;
@@ -23,6 +35,8 @@ use alloc::{sync::Arc, vec::Vec};
 
 #[cfg(mirror_log_to_vga)]
 pub use mirror_log::set_log_mirror_function;
+pub use filter::{DEFAULT_CONSOLE_LOG_LEVEL, MAX_CRATE_LOG_LEVELS};
+pub use ring::{LogRecord, log_records, clear_log_records, LOG_BUFFER_CAPACITY, MAX_MESSAGE_LEN};
 
 /// By default, Theseus will print all log levels, including `Trace` and above.
 pub const DEFAULT_LOG_LEVEL: Level = Level::Trace;
@@ -102,7 +116,7 @@ impl DummyLogger {
 impl Log for DummyLogger {
     #[inline(always)]
     fn enabled(&self, metadata: &Metadata) -> bool {
-        metadata.level() <= log::max_level()
+        metadata.level() <= log::max_level() && metadata.level() <= filter::level_for(metadata.target())
     }
 
     fn log(&self, record: &Record) {
@@ -110,6 +124,13 @@ impl Log for DummyLogger {
             return;
         }
 
+        if ring::is_initialized() {
+            ring::record(record);
+            if record.level() > filter::console_level() {
+                return;
+            }
+        }
+
         let (level_str, color) = match record.level() {
             Level::Error => ("[E] ", LogColor::Red),
             Level::Warn =>  ("[W] ", LogColor::Yellow),
@@ -182,6 +203,8 @@ pub fn early_init(
 
 /// Initialize the fully-featured Theseus system logger.
 ///
+/// This also allocates the per-CPU log buffers, so it should be invoked after all CPUs have been brought up.
+///
 /// # Arguments
 /// * `log_level`: the log level that should be used.
 ///    If `None`, the [`DEFAULT_LOG_LEVEL`] will be used.
@@ -202,6 +225,7 @@ pub fn init<I, W>(
             .collect::<Vec<_>>(),
     };
     *LOGGER.lock() = Some(logger);
+    ring::init();
 
     // Once the real logger has been initialized, tell the `log` crate to use our dummy logger instance.
     // Call `set_logger()` again, just in case we never ran the `early_init()` function;
@@ -217,8 +241,44 @@ pub fn init<I, W>(
 /// 
 /// If `Level::Info` is set, `debug!()` and `trace!()` will not be logged, 
 /// but `info!()`, `warn!()`, and `error!()` will be. 
+///
+/// This applies to all crates that don't have their own log level; see [`set_crate_log_level()`].
 pub fn set_log_level(level: Level) {
-    log::set_max_level(level.to_level_filter())
+    filter::set_global_level(level.to_level_filter())
+}
+
+/// Returns the log level of all crates that don't have their own log level.
+pub fn log_level() -> LevelFilter {
+    filter::global_level()
+}
+
+/// Sets the log level of the given crate or module (and all modules within it),
+/// which takes precedence over the log level set by [`set_log_level()`].
+///
+/// If `level` is `None`, the crate's own log level is removed.
+///
+/// Returns an error if [`MAX_CRATE_LOG_LEVELS`] crates already have their own log level.
+pub fn set_crate_log_level(name: &str, level: Option<LevelFilter>) -> Result<(), &'static str> {
+    filter::set_crate_level(name, level)
+}
+
+/// Returns the crates and modules that have their own log level, along with those levels.
+pub fn crate_log_levels() -> Vec<(&'static str, LevelFilter)> {
+    filter::crate_levels()
+}
+
+/// Sets the console log level: once the full logger has been initialized,
+/// only records at or above this level are written to the logger's writers,
+/// whereas all logged records are retained in the log buffers.
+///
+/// The default is [`DEFAULT_CONSOLE_LOG_LEVEL`].
+pub fn set_console_log_level(level: LevelFilter) {
+    filter::set_console_level(level)
+}
+
+/// Returns the console log level; see [`set_console_log_level()`].
+pub fn console_log_level() -> LevelFilter {
+    filter::console_level()
 }
 
 /// Convenience function for writing formatted arguments to the logger.
@@ -0,0 +1,247 @@
+//! Per-CPU ring buffers that retain the most recent log records.
+//!
+//! Each CPU appends records to its own buffer, so the lock on a buffer is only contended
+//! by readers of the log, e.g., the `dmesg` application.
+//!
+//! The buffers are allocated once when the full logger is initialized, and logging never allocates afterwards,
+//! because log statements may be issued while the heap itself is locked.
+
+use alloc::{collections::BTreeMap, string::String, vec::Vec};
+use apic::CpuId;
+use core::{
+    fmt::{self, Write},
+    str,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+use irq_safety::MutexIrqSafe;
+use log::{Level, Record};
+use spin::Once;
+use time::{Instant, Monotonic};
+
+/// The number of records retained by each CPU's log buffer.
+/// Once a buffer is full, its oldest records are overwritten.
+pub const LOG_BUFFER_CAPACITY: usize = 512;
+
+/// The maximum length in bytes of a retained log message. Longer messages are truncated.
+pub const MAX_MESSAGE_LEN: usize = 224;
+
+/// The log buffer of each CPU that was online when the full logger was initialized.
+static BUFFERS: Once<BTreeMap<CpuId, MutexIrqSafe<LogBuffer>>> = Once::new();
+
+/// The sequence number of the next log record, which orders records across all CPUs.
+static NEXT_SEQUENCE: AtomicU64 = AtomicU64::new(0);
+
+/// A log record retained in the log buffers, as returned by [`log_records()`].
+#[derive(Clone, Debug)]
+pub struct LogRecord {
+    /// The position of this record in the order in which records were logged across all CPUs.
+    pub sequence: u64,
+    /// The time since boot at which this record was logged,
+    /// or zero if it was logged before a monotonic clock was available.
+    pub timestamp: Duration,
+    /// The CPU on which this record was logged.
+    pub cpu: CpuId,
+    pub level: Level,
+    /// The module path of the log statement.
+    pub module: &'static str,
+    /// The source file of the log statement.
+    pub file: &'static str,
+    /// The source line of the log statement.
+    pub line: u32,
+    /// The message, which ends with `...` if it was truncated to [`MAX_MESSAGE_LEN`] bytes.
+    pub message: String,
+}
+
+/// A fixed-size log record, such that records can be stored without allocating.
+#[derive(Clone, Copy)]
+struct Slot {
+    sequence: u64,
+    timestamp: Duration,
+    cpu: CpuId,
+    level: Level,
+    module: &'static str,
+    file: &'static str,
+    line: u32,
+    message: MessageBuffer,
+}
+
+impl Slot {
+    const EMPTY: Slot = Slot {
+        sequence: 0,
+        timestamp: Duration::ZERO,
+        cpu: 0,
+        level: Level::Trace,
+        module: "",
+        file: "",
+        line: 0,
+        message: MessageBuffer::EMPTY,
+    };
+}
+
+impl From<&Slot> for LogRecord {
+    fn from(slot: &Slot) -> Self {
+        let mut message = String::from(slot.message.as_str());
+        if slot.message.truncated {
+            message.push_str("...");
+        }
+        LogRecord {
+            sequence: slot.sequence,
+            timestamp: slot.timestamp,
+            cpu: slot.cpu,
+            level: slot.level,
+            module: slot.module,
+            file: slot.file,
+            line: slot.line,
+            message,
+        }
+    }
+}
+
+/// A formatted log message, truncated to [`MAX_MESSAGE_LEN`] bytes.
+#[derive(Clone, Copy)]
+struct MessageBuffer {
+    bytes: [u8; MAX_MESSAGE_LEN],
+    len: usize,
+    truncated: bool,
+}
+
+impl MessageBuffer {
+    const EMPTY: MessageBuffer = MessageBuffer { bytes: [0; MAX_MESSAGE_LEN], len: 0, truncated: false };
+
+    fn as_str(&self) -> &str {
+        // `write_str()` only ever copies whole characters.
+        str::from_utf8(&self.bytes[..self.len]).unwrap_or("<invalid UTF-8>")
+    }
+}
+
+impl Write for MessageBuffer {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut len = s.len().min(MAX_MESSAGE_LEN - self.len);
+        if len < s.len() {
+            self.truncated = true;
+            while !s.is_char_boundary(len) {
+                len -= 1;
+            }
+        }
+        self.bytes[self.len..self.len + len].copy_from_slice(&s.as_bytes()[..len]);
+        self.len += len;
+        Ok(())
+    }
+}
+
+/// A ring buffer of [`LOG_BUFFER_CAPACITY`] log records.
+struct LogBuffer {
+    slots: Vec<Slot>,
+    /// The index of the slot that the next record is written to.
+    next: usize,
+    /// The number of slots that hold a record.
+    len: usize,
+}
+
+impl LogBuffer {
+    fn new() -> Self {
+        LogBuffer { slots: alloc::vec![Slot::EMPTY; LOG_BUFFER_CAPACITY], next: 0, len: 0 }
+    }
+
+    fn push(&mut self, slot: &Slot) {
+        self.slots[self.next] = *slot;
+        self.next = (self.next + 1) % LOG_BUFFER_CAPACITY;
+        self.len = (self.len + 1).min(LOG_BUFFER_CAPACITY);
+    }
+
+    /// Returns the records in this buffer, from oldest to newest.
+    fn iter(&self) -> impl Iterator<Item = &Slot> {
+        let start = (self.next + LOG_BUFFER_CAPACITY - self.len) % LOG_BUFFER_CAPACITY;
+        (0..self.len).map(move |i| &self.slots[(start + i) % LOG_BUFFER_CAPACITY])
+    }
+
+    fn clear(&mut self) {
+        self.len = 0;
+    }
+}
+
+/// Allocates a log buffer for each CPU that is currently online.
+///
+/// This must be called after all CPUs have been brought up;
+/// records logged on CPUs that come online later are stored in another CPU's buffer.
+pub(crate) fn init() {
+    BUFFERS.call_once(|| {
+        let mut buffers: BTreeMap<_, _> = apic::get_lapics().iter()
+            .map(|(cpu, _)| (*cpu, MutexIrqSafe::new(LogBuffer::new())))
+            .collect();
+        buffers.entry(apic::current_cpu()).or_insert_with(|| MutexIrqSafe::new(LogBuffer::new()));
+        buffers
+    });
+}
+
+/// Returns `true` if the log buffers have been allocated.
+pub(crate) fn is_initialized() -> bool {
+    BUFFERS.get().is_some()
+}
+
+/// Stores the given record in the current CPU's log buffer.
+///
+/// Does nothing if the log buffers haven't yet been allocated.
+pub(crate) fn record(record: &Record) {
+    let Some(buffers) = BUFFERS.get() else {
+        return;
+    };
+    let cpu = apic::current_cpu();
+    let Some(buffer) = buffers.get(&cpu).or_else(|| buffers.values().next()) else {
+        return;
+    };
+
+    // Format the message before taking the lock, in case formatting it issues another log statement.
+    let mut message = MessageBuffer::EMPTY;
+    let _ = message.write_fmt(*record.args());
+    let slot = Slot {
+        sequence: NEXT_SEQUENCE.fetch_add(1, Ordering::Relaxed),
+        timestamp: timestamp(),
+        cpu,
+        level: record.level(),
+        module: record.module_path_static().unwrap_or("??"),
+        file: record.file_static().unwrap_or("??"),
+        line: record.line().unwrap_or(0),
+        message,
+    };
+    buffer.lock().push(&slot);
+}
+
+/// Returns the time since boot according to the monotonic clock, if one has been registered.
+fn timestamp() -> Duration {
+    // The placeholder monotonic clock logs an error, which would recurse back into the logger.
+    if time::period::<Monotonic>().is_some() {
+        time::now::<Monotonic>().duration_since(Instant::ZERO)
+    } else {
+        Duration::ZERO
+    }
+}
+
+/// Returns the records currently retained in the log buffers of all CPUs,
+/// from oldest to newest.
+///
+/// Returns an empty list if the full logger has not yet been initialized.
+pub fn log_records() -> Vec<LogRecord> {
+    let Some(buffers) = BUFFERS.get() else {
+        return Vec::new();
+    };
+    let mut records = Vec::new();
+    for buffer in buffers.values() {
+        // Copy the slots into a preallocated list, such that we don't allocate while holding the lock.
+        let mut slots = Vec::with_capacity(LOG_BUFFER_CAPACITY);
+        slots.extend(buffer.lock().iter().copied());
+        records.extend(slots.iter().map(LogRecord::from));
+    }
+    records.sort_unstable_by_key(|record| record.sequence);
+    records
+}
+
+/// Removes all records from the log buffers of all CPUs.
+pub fn clear_log_records() {
+    if let Some(buffers) = BUFFERS.get() {
+        for buffer in buffers.values() {
+            buffer.lock().clear();
+        }
+    }
+}
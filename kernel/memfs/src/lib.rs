@@ -15,7 +15,7 @@ extern crate io;
 
 use alloc::string::String;
 use fs_node::{DirRef, WeakDirRef, File, FsNode};
-use memory::{MappedPages, get_kernel_mmi_ref, allocate_pages_by_bytes, PteFlags};
+use memory::{MappedPages, get_kernel_mmi_ref, allocate_pages_by_bytes, PteFlags, PAGE_SIZE};
 use alloc::sync::Arc;
 use spin::Mutex;
 use fs_node::{FileOrDir, FileRef};
@@ -144,6 +144,32 @@ impl File for MemFile {
     fn as_mapping(&self) -> Result<&MappedPages, &'static str> {
         Ok(&self.mp)
     }
+
+    fn set_len(&mut self, len: usize) -> Result<(), IoError> {
+        if len <= self.len {
+            // the underlying mapped pages are kept, such that the file can grow again without reallocating
+            self.len = len;
+        } else {
+            let zeros = alloc::vec![0u8; len - self.len];
+            self.write_at(&zeros, self.len)?;
+        }
+        Ok(())
+    }
+
+    /// Maps this file's pages copy-on-write if they're writable and contain the requested range,
+    /// such that no contents are copied until they are modified. Otherwise, the contents are copied.
+    fn mmap(&mut self, offset: usize, len: usize) -> Result<MappedPages, IoError> {
+        let end = offset.saturating_add(len);
+        if !self.mp.flags().is_writable() || end > self.mp.size_in_bytes() {
+            return fs_node::map_by_copying(self, offset, len);
+        }
+        // The pages may hold stale bytes beyond the end of the file, which must not be exposed.
+        if end > self.len {
+            let tail_end = core::cmp::min((end + PAGE_SIZE - 1) / PAGE_SIZE * PAGE_SIZE, self.mp.size_in_bytes());
+            self.mp.as_slice_mut::<u8>(self.len, tail_end - self.len)?.fill(0);
+        }
+        Ok(fs_node::map_copy_on_write(&mut self.mp, offset, len)?)
+    }
 }
 
 impl FsNode for MemFile {
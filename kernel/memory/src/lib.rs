@@ -21,18 +21,20 @@ mod paging;
 pub use self::paging::{
     PageTable, Mapper, Mutability, Mutable, Immutable,
     MappedPages, BorrowedMappedPages, BorrowedSliceMappedPages,
-    translate,
+    translate, handle_copy_on_write_fault, num_shared_frames, shared_frame_mappings,
+    handle_demand_paging_fault, HugePageSize,
 };
 
 pub use memory_structs::{Frame, Page, FrameRange, PageRange, VirtualAddress, PhysicalAddress};
 pub use page_allocator::{
-    AllocatedPages, allocate_pages, allocate_pages_at,
+    AllocatedPages, allocate_pages, allocate_pages_at, allocate_pages_aligned,
     allocate_pages_by_bytes, allocate_pages_by_bytes_at,
 };
 
 pub use frame_allocator::{
     AllocatedFrames, MemoryRegionType, PhysicalMemoryRegion,
-    allocate_frames, allocate_frames_at, allocate_frames_by_bytes_at, allocate_frames_by_bytes,
+    allocate_frames, allocate_frames_at, allocate_frames_aligned, allocate_frames_by_bytes_at, allocate_frames_by_bytes,
+    allocate_frames_in_range,
 };
 
 #[cfg(target_arch = "x86_64")]
@@ -45,8 +47,9 @@ pub use pte_flags::*;
 
 use boot_info::{BootInformation, MemoryRegion};
 use log::debug;
+use core::{ops::{Deref, DerefMut}, sync::atomic::{AtomicU16, Ordering}, fmt};
 use spin::Once;
-use irq_safety::MutexIrqSafe;
+use irq_safety::{MutexIrqSafe, MutexIrqSafeGuard};
 use alloc::vec::Vec;
 use alloc::sync::Arc;
 use no_drop::NoDrop;
@@ -56,7 +59,104 @@ pub use kernel_config::memory::PAGE_SIZE;
 static KERNEL_MMI: Once<MmiRef> = Once::new();
 
 /// A shareable reference to a `MemoryManagementInfo` struct wrapper in a lock.
-pub type MmiRef = Arc<MutexIrqSafe<MemoryManagementInfo>>;
+pub type MmiRef = Arc<MmiLock>;
+
+/// The value of [`MmiLock`]'s owner when no CPU is known to hold the lock.
+const NO_OWNER: u16 = u16::MAX;
+
+/// A lock around a [`MemoryManagementInfo`], which disables interrupts while held
+/// like the [`MutexIrqSafe`] that it wraps, and which records the CPU that holds it.
+///
+/// Knowing the owner allows a page fault handler to tell a fault that occurred while
+/// the current CPU holds this lock, which it must not wait for,
+/// apart from contention with another CPU, which it can simply wait out;
+/// see [`MmiLock::lock_unless_held_by_current_cpu()`].
+pub struct MmiLock {
+    inner: MutexIrqSafe<MemoryManagementInfo>,
+    /// The ID of the CPU that holds this lock, or [`NO_OWNER`].
+    owner: AtomicU16,
+}
+
+impl MmiLock {
+    /// Wraps the given `MemoryManagementInfo` in a new lock.
+    pub const fn new(mmi: MemoryManagementInfo) -> Self {
+        MmiLock { inner: MutexIrqSafe::new(mmi), owner: AtomicU16::new(NO_OWNER) }
+    }
+
+    /// Acquires this lock, spinning until it's available.
+    pub fn lock(&self) -> MmiGuard<'_> {
+        self.guard(self.inner.lock())
+    }
+
+    /// Acquires this lock only if it's available right now.
+    pub fn try_lock(&self) -> Option<MmiGuard<'_>> {
+        self.inner.try_lock().map(|guard| self.guard(guard))
+    }
+
+    /// Acquires this lock, spinning while it's held by another CPU.
+    ///
+    /// Returns `None` if the current CPU already holds this lock, e.g., when invoked
+    /// by a fault handler that interrupted code holding it, which would otherwise deadlock.
+    /// If the current CPU is unknown, this only acquires the lock if it's available right now.
+    pub fn lock_unless_held_by_current_cpu(&self) -> Option<MmiGuard<'_>> {
+        let Some(cpu) = CURRENT_CPU_FUNC.get().and_then(|func| func()) else {
+            return self.try_lock();
+        };
+        loop {
+            if let Some(guard) = self.try_lock() {
+                return Some(guard);
+            }
+            // The owner is only ever this CPU's ID if this CPU holds the lock,
+            // as each guard clears the owner before releasing the lock.
+            if self.owner.load(Ordering::Acquire) == cpu as u16 {
+                return None;
+            }
+            core::hint::spin_loop();
+        }
+    }
+
+    fn guard<'m>(&'m self, guard: MutexIrqSafeGuard<'m, MemoryManagementInfo>) -> MmiGuard<'m> {
+        let owner = CURRENT_CPU_FUNC.get().and_then(|func| func()).map_or(NO_OWNER, u16::from);
+        self.owner.store(owner, Ordering::Release);
+        MmiGuard { guard, owner: &self.owner }
+    }
+}
+
+impl fmt::Debug for MmiLock {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.try_lock() {
+            Some(guard) => f.debug_struct("MmiLock").field("data", &*guard).finish(),
+            None => f.debug_struct("MmiLock").field("owner", &self.owner.load(Ordering::Relaxed)).finish_non_exhaustive(),
+        }
+    }
+}
+
+/// A guard that provides access to a locked [`MemoryManagementInfo`];
+/// the [`MmiLock`] is released when this is dropped.
+pub struct MmiGuard<'m> {
+    guard: MutexIrqSafeGuard<'m, MemoryManagementInfo>,
+    owner: &'m AtomicU16,
+}
+
+impl Deref for MmiGuard<'_> {
+    type Target = MemoryManagementInfo;
+    fn deref(&self) -> &MemoryManagementInfo {
+        &self.guard
+    }
+}
+
+impl DerefMut for MmiGuard<'_> {
+    fn deref_mut(&mut self) -> &mut MemoryManagementInfo {
+        &mut self.guard
+    }
+}
+
+impl Drop for MmiGuard<'_> {
+    fn drop(&mut self) {
+        // This runs before the inner guard releases the lock.
+        self.owner.store(NO_OWNER, Ordering::Release);
+    }
+}
 
 /// Returns a reference to the kernel's `MemoryManagementInfo`, if initialized.
 /// If not, it returns `None`.
@@ -118,6 +218,48 @@ pub fn create_mapping<F: Into<PteFlagsArch>>(
     kernel_mmi_ref.lock().page_table.map_allocated_pages(allocated_pages, flags)
 }
 
+/// A convenience function that creates a new demand-paged memory mapping,
+/// whose pages are only backed by (zeroed) frames when they are first accessed.
+/// See [`Mapper::map_allocated_pages_on_demand()`].
+/// Returns the new `MappedPages.` 
+/// 
+/// # Locking / Deadlock
+/// Currently, this function acquires the lock on the kernel's `MemoryManagementInfo` instance.
+/// Thus, the caller should ensure that lock is not held when invoking this function,
+/// nor when accessing the pages of the returned mapping.
+pub fn create_mapping_on_demand<F: Into<PteFlagsArch>>(
+    size_in_bytes: usize,
+    flags: F,
+) -> Result<MappedPages, &'static str> {
+    let kernel_mmi_ref = get_kernel_mmi_ref().ok_or("create_mapping_on_demand(): KERNEL_MMI was not yet initialized!")?;
+    let allocated_pages = allocate_pages_by_bytes(size_in_bytes).ok_or("memory::create_mapping_on_demand(): couldn't allocate pages!")?;
+    kernel_mmi_ref.lock().page_table.map_allocated_pages_on_demand(allocated_pages, flags)
+}
+
+/// A convenience function that creates a new memory mapping backed by huge pages of the given `size`,
+/// whose frames are physically contiguous within each huge page.
+/// The `size_in_bytes` is rounded up to a multiple of the huge page size.
+/// See [`Mapper::map_allocated_pages_to_huge()`].
+/// Returns the new `MappedPages.` 
+/// 
+/// # Locking / Deadlock
+/// Currently, this function acquires the lock on the kernel's `MemoryManagementInfo` instance.
+/// Thus, the caller should ensure that lock is not held when invoking this function.
+pub fn create_huge_mapping<F: Into<PteFlagsArch>>(
+    size_in_bytes: usize,
+    flags: F,
+    size: HugePageSize,
+) -> Result<MappedPages, &'static str> {
+    let kernel_mmi_ref = get_kernel_mmi_ref().ok_or("create_huge_mapping(): KERNEL_MMI was not yet initialized!")?;
+    let num_huge_pages = (size_in_bytes.max(1) + size.size_in_bytes() - 1) / size.size_in_bytes();
+    let num_pages = num_huge_pages * size.size_in_pages();
+    let allocated_pages = allocate_pages_aligned(num_pages, size.size_in_pages())
+        .ok_or("memory::create_huge_mapping(): couldn't allocate aligned pages!")?;
+    let allocated_frames = allocate_frames_aligned(num_pages, size.size_in_pages())
+        .ok_or("memory::create_huge_mapping(): couldn't allocate aligned frames!")?;
+    kernel_mmi_ref.lock().page_table.map_allocated_pages_to_huge(allocated_pages, allocated_frames, flags, size)
+}
+
 
 static BROADCAST_TLB_SHOOTDOWN_FUNC: Once<fn(PageRange)> = Once::new();
 
@@ -127,6 +269,14 @@ pub fn set_broadcast_tlb_shootdown_cb(func: fn(PageRange)) {
     BROADCAST_TLB_SHOOTDOWN_FUNC.call_once(|| func);
 }
 
+static CURRENT_CPU_FUNC: Once<fn() -> Option<u8>> = Once::new();
+
+/// Set the function callback that returns the ID of the current CPU,
+/// or `None` if it isn't yet known, which [`MmiLock`] uses to record its owner.
+pub fn set_current_cpu_cb(func: fn() -> Option<u8>) {
+    CURRENT_CPU_FUNC.call_once(|| func);
+}
+
 /// Information returned after initialising the memory subsystem.
 #[derive(Debug)]
 pub struct InitialMemoryMappings {
@@ -272,7 +422,7 @@ pub fn init_post_heap(
     };
 
     let kernel_mmi_ref = KERNEL_MMI.call_once( || {
-        Arc::new(MutexIrqSafe::new(kernel_mmi))
+        Arc::new(MmiLock::new(kernel_mmi))
     });
 
     kernel_mmi_ref.clone()
@@ -0,0 +1,176 @@
+//! Support for copy-on-write (CoW) mappings.
+//!
+//! A frame that is shared by multiple copy-on-write mappings is not owned by any one
+//! of the page table entries that map it; instead, it is owned by the registry of shared frames below,
+//! which counts how many pages currently map it.
+//! Each of those pages is mapped as non-exclusive, read-only, and with the `COPY_ON_WRITE` bit set.
+//!
+//! The first write to such a page causes a page fault, which is resolved by
+//! [`handle_copy_on_write_fault()`]: that page is given its own private copy of the shared frame,
+//! or, if it is the last page that maps that frame, it simply takes back exclusive ownership of it.
+//! Either way, the page is then writable and exclusive again.
+
+use alloc::collections::BTreeMap;
+use irq_safety::MutexIrqSafe;
+use kernel_config::memory::PAGE_SIZE;
+use log::error;
+use crate::{
+    BROADCAST_TLB_SHOOTDOWN_FUNC, Frame, FrameRange, Page, PageRange, VirtualAddress,
+    AllocatedFrames, get_kernel_mmi_ref,
+};
+use super::{Mapper, tlb_flush_virt_addr, mapper::INTO_ALLOCATED_FRAMES_FUNC};
+
+/// A frame shared by one or more copy-on-write pages.
+struct SharedFrame {
+    /// The shared frame, which is deallocated once no more pages map it.
+    frame: AllocatedFrames,
+    /// The number of pages that currently map this frame.
+    mappings: usize,
+}
+
+/// The registry of all frames shared by copy-on-write pages.
+///
+/// This lock must only be acquired while the lock on the page table that maps those pages is held,
+/// if any, in order to avoid deadlock.
+static SHARED_FRAMES: MutexIrqSafe<BTreeMap<Frame, SharedFrame>> = MutexIrqSafe::new(BTreeMap::new());
+
+/// Returns the number of frames that are currently shared by copy-on-write pages.
+pub fn num_shared_frames() -> usize {
+    SHARED_FRAMES.lock().len()
+}
+
+/// Returns the number of pages that currently map the given shared `frame` as copy-on-write,
+/// or `0` if that frame is not shared.
+pub fn shared_frame_mappings(frame: Frame) -> usize {
+    SHARED_FRAMES.lock().get(&frame).map_or(0, |shared| shared.mappings)
+}
+
+/// Adds another mapping of the given `frame` to the registry of shared frames
+/// and invokes the given `map` function to map it.
+///
+/// If `owned_by_pte` is `true`, the `frame` is not yet shared, but is owned by the
+/// (formerly exclusive) page table entry that maps it, so ownership of it is transferred into the registry.
+/// That page table entry then counts as the first mapping of the `frame`.
+pub(super) fn add_mapping<R>(
+    frame: Frame,
+    owned_by_pte: bool,
+    map: impl FnOnce(&AllocatedFrames) -> Result<R, &'static str>,
+) -> Result<R, &'static str> {
+    let mut shared_frames = SHARED_FRAMES.lock();
+    if owned_by_pte {
+        let into_func = INTO_ALLOCATED_FRAMES_FUNC.get()
+            .ok_or("BUG: add_mapping(): the `INTO_ALLOCATED_FRAMES_FUNC` callback was not initialized")?;
+        if shared_frames.contains_key(&frame) {
+            return Err("BUG: add_mapping(): an exclusively-mapped frame was already shared");
+        }
+        shared_frames.insert(frame, SharedFrame {
+            frame: into_func(FrameRange::new(frame, frame)),
+            mappings: 1,
+        });
+    }
+    let shared = shared_frames.get_mut(&frame)
+        .ok_or("BUG: add_mapping(): a copy-on-write page mapped a frame that wasn't shared")?;
+    let result = map(&shared.frame)?;
+    shared.mappings += 1;
+    Ok(result)
+}
+
+/// Removes one mapping of the given shared `frame`, e.g., because a copy-on-write page that mapped it was unmapped.
+///
+/// The `frame` is deallocated if that was its last mapping.
+pub(super) fn remove_mapping(frame: Frame) {
+    let last_frame = {
+        let mut shared_frames = SHARED_FRAMES.lock();
+        match shared_frames.get_mut(&frame) {
+            Some(shared) if shared.mappings > 1 => {
+                shared.mappings -= 1;
+                None
+            }
+            Some(_) => shared_frames.remove(&frame),
+            None => {
+                error!("BUG: remove_mapping(): copy-on-write page mapped frame {:?}, which wasn't shared", frame);
+                None
+            }
+        }
+    };
+    // The last `AllocatedFrames` is dropped (and thus deallocated) here, after the registry lock is released.
+    drop(last_frame);
+}
+
+/// Gives the given copy-on-write `page` its own private, writable, and exclusive frame.
+///
+/// If `page` is the last page that maps its shared frame, it takes back ownership of that frame.
+/// Otherwise, the contents of the shared frame are copied into a newly-allocated frame.
+///
+/// Returns `Ok(true)` if `page` is now writable, including if it was already made private,
+/// e.g., by another CPU that concurrently faulted on the same page.
+/// Returns `Ok(false)` if `page` is not a copy-on-write page.
+pub(super) fn make_private(mapper: &mut Mapper, page: Page) -> Result<bool, &'static str> {
+    let mut shared_frames = SHARED_FRAMES.lock();
+
+    // Huge pages are never copy-on-write, and thus have no P1 entry.
+    let Ok(pte) = mapper.p1_entry_mut(page) else { return Ok(false) };
+    let flags = pte.flags();
+    let frame = match pte.pointed_frame() {
+        Some(frame) if flags.is_copy_on_write() => frame,
+        Some(_) => return Ok(flags.is_writable()),
+        None => return Ok(false),
+    };
+    let private_flags = flags
+        .copy_on_write(false)
+        .writable(true)
+        .exclusive(true);
+
+    let shared = shared_frames.get_mut(&frame)
+        .ok_or("BUG: make_private(): a copy-on-write page mapped a frame that wasn't shared")?;
+    if shared.mappings == 1 {
+        let SharedFrame { frame: af, .. } = shared_frames.remove(&frame).unwrap();
+        pte.set_flags(private_flags);
+        // The page table entry now exclusively owns this frame again,
+        // and will deallocate it when it is unmapped.
+        core::mem::forget(af);
+    } else {
+        let new_frame = mapper.allocate_initialized_frame(|contents| {
+            // SAFETY: the shared frame is mapped at `page` and cannot be written to,
+            //         because all of its mappings are read-only.
+            let shared_contents = unsafe { &*(page.start_address().value() as *const [u8; PAGE_SIZE]) };
+            contents.copy_from_slice(shared_contents);
+        })?;
+
+        mapper.p1_entry_mut(page)?.set_entry(new_frame.as_allocated_frame(), private_flags);
+        // The page table entry now exclusively owns the new frame,
+        // and will deallocate it when it is unmapped.
+        core::mem::forget(new_frame);
+        shared.mappings -= 1;
+    }
+
+    tlb_flush_virt_addr(page.start_address());
+    if let Some(func) = BROADCAST_TLB_SHOOTDOWN_FUNC.get() {
+        func(PageRange::new(page, page));
+    }
+    Ok(true)
+}
+
+/// Attempts to resolve a page fault caused by writing to the given `vaddr`
+/// in the kernel's address space, which may be a copy-on-write page.
+///
+/// This is intended to be invoked by the page fault handler upon a write protection violation.
+/// Returns `true` if the fault was resolved, in which case the faulting write should be retried.
+/// Returns `false` if `vaddr` is not mapped as copy-on-write, i.e., it is a genuine page fault.
+pub fn handle_copy_on_write_fault(vaddr: VirtualAddress) -> bool {
+    let Some(kernel_mmi_ref) = get_kernel_mmi_ref() else { return false };
+    // If another CPU holds the kernel's page table lock, simply wait for it.
+    // But the fault may have occurred while this CPU holds that lock, e.g., within the paging code itself,
+    // in which case we must not wait; instead, let the fault be reported as unhandled.
+    let Some(mut kernel_mmi) = kernel_mmi_ref.lock_unless_held_by_current_cpu() else {
+        error!("handle_copy_on_write_fault(): kernel page table was locked by this CPU, cannot handle fault at {:#X}", vaddr);
+        return false;
+    };
+    match make_private(&mut kernel_mmi.page_table, Page::containing_address(vaddr)) {
+        Ok(resolved) => resolved,
+        Err(e) => {
+            error!("handle_copy_on_write_fault(): failed to copy page at {:#X}: {}", vaddr, e);
+            false
+        }
+    }
+}
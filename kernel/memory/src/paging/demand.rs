@@ -0,0 +1,89 @@
+//! Support for demand paging, i.e., mappings whose frames are allocated lazily upon first access.
+//!
+//! A demand-paged mapping is created by [`Mapper::map_allocated_pages_on_demand()`],
+//! which reserves its pages but does not back them with any frames.
+//! Instead, each page's P1 entry is left not present (invalid) with the `ON_DEMAND` bit set,
+//! and holds the flags that the page will be mapped with once it is backed.
+//!
+//! The first access to such a page causes a page fault, which is resolved by
+//! [`handle_demand_paging_fault()`]: a zeroed frame is allocated and mapped exclusively to that page.
+//! A page can also be backed ahead of time via [`MappedPages::populate()`].
+//!
+//! [`MappedPages::populate()`]: super::MappedPages::populate
+
+use log::error;
+use crate::{Page, VirtualAddress, get_kernel_mmi_ref};
+use pte_flags::PteFlagsArch;
+use super::{Mapper, huge};
+
+/// Returns the flags of an unbacked demand-paged P1 entry
+/// whose page will be mapped with the given `flags` once it is backed.
+///
+/// Such an entry must not be present (valid) nor exclusive, as it does not point to any frame.
+pub(super) fn unbacked_flags(flags: PteFlagsArch) -> PteFlagsArch {
+    flags
+        .valid(false)
+        .exclusive(false)
+        .copy_on_write(false)
+        .on_demand(true)
+}
+
+/// Backs the given demand-paged `page` with a newly-allocated, zeroed frame.
+///
+/// The frame is fully zeroed before it is mapped,
+/// so other CPUs accessing `page` concurrently cannot observe its prior contents.
+///
+/// Returns `Ok(true)` if `page` is now backed by a frame, including if it already was,
+/// e.g., because another CPU concurrently faulted on the same page.
+/// Returns `Ok(false)` if `page` is neither mapped nor demand-paged.
+pub(super) fn populate(mapper: &mut Mapper, page: Page) -> Result<bool, &'static str> {
+    // Huge pages are always backed by the frames they were mapped to.
+    if huge::huge_entry_mut(mapper, page).is_some() {
+        return Ok(true);
+    }
+    let Ok(pte) = mapper.p1_entry_mut(page) else { return Ok(false) };
+    let flags = pte.flags();
+    if flags.is_valid() {
+        return Ok(true);
+    }
+    if !flags.is_on_demand() {
+        return Ok(false);
+    }
+
+    let frame = mapper.allocate_initialized_frame(|contents| contents.fill(0))?;
+    let backed_flags = flags
+        .on_demand(false)
+        .exclusive(true)
+        .valid(true);
+    mapper.p1_entry_mut(page)?.set_entry(frame.as_allocated_frame(), backed_flags);
+    // The page table entry now exclusively owns this frame,
+    // and will deallocate it when it is unmapped.
+    core::mem::forget(frame);
+
+    // No TLB flush is needed, as entries that were not present are never cached in the TLB.
+    Ok(true)
+}
+
+/// Attempts to resolve a page fault caused by accessing the given `vaddr`
+/// in the kernel's address space, which may be an unbacked demand-paged page.
+///
+/// This is intended to be invoked by the page fault handler upon accessing a page that is not present.
+/// Returns `true` if the fault was resolved, in which case the faulting access should be retried.
+/// Returns `false` if `vaddr` is not demand-paged, i.e., it is a genuine page fault.
+pub fn handle_demand_paging_fault(vaddr: VirtualAddress) -> bool {
+    let Some(kernel_mmi_ref) = get_kernel_mmi_ref() else { return false };
+    // If another CPU holds the kernel's page table lock, simply wait for it.
+    // But the fault may have occurred while this CPU holds that lock, e.g., within the paging code itself,
+    // in which case we must not wait; instead, let the fault be reported as unhandled.
+    let Some(mut kernel_mmi) = kernel_mmi_ref.lock_unless_held_by_current_cpu() else {
+        error!("handle_demand_paging_fault(): kernel page table was locked by this CPU, cannot handle fault at {:#X}", vaddr);
+        return false;
+    };
+    match populate(&mut kernel_mmi.page_table, Page::containing_address(vaddr)) {
+        Ok(resolved) => resolved,
+        Err(e) => {
+            error!("handle_demand_paging_fault(): failed to back page at {:#X}: {}", vaddr, e);
+            false
+        }
+    }
+}
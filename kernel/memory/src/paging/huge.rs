@@ -0,0 +1,168 @@
+//! Support for huge pages, i.e., 2MiB and 1GiB pages that are each mapped
+//! by a single P2-level or P3-level page table entry, respectively.
+//!
+//! Huge pages are mapped by [`Mapper::map_allocated_pages_to_huge()`].
+//! An exclusively-mapped huge page owns all of the frames it covers,
+//! and deallocates them all at once when it is unmapped.
+//!
+//! A huge page can be split into smaller pages, which is done transparently
+//! when only part of it must be remapped with different flags or unmapped.
+//! A 1GiB page is split into 512 2MiB pages, and a 2MiB page into 512 4KiB pages.
+//!
+//! Huge pages are currently only supported on x86_64.
+
+use core::mem;
+use kernel_config::memory::{ENTRIES_PER_PAGE_TABLE, PAGE_SIZE};
+use pte_flags::PteFlagsArch;
+use crate::{BROADCAST_TLB_SHOOTDOWN_FUNC, FrameRange, Page, PageRange, tlb_flush_all};
+use super::{Mapper, PageTableEntry, tlb_flush_virt_addr, mapper::INTO_ALLOCATED_FRAMES_FUNC};
+
+/// The size of a huge page.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HugePageSize {
+    /// A 2MiB page, mapped by a single P2-level page table entry.
+    Size2MiB,
+    /// A 1GiB page, mapped by a single P3-level page table entry.
+    Size1GiB,
+}
+
+impl HugePageSize {
+    /// Returns the number of 4KiB pages (or frames) covered by a huge page of this size.
+    pub const fn size_in_pages(self) -> usize {
+        match self {
+            HugePageSize::Size2MiB => ENTRIES_PER_PAGE_TABLE,
+            HugePageSize::Size1GiB => ENTRIES_PER_PAGE_TABLE * ENTRIES_PER_PAGE_TABLE,
+        }
+    }
+
+    /// Returns the size in bytes of a huge page of this size.
+    pub const fn size_in_bytes(self) -> usize {
+        self.size_in_pages() * PAGE_SIZE
+    }
+
+    /// Returns the size of the huge pages that a huge page of this size is split into,
+    /// or `None` if it is split into normal 4KiB pages.
+    const fn split_size(self) -> Option<HugePageSize> {
+        match self {
+            HugePageSize::Size2MiB => None,
+            HugePageSize::Size1GiB => Some(HugePageSize::Size2MiB),
+        }
+    }
+}
+
+/// Returns the range of pages covered by the huge page of the given `size` that contains `page`.
+pub(super) fn huge_page_range(page: Page, size: HugePageSize) -> PageRange {
+    let start = page - (page.number() % size.size_in_pages());
+    PageRange::new(start, start + (size.size_in_pages() - 1))
+}
+
+/// Returns the flags for a page table entry that maps a huge page with the given `flags`.
+#[cfg(target_arch = "x86_64")]
+pub(super) fn huge_entry_flags(flags: PteFlagsArch) -> Result<PteFlagsArch, &'static str> {
+    // In a P1-level entry, the `HUGE_PAGE` bit is the most-significant bit of the PAT index.
+    if flags.get_pat_index() >= 4 {
+        return Err("PAT indices 4 through 7 are not supported for huge pages");
+    }
+    Ok(flags.huge(true))
+}
+
+/// Returns the flags for a page table entry that maps a huge page with the given `flags`.
+#[cfg(target_arch = "aarch64")]
+pub(super) fn huge_entry_flags(_flags: PteFlagsArch) -> Result<PteFlagsArch, &'static str> {
+    Err("huge pages (block descriptors) are not yet supported on aarch64")
+}
+
+/// Returns the flags for a lower-level entry that maps part of a split huge page
+/// that was mapped with the given `huge_flags`, which is itself a huge page if `is_huge` is `true`.
+#[cfg(target_arch = "x86_64")]
+fn split_entry_flags(huge_flags: PteFlagsArch, is_huge: bool) -> PteFlagsArch {
+    huge_flags.huge(is_huge)
+}
+
+#[cfg(target_arch = "aarch64")]
+fn split_entry_flags(huge_flags: PteFlagsArch, _is_huge: bool) -> PteFlagsArch {
+    huge_flags
+}
+
+/// Returns the page table entry that maps the huge page containing the given `page`, and its size,
+/// or `None` if `page` is not part of a huge page.
+#[cfg(target_arch = "x86_64")]
+pub(super) fn huge_entry_mut(mapper: &mut Mapper, page: Page) -> Option<(&mut PageTableEntry, HugePageSize)> {
+    let is_huge = |entry: &PageTableEntry| entry.flags().is_valid() && entry.flags().is_huge();
+
+    let p3 = mapper.p4_mut().next_table_mut(page.p4_index())?;
+    if is_huge(&p3[page.p3_index()]) {
+        return Some((&mut p3[page.p3_index()], HugePageSize::Size1GiB));
+    }
+    let p2 = p3.next_table_mut(page.p3_index())?;
+    if is_huge(&p2[page.p2_index()]) {
+        return Some((&mut p2[page.p2_index()], HugePageSize::Size2MiB));
+    }
+    None
+}
+
+/// Returns the page table entry that maps the huge page containing the given `page`, and its size,
+/// or `None` if `page` is not part of a huge page.
+#[cfg(target_arch = "aarch64")]
+pub(super) fn huge_entry_mut(_mapper: &mut Mapper, _page: Page) -> Option<(&mut PageTableEntry, HugePageSize)> {
+    None
+}
+
+/// Splits the huge page that contains the given `page` into 512 smaller pages,
+/// which are mapped to the same frames with the same flags.
+///
+/// The smaller pages take over ownership of the huge page's frames.
+/// Returns an error if `page` is not part of a huge page,
+/// or if that huge page doesn't own its frames.
+pub(super) fn split_huge_page(mapper: &mut Mapper, page: Page) -> Result<(), &'static str> {
+    let (entry, size) = huge_entry_mut(mapper, page)
+        .ok_or("split_huge_page(): page was not part of a huge page")?;
+    let huge_flags = entry.flags();
+    let first_frame = entry.pointed_frame()
+        .ok_or("BUG: split_huge_page(): huge page was not mapped")?;
+    if !huge_flags.is_exclusive() {
+        return Err("split_huge_page(): cannot split a huge page that doesn't own its frames");
+    }
+    let into_func = INTO_ALLOCATED_FRAMES_FUNC.get()
+        .ok_or("BUG: split_huge_page(): the `INTO_ALLOCATED_FRAMES_FUNC` callback was not initialized")?;
+
+    let split_size = size.split_size();
+    let frames_per_entry = split_size.map_or(1, HugePageSize::size_in_pages);
+    let entry_flags = split_entry_flags(huge_flags, split_size.is_some());
+    let table_flags = split_entry_flags(huge_flags, false)
+        .adjust_for_higher_level_pte()
+        .writable(true); // must be writable on x86_64, like all other page table frames
+
+    // Fully populate the new lower-level table before replacing the huge page,
+    // such that other CPUs never observe a partially-populated table.
+    let new_table = mapper.allocate_initialized_frame(|contents| {
+        // SAFETY: a page table consists of exactly one page-aligned page of entries,
+        //         all of which are initialized below.
+        let entries = unsafe {
+            &mut *(contents as *mut [u8; PAGE_SIZE] as *mut [PageTableEntry; ENTRIES_PER_PAGE_TABLE])
+        };
+        for (i, entry) in entries.iter_mut().enumerate() {
+            let frame = first_frame + i * frames_per_entry;
+            let frame = into_func(FrameRange::new(frame, frame));
+            entry.set_entry(frame.as_allocated_frame(), entry_flags);
+            // This entry now owns all of the frames it covers, just like the huge page did.
+            mem::forget(frame);
+        }
+    })?;
+
+    let (entry, _) = huge_entry_mut(mapper, page)
+        .ok_or("BUG: split_huge_page(): huge page was unmapped while being split")?;
+    entry.set_entry(new_table.as_allocated_frame(), table_flags);
+    // We currently forget frames allocated as page table frames since we don't yet have a way to track them.
+    mem::forget(new_table);
+
+    let huge_range = huge_page_range(page, size);
+    match size {
+        HugePageSize::Size1GiB => tlb_flush_all(),
+        HugePageSize::Size2MiB => tlb_flush_virt_addr(huge_range.start().start_address()),
+    }
+    if let Some(func) = BROADCAST_TLB_SHOOTDOWN_FUNC.get() {
+        func(huge_range);
+    }
+    Ok(())
+}
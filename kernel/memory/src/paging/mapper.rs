@@ -22,6 +22,10 @@ use crate::{BROADCAST_TLB_SHOOTDOWN_FUNC, VirtualAddress, PhysicalAddress, Page,
 use crate::paging::{
     get_current_p4,
     PageRange,
+    PageTableEntry,
+    cow,
+    demand,
+    huge::{self, HugePageSize},
     table::{P4, UPCOMING_P4, Table, Level4},
 };
 use pte_flags::PteFlagsArch;
@@ -105,6 +109,43 @@ impl Mapper {
         unsafe { self.p4.as_mut() }
     }
 
+    /// Returns the P1 page table entry for the given `page`.
+    ///
+    /// Returns an error if any of the higher-level page tables for that `page` do not exist,
+    /// or if `page` is part of a huge page, which has no P1 entry.
+    pub(super) fn p1_entry_mut(&mut self, page: Page) -> Result<&mut PageTableEntry, &'static str> {
+        self.p4_mut()
+            .next_table_mut(page.p4_index())
+            .and_then(|p3| p3.next_table_mut(page.p3_index()))
+            .and_then(|p2| p2.next_table_mut(page.p2_index()))
+            .map(|p1| &mut p1[page.p1_index()])
+            .ok_or("page was not mapped by a P1 page table entry")
+    }
+
+    /// Allocates a new frame and initializes its contents using the given `init` function,
+    /// which is given access to the frame through a temporary mapping.
+    ///
+    /// This allows the frame to be fully initialized before it is mapped at its final page,
+    /// such that other CPUs accessing that page can never observe partially-initialized contents.
+    pub(super) fn allocate_initialized_frame(
+        &mut self,
+        init: impl FnOnce(&mut [u8; PAGE_SIZE]),
+    ) -> Result<AllocatedFrames, &'static str> {
+        let frame = frame_allocator::allocate_frames(1)
+            .ok_or("allocate_initialized_frame(): couldn't allocate new frame, out of memory")?;
+        let temp_pages = crate::allocate_pages(1)
+            .ok_or("allocate_initialized_frame(): couldn't allocate temporary page, out of virtual address space")?;
+        let mut temp_mapping = self.map_allocated_pages_to(
+            temp_pages,
+            frame,
+            PteFlagsArch::new().valid(true).writable(true),
+        )?;
+        init(temp_mapping.as_type_mut(0)?);
+        let (_temp_pages, frame) = temp_mapping.unmap_into_parts(self)
+            .map_err(|_| "BUG: allocate_initialized_frame(): couldn't unmap temporary page")?;
+        frame.ok_or("BUG: allocate_initialized_frame(): temporary page had no frame")
+    }
+
     /// Dumps all page table entries at all four page table levels for the given `VirtualAddress`, 
     /// and also shows their `PteFlags`.
     /// 
@@ -206,9 +247,11 @@ impl Mapper {
 
         // Only the lowest-level P1 entry can be considered exclusive, and only when
         // we are mapping it exclusively (i.e., owned `AllocatedFrames` are passed in).
+        // New mappings are never copy-on-write; see `MappedPages::share_copy_on_write()`.
         let actual_flags = flags
             .valid(true)
-            .exclusive(Frames::OWNED);
+            .exclusive(Frames::OWNED)
+            .copy_on_write(false);
 
         let pages_count = pages.size_in_pages();
         let frames_count = frames.borrow().size_in_frames();
@@ -278,9 +321,11 @@ impl Mapper {
 
         // Only the lowest-level P1 entry can be considered exclusive, and only because
         // we are mapping it exclusively (to owned `AllocatedFrames`).
+        // New mappings are never copy-on-write; see `MappedPages::share_copy_on_write()`.
         let actual_flags = flags
             .valid(true)
-            .exclusive(true);
+            .exclusive(true)
+            .copy_on_write(false);
 
         for page in pages.deref().clone() {
             let af = frame_allocator::allocate_frames(1).ok_or("map_allocated_pages(): couldn't allocate new frame, out of memory")?;
@@ -306,6 +351,146 @@ impl Mapper {
             flags: actual_flags,
         })
     }
+
+    /// Reserves the given `AllocatedPages` as a demand-paged mapping, without backing them with any frames.
+    ///
+    /// Each page is backed by a newly-allocated, zeroed frame upon its first access,
+    /// which causes a page fault that is resolved by [`handle_demand_paging_fault()`].
+    /// Pages can also be backed ahead of time using [`MappedPages::populate()`].
+    /// This avoids eagerly allocating frames for large regions that may be only sparsely used,
+    /// e.g., stacks, heaps, and TLS areas.
+    ///
+    /// Consumes the given `AllocatedPages` and returns a `MappedPages` object which contains those `AllocatedPages`.
+    /// The flags of the returned `MappedPages` are those that each page is mapped with once it is backed.
+    ///
+    /// # Locking / Deadlock
+    /// Demand paging faults are resolved using the kernel's page table,
+    /// so these pages must not be accessed while its lock is held, unless they have been populated.
+    ///
+    /// Demand paging faults are currently only handled on x86_64, so this always returns an error on other architectures.
+    ///
+    /// [`handle_demand_paging_fault()`]: crate::handle_demand_paging_fault
+    pub fn map_allocated_pages_on_demand<F: Into<PteFlagsArch>>(
+        &mut self,
+        pages: AllocatedPages,
+        flags: F,
+    ) -> Result<MappedPages, &'static str> {
+        if cfg!(not(target_arch = "x86_64")) {
+            return Err("Mapper::map_allocated_pages_on_demand(): demand paging faults are only handled on x86_64");
+        }
+        let flags = flags.into();
+        let higher_level_flags = flags.adjust_for_higher_level_pte();
+
+        // These are the flags that each page will be mapped with once it is backed by an owned frame.
+        let actual_flags = flags
+            .valid(true)
+            .exclusive(true)
+            .copy_on_write(false)
+            .on_demand(false);
+        let unbacked_flags = demand::unbacked_flags(actual_flags);
+
+        for page in pages.deref().clone() {
+            let p3 = self.p4_mut().next_table_create(page.p4_index(), higher_level_flags);
+            let p2 = p3.next_table_create(page.p3_index(), higher_level_flags);
+            let p1 = p2.next_table_create(page.p2_index(), higher_level_flags);
+
+            if !p1[page.p1_index()].is_unused() {
+                error!("map_allocated_pages_on_demand(): page {:#X} was already in use!", page.start_address());
+                return Err("map_allocated_pages_on_demand(): page was already in use");
+            }
+
+            p1[page.p1_index()].set_flags(unbacked_flags);
+        }
+
+        Ok(MappedPages {
+            page_table_p4: self.target_p4,
+            pages,
+            flags: actual_flags,
+        })
+    }
+
+    /// Maps the given `AllocatedPages` to the given `AllocatedFrames` using huge pages of the given `size`.
+    ///
+    /// Each huge page is mapped by a single P2-level (2MiB) or P3-level (1GiB) page table entry,
+    /// which reduces TLB pressure and page table overhead for large mappings.
+    /// Both `pages` and `frames` must be aligned to and contain a non-zero multiple of the huge page `size`.
+    /// Consider using [`allocate_pages_aligned()`] and [`allocate_frames_aligned()`] to obtain them.
+    ///
+    /// Consumes the given `AllocatedPages` and `AllocatedFrames`, and returns a `MappedPages` object
+    /// which contains those `AllocatedPages`. A huge page is transparently split into smaller pages
+    /// if only part of it is later remapped or unmapped.
+    ///
+    /// Huge pages are currently only supported on x86_64.
+    ///
+    /// [`allocate_pages_aligned()`]: crate::allocate_pages_aligned
+    /// [`allocate_frames_aligned()`]: crate::allocate_frames_aligned
+    pub fn map_allocated_pages_to_huge<F: Into<PteFlagsArch>>(
+        &mut self,
+        pages: AllocatedPages,
+        frames: AllocatedFrames,
+        flags: F,
+        size: HugePageSize,
+    ) -> Result<MappedPages, &'static str> {
+        let pages_per_huge_page = size.size_in_pages();
+        if pages.size_in_pages() != frames.size_in_frames() {
+            error!("map_allocated_pages_to_huge(): pages {:?} count {} must equal frames {:?} count {}!",
+                pages, pages.size_in_pages(), frames, frames.size_in_frames()
+            );
+            return Err("map_allocated_pages_to_huge(): page count must equal frame count");
+        }
+        if pages.size_in_pages() == 0 || pages.size_in_pages() % pages_per_huge_page != 0 {
+            return Err("map_allocated_pages_to_huge(): size must be a non-zero multiple of the huge page size");
+        }
+        if pages.start().number() % pages_per_huge_page != 0 || frames.start().number() % pages_per_huge_page != 0 {
+            return Err("map_allocated_pages_to_huge(): pages and frames must be aligned to the huge page size");
+        }
+
+        let flags = flags.into();
+        let higher_level_flags = flags.adjust_for_higher_level_pte();
+        let actual_flags = flags
+            .valid(true)
+            .exclusive(true)
+            .copy_on_write(false)
+            .on_demand(false);
+        let huge_flags = huge::huge_entry_flags(actual_flags)?;
+
+        let mut remaining_frames = frames;
+        for page in pages.deref().clone().into_iter().step_by(pages_per_huge_page) {
+            let p3 = self.p4_mut().next_table_create(page.p4_index(), higher_level_flags);
+            let entry = match size {
+                HugePageSize::Size1GiB => &mut p3[page.p3_index()],
+                HugePageSize::Size2MiB => {
+                    let p2 = p3.next_table_create(page.p3_index(), higher_level_flags);
+                    &mut p2[page.p2_index()]
+                }
+            };
+            if !entry.is_unused() {
+                error!("map_allocated_pages_to_huge(): page {:#X} -> frame {:#X}, page was already in use!",
+                    page.start_address(), remaining_frames.start_address()
+                );
+                return Err("map_allocated_pages_to_huge(): page was already in use");
+            }
+
+            let block_end = *remaining_frames.start() + pages_per_huge_page;
+            let (block, rest) = remaining_frames.split(block_end)
+                .map_err(|_| "BUG: map_allocated_pages_to_huge(): failed to split frames into huge pages")?;
+            let first_frame = *block.start() + 1;
+            let (first, others) = block.split(first_frame)
+                .map_err(|_| "BUG: map_allocated_pages_to_huge(): failed to split frames into huge pages")?;
+            entry.set_entry(first.as_allocated_frame(), huge_flags);
+            // The huge page table entry now exclusively owns all of the frames it covers,
+            // and will deallocate them when it is unmapped.
+            core::mem::forget(first);
+            core::mem::forget(others);
+            remaining_frames = rest;
+        }
+
+        Ok(MappedPages {
+            page_table_p4: self.target_p4,
+            pages,
+            flags: actual_flags,
+        })
+    }
 }
 
 // This implementation block contains a hacky function for non-bijective mappings 
@@ -387,6 +572,8 @@ impl MappedPages {
     /// * `mp`, with a page range including two pages at 0x3000 and 0x4000
     /// Then this `MappedPages` object will be updated to cover three pages from `[0x2000:0x4000]` inclusive.
     /// 
+    /// This is the inverse of [`MappedPages::split()`].
+    /// 
     /// In addition, the `MappedPages` objects must have the same flags and page table root frame
     /// (i.e., they must have all been mapped using the same set of page tables).
     /// 
@@ -436,6 +623,10 @@ impl MappedPages {
     /// 
     /// Returns an `Err` containing this `MappedPages` (`self`) if `at_page` is not within its bounds.
     /// 
+    /// The two returned `MappedPages` are independent: either one can be remapped with different flags
+    /// or unmapped (dropped) without affecting the other.
+    /// As long as they still have the same flags, they can be re-joined via [`MappedPages::merge()`].
+    /// 
     /// # Note
     /// No remapping actions or page reallocations will occur on either a failure or a success.
     /// 
@@ -486,6 +677,10 @@ impl MappedPages {
         warn!("MappedPages::deep_copy() has not been adequately tested yet.");
         let size_in_pages = self.size_in_pages();
 
+        // Back any demand-paged pages first, as a page fault while holding the
+        // `active_table_mapper` cannot be resolved.
+        self.populate(active_table_mapper)?;
+
         use crate::paging::allocate_pages;
         let new_pages = allocate_pages(size_in_pages).ok_or("Couldn't allocate_pages()")?;
 
@@ -513,12 +708,125 @@ impl MappedPages {
         Ok(new_mapped_pages)
     }
 
+    /// Backs all pages of this `MappedPages` that are demand-paged but not yet backed,
+    /// as if each of them had been accessed.
+    ///
+    /// This has no effect on pages that are already backed, or on mappings that are not demand-paged.
+    /// See [`Mapper::map_allocated_pages_on_demand()`].
+    pub fn populate(&self, active_table_mapper: &mut Mapper) -> Result<(), &'static str> {
+        if active_table_mapper.target_p4 != self.page_table_p4 {
+            return Err("MappedPages::populate(): current P4 must equal original P4");
+        }
+        for page in self.pages.clone() {
+            if !demand::populate(active_table_mapper, page)? {
+                return Err("MappedPages::populate(): page was not mapped");
+            }
+        }
+        Ok(())
+    }
+
+    /// Creates a copy-on-write copy of this `MappedPages` memory region at the given `new_pages`.
+    ///
+    /// Unlike [`MappedPages::deep_copy()`], no memory is copied up front.
+    /// Instead, the `new_pages` are mapped to the same frames as this `MappedPages`,
+    /// and all pages of both mappings are made read-only and marked as copy-on-write.
+    /// The first write to any of those pages, whether through this `MappedPages` or the new one,
+    /// causes a page fault that gives only that page its own private copy of its frame.
+    /// Thus, the contents of the two mappings appear to be independent of one another.
+    ///
+    /// This `MappedPages` can be shared multiple times, and the new `MappedPages` can be shared further.
+    /// The shared frames are deallocated once no more pages map them.
+    ///
+    /// The returned `MappedPages` has the same flags as this `MappedPages`, which must be writable.
+    /// These flags describe the logical permissions of the mapping, which take effect once its pages are copied.
+    ///
+    /// Copy-on-write faults are resolved using the kernel's page table,
+    /// so the pages of either mapping must not be written to while its lock is held.
+    ///
+    /// Returns an error if the `new_pages` are not the same size as this `MappedPages`,
+    /// if this `MappedPages` was not mapped using the given `active_table_mapper`,
+    /// or if it maps frames that it does not own, e.g., memory-mapped I/O regions.
+    /// Copy-on-write faults are currently only handled on x86_64, so this always returns an error on other architectures.
+    pub fn share_copy_on_write(
+        &mut self,
+        active_table_mapper: &mut Mapper,
+        new_pages: AllocatedPages,
+    ) -> Result<MappedPages, &'static str> {
+        if cfg!(not(target_arch = "x86_64")) {
+            return Err("MappedPages::share_copy_on_write(): copy-on-write faults are only handled on x86_64");
+        }
+        if active_table_mapper.target_p4 != self.page_table_p4 {
+            return Err("MappedPages::share_copy_on_write(): current P4 must equal original P4");
+        }
+        if new_pages.size_in_pages() != self.size_in_pages() {
+            return Err("MappedPages::share_copy_on_write(): new pages must be the same size as this MappedPages");
+        }
+        if !self.flags.is_writable() {
+            return Err("MappedPages::share_copy_on_write(): only writable mappings can be shared as copy-on-write");
+        }
+
+        // Unbacked demand-paged pages have no frame to share yet, so back them first.
+        self.populate(active_table_mapper)?;
+
+        // Ensure that every page is either exclusively mapped or already copy-on-write
+        // before changing any of them, such that an error leaves this mapping unmodified.
+        for page in self.pages.clone() {
+            let flags = active_table_mapper.p1_entry_mut(page)?.flags();
+            if !flags.is_exclusive() && !flags.is_copy_on_write() {
+                return Err("MappedPages::share_copy_on_write(): cannot share frames not owned by this MappedPages");
+            }
+        }
+
+        let shared_flags = self.flags
+            .writable(false)
+            .exclusive(false)
+            .copy_on_write(true)
+            .valid(true);
+        let higher_level_flags = self.flags.adjust_for_higher_level_pte();
+
+        for (page, new_page) in self.pages.clone().into_iter().zip(new_pages.deref().clone()) {
+            let pte = active_table_mapper.p1_entry_mut(page)?;
+            let frame = pte.pointed_frame().ok_or("MappedPages::share_copy_on_write(): page was not mapped")?;
+            let owned_by_pte = pte.flags().is_exclusive();
+            pte.set_flags(shared_flags);
+            tlb_flush_virt_addr(page.start_address());
+
+            cow::add_mapping(frame, owned_by_pte, |shared_frame| {
+                let p3 = active_table_mapper.p4_mut().next_table_create(new_page.p4_index(), higher_level_flags);
+                let p2 = p3.next_table_create(new_page.p3_index(), higher_level_flags);
+                let p1 = p2.next_table_create(new_page.p2_index(), higher_level_flags);
+
+                if !p1[new_page.p1_index()].is_unused() {
+                    error!("share_copy_on_write(): page {:#X} -> frame {:#X}, page was already in use!",
+                        new_page.start_address(), frame.start_address()
+                    );
+                    return Err("MappedPages::share_copy_on_write(): page was already in use");
+                }
+                p1[new_page.p1_index()].set_entry(shared_frame.as_allocated_frame(), shared_flags);
+                Ok(())
+            })?;
+        }
+
+        if let Some(func) = BROADCAST_TLB_SHOOTDOWN_FUNC.get() {
+            func(self.pages.deref().clone());
+        }
+
+        Ok(MappedPages {
+            page_table_p4: self.page_table_p4,
+            pages: new_pages,
+            flags: self.flags,
+        })
+    }
+
     
     /// Change the mapping flags of this `MappedPages`'s page table entries.
     ///
     /// Note that attempting to change certain "reserved" flags will have no effect. 
     /// For example, the `EXCLUSIVE` flag cannot be changed beause arbitrarily setting it
     /// would violate safety.
+    ///
+    /// Any pages of this mapping that are still copy-on-write are given their own private frames
+    /// before their flags are changed, as if they had been written to.
     pub fn remap<F: Into<PteFlagsArch>>(
         &mut self,
         active_table_mapper: &mut Mapper,
@@ -530,6 +838,8 @@ impl MappedPages {
         // Also ensure these flags are PRESENT (valid), since they are currently being mapped.
         let new_flags = new_flags.into()
             .exclusive(self.flags.is_exclusive())
+            .copy_on_write(false)
+            .on_demand(false)
             .valid(true);
 
         if new_flags == self.flags {
@@ -537,25 +847,59 @@ impl MappedPages {
             return Ok(());
         }
 
-        for page in self.pages.clone() {
-            let p1 = active_table_mapper.p4_mut()
-                .next_table_mut(page.p4_index())
-                .and_then(|p3| p3.next_table_mut(page.p3_index()))
-                .and_then(|p2| p2.next_table_mut(page.p2_index()))
-                .ok_or("mapping code does not support huge pages")?;
-            
-            p1[page.p1_index()].set_flags(new_flags);
-
-            tlb_flush_virt_addr(page.start_address());
-        }
-        
+        let (remapped, result) = remap_pages(active_table_mapper, self.pages.deref(), new_flags);
+        // Even if remapping failed, other CPUs must not keep using the old flags of the pages already remapped.
         if let Some(func) = BROADCAST_TLB_SHOOTDOWN_FUNC.get() {
-            func(self.pages.deref().clone());
+            func(remapped);
         }
+        result?;
 
         self.flags = new_flags;
         Ok(())
     }   
+
+    /// Changes the mapping flags of only the given `range` of pages within this `MappedPages`,
+    /// without splitting it into separate `MappedPages` objects.
+    ///
+    /// This is useful for giving a subrange of a mapping different permissions,
+    /// e.g., making the initial data template portion of a TLS image read-only.
+    /// The TLB entries for the given `range` are flushed on this CPU and shot down on all other CPUs.
+    ///
+    /// As with [`MappedPages::remap()`], the `EXCLUSIVE` flag cannot be changed,
+    /// the pages always remain mapped (valid), and copy-on-write pages are given their own private frames.
+    ///
+    /// Returns an error if the `range` is not fully contained within this `MappedPages`.
+    ///
+    /// # Note
+    /// The flags returned by [`MappedPages::flags()`] and checked by the mutable accessors
+    /// (e.g., [`MappedPages::as_slice_mut()`]) remain those of the whole mapping,
+    /// so writing to a subrange that was remapped as read-only will cause a page fault.
+    /// Also, [`MappedPages::merge()`] does not account for remapped subranges,
+    /// so a subrange should be remapped back to the mapping's flags before merging.
+    pub fn remap_range<F: Into<PteFlagsArch>>(
+        &mut self,
+        active_table_mapper: &mut Mapper,
+        range: PageRange,
+        new_flags: F,
+    ) -> Result<(), &'static str> {
+        if range.size_in_pages() == 0 { return Ok(()); }
+        if range.overlap(self.pages.deref()) != Some(range.clone()) {
+            return Err("MappedPages::remap_range(): range was not within the bounds of this MappedPages");
+        }
+
+        let new_flags = new_flags.into()
+            .exclusive(self.flags.is_exclusive())
+            .copy_on_write(false)
+            .on_demand(false)
+            .valid(true);
+
+        let (remapped, result) = remap_pages(active_table_mapper, &range, new_flags);
+        // Even if remapping failed, other CPUs must not keep using the old flags of the pages already remapped.
+        if let Some(func) = BROADCAST_TLB_SHOOTDOWN_FUNC.get() {
+            func(remapped);
+        }
+        result
+    }
     
     /// Consumes and unmaps this `MappedPages` object without auto-deallocating its `AllocatedPages` and `AllocatedFrames`,
     /// allowing the caller to continue using them directly, e.g., reusing them for a future mapping. 
@@ -613,19 +957,38 @@ impl MappedPages {
         let mut first_frame_range: Option<AllocatedFrames> = None; // this is what we'll return
         let mut current_frame_range: Option<AllocatedFrames> = None;
 
+        // The last page of the most recently unmapped huge page, whose other pages must be skipped.
+        let mut huge_page_end: Option<Page> = None;
+
         for page in self.pages.clone() {            
-            let p1 = active_table_mapper.p4_mut()
-                .next_table_mut(page.p4_index())
-                .and_then(|p3| p3.next_table_mut(page.p3_index()))
-                .and_then(|p2| p2.next_table_mut(page.p2_index()))
-                .ok_or("mapping code does not support huge pages")?;
-            let pte = &mut p1[page.p1_index()];
-            if pte.is_unused() {
-                return Err("unmap(): page not mapped");
+            if matches!(huge_page_end, Some(end) if page <= end) {
+                continue;
             }
 
-            let unmapped_frames = pte.set_unmapped();
-            tlb_flush_virt_addr(page.start_address());
+            let (unmapped_frames, was_copy_on_write) = loop {
+                if let Some((entry, size)) = huge::huge_entry_mut(active_table_mapper, page) {
+                    let huge_range = huge::huge_page_range(page, size);
+                    if huge_range.overlap(self.pages.deref()) == Some(huge_range.clone()) {
+                        let unmapped_frames = entry.set_unmapped_huge(size.size_in_pages());
+                        tlb_flush_virt_addr(huge_range.start().start_address());
+                        huge_page_end = Some(*huge_range.end());
+                        break (unmapped_frames, false);
+                    }
+                    // Only part of this huge page belongs to this mapping,
+                    // so split it such that just that part can be unmapped.
+                    huge::split_huge_page(active_table_mapper, page)?;
+                    continue;
+                }
+
+                let pte = active_table_mapper.p1_entry_mut(page)?;
+                if pte.is_unused() {
+                    return Err("unmap(): page not mapped");
+                }
+                let was_copy_on_write = pte.flags().is_copy_on_write();
+                let unmapped_frames = pte.set_unmapped();
+                tlb_flush_virt_addr(page.start_address());
+                break (unmapped_frames, was_copy_on_write);
+            };
 
             // Here, create (or extend) a contiguous ranges of frames here based on the `unmapped_frames`
             // freed from the newly-unmapped P1 PTE entry above.
@@ -667,6 +1030,11 @@ impl MappedPages {
                         current_frame_range = Some(newly_unmapped_frames);
                     }
                 }
+                UnmapResult::NonExclusive(frames) if was_copy_on_write => {
+                    // The shared frame is owned by the copy-on-write registry, not by this PTE,
+                    // so it is only deallocated once its last copy-on-write mapping is removed.
+                    cow::remove_mapping(*frames.start());
+                }
                 UnmapResult::NonExclusive(_frames) => {
                     // trace!("Note: FYI: page {:X?} -> frames {:X?} was just unmapped but not mapped as EXCLUSIVE.", page, _frames);
                 }
@@ -958,6 +1326,58 @@ impl MappedPages {
     }
 }
 
+/// Changes the flags of every page in the given `range` to `new_flags` via [`remap_page()`],
+/// stopping at the first page that fails.
+///
+/// Returns the range of pages whose entries may have changed, which must be shot down on other CPUs
+/// even if an error occurred, along with the result of remapping the whole `range`.
+fn remap_pages(mapper: &mut Mapper, range: &PageRange, new_flags: PteFlagsArch) -> (PageRange, Result<(), &'static str>) {
+    let mut last_remapped: Option<Page> = None;
+    for page in range.clone() {
+        if matches!(last_remapped, Some(last) if page <= last) { continue; }
+        match remap_page(mapper, page, range, new_flags) {
+            Ok(last) => last_remapped = Some(last),
+            // The failed page may have been changed partially, e.g., if its huge page was split.
+            Err(e) => return (PageRange::new(*range.start(), page), Err(e)),
+        }
+    }
+    (range.clone(), Ok(()))
+}
+
+/// Changes the flags of the given mapped `page` to `new_flags`, and flushes its TLB entry on this CPU.
+///
+/// A copy-on-write page is first given its own private frame,
+/// otherwise the new flags could make its shared frame writable.
+/// An unbacked demand-paged page remains unbacked, and will be mapped with `new_flags` once it is backed.
+///
+/// If `page` is part of a huge page that lies entirely within the given `range` being remapped,
+/// the whole huge page is remapped at once; otherwise, that huge page is first split.
+/// Returns the last page that was remapped.
+fn remap_page(mapper: &mut Mapper, page: Page, range: &PageRange, new_flags: PteFlagsArch) -> Result<Page, &'static str> {
+    while let Some((entry, size)) = huge::huge_entry_mut(mapper, page) {
+        let huge_range = huge::huge_page_range(page, size);
+        if huge_range.overlap(range) == Some(huge_range.clone()) {
+            entry.set_flags(huge::huge_entry_flags(new_flags)?);
+            tlb_flush_virt_addr(huge_range.start().start_address());
+            return Ok(*huge_range.end());
+        }
+        huge::split_huge_page(mapper, page)?;
+    }
+
+    let old_flags = mapper.p1_entry_mut(page)?.flags();
+    if old_flags.is_copy_on_write() {
+        cow::make_private(mapper, page)?;
+    }
+    let new_flags = if old_flags.is_on_demand() && !old_flags.is_valid() {
+        demand::unbacked_flags(new_flags)
+    } else {
+        new_flags
+    };
+    mapper.p1_entry_mut(page)?.set_flags(new_flags);
+    tlb_flush_virt_addr(page.start_address());
+    Ok(page)
+}
+
 impl Drop for MappedPages {
     fn drop(&mut self) {
         // if self.size_in_pages() > 0 {
@@ -10,6 +10,9 @@
 mod temporary_page;
 mod mapper;
 mod table;
+mod cow;
+mod demand;
+mod huge;
 
 pub use page_table_entry::PageTableEntry;
 
@@ -21,6 +24,9 @@ pub use self::{
         Mapper, MappedPages, BorrowedMappedPages, BorrowedSliceMappedPages,
         Mutability, Mutable, Immutable, translate,
     },
+    cow::{handle_copy_on_write_fault, num_shared_frames, shared_frame_mappings},
+    demand::handle_demand_paging_fault,
+    huge::HugePageSize,
 };
 
 use core::{
@@ -0,0 +1,248 @@
+//! Attributes memory usage to the tasks and crates that own it.
+//!
+//! Each task is charged for its kernel stack, its TLS area, and the heap memory it has allocated,
+//! as counted by its profiling counters (see the `tls_counters` crate).
+//! Each crate is charged for the pages that hold its sections,
+//! plus the size of its TLS sections, which are part of every task's TLS area.
+//! Each tmpfs instance is charged for the pages that hold its files, up to its size limit.
+//!
+//! Heap memory can also be attributed to individual allocations by enabling the heap profiler
+//! via [`enable_heap_profiling()`], which records the task or application crate
+//! that made each allocation, such that memory leaked by a task or crate can be pinpointed.
+//!
+//! These reports make it possible to detect regressions in memory usage,
+//! e.g., a newly-loaded crate that greatly increases the size of every task's TLS area.
+
+#![no_std]
+
+extern crate alloc;
+
+use alloc::{string::{String, ToString}, sync::Arc, vec::Vec};
+use core::ops::Range;
+use memory::VirtualAddress;
+use mod_mgmt::{CrateNamespace, LoadedCrate};
+use task::{Task, TaskRef};
+use tls_counters::Counter;
+
+/// The memory attributed to a single task.
+#[derive(Debug, Clone)]
+pub struct TaskMemoryUsage {
+    /// The ID of the task.
+    pub id: usize,
+    /// The name of the task.
+    pub name: String,
+    /// The size in bytes of the task's kernel stack.
+    pub kstack_bytes: usize,
+    /// The size in bytes of the task's TLS area.
+    pub tls_bytes: usize,
+    /// The total number of bytes the task has allocated from the heap.
+    pub heap_bytes_allocated: u64,
+    /// The total number of bytes the task has deallocated back to the heap.
+    pub heap_bytes_freed: u64,
+}
+
+impl TaskMemoryUsage {
+    /// Returns the number of heap bytes allocated but not yet deallocated by this task.
+    ///
+    /// Heap memory is charged to the task that deallocates it, not the one that allocated it,
+    /// so this is only an estimate for tasks that pass ownership of allocations to other tasks.
+    pub fn heap_bytes_live(&self) -> u64 {
+        self.heap_bytes_allocated.saturating_sub(self.heap_bytes_freed)
+    }
+
+    /// Returns the total number of bytes attributed to this task.
+    pub fn total_bytes(&self) -> u64 {
+        (self.kstack_bytes + self.tls_bytes) as u64 + self.heap_bytes_live()
+    }
+}
+
+/// Returns the memory currently attributed to the given `task`.
+///
+/// The heap usage is read from the task's profiling counters without synchronization,
+/// so it is a best-effort snapshot for running tasks.
+pub fn task_usage(task: &Task) -> TaskMemoryUsage {
+    let kstack_bytes = task.with_kstack(|kstack|
+        kstack.top_unusable().value() - kstack.bottom().value()
+    );
+    let counters = task.tls_counters();
+    TaskMemoryUsage {
+        id: task.id,
+        name: task.name.clone(),
+        kstack_bytes,
+        tls_bytes: task.tls_area_size(),
+        heap_bytes_allocated: counters.get(Counter::HeapBytesAllocated),
+        heap_bytes_freed: counters.get(Counter::HeapBytesFreed),
+    }
+}
+
+/// Returns the memory currently attributed to each task in the system, ordered by task ID.
+pub fn all_tasks_usage() -> Vec<TaskMemoryUsage> {
+    // Collect the tasks first, such that the task list isn't locked while inspecting each task.
+    let tasks: Vec<TaskRef> = task::TASKLIST.lock().values().cloned().collect();
+    tasks.iter().map(|taskref| task_usage(taskref)).collect()
+}
+
+/// The memory attributed to a single crate.
+#[derive(Debug, Clone)]
+pub struct CrateMemoryUsage {
+    /// The name of the crate.
+    pub name: String,
+    /// The size in bytes of the pages that hold the crate's executable sections.
+    pub text_bytes: usize,
+    /// The size in bytes of the pages that hold the crate's read-only sections.
+    pub rodata_bytes: usize,
+    /// The size in bytes of the pages that hold the crate's `.data` and `.bss` sections.
+    pub data_bytes: usize,
+    /// The size in bytes of the crate's TLS sections,
+    /// which is added to the size of every task's TLS area.
+    pub tls_bytes_per_task: usize,
+}
+
+impl CrateMemoryUsage {
+    /// Returns the total number of bytes of the pages that hold this crate's sections.
+    pub fn mapped_bytes(&self) -> usize {
+        self.text_bytes + self.rodata_bytes + self.data_bytes
+    }
+}
+
+/// Returns the memory currently attributed to the given crate.
+pub fn crate_usage(krate: &LoadedCrate) -> CrateMemoryUsage {
+    fn size_of<T>(pages: &Option<(T, Range<VirtualAddress>)>) -> usize {
+        pages.as_ref().map_or(0, |(_, range)| range.end.value() - range.start.value())
+    }
+
+    CrateMemoryUsage {
+        name: krate.crate_name.to_string(),
+        text_bytes: size_of(&krate.text_pages),
+        rodata_bytes: size_of(&krate.rodata_pages),
+        data_bytes: size_of(&krate.data_pages),
+        tls_bytes_per_task: krate.tls_sections_iter().map(|sec| sec.size).sum(),
+    }
+}
+
+/// Returns the memory currently attributed to each crate in the given `namespace`, ordered by crate name.
+///
+/// If `recursive` is true, crates in the namespace's recursive namespaces are included as well.
+pub fn all_crates_usage(namespace: &CrateNamespace, recursive: bool) -> Vec<CrateMemoryUsage> {
+    let mut usage = Vec::new();
+    namespace.for_each_crate(recursive, |_crate_name, crate_ref| {
+        usage.push(crate_usage(&crate_ref.lock_as_ref()));
+        true
+    });
+    usage.sort_by(|a, b| a.name.cmp(&b.name));
+    usage
+}
+
+/// The memory attributed to a single tmpfs instance.
+#[derive(Debug, Clone)]
+pub struct TmpfsMemoryUsage {
+    /// The name of the instance's root directory when it was mounted.
+    pub name: String,
+    /// The size in bytes of the pages that hold the instance's files.
+    pub used_bytes: usize,
+    /// The maximum size in bytes of the pages that the instance's files may occupy.
+    pub size_limit: usize,
+}
+
+/// Returns the memory currently attributed to each tmpfs instance, ordered by name.
+pub fn all_tmpfs_usage() -> Vec<TmpfsMemoryUsage> {
+    let mut usage: Vec<TmpfsMemoryUsage> = tmpfs::instances().iter().map(|fs| TmpfsMemoryUsage {
+        name: fs.name().to_string(),
+        used_bytes: fs.used_bytes(),
+        size_limit: fs.size_limit(),
+    }).collect();
+    usage.sort_by(|a, b| a.name.cmp(&b.name));
+    usage
+}
+
+/// The owners that heap allocations can be attributed to while heap profiling is enabled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HeapProfileOwner {
+    /// Each allocation is attributed to the task that made it.
+    Task,
+    /// Each allocation is attributed to the application crate of the task that made it.
+    /// Allocations made by tasks that don't belong to an application are attributed to the kernel.
+    AppCrate,
+}
+
+/// The heap profile of a single task or application crate.
+#[derive(Debug, Clone)]
+pub struct HeapProfile {
+    /// The opaque owner ID recorded by the heap profiler:
+    /// a task ID, or the address of an application crate reference.
+    pub owner: usize,
+    /// The name of the task or application crate,
+    /// or `None` if it has since exited or been unloaded.
+    pub name: Option<String>,
+    /// The heap usage of this owner, as recorded by the heap profiler.
+    pub profile: heap::OwnerProfile,
+}
+
+/// The owner ID recorded for allocations made by tasks that don't belong to an application crate.
+pub const KERNEL_OWNER: usize = 0;
+
+/// The kind of owner that the heap profiler is currently attributing allocations to.
+static HEAP_PROFILE_OWNER: spin::Mutex<Option<HeapProfileOwner>> = spin::Mutex::new(None);
+
+/// The owner function used for [`HeapProfileOwner::Task`].
+fn current_task_owner() -> usize {
+    task::get_my_current_task_id()
+}
+
+/// The owner function used for [`HeapProfileOwner::AppCrate`].
+fn current_app_crate_owner() -> usize {
+    task::with_current_task(|t| t.app_crate.as_ref().map_or(KERNEL_OWNER, |c| Arc::as_ptr(c) as usize))
+        .unwrap_or(KERNEL_OWNER)
+}
+
+/// Enables the heap profiler, attributing each allocation to the given kind of owner.
+///
+/// See [`heap::enable_profiling()`].
+pub fn enable_heap_profiling(owner: HeapProfileOwner) -> Result<(), &'static str> {
+    let owner_fn = match owner {
+        HeapProfileOwner::Task => current_task_owner,
+        HeapProfileOwner::AppCrate => current_app_crate_owner,
+    };
+    heap::enable_profiling(owner_fn)?;
+    *HEAP_PROFILE_OWNER.lock() = Some(owner);
+    Ok(())
+}
+
+/// Disables the heap profiler and discards its records.
+pub fn disable_heap_profiling() {
+    heap::disable_profiling();
+    *HEAP_PROFILE_OWNER.lock() = None;
+}
+
+/// Returns the kind of owner the heap profiler is attributing allocations to,
+/// or `None` if it wasn't enabled via [`enable_heap_profiling()`].
+pub fn heap_profile_owner() -> Option<HeapProfileOwner> {
+    *HEAP_PROFILE_OWNER.lock()
+}
+
+/// Returns the heap profile of each owner recorded since heap profiling was enabled,
+/// ordered by decreasing outstanding bytes, along with the number of allocations the profiler dropped.
+///
+/// Returns `None` if heap profiling wasn't enabled via [`enable_heap_profiling()`].
+pub fn heap_profiles() -> Option<(Vec<HeapProfile>, usize)> {
+    let owner_kind = heap_profile_owner()?;
+    let report = heap::profiling_report()?;
+    let tasks: Vec<TaskRef> = task::TASKLIST.lock().values().cloned().collect();
+
+    let mut profiles: Vec<HeapProfile> = report.owners.into_iter().map(|profile| {
+        let owner = profile.owner;
+        let name = match owner_kind {
+            HeapProfileOwner::Task => tasks.iter()
+                .find(|t| t.id == owner)
+                .map(|t| t.name.clone()),
+            HeapProfileOwner::AppCrate if owner == KERNEL_OWNER => Some(String::from("<kernel>")),
+            HeapProfileOwner::AppCrate => tasks.iter()
+                .filter_map(|t| t.app_crate.as_ref())
+                .find(|c| Arc::as_ptr(c) as usize == owner)
+                .map(|c| c.lock_as_ref().crate_name.to_string()),
+        };
+        HeapProfile { owner, name, profile }
+    }).collect();
+    profiles.sort_by(|a, b| b.profile.outstanding_bytes.cmp(&a.profile.outstanding_bytes));
+    Some((profiles, report.dropped))
+}
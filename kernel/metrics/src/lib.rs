@@ -0,0 +1,410 @@
+//! A registry of named counters, gauges and histograms that kernel subsystems update cheaply.
+//!
+//! Each metric is a `static` item created by a `const` constructor, e.g., [`Counter::new()`],
+//! which its subsystem registers once, typically in its `init()` function.
+//! Registered metrics can be listed with [`metrics()`] or exported in the Prometheus text format
+//! with [`write_text()`], e.g., by the `metrics` command.
+//!
+//! Counters and histograms are accumulated in per-CPU blocks of atomic slots, created by [`init()`],
+//! such that updating them never contends with other CPUs, blocks, or allocates;
+//! they can therefore be updated from any context, including interrupt handlers.
+//! Their values are summed across all CPUs when they are read.
+//! Updates are ignored if they occur before the metric is registered or on a CPU without a block.
+//!
+//! Gauges are set rather than accumulated, so each gauge is a single atomic value.
+//!
+//! Like the `tracepoint` crate, the current CPU's ID is obtained with the `rdtscp` instruction,
+//! such that this crate has no dependencies on other kernel crates.
+
+#![no_std]
+
+extern crate alloc;
+
+use alloc::{boxed::Box, string::String, vec::Vec};
+use core::{
+    fmt::{self, Write},
+    ptr,
+    sync::atomic::{AtomicI64, AtomicPtr, AtomicU64, AtomicUsize, Ordering},
+};
+use spin::Mutex;
+
+/// The maximum number of CPUs, one for each possible CPU ID.
+const MAX_CPUS: usize = u8::MAX as usize + 1;
+
+/// The number of slots in each CPU's block, which is shared by all counters and histograms.
+///
+/// A counter uses one slot, whereas a histogram uses two more slots than it has bucket bounds.
+pub const METRIC_SLOTS: usize = 1024;
+
+/// The `slot` of a metric that hasn't been registered yet.
+const UNREGISTERED: usize = usize::MAX;
+
+/// A value that only ever increases, e.g., the number of page faults.
+pub struct Counter {
+    name: &'static str,
+    help: &'static str,
+    /// The index of this counter's slot in each CPU's block.
+    slot: AtomicUsize,
+}
+
+impl Counter {
+    /// Creates a new counter with the given `name` and a one-line `help` text describing it.
+    pub const fn new(name: &'static str, help: &'static str) -> Counter {
+        Counter { name, help, slot: AtomicUsize::new(UNREGISTERED) }
+    }
+
+    /// Registers this counter, after which its updates are accumulated.
+    ///
+    /// Registering a metric more than once has no effect.
+    pub fn register(&'static self) -> Result<(), &'static str> {
+        register(Metric::Counter(self))
+    }
+
+    /// Increments this counter by one.
+    #[inline(always)]
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    /// Increments this counter by the given `value`.
+    #[inline(always)]
+    pub fn add(&self, value: u64) {
+        add(&self.slot, 0, value);
+    }
+
+    /// Returns the value of this counter, summed across all CPUs.
+    pub fn value(&self) -> u64 {
+        sum(&self.slot, 0)
+    }
+}
+
+/// A value that can go up and down, e.g., the number of entries in a cache.
+pub struct Gauge {
+    name: &'static str,
+    help: &'static str,
+    value: AtomicI64,
+}
+
+impl Gauge {
+    /// Creates a new gauge with the given `name` and a one-line `help` text describing it.
+    pub const fn new(name: &'static str, help: &'static str) -> Gauge {
+        Gauge { name, help, value: AtomicI64::new(0) }
+    }
+
+    /// Registers this gauge, such that it is included in [`metrics()`].
+    ///
+    /// Registering a metric more than once has no effect.
+    pub fn register(&'static self) -> Result<(), &'static str> {
+        register(Metric::Gauge(self))
+    }
+
+    /// Sets this gauge to the given `value`.
+    #[inline(always)]
+    pub fn set(&self, value: i64) {
+        self.value.store(value, Ordering::Relaxed);
+    }
+
+    /// Increases this gauge by the given `value`.
+    #[inline(always)]
+    pub fn add(&self, value: i64) {
+        self.value.fetch_add(value, Ordering::Relaxed);
+    }
+
+    /// Decreases this gauge by the given `value`.
+    #[inline(always)]
+    pub fn sub(&self, value: i64) {
+        self.value.fetch_sub(value, Ordering::Relaxed);
+    }
+
+    /// Returns the value of this gauge.
+    pub fn value(&self) -> i64 {
+        self.value.load(Ordering::Relaxed)
+    }
+}
+
+/// A distribution of observed values, e.g., latencies, counted in buckets with fixed upper bounds.
+pub struct Histogram {
+    name: &'static str,
+    help: &'static str,
+    /// The inclusive upper bounds of the buckets, in increasing order.
+    /// Values above the last bound are counted in an additional overflow bucket.
+    bounds: &'static [u64],
+    /// The index of this histogram's first slot in each CPU's block.
+    ///
+    /// Its slots hold the count of each bucket, then the overflow bucket, and then the sum of all values.
+    slot: AtomicUsize,
+}
+
+impl Histogram {
+    /// Creates a new histogram with the given `name`, a one-line `help` text describing it,
+    /// and the inclusive upper `bounds` of its buckets, which must be strictly increasing.
+    pub const fn new(name: &'static str, help: &'static str, bounds: &'static [u64]) -> Histogram {
+        let mut i = 1;
+        while i < bounds.len() {
+            assert!(bounds[i - 1] < bounds[i], "histogram bucket bounds must be strictly increasing");
+            i += 1;
+        }
+        Histogram { name, help, bounds, slot: AtomicUsize::new(UNREGISTERED) }
+    }
+
+    /// Registers this histogram, after which its observations are accumulated.
+    ///
+    /// Registering a metric more than once has no effect.
+    pub fn register(&'static self) -> Result<(), &'static str> {
+        register(Metric::Histogram(self))
+    }
+
+    /// Records the given `value` in the bucket it falls into.
+    #[inline]
+    pub fn observe(&self, value: u64) {
+        let bucket = self.bounds.iter()
+            .position(|bound| value <= *bound)
+            .unwrap_or(self.bounds.len());
+        add(&self.slot, bucket, 1);
+        add(&self.slot, self.bounds.len() + 1, value);
+    }
+
+    /// Returns the current state of this histogram, summed across all CPUs.
+    pub fn snapshot(&self) -> HistogramSnapshot {
+        HistogramSnapshot {
+            bounds: self.bounds,
+            counts: (0 ..= self.bounds.len()).map(|bucket| sum(&self.slot, bucket)).collect(),
+            sum: sum(&self.slot, self.bounds.len() + 1),
+        }
+    }
+
+    fn slot_count(&self) -> usize {
+        self.bounds.len() + 2
+    }
+}
+
+/// The state of a [`Histogram`] at the time it was read.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HistogramSnapshot {
+    /// The inclusive upper bounds of the buckets.
+    pub bounds: &'static [u64],
+    /// The number of values observed in each bucket, followed by the number of values above the last bound.
+    /// Unlike in the exported text format, these are not cumulative.
+    pub counts: Vec<u64>,
+    /// The sum of all observed values, which wraps around on overflow.
+    pub sum: u64,
+}
+
+impl HistogramSnapshot {
+    /// Returns the total number of observed values.
+    pub fn count(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+}
+
+/// A registered metric.
+#[derive(Clone, Copy)]
+pub enum Metric {
+    Counter(&'static Counter),
+    Gauge(&'static Gauge),
+    Histogram(&'static Histogram),
+}
+
+impl Metric {
+    /// Returns the name of this metric.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Metric::Counter(counter) => counter.name,
+            Metric::Gauge(gauge) => gauge.name,
+            Metric::Histogram(histogram) => histogram.name,
+        }
+    }
+
+    /// Returns the help text describing this metric.
+    pub fn help(&self) -> &'static str {
+        match self {
+            Metric::Counter(counter) => counter.help,
+            Metric::Gauge(gauge) => gauge.help,
+            Metric::Histogram(histogram) => histogram.help,
+        }
+    }
+
+    /// Returns the name of this metric's type in the Prometheus text format.
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Metric::Counter(_) => "counter",
+            Metric::Gauge(_) => "gauge",
+            Metric::Histogram(_) => "histogram",
+        }
+    }
+
+    /// Returns whether this and `other` refer to the same metric.
+    fn is(&self, other: &Metric) -> bool {
+        match (self, other) {
+            (Metric::Counter(a), Metric::Counter(b)) => ptr::eq(*a, *b),
+            (Metric::Gauge(a), Metric::Gauge(b)) => ptr::eq(*a, *b),
+            (Metric::Histogram(a), Metric::Histogram(b)) => ptr::eq(*a, *b),
+            _ => false,
+        }
+    }
+}
+
+/// The registered metrics, and the index of the next unused slot in each CPU's block.
+struct Registry {
+    metrics: Vec<Metric>,
+    next_slot: usize,
+}
+
+static REGISTRY: Mutex<Registry> = Mutex::new(Registry { metrics: Vec::new(), next_slot: 0 });
+
+fn register(metric: Metric) -> Result<(), &'static str> {
+    if !is_valid_name(metric.name()) {
+        return Err("invalid metric name");
+    }
+    let mut registry = REGISTRY.lock();
+    if let Some(existing) = registry.metrics.iter().find(|m| m.name() == metric.name()) {
+        return if existing.is(&metric) {
+            Ok(())
+        } else {
+            Err("a different metric with the same name is already registered")
+        };
+    }
+
+    let (slot, slot_count) = match metric {
+        Metric::Counter(counter) => (Some(&counter.slot), 1),
+        Metric::Gauge(_) => (None, 0),
+        Metric::Histogram(histogram) => (Some(&histogram.slot), histogram.slot_count()),
+    };
+    if registry.next_slot + slot_count > METRIC_SLOTS {
+        return Err("no free metric slots are left");
+    }
+    registry.metrics.try_reserve(1).map_err(|_| "couldn't allocate space for the metric")?;
+    if let Some(slot) = slot {
+        slot.store(registry.next_slot, Ordering::Release);
+        registry.next_slot += slot_count;
+    }
+    registry.metrics.push(metric);
+    Ok(())
+}
+
+/// Returns whether the given `name` is a valid metric name in the Prometheus text format.
+fn is_valid_name(name: &str) -> bool {
+    let valid = |c: char| c.is_ascii_alphanumeric() || c == '_' || c == ':';
+    name.chars().next().map_or(false, |first| valid(first) && !first.is_ascii_digit())
+        && name.chars().all(valid)
+}
+
+/// Returns all registered metrics, ordered by their names.
+pub fn metrics() -> Vec<Metric> {
+    let mut metrics = REGISTRY.lock().metrics.clone();
+    metrics.sort_unstable_by_key(|metric| metric.name());
+    metrics
+}
+
+/// A CPU's slots, shared by all counters and histograms.
+struct Block {
+    slots: Box<[AtomicU64]>,
+}
+
+/// The block of each CPU, or null if it hasn't been initialized.
+static BLOCKS: [AtomicPtr<Block>; MAX_CPUS] = {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const NULL: AtomicPtr<Block> = AtomicPtr::new(ptr::null_mut());
+    [NULL; MAX_CPUS]
+};
+
+/// Creates the block of the CPU with the given `cpu_id`.
+///
+/// This must be invoked once on every CPU while it boots up;
+/// updates on a CPU without a block are ignored.
+/// If that CPU already has a block, e.g., because it was previously brought online,
+/// its existing values are kept.
+pub fn init(cpu_id: u8) {
+    let entry = &BLOCKS[cpu_id as usize];
+    if !entry.load(Ordering::Acquire).is_null() {
+        return;
+    }
+    let block = Box::into_raw(Box::new(Block {
+        slots: (0 .. METRIC_SLOTS).map(|_| AtomicU64::new(0)).collect(),
+    }));
+    if entry.compare_exchange(ptr::null_mut(), block, Ordering::AcqRel, Ordering::Acquire).is_err() {
+        // SAFETY: `block` was created above and never shared, because another CPU won the race.
+        drop(unsafe { Box::from_raw(block) });
+    }
+}
+
+/// Returns the block of the CPU with the given `cpu_id`, if it has been initialized.
+fn block(cpu_id: u8) -> Option<&'static Block> {
+    let block = BLOCKS[cpu_id as usize].load(Ordering::Acquire);
+    // SAFETY: blocks are never freed once they have been published.
+    (!block.is_null()).then(|| unsafe { &*block })
+}
+
+/// Returns the ID of the current CPU.
+#[inline(always)]
+fn current_cpu() -> u8 {
+    #[cfg(target_arch = "x86_64")] {
+        let mut aux = 0;
+        // SAFETY: `rdtscp` only reads the TSC and the `IA32_TSC_AUX` MSR, which holds the current CPU's ID.
+        unsafe { core::arch::x86_64::__rdtscp(&mut aux) };
+        aux as u8
+    }
+    #[cfg(not(target_arch = "x86_64"))] {
+        0
+    }
+}
+
+/// Adds `value` to the slot at `offset` from the first slot of a metric, if it has been registered.
+///
+/// A task may be preempted and migrated between reading the CPU ID and updating the slot,
+/// which is harmless because the slot is updated atomically and values are summed across all CPUs.
+#[inline(always)]
+fn add(slot: &AtomicUsize, offset: usize, value: u64) {
+    let slot = slot.load(Ordering::Relaxed);
+    if slot == UNREGISTERED {
+        return;
+    }
+    if let Some(block) = block(current_cpu()) {
+        block.slots[slot + offset].fetch_add(value, Ordering::Relaxed);
+    }
+}
+
+/// Returns the sum across all CPUs of the slot at `offset` from the first slot of a metric.
+fn sum(slot: &AtomicUsize, offset: usize) -> u64 {
+    let slot = slot.load(Ordering::Acquire);
+    if slot == UNREGISTERED {
+        return 0;
+    }
+    (0 ..= u8::MAX)
+        .filter_map(block)
+        .fold(0, |sum, block| sum.wrapping_add(block.slots[slot + offset].load(Ordering::Relaxed)))
+}
+
+/// Writes all registered metrics to `writer` in the Prometheus text exposition format.
+pub fn write_text<W: Write>(writer: &mut W) -> fmt::Result {
+    for metric in metrics() {
+        let name = metric.name();
+        writeln!(writer, "# HELP {} {}", name, metric.help())?;
+        writeln!(writer, "# TYPE {} {}", name, metric.type_name())?;
+        match metric {
+            Metric::Counter(counter) => writeln!(writer, "{} {}", name, counter.value())?,
+            Metric::Gauge(gauge) => writeln!(writer, "{} {}", name, gauge.value())?,
+            Metric::Histogram(histogram) => {
+                let snapshot = histogram.snapshot();
+                // Buckets are cumulative in the text format.
+                let mut cumulative = 0;
+                for (bound, count) in snapshot.bounds.iter().zip(&snapshot.counts) {
+                    cumulative += count;
+                    writeln!(writer, "{}_bucket{{le=\"{}\"}} {}", name, bound, cumulative)?;
+                }
+                let count = snapshot.count();
+                writeln!(writer, "{}_bucket{{le=\"+Inf\"}} {}", name, count)?;
+                writeln!(writer, "{}_sum {}", name, snapshot.sum)?;
+                writeln!(writer, "{}_count {}", name, count)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Returns all registered metrics in the Prometheus text exposition format.
+pub fn to_text() -> String {
+    let mut text = String::new();
+    let _ = write_text(&mut text);
+    text
+}
@@ -0,0 +1,263 @@
+//! Support for loading crates directly from `ar` archives, e.g., the `.rlib` files produced by cargo.
+//!
+//! An archive holds one relocatable object file per codegen unit of a crate,
+//! plus members that aren't object files, such as a symbol table or the `lib.rmeta` crate metadata.
+//! [`CrateNamespace::load_crate_archive()`](crate::CrateNamespace::load_crate_archive)
+//! extracts each object file member next to the archive and loads all of them together
+//! as individual crates, such that references between codegen units can be resolved in any order.
+//!
+//! The TLS sections of all members are treated as a single module:
+//! a contiguous range of the TLS area that fits all of them is reserved up front,
+//! and each member's TLS sections are placed into that range as the member is loaded.
+
+use core::ops::Range;
+use alloc::{
+    collections::BTreeSet,
+    string::String,
+    vec::Vec,
+};
+use spin::Mutex;
+use path::Path;
+use xmas_elf::{ElfFile, sections::SHF_TLS};
+use crate_name_utils::crate_name_from_path;
+
+/// The global header at the start of every `ar` archive.
+pub const ARCHIVE_MAGIC: &[u8] = b"!<arch>\n";
+
+/// The size of the header that precedes each archive member.
+const MEMBER_HEADER_SIZE: usize = 60;
+/// The two bytes at the end of each member header.
+const MEMBER_HEADER_END: &[u8] = b"`\n";
+/// The magic bytes at the start of an ELF file.
+const ELF_MAGIC: &[u8] = b"\x7fELF";
+
+/// A single member of an `ar` archive.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ArchiveMember<'a> {
+    /// The file name of the member.
+    pub name: &'a str,
+    /// The contents of the member.
+    pub data: &'a [u8],
+}
+
+impl<'a> ArchiveMember<'a> {
+    /// Returns `true` if this member is an ELF file, i.e., an object file that can be loaded.
+    pub fn is_object_file(&self) -> bool {
+        self.data.starts_with(ELF_MAGIC)
+    }
+}
+
+/// Returns `true` if the given bytes start with the `ar` archive header.
+pub fn is_archive(bytes: &[u8]) -> bool {
+    bytes.starts_with(ARCHIVE_MAGIC)
+}
+
+/// Parses the given `ar` archive and returns all of its regular members in order.
+///
+/// Both the GNU/System V and the BSD variants of long member names are supported.
+/// The special symbol table and long name table members are not returned.
+pub fn members(bytes: &[u8]) -> Result<Vec<ArchiveMember<'_>>, &'static str> {
+    if !is_archive(bytes) {
+        return Err("not an ar archive");
+    }
+    let mut members = Vec::new();
+    let mut long_names: &[u8] = &[];
+    let mut offset = ARCHIVE_MAGIC.len();
+    while offset < bytes.len() {
+        let header = bytes.get(offset .. offset + MEMBER_HEADER_SIZE)
+            .ok_or("archive member header is truncated")?;
+        if &header[58..60] != MEMBER_HEADER_END {
+            return Err("archive member header is malformed");
+        }
+        let size = parse_decimal(&header[48..58])?;
+        let data_start = offset + MEMBER_HEADER_SIZE;
+        let mut data = bytes.get(data_start .. data_start + size)
+            .ok_or("archive member data is truncated")?;
+        // Member data is padded to an even offset.
+        offset = data_start + size + (size % 2);
+
+        let raw_name = trim_spaces(&header[0..16]);
+        let name: &[u8] = match raw_name {
+            // The GNU long name table, which is referenced by the names of later members.
+            b"//" => {
+                long_names = data;
+                continue;
+            }
+            // The GNU and BSD symbol tables, which we don't need.
+            b"/" | b"/SYM64/" | b"__.SYMDEF" | b"__.SYMDEF SORTED" => continue,
+            // A GNU long name, given as an offset into the long name table,
+            // that is terminated by "/\n".
+            [b'/', index @ ..] => {
+                let start = parse_decimal(index)?;
+                let rest = long_names.get(start ..).ok_or("archive member long name is out of bounds")?;
+                let end = rest.windows(2).position(|w| w == b"/\n").unwrap_or(rest.len());
+                &rest[.. end]
+            }
+            // A BSD long name, which is stored at the start of the member data.
+            [b'#', b'1', b'/', len @ ..] => {
+                let len = parse_decimal(len)?;
+                if len > data.len() {
+                    return Err("archive member long name is out of bounds");
+                }
+                let (name, rest) = data.split_at(len);
+                data = rest;
+                trim_nuls(name)
+            }
+            // A GNU short name is terminated by a slash.
+            _ => raw_name.strip_suffix(b"/").unwrap_or(raw_name),
+        };
+        let name = core::str::from_utf8(name).map_err(|_| "archive member name is not valid UTF-8")?;
+        members.push(ArchiveMember { name, data });
+    }
+    Ok(members)
+}
+
+/// Parses the given `ar` archive and returns only its object file members in order,
+/// skipping other members such as the `lib.rmeta` crate metadata.
+pub fn object_members(bytes: &[u8]) -> Result<Vec<ArchiveMember<'_>>, &'static str> {
+    let mut members = members(bytes)?;
+    members.retain(ArchiveMember::is_object_file);
+    Ok(members)
+}
+
+/// Returns the crate name that is derived from the path of an archive,
+/// which is the same as for object files, except that the `"lib"` prefix
+/// that cargo adds to the file names of `.rlib` and `.a` archives is removed.
+///
+/// # Examples
+/// * `"libkeyboard-36be916209949cef.rlib"` -> `"keyboard-36be916209949cef"`
+/// * `"k#keyboard-36be916209949cef.rlib"` -> `"keyboard-36be916209949cef"`
+pub fn crate_name_from_archive_path(archive_path: &Path) -> &str {
+    let name = crate_name_from_path(archive_path);
+    match archive_path.extension() {
+        Some("rlib" | "a") => name.strip_prefix("lib").unwrap_or(name),
+        _ => name,
+    }
+}
+
+/// Returns the name of the file that the object file member at the given `index`
+/// of an archive for the crate `archive_crate_name` is extracted into.
+///
+/// The crate name of an extracted member is the file name without its `".o"` extension.
+pub fn member_file_name(archive_crate_name: &str, index: usize) -> String {
+    format!("{}-cgu{}.o", archive_crate_name, index)
+}
+
+/// Returns the size and alignment of a range in the TLS area that can hold
+/// all TLS sections of the given object file members, regardless of the order they're placed in.
+pub(crate) fn tls_reservation_size(members: &[ArchiveMember]) -> Result<(usize, usize), &'static str> {
+    let mut size = 0;
+    let mut alignment = 1;
+    for member in members {
+        let elf_file = ElfFile::new(member.data)?;
+        for sec in elf_file.section_iter() {
+            if sec.flags() & SHF_TLS == SHF_TLS {
+                let align = (sec.align() as usize).max(1);
+                size += sec.size() as usize + align - 1;
+                alignment = alignment.max(align);
+            }
+        }
+    }
+    Ok((size, alignment))
+}
+
+/// A range of the TLS area that is reserved for the TLS sections of the members of one archive.
+struct TlsReservation {
+    namespace: String,
+    crates: BTreeSet<String>,
+    start: usize,
+    next: usize,
+    end: usize,
+}
+
+/// The TLS reservations of all archives that are currently being loaded.
+static TLS_RESERVATIONS: Mutex<Vec<TlsReservation>> = Mutex::new(Vec::new());
+
+/// Reserves the given `range` of the TLS area for the TLS sections of the given `crates`,
+/// i.e., the extracted members of an archive that is about to be loaded into `namespace`.
+pub(crate) fn reserve_tls(namespace: &str, crates: BTreeSet<String>, range: Range<usize>) {
+    TLS_RESERVATIONS.lock().push(TlsReservation {
+        namespace: namespace.into(),
+        crates,
+        start: range.start,
+        next: range.start,
+        end: range.end,
+    });
+}
+
+/// Releases the TLS reservation that starts at the given offset, once its archive is loaded.
+pub(crate) fn release_tls_reservation(namespace: &str, start: usize) {
+    TLS_RESERVATIONS.lock().retain(|r| !(r.namespace == namespace && r.start == start));
+}
+
+/// Returns the offset at which the next TLS section of the given crate should be placed,
+/// if that crate is the member of an archive with a TLS reservation that can still fit the section.
+pub(crate) fn reserved_tls_offset(namespace: &str, crate_name: &str, size: usize, alignment: usize) -> Option<usize> {
+    let mut reservations = TLS_RESERVATIONS.lock();
+    let reservation = reservations.iter_mut()
+        .find(|r| r.namespace == namespace && r.crates.contains(crate_name))?;
+    let offset = reservation.next.next_multiple_of(alignment.max(1));
+    let end = offset.checked_add(size).filter(|&end| end <= reservation.end)?;
+    reservation.next = end;
+    Some(offset)
+}
+
+/// Parses the given string of ASCII digits, which may be padded with spaces.
+fn parse_decimal(digits: &[u8]) -> Result<usize, &'static str> {
+    core::str::from_utf8(trim_spaces(digits)).ok()
+        .and_then(|s| s.parse().ok())
+        .ok_or("archive member header has an invalid number")
+}
+
+fn trim_spaces(bytes: &[u8]) -> &[u8] {
+    let end = bytes.iter().rposition(|&b| b != b' ').map_or(0, |i| i + 1);
+    &bytes[.. end]
+}
+
+fn trim_nuls(bytes: &[u8]) -> &[u8] {
+    let end = bytes.iter().rposition(|&b| b != 0).map_or(0, |i| i + 1);
+    &bytes[.. end]
+}
+
+
+#[cfg(ktest)]
+mod ktests {
+    use super::*;
+    use ktest_macros::ktest;
+
+    fn push_member(archive: &mut Vec<u8>, name: &str, data: &[u8]) {
+        let header = format!("{:<16}{:<12}{:<6}{:<6}{:<8}{:<10}`\n", name, 0, 0, 0, 644, data.len());
+        archive.extend_from_slice(header.as_bytes());
+        archive.extend_from_slice(data);
+        if data.len() % 2 == 1 {
+            archive.push(b'\n');
+        }
+    }
+
+    #[ktest]
+    fn archive_members_are_parsed() -> Result<(), &'static str> {
+        let long_name = "foo-0123.foo.4567-cgu.0.rcgu.o";
+        let mut archive = ARCHIVE_MAGIC.to_vec();
+        push_member(&mut archive, "/", b"\0\0\0\0");
+        push_member(&mut archive, "//", format!("{}/\n", long_name).as_bytes());
+        push_member(&mut archive, "/0", b"\x7fELF odd size");
+        push_member(&mut archive, "lib.rmeta/", b"rust");
+        push_member(&mut archive, "#1/8", b"bsd.o\0\0\0\x7fELF");
+
+        let expected = [
+            ArchiveMember { name: long_name, data: b"\x7fELF odd size" },
+            ArchiveMember { name: "lib.rmeta", data: b"rust" },
+            ArchiveMember { name: "bsd.o", data: b"\x7fELF" },
+        ];
+        if members(&archive)? != expected {
+            return Err("parsed archive members differ");
+        }
+        if object_members(&archive)? != [expected[0], expected[2]] {
+            return Err("non-object members weren't skipped");
+        }
+        if members(&archive[.. archive.len() - 1]).is_ok() {
+            return Err("truncated archive was parsed");
+        }
+        Ok(())
+    }
+}
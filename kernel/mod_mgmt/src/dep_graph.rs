@@ -0,0 +1,303 @@
+//! Export of the dependency graph of loaded crates, e.g., to assess whether a crate can be unloaded or swapped.
+//!
+//! A [`CrateGraph`] has one node per loaded crate and one edge per pair of crates in which
+//! at least one section of the dependent crate depends on (is relocated against) a section of the other crate.
+//! Each node lists the crate's TLS sections, since those are part of every task's TLS area,
+//! and each edge lists which sections of the dependent crate reference TLS sections of the other crate.
+//!
+//! The graph can be rendered in the Graphviz [DOT](CrateGraph::to_dot) format
+//! or as [JSON](CrateGraph::to_json).
+
+use core::fmt::Write;
+use alloc::{
+    collections::{BTreeMap, BTreeSet},
+    string::{String, ToString},
+    vec::Vec,
+};
+use crate_metadata::{SectionType, StrongSectionRef};
+use super::CrateNamespace;
+
+/// A loaded crate in a [`CrateGraph`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CrateNode {
+    /// The name of the crate.
+    pub name: String,
+    /// The name of the namespace that the crate is loaded into.
+    pub namespace: String,
+    /// The number of sections in the crate.
+    pub num_sections: usize,
+    /// The names of the crate's TLS sections.
+    pub tls_sections: Vec<String>,
+}
+
+/// A reference from a section in one crate to a TLS section in another crate.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct TlsReference {
+    /// The name of the section that references the TLS section.
+    pub section: String,
+    /// The name of the referenced TLS section.
+    pub tls_section: String,
+}
+
+/// A dependency of one crate on another in a [`CrateGraph`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CrateEdge {
+    /// The name of the crate that depends on `dependency`.
+    pub dependent: String,
+    /// The name of the crate that `dependent` depends on.
+    pub dependency: String,
+    /// The number of section-level dependencies, i.e., relocations,
+    /// from sections in `dependent` to sections in `dependency`.
+    pub num_relocations: usize,
+    /// The references from sections in `dependent` to TLS sections in `dependency`.
+    pub tls_references: Vec<TlsReference>,
+}
+
+/// The dependency graph of the crates loaded into a namespace.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CrateGraph {
+    /// All crates, ordered by name.
+    pub nodes: Vec<CrateNode>,
+    /// All dependencies between crates, ordered by dependent and then dependency.
+    pub edges: Vec<CrateEdge>,
+}
+
+impl CrateGraph {
+    /// Returns the names of the crates that directly depend on the given crate.
+    ///
+    /// A crate can only be unloaded or swapped without also handling these crates
+    /// if this is empty.
+    pub fn dependents_of(&self, crate_name: &str) -> Vec<&str> {
+        self.edges.iter()
+            .filter(|edge| edge.dependency == crate_name)
+            .map(|edge| edge.dependent.as_str())
+            .collect()
+    }
+
+    /// Returns the names of the crates that the given crate directly depends on.
+    pub fn dependencies_of(&self, crate_name: &str) -> Vec<&str> {
+        self.edges.iter()
+            .filter(|edge| edge.dependent == crate_name)
+            .map(|edge| edge.dependency.as_str())
+            .collect()
+    }
+
+    /// Returns the subgraph containing only the given crate and the crates that directly depend on it
+    /// or that it directly depends on.
+    pub fn neighborhood(&self, crate_name: &str) -> CrateGraph {
+        let edges: Vec<CrateEdge> = self.edges.iter()
+            .filter(|edge| edge.dependent == crate_name || edge.dependency == crate_name)
+            .cloned()
+            .collect();
+        let names: BTreeSet<&str> = edges.iter()
+            .flat_map(|edge| [edge.dependent.as_str(), edge.dependency.as_str()])
+            .chain([crate_name])
+            .collect();
+        let nodes = self.nodes.iter()
+            .filter(|node| names.contains(node.name.as_str()))
+            .cloned()
+            .collect();
+        CrateGraph { nodes, edges }
+    }
+
+    /// Renders this graph in the Graphviz DOT format.
+    ///
+    /// Crates with TLS sections are filled, and dependencies that reference TLS sections are drawn in red.
+    pub fn to_dot(&self) -> String {
+        let mut dot = String::from("digraph crates {\n    node [shape=box];\n");
+        for node in &self.nodes {
+            let _ = write!(dot, "    \"{}\" [label=\"{}", escape_dot(&node.name), escape_dot(&node.name));
+            if !node.tls_sections.is_empty() {
+                let _ = write!(dot, "\\n{} TLS sections\", style=filled, fillcolor=lightblue", node.tls_sections.len());
+            } else {
+                dot.push('"');
+            }
+            dot.push_str("];\n");
+        }
+        for edge in &self.edges {
+            let _ = write!(dot, "    \"{}\" -> \"{}\" [label=\"{}",
+                escape_dot(&edge.dependent), escape_dot(&edge.dependency), edge.num_relocations,
+            );
+            if !edge.tls_references.is_empty() {
+                let _ = write!(dot, " ({} TLS)\", color=red", edge.tls_references.len());
+            } else {
+                dot.push('"');
+            }
+            dot.push_str("];\n");
+        }
+        dot.push_str("}\n");
+        dot
+    }
+
+    /// Renders this graph as a JSON object with a `crates` array and a `dependencies` array.
+    pub fn to_json(&self) -> String {
+        let mut json = String::from("{\n  \"crates\": [");
+        for (i, node) in self.nodes.iter().enumerate() {
+            let _ = write!(json, "{}\n    {{\"name\": \"{}\", \"namespace\": \"{}\", \"sections\": {}, \"tls_sections\": [",
+                if i == 0 { "" } else { "," },
+                escape_json(&node.name), escape_json(&node.namespace), node.num_sections,
+            );
+            for (j, tls_section) in node.tls_sections.iter().enumerate() {
+                let _ = write!(json, "{}\"{}\"", if j == 0 { "" } else { ", " }, escape_json(tls_section));
+            }
+            json.push_str("]}");
+        }
+        json.push_str("\n  ],\n  \"dependencies\": [");
+        for (i, edge) in self.edges.iter().enumerate() {
+            let _ = write!(json, "{}\n    {{\"dependent\": \"{}\", \"dependency\": \"{}\", \"relocations\": {}, \"tls_references\": [",
+                if i == 0 { "" } else { "," },
+                escape_json(&edge.dependent), escape_json(&edge.dependency), edge.num_relocations,
+            );
+            for (j, reference) in edge.tls_references.iter().enumerate() {
+                let _ = write!(json, "{}{{\"section\": \"{}\", \"tls_section\": \"{}\"}}",
+                    if j == 0 { "" } else { ", " },
+                    escape_json(&reference.section), escape_json(&reference.tls_section),
+                );
+            }
+            json.push_str("]}");
+        }
+        json.push_str("\n  ]\n}\n");
+        json
+    }
+}
+
+fn escape_dot(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+fn escape_json(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if (c as u32) < 0x20 => { let _ = write!(escaped, "\\u{:04x}", c as u32); }
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+impl CrateNamespace {
+    /// Returns the dependency graph of the crates loaded into this namespace,
+    /// including the crates in its recursive namespaces if `recursive` is `true`.
+    ///
+    /// Dependencies on crates outside of the included namespaces are omitted.
+    pub fn dependency_graph(&self, recursive: bool) -> CrateGraph {
+        let mut namespaces = vec![self];
+        while recursive {
+            match namespaces.last().and_then(|ns| ns.recursive_namespace()) {
+                Some(r_ns) => namespaces.push(r_ns),
+                None => break,
+            }
+        }
+
+        // First, gather each crate's dependencies while it is locked.
+        // Crates are locked one at a time, so the names of dependencies are resolved afterwards.
+        let mut nodes = Vec::new();
+        let mut dependencies: Vec<(String, Vec<(String, StrongSectionRef)>)> = Vec::new();
+        for namespace in namespaces {
+            let crates: Vec<_> = namespace.crate_tree.lock().iter()
+                .map(|(_name, crate_ref)| crate_ref.clone_shallow())
+                .collect();
+            for crate_ref in crates {
+                let krate = crate_ref.lock_as_ref();
+                let name = krate.crate_name.to_string();
+                nodes.push(CrateNode {
+                    name: name.clone(),
+                    namespace: namespace.name().to_string(),
+                    num_sections: krate.sections.len(),
+                    tls_sections: krate.tls_sections_iter().map(|sec| sec.name.to_string()).collect(),
+                });
+                let deps = krate.sections.values()
+                    .flat_map(|sec| {
+                        let sec_name = sec.name.to_string();
+                        sec.inner.read().sections_i_depend_on.iter()
+                            .map(|dep| (sec_name.clone(), dep.section.clone()))
+                            .collect::<Vec<_>>()
+                    })
+                    .collect();
+                dependencies.push((name, deps));
+            }
+        }
+        nodes.sort_by(|a, b| a.name.cmp(&b.name));
+        let included: BTreeSet<&str> = nodes.iter().map(|node| node.name.as_str()).collect();
+
+        let mut edges: BTreeMap<(String, String), CrateEdge> = BTreeMap::new();
+        for (dependent, deps) in dependencies {
+            for (sec_name, dep_sec) in deps {
+                let Some(dependency) = dep_sec.parent_crate.upgrade()
+                    .map(|parent| parent.lock_as_ref().crate_name.to_string())
+                else { continue };
+                if dependency == dependent || !included.contains(dependency.as_str()) {
+                    continue;
+                }
+                let edge = edges.entry((dependent.clone(), dependency.clone()))
+                    .or_insert_with(|| CrateEdge {
+                        dependent: dependent.clone(),
+                        dependency,
+                        num_relocations: 0,
+                        tls_references: Vec::new(),
+                    });
+                edge.num_relocations += 1;
+                if matches!(dep_sec.typ, SectionType::TlsData | SectionType::TlsBss) {
+                    edge.tls_references.push(TlsReference { section: sec_name, tls_section: dep_sec.name.to_string() });
+                }
+            }
+        }
+        let edges = edges.into_values()
+            .map(|mut edge| {
+                edge.tls_references.sort();
+                edge.tls_references.dedup();
+                edge
+            })
+            .collect();
+
+        CrateGraph { nodes, edges }
+    }
+}
+
+#[cfg(ktest)]
+mod ktests {
+    use super::*;
+    use ktest_macros::ktest;
+
+    fn example_graph() -> CrateGraph {
+        CrateGraph {
+            nodes: vec![
+                CrateNode { name: "k#a".into(), namespace: "_kernel".into(), num_sections: 2, tls_sections: vec!["a::\"X\"".into()] },
+                CrateNode { name: "k#b".into(), namespace: "_kernel".into(), num_sections: 1, tls_sections: Vec::new() },
+            ],
+            edges: vec![CrateEdge {
+                dependent: "k#b".into(),
+                dependency: "k#a".into(),
+                num_relocations: 3,
+                tls_references: vec![TlsReference { section: "b::f".into(), tls_section: "a::\"X\"".into() }],
+            }],
+        }
+    }
+
+    #[ktest]
+    fn dependents_and_dependencies_are_found() -> Result<(), &'static str> {
+        let graph = example_graph();
+        if graph.dependents_of("k#a") != ["k#b"] || !graph.dependents_of("k#b").is_empty() {
+            return Err("wrong dependents");
+        }
+        if graph.dependencies_of("k#b") != ["k#a"] {
+            return Err("wrong dependencies");
+        }
+        Ok(())
+    }
+
+    #[ktest]
+    fn names_are_escaped() -> Result<(), &'static str> {
+        let graph = example_graph();
+        if !graph.to_json().contains(r#""tls_section": "a::\"X\"""#) {
+            return Err("JSON output wasn't escaped");
+        }
+        if !graph.to_dot().contains(r#""k#b" -> "k#a" [label="3 (1 TLS)", color=red];"#) {
+            return Err("DOT output is missing the TLS dependency");
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,401 @@
+//! Lazy binding of function calls to crates that haven't been loaded yet, similar to a PLT.
+//!
+//! When lazy binding is enabled for a namespace via [`CrateNamespace::enable_lazy_binding()`],
+//! a direct call to a function whose containing crate isn't loaded yet doesn't cause that crate
+//! to be loaded along with the calling crate. Instead, the call is bound to a small stub.
+//! The first time a stub is called, it loads the crate that contains the function,
+//! which also registers that crate's TLS sections, and then jumps to the function.
+//! Subsequent calls through the stub jump directly to the function.
+//! This avoids loading rarely used crates, e.g., at boot time, until they are actually used.
+//!
+//! Each stub consists of executable code and a writable slot with the stub's current target:
+//! ```text
+//! movabs r11, <slot address>
+//! jmp    [r11]                    ; initially jumps to the next instruction
+//! push   <stub index>
+//! movabs r11, <trampoline address>
+//! jmp    r11
+//! ```
+//! The trampoline preserves all argument registers while it resolves the stub's symbol,
+//! and then updates the stub's slot to point to the resolved function.
+//!
+//! Only relocations for direct function calls are lazily bound; references to data
+//! and function pointers always cause the containing crate to be loaded immediately.
+//! Lazily-bound calls are not tracked as section dependencies, so they won't be redirected
+//! by crate swapping. The stub keeps the resolved section alive, though,
+//! so a swapped-out section that a stub resolved to is never freed.
+//!
+//! Since a crate is loaded while the calling task runs the stub, functions that may be called
+//! from interrupt handlers or while holding locks used by crate loading must be loaded eagerly.
+//! If a stub's symbol cannot be resolved, the call cannot proceed, so the calling task is killed
+//! via the hook registered with [`set_lazy_binding_failure_hook()`].
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+use alloc::{
+    boxed::Box,
+    collections::BTreeMap,
+    string::{String, ToString},
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+use spin::{Mutex, Once};
+use memory::{MappedPages, MmiRef, VirtualAddress, PAGE_SIZE};
+use crate_metadata::{RelocationEntry, StrongSectionRef, TEXT_SECTION_FLAGS};
+use super::{CrateNamespace, allocate_and_map_as_writable, get_containing_crate_name};
+
+/// The size in bytes of each stub, which is padded with `int3` instructions.
+const STUB_SIZE: usize = 32;
+/// The number of stubs that fit in a single page.
+const STUBS_PER_PAGE: usize = PAGE_SIZE / STUB_SIZE;
+/// The offset within a stub of the code that invokes the trampoline,
+/// which is the initial target of each stub's slot.
+const LAZY_ENTRY_OFFSET: usize = 13;
+
+/// A function that refreshes the TLS areas of existing tasks.
+pub type TlsRefreshHook = fn() -> Result<(), &'static str>;
+
+/// The function invoked after a lazily-bound symbol's crate has changed the TLS layout.
+static TLS_REFRESH_HOOK: Once<TlsRefreshHook> = Once::new();
+
+/// Registers the function that refreshes the TLS areas of existing tasks
+/// after loading the crate of a lazily-bound symbol has added new TLS sections.
+///
+/// Without this, only tasks spawned afterwards can access those TLS sections.
+pub fn set_lazy_binding_tls_hook(hook: TlsRefreshHook) -> Result<(), &'static str> {
+    let mut newly_set = false;
+    TLS_REFRESH_HOOK.call_once(|| { newly_set = true; hook });
+    if newly_set {
+        Ok(())
+    } else {
+        Err("lazy binding TLS hook was already registered")
+    }
+}
+
+/// A function that kills the current task because the call it made through a stub
+/// cannot proceed, given a description of why the stub's symbol couldn't be resolved.
+pub type ResolveFailureHook = fn(String) -> !;
+
+/// The function invoked when a stub's symbol couldn't be resolved.
+static RESOLVE_FAILURE_HOOK: Once<ResolveFailureHook> = Once::new();
+
+/// Registers the function that kills the calling task when a stub's symbol couldn't be resolved.
+///
+/// Without this, a failure to resolve a stub's symbol halts the current CPU.
+pub fn set_lazy_binding_failure_hook(hook: ResolveFailureHook) -> Result<(), &'static str> {
+    let mut newly_set = false;
+    RESOLVE_FAILURE_HOOK.call_once(|| { newly_set = true; hook });
+    if newly_set {
+        Ok(())
+    } else {
+        Err("lazy binding failure hook was already registered")
+    }
+}
+
+/// Returns the number of lazy binding stubs that exist
+/// and the number of those that have been resolved by a call.
+pub fn lazy_binding_stats() -> (usize, usize) {
+    let table = LAZY_STUBS.lock();
+    let resolved = table.stubs.iter().filter(|stub| stub.resolved.is_some()).count();
+    (table.stubs.len(), resolved)
+}
+
+/// A symbol that a stub is bound to.
+struct LazyStub {
+    /// The namespace in which the symbol will be resolved.
+    namespace: Weak<CrateNamespace>,
+    /// The demangled name of the symbol.
+    symbol: String,
+    /// The section that the symbol was resolved to, if the stub has been called.
+    resolved: Option<StrongSectionRef>,
+}
+
+/// A page of stubs and their slots.
+struct StubPage {
+    /// The executable code of the stubs.
+    code: MappedPages,
+    /// The target of each stub, which never moves because stubs refer to it by address.
+    slots: Box<[AtomicUsize; STUBS_PER_PAGE]>,
+}
+
+struct LazyStubTable {
+    pages: Vec<StubPage>,
+    stubs: Vec<LazyStub>,
+    /// The index of the stub for each symbol, keyed by namespace name and symbol.
+    indices: BTreeMap<(String, String), usize>,
+}
+
+static LAZY_STUBS: Mutex<LazyStubTable> = Mutex::new(LazyStubTable {
+    pages: Vec::new(),
+    stubs: Vec::new(),
+    indices: BTreeMap::new(),
+});
+
+impl LazyStubTable {
+    fn slot(&self, index: usize) -> &AtomicUsize {
+        &self.pages[index / STUBS_PER_PAGE].slots[index % STUBS_PER_PAGE]
+    }
+
+    fn stub_address(&self, index: usize) -> VirtualAddress {
+        self.pages[index / STUBS_PER_PAGE].code.start_address() + (index % STUBS_PER_PAGE) * STUB_SIZE
+    }
+
+    /// Returns the index of the stub for the given `symbol` in the given `namespace`,
+    /// creating the stub if it doesn't already exist.
+    fn get_or_create(
+        &mut self,
+        namespace: &Weak<CrateNamespace>,
+        namespace_name: &str,
+        symbol: &str,
+        kernel_mmi_ref: &MmiRef,
+    ) -> Result<usize, &'static str> {
+        let key = (namespace_name.to_string(), symbol.to_string());
+        if let Some(&index) = self.indices.get(&key) {
+            return Ok(index);
+        }
+        let index = self.stubs.len();
+        if index == self.pages.len() * STUBS_PER_PAGE {
+            self.add_page(kernel_mmi_ref)?;
+        }
+        self.stubs.push(LazyStub { namespace: namespace.clone(), symbol: key.1.clone(), resolved: None });
+        self.indices.insert(key, index);
+        Ok(index)
+    }
+
+    /// Allocates a new page of stubs, each of which initially invokes the trampoline.
+    fn add_page(&mut self, kernel_mmi_ref: &MmiRef) -> Result<(), &'static str> {
+        let first_index = self.pages.len() * STUBS_PER_PAGE;
+        let slots: Box<[AtomicUsize; STUBS_PER_PAGE]> = Box::new(core::array::from_fn(|_| AtomicUsize::new(0)));
+        let mut code = allocate_and_map_as_writable(PAGE_SIZE, TEXT_SECTION_FLAGS, kernel_mmi_ref)?;
+        let start = code.start_address();
+        {
+            let stubs: &mut [u8] = code.as_slice_mut(0, STUBS_PER_PAGE * STUB_SIZE)?;
+            for (i, (stub, slot)) in stubs.chunks_exact_mut(STUB_SIZE).zip(slots.iter()).enumerate() {
+                write_stub(stub, slot as *const AtomicUsize as usize, first_index + i, trampoline_address());
+                slot.store(start.value() + i * STUB_SIZE + LAZY_ENTRY_OFFSET, Ordering::Relaxed);
+            }
+        }
+        code.remap(&mut kernel_mmi_ref.lock().page_table, TEXT_SECTION_FLAGS)?;
+        self.pages.push(StubPage { code, slots });
+        Ok(())
+    }
+}
+
+/// Writes the code of the stub with the given `index` into `stub`; see the module docs.
+fn write_stub(stub: &mut [u8], slot_address: usize, index: usize, trampoline_address: usize) {
+    stub.fill(0xCC);
+    stub[0..2].copy_from_slice(&[0x49, 0xBB]);
+    stub[2..10].copy_from_slice(&(slot_address as u64).to_le_bytes());
+    stub[10..13].copy_from_slice(&[0x41, 0xFF, 0x23]);
+    stub[13] = 0x68;
+    stub[14..18].copy_from_slice(&(index as u32).to_le_bytes());
+    stub[18..20].copy_from_slice(&[0x49, 0xBB]);
+    stub[20..28].copy_from_slice(&(trampoline_address as u64).to_le_bytes());
+    stub[28..31].copy_from_slice(&[0x41, 0xFF, 0xE3]);
+}
+
+#[cfg(target_arch = "x86_64")]
+fn trampoline_address() -> usize {
+    lazy_binding_trampoline as usize
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+fn trampoline_address() -> usize {
+    0
+}
+
+/// Resolves the stub whose index was pushed onto the stack, then jumps to the resolved function.
+///
+/// All registers that may hold function arguments are preserved.
+#[cfg(target_arch = "x86_64")]
+#[naked]
+unsafe extern "C" fn lazy_binding_trampoline() -> ! {
+    core::arch::asm!(
+        "push rdi",
+        "push rsi",
+        "push rdx",
+        "push rcx",
+        "push r8",
+        "push r9",
+        "push rax",
+        "push r10",
+        "sub rsp, 128",
+        "movdqu [rsp + 0x00], xmm0",
+        "movdqu [rsp + 0x10], xmm1",
+        "movdqu [rsp + 0x20], xmm2",
+        "movdqu [rsp + 0x30], xmm3",
+        "movdqu [rsp + 0x40], xmm4",
+        "movdqu [rsp + 0x50], xmm5",
+        "movdqu [rsp + 0x60], xmm6",
+        "movdqu [rsp + 0x70], xmm7",
+        // The stub index is above the saved registers; the stack is now 16-byte aligned.
+        "mov rdi, [rsp + 192]",
+        "call {resolve}",
+        "mov r11, rax",
+        "movdqu xmm0, [rsp + 0x00]",
+        "movdqu xmm1, [rsp + 0x10]",
+        "movdqu xmm2, [rsp + 0x20]",
+        "movdqu xmm3, [rsp + 0x30]",
+        "movdqu xmm4, [rsp + 0x40]",
+        "movdqu xmm5, [rsp + 0x50]",
+        "movdqu xmm6, [rsp + 0x60]",
+        "movdqu xmm7, [rsp + 0x70]",
+        "add rsp, 128",
+        "pop r10",
+        "pop rax",
+        "pop r9",
+        "pop r8",
+        "pop rcx",
+        "pop rdx",
+        "pop rsi",
+        "pop rdi",
+        // Discard the stub index.
+        "add rsp, 8",
+        "jmp r11",
+        resolve = sym resolve_lazy_stub,
+        options(noreturn)
+    )
+}
+
+/// Returns the address of the function that the stub with the given `index` is bound to,
+/// loading its crate if necessary.
+///
+/// This is invoked by the trampoline. If the symbol cannot be resolved, the call cannot proceed,
+/// so this kills the calling task via the [`ResolveFailureHook`] instead of returning.
+#[cfg_attr(not(target_arch = "x86_64"), allow(dead_code))]
+extern "C" fn resolve_lazy_stub(index: usize) -> usize {
+    let error = match resolve(index) {
+        Ok(address) => return address,
+        Err(e) => e,
+    };
+    let symbol = LAZY_STUBS.lock().stubs.get(index).map(|stub| stub.symbol.clone()).unwrap_or_default();
+    let description = format!("couldn't resolve lazily-bound symbol {:?} (stub {}): {}", symbol, index, error);
+    error!("lazy binding: {}", description);
+    if let Some(kill_current_task) = RESOLVE_FAILURE_HOOK.get() {
+        kill_current_task(description);
+    }
+    // Unwinding cannot cross this `extern "C"` function, so there's no way to fail the call.
+    error!("lazy binding: no failure hook is registered, so the current CPU cannot proceed");
+    loop { core::hint::spin_loop(); }
+}
+
+fn resolve(index: usize) -> Result<usize, &'static str> {
+    let (namespace, symbol, resolved) = {
+        let table = LAZY_STUBS.lock();
+        let stub = table.stubs.get(index).ok_or("BUG: invalid lazy binding stub index")?;
+        (stub.namespace.upgrade(), stub.symbol.clone(), stub.resolved.clone())
+    };
+    // Another task may have resolved this stub in the meantime.
+    if let Some(section) = resolved {
+        return Ok(section.virt_addr.value());
+    }
+    let namespace = namespace.ok_or("the namespace of the lazily-bound symbol no longer exists")?;
+    let section = namespace.load_lazily_bound_symbol(&symbol)?;
+    let address = section.virt_addr.value();
+
+    let mut table = LAZY_STUBS.lock();
+    table.slot(index).store(address, Ordering::Release);
+    table.stubs[index].resolved = Some(section);
+    Ok(address)
+}
+
+impl CrateNamespace {
+    /// Enables lazy binding of function calls in crates that are subsequently loaded into this namespace.
+    ///
+    /// See the [`lazy_binding`](crate::lazy_binding) module for more.
+    pub fn enable_lazy_binding(self: &Arc<Self>) -> Result<(), &'static str> {
+        if cfg!(not(target_arch = "x86_64")) {
+            return Err("lazy binding is only supported on x86_64");
+        }
+        *self.lazy_binding.lock() = Some(Arc::downgrade(self));
+        Ok(())
+    }
+
+    /// Disables lazy binding for crates that are subsequently loaded into this namespace.
+    ///
+    /// Existing stubs are unaffected.
+    pub fn disable_lazy_binding(&self) {
+        *self.lazy_binding.lock() = None;
+    }
+
+    /// Returns whether lazy binding is enabled for this namespace.
+    pub fn is_lazy_binding_enabled(&self) -> bool {
+        self.lazy_binding.lock().is_some()
+    }
+
+    /// Returns the address of the stub that the given `relocation` against the given symbol
+    /// should be bound to, or `None` if it should be resolved immediately.
+    ///
+    /// A stub is only used if lazy binding is enabled, the relocation is for a direct function call,
+    /// and the symbol isn't loaded yet but a crate that may contain it exists.
+    pub(crate) fn lazy_binding_stub(
+        &self,
+        demangled_full_symbol: &str,
+        relocation: &RelocationEntry,
+        temp_backup_namespace: Option<&CrateNamespace>,
+        kernel_mmi_ref: &MmiRef,
+    ) -> Result<Option<VirtualAddress>, &'static str> {
+        let Some(namespace) = self.lazy_binding.lock().clone() else { return Ok(None) };
+        if temp_backup_namespace.is_some()
+            || !relocation.is_function_call()
+            || self.get_symbol_internal(demangled_full_symbol).is_some()
+        {
+            return Ok(None);
+        }
+        let has_containing_crate = get_containing_crate_name(demangled_full_symbol).into_iter().any(|crate_name|
+            !self.method_get_crate_object_files_starting_with(&format!("{crate_name}-")).is_empty()
+        );
+        if !has_containing_crate {
+            return Ok(None);
+        }
+
+        let mut table = LAZY_STUBS.lock();
+        let index = table.get_or_create(&namespace, &self.name, demangled_full_symbol, kernel_mmi_ref)?;
+        Ok(Some(table.stub_address(index)))
+    }
+
+    /// Finds or loads the section for the given lazily-bound `symbol`,
+    /// and refreshes the TLS areas of existing tasks if its crate added TLS sections.
+    fn load_lazily_bound_symbol(&self, symbol: &str) -> Result<StrongSectionRef, &'static str> {
+        let kernel_mmi_ref = memory::get_kernel_mmi_ref().ok_or("couldn't get the kernel's MMI")?;
+        let generation = self.tls_initializer.lock().generation();
+        let section = self.get_symbol_or_load(symbol, None, kernel_mmi_ref, false)
+            .upgrade()
+            .ok_or("couldn't find or load the crate containing the symbol")?;
+        info!("Lazily bound symbol {:?} in namespace {:?}", symbol, self.name);
+
+        if self.tls_initializer.lock().generation() != generation {
+            match TLS_REFRESH_HOOK.get() {
+                Some(refresh) => refresh()?,
+                None => warn!("Lazily loaded TLS sections for {:?}, but existing tasks' TLS areas can't be refreshed", symbol),
+            }
+        }
+        Ok(section)
+    }
+}
+
+#[cfg(ktest)]
+mod ktests {
+    use super::*;
+    use ktest_macros::ktest;
+
+    #[ktest]
+    fn stub_encoding() -> Result<(), &'static str> {
+        let mut stub = [0u8; STUB_SIZE];
+        write_stub(&mut stub, 0x1122_3344_5566_7788, 5, 0xFFFF_8000_0000_1000);
+        let expected: [u8; STUB_SIZE] = [
+            0x49, 0xBB, 0x88, 0x77, 0x66, 0x55, 0x44, 0x33, 0x22, 0x11, // movabs r11, slot
+            0x41, 0xFF, 0x23,                                           // jmp [r11]
+            0x68, 0x05, 0x00, 0x00, 0x00,                               // push 5
+            0x49, 0xBB, 0x00, 0x10, 0x00, 0x00, 0x00, 0x80, 0xFF, 0xFF, // movabs r11, trampoline
+            0x41, 0xFF, 0xE3,                                           // jmp r11
+            0xCC,
+        ];
+        if stub != expected {
+            return Err("stub code was encoded incorrectly");
+        }
+        if stub[LAZY_ENTRY_OFFSET] != 0x68 {
+            return Err("lazy entry offset doesn't point to the push instruction");
+        }
+        Ok(())
+    }
+}
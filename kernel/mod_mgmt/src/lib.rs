@@ -2,6 +2,7 @@
 #![no_std]
 #![feature(int_roundings)]
 #![feature(let_chains)]
+#![feature(naked_functions)]
 
 #[macro_use] extern crate alloc;
 #[macro_use] extern crate log;
@@ -14,23 +15,46 @@ use alloc::{
 };
 use spin::{Mutex, Once};
 use xmas_elf::{ElfFile, sections::{SHF_ALLOC, SHF_EXECINSTR, SHF_TLS, SHF_WRITE, SectionData, ShType}, symbol_table::{Binding, Type}};
-use memory::{MmiRef, MemoryManagementInfo, VirtualAddress, MappedPages, PteFlags, allocate_pages_by_bytes, allocate_frames_by_bytes_at};
+use memory::{MmiRef, MemoryManagementInfo, VirtualAddress, MappedPages, PteFlags, allocate_pages_by_bytes, allocate_pages_by_bytes_at, allocate_frames_by_bytes_at};
 use bootloader_modules::BootloaderModule;
-use cow_arc::CowArc;
+use cow_arc::{CowArc, CowWeak};
 use rustc_demangle::demangle;
 use qp_trie::Trie;
 use fs_node::{FileOrDir, File, FileRef, DirRef};
 use vfs_node::VFSDirectory;
 use path::Path;
 use memfs::MemFile;
+use tracepoint::Tracepoint;
 use hashbrown::HashMap;
-
-pub use tls_initializer::{TlsInitializer, TlsDataImage};
+use prelink::{PrelinkedCrate, PrelinkedRelocation, RelocationSource};
+use symbol_version::SymbolVersion;
+use sandbox::{Sandbox, SymbolAllowList};
+
+pub use tls_initializer::{
+    TlsInitializer, TlsDataImage, TlsSectionRemapping, TlsAllocHint, TlsTemplateCell, TcbLayout, TlsIndex, THESEUS_TLS_MODULE_ID,
+    errno, set_errno, errno_location, current_tls_self_pointer, unwinding_context, set_unwinding_context, PanicBuffer, with_panic_buffer,
+    init_current_heap_cache, teardown_current_heap_cache,
+    TlsSnapshot, set_rehome_on_migration, init_bootstrap_tls, TlsLayoutInfo, TlsSectionDescription,
+    init_interrupt_tls, has_interrupt_tls, enter_interrupt_tls, InterruptTlsGuard,
+    MemoryPressureHooks, TlsImageUsage, set_memory_pressure_hooks, tls_image_usage,
+    init_hardened_cpu_id, hardened_cpu_id, verified_tls_base, repair_tls_register,
+};
 pub use crate_name_utils::*;
 pub use crate_metadata::*;
 
+pub mod archive;
+pub mod dep_graph;
+pub mod lazy_binding;
+pub mod load_trace;
+pub mod parallel;
 pub mod parse_nano_core;
+pub mod prelink;
 pub mod replace_nano_core_crates;
+pub mod sandbox;
+pub mod static_tls;
+pub mod symbolize;
+pub mod symbol_version;
+pub mod verification;
 mod serde;
 
 /// The name of the directory that contains all of the CrateNamespace files.
@@ -69,7 +93,11 @@ pub fn get_namespaces_directory() -> Option<DirRef> {
 /// 
 /// Thus, we stick with a singleton `TlsInitializer` instance, which makes sense 
 /// because it behaves much like an allocator, in that it reserves space (index ranges) in the TLS area.
-static TLS_INITIALIZER: Mutex<TlsInitializer> = Mutex::new(TlsInitializer::empty());
+static TLS_INITIALIZER: Mutex<TlsInitializer> = Mutex::new(TlsInitializer::new(&TLS_TEMPLATE));
+
+/// The most recently generated TLS data image from the [`static@TLS_INITIALIZER`],
+/// which allows new tasks to obtain a copy of it without acquiring that lock.
+static TLS_TEMPLATE: TlsTemplateCell = TlsTemplateCell::empty();
 
 
 /// Create a new application `CrateNamespace` that uses the default application directory 
@@ -110,6 +138,69 @@ pub fn init(
     // Create the default CrateNamespace for kernel crates.
     let name = default_kernel_namespace_dir.lock().get_name();
     let default_namespace = CrateNamespace::new(name, default_kernel_namespace_dir, None);
+    if let Err(e) = tls_initializer::register_metrics() {
+        warn!("Failed to register TLS metrics: {}", e);
+    }
+
+    // The nano_core's static TLS sections have fixed offsets, but no dynamic TLS sections exist yet.
+    #[cfg(kasan)]
+    TLS_INITIALIZER.lock().set_redzones(true)?;
+
+    #[cfg(record_load_trace)]
+    load_trace::start_recording();
+    let replay_file = root::get_root().lock()
+        .get_dir(EXTRA_FILES_DIRECTORY_NAME)
+        .and_then(|dir| dir.lock().get_file(load_trace::REPLAY_FILE_NAME));
+    if let Some(file) = replay_file {
+        let file = file.lock();
+        let trace = file.as_mapping()?.as_slice::<u8>(0, file.len())?;
+        let trace = core::str::from_utf8(trace).map_err(|_| "load trace file was not valid UTF-8")?;
+        let events = load_trace::parse(trace)?;
+        info!("Replaying load trace with {} events from {:?}", events.len(), file.get_absolute_path());
+        load_trace::start_replay(events)?;
+    }
+
+    #[cfg(record_prelink_cache)]
+    prelink::start_recording();
+    let prelink_file = root::get_root().lock()
+        .get_dir(EXTRA_FILES_DIRECTORY_NAME)
+        .and_then(|dir| dir.lock().get_file(prelink::CACHE_FILE_NAME));
+    if let Some(file) = prelink_file {
+        let file = file.lock();
+        let cache = file.as_mapping()?.as_slice::<u8>(0, file.len())?;
+        let cache = core::str::from_utf8(cache).map_err(|_| "prelink cache file was not valid UTF-8")?;
+        let crates = prelink::parse(cache)?;
+        info!("Using prelink cache with {} crates from {:?}", crates.len(), file.get_absolute_path());
+        prelink::use_cache(crates)?;
+    }
+
+    #[cfg(crate_verification_warn)]
+    verification::set_policy(verification::VerificationPolicy::Warn);
+    #[cfg(crate_verification_enforce)]
+    verification::set_policy(verification::VerificationPolicy::Enforce);
+    let trust_list_file = root::get_root().lock()
+        .get_dir(EXTRA_FILES_DIRECTORY_NAME)
+        .and_then(|dir| dir.lock().get_file(verification::TRUST_LIST_FILE_NAME));
+    if let Some(file) = trust_list_file {
+        let file = file.lock();
+        let trust_list = file.as_mapping()?.as_slice::<u8>(0, file.len())?;
+        let trust_list = core::str::from_utf8(trust_list).map_err(|_| "crate trust list file was not valid UTF-8")?;
+        let (num_keys, num_hashes) = verification::parse_trust_list(trust_list)?;
+        info!("Trusting {} keys and {} crate hashes from {:?}", num_keys, num_hashes, file.get_absolute_path());
+    }
+
+    let preload_file = root::get_root().lock()
+        .get_dir(EXTRA_FILES_DIRECTORY_NAME)
+        .and_then(|dir| dir.lock().get_file(parallel::PRELOAD_FILE_NAME));
+    if let Some(file) = preload_file {
+        let file = file.lock();
+        let preload_list = file.as_mapping()?.as_slice::<u8>(0, file.len())?;
+        let preload_list = core::str::from_utf8(preload_list).map_err(|_| "crate preload list file was not valid UTF-8")?;
+        let preload_list = parallel::parse_preload_list(preload_list);
+        info!("Preloading {} crates from {:?} once all CPUs are up", preload_list.len(), file.get_absolute_path());
+        parallel::set_preload_list(preload_list);
+    }
+
     Ok(INITIAL_KERNEL_NAMESPACE.call_once(|| Arc::new(default_namespace)))
 }
 
@@ -282,6 +373,13 @@ fn parse_extra_file(
 /// This is used for relocations, and for looking up function names.
 pub type SymbolMap = Trie<StrRef, WeakSectionRef>;
 
+/// A cache of the TLS offset of each TLS section that is the source of a relocation,
+/// since finding a section's TLS offset requires searching through the `TlsInitializer`.
+///
+/// This is only valid while loading a single crate, during which no static TLS sections are added
+/// and no dynamic TLS sections are moved, so the offset of each TLS section doesn't change.
+type TlsOffsetCache = BTreeMap<*const LoadedSection, isize>;
+
 
 /// A wrapper around a `Directory` reference that offers special convenience functions
 /// for getting and inserting crate object files into a directory.  
@@ -450,6 +548,20 @@ impl Drop for AppCrateRef {
 
 
 /// This struct represents a namespace of crates and their "global" (publicly-visible) symbols.
+/// An existing dynamic TLS section that should be replaced by a TLS section
+/// in a crate that is about to be loaded, e.g., when swapping crates.
+///
+/// See [`CrateNamespace::replace_tls_sections_on_load()`].
+#[derive(Debug, Clone)]
+pub struct TlsSectionReplacement {
+    /// The name of the crate that will contain the replacing TLS section.
+    pub new_crate_name: String,
+    /// The name of the replacing TLS section, without its trailing hash.
+    pub new_section_name_without_hash: String,
+    /// The existing TLS section to be replaced.
+    pub old_section: StrongSectionRef,
+}
+
 /// A crate namespace struct is basically a container around many crates 
 /// that have all been loaded and linked against each other, 
 /// completely separate and in isolation from any other crate namespace 
@@ -488,6 +600,11 @@ pub struct CrateNamespace {
     /// Symbols declared as "no_mangle" will appear in the map with no crate prefix, as expected.
     symbol_map: Mutex<SymbolMap>,
 
+    /// The versioning metadata of each global symbol defined by the crates in this `CrateNamespace`,
+    /// keyed by the same fully-qualified symbol name as the `symbol_map`.
+    /// See the [`symbol_version`] module for more.
+    symbol_versions: Mutex<HashMap<StrRef, SymbolVersion>>,
+
     /// The `CrateNamespace` that lies below this namespace, and can also be used by this namespace
     /// to resolve symbols and load crates that are relied on by other crates in this namespace.
     /// So, for example, if this namespace contains a set of application crates,
@@ -502,6 +619,10 @@ pub struct CrateNamespace {
     /// NOTE: this is currently a global system-wide singleton. See the static [`static@TLS_INITIALIZER`] for more.
     tls_initializer: &'static Mutex<TlsInitializer>,
 
+    /// The most recently published data image from the above `tls_initializer`,
+    /// from which new tasks can obtain their TLS area without locking the `tls_initializer`.
+    tls_template: &'static TlsTemplateCell,
+
     /// A setting that toggles whether to ignore hash differences in symbols when resolving a dependency. 
     /// For example, if `true`, the symbol `my_crate::foo::h123` will be used to satisfy a dependency 
     /// on any other `my_crate::foo::*` regardless of hash value. 
@@ -513,6 +634,19 @@ pub struct CrateNamespace {
     /// Thus, it is false by default, and should only be enabled with expert knowledge, 
     /// ideally only temporarily in order to manually load a given crate.
     fuzzy_symbol_matching: bool,
+
+    /// The existing TLS sections that should be replaced by TLS sections
+    /// in crates that are loaded into this namespace, rather than placed at new offsets.
+    tls_replacements: Mutex<Vec<TlsSectionReplacement>>,
+
+    /// A weak reference to this namespace if lazy binding is enabled for it, which each stub uses
+    /// to resolve its symbol. See the [`lazy_binding`] module for more.
+    lazy_binding: Mutex<Option<Weak<CrateNamespace>>>,
+
+    /// The restrictions on which symbols this namespace may resolve from its `recursive_namespace`
+    /// and where its TLS sections are placed, if it is sandboxed.
+    /// See the [`sandbox`] module for more.
+    sandbox: Option<Arc<Sandbox>>,
 }
 
 impl CrateNamespace {
@@ -528,12 +662,56 @@ impl CrateNamespace {
             dir,
             recursive_namespace,
             tls_initializer: &TLS_INITIALIZER,
+            tls_template: &TLS_TEMPLATE,
             crate_tree: Mutex::new(Trie::new()),
             symbol_map: Mutex::new(SymbolMap::new()),
+            symbol_versions: Mutex::new(HashMap::new()),
             fuzzy_symbol_matching: false,
+            tls_replacements: Mutex::new(Vec::new()),
+            lazy_binding: Mutex::new(None),
+            sandbox: None,
         }
     } 
 
+    /// Creates a new sandboxed `CrateNamespace` that is completely empty (no loaded crates),
+    /// which may only resolve the symbols of its `recursive_namespace` that are on the `allowed_symbols` list.
+    /// See the [`sandbox`] module for more.
+    ///
+    /// # Arguments
+    /// * `name`, `dir`: the same as for [`CrateNamespace::new()`].
+    /// * `recursive_namespace`: the namespace used to resolve the allowed missing crates/symbols.
+    /// * `allowed_symbols`: the symbols of the `recursive_namespace` that may be resolved.
+    /// * `tls_region_size`: the size of the region of the TLS area into which the TLS sections
+    ///    of all crates loaded into this namespace are placed.
+    ///    If `0`, its TLS sections are placed alongside all other TLS sections.
+    pub fn new_sandboxed(
+        name: String,
+        dir: NamespaceDir,
+        recursive_namespace: Arc<CrateNamespace>,
+        allowed_symbols: SymbolAllowList,
+        tls_region_size: usize,
+    ) -> Result<CrateNamespace, &'static str> {
+        let mut namespace = CrateNamespace::new(name, dir, Some(recursive_namespace));
+        namespace.sandbox = Some(Arc::new(Sandbox::new(allowed_symbols, namespace.tls_initializer, tls_region_size)?));
+        Ok(namespace)
+    }
+
+    /// Returns the list of symbols that this namespace may resolve from its recursive namespace,
+    /// or `None` if this namespace isn't sandboxed and may thus resolve all of them.
+    pub fn allowed_symbols(&self) -> Option<&SymbolAllowList> {
+        self.sandbox.as_ref().map(|sandbox| &sandbox.allowed_symbols)
+    }
+
+    /// Returns the range of offsets of the TLS region reserved for this namespace's TLS sections, if any.
+    pub fn tls_region(&self) -> Option<Range<usize>> {
+        self.sandbox.as_ref().and_then(|sandbox| sandbox.tls_region.clone())
+    }
+
+    /// Returns whether this namespace may resolve the given symbol from its recursive namespace.
+    fn may_resolve_recursively(&self, demangled_full_symbol: &str) -> bool {
+        self.allowed_symbols().map_or(true, |allowed| allowed.allows(demangled_full_symbol))
+    }
+
     /// Returns the name of this `CrateNamespace`, which is just used for debugging purposes. 
     pub fn name(&self) -> &str {
         &self.name
@@ -552,8 +730,330 @@ impl CrateNamespace {
 
     /// Returns a new copy of this namespace's initial TLS area,
     /// which can be used as the initial TLS area data for a new task.
-    pub fn get_tls_initializer_data(&self) -> TlsDataImage {
-        self.tls_initializer.lock().get_data()
+    ///
+    /// The given `hint` determines where the new copy's memory is allocated;
+    /// see [`TlsAllocHint`].
+    pub fn get_tls_initializer_data(&self, hint: TlsAllocHint) -> TlsDataImage {
+        // Fast path: copy the most recently published data image without locking.
+        if !self.tls_template.is_stale() {
+            if let Some(tls_image) = self.tls_template.get_data(hint) {
+                return tls_image;
+            }
+        }
+        // The published data image is out of date. If another CPU is busy re-generating it,
+        // e.g., while loading a new crate, we copy the previous one rather than waiting,
+        // because the TLS sections it lacks belong to crates that aren't yet fully loaded.
+        match self.tls_initializer.try_lock() {
+            Some(mut tls_initializer) => tls_initializer.get_data(hint),
+            None => self.tls_template.get_data(hint)
+                .unwrap_or_else(|| self.tls_initializer.lock().get_data(hint)),
+        }
+    }
+
+    /// Like [`Self::get_tls_initializer_data()`], but returns an error if the new TLS area
+    /// cannot be allocated under memory pressure, even after reclaiming memory.
+    ///
+    /// See [`MemoryPressureHooks`] for more.
+    pub fn try_get_tls_initializer_data(&self, hint: TlsAllocHint) -> Result<TlsDataImage, &'static str> {
+        if !self.tls_template.is_stale() {
+            if let Some(tls_image) = self.tls_template.try_get_data(hint) {
+                return tls_image;
+            }
+        }
+        match self.tls_initializer.try_lock() {
+            Some(mut tls_initializer) => tls_initializer.try_get_data(hint),
+            None => self.tls_template.try_get_data(hint)
+                .unwrap_or_else(|| self.tls_initializer.lock().try_get_data(hint)),
+        }
+    }
+
+    /// Creates a minimal TLS area for the current CPU from this namespace's static TLS sections
+    /// and installs it into the current CPU's TLS register.
+    ///
+    /// This is used while booting up each CPU, before that CPU's bootstrap task exists.
+    /// See [`init_bootstrap_tls()`] for more.
+    pub fn init_bootstrap_tls(&self, cpu_id: Option<u8>) -> Result<TlsDataImage, &'static str> {
+        let (total_static_size, tdata_template) = self.tls_initializer.lock().static_tls_template();
+        init_bootstrap_tls(total_static_size, &tdata_template, cpu_id)
+    }
+
+    /// Creates a full TLS area for a CPU that is coming online from this namespace's current TLS sections,
+    /// including those of crates that were loaded after boot,
+    /// and installs it into the current CPU's TLS register.
+    ///
+    /// This is used while starting up an application processor (AP), including one that was
+    /// hot-added after boot, before that CPU's bootstrap task exists.
+    /// Unlike the area from [`Self::init_bootstrap_tls()`], which only contains the static TLS sections,
+    /// the returned area allows any TLS variable to be accessed on that CPU.
+    /// The returned image must be kept alive until the bootstrap task's TLS area has been installed.
+    pub fn init_online_cpu_tls(&self, cpu_id: u8) -> Result<TlsDataImage, &'static str> {
+        let tls_image = self.get_tls_initializer_data(TlsAllocHint::Cpu(cpu_id));
+        if tls_image.self_pointer() == 0 {
+            return Err("cannot create a TLS area for an onlined CPU without any TLS sections");
+        }
+        tls_image.force_set_as_current_tls_base(Some(cpu_id));
+        Ok(tls_image)
+    }
+
+    /// Generates a new interrupt TLS area from this namespace's current TLS sections
+    /// for the CPU with the given `cpu_id`, replacing its previous one.
+    ///
+    /// See [`init_interrupt_tls()`] for more.
+    pub fn init_interrupt_tls(&self, cpu_id: u8) -> Result<(), &'static str> {
+        init_interrupt_tls(cpu_id, self.get_tls_initializer_data(TlsAllocHint::Cpu(cpu_id)))
+    }
+
+    /// Returns a new TLS area generated from this namespace's current TLS sections,
+    /// with the contents of each section restored from the given `snapshot`.
+    ///
+    /// See [`TlsInitializer::restore_snapshot()`] for how sections are matched.
+    pub fn restore_tls_snapshot(&self, snapshot: &TlsSnapshot, hint: TlsAllocHint) -> Result<TlsDataImage, &'static str> {
+        self.tls_initializer.lock().restore_snapshot(snapshot, hint)
+    }
+
+    /// Returns a new TLS area generated from this namespace's current TLS sections
+    /// that preserves the live contents of the given `tls_area`.
+    ///
+    /// See [`TlsInitializer::refresh_image()`] for more.
+    ///
+    /// # Safety
+    /// The task that owns the given `tls_area` must not be running.
+    pub unsafe fn refresh_tls_image(
+        &self,
+        tls_area: &TlsDataImage,
+        replaced_sections: &[(StrongSectionRef, StrongSectionRef)],
+    ) -> Result<TlsDataImage, &'static str> {
+        // SAFETY: ensured by the caller.
+        unsafe { self.tls_initializer.lock().refresh_image(tls_area, replaced_sections) }
+    }
+
+    /// Returns a new TLS area generated from this namespace's current TLS sections
+    /// that preserves the live contents of the given `tls_area`,
+    /// after this namespace's static TLS sections have been replaced.
+    ///
+    /// See [`TlsInitializer::rebase_image()`] and the [`static_tls`] module for more.
+    ///
+    /// # Safety
+    /// The task that owns the given `tls_area` must not be running.
+    pub unsafe fn rebase_tls_image(
+        &self,
+        tls_area: &TlsDataImage,
+        remappings: &[TlsSectionRemapping],
+    ) -> Result<TlsDataImage, &'static str> {
+        // SAFETY: ensured by the caller.
+        unsafe { self.tls_initializer.lock().rebase_image(tls_area, remappings) }
+    }
+
+    /// Requests that each of the given existing TLS sections be replaced by
+    /// the matching TLS section in a crate that is subsequently loaded into this namespace,
+    /// such that the new TLS section occupies the same offset in every TLS area.
+    ///
+    /// A new TLS section matches a replacement if it belongs to the crate named `new_crate_name`,
+    /// its name without the trailing hash is `new_section_name_without_hash`,
+    /// and it has the same type and size as the `old_section`.
+    /// New TLS sections without a matching replacement are placed at new offsets, as usual.
+    pub fn replace_tls_sections_on_load(&self, replacements: impl IntoIterator<Item = TlsSectionReplacement>) {
+        self.tls_replacements.lock().extend(replacements);
+    }
+
+    /// Removes the given dynamic TLS `section` from this namespace's TLS initializer,
+    /// such that new TLS areas no longer include it.
+    pub fn remove_tls_section(&self, section: &StrongSectionRef) -> Result<(), &'static str> {
+        self.tls_initializer.lock().remove_dynamic_tls_section(section)
+            .map_err(|_| "TLS section was not a dynamic TLS section in this namespace's TLS initializer")?;
+        self.publish_tls_template();
+        Ok(())
+    }
+
+    /// Adds the `PT_TLS` segment (program header) of the given fully-linked ELF executable
+    /// to this namespace's TLS initializer as a single TLS block,
+    /// such that every TLS area created from now on includes it.
+    ///
+    /// Unlike a relocatable crate object file, whose `.tdata` and `.tbss` sections are added separately,
+    /// an executable's TLS sections were already merged into one block by the static linker:
+    /// its initialized data (`p_filesz` bytes) is followed by zeroed data up to `p_memsz` bytes,
+    /// and it must be aligned to `p_align`.
+    ///
+    /// Returns `None` if the executable has no `PT_TLS` segment or if that segment is empty.
+    /// Otherwise, returns a tuple of:
+    /// 1. The offset of the TLS block from the TLS self pointer,
+    ///    which the executable's TLS accesses must use instead of the offset chosen by the static linker.
+    /// 2. The section that represents the TLS block, which does not belong to any crate.
+    ///    It should be removed via [`remove_tls_section()`](Self::remove_tls_section)
+    ///    once the executable is no longer running.
+    pub fn load_executable_tls_segment(
+        &self,
+        executable_file: &dyn File,
+        kernel_mmi_ref: &MmiRef,
+    ) -> Result<Option<(usize, StrongSectionRef)>, &'static str> {
+        let file_mp = executable_file.as_mapping()?;
+        let elf_file = ElfFile::new(file_mp.as_slice(0, executable_file.len())?)?;
+        use xmas_elf::header::Type;
+        let typ = elf_file.header.pt2.type_().as_type();
+        // Position-independent executables are shared objects.
+        if typ != Type::Executable && typ != Type::SharedObject {
+            error!("load_executable_tls_segment(): file {:?} was a {:?} Elf File, must be Executable!", executable_file.get_name(), typ);
+            return Err("not an executable elf file");
+        }
+
+        let segment = match find_pt_tls_segment(&elf_file)? {
+            Some(segment) if segment.mem_size > 0 => segment,
+            _ => return Ok(None),
+        };
+        if segment.file_data.len() > segment.mem_size {
+            return Err("PT_TLS segment's file size is larger than its memory size");
+        }
+        let alignment = segment.align.max(1);
+        if !alignment.is_power_of_two() {
+            return Err("PT_TLS segment's alignment is not a power of two");
+        }
+
+        let (typ, mapped_pages) = if segment.file_data.is_empty() {
+            // A `.tbss`-only block is entirely zeroed, so its data needn't be stored.
+            (SectionType::TlsBss, MappedPages::empty())
+        } else {
+            let mut mp = allocate_and_map_as_writable(segment.mem_size, PteFlags::new(), kernel_mmi_ref)?;
+            let block: &mut [u8] = mp.as_slice_mut(0, segment.mem_size)?;
+            block[.. segment.file_data.len()].copy_from_slice(segment.file_data);
+            block[segment.file_data.len() ..].fill(0);
+            // The TLS block's data is only read when generating TLS data images.
+            mp.remap(&mut kernel_mmi_ref.lock().page_table, PteFlags::new().valid(true))?;
+            (SectionType::TlsData, mp)
+        };
+        let section = LoadedSection::new(
+            typ,
+            StrRef::from(executable_file.get_name().as_str()),
+            Arc::new(Mutex::new(mapped_pages)),
+            0,
+            VirtualAddress::zero(), // will be set by the TLS initializer
+            segment.mem_size,
+            false,
+            CowWeak::new(),
+        );
+        let loaded = self.tls_initializer.lock().add_new_dynamic_tls_section(section, alignment)
+            .map_err(|_| "couldn't add executable's PT_TLS segment to the TLS initializer")?;
+        self.publish_tls_template();
+        Ok(Some(loaded))
+    }
+
+    /// Adds the given new TLS `sections` of a single crate, each with its section index and alignment,
+    /// to this namespace's TLS initializer, replacing existing TLS sections if requested via
+    /// [`Self::replace_tls_sections_on_load()`].
+    ///
+    /// The sections are added together such that they can be placed deterministically;
+    /// see [`Self::set_deterministic_tls_layout()`].
+    /// Returns the section index and modified section of each given section.
+    fn add_tls_sections(&self, sections: Vec<(Shndx, LoadedSection, usize)>) -> Result<Vec<(Shndx, StrongSectionRef)>, ()> {
+        let mut added = Vec::with_capacity(sections.len());
+        let mut new_shndxs = Vec::with_capacity(sections.len());
+        let mut new_sections = Vec::with_capacity(sections.len());
+        let crate_name = sections.first()
+            .and_then(|(_, section, _)| section.parent_crate.upgrade())
+            .map(|parent| parent.lock_as_ref().crate_name.to_string())
+            .unwrap_or_default();
+        for (shndx, section, alignment) in sections {
+            // The TLS sections of a sandboxed namespace's crates are only placed into its own TLS region.
+            if let Some(region) = self.tls_region() {
+                added.push((shndx, self.tls_initializer.lock().add_new_dynamic_tls_section_in_region(section, &region, alignment)?.1));
+                continue;
+            }
+            if let Some(r) = self.take_tls_replacement(&section)
+                && r.old_section.virt_addr.value() % alignment.max(1) == 0
+            {
+                let (_tls_offset, section) = self.tls_initializer.lock()
+                    .replace_dynamic_tls_section(&r.old_section, section, alignment)?;
+                added.push((shndx, section));
+                continue;
+            }
+            // When replaying a load trace, using the prelink cache, or loading the members of an archive,
+            // place the section at its recorded or reserved offset if that's still possible.
+            if let Some(offset) = load_trace::replayed_tls_offset(&crate_name, &section.name)
+                .or_else(|| prelink::cached_tls_offset(&crate_name, &section.name))
+                .or_else(|| archive::reserved_tls_offset(&self.name, &crate_name, section.size, alignment))
+            {
+                let mut tls_initializer = self.tls_initializer.lock();
+                if tls_initializer.can_add_dynamic_tls_section_at(offset, section.size, alignment) {
+                    added.push((shndx, tls_initializer.add_new_dynamic_tls_section_at(section, offset, alignment)?));
+                    continue;
+                }
+            }
+            new_shndxs.push(shndx);
+            new_sections.push((section, alignment));
+        }
+        if !new_sections.is_empty() {
+            let new_sections = self.tls_initializer.lock().add_new_dynamic_tls_sections(new_sections)?;
+            added.extend(new_shndxs.into_iter().zip(new_sections.into_iter().map(|(_tls_offset, sec)| sec)));
+        }
+        for (_shndx, section) in &added {
+            load_trace::record_tls_offset(&crate_name, &section.name, section.virt_addr.value());
+        }
+        Ok(added)
+    }
+
+    /// Removes and returns the pending replacement for the given new TLS `section`, if any.
+    fn take_tls_replacement(&self, section: &LoadedSection) -> Option<TlsSectionReplacement> {
+        let mut replacements = self.tls_replacements.lock();
+        if replacements.is_empty() {
+            return None;
+        }
+        let crate_name = section.parent_crate.upgrade()
+            .map(|parent| parent.lock_as_ref().crate_name.clone());
+        let name_without_hash = LoadedSection::section_name_without_hash(&section.name);
+        replacements.iter()
+            .position(|r| Some(r.new_crate_name.as_str()) == crate_name.as_deref()
+                && r.new_section_name_without_hash == name_without_hash
+                && r.old_section.typ == section.typ
+                && r.old_section.size == section.size
+            )
+            .map(|index| replacements.swap_remove(index))
+    }
+
+    /// Enables or disables deterministic placement of the TLS sections of crates loaded from now on,
+    /// such that the TLS layout doesn't depend on the timing of crate loading,
+    /// e.g., for verifying reproducible builds or for record/replay debugging.
+    ///
+    /// This must be done before any crates with TLS sections are dynamically loaded.
+    /// See [`TlsInitializer::set_deterministic_layout()`] for more.
+    pub fn set_deterministic_tls_layout(&self, enabled: bool) -> Result<(), &'static str> {
+        self.tls_initializer.lock().set_deterministic_layout(enabled)
+    }
+
+    /// Returns a hash of this namespace's current TLS layout,
+    /// which can be compared across boots to check whether they have identical TLS layouts.
+    ///
+    /// See [`TlsInitializer::layout_hash()`] for more.
+    pub fn tls_layout_hash(&self) -> u64 {
+        self.tls_initializer.lock().layout_hash()
+    }
+
+    /// Returns a description of this namespace's current TLS layout, including every TLS section.
+    ///
+    /// See [`TlsInitializer::layout_info()`] for more.
+    pub fn tls_layout_info(&self) -> TlsLayoutInfo {
+        self.tls_initializer.lock().layout_info()
+    }
+
+    /// Sets the layout of the Thread Control Block (TCB) header at the start of
+    /// every TLS area created from now on, e.g., to support a ported C library.
+    ///
+    /// This must be done before any crates with TLS sections are dynamically loaded.
+    /// See [`TcbLayout`] for more.
+    pub fn set_tcb_layout(&self, tcb_layout: TcbLayout) -> Result<(), &'static str> {
+        let mut tls_initializer = self.tls_initializer.lock();
+        tls_initializer.set_tcb_layout(tcb_layout)?;
+        tls_initializer.publish();
+        Ok(())
+    }
+
+    /// Re-generates this namespace's TLS data image (if needed) and publishes it
+    /// such that new tasks can obtain it without locking the `TlsInitializer`.
+    ///
+    /// This is invoked after a crate has been fully loaded, since loading a crate
+    /// may add or modify TLS sections.
+    fn publish_tls_template(&self) {
+        if self.tls_template.is_stale() {
+            self.tls_initializer.lock().publish();
+        }
     }
 
     #[doc(hidden)]
@@ -566,6 +1066,15 @@ impl CrateNamespace {
         &self.symbol_map
     }
 
+    /// Returns the versioning metadata of the global symbol with the given fully-qualified name,
+    /// searching this namespace first and then its recursive namespace.
+    ///
+    /// See the [`symbol_version`] module for more.
+    pub fn symbol_version(&self, demangled_full_symbol: &str) -> Option<SymbolVersion> {
+        self.symbol_versions.lock().get(demangled_full_symbol.as_bytes()).cloned()
+            .or_else(|| self.recursive_namespace.as_ref().and_then(|r_ns| r_ns.symbol_version(demangled_full_symbol)))
+    }
+
     #[doc(hidden)]
     pub fn enable_fuzzy_symbol_matching(&mut self) {
         self.fuzzy_symbol_matching = true;
@@ -878,9 +1387,27 @@ impl CrateNamespace {
         kernel_mmi_ref: &MmiRef, 
         verbose_log: bool
     ) -> Result<StrongCrateRef, &'static str> {
+        let start = tracepoint::timestamp();
         let cf = crate_object_file.lock();
         let (new_crate_ref, elf_file) = self.load_crate_sections(cf.deref(), kernel_mmi_ref, verbose_log)?;
-        self.perform_relocations(&elf_file, &new_crate_ref, temp_backup_namespace, kernel_mmi_ref, verbose_log)?;
+        // Apply the crate's relocations from the prelink cache, if they're still valid.
+        let prelinked = temp_backup_namespace.is_none()
+            .then(|| prelink::take_cached(&self.name, &new_crate_ref.lock_as_ref().crate_name))
+            .flatten();
+        let used_prelink_cache = match prelinked {
+            Some(prelinked) => {
+                let hit = self.perform_prelinked_relocations(&elf_file, &new_crate_ref, &prelinked, kernel_mmi_ref)?;
+                prelink::count(hit);
+                hit
+            }
+            None => false,
+        };
+        if !used_prelink_cache {
+            self.perform_relocations(&elf_file, &new_crate_ref, temp_backup_namespace, kernel_mmi_ref, verbose_log)?;
+        }
+        self.publish_tls_template();
+        let num_sections = new_crate_ref.lock_as_ref().sections.len();
+        tracepoint::trace(Tracepoint::CrateLoad, num_sections as u64, tracepoint::timestamp().wrapping_sub(start));
         Ok(new_crate_ref)
     }
 
@@ -924,10 +1451,228 @@ impl CrateNamespace {
             self.crate_tree.lock().insert(name, new_crate_ref);
         }
 
+        self.publish_tls_template();
+        Ok(())
+    }
+
+
+    /// Similar to [`load_crates()`](#method.load_crates), but loads the sections of the given crates
+    /// on multiple CPUs using the registered [`parallel::ParallelExecutor`], if any.
+    ///
+    /// Only the parsing and mapping of each crate's sections runs in parallel;
+    /// adding symbols to the symbol map and performing relocations are done serially afterwards,
+    /// so the given crates may still depend on each other.
+    /// See the [`parallel`] module for more.
+    ///
+    /// The durations of each phase are available from [`parallel::last_stats()`] afterwards.
+    pub fn load_crates_parallel<'f, I>(
+        &self,
+        crate_files: I,
+        temp_backup_namespace: Option<&CrateNamespace>,
+        kernel_mmi_ref: &MmiRef,
+        verbose_log: bool,
+    ) -> Result<(), &'static str>
+        where I: Iterator<Item = &'f FileRef>
+    {
+        let crate_files: Vec<&FileRef> = crate_files.collect();
+
+        // First, load the sections of each crate in parallel.
+        // The ELF file borrows the locked crate object file, so it is re-parsed in the last phase.
+        let start = tracepoint::timestamp();
+        let results: Vec<Mutex<Option<Result<StrongCrateRef, &'static str>>>> =
+            crate_files.iter().map(|_| Mutex::new(None)).collect();
+        let section_loading_serial = parallel::run(crate_files.len(), &|i| {
+            let locked_crate_file = crate_files[i].lock();
+            let result = self.load_crate_sections(locked_crate_file.deref(), kernel_mmi_ref, verbose_log)
+                .map(|(new_crate_ref, _elf_file)| new_crate_ref);
+            *results[i].lock() = Some(result);
+        });
+        let mut new_crates = Vec::with_capacity(results.len());
+        for result in results {
+            new_crates.push(result.into_inner().ok_or("BUG: load_crates_parallel(): a job didn't run")??);
+        }
+        let section_loading = tracepoint::timestamp().wrapping_sub(start);
+
+        // Second, add all public symbols to the symbol map.
+        let start = tracepoint::timestamp();
+        for new_crate_ref in &new_crates {
+            let _new_syms = self.add_symbols(new_crate_ref.lock_as_ref().sections.values(), verbose_log);
+        }
+        let symbols = tracepoint::timestamp().wrapping_sub(start);
+
+        // Finally, we do all of the relocations.
+        let start = tracepoint::timestamp();
+        for (crate_file, new_crate_ref) in crate_files.iter().zip(new_crates) {
+            let locked_crate_file = crate_file.lock();
+            let elf_file = ElfFile::new(locked_crate_file.as_mapping()?.as_slice(0, locked_crate_file.len())?)?;
+            self.perform_relocations(&elf_file, &new_crate_ref, temp_backup_namespace, kernel_mmi_ref, verbose_log)?;
+            let name = new_crate_ref.lock_as_ref().crate_name.clone();
+            self.crate_tree.lock().insert(name, new_crate_ref);
+        }
+        let relocations = tracepoint::timestamp().wrapping_sub(start);
+
+        self.publish_tls_template();
+        parallel::record_stats(parallel::PipelineStats {
+            crates: crate_files.len(),
+            section_loading,
+            section_loading_serial,
+            symbols,
+            relocations,
+        });
         Ok(())
     }
 
 
+    /// Loads all of the object files in the given `ar` archive, e.g., an `.rlib` produced by cargo,
+    /// such that unmodified cargo artifacts can be loaded without first repacking them into one object file.
+    ///
+    /// Each object file member is extracted into a file next to the archive,
+    /// named by [`archive::member_file_name()`], and is loaded as its own crate.
+    /// Members that were already extracted by a previous invocation are reused.
+    /// All members are loaded together via [`load_crates()`](#method.load_crates),
+    /// so the codegen units of the archived crate may depend on each other,
+    /// and their TLS sections are placed into one contiguous range of the TLS area if possible.
+    ///
+    /// See [`load_crate()`](#method.load_crate) for a description of the other arguments.
+    ///
+    /// Returns the newly-loaded crates, one per object file member, in archive order.
+    pub fn load_crate_archive(
+        &self,
+        archive_file: &FileRef,
+        temp_backup_namespace: Option<&CrateNamespace>,
+        kernel_mmi_ref: &MmiRef,
+        verbose_log: bool,
+    ) -> Result<Vec<StrongCrateRef>, &'static str> {
+        let (member_files, (tls_size, tls_alignment)) = {
+            let locked_archive = archive_file.lock();
+            let bytes: &[u8] = locked_archive.as_mapping()?.as_slice(0, locked_archive.len())?;
+            let archive_path = Path::new(locked_archive.get_absolute_path());
+            // The members of a verified archive are trusted as well.
+            verification::verify(archive_path.basename(), bytes)?;
+            let archive_crate_name = archive::crate_name_from_archive_path(&archive_path);
+            let dir = locked_archive.get_parent_dir()
+                .ok_or("load_crate_archive(): couldn't get the archive's parent directory")?;
+
+            let members = archive::object_members(bytes)?;
+            if members.is_empty() {
+                return Err("load_crate_archive(): the archive doesn't contain any object files");
+            }
+            let mut member_files = Vec::with_capacity(members.len());
+            for (index, member) in members.iter().enumerate() {
+                verification::trust_extracted(member.data);
+                let file_name = archive::member_file_name(archive_crate_name, index);
+                let existing_file = dir.lock().get_file(&file_name);
+                let member_file = match existing_file {
+                    Some(f) => f,
+                    None => {
+                        let f = MemFile::create(file_name, &dir)?;
+                        f.lock().write_at(member.data, 0)?;
+                        f
+                    }
+                };
+                member_files.push(member_file);
+            }
+            (member_files, archive::tls_reservation_size(&members)?)
+        };
+        let member_crate_names: Vec<String> = member_files.iter()
+            .map(|f| crate_name_from_path(&Path::new(f.lock().get_name())).into())
+            .collect();
+        #[cfg(not(loscd_eval))]
+        debug!("load_crate_archive: loading {} object files from {:?}", member_files.len(), archive_file.lock().get_absolute_path());
+
+        // Reserve one range of the TLS area for the TLS sections of all members.
+        let tls_reservation = if tls_size > 0 {
+            self.tls_initializer.lock().find_dynamic_tls_gap(tls_size, tls_alignment)
+                .map(|start| start .. start + tls_size)
+        } else {
+            None
+        };
+        if let Some(ref range) = tls_reservation {
+            archive::reserve_tls(&self.name, member_crate_names.iter().cloned().collect(), range.clone());
+        }
+        let result = self.load_crates(member_files.iter(), temp_backup_namespace, kernel_mmi_ref, verbose_log);
+        if let Some(range) = tls_reservation {
+            archive::release_tls_reservation(&self.name, range.start);
+        }
+        result?;
+
+        member_crate_names.iter()
+            .map(|name| self.get_crate(name).ok_or("BUG: load_crate_archive(): couldn't get a newly-loaded member crate"))
+            .collect()
+    }
+
+
+    /// Unloads the crate with the given `crate_name` from this namespace.
+    ///
+    /// This removes the crate's TLS sections from the namespace's TLS initializer,
+    /// removes its global and reexported symbols from the namespace's symbol map,
+    /// and removes the crate itself from this namespace.
+    /// The crate's sections are unmapped once the last reference to the crate is dropped.
+    ///
+    /// If `force` is `false`, this refuses to unload a crate that is still in use, i.e.,
+    /// if any other crate depends on it, if it is shared with another namespace,
+    /// or if anything else (e.g., a running application task) holds a reference to it.
+    /// If `force` is `true`, the crate is unloaded anyway, but its sections remain mapped
+    /// until all remaining references to it have been dropped.
+    ///
+    /// Only crates in this namespace itself are considered, not those in its recursive namespace.
+    pub fn unload_crate(&self, crate_name: &str, force: bool) -> Result<(), &'static str> {
+        let crate_ref = self.crate_tree.lock().get(crate_name.as_bytes())
+            .map(CowArc::clone_shallow)
+            .ok_or("unload_crate(): crate not found in this namespace")?;
+
+        if !force {
+            // One reference is held by this namespace's crate tree and one by `crate_ref` above.
+            if crate_ref.is_shared() || CowArc::strong_count(&crate_ref) > 2 {
+                return Err("unload_crate(): crate is still in use");
+            }
+            let dependents = crate_ref.lock_as_ref().crates_dependent_on_me();
+            let has_other_dependents = dependents.iter()
+                .filter_map(|weak_crate| weak_crate.upgrade())
+                .any(|dependent| dependent.lock_as_ref().crate_name.as_str() != crate_name);
+            if has_other_dependents {
+                return Err("unload_crate(): other crates depend on this crate");
+            }
+        }
+
+        {
+            let krate = crate_ref.lock_as_ref();
+
+            // Remove this crate's TLS sections such that new tasks no longer include them.
+            let mut tls_initializer = self.tls_initializer.lock();
+            for shndx in &krate.tls_sections {
+                if let Some(tls_sec) = krate.sections.get(shndx) {
+                    tls_initializer.remove_dynamic_tls_section(tls_sec)
+                        .map_err(|_| "unload_crate(): BUG: crate's TLS section was not in the TLS initializer")?;
+                }
+            }
+            drop(tls_initializer);
+            self.publish_tls_template();
+
+            // Remove this crate's symbols, but only if they still refer to this crate's sections,
+            // as they may have since been replaced by another crate's sections.
+            let mut symbol_map = self.symbol_map.lock();
+            let mut symbol_versions = self.symbol_versions.lock();
+            for sec in krate.global_sections_iter() {
+                let is_ours = symbol_map.get(sec.name.as_bytes())
+                    .and_then(|weak_sec| weak_sec.upgrade())
+                    .map_or(false, |existing| Arc::ptr_eq(&existing, sec));
+                if is_ours {
+                    symbol_map.remove(sec.name.as_bytes());
+                    symbol_versions.remove(&sec.name);
+                }
+            }
+            for sym in &krate.reexported_symbols {
+                symbol_map.remove(sym.as_bytes());
+            }
+        }
+
+        self.crate_tree.lock().remove(crate_name.as_bytes());
+        #[cfg(not(loscd_eval))]
+        info!("unloaded crate {:?} from namespace {}", crate_name, self.name);
+        Ok(())
+    }
+
     /// Duplicates this `CrateNamespace` into a new `CrateNamespace`, 
     /// but uses a copy-on-write/clone-on-write semantic that creates 
     /// a special shared reference to each crate that indicates it is shared across multiple namespaces.
@@ -954,10 +1699,15 @@ impl CrateNamespace {
             name: self.name.clone(),
             dir: self.dir.clone(),
             tls_initializer: &TLS_INITIALIZER,
+            tls_template: &TLS_TEMPLATE,
             recursive_namespace: self.recursive_namespace.clone(),
             crate_tree: Mutex::new(self.crate_tree.lock().clone()),
             symbol_map: Mutex::new(self.symbol_map.lock().clone()),
+            symbol_versions: Mutex::new(self.symbol_versions.lock().clone()),
             fuzzy_symbol_matching: self.fuzzy_symbol_matching,
+            tls_replacements: Mutex::new(Vec::new()),
+            lazy_binding: Mutex::new(None),
+            sandbox: self.sandbox.clone(),
         }
     }
 
@@ -987,13 +1737,16 @@ impl CrateNamespace {
                     target_sec_mapped_pages.remap(&mut kernel_mmi_ref.lock().page_table, target_sec_initial_flags.writable(true))?;
                 }
 
-                write_relocation(
-                    relocation_entry,
-                    target_sec_mapped_pages.as_slice_mut(0, target_sec.mapped_pages_offset + target_sec.size)?,
-                    target_sec.mapped_pages_offset,
-                    new_section.virt_addr,
-                    false
-                )?;
+                // The TLS module ID never changes, so module ID relocations needn't be rewritten.
+                if !relocation_entry.is_tls_module_id() {
+                    write_relocation(
+                        relocation_entry,
+                        target_sec_mapped_pages.as_slice_mut(0, target_sec.mapped_pages_offset + target_sec.size)?,
+                        target_sec.mapped_pages_offset,
+                        new_section.virt_addr,
+                        false
+                    )?;
+                }
 
                 // If we temporarily remapped the target_sec's mapped pages as writable, undo that here
                 if !target_sec_initial_flags.is_writable() {
@@ -1065,6 +1818,7 @@ impl CrateNamespace {
         if self.get_crate(&crate_name).is_some() {
             return Err("the crate has already been loaded, cannot load it again in the same namespace");
         }
+        load_trace::record_crate_load(&self.name, &crate_name);
 
         // It's probably better to pass in the actual crate file reference so we can use it here,
         // but since we don't currently do that, we just get another reference to the crate object file via its Path.
@@ -1075,6 +1829,8 @@ impl CrateNamespace {
 
         // Parse the crate file as an ELF file
         let byte_slice: &[u8] = mapped_pages.as_slice(0, size_in_bytes)?;
+        // Verify the crate object file before any of its sections are mapped or its TLS sections are registered.
+        verification::verify(abs_path.basename(), byte_slice)?;
         let elf_file = ElfFile::new(byte_slice)?; // returns Err(&str) if ELF parse fails
 
         // Check that elf_file is a relocatable type 
@@ -1094,7 +1850,8 @@ impl CrateNamespace {
             .unwrap_or(false);
 
         // Allocate enough space to load the sections
-        let section_pages = allocate_section_pages(&elf_file, kernel_mmi_ref)?;
+        let preferred_pages = prelink::cached_section_pages(&self.name, &crate_name);
+        let section_pages = allocate_section_pages(&elf_file, preferred_pages, kernel_mmi_ref)?;
         let text_pages   = section_pages.executable_pages.map(|(tp, range)| (Arc::new(Mutex::new(tp)), range));
         let rodata_pages = section_pages.read_only_pages.map( |(rp, range)| (Arc::new(Mutex::new(rp)), range));
         let data_pages   = section_pages.read_write_pages.map(|(dp, range)| (Arc::new(Mutex::new(dp)), range));
@@ -1144,11 +1901,45 @@ impl CrateNamespace {
             new_crate_mut.tls_sections    = tls_sections;
             new_crate_mut.data_sections   = data_sections;
         }
+        self.record_symbol_versions(&elf_file, &new_crate)?;
 
         Ok((new_crate, elf_file))
     }
 
 
+    /// Records the versioning metadata of each global symbol in the given newly-loaded crate.
+    ///
+    /// See the [`symbol_version`] module for more.
+    fn record_symbol_versions(&self, elf_file: &ElfFile, new_crate: &StrongCrateRef) -> Result<(), &'static str> {
+        use xmas_elf::symbol_table::Entry;
+        let tags = symbol_version::parse_version_tags(elf_file)?;
+
+        // Only data and TLS symbols need their alignment, which is that of their section in the object file.
+        let mut alignments: HashMap<String, usize> = HashMap::new();
+        for symbol_entry in find_symbol_table(elf_file)? {
+            let is_data_or_tls = matches!(symbol_entry.get_type(), Ok(Type::Object | Type::Tls));
+            if !is_data_or_tls || symbol_entry.get_binding() != Ok(Binding::Global) || symbol_entry.shndx() == 0 {
+                continue;
+            }
+            if let (Ok(name), Ok(sec)) = (symbol_entry.get_name(elf_file), elf_file.section_header(symbol_entry.shndx())) {
+                alignments.insert(demangle(name).to_string(), sec.align() as usize);
+            }
+        }
+
+        let new_crate = new_crate.lock_as_ref();
+        let mut symbol_versions = self.symbol_versions.lock();
+        for sec in new_crate.global_sections_iter() {
+            symbol_versions.insert(sec.name.clone(), SymbolVersion {
+                tag: tags.get(symbol_version::name_without_hash(&sec.name)).cloned(),
+                typ: sec.typ,
+                size: sec.size,
+                alignment: alignments.get(sec.name.as_str()).copied().unwrap_or(1),
+            });
+        }
+        Ok(())
+    }
+
+
     /// An internal routine to load and populate the sections of a crate's object file
     /// if those sections have already been merged.
     /// 
@@ -1196,6 +1987,8 @@ impl CrateNamespace {
         let mut loaded_sections: HashMap<usize, StrongSectionRef> = HashMap::new(); 
         let mut data_sections:   BTreeSet<usize> = BTreeSet::new();
         let mut tls_sections:    BTreeSet<usize> = BTreeSet::new();
+        // The new TLS sections, which are added to this namespace's TLS area after the loop below.
+        let mut new_tls_sections: Vec<(Shndx, LoadedSection, usize)> = Vec::new();
         let mut last_shndx = 0;
 
         // Iterate over all "allocated" sections to copy their data from the object file into the above `MappedPages`s.
@@ -1362,30 +2155,30 @@ impl CrateNamespace {
                 new_crate.clone(),
             );
 
-            let new_section_ref = if is_tls {
-                // Add the new TLS section to this namespace's initial TLS area,
-                // which will reserve/obtain a new offset into that TLS area which holds this section's data.
-                // This will also update the section's virtual address field to hold that offset value,
-                // which is used for relocation entries that ask for a section's offset from the TLS base.
-                let (_tls_offset, new_tls_section) = self.tls_initializer.lock()
-                    .add_new_dynamic_tls_section(new_section, sec.align() as usize)
-                    .map_err(|_| "Failed to add new dynamic TLS section")?;
-
-                // trace!("Updated new TLS section to have offset {:#X}: {:?}", _tls_offset, new_tls_section);
-                if new_tls_section.typ == SectionType::TlsData {
-                    tdata_shndx_and_section = Some((shndx, Arc::clone(&new_tls_section)));
-                } else {
-                    tbss_shndx_and_section = Some((shndx, Arc::clone(&new_tls_section)));
-                }
-                new_tls_section
+            if is_tls {
+                new_tls_sections.push((shndx, new_section, sec.align() as usize));
             } else {
-                Arc::new(new_section)
-            };
-
-            loaded_sections.insert(shndx, new_section_ref);
+                loaded_sections.insert(shndx, Arc::new(new_section));
+            }
             last_shndx = shndx + 1;
         }
 
+        // Add the new TLS sections to this namespace's initial TLS area,
+        // which will reserve/obtain a new offset into that TLS area which holds each section's data.
+        // This will also update each section's virtual address field to hold that offset value,
+        // which is used for relocation entries that ask for a section's offset from the TLS base.
+        let new_tls_sections = self.add_tls_sections(new_tls_sections)
+            .map_err(|_| "Failed to add new dynamic TLS section")?;
+        for (shndx, new_tls_section) in new_tls_sections {
+            // trace!("Updated new TLS section to have offset {:#X}: {:?}", new_tls_section.virt_addr, new_tls_section);
+            if new_tls_section.typ == SectionType::TlsData {
+                tdata_shndx_and_section = Some((shndx, Arc::clone(&new_tls_section)));
+            } else {
+                tbss_shndx_and_section = Some((shndx, Arc::clone(&new_tls_section)));
+            }
+            loaded_sections.insert(shndx, new_tls_section);
+        }
+
         // Now that we've copied all the section data from the object file to the various mapped pages,
         // we can populate the crate's sets of global sections by iterating over the symbol table.
         // The above loop just handled the merged sections, none of which should be made global.
@@ -1706,6 +2499,8 @@ impl CrateNamespace {
         let mut data_sections: BTreeSet<Shndx> = BTreeSet::new();
         // the set of Shndxes for TLS sections (.tdata, .tbss)
         let mut tls_sections: BTreeSet<Shndx> = BTreeSet::new();
+        // the new TLS sections, which are added to this namespace's TLS area after all sections are loaded
+        let mut new_tls_sections: Vec<(Shndx, LoadedSection, usize)> = Vec::new();
 
         let mut read_only_pages_locked  = rodata_pages.as_ref().map(|(rp, _)| (rp.clone(), rp.lock()));
         let mut read_write_pages_locked = data_pages  .as_ref().map(|(dp, _)| (dp.clone(), dp.lock()));
@@ -1853,16 +2648,8 @@ impl CrateNamespace {
                     );
                     // trace!("Loaded new TLS section: {:?}", new_tls_section);
                     
-                    // Add the new TLS section to this namespace's initial TLS area,
-                    // which will reserve/obtain a new offset into that TLS area which holds this section's data.
-                    // This will also update the section's virtual address field to hold that offset value,
-                    // which is used for relocation entries that ask for a section's offset from the TLS base.
-                    let (_tls_offset, new_tls_section) = self.tls_initializer.lock()
-                        .add_new_dynamic_tls_section(new_tls_section, sec_align)
-                        .map_err(|_| "Failed to add new TLS section")?;
-
-                    // trace!("\t --> updated new TLS section: {:?}", new_tls_section);
-                    loaded_sections.insert(shndx, new_tls_section);
+                    // The new TLS section is added to this namespace's TLS area after all sections are loaded.
+                    new_tls_sections.push((shndx, new_tls_section, sec_align));
                     tls_sections.insert(shndx);
 
                     rodata_offset += sec_size.next_multiple_of(sec_align);
@@ -2062,6 +2849,17 @@ impl CrateNamespace {
             }
         }
 
+        // Add the new TLS sections to this namespace's initial TLS area,
+        // which will reserve/obtain a new offset into that TLS area which holds each section's data.
+        // This will also update each section's virtual address field to hold that offset value,
+        // which is used for relocation entries that ask for a section's offset from the TLS base.
+        let new_tls_sections = self.add_tls_sections(new_tls_sections)
+            .map_err(|_| "Failed to add new TLS section")?;
+        for (shndx, new_tls_section) in new_tls_sections {
+            // trace!("\t --> updated new TLS section: {:?}", new_tls_section);
+            loaded_sections.insert(shndx, new_tls_section);
+        }
+
         Ok(SectionMetadata { 
             loaded_sections,
             global_sections,
@@ -2086,6 +2884,12 @@ impl CrateNamespace {
             .ok_or("BUG: perform_relocations(): couldn't get exclusive mutable access to new_crate")?;
         if verbose_log { debug!("=========== moving on to the relocations for crate {} =========", new_crate.crate_name); }
         let symtab = find_symbol_table(elf_file)?;
+        // The versions of foreign symbols that this crate expects.
+        let expected_versions = symbol_version::parse_version_tags(elf_file)?;
+
+        // The relocations to be saved in the prelink cache, if recording.
+        let mut prelinked_relocations = (temp_backup_namespace.is_none() && prelink::is_recording()).then(Vec::new);
+        let mut tls_offsets = TlsOffsetCache::new();
 
         // Fix up the sections that were just loaded, using proper relocation info.
         // Iterate over every non-zero relocation section in the file
@@ -2135,8 +2939,16 @@ impl CrateNamespace {
                     target_sec.mapped_pages_offset + target_sec.size,
                 )?;
 
+                // The offset of a call to `__tls_get_addr()` that was removed by relaxing a TLS code sequence,
+                // whose relocation entry must be skipped.
+                let mut relaxed_tls_call_offset: Option<usize> = None;
+
                 // iterate through each relocation entry in the relocation array for the target_sec
                 for rela_entry in rela_array {
+                    if relaxed_tls_call_offset.take() == Some(rela_entry.get_offset() as usize) {
+                        if verbose_log { trace!("      Skipping relocation for call removed by TLS relaxation at offset {:#X}", rela_entry.get_offset()); }
+                        continue;
+                    }
                     if verbose_log { 
                         trace!("      Rela64 offset: {:#X}, addend: {:#X}, symtab_index: {}, type: {:#X}", 
                             rela_entry.get_offset(), rela_entry.get_addend(), rela_entry.get_symbol_table_index(), rela_entry.get_type());
@@ -2156,7 +2968,9 @@ impl CrateNamespace {
                         //     source_sec_entry.shndx(), source_sec_entry.value(), source_sec_entry.size());
                     }
                     
+                    let relocation_entry = RelocationEntry::from_elf_relocation(rela_entry);
                     let mut source_and_target_in_same_crate = false;
+                    let mut foreign_symbol: Option<String> = None;
 
                     // We first try to get the source section from loaded_sections, which works if the section is in the crate currently being loaded.
                     let source_sec = match new_crate.sections.get(&source_sec_shndx) {
@@ -2178,10 +2992,27 @@ impl CrateNamespace {
                                 };
                                 let demangled = demangle(source_sec_name).to_string();
 
+                                // With lazy binding, a call to a function in a crate that isn't loaded yet is bound to a stub.
+                                if let Some(stub) = self.lazy_binding_stub(&demangled, &relocation_entry, temp_backup_namespace, kernel_mmi_ref)? {
+                                    write_relocation(
+                                        relocation_entry,
+                                        target_sec_slice,
+                                        target_sec.mapped_pages_offset,
+                                        stub,
+                                        verbose_log
+                                    )?;
+                                    target_sec_data_was_modified = true;
+                                    // The stub's target isn't known yet, so this crate can't be prelinked.
+                                    prelinked_relocations = None;
+                                    continue;
+                                }
+
                                 // search for the symbol's demangled name in the kernel's symbol map
-                                self.get_symbol_or_load(&demangled, temp_backup_namespace, kernel_mmi_ref, verbose_log)
+                                let source_sec = self.get_symbol_or_load(&demangled, temp_backup_namespace, kernel_mmi_ref, verbose_log)
                                     .upgrade()
-                                    .ok_or("Couldn't get symbol for foreign relocation entry, nor load its containing crate")
+                                    .ok_or("Couldn't get symbol for foreign relocation entry, nor load its containing crate");
+                                foreign_symbol = Some(demangled);
+                                source_sec
                             }
                             else {
                                 let _source_sec_header = source_sec_entry
@@ -2193,15 +3024,34 @@ impl CrateNamespace {
                         }
                     }?;
 
-                    let relocation_entry = RelocationEntry::from_elf_relocation(rela_entry);
+                    if let Some(ref demangled) = foreign_symbol {
+                        if let Some(expected_tag) = expected_versions.get(symbol_version::name_without_hash(demangled)) {
+                            self.check_symbol_version(demangled, expected_tag, temp_backup_namespace)?;
+                        }
+                    }
+
+                    let source_value = self.relocation_source_value(&relocation_entry, &source_sec, source_sec_value, &mut tls_offsets)?;
                     write_relocation(
                         relocation_entry,
                         target_sec_slice,
                         target_sec.mapped_pages_offset,
-                        source_sec.virt_addr + source_sec_value,
+                        source_value,
                         verbose_log
                     )?;
                     target_sec_data_was_modified = true;
+                    if let Some(relocations) = prelinked_relocations.as_mut() {
+                        relocations.push(PrelinkedRelocation {
+                            target_shndx: target_sec_shndx,
+                            relocation: relocation_entry,
+                            source: match foreign_symbol {
+                                Some(symbol) => RelocationSource::Foreign(symbol),
+                                None => RelocationSource::Local(source_sec_shndx),
+                            },
+                            symbol_value: source_sec_value,
+                            source_value: source_value.value(),
+                        });
+                    }
+                    relaxed_tls_call_offset = relocation_entry.relaxed_tls_call_offset();
 
                     if source_and_target_in_same_crate {
                         // We keep track of relocation information so that we can be aware of and faithfully reconstruct 
@@ -2231,12 +3081,15 @@ impl CrateNamespace {
 
             // If the target section of the relocation was a TLS section, 
             // that TLS section's initializer data has now changed.
-            // Thus, we need to invalidate the TLS initializer area's cached data.
+            // Thus, we need to invalidate that section's part of the TLS initializer area's cached data.
             if target_sec_data_was_modified && 
                 (target_sec.typ == SectionType::TlsData || target_sec.typ == SectionType::TlsBss)
             {
                 // debug!("Invalidating TlsInitializer due to relocation written to section {:?}", &*target_sec);
-                self.tls_initializer.lock().invalidate();
+                let mut tls_initializer = self.tls_initializer.lock();
+                if tls_initializer.invalidate_section(target_sec).is_err() {
+                    tls_initializer.invalidate();
+                }
             }
 
             // add the target section's dependencies and relocation details all at once
@@ -2249,7 +3102,148 @@ impl CrateNamespace {
         }
         // here, we're done with handling all the relocations in this entire crate
 
+        if let Some(relocations) = prelinked_relocations {
+            prelink::record_crate(|| PrelinkedCrate {
+                namespace: self.name.clone(),
+                crate_name: new_crate.crate_name.to_string(),
+                file_hash: prelink::hash(elf_file.input),
+                section_pages: section_page_addresses(&new_crate),
+                tls_offsets: new_crate.tls_sections_iter()
+                    .map(|sec| (sec.name.to_string(), sec.virt_addr.value()))
+                    .collect(),
+                relocations,
+            });
+        }
+
+        self.finish_relocations(&mut new_crate, kernel_mmi_ref)
+    }
+
+    /// Applies the cached relocations of a crate from the prelink cache,
+    /// instead of performing them based on the crate's ELF file.
+    ///
+    /// Returns `Ok(false)` without modifying the crate if the cached relocations are no longer valid,
+    /// in which case the crate must be fully relocated via [`Self::perform_relocations()`].
+    fn perform_prelinked_relocations(
+        &self,
+        elf_file: &ElfFile,
+        new_crate_ref: &StrongCrateRef,
+        prelinked: &PrelinkedCrate,
+        kernel_mmi_ref: &MmiRef,
+    ) -> Result<bool, &'static str> {
+        let mut new_crate = new_crate_ref.lock_as_mut()
+            .ok_or("BUG: perform_prelinked_relocations(): couldn't get exclusive mutable access to new_crate")?;
+        if prelinked.file_hash != prelink::hash(elf_file.input)
+            || prelinked.section_pages != section_page_addresses(&new_crate)
+        {
+            return Ok(false);
+        }
+
+        // First, resolve each relocation's source section and check that its source value hasn't changed,
+        // such that nothing is modified if the cached relocations are no longer valid.
+        let mut resolved = Vec::with_capacity(prelinked.relocations.len());
+        let mut tls_offsets = TlsOffsetCache::new();
+        for reloc in &prelinked.relocations {
+            let Some(target_sec) = new_crate.sections.get(&reloc.target_shndx) else { return Ok(false) };
+            let source_sec = match &reloc.source {
+                RelocationSource::Local(shndx) => new_crate.sections.get(shndx).cloned(),
+                RelocationSource::Foreign(symbol) => self.get_symbol_or_load(symbol, None, kernel_mmi_ref, false).upgrade(),
+            };
+            let Some(source_sec) = source_sec else { return Ok(false) };
+            let Ok(source_value) = self.relocation_source_value(&reloc.relocation, &source_sec, reloc.symbol_value, &mut tls_offsets) else { return Ok(false) };
+            if source_value.value() != reloc.source_value {
+                return Ok(false);
+            }
+            resolved.push((target_sec.clone(), source_sec, source_value, reloc));
+        }
 
+        // Then, write each relocation and track the dependencies between sections, just like `perform_relocations()`.
+        for (target_sec, source_sec, source_value, reloc) in resolved {
+            {
+                let mut target_sec_mapped_pages = target_sec.mapped_pages.lock();
+                let target_sec_slice: &mut [u8] = target_sec_mapped_pages.as_slice_mut(
+                    0,
+                    target_sec.mapped_pages_offset + target_sec.size,
+                )?;
+                write_relocation(reloc.relocation, target_sec_slice, target_sec.mapped_pages_offset, source_value, false)?;
+            }
+            match &reloc.source {
+                RelocationSource::Local(_source_sec_shndx) => {
+                    #[cfg(internal_deps)]
+                    target_sec.inner.write().internal_dependencies.push(InternalDependency::new(reloc.relocation, *_source_sec_shndx));
+                }
+                RelocationSource::Foreign(_) => {
+                    source_sec.inner.write().sections_dependent_on_me.push(WeakDependent {
+                        section: Arc::downgrade(&target_sec),
+                        relocation: reloc.relocation,
+                    });
+                    target_sec.inner.write().sections_i_depend_on.push(StrongDependency {
+                        section: Arc::clone(&source_sec),
+                        relocation: reloc.relocation,
+                    });
+                }
+            }
+            if target_sec.typ == SectionType::TlsData || target_sec.typ == SectionType::TlsBss {
+                let mut tls_initializer = self.tls_initializer.lock();
+                if tls_initializer.invalidate_section(&target_sec).is_err() {
+                    tls_initializer.invalidate();
+                }
+            }
+        }
+
+        self.finish_relocations(&mut new_crate, kernel_mmi_ref)?;
+        Ok(true)
+    }
+
+    /// Checks that the definition of the given foreign symbol, which a crate being loaded depends on,
+    /// has no version tag or the `expected_tag` that the crate declared for it.
+    fn check_symbol_version(
+        &self,
+        demangled_full_symbol: &str,
+        expected_tag: &str,
+        temp_backup_namespace: Option<&CrateNamespace>,
+    ) -> Result<(), &'static str> {
+        let version = self.symbol_version(demangled_full_symbol)
+            .or_else(|| temp_backup_namespace.and_then(|backup| backup.symbol_version(demangled_full_symbol)));
+        match version.and_then(|v| v.tag) {
+            Some(tag) if tag != expected_tag => {
+                error!("Symbol {:?} has version {:?}, but version {:?} was expected", demangled_full_symbol, tag, expected_tag);
+                Err("foreign symbol has an incompatible version")
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Returns the value that a relocation against the given `source_sec` writes into its target section,
+    /// where the relocation's symbol is at offset `source_sec_value` within `source_sec`.
+    ///
+    /// The TLS offset of each TLS source section is looked up once and then cached in `tls_offsets`.
+    fn relocation_source_value(
+        &self,
+        relocation_entry: &RelocationEntry,
+        source_sec: &StrongSectionRef,
+        source_sec_value: usize,
+        tls_offsets: &mut TlsOffsetCache,
+    ) -> Result<VirtualAddress, &'static str> {
+        // TLS relocations refer to the TLS module ID or an offset into the TLS area, not a virtual address.
+        if relocation_entry.is_tls_module_id() {
+            Ok(VirtualAddress::new_canonical(self.tls_initializer.lock().module_id()))
+        } else if relocation_entry.is_tls() {
+            let tls_offset = match tls_offsets.entry(Arc::as_ptr(source_sec)) {
+                btree_map::Entry::Occupied(entry) => *entry.get(),
+                btree_map::Entry::Vacant(entry) => *entry.insert(
+                    self.tls_initializer.lock().offset_of(source_sec)
+                        .ok_or("BUG: source section of TLS relocation was not in the TLS initializer")?
+                ),
+            };
+            Ok(VirtualAddress::new_canonical((tls_offset as usize).wrapping_add(source_sec_value)))
+        } else {
+            Ok(source_sec.virt_addr + source_sec_value)
+        }
+    }
+
+    /// Finishes relocating the given `new_crate` by remapping its pages with their proper permissions
+    /// and removing the metadata of sections that are no longer needed.
+    fn finish_relocations(&self, new_crate: &mut LoadedCrate, kernel_mmi_ref: &MmiRef) -> Result<(), &'static str> {
         // We need to remap each section's mapped pages with the proper permission bits, 
         // since we initially mapped them all as writable.
         if let Some(ref tp) = new_crate.text_pages { 
@@ -2504,7 +3498,10 @@ impl CrateNamespace {
         let weak_symbol = self.symbol_map.lock().get(demangled_full_symbol.as_bytes()).cloned();
         weak_symbol.map(|sym| (sym, self))
             // search the recursive namespace if the symbol cannot be found in this namespace
-            .or_else(|| self.recursive_namespace.as_ref().and_then(|rns| rns.get_symbol_and_namespace(demangled_full_symbol)))
+            .or_else(|| self.recursive_namespace.as_ref()
+                .filter(|_| self.may_resolve_recursively(demangled_full_symbol))
+                .and_then(|rns| rns.get_symbol_and_namespace(demangled_full_symbol))
+            )
     }
 
     /// A convenience function that returns a weak reference to the `LoadedSection`
@@ -2613,12 +3610,15 @@ impl CrateNamespace {
         } else {
             // use fuzzy matching (ignoring the symbol hash suffix)
             let fuzzy_matches = temp_backup_namespace.find_symbols_starting_with_and_namespace(LoadedSection::section_name_without_hash(demangled_full_symbol));
-            match fuzzy_matches.as_slice() {
-                [(sec_name, weak_sec, _found_in_ns)] => {
+            // When replaying a load trace, use the recorded match even if there are multiple fuzzy matches.
+            let replayed_match = load_trace::replayed_symbol(&self.name, demangled_full_symbol)
+                .and_then(|(_crate_name, section)| fuzzy_matches.iter().find(|(sec_name, ..)| *sec_name == section));
+            match (replayed_match, fuzzy_matches.as_slice()) {
+                (Some((sec_name, weak_sec, _found_in_ns)), _) | (None, [(sec_name, weak_sec, _found_in_ns)]) => {
                     _fuzzy_matched_symbol_name = Some(sec_name.clone());
                     (weak_sec.clone(), *_found_in_ns)
                 }
-                fuzzy_matches => {
+                (None, fuzzy_matches) => {
                     warn!("Cannot resolve dependency because there are {} fuzzy matches for symbol {:?} in backup namespace {:?}\n\t{:?}",
                         fuzzy_matches.len(), 
                         demangled_full_symbol, 
@@ -2651,6 +3651,7 @@ impl CrateNamespace {
             self.add_symbols(Some(sec.clone()).iter(), verbose_log);
             parent_crate.crate_name.clone()
         };
+        load_trace::record_symbol_resolution(&self.name, demangled_full_symbol, &parent_crate_name, &sec.name);
         
         #[cfg(not(loscd_eval))]
         info!("Symbol {:?} not initially found, using {}symbol {} from crate {:?} in backup namespace {:?} in new namespace {:?}",
@@ -2696,6 +3697,8 @@ impl CrateNamespace {
         kernel_mmi_ref: &MmiRef,
         verbose_log: bool,
     ) -> Option<WeakSectionRef> {
+        let replayed_crate_name = load_trace::replayed_symbol(&self.name, demangled_full_symbol)
+            .map(|(crate_name, _section)| crate_name);
         // Some symbols may have multiple potential containing crates, so we try to load each one to find the missing symbol.
         for potential_crate_name in get_containing_crate_name(demangled_full_symbol) {
             let potential_crate_name = format!("{potential_crate_name}-");
@@ -2704,7 +3707,18 @@ impl CrateNamespace {
             // (or from the backup namespace's directory set).
             // The object files from the recursive namespace(s) are appended after the files in the initial namespace,
             // so they'll only be searched if the symbol isn't found in the current namespace.
-            for (potential_crate_file, ns_of_crate_file) in self.method_get_crate_object_files_starting_with(&potential_crate_name) {
+            let mut potential_crate_files = self.method_get_crate_object_files_starting_with(&potential_crate_name);
+            // When replaying a load trace, first try the crate that contained the symbol in the recorded trace.
+            if let Some(replayed_crate_name) = replayed_crate_name.as_deref() {
+                potential_crate_files.sort_by_key(|(file, _ns)|
+                    crate_name_from_path(&Path::new(file.lock().get_absolute_path())) != replayed_crate_name
+                );
+            }
+            for (potential_crate_file, ns_of_crate_file) in potential_crate_files {
+                // A sandboxed namespace must not cause the crates of denied symbols to be loaded into its recursive namespace.
+                if !core::ptr::eq(ns_of_crate_file, self) && !self.may_resolve_recursively(demangled_full_symbol) {
+                    continue;
+                }
                 let potential_crate_file_path = Path::new(potential_crate_file.lock().get_absolute_path());
                 // Check to make sure this crate is not already loaded into this namespace (or its recursive namespace).
                 if self.get_crate(crate_name_from_path(&potential_crate_file_path)).is_some() {
@@ -2719,6 +3733,14 @@ impl CrateNamespace {
                     Ok((_new_crate_ref, _num_new_syms)) => {
                         // try again to find the missing symbol, now that we've loaded the missing crate
                         if let Some(sec) = ns_of_crate_file.get_symbol_internal(demangled_full_symbol) {
+                            if let Some(strong_sec) = sec.upgrade() {
+                                load_trace::record_symbol_resolution(
+                                    &self.name,
+                                    demangled_full_symbol,
+                                    crate_name_from_path(&potential_crate_file_path),
+                                    &strong_sec.name,
+                                );
+                            }
                             return Some(sec);
                         } else {
                             // the missing symbol wasn't in this crate, continue to load the other potential containing crates.
@@ -2758,8 +3780,8 @@ impl CrateNamespace {
             .map(|(k, v)| (String::from(k.as_str()), v.clone()))
             .collect();
 
-        if let Some(mut syms_recursive) = self.recursive_namespace.as_ref().map(|r_ns| r_ns.find_symbols_starting_with(symbol_prefix)) {
-            syms.append(&mut syms_recursive);
+        if let Some(syms_recursive) = self.recursive_namespace.as_ref().map(|r_ns| r_ns.find_symbols_starting_with(symbol_prefix)) {
+            syms.extend(syms_recursive.into_iter().filter(|(name, _)| self.may_resolve_recursively(name)));
         }
 
         syms
@@ -2774,8 +3796,8 @@ impl CrateNamespace {
             .map(|(k, v)| (String::from(k.as_str()), v.clone(), self))
             .collect();
 
-        if let Some(mut syms_recursive) = self.recursive_namespace.as_ref().map(|r_ns| r_ns.find_symbols_starting_with_and_namespace(symbol_prefix)) {
-            syms.append(&mut syms_recursive);
+        if let Some(syms_recursive) = self.recursive_namespace.as_ref().map(|r_ns| r_ns.find_symbols_starting_with_and_namespace(symbol_prefix)) {
+            syms.extend(syms_recursive.into_iter().filter(|(name, ..)| self.may_resolve_recursively(name)));
         }
 
         syms
@@ -2823,23 +3845,62 @@ impl CrateNamespace {
             .cloned();
         
         // Second, we see if there's a single matching symbol in the recursive namespace.
-        let symbol_in_recursive_namespace = self.recursive_namespace.as_ref().and_then(|r_ns| r_ns.get_symbol_starting_with_internal(symbol_prefix));
+        let symbol_in_recursive_namespace = self.recursive_namespace.as_ref()
+            .and_then(|r_ns| r_ns.get_symbol_starting_with_internal(symbol_prefix))
+            .filter(|weak_sec| weak_sec.upgrade().map_or(false, |sec| self.may_resolve_recursively(&sec.name)));
 
         // There can only be one matching crate across all recursive namespaces.
         symbol_in_this_namespace.xor(symbol_in_recursive_namespace)
     }
 
     
-    /// Simple debugging function that returns the entire symbol map as a String.
+    /// Simple debugging function that returns the entire symbol map as a String,
+    /// with one symbol and its value per line.
     /// This includes only symbols from this namespace, and excludes symbols from recursive namespaces.
+    ///
+    /// The value of a regular symbol is its virtual address.
+    /// The value of a TLS symbol is its offset from the TLS self pointer (the thread pointer),
+    /// followed by the name of the crate that owns it, e.g., `tp-0x40 crate my_crate-<hash>`,
+    /// because TLS symbols don't have a single virtual address;
+    /// instead, every task accesses its own copy at that offset into its TLS area.
     pub fn dump_symbol_map(&self) -> String {
         use core::fmt::Write;
+        // Release the symbol map lock before acquiring the TLS initializer lock below.
+        let symbols: Vec<(StrRef, Option<StrongSectionRef>)> = self.symbol_map.lock().iter()
+            .map(|(name, weak_sec)| (name.clone(), weak_sec.upgrade()))
+            .collect();
+        // Find the crate that owns each TLS symbol before acquiring the TLS initializer lock,
+        // since a crate is locked while relocating it, which also acquires the TLS initializer lock.
+        let symbols: Vec<(StrRef, Option<StrongSectionRef>, Option<StrRef>)> = symbols.into_iter()
+            .map(|(name, section)| {
+                let crate_name = section.as_ref()
+                    .filter(|sec| matches!(sec.typ, SectionType::TlsData | SectionType::TlsBss))
+                    .and_then(|sec| sec.parent_crate.upgrade())
+                    .map(|parent| parent.lock_as_ref().crate_name.clone());
+                (name, section, crate_name)
+            })
+            .collect();
+        let tls_initializer = self.tls_initializer.lock();
         let mut output: String = String::new();
-        let sysmap = self.symbol_map.lock();
-        match write!(&mut output, "{:?}", sysmap.keys().collect::<Vec<_>>()) {
-            Ok(_) => output,
-            _ => String::from("(error)"),
+        for (name, section, crate_name) in symbols {
+            let result = match section {
+                Some(sec) if matches!(sec.typ, SectionType::TlsData | SectionType::TlsBss) => {
+                    let crate_name = crate_name.as_deref().unwrap_or("<unknown>");
+                    match tls_initializer.offset_of(&sec) {
+                        Some(offset) => writeln!(&mut output, "{}: tp{}{:#X} crate {}",
+                            name, if offset < 0 { '-' } else { '+' }, offset.unsigned_abs(), crate_name,
+                        ),
+                        None => writeln!(&mut output, "{name}: tp<unknown> crate {crate_name}"),
+                    }
+                }
+                Some(sec) => writeln!(&mut output, "{}: {:#X}", name, sec.virt_addr),
+                None => writeln!(&mut output, "{name}: <unloaded>"),
+            };
+            if result.is_err() {
+                return String::from("(error)");
+            }
         }
+        output
     }
 
     /// Same as [`dump_symbol_map()`](#method.dump_symbol_map), 
@@ -2884,7 +3945,13 @@ struct SectionPages {
 
 /// Allocates and maps memory sufficient to hold the sections that are found in the given `ElfFile`.
 /// Only sections that are marked "allocated" (`ALLOC`) in the ELF object file will contribute to the mappings' sizes.
-fn allocate_section_pages(elf_file: &ElfFile, kernel_mmi_ref: &MmiRef) -> Result<SectionPages, &'static str> {
+/// If `preferred_pages` are given, e.g., from the prelink cache, each kind of pages is allocated
+/// at the given starting address if possible.
+fn allocate_section_pages(
+    elf_file: &ElfFile,
+    preferred_pages: Option<[Option<usize>; 3]>,
+    kernel_mmi_ref: &MmiRef,
+) -> Result<SectionPages, &'static str> {
     // Calculate how many bytes (and thus how many pages) we need for each of the three section types.
     //
     // If there are multiple .text sections, they will all exist at the beginning of the object file,
@@ -2954,9 +4021,11 @@ fn allocate_section_pages(elf_file: &ElfFile, kernel_mmi_ref: &MmiRef) -> Result
 
     // Allocate contiguous virtual memory pages for each section and map them to random frames as writable.
     // We must allocate these pages separately because they will have different flags later.
-    let executable_pages = if exec_bytes > 0 { Some(allocate_and_map_as_writable(exec_bytes, TEXT_SECTION_FLAGS,     kernel_mmi_ref)?) } else { None };
-    let read_only_pages  = if ro_bytes   > 0 { Some(allocate_and_map_as_writable(ro_bytes,   RODATA_SECTION_FLAGS,   kernel_mmi_ref)?) } else { None };
-    let read_write_pages = if rw_bytes   > 0 { Some(allocate_and_map_as_writable(rw_bytes,   DATA_BSS_SECTION_FLAGS, kernel_mmi_ref)?) } else { None };
+    let [text_addr, ro_addr, rw_addr] = preferred_pages.unwrap_or_default()
+        .map(|addr| addr.and_then(VirtualAddress::new));
+    let executable_pages = if exec_bytes > 0 { Some(allocate_and_map_as_writable_at(text_addr, exec_bytes, TEXT_SECTION_FLAGS,     kernel_mmi_ref)?) } else { None };
+    let read_only_pages  = if ro_bytes   > 0 { Some(allocate_and_map_as_writable_at(ro_addr,   ro_bytes,   RODATA_SECTION_FLAGS,   kernel_mmi_ref)?) } else { None };
+    let read_write_pages = if rw_bytes   > 0 { Some(allocate_and_map_as_writable_at(rw_addr,   rw_bytes,   DATA_BSS_SECTION_FLAGS, kernel_mmi_ref)?) } else { None };
 
     let range_tuple = |mp: MappedPages, size_in_bytes: usize| {
         let start = mp.start_address();
@@ -2971,6 +4040,14 @@ fn allocate_section_pages(elf_file: &ElfFile, kernel_mmi_ref: &MmiRef) -> Result
 }
 
 
+/// Returns the starting virtual addresses of the given crate's executable, read-only, and read-write pages.
+fn section_page_addresses(krate: &LoadedCrate) -> [Option<usize>; 3] {
+    let start = |pages: &Option<(Arc<Mutex<MappedPages>>, Range<VirtualAddress>)>|
+        pages.as_ref().map(|(_mp, range)| range.start.value());
+    [start(&krate.text_pages), start(&krate.rodata_pages), start(&krate.data_pages)]
+}
+
+
 /// A convenience function for allocating virtual pages and mapping them to random physical frames. 
 /// 
 /// The returned `MappedPages` will be at least as large as `size_in_bytes`,
@@ -2991,6 +4068,24 @@ fn allocate_and_map_as_writable(
 }
 
 
+/// Like [`allocate_and_map_as_writable()`], but allocates pages starting at the given `preferred_address`
+/// if it is given and those pages are free.
+fn allocate_and_map_as_writable_at(
+    preferred_address: Option<VirtualAddress>,
+    size_in_bytes: usize,
+    flags: PteFlags,
+    kernel_mmi_ref: &MmiRef,
+) -> Result<MappedPages, &'static str> {
+    match preferred_address.and_then(|addr| allocate_pages_by_bytes_at(addr, size_in_bytes).ok()) {
+        Some(allocated_pages) => kernel_mmi_ref.lock().page_table.map_allocated_pages(
+            allocated_pages,
+            flags.valid(true).writable(true)
+        ),
+        None => allocate_and_map_as_writable(size_in_bytes, flags, kernel_mmi_ref),
+    }
+}
+
+
 #[allow(dead_code)]
 fn dump_dependent_crates(krate: &LoadedCrate, prefix: String) {
 	for weak_crate_ref in krate.crates_dependent_on_me() {
@@ -3023,6 +4118,34 @@ fn dump_weak_dependents(sec: &LoadedSection, prefix: String) {
 }
 
 
+/// The contents of a fully-linked ELF executable's `PT_TLS` segment.
+#[derive(Debug, Clone, Copy)]
+pub struct PtTlsSegment<'e> {
+    /// The initialized TLS data (`.tdata`), i.e., the segment's file contents (`p_filesz` bytes).
+    pub file_data: &'e [u8],
+    /// The total size of the TLS block (`p_memsz`), which includes the zero-initialized `.tbss`.
+    pub mem_size: usize,
+    /// The required alignment of the TLS block (`p_align`).
+    pub align: usize,
+}
+
+/// Returns the `PT_TLS` segment (program header) of the given fully-linked `ElfFile`, if it has one.
+pub fn find_pt_tls_segment<'e>(elf_file: &ElfFile<'e>) -> Result<Option<PtTlsSegment<'e>>, &'static str> {
+    let tls_header = match elf_file.program_iter().find(|ph| ph.get_type() == Ok(xmas_elf::program::Type::Tls)) {
+        Some(ph) => ph,
+        None => return Ok(None),
+    };
+    let start = tls_header.offset() as usize;
+    let file_data = elf_file.input.get(start .. start + tls_header.file_size() as usize)
+        .ok_or("PT_TLS segment's file data was out of bounds")?;
+    Ok(Some(PtTlsSegment {
+        file_data,
+        mem_size: tls_header.mem_size() as usize,
+        align: tls_header.align() as usize,
+    }))
+}
+
+
 /// Returns a reference to the symbol table in the given `ElfFile`.
 pub fn find_symbol_table<'e>(elf_file: &'e ElfFile) 
     -> Result<&'e [xmas_elf::symbol_table::Entry64], &'static str>
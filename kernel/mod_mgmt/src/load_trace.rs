@@ -0,0 +1,319 @@
+//! Record and replay of the decisions made while loading crates, for debugging heisenbugs in the loader.
+//!
+//! While recording, every crate load, every symbol resolution that involves a decision,
+//! and every TLS offset assignment is appended to an in-memory trace.
+//! A symbol resolution involves a decision if the symbol wasn't already in the namespace's
+//! (or its recursive namespace's) symbol map, i.e., if it was taken from a backup namespace,
+//! fuzzy-matched, or found by loading the crate that may contain it.
+//!
+//! The trace can be [serialized](serialize) into a line-based text format,
+//! e.g., to be copied from the serial log into the `extra_files/load_trace` file for a later boot.
+//! If that file exists at boot, [`init()`](crate::init) replays it:
+//! * TLS sections are placed at their recorded offsets, if those offsets are still free,
+//!   and all other TLS sections are placed deterministically (see [`TlsInitializer::set_deterministic_layout()`]).
+//! * If a symbol had multiple fuzzy matches or multiple crates that may contain it,
+//!   the recorded section or crate is chosen.
+//! * Crate loads and symbol resolutions are compared against the trace,
+//!   and every difference is logged and counted as a [divergence](divergences).
+//!
+//! Events are also recorded while replaying, such that the traces of two boots can be compared.
+//!
+//! [`TlsInitializer::set_deterministic_layout()`]: crate::TlsInitializer::set_deterministic_layout
+
+use core::{fmt, str::FromStr};
+use alloc::{
+    collections::BTreeMap,
+    string::{String, ToString},
+    vec::Vec,
+};
+use spin::Mutex;
+
+/// The first line of a serialized trace, which identifies the format.
+const TRACE_HEADER: &str = "# theseus load trace v1";
+
+/// The name of the file in the `extra_files` directory that is replayed at boot, if it exists.
+pub const REPLAY_FILE_NAME: &str = "load_trace";
+
+/// A single decision made while loading crates.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LoadEvent {
+    /// A crate started being loaded into a namespace.
+    CrateLoad {
+        namespace: String,
+        crate_name: String,
+    },
+    /// A symbol that was missing from a namespace was resolved to a section in the given crate.
+    SymbolResolution {
+        namespace: String,
+        symbol: String,
+        crate_name: String,
+        section: String,
+    },
+    /// A TLS section in the given crate was assigned an offset from the TLS self pointer.
+    TlsOffset {
+        crate_name: String,
+        section: String,
+        offset: usize,
+    },
+}
+
+// Fields are separated by tabs, which never occur in crate, namespace, or symbol names.
+impl fmt::Display for LoadEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LoadEvent::CrateLoad { namespace, crate_name } =>
+                write!(f, "crate\t{}\t{}", namespace, crate_name),
+            LoadEvent::SymbolResolution { namespace, symbol, crate_name, section } =>
+                write!(f, "symbol\t{}\t{}\t{}\t{}", namespace, symbol, crate_name, section),
+            LoadEvent::TlsOffset { crate_name, section, offset } =>
+                write!(f, "tls\t{}\t{}\t{:#x}", crate_name, section, offset),
+        }
+    }
+}
+
+impl FromStr for LoadEvent {
+    type Err = &'static str;
+
+    fn from_str(line: &str) -> Result<Self, Self::Err> {
+        let fields: Vec<&str> = line.split('\t').collect();
+        match fields.as_slice() {
+            ["crate", namespace, crate_name] => Ok(LoadEvent::CrateLoad {
+                namespace: namespace.to_string(),
+                crate_name: crate_name.to_string(),
+            }),
+            ["symbol", namespace, symbol, crate_name, section] => Ok(LoadEvent::SymbolResolution {
+                namespace: namespace.to_string(),
+                symbol: symbol.to_string(),
+                crate_name: crate_name.to_string(),
+                section: section.to_string(),
+            }),
+            ["tls", crate_name, section, offset] => Ok(LoadEvent::TlsOffset {
+                crate_name: crate_name.to_string(),
+                section: section.to_string(),
+                offset: usize::from_str_radix(offset.trim_start_matches("0x"), 16)
+                    .map_err(|_| "invalid TLS offset in load trace")?,
+            }),
+            _ => Err("invalid event in load trace"),
+        }
+    }
+}
+
+/// The decisions of a previously-recorded trace that is being replayed.
+struct Replay {
+    /// The recorded crate loads, in order.
+    crate_loads: Vec<(String, String)>,
+    /// The index of the next expected crate load in `crate_loads`.
+    next_crate_load: usize,
+    /// Maps a (namespace, symbol) pair to the (crate, section) that it was resolved to.
+    symbols: BTreeMap<(String, String), (String, String)>,
+    /// Maps a (crate, section) pair to the TLS offset assigned to that section.
+    tls_offsets: BTreeMap<(String, String), usize>,
+    /// The number of decisions that differed from the recorded trace.
+    divergences: usize,
+}
+
+struct State {
+    recording: bool,
+    events: Vec<LoadEvent>,
+    replay: Option<Replay>,
+}
+
+static STATE: Mutex<State> = Mutex::new(State {
+    recording: false,
+    events: Vec::new(),
+    replay: None,
+});
+
+/// Starts recording a new trace, discarding any previously-recorded events.
+pub fn start_recording() {
+    let mut state = STATE.lock();
+    state.recording = true;
+    state.events.clear();
+}
+
+/// Stops recording and returns the recorded trace.
+pub fn stop_recording() -> Vec<LoadEvent> {
+    let mut state = STATE.lock();
+    state.recording = false;
+    core::mem::take(&mut state.events)
+}
+
+/// Returns `true` if load events are currently being recorded.
+pub fn is_recording() -> bool {
+    STATE.lock().recording
+}
+
+/// Returns a copy of the events recorded so far.
+pub fn recorded_events() -> Vec<LoadEvent> {
+    STATE.lock().events.clone()
+}
+
+/// Starts replaying the given trace, which affects all crates loaded from now on.
+///
+/// This also enables the deterministic TLS layout, so it must be invoked
+/// before any crates with TLS sections are dynamically loaded.
+pub fn start_replay(trace: Vec<LoadEvent>) -> Result<(), &'static str> {
+    crate::TLS_INITIALIZER.lock().set_deterministic_layout(true)?;
+    let mut replay = Replay {
+        crate_loads: Vec::new(),
+        next_crate_load: 0,
+        symbols: BTreeMap::new(),
+        tls_offsets: BTreeMap::new(),
+        divergences: 0,
+    };
+    for event in trace {
+        match event {
+            LoadEvent::CrateLoad { namespace, crate_name } => {
+                replay.crate_loads.push((namespace, crate_name));
+            }
+            LoadEvent::SymbolResolution { namespace, symbol, crate_name, section } => {
+                replay.symbols.insert((namespace, symbol), (crate_name, section));
+            }
+            LoadEvent::TlsOffset { crate_name, section, offset } => {
+                replay.tls_offsets.insert((crate_name, section), offset);
+            }
+        }
+    }
+    STATE.lock().replay = Some(replay);
+    Ok(())
+}
+
+/// Stops replaying the current trace, if any.
+///
+/// Returns the number of decisions that differed from the trace.
+pub fn stop_replay() -> Option<usize> {
+    STATE.lock().replay.take().map(|r| r.divergences)
+}
+
+/// Returns `true` if a trace is currently being replayed.
+pub fn is_replaying() -> bool {
+    STATE.lock().replay.is_some()
+}
+
+/// Returns the number of decisions that differed from the trace being replayed,
+/// or `None` if no trace is being replayed.
+pub fn divergences() -> Option<usize> {
+    STATE.lock().replay.as_ref().map(|r| r.divergences)
+}
+
+/// Serializes the given `events` into a trace that can be [parsed](parse) on a later boot.
+pub fn serialize(events: &[LoadEvent]) -> String {
+    let mut trace = String::from(TRACE_HEADER);
+    trace.push('\n');
+    for event in events {
+        trace.push_str(&event.to_string());
+        trace.push('\n');
+    }
+    trace
+}
+
+/// Parses a trace that was created by [`serialize()`].
+///
+/// Empty lines are ignored, as are comment lines starting with `#` after the header.
+pub fn parse(trace: &str) -> Result<Vec<LoadEvent>, &'static str> {
+    let mut lines = trace.lines().map(|line| line.trim_end_matches('\r'));
+    if lines.next() != Some(TRACE_HEADER) {
+        return Err("load trace doesn't start with the expected header");
+    }
+    lines.filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(LoadEvent::from_str)
+        .collect()
+}
+
+/// Records the event created by `make_event` if recording,
+/// and applies the given `check` against the replayed trace if replaying.
+fn record(make_event: impl FnOnce() -> LoadEvent, check: impl FnOnce(&mut Replay) -> bool) {
+    let mut state = STATE.lock();
+    if !state.recording && state.replay.is_none() {
+        return;
+    }
+    let event = make_event();
+    if let Some(replay) = state.replay.as_mut() {
+        if !check(replay) {
+            replay.divergences += 1;
+            warn!("load_trace: replay diverged from the recorded trace at event: {}", event);
+        }
+    }
+    if state.recording {
+        state.events.push(event);
+    }
+}
+
+/// Records that the crate `crate_name` started being loaded into the given `namespace`.
+pub(crate) fn record_crate_load(namespace: &str, crate_name: &str) {
+    record(
+        || LoadEvent::CrateLoad { namespace: namespace.to_string(), crate_name: crate_name.to_string() },
+        |replay| {
+            let expected = replay.crate_loads.get(replay.next_crate_load);
+            let matches = expected.map_or(false, |(ns, krate)| ns == namespace && krate == crate_name);
+            if matches {
+                replay.next_crate_load += 1;
+            }
+            matches
+        },
+    );
+}
+
+/// Records that the missing `symbol` in the given `namespace` was resolved to the given `section` in `crate_name`.
+pub(crate) fn record_symbol_resolution(namespace: &str, symbol: &str, crate_name: &str, section: &str) {
+    record(
+        || LoadEvent::SymbolResolution {
+            namespace: namespace.to_string(),
+            symbol: symbol.to_string(),
+            crate_name: crate_name.to_string(),
+            section: section.to_string(),
+        },
+        |replay| replay.symbols.get(&(namespace.to_string(), symbol.to_string()))
+            .map_or(false, |(krate, sec)| krate == crate_name && sec == section),
+    );
+}
+
+/// Records that the TLS `section` in `crate_name` was assigned the given `offset`.
+pub(crate) fn record_tls_offset(crate_name: &str, section: &str, offset: usize) {
+    record(
+        || LoadEvent::TlsOffset { crate_name: crate_name.to_string(), section: section.to_string(), offset },
+        |replay| replay.tls_offsets.get(&(crate_name.to_string(), section.to_string())) == Some(&offset),
+    );
+}
+
+/// Returns the crate and section that the missing `symbol` in the given `namespace`
+/// was resolved to in the trace being replayed, if any.
+pub(crate) fn replayed_symbol(namespace: &str, symbol: &str) -> Option<(String, String)> {
+    STATE.lock().replay.as_ref()?
+        .symbols.get(&(namespace.to_string(), symbol.to_string()))
+        .cloned()
+}
+
+/// Returns the offset that the TLS `section` in `crate_name` was assigned in the trace being replayed, if any.
+pub(crate) fn replayed_tls_offset(crate_name: &str, section: &str) -> Option<usize> {
+    STATE.lock().replay.as_ref()?
+        .tls_offsets.get(&(crate_name.to_string(), section.to_string()))
+        .copied()
+}
+
+#[cfg(ktest)]
+mod ktests {
+    use super::*;
+    use ktest_macros::ktest;
+
+    #[ktest]
+    fn serialized_trace_can_be_parsed() -> Result<(), &'static str> {
+        let events = vec![
+            LoadEvent::CrateLoad { namespace: "_kernel".into(), crate_name: "k#captain-0123".into() },
+            LoadEvent::SymbolResolution {
+                namespace: "_applications".into(),
+                symbol: "<a::A as core::ops::drop::Drop>::drop::h0123".into(),
+                crate_name: "k#a-4567".into(),
+                section: "<a::A as core::ops::drop::Drop>::drop::h89ab".into(),
+            },
+            LoadEvent::TlsOffset { crate_name: "k#a-4567".into(), section: "a::FOO::hcdef".into(), offset: 0x1a8 },
+        ];
+        if parse(&serialize(&events))? != events {
+            return Err("parsed trace differs from the serialized events");
+        }
+        if parse("crate\t_kernel\tk#captain-0123\n").is_ok() {
+            return Err("trace without a header was parsed");
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,135 @@
+//! A pipeline that loads independent crates on multiple CPUs.
+//!
+//! Loading a batch of crates via [`CrateNamespace::load_crates_parallel()`] proceeds in three phases:
+//! 1. Each crate's object file is parsed and its sections are mapped and copied into memory,
+//!    with each crate being a separate job that is run by the [`ParallelExecutor`] on multiple CPUs.
+//!    The only shared state mutated in this phase is the `TlsInitializer`,
+//!    with which each crate registers all of its TLS sections as one batch.
+//! 2. The global symbols of all crates are added to the namespace's symbol map, serially.
+//! 3. Each crate's relocations are performed, serially, because that may load further crates
+//!    and mutates the dependency lists of sections in other crates.
+//!
+//! Until an executor is registered via [`set_parallel_executor()`], e.g., before other CPUs are booted,
+//! the first phase runs serially on the current CPU.
+//! Note that the TLS offsets assigned to crates loaded in parallel depend on the order in which the jobs run,
+//! unless the namespace uses a [deterministic TLS layout](CrateNamespace::set_deterministic_tls_layout).
+//!
+//! At boot, the crates listed in the `extra_files/preload_crates` file, one crate name prefix per line,
+//! are loaded by [`preload()`] once all CPUs are up, rather than one at a time on demand later.
+//! The duration of each phase of the most recent batch is available from [`last_stats()`]
+//! in order to measure the boot time that is saved.
+
+use alloc::{
+    string::String,
+    sync::Arc,
+    vec::Vec,
+};
+use spin::{Mutex, Once};
+use memory::MmiRef;
+use crate::CrateNamespace;
+
+/// The name of the file in the `extra_files` directory that lists the crates to preload at boot, if it exists.
+pub const PRELOAD_FILE_NAME: &str = "preload_crates";
+
+/// A function that invokes `job` once for each index in `0 .. num_jobs`, distributing the jobs across CPUs,
+/// and returns only once all jobs have completed.
+pub type ParallelExecutor = fn(num_jobs: usize, job: &(dyn Fn(usize) + Sync));
+
+/// The durations of the phases of loading one batch of crates, in [`tracepoint::timestamp()`] ticks.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PipelineStats {
+    /// The number of crates in the batch.
+    pub crates: usize,
+    /// The time it took to load the sections of all crates, i.e., the parallel phase.
+    pub section_loading: u64,
+    /// The sum of the times it took to load the sections of each crate,
+    /// i.e., how long the parallel phase would have taken on a single CPU.
+    pub section_loading_serial: u64,
+    /// The time it took to add the symbols of all crates to the symbol map.
+    pub symbols: u64,
+    /// The time it took to perform the relocations of all crates.
+    pub relocations: u64,
+}
+
+static EXECUTOR: Once<ParallelExecutor> = Once::new();
+static LAST_STATS: Mutex<Option<PipelineStats>> = Mutex::new(None);
+static PRELOAD_LIST: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+/// Registers the executor that runs the jobs of the crate loading pipeline on multiple CPUs.
+///
+/// Returns an error if an executor was already registered.
+pub fn set_parallel_executor(executor: ParallelExecutor) -> Result<(), &'static str> {
+    let mut newly_set = false;
+    EXECUTOR.call_once(|| { newly_set = true; executor });
+    if newly_set {
+        Ok(())
+    } else {
+        Err("parallel executor was already registered")
+    }
+}
+
+/// Returns the phase durations of the most recent batch of crates loaded by the pipeline, if any.
+pub fn last_stats() -> Option<PipelineStats> {
+    *LAST_STATS.lock()
+}
+
+/// Parses the given list of crates to preload, which holds one crate name prefix per line.
+/// Empty lines and lines starting with `#` are ignored.
+pub fn parse_preload_list(text: &str) -> Vec<String> {
+    text.lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        .map(String::from)
+        .collect()
+}
+
+/// Sets the crate name prefixes that [`preload()`] will load.
+pub fn set_preload_list(crate_name_prefixes: Vec<String>) {
+    *PRELOAD_LIST.lock() = crate_name_prefixes;
+}
+
+/// Loads all crates in the preload list that aren't yet loaded into the given `namespace`
+/// using the parallel pipeline, and then clears the preload list.
+///
+/// This should be invoked once all CPUs are up and a [`ParallelExecutor`] has been registered.
+///
+/// Returns the number of crates that were loaded.
+pub fn preload(namespace: &Arc<CrateNamespace>, kernel_mmi_ref: &MmiRef) -> Result<usize, &'static str> {
+    let prefixes = core::mem::take(&mut *PRELOAD_LIST.lock());
+    let mut crate_files = Vec::with_capacity(prefixes.len());
+    for prefix in &prefixes {
+        if !CrateNamespace::get_crates_starting_with(namespace, prefix).is_empty() {
+            continue;
+        }
+        match namespace.dir().get_file_starting_with(prefix) {
+            Some(file) if !crate_files.iter().any(|f| Arc::ptr_eq(f, &file)) => crate_files.push(file),
+            Some(_) => { }
+            None => warn!("preload(): couldn't find a single crate object file starting with {:?}", prefix),
+        }
+    }
+    if !crate_files.is_empty() {
+        namespace.load_crates_parallel(crate_files.iter(), None, kernel_mmi_ref, false)?;
+    }
+    Ok(crate_files.len())
+}
+
+/// Runs `job` for each index in `0 .. num_jobs` using the registered executor, if any,
+/// and returns the sum of the durations of all jobs.
+pub(crate) fn run(num_jobs: usize, job: &(dyn Fn(usize) + Sync)) -> u64 {
+    let total = core::sync::atomic::AtomicU64::new(0);
+    let timed_job = |i| {
+        let start = tracepoint::timestamp();
+        job(i);
+        total.fetch_add(tracepoint::timestamp().wrapping_sub(start), core::sync::atomic::Ordering::Relaxed);
+    };
+    match EXECUTOR.get() {
+        Some(executor) if num_jobs > 1 => executor(num_jobs, &timed_job),
+        _ => (0 .. num_jobs).for_each(timed_job),
+    }
+    total.into_inner()
+}
+
+/// Records the phase durations of a batch of crates that was just loaded.
+pub(crate) fn record_stats(stats: PipelineStats) {
+    *LAST_STATS.lock() = Some(stats);
+}
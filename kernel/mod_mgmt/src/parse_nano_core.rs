@@ -149,8 +149,8 @@ pub fn parse_nano_core(
 
     // Now that we've initialized the nano_core, i.e., set up its sections,
     // we can obtain a new TLS data image and initialize the TLS register to point to it.
-    let tls_image = namespace.get_tls_initializer_data();
-    tls_image.set_as_current_tls_base();
+    // CPU IDs aren't yet available, so we cannot use the cached TLS register update.
+    let tls_image = try_mp!(namespace.init_bootstrap_tls(None));
 
     Ok(NanoCoreItems {
         nano_core_crate_ref,
@@ -0,0 +1,337 @@
+//! A prelink cache that accelerates loading crates at boot by reusing the relocation results of a previous boot.
+//!
+//! While recording, the loader saves the following for every crate that it fully relocates:
+//! * a hash of the crate's object file,
+//! * the virtual addresses that its sections were loaded at,
+//! * the offset assigned to each of its TLS sections,
+//! * and every relocation along with the source section it was resolved to and the resulting source value.
+//!
+//! The recorded cache can be [serialized](serialize) into a line-based text format,
+//! e.g., to be copied from the serial log into the `extra_files/prelink_cache` file for later boots.
+//! If that file exists at boot, [`init()`](crate::init) uses it as follows:
+//! * A crate's sections are loaded at their cached virtual addresses, if those addresses are still free.
+//! * TLS sections are placed at their cached offsets, if those offsets are still free,
+//!   and all other TLS sections are placed deterministically.
+//! * If a crate's object file has the same hash and its sections were loaded at the same addresses,
+//!   its relocations are applied from the cache, without parsing its relocation sections
+//!   or demangling the names of the symbols it depends on.
+//!   Each cached source value is validated against the current address of its source section first;
+//!   if any differs, e.g., because another crate changed, the crate is fully relocated instead.
+//!
+//! See the `prelink` application for recording and saving the cache.
+
+use core::fmt::{self, Write};
+use alloc::{
+    collections::BTreeMap,
+    string::{String, ToString},
+    vec::Vec,
+};
+use spin::Mutex;
+use crate_metadata::{RelocationEntry, Shndx};
+
+/// The first line of a serialized cache, which identifies the format.
+const CACHE_HEADER: &str = "# theseus prelink cache v1";
+
+/// The name of the file in the `extra_files` directory that is used as the prelink cache at boot, if it exists.
+pub const CACHE_FILE_NAME: &str = "prelink_cache";
+
+/// The cached relocation results of a single crate.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PrelinkedCrate {
+    /// The namespace that the crate was loaded into.
+    pub namespace: String,
+    /// The name of the crate.
+    pub crate_name: String,
+    /// The hash of the crate's object file; see [`hash()`].
+    pub file_hash: u64,
+    /// The starting virtual addresses of the crate's executable, read-only, and read-write pages, if any.
+    pub section_pages: [Option<usize>; 3],
+    /// The offset from the TLS self pointer assigned to each of the crate's TLS sections.
+    pub tls_offsets: Vec<(String, usize)>,
+    /// All relocations of the crate, in the order they were applied.
+    pub relocations: Vec<PrelinkedRelocation>,
+}
+
+/// A single relocation within a [`PrelinkedCrate`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PrelinkedRelocation {
+    /// The section index of the target section that the relocation is written into.
+    pub target_shndx: Shndx,
+    /// The relocation itself.
+    pub relocation: RelocationEntry,
+    /// The section that the relocation was resolved against.
+    pub source: RelocationSource,
+    /// The value of the symbol within the source section, i.e., its offset.
+    pub symbol_value: usize,
+    /// The source value that was written, which must be identical when the cache is used.
+    pub source_value: usize,
+}
+
+/// The source section of a [`PrelinkedRelocation`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RelocationSource {
+    /// A section in the same crate, with the given section index.
+    Local(Shndx),
+    /// A section in another crate, with the given demangled symbol name.
+    Foreign(String),
+}
+
+/// Statistics about the use of the prelink cache during this boot.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PrelinkStats {
+    /// The number of crates in the cache that haven't been loaded yet.
+    pub pending: usize,
+    /// The number of crates whose relocations were applied from the cache.
+    pub hits: usize,
+    /// The number of cached crates that had to be fully relocated
+    /// because their cached relocation results were no longer valid.
+    pub misses: usize,
+}
+
+struct State {
+    recording: bool,
+    recorded: Vec<PrelinkedCrate>,
+    /// Maps a (namespace, crate) pair to its cached relocation results.
+    cache: BTreeMap<(String, String), PrelinkedCrate>,
+    /// Maps a (crate, section) pair to the cached offset of that TLS section.
+    tls_offsets: BTreeMap<(String, String), usize>,
+    stats: PrelinkStats,
+}
+
+static STATE: Mutex<State> = Mutex::new(State {
+    recording: false,
+    recorded: Vec::new(),
+    cache: BTreeMap::new(),
+    tls_offsets: BTreeMap::new(),
+    stats: PrelinkStats { pending: 0, hits: 0, misses: 0 },
+});
+
+/// Starts recording the relocation results of all subsequently loaded crates,
+/// discarding any previously-recorded crates.
+pub fn start_recording() {
+    let mut state = STATE.lock();
+    state.recording = true;
+    state.recorded.clear();
+}
+
+/// Stops recording and returns the recorded crates.
+pub fn stop_recording() -> Vec<PrelinkedCrate> {
+    let mut state = STATE.lock();
+    state.recording = false;
+    core::mem::take(&mut state.recorded)
+}
+
+/// Returns `true` if relocation results are currently being recorded.
+pub fn is_recording() -> bool {
+    STATE.lock().recording
+}
+
+/// Returns a copy of the crates recorded so far.
+pub fn recorded_crates() -> Vec<PrelinkedCrate> {
+    STATE.lock().recorded.clone()
+}
+
+/// Uses the given cached crates for all crates loaded from now on.
+///
+/// This also enables the deterministic TLS layout, so it must be invoked
+/// before any crates with TLS sections are dynamically loaded.
+pub fn use_cache(crates: Vec<PrelinkedCrate>) -> Result<(), &'static str> {
+    crate::TLS_INITIALIZER.lock().set_deterministic_layout(true)?;
+    let mut state = STATE.lock();
+    for prelinked in crates {
+        for (section, offset) in &prelinked.tls_offsets {
+            state.tls_offsets.insert((prelinked.crate_name.clone(), section.clone()), *offset);
+        }
+        state.cache.insert((prelinked.namespace.clone(), prelinked.crate_name.clone()), prelinked);
+    }
+    state.stats.pending = state.cache.len();
+    Ok(())
+}
+
+/// Discards the prelink cache, such that all crates loaded from now on are fully relocated.
+pub fn clear_cache() {
+    let mut state = STATE.lock();
+    state.cache.clear();
+    state.tls_offsets.clear();
+    state.stats.pending = 0;
+}
+
+/// Returns statistics about the use of the prelink cache.
+pub fn stats() -> PrelinkStats {
+    STATE.lock().stats
+}
+
+/// Returns the hash of a crate object file's contents, which is its 64-bit FNV-1a hash.
+pub fn hash(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| (hash ^ *byte as u64).wrapping_mul(0x0100_0000_01b3))
+}
+
+// Fields are separated by tabs, which never occur in crate, namespace, or symbol names.
+impl fmt::Display for PrelinkedCrate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "crate\t{}\t{}\t{:#x}", self.namespace, self.crate_name, self.file_hash)?;
+        for pages in self.section_pages {
+            match pages {
+                Some(address) => write!(f, "\t{:#x}", address)?,
+                None => f.write_str("\t-")?,
+            }
+        }
+        for (section, offset) in &self.tls_offsets {
+            write!(f, "\ntls\t{}\t{:#x}", section, offset)?;
+        }
+        for r in &self.relocations {
+            let (kind, source) = match &r.source {
+                RelocationSource::Local(shndx) => ("local", shndx.to_string()),
+                RelocationSource::Foreign(symbol) => ("foreign", symbol.clone()),
+            };
+            write!(f, "\n{}\t{}\t{}\t{:#x}\t{:#x}\t{:#x}\t{:#x}\t{:#x}",
+                kind, source, r.target_shndx, r.relocation.typ, r.relocation.addend,
+                r.relocation.offset, r.symbol_value, r.source_value,
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Serializes the given `crates` into a cache that can be [parsed](parse) on a later boot.
+pub fn serialize(crates: &[PrelinkedCrate]) -> String {
+    let mut cache = String::from(CACHE_HEADER);
+    cache.push('\n');
+    for prelinked in crates {
+        let _ = writeln!(cache, "{}", prelinked);
+    }
+    cache
+}
+
+fn parse_hex(field: &str) -> Result<usize, &'static str> {
+    usize::from_str_radix(field.trim_start_matches("0x"), 16).map_err(|_| "invalid number in prelink cache")
+}
+
+/// Parses a cache that was created by [`serialize()`].
+///
+/// Empty lines are ignored, as are comment lines starting with `#` after the header.
+pub fn parse(cache: &str) -> Result<Vec<PrelinkedCrate>, &'static str> {
+    let mut lines = cache.lines().map(|line| line.trim_end_matches('\r'));
+    if lines.next() != Some(CACHE_HEADER) {
+        return Err("prelink cache doesn't start with the expected header");
+    }
+    let mut crates: Vec<PrelinkedCrate> = Vec::new();
+    for line in lines.filter(|line| !line.is_empty() && !line.starts_with('#')) {
+        let fields: Vec<&str> = line.split('\t').collect();
+        if let ["crate", namespace, crate_name, file_hash, pages @ ..] = fields.as_slice() {
+            let [text, rodata, data] = pages else { return Err("invalid crate in prelink cache") };
+            let page = |field: &str| if field == "-" { Ok(None) } else { parse_hex(field).map(Some) };
+            crates.push(PrelinkedCrate {
+                namespace: namespace.to_string(),
+                crate_name: crate_name.to_string(),
+                file_hash: parse_hex(file_hash)? as u64,
+                section_pages: [page(*text)?, page(*rodata)?, page(*data)?],
+                tls_offsets: Vec::new(),
+                relocations: Vec::new(),
+            });
+            continue;
+        }
+        let prelinked = crates.last_mut().ok_or("prelink cache entry doesn't belong to a crate")?;
+        match fields.as_slice() {
+            ["tls", section, offset] => prelinked.tls_offsets.push((section.to_string(), parse_hex(offset)?)),
+            [kind @ ("local" | "foreign"), source, target_shndx, typ, addend, offset, symbol_value, source_value] => {
+                let source = if *kind == "local" {
+                    RelocationSource::Local(source.parse().map_err(|_| "invalid section index in prelink cache")?)
+                } else {
+                    RelocationSource::Foreign(source.to_string())
+                };
+                prelinked.relocations.push(PrelinkedRelocation {
+                    target_shndx: target_shndx.parse().map_err(|_| "invalid section index in prelink cache")?,
+                    relocation: RelocationEntry {
+                        typ: parse_hex(typ)? as u32,
+                        addend: parse_hex(addend)?,
+                        offset: parse_hex(offset)?,
+                    },
+                    source,
+                    symbol_value: parse_hex(symbol_value)?,
+                    source_value: parse_hex(source_value)?,
+                });
+            }
+            _ => return Err("invalid entry in prelink cache"),
+        }
+    }
+    Ok(crates)
+}
+
+/// Records the relocation results of a fully relocated crate, if recording.
+pub(crate) fn record_crate(make_prelinked: impl FnOnce() -> PrelinkedCrate) {
+    let mut state = STATE.lock();
+    if state.recording {
+        let prelinked = make_prelinked();
+        state.recorded.push(prelinked);
+    }
+}
+
+/// Removes and returns the cached relocation results of `crate_name` in the given `namespace`, if any.
+pub(crate) fn take_cached(namespace: &str, crate_name: &str) -> Option<PrelinkedCrate> {
+    let mut state = STATE.lock();
+    let prelinked = state.cache.remove(&(namespace.to_string(), crate_name.to_string()))?;
+    state.stats.pending -= 1;
+    Some(prelinked)
+}
+
+/// Returns the cached starting addresses of the pages of `crate_name` in the given `namespace`, if any.
+pub(crate) fn cached_section_pages(namespace: &str, crate_name: &str) -> Option<[Option<usize>; 3]> {
+    STATE.lock().cache.get(&(namespace.to_string(), crate_name.to_string()))
+        .map(|prelinked| prelinked.section_pages)
+}
+
+/// Returns the cached offset of the TLS `section` in `crate_name`, if any.
+pub(crate) fn cached_tls_offset(crate_name: &str, section: &str) -> Option<usize> {
+    STATE.lock().tls_offsets.get(&(crate_name.to_string(), section.to_string())).copied()
+}
+
+/// Counts a crate whose cached relocation results were used (`hit`) or were invalid.
+pub(crate) fn count(hit: bool) {
+    let mut state = STATE.lock();
+    if hit {
+        state.stats.hits += 1;
+    } else {
+        state.stats.misses += 1;
+    }
+}
+
+#[cfg(ktest)]
+mod ktests {
+    use super::*;
+    use ktest_macros::ktest;
+
+    #[ktest]
+    fn serialized_cache_can_be_parsed() -> Result<(), &'static str> {
+        let crates = vec![PrelinkedCrate {
+            namespace: "_kernel".into(),
+            crate_name: "k#a-4567".into(),
+            file_hash: hash(b"object file"),
+            section_pages: [Some(0xFFFF_FE80_0000_0000), None, Some(0xFFFF_FE80_0000_2000)],
+            tls_offsets: vec![("a::FOO::hcdef".into(), 0x1a8)],
+            relocations: vec![
+                PrelinkedRelocation {
+                    target_shndx: 3,
+                    relocation: RelocationEntry { typ: 4, addend: (-4isize) as usize, offset: 0x10 },
+                    source: RelocationSource::Foreign("<b::B as core::ops::drop::Drop>::drop::h0123".into()),
+                    symbol_value: 0,
+                    source_value: 0xFFFF_FE80_0001_0000,
+                },
+                PrelinkedRelocation {
+                    target_shndx: 3,
+                    relocation: RelocationEntry { typ: 1, addend: 8, offset: 0x20 },
+                    source: RelocationSource::Local(5),
+                    symbol_value: 0x18,
+                    source_value: 0xFFFF_FE80_0000_2018,
+                },
+            ],
+        }];
+        if parse(&serialize(&crates))? != crates {
+            return Err("parsed cache differs from the serialized crates");
+        }
+        if parse("crate\t_kernel\tk#a-4567\t0x0\t-\t-\t-\n").is_ok() {
+            return Err("cache without a header was parsed");
+        }
+        Ok(())
+    }
+}
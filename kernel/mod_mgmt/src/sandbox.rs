@@ -0,0 +1,118 @@
+//! Lightweight sandboxing of the crates loaded into a namespace, e.g., third-party crates.
+//!
+//! A sandboxed `CrateNamespace`, created via [`CrateNamespace::new_sandboxed()`],
+//! may only resolve the symbols of its recursive (parent) namespace that are on its [`SymbolAllowList`].
+//! All other symbols of the parent namespace are invisible to it, so a crate in the sandbox
+//! that depends on a denied symbol, e.g., raw port I/O or the frame allocator, fails to load
+//! rather than being linked against it. Denied symbols' crates are also never loaded on its behalf.
+//!
+//! A sandboxed namespace can also have its own region of the TLS area,
+//! into which the TLS sections of all of its crates are placed, isolating them from all other TLS sections.
+//! The region is reserved when the namespace is created and released once it is dropped,
+//! and the sandbox's crates fail to load once the region is full.
+
+use core::ops::Range;
+use alloc::{
+    string::String,
+    vec::Vec,
+};
+use spin::Mutex;
+use crate::TlsInitializer;
+
+/// The alignment of a sandbox's TLS region, which is a cache line such that
+/// the sandbox's TLS sections never share a cache line with other TLS sections.
+const TLS_REGION_ALIGNMENT: usize = 64;
+
+/// The list of symbols that a sandboxed namespace may resolve from its recursive namespace.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SymbolAllowList {
+    prefixes: Vec<String>,
+}
+
+impl SymbolAllowList {
+    /// Creates an allow-list of all symbols that start with one of the given prefixes,
+    /// e.g., `"core::"` for every symbol in the `core` crate or `"memcpy"` for a `no_mangle` symbol.
+    pub fn new(prefixes: Vec<String>) -> SymbolAllowList {
+        SymbolAllowList { prefixes }
+    }
+
+    /// Parses an allow-list that holds one symbol prefix per line.
+    /// Empty lines and lines starting with `#` are ignored.
+    pub fn parse(text: &str) -> SymbolAllowList {
+        SymbolAllowList::new(text.lines()
+            .map(str::trim)
+            .filter(|l| !l.is_empty() && !l.starts_with('#'))
+            .map(String::from)
+            .collect()
+        )
+    }
+
+    /// Returns the symbol prefixes on this allow-list.
+    pub fn prefixes(&self) -> &[String] {
+        &self.prefixes
+    }
+
+    /// Returns whether the given fully-qualified symbol is on this allow-list.
+    pub fn allows(&self, demangled_full_symbol: &str) -> bool {
+        self.prefixes.iter().any(|prefix| demangled_full_symbol.starts_with(prefix.as_str()))
+    }
+}
+
+/// The restrictions placed on a sandboxed `CrateNamespace`.
+pub(crate) struct Sandbox {
+    pub(crate) allowed_symbols: SymbolAllowList,
+    /// The TLS initializer in which `tls_region` is reserved.
+    tls_initializer: &'static Mutex<TlsInitializer>,
+    /// The region of the TLS area reserved for this sandbox's TLS sections, if any.
+    pub(crate) tls_region: Option<Range<usize>>,
+}
+
+impl Sandbox {
+    /// Creates a new sandbox with the given allow-list,
+    /// reserving a TLS region of `tls_region_size` bytes if it is nonzero.
+    pub(crate) fn new(
+        allowed_symbols: SymbolAllowList,
+        tls_initializer: &'static Mutex<TlsInitializer>,
+        tls_region_size: usize,
+    ) -> Result<Sandbox, &'static str> {
+        let tls_region = if tls_region_size > 0 {
+            Some(tls_initializer.lock().reserve_dynamic_tls_region(tls_region_size, TLS_REGION_ALIGNMENT)
+                .map_err(|_| "couldn't reserve a TLS region for the sandboxed namespace")?)
+        } else {
+            None
+        };
+        Ok(Sandbox { allowed_symbols, tls_initializer, tls_region })
+    }
+}
+
+impl Drop for Sandbox {
+    fn drop(&mut self) {
+        if let Some(region) = self.tls_region.take() {
+            if self.tls_initializer.lock().release_dynamic_tls_region(&region).is_err() {
+                error!("BUG: couldn't release the TLS region {:X?} of a sandboxed namespace", region);
+            }
+        }
+    }
+}
+
+
+#[cfg(ktest)]
+mod ktests {
+    use super::*;
+    use ktest_macros::ktest;
+
+    #[ktest]
+    fn allow_list_is_parsed() -> Result<(), &'static str> {
+        let allow_list = SymbolAllowList::parse("# sandbox\ncore::\n\n  alloc::  \nmemcpy\n");
+        if allow_list.prefixes().len() != 3 {
+            return Err("wrong number of prefixes parsed from allow-list");
+        }
+        if !allow_list.allows("core::fmt::write::h0123456789abcdef") || !allow_list.allows("memcpy") {
+            return Err("allowed symbol was denied");
+        }
+        if allow_list.allows("port_io::Port::write::h0123456789abcdef") || allow_list.allows("frame_allocator::allocate_frames") {
+            return Err("denied symbol was allowed");
+        }
+        Ok(())
+    }
+}
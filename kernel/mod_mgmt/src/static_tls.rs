@@ -0,0 +1,139 @@
+//! Live updates of the static TLS layout of the statically-linked base kernel image (the nano_core).
+//!
+//! The static TLS sections of a new nano_core may be at different offsets than those of the current one,
+//! so replacing them via [`CrateNamespace::replace_static_tls_sections()`] proceeds in three steps:
+//! 1. The namespace's `TlsInitializer` remaps each current static TLS section to its counterpart
+//!    in the new nano_core, while keeping all dynamic TLS sections at their offsets.
+//! 2. The relocations in every section that depends on a remapped static TLS section are rewritten
+//!    to use its new offset, and the symbol map is updated to refer to the new sections.
+//! 3. The caller regenerates each task's TLS data image via [`CrateNamespace::rebase_tls_image()`],
+//!    e.g., using `task::rebase_tls_areas()`.
+//!
+//! The first two steps are undone by [`StaticTlsUpdate::rollback()`] if the third step fails,
+//! such that the existing TLS data images, which are only replaced once all of them
+//! have been regenerated, remain valid.
+//! Once all tasks' TLS areas have been replaced, the update is finalized via [`StaticTlsUpdate::commit()`],
+//! after which new tasks also obtain TLS areas with the new layout.
+
+use alloc::vec::Vec;
+use memory::MmiRef;
+use crate::{CrateNamespace, LoadedSection, StrRef, TlsInitializer, TlsSectionRemapping, WeakDependent, WeakSectionRef};
+
+/// An update of a namespace's static TLS sections that has been applied but not yet committed.
+///
+/// See the [module-level documentation](self) for more.
+pub struct StaticTlsUpdate<'n> {
+    namespace: &'n CrateNamespace,
+    kernel_mmi_ref: MmiRef,
+    /// The namespace's `TlsInitializer` from before the update.
+    previous_tls_initializer: TlsInitializer,
+    remappings: Vec<TlsSectionRemapping>,
+    /// The dependents of each remapping's `old_section` from before their relocations were rewritten,
+    /// in the same order as the `remappings` that have been rewritten so far.
+    previous_dependents: Vec<Vec<WeakDependent>>,
+    /// The symbol map entries that were replaced, along with their previous values.
+    previous_symbols: Vec<(StrRef, Option<WeakSectionRef>)>,
+}
+
+impl<'n> StaticTlsUpdate<'n> {
+    /// Returns the remapping of every old static TLS section to its new counterpart,
+    /// which is needed to rebase existing TLS data images.
+    pub fn remappings(&self) -> &[TlsSectionRemapping] {
+        &self.remappings
+    }
+
+    /// Finalizes this update, such that new tasks obtain TLS areas with the new static TLS layout.
+    pub fn commit(self) {
+        self.namespace.publish_tls_template();
+    }
+
+    /// Undoes this update, restoring the namespace's previous static TLS sections,
+    /// the relocations in all sections that depend on them, and its symbol map.
+    ///
+    /// This continues past any failure, returning the first error, if any.
+    pub fn rollback(self) -> Result<(), &'static str> {
+        let mut result = Ok(());
+        {
+            let mut symbol_map = self.namespace.symbol_map.lock();
+            for (name, previous) in self.previous_symbols.into_iter().rev() {
+                match previous {
+                    Some(weak_sec) => { symbol_map.insert(name, weak_sec); }
+                    None => { symbol_map.remove(name.as_bytes()); }
+                }
+            }
+        }
+        for (r, dependents) in self.remappings.iter().zip(self.previous_dependents).rev() {
+            if let Err(e) = CrateNamespace::rewrite_section_dependents(&r.new_section, &r.old_section, &self.kernel_mmi_ref) {
+                error!("couldn't restore the dependents of static TLS section {:?}: {}", r.old_section.name, e);
+                result = result.and(Err(e));
+            }
+            r.old_section.inner.write().sections_dependent_on_me = dependents;
+            r.new_section.inner.write().sections_dependent_on_me.clear();
+        }
+        self.namespace.tls_initializer.lock().restore(self.previous_tls_initializer);
+        self.namespace.publish_tls_template();
+        result
+    }
+}
+
+impl CrateNamespace {
+    /// Replaces the static TLS sections of this namespace with the given TLS sections
+    /// of a new statically-linked base kernel image, whose offsets may differ from the current ones.
+    ///
+    /// Each of the `new_sections` is given along with its offset as determined by the linker;
+    /// see [`TlsInitializer::replace_static_tls_sections()`] for how they're matched to the current sections.
+    /// This also rewrites the relocations of all sections that depend on a current static TLS section
+    /// and replaces its entry in the symbol map, if any.
+    ///
+    /// Existing TLS data images are unaffected; they must be regenerated via [`Self::rebase_tls_image()`]
+    /// before the returned update is committed, or the update must be rolled back.
+    /// See the [`static_tls`](crate::static_tls) module for more.
+    ///
+    /// If an error occurs, everything that was already modified is restored before returning.
+    pub fn replace_static_tls_sections(
+        &self,
+        new_sections: Vec<(LoadedSection, usize)>,
+        total_static_tls_size: usize,
+        kernel_mmi_ref: &MmiRef,
+    ) -> Result<StaticTlsUpdate<'_>, &'static str> {
+        let mut tls_initializer = self.tls_initializer.lock();
+        let previous_tls_initializer = tls_initializer.clone();
+        let remappings = tls_initializer.replace_static_tls_sections(new_sections, total_static_tls_size)?;
+        drop(tls_initializer);
+
+        let mut update = StaticTlsUpdate {
+            namespace: self,
+            kernel_mmi_ref: kernel_mmi_ref.clone(),
+            previous_tls_initializer,
+            previous_dependents: Vec::with_capacity(remappings.len()),
+            remappings: Vec::with_capacity(remappings.len()),
+            previous_symbols: Vec::new(),
+        };
+        for r in remappings {
+            update.previous_dependents.push(r.old_section.inner.read().sections_dependent_on_me.clone());
+            let rewritten = CrateNamespace::rewrite_section_dependents(&r.old_section, &r.new_section, kernel_mmi_ref);
+            update.remappings.push(r);
+            if let Err(e) = rewritten {
+                // The partially-rewritten dependents of the failed remapping are restored, too.
+                if let Err(rollback_err) = update.rollback() {
+                    error!("couldn't roll back a failed static TLS update: {}", rollback_err);
+                }
+                return Err(e);
+            }
+        }
+
+        let mut symbol_map = self.symbol_map.lock();
+        for r in update.remappings.iter().filter(|r| r.old_section.global) {
+            let previous = symbol_map.get(r.old_section.name.as_bytes()).cloned();
+            update.previous_symbols.push((r.old_section.name.clone(), previous));
+            symbol_map.remove(r.old_section.name.as_bytes());
+            if r.new_section.global {
+                let previous = symbol_map.get(r.new_section.name.as_bytes()).cloned();
+                update.previous_symbols.push((r.new_section.name.clone(), previous));
+                CrateNamespace::add_symbol(&mut symbol_map, r.new_section.name.clone(), &r.new_section, false);
+            }
+        }
+        drop(symbol_map);
+        Ok(update)
+    }
+}
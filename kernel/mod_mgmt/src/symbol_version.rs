@@ -0,0 +1,135 @@
+//! Versioning metadata for global symbols, which is used to reject resolving a user of a symbol
+//! against an incompatible definition, rather than silently producing an ABI mismatch.
+//!
+//! Each `CrateNamespace` records a [`SymbolVersion`] alongside every global symbol in its symbol map:
+//! the symbol's section type, size, and alignment, plus an optional version tag,
+//! e.g., a semver string or a hash of the symbol's type signature.
+//!
+//! Version tags are declared in an optional, non-allocated section of a crate object file
+//! named `.theseus_symbol_versions`, e.g., one added by `objcopy --add-section`.
+//! Each line of that section holds a demangled symbol name without its trailing hash and its tag,
+//! separated by whitespace, e.g., `keyboard::init 1.2.0`.
+//! A tag declared for a symbol that the crate defines is the version of that definition,
+//! whereas a tag declared for a symbol that the crate only uses is the version it expects.
+//!
+//! Versions are checked as follows:
+//! * When loading a crate, each foreign symbol that it expects a version of
+//!   must be resolved against a definition that has no tag or the same tag.
+//! * When swapping crates, each global symbol in an old crate must be replaced by a definition
+//!   that is [compatible](SymbolVersion::check_replaces) with it, which includes
+//!   data and TLS symbols keeping their size and alignment.
+
+use alloc::{
+    collections::BTreeMap,
+    string::String,
+};
+use xmas_elf::{ElfFile, sections::SectionData};
+use crate_metadata::{LoadedSection, SectionType};
+
+/// The name of the section that declares the version tags of a crate's symbols.
+pub const VERSIONS_SECTION_NAME: &str = ".theseus_symbol_versions";
+
+/// The versioning metadata of a global symbol.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SymbolVersion {
+    /// The version tag declared for this symbol, if any.
+    pub tag: Option<String>,
+    /// The type of the symbol's section.
+    pub typ: SectionType,
+    /// The size in bytes of the symbol's section.
+    pub size: usize,
+    /// The alignment of the section that contains the symbol in its crate object file.
+    pub alignment: usize,
+}
+
+impl SymbolVersion {
+    /// Checks whether this new definition of a symbol can replace the `old` definition
+    /// without the existing users of the `old` definition observing an ABI mismatch.
+    ///
+    /// This requires that:
+    /// * both definitions have the same version tag, if both have one,
+    /// * both are the same kind of symbol, i.e., a function, a data symbol, or a TLS symbol, and
+    /// * if they're data or TLS symbols, the new definition has the same size and at least the same alignment.
+    pub fn check_replaces(&self, old: &SymbolVersion) -> Result<(), &'static str> {
+        if let (Some(new_tag), Some(old_tag)) = (&self.tag, &old.tag) {
+            if new_tag != old_tag {
+                return Err("the new definition of a symbol has a different version tag");
+            }
+        }
+        let kind = |typ: SectionType| match typ {
+            SectionType::Text => 0,
+            SectionType::Rodata | SectionType::Data | SectionType::Bss => 1,
+            SectionType::TlsData | SectionType::TlsBss => 2,
+            SectionType::GccExceptTable | SectionType::EhFrame => 3,
+        };
+        if kind(self.typ) != kind(old.typ) {
+            return Err("the new definition of a symbol is a different kind of symbol");
+        }
+        if self.typ != SectionType::Text && (self.size != old.size || self.alignment < old.alignment) {
+            return Err("the new definition of a symbol has a different size or alignment");
+        }
+        Ok(())
+    }
+}
+
+/// Returns the given demangled symbol name without its trailing hash, i.e., the name that version tags are declared for.
+pub fn name_without_hash(symbol: &str) -> &str {
+    let name = LoadedSection::section_name_without_hash(symbol);
+    name.strip_suffix("::h").unwrap_or(name)
+}
+
+/// Parses the version tags declared in the given crate object file's `.theseus_symbol_versions` section.
+///
+/// Returns a map from each symbol name without its hash to its tag, which is empty if the section doesn't exist.
+pub fn parse_version_tags(elf_file: &ElfFile) -> Result<BTreeMap<String, String>, &'static str> {
+    let mut tags = BTreeMap::new();
+    let section = match elf_file.find_section_by_name(VERSIONS_SECTION_NAME) {
+        Some(section) => section,
+        None => return Ok(tags),
+    };
+    let text = match section.get_data(elf_file) {
+        Ok(SectionData::Undefined(bytes)) => core::str::from_utf8(bytes)
+            .map_err(|_| "symbol versions section was not valid UTF-8")?,
+        _ => return Err("couldn't get the data of the symbol versions section"),
+    };
+    for line in text.lines().map(str::trim).filter(|l| !l.is_empty()) {
+        let (name, tag) = line.rsplit_once(char::is_whitespace)
+            .ok_or("malformed line in symbol versions section")?;
+        tags.insert(String::from(name.trim_end()), String::from(tag));
+    }
+    Ok(tags)
+}
+
+
+#[cfg(ktest)]
+mod ktests {
+    use super::*;
+    use ktest_macros::ktest;
+
+    #[ktest]
+    fn changed_tls_symbols_are_incompatible() -> Result<(), &'static str> {
+        let old = SymbolVersion { tag: None, typ: SectionType::TlsData, size: 8, alignment: 8 };
+        let same_layout = SymbolVersion { typ: SectionType::TlsBss, ..old.clone() };
+        let bigger = SymbolVersion { size: 16, ..old.clone() };
+        let less_aligned = SymbolVersion { alignment: 4, ..old.clone() };
+        let data = SymbolVersion { typ: SectionType::Data, ..old.clone() };
+        if same_layout.check_replaces(&old).is_err() {
+            return Err("TLS symbol with the same layout was rejected");
+        }
+        if bigger.check_replaces(&old).is_ok() || less_aligned.check_replaces(&old).is_ok() || data.check_replaces(&old).is_ok() {
+            return Err("incompatible TLS symbol was accepted");
+        }
+
+        let tagged = |tag: &str| SymbolVersion { tag: Some(tag.into()), typ: SectionType::Text, size: 32, alignment: 16 };
+        if tagged("1.0").check_replaces(&tagged("1.1")).is_ok() {
+            return Err("function with a different tag was accepted");
+        }
+        if tagged("1.0").check_replaces(&SymbolVersion { tag: None, size: 64, ..tagged("1.0") }).is_err() {
+            return Err("resized function without an old tag was rejected");
+        }
+        if name_without_hash("keyboard::init::h832430094f98e56b") != "keyboard::init" {
+            return Err("symbol hash wasn't removed");
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,62 @@
+//! Symbolization of code addresses, e.g., the return addresses in a backtrace.
+
+use core::fmt;
+use alloc::string::String;
+use memory::VirtualAddress;
+use crate::{CrateNamespace, StrRef, SECTION_HASH_DELIMITER};
+
+/// A code address resolved to the function (section) that contains it and the crate that contains that function.
+///
+/// This is displayed as `crate::function + offset`, without the trailing hash of the function name.
+/// If the function's path doesn't start with its crate's name, e.g., for an instance of a generic function
+/// or a `no_mangle` function, the crate's name is appended as `function + offset (in crate)`.
+#[derive(Clone, Debug)]
+pub struct SymbolizedAddress {
+    pub address: VirtualAddress,
+    /// The name of the crate that contains the function, without its trailing hash.
+    pub crate_name: String,
+    /// The full name of the section that contains the address, including its trailing hash.
+    pub section_name: StrRef,
+    /// The offset of the address from the start of the section.
+    pub offset: usize,
+}
+
+impl SymbolizedAddress {
+    /// Returns the name of the function that contains the address, without its trailing hash.
+    pub fn function_name(&self) -> &str {
+        let name = self.section_name.as_str();
+        name.rfind(SECTION_HASH_DELIMITER).map_or(name, |end| &name[..end])
+    }
+}
+
+impl fmt::Display for SymbolizedAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let function = self.function_name();
+        let has_crate_prefix = function.strip_prefix(self.crate_name.as_str())
+            .map_or(false, |rest| rest.starts_with("::"));
+        if has_crate_prefix {
+            write!(f, "{} + {:#X}", function, self.offset)
+        } else {
+            write!(f, "{} + {:#X} (in {})", function, self.offset, self.crate_name)
+        }
+    }
+}
+
+impl CrateNamespace {
+    /// Resolves the given code address to the section that contains it and that section's crate.
+    ///
+    /// Like [`Self::get_section_containing_address()`], only executable sections are searched,
+    /// and the same locking considerations apply.
+    pub fn symbolize_address(&self, virt_addr: VirtualAddress) -> Option<SymbolizedAddress> {
+        let (section, offset) = self.get_section_containing_address(virt_addr, false)?;
+        let crate_name = section.parent_crate.upgrade()
+            .map(|c| String::from(c.lock_as_ref().crate_name_without_hash()))
+            .unwrap_or_else(|| String::from("??"));
+        Some(SymbolizedAddress {
+            address: virt_addr,
+            crate_name,
+            section_name: section.name.clone(),
+            offset,
+        })
+    }
+}
@@ -0,0 +1,275 @@
+//! Verification of crate object files before they are loaded.
+//!
+//! Before any of a crate's sections are mapped and registered,
+//! including TLS sections that become part of every task's TLS data image,
+//! the loader checks that the crate object file is trusted, i.e., that either:
+//! * the SHA3-512 hash of the object file is on the allow-list, or
+//! * the object file has a detached Ed25519 signature made by one of the trusted keys.
+//!
+//! The signature of an object file is read from `extra_files/crate_signatures/<object file name>.sig`,
+//! which must start with the 64-byte signature of the whole object file as 128 hexadecimal characters.
+//! The members of an `ar` archive are trusted if the archive itself is.
+//!
+//! Trusted keys and allowed hashes can be added at runtime, and are read at boot
+//! from the `extra_files/trusted_crates` file, if it exists; see [`parse_trust_list()`].
+//!
+//! What happens to a crate that fails verification is determined by the [`VerificationPolicy`],
+//! which is [`Off`](VerificationPolicy::Off) by default and can be set at boot
+//! via the `crate_verification_warn` or `crate_verification_enforce` config options.
+
+use core::{
+    convert::TryFrom,
+    sync::atomic::{AtomicU8, Ordering},
+};
+use alloc::{
+    collections::BTreeSet,
+    vec::Vec,
+};
+use spin::Mutex;
+use ed25519_compact::{PublicKey, Signature};
+use sha3::{Digest, Sha3_512};
+use fs_node::FileRef;
+use crate::EXTRA_FILES_DIRECTORY_NAME;
+
+/// The name of the file in the `extra_files` directory that lists the keys and hashes trusted at boot.
+pub const TRUST_LIST_FILE_NAME: &str = "trusted_crates";
+
+/// The name of the directory within the `extra_files` directory that holds the signatures of crate object files.
+pub const SIGNATURES_DIRECTORY_NAME: &str = "crate_signatures";
+
+/// The file extension of a signature file, which is appended to the name of the object file it signs.
+pub const SIGNATURE_FILE_EXTENSION: &str = "sig";
+
+/// The SHA3-512 hash of a crate object file.
+pub type CrateHash = [u8; 64];
+
+/// What to do when a crate object file fails verification.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum VerificationPolicy {
+    /// Crate object files aren't verified at all.
+    Off = 0,
+    /// Crate object files that fail verification are loaded anyway, but a warning is logged.
+    Warn = 1,
+    /// Crate object files that fail verification are not loaded.
+    Enforce = 2,
+}
+
+impl VerificationPolicy {
+    /// Parses a policy from its lowercase name, i.e., `"off"`, `"warn"`, or `"enforce"`.
+    pub fn from_name(name: &str) -> Option<VerificationPolicy> {
+        match name {
+            "off" => Some(VerificationPolicy::Off),
+            "warn" => Some(VerificationPolicy::Warn),
+            "enforce" => Some(VerificationPolicy::Enforce),
+            _ => None,
+        }
+    }
+}
+
+/// The reasons why a crate object file can fail verification.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VerificationError {
+    /// The object file's hash isn't allowed and it has no signature file.
+    Unsigned,
+    /// The object file's signature file doesn't contain a well-formed signature.
+    MalformedSignature,
+    /// The object file's signature wasn't made by any trusted key.
+    UntrustedSignature,
+}
+
+impl VerificationError {
+    /// Returns a description of this error.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            VerificationError::Unsigned => "crate object file is not signed and its hash is not allowed",
+            VerificationError::MalformedSignature => "crate object file has a malformed signature",
+            VerificationError::UntrustedSignature => "crate object file has a signature that wasn't made by a trusted key",
+        }
+    }
+}
+
+/// The keys and hashes that crate object files are verified against.
+struct TrustList {
+    keys: Vec<PublicKey>,
+    hashes: BTreeSet<CrateHash>,
+}
+
+static POLICY: AtomicU8 = AtomicU8::new(VerificationPolicy::Off as u8);
+
+static TRUST_LIST: Mutex<TrustList> = Mutex::new(TrustList {
+    keys: Vec::new(),
+    hashes: BTreeSet::new(),
+});
+
+/// Sets the policy for crate object files that fail verification.
+pub fn set_policy(policy: VerificationPolicy) {
+    POLICY.store(policy as u8, Ordering::Release);
+}
+
+/// Returns the current policy for crate object files that fail verification.
+pub fn policy() -> VerificationPolicy {
+    match POLICY.load(Ordering::Acquire) {
+        1 => VerificationPolicy::Warn,
+        2 => VerificationPolicy::Enforce,
+        _ => VerificationPolicy::Off,
+    }
+}
+
+/// Adds the given 32-byte Ed25519 public key to the trusted keys.
+pub fn add_trusted_key(key: &[u8]) -> Result<(), &'static str> {
+    let key = PublicKey::from_slice(key).map_err(|_| "invalid Ed25519 public key")?;
+    let mut trust_list = TRUST_LIST.lock();
+    if !trust_list.keys.contains(&key) {
+        trust_list.keys.push(key);
+    }
+    Ok(())
+}
+
+/// Adds the given crate object file hash to the allow-list.
+pub fn allow_hash(hash: CrateHash) {
+    TRUST_LIST.lock().hashes.insert(hash);
+}
+
+/// Returns the number of trusted keys and allowed hashes.
+pub fn trust_list_len() -> (usize, usize) {
+    let trust_list = TRUST_LIST.lock();
+    (trust_list.keys.len(), trust_list.hashes.len())
+}
+
+/// Returns the SHA3-512 hash of the given crate object file contents.
+pub fn hash(bytes: &[u8]) -> CrateHash {
+    let mut hash = [0; 64];
+    hash.copy_from_slice(&Sha3_512::digest(bytes));
+    hash
+}
+
+/// Adds the keys and hashes in the given trust list to the trusted keys and the allow-list.
+///
+/// Each non-empty line that doesn't start with `#` holds one entry, either:
+/// * `ed25519 <key>`, a trusted public key as 64 hexadecimal characters, or
+/// * `sha3-512 <hash>`, an allowed object file hash as 128 hexadecimal characters.
+///
+/// Returns the number of keys and hashes that were added.
+pub fn parse_trust_list(text: &str) -> Result<(usize, usize), &'static str> {
+    let mut keys = Vec::new();
+    let mut hashes = Vec::new();
+    for line in text.lines().map(str::trim).filter(|l| !l.is_empty() && !l.starts_with('#')) {
+        let mut fields = line.split_whitespace();
+        match (fields.next(), fields.next().map(parse_hex), fields.next()) {
+            (Some("ed25519"), Some(Some(key)), None) => keys.push(key),
+            (Some("sha3-512"), Some(Some(hash)), None) => hashes.push(
+                CrateHash::try_from(hash.as_slice()).map_err(|_| "trust list hash must be 64 bytes")?
+            ),
+            _ => return Err("malformed line in trust list"),
+        }
+    }
+    for key in &keys {
+        add_trusted_key(key)?;
+    }
+    let num_hashes = hashes.len();
+    hashes.into_iter().for_each(allow_hash);
+    Ok((keys.len(), num_hashes))
+}
+
+/// Checks whether the crate object file with the given name and contents is trusted.
+pub fn check(object_file_name: &str, bytes: &[u8]) -> Result<(), VerificationError> {
+    if TRUST_LIST.lock().hashes.contains(&hash(bytes)) {
+        return Ok(());
+    }
+    let signature_file = signature_file(object_file_name).ok_or(VerificationError::Unsigned)?;
+    let signature = {
+        let file = signature_file.lock();
+        let contents = file.as_mapping()
+            .and_then(|mp| mp.as_slice::<u8>(0, file.len()))
+            .map_err(|_| VerificationError::MalformedSignature)?;
+        core::str::from_utf8(contents).ok()
+            .and_then(|s| s.split_whitespace().next())
+            .and_then(parse_hex)
+            .and_then(|sig| Signature::from_slice(&sig).ok())
+            .ok_or(VerificationError::MalformedSignature)?
+    };
+    let trust_list = TRUST_LIST.lock();
+    if trust_list.keys.iter().any(|key| key.verify(bytes, &signature).is_ok()) {
+        Ok(())
+    } else {
+        Err(VerificationError::UntrustedSignature)
+    }
+}
+
+/// Verifies the crate object file with the given name and contents according to the current [`policy()`].
+///
+/// Returns an error only if the object file failed verification and the policy is
+/// [`Enforce`](VerificationPolicy::Enforce), in which case it must not be loaded.
+pub(crate) fn verify(object_file_name: &str, bytes: &[u8]) -> Result<(), &'static str> {
+    let policy = policy();
+    if policy == VerificationPolicy::Off {
+        return Ok(());
+    }
+    match check(object_file_name, bytes) {
+        Ok(()) => Ok(()),
+        Err(e) if policy == VerificationPolicy::Warn => {
+            warn!("Loading crate object file {:?} anyway: {}", object_file_name, e.as_str());
+            Ok(())
+        }
+        Err(e) => {
+            error!("Refusing to load crate object file {:?}: {}", object_file_name, e.as_str());
+            Err(e.as_str())
+        }
+    }
+}
+
+/// Trusts the given object file that was extracted from an archive that was already verified.
+pub(crate) fn trust_extracted(bytes: &[u8]) {
+    if policy() != VerificationPolicy::Off {
+        allow_hash(hash(bytes));
+    }
+}
+
+/// Returns the signature file of the crate object file with the given name, if it exists.
+fn signature_file(object_file_name: &str) -> Option<FileRef> {
+    let signatures_dir = root::get_root().lock()
+        .get_dir(EXTRA_FILES_DIRECTORY_NAME)?
+        .lock()
+        .get_dir(SIGNATURES_DIRECTORY_NAME)?;
+    let file_name = format!("{}.{}", object_file_name, SIGNATURE_FILE_EXTENSION);
+    let file = signatures_dir.lock().get_file(&file_name);
+    file
+}
+
+/// Parses the given string of hexadecimal characters into bytes.
+fn parse_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+    (0 .. hex.len()).step_by(2)
+        .map(|i| hex.get(i .. i + 2).and_then(|byte| u8::from_str_radix(byte, 16).ok()))
+        .collect()
+}
+
+
+#[cfg(ktest)]
+mod ktests {
+    use super::*;
+    use ktest_macros::ktest;
+
+    #[ktest]
+    fn trust_list_is_parsed() -> Result<(), &'static str> {
+        let allowed = hash(b"allowed object file");
+        let mut text = alloc::string::String::from("# trusted crates\n\nsha3-512 ");
+        for byte in allowed {
+            text.push_str(&format!("{:02x}", byte));
+        }
+        text.push('\n');
+        if parse_trust_list(&text)? != (0, 1) {
+            return Err("wrong number of entries parsed from trust list");
+        }
+        if check("allowed.o", b"allowed object file").is_err() {
+            return Err("allowed hash wasn't trusted");
+        }
+        if parse_trust_list("sha3-512 abc\n").is_ok() || parse_trust_list("rsa 00\n").is_ok() {
+            return Err("malformed trust list was parsed");
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,467 @@
+//! A bounded lock-free multi-producer multi-consumer (MPMC) channel.
+//!
+//! Messages are buffered in a fixed-capacity ring of slots, each of which carries
+//! a sequence number that tells senders and receivers whether the slot is ready
+//! to be written or read, so neither side ever takes a lock.
+//! Thus, [`Sender::try_send()`] can be used in interrupt context, e.g.,
+//! to hand data over from an interrupt handler to a task.
+//!
+//! Receivers can wait for a message by blocking the current task via [`Receiver::recv()`]
+//! or by awaiting the future returned from [`Receiver::recv_async()`].
+//! A waiting receiver registers a waker in one of a fixed set of waiter slots,
+//! which senders wake without taking any locks either.
+//! Waking a blocked receiver only unblocks its task; it doesn't invoke the scheduler.
+//!
+//! This is not a zero-copy channel;
+//! to avoid copying large messages, use a reference (layer of indirection) like `Box`.
+
+#![no_std]
+
+extern crate alloc;
+
+use alloc::{boxed::Box, sync::Arc, task::Wake, vec::Vec};
+use core::{
+    cell::UnsafeCell,
+    fmt,
+    future::Future,
+    mem::MaybeUninit,
+    pin::Pin,
+    sync::atomic::{fence, AtomicU64, AtomicUsize, Ordering},
+    task::{Context, Poll, Waker},
+};
+use futures::task::AtomicWaker;
+use task::TaskRef;
+
+/// The maximum number of receivers that can wait on a channel at the same time.
+///
+/// Any further waiting receivers yield the CPU and retry rather than sleeping.
+const MAX_WAITERS: usize = 64;
+
+/// Creates a new channel that can buffer at least `minimum_capacity` messages.
+///
+/// The capacity is rounded up to the next power of two, with a minimum of 2.
+///
+/// Returns a tuple of `(Sender, Receiver)`, both of which can be cloned.
+pub fn new_channel<T: Send>(minimum_capacity: usize) -> (Sender<T>, Receiver<T>) {
+    let capacity = core::cmp::max(minimum_capacity, 2).next_power_of_two();
+    let slots = (0..capacity)
+        .map(|i| Slot { sequence: AtomicUsize::new(i), value: UnsafeCell::new(MaybeUninit::uninit()) })
+        .collect::<Vec<_>>()
+        .into_boxed_slice();
+    let channel = Arc::new(Channel {
+        slots,
+        head: AtomicUsize::new(0),
+        tail: AtomicUsize::new(0),
+        waiters: {
+            #[allow(clippy::declare_interior_mutable_const)]
+            const EMPTY: AtomicWaker = AtomicWaker::new();
+            [EMPTY; MAX_WAITERS]
+        },
+        registered_waiters: AtomicU64::new(0),
+        sender_count: AtomicUsize::new(1),
+        receiver_count: AtomicUsize::new(1),
+    });
+    (Sender { channel: channel.clone() }, Receiver { channel })
+}
+
+/// The error returned from [`Sender::try_send()`], which contains the message that wasn't sent.
+#[derive(PartialEq, Eq)]
+pub enum TrySendError<T> {
+    /// The channel's buffer is full.
+    Full(T),
+    /// Every receiver has been dropped.
+    Disconnected(T),
+}
+
+impl<T> TrySendError<T> {
+    /// Returns the message that wasn't sent.
+    pub fn into_inner(self) -> T {
+        match self {
+            TrySendError::Full(msg) | TrySendError::Disconnected(msg) => msg,
+        }
+    }
+}
+
+impl<T> fmt::Debug for TrySendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TrySendError::Full(_) => write!(f, "Full(..)"),
+            TrySendError::Disconnected(_) => write!(f, "Disconnected(..)"),
+        }
+    }
+}
+
+/// The error returned from [`Receiver::try_recv()`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TryRecvError {
+    /// The channel's buffer is empty.
+    Empty,
+    /// The channel's buffer is empty and every sender has been dropped.
+    Disconnected,
+}
+
+/// The error returned from [`Receiver::recv()`] and [`Receiver::recv_async()`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RecvError {
+    /// The channel's buffer is empty and every sender has been dropped.
+    Disconnected,
+    /// There is no current task to block.
+    NoCurrentTask,
+}
+
+/// A slot in the channel's ring buffer.
+struct Slot<T> {
+    /// Equals the position that may be written next if the slot is empty,
+    /// or that position plus one if the slot holds a message to be read.
+    sequence: AtomicUsize,
+    value: UnsafeCell<MaybeUninit<T>>,
+}
+
+/// The inner channel shared by all `Sender`s and `Receiver`s.
+struct Channel<T> {
+    slots: Box<[Slot<T>]>,
+    /// The position of the next message to be received.
+    head: AtomicUsize,
+    /// The position of the next message to be sent.
+    tail: AtomicUsize,
+    /// The wakers of the waiting receivers.
+    waiters: [AtomicWaker; MAX_WAITERS],
+    /// A bitmap of the `waiters` that are in use.
+    registered_waiters: AtomicU64,
+    sender_count: AtomicUsize,
+    receiver_count: AtomicUsize,
+}
+
+// SAFETY: each message is accessed by exactly one sender and then by exactly one receiver,
+// which is synchronized via the slot's sequence number.
+unsafe impl<T: Send> Send for Channel<T> {}
+unsafe impl<T: Send> Sync for Channel<T> {}
+
+impl<T> Channel<T> {
+    fn mask(&self) -> usize {
+        self.slots.len() - 1
+    }
+
+    fn push(&self, msg: T) -> Result<(), T> {
+        let mut pos = self.tail.load(Ordering::Relaxed);
+        loop {
+            let slot = &self.slots[pos & self.mask()];
+            let sequence = slot.sequence.load(Ordering::Acquire);
+            let diff = sequence.wrapping_sub(pos) as isize;
+            if diff == 0 {
+                match self.tail.compare_exchange_weak(pos, pos.wrapping_add(1), Ordering::Relaxed, Ordering::Relaxed) {
+                    Ok(_) => {
+                        // SAFETY: claiming position `pos` gives this sender exclusive access to the empty slot.
+                        unsafe { (*slot.value.get()).write(msg) };
+                        slot.sequence.store(pos.wrapping_add(1), Ordering::Release);
+                        return Ok(());
+                    }
+                    Err(current) => pos = current,
+                }
+            } else if diff < 0 {
+                // The slot still holds a message from the previous lap, so the buffer is full.
+                return Err(msg);
+            } else {
+                pos = self.tail.load(Ordering::Relaxed);
+            }
+        }
+    }
+
+    fn pop(&self) -> Option<T> {
+        let mut pos = self.head.load(Ordering::Relaxed);
+        loop {
+            let slot = &self.slots[pos & self.mask()];
+            let sequence = slot.sequence.load(Ordering::Acquire);
+            let diff = sequence.wrapping_sub(pos.wrapping_add(1)) as isize;
+            if diff == 0 {
+                match self.head.compare_exchange_weak(pos, pos.wrapping_add(1), Ordering::Relaxed, Ordering::Relaxed) {
+                    Ok(_) => {
+                        // SAFETY: claiming position `pos` gives this receiver exclusive access to the full slot.
+                        let msg = unsafe { (*slot.value.get()).assume_init_read() };
+                        slot.sequence.store(pos.wrapping_add(self.slots.len()), Ordering::Release);
+                        return Some(msg);
+                    }
+                    Err(current) => pos = current,
+                }
+            } else if diff < 0 {
+                // The slot hasn't been written in this lap, so the buffer is empty.
+                return None;
+            } else {
+                pos = self.head.load(Ordering::Relaxed);
+            }
+        }
+    }
+
+    fn try_recv(&self) -> Result<T, TryRecvError> {
+        if let Some(msg) = self.pop() {
+            return Ok(msg);
+        }
+        if self.sender_count.load(Ordering::Acquire) == 0 {
+            // A message may have been sent right before the last sender was dropped.
+            return self.pop().ok_or(TryRecvError::Disconnected);
+        }
+        Err(TryRecvError::Empty)
+    }
+
+    /// Claims an unused waiter slot, returning its index.
+    fn claim_waiter(&self) -> Option<usize> {
+        let mut registered = self.registered_waiters.load(Ordering::Relaxed);
+        while registered != u64::MAX {
+            let index = (!registered).trailing_zeros() as usize;
+            let bit = 1 << index;
+            registered = self.registered_waiters.fetch_or(bit, Ordering::AcqRel);
+            if registered & bit == 0 {
+                return Some(index);
+            }
+        }
+        None
+    }
+
+    fn release_waiter(&self, index: usize) {
+        drop(self.waiters[index].take());
+        self.registered_waiters.fetch_and(!(1 << index), Ordering::AcqRel);
+    }
+
+    /// Wakes one waiting receiver, or all of them if `all` is `true`.
+    fn wake_receivers(&self, all: bool) {
+        // Pairs with the fence in `WaiterSlot::register()`, such that either the receiver
+        // observes the new message or this observes the receiver's waker.
+        fence(Ordering::SeqCst);
+        let mut registered = self.registered_waiters.load(Ordering::Acquire);
+        while registered != 0 {
+            let index = registered.trailing_zeros() as usize;
+            registered &= registered - 1;
+            if let Some(waker) = self.waiters[index].take() {
+                waker.wake();
+                if !all {
+                    return;
+                }
+            }
+        }
+    }
+}
+
+impl<T> Drop for Channel<T> {
+    fn drop(&mut self) {
+        while self.pop().is_some() {}
+    }
+}
+
+/// A claimed waiter slot of a channel, which is released when dropped.
+struct WaiterSlot<'a, T> {
+    channel: &'a Channel<T>,
+    index: usize,
+}
+
+impl<'a, T> WaiterSlot<'a, T> {
+    fn claim(channel: &'a Channel<T>) -> Option<WaiterSlot<'a, T>> {
+        channel.claim_waiter().map(|index| WaiterSlot { channel, index })
+    }
+
+    /// Registers the given waker to be woken when a message is sent.
+    fn register(&self, waker: &Waker) {
+        self.channel.waiters[self.index].register(waker);
+        fence(Ordering::SeqCst);
+    }
+
+    /// Unregisters the waker, returning whether it was still registered, i.e., hasn't been woken.
+    fn unregister(&self) -> bool {
+        self.channel.waiters[self.index].take().is_some()
+    }
+}
+
+impl<'a, T> Drop for WaiterSlot<'a, T> {
+    fn drop(&mut self) {
+        self.channel.release_waiter(self.index);
+    }
+}
+
+/// A waker that unblocks a task that is blocked in [`Receiver::recv()`].
+struct TaskWaker(TaskRef);
+
+impl Wake for TaskWaker {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        let _ = self.0.unblock();
+    }
+}
+
+/// The sending side of a channel.
+pub struct Sender<T: Send> {
+    channel: Arc<Channel<T>>,
+}
+
+impl<T: Send> Sender<T> {
+    /// Sends a message without blocking.
+    ///
+    /// This never takes a lock, so it is safe to invoke in interrupt context,
+    /// e.g., to hand data over from an interrupt handler to a task.
+    /// If a receiver is waiting, this wakes it up.
+    pub fn try_send(&self, msg: T) -> Result<(), TrySendError<T>> {
+        if self.channel.receiver_count.load(Ordering::Acquire) == 0 {
+            return Err(TrySendError::Disconnected(msg));
+        }
+        self.channel.push(msg).map_err(TrySendError::Full)?;
+        self.channel.wake_receivers(false);
+        Ok(())
+    }
+
+    /// Returns the number of messages that can be buffered in this channel.
+    pub fn capacity(&self) -> usize {
+        self.channel.slots.len()
+    }
+
+    /// Returns `true` if every receiver has been dropped.
+    pub fn is_disconnected(&self) -> bool {
+        self.channel.receiver_count.load(Ordering::Acquire) == 0
+    }
+}
+
+impl<T: Send> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.channel.sender_count.fetch_add(1, Ordering::AcqRel);
+        Sender { channel: self.channel.clone() }
+    }
+}
+
+impl<T: Send> Drop for Sender<T> {
+    fn drop(&mut self) {
+        if self.channel.sender_count.fetch_sub(1, Ordering::AcqRel) == 1 {
+            // Wake every waiting receiver such that it observes the disconnection.
+            self.channel.wake_receivers(true);
+        }
+    }
+}
+
+/// The receiving side of a channel.
+pub struct Receiver<T: Send> {
+    channel: Arc<Channel<T>>,
+}
+
+impl<T: Send> Receiver<T> {
+    /// Receives a message without blocking.
+    pub fn try_recv(&self) -> Result<T, TryRecvError> {
+        self.channel.try_recv()
+    }
+
+    /// Receives a message, blocking the current task until one is available.
+    ///
+    /// Returns an error if the channel is empty and every sender has been dropped.
+    pub fn recv(&self) -> Result<T, RecvError> {
+        // Fast path: a message is available.
+        match self.try_recv() {
+            Ok(msg) => return Ok(msg),
+            Err(TryRecvError::Disconnected) => return Err(RecvError::Disconnected),
+            Err(TryRecvError::Empty) => {}
+        }
+
+        let current = task::get_my_current_task().ok_or(RecvError::NoCurrentTask)?;
+        // This waker is kept alive here, such that a sender in interrupt context
+        // never drops the last reference to it and thus never deallocates.
+        let waker = Waker::from(Arc::new(TaskWaker(current.clone())));
+        let mut slot = WaiterSlot::claim(&self.channel);
+        loop {
+            let Some(slot) = slot.as_ref() else {
+                // Every waiter slot is taken, so poll instead of sleeping.
+                match self.try_recv() {
+                    Ok(msg) => return Ok(msg),
+                    Err(TryRecvError::Disconnected) => return Err(RecvError::Disconnected),
+                    Err(TryRecvError::Empty) => {
+                        scheduler::schedule();
+                        slot = WaiterSlot::claim(&self.channel);
+                        continue;
+                    }
+                }
+            };
+
+            // Block before registering the waker, such that a wakeup cannot be lost
+            // by arriving before the current task has blocked.
+            let _ = current.block();
+            slot.register(&waker);
+            let result = match self.try_recv() {
+                Ok(msg) => Ok(msg),
+                Err(TryRecvError::Disconnected) => Err(RecvError::Disconnected),
+                Err(TryRecvError::Empty) => {
+                    scheduler::schedule();
+                    continue;
+                }
+            };
+            // If a sender already took the waker, it unblocks the current task itself.
+            if slot.unregister() {
+                let _ = current.unblock();
+            }
+            return result;
+        }
+    }
+
+    /// Returns a future that receives a message, completing once one is available.
+    ///
+    /// The future completes with an error if the channel is empty and every sender has been dropped.
+    pub fn recv_async(&self) -> RecvFuture<'_, T> {
+        RecvFuture { receiver: self, slot: None }
+    }
+
+    /// Returns the number of messages that can be buffered in this channel.
+    pub fn capacity(&self) -> usize {
+        self.channel.slots.len()
+    }
+
+    /// Returns `true` if every sender has been dropped.
+    ///
+    /// Messages that were already sent can still be received.
+    pub fn is_disconnected(&self) -> bool {
+        self.channel.sender_count.load(Ordering::Acquire) == 0
+    }
+}
+
+impl<T: Send> Clone for Receiver<T> {
+    fn clone(&self) -> Self {
+        self.channel.receiver_count.fetch_add(1, Ordering::AcqRel);
+        Receiver { channel: self.channel.clone() }
+    }
+}
+
+impl<T: Send> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.channel.receiver_count.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// The future returned from [`Receiver::recv_async()`].
+///
+/// The waker passed to this future may be woken in interrupt context,
+/// so the executor should keep its own reference to that waker.
+pub struct RecvFuture<'a, T: Send> {
+    receiver: &'a Receiver<T>,
+    slot: Option<WaiterSlot<'a, T>>,
+}
+
+impl<'a, T: Send> Future for RecvFuture<'a, T> {
+    type Output = Result<T, RecvError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = &mut *self;
+        match this.receiver.try_recv() {
+            Ok(msg) => return Poll::Ready(Ok(msg)),
+            Err(TryRecvError::Disconnected) => return Poll::Ready(Err(RecvError::Disconnected)),
+            Err(TryRecvError::Empty) => {}
+        }
+        if this.slot.is_none() {
+            this.slot = WaiterSlot::claim(&this.receiver.channel);
+        }
+        let Some(slot) = this.slot.as_ref() else {
+            // Every waiter slot is taken, so ask to be polled again.
+            cx.waker().wake_by_ref();
+            return Poll::Pending;
+        };
+        slot.register(cx.waker());
+        match this.receiver.try_recv() {
+            Ok(msg) => Poll::Ready(Ok(msg)),
+            Err(TryRecvError::Disconnected) => Poll::Ready(Err(RecvError::Disconnected)),
+            Err(TryRecvError::Empty) => Poll::Pending,
+        }
+    }
+}
@@ -0,0 +1,165 @@
+//! The MSI and MSI-X capabilities in a PCI device's configuration space.
+//!
+//! See section 6.8 of the PCI Local Bus specification 3.0.
+
+use pci::{Msix, PciDevice, PciLocation, MSI_CAPABILITY};
+use crate::{message_address, message_data};
+
+/// The offset of the Message Control register within both capabilities.
+const MESSAGE_CONTROL: u8 = 2;
+
+/// MSI Message Control: enables MSI.
+const MSI_ENABLE: u16 = 1 << 0;
+/// MSI Message Control: the number of enabled messages, as a power of two.
+const MSI_MULTIPLE_MESSAGE_ENABLE: u16 = 0b111 << 4;
+/// MSI Message Control: the device supports 64-bit message addresses.
+const MSI_64_BIT: u16 = 1 << 7;
+/// MSI Message Control: the device supports masking its vectors.
+const MSI_PER_VECTOR_MASKING: u16 = 1 << 8;
+
+/// MSI-X Message Control: masks all of the function's vectors.
+const MSIX_FUNCTION_MASK: u16 = 1 << 14;
+/// MSI-X Message Control: enables MSI-X.
+const MSIX_ENABLE: u16 = 1 << 15;
+
+/// Reads the Message Control register of the capability at `cap` in the given device's config space.
+fn read_control(location: &PciLocation, cap: u8) -> u16 {
+    location.pci_read_16(cap + MESSAGE_CONTROL)
+}
+
+/// Writes the Message Control register of the capability at `cap`,
+/// preserving the capability header that shares its 32-bit word.
+fn write_control(location: &PciLocation, cap: u8, control: u16) {
+    let header = location.pci_read_32(cap) & 0xFFFF;
+    location.pci_write(cap, header | ((control as u32) << 16));
+}
+
+/// The MSI capability of a PCI device, which supports a single interrupt message.
+///
+/// Devices may request multiple messages with consecutive interrupt numbers,
+/// but those cannot be routed to different CPUs, so only one is ever enabled.
+#[derive(Clone, Copy, Debug)]
+pub struct MsiCapability {
+    location: PciLocation,
+    cap: u8,
+}
+
+impl MsiCapability {
+    /// Finds the MSI capability of the given device.
+    pub fn find(dev: &PciDevice) -> Option<MsiCapability> {
+        dev.find_pci_capability(MSI_CAPABILITY)
+            .map(|cap| MsiCapability { location: dev.location, cap })
+    }
+
+    /// Returns the location of the device that this capability belongs to.
+    pub fn location(&self) -> PciLocation {
+        self.location
+    }
+
+    fn control(&self) -> u16 {
+        read_control(&self.location, self.cap)
+    }
+
+    fn is_64_bit(&self) -> bool {
+        self.control() & MSI_64_BIT != 0
+    }
+
+    /// The offset of the Message Data register, which follows the upper address register if there is one.
+    fn data_offset(&self) -> u8 {
+        if self.is_64_bit() { self.cap + 12 } else { self.cap + 8 }
+    }
+
+    /// The offset of the Mask Bits register, if the device supports masking.
+    fn mask_offset(&self) -> Option<u8> {
+        (self.control() & MSI_PER_VECTOR_MASKING != 0)
+            .then(|| self.data_offset() + 4)
+    }
+
+    /// Programs the message to raise `interrupt_num` on the given CPU,
+    /// and limits the device to a single message.
+    ///
+    /// This doesn't enable MSI; see [`Self::enable()`].
+    pub fn configure(&self, interrupt_num: u8, cpu: u8) {
+        self.set_destination(cpu);
+        if self.is_64_bit() {
+            self.location.pci_write(self.cap + 8, 0);
+        }
+        self.location.pci_write(self.data_offset(), message_data(interrupt_num));
+        write_control(&self.location, self.cap, self.control() & !MSI_MULTIPLE_MESSAGE_ENABLE);
+    }
+
+    /// Redirects the message to the given CPU.
+    ///
+    /// If the device supports masking, its vector is masked while the address is being changed.
+    pub fn set_destination(&self, cpu: u8) {
+        let mask_offset = self.mask_offset();
+        let mask = mask_offset.map(|offset| self.location.pci_read_32(offset));
+        if let (Some(offset), Some(mask)) = (mask_offset, mask) {
+            self.location.pci_write(offset, mask | 1);
+        }
+        self.location.pci_write(self.cap + 4, message_address(cpu));
+        if let (Some(offset), Some(mask)) = (mask_offset, mask) {
+            self.location.pci_write(offset, mask);
+        }
+    }
+
+    /// Masks or unmasks the message, if the device supports masking.
+    pub fn set_masked(&self, masked: bool) {
+        if let Some(offset) = self.mask_offset() {
+            let mask = self.location.pci_read_32(offset);
+            self.location.pci_write(offset, if masked { mask | 1 } else { mask & !1 });
+        }
+    }
+
+    /// Enables MSI, which also disables the device's legacy interrupts.
+    pub fn enable(&self) {
+        write_control(&self.location, self.cap, self.control() | MSI_ENABLE);
+    }
+
+    /// Disables MSI.
+    pub fn disable(&self) {
+        write_control(&self.location, self.cap, self.control() & !MSI_ENABLE);
+    }
+}
+
+/// The MSI-X capability of a PCI device, whose vectors are programmed via its [`MsixTable`](crate::MsixTable).
+#[derive(Clone, Copy, Debug)]
+pub struct MsixCapability {
+    msix: Msix,
+}
+
+impl MsixCapability {
+    /// Finds the MSI-X capability of the given device.
+    pub fn find(dev: &PciDevice) -> Option<MsixCapability> {
+        dev.msix().map(|msix| MsixCapability { msix })
+    }
+
+    /// Returns the location of the device that this capability belongs to.
+    pub fn location(&self) -> PciLocation {
+        self.msix.location()
+    }
+
+    /// Returns the number of vectors in the device's MSI-X table.
+    pub fn num_vectors(&self) -> u16 {
+        self.msix.table_size()
+    }
+
+    /// Returns the index of the BAR that contains the MSI-X table,
+    /// and the offset of the table within that BAR.
+    pub fn table_location(&self) -> (usize, usize) {
+        let table = self.msix.table();
+        (table.bar, table.offset)
+    }
+
+    /// Enables MSI-X, which also disables the device's legacy interrupts and MSI.
+    pub fn enable(&self) {
+        let control = read_control(&self.location(), self.msix.offset());
+        write_control(&self.location(), self.msix.offset(), (control | MSIX_ENABLE) & !MSIX_FUNCTION_MASK);
+    }
+
+    /// Disables MSI-X.
+    pub fn disable(&self) {
+        let control = read_control(&self.location(), self.msix.offset());
+        write_control(&self.location(), self.msix.offset(), control & !MSIX_ENABLE);
+    }
+}
@@ -0,0 +1,250 @@
+//! Allocating, programming, and routing the MSI and MSI-X interrupt vectors of PCI devices.
+//!
+//! A device driver first finds its device's [`MsiCapability`] or maps its [`MsixTable`],
+//! and then [`allocate()`]s an interrupt number for each of its vectors.
+//! Each allocated interrupt is routed to a single CPU, which can be changed at runtime
+//! via [`set_affinity()`], e.g., to spread the queues of a multiqueue device across CPUs.
+//! [`rebalance()`] spreads all routed interrupts evenly across the online CPUs,
+//! and [`migrate_from()`] moves every interrupt away from a CPU that is going offline.
+//!
+//! MSI-X vectors are left masked when allocated, so the driver must unmask them
+//! once it's ready to handle their interrupts, e.g., via [`unmask()`].
+//!
+//! See section 10.11 of Volume 3 of the Intel SDM for the format of interrupt messages.
+
+#![no_std]
+
+extern crate alloc;
+
+mod capability;
+mod table;
+
+pub use capability::{MsiCapability, MsixCapability};
+pub use table::{MsixTable, MsixVectorEntry};
+
+use alloc::{collections::BTreeMap, sync::Arc, vec::Vec};
+use irq_safety::MutexIrqSafe;
+use log::{debug, warn};
+use pci::PciLocation;
+use x86_64::structures::idt::HandlerFunc;
+
+/// The region that is reserved for interrupt messages.
+const INTERRUPT_REGION: u32 = 0xFEE << 20;
+/// The location in the message address where the destination CPU's APIC ID is written.
+const DEST_ID_SHIFT: u32 = 12;
+
+/// Returns the message address that delivers interrupts to the given CPU.
+fn message_address(cpu: u8) -> u32 {
+    INTERRUPT_REGION | ((cpu as u32) << DEST_ID_SHIFT)
+}
+
+/// Returns the message data that raises the given interrupt with fixed delivery and edge triggering.
+fn message_data(interrupt_num: u8) -> u32 {
+    interrupt_num as u32
+}
+
+/// An interrupt vector of a PCI device.
+#[derive(Clone)]
+pub enum MsiVector {
+    /// The single vector of a device that uses MSI.
+    Msi(MsiCapability),
+    /// The vector with the given index in a device's MSI-X table.
+    Msix(Arc<MutexIrqSafe<MsixTable>>, u16),
+}
+
+impl MsiVector {
+    fn location(&self) -> PciLocation {
+        match self {
+            MsiVector::Msi(capability) => capability.location(),
+            MsiVector::Msix(table, _) => table.lock().location(),
+        }
+    }
+
+    fn configure(&self, interrupt_num: u8, cpu: u8) -> Result<(), &'static str> {
+        match self {
+            MsiVector::Msi(capability) => capability.configure(interrupt_num, cpu),
+            MsiVector::Msix(table, vector) => table.lock().entry(*vector)
+                .ok_or("msi: MSI-X vector is beyond the end of the device's table")?
+                .set(interrupt_num, cpu),
+        }
+        Ok(())
+    }
+
+    fn set_destination(&self, cpu: u8) {
+        match self {
+            MsiVector::Msi(capability) => capability.set_destination(cpu),
+            MsiVector::Msix(table, vector) => if let Some(entry) = table.lock().entry(*vector) {
+                entry.set_destination(cpu);
+            }
+        }
+    }
+
+    fn set_masked(&self, masked: bool) {
+        match self {
+            MsiVector::Msi(capability) => capability.set_masked(masked),
+            MsiVector::Msix(table, vector) => if let Some(entry) = table.lock().entry(*vector) {
+                if masked { entry.mask() } else { entry.unmask() }
+            }
+        }
+    }
+}
+
+/// An allocated interrupt and the CPU that it's routed to.
+struct Route {
+    vector: MsiVector,
+    handler: HandlerFunc,
+    cpu: u8,
+}
+
+/// All allocated interrupts, indexed by interrupt number.
+///
+/// When both are needed, this lock must be acquired before the lock of an [`MsixTable`].
+static ROUTES: MutexIrqSafe<BTreeMap<u8, Route>> = MutexIrqSafe::new(BTreeMap::new());
+
+/// Information about an allocated interrupt, as returned by [`routes()`].
+#[derive(Clone, Copy, Debug)]
+pub struct RouteInfo {
+    pub interrupt_num: u8,
+    /// The CPU that the interrupt is delivered to.
+    pub cpu: u8,
+    /// The device that raises the interrupt.
+    pub device: PciLocation,
+    /// The index of the device's MSI-X vector, or `None` if the device uses MSI.
+    pub msix_vector: Option<u16>,
+}
+
+/// Returns the CPUs that interrupts can be routed to, in ascending order.
+fn online_cpus() -> Vec<u8> {
+    let mut cpus: Vec<u8> = apic::get_lapics().iter()
+        .map(|(&cpu, _)| cpu)
+        .filter(|&cpu| !cpu::is_offline(cpu))
+        .collect();
+    cpus.sort_unstable();
+    cpus
+}
+
+/// Returns the online CPU that the fewest interrupts are routed to.
+fn least_loaded_cpu(routes: &BTreeMap<u8, Route>) -> Result<u8, &'static str> {
+    online_cpus().into_iter()
+        .min_by_key(|&cpu| routes.values().filter(|route| route.cpu == cpu).count())
+        .ok_or("msi: there are no online CPUs")
+}
+
+fn check_online(cpu: u8) -> Result<(), &'static str> {
+    if apic::get_lapics().get(&cpu).is_none() {
+        return Err("msi: the given CPU doesn't exist");
+    }
+    if cpu::is_offline(cpu) {
+        return Err("msi: the given CPU is offline");
+    }
+    Ok(())
+}
+
+/// Allocates an interrupt number for the given vector, registers `handler` for it,
+/// and routes it to the given CPU, or to the online CPU with the fewest interrupts if `cpu` is `None`.
+///
+/// MSI-X vectors are left masked; MSI vectors aren't enabled.
+///
+/// Returns the allocated interrupt number.
+pub fn allocate(vector: MsiVector, handler: HandlerFunc, cpu: Option<u8>) -> Result<u8, &'static str> {
+    let mut routes = ROUTES.lock();
+    let cpu = match cpu {
+        Some(cpu) => check_online(cpu).map(|_| cpu)?,
+        None => least_loaded_cpu(&routes)?,
+    };
+    let interrupt_num = interrupts::register_msi_interrupt(handler)?;
+    if let Err(e) = vector.configure(interrupt_num, cpu) {
+        let _ = interrupts::deregister_interrupt(interrupt_num, handler);
+        return Err(e);
+    }
+    debug!("msi: routed interrupt {} of device {} to CPU {}", interrupt_num, vector.location(), cpu);
+    routes.insert(interrupt_num, Route { vector, handler, cpu });
+    Ok(interrupt_num)
+}
+
+/// Masks the vector of the given interrupt and deregisters its handler.
+pub fn free(interrupt_num: u8) -> Result<(), &'static str> {
+    let mut routes = ROUTES.lock();
+    let route = routes.remove(&interrupt_num).ok_or("msi: the given interrupt wasn't allocated")?;
+    route.vector.set_masked(true);
+    interrupts::deregister_interrupt(interrupt_num, route.handler)
+}
+
+/// Unmasks the vector of the given interrupt, allowing the device to raise it.
+pub fn unmask(interrupt_num: u8) -> Result<(), &'static str> {
+    let routes = ROUTES.lock();
+    let route = routes.get(&interrupt_num).ok_or("msi: the given interrupt wasn't allocated")?;
+    route.vector.set_masked(false);
+    Ok(())
+}
+
+/// Routes the given interrupt to the given CPU, which must be online.
+pub fn set_affinity(interrupt_num: u8, cpu: u8) -> Result<(), &'static str> {
+    check_online(cpu)?;
+    let mut routes = ROUTES.lock();
+    let route = routes.get_mut(&interrupt_num).ok_or("msi: the given interrupt wasn't allocated")?;
+    if route.cpu != cpu {
+        route.vector.set_destination(cpu);
+        route.cpu = cpu;
+    }
+    Ok(())
+}
+
+/// Returns the CPU that the given interrupt is routed to, if it was allocated.
+pub fn affinity(interrupt_num: u8) -> Option<u8> {
+    ROUTES.lock().get(&interrupt_num).map(|route| route.cpu)
+}
+
+/// Returns information about every allocated interrupt, in order of interrupt number.
+pub fn routes() -> Vec<RouteInfo> {
+    ROUTES.lock().iter()
+        .map(|(&interrupt_num, route)| RouteInfo {
+            interrupt_num,
+            cpu: route.cpu,
+            device: route.vector.location(),
+            msix_vector: match route.vector {
+                MsiVector::Msi(_) => None,
+                MsiVector::Msix(_, vector) => Some(vector),
+            },
+        })
+        .collect()
+}
+
+/// Spreads all allocated interrupts evenly across the online CPUs, in order of interrupt number.
+pub fn rebalance() -> Result<(), &'static str> {
+    let cpus = online_cpus();
+    if cpus.is_empty() {
+        return Err("msi: there are no online CPUs");
+    }
+    let mut routes = ROUTES.lock();
+    for (route, &cpu) in routes.values_mut().zip(cpus.iter().cycle()) {
+        if route.cpu != cpu {
+            route.vector.set_destination(cpu);
+            route.cpu = cpu;
+        }
+    }
+    Ok(())
+}
+
+/// Moves every interrupt that is routed to the given CPU to the least-loaded online CPU.
+///
+/// This is invoked when the given CPU is going offline, after it has been marked as such.
+pub fn migrate_from(cpu: u8) -> Result<(), &'static str> {
+    let mut routes = ROUTES.lock();
+    let interrupt_nums: Vec<u8> = routes.iter()
+        .filter(|(_, route)| route.cpu == cpu)
+        .map(|(&interrupt_num, _)| interrupt_num)
+        .collect();
+    for interrupt_num in interrupt_nums {
+        let new_cpu = least_loaded_cpu(&routes)?;
+        if new_cpu == cpu {
+            warn!("msi: couldn't move interrupt {} away from CPU {}", interrupt_num, cpu);
+            return Err("msi: the given CPU is the only online CPU");
+        }
+        let route = routes.get_mut(&interrupt_num).expect("BUG: msi route disappeared while locked");
+        route.vector.set_destination(new_cpu);
+        route.cpu = new_cpu;
+        debug!("msi: moved interrupt {} from CPU {} to CPU {}", interrupt_num, cpu, new_cpu);
+    }
+    Ok(())
+}
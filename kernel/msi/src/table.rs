@@ -0,0 +1,148 @@
+//! The MSI-X table, an array of vector entries located in one of the device's BARs.
+
+use memory::{
+    allocate_frames_by_bytes_at, allocate_pages_by_bytes, get_kernel_mmi_ref, MappedPages,
+    PteFlags,
+};
+use pci::{PciDevice, PciLocation};
+use volatile::Volatile;
+use zerocopy::FromBytes;
+use crate::{message_address, message_data, MsixCapability};
+
+/// The bit in the vector control register that masks the vector.
+const VECTOR_MASKED: u32 = 1;
+
+/// The mapping flags used to map an MSI-X table.
+const MSIX_MAPPING_FLAGS: PteFlags = PteFlags::from_bits_truncate(
+    PteFlags::new().bits()
+    | PteFlags::VALID.bits()
+    | PteFlags::WRITABLE.bits()
+    | PteFlags::DEVICE_MEMORY.bits()
+);
+
+/// The size of a page, which the start of a mapped MSI-X table is rounded down to.
+const PAGE_SIZE: usize = 4096;
+
+/// A single entry in the MSI-X vector table.
+#[derive(FromBytes)]
+#[repr(C)]
+pub struct MsixVectorEntry {
+    /// The lower portion of the address for the memory write transaction,
+    /// which contains the ID of the CPU that the interrupt is redirected to.
+    msg_lower_addr: Volatile<u32>,
+    msg_upper_addr: Volatile<u32>,
+    /// The interrupt number.
+    msg_data:       Volatile<u32>,
+    /// Contains the bit that masks this vector.
+    vector_control: Volatile<u32>,
+}
+
+const _: () = assert!(core::mem::size_of::<MsixVectorEntry>() == 16);
+
+impl MsixVectorEntry {
+    /// Sets this vector to raise `interrupt_num` on the given CPU, leaving it masked.
+    pub fn set(&mut self, interrupt_num: u8, cpu: u8) {
+        self.mask();
+        self.msg_lower_addr.write(message_address(cpu));
+        self.msg_upper_addr.write(0);
+        self.msg_data.write(message_data(interrupt_num));
+    }
+
+    /// Redirects this vector to the given CPU.
+    ///
+    /// The vector is masked while its address is being changed, and then restored to its previous state.
+    pub fn set_destination(&mut self, cpu: u8) {
+        let was_masked = self.is_masked();
+        self.mask();
+        self.msg_lower_addr.write(message_address(cpu));
+        if !was_masked {
+            self.unmask();
+        }
+    }
+
+    pub fn mask(&mut self) {
+        let control = self.vector_control.read();
+        self.vector_control.write(control | VECTOR_MASKED);
+    }
+
+    pub fn unmask(&mut self) {
+        let control = self.vector_control.read();
+        self.vector_control.write(control & !VECTOR_MASKED);
+    }
+
+    pub fn is_masked(&self) -> bool {
+        self.vector_control.read() & VECTOR_MASKED != 0
+    }
+}
+
+/// The MSI-X table of a PCI device.
+pub struct MsixTable {
+    capability: MsixCapability,
+    entries: *mut MsixVectorEntry,
+    num_vectors: u16,
+    /// The mapping of the table, if it's owned by this table rather than by the device driver.
+    _mapping: Option<MappedPages>,
+}
+
+// SAFETY: the table is only accessed through `&mut self`,
+//         and the memory it points to is never unmapped while the table exists.
+unsafe impl Send for MsixTable {}
+
+impl MsixTable {
+    /// Maps the MSI-X table of the given device.
+    ///
+    /// The pages that hold the table must not already be mapped,
+    /// e.g., by the driver mapping the whole BAR that contains it;
+    /// in that case, use [`MsixTable::from_raw()`] instead.
+    pub fn map(dev: &PciDevice) -> Result<MsixTable, &'static str> {
+        let capability = MsixCapability::find(dev).ok_or("msi: device does not have MSI-X capability")?;
+        let num_vectors = capability.num_vectors();
+        let (bar, offset) = capability.table_location();
+        // The table need not be page-aligned, so map it starting from the beginning of its page.
+        let table_offset = offset % PAGE_SIZE;
+        let mem_base = dev.determine_mem_base(bar)? + (offset - table_offset);
+        let mem_size = table_offset + num_vectors as usize * core::mem::size_of::<MsixVectorEntry>();
+
+        let pages = allocate_pages_by_bytes(mem_size)
+            .ok_or("msi: couldn't allocate virtual pages for MSI-X table")?;
+        let frames = allocate_frames_by_bytes_at(mem_base, mem_size)
+            .map_err(|_e| "msi: couldn't allocate physical frames for MSI-X table")?;
+        let kernel_mmi_ref = get_kernel_mmi_ref().ok_or("msi: KERNEL_MMI was not yet initialized!")?;
+        let mut mapping = kernel_mmi_ref.lock().page_table.map_allocated_pages_to(pages, frames, MSIX_MAPPING_FLAGS)?;
+
+        let entries = mapping.as_type_mut::<MsixVectorEntry>(table_offset)? as *mut MsixVectorEntry;
+        Ok(MsixTable { capability, entries, num_vectors, _mapping: Some(mapping) })
+    }
+
+    /// Creates a table from the given entries, which the device driver has already mapped.
+    ///
+    /// # Safety
+    /// `entries` must point to the first of `capability.num_vectors()` mapped entries of the device's MSI-X table,
+    /// which must remain mapped for as long as this table exists.
+    pub unsafe fn from_raw(capability: MsixCapability, entries: *mut MsixVectorEntry) -> MsixTable {
+        MsixTable { capability, entries, num_vectors: capability.num_vectors(), _mapping: None }
+    }
+
+    /// Returns the location of the device that this table belongs to.
+    pub fn location(&self) -> PciLocation {
+        self.capability.location()
+    }
+
+    pub fn num_vectors(&self) -> u16 {
+        self.num_vectors
+    }
+
+    /// Returns the entry of the given vector, or `None` if it's beyond the end of the table.
+    pub fn entry(&mut self, vector: u16) -> Option<&mut MsixVectorEntry> {
+        if vector >= self.num_vectors {
+            return None;
+        }
+        // SAFETY: the entry is within the mapped table, and `&mut self` ensures it isn't aliased.
+        Some(unsafe { &mut *self.entries.add(vector as usize) })
+    }
+
+    /// Enables MSI-X for the device, which also disables its legacy interrupts and MSI.
+    pub fn enable(&self) {
+        self.capability.enable();
+    }
+}
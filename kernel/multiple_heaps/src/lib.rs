@@ -18,6 +18,12 @@
 //! When a per-core heap runs out of memory, pages are first moved between the slab allocators of the per-core heap, then requested from other per-core heaps.
 //! If no empty pages are available within any of the per-core heaps, then more virtual pages are allocated from the range of virtual addresses dedicated to the heap
 //! [KERNEL_HEAP_START](../kernel_config/memory/constant.KERNEL_HEAP_START.html) and dynamically mapped to physical memory frames.
+//! 
+//! To avoid per-core heaps running out of memory and contending on each other's locks in the first place,
+//! [`rebalance_heaps()`] moves empty pages from per-core heaps with a surplus of empty pages
+//! to per-core heaps that are running low on empty pages.
+//! Whenever an allocation leaves a per-core heap low on empty pages, a rebalancing is requested
+//! by invoking the notifier given to [`set_rebalance_notifier()`], which should wake up a task that rebalances the heaps.
 
 #![feature(allocator_api)]
 #![no_std]
@@ -30,6 +36,7 @@ extern crate kernel_config;
 extern crate apic;
 extern crate heap;
 extern crate hashbrown;
+extern crate spin;
 #[macro_use] extern crate cfg_if;
 
 #[cfg(all(not(unsafe_heap), not(safe_heap)))]
@@ -49,8 +56,10 @@ use memory::{MappedPages, VirtualAddress, get_kernel_mmi_ref, create_mapping};
 use kernel_config::memory::{PAGE_SIZE, KERNEL_HEAP_START, KERNEL_HEAP_INITIAL_SIZE};
 use core::ops::Deref;
 use core::ptr;
+use core::sync::atomic::{AtomicBool, Ordering};
 use heap::HEAP_FLAGS;
 use irq_safety::MutexIrqSafe;
+use spin::Once;
 use page_allocator::{DeferredAllocAction, allocate_pages_by_bytes_deferred};
 
 #[cfg(all(not(unsafe_heap), not(safe_heap)))]
@@ -92,6 +101,21 @@ pub const PER_CORE_HEAP_INITIAL_SIZE_PAGES: usize = ZoneAllocator::MAX_BASE_SIZE
 /// `(3 * HEAP_GROWTH_AMOUNT * sizeof(Chunk)` bytes must fit within one 8KiB heap page set.
 const HEAP_GROWTH_AMOUNT: usize = 2;
 
+/// When rebalancing, a heap with fewer than this number of empty pages receives empty pages from other heaps.
+const REBALANCE_LOW_WATERMARK: usize = EMPTY_PAGES_THRESHOLD;
+
+/// When rebalancing, a heap with more than this number of empty pages gives its surplus empty pages to other heaps.
+const REBALANCE_HIGH_WATERMARK: usize = EMPTY_PAGES_THRESHOLD * 4;
+
+/// The multiple heaps that were set as the default allocator, which are used for rebalancing.
+static MULTIPLE_HEAPS: Once<&'static MultipleHeaps> = Once::new();
+
+/// Whether a rebalancing has been requested since the last call to [`take_rebalance_request()`].
+static REBALANCE_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// The function that is invoked to request a rebalancing; see [`set_rebalance_notifier()`].
+static REBALANCE_NOTIFIER: Once<fn()> = Once::new();
+
 /// Creates and initializes the multiple heaps using the apic id as the key, which is mapped to a heap.
 /// If we want to change the value the heap id is based on, we would substitute 
 /// the lapic iterator with an iterator containing the desired keys.
@@ -110,13 +134,47 @@ fn initialize_multiple_heaps() -> Result<MultipleHeaps, &'static str> {
 /// then sets the multiple heaps as the default allocator.
 /// Only call this function when the multiple heaps are ready to be used.
 pub fn switch_to_multiple_heaps() -> Result<(), &'static str> {
-    let multiple_heaps = Box::new(initialize_multiple_heaps()?);
+    // The default allocator is never dropped, so we keep a static reference to it for rebalancing.
+    let multiple_heaps: &'static MultipleHeaps = Box::leak(Box::new(initialize_multiple_heaps()?));
+    MULTIPLE_HEAPS.call_once(|| multiple_heaps);
     //set the multiple heaps as the default allocator
-    heap::set_allocator(multiple_heaps);
+    heap::set_allocator(Box::new(multiple_heaps));
 
     Ok(())
 }
 
+/// Rebalances the empty pages across the per-core heaps; see [`MultipleHeaps::rebalance()`].
+///
+/// This should be invoked whenever [`take_rebalance_request()`] returns `true`, e.g., by a low-priority system task.
+/// Returns the number of empty pages that were moved between heaps,
+/// which is `0` if the multiple heaps have not yet been set as the default allocator.
+pub fn rebalance_heaps() -> usize {
+    MULTIPLE_HEAPS.get().map_or(0, |multiple_heaps| multiple_heaps.rebalance())
+}
+
+/// Sets the function that is invoked when a per-core heap runs low on empty pages,
+/// which should wake up the task that rebalances the heaps.
+///
+/// The `notifier` is invoked from within the allocator, so it must not allocate memory or block.
+/// It is only invoked again once the pending request has been taken via [`take_rebalance_request()`].
+pub fn set_rebalance_notifier(notifier: fn()) {
+    REBALANCE_NOTIFIER.call_once(|| notifier);
+}
+
+/// Returns whether a rebalancing has been requested since this was last called, and clears that request.
+pub fn take_rebalance_request() -> bool {
+    REBALANCE_REQUESTED.swap(false, Ordering::SeqCst)
+}
+
+/// Requests a rebalancing, invoking the rebalance notifier unless a request is already pending.
+fn request_rebalance() {
+    if !REBALANCE_REQUESTED.swap(true, Ordering::SeqCst) {
+        if let Some(notifier) = REBALANCE_NOTIFIER.get() {
+            notifier();
+        }
+    }
+}
+
 
 
 /// Allocates pages from the given starting address and maps them to frames.
@@ -146,9 +204,6 @@ fn create_heap_mapping(
 cfg_if! {
 if #[cfg(unsafe_heap)] {
     extern crate alloc;
-    extern crate spin;
-
-    use spin::Once;
 
     /// Initializes the heap given by `key`.
     /// There are 11 size classes in each heap ranging from [8,16,32,64 ..`ZoneAllocator::MAX_ALLOC_SIZE`].
@@ -309,6 +364,9 @@ pub struct MultipleHeaps{
 // In the unsafe version, the new heap mapping is merged into the heap MappedPages object in the kernel mmi
 // and then a reference to the starting address is passed to the heap that needs to be grown.
 // In the safe version, an Err is returned since the heap is statically sized.
+//
+// Likewise, rebalance() moves MappedPages8k objects between heaps in the default and safe versions,
+// but only references to pages within the heap MappedPages object in the unsafe version.
 cfg_if! {
 if #[cfg(unsafe_heap)] {
     impl MultipleHeaps {
@@ -373,7 +431,61 @@ if #[cfg(unsafe_heap)] {
             }
             Ok(())
         }
+
+        /// Moves empty pages from per-core heaps that have more than `REBALANCE_HIGH_WATERMARK` empty pages
+        /// to per-core heaps that have fewer than `REBALANCE_LOW_WATERMARK` empty pages.
+        ///
+        /// This allows a per-core heap to keep serving allocations from its own pages,
+        /// rather than having to take pages from other heaps (and thus contend on their locks)
+        /// or grow the heap once it has run out of memory.
+        ///
+        /// Heaps that are currently locked are skipped, so this never blocks on a heap in use.
+        /// At most one heap lock is held at a time.
+        /// Returns the number of empty pages that were moved.
+        pub fn rebalance(&self) -> usize {
+            // The receiving heap can later move these empty pages from the smallest size class
+            // to whichever size class needs them (see `ZoneAllocator::exchange_pages_within_heap()`).
+            let refill_layout = Layout::from_size_align(ZoneAllocator::BASE_ALLOC_SIZES[0], ZoneAllocator::BASE_ALLOC_SIZES[0])
+                .expect("BUG: multiple_heaps: invalid layout for the smallest size class");
+
+            let mut moved = 0;
+            for receiver in self.heaps.values() {
+                let Some(mut needed) = receiver.try_lock()
+                    .map(|heap| REBALANCE_LOW_WATERMARK.saturating_sub(heap.empty_pages()))
+                    else { continue };
+
+                for donor in self.heaps.values() {
+                    if needed == 0 { break; }
+                    if ptr::eq(donor, receiver) { continue; }
+
+                    while needed > 0 {
+                        let Some(page) = donor.try_lock().and_then(|mut heap| heap.retrieve_empty_page(REBALANCE_HIGH_WATERMARK))
+                            else { break };
+                        if let Err(e) = receiver.lock().refill(refill_layout, page) {
+                            error!("multiple_heaps: failed to move an empty page while rebalancing: {}", e);
+                            return moved;
+                        }
+                        needed -= 1;
+                        moved += 1;
+                    }
+                }
+            }
+            moved
+        }
     }
+
+    // The multiple heaps are set as the default allocator by reference,
+    // such that they can also be accessed directly for rebalancing.
+    unsafe impl GlobalAlloc for &'static MultipleHeaps {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            (**self).alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            (**self).dealloc(ptr, layout)
+        }
+    }
+
 } else if #[cfg(safe_heap)] {
     impl MultipleHeaps {
         pub fn empty() -> MultipleHeaps {
@@ -420,6 +532,59 @@ if #[cfg(unsafe_heap)] {
             }
             Ok(())
         }  
+
+        /// Moves empty pages from per-core heaps that have more than `REBALANCE_HIGH_WATERMARK` empty pages
+        /// to per-core heaps that have fewer than `REBALANCE_LOW_WATERMARK` empty pages.
+        ///
+        /// This allows a per-core heap to keep serving allocations from its own pages,
+        /// rather than having to take pages from other heaps (and thus contend on their locks)
+        /// or grow the heap once it has run out of memory.
+        ///
+        /// Heaps that are currently locked are skipped, so this never blocks on a heap in use.
+        /// At most one heap lock is held at a time.
+        /// Returns the number of empty pages that were moved.
+        pub fn rebalance(&self) -> usize {
+            // The receiving heap can later move these empty pages from the smallest size class
+            // to whichever size class needs them (see `ZoneAllocator::exchange_pages_within_heap()`).
+            let refill_layout = Layout::from_size_align(ZoneAllocator::BASE_ALLOC_SIZES[0], ZoneAllocator::BASE_ALLOC_SIZES[0])
+                .expect("BUG: multiple_heaps: invalid layout for the smallest size class");
+
+            let mut moved = 0;
+            for receiver in self.heaps.values() {
+                let Some(mut needed) = receiver.try_lock()
+                    .map(|heap| REBALANCE_LOW_WATERMARK.saturating_sub(heap.empty_pages()))
+                    else { continue };
+
+                for donor in self.heaps.values() {
+                    if needed == 0 { break; }
+                    if ptr::eq(donor, receiver) { continue; }
+
+                    while needed > 0 {
+                        let Some(mp) = donor.try_lock().and_then(|mut heap| heap.retrieve_empty_page(REBALANCE_HIGH_WATERMARK))
+                            else { break };
+                        if let Err(e) = receiver.lock().refill(refill_layout, mp) {
+                            error!("multiple_heaps: failed to move an empty page while rebalancing: {}", e);
+                            return moved;
+                        }
+                        needed -= 1;
+                        moved += 1;
+                    }
+                }
+            }
+            moved
+        }
+    }
+
+    // The multiple heaps are set as the default allocator by reference,
+    // such that they can also be accessed directly for rebalancing.
+    unsafe impl GlobalAlloc for &'static MultipleHeaps {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            (**self).alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            (**self).dealloc(ptr, layout)
+        }
     }
 
 } else {
@@ -468,11 +633,63 @@ if #[cfg(unsafe_heap)] {
             }
             Ok(())
         }  
+
+        /// Moves empty pages from per-core heaps that have more than `REBALANCE_HIGH_WATERMARK` empty pages
+        /// to per-core heaps that have fewer than `REBALANCE_LOW_WATERMARK` empty pages.
+        ///
+        /// This allows a per-core heap to keep serving allocations from its own pages,
+        /// rather than having to take pages from other heaps (and thus contend on their locks)
+        /// or grow the heap once it has run out of memory.
+        ///
+        /// Heaps that are currently locked are skipped, so this never blocks on a heap in use.
+        /// At most one heap lock is held at a time.
+        /// Returns the number of empty pages that were moved.
+        pub fn rebalance(&self) -> usize {
+            // The receiving heap can later move these empty pages from the smallest size class
+            // to whichever size class needs them (see `ZoneAllocator::exchange_pages_within_heap()`).
+            let refill_layout = Layout::from_size_align(ZoneAllocator::BASE_ALLOC_SIZES[0], ZoneAllocator::BASE_ALLOC_SIZES[0])
+                .expect("BUG: multiple_heaps: invalid layout for the smallest size class");
+
+            let mut moved = 0;
+            for receiver in self.heaps.values() {
+                let Some(mut needed) = receiver.try_lock()
+                    .map(|heap| REBALANCE_LOW_WATERMARK.saturating_sub(heap.empty_pages()))
+                    else { continue };
+
+                for donor in self.heaps.values() {
+                    if needed == 0 { break; }
+                    if ptr::eq(donor, receiver) { continue; }
+
+                    while needed > 0 {
+                        let Some(mp) = donor.try_lock().and_then(|mut heap| heap.retrieve_empty_page(REBALANCE_HIGH_WATERMARK))
+                            else { break };
+                        if let Err(e) = receiver.lock().refill(refill_layout, mp) {
+                            error!("multiple_heaps: failed to move an empty page while rebalancing: {}", e);
+                            return moved;
+                        }
+                        needed -= 1;
+                        moved += 1;
+                    }
+                }
+            }
+            moved
+        }
+    }
+
+    // The multiple heaps are set as the default allocator by reference,
+    // such that they can also be accessed directly for rebalancing.
+    unsafe impl GlobalAlloc for &'static MultipleHeaps {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            (**self).alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            (**self).dealloc(ptr, layout)
+        }
     }
 }
 } // end cfg_if for MultipleHeaps impl
 
-
 unsafe impl GlobalAlloc for MultipleHeaps {
 
     /// Allocates the given `layout` from the heap of the core the task is currently running on.
@@ -493,7 +710,20 @@ unsafe impl GlobalAlloc for MultipleHeaps {
         // For regular-sized allocations, we first try to allocated from "our" heap, 
         // which is currently the per-core heap for the current CPU core. 
         let our_heap = self.heaps.get(&get_key()).expect("Multiple Heaps: heap is not initialized!");
-        if let Ok(ptr) = { our_heap.lock().allocate(layout) } {
+        let (result, ran_low) = {
+            let mut heap = our_heap.lock();
+            let empty_pages = heap.empty_pages();
+            let result = heap.allocate(layout);
+            let ran_low = result.is_err()
+                || (empty_pages >= REBALANCE_LOW_WATERMARK && heap.empty_pages() < REBALANCE_LOW_WATERMARK);
+            (result, ran_low)
+        };
+        // Request a rebalancing once this allocation leaves our heap low on empty pages,
+        // which must be done after releasing our heap's lock.
+        if ran_low {
+            request_rebalance();
+        }
+        if let Ok(ptr) = result {
             return ptr.as_ptr();
         };
         // If it fails the first time, we try to grow the heap and then try again. 
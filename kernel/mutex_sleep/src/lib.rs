@@ -3,11 +3,17 @@
 //! These are Theseus-specific locking types that ensure mutual exclusion
 //! using [`spin::Mutex`] and [`spin::RwLock`] under the hood;
 //! see those types for more details on how they work.
+//!
+//! [`MutexSleepPi`] additionally uses priority inheritance to prevent priority inversion.
 
 #![no_std]
+#![feature(thread_local)]
+#![feature(negative_impls)]
 
 mod mutex;
+mod mutex_pi;
 mod rwlock;
 
 pub use mutex::*;
+pub use mutex_pi::*;
 pub use rwlock::*;
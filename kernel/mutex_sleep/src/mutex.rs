@@ -2,6 +2,7 @@ use core::fmt;
 use core::ops::{Deref, DerefMut};
 use spin::{Mutex, MutexGuard};
 use wait_queue::WaitQueue;
+use tls_counters::Counter;
 use lockable::{Lockable, LockableSized};
 
 /// A mutual exclusion wrapper that puts a `Task` to sleep while waiting for the lock to become available. 
@@ -70,6 +71,7 @@ impl<T: ?Sized> MutexSleep<T> {
             return Ok(guard);
         }
         // Slow path if already locked elsewhere: wait until we obtain the lock.
+        tls_counters::incr(Counter::LockWaits);
         self.queue
             .wait_until(&|| self.try_lock())
             .map_err(|_| "failed to add current task to waitqueue")
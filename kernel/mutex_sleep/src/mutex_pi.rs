@@ -0,0 +1,236 @@
+use core::cell::Cell;
+use core::fmt;
+use core::mem::ManuallyDrop;
+use core::ops::{Deref, DerefMut};
+use spin::{Mutex, MutexGuard};
+use task::TaskRef;
+use wait_queue::WaitQueue;
+use tls_counters::Counter;
+use lockable::{Lockable, LockableSized};
+
+/// The number of [`MutexSleepPi`] locks held by the current task.
+///
+/// A task keeps the priority it inherited until it has released all of them,
+/// as it cannot tell which of its locks the higher-priority tasks are waiting for.
+#[thread_local]
+static PI_LOCKS_HELD: Cell<usize> = Cell::new(0);
+
+/// A sleeping mutual exclusion wrapper, like [`MutexSleep`], that uses priority inheritance
+/// to prevent priority inversion.
+///
+/// The lock records which `Task` currently holds it.
+/// When a `Task` blocks while waiting for the lock, the holder inherits that `Task`'s
+/// effective priority if it is higher than the holder's own, such that a low-priority holder
+/// cannot be starved by medium-priority tasks while a high-priority task waits for it.
+/// The holder reverts to its own priority once it has released every `MutexSleepPi` it holds.
+///
+/// Inherited priorities are only honored by priority-based scheduling policies;
+/// see [`Task::effective_priority()`].
+///
+/// [`MutexSleep`]: crate::MutexSleep
+/// [`Task::effective_priority()`]: task::Task::effective_priority
+pub struct MutexSleepPi<T: ?Sized> {
+    queue: WaitQueue,
+    /// The task that currently holds the lock, if any.
+    ///
+    /// This is locked while acquiring and releasing `lock`, such that a waiting task
+    /// always observes the holder of the lock at the moment it failed to acquire it.
+    owner: Mutex<Option<TaskRef>>,
+    lock: Mutex<T>,
+}
+
+/// A guard that allows the locked data to be accessed, during which mutual exclusion is guaranteed.
+///
+/// When the guard falls out of scope, the lock will be automatically released,
+/// which reverts any priority the holder inherited and notifies any `Task`s waiting on the lock.
+pub struct MutexSleepPiGuard<'a, T: ?Sized + 'a> {
+    guard: ManuallyDrop<MutexGuard<'a, T>>,
+    owner: &'a Mutex<Option<TaskRef>>,
+    queue: &'a WaitQueue,
+}
+
+// Same unsafe impls as `std::sync::Mutex`
+unsafe impl<T: ?Sized + Send> Send for MutexSleepPi<T> {}
+unsafe impl<T: ?Sized + Send> Sync for MutexSleepPi<T> {}
+
+// The guard must be released by the task that acquired it, which holds the inherited priority.
+impl<'a, T: ?Sized> !Send for MutexSleepPiGuard<'a, T> {}
+
+impl<T> MutexSleepPi<T> {
+    /// Creates a new lock wrapping the supplied data.
+    pub fn new(data: T) -> MutexSleepPi<T> {
+        MutexSleepPi {
+            queue: WaitQueue::new(),
+            owner: Mutex::new(None),
+            lock: Mutex::new(data),
+        }
+    }
+
+    /// Consumes this `MutexSleepPi`, returning the underlying data.
+    pub fn into_inner(self) -> T {
+        self.lock.into_inner()
+    }
+}
+
+impl<T: ?Sized> MutexSleepPi<T> {
+    /// Returns `true` if the lock is currently held.
+    ///
+    /// # Safety
+    ///
+    /// This function provides no synchronization guarantees and so its result should be considered 'out of date'
+    /// the instant it is called. Do not use it for synchronization purposes. However, it may be useful as a heuristic.
+    #[inline(always)]
+    pub fn is_locked(&self) -> bool {
+        self.lock.is_locked()
+    }
+
+    /// Returns the `Task` that currently holds the lock, if any.
+    ///
+    /// Like [`is_locked()`](#method.is_locked), the result may be out of date the instant it is returned.
+    pub fn owner(&self) -> Option<TaskRef> {
+        self.owner.lock().clone()
+    }
+
+    /// Blocks until the lock is acquired by putting this `Task` to sleep
+    /// until another `Task` that has the lock releases it.
+    ///
+    /// While this `Task` is waiting, the holder of the lock inherits its effective priority
+    /// if that is higher than the holder's own.
+    ///
+    /// The returned guard may be dereferenced to access the protected data;
+    /// the lock will be released when the returned guard falls out of scope and is dropped.
+    pub fn lock(&self) -> Result<MutexSleepPiGuard<T>, &'static str> {
+        // Fast path: check for the uncontended case.
+        if let Some(guard) = self.try_lock() {
+            return Ok(guard);
+        }
+        // Slow path if already locked elsewhere: lend our priority to the holder
+        // and wait until we obtain the lock.
+        tls_counters::incr(Counter::LockWaits);
+        self.queue
+            .wait_until(&|| self.try_lock_or_donate_priority())
+            .map_err(|_| "failed to add current task to waitqueue")
+    }
+
+    /// Tries to lock the MutexSleepPi. If it is already locked, it will return `None`.
+    /// Otherwise it returns a guard within `Some`.
+    ///
+    /// This also returns `None` if there is no current task to record as the holder of the lock.
+    pub fn try_lock(&self) -> Option<MutexSleepPiGuard<T>> {
+        let current = task::get_my_current_task()?;
+        let mut owner = self.owner.lock();
+        let guard = self.lock.try_lock()?;
+        Some(self.acquired(guard, &mut owner, current))
+    }
+
+    /// Like [`try_lock()`](#method.try_lock), but if the lock is held by another task,
+    /// raises that task's inherited priority to the current task's effective priority.
+    fn try_lock_or_donate_priority(&self) -> Option<MutexSleepPiGuard<T>> {
+        let current = task::get_my_current_task()?;
+        let mut owner = self.owner.lock();
+        if let Some(guard) = self.lock.try_lock() {
+            return Some(self.acquired(guard, &mut owner, current));
+        }
+        if let Some(holder) = owner.as_ref() {
+            let priority = current.effective_priority();
+            if priority > holder.effective_priority() {
+                holder.inherit_priority(priority);
+            }
+        }
+        None
+    }
+
+    /// Records the `current` task as the holder of this lock, which it has just acquired.
+    fn acquired<'a>(
+        &'a self,
+        guard: MutexGuard<'a, T>,
+        owner: &mut Option<TaskRef>,
+        current: TaskRef,
+    ) -> MutexSleepPiGuard<'a, T> {
+        *owner = Some(current);
+        PI_LOCKS_HELD.set(PI_LOCKS_HELD.get() + 1);
+        MutexSleepPiGuard {
+            guard: ManuallyDrop::new(guard),
+            owner: &self.owner,
+            queue: &self.queue,
+        }
+    }
+
+    /// Returns a mutable reference to the underlying data.
+    ///
+    /// Since this call borrows the [`MutexSleepPi`] mutably, and a mutable reference is guaranteed to be exclusive in Rust,
+    /// no actual locking needs to take place -- the mutable borrow statically guarantees no locks exist. As such,
+    /// this is a 'zero-cost' operation.
+    #[inline(always)]
+    pub fn get_mut(&mut self) -> &mut T {
+        self.lock.get_mut()
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for MutexSleepPi<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.lock.try_lock() {
+            Some(guard) => write!(f, "MutexSleepPi {{ data: {:?} }}", &*guard),
+            None => write!(f, "MutexSleepPi {{ <locked> }}"),
+        }
+    }
+}
+
+impl<T: ?Sized + Default> Default for MutexSleepPi<T> {
+    fn default() -> MutexSleepPi<T> {
+        MutexSleepPi::new(Default::default())
+    }
+}
+
+impl<'a, T: ?Sized> Deref for MutexSleepPiGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<'a, T: ?Sized> DerefMut for MutexSleepPiGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<'a, T: ?Sized> Drop for MutexSleepPiGuard<'a, T> {
+    fn drop(&mut self) {
+        {
+            let mut owner = self.owner.lock();
+            if let Some(holder) = owner.take() {
+                let remaining = PI_LOCKS_HELD.get().saturating_sub(1);
+                PI_LOCKS_HELD.set(remaining);
+                if remaining == 0 {
+                    holder.clear_inherited_priority();
+                }
+            }
+            // SAFETY: the inner guard is dropped exactly once, here, and is never used afterwards.
+            unsafe { ManuallyDrop::drop(&mut self.guard) };
+        }
+        // Notify a waiting task only after the lock is released,
+        // such that it cannot fail to acquire the lock and then miss this notification.
+        self.queue.notify_one();
+    }
+}
+
+/// Implement `Lockable` for [`MutexSleepPi`].
+/// Because [`MutexSleepPi::lock()`] returns a `Result` and may fail,
+/// the [`Lockable::lock()`] function internally `unwrap`s that `Result`.
+impl<'t, T> Lockable<'t, T> for MutexSleepPi<T> where T: 't + ?Sized {
+    type Guard = MutexSleepPiGuard<'t, T>;
+    type GuardMut = Self::Guard;
+
+    fn lock(&'t self) -> Self::Guard { self.lock().unwrap() }
+    fn try_lock(&'t self) -> Option<Self::Guard> { self.try_lock() }
+    fn lock_mut(&'t self) -> Self::GuardMut { self.lock().unwrap() }
+    fn try_lock_mut(&'t self) -> Option<Self::GuardMut> { self.try_lock() }
+    fn is_locked(&self) -> bool { self.is_locked() }
+    fn get_mut(&'t mut self) -> &mut T { self.get_mut() }
+}
+/// Implement `LockableSized` for [`MutexSleepPi`].
+impl<'t, T> LockableSized<'t, T> for MutexSleepPi<T> where T: 't + Sized {
+    fn into_inner(self) -> T { self.into_inner() }
+}
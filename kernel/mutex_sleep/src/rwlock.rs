@@ -5,6 +5,7 @@ use core::fmt;
 use core::ops::{Deref, DerefMut};
 use spin::{RwLock, RwLockReadGuard, RwLockWriteGuard};
 use wait_queue::WaitQueue;
+use tls_counters::Counter;
 use lockable::{Lockable, LockableSized};
 
 /// A multi-reader, single-writer mutual exclusion wrapper that puts a `Task` to sleep
@@ -101,6 +102,7 @@ impl<T: ?Sized> RwLockSleep<T> {
             return Ok(guard);
         }
         // Slow path if already locked elsewhere: wait until we obtain the lock.
+        tls_counters::incr(Counter::LockWaits);
         self.queue
             .wait_until(&|| self.try_read())
             .map_err(|_| "failed to add current task to waitqueue")
@@ -197,6 +199,7 @@ impl<T: ?Sized> RwLockSleep<T> {
             return Ok(guard);
         }
         // Slow path if already locked elsewhere: wait until we obtain the write lock.
+        tls_counters::incr(Counter::LockWaits);
         self.queue
             .wait_until(&|| self.try_write())
             .map_err(|_| "failed to add current task to waitqueue")
@@ -207,6 +207,9 @@ where
     // if in loadable mode, parse the crates we always need: the core library (Rust no_std lib), the panic handlers, and the captain
     #[cfg(loadable)] {
         use mod_mgmt::CrateNamespace;
+        // Defer loading crates that are only called into until they are first used.
+        #[cfg(lazy_binding)]
+        default_namespace.enable_lazy_binding()?;
         println_raw!("nano_core(): loading the \"captain\" crate...");
         let (captain_file, _ns) = CrateNamespace::get_crate_object_file_starting_with(default_namespace, "captain-").ok_or("couldn't find the singular \"captain\" crate object file")?;
         let (_captain_crate, _num_captain_syms) = default_namespace.load_crate(&captain_file, None, &kernel_mmi_ref, false)?;
@@ -273,3 +276,9 @@ mod libm;
 /// 
 /// You can disable the need for this via the `-fno-stack-protection` GCC option.
 mod stack_smash_protection;
+
+/// Implements the TLS helper functions that the general-dynamic TLS model
+/// and TLS descriptors rely on, e.g., `__tls_get_addr()`.
+/// These live in the nano_core so that they're published in the initial symbol map
+/// and can be resolved by `mod_mgmt` when loading crates that use them.
+mod tls_symbols;
@@ -0,0 +1,72 @@
+//! TLS helper functions required by the ELF TLS ABI for non-local-exec TLS models.
+//!
+//! Theseus compiles its own crates with the local-exec TLS model,
+//! but foreign code (e.g., C libraries or crates built with other TLS models)
+//! may access TLS variables via `__tls_get_addr()` or TLS descriptors.
+//! Because Theseus places all TLS sections into a single TLS area,
+//! these functions simply add the given offset to the current TLS self pointer.
+//!
+//! See the [`TlsIndex`] docs for more about how offsets are represented.
+
+use core::arch::asm;
+use mod_mgmt::{current_tls_self_pointer, TlsIndex, THESEUS_TLS_MODULE_ID};
+
+/// Returns the address of the TLS variable described by the given `tls_index`
+/// in the current task's TLS area.
+///
+/// This is invoked by code compiled with the general-dynamic or local-dynamic TLS models.
+#[no_mangle]
+pub extern "C" fn __tls_get_addr(tls_index: &TlsIndex) -> *mut u8 {
+    debug_assert_eq!(tls_index.module, THESEUS_TLS_MODULE_ID, "__tls_get_addr: invalid TLS module ID");
+    current_tls_self_pointer().wrapping_add(tls_index.offset) as *mut u8
+}
+
+/// Returns the value of the current CPU's thread pointer (`TPIDR_EL0`) in `x0`.
+///
+/// This is the aarch64 equivalent of `__tls_get_addr()` for code
+/// that cannot directly access the thread pointer register.
+/// It only clobbers `x0`.
+#[cfg(target_arch = "aarch64")]
+#[naked]
+#[no_mangle]
+pub unsafe extern "C" fn __aarch64_read_tp() -> usize {
+    asm!(
+        "mrs x0, tpidr_el0",
+        "ret",
+        options(noreturn)
+    );
+}
+
+/// The resolver function for static TLS descriptors.
+///
+/// A TLS descriptor is a pair of words: a pointer to this resolver function
+/// and an argument, which in Theseus is the TLS variable's offset from the TLS self pointer.
+/// The resolver is called with the address of the descriptor in `rax`
+/// and must return the offset in `rax`, clobbering no other registers.
+#[cfg(target_arch = "x86_64")]
+#[naked]
+#[no_mangle]
+pub unsafe extern "C" fn _dl_tlsdesc_return() -> usize {
+    asm!(
+        "mov rax, [rax + 8]",
+        "ret",
+        options(noreturn)
+    );
+}
+
+/// The resolver function for static TLS descriptors.
+///
+/// A TLS descriptor is a pair of words: a pointer to this resolver function
+/// and an argument, which in Theseus is the TLS variable's offset from the TLS self pointer.
+/// The resolver is called with the address of the descriptor in `x0`
+/// and must return the offset in `x0`, clobbering no other registers.
+#[cfg(target_arch = "aarch64")]
+#[naked]
+#[no_mangle]
+pub unsafe extern "C" fn _dl_tlsdesc_return() -> usize {
+    asm!(
+        "ldr x0, [x0, #8]",
+        "ret",
+        options(noreturn)
+    );
+}
@@ -1,6 +1,7 @@
 use alloc::vec;
 use core::any::Any;
 use log::error;
+use metrics::Counter;
 use nic_buffers::ReceivedFrame;
 use smoltcp::phy;
 
@@ -11,6 +12,31 @@ use crate::Error;
 /// Standard maximum transition unit for ethernet cards.
 const STANDARD_MTU: usize = 1500;
 
+static RX_PACKETS: Counter = Counter::new(
+    "net_rx_packets_total",
+    "Number of frames received by all network devices",
+);
+static RX_BYTES: Counter = Counter::new(
+    "net_rx_bytes_total",
+    "Number of bytes received by all network devices",
+);
+static TX_PACKETS: Counter = Counter::new(
+    "net_tx_packets_total",
+    "Number of frames sent by all network devices",
+);
+static TX_BYTES: Counter = Counter::new(
+    "net_tx_bytes_total",
+    "Number of bytes sent by all network devices",
+);
+
+/// Registers the packet and byte counters of all network devices.
+pub(crate) fn register_metrics() -> core::result::Result<(), &'static str> {
+    RX_PACKETS.register()?;
+    RX_BYTES.register()?;
+    TX_PACKETS.register()?;
+    TX_BYTES.register()
+}
+
 /// A network device.
 ///
 /// Devices implementing this trait can then be registered using
@@ -84,6 +110,8 @@ impl phy::RxToken for RxToken {
             );
         }
         let slice = self.inner.0.first_mut().ok_or(Error::Exhausted)?;
+        RX_PACKETS.inc();
+        RX_BYTES.add(slice.len() as u64);
         f(slice)
     }
 }
@@ -106,6 +134,8 @@ impl<'a> phy::TxToken for TxToken<'a> {
         let mut buf = vec![0; len];
         let ret = f(&mut buf)?;
         self.device.send(&buf)?;
+        TX_PACKETS.inc();
+        TX_BYTES.add(len as u64);
         Ok(ret)
     }
 }
@@ -0,0 +1,78 @@
+use crate::{NetworkInterface, DEFAULT_DNS_SERVER, DEFAULT_GATEWAY_IP, DEFAULT_LOCAL_IP};
+use alloc::{string::ToString, sync::Arc, vec::Vec};
+use log::{info, warn};
+use smoltcp::{socket::dhcpv4, wire::IpAddress};
+use time::{Duration, Monotonic};
+
+/// How often the DHCP client polls the interface.
+///
+/// This also drives the interface's timers, e.g. TCP retransmissions and DNS
+/// query retries, while no packets are received.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How long the DHCP client waits for a lease before falling back to the
+/// default static configuration.
+const DHCP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Spawns a task that configures the given interface via DHCP, and keeps
+/// renewing its lease.
+pub(crate) fn spawn_client(interface: Arc<NetworkInterface>) -> Result<(), &'static str> {
+    spawn::new_task_builder(client_loop, interface)
+        .name("dhcp_client".to_string())
+        .spawn()
+        .map(|_| ())
+}
+
+/// The entry point of the DHCP client task.
+fn client_loop(interface: Arc<NetworkInterface>) -> Result<(), &'static str> {
+    let start = time::now::<Monotonic>();
+    let mut configured = false;
+    let mut fell_back = false;
+    loop {
+        if let Err(e) = interface.poll() {
+            warn!("dhcp_client: failed to poll interface: {e}");
+        }
+        let event = interface.with_sockets(|sockets| {
+            sockets.get_mut::<dhcpv4::Socket>(interface.dhcp).poll()
+        });
+        match event {
+            Some(dhcpv4::Event::Configured(config)) => {
+                info!(
+                    "dhcp_client: acquired address {}, gateway {:?}",
+                    config.address, config.router
+                );
+                interface.set_ipv4_config(config.address, config.router);
+                let dns_servers: Vec<IpAddress> = config
+                    .dns_servers
+                    .iter()
+                    .flatten()
+                    .map(|&server| IpAddress::Ipv4(server))
+                    .collect();
+                interface.set_dns_servers(&dns_servers);
+                configured = true;
+            }
+            Some(dhcpv4::Event::Deconfigured) => {
+                warn!("dhcp_client: lost lease");
+                interface.clear_ipv4_config();
+                interface.set_dns_servers(&[]);
+                configured = false;
+            }
+            None => {}
+        }
+
+        if !configured
+            && !fell_back
+            && time::now::<Monotonic>().duration_since(start) >= DHCP_TIMEOUT
+        {
+            warn!(
+                "dhcp_client: no lease acquired, falling back to static address {}",
+                DEFAULT_LOCAL_IP
+            );
+            interface.set_ipv4_config(DEFAULT_LOCAL_IP, Some(DEFAULT_GATEWAY_IP));
+            interface.set_dns_servers(&[IpAddress::Ipv4(DEFAULT_DNS_SERVER)]);
+            fell_back = true;
+        }
+
+        let _ = sleep::sleep(POLL_INTERVAL);
+    }
+}
@@ -0,0 +1,164 @@
+use crate::{get_default_interface, Error, IpAddress, NetworkInterface, Result};
+use alloc::{collections::BTreeMap, string::String, vec, vec::Vec};
+use core::{future::poll_fn, task::Poll};
+use metrics::Gauge;
+use smoltcp::socket::dns::{self, GetQueryResultError, QueryHandle};
+use spin::Mutex;
+use time::{Duration, Instant, Monotonic};
+
+/// The maximum number of domain names held in the cache.
+const CACHE_CAPACITY: usize = 32;
+
+/// How long a resolved domain name is cached.
+///
+/// The DNS socket doesn't report the TTL of its results, so a fixed, short
+/// duration is used instead.
+const CACHE_TTL: Duration = Duration::from_secs(300);
+
+/// Recently-resolved domain names, shared by all interfaces.
+static CACHE: Mutex<BTreeMap<String, CacheEntry>> = Mutex::new(BTreeMap::new());
+
+static CACHE_ENTRIES: Gauge = Gauge::new(
+    "net_dns_cache_entries",
+    "Number of domain names in the DNS cache, including expired ones",
+);
+
+/// Registers the DNS cache's metrics.
+pub(crate) fn register_metrics() -> core::result::Result<(), &'static str> {
+    CACHE_ENTRIES.register()
+}
+
+struct CacheEntry {
+    addrs: Vec<IpAddress>,
+    expires: Instant,
+}
+
+/// Resolves the given domain name to its IP addresses using the default
+/// interface.
+///
+/// See [`NetworkInterface::resolve`].
+pub async fn resolve(name: &str) -> Result<Vec<IpAddress>> {
+    if let Ok(addr) = name.parse::<IpAddress>() {
+        return Ok(vec![addr]);
+    }
+    let interface = get_default_interface().ok_or(Error::Unaddressable)?;
+    interface.resolve(name).await
+}
+
+impl NetworkInterface {
+    /// Resolves the given domain name to its IP addresses.
+    ///
+    /// IP address literals are returned as is, and recently-resolved names
+    /// are served from a cache. Otherwise, the interface's DNS servers, which
+    /// are usually configured via DHCP, are queried.
+    pub async fn resolve(&self, name: &str) -> Result<Vec<IpAddress>> {
+        if let Ok(addr) = name.parse::<IpAddress>() {
+            return Ok(vec![addr]);
+        }
+        if let Some(addrs) = cached(name) {
+            return Ok(addrs);
+        }
+        if self.dns_servers().is_empty() {
+            return Err(Error::Unaddressable);
+        }
+
+        let mut query = Query {
+            interface: self,
+            handle: Some(self.start_dns_query(name)?),
+        };
+        // Send the query right away, rather than upon the next poll.
+        self.poll()?;
+        let addrs = poll_fn(|cx| query.poll_result(cx.waker())).await?;
+        insert(name, &addrs);
+        Ok(addrs)
+    }
+
+    fn start_dns_query(&self, name: &str) -> Result<QueryHandle> {
+        let mut inner = self.inner.lock().expect("failed to lock inner interface");
+        self.with_sockets(|sockets| {
+            sockets
+                .get_mut::<dns::Socket>(self.dns)
+                .start_query(inner.context(), name)
+                .map_err(Error::from)
+        })
+    }
+}
+
+/// An in-flight DNS query, which is cancelled if it's dropped before it
+/// completes.
+struct Query<'a> {
+    interface: &'a NetworkInterface,
+    handle: Option<QueryHandle>,
+}
+
+impl Query<'_> {
+    fn poll_result(&mut self, waker: &core::task::Waker) -> Poll<Result<Vec<IpAddress>>> {
+        let Some(handle) = self.handle else {
+            return Poll::Ready(Err(Error::Illegal));
+        };
+        self.interface.with_sockets(|sockets| {
+            let socket = sockets.get_mut::<dns::Socket>(self.interface.dns);
+            match socket.get_query_result(handle) {
+                Ok(addrs) => {
+                    self.handle = None;
+                    Poll::Ready(Ok(addrs.iter().copied().collect()))
+                }
+                Err(GetQueryResultError::Pending) => {
+                    socket.register_query_waker(handle, waker);
+                    Poll::Pending
+                }
+                Err(GetQueryResultError::Failed) => {
+                    self.handle = None;
+                    Poll::Ready(Err(Error::Unaddressable))
+                }
+            }
+        })
+    }
+}
+
+impl Drop for Query<'_> {
+    fn drop(&mut self) {
+        if let Some(handle) = self.handle.take() {
+            self.interface.with_sockets(|sockets| {
+                sockets
+                    .get_mut::<dns::Socket>(self.interface.dns)
+                    .cancel_query(handle)
+            });
+        }
+    }
+}
+
+/// Returns the cached addresses of the given domain name, if they haven't
+/// expired.
+fn cached(name: &str) -> Option<Vec<IpAddress>> {
+    let cache = CACHE.lock();
+    let entry = cache.get(name)?;
+    (entry.expires > time::now::<Monotonic>()).then(|| entry.addrs.clone())
+}
+
+/// Caches the addresses of the given domain name, evicting expired entries
+/// or else the entry closest to expiring if the cache is full.
+fn insert(name: &str, addrs: &[IpAddress]) {
+    let now = time::now::<Monotonic>();
+    let mut cache = CACHE.lock();
+    if cache.len() >= CACHE_CAPACITY && !cache.contains_key(name) {
+        cache.retain(|_, entry| entry.expires > now);
+        if cache.len() >= CACHE_CAPACITY {
+            let oldest = cache
+                .iter()
+                .min_by_key(|(_, entry)| entry.expires)
+                .map(|(name, _)| name.clone());
+            if let Some(oldest) = oldest {
+                cache.remove(&oldest);
+            }
+        }
+    }
+    cache.insert(
+        String::from(name),
+        CacheEntry {
+            addrs: addrs.to_vec(),
+            expires: now + CACHE_TTL,
+        },
+    );
+    CACHE_ENTRIES.set(cache.len() as i64);
+}
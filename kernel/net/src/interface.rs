@@ -2,12 +2,23 @@ use crate::{device::DeviceWrapper, NetworkDevice, Result, Socket};
 use alloc::vec::Vec;
 use core::marker::PhantomData;
 use irq_safety::MutexIrqSafe;
+use log::error;
 use mutex_sleep::MutexSleep;
-use smoltcp::{iface, phy::DeviceCapabilities, socket::AnySocket, wire};
+use smoltcp::{
+    iface::{self, SocketHandle},
+    phy::DeviceCapabilities,
+    socket::{dhcpv4, dns, tcp, AnySocket},
+    wire::{self, Ipv4Address, Ipv4Cidr},
+};
+use spin::Mutex;
 
 pub use smoltcp::iface::SocketSet;
 pub use wire::{IpAddress, IpCidr};
 
+/// The maximum number of DNS queries that can be in flight on an interface at
+/// a time.
+const MAX_DNS_QUERIES: usize = 4;
+
 /// A network interface.
 ///
 /// This is a wrapper around a network device which provides higher level
@@ -16,23 +27,27 @@ pub struct NetworkInterface {
     inner: MutexSleep<iface::Interface<'static>>,
     device: &'static MutexIrqSafe<dyn crate::NetworkDevice>,
     sockets: MutexSleep<SocketSet<'static>>,
+    /// TCP sockets that have been closed by their owner, which are removed from `sockets`
+    /// once their connection has been fully shut down.
+    closing: Mutex<Vec<SocketHandle>>,
+    /// The socket used by the interface's DHCP client.
+    pub(crate) dhcp: SocketHandle,
+    /// The socket used to resolve domain names.
+    pub(crate) dns: SocketHandle,
+    dns_servers: Mutex<Vec<IpAddress>>,
 }
 
 impl NetworkInterface {
-    pub(crate) fn new<T>(device: &'static MutexIrqSafe<T>, ip: IpCidr, gateway: IpAddress) -> Self
+    /// Creates an interface for the given device without any IP addresses,
+    /// which are configured later via DHCP or [`set_ipv4_config`].
+    ///
+    /// [`set_ipv4_config`]: Self::set_ipv4_config
+    pub(crate) fn new<T>(device: &'static MutexIrqSafe<T>) -> Self
     where
         T: NetworkDevice,
     {
         let hardware_addr = wire::EthernetAddress(device.lock().mac_address()).into();
 
-        let mut routes = iface::Routes::new();
-
-        match gateway {
-            IpAddress::Ipv4(addr) => routes.add_default_ipv4_route(addr),
-            IpAddress::Ipv6(addr) => routes.add_default_ipv6_route(addr),
-        }
-        .expect("btree map route storage exhausted");
-
         let mut wrapper = DeviceWrapper {
             inner: &mut *device.lock(),
         };
@@ -40,21 +55,78 @@ impl NetworkInterface {
             iface::InterfaceBuilder::new()
                 .random_seed(random::next_u64())
                 .hardware_addr(hardware_addr)
-                .ip_addrs(heapless::Vec::<_, 5>::from_slice(&[ip]).unwrap())
-                .routes(routes)
+                .ip_addrs(heapless::Vec::<_, 5>::new())
+                .routes(iface::Routes::new())
                 .neighbor_cache(iface::NeighborCache::new())
                 .finalize(&mut wrapper),
         );
 
-        let sockets = MutexSleep::new(SocketSet::new(Vec::new()));
+        let mut sockets = SocketSet::new(Vec::new());
+        let dhcp = sockets.add(dhcpv4::Socket::new());
+        let dns_queries: Vec<_> = (0..MAX_DNS_QUERIES).map(|_| None).collect();
+        let dns = sockets.add(dns::Socket::new(&[], dns_queries));
 
         Self {
             inner,
             device,
-            sockets,
+            sockets: MutexSleep::new(sockets),
+            closing: Mutex::new(Vec::new()),
+            dhcp,
+            dns,
+            dns_servers: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Sets the IPv4 address and default gateway of the interface, replacing
+    /// any previous IPv4 configuration.
+    pub fn set_ipv4_config(&self, address: Ipv4Cidr, gateway: Option<Ipv4Address>) {
+        let mut inner = self.inner.lock().expect("failed to lock inner interface");
+        inner.update_ip_addrs(|addrs| {
+            addrs.retain(|addr| !matches!(addr, IpCidr::Ipv4(_)));
+            if addrs.push(IpCidr::Ipv4(address)).is_err() {
+                error!("failed to add IP address {address}: too many addresses");
+            }
+        });
+        match gateway {
+            Some(gateway) => {
+                inner
+                    .routes_mut()
+                    .add_default_ipv4_route(gateway)
+                    .expect("btree map route storage exhausted");
+            }
+            None => {
+                inner.routes_mut().remove_default_ipv4_route();
+            }
         }
     }
 
+    /// Removes the IPv4 address and default gateway of the interface.
+    pub fn clear_ipv4_config(&self) {
+        let mut inner = self.inner.lock().expect("failed to lock inner interface");
+        inner.update_ip_addrs(|addrs| addrs.retain(|addr| !matches!(addr, IpCidr::Ipv4(_))));
+        inner.routes_mut().remove_default_ipv4_route();
+    }
+
+    /// Returns the IP addresses of the interface.
+    pub fn ip_addrs(&self) -> Vec<IpCidr> {
+        self.inner
+            .lock()
+            .expect("failed to lock inner interface")
+            .ip_addrs()
+            .to_vec()
+    }
+
+    /// Returns the DNS servers used to resolve domain names.
+    pub fn dns_servers(&self) -> Vec<IpAddress> {
+        self.dns_servers.lock().clone()
+    }
+
+    /// Sets the DNS servers used to resolve domain names.
+    pub fn set_dns_servers(&self, servers: &[IpAddress]) {
+        self.with_sockets(|sockets| sockets.get_mut::<dns::Socket>(self.dns).update_servers(servers));
+        *self.dns_servers.lock() = servers.to_vec();
+    }
+
     /// Adds a socket to the interface.
     pub fn add_socket<T>(&self, socket: T) -> Socket<T>
     where
@@ -72,7 +144,28 @@ impl NetworkInterface {
         }
     }
 
+    /// Runs the given function on the set of sockets associated with the
+    /// interface.
+    ///
+    /// The interface must not be polled from within the function.
+    pub(crate) fn with_sockets<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce(&mut SocketSet<'static>) -> R,
+    {
+        f(&mut self.sockets.lock().expect("failed to lock sockets"))
+    }
+
+    /// Closes the given TCP socket, and removes it from the interface once its
+    /// connection has been shut down.
+    pub(crate) fn close_tcp_socket(&self, handle: SocketHandle) {
+        self.with_sockets(|sockets| sockets.get_mut::<tcp::Socket>(handle).close());
+        self.closing.lock().push(handle);
+    }
+
     /// Polls the sockets associated with the interface.
+    ///
+    /// Timers, e.g. TCP retransmission and keepalive timers, only fire when
+    /// the interface is polled.
     pub fn poll(&self) -> Result<()> {
         let mut inner = self.inner.lock().expect("failed to lock inner interface");
         let mut wrapper = DeviceWrapper {
@@ -80,7 +173,15 @@ impl NetworkInterface {
         };
         let mut sockets = self.sockets.lock().expect("failed to lock sockets");
 
-        inner.poll(smoltcp::time::Instant::ZERO, &mut wrapper, &mut sockets)?;
+        inner.poll(crate::now(), &mut wrapper, &mut sockets)?;
+
+        self.closing.lock().retain(|&handle| {
+            let closed = sockets.get::<tcp::Socket>(handle).state() == tcp::State::Closed;
+            if closed {
+                sockets.remove(handle);
+            }
+            !closed
+        });
 
         Ok(())
     }
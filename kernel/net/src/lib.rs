@@ -4,15 +4,21 @@ extern crate alloc;
 
 use alloc::{sync::Arc, vec::Vec};
 use irq_safety::MutexIrqSafe;
-use smoltcp::wire::Ipv4Address;
+use log::{error, warn};
+use smoltcp::wire::{Ipv4Address, Ipv4Cidr};
 use spin::Mutex;
 
 mod device;
+mod dhcp;
+mod dns;
 mod error;
 mod interface;
+mod listener;
 mod socket;
+mod stream;
 
 pub use device::{DeviceCapabilities, NetworkDevice};
+pub use dns::resolve;
 pub use error::{Error, Result};
 pub use interface::{IpAddress, IpCidr, NetworkInterface, SocketSet};
 pub use smoltcp::{
@@ -21,17 +27,24 @@ pub use smoltcp::{
     time::Instant,
     wire,
 };
+pub use listener::TcpListener;
 pub use socket::Socket;
+pub use stream::{TcpOptions, TcpStream};
 
-/// A randomly chosen IP address that must be outside of the DHCP range.
+/// The static IP address used if no DHCP server responds.
 ///
 /// The default QEMU user-slirp network gives IP address of `10.0.2.*`.
-const DEFAULT_LOCAL_IP: &str = "10.0.2.15/24";
+pub(crate) const DEFAULT_LOCAL_IP: Ipv4Cidr = Ipv4Cidr::new(Ipv4Address::new(10, 0, 2, 15), 24);
 
-/// Standard home router address.
+/// The gateway used if no DHCP server responds.
 ///
 /// `10.0.2.2` is the default QEMU user-slirp networking gateway IP.
-const DEFAULT_GATEWAY_IP: IpAddress = IpAddress::Ipv4(Ipv4Address::new(10, 0, 2, 2));
+pub(crate) const DEFAULT_GATEWAY_IP: Ipv4Address = Ipv4Address::new(10, 0, 2, 2);
+
+/// The DNS server used if no DHCP server responds.
+///
+/// `10.0.2.3` is the default QEMU user-slirp networking DNS server IP.
+pub(crate) const DEFAULT_DNS_SERVER: Ipv4Address = Ipv4Address::new(10, 0, 2, 3);
 
 // TODO: Make mutex rwlock?
 // TODO: Use atomic append-only vec?
@@ -40,20 +53,24 @@ static NETWORK_INTERFACES: Mutex<Vec<Arc<NetworkInterface>>> = Mutex::new(Vec::n
 /// Registers a network device.
 ///
 /// The function will convert the device to an interface and it will then be
-/// accessible using [`get_interfaces()`].
+/// accessible using [`get_interfaces()`]. Its IP address, gateway and DNS
+/// servers are configured via DHCP by a background task.
 pub fn register_device<T>(device: &'static MutexIrqSafe<T>) -> Arc<NetworkInterface>
 where
     T: 'static + NetworkDevice + Send,
 {
-    let interface = NetworkInterface::new(
-        device,
-        // TODO: use DHCP to acquire an IP address and gateway.
-        DEFAULT_LOCAL_IP.parse().unwrap(),
-        DEFAULT_GATEWAY_IP,
-    );
-
-    let interface_arc = Arc::new(interface);
+    let interface_arc = Arc::new(NetworkInterface::new(device));
     NETWORK_INTERFACES.lock().push(interface_arc.clone());
+
+    if let Err(e) = device::register_metrics().and_then(|_| dns::register_metrics()) {
+        warn!("failed to register network metrics: {e}");
+    }
+
+    if let Err(e) = dhcp::spawn_client(interface_arc.clone()) {
+        error!("failed to spawn DHCP client, using static configuration: {e}");
+        interface_arc.set_ipv4_config(DEFAULT_LOCAL_IP, Some(DEFAULT_GATEWAY_IP));
+        interface_arc.set_dns_servers(&[IpAddress::Ipv4(DEFAULT_DNS_SERVER)]);
+    }
     interface_arc
 }
 
@@ -66,3 +83,9 @@ pub fn get_interfaces() -> &'static Mutex<Vec<Arc<NetworkInterface>>> {
 pub fn get_default_interface() -> Option<Arc<NetworkInterface>> {
     NETWORK_INTERFACES.lock().get(0).cloned()
 }
+
+/// Returns the current time as a timestamp for the network stack.
+fn now() -> Instant {
+    let elapsed = time::now::<time::Monotonic>().duration_since(time::Instant::ZERO);
+    Instant::from_micros(elapsed.as_micros() as i64)
+}
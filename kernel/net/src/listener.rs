@@ -0,0 +1,142 @@
+use crate::{Error, NetworkInterface, Result, SocketSet, TcpOptions, TcpStream};
+use alloc::{sync::Arc, vec::Vec};
+use core::{
+    future::poll_fn,
+    task::{Context, Poll},
+};
+use smoltcp::{iface::SocketHandle, socket::tcp};
+use spin::Mutex;
+
+/// A TCP socket that listens for incoming connections on a port.
+///
+/// The listener holds a backlog of sockets listening on the same port, each
+/// of which can be connected to by one peer. Connected sockets form the accept
+/// queue until they're returned by [`accept`], at which point they're replaced
+/// by a new listening socket. Peers that connect while all sockets in the
+/// backlog are connected are refused.
+///
+/// [`accept`]: Self::accept
+pub struct TcpListener {
+    interface: Arc<NetworkInterface>,
+    port: u16,
+    options: TcpOptions,
+    backlog: Mutex<Vec<SocketHandle>>,
+}
+
+impl TcpListener {
+    /// Listens for connections to the given `port` on the given `interface`.
+    ///
+    /// Up to `backlog` connections can be waiting to be accepted at a time.
+    /// Accepted connections use the given `options`.
+    pub fn bind(
+        interface: Arc<NetworkInterface>,
+        port: u16,
+        backlog: usize,
+        options: TcpOptions,
+    ) -> Result<Self> {
+        if backlog == 0 {
+            return Err(Error::Illegal);
+        }
+        let handles = interface.with_sockets(|sockets| {
+            let mut handles = Vec::with_capacity(backlog);
+            for _ in 0..backlog {
+                match listening_socket(sockets, port, &options) {
+                    Ok(handle) => handles.push(handle),
+                    Err(e) => {
+                        for handle in handles {
+                            sockets.remove(handle);
+                        }
+                        return Err(e);
+                    }
+                }
+            }
+            Ok(handles)
+        })?;
+        Ok(Self {
+            interface,
+            port,
+            options,
+            backlog: Mutex::new(handles),
+        })
+    }
+
+    /// Returns the port this listener is listening on.
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// Waits for and accepts the next incoming connection.
+    pub async fn accept(&self) -> Result<TcpStream> {
+        poll_fn(|cx| self.poll_accept(cx)).await
+    }
+
+    /// Accepts the next incoming connection if one is waiting.
+    pub fn try_accept(&self) -> Result<Option<TcpStream>> {
+        self.accept_with(None)
+    }
+
+    /// Attempts to accept the next incoming connection, registering the
+    /// current task to be woken once a connection is established if none is
+    /// waiting.
+    pub fn poll_accept(&self, cx: &mut Context) -> Poll<Result<TcpStream>> {
+        match self.accept_with(Some(cx)) {
+            Ok(Some(stream)) => Poll::Ready(Ok(stream)),
+            Ok(None) => Poll::Pending,
+            Err(e) => Poll::Ready(Err(e)),
+        }
+    }
+
+    /// Accepts the first connected socket in the backlog, replacing it with a
+    /// new listening socket.
+    ///
+    /// If no socket is connected and `cx` is given, its waker is registered
+    /// with all sockets in the backlog.
+    fn accept_with(&self, cx: Option<&mut Context>) -> Result<Option<TcpStream>> {
+        let mut backlog = self.backlog.lock();
+        let accepted = self.interface.with_sockets(|sockets| -> Result<_> {
+            for handle in backlog.iter_mut() {
+                let socket = sockets.get_mut::<tcp::Socket>(*handle);
+                match socket.state() {
+                    tcp::State::Listen | tcp::State::SynReceived => {}
+                    // The peer aborted the connection before it was accepted.
+                    tcp::State::Closed => socket.listen(self.port)?,
+                    _ => {
+                        let connected = *handle;
+                        *handle = listening_socket(sockets, self.port, &self.options)?;
+                        return Ok(Some(connected));
+                    }
+                }
+            }
+            if let Some(cx) = cx {
+                for handle in backlog.iter() {
+                    sockets
+                        .get_mut::<tcp::Socket>(*handle)
+                        .register_recv_waker(cx.waker());
+                }
+            }
+            Ok(None)
+        })?;
+        Ok(accepted.map(|handle| TcpStream::new(self.interface.clone(), handle)))
+    }
+}
+
+impl Drop for TcpListener {
+    fn drop(&mut self) {
+        // Connections in the accept queue are closed, and all sockets are
+        // removed from the interface once they're closed.
+        for handle in self.backlog.lock().drain(..) {
+            self.interface.close_tcp_socket(handle);
+        }
+    }
+}
+
+/// Adds a socket listening on the given `port` to the set of `sockets`.
+fn listening_socket(
+    sockets: &mut SocketSet<'static>,
+    port: u16,
+    options: &TcpOptions,
+) -> Result<SocketHandle> {
+    let mut socket = options.new_socket();
+    socket.listen(port)?;
+    Ok(sockets.add(socket))
+}
@@ -0,0 +1,188 @@
+use crate::{Error, NetworkInterface, Result};
+use alloc::{sync::Arc, vec};
+use core::{
+    future::poll_fn,
+    task::{Context, Poll},
+};
+use log::warn;
+use smoltcp::{iface::SocketHandle, socket::tcp, time::Duration};
+
+/// The default size of a TCP socket's receive and transmit buffers.
+pub const DEFAULT_BUFFER_SIZE: usize = 8192;
+
+/// Options for the TCP sockets created by a [`TcpListener`].
+///
+/// [`TcpListener`]: crate::TcpListener
+#[derive(Clone, Copy, Debug)]
+pub struct TcpOptions {
+    /// The size of the receive buffer, which bounds the receive window
+    /// advertised to the peer.
+    pub rx_buffer_size: usize,
+    /// The size of the transmit buffer. Writes wait for space in this buffer.
+    pub tx_buffer_size: usize,
+    /// The interval at which keepalive probes are sent on an idle connection,
+    /// or `None` to disable keepalive.
+    pub keep_alive: Option<Duration>,
+    /// How long the peer may go without acknowledging sent data before the
+    /// connection is aborted, or `None` to wait forever.
+    pub timeout: Option<Duration>,
+}
+
+impl Default for TcpOptions {
+    fn default() -> Self {
+        Self {
+            rx_buffer_size: DEFAULT_BUFFER_SIZE,
+            tx_buffer_size: DEFAULT_BUFFER_SIZE,
+            keep_alive: None,
+            timeout: None,
+        }
+    }
+}
+
+impl TcpOptions {
+    pub(crate) fn new_socket(&self) -> tcp::Socket<'static> {
+        let mut socket = tcp::Socket::new(
+            tcp::SocketBuffer::new(vec![0; self.rx_buffer_size]),
+            tcp::SocketBuffer::new(vec![0; self.tx_buffer_size]),
+        );
+        socket.set_keep_alive(self.keep_alive);
+        socket.set_timeout(self.timeout);
+        socket
+    }
+}
+
+/// A connected TCP stream.
+///
+/// Reads wait for data to arrive and writes wait for space in the transmit
+/// buffer, such that a task is only woken once it can make progress. This
+/// propagates backpressure from the peer to the async executor.
+///
+/// Dropping the stream closes the connection.
+pub struct TcpStream {
+    interface: Arc<NetworkInterface>,
+    handle: SocketHandle,
+}
+
+impl TcpStream {
+    pub(crate) fn new(interface: Arc<NetworkInterface>, handle: SocketHandle) -> Self {
+        Self { interface, handle }
+    }
+
+    /// Returns the state of the connection.
+    pub fn state(&self) -> tcp::State {
+        self.with_socket(|socket| socket.state())
+    }
+
+    /// Sets the keepalive interval of the connection. See
+    /// [`TcpOptions::keep_alive`].
+    pub fn set_keep_alive(&self, interval: Option<Duration>) {
+        self.with_socket(|socket| socket.set_keep_alive(interval));
+    }
+
+    /// Reads data into `buf`, returning the number of bytes read.
+    ///
+    /// Returns zero once the peer has closed the connection and all of its
+    /// data has been read.
+    pub async fn read(&self, buf: &mut [u8]) -> Result<usize> {
+        poll_fn(|cx| self.poll_read(cx, buf)).await
+    }
+
+    /// Attempts to read data into `buf`, registering the current task to be
+    /// woken once data arrives if there is none.
+    pub fn poll_read(&self, cx: &mut Context, buf: &mut [u8]) -> Poll<Result<usize>> {
+        let result = self.with_socket(|socket| {
+            if socket.can_recv() {
+                Poll::Ready(socket.recv_slice(buf).map_err(Error::from))
+            } else if !socket.may_recv() {
+                Poll::Ready(Ok(0))
+            } else {
+                socket.register_recv_waker(cx.waker());
+                Poll::Pending
+            }
+        });
+        if let Poll::Ready(Ok(len)) = result {
+            if len > 0 {
+                // Advertise the newly-freed receive window to the peer.
+                self.poll_interface();
+            }
+        }
+        result
+    }
+
+    /// Writes data from `buf`, returning the number of bytes written.
+    ///
+    /// Waits until there is space in the transmit buffer, but may write fewer
+    /// bytes than requested.
+    pub async fn write(&self, buf: &[u8]) -> Result<usize> {
+        poll_fn(|cx| self.poll_write(cx, buf)).await
+    }
+
+    /// Writes all of `buf`, waiting for space in the transmit buffer as
+    /// necessary.
+    pub async fn write_all(&self, mut buf: &[u8]) -> Result<()> {
+        while !buf.is_empty() {
+            let len = self.write(buf).await?;
+            buf = &buf[len..];
+        }
+        Ok(())
+    }
+
+    /// Attempts to write data from `buf`, registering the current task to be
+    /// woken once there is space in the transmit buffer if it is full.
+    pub fn poll_write(&self, cx: &mut Context, buf: &[u8]) -> Poll<Result<usize>> {
+        let result = self.with_socket(|socket| {
+            if !socket.may_send() {
+                Poll::Ready(Err(Error::Illegal))
+            } else if socket.can_send() {
+                Poll::Ready(socket.send_slice(buf).map_err(Error::from))
+            } else {
+                socket.register_send_waker(cx.waker());
+                Poll::Pending
+            }
+        });
+        if let Poll::Ready(Ok(_)) = result {
+            self.poll_interface();
+        }
+        result
+    }
+
+    /// Waits until all written data has been acknowledged by the peer.
+    pub async fn flush(&self) -> Result<()> {
+        poll_fn(|cx| {
+            self.with_socket(|socket| {
+                if socket.send_queue() == 0 {
+                    Poll::Ready(Ok(()))
+                } else if !socket.may_send() {
+                    Poll::Ready(Err(Error::Illegal))
+                } else {
+                    socket.register_send_waker(cx.waker());
+                    Poll::Pending
+                }
+            })
+        })
+        .await
+    }
+
+    fn with_socket<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce(&mut tcp::Socket<'static>) -> R,
+    {
+        self.interface
+            .with_sockets(|sockets| f(sockets.get_mut::<tcp::Socket>(self.handle)))
+    }
+
+    /// Polls the interface, such that queued data is sent without waiting for
+    /// the next packet to arrive.
+    fn poll_interface(&self) {
+        if let Err(e) = self.interface.poll() {
+            warn!("TcpStream: failed to poll interface: {e}");
+        }
+    }
+}
+
+impl Drop for TcpStream {
+    fn drop(&mut self) {
+        self.interface.close_tcp_socket(self.handle);
+        self.poll_interface();
+    }
+}
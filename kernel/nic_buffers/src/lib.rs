@@ -135,6 +135,14 @@ impl ReceiveBuffer {
             Ok(())
         }
     }
+
+    /// Resets the buffer's length to the full size of its underlying memory, up to `u16::MAX` bytes.
+    ///
+    /// This is needed before giving a buffer that was returned to its pool back to the NIC,
+    /// because a returned buffer has a length of zero.
+    pub fn reset_length(&mut self) {
+        self.length = core::cmp::min(self.mp.size_in_bytes(), u16::MAX as usize) as u16;
+    }
 }
 
 impl Deref for ReceiveBuffer {
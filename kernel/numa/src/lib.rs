@@ -0,0 +1,118 @@
+//! The NUMA (Non-Uniform Memory Access) topology of the system,
+//! i.e., which memory node each CPU and each range of physical memory belongs to.
+//!
+//! The topology is provided by the ACPI SRAT via [`init()`], which also registers
+//! a [`NodeAwareAllocator`] with `tls_initializer` so that TLS data images
+//! are placed in memory that is local to the CPU their task runs on.
+//! On machines without a SRAT, no topology is known and nothing is registered,
+//! so TLS data images are allocated from the regular heap.
+
+#![no_std]
+
+extern crate alloc;
+
+use alloc::{collections::BTreeMap, vec::Vec};
+use core::{alloc::Layout, ptr::NonNull};
+use irq_safety::MutexIrqSafe;
+use log::{info, warn};
+use memory::{
+    allocate_frames_in_range, allocate_pages, get_kernel_mmi_ref,
+    Frame, FrameRange, MappedPages, PhysicalAddress, PteFlags, PAGE_SIZE,
+};
+use spin::Once;
+use tls_initializer::NodeAwareAllocator;
+
+/// The NUMA topology, which is set once by [`init()`].
+static TOPOLOGY: Once<Topology> = Once::new();
+
+/// The allocator registered with `tls_initializer`, which places memory on a specific node.
+static NODE_ALLOCATOR: NodeAllocator = NodeAllocator { mappings: MutexIrqSafe::new(BTreeMap::new()) };
+
+struct Topology {
+    /// The node of each CPU, indexed by CPU (APIC) ID.
+    cpu_nodes: BTreeMap<u8, usize>,
+    /// The node of each range of physical memory.
+    memory_ranges: Vec<(usize, FrameRange)>,
+}
+
+/// Sets the NUMA topology of the system and registers the node-aware allocator for TLS data images.
+///
+/// # Arguments
+/// * `cpus`: the node of each CPU, given as `(cpu_id, node)` pairs.
+/// * `memory_ranges`: the node of each range of physical memory, given as `(node, start, length_in_bytes)`.
+///
+/// This can only be invoked once.
+pub fn init<C, M>(cpus: C, memory_ranges: M) -> Result<(), &'static str>
+where
+    C: IntoIterator<Item = (u8, usize)>,
+    M: IntoIterator<Item = (usize, PhysicalAddress, usize)>,
+{
+    if TOPOLOGY.is_completed() {
+        return Err("the NUMA topology was already initialized");
+    }
+    let cpu_nodes: BTreeMap<u8, usize> = cpus.into_iter().collect();
+    let memory_ranges: Vec<(usize, FrameRange)> = memory_ranges.into_iter()
+        .filter(|&(_, _, len)| len > 0)
+        .map(|(node, start, len)| (
+            node,
+            FrameRange::new(Frame::containing_address(start), Frame::containing_address(start + (len - 1))),
+        ))
+        .collect();
+    if cpu_nodes.is_empty() || memory_ranges.is_empty() {
+        warn!("NUMA topology didn't describe any CPUs or memory, ignoring it.");
+        return Ok(());
+    }
+    info!("NUMA topology: CPU nodes: {:?}, memory ranges: {:?}", cpu_nodes, memory_ranges);
+    TOPOLOGY.call_once(|| Topology { cpu_nodes, memory_ranges });
+    tls_initializer::set_node_aware_allocator(&NODE_ALLOCATOR)
+}
+
+/// Returns the memory node that is local to the CPU with the given ID,
+/// or `None` if the NUMA topology is unknown.
+pub fn node_of_cpu(cpu: u8) -> Option<usize> {
+    TOPOLOGY.get()?.cpu_nodes.get(&cpu).copied()
+}
+
+/// Allocates whole frames from a node's memory ranges and maps them into the kernel's address space.
+///
+/// Because it allocates at page granularity, this is intended for long-lived allocations
+/// such as TLS data images, not for general-purpose heap allocations.
+struct NodeAllocator {
+    /// The mappings that back each live allocation, keyed by their starting virtual address.
+    mappings: MutexIrqSafe<BTreeMap<usize, MappedPages>>,
+}
+
+impl NodeAwareAllocator for NodeAllocator {
+    fn node_of_cpu(&self, cpu: u8) -> Option<usize> {
+        node_of_cpu(cpu)
+    }
+
+    fn allocate_on_node(&self, layout: Layout, node: usize) -> Option<NonNull<u8>> {
+        // Mappings are page-aligned, so they cannot satisfy larger alignments.
+        if layout.align() > PAGE_SIZE {
+            return None;
+        }
+        let num_frames = (layout.size().max(1) + PAGE_SIZE - 1) / PAGE_SIZE;
+        let frames = TOPOLOGY.get()?.memory_ranges.iter()
+            .filter(|(range_node, _)| *range_node == node)
+            .find_map(|(_, range)| allocate_frames_in_range(num_frames, range))?;
+        let pages = allocate_pages(num_frames)?;
+        let kernel_mmi_ref = get_kernel_mmi_ref()?;
+        // We may be invoked while the kernel's page table is locked,
+        // in which case the caller falls back to the regular heap rather than deadlocking.
+        let mut kernel_mmi = kernel_mmi_ref.try_lock()?;
+        let mapping = kernel_mmi.page_table
+            .map_allocated_pages_to(pages, frames, PteFlags::new().valid(true).writable(true))
+            .ok()?;
+        drop(kernel_mmi);
+        let ptr = NonNull::new(mapping.start_address().value() as *mut u8)?;
+        self.mappings.lock().insert(ptr.as_ptr() as usize, mapping);
+        Some(ptr)
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, _layout: Layout, _node: usize) {
+        // Dropping the mapping unmaps it and frees its pages and frames.
+        let mapping = self.mappings.lock().remove(&(ptr.as_ptr() as usize));
+        drop(mapping);
+    }
+}
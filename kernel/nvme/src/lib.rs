@@ -0,0 +1,624 @@
+//! A driver for NVMe (Non-Volatile Memory Express) storage controllers attached via PCIe.
+//!
+//! The first namespace of the controller is exposed as an [`NvmeDrive`], which implements both
+//! the blocking [`StorageDevice`] traits and the asynchronous [`BlockDevice`] trait.
+//! Admin commands are only issued during initialization and are polled for,
+//! whereas I/O commands are submitted on a single I/O queue pair
+//! and completed by its MSI-X interrupt handler.
+//! If an IOMMU is present, the controller is isolated in its own DMA domain,
+//! such that it can only access its queues and the buffers of commands that are in flight.
+//!
+//! Only a single NVMe controller is currently supported.
+//!
+//! See the [NVMe 1.4 specification](https://nvmexpress.org/specifications/).
+
+#![no_std]
+#![feature(abi_x86_interrupt)]
+
+extern crate alloc;
+
+mod queue;
+mod regs;
+
+use alloc::{boxed::Box, sync::Arc, vec, vec::Vec};
+use core::iter;
+use interrupts::eoi;
+use iommu::DmaDomain;
+use io::{BlockIo, BlockReader, BlockWriter, IoError, KnownLength};
+use irq_safety::MutexIrqSafe;
+use log::{debug, error, info};
+use memory::{
+    allocate_frames_by_bytes_at, allocate_pages_by_bytes, create_contiguous_mapping,
+    get_kernel_mmi_ref, MappedPages, PhysicalAddress, PteFlags,
+};
+use msi::{MsiVector, MsixTable};
+use pci::PciDevice;
+use queue::{Command, CompletionQueue, SubmissionQueue};
+use regs::*;
+use spin::{Mutex, Once};
+use storage_device::{
+    new_block_request, BlockCompleter, BlockDevice, BlockRequest, BlockResult, StorageController,
+    StorageDevice, StorageDeviceRef,
+};
+use volatile::Volatile;
+use x86_64::structures::idt::InterruptStackFrame;
+
+/// The PCI class, subclass, and programming interface of an NVMe controller.
+pub const NVME_CLASS:    u8 = 0x01;
+pub const NVME_SUBCLASS: u8 = 0x08;
+pub const NVME_PROG_IF:  u8 = 0x02;
+
+/// The mapping flags used to map NVMe device memory,
+/// e.g., MMIO registers, queues, and DMA buffers.
+const NVME_MAPPING_FLAGS: PteFlags = PteFlags::from_bits_truncate(
+    PteFlags::new().bits()
+    | PteFlags::VALID.bits()
+    | PteFlags::WRITABLE.bits()
+    | PteFlags::DEVICE_MEMORY.bits()
+);
+
+/// The memory page size used by the controller, which is its minimum.
+const NVME_PAGE_SIZE: usize = 4096;
+/// The number of entries in the admin submission and completion queues.
+const ADMIN_QUEUE_SIZE: u16 = 32;
+/// The maximum number of entries in the I/O submission and completion queues.
+const IO_QUEUE_SIZE: u16 = 64;
+/// The ID of the single I/O queue pair.
+const IO_QUEUE_ID: u16 = 1;
+/// The maximum number of bytes transferred by a single request,
+/// such that a request's PRP list fits in a single page.
+const MAX_TRANSFER_SIZE: usize = 64 * 1024;
+/// The number of times the controller status is polled while waiting for it to become (not) ready.
+const READY_TIMEOUT_POLLS: usize = 10_000_000;
+
+/// Admin command opcodes.
+const ADMIN_CREATE_IO_SQ: u8 = 0x01;
+const ADMIN_CREATE_IO_CQ: u8 = 0x05;
+const ADMIN_IDENTIFY:     u8 = 0x06;
+/// NVM command opcodes.
+const NVM_FLUSH:          u8 = 0x00;
+const NVM_WRITE:          u8 = 0x01;
+const NVM_READ:           u8 = 0x02;
+const NVM_DATASET_MGMT:   u8 = 0x09;
+
+/// The Controller or Namespace Structure (CNS) values for the Identify command.
+const CNS_NAMESPACE:  u32 = 0x00;
+const CNS_CONTROLLER: u32 = 0x01;
+
+/// The state of the single NVMe controller, which is shared with its interrupt handler.
+static NVME_CONTROLLER: Once<MutexIrqSafe<Controller>> = Once::new();
+
+/// A command that has been submitted to the I/O queue but not yet completed.
+struct InFlight {
+    opcode: u8,
+    /// The bounce buffer that the controller transfers data to or from.
+    dma_buffer: Option<(MappedPages, PhysicalAddress)>,
+    /// The page holding the PRP list or the dataset management range, if any.
+    dma_list: Option<(MappedPages, PhysicalAddress)>,
+    /// The buffer returned to the requester upon completion.
+    buffer: Vec<u8>,
+    completer: BlockCompleter,
+}
+
+/// The registers and queues of an NVMe controller.
+struct Controller {
+    regs: MappedPages,
+    /// The stride between doorbell registers in bytes.
+    doorbell_stride: usize,
+    admin_sq: SubmissionQueue,
+    admin_cq: CompletionQueue,
+    io_sq: SubmissionQueue,
+    io_cq: CompletionQueue,
+    /// Indexed by command ID.
+    in_flight: Vec<Option<InFlight>>,
+    /// The DMA buffers of completed commands, which are unmapped outside of interrupt context.
+    retired: Vec<MappedPages>,
+    interrupt_num: u8,
+    /// The DMA domain that isolates the controller, if there is an IOMMU.
+    dma_domain: Option<DmaDomain>,
+}
+
+impl Controller {
+    /// Writes to the doorbell of the given queue.
+    fn ring_doorbell(&mut self, queue_id: u16, completion: bool, value: u16) {
+        let offset = DOORBELL_BASE + (2 * queue_id as usize + completion as usize) * self.doorbell_stride;
+        self.regs.as_type_mut::<Volatile<u32>>(offset)
+            .expect("BUG: NVMe doorbell wasn't mapped")
+            .write(value as u32);
+    }
+
+    /// Submits an admin command and polls for its completion, returning its first dword.
+    fn admin_command(&mut self, command: Command) -> Result<u32, &'static str> {
+        let tail = self.admin_sq.push(command).ok_or("nvme: admin submission queue is full")?;
+        self.ring_doorbell(0, false, tail);
+        let completion = loop {
+            if let Some(completion) = self.admin_cq.pop() {
+                break completion;
+            }
+            core::hint::spin_loop();
+        };
+        let head = self.admin_cq.head();
+        self.ring_doorbell(0, true, head);
+        self.admin_sq.set_head(completion.sq_head);
+        if completion.status_code() != 0 {
+            error!("nvme: admin command {:#X} failed with status {:#X}", command.opcode(), completion.status_code());
+            return Err("nvme: admin command failed");
+        }
+        Ok(completion.dw0)
+    }
+
+    /// Submits an I/O command for the given data transfer, returning a request that completes with `buffer`.
+    fn submit(
+        &mut self,
+        mut command: Command,
+        buffer: Vec<u8>,
+        dma_buffer: Option<(MappedPages, PhysicalAddress)>,
+        dma_list: Option<(MappedPages, PhysicalAddress)>,
+    ) -> Result<BlockRequest, IoError> {
+        self.retired.clear();
+        let command_id = self.in_flight.iter().position(Option::is_none)
+            .ok_or(IoError::Other("nvme: too many commands in flight"))?;
+        command.set_command_id(command_id as u16);
+
+        if let Some((_, phys_addr)) = dma_buffer {
+            command.prp1 = phys_addr.value() as u64;
+            if let Some((_, list_phys_addr)) = dma_list.as_ref().filter(|_| command.opcode() != NVM_DATASET_MGMT) {
+                command.prp2 = list_phys_addr.value() as u64;
+            } else if buffer.len() > NVME_PAGE_SIZE {
+                command.prp2 = (phys_addr + NVME_PAGE_SIZE).value() as u64;
+            }
+        } else if let Some((_, range_phys_addr)) = dma_list.as_ref() {
+            command.prp1 = range_phys_addr.value() as u64;
+        }
+
+        // The controller may only access the command's buffers while it's in flight.
+        self.grant_dma(dma_buffer.as_ref(), dma_list.as_ref(), command.opcode() == NVM_READ)?;
+        let Some(tail) = self.io_sq.push(command) else {
+            self.revoke_dma(dma_buffer.as_ref(), dma_list.as_ref());
+            return Err(IoError::Other("nvme: I/O submission queue is full"));
+        };
+        let (request, completer) = new_block_request();
+        self.in_flight[command_id] = Some(InFlight {
+            opcode: command.opcode(),
+            dma_buffer,
+            dma_list,
+            buffer,
+            completer,
+        });
+        self.ring_doorbell(IO_QUEUE_ID, false, tail);
+        Ok(request)
+    }
+
+    /// Completes all I/O commands that the controller has finished.
+    fn handle_completions(&mut self) {
+        let mut handled = false;
+        while let Some(completion) = self.io_cq.pop() {
+            handled = true;
+            self.io_sq.set_head(completion.sq_head);
+            let Some(in_flight) = self.in_flight.get_mut(completion.command_id as usize).and_then(Option::take) else {
+                error!("nvme: controller completed unknown command {}", completion.command_id);
+                continue;
+            };
+            self.revoke_dma(in_flight.dma_buffer.as_ref(), in_flight.dma_list.as_ref());
+            let result = if completion.status_code() == 0 {
+                Self::finish(in_flight.opcode, in_flight.dma_buffer.as_ref().map(|(mp, _)| mp), in_flight.buffer)
+            } else {
+                error!("nvme: I/O command {:#X} failed with status {:#X}", in_flight.opcode, completion.status_code());
+                Err(IoError::Other("nvme: controller reported an I/O error"))
+            };
+            in_flight.completer.complete(result);
+            // These never reallocate, as there can't be more retired buffers than commands in flight.
+            self.retired.extend(in_flight.dma_buffer.map(|(mp, _)| mp));
+            self.retired.extend(in_flight.dma_list.map(|(mp, _)| mp));
+        }
+        if handled {
+            let head = self.io_cq.head();
+            self.ring_doorbell(IO_QUEUE_ID, true, head);
+        }
+    }
+
+    /// Allows the controller to access the given buffers of a command, if it's isolated by the IOMMU.
+    ///
+    /// The controller may only write to the data buffer if `writable` is `true`.
+    fn grant_dma(
+        &self,
+        dma_buffer: Option<&(MappedPages, PhysicalAddress)>,
+        dma_list: Option<&(MappedPages, PhysicalAddress)>,
+        writable: bool,
+    ) -> Result<(), &'static str> {
+        let Some(domain) = self.dma_domain else { return Ok(()) };
+        if let Some((mp, phys_addr)) = dma_buffer {
+            domain.grant(*phys_addr, mp.size_in_bytes(), writable)?;
+        }
+        if let Some((mp, phys_addr)) = dma_list {
+            if let Err(e) = domain.grant(*phys_addr, mp.size_in_bytes(), false) {
+                self.revoke_dma(dma_buffer, None);
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Revokes the controller's access to the given buffers of a command.
+    fn revoke_dma(&self, dma_buffer: Option<&(MappedPages, PhysicalAddress)>, dma_list: Option<&(MappedPages, PhysicalAddress)>) {
+        let Some(domain) = self.dma_domain else { return };
+        for (mp, phys_addr) in dma_buffer.into_iter().chain(dma_list) {
+            if let Err(e) = domain.revoke(*phys_addr, mp.size_in_bytes()) {
+                error!("nvme: couldn't revoke the controller's access to {:#X}: {}", phys_addr.value(), e);
+            }
+        }
+    }
+
+    fn finish(opcode: u8, dma_buffer: Option<&MappedPages>, mut buffer: Vec<u8>) -> BlockResult {
+        if let (NVM_READ, Some(mp)) = (opcode, dma_buffer) {
+            let len = buffer.len();
+            buffer.copy_from_slice(mp.as_slice(0, len)?);
+        }
+        Ok(buffer)
+    }
+}
+
+/// Maps the given physical memory region of the controller, e.g., its registers.
+fn map_device_memory(mem_base: PhysicalAddress, mem_size_in_bytes: usize) -> Result<MappedPages, &'static str> {
+    let pages = allocate_pages_by_bytes(mem_size_in_bytes)
+        .ok_or("nvme: couldn't allocate virtual pages for device memory")?;
+    let frames = allocate_frames_by_bytes_at(mem_base, mem_size_in_bytes)
+        .map_err(|_e| "nvme: couldn't allocate physical frames for device memory")?;
+    let kernel_mmi_ref = get_kernel_mmi_ref().ok_or("nvme: KERNEL_MMI was not yet initialized!")?;
+    kernel_mmi_ref.lock().page_table.map_allocated_pages_to(pages, frames, NVME_MAPPING_FLAGS)
+}
+
+/// Waits until the controller's ready status matches `ready`.
+fn wait_for_ready(regs: &mut NvmeRegisters, ready: bool) -> Result<(), &'static str> {
+    for _ in 0..READY_TIMEOUT_POLLS {
+        if regs.csts.read() & CSTS_FATAL != 0 {
+            return Err("nvme: controller reported a fatal status");
+        }
+        if (regs.csts.read() & CSTS_READY != 0) == ready {
+            return Ok(());
+        }
+        core::hint::spin_loop();
+    }
+    Err("nvme: timed out waiting for controller to change its ready status")
+}
+
+/// A namespace of an NVMe controller.
+pub struct NvmeDrive {
+    controller: &'static MutexIrqSafe<Controller>,
+    namespace_id: u32,
+    block_size: usize,
+    num_blocks: usize,
+    max_blocks_per_request: usize,
+    supports_discard: bool,
+    has_volatile_write_cache: bool,
+}
+
+impl NvmeDrive {
+    /// Initializes the NVMe controller that is connected as the given PciDevice,
+    /// and returns a drive for its first namespace.
+    pub fn init(nvme_pci_dev: &PciDevice) -> Result<NvmeDrive, &'static str> {
+        if NVME_CONTROLLER.get().is_some() {
+            return Err("nvme: only a single NVMe controller is currently supported");
+        }
+
+        let mem_base = nvme_pci_dev.determine_mem_base(0)?;
+        let mem_size = nvme_pci_dev.determine_mem_size(0) as usize;
+        let mut regs_mp = map_device_memory(mem_base, mem_size)?;
+
+        // set the bus mastering bit for this PciDevice, which allows it to use DMA
+        nvme_pci_dev.pci_set_command_bus_master_bit();
+        nvme_pci_dev.pci_set_interrupt_disable_bit();
+
+        let regs: &mut NvmeRegisters = regs_mp.as_type_mut(0)?;
+        let cap = regs.cap.read();
+        let doorbell_stride = 4 << ((cap >> CAP_DSTRD_SHIFT) & 0xF);
+        let max_queue_entries = ((cap & CAP_MQES_MASK) + 1) as u16;
+        if (cap >> CAP_MPSMIN_SHIFT) & 0xF != 0 {
+            return Err("nvme: controller doesn't support 4 KiB memory pages");
+        }
+        debug!("nvme: version {:#X}, CAP {:#X}", regs.vs.read(), cap);
+
+        // Disable the controller before configuring its admin queues.
+        regs.cc.write(regs.cc.read() & !CC_ENABLE);
+        wait_for_ready(regs, false)?;
+
+        // Isolate the controller before giving it any memory to access.
+        let dma_domain = if iommu::iommu_present() {
+            Some(iommu::create_domain(nvme_pci_dev.location)?)
+        } else {
+            None
+        };
+
+        let admin_sq = SubmissionQueue::new(ADMIN_QUEUE_SIZE)?;
+        let admin_cq = CompletionQueue::new(ADMIN_QUEUE_SIZE)?;
+        let io_queue_size = core::cmp::min(IO_QUEUE_SIZE, max_queue_entries);
+        let io_sq = SubmissionQueue::new(io_queue_size)?;
+        let io_cq = CompletionQueue::new(io_queue_size)?;
+        if let Some(domain) = dma_domain {
+            domain.grant(admin_sq.phys_addr(), admin_sq.size_in_bytes(), false)?;
+            domain.grant(admin_cq.phys_addr(), admin_cq.size_in_bytes(), true)?;
+            domain.grant(io_sq.phys_addr(), io_sq.size_in_bytes(), false)?;
+            domain.grant(io_cq.phys_addr(), io_cq.size_in_bytes(), true)?;
+        }
+        regs.aqa.write(((ADMIN_QUEUE_SIZE as u32 - 1) << 16) | (ADMIN_QUEUE_SIZE as u32 - 1));
+        regs.asq.write(admin_sq.phys_addr().value() as u64);
+        regs.acq.write(admin_cq.phys_addr().value() as u64);
+        regs.cc.write(CC_ENABLE | CC_CSS_NVM | CC_MPS_4K | CC_AMS_ROUND_ROBIN | CC_IOSQES | CC_IOCQES);
+        wait_for_ready(regs, true)?;
+
+        // All commands complete on MSI-X vector 0, which is unmasked once the I/O queues exist.
+        let msix_table = MsixTable::map(nvme_pci_dev)?;
+        msix_table.enable();
+
+        let mut controller = Controller {
+            regs: regs_mp,
+            doorbell_stride,
+            admin_sq,
+            admin_cq,
+            io_sq,
+            io_cq,
+            // The submission queue is full when it holds one less than its size.
+            in_flight: (1..io_queue_size).map(|_| None).collect(),
+            retired: Vec::with_capacity(2 * io_queue_size as usize),
+            interrupt_num: 0,
+            dma_domain,
+        };
+
+        let (identify_mp, identify_phys_addr) = create_contiguous_mapping(NVME_PAGE_SIZE, NVME_MAPPING_FLAGS)?;
+        if let Some(domain) = dma_domain {
+            domain.grant(identify_phys_addr, NVME_PAGE_SIZE, true)?;
+        }
+
+        let mut identify = Command::new(ADMIN_IDENTIFY);
+        identify.prp1 = identify_phys_addr.value() as u64;
+        identify.cdw10 = CNS_CONTROLLER;
+        controller.admin_command(identify)?;
+        let identify_data: &[u8] = identify_mp.as_slice(0, NVME_PAGE_SIZE)?;
+        let mdts = identify_data[77];
+        let oncs = u16::from_le_bytes([identify_data[520], identify_data[521]]);
+        let vwc = identify_data[525];
+        let max_transfer_size = match mdts {
+            0 => MAX_TRANSFER_SIZE,
+            mdts => core::cmp::min(MAX_TRANSFER_SIZE, NVME_PAGE_SIZE << mdts),
+        };
+
+        let namespace_id = 1;
+        let mut identify = Command::new(ADMIN_IDENTIFY);
+        identify.nsid = namespace_id;
+        identify.prp1 = identify_phys_addr.value() as u64;
+        identify.cdw10 = CNS_NAMESPACE;
+        controller.admin_command(identify)?;
+        if let Some(domain) = dma_domain {
+            domain.revoke(identify_phys_addr, NVME_PAGE_SIZE)?;
+        }
+        let identify_data: &[u8] = identify_mp.as_slice(0, NVME_PAGE_SIZE)?;
+        let mut nsze = [0; 8];
+        nsze.copy_from_slice(&identify_data[0..8]);
+        let num_blocks = u64::from_le_bytes(nsze) as usize;
+        let lba_format = (identify_data[26] & 0xF) as usize;
+        let lba_data_size = identify_data[128 + 4 * lba_format + 2];
+        if !(9..=12).contains(&lba_data_size) {
+            return Err("nvme: namespace has an unsupported block size");
+        }
+        let block_size = 1 << lba_data_size;
+        if num_blocks == 0 {
+            return Err("nvme: namespace 1 is not active");
+        }
+
+        let msix_vector = MsiVector::Msix(Arc::new(MutexIrqSafe::new(msix_table)), 0);
+        let interrupt_num = msi::allocate(msix_vector, nvme_handler, Some(cpu::current_cpu()))?;
+        controller.interrupt_num = interrupt_num;
+
+        let mut create_cq = Command::new(ADMIN_CREATE_IO_CQ);
+        create_cq.prp1 = controller.io_cq.phys_addr().value() as u64;
+        create_cq.cdw10 = ((io_queue_size as u32 - 1) << 16) | IO_QUEUE_ID as u32;
+        // Interrupt vector 0, interrupts enabled, physically contiguous.
+        create_cq.cdw11 = 0b11;
+        controller.admin_command(create_cq)?;
+
+        let mut create_sq = Command::new(ADMIN_CREATE_IO_SQ);
+        create_sq.prp1 = controller.io_sq.phys_addr().value() as u64;
+        create_sq.cdw10 = ((io_queue_size as u32 - 1) << 16) | IO_QUEUE_ID as u32;
+        // Completion queue ID, physically contiguous.
+        create_sq.cdw11 = ((IO_QUEUE_ID as u32) << 16) | 0b1;
+        controller.admin_command(create_sq)?;
+
+        let controller = NVME_CONTROLLER.call_once(|| MutexIrqSafe::new(controller));
+        msi::unmask(interrupt_num)?;
+
+        info!("nvme: initialized namespace {} with {} blocks of {} bytes", namespace_id, num_blocks, block_size);
+        Ok(NvmeDrive {
+            controller,
+            namespace_id,
+            block_size,
+            num_blocks,
+            max_blocks_per_request: max_transfer_size / block_size,
+            supports_discard: oncs & ONCS_DATASET_MGMT != 0,
+            has_volatile_write_cache: vwc & 0x1 != 0,
+        })
+    }
+
+    fn check_bounds(&self, block_offset: usize, num_blocks: usize) -> Result<(), IoError> {
+        match block_offset.checked_add(num_blocks) {
+            Some(end) if end <= self.num_blocks => Ok(()),
+            _ => Err(IoError::InvalidInput),
+        }
+    }
+
+    /// Submits a read or write of the given buffer, which must hold whole blocks.
+    fn submit_transfer(&mut self, opcode: u8, buffer: Vec<u8>, block_offset: usize) -> Result<BlockRequest, IoError> {
+        let num_blocks = buffer.len() / self.block_size;
+        if buffer.len() % self.block_size != 0 || num_blocks == 0 || num_blocks > self.max_blocks_per_request {
+            return Err(IoError::InvalidInput);
+        }
+        self.check_bounds(block_offset, num_blocks)?;
+
+        let (mut dma_buffer, phys_addr) = create_contiguous_mapping(buffer.len(), NVME_MAPPING_FLAGS)?;
+        if opcode == NVM_WRITE {
+            dma_buffer.as_slice_mut(0, buffer.len())?.copy_from_slice(&buffer);
+        }
+        // Transfers that span more than two pages describe the pages after the first in a PRP list.
+        let num_pages = (buffer.len() + NVME_PAGE_SIZE - 1) / NVME_PAGE_SIZE;
+        let prp_list = if num_pages > 2 {
+            let (mut list, list_phys_addr) = create_contiguous_mapping(NVME_PAGE_SIZE, NVME_MAPPING_FLAGS)?;
+            let entries: &mut [u64] = list.as_slice_mut(0, num_pages - 1)?;
+            for (i, entry) in entries.iter_mut().enumerate() {
+                *entry = (phys_addr + (i + 1) * NVME_PAGE_SIZE).value() as u64;
+            }
+            Some((list, list_phys_addr))
+        } else {
+            None
+        };
+
+        let mut command = Command::new(opcode);
+        command.nsid = self.namespace_id;
+        command.cdw10 = block_offset as u32;
+        command.cdw11 = (block_offset as u64 >> 32) as u32;
+        command.cdw12 = num_blocks as u32 - 1;
+        self.controller.lock().submit(command, buffer, Some((dma_buffer, phys_addr)), prp_list)
+    }
+
+    /// Waits for the given request to complete.
+    ///
+    /// If the current task cannot block, e.g., during early initialization,
+    /// this polls the I/O completion queue instead of waiting for an interrupt.
+    fn wait(&self, mut request: BlockRequest) -> BlockResult {
+        if io_wait::can_block() {
+            return request.wait();
+        }
+        loop {
+            self.controller.lock().handle_completions();
+            if let Some(result) = request.try_take() {
+                return result;
+            }
+            core::hint::spin_loop();
+        }
+    }
+}
+
+impl BlockDevice for NvmeDrive {
+    fn max_blocks_per_request(&self) -> usize {
+        self.max_blocks_per_request
+    }
+
+    fn submit_read(&mut self, block_offset: usize, num_blocks: usize) -> Result<BlockRequest, IoError> {
+        let buffer = vec![0; num_blocks * self.block_size];
+        self.submit_transfer(NVM_READ, buffer, block_offset)
+    }
+
+    fn submit_write(&mut self, buffer: Vec<u8>, block_offset: usize) -> Result<BlockRequest, IoError> {
+        self.submit_transfer(NVM_WRITE, buffer, block_offset)
+    }
+
+    fn submit_flush(&mut self) -> Result<BlockRequest, IoError> {
+        // Without a volatile write cache, all writes are already persistent.
+        if !self.has_volatile_write_cache {
+            return Ok(BlockRequest::completed(Ok(Vec::new())));
+        }
+        let mut command = Command::new(NVM_FLUSH);
+        command.nsid = self.namespace_id;
+        self.controller.lock().submit(command, Vec::new(), None, None)
+    }
+
+    fn submit_discard(&mut self, block_offset: usize, num_blocks: usize) -> Result<BlockRequest, IoError> {
+        if !self.supports_discard {
+            return Err(IoError::Other("nvme: controller doesn't support discarding blocks"));
+        }
+        self.check_bounds(block_offset, num_blocks)?;
+        let num_blocks = u32::try_from(num_blocks).map_err(|_| IoError::InvalidInput)?;
+
+        // A single range: context attributes, number of blocks, and starting block.
+        let (mut range, range_phys_addr) = create_contiguous_mapping(16, NVME_MAPPING_FLAGS)?;
+        let range_data: &mut [u8] = range.as_slice_mut(0, 16)?;
+        range_data[0..4].copy_from_slice(&0u32.to_le_bytes());
+        range_data[4..8].copy_from_slice(&num_blocks.to_le_bytes());
+        range_data[8..16].copy_from_slice(&(block_offset as u64).to_le_bytes());
+
+        let mut command = Command::new(NVM_DATASET_MGMT);
+        command.nsid = self.namespace_id;
+        // One range, with the "deallocate" attribute.
+        command.cdw10 = 0;
+        command.cdw11 = DSM_ATTRIBUTE_DEALLOCATE;
+        self.controller.lock().submit(command, Vec::new(), None, Some((range, range_phys_addr)))
+    }
+}
+
+impl StorageDevice for NvmeDrive {
+    fn size_in_blocks(&self) -> usize {
+        self.num_blocks
+    }
+
+    fn as_block_device_mut(&mut self) -> Option<&mut dyn BlockDevice> {
+        Some(self)
+    }
+}
+impl BlockIo for NvmeDrive {
+    fn block_size(&self) -> usize { self.block_size }
+}
+impl KnownLength for NvmeDrive {
+    fn len(&self) -> usize { self.block_size() * self.size_in_blocks() }
+}
+impl BlockReader for NvmeDrive {
+    fn read_blocks(&mut self, buffer: &mut [u8], block_offset: usize) -> Result<usize, IoError> {
+        if buffer.len() % self.block_size != 0 {
+            return Err(IoError::InvalidInput);
+        }
+        let mut offset = block_offset;
+        for chunk in buffer.chunks_mut(self.max_blocks_per_request * self.block_size) {
+            let num_blocks = chunk.len() / self.block_size;
+            let request = self.submit_read(offset, num_blocks)?;
+            chunk.copy_from_slice(&self.wait(request)?);
+            offset += num_blocks;
+        }
+        Ok(offset - block_offset)
+    }
+}
+impl BlockWriter for NvmeDrive {
+    fn write_blocks(&mut self, buffer: &[u8], block_offset: usize) -> Result<usize, IoError> {
+        if buffer.len() % self.block_size != 0 {
+            return Err(IoError::InvalidInput);
+        }
+        let mut offset = block_offset;
+        for chunk in buffer.chunks(self.max_blocks_per_request * self.block_size) {
+            let request = self.submit_write(chunk.to_vec(), offset)?;
+            self.wait(request)?;
+            offset += chunk.len() / self.block_size;
+        }
+        Ok(offset - block_offset)
+    }
+
+    fn flush(&mut self) -> Result<(), IoError> {
+        let request = self.submit_flush()?;
+        self.wait(request).map(|_| ())
+    }
+}
+
+/// A storage controller for an NVMe controller, whose first namespace is its only device.
+pub struct NvmeController {
+    drive: Arc<Mutex<NvmeDrive>>,
+}
+
+impl NvmeController {
+    /// Initializes the NVMe controller that is connected as the given PciDevice.
+    pub fn new(nvme_pci_dev: &PciDevice) -> Result<NvmeController, &'static str> {
+        let drive = NvmeDrive::init(nvme_pci_dev)?;
+        Ok(NvmeController { drive: Arc::new(Mutex::new(drive)) })
+    }
+}
+
+impl StorageController for NvmeController {
+    fn devices<'c>(&'c self) -> Box<(dyn Iterator<Item = StorageDeviceRef> + 'c)> {
+        Box::new(iter::once(Arc::clone(&self.drive) as StorageDeviceRef))
+    }
+}
+
+extern "x86-interrupt" fn nvme_handler(_stack_frame: InterruptStackFrame) {
+    if let Some(controller_ref) = NVME_CONTROLLER.get() {
+        let mut controller = controller_ref.lock();
+        controller.handle_completions();
+        eoi(Some(controller.interrupt_num));
+    } else {
+        error!("BUG: nvme_handler(): NVMe controller hasn't yet been initialized!");
+        eoi(None);
+    }
+}
@@ -0,0 +1,181 @@
+//! Submission and completion queues, which are shared with the controller.
+
+use core::sync::atomic::{fence, Ordering};
+use memory::{create_contiguous_mapping, MappedPages, PhysicalAddress};
+use volatile::ReadOnly;
+use zerocopy::FromBytes;
+use crate::NVME_MAPPING_FLAGS;
+
+/// A submission queue entry.
+#[derive(Clone, Copy, Debug, Default, FromBytes)]
+#[repr(C)]
+pub struct Command {
+    /// The opcode in bits 0:7 and the command ID in bits 16:31.
+    cdw0: u32,
+    /// Namespace Identifier.
+    pub nsid: u32,
+    cdw2: u32,
+    cdw3: u32,
+    /// Metadata Pointer.
+    mptr: u64,
+    /// Physical Region Page entries, which point to the command's data.
+    pub prp1: u64,
+    pub prp2: u64,
+    /// Command-specific dwords.
+    pub cdw10: u32,
+    pub cdw11: u32,
+    pub cdw12: u32,
+    pub cdw13: u32,
+    pub cdw14: u32,
+    pub cdw15: u32,
+}
+
+const _: () = assert!(core::mem::size_of::<Command>() == 64);
+
+impl Command {
+    pub fn new(opcode: u8) -> Command {
+        Command { cdw0: opcode as u32, ..Default::default() }
+    }
+
+    pub fn opcode(&self) -> u8 {
+        self.cdw0 as u8
+    }
+
+    pub fn set_command_id(&mut self, command_id: u16) {
+        self.cdw0 = (self.cdw0 & 0xFFFF) | ((command_id as u32) << 16);
+    }
+}
+
+/// A completion queue entry, as written by the controller.
+#[derive(FromBytes)]
+#[repr(C)]
+struct CompletionEntry {
+    dw0:        ReadOnly<u32>,
+    _dw1:       ReadOnly<u32>,
+    sq_head:    ReadOnly<u16>,
+    _sq_id:     ReadOnly<u16>,
+    command_id: ReadOnly<u16>,
+    /// The phase tag in bit 0 and the status field in bits 1:15.
+    status:     ReadOnly<u16>,
+}
+
+/// A completion that has been removed from a completion queue.
+#[derive(Clone, Copy, Debug)]
+pub struct Completion {
+    /// The command-specific result.
+    pub dw0: u32,
+    /// The head of the submission queue after the controller consumed the completed command.
+    pub sq_head: u16,
+    pub command_id: u16,
+    status: u16,
+}
+
+impl Completion {
+    /// Returns the status field without the phase tag, which is zero upon success.
+    pub fn status_code(&self) -> u16 {
+        self.status >> 1
+    }
+}
+
+/// Allocates a zeroed, physically-contiguous queue.
+fn allocate_queue(size_in_bytes: usize) -> Result<(MappedPages, PhysicalAddress), &'static str> {
+    let (mut mp, phys_addr) = create_contiguous_mapping(size_in_bytes, NVME_MAPPING_FLAGS)?;
+    mp.as_slice_mut::<u8>(0, size_in_bytes)?.fill(0);
+    Ok((mp, phys_addr))
+}
+
+pub struct SubmissionQueue {
+    mp: MappedPages,
+    phys_addr: PhysicalAddress,
+    size: u16,
+    tail: u16,
+    /// The last head reported by the controller.
+    head: u16,
+}
+
+impl SubmissionQueue {
+    pub fn new(size: u16) -> Result<SubmissionQueue, &'static str> {
+        let (mp, phys_addr) = allocate_queue(size as usize * core::mem::size_of::<Command>())?;
+        Ok(SubmissionQueue { mp, phys_addr, size, tail: 0, head: 0 })
+    }
+
+    pub fn phys_addr(&self) -> PhysicalAddress {
+        self.phys_addr
+    }
+
+    pub fn size_in_bytes(&self) -> usize {
+        self.mp.size_in_bytes()
+    }
+
+    /// Adds the given command to the queue, returning the new tail
+    /// that must be written to the queue's doorbell, or `None` if the queue is full.
+    pub fn push(&mut self, command: Command) -> Option<u16> {
+        let next_tail = (self.tail + 1) % self.size;
+        if next_tail == self.head {
+            return None;
+        }
+        let offset = self.tail as usize * core::mem::size_of::<Command>();
+        *self.mp.as_type_mut::<Command>(offset).ok()? = command;
+        self.tail = next_tail;
+        // The controller must see the command before the doorbell is written.
+        fence(Ordering::SeqCst);
+        Some(next_tail)
+    }
+
+    pub fn set_head(&mut self, head: u16) {
+        self.head = head;
+    }
+}
+
+pub struct CompletionQueue {
+    mp: MappedPages,
+    phys_addr: PhysicalAddress,
+    size: u16,
+    head: u16,
+    /// The phase tag of new entries, which inverts each time the queue wraps around.
+    phase: bool,
+}
+
+impl CompletionQueue {
+    pub fn new(size: u16) -> Result<CompletionQueue, &'static str> {
+        let (mp, phys_addr) = allocate_queue(size as usize * core::mem::size_of::<CompletionEntry>())?;
+        Ok(CompletionQueue { mp, phys_addr, size, head: 0, phase: true })
+    }
+
+    pub fn phys_addr(&self) -> PhysicalAddress {
+        self.phys_addr
+    }
+
+    pub fn size_in_bytes(&self) -> usize {
+        self.mp.size_in_bytes()
+    }
+
+    /// Returns the head that must be written to the queue's doorbell after popping completions.
+    pub fn head(&self) -> u16 {
+        self.head
+    }
+
+    /// Removes the next completion from the queue, if the controller has posted one.
+    pub fn pop(&mut self) -> Option<Completion> {
+        let offset = self.head as usize * core::mem::size_of::<CompletionEntry>();
+        let entry: &CompletionEntry = self.mp.as_type(offset).ok()?;
+        let status = entry.status.read();
+        if (status & 1 != 0) != self.phase {
+            return None;
+        }
+        // Don't read the rest of the entry before seeing its phase tag.
+        fence(Ordering::Acquire);
+        let completion = Completion {
+            dw0: entry.dw0.read(),
+            sq_head: entry.sq_head.read(),
+            command_id: entry.command_id.read(),
+            status,
+        };
+        self.head += 1;
+        if self.head == self.size {
+            self.head = 0;
+            self.phase = !self.phase;
+        }
+        Some(completion)
+    }
+}
@@ -0,0 +1,66 @@
+//! The memory-mapped controller registers of an NVMe controller.
+
+use volatile::{ReadOnly, Volatile};
+use zerocopy::FromBytes;
+
+/// Controller Capabilities: Maximum Queue Entries Supported, minus one.
+pub const CAP_MQES_MASK:     u64 = 0xFFFF;
+/// Controller Capabilities: Doorbell Stride, as a power of two times 4 bytes.
+pub const CAP_DSTRD_SHIFT:   u64 = 32;
+/// Controller Capabilities: Memory Page Size Minimum, as a power of two times 4 KiB.
+pub const CAP_MPSMIN_SHIFT:  u64 = 48;
+
+/// Controller Configuration: Enable.
+pub const CC_ENABLE:             u32 = 1 << 0;
+/// Controller Configuration: I/O Command Set Selected is the NVM command set.
+pub const CC_CSS_NVM:            u32 = 0 << 4;
+/// Controller Configuration: Memory Page Size is 4 KiB.
+pub const CC_MPS_4K:             u32 = 0 << 7;
+/// Controller Configuration: Arbitration Mechanism Selected is round robin.
+pub const CC_AMS_ROUND_ROBIN:    u32 = 0 << 11;
+/// Controller Configuration: I/O Submission Queue Entry Size is 64 bytes.
+pub const CC_IOSQES:             u32 = 6 << 16;
+/// Controller Configuration: I/O Completion Queue Entry Size is 16 bytes.
+pub const CC_IOCQES:             u32 = 4 << 20;
+
+/// Controller Status: Ready.
+pub const CSTS_READY: u32 = 1 << 0;
+/// Controller Status: Controller Fatal Status.
+pub const CSTS_FATAL: u32 = 1 << 1;
+
+/// The offset of the first doorbell register.
+pub const DOORBELL_BASE: usize = 0x1000;
+
+/// Optional NVM Command Support: the Dataset Management command.
+pub const ONCS_DATASET_MGMT: u16 = 1 << 2;
+/// Dataset Management: the "deallocate" attribute.
+pub const DSM_ATTRIBUTE_DEALLOCATE: u32 = 1 << 2;
+
+/// The controller registers that precede the doorbells.
+#[derive(FromBytes)]
+#[repr(C)]
+pub struct NvmeRegisters {
+    /// Controller Capabilities.
+    pub cap:        ReadOnly<u64>,      // 0x00
+    /// Version.
+    pub vs:         ReadOnly<u32>,      // 0x08
+    /// Interrupt Mask Set.
+    pub intms:      Volatile<u32>,      // 0x0C
+    /// Interrupt Mask Clear.
+    pub intmc:      Volatile<u32>,      // 0x10
+    /// Controller Configuration.
+    pub cc:         Volatile<u32>,      // 0x14
+    _reserved:      ReadOnly<u32>,      // 0x18
+    /// Controller Status.
+    pub csts:       ReadOnly<u32>,      // 0x1C
+    /// NVM Subsystem Reset.
+    pub nssr:       Volatile<u32>,      // 0x20
+    /// Admin Queue Attributes.
+    pub aqa:        Volatile<u32>,      // 0x24
+    /// Admin Submission Queue Base Address.
+    pub asq:        Volatile<u64>,      // 0x28
+    /// Admin Completion Queue Base Address.
+    pub acq:        Volatile<u64>,      // 0x30
+}
+
+const _: () = assert!(core::mem::size_of::<NvmeRegisters>() == 0x38);
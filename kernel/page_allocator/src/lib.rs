@@ -23,6 +23,7 @@ extern crate memory_structs;
 extern crate spin;
 #[macro_use] extern crate static_assertions;
 extern crate intrusive_collections;
+extern crate fault_injection;
 use intrusive_collections::Bound;
 
 
@@ -629,6 +630,9 @@ pub fn allocate_pages_deferred(
 		warn!("PageAllocator: requested an allocation of 0 pages... stupid!");
 		return Err("cannot allocate zero pages");
 	}
+	if fault_injection::should_fail(fault_injection::FaultPoint::PageAllocation) {
+		return Err(fault_injection::FaultPoint::PageAllocation.error());
+	}
 
 	let mut locked_list = FREE_PAGE_LIST.lock();
 
@@ -673,6 +677,31 @@ pub fn allocate_pages(num_pages: usize) -> Option<AllocatedPages> {
 }
 
 
+/// Allocates the given number of pages, starting at a virtual address that is aligned
+/// to a multiple of `alignment_in_pages` pages, e.g., `512` for a 2MiB-aligned huge page.
+///
+/// This works by allocating a larger range of pages and then freeing the unaligned parts
+/// before and after the aligned range, so it temporarily reserves up to `alignment_in_pages - 1` extra pages.
+/// 
+/// See [`allocate_pages_deferred()`](fn.allocate_pages_deferred.html) for more details. 
+pub fn allocate_pages_aligned(num_pages: usize, alignment_in_pages: usize) -> Option<AllocatedPages> {
+	if alignment_in_pages <= 1 {
+		return allocate_pages(num_pages);
+	}
+	let padded = allocate_pages(num_pages.checked_add(alignment_in_pages - 1)?)?;
+	let misalignment = padded.start().number() % alignment_in_pages;
+	let aligned_start = if misalignment == 0 {
+		*padded.start()
+	} else {
+		*padded.start() + (alignment_in_pages - misalignment)
+	};
+	// The unaligned parts before and after the aligned range are freed when dropped here.
+	let (_before, rest) = padded.split(aligned_start).ok()?;
+	let (aligned, _after) = rest.split(aligned_start + num_pages).ok()?;
+	Some(aligned)
+}
+
+
 /// Allocates pages with no constraints on the starting virtual address, 
 /// with a size given by the number of bytes. 
 /// 
@@ -0,0 +1,418 @@
+//! A page cache that sits between filesystems and block storage devices.
+//!
+//! A [`PageCache`] caches the contents of a storage device in page-sized chunks,
+//! such that repeated reads of the same region, e.g., of filesystem metadata,
+//! are served from memory rather than from the device.
+//!
+//! Writes only modify the cached pages, which are marked dirty and later written back to the device:
+//! * by a background writeback task, once they have been dirty for at least [`DIRTY_EXPIRE`],
+//! * by the writer itself, if more than half of the cache's pages are dirty,
+//! * when a dirty page must be evicted to make room for another page, or
+//! * when the cache is explicitly flushed with [`PageCache::flush()`] or dropped.
+//!
+//! Each cache holds a bounded number of pages, beyond which the least-recently used clean page is evicted.
+//! Clean pages can also be evicted from all caches on demand via [`reclaim()`],
+//! which a memory-pressure subsystem can invoke when memory runs low.
+//!
+//! As with the `block_cache` crate, writes to the underlying storage device that bypass its page cache
+//! are not reflected in the cache, so a device should only be accessed through its page cache.
+
+#![no_std]
+
+extern crate alloc;
+
+use alloc::{
+    boxed::Box,
+    string::ToString,
+    sync::{Arc, Weak},
+    vec,
+    vec::Vec,
+};
+use core::{
+    cmp::min,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+};
+use hashbrown::HashMap;
+use io::{BlockIo, BlockReader, BlockWriter, IoError, KnownLength};
+use log::error;
+use spin::Mutex;
+use storage_device::StorageDeviceRef;
+use time::{now, Duration, Instant, Monotonic};
+
+/// The size in bytes of a cached page.
+pub const PAGE_SIZE: usize = 4096;
+/// The default maximum number of pages held by a [`PageCache`].
+pub const DEFAULT_CAPACITY_IN_PAGES: usize = 1024;
+/// How often the background writeback task looks for expired dirty pages.
+pub const WRITEBACK_INTERVAL: Duration = Duration::from_secs(1);
+/// How long a page may stay dirty before the background writeback task writes it back.
+pub const DIRTY_EXPIRE: Duration = Duration::from_secs(5);
+
+/// All page caches that have been created, which are visited by writeback and reclamation.
+static PAGE_CACHES: Mutex<Vec<Weak<PageCache>>> = Mutex::new(Vec::new());
+/// Whether the background writeback task has been spawned.
+static WRITEBACK_TASK_SPAWNED: AtomicBool = AtomicBool::new(false);
+
+/// A page of a storage device's contents held in a [`PageCache`].
+struct CachedPage {
+    /// The page's contents, which are shorter than [`PAGE_SIZE`] for the last page
+    /// of a device whose length isn't a multiple of the page size.
+    data: Box<[u8]>,
+    /// The value of the cache's access counter upon the most recent access to this page.
+    last_access: u64,
+    /// When this page was first modified since it was last written back, if it is dirty.
+    dirty_since: Option<Instant>,
+}
+
+/// The pages held in a [`PageCache`], indexed by their page number on the device.
+struct Pages {
+    map: HashMap<usize, CachedPage>,
+    /// Incremented upon every access, in order to find the least-recently used page.
+    access_counter: u64,
+    /// The number of dirty pages in `map`.
+    num_dirty: usize,
+}
+
+/// Statistics about the effectiveness of a [`PageCache`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PageCacheStats {
+    /// The number of page accesses that were served from the cache.
+    pub hits: usize,
+    /// The number of page accesses that required a page to be added to the cache.
+    pub misses: usize,
+    /// The number of dirty pages that were written back to the device.
+    pub writebacks: usize,
+    /// The number of pages that were evicted from the cache.
+    pub evictions: usize,
+}
+
+/// A write-back cache of a storage device's contents, in units of [`PAGE_SIZE`] bytes.
+///
+/// All accesses are byte-granular, and only the pages that are partially written
+/// are read from the device before they are modified.
+pub struct PageCache {
+    device: StorageDeviceRef,
+    /// The number of device blocks in each page.
+    blocks_per_page: usize,
+    /// The length of the device in bytes.
+    len: usize,
+    /// The maximum number of pages held in this cache.
+    capacity: usize,
+    pages: Mutex<Pages>,
+    hits: AtomicUsize,
+    misses: AtomicUsize,
+    writebacks: AtomicUsize,
+    evictions: AtomicUsize,
+}
+
+impl PageCache {
+    /// Creates a new page cache for the given storage device
+    /// that holds up to [`DEFAULT_CAPACITY_IN_PAGES`] pages.
+    pub fn new(device: StorageDeviceRef) -> Result<Arc<PageCache>, &'static str> {
+        Self::with_capacity(device, DEFAULT_CAPACITY_IN_PAGES)
+    }
+
+    /// Creates a new page cache for the given storage device that holds up to `capacity_in_pages` pages.
+    ///
+    /// This spawns the background writeback task if it isn't already running.
+    /// Returns an error if the device's block size doesn't evenly divide [`PAGE_SIZE`].
+    pub fn with_capacity(device: StorageDeviceRef, capacity_in_pages: usize) -> Result<Arc<PageCache>, &'static str> {
+        if capacity_in_pages == 0 {
+            return Err("page cache must be able to hold at least one page");
+        }
+        let (block_size, len) = {
+            let locked_device = device.lock();
+            (locked_device.block_size(), locked_device.len())
+        };
+        if block_size == 0 || PAGE_SIZE % block_size != 0 {
+            return Err("page cache requires a device block size that evenly divides the page size");
+        }
+        spawn_writeback_task()?;
+
+        let cache = Arc::new(PageCache {
+            device,
+            blocks_per_page: PAGE_SIZE / block_size,
+            len,
+            capacity: capacity_in_pages,
+            pages: Mutex::new(Pages { map: HashMap::new(), access_counter: 0, num_dirty: 0 }),
+            hits: AtomicUsize::new(0),
+            misses: AtomicUsize::new(0),
+            writebacks: AtomicUsize::new(0),
+            evictions: AtomicUsize::new(0),
+        });
+        PAGE_CACHES.lock().push(Arc::downgrade(&cache));
+        Ok(cache)
+    }
+
+    /// Returns the length in bytes of the underlying storage device.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns `true` if the underlying storage device is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Returns the number of pages currently held in this cache.
+    pub fn cached_pages(&self) -> usize {
+        self.pages.lock().map.len()
+    }
+
+    /// Returns the number of dirty pages that have yet to be written back.
+    pub fn dirty_pages(&self) -> usize {
+        self.pages.lock().num_dirty
+    }
+
+    /// Returns statistics about the accesses to this cache so far.
+    pub fn stats(&self) -> PageCacheStats {
+        PageCacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            writebacks: self.writebacks.load(Ordering::Relaxed),
+            evictions: self.evictions.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Reads bytes from the device into the given `buffer`, starting at the given byte `offset`.
+    ///
+    /// Returns the number of bytes read, which is less than the buffer's length
+    /// if the read extends past the end of the device.
+    pub fn read_at(&self, buffer: &mut [u8], offset: usize) -> Result<usize, IoError> {
+        if offset > self.len {
+            return Err(IoError::InvalidInput);
+        }
+        let end = min(offset.saturating_add(buffer.len()), self.len);
+        let mut pages = self.pages.lock();
+        let mut pos = offset;
+        while pos < end {
+            let index = pos / PAGE_SIZE;
+            let page_offset = pos % PAGE_SIZE;
+            let count = min(PAGE_SIZE - page_offset, end - pos);
+            let page = self.get_page(&mut pages, index, true)?;
+            buffer[pos - offset .. pos - offset + count].copy_from_slice(&page.data[page_offset .. page_offset + count]);
+            pos += count;
+        }
+        Ok(end - offset)
+    }
+
+    /// Writes the given `buffer` to the cached contents of the device, starting at the given byte `offset`.
+    ///
+    /// The modified pages are written back to the device at a later time;
+    /// use [`PageCache::flush()`] to ensure they have reached the device.
+    pub fn write_at(&self, buffer: &[u8], offset: usize) -> Result<usize, IoError> {
+        let end = offset.checked_add(buffer.len())
+            .filter(|&end| end <= self.len)
+            .ok_or(IoError::InvalidInput)?;
+        let mut pages = self.pages.lock();
+        let mut pos = offset;
+        while pos < end {
+            let index = pos / PAGE_SIZE;
+            let page_offset = pos % PAGE_SIZE;
+            let count = min(PAGE_SIZE - page_offset, end - pos);
+            // A page that is entirely overwritten doesn't need to be read from the device first.
+            let overwrite = page_offset == 0 && count == self.page_len(index);
+            let page = self.get_page(&mut pages, index, !overwrite)?;
+            page.data[page_offset .. page_offset + count].copy_from_slice(&buffer[pos - offset .. pos - offset + count]);
+            let newly_dirty = page.dirty_since.is_none();
+            if newly_dirty {
+                page.dirty_since = Some(now::<Monotonic>());
+                pages.num_dirty += 1;
+            }
+            pos += count;
+        }
+        // Throttle writers that dirty pages faster than they are written back.
+        while pages.num_dirty > self.capacity / 2 {
+            self.write_back_oldest(&mut pages)?;
+        }
+        Ok(buffer.len())
+    }
+
+    /// Writes back all dirty pages and then flushes the underlying storage device.
+    pub fn flush(&self) -> Result<(), IoError> {
+        let mut pages = self.pages.lock();
+        let mut dirty: Vec<usize> = pages.map.iter()
+            .filter(|(_, page)| page.dirty_since.is_some())
+            .map(|(index, _)| *index)
+            .collect();
+        dirty.sort_unstable();
+        for index in dirty {
+            self.write_back_page(&mut pages, index)?;
+        }
+        self.device.lock().flush()
+    }
+
+    /// Evicts clean pages from this cache, least-recently used first,
+    /// until at least `bytes` have been freed or no clean pages remain.
+    ///
+    /// Returns the number of bytes freed, which is zero if this cache is currently in use.
+    /// This doesn't allocate memory, so it can be used to relieve memory pressure.
+    pub fn shrink(&self, bytes: usize) -> usize {
+        let Some(mut pages) = self.pages.try_lock() else {
+            return 0;
+        };
+        let mut freed = 0;
+        while freed < bytes {
+            let Some(index) = Self::least_recently_used_clean(&pages) else {
+                break;
+            };
+            if let Some(page) = pages.map.remove(&index) {
+                freed += page.data.len();
+                self.evictions.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        freed
+    }
+
+    /// Returns the length in bytes of the page at the given `index`.
+    fn page_len(&self, index: usize) -> usize {
+        min(PAGE_SIZE, self.len - index * PAGE_SIZE)
+    }
+
+    /// Returns the page at the given `index`, adding it to the cache if necessary.
+    ///
+    /// If `fill` is `true`, a newly-added page is read from the device;
+    /// otherwise, its contents are left zeroed because the caller will overwrite them.
+    fn get_page<'p>(&self, pages: &'p mut Pages, index: usize, fill: bool) -> Result<&'p mut CachedPage, IoError> {
+        pages.access_counter += 1;
+        let access = pages.access_counter;
+        if pages.map.contains_key(&index) {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.misses.fetch_add(1, Ordering::Relaxed);
+            while pages.map.len() >= self.capacity {
+                self.evict_one(pages)?;
+            }
+            let mut data = vec![0; self.page_len(index)].into_boxed_slice();
+            if fill {
+                self.device.lock().read_blocks(&mut data, index * self.blocks_per_page)?;
+            }
+            pages.map.insert(index, CachedPage { data, last_access: access, dirty_since: None });
+        }
+        let page = pages.map.get_mut(&index).ok_or(IoError::Other("BUG: page cache lost a newly-added page"))?;
+        page.last_access = access;
+        Ok(page)
+    }
+
+    /// Evicts the least-recently used clean page,
+    /// or writes back and evicts the oldest dirty page if all pages are dirty.
+    fn evict_one(&self, pages: &mut Pages) -> Result<(), IoError> {
+        let index = match Self::least_recently_used_clean(pages) {
+            Some(index) => index,
+            None => self.write_back_oldest(pages)?,
+        };
+        pages.map.remove(&index);
+        self.evictions.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn least_recently_used_clean(pages: &Pages) -> Option<usize> {
+        pages.map.iter()
+            .filter(|(_, page)| page.dirty_since.is_none())
+            .min_by_key(|(_, page)| page.last_access)
+            .map(|(index, _)| *index)
+    }
+
+    /// Writes back the page that has been dirty for the longest time, and returns its index.
+    fn write_back_oldest(&self, pages: &mut Pages) -> Result<usize, IoError> {
+        let index = pages.map.iter()
+            .filter_map(|(index, page)| page.dirty_since.map(|since| (since, *index)))
+            .min()
+            .map(|(_, index)| index)
+            .ok_or(IoError::Other("page cache has no dirty pages to write back"))?;
+        self.write_back_page(pages, index)?;
+        Ok(index)
+    }
+
+    /// Writes back the page at the given `index` if it is dirty.
+    fn write_back_page(&self, pages: &mut Pages, index: usize) -> Result<(), IoError> {
+        if let Some(page) = pages.map.get_mut(&index) {
+            if page.dirty_since.is_some() {
+                self.device.lock().write_blocks(&page.data, index * self.blocks_per_page)?;
+                page.dirty_since = None;
+                pages.num_dirty -= 1;
+                self.writebacks.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes back the pages that have been dirty for at least [`DIRTY_EXPIRE`].
+    ///
+    /// The cache is only locked while writing back each individual page,
+    /// such that other users of the cache aren't held up for the whole writeback.
+    fn write_back_expired(&self) -> Result<(), IoError> {
+        let current_time = now::<Monotonic>();
+        let mut expired: Vec<usize> = self.pages.lock().map.iter()
+            .filter(|(_, page)| page.dirty_since.map_or(false, |since| current_time.duration_since(since) >= DIRTY_EXPIRE))
+            .map(|(index, _)| *index)
+            .collect();
+        expired.sort_unstable();
+        for index in expired {
+            self.write_back_page(&mut self.pages.lock(), index)?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for PageCache {
+    fn drop(&mut self) {
+        if let Err(e) = self.flush() {
+            error!("page_cache: failed to write back dirty pages upon drop: {:?}", e);
+        }
+    }
+}
+
+/// Evicts clean pages from all page caches until at least `bytes` have been freed,
+/// and returns the number of bytes actually freed.
+///
+/// This is intended to be invoked by a memory-pressure subsystem when memory runs low,
+/// and has the same signature as the `reclaim` callback of `tls_initializer::MemoryPressureHooks`.
+/// Caches that are currently in use are skipped, such that this can be safely invoked
+/// upon an allocation failure that occurred within the page cache itself.
+/// Dirty pages are never written back here, as that would require waiting for the device.
+pub fn reclaim(bytes: usize) -> usize {
+    let Some(caches) = PAGE_CACHES.try_lock() else {
+        return 0;
+    };
+    let mut freed = 0;
+    for cache in caches.iter().filter_map(Weak::upgrade) {
+        if freed >= bytes {
+            break;
+        }
+        freed += cache.shrink(bytes - freed);
+    }
+    freed
+}
+
+/// Spawns the background writeback task, unless it has already been spawned.
+fn spawn_writeback_task() -> Result<(), &'static str> {
+    if WRITEBACK_TASK_SPAWNED.swap(true, Ordering::AcqRel) {
+        return Ok(());
+    }
+    let spawned = spawn::new_task_builder(writeback_loop, ())
+        .name("page_cache_writeback".to_string())
+        .spawn();
+    if spawned.is_err() {
+        WRITEBACK_TASK_SPAWNED.store(false, Ordering::Release);
+    }
+    spawned.map(|_| ())
+}
+
+/// The entry point of the background writeback task,
+/// which periodically writes back expired dirty pages from all page caches.
+fn writeback_loop(_: ()) -> Result<(), &'static str> {
+    loop {
+        let _ = sleep::sleep(WRITEBACK_INTERVAL);
+        let caches: Vec<Arc<PageCache>> = {
+            let mut caches = PAGE_CACHES.lock();
+            caches.retain(|cache| cache.strong_count() > 0);
+            caches.iter().filter_map(Weak::upgrade).collect()
+        };
+        for cache in caches {
+            if let Err(e) = cache.write_back_expired() {
+                error!("page_cache: failed to write back expired dirty pages: {:?}", e);
+            }
+        }
+    }
+}
@@ -48,14 +48,22 @@ impl PageTableEntry {
     /// then this function returns those frames.
     /// This is useful because those returned frames can then be safely deallocated.
     pub fn set_unmapped(&mut self) -> UnmapResult {
+        self.set_unmapped_huge(1)
+    }
+
+    /// Removes the mapping represented by this page table entry,
+    /// which maps a huge page that covers the given number of 4KiB frames,
+    /// e.g., a P2-level entry that maps a 2MiB page covers 512 frames.
+    ///
+    /// Because a page table entry doesn't know which level of page table it is in,
+    /// the caller must specify how many frames this entry covers.
+    /// Otherwise, this is identical to [`PageTableEntry::set_unmapped()`].
+    pub fn set_unmapped_huge(&mut self, size_in_frames: usize) -> UnmapResult {
         let frame = self.frame_value();
         let flags = self.flags();
         self.zero();
 
-        // Since we don't support huge pages, this PTE can only cover one 4KiB frame.
-        // Once we support huge pages, we can use a type parameter
-        // to specify whether this is a 4KiB, 2MiB, or 1GiB PTE.
-        let frame_range = FrameRange::new(frame, frame);
+        let frame_range = FrameRange::new(frame, frame + (size_in_frames.max(1) - 1));
         if flags.is_exclusive() {
             UnmapResult::Exclusive(UnmappedFrames(frame_range))
         } else {
@@ -4,6 +4,7 @@
 //! (although it does require alloc types like String).
 //! 
 #![no_std]
+#![feature(panic_info_message)]
 
 extern crate alloc;
 #[macro_use] extern crate log;
@@ -15,9 +16,10 @@ extern crate stack_trace;
 extern crate stack_trace_frame_pointers;
 extern crate fault_log;
 
-use core::panic::PanicInfo;
+use core::{fmt::Write, panic::PanicInfo};
 // use alloc::string::String;
 use memory::VirtualAddress;
+use mod_mgmt::CrateNamespace;
 use task::{KillReason, PanicInfoOwned};
 use fault_log::log_panic_entry;
 
@@ -33,6 +35,12 @@ pub fn panic_wrapper(panic_info: &PanicInfo) -> Result<(), &'static str> {
     log_panic_entry (panic_info);
     // fault_log::print_fault_log();
 
+    // The TLS base is needed to make sense of TLS addresses in the panic message or a register dump.
+    match task::with_current_task(|t| (t.id, t.name.clone(), t.tls_self_pointer())) {
+        Ok((id, name, tls_base)) => error!("Task {} {:?} panicked, TLS base: {:#X}", id, name, tls_base),
+        Err(_) => error!("Panicked before the current task was initialized"),
+    }
+
     // print a stack trace
     let stack_trace_result = {
         // By default, we use DWARF-based debugging stack traces
@@ -40,15 +48,10 @@ pub fn panic_wrapper(panic_info: &PanicInfo) -> Result<(), &'static str> {
             error!("------------------ Stack Trace (DWARF) ---------------------------");
             stack_trace::stack_trace(
                 &mut |stack_frame, stack_frame_iter| {
-                    let symbol_offset = stack_frame_iter.namespace().get_section_containing_address(
+                    print_frame(
+                        stack_frame_iter.namespace(),
                         VirtualAddress::new_canonical(stack_frame.call_site_address() as usize),
-                        false
-                    ).map(|(sec, offset)| (sec.name.clone(), offset));
-                    if let Some((symbol_name, offset)) = symbol_offset {
-                        error!("  {:>#018X} in {} + {:#X}", stack_frame.call_site_address(), symbol_name, offset);
-                    } else {
-                        error!("  {:>#018X} in ??", stack_frame.call_site_address());
-                    }
+                    );
                     true
                 },
                 None,
@@ -73,13 +76,7 @@ pub fn panic_wrapper(panic_info: &PanicInfo) -> Result<(), &'static str> {
             stack_trace_frame_pointers::stack_trace_using_frame_pointers(
                 &mmi.page_table,
                 &mut |_frame_pointer, instruction_pointer: VirtualAddress| {
-                    let symbol_offset = namespace.get_section_containing_address(instruction_pointer, false)
-                        .map(|(sec, offset)| (sec.name.clone(), offset));
-                    if let Some((symbol_name, offset)) = symbol_offset {
-                        error!("  {:>#018X} in {} + {:#X}", instruction_pointer, symbol_name, offset);
-                    } else {
-                        error!("  {:>#018X} in ??", instruction_pointer);
-                    }
+                    print_frame(&namespace, instruction_pointer);
                     true
                 },
                 None,
@@ -92,17 +89,27 @@ pub fn panic_wrapper(panic_info: &PanicInfo) -> Result<(), &'static str> {
     }
     error!("------------------------------------------------------------------");
 
+    // Format the panic message into the current task's TLS panic buffer rather than onto the heap,
+    // such that we can handle a panic that occurred inside the heap allocator.
+    let panic_info_owned = mod_mgmt::with_panic_buffer(|buf| {
+        if let Some(msg) = panic_info.message() {
+            // Formatting only fails if the message was truncated, which is fine.
+            let _ = write!(buf, "{}", msg);
+        }
+        PanicInfoOwned::new(buf.as_str(), panic_info.location())
+    }).unwrap_or_else(|| PanicInfoOwned::new("nested panic while formatting a previous panic message", panic_info.location()));
+    let cause = KillReason::Panic(panic_info_owned);
+
     // Call this task's kill handler, if it has one.
     if let Some(ref kh_func) = task::take_kill_handler() {
         debug!("Found kill handler callback to invoke in Task {:?}", task::get_my_current_task());
-        kh_func(&KillReason::Panic(PanicInfoOwned::from(panic_info)));
+        kh_func(&cause);
     } else {
         debug!("No kill handler callback in Task {:?}", task::get_my_current_task());
     }
 
     // Start the unwinding process
     {
-        let cause = KillReason::Panic(PanicInfoOwned::from(panic_info));
         match unwind::start_unwinding(cause, 5) {
             Ok(_) => {
                 warn!("BUG: start_unwinding() returned an Ok() value, which is unexpected because it means no unwinding actually occurred. Task: {:?}.", task::get_my_current_task());
@@ -115,3 +122,12 @@ pub fn panic_wrapper(panic_info: &PanicInfo) -> Result<(), &'static str> {
         }
     }
 }
+
+/// Prints a frame of a stack trace, with the given return address resolved
+/// to the function and crate that contain it, i.e., `crate::function + offset`.
+fn print_frame(namespace: &CrateNamespace, return_address: VirtualAddress) {
+    match namespace.symbolize_address(return_address) {
+        Some(symbolized) => error!("  {:>#018X} in {}", return_address, symbolized),
+        None => error!("  {:>#018X} in ??", return_address),
+    }
+}
@@ -0,0 +1,340 @@
+//! Typed access to the capabilities in a PCI device's config space.
+//!
+//! Capabilities form a linked list in the first 256 bytes of the config space,
+//! while PCI Express extended capabilities form a separate list starting at offset 0x100,
+//! which is only accessible through ECAM.
+//! See section 6.7 of the PCI Local Bus specification 3.0
+//! and sections 7.5 and 9.3.3 of the PCI Express Base specification 3.0.
+
+use super::{
+    PciLocation, POWER_MANAGEMENT_CAPABILITY, MSI_CAPABILITY, MSIX_CAPABILITY,
+    VENDOR_SPECIFIC_CAPABILITY, PCI_EXPRESS_CAPABILITY, SRIOV_EXTENDED_CAPABILITY,
+};
+
+/// The offset of the first extended capability in the config space.
+const EXTENDED_CAPABILITIES_START: u16 = 0x100;
+
+/// Power Management Capabilities register (PMC).
+const PM_CAPABILITIES: u8 = 2;
+/// PMC: the device supports the D1 power state.
+const PMC_D1_SUPPORT: u16 = 1 << 9;
+/// PMC: the device supports the D2 power state.
+const PMC_D2_SUPPORT: u16 = 1 << 10;
+/// Power Management Control/Status register (PMCSR).
+const PM_CONTROL_STATUS: u8 = 4;
+/// PMCSR: the current power state.
+const PMCSR_POWER_STATE: u16 = 0b11;
+/// PMCSR: set when the device has asserted a power management event; cleared by writing 1.
+const PMCSR_PME_STATUS: u16 = 1 << 15;
+
+/// MSI-X Message Control register.
+const MSIX_MESSAGE_CONTROL: u8 = 2;
+/// MSI-X Message Control: the number of table entries minus one.
+const MSIX_TABLE_SIZE: u16 = 0x7FF;
+/// The register that locates the MSI-X table.
+const MSIX_TABLE: u8 = 4;
+/// The register that locates the MSI-X Pending Bit Array.
+const MSIX_PBA: u8 = 8;
+/// The bits of an MSI-X offset register that hold the BAR Indicator Register (BIR).
+const MSIX_BIR: u32 = 0x7;
+
+/// SR-IOV Control register.
+const SRIOV_CONTROL: u16 = 0x08;
+/// SR-IOV Control: enables the virtual functions.
+const SRIOV_VF_ENABLE: u16 = 1 << 0;
+/// SR-IOV Control: enables the memory space of the virtual functions.
+const SRIOV_VF_MEMORY_SPACE_ENABLE: u16 = 1 << 3;
+const SRIOV_INITIAL_VFS: u16 = 0x0C;
+const SRIOV_TOTAL_VFS: u16 = 0x0E;
+const SRIOV_NUM_VFS: u16 = 0x10;
+const SRIOV_FIRST_VF_OFFSET: u16 = 0x14;
+const SRIOV_VF_STRIDE: u16 = 0x16;
+const SRIOV_VF_DEVICE_ID: u16 = 0x1A;
+
+
+/// A capability in a device's config space, as returned by [`PciLocation::capabilities()`].
+#[derive(Clone, Copy, Debug)]
+pub enum PciCapability {
+    PowerManagement(PowerManagement),
+    /// The MSI capability at the given offset.
+    Msi(u8),
+    Msix(Msix),
+    /// The PCI Express capability at the given offset.
+    PciExpress(u8),
+    /// A vendor-specific capability at the given offset, e.g., one of a virtio device's config structures.
+    VendorSpecific(u8),
+    /// A capability that has no typed representation.
+    Other { id: u8, offset: u8 },
+}
+
+/// An extended capability in a device's config space, as returned by [`PciLocation::extended_capabilities()`].
+#[derive(Clone, Copy, Debug)]
+pub enum PciExtendedCapability {
+    Sriov(Sriov),
+    /// An extended capability that has no typed representation.
+    Other { id: u16, version: u8, offset: u16 },
+}
+
+impl PciLocation {
+    /// Returns an iterator over every capability in this device's config space.
+    pub fn capabilities(&self) -> impl Iterator<Item = PciCapability> + '_ {
+        let location = *self;
+        self.pci_capabilities().map(move |(id, offset)| match id {
+            POWER_MANAGEMENT_CAPABILITY => PciCapability::PowerManagement(PowerManagement { location, offset }),
+            MSI_CAPABILITY              => PciCapability::Msi(offset),
+            MSIX_CAPABILITY             => PciCapability::Msix(Msix { location, offset }),
+            PCI_EXPRESS_CAPABILITY      => PciCapability::PciExpress(offset),
+            VENDOR_SPECIFIC_CAPABILITY  => PciCapability::VendorSpecific(offset),
+            _                           => PciCapability::Other { id, offset },
+        })
+    }
+
+    /// Returns this device's power management capability, if it has one.
+    pub fn power_management(&self) -> Option<PowerManagement> {
+        self.capabilities().find_map(|cap| match cap {
+            PciCapability::PowerManagement(pm) => Some(pm),
+            _ => None,
+        })
+    }
+
+    /// Returns this device's MSI-X capability, if it has one.
+    pub fn msix(&self) -> Option<Msix> {
+        self.capabilities().find_map(|cap| match cap {
+            PciCapability::Msix(msix) => Some(msix),
+            _ => None,
+        })
+    }
+
+    /// Returns an iterator over every extended capability in this device's config space,
+    /// which is empty if the extended config space isn't accessible through ECAM.
+    pub fn extended_capabilities(&self) -> impl Iterator<Item = PciExtendedCapability> + '_ {
+        let location = *self;
+        let mut offset = EXTENDED_CAPABILITIES_START;
+        core::iter::from_fn(move || {
+            // the last capability will have its next pointer equal to zero
+            if offset < EXTENDED_CAPABILITIES_START {
+                return None;
+            }
+            let header = self.pci_read_ext_32(offset)?;
+            // A device without extended capabilities has an all-zero header,
+            // and a config space that doesn't exist reads as all ones.
+            if header == 0 || header == 0xFFFF_FFFF {
+                return None;
+            }
+            let current = match (header & 0xFFFF) as u16 {
+                SRIOV_EXTENDED_CAPABILITY => PciExtendedCapability::Sriov(Sriov { location, offset }),
+                id => PciExtendedCapability::Other { id, version: ((header >> 16) & 0xF) as u8, offset },
+            };
+            offset = ((header >> 20) & 0xFFC) as u16;
+            Some(current)
+        })
+    }
+
+    /// Returns this device's SR-IOV extended capability, if it has one.
+    pub fn sriov(&self) -> Option<Sriov> {
+        self.extended_capabilities().find_map(|cap| match cap {
+            PciExtendedCapability::Sriov(sriov) => Some(sriov),
+            _ => None,
+        })
+    }
+}
+
+
+/// A device power state, from fully on (D0) to off with power still applied (D3hot).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PowerState {
+    D0 = 0,
+    D1 = 1,
+    D2 = 2,
+    D3Hot = 3,
+}
+
+/// The power management capability of a PCI device, which allows changing its power state.
+#[derive(Clone, Copy, Debug)]
+pub struct PowerManagement {
+    location: PciLocation,
+    offset: u8,
+}
+
+impl PowerManagement {
+    /// Returns the offset of this capability in the config space.
+    pub fn offset(&self) -> u8 {
+        self.offset
+    }
+
+    /// Returns whether the device supports the given power state.
+    pub fn supports(&self, state: PowerState) -> bool {
+        let capabilities = self.location.pci_read_16(self.offset + PM_CAPABILITIES);
+        match state {
+            PowerState::D0 | PowerState::D3Hot => true,
+            PowerState::D1 => capabilities & PMC_D1_SUPPORT != 0,
+            PowerState::D2 => capabilities & PMC_D2_SUPPORT != 0,
+        }
+    }
+
+    /// Returns the device's current power state.
+    pub fn power_state(&self) -> PowerState {
+        match self.location.pci_read_16(self.offset + PM_CONTROL_STATUS) & PMCSR_POWER_STATE {
+            0 => PowerState::D0,
+            1 => PowerState::D1,
+            2 => PowerState::D2,
+            _ => PowerState::D3Hot,
+        }
+    }
+
+    /// Puts the device into the given power state.
+    ///
+    /// The device must not be accessed until the transition has completed,
+    /// e.g., for 10 ms after a transition from D3hot to D0, which is up to the caller.
+    pub fn set_power_state(&self, state: PowerState) -> Result<(), &'static str> {
+        if !self.supports(state) {
+            return Err("PCI device doesn't support the given power state");
+        }
+        // Don't write back the PME status bit, since writing 1 to it clears it.
+        let control = self.location.pci_read_16(self.offset + PM_CONTROL_STATUS)
+            & !(PMCSR_POWER_STATE | PMCSR_PME_STATUS);
+        self.location.pci_write(self.offset + PM_CONTROL_STATUS, (control | state as u16) as u32);
+        Ok(())
+    }
+}
+
+
+/// The location of a structure within the memory region of one of a device's BARs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BarRegion {
+    /// The index of the BAR, between `0` and `5` inclusive.
+    pub bar: usize,
+    /// The offset of the structure from the base address of the BAR.
+    pub offset: usize,
+}
+
+/// The MSI-X capability of a PCI device, which locates its MSI-X table.
+///
+/// This only describes the table; programming and enabling its vectors is up to the `msi` crate.
+#[derive(Clone, Copy, Debug)]
+pub struct Msix {
+    location: PciLocation,
+    offset: u8,
+}
+
+impl Msix {
+    /// Returns the location of the device that this capability belongs to.
+    pub fn location(&self) -> PciLocation {
+        self.location
+    }
+
+    /// Returns the offset of this capability in the config space.
+    pub fn offset(&self) -> u8 {
+        self.offset
+    }
+
+    /// Returns the number of vectors in the device's MSI-X table.
+    pub fn table_size(&self) -> u16 {
+        (self.location.pci_read_16(self.offset + MSIX_MESSAGE_CONTROL) & MSIX_TABLE_SIZE) + 1
+    }
+
+    /// Returns where the MSI-X table is located.
+    pub fn table(&self) -> BarRegion {
+        self.bar_region(MSIX_TABLE)
+    }
+
+    /// Returns where the Pending Bit Array is located, which has one bit per vector.
+    pub fn pending_bit_array(&self) -> BarRegion {
+        self.bar_region(MSIX_PBA)
+    }
+
+    fn bar_region(&self, register: u8) -> BarRegion {
+        let value = self.location.pci_read_32(self.offset + register);
+        BarRegion { bar: (value & MSIX_BIR) as usize, offset: (value & !MSIX_BIR) as usize }
+    }
+}
+
+
+/// The Single Root I/O Virtualization (SR-IOV) extended capability of a PCI Express device,
+/// which allows the device (the physical function) to expose lightweight virtual functions (VFs).
+///
+/// VFs are enabled after the PCI bus is scanned, so they never appear in [`get_pci_buses()`](crate::get_pci_buses);
+/// instead, use [`Sriov::vf_location()`] to access them.
+#[derive(Clone, Copy, Debug)]
+pub struct Sriov {
+    location: PciLocation,
+    offset: u16,
+}
+
+impl Sriov {
+    /// Returns the offset of this capability in the extended config space.
+    pub fn offset(&self) -> u16 {
+        self.offset
+    }
+
+    /// Reads a register of this capability, which is accessible because the capability was found through ECAM.
+    fn read_16(&self, register: u16) -> u16 {
+        self.location.pci_read_ext_16(self.offset + register).unwrap_or(0)
+    }
+
+    /// Returns the number of VFs that the device initially associates with the physical function.
+    pub fn initial_vfs(&self) -> u16 {
+        self.read_16(SRIOV_INITIAL_VFS)
+    }
+
+    /// Returns the maximum number of VFs that can be enabled.
+    pub fn total_vfs(&self) -> u16 {
+        self.read_16(SRIOV_TOTAL_VFS)
+    }
+
+    /// Returns the number of VFs that are enabled, which is zero if VFs are disabled.
+    pub fn num_vfs(&self) -> u16 {
+        if self.vfs_enabled() { self.read_16(SRIOV_NUM_VFS) } else { 0 }
+    }
+
+    pub fn vfs_enabled(&self) -> bool {
+        self.read_16(SRIOV_CONTROL) & SRIOV_VF_ENABLE != 0
+    }
+
+    /// Returns the device ID of the VFs; their vendor ID is the same as the physical function's.
+    pub fn vf_device_id(&self) -> u16 {
+        self.read_16(SRIOV_VF_DEVICE_ID)
+    }
+
+    /// Returns the location of the VF with the given zero-based `index`, if it's enabled.
+    pub fn vf_location(&self, index: u16) -> Option<PciLocation> {
+        if index >= self.num_vfs() {
+            return None;
+        }
+        // A VF's routing ID (bus, slot, and function) is an offset from that of the physical function.
+        let routing_id = ((self.location.bus as u16) << 8) | ((self.location.slot as u16) << 3) | self.location.func as u16;
+        let routing_id = routing_id
+            .checked_add(self.read_16(SRIOV_FIRST_VF_OFFSET))?
+            .checked_add(self.read_16(SRIOV_VF_STRIDE).checked_mul(index)?)?;
+        Some(PciLocation {
+            bus:  (routing_id >> 8) as u8,
+            slot: ((routing_id >> 3) & 0x1F) as u8,
+            func: (routing_id & 0x7) as u8,
+        })
+    }
+
+    /// Enables the given number of VFs, along with their memory space.
+    ///
+    /// The VFs must not be accessed for 100 ms after they are enabled, which is up to the caller.
+    pub fn enable_vfs(&self, num_vfs: u16) -> Result<(), &'static str> {
+        if num_vfs == 0 || num_vfs > self.total_vfs() {
+            return Err("SR-IOV: the number of VFs must be between 1 and the device's total VFs");
+        }
+        if self.vfs_enabled() {
+            return Err("SR-IOV: VFs are already enabled, so they must be disabled first");
+        }
+        // The VF offset and stride depend on the number of VFs, so it must be set before they're enabled.
+        self.location.pci_write_ext(self.offset + SRIOV_NUM_VFS, num_vfs as u32)?;
+        let control = self.read_16(SRIOV_CONTROL) | SRIOV_VF_ENABLE | SRIOV_VF_MEMORY_SPACE_ENABLE;
+        self.location.pci_write_ext(self.offset + SRIOV_CONTROL, control as u32)?;
+        debug!("SR-IOV: enabled {} VFs of device {}", num_vfs, self.location);
+        Ok(())
+    }
+
+    /// Disables all VFs, which must no longer be in use.
+    pub fn disable_vfs(&self) -> Result<(), &'static str> {
+        let control = self.read_16(SRIOV_CONTROL) & !(SRIOV_VF_ENABLE | SRIOV_VF_MEMORY_SPACE_ENABLE);
+        self.location.pci_write_ext(self.offset + SRIOV_CONTROL, control as u32)?;
+        self.location.pci_write_ext(self.offset + SRIOV_NUM_VFS, 0)
+    }
+}
@@ -0,0 +1,99 @@
+//! The Enhanced Configuration Access Mechanism (ECAM), i.e., memory-mapped PCI config space.
+//!
+//! Each function's 4 KiB config space is mapped at a fixed offset from a base address,
+//! which is given by the ACPI MCFG table. Unlike the legacy I/O ports, ECAM doesn't require locking
+//! and gives access to the extended config space beyond the first 256 bytes.
+//! See section 7.2.2 of the PCI Express Base specification 3.0.
+
+use core::ptr;
+use spin::Once;
+use memory::{allocate_frames_by_bytes_at, allocate_pages_by_bytes, MappedPages, PageTable, PhysicalAddress, PteFlags};
+use super::PciLocation;
+
+/// The size of each function's config space.
+pub const CONFIG_SPACE_SIZE: u16 = 4096;
+
+/// The memory-mapped config space of the buses in the first PCI segment group.
+static ECAM: Once<Ecam> = Once::new();
+
+struct Ecam {
+    mapping: MappedPages,
+    start_bus: u8,
+    end_bus: u8,
+}
+
+impl Ecam {
+    /// Returns the virtual address of the aligned 32-bit register at `offset` in the config space of `location`,
+    /// or `None` if its bus isn't covered by this mapping.
+    fn address(&self, location: &PciLocation, offset: u16) -> Option<usize> {
+        if location.bus < self.start_bus || location.bus > self.end_bus {
+            return None;
+        }
+        let offset_in_mapping = ((location.bus - self.start_bus) as usize) << 20
+            | (location.slot as usize) << 15
+            | (location.func as usize) << 12
+            | (offset & (CONFIG_SPACE_SIZE - 4)) as usize;
+        Some(self.mapping.start_address().value() + offset_in_mapping)
+    }
+}
+
+/// Maps the config space of the given range of buses in the first PCI segment group,
+/// after which it is accessed through memory rather than through the legacy I/O ports.
+///
+/// This must be invoked before the PCI bus is scanned, and can only be invoked once.
+///
+/// # Arguments
+/// * `base_address`: the address of the config space of bus 0, as given by the ACPI MCFG table,
+///    even if `start_bus` is greater than 0.
+/// * `start_bus` and `end_bus`: the first and last (inclusive) buses whose config space is mapped.
+/// * `page_table`: page table to install the mapping
+pub fn init_ecam(
+    base_address: PhysicalAddress,
+    start_bus: u8,
+    end_bus: u8,
+    page_table: &mut PageTable,
+) -> Result<(), &'static str> {
+    if end_bus < start_bus {
+        return Err("PCI ECAM: end bus is less than start bus");
+    }
+    if ECAM.is_completed() {
+        return Err("PCI ECAM was already initialized");
+    }
+    // Each bus has 32 slots with 8 functions each, so its config space is 1 MiB.
+    let start = base_address + ((start_bus as usize) << 20);
+    let size = ((end_bus - start_bus) as usize + 1) << 20;
+    let frames = allocate_frames_by_bytes_at(start, size)
+        .map_err(|_e| "PCI ECAM: couldn't allocate physical frames for the config space")?;
+    let pages = allocate_pages_by_bytes(size).ok_or("PCI ECAM: couldn't allocate virtual pages for the config space")?;
+    let flags = PteFlags::new().valid(true).writable(true).device_memory(true);
+    let mapping = page_table.map_allocated_pages_to(pages, frames, flags)?;
+    info!("PCI ECAM: mapped config space of buses {}..={} at {:#X}", start_bus, end_bus, start);
+    ECAM.call_once(|| Ecam { mapping, start_bus, end_bus });
+    Ok(())
+}
+
+/// Returns whether the config space is accessed through memory rather than through the legacy I/O ports.
+pub fn ecam_enabled() -> bool {
+    ECAM.is_completed()
+}
+
+/// Reads the aligned 32-bit register at `offset` in the config space of `location`,
+/// or returns `None` if it isn't accessible through ECAM.
+pub(crate) fn read(location: &PciLocation, offset: u16) -> Option<u32> {
+    let address = ECAM.get()?.address(location, offset)?;
+    // SAFETY: the address is within the config space mapping, which is never unmapped.
+    Some(unsafe { ptr::read_volatile(address as *const u32) })
+}
+
+/// Writes the aligned 32-bit register at `offset` in the config space of `location`,
+/// returning `false` if it isn't accessible through ECAM.
+pub(crate) fn write(location: &PciLocation, offset: u16, value: u32) -> bool {
+    match ECAM.get().and_then(|ecam| ecam.address(location, offset)) {
+        // SAFETY: the address is within the config space mapping, which is never unmapped.
+        Some(address) => {
+            unsafe { ptr::write_volatile(address as *mut u32, value) };
+            true
+        }
+        None => false,
+    }
+}
@@ -17,6 +17,12 @@ use spin::{Once, Mutex};
 use memory::PhysicalAddress;
 use bit_field::BitField;
 
+mod capability;
+mod ecam;
+
+pub use capability::*;
+pub use ecam::{init_ecam, ecam_enabled};
+
 // The below constants define the PCI configuration space. 
 // More info here: <http://wiki.osdev.org/PCI#PCI_Device_Structure>
 pub const PCI_VENDOR_ID:             u8 = 0x0;
@@ -49,9 +55,15 @@ pub const PCI_MIN_GRANT:             u8 = 0x3E;
 pub const PCI_MAX_LATENCY:           u8 = 0x3F;
 
 // PCI Capability IDs
+pub const POWER_MANAGEMENT_CAPABILITY: u8 = 0x01;
 pub const MSI_CAPABILITY:           u8 = 0x05;
+pub const VENDOR_SPECIFIC_CAPABILITY: u8 = 0x09;
+pub const PCI_EXPRESS_CAPABILITY:   u8 = 0x10;
 pub const MSIX_CAPABILITY:          u8 = 0x11;
 
+// PCI Express Extended Capability IDs
+pub const SRIOV_EXTENDED_CAPABILITY: u16 = 0x0010;
+
 /// If a BAR's bits [2:1] equal this value, that BAR describes a 64-bit address.
 /// If not, that BAR describes a 32-bit address.
 const BAR_ADDRESS_IS_64_BIT: u32 = 2;
@@ -220,12 +232,30 @@ impl PciLocation {
         0x8000_0000
     }
 
+    /// Reads the 32-bit register that contains `offset`, 
+    /// through ECAM if it's available or through the legacy I/O ports otherwise.
+    fn read_config(&self, offset: u8) -> u32 {
+        ecam::read(self, offset as u16).unwrap_or_else(|| {
+            // Hold the address port lock until the data has been read, so no one else can change the address.
+            let mut address_port = PCI_CONFIG_ADDRESS_PORT.lock();
+            unsafe { address_port.write(self.pci_address(offset)); }
+            Self::read_data_port()
+        })
+    }
+
+    /// Writes the 32-bit register that contains `offset`,
+    /// through ECAM if it's available or through the legacy I/O ports otherwise.
+    fn write_config(&self, offset: u8, value: u32) {
+        if !ecam::write(self, offset as u16, value) {
+            let mut address_port = PCI_CONFIG_ADDRESS_PORT.lock();
+            unsafe { address_port.write(self.pci_address(offset)); }
+            Self::write_data_port(value);
+        }
+    }
+
     /// read 32-bit data at the specified `offset` from the PCI device specified by the given `bus`, `slot`, `func` set.
     pub fn pci_read_32(&self, offset: u8) -> u32 {
-        unsafe { 
-            PCI_CONFIG_ADDRESS_PORT.lock().write(self.pci_address(offset)); 
-        }
-        Self::read_data_port() >> ((offset & (!PCI_CONFIG_ADDRESS_OFFSET_MASK)) * 8)
+        self.read_config(offset) >> ((offset & (!PCI_CONFIG_ADDRESS_OFFSET_MASK)) * 8)
     }
 
     /// Read 16-bit data at the specified `offset` from this PCI device.
@@ -240,9 +270,38 @@ impl PciLocation {
 
     /// Write 32-bit data to the specified `offset` for the PCI device.
     pub fn pci_write(&self, offset: u8, value: u32) {
-        unsafe {
-            PCI_CONFIG_ADDRESS_PORT.lock().write(self.pci_address(offset)); 
-            Self::write_data_port((value) << ((offset & 2) * 8));
+        self.write_config(offset, (value) << ((offset & 2) * 8));
+    }
+
+    /// Reads 32-bit data at the specified `offset` in this device's extended config space,
+    /// which spans 4096 bytes rather than 256 but is only accessible through ECAM.
+    ///
+    /// Returns `None` if ECAM isn't available for this device or `offset` is beyond its config space.
+    pub fn pci_read_ext_32(&self, offset: u16) -> Option<u32> {
+        if offset >= ecam::CONFIG_SPACE_SIZE {
+            return None;
+        }
+        ecam::read(self, offset).map(|value| value >> ((offset & 3) * 8))
+    }
+
+    /// Reads 16-bit data at the specified `offset` in this device's extended config space.
+    /// See [`pci_read_ext_32()`](Self::pci_read_ext_32).
+    pub fn pci_read_ext_16(&self, offset: u16) -> Option<u16> {
+        self.pci_read_ext_32(offset).map(|value| value as u16)
+    }
+
+    /// Writes 32-bit data to the specified `offset` in this device's extended config space,
+    /// in the same manner as [`pci_write()`](Self::pci_write).
+    ///
+    /// Returns an error if ECAM isn't available for this device or `offset` is beyond its config space.
+    pub fn pci_write_ext(&self, offset: u16, value: u32) -> Result<(), &'static str> {
+        if offset >= ecam::CONFIG_SPACE_SIZE {
+            return Err("PCI config space offset is out of bounds");
+        }
+        if ecam::write(self, offset, value << ((offset & 2) * 8)) {
+            Ok(())
+        } else {
+            Err("PCI extended config space is only accessible through ECAM")
         }
     }
 
@@ -258,29 +317,23 @@ impl PciLocation {
 
     /// Sets the PCI device's bit 3 in the command portion, which is apparently needed to activate DMA (??)
     pub fn pci_set_command_bus_master_bit(&self) {
-        unsafe { 
-            PCI_CONFIG_ADDRESS_PORT.lock().write(self.pci_address(PCI_COMMAND));
-        }
-        let inval = Self::read_data_port(); 
+        let inval = self.read_config(PCI_COMMAND); 
         trace!("pci_set_command_bus_master_bit: PciDevice: {}, read value: {:#x}", self, inval);
-        Self::write_data_port(inval | (1 << 2));
+        self.write_config(PCI_COMMAND, inval | (1 << 2));
         trace!("pci_set_command_bus_master_bit: PciDevice: {}, read value AFTER WRITE CMD: {:#x}", 
             self,
-            Self::read_data_port()
+            self.read_config(PCI_COMMAND)
         );
     }
 
     /// Sets the PCI device's command bit 10 to disable legacy interrupts
     pub fn pci_set_interrupt_disable_bit(&self) {
-        unsafe { 
-            PCI_CONFIG_ADDRESS_PORT.lock().write(self.pci_address(PCI_COMMAND));
-        }
-        let command = Self::read_data_port(); 
+        let command = self.read_config(PCI_COMMAND); 
         trace!("pci_set_interrupt_disable_bit: PciDevice: {}, read value: {:#x}", self, command);
         const INTERRUPT_DISABLE: u32 = 1 << 10;
-        Self::write_data_port(command | INTERRUPT_DISABLE);
+        self.write_config(PCI_COMMAND, command | INTERRUPT_DISABLE);
         trace!("pci_set_interrupt_disable_bit: PciDevice: {} read value AFTER WRITE CMD: {:#x}", 
-            self, Self::read_data_port());
+            self, self.read_config(PCI_COMMAND));
     }
 
     /// Explores the PCI config space and returns address of requested capability, if present. 
@@ -324,6 +377,31 @@ impl PciLocation {
         }
         None
     }
+
+    /// Returns an iterator over the ID and address of every capability in the PCI config space,
+    /// which is empty if capabilities are not valid for this device.
+    ///
+    /// Unlike [`find_pci_capability()`](Self::find_pci_capability), this includes multiple capabilities
+    /// with the same ID, e.g., the vendor-specific capabilities of a virtio device.
+    pub fn pci_capabilities(&self) -> impl Iterator<Item = (u8, u8)> + '_ {
+        // capabilities are only valid if bit 4 of status register is set
+        const CAPABILITIES_VALID: u16 = 1 << 4;
+        let mut cap_addr = if self.pci_read_16(PCI_STATUS) & CAPABILITIES_VALID != 0 {
+            self.pci_read_8(PCI_CAPABILITIES) & 0xFC
+        } else {
+            0
+        };
+        core::iter::from_fn(move || {
+            // the last capability will have its next pointer equal to zero
+            if cap_addr == 0 {
+                return None;
+            }
+            let cap_header = self.pci_read_16(cap_addr);
+            let current = ((cap_header & 0xFF) as u8, cap_addr);
+            cap_addr = ((cap_header >> 8) & 0xFC) as u8;
+            Some(current)
+        })
+    }
 }
 
 impl fmt::Display for PciLocation {
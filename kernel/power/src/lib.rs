@@ -0,0 +1,213 @@
+//! Shutting down and rebooting the machine.
+//!
+//! Shutdown enters the ACPI S5 (soft off) sleep state, using the `\_S5` sleep type
+//! from the DSDT and the PM1 control registers from the FADT.
+//! Reboot uses the FADT's reset register if it is supported.
+//!
+//! If ACPI is unavailable or fails, both fall back to legacy mechanisms:
+//! emulator-specific shutdown ports, the keyboard controller's reset line,
+//! and finally a triple fault.
+
+#![no_std]
+
+use core::hint::spin_loop;
+use log::{error, info, warn};
+use port_io::Port;
+use x86_64::instructions::{hlt, interrupts};
+
+/// The `SCI_EN` bit of the PM1 control register, which is set once the system is in ACPI mode.
+const SCI_EN: u16 = 1 << 0;
+/// The `SLP_EN` bit of the PM1 control register, which enters the sleep state given by `SLP_TYP`.
+const SLP_EN: u16 = 1 << 13;
+/// The bit offset of the `SLP_TYP` field in the PM1 control register.
+const SLP_TYP_SHIFT: u16 = 10;
+/// The FADT flag indicating that the reset register is supported.
+const RESET_REG_SUP: u32 = 1 << 10;
+/// The address space ID of the system I/O space in a generic address structure.
+const ADDRESS_SPACE_IO: u8 = 1;
+/// The number of times to poll for the system to enter ACPI mode before giving up.
+const ACPI_ENABLE_POLLS: usize = 1_000_000;
+
+/// The keyboard controller's status and command port.
+const KEYBOARD_CONTROLLER_PORT: u16 = 0x64;
+/// The keyboard controller's status bit indicating that its input buffer is full.
+const KEYBOARD_INPUT_FULL: u8 = 1 << 1;
+/// The keyboard controller command that pulses the CPU reset line.
+const KEYBOARD_RESET_COMMAND: u8 = 0xFE;
+
+/// The ports and values that power off common emulators that lack a usable ACPI setup:
+/// QEMU, Bochs and older QEMU versions, and VirtualBox.
+const EMULATOR_SHUTDOWN_PORTS: [(u16, u16); 3] = [
+    (0x604, 0x2000),
+    (0xB004, 0x2000),
+    (0x4004, 0x3400),
+];
+
+/// The FADT and DSDT values needed to enter a sleep state, copied out of the ACPI tables
+/// such that the tables need not stay locked.
+struct SleepRegisters {
+    pm1a_control: u16,
+    pm1b_control: u16,
+    smi_command: u16,
+    acpi_enable: u8,
+    sleep_type: dsdt::SleepType,
+}
+
+/// Powers off the machine.
+///
+/// This first tries to enter the ACPI S5 sleep state, then falls back to emulator-specific ports.
+/// If all of those fail, this halts the current CPU forever.
+pub fn shutdown() -> ! {
+    info!("Shutting down...");
+    match sleep_registers(5) {
+        Ok(registers) => {
+            interrupts::disable();
+            // SAFETY: these ports are specified by the FADT, and we are powering off the machine.
+            unsafe { enter_sleep_state(&registers) };
+            warn!("ACPI shutdown failed, trying emulator-specific ports");
+        }
+        Err(e) => warn!("ACPI shutdown is unavailable: {}. Trying emulator-specific ports", e),
+    }
+
+    interrupts::disable();
+    for (port, value) in EMULATOR_SHUTDOWN_PORTS {
+        // SAFETY: these ports have no effect on real hardware that lacks them.
+        unsafe { Port::<u16>::new(port).write(value) };
+    }
+    error!("Couldn't shut down the machine, halting instead");
+    halt()
+}
+
+/// Reboots the machine.
+///
+/// This first tries the ACPI reset register, then the keyboard controller,
+/// and finally triggers a triple fault, which resets the CPU.
+pub fn reboot() -> ! {
+    info!("Rebooting...");
+    interrupts::disable();
+    match acpi_reset() {
+        Ok(()) => warn!("ACPI reset failed, trying the keyboard controller"),
+        Err(e) => warn!("ACPI reset is unavailable: {}. Trying the keyboard controller", e),
+    }
+
+    let keyboard_controller = Port::<u8>::new(KEYBOARD_CONTROLLER_PORT);
+    for _ in 0..ACPI_ENABLE_POLLS {
+        if keyboard_controller.read() & KEYBOARD_INPUT_FULL == 0 {
+            break;
+        }
+        spin_loop();
+    }
+    // SAFETY: this pulses the CPU reset line, and we are rebooting the machine.
+    unsafe { keyboard_controller.write(KEYBOARD_RESET_COMMAND) };
+    for _ in 0..ACPI_ENABLE_POLLS {
+        spin_loop();
+    }
+
+    warn!("Keyboard controller reset failed, triggering a triple fault");
+    triple_fault()
+}
+
+/// Reads the registers and sleep type needed to enter the given sleep `state`.
+fn sleep_registers(state: u8) -> Result<SleepRegisters, &'static str> {
+    let acpi_tables = acpi::get_acpi_tables().lock();
+    let fadt = fadt::Fadt::get(&acpi_tables).ok_or("the FADT wasn't found")?;
+    let sleep_type = dsdt::Dsdt::get(&acpi_tables)
+        .ok_or("the DSDT wasn't found")?
+        .sleep_type(state)
+        .ok_or("the DSDT doesn't define the sleep state")?;
+
+    let pm1a_control = fadt.pm1a_control_block;
+    let pm1b_control = fadt.pm1b_control_block;
+    if pm1a_control == 0 {
+        return Err("the FADT doesn't specify a PM1a control block");
+    }
+    let smi_command = fadt.smi_command_port;
+    Ok(SleepRegisters {
+        pm1a_control: u16::try_from(pm1a_control).map_err(|_| "invalid PM1a control block port")?,
+        pm1b_control: u16::try_from(pm1b_control).map_err(|_| "invalid PM1b control block port")?,
+        smi_command: u16::try_from(smi_command).map_err(|_| "invalid SMI command port")?,
+        acpi_enable: fadt.acpi_enable,
+        sleep_type,
+    })
+}
+
+/// Switches the system into ACPI mode if needed, then enters the sleep state.
+///
+/// # Safety
+/// The given registers must be those specified by the FADT.
+unsafe fn enter_sleep_state(registers: &SleepRegisters) {
+    let pm1a = Port::<u16>::new(registers.pm1a_control);
+    if pm1a.read() & SCI_EN == 0 {
+        if registers.smi_command == 0 || registers.acpi_enable == 0 {
+            warn!("The system isn't in ACPI mode and can't be switched to it");
+            return;
+        }
+        Port::<u8>::new(registers.smi_command).write(registers.acpi_enable);
+        let enabled = (0..ACPI_ENABLE_POLLS).any(|_| {
+            spin_loop();
+            pm1a.read() & SCI_EN != 0
+        });
+        if !enabled {
+            warn!("Timed out switching the system into ACPI mode");
+            return;
+        }
+    }
+
+    let sleep_value = |slp_typ: u8| ((slp_typ as u16 & 0b111) << SLP_TYP_SHIFT) | SLP_EN;
+    pm1a.write(sleep_value(registers.sleep_type.slp_typ_a));
+    if registers.pm1b_control != 0 {
+        Port::<u16>::new(registers.pm1b_control).write(sleep_value(registers.sleep_type.slp_typ_b));
+    }
+    // The machine may take a moment to power off.
+    for _ in 0..ACPI_ENABLE_POLLS {
+        spin_loop();
+    }
+}
+
+/// Writes the FADT's reset value to its reset register.
+///
+/// Returns `Ok` if the write happened but the machine didn't reset.
+fn acpi_reset() -> Result<(), &'static str> {
+    let (reset_reg, reset_value) = {
+        let acpi_tables = acpi::get_acpi_tables().lock();
+        let fadt = fadt::Fadt::get(&acpi_tables).ok_or("the FADT wasn't found")?;
+        let flags = fadt.flags;
+        if flags & RESET_REG_SUP == 0 {
+            return Err("the FADT's reset register isn't supported");
+        }
+        (fadt.reset_reg, fadt.reset_value)
+    };
+    if reset_reg.address_space != ADDRESS_SPACE_IO {
+        return Err("the FADT's reset register isn't in the I/O space");
+    }
+    let port = u16::try_from(reset_reg.phys_addr).map_err(|_| "invalid reset register port")?;
+    // SAFETY: this port is specified by the FADT, and we are rebooting the machine.
+    unsafe { Port::<u8>::new(port).write(reset_value) };
+    for _ in 0..ACPI_ENABLE_POLLS {
+        spin_loop();
+    }
+    Ok(())
+}
+
+/// Resets the CPU by loading an empty IDT and raising an exception,
+/// which can't be delivered and therefore causes a triple fault.
+fn triple_fault() -> ! {
+    let empty_idt = x86_64::structures::DescriptorTablePointer {
+        limit: 0,
+        base: x86_64::VirtAddr::zero(),
+    };
+    // SAFETY: we intend to reset the machine, so no further interrupts need to be handled.
+    unsafe {
+        x86_64::instructions::tables::lidt(&empty_idt);
+        core::arch::asm!("int3", options(nomem, nostack));
+    }
+    halt()
+}
+
+/// Halts the current CPU forever.
+fn halt() -> ! {
+    loop {
+        interrupts::disable();
+        hlt();
+    }
+}
@@ -141,6 +141,33 @@ bitflags! {
         //
         // This does not require a conversion between architectures.
         const EXCLUSIVE = PteFlagsArch::EXCLUSIVE.bits();
+
+        /// Note: code that invokes memory management functions in Theseus cannot actually
+        ///       set this flag. When flags are passed to those functions, 
+        ///       this bit value is ignored and overridden as appropriate.
+        /// 
+        /// * If set, this P1-level page table entry maps a frame that is shared
+        ///   by multiple copy-on-write mappings, and is thus mapped as read-only
+        ///   even though its mapping is logically writable.
+        ///   The first write to this page causes a page fault, upon which the page
+        ///   is given its own private copy of that frame.
+        /// * If not set, this page is not a copy-on-write page.
+        //
+        // This does not require a conversion between architectures.
+        const COPY_ON_WRITE = PteFlagsArch::COPY_ON_WRITE.bits();
+
+        /// Note: code that invokes memory management functions in Theseus cannot actually
+        ///       set this flag. When flags are passed to those functions, 
+        ///       this bit value is ignored and overridden as appropriate.
+        /// 
+        /// * If set, this P1-level page table entry belongs to a demand-paged mapping
+        ///   but is not yet backed by a frame, so it is not present (valid).
+        ///   The first access to this page causes a page fault, upon which a zeroed frame
+        ///   is allocated and mapped with the other flags of this entry.
+        /// * If not set, this page is not an unbacked demand-paged page.
+        //
+        // This does not require a conversion between architectures.
+        const ON_DEMAND = PteFlagsArch::ON_DEMAND.bits();
     }
 }
 
@@ -247,6 +274,26 @@ impl PteFlags {
         self
     }
 
+    /// Returns a copy of this `PteFlags` with the `COPY_ON_WRITE` bit set or cleared.
+    ///
+    /// * If `enable` is `true`, this page maps a shared frame that is copied upon the first write.
+    /// * If `enable` is `false`, this page is not a copy-on-write page.
+    #[must_use]
+    pub fn copy_on_write(mut self, enable: bool) -> Self {
+        self.set(Self::COPY_ON_WRITE, enable);
+        self
+    }
+
+    /// Returns a copy of this `PteFlags` with the `ON_DEMAND` bit set or cleared.
+    ///
+    /// * If `enable` is `true`, this page is not yet backed by a frame, which is allocated upon first access.
+    /// * If `enable` is `false`, this page is not an unbacked demand-paged page.
+    #[must_use]
+    pub fn on_demand(mut self, enable: bool) -> Self {
+        self.set(Self::ON_DEMAND, enable);
+        self
+    }
+
     /// Returns a copy of this `PteFlags` with the `ACCESSED` bit set or cleared.
     ///
     /// Typically this is used to clear the `ACCESSED` bit, in order to indicate
@@ -307,4 +354,12 @@ impl PteFlags {
     pub const fn is_exclusive(&self) -> bool {
         self.contains(Self::EXCLUSIVE)
     }
+
+    pub const fn is_copy_on_write(&self) -> bool {
+        self.contains(Self::COPY_ON_WRITE)
+    }
+
+    pub const fn is_on_demand(&self) -> bool {
+        self.contains(Self::ON_DEMAND)
+    }
 }
@@ -184,6 +184,14 @@ bitflags! {
         /// See [PteFlags::EXCLUSIVE].
         ///  We use bit 55 because it is available for custom OS usage on both x86_64 and aarch64.
         const EXCLUSIVE          = 1 << 55;
+
+        /// See [PteFlags::COPY_ON_WRITE].
+        ///  We use bit 56 because it is available for custom OS usage on both x86_64 and aarch64.
+        const COPY_ON_WRITE      = 1 << 56;
+
+        /// See [PteFlags::ON_DEMAND].
+        ///  We use bit 57 because it is available for custom OS usage on both x86_64 and aarch64.
+        const ON_DEMAND          = 1 << 57;
     }
 }
 
@@ -290,6 +298,26 @@ impl PteFlagsAarch64 {
         self
     }
 
+    /// Returns a copy of this `PteFlagsAarch64` with the `COPY_ON_WRITE` bit set or cleared.
+    ///
+    /// * If `enable` is `true`, this page maps a shared frame that is copied upon the first write.
+    /// * If `enable` is `false`, this page is not a copy-on-write page.
+    #[must_use]
+    pub fn copy_on_write(mut self, enable: bool) -> Self {
+        self.set(Self::COPY_ON_WRITE, enable);
+        self
+    }
+
+    /// Returns a copy of this `PteFlagsAarch64` with the `ON_DEMAND` bit set or cleared.
+    ///
+    /// * If `enable` is `true`, this page is not yet backed by a frame, which is allocated upon first access.
+    /// * If `enable` is `false`, this page is not an unbacked demand-paged page.
+    #[must_use]
+    pub fn on_demand(mut self, enable: bool) -> Self {
+        self.set(Self::ON_DEMAND, enable);
+        self
+    }
+
     /// Returns a copy of this `PteFlagsAarch64` with the `ACCESSED` bit set or cleared.
     ///
     /// Typically this is used to clear the `ACCESSED` bit, in order to indicate
@@ -350,6 +378,14 @@ impl PteFlagsAarch64 {
     pub const fn is_exclusive(&self) -> bool {
         self.contains(Self::EXCLUSIVE)
     }
+
+    pub const fn is_copy_on_write(&self) -> bool {
+        self.contains(Self::COPY_ON_WRITE)
+    }
+
+    pub const fn is_on_demand(&self) -> bool {
+        self.contains(Self::ON_DEMAND)
+    }
 }
 
 /// Functions specific to aarch64 PTE flags only.
@@ -376,6 +412,8 @@ impl PteFlagsAarch64 {
     ///     because another page table frame may re-use it (create another alias to it)
     ///     without our page table implementation knowing about it.
     ///   * Only P1-level PTEs can map a frame exclusively.
+    /// * Clears the `COPY_ON_WRITE` and `ON_DEMAND` bits, as only P1-level PTEs can be
+    ///   copy-on-write or demand-paged.
     /// * Sets the `ACCESSED` bit, since Theseus currently does not use it
     ///   and aarch64 will throw an Access Flag Fault if it is not set.
     /// * Sets the `PAGE_DESCRIPTOR` bit, since Theseus currently does not
@@ -385,6 +423,8 @@ impl PteFlagsAarch64 {
     pub fn adjust_for_higher_level_pte(self) -> Self {
         self.executable(true)
             .exclusive(false)
+            .copy_on_write(false)
+            .on_demand(false)
             .accessed(true)
             .page_descriptor(true)
             .valid(true)
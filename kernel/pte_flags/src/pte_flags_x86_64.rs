@@ -116,6 +116,14 @@ bitflags! {
         ///  We use bit 55 because it is available for custom OS usage on both x86_64 and aarch64.
         const EXCLUSIVE          = 1 << 55;
 
+        /// See [PteFlags::COPY_ON_WRITE].
+        ///  We use bit 56 because it is available for custom OS usage on both x86_64 and aarch64.
+        const COPY_ON_WRITE      = 1 << 56;
+
+        /// See [PteFlags::ON_DEMAND].
+        ///  We use bit 57 because it is available for custom OS usage on both x86_64 and aarch64.
+        const ON_DEMAND          = 1 << 57;
+
         /// * If set, this page is not executable.
         /// * If not set, this page is executable.
         const NOT_EXECUTABLE     = 1 << 63;
@@ -195,6 +203,26 @@ impl PteFlagsX86_64 {
         self
     }
 
+    /// Returns a copy of this `PteFlagsX86_64` with the `COPY_ON_WRITE` bit set or cleared.
+    ///
+    /// * If `enable` is `true`, this page maps a shared frame that is copied upon the first write.
+    /// * If `enable` is `false`, this page is not a copy-on-write page.
+    #[must_use]
+    pub fn copy_on_write(mut self, enable: bool) -> Self {
+        self.set(Self::COPY_ON_WRITE, enable);
+        self
+    }
+
+    /// Returns a copy of this `PteFlagsX86_64` with the `ON_DEMAND` bit set or cleared.
+    ///
+    /// * If `enable` is `true`, this page is not yet backed by a frame, which is allocated upon first access.
+    /// * If `enable` is `false`, this page is not an unbacked demand-paged page.
+    #[must_use]
+    pub fn on_demand(mut self, enable: bool) -> Self {
+        self.set(Self::ON_DEMAND, enable);
+        self
+    }
+
     /// Returns a copy of this `PteFlagsX86_64` with the `ACCESSED` bit set or cleared.
     ///
     /// Typically this is used to clear the `ACCESSED` bit, in order to indicate
@@ -255,6 +283,14 @@ impl PteFlagsX86_64 {
     pub const fn is_exclusive(&self) -> bool {
         self.contains(Self::EXCLUSIVE)
     }
+
+    pub const fn is_copy_on_write(&self) -> bool {
+        self.contains(Self::COPY_ON_WRITE)
+    }
+
+    pub const fn is_on_demand(&self) -> bool {
+        self.contains(Self::ON_DEMAND)
+    }
 }
 
 const BIT_0: u8 = 1 << 0;
@@ -275,12 +311,16 @@ impl PteFlagsX86_64 {
     ///     because another page table frame may re-use it (create another alias to it)
     ///     without our page table implementation knowing about it.
     ///   * Only P1-level PTEs can map a frame exclusively.
+    /// * Clears the `COPY_ON_WRITE` and `ON_DEMAND` bits, as only P1-level PTEs can be
+    ///   copy-on-write or demand-paged.
     /// * Clears the PAT index value, as we only support PAT on P1-level PTEs.
     /// * Sets the `VALID` bit, as every P4, P3, and P2 entry must be valid.
     #[must_use]
     pub fn adjust_for_higher_level_pte(self) -> Self {
         self.executable(true)
             .exclusive(false)
+            .copy_on_write(false)
+            .on_demand(false)
             .pat_index(0)
             .valid(true)
     }
@@ -312,6 +352,19 @@ impl PteFlagsX86_64 {
         pat_index
     }
 
+    /// Returns a copy of this `PteFlagsX86_64` with the `HUGE_PAGE` bit set or cleared.
+    ///
+    /// * If `enable` is `true`, this P3- or P2-level entry maps a huge page.
+    /// * If `enable` is `false`, this entry points to a lower-level page table.
+    ///
+    /// This must not be used for P1-level entries, in which this bit is the
+    /// most-significant bit of the PAT index; see [`Self::pat_index()`].
+    #[must_use]
+    pub fn huge(mut self, enable: bool) -> Self {
+        self.set(Self::HUGE_PAGE, enable);
+        self
+    }
+
     pub const fn is_huge(&self) -> bool {
         self.contains(Self::HUGE_PAGE)
     }
@@ -19,6 +19,10 @@
 //! seed a local PRNG, which can then be used as a source of randomness. Using a
 //! local PRNG avoids contention on the global CSPRNG and allows for PRNGs
 //! better suited for the task (e.g. non-crypto PRNGs).
+//!
+//! For fast, non-cryptographic randomness, [`random_u64`] uses a per-task
+//! xoshiro256** PRNG whose state lives in the current task's TLS area.
+//! It is seeded from the global CSPRNG when the task is spawned.
 
 #![no_std]
 
@@ -138,6 +142,65 @@ pub fn fill_bytes(dest: &mut [u8]) {
     csprng.fill_bytes(dest);
 }
 
+/// The per-task PRNG state stored in the TLS area (see [`tls_initializer::RNG_STATE_OFFSET`]).
+#[repr(C)]
+struct TaskRngState {
+    /// Nonzero once this state has been seeded.
+    seeded: u64,
+    /// The xoshiro256** state, which must not be all zeroes.
+    s: [u64; 4],
+}
+
+const _: () = assert!(core::mem::size_of::<TaskRngState>() <= tls_initializer::RNG_STATE_SIZE);
+
+/// Returns the current task's PRNG state.
+fn current_task_rng_state() -> *mut TaskRngState {
+    tls_initializer::rng_state_location() as *mut TaskRngState
+}
+
+/// Re-seeds the current task's PRNG from the global CSPRNG.
+///
+/// This is invoked when a new task is spawned, and must also be invoked
+/// whenever a task's TLS area is duplicated or restored (e.g., when forking
+/// or restoring a checkpoint) to avoid multiple tasks generating the same numbers.
+pub fn reseed_current_task_rng() {
+    let mut s = [0; 4];
+    {
+        let mut csprng = CSPRNG.lock();
+        while s == [0; 4] {
+            s = [csprng.next_u64(), csprng.next_u64(), csprng.next_u64(), csprng.next_u64()];
+        }
+    }
+    // SAFE: the TLS area always reserves a region for the PRNG state,
+    // which is only ever accessed by the current task.
+    unsafe { current_task_rng_state().write(TaskRngState { seeded: 1, s }) };
+}
+
+/// Returns a random [`u64`] from the current task's PRNG, which is stored in its TLS area.
+///
+/// This never takes a lock unless the current task's PRNG hasn't been seeded yet.
+/// The results are **not** cryptographically secure; use [`next_u64`] for that.
+pub fn random_u64() -> u64 {
+    let state = current_task_rng_state();
+    // SAFE: the TLS area always reserves a region for the PRNG state,
+    // which is only ever accessed by the current task.
+    unsafe {
+        if (*state).seeded == 0 {
+            reseed_current_task_rng();
+        }
+        let s = &mut (*state).s;
+        let result = s[1].wrapping_mul(5).rotate_left(7).wrapping_mul(9);
+        let t = s[1] << 17;
+        s[2] ^= s[0];
+        s[3] ^= s[1];
+        s[1] ^= s[2];
+        s[0] ^= s[3];
+        s[2] ^= t;
+        s[3] = s[3].rotate_left(45);
+        result
+    }
+}
+
 /// Initialises a `T` RNG.
 ///
 /// Directly accessing the global CSPRNG can be expensive and so it is often
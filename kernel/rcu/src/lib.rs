@@ -0,0 +1,311 @@
+//! Read-copy-update (RCU) synchronization for read-mostly kernel data structures.
+//!
+//! Readers access RCU-protected data without taking any locks or writing to shared memory:
+//! they only need to hold an [`RcuReadGuard`], obtained from [`read_lock()`],
+//! for as long as they use the data. An updater publishes a new copy of the data
+//! and then defers reclaiming the old copy until every reader that could still be
+//! using it has finished, i.e., until a *grace period* has elapsed.
+//!
+//! Holding an [`RcuReadGuard`] disables preemption, so a CPU cannot switch tasks
+//! while it is inside a read-side critical section. Thus, every time a CPU enters
+//! the scheduler with preemption enabled, it passes through a *quiescent state*
+//! in which it holds no references to RCU-protected data; the scheduler reports this
+//! via [`note_quiescent_state()`]. A grace period has elapsed once every CPU has passed
+//! through a quiescent state after it started. Since the scheduler runs upon every
+//! timer tick, grace periods typically last no longer than one tick.
+//!
+//! Old data is reclaimed either by blocking in [`synchronize()`] until a grace period
+//! has elapsed, or by deferring a callback via [`call_rcu()`], which is invoked during
+//! a later quiescent state. [`Rcu`] wraps a single value and handles both sides of this.
+//!
+//! A CPU only participates in grace periods once it has entered the scheduler for the first time,
+//! so RCU-protected data must not be read by a CPU during its early boot.
+//! Likewise, an offline CPU stops participating via [`note_cpu_offline()`] until it comes back online.
+
+#![no_std]
+
+extern crate alloc;
+
+use alloc::{boxed::Box, collections::VecDeque, vec::Vec};
+use core::{
+    fmt,
+    marker::PhantomData,
+    ops::Deref,
+    ptr,
+    sync::atomic::{AtomicPtr, AtomicU64, AtomicUsize, Ordering},
+};
+use irq_safety::MutexIrqSafe;
+use preemption::PreemptionGuard;
+
+/// The maximum number of CPUs, one for each possible CPU ID.
+const MAX_CPUS: usize = u8::MAX as usize + 1;
+
+/// The current grace-period epoch, which is advanced whenever a grace period is started.
+static EPOCH: AtomicU64 = AtomicU64::new(1);
+/// The epoch at each CPU's most recent quiescent state,
+/// or `0` if that CPU has never passed through one and thus doesn't participate in grace periods.
+static CPU_EPOCHS: [AtomicU64; MAX_CPUS] = {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const ZERO: AtomicU64 = AtomicU64::new(0);
+    [ZERO; MAX_CPUS]
+};
+/// The deferred callbacks, each with the epoch after which it may be invoked, in increasing order of epoch.
+static CALLBACKS: MutexIrqSafe<VecDeque<(u64, Box<dyn FnOnce() + Send>)>> = MutexIrqSafe::new(VecDeque::new());
+/// The number of callbacks in `CALLBACKS`, which is checked before taking its lock upon each quiescent state.
+static NUM_CALLBACKS: AtomicUsize = AtomicUsize::new(0);
+
+/// A guard that marks a read-side critical section, during which
+/// RCU-protected data read by the current task will not be reclaimed.
+///
+/// Preemption is disabled as long as this guard is held,
+/// so read-side critical sections should be short and must not block.
+/// Guards may be nested.
+pub struct RcuReadGuard {
+    _preemption: PreemptionGuard,
+}
+
+impl fmt::Debug for RcuReadGuard {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "RcuReadGuard")
+    }
+}
+
+/// Enters a read-side critical section that lasts until the returned guard is dropped.
+pub fn read_lock() -> RcuReadGuard {
+    RcuReadGuard { _preemption: preemption::hold_preemption() }
+}
+
+/// Starts a new grace period, returning the epoch that every CPU must reach for it to have elapsed.
+fn start_grace_period() -> u64 {
+    EPOCH.fetch_add(1, Ordering::SeqCst) + 1
+}
+
+/// Returns the latest epoch that every participating CPU has reached.
+fn completed_epoch() -> u64 {
+    CPU_EPOCHS.iter()
+        .map(|epoch| epoch.load(Ordering::SeqCst))
+        .filter(|&epoch| epoch != 0)
+        .min()
+        .unwrap_or(u64::MAX)
+}
+
+/// Reports that the given CPU, which must be the current CPU, is in a quiescent state,
+/// i.e., that it isn't inside any read-side critical section.
+///
+/// This is invoked by the scheduler whenever it runs with preemption enabled.
+/// It also invokes the deferred callbacks whose grace period has elapsed,
+/// unless another CPU is already doing so.
+pub fn note_quiescent_state(cpu: u8) {
+    CPU_EPOCHS[cpu as usize].store(EPOCH.load(Ordering::SeqCst), Ordering::SeqCst);
+    if NUM_CALLBACKS.load(Ordering::Acquire) != 0 {
+        invoke_ready_callbacks(false);
+    }
+}
+
+/// Reports that the given CPU, which must be the current CPU, is going offline,
+/// after which it no longer participates in grace periods until it next passes through
+/// a quiescent state via [`note_quiescent_state()`].
+///
+/// The current CPU must not be inside any read-side critical section,
+/// and must not read RCU-protected data until it has passed through that quiescent state.
+pub fn note_cpu_offline(cpu: u8) {
+    CPU_EPOCHS[cpu as usize].store(0, Ordering::SeqCst);
+}
+
+/// Invokes every deferred callback whose grace period has elapsed.
+///
+/// If `wait` is `false`, this returns immediately if another CPU is already accessing the callbacks.
+fn invoke_ready_callbacks(wait: bool) {
+    let completed = completed_epoch();
+    let ready: Vec<_> = {
+        let mut callbacks = if wait {
+            CALLBACKS.lock()
+        } else {
+            match CALLBACKS.try_lock() {
+                Some(callbacks) => callbacks,
+                None => return,
+            }
+        };
+        let num_ready = callbacks.iter().take_while(|(epoch, _)| *epoch <= completed).count();
+        NUM_CALLBACKS.fetch_sub(num_ready, Ordering::AcqRel);
+        callbacks.drain(..num_ready).map(|(_, callback)| callback).collect()
+    };
+    for callback in ready {
+        callback();
+    }
+}
+
+/// Defers the given `callback` until a grace period has elapsed,
+/// i.e., until no reader can still be using data that was unpublished before this call.
+///
+/// The callback is invoked during a later quiescent state of some CPU,
+/// with preemption disabled, so it must be short and must not block;
+/// typically it just drops the unpublished data.
+pub fn call_rcu<F: FnOnce() + Send + 'static>(callback: F) {
+    let mut callbacks = CALLBACKS.lock();
+    // Start the grace period while holding the lock, such that the callbacks remain sorted by epoch.
+    let epoch = start_grace_period();
+    callbacks.push_back((epoch, Box::new(callback)));
+    NUM_CALLBACKS.fetch_add(1, Ordering::AcqRel);
+}
+
+/// Waits until a grace period has elapsed, i.e., until every read-side critical section
+/// that was active when this was called has ended, and then invokes the deferred callbacks
+/// whose grace period has elapsed.
+///
+/// This busy-waits, typically for no longer than one scheduler tick, so [`call_rcu()`]
+/// should be preferred where possible. It must not be called inside a read-side critical section.
+pub fn synchronize() -> Result<(), &'static str> {
+    let preemption = preemption::hold_preemption();
+    if !preemption.preemption_was_enabled() {
+        return Err("rcu::synchronize() cannot wait for a grace period with preemption disabled");
+    }
+    let epoch = start_grace_period();
+    // The current CPU isn't inside a read-side critical section, so it has already reached the new epoch.
+    note_quiescent_state(preemption.cpu_id());
+    drop(preemption);
+
+    while completed_epoch() < epoch {
+        core::hint::spin_loop();
+    }
+    invoke_ready_callbacks(true);
+    Ok(())
+}
+
+/// Returns the number of deferred callbacks that haven't been invoked yet.
+pub fn num_pending_callbacks() -> usize {
+    NUM_CALLBACKS.load(Ordering::Acquire)
+}
+
+/// A value protected by RCU, which can be read without locking and is updated by replacing it.
+///
+/// Readers obtain a reference to the current value via [`read()`](Rcu::read),
+/// which remains valid for as long as their [`RcuReadGuard`] is held,
+/// even if the value is replaced in the meantime.
+/// A replaced value is dropped once a grace period has elapsed.
+pub struct Rcu<T: Send + Sync + 'static> {
+    ptr: AtomicPtr<T>,
+    _phantom: PhantomData<Box<T>>,
+}
+
+/// A pointer to a replaced value that is sent to the deferred callback that drops it.
+struct Unpublished<T>(*mut T);
+// SAFETY: the value is only accessed by the callback that drops it, and `T: Send`.
+unsafe impl<T: Send> Send for Unpublished<T> {}
+
+impl<T: Send + Sync + 'static> Rcu<T> {
+    /// Creates a new RCU-protected value.
+    pub fn new(value: T) -> Rcu<T> {
+        Rcu { ptr: AtomicPtr::new(Box::into_raw(Box::new(value))), _phantom: PhantomData }
+    }
+
+    /// Returns a reference to the current value, which remains valid as long as the given `guard` is held.
+    pub fn read<'g>(&'g self, _guard: &'g RcuReadGuard) -> &'g T {
+        // SAFETY: the pointer is always valid, and a replaced value is only dropped
+        // after a grace period, which cannot elapse while `_guard` is held.
+        unsafe { &*self.ptr.load(Ordering::Acquire) }
+    }
+
+    /// Enters a read-side critical section and returns a reference to the current value,
+    /// which remains valid until the returned reference is dropped.
+    pub fn read_lock(&self) -> RcuRef<'_, T> {
+        let guard = read_lock();
+        let value: *const T = self.read(&guard);
+        RcuRef { value, _guard: guard, _phantom: PhantomData }
+    }
+
+    /// Publishes the given `value`, replacing the current value,
+    /// which is dropped once a grace period has elapsed.
+    pub fn replace(&self, value: T) {
+        let old = self.ptr.swap(Box::into_raw(Box::new(value)), Ordering::AcqRel);
+        defer_drop(old);
+    }
+
+    /// Publishes a new value computed by `update` from (a copy of) the current value.
+    ///
+    /// If another update is published concurrently, `update` is invoked again on that newer value,
+    /// so no update is lost. `update` runs inside a read-side critical section,
+    /// so it must be short and must not block.
+    pub fn update<F: FnMut(&T) -> T>(&self, mut update: F) {
+        let guard = read_lock();
+        let mut current = self.ptr.load(Ordering::Acquire);
+        loop {
+            // SAFETY: `current` cannot be dropped while `guard` is held.
+            let new = Box::into_raw(Box::new(update(unsafe { &*current })));
+            match self.ptr.compare_exchange(current, new, Ordering::AcqRel, Ordering::Acquire) {
+                Ok(old) => {
+                    drop(guard);
+                    defer_drop(old);
+                    return;
+                }
+                Err(newer) => {
+                    // SAFETY: `new` was never published.
+                    drop(unsafe { Box::from_raw(new) });
+                    current = newer;
+                }
+            }
+        }
+    }
+
+    /// Returns a mutable reference to the current value.
+    ///
+    /// This doesn't require a read-side critical section because
+    /// the mutable borrow statically guarantees that there are no readers.
+    pub fn get_mut(&mut self) -> &mut T {
+        // SAFETY: the pointer is always valid, and `&mut self` guarantees exclusive access.
+        unsafe { &mut *self.ptr.load(Ordering::Acquire) }
+    }
+}
+
+/// Drops the given replaced value once a grace period has elapsed.
+fn defer_drop<T: Send + 'static>(old: *mut T) {
+    let old = Unpublished(old);
+    call_rcu(move || {
+        let old = old;
+        // SAFETY: the value was allocated by `Rcu` and was unpublished a grace period ago,
+        // so no reader can still reference it.
+        drop(unsafe { Box::from_raw(old.0) });
+    });
+}
+
+impl<T: Send + Sync + 'static> Drop for Rcu<T> {
+    fn drop(&mut self) {
+        let ptr = self.ptr.swap(ptr::null_mut(), Ordering::AcqRel);
+        // SAFETY: `&mut self` guarantees there are no readers of the current value.
+        drop(unsafe { Box::from_raw(ptr) });
+    }
+}
+
+impl<T: Send + Sync + Default + 'static> Default for Rcu<T> {
+    fn default() -> Rcu<T> {
+        Rcu::new(T::default())
+    }
+}
+
+impl<T: Send + Sync + fmt::Debug + 'static> fmt::Debug for Rcu<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Rcu {{ value: {:?} }}", &*self.read_lock())
+    }
+}
+
+// SAFETY: values are shared across tasks by reference (`T: Sync`) and dropped by any task (`T: Send`).
+unsafe impl<T: Send + Sync + 'static> Send for Rcu<T> {}
+unsafe impl<T: Send + Sync + 'static> Sync for Rcu<T> {}
+
+/// A reference to an RCU-protected value that holds its own read-side critical section.
+///
+/// Preemption is disabled as long as this reference is held.
+pub struct RcuRef<'a, T> {
+    value: *const T,
+    _guard: RcuReadGuard,
+    _phantom: PhantomData<&'a T>,
+}
+
+impl<'a, T> Deref for RcuRef<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // SAFETY: the value cannot be dropped while `_guard` is held, and `'a` borrows its `Rcu`.
+        unsafe { &*self.value }
+    }
+}
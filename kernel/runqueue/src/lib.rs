@@ -6,9 +6,11 @@
 #![no_std]
 
 extern crate alloc;
+#[macro_use] extern crate log;
 extern crate mutex_preemption;
 extern crate atomic_linked_list;
 extern crate task;
+extern crate cpu;
 #[macro_use] extern crate cfg_if;
 cfg_if! {
     if #[cfg(priority_scheduler)] {
@@ -23,6 +25,7 @@ cfg_if! {
 #[cfg(single_simd_task_optimization)]
 extern crate single_simd_task_optimization;
 
+use alloc::vec::Vec;
 use mutex_preemption::RwLockPreempt;
 use task::TaskRef;
 use runqueue::RunQueue;
@@ -38,19 +41,32 @@ pub fn get_runqueue(which_core: u8) -> Option<&'static RwLockPreempt<RunQueue>>
     RunQueue::get_runqueue(which_core)
 }
 
-/// Returns the "least busy" core
+/// Returns the "least busy" core, excluding offline cores.
 pub fn get_least_busy_core() -> Option<u8> {
-    RunQueue::get_least_busy_core()
+    if cpu::offline_cpus().is_empty() {
+        RunQueue::get_least_busy_core()
+    } else {
+        least_busy_core_in(0..=u8::MAX)
+    }
 }
 
-/// Chooses the "least busy" core's runqueue
+/// Chooses the "least busy" core's runqueue, excluding offline cores,
 /// and adds the given `Task` reference to that core's runqueue.
 pub fn add_task_to_any_runqueue(task: TaskRef) -> Result<(), &'static str> {
-    RunQueue::add_task_to_any_runqueue(task)
+    if cpu::offline_cpus().is_empty() {
+        return RunQueue::add_task_to_any_runqueue(task);
+    }
+    let core = least_busy_core_in(0..=u8::MAX).ok_or("couldn't find any online runqueues to add the task to!")?;
+    RunQueue::add_task_to_specific_runqueue(core, task)
 }
 
 /// Adds the given `Task` reference to given core's runqueue.
+///
+/// Returns an error if the given core is offline, unless the task is that core's idle task.
 pub fn add_task_to_specific_runqueue(which_core: u8, task: TaskRef) -> Result<(), &'static str> {
+    if cpu::is_offline(which_core) && !task.is_an_idle_task {
+        return Err("cannot add a task to the runqueue of an offline core");
+    }
     RunQueue::add_task_to_specific_runqueue(which_core, task)
 }
 
@@ -58,3 +74,141 @@ pub fn add_task_to_specific_runqueue(which_core: u8, task: TaskRef) -> Result<()
 pub fn remove_task_from_all(task: &TaskRef) -> Result<(), &'static str> {
     RunQueue::remove_task_from_all(task)
 }
+
+/// Adds the given `Task` reference to the runqueue of the "least busy" core
+/// among the cores that the task is allowed to run on, according to its affinity.
+pub fn add_task_to_allowed_runqueue(task: TaskRef) -> Result<(), &'static str> {
+    let affinity = task.affinity();
+    if affinity.is_all() {
+        return add_task_to_any_runqueue(task);
+    }
+    let core = least_busy_core_in(affinity.iter())
+        .ok_or("none of the cores that the task is allowed to run on have an online runqueue")?;
+    add_task_to_specific_runqueue(core, task)
+}
+
+/// Returns the core whose runqueue contains the given `Task` reference, if any.
+///
+/// This is a brute force approach that iterates over all runqueues.
+pub fn find_task(task: &TaskRef) -> Option<u8> {
+    (0..=u8::MAX).find(|&core| get_runqueue(core)
+        .map_or(false, |rq| rq.read().iter().any(|t| **t == *task))
+    )
+}
+
+/// Moves every task that isn't allowed to run on the given core, according to its affinity,
+/// from that core's runqueue to the runqueue of a core that it is allowed to run on.
+///
+/// This must only be invoked on `which_core` itself, with preemption disabled,
+/// such that no task in its runqueue can start running during the migration.
+/// A task that is currently running on `which_core` cannot be migrated yet.
+///
+/// Returns `true` if any disallowed tasks remain in this core's runqueue
+/// and must be migrated later, i.e., after they have been switched away from.
+pub fn migrate_disallowed_tasks(which_core: u8) -> bool {
+    let Some(rq) = get_runqueue(which_core) else { return false };
+    let mut disallowed: Vec<TaskRef> = Vec::new();
+    let mut remaining = false;
+    {
+        let mut rq_locked = rq.write();
+        for t in rq_locked.iter() {
+            if t.is_allowed_on(which_core) {
+                continue;
+            }
+            if t.is_running() {
+                remaining = true;
+            } else {
+                disallowed.push(TaskRef::clone(t));
+            }
+        }
+        for task in &disallowed {
+            if let Err(e) = rq_locked.remove_task(task) {
+                error!("BUG: couldn't remove task {:?} from runqueue {}: {}", task, which_core, e);
+            }
+        }
+    }
+
+    for task in disallowed {
+        let Some(to_core) = least_busy_core_in(task.affinity().iter()) else {
+            error!("None of the cores that task {:?} is allowed to run on have an online runqueue, leaving it on core {}", task, which_core);
+            // This bypasses the offline check, as the task must not be lost if `which_core` is offline.
+            let _ = RunQueue::add_task_to_specific_runqueue(which_core, task);
+            continue;
+        };
+        // A task that has already run may have been preempted while referring to its TLS area,
+        // so only a task that hasn't started yet is prepared for migration; see `Task::on_migrate()`.
+        if !task.has_started() {
+            // SAFETY: the task was removed from the only runqueue that contained it while it wasn't running,
+            //         so it cannot start running until it is added to the destination runqueue.
+            unsafe { task.on_migrate(which_core, to_core); }
+        }
+        if let Err(e) = add_task_to_specific_runqueue(to_core, task) {
+            error!("BUG: couldn't add migrated task to runqueue {}: {}", to_core, e);
+        }
+    }
+    remaining
+}
+
+/// Returns the number of tasks on the given core's runqueue that another core could steal,
+/// i.e., runnable tasks that are neither idle tasks nor currently running.
+///
+/// This is only a hint, as the runqueue may change right after it is read.
+pub fn stealable_tasks(which_core: u8) -> usize {
+    get_runqueue(which_core).map_or(0, |rq| rq.read().iter()
+        .filter(|t| !t.is_an_idle_task && t.is_runnable() && !t.is_running())
+        .count()
+    )
+}
+
+/// Moves one task from the runqueue of `from_core` to the runqueue of `to_core`,
+/// choosing a runnable task that isn't running and is allowed to run on `to_core`.
+///
+/// Like [`migrate_disallowed_tasks()`], this must only be invoked on `from_core` itself,
+/// with preemption disabled, such that the chosen task cannot start running during the handover.
+///
+/// Returns `true` if a task was moved.
+pub fn hand_over_task(from_core: u8, to_core: u8) -> bool {
+    if from_core == to_core || get_runqueue(to_core).is_none() {
+        return false;
+    }
+    let Some(rq) = get_runqueue(from_core) else { return false };
+    let task = {
+        let mut rq_locked = rq.write();
+        // Prefer the task that was run least recently, i.e., the one nearest to the front.
+        let candidate = rq_locked.iter()
+            .find(|t| !t.is_an_idle_task
+                && t.is_runnable()
+                && !t.is_running()
+                && t.is_allowed_on(to_core)
+            )
+            .map(|t| TaskRef::clone(t));
+        let Some(task) = candidate else { return false };
+        if let Err(e) = rq_locked.remove_task(&task) {
+            error!("BUG: couldn't remove task {:?} from runqueue {}: {}", task, from_core, e);
+            return false;
+        }
+        task
+    };
+
+    // A task that has already run may have been preempted while referring to its TLS area,
+    // so only a task that hasn't started yet is prepared for migration; see `Task::on_migrate()`.
+    if !task.has_started() {
+        // SAFETY: the task was removed from the only runqueue that contained it while it wasn't running,
+        //         so it cannot start running until it is added to the destination runqueue.
+        unsafe { task.on_migrate(from_core, to_core); }
+    }
+    if let Err(e) = add_task_to_specific_runqueue(to_core, task) {
+        error!("BUG: couldn't add stolen task to runqueue {}: {}", to_core, e);
+        return false;
+    }
+    true
+}
+
+/// Returns the core with the shortest runqueue among the given `cores`, excluding offline cores.
+fn least_busy_core_in(cores: impl Iterator<Item = u8>) -> Option<u8> {
+    cores
+        .filter(|&core| !cpu::is_offline(core))
+        .filter_map(|core| get_runqueue(core).map(|rq| (core, rq.read().len())))
+        .min_by_key(|(_core, len)| *len)
+        .map(|(core, _len)| core)
+}
@@ -20,8 +20,8 @@ use atomic_linked_list::atomic_map::AtomicMap;
 use task::TaskRef;
 use core::ops::{Deref, DerefMut};
 
-pub const MAX_PRIORITY: u8 = 40;
-pub const DEFAULT_PRIORITY: u8 = 20;
+pub const MAX_PRIORITY: u8 = task::MAX_PRIORITY;
+pub const DEFAULT_PRIORITY: u8 = task::DEFAULT_PRIORITY;
 pub const INITIAL_TOKENS: usize = 10;
 
 /// A cloneable reference to a `Taskref` that exposes more methods
@@ -64,10 +64,11 @@ impl PriorityTaskRef {
     /// Creates a new `PriorityTaskRef` that wraps the given `TaskRef`.
     /// We just give an initial number of tokens to run the task till 
     /// next scheduling epoch
+    /// The task's own priority is used, such that it is preserved when the task migrates across runqueues.
     pub fn new(taskref: TaskRef) -> PriorityTaskRef {
         PriorityTaskRef {
+            priority: taskref.priority(),
             taskref,
-            priority: DEFAULT_PRIORITY,
             tokens_remaining: INITIAL_TOKENS,
             context_switches: 0,
         }
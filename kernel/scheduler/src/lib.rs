@@ -1,5 +1,7 @@
 #![no_std]
 
+extern crate alloc;
+
 cfg_if::cfg_if! {
     if #[cfg(priority_scheduler)] {
         extern crate scheduler_priority as scheduler;
@@ -10,8 +12,50 @@ cfg_if::cfg_if! {
     }
 }
 
+mod policy;
+mod steal;
+
+pub use policy::{
+    SchedulerPolicy, RoundRobinPolicy, PriorityPolicy, DEFAULT_POLICY_NAME,
+    set_policy, reset_policy, policy_name, policy_by_name,
+};
+pub use steal::{set_work_stealing, is_work_stealing_enabled};
+
+use core::sync::atomic::{AtomicBool, Ordering};
+use cpu::CpuSet;
+use spin::Once;
 use task::TaskRef;
 
+/// The maximum number of CPUs, one for each possible CPU ID.
+const MAX_CPUS: usize = u8::MAX as usize + 1;
+
+/// Whether each CPU's runqueue may contain tasks that are no longer allowed to run on that CPU,
+/// which must be migrated by that CPU the next time it runs the scheduler.
+static MIGRATION_PENDING: [AtomicBool; MAX_CPUS] = {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const FALSE: AtomicBool = AtomicBool::new(false);
+    [FALSE; MAX_CPUS]
+};
+
+/// The function invoked whenever a task resumes running in [`schedule()`] at a safe point.
+static RESUME_CALLBACK: Once<fn()> = Once::new();
+
+/// Registers a function to be invoked whenever a task resumes running in [`schedule()`]
+/// after having been switched away from, e.g., to deliver asynchronous events to that task.
+///
+/// The function runs in the context of the resumed task, with preemption enabled,
+/// but only if interrupts are enabled, i.e., not if the task was preempted by an interrupt handler.
+///
+/// Returns `false` if a function was already registered, in which case it is kept.
+pub fn register_resume_callback(callback: fn()) -> bool {
+    let mut registered = false;
+    RESUME_CALLBACK.call_once(|| {
+        registered = true;
+        callback
+    });
+    registered
+}
+
 /// Yields the current CPU by selecting a new `Task` to run 
 /// and then switching to that new `Task`.
 ///
@@ -33,10 +77,31 @@ pub fn schedule() -> bool {
 
     let cpu_id = preemption_guard.cpu_id();
 
-    let Some(next_task) = scheduler::select_next_task(cpu_id) else {
+    // Preemption was enabled, so this CPU cannot be inside an RCU read-side critical section.
+    rcu::note_quiescent_state(cpu_id);
+
+    // Move tasks that are no longer allowed to run on this CPU to another CPU's runqueue.
+    // A disallowed task that is still running here, i.e., the current task, is migrated
+    // the next time this CPU runs the scheduler, after it has been switched away from.
+    if MIGRATION_PENDING[cpu_id as usize].swap(false, Ordering::Acquire)
+        && runqueue::migrate_disallowed_tasks(cpu_id)
+    {
+        MIGRATION_PENDING[cpu_id as usize].store(true, Ordering::Release);
+    }
+
+    // Hand a task over to an idle CPU that asked to steal work from this CPU.
+    steal::service_steal_request(cpu_id);
+
+    let Some(next_task) = policy::select_next_task(cpu_id) else {
         return false; // keep running the same current task
     };
 
+    // This CPU has nothing else to run, so ask a busier CPU to hand over one of its tasks,
+    // unless it is being taken offline.
+    if next_task.is_an_idle_task && !cpu::is_offline(cpu_id) {
+        steal::request_steal(cpu_id);
+    }
+
     let (did_switch, recovered_preemption_guard) = task::task_switch(
         next_task,
         cpu_id,
@@ -46,30 +111,35 @@ pub fn schedule() -> bool {
     // trace!("AFTER TASK_SWITCH CALL (CPU {}) new current: {:?}, interrupts are {}", cpu_id, task::get_my_current_task(), irq_safety::interrupts_enabled());
 
     drop(recovered_preemption_guard);
+
+    // The current task has just resumed. If it yielded voluntarily rather than being preempted
+    // by an interrupt handler, this is a safe point to run code in its context.
+    if did_switch && irq_safety::interrupts_enabled() {
+        if let Some(callback) = RESUME_CALLBACK.get() {
+            callback();
+        }
+    }
     did_switch
 }
 
 /// Changes the priority of the given task with the given priority level.
 /// Priority values must be between 40 (maximum priority) and 0 (minimum prriority).
-/// This function returns an error when a scheduler without priority is loaded. 
-pub fn set_priority(_task: &TaskRef, _priority: u8) -> Result<(), &'static str> {
+///
+/// The priority is used by the [`PriorityPolicy`] on any CPU that has selected it,
+/// as well as by the token-based priority scheduler if it is the default policy.
+pub fn set_priority(task: &TaskRef, priority: u8) -> Result<(), &'static str> {
+    task.set_priority(priority);
     #[cfg(priority_scheduler)] {
-        scheduler_priority::set_priority(_task, _priority)
+        scheduler_priority::set_priority(task, priority)
     }
     #[cfg(not(priority_scheduler))] {
-        Err("no scheduler that uses task priority is currently loaded")
+        Ok(())
     }
 }
 
 /// Returns the priority of a given task.
-/// This function returns None when a scheduler without priority is loaded.
-pub fn get_priority(_task: &TaskRef) -> Option<u8> {
-    #[cfg(priority_scheduler)] {
-        scheduler_priority::get_priority(_task)
-    }
-    #[cfg(not(priority_scheduler))] {
-        None
-    }
+pub fn get_priority(task: &TaskRef) -> Option<u8> {
+    Some(task.priority())
 }
 
 pub fn set_periodicity(_task: &TaskRef, _period: usize) -> Result<(), &'static str> {
@@ -80,3 +150,42 @@ pub fn set_periodicity(_task: &TaskRef, _period: usize) -> Result<(), &'static s
         Err("no scheduler that supports periodic tasks is currently loaded")
     }
 }
+
+/// Sets the CPUs that the given task is allowed to run on.
+///
+/// If the task is on the runqueue of a CPU that is no longer allowed,
+/// it is migrated to the runqueue of an allowed CPU the next time that CPU runs the scheduler,
+/// which happens at the latest upon its next timer tick.
+/// If the task is the current task, this yields the current CPU such that the migration happens sooner.
+///
+/// Returns an error if none of the given CPUs are online or if the task is an idle task,
+/// which must always remain pinned to its own CPU.
+pub fn set_affinity(task: &TaskRef, cpus: CpuSet) -> Result<(), &'static str> {
+    if task.is_an_idle_task {
+        return Err("cannot change the affinity of an idle task");
+    }
+    if !cpus.iter().any(|cpu| runqueue::get_runqueue(cpu).is_some() && !cpu::is_offline(cpu)) {
+        return Err("none of the given CPUs are online");
+    }
+    task.set_affinity(cpus);
+
+    let Some(current_core) = runqueue::find_task(task) else {
+        // The task isn't on any runqueue, e.g., it has exited.
+        return Ok(());
+    };
+    if !cpus.contains(current_core) {
+        request_migration(current_core);
+        if task::with_current_task(|t| t == task).unwrap_or(false) {
+            schedule();
+        }
+    }
+    Ok(())
+}
+
+/// Asks the given CPU to migrate every task on its runqueue that is no longer allowed to run on it,
+/// e.g., because it is being taken offline, the next time that CPU runs the scheduler.
+///
+/// A task that is running on that CPU is migrated once it has been switched away from.
+pub fn request_migration(cpu: u8) {
+    MIGRATION_PENDING[cpu as usize].store(true, Ordering::Release);
+}
@@ -0,0 +1,196 @@
+//! Scheduling policies that can be selected for each CPU at runtime.
+//!
+//! Each CPU uses the default policy chosen at build time (round robin unless the
+//! `priority_scheduler` or `realtime_scheduler` cfg options are set),
+//! until another [`SchedulerPolicy`] is selected for it via [`set_policy()`].
+//! A policy selects the next task from its CPU's runqueue; the set of tasks on
+//! each runqueue is managed by the `runqueue` crate regardless of the policy.
+//!
+//! This module provides two such policies:
+//! * [`RoundRobinPolicy`]: picks the runnable tasks in turn.
+//! * [`PriorityPolicy`]: picks the runnable task with the highest priority,
+//!   including the temporary boost that tasks receive when they are unblocked (see [`Task::unblock()`]).
+//!   This favors tasks that often block on I/O, e.g., driver tasks, over compute-bound tasks.
+//!
+//! [`Task::unblock()`]: task::Task::unblock
+
+use alloc::{collections::{BTreeMap, VecDeque}, sync::Arc};
+use core::{ops::Deref, sync::atomic::{AtomicBool, Ordering}};
+use irq_safety::MutexIrqSafe;
+use task::TaskRef;
+use crate::MAX_CPUS;
+
+/// A policy that selects which task a CPU should run next.
+pub trait SchedulerPolicy: Send + Sync {
+    /// Returns the name of this policy.
+    fn name(&self) -> &'static str;
+
+    /// Selects the next task to run on the given CPU from that CPU's runqueue.
+    ///
+    /// This is invoked with preemption disabled.
+    /// The selected task must be runnable and allowed to run on the given CPU;
+    /// if no such task exists, the CPU's idle task should be selected.
+    /// Returns `None` if the current task should keep running.
+    fn select_next_task(&self, cpu: u8) -> Option<TaskRef>;
+}
+
+/// The name of the default policy chosen at build time.
+pub const DEFAULT_POLICY_NAME: &str = {
+    #[cfg(priority_scheduler)] { "token_priority" }
+    #[cfg(all(realtime_scheduler, not(priority_scheduler)))] { "realtime" }
+    #[cfg(not(any(priority_scheduler, realtime_scheduler)))] { "round_robin" }
+};
+
+/// Whether each CPU uses a policy from `POLICIES` rather than the default policy,
+/// which avoids taking the `POLICIES` lock on the common path.
+static HAS_POLICY: [AtomicBool; MAX_CPUS] = {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const FALSE: AtomicBool = AtomicBool::new(false);
+    [FALSE; MAX_CPUS]
+};
+/// The policy selected for each CPU that doesn't use the default policy.
+static POLICIES: MutexIrqSafe<BTreeMap<u8, Arc<dyn SchedulerPolicy>>> = MutexIrqSafe::new(BTreeMap::new());
+
+/// Selects the given `policy` for the given CPU, replacing its previous policy.
+///
+/// Returns an error if the given CPU doesn't have a runqueue.
+pub fn set_policy(cpu: u8, policy: Arc<dyn SchedulerPolicy>) -> Result<(), &'static str> {
+    if runqueue::get_runqueue(cpu).is_none() {
+        return Err("the given CPU doesn't have a runqueue");
+    }
+    // Drop the previous policy after releasing the lock.
+    let _previous = POLICIES.lock().insert(cpu, policy);
+    HAS_POLICY[cpu as usize].store(true, Ordering::Release);
+    Ok(())
+}
+
+/// Reverts the given CPU to the default policy chosen at build time.
+pub fn reset_policy(cpu: u8) {
+    HAS_POLICY[cpu as usize].store(false, Ordering::Release);
+    let _previous = POLICIES.lock().remove(&cpu);
+}
+
+/// Returns the name of the policy currently selected for the given CPU.
+pub fn policy_name(cpu: u8) -> &'static str {
+    policy_of(cpu).map_or(DEFAULT_POLICY_NAME, |policy| policy.name())
+}
+
+/// Returns a new instance of the policy with the given name,
+/// or `None` if no such policy exists or it is the default policy.
+pub fn policy_by_name(name: &str) -> Option<Arc<dyn SchedulerPolicy>> {
+    match name {
+        RoundRobinPolicy::NAME => Some(Arc::new(RoundRobinPolicy)),
+        PriorityPolicy::NAME => Some(Arc::new(PriorityPolicy)),
+        _ => None,
+    }
+}
+
+/// Returns the policy selected for the given CPU, or `None` if it uses the default policy.
+fn policy_of(cpu: u8) -> Option<Arc<dyn SchedulerPolicy>> {
+    if !HAS_POLICY[cpu as usize].load(Ordering::Acquire) {
+        return None;
+    }
+    POLICIES.lock().get(&cpu).cloned()
+}
+
+/// Selects the next task to run on the given CPU using that CPU's policy.
+pub(crate) fn select_next_task(cpu: u8) -> Option<TaskRef> {
+    match policy_of(cpu) {
+        Some(policy) => policy.select_next_task(cpu),
+        None => crate::scheduler::select_next_task(cpu),
+    }
+}
+
+/// Returns whether the given task can be selected to run on the given CPU.
+fn is_eligible(task: &TaskRef, cpu: u8) -> bool {
+    task.is_runnable() && task.is_allowed_on(cpu)
+}
+
+/// Moves the task at the given `index` to the end of the given runqueue,
+/// and returns a reference to that task.
+fn move_to_end<T: Deref<Target = TaskRef>>(queue: &mut VecDeque<T>, index: usize) -> Option<TaskRef> {
+    let entry = queue.remove(index)?;
+    let taskref = TaskRef::clone(&entry);
+    queue.push_back(entry);
+    Some(taskref)
+}
+
+/// Picks each runnable task on a runqueue in turn.
+pub struct RoundRobinPolicy;
+
+impl RoundRobinPolicy {
+    pub const NAME: &'static str = "round_robin";
+}
+
+impl SchedulerPolicy for RoundRobinPolicy {
+    fn name(&self) -> &'static str {
+        Self::NAME
+    }
+
+    fn select_next_task(&self, cpu: u8) -> Option<TaskRef> {
+        let mut runqueue_locked = runqueue::get_runqueue(cpu)?.write();
+        let mut idle_task_index: Option<usize> = None;
+        let mut chosen_task_index: Option<usize> = None;
+
+        for (i, t) in runqueue_locked.iter().enumerate() {
+            // we skip the idle task, and only choose it if no other tasks are eligible
+            if t.is_an_idle_task {
+                idle_task_index = Some(i);
+                continue;
+            }
+            if is_eligible(t, cpu) {
+                chosen_task_index = Some(i);
+                break;
+            }
+        }
+
+        let index = chosen_task_index.or(idle_task_index)?;
+        move_to_end(&mut **runqueue_locked, index)
+    }
+}
+
+/// Picks the runnable task with the highest effective priority on a runqueue,
+/// choosing among tasks with equal priority in turn.
+///
+/// A task's effective priority includes the temporary boost it receives upon being unblocked,
+/// which decays by one each time the task is picked.
+/// Tasks with a lower priority only run when no higher-priority task is runnable.
+pub struct PriorityPolicy;
+
+impl PriorityPolicy {
+    pub const NAME: &'static str = "priority";
+}
+
+impl SchedulerPolicy for PriorityPolicy {
+    fn name(&self) -> &'static str {
+        Self::NAME
+    }
+
+    fn select_next_task(&self, cpu: u8) -> Option<TaskRef> {
+        let mut runqueue_locked = runqueue::get_runqueue(cpu)?.write();
+        let mut idle_task_index: Option<usize> = None;
+        // The index and effective priority of the best task found so far.
+        let mut chosen: Option<(usize, u8)> = None;
+
+        for (i, t) in runqueue_locked.iter().enumerate() {
+            if t.is_an_idle_task {
+                idle_task_index = Some(i);
+                continue;
+            }
+            if !is_eligible(t, cpu) {
+                continue;
+            }
+            let priority = t.effective_priority();
+            // Using a strict comparison picks the earliest of equal-priority tasks,
+            // and moving the chosen task to the end rotates among them.
+            if chosen.map_or(true, |(_, best)| priority > best) {
+                chosen = Some((i, priority));
+            }
+        }
+
+        let index = chosen.map(|(i, _)| i).or(idle_task_index)?;
+        let next = move_to_end(&mut **runqueue_locked, index)?;
+        next.consume_priority_boost();
+        Some(next)
+    }
+}
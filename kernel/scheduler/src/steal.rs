@@ -0,0 +1,102 @@
+//! Idle-time work stealing across the per-CPU runqueues.
+//!
+//! When a CPU has nothing to run but its idle task, it becomes a thief:
+//! it picks a victim CPU whose runqueue holds tasks that are waiting to run,
+//! preferring victims on the same memory node as itself, and posts a steal request to that victim.
+//! The victim services the request the next time it runs the scheduler
+//! by handing one of its waiting tasks over to the thief's runqueue.
+//!
+//! Tasks are only ever moved by the CPU that owns the runqueue they are on,
+//! as is the case for affinity-based migration, because another CPU cannot tell
+//! whether a task it removes from a remote runqueue is about to be switched to.
+//!
+//! Each posted request counts as a [`Stat::StealAttempts`] on the thief,
+//! and each task handed over counts as a [`Stat::TasksStolen`] on the victim.
+
+use core::sync::atomic::{AtomicBool, AtomicU16, Ordering};
+use cpu_stats::Stat;
+use crate::MAX_CPUS;
+
+/// Whether idle CPUs may steal tasks from other CPUs' runqueues.
+static WORK_STEALING: AtomicBool = AtomicBool::new(true);
+
+/// The pending steal request posted to each victim CPU,
+/// which is `0` if there is none, or the ID of the thief CPU plus one.
+static STEAL_REQUESTS: [AtomicU16; MAX_CPUS] = {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const NONE: AtomicU16 = AtomicU16::new(0);
+    [NONE; MAX_CPUS]
+};
+
+/// Whether each thief CPU has posted a steal request that hasn't yet been serviced,
+/// which prevents an idle CPU from posting requests to several victims at once.
+static STEAL_PENDING: [AtomicBool; MAX_CPUS] = {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const FALSE: AtomicBool = AtomicBool::new(false);
+    [FALSE; MAX_CPUS]
+};
+
+/// Enables or disables work stealing on all CPUs.
+///
+/// Steal requests that were already posted are still serviced.
+pub fn set_work_stealing(enabled: bool) {
+    WORK_STEALING.store(enabled, Ordering::Release);
+}
+
+/// Returns whether work stealing is enabled.
+pub fn is_work_stealing_enabled() -> bool {
+    WORK_STEALING.load(Ordering::Acquire)
+}
+
+/// Services the steal request posted to the given CPU, if any,
+/// by handing one of its waiting tasks over to the thief.
+///
+/// This must be invoked on the given CPU with preemption disabled.
+pub(crate) fn service_steal_request(cpu: u8) {
+    let request = STEAL_REQUESTS[cpu as usize].swap(0, Ordering::AcqRel);
+    if request == 0 {
+        return;
+    }
+    let thief = (request - 1) as u8;
+    if runqueue::hand_over_task(cpu, thief) {
+        cpu_stats::incr(Stat::TasksStolen);
+    }
+    STEAL_PENDING[thief as usize].store(false, Ordering::Release);
+}
+
+/// Posts a steal request on behalf of the given idle CPU to the most suitable victim, if any.
+///
+/// A victim must have at least one task waiting to run.
+/// Victims on the same memory node as the thief are preferred over remote ones,
+/// and among those, the victim with the most waiting tasks is chosen.
+pub(crate) fn request_steal(thief: u8) {
+    if !is_work_stealing_enabled() || STEAL_PENDING[thief as usize].load(Ordering::Acquire) {
+        return;
+    }
+    let thief_node = tls_initializer::node_of_cpu(thief);
+    let victim = (0..=u8::MAX)
+        .filter(|&cpu| cpu != thief)
+        .filter_map(|cpu| match runqueue::stealable_tasks(cpu) {
+            0 => None,
+            waiting => {
+                let is_local = thief_node.is_some() && tls_initializer::node_of_cpu(cpu) == thief_node;
+                Some((cpu, is_local, waiting))
+            }
+        })
+        .max_by_key(|&(_cpu, is_local, waiting)| (is_local, waiting))
+        .map(|(cpu, ..)| cpu);
+    let Some(victim) = victim else { return };
+
+    // Mark our request as pending before posting it, because the victim may service it
+    // (and clear this flag) as soon as it's posted.
+    STEAL_PENDING[thief as usize].store(true, Ordering::Release);
+    // Only one request can be pending per victim; another thief may have beaten us to it.
+    if STEAL_REQUESTS[victim as usize]
+        .compare_exchange(0, thief as u16 + 1, Ordering::AcqRel, Ordering::Relaxed)
+        .is_ok()
+    {
+        cpu_stats::incr(Stat::StealAttempts);
+    } else {
+        STEAL_PENDING[thief as usize].store(false, Ordering::Release);
+    }
+}
@@ -11,7 +11,6 @@
 #![no_std]
 
 extern crate alloc;
-#[macro_use] extern crate log;
 extern crate task;
 extern crate runqueue_priority;
 
@@ -94,13 +93,9 @@ fn select_next_task_priority(apic_id: u8) -> Option<NextTaskResult>  {
             continue;
         }
 
-        // if this task is pinned, it must not be pinned to a different core
-        if let Some(pinned) = t.pinned_core() {
-            if pinned != apic_id {
-                // with per-core runqueues, this should never happen!
-                error!("select_next_task() (AP {}) found a task pinned to a different core: {:?}", apic_id, t);
-                return None;
-            }
+        // must be allowed to run on this core; disallowed tasks are migrated away by the scheduler
+        if !t.is_allowed_on(apic_id) {
+            continue;
         }
 
         // if the task has no remaining tokens we ignore the task
@@ -164,13 +159,9 @@ fn assign_tokens(apic_id: u8) -> bool  {
             continue;
         }
 
-        // if this task is pinned, it must not be pinned to a different core
-        if let Some(pinned) = t.pinned_core() {
-            if pinned != apic_id {
-                // with per-core runqueues, this should never happen!
-                error!("select_next_task() (AP {}) found a task pinned to a different core: {:?}", apic_id, t);
-                return false;
-            }
+        // must be allowed to run on this core; disallowed tasks are migrated away by the scheduler
+        if !t.is_allowed_on(apic_id) {
+            continue;
         }
             
         // found a runnable task!
@@ -204,13 +195,9 @@ fn assign_tokens(apic_id: u8) -> bool  {
             continue;
         }
 
-        // if this task is pinned, it must not be pinned to a different core
-        if let Some(pinned) = t.pinned_core() {
-            if pinned != apic_id {
-                // with per-core runqueues, this should never happen!
-                error!("select_next_task() (AP {}) found a task pinned to a different core: {:?}", apic_id, &*t);
-                return false;
-            }
+        // must be allowed to run on this core; disallowed tasks are migrated away by the scheduler
+        if !t.is_allowed_on(apic_id) {
+            continue;
         }
         // task_tokens = epoch * (taskref + 1) / total_priorities;
         let task_tokens = epoch.saturating_mul((t.priority as usize).saturating_add(1)).wrapping_div(total_priorities);
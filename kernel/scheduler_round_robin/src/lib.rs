@@ -40,6 +40,11 @@ pub fn select_next_task(apic_id: u8) -> Option<TaskRef> {
         if !t.is_runnable() {
             continue;
         }
+
+        // must be allowed to run on this core
+        if !t.is_allowed_on(apic_id) {
+            continue;
+        }
             
         // found a runnable task!
         chosen_task_index = Some(i);
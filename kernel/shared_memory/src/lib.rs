@@ -0,0 +1,353 @@
+//! Shared memory regions between tasks, accessed via capability-style handles.
+//!
+//! A task creates a region of memory via [`create()`], which returns the [`SharedRegion`]
+//! that owns it. The owner can grant other tasks [`Rights`] to the region and revoke them again.
+//! A task that has been granted rights obtains an [`Attachment`] to the region via [`attach()`],
+//! through which it can access the region's memory directly, without copying,
+//! but only as permitted by its rights at the time of each access.
+//!
+//! Since all tasks share a single address space, rights are enforced by the handles rather than
+//! by page table permissions: a region's memory is only ever exposed for the duration of
+//! an access through a handle, and revoking a task's rights waits for that task's ongoing
+//! accesses to finish, after which its [`Attachment`]s can no longer access the region.
+//!
+//! A region is destroyed, and its memory unmapped, when its [`SharedRegion`] is dropped.
+//! Regions are also torn down when tasks exit: every region still owned by an exiting task
+//! is destroyed, and every right granted to an exiting task that it used is revoked.
+
+#![no_std]
+
+extern crate alloc;
+
+use alloc::{
+    collections::BTreeMap,
+    string::{String, ToString},
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+use core::{
+    cell::RefCell,
+    fmt,
+    marker::PhantomData,
+    sync::atomic::{AtomicU64, Ordering},
+};
+use log::debug;
+use memory::{MappedPages, PteFlags};
+use spin::{Mutex, RwLock};
+use task::TaskRef;
+use thread_local_macro::thread_local;
+
+/// All regions that haven't been destroyed, by ID.
+static REGIONS: Mutex<BTreeMap<RegionId, Arc<Region>>> = Mutex::new(BTreeMap::new());
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+thread_local! {
+    /// The regions that the current task owns or has attached to,
+    /// which are torn down when the current task exits.
+    static TASK_REGIONS: RefCell<TaskRegions> = RefCell::new(TaskRegions::default());
+}
+
+/// A unique identifier of a shared memory region.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct RegionId(u64);
+
+/// The rights that a task has to a shared memory region.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Rights {
+    /// The region can be read but not written.
+    ReadOnly,
+    /// The region can be read and written.
+    ReadWrite,
+}
+
+impl Rights {
+    /// Returns whether these rights include the given rights.
+    pub fn includes(self, other: Rights) -> bool {
+        self >= other
+    }
+}
+
+struct Region {
+    id: RegionId,
+    name: Option<String>,
+    /// The ID of the task that owns this region.
+    owner: usize,
+    size_in_bytes: usize,
+    /// The region's memory, which is `None` once the region has been destroyed.
+    ///
+    /// Every access holds this lock, such that revoking rights or destroying the region
+    /// can wait for ongoing accesses by acquiring it for writing.
+    pages: RwLock<Option<MappedPages>>,
+    /// The rights granted to other tasks, by task ID.
+    grants: Mutex<BTreeMap<usize, Rights>>,
+}
+
+impl Region {
+    /// Returns the rights that the given task has to this region.
+    fn rights_of(&self, task_id: usize) -> Option<Rights> {
+        if task_id == self.owner {
+            Some(Rights::ReadWrite)
+        } else {
+            self.grants.lock().get(&task_id).copied()
+        }
+    }
+
+    /// Checks that the given task currently has the given rights to this region.
+    fn check_rights(&self, task_id: usize, needed: Rights) -> Result<(), &'static str> {
+        match self.rights_of(task_id) {
+            Some(rights) if rights.includes(needed) => Ok(()),
+            Some(_) => Err("the current task only has read-only rights to this shared memory region"),
+            None => Err("the current task has no rights to this shared memory region"),
+        }
+    }
+
+    fn read<R>(&self, task_id: usize, f: impl FnOnce(&[u8]) -> R) -> Result<R, &'static str> {
+        let pages = self.pages.read();
+        let pages = pages.as_ref().ok_or("the shared memory region has been destroyed")?;
+        self.check_rights(task_id, Rights::ReadOnly)?;
+        Ok(f(pages.as_slice(0, self.size_in_bytes)?))
+    }
+
+    fn write<R>(&self, task_id: usize, f: impl FnOnce(&mut [u8]) -> R) -> Result<R, &'static str> {
+        let mut pages = self.pages.write();
+        let pages = pages.as_mut().ok_or("the shared memory region has been destroyed")?;
+        self.check_rights(task_id, Rights::ReadWrite)?;
+        Ok(f(pages.as_slice_mut(0, self.size_in_bytes)?))
+    }
+
+    /// Removes the rights granted to the given task, waiting for its ongoing accesses to finish.
+    fn revoke(&self, task_id: usize) -> bool {
+        let revoked = self.grants.lock().remove(&task_id).is_some();
+        if revoked {
+            drop(self.pages.write());
+        }
+        revoked
+    }
+
+    /// Revokes all rights and unmaps the region's memory.
+    fn destroy(&self) {
+        REGIONS.lock().remove(&self.id);
+        self.grants.lock().clear();
+        if self.pages.write().take().is_some() {
+            debug!("Destroyed shared memory region {:?} ({:?})", self.id, self.name);
+        }
+    }
+}
+
+/// The regions that a task owns or has attached to.
+#[derive(Default)]
+struct TaskRegions {
+    owned: Vec<Weak<Region>>,
+    /// The regions attached to, along with the ID of the current task.
+    attached: Vec<(Weak<Region>, usize)>,
+}
+
+impl Drop for TaskRegions {
+    /// Tears down the exiting task's regions.
+    fn drop(&mut self) {
+        for region in self.owned.drain(..).filter_map(|r| r.upgrade()) {
+            region.destroy();
+        }
+        for (region, task_id) in self.attached.drain(..) {
+            if let Some(region) = region.upgrade() {
+                region.revoke(task_id);
+            }
+        }
+    }
+}
+
+fn current_task_id() -> Result<usize, &'static str> {
+    task::with_current_task(|t| t.id).map_err(|_| "couldn't get the current task")
+}
+
+/// Creates a new shared memory region of at least `size_in_bytes`, owned by the current task.
+///
+/// The region's memory is zeroed. If a `name` is given, other tasks can find the region
+/// by that name via [`lookup()`]; names must be unique among existing regions.
+///
+/// The region is destroyed when the returned [`SharedRegion`] is dropped or the current task exits.
+pub fn create(name: Option<&str>, size_in_bytes: usize) -> Result<SharedRegion, &'static str> {
+    if size_in_bytes == 0 {
+        return Err("a shared memory region cannot be empty");
+    }
+    let owner = current_task_id()?;
+    let mut pages = memory::create_mapping(size_in_bytes, PteFlags::new().valid(true).writable(true))?;
+    pages.as_slice_mut::<u8>(0, size_in_bytes)?.fill(0);
+
+    let mut regions = REGIONS.lock();
+    if name.is_some() && regions.values().any(|r| r.name.as_deref() == name) {
+        return Err("a shared memory region with that name already exists");
+    }
+    let region = Arc::new(Region {
+        id: RegionId(NEXT_ID.fetch_add(1, Ordering::Relaxed)),
+        name: name.map(ToString::to_string),
+        owner,
+        size_in_bytes,
+        pages: RwLock::new(Some(pages)),
+        grants: Mutex::new(BTreeMap::new()),
+    });
+    regions.insert(region.id, region.clone());
+    drop(regions);
+
+    TASK_REGIONS.with(|r| r.borrow_mut().owned.push(Arc::downgrade(&region)));
+    Ok(SharedRegion { region, _not_send: PhantomData })
+}
+
+/// Returns the ID of the shared memory region with the given name, if it exists.
+pub fn lookup(name: &str) -> Option<RegionId> {
+    REGIONS.lock().values().find(|r| r.name.as_deref() == Some(name)).map(|r| r.id)
+}
+
+/// Attaches the current task to the shared memory region with the given ID,
+/// to which it must have been granted rights.
+///
+/// The rights are checked again upon every access through the returned [`Attachment`],
+/// so the attachment stops working if the rights are revoked.
+pub fn attach(id: RegionId) -> Result<Attachment, &'static str> {
+    let task_id = current_task_id()?;
+    let region = REGIONS.lock().get(&id).cloned().ok_or("no shared memory region with that ID exists")?;
+    let rights = region.rights_of(task_id).ok_or("the current task has no rights to this shared memory region")?;
+    if task_id != region.owner {
+        TASK_REGIONS.with(|r| r.borrow_mut().attached.push((Arc::downgrade(&region), task_id)));
+    }
+    Ok(Attachment { region, task_id, rights })
+}
+
+/// The handle that owns a shared memory region, through which its owner accesses it
+/// and grants rights to it.
+///
+/// The region is destroyed when this is dropped. It cannot be sent to another task,
+/// as the region is also destroyed when its owning task exits.
+pub struct SharedRegion {
+    region: Arc<Region>,
+    _not_send: PhantomData<*const ()>,
+}
+
+impl SharedRegion {
+    /// Returns the ID of this region, which other tasks use to [`attach()`] to it.
+    pub fn id(&self) -> RegionId {
+        self.region.id
+    }
+
+    /// Returns the name of this region, if it has one.
+    pub fn name(&self) -> Option<&str> {
+        self.region.name.as_deref()
+    }
+
+    /// Returns the size of this region in bytes.
+    pub fn size_in_bytes(&self) -> usize {
+        self.region.size_in_bytes
+    }
+
+    /// Grants the given `rights` to this region to the given `task`, replacing any rights granted before.
+    pub fn grant(&self, task: &TaskRef, rights: Rights) -> Result<(), &'static str> {
+        if task.id == self.region.owner {
+            return Err("the owner of a shared memory region cannot be granted rights to it");
+        }
+        let previous = self.region.grants.lock().insert(task.id, rights);
+        if previous == Some(Rights::ReadWrite) && rights == Rights::ReadOnly {
+            // Wait for any ongoing write by that task to finish.
+            drop(self.region.pages.write());
+        }
+        Ok(())
+    }
+
+    /// Revokes all rights to this region from the given `task`.
+    ///
+    /// Once this returns, the task's ongoing accesses have finished and it cannot access the region again.
+    ///
+    /// Returns `true` if the task had been granted rights.
+    pub fn revoke(&self, task: &TaskRef) -> bool {
+        self.region.revoke(task.id)
+    }
+
+    /// Returns the rights granted to the given `task`, if any.
+    pub fn rights_of(&self, task: &TaskRef) -> Option<Rights> {
+        self.region.grants.lock().get(&task.id).copied()
+    }
+
+    /// Invokes `f` with the region's memory, which may be read concurrently by other tasks.
+    pub fn read<R>(&self, f: impl FnOnce(&[u8]) -> R) -> Result<R, &'static str> {
+        self.region.read(self.region.owner, f)
+    }
+
+    /// Invokes `f` with exclusive access to the region's memory.
+    pub fn write<R>(&self, f: impl FnOnce(&mut [u8]) -> R) -> Result<R, &'static str> {
+        self.region.write(self.region.owner, f)
+    }
+}
+
+impl Drop for SharedRegion {
+    fn drop(&mut self) {
+        self.region.destroy();
+    }
+}
+
+impl fmt::Debug for SharedRegion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SharedRegion")
+            .field("id", &self.region.id)
+            .field("name", &self.region.name)
+            .field("size_in_bytes", &self.region.size_in_bytes)
+            .finish()
+    }
+}
+
+/// A task's handle to a shared memory region that it was granted rights to.
+///
+/// Each access is only permitted if the task still has the necessary rights,
+/// and the closure passed to each access must not block, as it holds a lock on the region.
+pub struct Attachment {
+    region: Arc<Region>,
+    task_id: usize,
+    /// The rights at the time of attaching, for informational purposes.
+    rights: Rights,
+}
+
+impl Attachment {
+    /// Returns the ID of the attached region.
+    pub fn id(&self) -> RegionId {
+        self.region.id
+    }
+
+    /// Returns the size of the attached region in bytes.
+    pub fn size_in_bytes(&self) -> usize {
+        self.region.size_in_bytes
+    }
+
+    /// Returns the rights that the attached task had when it attached to the region.
+    pub fn rights(&self) -> Rights {
+        self.rights
+    }
+
+    /// Returns whether the attached task can still access the region,
+    /// i.e., whether its rights haven't been revoked and the region hasn't been destroyed.
+    pub fn is_valid(&self) -> bool {
+        self.region.pages.read().is_some() && self.region.rights_of(self.task_id).is_some()
+    }
+
+    /// Invokes `f` with the region's memory, which may be read concurrently by other tasks.
+    ///
+    /// Returns an error if the attached task's rights have been revoked or the region has been destroyed.
+    pub fn read<R>(&self, f: impl FnOnce(&[u8]) -> R) -> Result<R, &'static str> {
+        self.region.read(self.task_id, f)
+    }
+
+    /// Invokes `f` with exclusive access to the region's memory.
+    ///
+    /// Returns an error if the attached task doesn't have read-write rights
+    /// or the region has been destroyed.
+    pub fn write<R>(&self, f: impl FnOnce(&mut [u8]) -> R) -> Result<R, &'static str> {
+        self.region.write(self.task_id, f)
+    }
+}
+
+impl fmt::Debug for Attachment {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Attachment")
+            .field("id", &self.region.id)
+            .field("task_id", &self.task_id)
+            .field("rights", &self.rights)
+            .finish()
+    }
+}
@@ -31,17 +31,28 @@ use spin::Mutex;
 use irq_safety::enable_interrupts;
 use memory::{get_kernel_mmi_ref, MmiRef};
 use stack::Stack;
-use task::{Task, TaskRef, RestartInfo, RunState, TASKLIST, JoinableTaskRef, ExitableTaskRef};
-use mod_mgmt::{CrateNamespace, SectionType, SECTION_HASH_DELIMITER};
+use cpu::CpuSet;
+use task::{Task, TaskRef, RestartInfo, RestartPolicy, RunState, TASKLIST, JoinableTaskRef, ExitableTaskRef};
+use mod_mgmt::{CrateNamespace, SectionType, TlsAllocHint, SECTION_HASH_DELIMITER};
 use path::Path;
 use fs_node::FileOrDir;
 use preemption::{hold_preemption, PreemptionGuard};
 use no_drop::NoDrop;
+use tracepoint::Tracepoint;
+use metrics::Histogram;
 
 #[cfg(simd_personality)]
 use task::SimdExt;
 
 
+/// How long spawning a task took, in TSC ticks, measured from the start of [`TaskBuilder::spawn()`]
+/// until the new task was added to a runqueue.
+static SPAWN_LATENCY: Histogram = Histogram::new(
+    "task_spawn_latency_ticks",
+    "Time taken to spawn a task until it was added to a runqueue, in TSC ticks",
+    &[1 << 12, 1 << 14, 1 << 16, 1 << 18, 1 << 20, 1 << 22, 1 << 24, 1 << 26],
+);
+
 /// Initializes tasking for the given AP core, including creating a runqueue for it
 /// and creating its initial task bootstrapped from the current execution context for that core. 
 pub fn init(
@@ -50,6 +61,9 @@ pub fn init(
     stack: NoDrop<Stack>,
 ) -> Result<BootstrapTaskRef, &'static str> {
     runqueue::init(apic_id)?;
+    if let Err(e) = SPAWN_LATENCY.register() {
+        warn!("Failed to register spawn metrics: {}", e);
+    }
     
     let (joinable_bootstrap_task, exitable_bootstrap_task) =
         task::bootstrap_task(apic_id, stack, kernel_mmi_ref)?;
@@ -266,15 +280,45 @@ pub struct TaskBuilder<F, A, R> {
     name: Option<String>,
     stack: Option<Stack>,
     parent: Option<TaskRef>,
-    pin_on_core: Option<u8>,
+    affinity: Option<CpuSet>,
     blocked: bool,
     idle: bool,
     post_build_function: Option<Box< dyn FnOnce(&mut Task) -> Result<(), &'static str> >>,
+    tls_values: Vec<(TlsVariable, Vec<u8>)>,
+    inherit_tls: bool,
+    restart_policy: RestartPolicy,
+    /// The number of times the task being built has already been restarted after failing.
+    restarts: usize,
 
     #[cfg(simd_personality)]
     simd: SimdExt,
 }
 
+/// Identifies a TLS variable whose initial value can be set via [`TaskBuilder::with_tls_value()`].
+#[derive(Debug, Clone)]
+pub enum TlsVariable {
+    /// The fully-qualified demangled name of a TLS variable,
+    /// which is looked up in the new task's namespace.
+    Symbol(String),
+    /// An offset from the TLS self pointer, as used by the local-exec TLS model.
+    Offset(isize),
+}
+impl From<&str> for TlsVariable {
+    fn from(symbol: &str) -> Self {
+        TlsVariable::Symbol(String::from(symbol))
+    }
+}
+impl From<String> for TlsVariable {
+    fn from(symbol: String) -> Self {
+        TlsVariable::Symbol(symbol)
+    }
+}
+impl From<isize> for TlsVariable {
+    fn from(offset: isize) -> Self {
+        TlsVariable::Offset(offset)
+    }
+}
+
 impl<F, A, R> TaskBuilder<F, A, R> 
     where A: Send + 'static, 
           R: Send + 'static,
@@ -290,10 +334,14 @@ impl<F, A, R> TaskBuilder<F, A, R>
             name: None,
             stack: None,
             parent: None,
-            pin_on_core: None,
+            affinity: None,
             blocked: false,
             idle: false,
             post_build_function: None,
+            tls_values: Vec::new(),
+            inherit_tls: false,
+            restart_policy: RestartPolicy::ALWAYS,
+            restarts: 0,
 
             #[cfg(simd_personality)]
             simd: SimdExt::None,
@@ -313,6 +361,10 @@ impl<F, A, R> TaskBuilder<F, A, R>
     }
 
     /// Set the `Stack` that will be used by the new Task.
+    ///
+    /// Like all stacks, the given `Stack` has an unmapped guard page beneath it,
+    /// such that overflowing it causes a page fault that is reported as a stack overflow.
+    /// If no stack is set, a new stack with a guard page is allocated.
     pub fn stack(mut self, stack: Stack) -> TaskBuilder<F, A, R> {
         self.stack = Some(stack);
         self
@@ -327,9 +379,25 @@ impl<F, A, R> TaskBuilder<F, A, R>
         self
     }
 
+    /// Pin the new Task to a specific CPU, such that it only ever runs on that CPU.
+    pub fn pin_to(self, cpu: u8) -> TaskBuilder<F, A, R> {
+        self.affinity(CpuSet::single(cpu))
+    }
+
     /// Pin the new Task to a specific core.
-    pub fn pin_on_core(mut self, core_apic_id: u8) -> TaskBuilder<F, A, R> {
-        self.pin_on_core = Some(core_apic_id);
+    ///
+    /// This is equivalent to [`TaskBuilder::pin_to()`].
+    pub fn pin_on_core(self, core_apic_id: u8) -> TaskBuilder<F, A, R> {
+        self.pin_to(core_apic_id)
+    }
+
+    /// Set the CPUs that the new Task is allowed to run on.
+    ///
+    /// The new Task is placed on the least busy of these CPUs.
+    /// By default, a Task is allowed to run on any CPU.
+    /// The affinity can be changed after spawning via `scheduler::set_affinity()`.
+    pub fn affinity(mut self, cpus: CpuSet) -> TaskBuilder<F, A, R> {
+        self.affinity = Some(cpus);
         self
     }
 
@@ -341,6 +409,39 @@ impl<F, A, R> TaskBuilder<F, A, R>
         self
     }
 
+    /// Sets the initial value of the given TLS `variable` in the new Task's TLS area to the given `bytes`,
+    /// which allows passing configuration to the new Task through thread-local variables.
+    ///
+    /// The value is written into the new Task's TLS data image before it first runs.
+    /// If `variable` is a [`TlsVariable::Symbol`], the length of `bytes` must exactly match
+    /// the size of that TLS variable, otherwise [`spawn()`](Self::spawn) will return an error.
+    ///
+    /// Note that this bypasses any lazy initialization performed by the `thread_local!()` macro,
+    /// so it should only be used with plain `#[thread_local]` statics.
+    pub fn with_tls_value<V: Into<TlsVariable>>(mut self, variable: V, bytes: Vec<u8>) -> TaskBuilder<F, A, R> {
+        self.tls_values.push((variable.into(), bytes));
+        self
+    }
+
+    /// Seeds the new Task's TLS area by copying the current task's live TLS area,
+    /// such that the new Task inherits the current values of all TLS variables
+    /// instead of their initial values.
+    ///
+    /// This is useful for fork-like task creation and for cloned worker pools.
+    /// The new Task does not inherit the current task's TLS destructors,
+    /// nor the fixed per-task TLS slots other than `errno`.
+    ///
+    /// # Safety
+    /// TLS variables are duplicated bitwise, so the caller must ensure that
+    /// the current values of all TLS variables that the new Task may access
+    /// can be safely duplicated, e.g., they don't own heap memory.
+    /// In particular, lazily-initialized `thread_local!()` values are inherited
+    /// but will never be dropped by the new Task.
+    pub unsafe fn inherit_tls(mut self) -> TaskBuilder<F, A, R> {
+        self.inherit_tls = true;
+        self
+    }
+
     /// Set the new Task's `RunState` to be `Blocked` instead of `Runnable` when it is first spawned.
     /// This allows another task to delay the new task's execution arbitrarily, 
     /// e.g., to set up other things for the newly-spawned (but not yet running) task. 
@@ -359,10 +460,16 @@ impl<F, A, R> TaskBuilder<F, A, R>
     /// It does not switch to it immediately; that will happen on the next scheduler invocation.
     #[inline(never)]
     pub fn spawn(self) -> Result<JoinableTaskRef, &'static str> {
-        let mut new_task = Task::new(
+        let start = tracepoint::timestamp();
+        // A task pinned to a single core has its TLS area placed on the memory node local to that core.
+        let tls_alloc_hint = self.affinity
+            .and_then(|cpus| cpus.single_cpu())
+            .map_or(TlsAllocHint::Any, TlsAllocHint::Cpu);
+        let mut new_task = Task::new_with_tls_alloc_hint(
             self.stack,
             self.parent.as_ref(),
             task_cleanup_failure::<F, A, R>,
+            tls_alloc_hint,
         )?;
         // If a Task name wasn't provided, then just use the function's name.
         new_task.name = self.name.unwrap_or_else(|| String::from(core::any::type_name::<F>()));
@@ -382,10 +489,45 @@ impl<F, A, R> TaskBuilder<F, A, R>
         let box_ptr = Box::into_raw(Box::new(TaskFuncArg::<F, A, R> {
             arg:  self.argument,
             func: self.func,
+            inherited_tls: self.inherit_tls,
             _ret: PhantomData,
         }));
         *bottom_of_stack = box_ptr as usize;
 
+        // Copy the current task's live TLS area into the new task, if requested.
+        if self.inherit_tls {
+            // SAFE: the caller of `inherit_tls()` guaranteed that this is safe.
+            unsafe { new_task.inherit_current_tls_area()?; }
+        }
+
+        // Apply the new task's affinity.
+        if let Some(cpus) = self.affinity {
+            if cpus.is_empty() {
+                return Err("TaskBuilder::spawn(): the new task's affinity contains no CPUs");
+            }
+            new_task.set_affinity(cpus);
+        }
+
+        // Pre-populate any TLS variables in the new task's TLS area.
+        for (variable, bytes) in &self.tls_values {
+            let offset = match variable {
+                TlsVariable::Offset(offset) => *offset,
+                TlsVariable::Symbol(symbol) => {
+                    let section = new_task.get_namespace().get_symbol(symbol).upgrade()
+                        .ok_or("with_tls_value(): couldn't find TLS variable symbol in the new task's namespace")?;
+                    if !matches!(section.typ, SectionType::TlsData | SectionType::TlsBss) {
+                        return Err("with_tls_value(): the given symbol is not a TLS variable");
+                    }
+                    if section.size != bytes.len() {
+                        return Err("with_tls_value(): the given value's size doesn't match the TLS variable's size");
+                    }
+                    // A TLS section's virtual address holds its offset from the TLS self pointer.
+                    section.virt_addr.value() as isize
+                }
+            };
+            new_task.write_tls_area(offset, bytes)?;
+        }
+
         // The new task is marked as idle
         if self.idle {
             new_task.is_an_idle_task = true;
@@ -418,11 +560,10 @@ impl<F, A, R> TaskBuilder<F, A, R>
         // (in `spawn::task_cleanup_final_internal()`).
         fence(Ordering::Release);
         
-        if let Some(core) = self.pin_on_core {
-            runqueue::add_task_to_specific_runqueue(core, task_ref.clone())?;
-        } else {
-            runqueue::add_task_to_any_runqueue(task_ref.clone())?;
-        }
+        runqueue::add_task_to_allowed_runqueue(task_ref.clone())?;
+        let ticks = tracepoint::timestamp().wrapping_sub(start);
+        tracepoint::trace(Tracepoint::Spawn, task_ref.id as u64, ticks);
+        SPAWN_LATENCY.observe(ticks);
 
         Ok(task_ref)
 
@@ -456,6 +597,17 @@ impl<F, A, R> TaskBuilder<F, A, R>
         self.pin_on_core(core_id)
     }
 
+    /// Sets the policy that determines whether and how often the new Task is restarted,
+    /// which only takes effect if it is spawned via [`spawn_restartable()`](Self::spawn_restartable).
+    ///
+    /// By default, a restartable task is restarted indefinitely, whenever it exits or fails.
+    /// See [`RestartPolicy`] for the available options, e.g., limiting the number of restarts,
+    /// running a cleanup closure upon failure, or escalating once restarts are exhausted.
+    pub fn restart_policy(mut self, policy: RestartPolicy) -> TaskBuilder<F, A, R> {
+        self.restart_policy = policy;
+        self
+    }
+
     /// Like [`TaskBuilder::spawn()`], this finishes this `TaskBuilder` and spawns the new task.
     /// It also stores the new Task's function and argument within the Task,
     /// enabling it to be restarted upon exit.
@@ -487,6 +639,8 @@ impl<F, A, R> TaskBuilder<F, A, R>
         let restart_info = RestartInfo {
             argument: Box::new(restart_with_arg.unwrap_or_else(|| self.argument.clone())),
             func: Box::new(self.func.clone()),
+            policy: self.restart_policy.clone(),
+            restarts: self.restarts,
         };
 
         // Once the new task is created, we set its restart info (func and arg),
@@ -548,6 +702,8 @@ impl<F, A, R> TaskBuilder<F, A, R>
 struct TaskFuncArg<F, A, R> {
     func: F,
     arg:  A,
+    /// Whether this task's TLS area was copied from its parent's live TLS area.
+    inherited_tls: bool,
     _ret: PhantomData<*const R>,
 }
 
@@ -666,6 +822,11 @@ where
             panic!("BUG: task_wrapper: couldn't init task {} as the current task", current_task_id)
         );
 
+        // Now that this task's TLS area is active, initialize its per-task heap allocation cache.
+        mod_mgmt::init_current_heap_cache();
+        // Seed this task's own PRNG so it doesn't need to contend on the global one.
+        random::reseed_current_task_rng();
+
         // The first time that a task runs, its entry function `task_wrapper()` is jumped to
         // from the `task_switch()` function, right after the end of `context_switch`().
         // Thus, the first thing we must do here is to perform post-context switch actions,
@@ -685,6 +846,11 @@ where
         task_entry_func = task_func_arg.func;
         task_arg        = task_func_arg.arg;
 
+        // An inherited TLS area contains a copy of the parent's TLS destructors, which the parent still owns.
+        if task_func_arg.inherited_tls {
+            unsafe { thread_local_macro::forget_inherited_tls_destructors(); }
+        }
+
         #[cfg(not(any(rq_eval, downtime_eval)))]
         debug!("task_wrapper [1]: \"{}\" about to call task entry func {:?} {{{}}} with arg {:?}",
             &**exitable_taskref, debugit!(task_entry_func), core::any::type_name::<F>(), debugit!(task_arg)
@@ -801,8 +967,15 @@ fn task_restartable_cleanup_success<F, A, R>(current_task: ExitableTaskRef, exit
           R: Send + 'static,
           F: FnOnce(A) -> R + Send + Clone +'static,
 {
+    let restart_on_success = current_task.with_restart_info(|restart_info_opt|
+        restart_info_opt.map_or(true, |restart_info| restart_info.policy.restart_on_success)
+    );
     let (preemption_guard, current_task) = task_cleanup_success_internal(current_task, exit_value);
-    task_restartable_cleanup_final::<F, A, R>(preemption_guard, current_task)
+    if restart_on_success {
+        task_restartable_cleanup_final::<F, A, R>(preemption_guard, current_task, RestartKind::AfterSuccess)
+    } else {
+        task_cleanup_final::<F, A, R>(preemption_guard, current_task)
+    }
 }
 
 
@@ -846,8 +1019,35 @@ fn task_restartable_cleanup_failure<F, A, R>(current_task: ExitableTaskRef, kill
           R: Send + 'static,
           F: FnOnce(A) -> R + Send + Clone + 'static, 
 {
+    // Apply the task's restart policy before disabling preemption,
+    // because the cleanup and escalation functions are arbitrary code.
+    let (policy, restarts) = current_task.with_restart_info(|restart_info_opt|
+        restart_info_opt.map(|restart_info| (restart_info.policy.clone(), restart_info.restarts))
+    ).unwrap_or_default();
+    if let Some(cleanup) = policy.cleanup.as_ref() {
+        cleanup(&kill_reason);
+    }
+    let restart = policy.allows_restart(restarts);
+    if !restart {
+        match policy.escalate.as_ref() {
+            Some(escalate) => escalate(&kill_reason),
+            None => error!("task_restartable_cleanup_failure: {:?} failed after {} restarts, not restarting it again", current_task.name, restarts),
+        }
+    }
+
     let (preemption_guard, current_task) = task_cleanup_failure_internal(current_task, kill_reason);
-    task_restartable_cleanup_final::<F, A, R>(preemption_guard, current_task)
+    if restart {
+        task_restartable_cleanup_final::<F, A, R>(preemption_guard, current_task, RestartKind::AfterFailure)
+    } else {
+        task_cleanup_final::<F, A, R>(preemption_guard, current_task)
+    }
+}
+
+/// Why a restartable task is being restarted, which determines whether it counts against its restart limit.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum RestartKind {
+    AfterSuccess,
+    AfterFailure,
 }
 
 
@@ -857,13 +1057,11 @@ fn task_cleanup_final_internal(current_task: &ExitableTaskRef) {
     // First, remove the task from its runqueue(s).
     remove_current_task_from_runqueue(current_task);
 
-    // Second, run TLS object destructors, which will drop any TLS objects
-    // that were lazily initialized during this execution of this task.
-    for tls_dtor in thread_local_macro::take_current_tls_destructors().into_iter() {
-        unsafe {
-            (tls_dtor.dtor)(tls_dtor.object_ptr as *mut u8);
-        }
-    }
+    // Second, run TLS object destructors (in reverse order of registration), which will drop
+    // any TLS objects that were lazily initialized during this execution of this task.
+    unsafe { thread_local_macro::run_current_tls_destructors(); }
+    // Then, return any cached free heap blocks from this task's TLS area to the heap.
+    mod_mgmt::teardown_current_heap_cache();
 
     // Third, reap the task if it has been orphaned (if it's non-joinable).
     current_task.reap_if_orphaned();
@@ -896,7 +1094,7 @@ fn task_cleanup_final<F, A, R>(preemption_guard: PreemptionGuard, current_task:
 /// The final piece of the task cleanup logic for restartable tasks.
 /// which removes the task from its runqueue and spawns it again with 
 /// same entry function (F) and argument (A). 
-fn task_restartable_cleanup_final<F, A, R>(preemption_guard: PreemptionGuard, current_task: ExitableTaskRef) -> !
+fn task_restartable_cleanup_final<F, A, R>(preemption_guard: PreemptionGuard, current_task: ExitableTaskRef, kind: RestartKind) -> !
 where
     A: Send + Clone + 'static,
     R: Send + 'static,
@@ -946,17 +1144,19 @@ where
 
                 let func: &F = restart_info.func.downcast_ref().expect("BUG: failed to downcast restartable task's function");
                 let arg : &A = restart_info.argument.downcast_ref().expect("BUG: failed to downcast restartable task's argument");
-                (func.clone(), arg.clone())
+                let restarts = restart_info.restarts + (kind == RestartKind::AfterFailure) as usize;
+                (func.clone(), arg.clone(), restart_info.policy.clone(), restarts)
             })
         });
 
-        if let Some((func, arg)) = restartable_info {
-            let mut new_task = new_task_builder(func, arg)
-                .name(current_task.name.clone());
-            if let Some(core) = current_task.pinned_core() {
-                new_task = new_task.pin_on_core(core);
-            }
-            new_task.spawn_restartable(None)
+        if let Some((func, arg, policy, restarts)) = restartable_info {
+            // The new instance gets a fresh stack and TLS area, but inherits the restart count.
+            let mut builder = new_task_builder(func, arg)
+                .name(current_task.name.clone())
+                .affinity(current_task.affinity())
+                .restart_policy(policy);
+            builder.restarts = restarts;
+            builder.spawn_restartable(None)
                 .expect("Failed to respawn the restartable task");
         } else {
             error!("BUG: Restartable task has no restart information available");
@@ -982,7 +1182,7 @@ fn remove_current_task_from_runqueue(current_task: &ExitableTaskRef) {
         runqueue::remove_task_from_all(current_task).unwrap();
     }
 
-    // In the regular case, we do not perform task migration between cores,
+    // In the regular case, tasks are only migrated between cores while they're not running,
     // so we can use the heuristic that the task is only on the current core's runqueue.
     #[cfg(not(rq_eval))] {
         if let Err(e) = runqueue::get_runqueue(cpu::current_cpu())
@@ -1005,16 +1205,18 @@ pub fn create_idle_task() -> Result<JoinableTaskRef, &'static str> {
         .spawn_restartable(None)
 }
 
-/// A basic idle task that does nothing but loop endlessly.
+/// A basic idle task that puts its CPU into a low-power state until the next interrupt,
+/// upon which the scheduler may switch to another runnable task.
+/// If its CPU has been taken offline, it parks that CPU until it is brought back online.
 /// 
 /// Note: the current spawn API does not support spawning a task with the return type `!`,
 /// so we use `()` here instead. 
 #[inline(never)]
-fn idle_task_entry(_apic_id: u8) {
+fn idle_task_entry(apic_id: u8) {
     info!("Entered idle task loop on core {}: {:?}", cpu::current_cpu(), task::get_my_current_task());
     loop {
-        // TODO: put this core into a low-power state
-        pause::spin_loop_hint();
+        cpu_hotplug::park_if_offline(apic_id);
+        idle::idle();
     }
 }
 
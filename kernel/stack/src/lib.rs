@@ -119,7 +119,8 @@ impl Stack {
         guard_page: AllocatedPages,
         stack_pages: MappedPages
     ) -> Result<Stack, (AllocatedPages, MappedPages)> {
-        if (*guard_page.end() + 1) == *stack_pages.start() 
+        if guard_page.size_in_pages() >= 1
+            && (*guard_page.end() + 1) == *stack_pages.start() 
             && stack_pages.flags().is_writable()
         {
             Ok(Stack { guard_page, pages: stack_pages })
@@ -135,4 +136,10 @@ impl Stack {
     pub fn guard_page(&self) -> &memory_structs::PageRange {
         &self.guard_page
     }
+
+    /// Returns `true` if the given `vaddr` is within this stack's guard page(s),
+    /// meaning that an access to it was caused by overflowing this stack.
+    pub fn is_guard_page_address(&self, vaddr: VirtualAddress) -> bool {
+        self.guard_page.contains(&memory_structs::Page::containing_address(vaddr))
+    }
 }
@@ -45,6 +45,16 @@
 //! }
 //! ```
 //! 
+//! Devices that can perform I/O asynchronously, e.g., those that use DMA and interrupts,
+//! should also implement the `BlockDevice` trait, which submits read, write, flush, and discard
+//! requests to the device without waiting for them to complete.
+//! Such devices can be accessed as a `BlockDevice` through `StorageDevice::as_block_device_mut()`.
+//! ```rust
+//! let request = sd.lock().as_block_device_mut().unwrap().submit_read(0, 8)?;
+//! // The lock on the device is released before waiting for (or `.await`ing) the request.
+//! let sectors = request.wait()?;
+//! ```
+//! 
 //! # Limitations
 //! 
 //! Note that if other crates are using a storage device through a block cache, 
@@ -57,14 +67,21 @@ extern crate alloc;
 extern crate spin;
 #[macro_use] extern crate downcast_rs;
 extern crate io;
+extern crate irq_safety;
+extern crate dreadnought;
+
+mod request;
 
 use alloc::{
     boxed::Box,
     sync::Arc,
+    vec::Vec,
 };
 use spin::Mutex;
 use downcast_rs::Downcast;
-use io::{BlockIo, KnownLength, BlockReader, BlockWriter};
+use io::{BlockIo, KnownLength, BlockReader, BlockWriter, IoError};
+
+pub use request::{new_block_request, BlockCompleter, BlockRequest, BlockResult};
 
 
 /// A trait that represents a storage controller,
@@ -100,9 +117,48 @@ pub type StorageControllerRef = Arc<Mutex<dyn StorageController + Send>>;
 pub trait StorageDevice: BlockIo + BlockReader + BlockWriter + KnownLength + Downcast {
 	/// Returns the total size of this device, given in number of blocks (sectors).
     fn size_in_blocks(&self) -> usize;
+
+    /// Returns this device as a `BlockDevice`, if it supports asynchronous block I/O.
+    fn as_block_device_mut(&mut self) -> Option<&mut dyn BlockDevice> { None }
 }
 impl_downcast!(StorageDevice);
 
 /// A trait object wrapped in an Arc and Mutex that allows 
 /// arbitrary storage devices to be shared in a thread-safe manner.
 pub type StorageDeviceRef = Arc<Mutex<dyn StorageDevice + Send>>;
+
+
+/// A trait that represents a storage device that supports asynchronous block I/O,
+/// such as a virtio-blk or NVMe device.
+///
+/// Each function submits a request to the device and returns a [`BlockRequest`] without waiting for it;
+/// the request is a future that resolves once the device has completed it.
+/// Requests own their buffers, so they can be awaited after the lock on the device is released.
+///
+/// All offsets and lengths are given in number of blocks, see [`BlockIo::block_size()`].
+pub trait BlockDevice: StorageDevice {
+    /// Returns the maximum number of blocks that a single read or write request can transfer.
+    fn max_blocks_per_request(&self) -> usize;
+
+    /// Submits a request to read `num_blocks` blocks starting at `block_offset`.
+    ///
+    /// The request completes with a buffer holding the blocks that were read.
+    fn submit_read(&mut self, block_offset: usize, num_blocks: usize) -> Result<BlockRequest, IoError>;
+
+    /// Submits a request to write the given `buffer` to the device starting at `block_offset`.
+    /// The length of the `buffer` must be a multiple of the block size.
+    ///
+    /// The request completes with the given `buffer`.
+    fn submit_write(&mut self, buffer: Vec<u8>, block_offset: usize) -> Result<BlockRequest, IoError>;
+
+    /// Submits a request to flush the device's volatile write cache, if it has one.
+    ///
+    /// The request completes with an empty buffer.
+    fn submit_flush(&mut self) -> Result<BlockRequest, IoError>;
+
+    /// Submits a request to discard (deallocate) `num_blocks` blocks starting at `block_offset`,
+    /// after which their contents are undefined.
+    ///
+    /// The request completes with an empty buffer.
+    fn submit_discard(&mut self, block_offset: usize, num_blocks: usize) -> Result<BlockRequest, IoError>;
+}
@@ -0,0 +1,122 @@
+//! Asynchronous block I/O requests, which are completed by a storage device driver.
+
+use alloc::{sync::Arc, vec::Vec};
+use core::{fmt, future::Future, pin::Pin, task::{Context, Poll, Waker}};
+use io::IoError;
+use irq_safety::MutexIrqSafe;
+
+/// The result of a block I/O request: the buffer that was read or written, if successful.
+pub type BlockResult = Result<Vec<u8>, IoError>;
+
+/// The state shared between a [`BlockRequest`] and its [`BlockCompleter`].
+struct RequestState {
+    result: Option<BlockResult>,
+    waker: Option<Waker>,
+    /// Whether the request has been completed, even if its result was already taken.
+    completed: bool,
+}
+
+/// Creates a new block I/O request and the completer that its driver uses to complete it.
+pub fn new_block_request() -> (BlockRequest, BlockCompleter) {
+    let state = Arc::new(MutexIrqSafe::new(RequestState { result: None, waker: None, completed: false }));
+    (BlockRequest { state: Arc::clone(&state) }, BlockCompleter { state })
+}
+
+/// A block I/O request that has been submitted to a storage device.
+///
+/// This is a future that resolves to the request's [`BlockResult`],
+/// which can also be waited for with [`BlockRequest::wait()`].
+pub struct BlockRequest {
+    state: Arc<MutexIrqSafe<RequestState>>,
+}
+
+impl BlockRequest {
+    /// Returns a request that has already completed with the given result,
+    /// which is useful for devices that perform I/O synchronously.
+    pub fn completed(result: BlockResult) -> BlockRequest {
+        let (request, completer) = new_block_request();
+        completer.complete(result);
+        request
+    }
+
+    /// Returns whether this request has completed.
+    pub fn is_complete(&self) -> bool {
+        self.state.lock().completed
+    }
+
+    /// Takes this request's result, if it has completed.
+    pub fn try_take(&mut self) -> Option<BlockResult> {
+        self.state.lock().result.take()
+    }
+
+    /// Blocks the current task until this request completes, and returns its result.
+    ///
+    /// This must be called from a task with interrupts enabled,
+    /// as the request is typically completed by the device's interrupt handler.
+    pub fn wait(self) -> BlockResult {
+        dreadnought::block_on(self)
+    }
+}
+
+impl Future for BlockRequest {
+    type Output = BlockResult;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut state = self.state.lock();
+        match state.result.take() {
+            Some(result) => Poll::Ready(result),
+            None => {
+                state.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl fmt::Debug for BlockRequest {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("BlockRequest").field("complete", &self.is_complete()).finish()
+    }
+}
+
+/// The means for a driver to complete a [`BlockRequest`], which can be used in interrupt context.
+///
+/// If a completer is dropped without being completed, its request fails.
+pub struct BlockCompleter {
+    state: Arc<MutexIrqSafe<RequestState>>,
+}
+
+impl BlockCompleter {
+    /// Completes the request with the given result, waking up whoever is waiting for it.
+    pub fn complete(self, result: BlockResult) {
+        self.finish(result);
+    }
+
+    fn finish(&self, result: BlockResult) {
+        let waker = {
+            let mut state = self.state.lock();
+            if state.completed {
+                return;
+            }
+            state.completed = true;
+            state.result = Some(result);
+            state.waker.take()
+        };
+        // Wake outside of the lock, as the waker may need to run other code.
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+impl Drop for BlockCompleter {
+    fn drop(&mut self) {
+        self.finish(Err(IoError::Other("block request was dropped by the device driver")));
+    }
+}
+
+impl fmt::Debug for BlockCompleter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("BlockCompleter").finish_non_exhaustive()
+    }
+}
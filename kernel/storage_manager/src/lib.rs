@@ -8,6 +8,8 @@ extern crate alloc;
 extern crate spin;
 extern crate pci;
 extern crate ata;
+extern crate virtio_blk;
+extern crate nvme;
 extern crate storage_device;
 
 use alloc::{
@@ -53,14 +55,34 @@ pub fn storage_devices() -> impl Iterator<Item = StorageDeviceRef> {
 /// * `Ok(None)` if the given `PciDevice` isn't a supported storage device,
 /// * An error if it fails to initialize a supported storage device.
 pub fn init_device(pci_device: &PciDevice) -> Result<Option<StorageControllerRef>, &'static str> {
-    // We currently only support IDE controllers for ATA drives (aka PATA).
+    // IDE controllers for ATA drives (aka PATA).
     let storage_controller = if pci_device.class == 0x01 && pci_device.subclass == 0x01 {
         info!("IDE controller PCI device found at: {:?}", pci_device.location);
         let ide_controller = ata::IdeController::new(pci_device)?;
         let storage_controller_ref: StorageControllerRef = Arc::new(Mutex::new(ide_controller));
         STORAGE_CONTROLLERS.lock().push(Arc::clone(&storage_controller_ref));
         Some(storage_controller_ref)
-    } 
+    }
+    else if pci_device.class == nvme::NVME_CLASS
+        && pci_device.subclass == nvme::NVME_SUBCLASS
+        && pci_device.prog_if == nvme::NVME_PROG_IF
+    {
+        info!("NVMe controller PCI device found at: {:?}", pci_device.location);
+        let nvme_controller = nvme::NvmeController::new(pci_device)?;
+        let storage_controller_ref: StorageControllerRef = Arc::new(Mutex::new(nvme_controller));
+        STORAGE_CONTROLLERS.lock().push(Arc::clone(&storage_controller_ref));
+        Some(storage_controller_ref)
+    }
+    else if pci_device.vendor_id == virtio_blk::VIRTIO_VENDOR_ID
+        && (pci_device.device_id == virtio_blk::VIRTIO_BLK_DEV_ID
+            || pci_device.device_id == virtio_blk::VIRTIO_BLK_TRANSITIONAL_DEV_ID)
+    {
+        info!("virtio-blk PCI device found at: {:?}", pci_device.location);
+        let virtio_blk_controller = virtio_blk::VirtioBlkController::new(pci_device)?;
+        let storage_controller_ref: StorageControllerRef = Arc::new(Mutex::new(virtio_blk_controller));
+        STORAGE_CONTROLLERS.lock().push(Arc::clone(&storage_controller_ref));
+        Some(storage_controller_ref)
+    }
     // Here: in the future, handle other supported storage devices
     else {
         None
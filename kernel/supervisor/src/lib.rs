@@ -0,0 +1,192 @@
+//! Supervision of restartable tasks, e.g., device driver tasks.
+//!
+//! Each task started via [`Supervisor::supervise()`] is spawned as a restartable task
+//! with its own [`RestartPolicy`], so it is restarted in place whenever it fails,
+//! with a fresh stack and TLS area.
+//! Once it exhausts the restarts allowed by its policy, it escalates to its supervisor,
+//! which restarts it again with a fresh restart budget, up to the supervisor's `max_escalations`.
+//! After that, the supervisor gives up on the task and marks it as failed,
+//! invoking the escalation function of the task's own policy, if any.
+//! A failed task can be restarted explicitly via [`Supervisor::restart()`].
+
+#![no_std]
+
+extern crate alloc;
+
+use alloc::{
+    collections::BTreeMap,
+    format,
+    string::{String, ToString},
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+use log::{error, warn};
+use spin::Mutex;
+use task::{FailureHandler, JoinableTaskRef, KillReason, RestartPolicy};
+
+/// A function that spawns a new instance of a supervised task.
+type Respawn = Arc<dyn Fn() -> Result<JoinableTaskRef, &'static str> + Send + Sync>;
+
+/// The state of a supervised task, as returned by [`Supervisor::status()`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ChildStatus {
+    /// The number of times the task has escalated to its supervisor since it was last (re)started explicitly.
+    pub escalations: usize,
+    /// Whether the supervisor has given up on restarting the task.
+    pub failed: bool,
+    /// The reason that the task most recently failed, if it has failed at all.
+    pub last_failure: Option<String>,
+}
+
+struct Child {
+    respawn: Respawn,
+    /// The escalation function of the task's own restart policy.
+    escalate: Option<FailureHandler>,
+    status: ChildStatus,
+}
+
+struct SupervisorInner {
+    name: String,
+    max_escalations: usize,
+    children: Mutex<BTreeMap<String, Child>>,
+}
+
+/// Supervises a set of named restartable tasks and restarts them when they fail.
+///
+/// Dropping a `Supervisor` doesn't stop its tasks, but they are no longer restarted
+/// after exhausting the restarts allowed by their own policies.
+pub struct Supervisor {
+    inner: Arc<SupervisorInner>,
+}
+
+impl Supervisor {
+    /// Creates a new supervisor that restarts each of its tasks at most `max_escalations` times
+    /// after that task has exhausted the restarts allowed by its own policy.
+    pub fn new(name: String, max_escalations: usize) -> Supervisor {
+        Supervisor {
+            inner: Arc::new(SupervisorInner {
+                name,
+                max_escalations,
+                children: Mutex::new(BTreeMap::new()),
+            }),
+        }
+    }
+
+    /// Returns the name of this supervisor.
+    pub fn name(&self) -> &str {
+        &self.inner.name
+    }
+
+    /// Spawns a new supervised task named `name` that runs `func` with `argument`,
+    /// and is restarted according to the given `policy` whenever it fails.
+    ///
+    /// Returns the initial instance of the task; restarted instances are not joinable.
+    /// Returns an error if this supervisor already supervises a task with the same name.
+    pub fn supervise<F, A, R>(
+        &self,
+        name: String,
+        func: F,
+        argument: A,
+        policy: RestartPolicy,
+    ) -> Result<JoinableTaskRef, &'static str>
+    where
+        A: Send + Clone + 'static,
+        R: Send + 'static,
+        F: FnOnce(A) -> R + Send + Clone + 'static,
+    {
+        let mut children = self.inner.children.lock();
+        if children.contains_key(&name) {
+            return Err("Supervisor::supervise(): a task with that name is already supervised");
+        }
+
+        // Route escalations to this supervisor instead of the policy's own escalation function.
+        let supervisor = Arc::downgrade(&self.inner);
+        let child_name = name.clone();
+        let escalate = policy.escalate.clone();
+        let policy = policy.escalate(move |reason| escalate_to(&supervisor, &child_name, reason));
+
+        // The template is only cloned from, so a lock makes it shareable without requiring `F` and `A` to be `Sync`.
+        let template = Mutex::new((func, argument));
+        let task_name = format!("{}/{}", self.inner.name, name);
+        let respawn: Respawn = Arc::new(move || {
+            let (func, argument) = template.lock().clone();
+            spawn::new_task_builder(func, argument)
+                .name(task_name.clone())
+                .restart_policy(policy.clone())
+                .spawn_restartable(None)
+        });
+
+        let task = respawn()?;
+        children.insert(name, Child {
+            respawn,
+            escalate,
+            status: ChildStatus { escalations: 0, failed: false, last_failure: None },
+        });
+        Ok(task)
+    }
+
+    /// Explicitly restarts the supervised task named `name`, resetting its escalation count.
+    ///
+    /// This is intended for tasks that the supervisor has given up on;
+    /// restarting a task that is still running spawns a second instance of it.
+    pub fn restart(&self, name: &str) -> Result<JoinableTaskRef, &'static str> {
+        let respawn = {
+            let mut children = self.inner.children.lock();
+            let child = children.get_mut(name).ok_or("Supervisor::restart(): no supervised task with that name")?;
+            child.status.escalations = 0;
+            child.status.failed = false;
+            child.respawn.clone()
+        };
+        respawn()
+    }
+
+    /// Returns the status of the supervised task named `name`, if there is one.
+    pub fn status(&self, name: &str) -> Option<ChildStatus> {
+        self.inner.children.lock().get(name).map(|child| child.status.clone())
+    }
+
+    /// Returns the names of all tasks supervised by this supervisor.
+    pub fn children(&self) -> Vec<String> {
+        self.inner.children.lock().keys().cloned().collect()
+    }
+}
+
+/// Handles a supervised task that has exhausted the restarts allowed by its own policy.
+///
+/// This runs in the context of the failed task, before it is cleaned up.
+fn escalate_to(supervisor: &Weak<SupervisorInner>, name: &str, reason: &KillReason) {
+    let Some(supervisor) = supervisor.upgrade() else {
+        warn!("supervised task {:?} failed after its supervisor was dropped: {}", name, reason);
+        return;
+    };
+    let (respawn, escalate) = {
+        let mut children = supervisor.children.lock();
+        let Some(child) = children.get_mut(name) else { return };
+        child.status.escalations += 1;
+        child.status.last_failure = Some(reason.to_string());
+        if child.status.escalations > supervisor.max_escalations {
+            child.status.failed = true;
+            (None, child.escalate.clone())
+        } else {
+            (Some(child.respawn.clone()), None)
+        }
+    };
+
+    match respawn {
+        Some(respawn) => {
+            warn!("supervisor {:?} is restarting task {:?}, which failed: {}", supervisor.name, name, reason);
+            if let Err(e) = respawn() {
+                error!("supervisor {:?} couldn't restart task {:?}: {}", supervisor.name, name, e);
+                if let Some(child) = supervisor.children.lock().get_mut(name) {
+                    child.status.failed = true;
+                }
+            }
+        }
+        None => {
+            error!("supervisor {:?} gave up on task {:?}, which failed: {}", supervisor.name, name, reason);
+            if let Some(escalate) = escalate {
+                escalate(reason);
+            }
+        }
+    }
+}
@@ -43,6 +43,7 @@ extern crate context_switch;
 extern crate preemption;
 extern crate environment;
 extern crate root;
+extern crate vfs;
 extern crate spin;
 extern crate kernel_config;
 extern crate crossbeam_utils;
@@ -51,40 +52,73 @@ extern crate no_drop;
 
 use core::{
     any::Any,
-    fmt,
+    cell::UnsafeCell,
+    fmt::{self, Write},
     hash::{Hash, Hasher},
     ops::Deref,
-    panic::PanicInfo,
-    sync::atomic::{AtomicBool, AtomicUsize, Ordering, fence},
+    panic::{Location, PanicInfo},
+    sync::atomic::{AtomicBool, AtomicU8, AtomicU32, AtomicU64, AtomicUsize, Ordering, fence},
     task::Waker,
+    time::Duration,
 };
 use alloc::{
     boxed::Box,
     collections::BTreeMap,
     string::String,
     sync::Arc,
+    vec::Vec,
 };
 use crossbeam_utils::atomic::AtomicCell;
 use irq_safety::{MutexIrqSafe, hold_interrupts};
 use memory::MmiRef;
 use stack::Stack;
 use kernel_config::memory::KERNEL_STACK_SIZE_IN_PAGES;
-use mod_mgmt::{AppCrateRef, CrateNamespace, TlsDataImage};
+use mod_mgmt::{AppCrateRef, CrateNamespace, StrongSectionRef, TlsDataImage, TlsAllocHint, TlsSectionRemapping};
 use environment::Environment;
+use vfs::{FdTable, FdTableRef};
 use spin::Mutex;
 use preemption::PreemptionGuard;
 use no_drop::NoDrop;
+use cpu::CpuSet;
+
+/// The default scheduling priority of a new task.
+pub const DEFAULT_PRIORITY: u8 = 20;
+/// The maximum scheduling priority of a task.
+pub const MAX_PRIORITY: u8 = 40;
+/// The temporary priority boost granted to a task when it is unblocked.
+/// It decays by one each time the task is scheduled in by a priority-based scheduling policy.
+pub const WAKE_PRIORITY_BOOST: u8 = 8;
+
+/// The function signature of a callback that is invoked whenever a task that hasn't started running
+/// migrates from one CPU (the first `u8`) to another (the second `u8`).
+///
+/// This allows per-CPU state that caches information about a task,
+/// e.g., in CPU-local storage, to be invalidated or moved along with the task.
+pub type MigrationHook = fn(&Task, u8, u8);
+
+/// The hooks invoked by [`Task::on_migrate()`].
+static MIGRATION_HOOKS: spin::RwLock<Vec<MigrationHook>> = spin::RwLock::new(Vec::new());
+
+/// Registers a hook that is invoked whenever a task that hasn't started running migrates across CPUs.
+///
+/// See [`MigrationHook`].
+pub fn register_migration_hook(hook: MigrationHook) {
+    MIGRATION_HOOKS.write().push(hook);
+}
 
 /// The function signature of the callback that will be invoked
 /// when a given Task panics or otherwise fails, e.g., a machine exception occurs.
 pub type KillHandler = Box<dyn Fn(&KillReason) + Send>;
 
-/// Just like `core::panic::PanicInfo`, but with owned String types instead of &str references.
+/// Just like `core::panic::PanicInfo`, but with owned strings instead of &str references.
+///
+/// The strings are stored inline rather than on the heap, so creating a `PanicInfoOwned`
+/// never allocates, which allows panics inside the heap allocator to be handled.
 #[derive(Debug, Default)]
 pub struct PanicInfoOwned {
     pub payload:  Option<Box<dyn Any + Send>>,
-    pub msg:      String,
-    pub file:     String,
+    pub msg:      PanicString,
+    pub file:     PanicString,
     pub line:     u32, 
     pub column:   u32,
 }
@@ -95,19 +129,26 @@ impl fmt::Display for PanicInfoOwned {
 }
 impl<'p> From<&PanicInfo<'p>> for PanicInfoOwned {
     fn from(info: &PanicInfo) -> PanicInfoOwned {
-        let msg = info.message()
-            .map(|m| format!("{m}"))
-            .unwrap_or_default();
-        let (file, line, column) = if let Some(loc) = info.location() {
-            (String::from(loc.file()), loc.line(), loc.column())
-        } else {
-            (String::new(), 0, 0)
-        };
-
-        PanicInfoOwned { payload: None, msg, file, line, column }
+        let mut msg = PanicString::new();
+        if let Some(m) = info.message() {
+            // Formatting only fails if the message was truncated, which is fine.
+            let _ = write!(msg, "{m}");
+        }
+        PanicInfoOwned::new(&msg, info.location())
     }
 }
 impl PanicInfoOwned {
+    /// Constructs a new `PanicInfoOwned` object with the given `msg` and `location`,
+    /// e.g., a message that was already formatted into the current task's TLS panic buffer.
+    pub fn new(msg: &str, location: Option<&Location>) -> PanicInfoOwned {
+        let (file, line, column) = if let Some(loc) = location {
+            (PanicString::from(loc.file()), loc.line(), loc.column())
+        } else {
+            (PanicString::new(), 0, 0)
+        };
+        PanicInfoOwned { payload: None, msg: PanicString::from(msg), file, line, column }
+    }
+
     /// Constructs a new `PanicInfoOwned` object containing only the given `payload`
     /// without any location or message info.
     /// 
@@ -121,15 +162,199 @@ impl PanicInfoOwned {
 }
 
 
+/// A fixed-capacity string that is stored inline, used for the strings in a [`PanicInfoOwned`].
+///
+/// Text that doesn't fit is truncated at a `char` boundary,
+/// in which case the [`fmt::Write`] implementation returns an error to stop formatting early.
+#[derive(Clone)]
+pub struct PanicString {
+    /// The number of valid bytes in `data`.
+    len: usize,
+    data: [u8; PanicString::CAPACITY],
+}
+impl PanicString {
+    /// The maximum number of bytes that a `PanicString` can hold.
+    pub const CAPACITY: usize = 256;
+
+    /// Returns a new empty `PanicString`.
+    pub const fn new() -> PanicString {
+        PanicString { len: 0, data: [0; PanicString::CAPACITY] }
+    }
+
+    /// Returns the text in this `PanicString`.
+    pub fn as_str(&self) -> &str {
+        // SAFE: only complete UTF-8 `str`s are ever written into `data`.
+        unsafe { core::str::from_utf8_unchecked(&self.data[..self.len]) }
+    }
+}
+impl Default for PanicString {
+    fn default() -> Self {
+        PanicString::new()
+    }
+}
+impl Deref for PanicString {
+    type Target = str;
+    fn deref(&self) -> &str {
+        self.as_str()
+    }
+}
+impl From<&str> for PanicString {
+    fn from(s: &str) -> Self {
+        let mut string = PanicString::new();
+        let _ = string.write_str(s);
+        string
+    }
+}
+impl fmt::Write for PanicString {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let remaining = PanicString::CAPACITY - self.len;
+        let mut to_copy = core::cmp::min(s.len(), remaining);
+        while !s.is_char_boundary(to_copy) {
+            to_copy -= 1;
+        }
+        self.data[self.len .. self.len + to_copy].copy_from_slice(&s.as_bytes()[..to_copy]);
+        self.len += to_copy;
+        if to_copy == s.len() { Ok(()) } else { Err(fmt::Error) }
+    }
+}
+impl fmt::Debug for PanicString {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(self.as_str(), f)
+    }
+}
+impl fmt::Display for PanicString {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(self.as_str(), f)
+    }
+}
+
+
 /// The list of all Tasks in the system.
 pub static TASKLIST: MutexIrqSafe<BTreeMap<usize, TaskRef>> = MutexIrqSafe::new(BTreeMap::new());
 
+/// The time at which each CPU last switched tasks, in nanoseconds of the monotonic clock,
+/// from which the CPU time of each CPU's current task is calculated.
+static LAST_SWITCH_TIME: [AtomicU64; u8::MAX as usize + 1] = {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const ZERO: AtomicU64 = AtomicU64::new(0);
+    [ZERO; u8::MAX as usize + 1]
+};
+
+/// Returns the current time of the monotonic clock in nanoseconds.
+fn monotonic_nanos() -> u64 {
+    time::now::<time::Monotonic>().duration_since(time::Instant::ZERO).as_nanos() as u64
+}
+
 
 /// returns a shared reference to the `Task` specified by the given `task_id`
 pub fn get_task(task_id: usize) -> Option<TaskRef> {
     TASKLIST.lock().get(&task_id).cloned()
 }
 
+/// Returns an error if any task's TLS area cannot be replaced,
+/// i.e., if any task has started running and hasn't yet exited.
+///
+/// A change to the TLS layout requires replacing every task's TLS area,
+/// so this must be checked before such a change is made; see [`Task::can_replace_tls_area()`].
+/// Note that this always fails when invoked by a task that hasn't exited, including the current task.
+pub fn ensure_tls_areas_replaceable() -> Result<(), &'static str> {
+    match TASKLIST.lock().values().find(|taskref| !taskref.can_replace_tls_area()) {
+        Some(taskref) => {
+            error!("the TLS area of task {:?} cannot be replaced, as it has already started running", taskref);
+            Err("the TLS layout cannot change while tasks that have started running may refer to their TLS areas")
+        }
+        None => Ok(()),
+    }
+}
+
+/// Replaces the TLS area of every task with a refreshed TLS area
+/// that includes the current set of TLS sections, preserving the values of its TLS variables.
+///
+/// The values of the old TLS sections in the given `replaced_sections` are copied into the new TLS sections.
+///
+/// This is all-or-nothing: if any task's TLS area cannot be replaced (see [`ensure_tls_areas_replaceable()`])
+/// or generating a new TLS area fails, an error is returned and all tasks keep their existing TLS area.
+pub fn refresh_tls_areas(replaced_sections: &[(StrongSectionRef, StrongSectionRef)]) -> Result<(), &'static str> {
+    replace_tls_areas(|taskref| {
+        // SAFETY: the task cannot be running, as checked by `replace_tls_areas()`.
+        unsafe { taskref.namespace.refresh_tls_image(taskref.tls_area.get(), replaced_sections) }
+    }).map(|_| ())
+}
+
+/// Replaces the TLS area of every task with a rebased TLS area
+/// that reflects the given `remappings` of the static TLS sections, preserving the values of its TLS variables.
+///
+/// This is all-or-nothing: if any task's TLS area cannot be replaced (see [`ensure_tls_areas_replaceable()`])
+/// or generating a new TLS area fails, an error is returned and all tasks keep their existing TLS area,
+/// such that the static TLS update that produced the `remappings` can be rolled back;
+/// see [`mod_mgmt::static_tls`].
+///
+/// Returns the number of tasks whose TLS area was replaced.
+pub fn rebase_tls_areas(remappings: &[TlsSectionRemapping]) -> Result<usize, &'static str> {
+    replace_tls_areas(|taskref| {
+        // SAFETY: the task cannot be running, as checked by `replace_tls_areas()`.
+        unsafe { taskref.namespace.rebase_tls_image(taskref.tls_area.get(), remappings) }
+    })
+}
+
+/// Replaces the TLS area of every task with the one generated by `new_tls_area`,
+/// but only if every task's TLS area can be replaced and every new TLS area is generated successfully.
+///
+/// Returns the number of tasks whose TLS area was replaced.
+fn replace_tls_areas<F>(new_tls_area: F) -> Result<usize, &'static str>
+    where F: Fn(&TaskRef) -> Result<TlsDataImage, &'static str>
+{
+    let tasks: Vec<_> = TASKLIST.lock().values().cloned().collect();
+    // Suspend all tasks such that none that hasn't yet run can be scheduled in
+    // until every TLS area has been replaced.
+    let was_suspended: Vec<bool> = tasks.iter().map(|taskref| {
+        let was_suspended = taskref.is_suspended();
+        taskref.suspend();
+        was_suspended
+    }).collect();
+
+    let mut new_tls_areas = Vec::with_capacity(tasks.len());
+    let mut result = Ok(());
+    for taskref in &tasks {
+        if !taskref.can_replace_tls_area() {
+            error!("the TLS area of task {:?} cannot be replaced, as it has already started running", taskref);
+            result = Err("the TLS layout cannot change while tasks that have started running may refer to their TLS areas");
+            break;
+        }
+        match new_tls_area(taskref) {
+            Ok(tls_area) => new_tls_areas.push((taskref, tls_area)),
+            Err(e) => {
+                result = Err(e);
+                break;
+            }
+        }
+    }
+
+    let num_replaced = new_tls_areas.len();
+    if result.is_ok() {
+        for (taskref, tls_area) in new_tls_areas {
+            // SAFETY: the task is still suspended and has either never run or exited,
+            //         so nothing refers to its TLS area.
+            unsafe { *taskref.tls_area.0.get() = tls_area; }
+        }
+    }
+    for (taskref, was_suspended) in tasks.iter().zip(was_suspended) {
+        if !was_suspended {
+            taskref.unsuspend();
+        }
+    }
+    result.map(|_| num_replaced)
+}
+
+/// Sums the per-task profiling counters of all tasks in the system
+/// into a system-wide report.
+///
+/// See the `tls_counters` crate for more details.
+pub fn aggregate_tls_counters() -> tls_counters::CounterReport {
+    let tasklist = TASKLIST.lock();
+    tls_counters::aggregate(tasklist.values().map(|taskref| taskref.tls_area.get()))
+}
+
 
 /// Registers a kill handler function for the current `Task`.
 /// 
@@ -174,6 +399,9 @@ pub enum KillReason {
     /// A non-language-level problem, such as a Page Fault or some other machine exception.
     /// The number of the exception is included, e.g., 15 (0xE) for a Page Fault.
     Exception(u8),
+    /// Code that this `Task` called couldn't be loaded, e.g., the crate containing
+    /// a lazily-bound function. A description of the failure is included.
+    LoadFailure(String),
 }
 impl fmt::Display for KillReason {
     fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
@@ -181,6 +409,7 @@ impl fmt::Display for KillReason {
             Self::Requested         => write!(f, "Requested"),
             Self::Panic(panic_info) => write!(f, "Panicked at {panic_info}"),
             Self::Exception(num)    => write!(f, "Exception {num:#X}({num})"),
+            Self::LoadFailure(err)  => write!(f, "Load failure: {err}"),
         }
     }
 }
@@ -238,6 +467,86 @@ pub struct RestartInfo {
     pub argument: Box<dyn Any + Send>,
     /// Stores the function of the task for restartable tasks
     pub func: Box<dyn Any + Send>,
+    /// Determines whether and how often the task is restarted.
+    pub policy: RestartPolicy,
+    /// The number of times this task has already been restarted after failing.
+    pub restarts: usize,
+}
+
+/// A function invoked with the reason that a restartable `Task` failed.
+pub type FailureHandler = Arc<dyn Fn(&KillReason) + Send + Sync>;
+
+/// Determines whether and how a restartable `Task` is restarted after it exits.
+///
+/// Each restart spawns a fresh instance of the task with a new stack and a new TLS area
+/// initialized from the current TLS data image, so no state carries over from the failed instance
+/// except for its function and argument.
+#[derive(Clone)]
+pub struct RestartPolicy {
+    /// The maximum number of times the task is restarted after failing,
+    /// or `None` if it is restarted indefinitely.
+    pub max_restarts: Option<usize>,
+    /// Whether the task is also restarted after it exits successfully.
+    pub restart_on_success: bool,
+    /// Invoked in the failed task's context whenever it fails, before it is restarted or escalated.
+    pub cleanup: Option<FailureHandler>,
+    /// Invoked in the failed task's context when it fails after being restarted `max_restarts` times,
+    /// at which point it is no longer restarted.
+    pub escalate: Option<FailureHandler>,
+}
+impl RestartPolicy {
+    /// The policy that restarts a task indefinitely, whenever it exits or fails.
+    ///
+    /// This is the default policy, which is used for idle tasks.
+    pub const ALWAYS: RestartPolicy = RestartPolicy {
+        max_restarts: None,
+        restart_on_success: true,
+        cleanup: None,
+        escalate: None,
+    };
+
+    /// Returns a policy that restarts a task at most `max_restarts` times, and only if it fails.
+    pub const fn on_failure(max_restarts: usize) -> RestartPolicy {
+        RestartPolicy {
+            max_restarts: Some(max_restarts),
+            restart_on_success: false,
+            cleanup: None,
+            escalate: None,
+        }
+    }
+
+    /// Sets the function invoked whenever the task fails, e.g., to release resources that it held.
+    pub fn cleanup<C: Fn(&KillReason) + Send + Sync + 'static>(mut self, cleanup: C) -> RestartPolicy {
+        self.cleanup = Some(Arc::new(cleanup));
+        self
+    }
+
+    /// Sets the function invoked when the task fails after exhausting its restarts.
+    pub fn escalate<E: Fn(&KillReason) + Send + Sync + 'static>(mut self, escalate: E) -> RestartPolicy {
+        self.escalate = Some(Arc::new(escalate));
+        self
+    }
+
+    /// Returns whether a task that has already been restarted `restarts` times
+    /// should be restarted again after failing.
+    pub fn allows_restart(&self, restarts: usize) -> bool {
+        self.max_restarts.map_or(true, |max| restarts < max)
+    }
+}
+impl Default for RestartPolicy {
+    fn default() -> Self {
+        RestartPolicy::ALWAYS
+    }
+}
+impl fmt::Debug for RestartPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("RestartPolicy")
+            .field("max_restarts", &self.max_restarts)
+            .field("restart_on_success", &self.restart_on_success)
+            .field("cleanup", &self.cleanup.is_some())
+            .field("escalate", &self.escalate.is_some())
+            .finish()
+    }
 }
 
 /// The signature of a Task's failure cleanup function.
@@ -296,15 +605,18 @@ pub struct TaskInner {
     drop_after_task_switch: Option<TaskRef>,
     /// The kernel stack, which all `Task`s must have in order to execute.
     pub kstack: Stack,
-    /// Whether or not this task is pinned to a certain core.
+    /// The set of CPUs that this task is allowed to run on.
     /// The idle tasks are always pinned to their respective cores.
-    pub pinned_core: Option<u8>,
+    pub affinity: CpuSet,
     /// The function that will be called when this `Task` panics or fails due to a machine exception.
     /// It will be invoked before the task is cleaned up via stack unwinding.
     /// This is similar to Rust's built-in panic hook, but is also called upon a machine exception, not just a panic.
     kill_handler: Option<KillHandler>,
     /// The environment variables for this task, which are shared among child and parent tasks by default.
     env: Arc<Mutex<Environment>>,
+    /// The file descriptor table of this task, which is copied from its parent task when it is created,
+    /// such that both tasks' file descriptors refer to the same open files.
+    fd_table: FdTableRef,
     /// Stores the restartable information of the task. 
     /// `Some(RestartInfo)` indicates that the task is restartable.
     pub restart_info: Option<RestartInfo>,
@@ -343,6 +655,10 @@ pub struct Task {
     ///
     /// This is not public because it permits interior mutability.
     runstate: AtomicCell<RunState>,
+    /// Whether this Task has ever been switched to, i.e., whether it has started running.
+    ///
+    /// This is not public because it permits interior mutability.
+    started: AtomicBool,
     /// Whether the task is suspended.
     ///
     /// This is only triggered by a Ctrl + Z in the terminal.
@@ -378,13 +694,58 @@ pub struct Task {
     /// 
     /// Upon each task switch, we must set the value of the TLS base register 
     /// (e.g., FS_BASE on x86_64) to the value of this TLS area's self pointer.
-    tls_area: TlsDataImage,
+    /// Because every task runs in kernel mode, this is the only value the TLS register holds
+    /// while this task runs; there is no separate user-mode TLS base that a task could set.
+    ///
+    /// This is not public because it permits interior mutability, see [`Task::on_migrate()`].
+    tls_area: TlsAreaCell,
+    /// The scheduling priority of this task, from `0` (lowest) to [`MAX_PRIORITY`],
+    /// which is used by priority-based scheduling policies.
+    ///
+    /// This is not public because it permits interior mutability.
+    priority: AtomicU8,
+    /// A temporary priority boost granted when this task is unblocked, e.g., after waiting for I/O,
+    /// which priority-based scheduling policies consume as this task is scheduled in.
+    ///
+    /// This is not public because it permits interior mutability.
+    priority_boost: AtomicU8,
+    /// The priority that this task has inherited from higher-priority tasks
+    /// that are waiting for a lock it holds, or `0` if it hasn't inherited any priority.
+    ///
+    /// This is not public because it permits interior mutability.
+    inherited_priority: AtomicU8,
+    /// A bitmask of the asynchronous events posted to this task that it hasn't yet handled.
+    ///
+    /// The meaning of each bit is defined by the `task_events` crate.
+    pending_events: AtomicU32,
+    /// The total time in nanoseconds that this task has run on any CPU,
+    /// up to the most recent time it was switched out.
+    ///
+    /// This is not public because it permits interior mutability.
+    cpu_time_ns: AtomicU64,
     
     #[cfg(simd_personality)]
     /// Whether this Task is SIMD enabled and what level of SIMD extensions it uses.
     pub simd: SimdExt,
 }
 
+/// A wrapper around a `Task`'s TLS area that permits it to be moved
+/// while the `Task` isn't running, e.g., upon migration to another CPU.
+struct TlsAreaCell(UnsafeCell<TlsDataImage>);
+// SAFETY: the TLS area is only mutated via `Task::on_migrate()`,
+//         `Task::write_existing_tls_area()`, and `replace_tls_areas()`,
+//         whose callers guarantee that no other references to it are in use.
+unsafe impl Sync for TlsAreaCell { }
+impl TlsAreaCell {
+    fn get(&self) -> &TlsDataImage {
+        // SAFETY: see above.
+        unsafe { &*self.0.get() }
+    }
+    fn get_mut(&mut self) -> &mut TlsDataImage {
+        self.0.get_mut()
+    }
+}
+
 // Ensure that atomic fields in the `Tast` struct are actually lock-free atomics.
 const _: () = assert!(AtomicCell::<OptionU8>::is_lock_free());
 const _: () = assert!(AtomicCell::<RunState>::is_lock_free());
@@ -395,11 +756,12 @@ impl fmt::Debug for Task {
         ds.field("name", &self.name)
             .field("id", &self.id)
             .field("running_on", &self.running_on_cpu())
-            .field("runstate", &self.runstate());
+            .field("runstate", &self.runstate())
+            .field("tls_area", &format_args!("{:#X} ({} bytes)", self.tls_area_base(), self.tls_area_size()));
         if let Some(inner) = self.inner.try_lock() {
-            ds.field("pinned", &inner.pinned_core);
+            ds.field("affinity", &inner.affinity);
         } else {
-            ds.field("pinned", &"<Locked>");
+            ds.field("affinity", &"<Locked>");
         }
         ds.finish()
     }
@@ -440,16 +802,29 @@ impl Task {
         kstack: Option<Stack>,
         parent_task: Option<&TaskRef>,
         failure_cleanup_function: FailureCleanupFunction,
+    ) -> Result<Task, &'static str> {
+        Task::new_with_tls_alloc_hint(kstack, parent_task, failure_cleanup_function, TlsAllocHint::Any)
+    }
+
+    /// Like [`Task::new()`], but places the new `Task`'s TLS area according to the given `tls_alloc_hint`,
+    /// e.g., on the memory node local to the CPU that this `Task` will be pinned to.
+    pub fn new_with_tls_alloc_hint(
+        kstack: Option<Stack>,
+        parent_task: Option<&TaskRef>,
+        failure_cleanup_function: FailureCleanupFunction,
+        tls_alloc_hint: TlsAllocHint,
     ) -> Result<Task, &'static str> {
         let clone_inherited_items = |taskref: &TaskRef| {
+            let inner = taskref.inner.lock();
             (
                 taskref.mmi.clone(),
                 taskref.namespace.clone(),
-                taskref.inner.lock().env.clone(),
+                inner.env.clone(),
+                Arc::new(Mutex::new(inner.fd_table.lock().try_clone())),
                 taskref.app_crate.clone(),
             )
         };
-        let (mmi, namespace, env, app_crate) = parent_task
+        let (mmi, namespace, env, fd_table, app_crate) = parent_task
             .map(clone_inherited_items)
             .ok_or(())
             .or_else(|_| with_current_task(clone_inherited_items))
@@ -459,20 +834,24 @@ impl Task {
             .or_else(|| stack::alloc_stack(KERNEL_STACK_SIZE_IN_PAGES, &mut mmi.lock().page_table))
             .ok_or("couldn't allocate kernel stack!")?;
 
-        Ok(Task::new_internal(kstack, mmi, namespace, env, app_crate, failure_cleanup_function))
+        Task::new_internal(kstack, mmi, namespace, env, fd_table, app_crate, failure_cleanup_function, tls_alloc_hint)
     }
     
     /// The internal routine for creating a `Task`, which does not make assumptions 
     /// about whether a currently-running `Task` exists or whether the new `Task`
     /// should inherit any states from it.
+    ///
+    /// Returns an error if the new task's TLS area cannot be allocated under memory pressure.
     fn new_internal(
         kstack: Stack, 
         mmi: MmiRef,
         namespace: Arc<CrateNamespace>,
         env: Arc<Mutex<Environment>>,
+        fd_table: FdTableRef,
         app_crate: Option<Arc<AppCrateRef>>,
         failure_cleanup_function: FailureCleanupFunction,
-    ) -> Self {
+        tls_alloc_hint: TlsAllocHint,
+    ) -> Result<Self, &'static str> {
          /// The counter of task IDs
         static TASKID_COUNTER: AtomicUsize = AtomicUsize::new(0);
 
@@ -480,18 +859,20 @@ impl Task {
         // TODO FIXME: or use random values to avoid state spill
         let task_id = TASKID_COUNTER.fetch_add(1, Ordering::Relaxed);
 
-        // Obtain a new copied instance of the TLS data image for this task.
-        let tls_area = namespace.get_tls_initializer_data();
+        // Obtain a new copied instance of the TLS data image for this task,
+        // reclaiming memory (e.g., pooled TLS images) if it cannot be allocated.
+        let tls_area = namespace.try_get_tls_initializer_data(tls_alloc_hint)?;
 
-        Task {
+        Ok(Task {
             inner: MutexIrqSafe::new(TaskInner {
                 saved_sp: 0,
                 preemption_guard: None,
                 drop_after_task_switch: None,
                 kstack,
-                pinned_core: None,
+                affinity: CpuSet::all(),
                 kill_handler: None,
                 env,
+                fd_table,
                 restart_info: None,
                 waker: None,
             }),
@@ -499,6 +880,7 @@ impl Task {
             name: format!("task_{task_id}"),
             running_on_cpu: AtomicCell::new(None.into()),
             runstate: AtomicCell::new(RunState::Initing),
+            started: AtomicBool::new(false),
             suspended: AtomicBool::new(false),
             // Tasks are not considered "joinable" until passed to `TaskRef::new()`
             joinable: AtomicBool::new(false),
@@ -507,11 +889,16 @@ impl Task {
             app_crate,
             namespace,
             failure_cleanup_function,
-            tls_area,
+            tls_area: TlsAreaCell(UnsafeCell::new(tls_area)),
+            priority: AtomicU8::new(DEFAULT_PRIORITY),
+            priority_boost: AtomicU8::new(0),
+            inherited_priority: AtomicU8::new(0),
+            pending_events: AtomicU32::new(0),
+            cpu_time_ns: AtomicU64::new(0),
 
             #[cfg(simd_personality)]
             simd: SimdExt::None,
-        }
+        })
     }
 
     /// Sets the `Environment` of this Task.
@@ -530,11 +917,42 @@ impl Task {
         Arc::clone(&self.inner.lock().env)
     }
 
+    /// Sets the file descriptor table of this Task.
+    ///
+    /// # Locking / Deadlock
+    /// Obtains the lock on this `Task`'s inner state in order to mutate it.
+    pub fn set_fd_table(&self, new_fd_table: FdTableRef) {
+        self.inner.lock().fd_table = new_fd_table;
+    }
+
+    /// Gets a reference to this task's file descriptor table.
+    ///
+    /// # Locking / Deadlock
+    /// Obtains the lock on this `Task`'s inner state in order to access it.
+    pub fn fd_table(&self) -> FdTableRef {
+        Arc::clone(&self.inner.lock().fd_table)
+    }
+
     /// Returns `true` if this `Task` is currently running.
     pub fn is_running(&self) -> bool {
         self.running_on_cpu().is_some()
     }
 
+    /// Returns `true` if this `Task` has ever been switched to, i.e., if it has started running.
+    pub fn has_started(&self) -> bool {
+        self.started.load(Ordering::Acquire)
+    }
+
+    /// Returns `true` if this `Task`'s TLS area can be replaced with a new one,
+    /// which is only the case if it has either never run or exited and been switched out.
+    ///
+    /// Any other task, even one that isn't currently running, may hold references
+    /// to its TLS variables, e.g., a task preempted in the middle of a heap allocation,
+    /// which would dangle if its TLS area were moved.
+    pub fn can_replace_tls_area(&self) -> bool {
+        !self.is_running() && (!self.has_started() || self.has_exited())
+    }
+
     /// Returns the APIC ID of the CPU this `Task` is currently running on.
     pub fn running_on_cpu(&self) -> Option<u8> {
         self.running_on_cpu.load().into()
@@ -557,9 +975,92 @@ impl Task {
     }
 
     /// Returns the APIC ID of the CPU this `Task` is pinned on,
-    /// or `None` if it is not pinned.
+    /// or `None` if it is not pinned, i.e., it's allowed to run on more than one CPU.
     pub fn pinned_core(&self) -> Option<u8> {
-        self.inner.lock().pinned_core
+        self.inner.lock().affinity.single_cpu()
+    }
+
+    /// Returns the set of CPUs that this `Task` is allowed to run on.
+    pub fn affinity(&self) -> CpuSet {
+        self.inner.lock().affinity
+    }
+
+    /// Returns whether this `Task` is allowed to run on the given CPU.
+    ///
+    /// No task other than an idle task is allowed to run on an offline CPU,
+    /// regardless of its affinity.
+    pub fn is_allowed_on(&self, cpu: u8) -> bool {
+        (self.is_an_idle_task || !cpu::is_offline(cpu))
+            && self.inner.lock().affinity.contains(cpu)
+    }
+
+    /// Sets the set of CPUs that this `Task` is allowed to run on.
+    ///
+    /// This only records the new affinity; it does not move this `Task` to an allowed CPU.
+    /// Use `scheduler::set_affinity()` to change the affinity of a spawned task,
+    /// which also migrates it off of a disallowed CPU.
+    pub fn set_affinity(&self, cpus: CpuSet) {
+        self.inner.lock().affinity = cpus;
+    }
+
+    /// Returns the scheduling priority of this `Task`, excluding any temporary boost.
+    pub fn priority(&self) -> u8 {
+        self.priority.load(Ordering::Relaxed)
+    }
+
+    /// Sets the scheduling priority of this `Task`, which is capped at [`MAX_PRIORITY`].
+    ///
+    /// This only affects priority-based scheduling policies.
+    pub fn set_priority(&self, priority: u8) {
+        self.priority.store(core::cmp::min(priority, MAX_PRIORITY), Ordering::Relaxed);
+    }
+
+    /// Returns the temporary priority boost that this `Task` received when it was last unblocked,
+    /// which has not yet been consumed by the scheduler.
+    pub fn priority_boost(&self) -> u8 {
+        self.priority_boost.load(Ordering::Relaxed)
+    }
+
+    /// Returns the priority of this `Task` including its temporary boost, capped at [`MAX_PRIORITY`],
+    /// or the priority it has inherited from tasks waiting for a lock it holds, if that is higher.
+    pub fn effective_priority(&self) -> u8 {
+        let own = core::cmp::min(self.priority().saturating_add(self.priority_boost()), MAX_PRIORITY);
+        core::cmp::max(own, self.inherited_priority())
+    }
+
+    /// Returns the priority that this `Task` has inherited from higher-priority tasks
+    /// waiting for a lock that it holds, or `0` if it hasn't inherited any priority.
+    pub fn inherited_priority(&self) -> u8 {
+        self.inherited_priority.load(Ordering::Relaxed)
+    }
+
+    /// Raises the priority that this `Task` has inherited to at least the given `priority`,
+    /// which is capped at [`MAX_PRIORITY`].
+    ///
+    /// This is used by priority-inheriting locks when a task blocks on a lock held by this `Task`.
+    pub fn inherit_priority(&self, priority: u8) {
+        self.inherited_priority.fetch_max(core::cmp::min(priority, MAX_PRIORITY), Ordering::Relaxed);
+    }
+
+    /// Drops any priority that this `Task` has inherited, reverting it to its own priority.
+    pub fn clear_inherited_priority(&self) {
+        self.inherited_priority.store(0, Ordering::Relaxed);
+    }
+
+    /// Returns the bitmask of asynchronous events posted to this `Task` that it hasn't yet handled.
+    ///
+    /// Events should be posted and consumed via the `task_events` crate,
+    /// which defines the meaning of each bit.
+    pub fn pending_events(&self) -> &AtomicU32 {
+        &self.pending_events
+    }
+
+    /// Consumes one unit of this `Task`'s temporary priority boost, if any remains.
+    ///
+    /// Priority-based scheduling policies invoke this each time they schedule in this `Task`,
+    /// such that the boost it received upon being unblocked decays as it runs.
+    pub fn consume_priority_boost(&self) {
+        let _ = self.priority_boost.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |b| b.checked_sub(1));
     }
 
     /// Returns the current [`RunState`] of this `Task`.
@@ -583,6 +1084,44 @@ impl Task {
         self.runstate() == RunState::Runnable && !self.is_suspended()
     }
 
+    /// Returns the starting address of this `Task`'s TLS area, or `0` if it has no TLS area.
+    pub fn tls_area_base(&self) -> usize {
+        self.tls_area.get().base_address()
+    }
+
+    /// Returns the value of this `Task`'s TLS self pointer, i.e., its TLS base,
+    /// or `0` if it has no TLS area.
+    pub fn tls_self_pointer(&self) -> usize {
+        self.tls_area.get().self_pointer()
+    }
+
+    /// Returns the size in bytes of this `Task`'s TLS area.
+    pub fn tls_area_size(&self) -> usize {
+        self.tls_area.get().size()
+    }
+
+    /// Returns the generation of the TLS layout that this `Task`'s TLS area was generated from,
+    /// which is older than the current TLS layout if this `Task`'s TLS area hasn't been refreshed.
+    pub fn tls_area_generation(&self) -> u64 {
+        self.tls_area.get().layout_generation()
+    }
+
+    /// Returns the total time that this `Task` has run on any CPU, including its current time slice.
+    pub fn cpu_time(&self) -> Duration {
+        let mut nanos = self.cpu_time_ns.load(Ordering::Relaxed);
+        if let Some(cpu) = self.running_on_cpu() {
+            nanos += monotonic_nanos().saturating_sub(LAST_SWITCH_TIME[cpu as usize].load(Ordering::Relaxed));
+        }
+        Duration::from_nanos(nanos)
+    }
+
+    /// Returns a snapshot of this `Task`'s profiling counters.
+    ///
+    /// See the `tls_counters` crate for more details.
+    pub fn tls_counters(&self) -> tls_counters::CounterReport {
+        tls_counters::aggregate(core::iter::once(self.tls_area.get()))
+    }
+
     /// Returns the namespace in which this `Task` is loaded/linked into and runs within.
     pub fn get_namespace(&self) -> &Arc<CrateNamespace> {
         &self.namespace
@@ -617,6 +1156,108 @@ impl Task {
         self.inner.get_mut()
     }
 
+    /// Moves this `Task`'s TLS area to the memory node described by the given `hint`,
+    /// e.g., the node local to the CPU that this `Task` is pinned to.
+    ///
+    /// The current values of all TLS variables are preserved.
+    /// This does nothing if the TLS area already resides on the requested node.
+    ///
+    /// # Note about mutability
+    /// Like [`Task::inner_mut()`], this requires a mutable reference to this `Task`,
+    /// which can only be obtained before it is enclosed in a `TaskRef`,
+    /// i.e., before it has ever run.
+    pub fn reallocate_tls_area(&mut self, hint: TlsAllocHint) {
+        // SAFETY: this task has never run, so nothing can reference its TLS area.
+        unsafe { self.tls_area.get_mut().reallocate(hint) }
+    }
+
+    /// Replaces this `Task`'s TLS area with a copy of the current task's live TLS area,
+    /// such that this `Task` inherits the current values of all TLS variables.
+    ///
+    /// See [`TlsDataImage::duplicate()`] for more details.
+    ///
+    /// Like [`Task::inner_mut()`], this requires a mutable reference to this `Task`,
+    /// which can only be obtained before it is enclosed in a `TaskRef`,
+    /// i.e., before it has ever run.
+    ///
+    /// # Safety
+    /// The caller must ensure that the current values of all TLS variables
+    /// can be safely duplicated into this `Task`, see [`TlsDataImage::duplicate()`].
+    pub unsafe fn inherit_current_tls_area(&mut self) -> Result<(), &'static str> {
+        let hint = self.tls_area.get().node().map_or(TlsAllocHint::Any, TlsAllocHint::Node);
+        // We must not hold a borrow of the current task TLS variable while copying the TLS area.
+        let current_task = get_my_current_task()
+            .ok_or("inherit_current_tls_area(): couldn't get current task")?;
+        let mut new_tls_area = unsafe { current_task.tls_area.get().duplicate(hint) };
+        // This task must not inherit the current task's "current task" TLS variable.
+        tls_current_task::reset_current_task_in(&mut new_tls_area, current_task.tls_area.get().self_pointer())?;
+        *self.tls_area.get_mut() = new_tls_area;
+        Ok(())
+    }
+
+    /// Prepares this `Task`'s TLS area for this `Task` to run on `to_cpu`
+    /// after having previously run on `from_cpu`,
+    /// and then invokes every registered [`MigrationHook`].
+    ///
+    /// The scheduler must invoke this whenever it migrates a task that hasn't started running across CPUs.
+    /// If this `Task` has never run, its TLS area may be moved onto the memory node local to `to_cpu`;
+    /// see [`TlsDataImage::rehome()`]. The TLS area of a `Task` that has started running is never moved,
+    /// as it may hold references into its TLS area, e.g., if it was preempted in the middle of a heap allocation.
+    ///
+    /// Returns `true` if this `Task`'s TLS area was moved.
+    ///
+    /// # Safety
+    /// This `Task` must not be running, and must not start running until this returns.
+    pub unsafe fn on_migrate(&self, from_cpu: u8, to_cpu: u8) -> bool {
+        // SAFETY: ensured by the caller.
+        let tls_area = unsafe { &mut *self.tls_area.0.get() };
+        tls_area.on_migrate(from_cpu);
+        // SAFETY: this task has never run, and the caller ensures that it won't start running until we return.
+        let moved = from_cpu != to_cpu && !self.has_started() && unsafe { tls_area.rehome(to_cpu) };
+        for hook in MIGRATION_HOOKS.read().iter() {
+            hook(self, from_cpu, to_cpu);
+        }
+        moved
+    }
+
+    /// Sets the initial bytes at the given `offset` from the TLS self pointer
+    /// in this `Task`'s TLS area, e.g., to pre-populate a TLS variable.
+    ///
+    /// See [`TlsDataImage::write_at()`] for more details.
+    ///
+    /// Like [`Task::inner_mut()`], this requires a mutable reference to this `Task`,
+    /// which can only be obtained before it is enclosed in a `TaskRef`,
+    /// i.e., before it has ever run.
+    pub fn write_tls_area(&mut self, offset: isize, bytes: &[u8]) -> Result<(), &'static str> {
+        self.tls_area.get_mut().write_at(offset, bytes)
+    }
+
+    /// Copies the bytes at the given `offset` from the TLS self pointer in this `Task`'s TLS area
+    /// into `buf`, e.g., to read the current value of one of its TLS variables.
+    ///
+    /// See [`TlsDataImage::read_at()`] for more details.
+    ///
+    /// # Safety
+    /// This task must not be running and must not be scheduled in during this call,
+    /// e.g., it must be suspended; otherwise, the read may race with the task's own accesses.
+    pub unsafe fn read_tls_area(&self, offset: isize, buf: &mut [u8]) -> Result<(), &'static str> {
+        buf.copy_from_slice(self.tls_area.get().read_at(offset, buf.len())?);
+        Ok(())
+    }
+
+    /// Overwrites the bytes at the given `offset` from the TLS self pointer in this `Task`'s
+    /// existing TLS area, e.g., to migrate the value of one of its TLS variables when swapping crates.
+    ///
+    /// Unlike [`Task::write_tls_area()`], this can be used on a `Task` that has already run.
+    ///
+    /// # Safety
+    /// The same requirements as for [`Task::read_tls_area()`] apply.
+    /// In addition, the `bytes` must be a valid value for the TLS variable(s) at that `offset`.
+    pub unsafe fn write_existing_tls_area(&self, offset: isize, bytes: &[u8]) -> Result<(), &'static str> {
+        // SAFETY: ensured by the caller.
+        unsafe { (*self.tls_area.0.get()).write_at(offset, bytes) }
+    }
+
     /// Exposes read-only access to this `Task`'s [`RestartInfo`] by invoking
     /// the given `func` with a reference to its `RestartInfo`.
     ///
@@ -700,6 +1341,9 @@ impl Task {
         use RunState::{Blocked, Runnable};
 
         if self.runstate.compare_exchange(Blocked, Runnable).is_ok() {
+            // Tasks that wake up after blocking, e.g., on I/O, get a temporary boost,
+            // such that latency-sensitive tasks aren't starved by compute-bound tasks.
+            self.priority_boost.store(WAKE_PRIORITY_BOOST, Ordering::Relaxed);
             Ok(Blocked)
         } else if self.runstate.compare_exchange(Runnable, Runnable).is_ok() {
             warn!("Unblocked an already runnable task: {:?}\n\t --> Current {:?}",
@@ -751,8 +1395,14 @@ impl Task {
     /// Sets this `Task` as this CPU's current task.
     ///
     /// Currently, this only updates the current TLS area.
-    fn set_as_current_task(&self) {
-        self.tls_area.set_as_current_tls_base();
+    fn set_as_current_task(&self, cpu_id: u8) {
+        self.tls_area.get().set_as_current_tls_base(cpu_id);
+    }
+
+    /// Sets this `Task` as this CPU's current task, even if this CPU's TLS register
+    /// is believed to already point to this `Task`'s TLS area.
+    fn force_set_as_current_task(&self, cpu_id: u8) {
+        self.tls_area.get().force_set_as_current_tls_base(Some(cpu_id));
     }
 
     /// Perform any actions needed after a context switch.
@@ -762,7 +1412,14 @@ impl Task {
     ///    prepared for us to drop, as specified by `TaskInner::drop_after_task_switch`.
     /// 2. Obtains the preemption guard such that preemption can be re-enabled
     ///    when it is appropriate to do so.
+    ///
+    /// It also counts the context switch in this task's TLS profiling counters
+    /// and in the current CPU's statistics.
     fn post_context_switch_action(&self) -> PreemptionGuard {
+        // This task's TLS area is now active, so we can count this context switch.
+        tls_counters::incr(tls_counters::Counter::ContextSwitches);
+        cpu_stats::incr(cpu_stats::Stat::ContextSwitches);
+
         // Step 1: drop data from previously running task
         {
             let prev_task_data_to_drop = self.inner.lock().drop_after_task_switch.take();
@@ -793,6 +1450,11 @@ impl Drop for Task {
             warn!("While dropping task {:?}, its kill handler callback was still present. Removing it now.", self);
             drop(kill_handler);
         }
+
+        // A TLS variable that overflowed its section is only detected once its redzone is checked.
+        if let Err(violation) = self.tls_area.get().verify_redzones() {
+            error!("Task {} overflowed a TLS section: {}", self, violation);
+        }
     }
 }
 
@@ -982,6 +1644,12 @@ fn task_switch_inner(
     if curr.id == next.id {
         return Err((false, preemption_guard));
     }
+    tracepoint::trace(tracepoint::Tracepoint::ContextSwitch, curr.id as u64, next.id as u64);
+
+    // Charge the time since this CPU's previous task switch to the current task.
+    let now = monotonic_nanos();
+    let last_switch_time = LAST_SWITCH_TIME[apic_id as usize].swap(now, Ordering::Relaxed);
+    curr.cpu_time_ns.fetch_add(now.saturating_sub(last_switch_time), Ordering::Relaxed);
 
     // trace!("task_switch [0]: (CPU {}) prev {:?}, next {:?}, interrupts?: {}", apic_id, curr, next, irq_safety::interrupts_enabled());
 
@@ -1083,7 +1751,8 @@ fn task_switch_inner(
     {
         let _held_interrupts = hold_interrupts();
         next.running_on_cpu.store(Some(apic_id).into());
-        next.set_as_current_task();
+        next.started.store(true, Ordering::Release);
+        next.set_as_current_task(apic_id);
         drop(_held_interrupts);
     }
 
@@ -1268,6 +1937,8 @@ impl ExitableTaskRef {
     /// 2. Obtains the preemption guard such that preemption can be re-enabled
     ///    when it is appropriate to do so.
     ///
+    /// It also counts the context switch in this task's TLS profiling counters.
+    ///
     /// Note: this publicly re-exports the private `TaskRef::post_context_switch_action()`
     ///       function for use in the early `spawn::task_wrapper` functions,
     ///       which is the only place where an `ExitableTaskRef` can be obtained. 
@@ -1458,18 +2129,22 @@ pub fn bootstrap_task(
         kernel_mmi_ref,
         default_namespace,
         default_env,
+        Arc::new(Mutex::new(FdTable::new())),
         None,
         bootstrap_task_cleanup_failure,
-    );
+        TlsAllocHint::Cpu(apic_id),
+    )?;
     bootstrap_task.name = format!("bootstrap_task_core_{apic_id}");
     bootstrap_task.runstate.store(RunState::Runnable);
     bootstrap_task.running_on_cpu.store(Some(apic_id).into()); 
-    bootstrap_task.inner.get_mut().pinned_core = Some(apic_id); // can only run on this CPU core
+    bootstrap_task.started.store(true, Ordering::Release);
+    bootstrap_task.inner.get_mut().affinity = CpuSet::single(apic_id); // can only run on this CPU core
     let bootstrap_task_id = bootstrap_task.id;
     let joinable_taskref = TaskRef::create(bootstrap_task);
 
     // Set this task as this CPU's current task, as it's already running.
-    joinable_taskref.set_as_current_task();
+    // The TLS register was previously set without the per-CPU cache, so we must force it.
+    joinable_taskref.force_set_as_current_task(apic_id);
     let Ok(exitable_taskref) = init_current_task(
         bootstrap_task_id, 
         Some(joinable_taskref.clone()),
@@ -1516,7 +2191,7 @@ pub use tls_current_task::*;
 /// A private module to ensure the below TLS variables aren't modified directly.
 mod tls_current_task {
     use core::cell::{Cell, RefCell};
-    use super::{TASKLIST, TaskRef, ExitableTaskRef};
+    use super::{TASKLIST, TaskRef, ExitableTaskRef, TlsDataImage};
 
     /// The TLS area that holds the current task's ID.
     #[thread_local]
@@ -1623,6 +2298,24 @@ mod tls_current_task {
         }
     }
 
+    /// Resets the current task TLS variable in the given `tls_area`,
+    /// which was duplicated from the current task's TLS area whose self pointer is `current_tls_self_ptr`.
+    ///
+    /// This ensures that the task that owns the duplicated `tls_area` can initialize itself
+    /// as the current task, and that it doesn't drop a reference it never owned.
+    pub(crate) fn reset_current_task_in(
+        tls_area: &mut TlsDataImage,
+        current_tls_self_ptr: usize,
+    ) -> Result<(), &'static str> {
+        let fresh: RefCell<Option<TaskRef>> = RefCell::new(None);
+        let offset = (&CURRENT_TASK as *const _ as usize).wrapping_sub(current_tls_self_ptr) as isize;
+        // SAFE: `fresh` is a valid, initialized value that outlives this slice.
+        let bytes = unsafe {
+            core::slice::from_raw_parts(&fresh as *const _ as *const u8, core::mem::size_of_val(&fresh))
+        };
+        tls_area.write_at(offset, bytes)
+    }
+
     /// An internal routine that exposes mutable access to the current task's TLS variable.
     /// 
     /// This mutable access to the TLS variable is only needed for task switching,
@@ -0,0 +1,265 @@
+//! Asynchronous event delivery to tasks.
+//!
+//! Any code, including interrupt handlers, can [`post()`] an [`Event`] to a task,
+//! e.g., when a timer expires or a crate swap changes the TLS layout.
+//! Posting only sets a bit in the task's set of pending events, so it never blocks or takes a lock.
+//! The task then receives the event in one of two ways:
+//! * If it has [registered a handler] for that event, the handler is invoked in the task's own context
+//!   at the next safe point, i.e., when the task resumes after voluntarily yielding the CPU,
+//!   or when it explicitly invokes [`deliver_pending()`].
+//! * Otherwise, the event remains pending until the task [polls] or [waits] for it.
+//!
+//! Posting an event that is already pending has no further effect, i.e., events are coalesced.
+//!
+//! [registered a handler]: register_handler
+//! [polls]: poll
+//! [waits]: wait
+
+#![no_std]
+
+extern crate alloc;
+
+use alloc::boxed::Box;
+use core::{
+    cell::{Cell, RefCell},
+    fmt,
+    sync::atomic::Ordering,
+};
+use task::{TaskRef, TASKLIST};
+use thread_local_macro::thread_local;
+
+/// The maximum number of [`Event::Custom`] events.
+pub const MAX_CUSTOM_EVENTS: u8 = 16;
+/// The bit of the first custom event.
+const CUSTOM_SHIFT: u32 = 8;
+/// The number of distinct events, one per bit below `WAITING`.
+const NUM_EVENTS: usize = (CUSTOM_SHIFT + MAX_CUSTOM_EVENTS as u32) as usize;
+/// Set in a task's pending events while it is blocked in [`wait()`].
+const WAITING: u32 = 1 << 31;
+
+thread_local! {
+    /// The event handlers registered by the current task.
+    static HANDLERS: RefCell<[Option<Box<dyn FnMut(Event)>>; NUM_EVENTS]> = RefCell::new([(); NUM_EVENTS].map(|_| None));
+    /// The set of events that the current task has registered handlers for.
+    static HANDLED: Cell<EventSet> = Cell::new(EventSet::EMPTY);
+    /// Whether the current task is running event handlers, which prevents them from being re-entered.
+    static DELIVERING: Cell<bool> = Cell::new(false);
+}
+
+/// An asynchronous event that can be posted to a task.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Event {
+    /// A timer started by the task has expired.
+    TimerExpired,
+    /// I/O that the task is waiting for is ready.
+    IoReady,
+    /// A crate is about to be swapped.
+    CrateSwapPending,
+    /// The TLS layout has changed, e.g., because a crate with TLS sections was loaded, unloaded, or swapped.
+    TlsLayoutChanged,
+    /// An application-defined event, numbered below [`MAX_CUSTOM_EVENTS`].
+    Custom(u8),
+}
+
+impl Event {
+    const BUILTIN: [Event; 4] = [Event::TimerExpired, Event::IoReady, Event::CrateSwapPending, Event::TlsLayoutChanged];
+
+    /// Returns the bit index of this event, or `None` if it is an out-of-range custom event.
+    fn index(self) -> Option<u32> {
+        match self {
+            Event::TimerExpired => Some(0),
+            Event::IoReady => Some(1),
+            Event::CrateSwapPending => Some(2),
+            Event::TlsLayoutChanged => Some(3),
+            Event::Custom(n) if n < MAX_CUSTOM_EVENTS => Some(CUSTOM_SHIFT + n as u32),
+            Event::Custom(_) => None,
+        }
+    }
+
+    fn from_index(index: u32) -> Event {
+        match index {
+            0..=3 => Event::BUILTIN[index as usize],
+            _ => Event::Custom((index - CUSTOM_SHIFT) as u8),
+        }
+    }
+}
+
+/// A set of [`Event`]s.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct EventSet(u32);
+
+impl EventSet {
+    /// The empty set.
+    pub const EMPTY: EventSet = EventSet(0);
+    /// The set of all events.
+    pub const ALL: EventSet = EventSet(0b1111 | (((1 << MAX_CUSTOM_EVENTS as u32) - 1) << CUSTOM_SHIFT));
+
+    /// Returns a set containing only the given event, which is empty if the event is out of range.
+    pub fn of(event: Event) -> EventSet {
+        EventSet(event.index().map_or(0, |i| 1 << i))
+    }
+
+    /// Returns this set with the given event added.
+    pub fn with(self, event: Event) -> EventSet {
+        EventSet(self.0 | EventSet::of(event).0)
+    }
+
+    /// Returns whether this set contains the given event.
+    pub fn contains(&self, event: Event) -> bool {
+        let bit = EventSet::of(event).0;
+        bit != 0 && self.0 & bit == bit
+    }
+
+    /// Returns whether this set contains no events.
+    pub fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+
+    /// Returns an iterator over the events in this set.
+    pub fn iter(&self) -> impl Iterator<Item = Event> {
+        let bits = self.0;
+        (0..NUM_EVENTS as u32).filter(move |i| bits & (1 << i) != 0).map(Event::from_index)
+    }
+}
+
+impl From<Event> for EventSet {
+    fn from(event: Event) -> EventSet {
+        EventSet::of(event)
+    }
+}
+
+impl fmt::Debug for EventSet {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_set().entries(self.iter()).finish()
+    }
+}
+
+/// Initializes event delivery by registering it to run whenever a task resumes at a safe point.
+///
+/// This must be invoked once before any handlers can run automatically;
+/// until then, handlers only run when a task invokes [`deliver_pending()`].
+pub fn init() {
+    scheduler::register_resume_callback(deliver_pending);
+}
+
+/// Posts the given `event` to the given `task`.
+///
+/// This never blocks or takes a lock, so it can be invoked in interrupt context.
+/// If the task is blocked in [`wait()`], it is unblocked.
+pub fn post(task: &TaskRef, event: Event) -> Result<(), &'static str> {
+    let bit = EventSet::of(event).0;
+    if bit == 0 {
+        return Err("custom event number is out of range");
+    }
+    let previous = task.pending_events().fetch_or(bit, Ordering::AcqRel);
+    if previous & WAITING != 0 {
+        let _ = task.unblock();
+    }
+    Ok(())
+}
+
+/// Posts the given `event` to every task, returning the number of tasks it was posted to.
+///
+/// This takes the lock on the task list, so it must not be invoked in interrupt context.
+pub fn broadcast(event: Event) -> Result<usize, &'static str> {
+    let tasks = TASKLIST.lock();
+    for task in tasks.values() {
+        post(task, event)?;
+    }
+    Ok(tasks.len())
+}
+
+/// Registers a handler that is invoked in the current task's context whenever the given `event`
+/// is posted to the current task, replacing any handler previously registered for that event.
+///
+/// Handlers run at safe points, i.e., when the current task resumes after voluntarily yielding,
+/// or when it invokes [`deliver_pending()`]. Events for which a handler is registered
+/// are never returned by [`poll()`] or [`wait()`].
+pub fn register_handler(event: Event, handler: Box<dyn FnMut(Event)>) -> Result<(), &'static str> {
+    let index = event.index().ok_or("custom event number is out of range")?;
+    HANDLERS.with(|handlers| handlers.borrow_mut()[index as usize] = Some(handler));
+    HANDLED.with(|handled| handled.set(handled.get().with(event)));
+    Ok(())
+}
+
+/// Removes the handler registered by the current task for the given `event`, returning it.
+///
+/// Afterwards, that event can be polled for again.
+pub fn unregister_handler(event: Event) -> Option<Box<dyn FnMut(Event)>> {
+    let index = event.index()?;
+    HANDLED.with(|handled| handled.set(EventSet(handled.get().0 & !(1 << index))));
+    HANDLERS.with(|handlers| handlers.borrow_mut()[index as usize].take())
+}
+
+/// Invokes the current task's handlers for each of its pending events that it has registered a handler for.
+///
+/// This is invoked automatically at safe points, but can also be invoked explicitly,
+/// e.g., by a long-running task that rarely yields.
+/// Handlers are not re-entered: if a handler yields, events posted meanwhile are delivered afterwards.
+pub fn deliver_pending() {
+    let handled = HANDLED.with(|handled| handled.get());
+    if handled.is_empty() || DELIVERING.with(|d| d.replace(true)) {
+        return;
+    }
+    while let Some(events) = take_pending(handled) {
+        for event in events.iter() {
+            let index = event.index().unwrap_or_default() as usize;
+            // Take the handler out while it runs, such that it may register or unregister handlers.
+            let handler = HANDLERS.with(|handlers| handlers.borrow_mut()[index].take());
+            if let Some(mut handler) = handler {
+                handler(event);
+                HANDLERS.with(|handlers| {
+                    let mut handlers = handlers.borrow_mut();
+                    if handlers[index].is_none() && HANDLED.with(|h| h.get()).contains(event) {
+                        handlers[index] = Some(handler);
+                    }
+                });
+            }
+        }
+    }
+    DELIVERING.with(|d| d.set(false));
+}
+
+/// Atomically removes the given events from the current task's pending events,
+/// returning those that were pending, or `None` if none were.
+fn take_pending(events: EventSet) -> Option<EventSet> {
+    let pending = task::with_current_task(|t| t.pending_events().fetch_and(!events.0, Ordering::AcqRel)).ok()?;
+    let taken = EventSet(pending & events.0);
+    (!taken.is_empty()).then_some(taken)
+}
+
+/// Returns and clears the current task's pending events that it hasn't registered handlers for,
+/// without blocking.
+pub fn poll() -> EventSet {
+    let unhandled = EventSet(EventSet::ALL.0 & !HANDLED.with(|handled| handled.get()).0);
+    take_pending(unhandled).unwrap_or_default()
+}
+
+/// Blocks the current task until any of the given `events` is posted to it,
+/// then returns and clears the pending events among them.
+///
+/// Events that the current task has registered handlers for are delivered to those handlers instead.
+pub fn wait(events: EventSet) -> Result<EventSet, &'static str> {
+    let events = EventSet(events.0 & !HANDLED.with(|handled| handled.get()).0);
+    if events.is_empty() {
+        return Err("cannot wait for no events, or only for events that have handlers");
+    }
+    let current = task::get_my_current_task().ok_or("couldn't get the current task")?;
+    let pending_events = current.pending_events();
+    loop {
+        if let Some(taken) = take_pending(events) {
+            return Ok(taken);
+        }
+        // Block before announcing that we're waiting, such that a poster that sees `WAITING`
+        // cannot unblock the current task before it has blocked.
+        current.block().map_err(|_| "couldn't block the current task")?;
+        let pending = pending_events.fetch_or(WAITING, Ordering::AcqRel);
+        if pending & events.0 != 0 {
+            pending_events.fetch_and(!WAITING, Ordering::AcqRel);
+            let _ = current.unblock();
+        } else {
+            scheduler::schedule();
+            pending_events.fetch_and(!WAITING, Ordering::AcqRel);
+        }
+    }
+}
@@ -48,7 +48,7 @@ use core::cell::RefCell;
 /// 1. We statically assert that the `TlsObjectDestructor` doesn't implement [`Drop`],
 ///    which makes sense because it only holds raw pointer values and function pointers.
 /// 2. The actual `Vec` is drained upon task exit by the task cleanup functions
-///    in the `spawn` crate`, ensuring that there is no `Vec` memory itself
+///    in the `spawn` crate (via [`run_current_tls_destructors()`]), ensuring that there is no `Vec` memory itself
 ///    to actually be deallocated, as the contents of this `Vec` have been cleared.
 /// 
 /// Note that this will always be safe even if the two conditions **aren't** met, 
@@ -79,18 +79,64 @@ pub fn take_current_tls_destructors() -> Vec<TlsObjectDestructor> {
     TLS_DESTRUCTORS.take()
 }
 
+/// Discards the list of TLS destructors that the current task inherited
+/// from another task's TLS area, without running them or freeing the list.
+///
+/// This is only intended to be used by a newly-spawned task whose TLS area
+/// was duplicated from its parent's live TLS area, because the inherited list
+/// is a bitwise copy of the parent's list, which the parent still owns.
+///
+/// # Safety
+/// This must be invoked before the current task registers or runs any TLS destructors.
+#[doc(hidden)]
+pub unsafe fn forget_inherited_tls_destructors() {
+    // SAFE: the inherited `RefCell` isn't borrowed, because the parent cannot
+    // have been borrowing its list while duplicating its TLS area.
+    core::mem::forget(TLS_DESTRUCTORS.replace(Vec::new()));
+}
+
+/// Runs the destructors of all TLS objects that have been initialized
+/// in this current task's TLS area, in the reverse order of their registration.
+///
+/// A destructor may access other TLS objects, which may register new destructors.
+/// Thus, this repeats until no registered destructors remain.
+///
+/// # Safety
+/// This is only intended to be used by the task cleanup functions
+/// after the current task has exited, as the destructed TLS objects
+/// cannot be safely accessed afterwards.
+#[doc(hidden)]
+pub unsafe fn run_current_tls_destructors() {
+    loop {
+        let mut tls_dtors = TLS_DESTRUCTORS.take();
+        if tls_dtors.is_empty() {
+            break;
+        }
+        while let Some(tls_dtor) = tls_dtors.pop() {
+            unsafe { (tls_dtor.dtor)(tls_dtor.object_ptr) };
+        }
+    }
+}
+
 /// Adds the given destructor callback to the current task's list of
 /// TLS destructors that should be run when that task exits.
 /// 
+/// Destructors are run in the reverse order of their registration,
+/// such that objects initialized later are destructed first.
+/// 
 /// # Arguments
-/// * `a`: the pointer to the object that will be destructed.
-/// * `dtor`: the function that should be invoked to destruct the object pointed to by `a`.
-///   When the current task exits, this function will be invoked with `a`
-///   as its only argument, at which point the `dtor` function should drop `a`.
+/// * `object_ptr`: the pointer to the object that will be destructed.
+/// * `dtor`: the function that should be invoked to destruct the object pointed to by `object_ptr`.
+///   When the current task exits, this function will be invoked with `object_ptr`
+///   as its only argument, at which point the `dtor` function should drop that object.
 /// 
-/// Currently the only value of `dtor` that is used is a type-specific monomorphized
+/// Within this crate, the only value of `dtor` that is used is a type-specific monomorphized
 /// version of the above [`fast::destroy_value()`] function.
-fn register_dtor(object_ptr: *mut u8, dtor: unsafe extern "C" fn(*mut u8)) {
+///
+/// # Safety
+/// The caller must ensure that `object_ptr` remains valid until the current task exits
+/// and that `dtor` can be safely invoked with `object_ptr` at that point.
+pub unsafe fn register_dtor(object_ptr: *mut u8, dtor: unsafe extern "C" fn(*mut u8)) {
     TLS_DESTRUCTORS.borrow_mut().push(TlsObjectDestructor { object_ptr, dtor });
 }
 
@@ -102,8 +148,7 @@ fn register_dtor(object_ptr: *mut u8, dtor: unsafe extern "C" fn(*mut u8)) {
 use core::cell::{Cell, UnsafeCell};
 use core::fmt;
 #[doc(hidden)]
-pub use core::option;
-use core::mem;
+pub use core::{mem, option, ptr};
 use core::hint;
 use alloc::vec::Vec;
 
@@ -275,7 +320,7 @@ macro_rules! __thread_local_inner {
                         // 0 == we haven't registered a destructor, so do
                         //   so now.
                         0 => {
-                            $crate::fast::Key::<$t>::register_dtor(
+                            $crate::register_dtor(
                                 $crate::ptr::addr_of_mut!(VAL) as *mut u8,
                                 destroy,
                             );
@@ -396,6 +441,125 @@ impl<T: 'static> LocalKey<T> {
     }
 }
 
+impl<T: 'static> LocalKey<Cell<T>> {
+    /// Sets the contained value, lazily initializing it first if needed.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the key currently has its destructor running,
+    /// and it **may** panic if the destructor has previously been run for this thread.
+    pub fn set(&'static self, value: T) {
+        self.with(|cell| cell.set(value))
+    }
+
+    /// Returns a copy of the contained value, lazily initializing it first if needed.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the key currently has its destructor running,
+    /// and it **may** panic if the destructor has previously been run for this thread.
+    pub fn get(&'static self) -> T
+    where
+        T: Copy,
+    {
+        self.with(|cell| cell.get())
+    }
+
+    /// Takes the contained value, leaving `Default::default()` in its place.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the key currently has its destructor running,
+    /// and it **may** panic if the destructor has previously been run for this thread.
+    pub fn take(&'static self) -> T
+    where
+        T: Default,
+    {
+        self.with(|cell| cell.take())
+    }
+
+    /// Replaces the contained value, returning the old value.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the key currently has its destructor running,
+    /// and it **may** panic if the destructor has previously been run for this thread.
+    pub fn replace(&'static self, value: T) -> T {
+        self.with(|cell| cell.replace(value))
+    }
+}
+
+impl<T: 'static> LocalKey<RefCell<T>> {
+    /// Acquires a reference to the contained value, lazily initializing it first if needed.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the value is currently mutably borrowed.
+    ///
+    /// Panics if the key currently has its destructor running,
+    /// and it **may** panic if the destructor has previously been run for this thread.
+    pub fn with_borrow<F, R>(&'static self, f: F) -> R
+    where
+        F: FnOnce(&T) -> R,
+    {
+        self.with(|cell| f(&cell.borrow()))
+    }
+
+    /// Acquires a mutable reference to the contained value, lazily initializing it first if needed.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the value is currently borrowed.
+    ///
+    /// Panics if the key currently has its destructor running,
+    /// and it **may** panic if the destructor has previously been run for this thread.
+    pub fn with_borrow_mut<F, R>(&'static self, f: F) -> R
+    where
+        F: FnOnce(&mut T) -> R,
+    {
+        self.with(|cell| f(&mut cell.borrow_mut()))
+    }
+
+    /// Sets the contained value, lazily initializing it first if needed.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the value is currently borrowed.
+    ///
+    /// Panics if the key currently has its destructor running,
+    /// and it **may** panic if the destructor has previously been run for this thread.
+    pub fn set(&'static self, value: T) {
+        self.with(|cell| *cell.borrow_mut() = value)
+    }
+
+    /// Takes the contained value, leaving `Default::default()` in its place.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the value is currently borrowed.
+    ///
+    /// Panics if the key currently has its destructor running,
+    /// and it **may** panic if the destructor has previously been run for this thread.
+    pub fn take(&'static self) -> T
+    where
+        T: Default,
+    {
+        self.with(|cell| cell.take())
+    }
+
+    /// Replaces the contained value, returning the old value.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the value is currently borrowed.
+    ///
+    /// Panics if the key currently has its destructor running,
+    /// and it **may** panic if the destructor has previously been run for this thread.
+    pub fn replace(&'static self, value: T) -> T {
+        self.with(|cell| cell.replace(value))
+    }
+}
+
 mod lazy {
     use crate::UnsafeCell;
     use crate::hint;
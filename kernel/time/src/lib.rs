@@ -230,6 +230,16 @@ where
     f()
 }
 
+/// Returns the period of the current clock source of the specified type,
+/// or `None` if no such clock source has been registered.
+pub fn period<T>() -> Option<Period>
+where
+    T: ClockType,
+{
+    let period = T::period_atomic().load();
+    (period != Period::MAX).then_some(period)
+}
+
 /// A clock source.
 pub trait ClockSource {
     /// The type of clock (either [`Monotonic`] or [`WallTime`]).
@@ -0,0 +1,322 @@
+//! High-resolution one-shot and periodic timers.
+//!
+//! A timer fires at a deadline with nanosecond resolution, as measured by the monotonic clock
+//! registered with the `time` crate, and then performs its [`TimerAction`]:
+//! invoking a callback, unblocking a task, posting an event to a task, or waking an async [`Waker`].
+//! Periodic timers are re-armed after each expiry.
+//!
+//! Pending timers are kept in a hashed timer wheel: each slot of the wheel covers
+//! a fixed interval of time, and a timer is placed in the slot covering its deadline
+//! (modulo the length of the wheel), so adding a timer is O(1) and expiring timers
+//! only requires scanning the slots that have elapsed since the last expiry.
+//!
+//! Expired timers are processed by [`process_expired_timers()`], which is invoked upon
+//! every timer interrupt. To fire timers between the periodic scheduler ticks,
+//! the platform registers a hardware one-shot timer via [`register_hardware_timer()`],
+//! e.g., the local APIC in TSC-deadline mode on x86_64,
+//! which this crate programs to interrupt at the earliest pending deadline.
+//! Without one, timers fire upon the first scheduler tick after their deadline.
+
+#![no_std]
+
+extern crate alloc;
+
+use alloc::{boxed::Box, vec::Vec};
+use core::{
+    fmt,
+    sync::atomic::{AtomicU64, Ordering},
+    task::Waker,
+};
+use irq_safety::MutexIrqSafe;
+use spin::Once;
+use task::TaskRef;
+use task_events::Event;
+use time::{now, Duration, Instant, Monotonic};
+
+/// Each slot of the timer wheel covers `2^SLOT_SHIFT` nanoseconds (about 1 ms).
+const SLOT_SHIFT: u32 = 20;
+/// The number of slots in the timer wheel, which covers about 268 ms per rotation.
+const NUM_SLOTS: usize = 256;
+
+/// The pending timers.
+static WHEEL: MutexIrqSafe<TimerWheel> = MutexIrqSafe::new(TimerWheel::new());
+/// The earliest deadline of any pending timer, in nanoseconds, or `u64::MAX` if there is none.
+///
+/// This is checked before taking the `WHEEL` lock upon every timer interrupt.
+static NEXT_DEADLINE: AtomicU64 = AtomicU64::new(u64::MAX);
+/// The ID of the next timer to be started.
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+/// The function that requests a hardware timer interrupt on the current CPU after a given duration.
+static HARDWARE_TIMER: Once<fn(Duration)> = Once::new();
+
+/// What a timer does when it fires.
+pub enum TimerAction {
+    /// Invokes the given callback.
+    ///
+    /// The callback runs in interrupt context with interrupts disabled,
+    /// so it must be short and must not block.
+    Callback(Box<dyn FnMut() + Send>),
+    /// Unblocks the given task, e.g., a task that is sleeping until the deadline.
+    Unblock(TaskRef),
+    /// Posts [`Event::TimerExpired`] to the given task, see the `task_events` crate.
+    PostEvent(TaskRef),
+    /// Wakes the given async waker.
+    Wake(Waker),
+}
+
+impl TimerAction {
+    fn fire(&mut self) {
+        match self {
+            TimerAction::Callback(callback) => callback(),
+            // A periodic timer may fire again before its task has blocked, which is harmless.
+            TimerAction::Unblock(task) => { let _ = task.unblock(); }
+            TimerAction::PostEvent(task) => { let _ = task_events::post(task, Event::TimerExpired); }
+            TimerAction::Wake(waker) => waker.wake_by_ref(),
+        }
+    }
+}
+
+impl fmt::Debug for TimerAction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TimerAction::Callback(_) => write!(f, "Callback"),
+            TimerAction::Unblock(task) => write!(f, "Unblock({:?})", task),
+            TimerAction::PostEvent(task) => write!(f, "PostEvent({:?})", task),
+            TimerAction::Wake(_) => write!(f, "Wake"),
+        }
+    }
+}
+
+/// A unique identifier of a started timer, which can be used to cancel it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct TimerId(u64);
+
+struct Timer {
+    id: TimerId,
+    /// The deadline, in nanoseconds of the monotonic clock.
+    deadline: u64,
+    /// The period of a periodic timer, in nanoseconds.
+    period: Option<u64>,
+    action: TimerAction,
+}
+
+struct TimerWheel {
+    slots: [Vec<Timer>; NUM_SLOTS],
+    /// The time, in nanoseconds, up to which all timers have been expired.
+    expired_until: u64,
+    /// The number of pending timers.
+    len: usize,
+    /// The periodic timers that have expired and are currently firing,
+    /// and whether each was cancelled while firing, in which case it is not re-armed.
+    firing: Vec<(TimerId, bool)>,
+}
+
+impl TimerWheel {
+    const fn new() -> TimerWheel {
+        const EMPTY: Vec<Timer> = Vec::new();
+        TimerWheel { slots: [EMPTY; NUM_SLOTS], expired_until: 0, len: 0, firing: Vec::new() }
+    }
+
+    fn insert(&mut self, timer: Timer) {
+        // A timer whose deadline has already passed goes into the next slot to be expired.
+        let slot = slot_of(core::cmp::max(timer.deadline, self.expired_until));
+        self.slots[slot].push(timer);
+        self.len += 1;
+    }
+
+    /// Removes the timer with the given ID, returning whether it was pending or firing.
+    fn cancel(&mut self, id: TimerId) -> bool {
+        for slot in self.slots.iter_mut() {
+            if let Some(index) = slot.iter().position(|t| t.id == id) {
+                slot.swap_remove(index);
+                self.len -= 1;
+                return true;
+            }
+        }
+        if let Some((_, cancelled)) = self.firing.iter_mut().find(|(firing_id, _)| *firing_id == id) {
+            *cancelled = true;
+            return true;
+        }
+        false
+    }
+
+    /// Moves every timer whose deadline is at or before `now` into `expired`.
+    fn take_expired(&mut self, now: u64, expired: &mut Vec<Timer>) {
+        if now < self.expired_until {
+            return;
+        }
+        let first = self.expired_until >> SLOT_SHIFT;
+        let last = now >> SLOT_SHIFT;
+        let num_slots = core::cmp::min(last - first + 1, NUM_SLOTS as u64);
+        for i in 0..num_slots {
+            let slot = &mut self.slots[((first + i) % NUM_SLOTS as u64) as usize];
+            let mut index = 0;
+            while index < slot.len() {
+                if slot[index].deadline <= now {
+                    expired.push(slot.swap_remove(index));
+                } else {
+                    index += 1;
+                }
+            }
+        }
+        self.len -= expired.len();
+        self.expired_until = now;
+    }
+
+    /// Returns the earliest deadline of any pending timer, or `u64::MAX` if there is none.
+    fn next_deadline(&self) -> u64 {
+        if self.len == 0 {
+            return u64::MAX;
+        }
+        // Timers in a slot may belong to later rotations of the wheel,
+        // so the first slot containing a timer from the current rotation holds the earliest deadline.
+        let first = self.expired_until >> SLOT_SHIFT;
+        for i in 0..NUM_SLOTS as u64 {
+            let slot = &self.slots[((first + i) % NUM_SLOTS as u64) as usize];
+            let earliest = slot.iter()
+                .filter(|t| t.deadline >> SLOT_SHIFT <= first + i)
+                .map(|t| t.deadline)
+                .min();
+            if let Some(deadline) = earliest {
+                return deadline;
+            }
+        }
+        // Every pending timer is at least one rotation away.
+        self.slots.iter().flatten().map(|t| t.deadline).min().unwrap_or(u64::MAX)
+    }
+}
+
+/// Returns the index of the wheel slot that covers the given time in nanoseconds.
+fn slot_of(nanos: u64) -> usize {
+    ((nanos >> SLOT_SHIFT) % NUM_SLOTS as u64) as usize
+}
+
+/// Returns the given instant in nanoseconds of the monotonic clock.
+fn nanos_of(instant: Instant) -> u64 {
+    instant.duration_since(Instant::ZERO).as_nanos() as u64
+}
+
+/// Returns the current time in nanoseconds of the monotonic clock.
+fn now_nanos() -> u64 {
+    nanos_of(now::<Monotonic>())
+}
+
+/// Registers the function that requests a one-shot hardware timer interrupt
+/// on the current CPU after the given duration, or earlier.
+///
+/// Upon that interrupt, the interrupt handler must invoke [`process_expired_timers()`].
+///
+/// Returns `false` if a hardware timer was already registered, in which case it is kept.
+pub fn register_hardware_timer(request_interrupt_after: fn(Duration)) -> bool {
+    let mut registered = false;
+    HARDWARE_TIMER.call_once(|| {
+        registered = true;
+        request_interrupt_after
+    });
+    registered
+}
+
+/// Requests a hardware timer interrupt at the given deadline, if there is a hardware timer.
+fn program_hardware_timer(deadline: u64) {
+    if deadline == u64::MAX {
+        return;
+    }
+    if let Some(request_interrupt_after) = HARDWARE_TIMER.get() {
+        request_interrupt_after(Duration::from_nanos(deadline.saturating_sub(now_nanos())));
+    }
+}
+
+fn start(deadline: u64, period: Option<u64>, action: TimerAction) -> TimerId {
+    let id = TimerId(NEXT_ID.fetch_add(1, Ordering::Relaxed));
+    WHEEL.lock().insert(Timer { id, deadline, period, action });
+    if NEXT_DEADLINE.fetch_min(deadline, Ordering::AcqRel) > deadline {
+        program_hardware_timer(deadline);
+    }
+    id
+}
+
+/// Starts a timer that performs the given `action` once, at the given `deadline`.
+///
+/// If the deadline has already passed, the timer fires upon the next timer interrupt.
+pub fn start_one_shot(deadline: Instant, action: TimerAction) -> TimerId {
+    start(nanos_of(deadline), None, action)
+}
+
+/// Starts a timer that performs the given `action` once, after the given `delay`.
+pub fn start_one_shot_after(delay: Duration, action: TimerAction) -> TimerId {
+    start(now_nanos().saturating_add(delay.as_nanos() as u64), None, action)
+}
+
+/// Starts a timer that performs the given `action` every `period`, beginning one `period` from now,
+/// until it is cancelled.
+///
+/// If the timer cannot fire in time, e.g., because interrupts were disabled,
+/// the missed expiries are skipped rather than fired in a burst.
+///
+/// Returns an error if the `period` is zero.
+pub fn start_periodic(period: Duration, action: TimerAction) -> Result<TimerId, &'static str> {
+    let period = period.as_nanos() as u64;
+    if period == 0 {
+        return Err("the period of a periodic timer must not be zero");
+    }
+    Ok(start(now_nanos().saturating_add(period), Some(period), action))
+}
+
+/// Cancels the timer with the given ID, such that it doesn't fire again.
+///
+/// Returns `true` if the timer was pending, or `false` if it had already fired or been cancelled.
+/// A periodic timer that is firing while it is cancelled finishes its current action but isn't re-armed.
+pub fn cancel(id: TimerId) -> bool {
+    // `NEXT_DEADLINE` may now be earlier than necessary, which only causes a spurious timer interrupt.
+    WHEEL.lock().cancel(id)
+}
+
+/// Returns the number of pending timers.
+pub fn num_pending() -> usize {
+    WHEEL.lock().len
+}
+
+/// Fires every timer whose deadline has passed and re-arms the expired periodic timers,
+/// and then programs the hardware timer for the earliest remaining deadline.
+///
+/// This is invoked upon every timer interrupt.
+pub fn process_expired_timers() {
+    let now = now_nanos();
+    if now < NEXT_DEADLINE.load(Ordering::Acquire) {
+        return;
+    }
+
+    let mut expired = Vec::new();
+    {
+        let mut wheel = WHEEL.lock();
+        wheel.take_expired(now, &mut expired);
+        for timer in expired.iter().filter(|t| t.period.is_some()) {
+            wheel.firing.push((timer.id, false));
+        }
+        NEXT_DEADLINE.store(wheel.next_deadline(), Ordering::Release);
+    }
+
+    for timer in expired.iter_mut() {
+        timer.action.fire();
+    }
+
+    let next_deadline = {
+        let mut wheel = WHEEL.lock();
+        for mut timer in expired.into_iter() {
+            let Some(period) = timer.period else { continue };
+            let Some(index) = wheel.firing.iter().position(|(id, _)| *id == timer.id) else { continue };
+            let (_, cancelled) = wheel.firing.swap_remove(index);
+            if cancelled {
+                continue;
+            }
+            // Skip any expiries that were missed, keeping the timer aligned to its period.
+            let missed = now.saturating_sub(timer.deadline) / period;
+            timer.deadline = timer.deadline.saturating_add((missed + 1) * period);
+            wheel.insert(timer);
+        }
+        let next_deadline = wheel.next_deadline();
+        NEXT_DEADLINE.store(next_deadline, Ordering::Release);
+        next_deadline
+    };
+    program_hardware_timer(next_deadline);
+}
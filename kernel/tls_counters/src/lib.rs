@@ -0,0 +1,134 @@
+//! Cheap per-task profiling counters stored in each task's TLS area.
+//!
+//! Each task's TLS area contains a block of counters at a fixed offset
+//! (see [`tls_initializer::TLS_COUNTERS_OFFSET`]), which only that task updates.
+//! Thus, [`incr()`] and [`add()`] need no atomic instructions or locks.
+//!
+//! Other tasks (e.g., a profiler) can read any task's counters from its `TlsDataImage`
+//! and combine them into a system-wide [`CounterReport`] via [`aggregate()`].
+
+#![no_std]
+
+use core::fmt;
+use tls_initializer::{TlsDataImage, NUM_TLS_COUNTERS};
+
+/// The events counted for each task.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(usize)]
+pub enum Counter {
+    /// The number of heap allocations.
+    Allocations = 0,
+    /// The number of times the task was switched to.
+    ContextSwitches = 1,
+    /// The number of times the task had to sleep while waiting to acquire a lock.
+    LockWaits = 2,
+    /// The total number of bytes allocated from the heap.
+    HeapBytesAllocated = 3,
+    /// The total number of bytes deallocated back to the heap.
+    HeapBytesFreed = 4,
+}
+
+impl Counter {
+    /// All counters, in order of their index into the block of counters.
+    pub const ALL: [Counter; 5] = [
+        Counter::Allocations,
+        Counter::ContextSwitches,
+        Counter::LockWaits,
+        Counter::HeapBytesAllocated,
+        Counter::HeapBytesFreed,
+    ];
+
+    /// Returns the name of this counter.
+    pub fn name(self) -> &'static str {
+        match self {
+            Counter::Allocations        => "Allocations",
+            Counter::ContextSwitches    => "ContextSwitches",
+            Counter::LockWaits          => "LockWaits",
+            Counter::HeapBytesAllocated => "HeapBytesAllocated",
+            Counter::HeapBytesFreed     => "HeapBytesFreed",
+        }
+    }
+}
+
+const _: () = assert!(Counter::ALL.len() <= NUM_TLS_COUNTERS);
+
+/// Increments the given `counter` of the current task by one.
+#[inline(always)]
+pub fn incr(counter: Counter) {
+    add(counter, 1);
+}
+
+/// Adds `value` to the given `counter` of the current task.
+#[inline(always)]
+pub fn add(counter: Counter, value: u64) {
+    // SAFE: every TLS data image reserves the block of counters,
+    // which only the current task writes to.
+    unsafe {
+        let ptr = tls_initializer::tls_counters_location().add(counter as usize);
+        ptr.write_volatile(ptr.read_volatile().wrapping_add(value));
+    }
+}
+
+/// Adds `value` to the given `counter` of the task whose TLS self pointer is `tls_self_pointer`.
+///
+/// This is intended for use by the heap, which reads the TLS register only once per allocation;
+/// see [`tls_initializer::try_current_tls_self_pointer()`].
+///
+/// # Safety
+/// The `tls_self_pointer` must be that of a live TLS data image,
+/// and the caller must prevent concurrent updates to its counters,
+/// e.g., by holding interrupts while the current task updates its own counters.
+#[inline(always)]
+pub unsafe fn add_at(tls_self_pointer: usize, counter: Counter, value: u64) {
+    let ptr = tls_initializer::tls_counters_location_at(tls_self_pointer).add(counter as usize);
+    ptr.write_volatile(ptr.read_volatile().wrapping_add(value));
+}
+
+/// Returns the value of the given `counter` of the current task.
+pub fn get(counter: Counter) -> u64 {
+    // SAFE: every TLS data image reserves the block of counters.
+    unsafe { tls_initializer::tls_counters_location().add(counter as usize).read_volatile() }
+}
+
+/// The sum of each counter across a set of tasks.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CounterReport {
+    /// The number of tasks whose counters were included in this report.
+    pub num_tasks: usize,
+    totals: [u64; NUM_TLS_COUNTERS],
+}
+
+impl CounterReport {
+    /// Returns the total value of the given `counter`.
+    pub fn get(&self, counter: Counter) -> u64 {
+        self.totals[counter as usize]
+    }
+}
+
+impl fmt::Display for CounterReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Counters summed across {} tasks:", self.num_tasks)?;
+        for counter in Counter::ALL {
+            writeln!(f, "  {:<18} {}", counter.name(), self.get(counter))?;
+        }
+        Ok(())
+    }
+}
+
+/// Sums the counters stored in each of the given TLS data images into a system-wide report.
+///
+/// Counters of running tasks are read without synchronization,
+/// so the report is a best-effort snapshot.
+pub fn aggregate<'a, I>(tls_images: I) -> CounterReport
+where
+    I: IntoIterator<Item = &'a TlsDataImage>,
+{
+    let mut report = CounterReport::default();
+    for counters in tls_images.into_iter().filter_map(TlsDataImage::counters) {
+        report.num_tasks += 1;
+        for (total, value) in report.totals.iter_mut().zip(counters) {
+            *total = total.wrapping_add(value);
+        }
+    }
+    report
+}
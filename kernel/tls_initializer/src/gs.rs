@@ -0,0 +1,141 @@
+//! Management of the `GS` segment base registers on x86_64, which separates
+//! the kernel's per-CPU base from user-space `GS` values.
+//!
+//! While in kernel mode, the `GS_BASE` MSR holds the kernel's per-CPU base,
+//! i.e., the per-CPU area that the `cpu_stats` crate installs via [`init_kernel_gs_base()`]
+//! while each CPU boots up, and the `KERNEL_GS_BASE` MSR holds the user's `GS` base.
+//! While in user mode, the two are swapped (via the `swapgs` instruction).
+//!
+//! This module is the only code that writes either MSR. It records each CPU's kernel base,
+//! such that `GS`-relative accesses can first check [`has_kernel_gs_base()`].
+//!
+//! A kernel entry point that can be reached from user mode must obtain a [`KernelGsGuard`]
+//! before accessing per-CPU data:
+//! * Regular interrupt and exception handlers use [`KernelGsGuard::enter()`],
+//!   which only executes `swapgs` if the interrupted context was in user mode.
+//! * Handlers for exceptions that can interrupt the kernel *anywhere*, including
+//!   in between an entry point and its `swapgs` (NMIs, machine checks, and debug exceptions),
+//!   must use [`KernelGsGuard::enter_paranoid()`], which inspects `GS_BASE` itself.
+//!
+//! Currently, Theseus runs every task in kernel mode and has no syscall entry path,
+//! so `GS_BASE` always holds the kernel's per-CPU base and these guards never swap.
+//! The exception handlers in `exceptions_full` already obtain them;
+//! a syscall entry path must execute `swapgs` itself upon entry and before `sysret`,
+//! because it runs before any Rust code can obtain a guard.
+
+use core::arch::asm;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use x86_64::registers::model_specific::{GsBase, KernelGsBase};
+use x86_64::VirtAddr;
+use super::{MAX_CPUS, hardened_cpu_id};
+
+/// The kernel `GS` base installed on each CPU, or `0` if none has been installed yet.
+///
+/// Each entry is only written by its own CPU, and CPUs keep their `GS` base while offline.
+static KERNEL_GS_BASES: [AtomicUsize; MAX_CPUS] = {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const ZERO: AtomicUsize = AtomicUsize::new(0);
+    [ZERO; MAX_CPUS]
+};
+
+/// Initializes the current CPU's `GS` base registers for kernel mode,
+/// such that `GS_BASE` holds the given kernel `per_cpu_base`
+/// and `KERNEL_GS_BASE` holds a null user `GS` base.
+///
+/// The current CPU must be the CPU with the given `cpu_id`, whose hardened CPU ID
+/// must already be initialized; see [`init_hardened_cpu_id()`](super::init_hardened_cpu_id).
+/// The `per_cpu_base` must be a higher-half (kernel) address.
+/// This must be invoked on every CPU while it boots up, before interrupts are enabled on it.
+///
+/// Returns an error if a different kernel `GS` base was already installed on this CPU,
+/// because existing `GS`-relative accesses rely on it.
+pub fn init_kernel_gs_base(cpu_id: u8, per_cpu_base: usize) -> Result<(), &'static str> {
+    if !is_kernel_address(per_cpu_base) {
+        return Err("kernel per-CPU GS base must be a higher-half address");
+    }
+    if hardened_cpu_id() != Some(cpu_id) {
+        return Err("kernel GS base must be initialized on its own CPU, after its hardened CPU ID");
+    }
+    let installed = &KERNEL_GS_BASES[cpu_id as usize];
+    match installed.load(Ordering::Relaxed) {
+        0 => { }
+        existing if existing == per_cpu_base => { }
+        _ => return Err("a different kernel GS base was already installed on this CPU"),
+    }
+    GsBase::write(VirtAddr::new_truncate(per_cpu_base as u64));
+    KernelGsBase::write(VirtAddr::zero());
+    // Only record the base once both MSRs hold their kernel-mode values.
+    installed.store(per_cpu_base, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Returns `true` if the current CPU's kernel `GS` base has been installed
+/// via [`init_kernel_gs_base()`], i.e., if `GS`-relative accesses to per-CPU data are valid.
+///
+/// This is safe to invoke from any context, including interrupt and NMI handlers.
+#[inline(always)]
+pub fn has_kernel_gs_base() -> bool {
+    // Each entry is only written by its own CPU, so relaxed ordering suffices.
+    hardened_cpu_id().map_or(false, |cpu_id| KERNEL_GS_BASES[cpu_id as usize].load(Ordering::Relaxed) != 0)
+}
+
+/// Returns `true` if the given address is in the kernel's (higher) half of the address space.
+fn is_kernel_address(addr: usize) -> bool {
+    (addr as isize) < 0
+}
+
+/// A guard that ensures `GS_BASE` holds the kernel's per-CPU base
+/// for as long as it exists, and restores the interrupted context's `GS` base when dropped.
+///
+/// This must be dropped at the very end of a kernel entry point, right before returning.
+#[must_use]
+pub struct KernelGsGuard {
+    swapped: bool,
+}
+
+impl KernelGsGuard {
+    /// Switches to the kernel's `GS` base if the interrupted context was in user mode,
+    /// based on the privilege level of the interrupted code segment (`interrupted_cs`)
+    /// as saved in the interrupt stack frame.
+    #[inline(always)]
+    pub fn enter(interrupted_cs: u64) -> KernelGsGuard {
+        let from_user = interrupted_cs & 0b11 == 3;
+        if from_user {
+            // SAFETY: the interrupted context was in user mode, so `GS_BASE` holds the user's base.
+            unsafe { swapgs(); }
+        }
+        KernelGsGuard { swapped: from_user }
+    }
+
+    /// Switches to the kernel's `GS` base if it isn't already active,
+    /// based on the current value of `GS_BASE` rather than the interrupted privilege level.
+    ///
+    /// This is required for NMIs, machine checks, and debug exceptions, which may interrupt
+    /// a kernel entry or exit path after the privilege level changed but before or after `swapgs`.
+    #[inline(always)]
+    pub fn enter_paranoid() -> KernelGsGuard {
+        let needs_swap = !is_kernel_address(GsBase::read().as_u64() as usize)
+            && is_kernel_address(KernelGsBase::read().as_u64() as usize);
+        if needs_swap {
+            // SAFETY: `KERNEL_GS_BASE` holds the kernel's per-CPU base, so we swap it in.
+            unsafe { swapgs(); }
+        }
+        KernelGsGuard { swapped: needs_swap }
+    }
+}
+
+impl Drop for KernelGsGuard {
+    #[inline(always)]
+    fn drop(&mut self) {
+        if self.swapped {
+            // SAFETY: we swapped `GS` upon entry, so we restore the interrupted context's `GS` base.
+            unsafe { swapgs(); }
+        }
+    }
+}
+
+/// Executes the `swapgs` instruction.
+#[inline(always)]
+unsafe fn swapgs() {
+    asm!("swapgs", options(nostack, preserves_flags));
+}
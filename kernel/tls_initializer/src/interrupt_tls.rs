@@ -0,0 +1,122 @@
+//! Dedicated per-CPU TLS areas for interrupt handlers.
+//!
+//! By default, an interrupt handler runs atop the TLS area of whichever task it interrupted,
+//! so any TLS variables it accesses belong to that task.
+//! To isolate them, each CPU can be given an interrupt TLS area via [`init_interrupt_tls()`],
+//! which an interrupt handler installs via [`enter_interrupt_tls()`] upon entry.
+//! The interrupted TLS area is restored when the returned [`InterruptTlsGuard`] is dropped.
+//!
+//! Because the interrupt TLS area doesn't belong to any task, handlers must not rely on
+//! task-specific TLS variables (e.g., the current task) while it is installed,
+//! and must drop the guard before switching to another task.
+
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::Mutex;
+use super::{TlsDataImage, MAX_CPUS, read_tls_register, write_tls_register};
+
+/// The interrupt TLS area of a single CPU.
+struct InterruptTls {
+    /// The TLS self pointer of the current interrupt TLS image, or `0` if it hasn't been initialized.
+    base: AtomicUsize,
+    /// The number of handlers on this CPU that have installed an interrupt TLS image
+    /// and haven't yet restored the interrupted TLS area.
+    active: AtomicUsize,
+    /// The current interrupt TLS image, followed by any replaced images
+    /// that a handler on this CPU may still be using.
+    images: Mutex<Vec<TlsDataImage>>,
+}
+
+impl InterruptTls {
+    /// Drops all replaced images if no handler on this CPU is using an interrupt TLS image.
+    ///
+    /// Does nothing if the images are locked, in which case their holder will do this instead.
+    fn release_replaced(&self) {
+        if let Some(mut images) = self.images.try_lock() {
+            if self.active.load(Ordering::SeqCst) == 0 && images.len() > 1 {
+                let current = images.pop();
+                images.clear();
+                images.extend(current);
+            }
+        }
+    }
+}
+
+static INTERRUPT_TLS: [InterruptTls; MAX_CPUS] = {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const EMPTY: InterruptTls = InterruptTls {
+        base: AtomicUsize::new(0),
+        active: AtomicUsize::new(0),
+        images: Mutex::new(Vec::new()),
+    };
+    [EMPTY; MAX_CPUS]
+};
+
+/// Sets the given `image` as the interrupt TLS area for the CPU with the given `cpu_id`.
+///
+/// The `image` should be obtained from the [`TlsInitializer`](super::TlsInitializer)
+/// after all crates whose TLS variables are used in interrupt context have been loaded;
+/// otherwise, it can be replaced by invoking this again with a newer image.
+/// A replaced image is dropped once no handler on that CPU is using it.
+pub fn init_interrupt_tls(cpu_id: u8, image: TlsDataImage) -> Result<(), &'static str> {
+    if image.self_pointer() == 0 {
+        return Err("cannot use an empty TLS data image as an interrupt TLS area");
+    }
+    let interrupt_tls = &INTERRUPT_TLS[cpu_id as usize];
+    let base = image.self_pointer();
+    // The image must be owned before any handler can install it.
+    // Handlers only ever `try_lock()` the images, so this cannot deadlock with a handler on this CPU.
+    interrupt_tls.images.lock().push(image);
+    interrupt_tls.base.store(base, Ordering::SeqCst);
+    interrupt_tls.release_replaced();
+    Ok(())
+}
+
+/// Returns `true` if the CPU with the given `cpu_id` has an interrupt TLS area.
+pub fn has_interrupt_tls(cpu_id: u8) -> bool {
+    INTERRUPT_TLS[cpu_id as usize].base.load(Ordering::Acquire) != 0
+}
+
+/// Installs the current CPU's interrupt TLS area, if it has one,
+/// and returns a guard that restores the interrupted TLS area when dropped.
+///
+/// Nested interrupts are supported: if the interrupt TLS area is already installed,
+/// the returned guard does nothing.
+/// The given `cpu_id` must be the ID of the current CPU.
+#[must_use]
+pub fn enter_interrupt_tls(cpu_id: u8) -> InterruptTlsGuard {
+    let interrupt_tls = &INTERRUPT_TLS[cpu_id as usize];
+    // Mark the image as in use before reading its base, such that it cannot be dropped
+    // by a concurrent `init_interrupt_tls()` in between.
+    interrupt_tls.active.fetch_add(1, Ordering::SeqCst);
+    let base = interrupt_tls.base.load(Ordering::SeqCst);
+    let interrupted = read_tls_register();
+    if base == 0 || base == interrupted {
+        interrupt_tls.active.fetch_sub(1, Ordering::SeqCst);
+        return InterruptTlsGuard { cpu_id, interrupted: None };
+    }
+    write_tls_register(base, Some(cpu_id));
+    InterruptTlsGuard { cpu_id, interrupted: Some(interrupted) }
+}
+
+/// A guard that restores the interrupted TLS area when dropped.
+///
+/// See [`enter_interrupt_tls()`].
+pub struct InterruptTlsGuard {
+    cpu_id: u8,
+    /// The value of the TLS register before the interrupt TLS area was installed,
+    /// or `None` if this guard didn't install it.
+    interrupted: Option<usize>,
+}
+
+impl Drop for InterruptTlsGuard {
+    fn drop(&mut self) {
+        if let Some(interrupted) = self.interrupted {
+            write_tls_register(interrupted, Some(self.cpu_id));
+            let interrupt_tls = &INTERRUPT_TLS[self.cpu_id as usize];
+            if interrupt_tls.active.fetch_sub(1, Ordering::SeqCst) == 1 {
+                interrupt_tls.release_replaced();
+            }
+        }
+    }
+}
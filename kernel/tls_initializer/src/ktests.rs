@@ -0,0 +1,282 @@
+//! In-kernel tests of the TLS layout logic, which use real `LoadedSection`s backed by mapped pages.
+//!
+//! These are only compiled with the `ktest` configuration; run them via `make ktest`.
+
+use alloc::{boxed::Box, sync::Arc, vec::Vec};
+use core::ops::Range;
+use cow_arc::CowWeak;
+use crate_metadata::{LoadedSection, SectionType, StrRef, StrongSectionRef};
+use ktest_macros::ktest;
+use memory::PteFlags;
+use memory_structs::VirtualAddress;
+use spin::Mutex;
+use crate::{TlsAllocHint, TlsInitializer, TlsTemplateCell, UNWINDING_CONTEXT_OFFSET};
+
+/// Creates an empty `TlsInitializer` that publishes to its own template cell,
+/// such that tests don't interfere with the kernel's real TLS initializer.
+fn new_initializer() -> TlsInitializer {
+    TlsInitializer::new(Box::leak(Box::new(TlsTemplateCell::empty())))
+}
+
+/// Creates a TLS section of the given type and size, backed by newly-mapped pages
+/// in which every byte is `fill`.
+fn new_section(typ: SectionType, name: &str, size: usize, fill: u8) -> Result<LoadedSection, &'static str> {
+    let mut mp = memory::create_mapping(size, PteFlags::new().valid(true).writable(true))?;
+    mp.as_slice_mut::<u8>(0, size)?.fill(fill);
+    Ok(LoadedSection::new(
+        typ,
+        StrRef::from(name),
+        Arc::new(Mutex::new(mp)),
+        0,
+        VirtualAddress::zero(),
+        size,
+        true,
+        CowWeak::new(),
+    ))
+}
+
+/// Returns the range of offsets from the TLS self pointer occupied by the given dynamic TLS `section`.
+fn range_of(tls: &TlsInitializer, section: &StrongSectionRef) -> Result<Range<usize>, &'static str> {
+    let offset = tls.offset_of(section).ok_or("section isn't in the TLS initializer")?;
+    if offset < 0 {
+        return Err("dynamic TLS section has a negative offset");
+    }
+    Ok(offset as usize .. offset as usize + section.size)
+}
+
+#[ktest]
+fn dynamic_sections_are_aligned_and_disjoint() -> Result<(), &'static str> {
+    let mut tls = new_initializer();
+    let mut ranges: Vec<Range<usize>> = Vec::new();
+    for (size, alignment) in [(3, 1), (16, 16), (8, 8), (1, 64)] {
+        let section = new_section(SectionType::TlsData, "test_data", size, 0xAA)?;
+        let (offset, section) = tls.add_new_dynamic_tls_section(section, alignment)
+            .map_err(|_| "couldn't add dynamic TLS section")?;
+        if offset % alignment != 0 {
+            return Err("dynamic TLS section isn't aligned");
+        }
+        if offset < UNWINDING_CONTEXT_OFFSET + core::mem::size_of::<usize>() {
+            return Err("dynamic TLS section overlaps the reserved area");
+        }
+        if section.virt_addr.value() != offset {
+            return Err("section's virtual address isn't its TLS offset");
+        }
+        let range = range_of(&tls, &section)?;
+        if range.start != offset {
+            return Err("offset_of() doesn't match the offset returned when adding the section");
+        }
+        if ranges.iter().any(|r| r.start < range.end && range.start < r.end) {
+            return Err("dynamic TLS sections overlap");
+        }
+        ranges.push(range);
+    }
+    Ok(())
+}
+
+#[ktest]
+fn removed_range_is_reused() -> Result<(), &'static str> {
+    let mut tls = new_initializer();
+    let (first_offset, first) = tls.add_new_dynamic_tls_section(new_section(SectionType::TlsBss, "first", 32, 0)?, 8)
+        .map_err(|_| "couldn't add first section")?;
+    tls.add_new_dynamic_tls_section(new_section(SectionType::TlsBss, "second", 32, 0)?, 8)
+        .map_err(|_| "couldn't add second section")?;
+    tls.remove_dynamic_tls_section(&first).map_err(|_| "couldn't remove first section")?;
+    if tls.offset_of(&first).is_some() {
+        return Err("removed section still has an offset");
+    }
+    if tls.remove_dynamic_tls_section(&first).is_ok() {
+        return Err("removing a section twice succeeded");
+    }
+    let (third_offset, _) = tls.add_new_dynamic_tls_section(new_section(SectionType::TlsBss, "third", 16, 0)?, 8)
+        .map_err(|_| "couldn't add third section")?;
+    if third_offset != first_offset {
+        return Err("the removed section's range wasn't reused");
+    }
+    Ok(())
+}
+
+#[ktest]
+fn replacement_keeps_offset() -> Result<(), &'static str> {
+    let mut tls = new_initializer();
+    let (offset, old) = tls.add_new_dynamic_tls_section(new_section(SectionType::TlsData, "old", 24, 1)?, 8)
+        .map_err(|_| "couldn't add old section")?;
+    if tls.replace_dynamic_tls_section(&old, new_section(SectionType::TlsData, "bigger", 32, 2)?, 8).is_ok() {
+        return Err("replacing a section with one of a different size succeeded");
+    }
+    let (new_offset, new) = tls.replace_dynamic_tls_section(&old, new_section(SectionType::TlsData, "new", 24, 2)?, 8)
+        .map_err(|_| "couldn't replace section")?;
+    if new_offset != offset || tls.offset_of(&new) != Some(offset as isize) {
+        return Err("replacement section isn't at the old section's offset");
+    }
+    if tls.offset_of(&old).is_some() {
+        return Err("replaced section still has an offset");
+    }
+    Ok(())
+}
+
+#[ktest]
+fn deterministic_layout_sorts_by_name() -> Result<(), &'static str> {
+    let mut tls = new_initializer();
+    tls.set_deterministic_layout(true)?;
+    let sections = vec![
+        (new_section(SectionType::TlsData, "c", 4, 0)?, 4),
+        (new_section(SectionType::TlsData, "a", 8, 0)?, 8),
+        (new_section(SectionType::TlsBss, "b", 2, 0)?, 2),
+    ];
+    let added = tls.add_new_dynamic_tls_sections(sections).map_err(|_| "couldn't add sections")?;
+    let [(c, _), (a, _), (b, _)] = added[..] else {
+        return Err("wrong number of sections were added");
+    };
+    if !(a < b && b < c) {
+        return Err("sections weren't placed in order of their names");
+    }
+    if b != a + 8 || c != b + 4 {
+        return Err("sections weren't placed contiguously");
+    }
+    if tls.set_deterministic_layout(false).is_ok() {
+        return Err("layout mode was changed after sections were added");
+    }
+    Ok(())
+}
+
+#[ktest]
+fn data_image_contains_section_contents() -> Result<(), &'static str> {
+    let mut tls = new_initializer();
+    let (data_offset, data) = tls.add_new_dynamic_tls_section(new_section(SectionType::TlsData, "data", 64, 0x5A)?, 8)
+        .map_err(|_| "couldn't add .tdata section")?;
+    let (bss_offset, bss) = tls.add_new_dynamic_tls_section(new_section(SectionType::TlsBss, "bss", 64, 0xFF)?, 8)
+        .map_err(|_| "couldn't add .tbss section")?;
+    let image = tls.try_get_data(TlsAllocHint::Any)?;
+    let read = |offset: usize, size: usize| {
+        // SAFETY: the range lies within the dynamic TLS sections of the image, which we own.
+        unsafe { core::slice::from_raw_parts((image.self_pointer() + offset) as *const u8, size) }
+    };
+    if read(data_offset, data.size).iter().any(|&b| b != 0x5A) {
+        return Err("TLS data image doesn't contain the .tdata section's contents");
+    }
+    if read(bss_offset, bss.size).iter().any(|&b| b != 0) {
+        return Err("TLS data image's .tbss section isn't zeroed");
+    }
+    Ok(())
+}
+
+#[ktest]
+fn reserved_region_is_isolated() -> Result<(), &'static str> {
+    let mut tls = new_initializer();
+    let region = tls.reserve_dynamic_tls_region(64, 16).map_err(|_| "couldn't reserve region")?;
+    let (outside, _) = tls.add_new_dynamic_tls_section(new_section(SectionType::TlsData, "outside", 8, 0)?, 8)
+        .map_err(|_| "couldn't add section outside the region")?;
+    if region.contains(&outside) {
+        return Err("section was placed into a reserved region");
+    }
+    if tls.can_add_dynamic_tls_section_at(region.start, 8, 8) {
+        return Err("reserved region's offsets were available to other sections");
+    }
+    let (inside, _) = tls.add_new_dynamic_tls_section_in_region(new_section(SectionType::TlsBss, "inside", 32, 0)?, &region, 16)
+        .map_err(|_| "couldn't add section into the region")?;
+    if !region.contains(&inside) || inside + 32 > region.end {
+        return Err("section wasn't placed within its region");
+    }
+    if tls.add_new_dynamic_tls_section_in_region(new_section(SectionType::TlsBss, "too_big", 64, 0)?, &region, 1).is_ok() {
+        return Err("section was placed beyond the end of its region");
+    }
+    tls.release_dynamic_tls_region(&region).map_err(|_| "couldn't release region")?;
+    if tls.release_dynamic_tls_region(&region).is_ok() {
+        return Err("region was released twice");
+    }
+    Ok(())
+}
+
+#[ktest]
+fn merged_section_avoids_reserved_region() -> Result<(), &'static str> {
+    let mut tls = new_initializer();
+    let region = tls.reserve_dynamic_tls_region(64, 16).map_err(|_| "couldn't reserve region")?;
+    let mut other = new_initializer();
+    let section = other.add_new_dynamic_tls_section_at(new_section(SectionType::TlsData, "other", 8, 0)?, region.start, 16)
+        .map_err(|_| "couldn't add section at the region's offset")?;
+    let remappings = tls.merge(&other)?;
+    let remapping = match remappings.as_slice() {
+        [remapping] if Arc::ptr_eq(&remapping.old_section, &section) => remapping,
+        _ => return Err("section in a reserved region wasn't re-homed"),
+    };
+    let new_range = range_of(&tls, &remapping.new_section)?;
+    if new_range.start < region.end && region.start < new_range.end {
+        return Err("merged section was placed into a reserved region");
+    }
+    Ok(())
+}
+
+#[ktest]
+fn static_sections_are_rebased() -> Result<(), &'static str> {
+    let mut tls = new_initializer();
+    let x = tls.add_existing_static_tls_section(new_section(SectionType::TlsData, "a::X::h1111", 8, 1)?, 0, 16)
+        .map_err(|_| "couldn't add static section X")?;
+    tls.add_existing_static_tls_section(new_section(SectionType::TlsData, "a::Y::h1111", 8, 2)?, 8, 16)
+        .map_err(|_| "couldn't add static section Y")?;
+    let (dyn_offset, _) = tls.add_new_dynamic_tls_section(new_section(SectionType::TlsData, "dynamic", 8, 3)?, 8)
+        .map_err(|_| "couldn't add dynamic section")?;
+    let mut image = tls.try_get_data(TlsAllocHint::Any)?;
+    image.write_at(x.virt_addr.value() as isize, &[0x11; 8])?;
+    image.write_at(dyn_offset as isize, &[0x33; 8])?;
+
+    let new_sections = vec![
+        (new_section(SectionType::TlsData, "a::Y::h2222", 8, 2)?, 0),
+        (new_section(SectionType::TlsData, "a::Z::h2222", 8, 4)?, 8),
+        (new_section(SectionType::TlsData, "a::X::h2222", 8, 1)?, 16),
+    ];
+    let remappings = tls.replace_static_tls_sections(new_sections, 24)?;
+    if remappings.len() != 2 {
+        return Err("wrong number of static TLS sections were remapped");
+    }
+    let new_x = remappings.iter().find(|r| Arc::ptr_eq(&r.old_section, &x)).ok_or("static section X wasn't remapped")?;
+    if new_x.new_offset as isize != -8 {
+        return Err("static section X wasn't moved to its new offset");
+    }
+
+    // SAFETY: the image isn't used by any task.
+    let rebased = unsafe { tls.rebase_image(&image, &remappings)? };
+    if rebased.read_at(-8, 8)? != [0x11; 8] {
+        return Err("static section X's value wasn't carried over");
+    }
+    if rebased.read_at(-16, 8)? != [0x04; 8] {
+        return Err("new static section Z doesn't have its initial value");
+    }
+    if rebased.read_at(dyn_offset as isize, 8)? != [0x33; 8] {
+        return Err("dynamic section's value wasn't carried over");
+    }
+    Ok(())
+}
+
+#[ktest]
+fn redzones_catch_overflows() -> Result<(), &'static str> {
+    let mut tls = new_initializer();
+    tls.set_redzones(true)?;
+    let a = new_section(SectionType::TlsData, "a::A::h1111", 8, 0xAA)?;
+    let b = new_section(SectionType::TlsData, "a::B::h1111", 8, 0xBB)?;
+    let (a_offset, _) = tls.add_new_dynamic_tls_section(a, 8).map_err(|_| "couldn't add section A")?;
+    let (b_offset, _) = tls.add_new_dynamic_tls_section(b, 8).map_err(|_| "couldn't add section B")?;
+    let (first, second) = (a_offset.min(b_offset), a_offset.max(b_offset));
+    if second < first + 8 + crate::TLS_REDZONE_SIZE {
+        return Err("dynamic TLS sections aren't separated by a redzone");
+    }
+
+    let mut image = tls.get_data(TlsAllocHint::Any);
+    image.verify_redzones().map_err(|_| "fresh TLS data image has a corrupted redzone")?;
+    if image.write_at(first as isize, &[0; 9]).is_ok() {
+        return Err("write_at() allowed a write into a redzone");
+    }
+    image.write_at(first as isize, &[0; 8])?;
+
+    // Simulate a direct overflow of the first section by one byte.
+    let self_ptr_index = image.ptr - image.base_address();
+    image._data.as_mut().ok_or("TLS data image was empty")?.as_mut_slice()[self_ptr_index + first + 8] = 0;
+    let violation = image.verify_redzones().err().ok_or("overflow wasn't detected")?;
+    if violation.offset != (first + 8) as isize || violation.distance != 0 {
+        return Err("overflow was detected at the wrong offset");
+    }
+    let culprit = if first == a_offset { "a::A::h1111" } else { "a::B::h1111" };
+    if violation.preceding_section.as_deref() != Some(culprit) {
+        return Err("overflow was attributed to the wrong section");
+    }
+    Ok(())
+}
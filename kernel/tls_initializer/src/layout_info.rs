@@ -0,0 +1,79 @@
+//! Introspection of the current TLS layout, e.g., for debugging tools like `tlsinfo`.
+//!
+//! A [`TlsLayoutInfo`] describes every TLS section in a [`TlsInitializer`] and where it resides
+//! relative to the TLS self pointer, along with the layout's generation and total image size.
+
+use alloc::{string::String, vec::Vec};
+use crate_metadata::{SectionType, StrongSectionRef};
+use super::{TcbLayout, TlsInitializer};
+
+/// A description of the TLS layout of a [`TlsInitializer`] at the time it was obtained.
+#[derive(Debug, Clone)]
+pub struct TlsLayoutInfo {
+    /// The generation of the TLS layout; see [`TlsDataImage::layout_generation()`](super::TlsDataImage::layout_generation).
+    pub generation: u64,
+    /// The layout of the TCB header that begins at the TLS self pointer.
+    pub tcb_layout: TcbLayout,
+    /// Whether dynamic TLS sections are placed deterministically.
+    pub deterministic: bool,
+    /// The size in bytes of each TLS data image generated from this layout.
+    pub image_size: usize,
+    /// The offset of the TLS self pointer from the start of each TLS data image,
+    /// which is the total size of all static TLS sections.
+    pub self_ptr_offset: usize,
+    /// All TLS sections, in order of their offsets.
+    pub sections: Vec<TlsSectionDescription>,
+}
+
+/// A single TLS section within a [`TlsLayoutInfo`].
+#[derive(Debug, Clone)]
+pub struct TlsSectionDescription {
+    /// The TLS section itself.
+    pub section: StrongSectionRef,
+    /// The offset of this section from the TLS self pointer,
+    /// which is negative for static TLS sections.
+    pub offset: isize,
+}
+
+impl TlsSectionDescription {
+    /// Returns whether this section is a static TLS section from the base kernel image,
+    /// rather than a dynamic TLS section from a dynamically-loaded crate.
+    pub fn is_static(&self) -> bool {
+        self.offset < 0
+    }
+
+    /// Returns whether this section is a TLS BSS (`.tbss`) section, which is always zero-initialized.
+    pub fn is_bss(&self) -> bool {
+        self.section.typ == SectionType::TlsBss
+    }
+
+    /// Returns the name of the crate that contains this section, if it still exists.
+    ///
+    /// This locks that crate, so it must not be invoked while the `TlsInitializer` is locked,
+    /// as loading a crate locks them in the opposite order.
+    pub fn crate_name(&self) -> Option<String> {
+        self.section.parent_crate.upgrade()
+            .map(|parent| String::from(parent.lock_as_ref().crate_name.as_str()))
+    }
+}
+
+impl TlsInitializer {
+    /// Returns a description of the current TLS layout, including every TLS section.
+    pub fn layout_info(&self) -> TlsLayoutInfo {
+        let self_ptr_offset = self.area.pointer_offset();
+        let sections = self.area.image_ranges()
+            .map(|(image_range, sec)| TlsSectionDescription {
+                section: sec.0.clone(),
+                offset: image_range.start as isize - self_ptr_offset as isize,
+            })
+            .collect();
+        TlsLayoutInfo {
+            generation: self.generation,
+            tcb_layout: self.tcb_layout,
+            deterministic: self.deterministic_layout,
+            image_size: self.area.image_size(),
+            self_ptr_offset,
+            sections,
+        }
+    }
+}
@@ -5,75 +5,722 @@
 //!    in order to correctly generate new TLS data images.
 //! 2. [`TlsDataImage`]: a generated TLS data image that can be used as the TLS area
 //!    for a single task.
+//!
+//! Newly-generated TLS data images are published to a [`TlsTemplateCell`],
+//! from which new tasks can obtain a copy without locking the [`TlsInitializer`].
+//!
+//! By default, the TLS self pointer is the only thing at the address held in the TLS register;
+//! a libc-compatible Thread Control Block (TCB) header can be requested via [`TcbLayout`].
+//!
+//! Every TLS data image also reserves fixed slots for the task's `errno` value,
+//! its unwinding context, its panic message buffer, its heap allocation cache, its RNG state,
+//! its profiling counters, and its task-local storage root; see [`errno()`], [`unwinding_context()`],
+//! [`with_panic_buffer()`], [`HeapCacheHooks`], [`rng_state_location()`], [`tls_counters_location()`],
+//! and [`task_local_root()`].
+//!
+//! The contents of a stopped task's [`TlsDataImage`] can be saved into a [`TlsSnapshot`]
+//! and later restored against the current set of TLS sections, which may have changed,
+//! via [`TlsInitializer::restore_snapshot()`].
+//!
+//! The current TLS layout, i.e., every TLS section and its offset, can be inspected
+//! via [`TlsInitializer::layout_info()`].
+//!
+//! Interrupt handlers can run atop a dedicated per-CPU TLS area rather than
+//! the interrupted task's TLS area; see [`enter_interrupt_tls()`].
+//!
+//! Fault handlers that cannot trust the TLS register can identify the current CPU
+//! and validate or repair the TLS register via [`hardened_cpu_id()`] and [`repair_tls_register()`].
+//!
+//! On multi-socket machines, the backing memory of a [`TlsDataImage`] can be placed
+//! on a specific memory node by passing a [`TlsAllocHint`] to [`TlsInitializer::get_data()`]
+//! and registering a [`NodeAwareAllocator`] via [`set_node_aware_allocator()`].
+//!
+//! A memory-pressure subsystem can reclaim memory when a TLS data image cannot be allocated
+//! and track the memory occupied by TLS data images; see [`MemoryPressureHooks`].
+//!
+//! Overflows of dynamic TLS sections can be caught by separating them with poisoned redzones;
+//! see [`TlsInitializer::set_redzones()`] and [`TlsDataImage::verify_redzones()`].
 
 #![no_std]
 #![feature(int_roundings)]
 
-extern crate alloc;
+#[macro_use] extern crate alloc;
+
+mod interrupt_tls;
+#[cfg(ktest)]
+mod ktests;
+mod layout_info;
+mod memory_pressure;
+mod per_cpu;
+mod redzone;
+mod snapshot;
+pub use interrupt_tls::*;
+pub use layout_info::*;
+pub use memory_pressure::*;
+pub use per_cpu::*;
+pub use redzone::*;
+pub use snapshot::*;
+#[cfg(target_arch = "x86_64")]
+pub mod gs;
 
-use alloc::{sync::Arc, vec::Vec, boxed::Box};
-use core::{mem::size_of, cmp::max, ops::Deref};
+use alloc::{alloc::Layout, sync::Arc, vec::Vec, boxed::Box};
+use core::{
+    fmt,
+    mem::size_of,
+    cmp::{max, min},
+    ops::{Deref, Range},
+    ptr::NonNull,
+    sync::atomic::{AtomicBool, AtomicPtr, AtomicUsize, Ordering},
+};
 use crate_metadata::{LoadedSection, SectionType, StrongSectionRef};
+use fault_injection::FaultPoint;
 use memory_structs::VirtualAddress;
-use rangemap::RangeMap;
+use metrics::Counter;
+use spin::{Mutex, Once};
+use tp_area::{AreaBuilder, AreaVariant};
+use tracepoint::Tracepoint;
 
 #[cfg(target_arch = "x86_64")]
 use x86_64::{registers::model_specific::FsBase, VirtAddr};
 
 #[cfg(target_arch = "aarch64")]
 use {
-    cortex_a::registers::TPIDR_EL1,
-    tock_registers::interfaces::Writeable,
+    cortex_a::registers::TPIDR_EL0,
+    tock_registers::interfaces::{Readable, Writeable},
 };
 
 /// A Thread-Local Storage (TLS) area data "image" that is used
 /// to initialize a new `Task`'s TLS area.
-#[derive(Debug, Clone)]
+///
+/// Cloning a `TlsInitializer` yields one that publishes to its own separate [`TlsTemplateCell`],
+/// such that publishing from the clone never replaces the templates that tasks obtain from the original.
+#[derive(Debug)]
 pub struct TlsInitializer {
     /// The cached data image (with blank space for the TLS self pointer).
     /// This is used to avoid unnecessarily re-generating the TLS data image
     /// every time a new task is spawned if no TLS data sections have been added.
-    data_cache: Vec<u8>,
+    /// It is `None` until it is first generated.
+    data_cache: Option<Arc<TlsTemplate>>,
     /// The status of the above `data_cache`: whether it is ready to be used
     /// immediately or needs to be regenerated.
     cache_status: CacheStatus,
-    /// The set of TLS data sections that are defined at link time
+    /// The cell to which the above `data_cache` is published once it has been generated,
+    /// which allows tasks to obtain a copy of it without locking this `TlsInitializer`.
+    template_cell: TemplateCellRef,
+    /// The placement of all TLS sections, in which the area pointer is the TLS self pointer.
+    ///
+    /// The area's fixed sections are the TLS sections that are defined at link time
     /// and come from the statically-linked base kernel image (the nano_core).
     /// According to the x86_64 TLS ABI, these exist at **negative** offsets
     /// from the TLS self pointer, i.e., they exist **before** the TLS self pointer in memory.
     /// Thus, their actual location in memory depends on the size of **all** static TLS data sections.
-    /// For example, the last section in this set (with the highest offset) will be placed
-    /// right before the TLS self pointer in memory. 
-    static_section_offsets:  RangeMap<usize, StrongSectionRefWrapper>,
-    /// The ending offset (an exclusive range end bound) of the last TLS section
-    /// in the above set of `static_section_offsets`.
-    /// This is the offset where the TLS self pointer exists.
-    end_of_static_sections: usize,
-    /// The set of TLS data sections that come from dynamically-loaded crate object files.
+    ///
+    /// The area's dynamic sections are the TLS sections that come from dynamically-loaded crate object files.
     /// We can control and arbitrarily assign their offsets, and thus,
-    /// we place all of these sections **after** the TLS self pointer in memory.
-    /// For example, the first section in this set (with an offset of `0`) will be place
-    /// right after the TLS self pointer in memory.
-    dynamic_section_offsets: RangeMap<usize, StrongSectionRefWrapper>,
-    /// The ending offset (an exclusive range end bound) of the last TLS section
-    /// in the above set of `dynamic_section_offsets`.
-    end_of_dynamic_sections: usize,
+    /// we place all of these sections **after** the TLS self pointer and the reserved area in memory.
+    area: AreaBuilder<StrongSectionRefWrapper>,
+    /// The layout of the Thread Control Block (TCB) header that begins at the TLS self pointer,
+    /// which determines how much space is reserved before the first dynamic TLS section.
+    tcb_layout: TcbLayout,
+    /// The generation of the TLS layout, i.e., the set of TLS sections and their offsets,
+    /// which is incremented every time the data image is fully re-generated.
+    generation: u64,
+    /// Whether dynamic TLS sections are placed deterministically;
+    /// see [`TlsInitializer::set_deterministic_layout()`].
+    deterministic_layout: bool,
+    /// The ranges of offsets (from the TLS self pointer) that are reserved for the dynamic TLS sections
+    /// of one owner, e.g., a sandboxed namespace, and in which no other dynamic TLS sections are placed;
+    /// see [`TlsInitializer::reserve_dynamic_tls_region()`].
+    regions: Vec<Range<usize>>,
+    /// Whether dynamic TLS sections are separated by poisoned redzones;
+    /// see [`TlsInitializer::set_redzones()`].
+    redzones: bool,
 } 
 
+impl Clone for TlsInitializer {
+    fn clone(&self) -> TlsInitializer {
+        TlsInitializer {
+            data_cache: self.data_cache.clone(),
+            cache_status: self.cache_status.clone(),
+            template_cell: TemplateCellRef::Owned(Arc::new(TlsTemplateCell::empty())),
+            area: self.area.clone(),
+            tcb_layout: self.tcb_layout,
+            generation: self.generation,
+            deterministic_layout: self.deterministic_layout,
+            regions: self.regions.clone(),
+            redzones: self.redzones,
+        }
+    }
+}
+
+/// The [`TlsTemplateCell`] to which a [`TlsInitializer`] publishes its data images.
+#[derive(Debug)]
+enum TemplateCellRef {
+    /// The cell given to [`TlsInitializer::new()`].
+    Static(&'static TlsTemplateCell),
+    /// A separate cell owned by a clone of a [`TlsInitializer`].
+    Owned(Arc<TlsTemplateCell>),
+}
+impl Deref for TemplateCellRef {
+    type Target = TlsTemplateCell;
+    fn deref(&self) -> &TlsTemplateCell {
+        match self {
+            Self::Static(cell) => cell,
+            Self::Owned(cell) => cell,
+        }
+    }
+}
+
 const POINTER_SIZE: usize = size_of::<usize>();
 
+/// The offset of the current task's `errno` value from the TLS self pointer.
+///
+/// This slot is placed right after the largest supported [`TcbLayout`] header,
+/// so it resides at the same thread-pointer-relative offset regardless of which layout is used.
+/// Every TLS data image reserves this slot, so it can be accessed without any relocations;
+/// see [`errno()`], [`set_errno()`], and [`errno_location()`].
+pub const ERRNO_OFFSET: usize = TcbLayout::Glibc.size();
+
+/// The offset of the current task's unwinding context pointer from the TLS self pointer.
+///
+/// Like the `errno` slot, this slot is reserved in every TLS data image,
+/// such that each task's unwinder state is independent of all other tasks.
+/// See [`unwinding_context()`] and [`set_unwinding_context()`].
+pub const UNWINDING_CONTEXT_OFFSET: usize = ERRNO_OFFSET + POINTER_SIZE;
+
+/// The offset of the current task's [`PanicBuffer`] from the TLS self pointer.
+pub const PANIC_BUFFER_OFFSET: usize = UNWINDING_CONTEXT_OFFSET + POINTER_SIZE;
+
+/// The offset of the current task's heap allocation cache from the TLS self pointer.
+///
+/// The contents of this region are defined by the heap, which registers [`HeapCacheHooks`]
+/// to initialize and tear down each task's cache.
+pub const HEAP_CACHE_OFFSET: usize = PANIC_BUFFER_OFFSET + size_of::<PanicBuffer>();
+
+/// The size in bytes of the per-task heap allocation cache region in the TLS area.
+pub const HEAP_CACHE_SIZE: usize = 512;
+
+/// The offset of the current task's random number generator state from the TLS self pointer.
+///
+/// The contents of this region are defined by the `random` crate.
+pub const RNG_STATE_OFFSET: usize = HEAP_CACHE_OFFSET + HEAP_CACHE_SIZE;
+
+/// The size in bytes of the per-task random number generator state region in the TLS area.
+pub const RNG_STATE_SIZE: usize = 5 * POINTER_SIZE;
+
+/// The offset of the current task's block of profiling counters from the TLS self pointer.
+///
+/// The meaning of each counter is defined by the `tls_counters` crate.
+pub const TLS_COUNTERS_OFFSET: usize = RNG_STATE_OFFSET + RNG_STATE_SIZE;
+
+/// The number of `u64` profiling counters reserved in each TLS data image.
+pub const NUM_TLS_COUNTERS: usize = 8;
+
+/// The offset of the current task's task-local storage root pointer from the TLS self pointer.
+///
+/// The meaning of the root pointer is defined by the async task-local storage in `dreadnought`.
+/// See [`task_local_root()`] and [`set_task_local_root()`].
+pub const TASK_LOCAL_ROOT_OFFSET: usize = TLS_COUNTERS_OFFSET + NUM_TLS_COUNTERS * size_of::<u64>();
+
+/// The size of the area after the TLS self pointer that is reserved for
+/// the TCB header and the fixed per-task slots (`errno`, the unwinding context,
+/// the panic buffer, the heap cache, the RNG state, the profiling counters,
+/// and the task-local storage root).
+/// Dynamic TLS sections are only placed after this area.
+const RESERVED_AREA_SIZE: usize = TASK_LOCAL_ROOT_OFFSET + POINTER_SIZE;
+
+/// The ID of the single TLS "module" that Theseus exposes to code using
+/// the general-dynamic TLS model (e.g., via `__tls_get_addr()` or TLS descriptors).
+///
+/// Unlike a conventional dynamic linker, Theseus places all TLS sections into a single TLS area,
+/// so every TLS section belongs to this one module.
+pub const THESEUS_TLS_MODULE_ID: usize = 1;
+
+/// The argument to `__tls_get_addr()`, as defined by the ELF TLS ABI.
+///
+/// In Theseus, the `offset` is relative to the TLS self pointer,
+/// just like the offsets used by the local-exec TLS model.
+/// Thus, negative offsets (for static TLS sections) are represented
+/// by their two's complement bit pattern.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct TlsIndex {
+    /// The TLS module ID, which is always [`THESEUS_TLS_MODULE_ID`].
+    pub module: usize,
+    /// The offset of the TLS variable from the TLS self pointer.
+    pub offset: usize,
+}
+
+/// A hint that describes where the backing memory of a new [`TlsDataImage`] should reside.
+///
+/// Hints are best-effort: if no [`NodeAwareAllocator`] has been registered,
+/// or if it cannot satisfy the request, the image is allocated from the regular heap.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TlsAllocHint {
+    /// No preference; the image is allocated from the regular heap.
+    #[default]
+    Any,
+    /// Allocate the image on the memory node local to the CPU with the given ID.
+    Cpu(u8),
+    /// Allocate the image on the given memory node.
+    Node(usize),
+}
+
+/// An allocator that can place memory on a specific memory (NUMA) node.
+///
+/// This is implemented by a node-aware frame or heap allocator and registered
+/// via [`set_node_aware_allocator()`], which allows this crate to honor [`TlsAllocHint`]s
+/// without depending on a specific allocator implementation.
+pub trait NodeAwareAllocator: Send + Sync {
+    /// Returns the memory node that is local to the CPU with the given ID, if known.
+    fn node_of_cpu(&self, cpu: u8) -> Option<usize>;
+
+    /// Allocates memory that satisfies the given `layout` on the given memory `node`.
+    fn allocate_on_node(&self, layout: Layout, node: usize) -> Option<NonNull<u8>>;
+
+    /// Deallocates memory previously returned from [`NodeAwareAllocator::allocate_on_node()`].
+    ///
+    /// # Safety
+    /// The given `ptr` must have been allocated by this allocator
+    /// with the same `layout` on the same `node`.
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout, node: usize);
+}
+
+/// The number of times any `TlsInitializer` re-generated its TLS data image from scratch.
+static TLS_IMAGE_REGENERATIONS: Counter = Counter::new(
+    "tls_image_regenerations_total",
+    "Number of times a TLS data image was re-generated from scratch",
+);
+
+/// Registers this crate's metrics, such that they are accumulated and exported.
+pub fn register_metrics() -> Result<(), &'static str> {
+    TLS_IMAGE_REGENERATIONS.register()
+}
+
+/// The system-wide node-aware allocator used for placing TLS data images.
+static NODE_AWARE_ALLOCATOR: Once<&'static dyn NodeAwareAllocator> = Once::new();
+
+/// Registers the node-aware allocator that will back all future TLS data images
+/// created with a [`TlsAllocHint`] other than [`TlsAllocHint::Any`].
+///
+/// Returns an error if a node-aware allocator was already registered.
+pub fn set_node_aware_allocator(allocator: &'static dyn NodeAwareAllocator) -> Result<(), &'static str> {
+    let mut newly_set = false;
+    NODE_AWARE_ALLOCATOR.call_once(|| { newly_set = true; allocator });
+    if newly_set {
+        Ok(())
+    } else {
+        Err("a node-aware allocator for TLS data images was already registered")
+    }
+}
+
+/// Returns the memory node that is local to the CPU with the given ID,
+/// as reported by the registered [`NodeAwareAllocator`].
+///
+/// Returns `None` if no node-aware allocator has been registered or it doesn't know the CPU's node.
+pub fn node_of_cpu(cpu: u8) -> Option<usize> {
+    NODE_AWARE_ALLOCATOR.get()?.node_of_cpu(cpu)
+}
+
+/// The layout of the Thread Control Block (TCB) header that begins at the TLS self pointer,
+/// i.e., the address held in the TLS register (e.g., `FS_BASE` on x86_64).
+///
+/// By default, Theseus only places the TLS self pointer there.
+/// Ported C libraries, however, expect the thread pointer to reference their own TCB structure,
+/// e.g., to implement `pthread_self()` or to load the stack protector canary from `%fs:0x28`.
+/// The other layouts reserve and initialize a header compatible with that libc's x86_64 ABI.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TcbLayout {
+    /// Only the TLS self pointer, which is Theseus's default.
+    #[default]
+    SelfPointerOnly,
+    /// glibc's `tcbhead_t`: `tcb`, `dtv`, `self`, `multiple_threads`/`gscope_flag`,
+    /// `sysinfo`, `stack_guard`, and `pointer_guard`.
+    Glibc,
+    /// The beginning of musl's `struct pthread`: `self`, `dtv`, `prev`, `next`,
+    /// `sysinfo`, and `canary`.
+    Musl,
+}
+impl TcbLayout {
+    /// Returns the size in bytes of this TCB header.
+    pub const fn size(self) -> usize {
+        match self {
+            Self::SelfPointerOnly => POINTER_SIZE,
+            Self::Glibc => 7 * POINTER_SIZE,
+            Self::Musl  => 6 * POINTER_SIZE,
+        }
+    }
+
+    /// Returns the offsets of the fields in this TCB header that point to the header itself.
+    const fn self_pointer_offsets(self) -> &'static [usize] {
+        match self {
+            Self::SelfPointerOnly | Self::Musl => &[0],
+            Self::Glibc => &[0, 2 * POINTER_SIZE],
+        }
+    }
+
+    /// Returns the offset of the stack protector canary in this TCB header, if any.
+    const fn stack_guard_offset(self) -> Option<usize> {
+        match self {
+            Self::SelfPointerOnly => None,
+            Self::Glibc | Self::Musl => Some(5 * POINTER_SIZE),
+        }
+    }
+
+    /// Returns the offset of the pointer mangling guard in this TCB header, if any.
+    const fn pointer_guard_offset(self) -> Option<usize> {
+        match self {
+            Self::SelfPointerOnly | Self::Musl => None,
+            Self::Glibc => Some(6 * POINTER_SIZE),
+        }
+    }
+}
+
+/// The function used to generate the stack guard and pointer guard values
+/// for TCB headers that contain them.
+static TCB_GUARD_SOURCE: Once<fn() -> usize> = Once::new();
+
+/// Registers the function used to generate the stack guard (canary) and pointer guard values
+/// that are written into each new TLS data image's TCB header (see [`TcbLayout`]).
+///
+/// Until this is registered, those guard values are zero.
+///
+/// Returns an error if a guard source was already registered.
+pub fn set_tcb_guard_source(source: fn() -> usize) -> Result<(), &'static str> {
+    let mut newly_set = false;
+    TCB_GUARD_SOURCE.call_once(|| { newly_set = true; source });
+    if newly_set {
+        Ok(())
+    } else {
+        Err("a TCB guard source was already registered")
+    }
+}
+
+/// Returns a pointer to the current task's `errno` value.
+///
+/// The `errno` slot resides at a fixed offset ([`ERRNO_OFFSET`]) from the TLS self pointer,
+/// so each task has its own `errno` value.
+/// This is only valid once the current CPU's TLS register points to a [`TlsDataImage`].
+#[inline(always)]
+pub fn errno_location() -> *mut i32 {
+    (current_tls_self_pointer() + ERRNO_OFFSET) as *mut i32
+}
+
+/// Returns the current task's `errno` value.
+pub fn errno() -> i32 {
+    unsafe { errno_location().read() }
+}
+
+/// Sets the current task's `errno` value.
+pub fn set_errno(value: i32) {
+    unsafe { errno_location().write(value) }
+}
+
+/// Returns the address of the unwinding context of the current task,
+/// or `0` if the current task is not being unwound.
+///
+/// The unwinding context is stored at a fixed offset ([`UNWINDING_CONTEXT_OFFSET`])
+/// from the TLS self pointer, so it can be accessed without locking.
+pub fn unwinding_context() -> usize {
+    unsafe { ((current_tls_self_pointer() + UNWINDING_CONTEXT_OFFSET) as *const usize).read() }
+}
+
+/// Sets the address of the unwinding context of the current task.
+///
+/// Setting it to `0` indicates that the current task is not being unwound.
+pub fn set_unwinding_context(context: usize) {
+    unsafe { ((current_tls_self_pointer() + UNWINDING_CONTEXT_OFFSET) as *mut usize).write(context) }
+}
+
+/// The number of bytes of message text that a [`PanicBuffer`] can hold.
+pub const PANIC_BUFFER_CAPACITY: usize = 240;
+
+/// A fixed-size, per-task scratch buffer into which panic messages can be formatted
+/// without allocating from the heap, e.g., when panicking inside the heap allocator.
+///
+/// Each task's `PanicBuffer` resides at a fixed offset ([`PANIC_BUFFER_OFFSET`])
+/// in its TLS area; use [`with_panic_buffer()`] to access it.
+///
+/// Text that doesn't fit into the buffer is truncated at a `char` boundary,
+/// in which case the [`fmt::Write`] implementation returns an error to stop formatting early.
+#[repr(C)]
+pub struct PanicBuffer {
+    /// Whether this buffer is currently in use, which prevents nested panics
+    /// from overwriting a message that is still being formatted.
+    in_use: usize,
+    /// The number of valid bytes in `data`.
+    len: usize,
+    data: [u8; PANIC_BUFFER_CAPACITY],
+}
+impl PanicBuffer {
+    /// Returns the message text that has been written into this buffer.
+    pub fn as_str(&self) -> &str {
+        // SAFE: only complete UTF-8 `str`s are ever written into `data`.
+        unsafe { core::str::from_utf8_unchecked(&self.data[..self.len]) }
+    }
+
+    /// Clears the message text in this buffer.
+    pub fn clear(&mut self) {
+        self.len = 0;
+    }
+}
+impl fmt::Write for PanicBuffer {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let remaining = PANIC_BUFFER_CAPACITY - self.len;
+        let mut to_copy = min(s.len(), remaining);
+        while !s.is_char_boundary(to_copy) {
+            to_copy -= 1;
+        }
+        self.data[self.len .. self.len + to_copy].copy_from_slice(&s.as_bytes()[..to_copy]);
+        self.len += to_copy;
+        if to_copy == s.len() { Ok(()) } else { Err(fmt::Error) }
+    }
+}
+
+/// Invokes the given closure `f` with the current task's [`PanicBuffer`], which is cleared beforehand.
+///
+/// This never allocates, so it can be safely used by the panic handler.
+///
+/// Returns `None` if the current task's panic buffer is already in use,
+/// e.g., if a panic occurred while formatting a previous panic message.
+pub fn with_panic_buffer<R, F: FnOnce(&mut PanicBuffer) -> R>(f: F) -> Option<R> {
+    // SAFE: every TLS data image reserves a zero-initialized `PanicBuffer` at this offset,
+    // and the `in_use` flag guarantees that only one mutable reference to it exists at a time.
+    let buffer = unsafe { &mut *((current_tls_self_pointer() + PANIC_BUFFER_OFFSET) as *mut PanicBuffer) };
+    if buffer.in_use != 0 {
+        return None;
+    }
+    buffer.in_use = 1;
+    buffer.clear();
+    let ret = f(buffer);
+    buffer.in_use = 0;
+    Some(ret)
+}
+
+/// Callbacks that manage the per-task heap allocation cache in the TLS area.
+///
+/// Both callbacks are given a pointer to the current task's cache region,
+/// which is [`HEAP_CACHE_SIZE`] bytes long, pointer-aligned, and zero-initialized.
+#[derive(Clone, Copy)]
+pub struct HeapCacheHooks {
+    /// Invoked when a task starts running, before it executes its entry function.
+    pub init: fn(*mut u8),
+    /// Invoked when a task exits, in order to return all cached blocks to the heap.
+    pub teardown: fn(*mut u8),
+}
+
+/// The callbacks registered by the heap to manage each task's heap allocation cache.
+static HEAP_CACHE_HOOKS: Once<HeapCacheHooks> = Once::new();
+
+/// Registers the callbacks that initialize and tear down each task's heap allocation cache.
+///
+/// Returns an error if hooks were already registered.
+pub fn set_heap_cache_hooks(hooks: HeapCacheHooks) -> Result<(), &'static str> {
+    let mut newly_set = false;
+    HEAP_CACHE_HOOKS.call_once(|| { newly_set = true; hooks });
+    if newly_set {
+        Ok(())
+    } else {
+        Err("heap cache hooks were already registered")
+    }
+}
+
+/// Returns the current value of the TLS register, i.e., the current task's TLS self pointer,
+/// or `None` if the TLS register has not yet been set.
+///
+/// Because the heap is used before the current CPU's TLS register has been set,
+/// it reads the TLS register once per allocation via this function, rather than via
+/// [`current_tls_self_pointer()`], and passes the result to [`heap_cache_location_at()`]
+/// and [`tls_counters_location_at()`].
+#[inline(always)]
+pub fn try_current_tls_self_pointer() -> Option<usize> {
+    let tp = read_tls_register();
+    (tp != 0).then_some(tp)
+}
+
+/// Returns a pointer to the heap allocation cache region of the TLS area
+/// with the given TLS self pointer.
+#[inline(always)]
+pub fn heap_cache_location_at(tls_self_pointer: usize) -> *mut u8 {
+    (tls_self_pointer + HEAP_CACHE_OFFSET) as *mut u8
+}
+
+/// Invokes the registered heap cache `init` hook for the current task, if any.
+///
+/// This should be invoked once when a new task first starts running.
+pub fn init_current_heap_cache() {
+    if let (Some(hooks), Some(region)) = (HEAP_CACHE_HOOKS.get(), try_current_tls_self_pointer().map(heap_cache_location_at)) {
+        (hooks.init)(region);
+    }
+}
+
+/// Invokes the registered heap cache `teardown` hook for the current task, if any.
+///
+/// This should be invoked once when the current task is exiting,
+/// after which its heap allocations will no longer be cached.
+pub fn teardown_current_heap_cache() {
+    if let (Some(hooks), Some(region)) = (HEAP_CACHE_HOOKS.get(), try_current_tls_self_pointer().map(heap_cache_location_at)) {
+        (hooks.teardown)(region);
+    }
+}
+
+/// Returns a pointer to the current task's random number generator state region,
+/// which is [`RNG_STATE_SIZE`] bytes long, pointer-aligned, and zero-initialized.
+///
+/// This is only valid once the current CPU's TLS register points to a [`TlsDataImage`].
+#[inline(always)]
+pub fn rng_state_location() -> *mut u8 {
+    (current_tls_self_pointer() + RNG_STATE_OFFSET) as *mut u8
+}
+
+/// Returns a pointer to the current task's block of [`NUM_TLS_COUNTERS`] profiling counters.
+///
+/// This is only valid once the current CPU's TLS register points to a [`TlsDataImage`].
+#[inline(always)]
+pub fn tls_counters_location() -> *mut u64 {
+    (current_tls_self_pointer() + TLS_COUNTERS_OFFSET) as *mut u64
+}
+
+/// Returns a pointer to the block of [`NUM_TLS_COUNTERS`] profiling counters
+/// of the TLS area with the given TLS self pointer.
+///
+/// Like [`heap_cache_location_at()`], this is intended for use by the heap;
+/// see [`try_current_tls_self_pointer()`].
+#[inline(always)]
+pub fn tls_counters_location_at(tls_self_pointer: usize) -> *mut u64 {
+    (tls_self_pointer + TLS_COUNTERS_OFFSET) as *mut u64
+}
+
+/// Returns the current task's task-local storage root pointer,
+/// or `0` if no task-local values are currently set.
+pub fn task_local_root() -> usize {
+    unsafe { ((current_tls_self_pointer() + TASK_LOCAL_ROOT_OFFSET) as *const usize).read() }
+}
+
+/// Sets the current task's task-local storage root pointer.
+pub fn set_task_local_root(root: usize) {
+    unsafe { ((current_tls_self_pointer() + TASK_LOCAL_ROOT_OFFSET) as *mut usize).write(root) }
+}
+
+/// Returns the value of the TLS self pointer of the current task,
+/// which is also the value of the current CPU's thread pointer.
+///
+/// This is only valid once the current CPU's TLS register points to a [`TlsDataImage`].
+#[inline(always)]
+pub fn current_tls_self_pointer() -> usize {
+    let tp: usize;
+    // On x86_64, the TLS self pointer is the first item in the TLS area,
+    // so we read it via an `FS`-relative load instead of reading the `FS_BASE` MSR.
+    #[cfg(target_arch = "x86_64")]
+    unsafe { core::arch::asm!("mov {}, fs:0", out(reg) tp, options(nostack, preserves_flags, readonly)); }
+    #[cfg(target_arch = "aarch64")]
+    unsafe { core::arch::asm!("mrs {}, tpidr_el0", out(reg) tp, options(nomem, nostack, preserves_flags)); }
+    tp
+}
+
+impl TlsAllocHint {
+    /// Resolves this hint into a specific memory node, if possible.
+    fn node(self, allocator: &dyn NodeAwareAllocator) -> Option<usize> {
+        match self {
+            Self::Any => None,
+            Self::Cpu(cpu) => allocator.node_of_cpu(cpu),
+            Self::Node(node) => Some(node),
+        }
+    }
+}
+
 impl TlsInitializer {
-    /// Creates an empty TLS initializer with no TLS data sections.
-    pub const fn empty() -> TlsInitializer {
+    /// Creates an empty TLS initializer with no TLS data sections
+    /// that publishes its generated data images to the given `template_cell`.
+    pub const fn new(template_cell: &'static TlsTemplateCell) -> TlsInitializer {
         TlsInitializer {
             // The data image will be generated lazily on the next request to use it.
-            data_cache: Vec::new(),
+            data_cache: None,
             cache_status: CacheStatus::Invalidated,
-            static_section_offsets: RangeMap::new(),
-            end_of_static_sections: 0,
-            dynamic_section_offsets: RangeMap::new(),
-            end_of_dynamic_sections: 0,
+            template_cell: TemplateCellRef::Static(template_cell),
+            area: AreaBuilder::new(AreaVariant::FixedBelowPointer, RESERVED_AREA_SIZE),
+            tcb_layout: TcbLayout::SelfPointerOnly,
+            generation: 0,
+            deterministic_layout: false,
+            regions: Vec::new(),
+            redzones: false,
+        }
+    }
+
+    /// Sets the layout of the TCB header that begins at the TLS self pointer
+    /// in all TLS data images generated from now on.
+    ///
+    /// This must be set before any dynamic TLS sections have been added,
+    /// because those sections are placed directly after the TCB header.
+    pub fn set_tcb_layout(&mut self, tcb_layout: TcbLayout) -> Result<(), &'static str> {
+        if tcb_layout == self.tcb_layout {
+            return Ok(());
+        }
+        if self.area.end_of_dynamic_sections() != 0 {
+            return Err("cannot change the TCB layout after dynamic TLS sections have been added");
+        }
+        self.tcb_layout = tcb_layout;
+        self.invalidate();
+        Ok(())
+    }
+
+    /// Returns the layout of the TCB header that begins at the TLS self pointer.
+    pub fn tcb_layout(&self) -> TcbLayout {
+        self.tcb_layout
+    }
+
+    /// Enables or disables the deterministic placement of dynamic TLS sections,
+    /// e.g., for verifying reproducible builds or for record/replay debugging.
+    ///
+    /// In deterministic mode, the TLS sections of each crate that are added together via
+    /// [`add_new_dynamic_tls_sections()`](Self::add_new_dynamic_tls_sections)
+    /// are placed into a single contiguous reservation, sorted by section name,
+    /// rather than into the first gap that fits each section in the order they were loaded.
+    /// Thus, the resulting layout depends only on the contents and order of the loaded crates,
+    /// which can be compared across boots via [`layout_hash()`](Self::layout_hash).
+    ///
+    /// This must be set before any dynamic TLS sections have been added.
+    pub fn set_deterministic_layout(&mut self, enabled: bool) -> Result<(), &'static str> {
+        if enabled == self.deterministic_layout {
+            return Ok(());
+        }
+        if self.area.end_of_dynamic_sections() != 0 {
+            return Err("cannot change the TLS layout mode after dynamic TLS sections have been added");
+        }
+        self.deterministic_layout = enabled;
+        Ok(())
+    }
+
+    /// Returns whether dynamic TLS sections are placed deterministically.
+    pub fn is_deterministic_layout(&self) -> bool {
+        self.deterministic_layout
+    }
+
+    /// Returns a hash of the current TLS layout, i.e., the TCB layout and
+    /// the name, type, offset, and size of every TLS section.
+    ///
+    /// The hash is stable across boots and builds, so two boots of a reproducible kernel
+    /// have the same TLS layout if and only if (barring collisions) they have the same hash.
+    /// It doesn't depend on the contents of TLS sections or on the layout's generation.
+    pub fn layout_hash(&self) -> u64 {
+        // 64-bit FNV-1a, which is simple and doesn't depend on any random state.
+        const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+        const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+        fn hash_bytes(hash: u64, bytes: &[u8]) -> u64 {
+            bytes.iter().fold(hash, |hash, &b| (hash ^ b as u64).wrapping_mul(FNV_PRIME))
+        }
+
+        let mut hash = hash_bytes(FNV_OFFSET_BASIS, &(self.tcb_layout.size() as u64).to_le_bytes());
+        let self_ptr_offset = self.area.pointer_offset();
+        for (image_range, sec) in self.area.image_ranges() {
+            let offset = image_range.start as i64 - self_ptr_offset as i64;
+            hash = hash_bytes(hash, sec.name.as_bytes());
+            // Separate the name from the next field, as names vary in length.
+            hash = hash_bytes(hash, &[0, (sec.typ == SectionType::TlsData) as u8]);
+            hash = hash_bytes(hash, &offset.to_le_bytes());
+            hash = hash_bytes(hash, &(sec.size as u64).to_le_bytes());
         }
+        hash
     }
 
     /// Add a TLS section that has pre-determined offset, e.g.,
@@ -105,22 +752,88 @@ impl TlsInitializer {
         total_static_tls_size: usize,
     ) -> Result<StrongSectionRef, ()> {
         let range = offset .. (offset + tls_section.size);
-        if self.static_section_offsets.contains_key(&range.start) || 
-            self.static_section_offsets.contains_key(&(range.end - 1))
-        {
+        if self.area.fixed_sections().overlaps(&range) {
             return Err(());
         }
 
         // Calculate the new value of this section's virtual address based on its offset.
         let starting_offset = (total_static_tls_size - offset).wrapping_neg();
         tls_section.virt_addr = VirtualAddress::new(starting_offset).ok_or(())?;
-        self.end_of_static_sections = max(self.end_of_static_sections, range.end);
         let section_ref = Arc::new(tls_section);
-        self.static_section_offsets.insert(range, StrongSectionRefWrapper(section_ref.clone()));
-        self.cache_status = CacheStatus::Invalidated;
+        self.area.insert_fixed(range, StrongSectionRefWrapper(section_ref.clone())).map_err(|_| ())?;
+        self.invalidate();
         Ok(section_ref)
     }
 
+    /// Replaces all static TLS sections with those of a new statically-linked base kernel image,
+    /// e.g., when live-updating the nano_core, whose static TLS offsets may differ from the current ones.
+    ///
+    /// Each of the `new_sections` is given along with its offset as determined by the linker,
+    /// as in [`add_existing_static_tls_section()`](Self::add_existing_static_tls_section).
+    /// Each current static TLS section is matched to the new section with the same name, or if there is none,
+    /// with the same name without the trailing hash, as long as that match is unambiguous.
+    /// New sections without a match are simply added.
+    /// Dynamic TLS sections keep their offsets, as those are relative to the TLS self pointer.
+    ///
+    /// Returns the remapping of every current static TLS section to its matching new section,
+    /// such that the caller can rewrite the relocations of each section that depends on an `old_section`
+    /// and carry existing TLS data images over via [`rebase_image()`](Self::rebase_image).
+    /// Existing TLS data images are unaffected.
+    ///
+    /// Returns an error, without modifying this `TlsInitializer`, if the new sections overlap,
+    /// or if a current static TLS section has no matching new section or its size has changed.
+    pub fn replace_static_tls_sections(
+        &mut self,
+        new_sections: Vec<(LoadedSection, usize)>,
+        total_static_tls_size: usize,
+    ) -> Result<Vec<TlsSectionRemapping>, &'static str> {
+        let mut area = AreaBuilder::new(AreaVariant::FixedBelowPointer, RESERVED_AREA_SIZE);
+        for (range, sec) in self.area.dynamic_sections().iter() {
+            area.insert_dynamic(range.clone(), sec.clone());
+        }
+        let mut new_section_refs = Vec::with_capacity(new_sections.len());
+        for (mut section, offset) in new_sections {
+            let range = offset .. (offset + section.size);
+            let starting_offset = total_static_tls_size.checked_sub(offset)
+                .ok_or("new static TLS section's offset exceeds the total static TLS size")?
+                .wrapping_neg();
+            section.virt_addr = VirtualAddress::new(starting_offset)
+                .ok_or("new static TLS section's offset was invalid")?;
+            let section_ref = Arc::new(section);
+            area.insert_fixed(range, StrongSectionRefWrapper(section_ref.clone()))?;
+            new_section_refs.push(section_ref);
+        }
+
+        let mut remappings = Vec::new();
+        for (_range, old) in self.area.fixed_sections().iter() {
+            let new = match new_section_refs.iter().find(|new| new.name == old.name) {
+                Some(exact) => exact,
+                None => {
+                    let hashless = LoadedSection::section_name_without_hash(old.name.as_str());
+                    let mut candidates = new_section_refs.iter()
+                        .filter(|new| LoadedSection::section_name_without_hash(new.name.as_str()) == hashless);
+                    match (candidates.next(), candidates.next()) {
+                        (Some(only), None) => only,
+                        _ => return Err("static TLS section has no matching section in the new base kernel image"),
+                    }
+                }
+            };
+            if new.size != old.size {
+                return Err("size of a static TLS section has changed in the new base kernel image");
+            }
+            remappings.push(TlsSectionRemapping {
+                old_section: Arc::clone(old),
+                old_offset: old.virt_addr.value(),
+                new_section: Arc::clone(new),
+                new_offset: new.virt_addr.value(),
+            });
+        }
+
+        self.area = area;
+        self.invalidate();
+        Ok(remappings)
+    }
+
     /// Inserts the given `section` into this TLS area at the next index
     /// (i.e., offset into the TLS area) where the section will fit.
     /// 
@@ -128,8 +841,9 @@ impl TlsInitializer {
     /// to hold the value of that offset, which is necessary for relocation entries
     /// that depend on this section.
     /// 
-    /// Note: this will never return an index/offset value less than `size_of::<usize>()`,
-    /// (`8` on a 64-bit machine), as the first slot is reserved for the TLS self pointer.
+    /// Note: this will never return an index/offset value less than [`UNWINDING_CONTEXT_OFFSET`]` + size_of::<usize>()`,
+    /// as the area after the TLS self pointer is reserved for the largest supported [`TcbLayout`] header
+    /// and the fixed per-task slots.
     /// 
     /// Returns a tuple of:
     /// 1. The index at which the new section was inserted, 
@@ -139,30 +853,346 @@ impl TlsInitializer {
     /// Returns an Error if there is no remaining space that can fit the section.
     pub fn add_new_dynamic_tls_section(
         &mut self,
-        mut section: LoadedSection,
+        section: LoadedSection,
+        alignment: usize,
+    ) -> Result<(usize, StrongSectionRef), ()> {
+        if fault_injection::should_fail(FaultPoint::TlsInitializer) {
+            return Err(());
+        }
+        let start = self.find_unreserved_gap(section.size, alignment).ok_or(())?;
+        let section_ref = self.insert_dynamic_section(section, start)?;
+        // Now that we've added a new section, the cached data is invalid.
+        self.invalidate();
+        Ok((start, section_ref))
+    }
+
+    /// Inserts the given dynamic TLS `sections`, each with its required alignment,
+    /// which must all belong to the same crate.
+    ///
+    /// If this `TlsInitializer` is in [deterministic mode](Self::set_deterministic_layout),
+    /// the sections are placed in order of their names into one contiguous reservation
+    /// at the first gap that fits all of them.
+    /// Otherwise, this is the same as invoking [`add_new_dynamic_tls_section()`](Self::add_new_dynamic_tls_section)
+    /// on each section in the given order.
+    ///
+    /// Returns the index and modified section of each given section, in the same order as the given `sections`.
+    /// Returns an Error if there is no remaining space that can fit the sections,
+    /// in which case none of the sections are added in deterministic mode.
+    pub fn add_new_dynamic_tls_sections(
+        &mut self,
+        sections: Vec<(LoadedSection, usize)>,
+    ) -> Result<Vec<(usize, StrongSectionRef)>, ()> {
+        if !self.deterministic_layout {
+            return sections.into_iter()
+                .map(|(section, alignment)| self.add_new_dynamic_tls_section(section, alignment))
+                .collect();
+        }
+
+        // Lay out the sections relative to the start of the reservation, in order of their names.
+        // The sort is stable, so sections with the same name remain in their object file order.
+        let mut order: Vec<usize> = (0 .. sections.len()).collect();
+        order.sort_by(|&a, &b| sections[a].0.name.as_str().cmp(sections[b].0.name.as_str()));
+        let mut relative_offsets = vec![0; sections.len()];
+        let redzone = self.redzone_size();
+        let mut reservation_size = 0;
+        let mut reservation_alignment = 1;
+        for &i in &order {
+            let (section, alignment) = &sections[i];
+            let alignment = (*alignment).max(1);
+            let after_redzone = if reservation_size == 0 { 0 } else { reservation_size + redzone };
+            relative_offsets[i] = after_redzone.next_multiple_of(alignment);
+            reservation_size = relative_offsets[i] + section.size;
+            reservation_alignment = max(reservation_alignment, alignment);
+        }
+
+        if fault_injection::should_fail(FaultPoint::TlsInitializer) {
+            return Err(());
+        }
+        let start = self.find_unreserved_gap(reservation_size, reservation_alignment).ok_or(())?;
+        let mut added = Vec::with_capacity(sections.len());
+        for ((section, _), relative_offset) in sections.into_iter().zip(relative_offsets) {
+            let offset = start + relative_offset;
+            added.push((offset, self.insert_dynamic_section(section, offset)?));
+        }
+        self.invalidate();
+        Ok(added)
+    }
+
+    /// Returns `true` if a dynamic TLS section of the given `size` and `alignment`
+    /// can be inserted at the given `offset`, i.e., if that offset satisfies the `alignment`,
+    /// lies after the reserved area, and the section wouldn't overlap an existing dynamic TLS section
+    /// or a [reserved region](Self::reserve_dynamic_tls_region).
+    pub fn can_add_dynamic_tls_section_at(&self, offset: usize, size: usize, alignment: usize) -> bool {
+        offset % alignment.max(1) == 0
+            && offset >= self.area.reserved_size()
+            && offset.checked_add(size).map_or(false, |end|
+                !self.area.dynamic_sections().overlaps(&(offset .. end))
+                    && !self.regions.iter().any(|r| r.start < end && offset < r.end)
+            )
+    }
+
+    /// Returns the first offset at which `size` bytes aligned to `alignment`
+    /// fit between the existing dynamic TLS sections, without reserving that range.
+    ///
+    /// This can be used to place several sections contiguously
+    /// via [`add_new_dynamic_tls_section_at()`](Self::add_new_dynamic_tls_section_at).
+    pub fn find_dynamic_tls_gap(&self, size: usize, alignment: usize) -> Option<usize> {
+        self.find_unreserved_gap(size, alignment)
+    }
+
+    /// Reserves a contiguous region of `size` bytes aligned to `alignment` for the dynamic TLS sections
+    /// of a single owner, e.g., the crates of a sandboxed namespace,
+    /// such that its sections are isolated from all other dynamic TLS sections.
+    ///
+    /// Sections are placed into the region only via
+    /// [`add_new_dynamic_tls_section_in_region()`](Self::add_new_dynamic_tls_section_in_region),
+    /// and all other ways of adding dynamic TLS sections avoid it.
+    ///
+    /// Returns the range of offsets of the region, or an error if there is no gap that can fit it.
+    pub fn reserve_dynamic_tls_region(&mut self, size: usize, alignment: usize) -> Result<Range<usize>, ()> {
+        let start = self.find_unreserved_gap(size, alignment).ok_or(())?;
+        let region = start .. (start + size);
+        self.regions.push(region.clone());
+        Ok(region)
+    }
+
+    /// Releases the given reserved `region`, after which other dynamic TLS sections may be placed into it.
+    ///
+    /// The sections that were placed into the region are unaffected and must be removed separately.
+    /// Returns an error if the `region` isn't currently reserved.
+    pub fn release_dynamic_tls_region(&mut self, region: &Range<usize>) -> Result<(), ()> {
+        let index = self.regions.iter().position(|r| r == region).ok_or(())?;
+        self.regions.swap_remove(index);
+        Ok(())
+    }
+
+    /// Inserts the given `section` into the first gap within the reserved `region` where it will fit.
+    ///
+    /// Like [`add_new_dynamic_tls_section()`](Self::add_new_dynamic_tls_section),
+    /// this modifies the virtual address field of the given `section` to hold its offset.
+    ///
+    /// Returns an error if the `region` isn't currently reserved or has no remaining space that can fit the section.
+    pub fn add_new_dynamic_tls_section_in_region(
+        &mut self,
+        section: LoadedSection,
+        region: &Range<usize>,
         alignment: usize,
     ) -> Result<(usize, StrongSectionRef), ()> {
-        let mut start_index = None;
-        // Find the next "gap" big enough to fit the new TLS section, 
-        // skipping the first `POINTER_SIZE` bytes, which are reserved for the TLS self pointer.
-        let range_after_tls_self_pointer = POINTER_SIZE .. usize::MAX;
-        for gap in self.dynamic_section_offsets.gaps(&range_after_tls_self_pointer) {
-            let aligned_start = gap.start.next_multiple_of(alignment);
-            if aligned_start + section.size <= gap.end {
-                start_index = Some(aligned_start);
-                break;
+        if !self.regions.contains(region) || fault_injection::should_fail(FaultPoint::TlsInitializer) {
+            return Err(());
+        }
+        let redzone = self.redzone_size();
+        let start = self.area.dynamic_sections().gaps(region)
+            .map(|gap| ((gap.start + redzone).next_multiple_of(alignment.max(1)), gap.end))
+            .find(|&(aligned_start, gap_end)| aligned_start.checked_add(section.size + redzone).map_or(false, |end| end <= gap_end))
+            .map(|(aligned_start, _)| aligned_start)
+            .ok_or(())?;
+        let section_ref = self.insert_dynamic_section(section, start)?;
+        self.invalidate();
+        Ok((start, section_ref))
+    }
+
+    /// Returns the first offset at which `size` bytes aligned to `alignment`
+    /// fit between the existing dynamic TLS sections and outside of all reserved regions,
+    /// along with a redzone on both sides if [redzones](Self::set_redzones) are enabled.
+    fn find_unreserved_gap(&self, size: usize, alignment: usize) -> Option<usize> {
+        let alignment = alignment.max(1);
+        let redzone = self.redzone_size();
+        let mut from = self.area.reserved_size();
+        loop {
+            let start = self.area.dynamic_sections().gaps(&(from .. usize::MAX))
+                .map(|gap| ((gap.start + redzone).next_multiple_of(alignment), gap.end))
+                .find(|&(aligned_start, gap_end)| aligned_start.checked_add(size + redzone).map_or(false, |end| end <= gap_end))
+                .map(|(aligned_start, _)| aligned_start)?;
+            match self.overlapping_region(&(start .. start + size)) {
+                Some(region) => from = region.end,
+                None => return Some(start),
+            }
+        }
+    }
+
+    /// Returns `true` if a dynamic TLS section can occupy the given `range` of offsets
+    /// under the same constraints as [`find_unreserved_gap()`](Self::find_unreserved_gap), i.e.,
+    /// if it lies after the reserved area and outside of all reserved regions,
+    /// and it is separated from the reserved area and every existing dynamic TLS section by a redzone
+    /// if [redzones](Self::set_redzones) are enabled.
+    fn is_unreserved_range(&self, range: &Range<usize>) -> bool {
+        let redzone = self.redzone_size();
+        let padded = range.start.saturating_sub(redzone) .. range.end.saturating_add(redzone);
+        range.start >= self.area.reserved_size() + redzone
+            && !self.area.dynamic_sections().overlaps(&padded)
+            && self.overlapping_region(range).is_none()
+    }
+
+    /// Returns the first [reserved region](Self::reserve_dynamic_tls_region) that overlaps the given `range`.
+    fn overlapping_region(&self, range: &Range<usize>) -> Option<&Range<usize>> {
+        self.regions.iter().find(|r| r.start < range.end && range.start < r.end)
+    }
+
+    /// Inserts the given `section` into this TLS area at the given `offset`,
+    /// e.g., to reproduce the TLS layout of a previous boot.
+    ///
+    /// Like [`add_new_dynamic_tls_section()`](Self::add_new_dynamic_tls_section),
+    /// this modifies the virtual address field of the given `section` to hold that offset.
+    ///
+    /// Returns an error if the section can't be inserted at that `offset`;
+    /// see [`can_add_dynamic_tls_section_at()`](Self::can_add_dynamic_tls_section_at).
+    pub fn add_new_dynamic_tls_section_at(
+        &mut self,
+        section: LoadedSection,
+        offset: usize,
+        alignment: usize,
+    ) -> Result<StrongSectionRef, ()> {
+        if !self.can_add_dynamic_tls_section_at(offset, section.size, alignment) {
+            return Err(());
+        }
+        let section_ref = self.insert_dynamic_section(section, offset)?;
+        self.invalidate();
+        Ok(section_ref)
+    }
+
+    /// Merges the TLS sections of the `other` TLS initializer into this one,
+    /// e.g., when combining namespaces whose crates registered their TLS sections in different initializers.
+    ///
+    /// Both initializers must have the same static TLS sections, as those have fixed offsets.
+    /// Each of `other`'s dynamic TLS sections that isn't already in this `TlsInitializer`
+    /// keeps its offset if that range is free in this `TlsInitializer`, i.e., if it doesn't overlap
+    /// a [reserved region](Self::reserve_dynamic_tls_region) or an existing dynamic TLS section
+    /// or its [redzones](Self::set_redzones).
+    /// Otherwise, it is re-homed into the first free gap that preserves the alignment of its old offset,
+    /// for which a copy of the section is created with its virtual address field set to the new offset.
+    ///
+    /// Returns the list of re-homed sections, such that the caller can rewrite the relocations
+    /// of each section that depends on an `old_section` to use its `new_section` instead.
+    /// Existing TLS data images are unaffected.
+    pub fn merge(&mut self, other: &TlsInitializer) -> Result<Vec<TlsSectionRemapping>, &'static str> {
+        let same_static_sections = self.area.pointer_offset() == other.area.pointer_offset()
+            && other.area.fixed_sections().iter().all(|(range, sec)| {
+                self.area.fixed_sections().get_key_value(&range.start)
+                    .map_or(false, |(r, s)| r == range && Arc::ptr_eq(s, sec))
+            });
+        if !same_static_sections {
+            return Err("cannot merge TLS initializers with different static TLS sections");
+        }
+
+        let mut remappings = Vec::new();
+        for (range, sec) in other.area.dynamic_sections().iter() {
+            if self.area.find_dynamic(|s| Arc::ptr_eq(s, sec)).is_some() {
+                continue;
             }
+            if self.is_unreserved_range(range) {
+                self.area.insert_dynamic(range.clone(), sec.clone());
+                continue;
+            }
+
+            let alignment = 1 << range.start.trailing_zeros();
+            let new_offset = self.find_unreserved_gap(sec.size, alignment)
+                .ok_or("no remaining space in the TLS area for a merged TLS section")?;
+            let old_inner = sec.inner.read();
+            let new_section = LoadedSection::with_dependencies(
+                sec.typ,
+                sec.name.clone(),
+                Arc::clone(&sec.mapped_pages),
+                sec.mapped_pages_offset,
+                VirtualAddress::zero(), // will be set in `insert_dynamic_section()` below
+                sec.size,
+                sec.global,
+                sec.parent_crate.clone(),
+                old_inner.sections_i_depend_on.clone(),
+                old_inner.sections_dependent_on_me.clone(),
+                #[cfg(internal_deps)]
+                old_inner.internal_dependencies.clone(),
+            );
+            let new_section = self.insert_dynamic_section(new_section, new_offset)
+                .map_err(|_| "merged TLS section's new offset was invalid")?;
+            remappings.push(TlsSectionRemapping {
+                old_section: Arc::clone(sec),
+                old_offset: range.start,
+                new_section,
+                new_offset,
+            });
         }
+        self.invalidate();
+        Ok(remappings)
+    }
 
-        let start = start_index.ok_or(())?;
+    /// Inserts the given dynamic TLS `section` at the given `start` offset,
+    /// setting its virtual address field to that offset.
+    ///
+    /// This does not invalidate the cached data image.
+    fn insert_dynamic_section(&mut self, mut section: LoadedSection, start: usize) -> Result<StrongSectionRef, ()> {
         let range = start .. (start + section.size);
         section.virt_addr = VirtualAddress::new(range.start).ok_or(())?;
         let section_ref = Arc::new(section);
-        self.end_of_dynamic_sections = max(self.end_of_dynamic_sections, range.end);
-        self.dynamic_section_offsets.insert(range, StrongSectionRefWrapper(section_ref.clone()));
-        // Now that we've added a new section, the cached data is invalid.
-        self.cache_status = CacheStatus::Invalidated;
-        Ok((start, section_ref))
+        self.area.insert_dynamic(range, StrongSectionRefWrapper(section_ref.clone()));
+        Ok(section_ref)
+    }
+
+    /// Removes the given dynamic TLS `section` from this `TlsInitializer`,
+    /// e.g., when the crate that contains it is being unloaded.
+    ///
+    /// The removed section will not be included in TLS data images generated from now on,
+    /// and its range of offsets may be reused by TLS sections that are added later.
+    /// Existing TLS data images are unaffected.
+    ///
+    /// Returns an error if the `section` is not a dynamic TLS section in this `TlsInitializer`;
+    /// static TLS sections can never be removed.
+    pub fn remove_dynamic_tls_section(&mut self, section: &StrongSectionRef) -> Result<(), ()> {
+        let range = self.area.find_dynamic(|sec| Arc::ptr_eq(&sec.0, section)).ok_or(())?;
+        self.area.remove_dynamic(range);
+        self.invalidate();
+        Ok(())
+    }
+
+    /// Inserts the given `section` into this TLS area at the same offset as the existing
+    /// dynamic TLS section `old_section`, which it replaces, e.g., when swapping crates.
+    ///
+    /// Because the offset is unchanged, every existing TLS data image already contains
+    /// the `old_section`'s live values at the location from which the new `section` is accessed.
+    /// Like [`add_new_dynamic_tls_section()`](Self::add_new_dynamic_tls_section),
+    /// this modifies the virtual address field of the given `section` to hold that offset.
+    ///
+    /// Returns an error if the `old_section` is not a dynamic TLS section in this `TlsInitializer`,
+    /// if the two sections' sizes differ, or if the offset doesn't satisfy the given `alignment`.
+    pub fn replace_dynamic_tls_section(
+        &mut self,
+        old_section: &StrongSectionRef,
+        mut section: LoadedSection,
+        alignment: usize,
+    ) -> Result<(usize, StrongSectionRef), ()> {
+        let range = self.area.find_dynamic(|sec| Arc::ptr_eq(&sec.0, old_section)).ok_or(())?;
+        if section.size != old_section.size || range.start % alignment.max(1) != 0 {
+            return Err(());
+        }
+        section.virt_addr = VirtualAddress::new(range.start).ok_or(())?;
+        let section_ref = Arc::new(section);
+        self.area.insert_dynamic(range.clone(), StrongSectionRefWrapper(section_ref.clone()));
+        // The data image must be fully re-generated, as the TLS layout's section names have changed.
+        self.invalidate();
+        Ok((range.start, section_ref))
+    }
+
+    /// Returns the offset of the given TLS `section` from the TLS self pointer,
+    /// which is the value that code uses to access it, e.g., via the local-exec TLS model.
+    ///
+    /// Static TLS sections have negative offsets, while dynamic TLS sections have positive offsets.
+    /// Returns `None` if the `section` is not part of this `TlsInitializer`.
+    pub fn offset_of(&self, section: &StrongSectionRef) -> Option<isize> {
+        self.area.offset_of(|sec| Arc::ptr_eq(&sec.0, section))
+    }
+
+    /// Returns the ID of the TLS module that contains every TLS section in this `TlsInitializer`,
+    /// which is used by the general-dynamic and local-dynamic TLS models.
+    ///
+    /// This is always [`THESEUS_TLS_MODULE_ID`].
+    pub fn module_id(&self) -> usize {
+        THESEUS_TLS_MODULE_ID
+    }
+
+    /// Returns the generation of the most recently generated TLS layout,
+    /// which changes whenever TLS sections are added, removed, or moved.
+    pub fn generation(&self) -> u64 {
+        self.generation
     }
 
     /// Invalidates the cached data image in this `TlsInitializer` area.
@@ -172,96 +1202,331 @@ impl TlsInitializer {
     /// and thus the data image needs to be re-created by re-reading the section data.
     pub fn invalidate(&mut self) {
         self.cache_status = CacheStatus::Invalidated;
+        self.template_cell.mark_stale();
     }
 
-    /// Returns a new copy of the TLS data image.
-    /// 
-    /// This function lazily generates the TLS image data on demand, if needed.
-    pub fn get_data(&mut self) -> TlsDataImage {
-        let total_section_size = self.end_of_static_sections + self.end_of_dynamic_sections;
-        let required_capacity = if total_section_size > 0 { total_section_size + POINTER_SIZE } else { 0 };
-        if required_capacity == 0 {
-            return TlsDataImage { _data: None, ptr: 0 };
-        }
-
-        // An internal function that iterates over all TLS sections and copies their data into the new data image.
-        fn copy_tls_section_data(
-            new_data: &mut Vec<u8>,
-            section_offsets: &RangeMap<usize, StrongSectionRefWrapper>,
-            end_of_previous_range: &mut usize,
-        ) {
-            for (range, sec) in section_offsets.iter() {
-                // Insert padding bytes into the data vec to ensure the section data is inserted at the correct index.
-                let num_padding_bytes = range.start.saturating_sub(*end_of_previous_range);
-                new_data.extend(core::iter::repeat(0).take(num_padding_bytes));
-
-                // Insert the section data into the new data vec.
-                if sec.typ == SectionType::TlsData {
-                    let sec_mp = sec.mapped_pages.lock();
-                    let sec_data: &[u8] = sec_mp.as_slice(sec.mapped_pages_offset, sec.size).unwrap();
-                    new_data.extend_from_slice(sec_data);
-                } else {
-                    // For TLS BSS sections (.tbss), fill the section size with all zeroes.
-                    new_data.extend(core::iter::repeat(0).take(sec.size));
-                }
-                *end_of_previous_range = range.end;
+    /// Invalidates only the given byte `range` of the cached data image in this `TlsInitializer`.
+    ///
+    /// The `range` is relative to the start of the data image, i.e., the start of the first static TLS section.
+    /// On the next call to [`get_data()`](Self::get_data), only the bytes in this range
+    /// are re-read from the underlying TLS sections, rather than re-generating the entire image.
+    pub fn invalidate_range(&mut self, range: Range<usize>) {
+        if range.is_empty() {
+            return;
+        }
+        match &mut self.cache_status {
+            // The whole image will be regenerated anyway.
+            CacheStatus::Invalidated => { }
+            CacheStatus::Fresh => self.cache_status = CacheStatus::PartiallyDirty(vec![range]),
+            CacheStatus::PartiallyDirty(dirty_ranges) => dirty_ranges.push(range),
+        }
+        self.template_cell.mark_stale();
+    }
+
+    /// Invalidates only the bytes of the cached data image that belong to the given TLS `section`.
+    ///
+    /// This is useful for when a single TLS section's data has been modified,
+    /// e.g., after writing relocations into it.
+    ///
+    /// Returns an error if the given `section` is not part of this `TlsInitializer`.
+    pub fn invalidate_section(&mut self, section: &StrongSectionRef) -> Result<(), ()> {
+        let range = self.image_range_of(section).ok_or(())?;
+        self.invalidate_range(range);
+        Ok(())
+    }
+
+    /// Returns the range of bytes in the data image that hold the given TLS `section`.
+    fn image_range_of(&self, section: &StrongSectionRef) -> Option<Range<usize>> {
+        self.area.image_ranges()
+            .find(|(_, sec)| Arc::ptr_eq(&sec.0, section))
+            .map(|(image_range, _)| image_range)
+    }
+
+    /// Re-reads the bytes in the given `dirty` range of the cached data image
+    /// from the TLS sections that overlap it, writing them into `data`.
+    fn refresh_cached_range(&self, data: &mut [u8], dirty: &Range<usize>) {
+        for (image_range, sec) in self.area.image_ranges() {
+            let start = max(image_range.start, dirty.start);
+            let end = min(image_range.end, dirty.end);
+            if start >= end {
+                continue;
+            }
+            let dest = &mut data[start .. end];
+            if sec.typ == SectionType::TlsData {
+                let sec_mp = sec.mapped_pages.lock();
+                let offset_in_sec = start - image_range.start;
+                let sec_data: &[u8] = sec_mp.as_slice(sec.mapped_pages_offset + offset_in_sec, end - start).unwrap();
+                dest.copy_from_slice(sec_data);
+            } else {
+                // TLS BSS sections (.tbss) are always all zeroes.
+                dest.fill(0);
             }
         }
+    }
 
-        if self.cache_status == CacheStatus::Invalidated {
+    /// Returns a new copy of the TLS data image.
+    /// 
+    /// This function lazily generates the TLS image data on demand, if needed,
+    /// and publishes it to this `TlsInitializer`'s [`TlsTemplateCell`].
+    ///
+    /// The given `hint` determines where the backing memory of the new copy is allocated,
+    /// e.g., on the memory node local to the CPU that the new task will run on.
+    pub fn get_data(&mut self, hint: TlsAllocHint) -> TlsDataImage {
+        self.publish().instantiate(hint)
+    }
+
+    /// Like [`Self::get_data()`], but returns an error if the new copy cannot be allocated
+    /// under memory pressure, even after reclaiming memory; see [`MemoryPressureHooks`].
+    pub fn try_get_data(&mut self, hint: TlsAllocHint) -> Result<TlsDataImage, &'static str> {
+        self.publish().try_instantiate(hint)
+    }
+
+    /// Returns the total size of all static TLS sections and their initial contents,
+    /// which can be passed to [`init_bootstrap_tls()`] to boot up another CPU.
+    pub fn static_tls_template(&mut self) -> (usize, Vec<u8>) {
+        let template = self.publish();
+        let static_data = template.data.get(.. template.self_ptr_offset).unwrap_or_default();
+        (self.area.pointer_offset(), static_data.to_vec())
+    }
+
+    /// Restores the TLS sections and layout of the given `previous` clone of this `TlsInitializer`,
+    /// e.g., to roll back a failed update, while still publishing to this `TlsInitializer`'s own [`TlsTemplateCell`].
+    ///
+    /// The restored data image is published upon the next call to [`Self::publish()`].
+    pub fn restore(&mut self, mut previous: TlsInitializer) {
+        core::mem::swap(&mut self.template_cell, &mut previous.template_cell);
+        *self = previous;
+        self.template_cell.mark_stale();
+    }
+
+    /// Re-generates the TLS data image (if needed) and publishes it
+    /// to this `TlsInitializer`'s [`TlsTemplateCell`].
+    ///
+    /// After this returns, tasks can obtain a new copy of the up-to-date TLS data image
+    /// via [`TlsTemplateCell::get_data()`] without locking this `TlsInitializer`.
+    pub fn publish(&mut self) -> Arc<TlsTemplate> {
+        if self.cache_status == CacheStatus::Invalidated || self.data_cache.is_none() {
             // debug!("TlsInitializer was invalidated, re-generating data.\n{:#X?}", self);
 
             // On some architectures, such as x86_64, the ABI convention REQUIRES that
             // the TLS area data starts with a pointer to itself (the TLS self pointer).
             // Also, all data for "existing" (statically-linked) TLS sections must
             // come *before* the TLS self pointer, i.e., at negative offsets from the TLS self pointer.
-            // Thus, we handle that here by appending space for a pointer (one `usize`)
-            // to the `new_data` vector after we insert the static TLS data sections.
-            // The location of the new pointer value is the conceptual "start" of the TLS image,
+            // The area builder handles that by placing the static TLS sections (its fixed sections)
+            // before its reserved area, which begins with space for the TLS self pointer.
+            // Its actual value will be filled in later (in `TlsTemplate::instantiate()`) after a new copy of the TLS data image is made.
+            // The fixed per-task slots (e.g., `errno`) after the TCB header are zero-initialized.
+            // The location of the TLS self pointer is the conceptual "start" of the TLS image,
             // and that's what should be used for the value of the TLS register (e.g., `FS_BASE` MSR on x86_64).
-            let mut new_data: Vec<u8> = Vec::with_capacity(required_capacity);
-            
-            // Iterate through all static TLS sections and copy their data into the new data image.
-            let mut end_of_previous_range: usize = 0;
-            copy_tls_section_data(&mut new_data, &self.static_section_offsets, &mut end_of_previous_range);
-            assert_eq!(end_of_previous_range, self.end_of_static_sections);
-
-            // Append space for the TLS self pointer immediately after the end of the last static TLS data section;
-            // its actual value will be filled in later (in `get_data()`) after a new copy of the TLS data image is made.
-            new_data.extend_from_slice(&[0u8; POINTER_SIZE]);
-
-            // Iterate through all dynamic TLS sections and copy their data into the new data image.
-            end_of_previous_range = POINTER_SIZE; // we already pushed room for the TLS self pointer above.
-            copy_tls_section_data(&mut new_data, &self.dynamic_section_offsets, &mut end_of_previous_range);
-            if self.end_of_dynamic_sections != 0 {
-                // this assertion only makes sense if there are any dynamic sections
-                assert_eq!(end_of_previous_range, self.end_of_dynamic_sections);
-            }
+            //
+            // If there are no TLS sections at all, the data image is empty, without a TLS self pointer.
+            let mut new_data = self.area.build_image(|sec, dest| {
+                // TLS BSS sections (.tbss) are left as all zeroes.
+                if sec.typ == SectionType::TlsData {
+                    let sec_mp = sec.mapped_pages.lock();
+                    let sec_data: &[u8] = sec_mp.as_slice(sec.mapped_pages_offset, sec.size).unwrap();
+                    dest.copy_from_slice(sec_data);
+                }
+            });
 
-            self.data_cache = new_data;
+            self.generation += 1;
+            let layout = self.current_layout();
+            if let Some(shadow) = layout.shadow.as_ref() {
+                shadow.poison(&mut new_data, self.area.pointer_offset());
+            }
+            tracepoint::trace(Tracepoint::TlsImage, self.generation, new_data.len() as u64);
+            TLS_IMAGE_REGENERATIONS.inc();
+            self.data_cache = Some(Arc::new(TlsTemplate {
+                data: new_data.into_boxed_slice(),
+                self_ptr_offset: self.area.pointer_offset(),
+                tcb_layout: self.tcb_layout,
+                layout: Arc::new(layout),
+            }));
             self.cache_status = CacheStatus::Fresh;
         }
+        else if let CacheStatus::PartiallyDirty(dirty_ranges) = core::mem::replace(&mut self.cache_status, CacheStatus::Fresh) {
+            // Readers may still be copying the currently-published template,
+            // so we modify a private copy of it (if shared) and then publish that copy.
+            let mut template = self.data_cache.take().expect("BUG: partially-dirty TLS data image didn't exist");
+            let template_mut = Arc::make_mut(&mut template);
+            for dirty in &dirty_ranges {
+                self.refresh_cached_range(&mut template_mut.data, dirty);
+            }
+            self.data_cache = Some(template);
+        }
 
         // Here, the `data_cache` is guaranteed to be fresh and ready to use.
-        let mut data_copy: Box<[u8]> = self.data_cache.as_slice().into();
+        let template = self.data_cache.clone().expect("BUG: fresh TLS data image didn't exist");
+        if self.template_cell.is_stale() {
+            self.template_cell.publish(template.clone());
+        }
+        template
+    }
+}
+
+/// A fully-generated TLS data image that serves as the template
+/// from which each new task's [`TlsDataImage`] is copied.
+#[derive(Debug, Clone)]
+pub struct TlsTemplate {
+    data: Box<[u8]>,
+    /// The offset into `data` of the TLS self pointer.
+    self_ptr_offset: usize,
+    /// The layout of the TCB header at `self_ptr_offset`.
+    tcb_layout: TcbLayout,
+    /// The TLS sections contained in `data`.
+    layout: Arc<TlsLayout>,
+}
+impl TlsTemplate {
+    /// Returns a new copy of this TLS data image template,
+    /// allocated according to the given `hint`.
+    pub fn instantiate(&self, hint: TlsAllocHint) -> TlsDataImage {
+        self.instantiate_with(|data| Ok(ImageStorage::new_copy(data, hint)))
+            .expect("BUG: infallible TLS data image allocation failed")
+    }
+
+    /// Like [`Self::instantiate()`], but returns an error if the new copy cannot be allocated,
+    /// even after asking the registered [`MemoryPressureHooks`] to reclaim memory.
+    pub fn try_instantiate(&self, hint: TlsAllocHint) -> Result<TlsDataImage, &'static str> {
+        self.instantiate_with(|data| ImageStorage::try_new_copy(data, hint))
+    }
+
+    fn instantiate_with(
+        &self,
+        alloc: impl FnOnce(&[u8]) -> Result<ImageStorage, &'static str>,
+    ) -> Result<TlsDataImage, &'static str> {
+        if self.data.is_empty() {
+            return Ok(TlsDataImage { _data: None, ptr: 0, tcb_layout: self.tcb_layout, layout: self.layout.clone() });
+        }
+
+        let mut data_copy = alloc(&self.data)?;
         // Every time we create a new copy of the TLS data image, we have to re-calculate
         // and re-assign the TLS self pointer value (located after the static TLS section data),
         // because the virtual address of that new TLS data image copy will be unique.
         // Note that we only do this if the data_copy actually contains any TLS data.
-        let self_ptr_offset = self.end_of_static_sections;
-        if let Some(dest_slice) = data_copy.get_mut(self_ptr_offset .. (self_ptr_offset + POINTER_SIZE)) {
-            let tls_self_ptr_value = dest_slice.as_ptr() as usize;
-            dest_slice.copy_from_slice(&tls_self_ptr_value.to_ne_bytes());
-            TlsDataImage {
+        if let Some(tls_self_ptr_value) = data_copy.write_self_ptr(self.self_ptr_offset, self.tcb_layout) {
+            data_copy.write_tcb_guards(self.self_ptr_offset, self.tcb_layout);
+            Ok(TlsDataImage {
                 _data: Some(data_copy),
                 ptr:   tls_self_ptr_value,
-            }
+                tcb_layout: self.tcb_layout,
+                layout: self.layout.clone(),
+            })
         } else {
-            panic!("BUG: offset of TLS self pointer was out of bounds in the TLS data image:\n{:02X?}", data_copy);
+            panic!("BUG: offset of TLS self pointer was out of bounds in the TLS data image:\n{:02X?}", data_copy.as_slice());
         }
     }
 }
 
+/// A cell that holds the most recently published [`TlsTemplate`],
+/// which allows new tasks to obtain a copy of it without locking the [`TlsInitializer`].
+///
+/// This works much like read-copy-update (RCU):
+/// * Readers never block; they atomically obtain a reference to the current template.
+///   While a new template is being generated, readers continue to copy the previous one.
+/// * A [`TlsInitializer`] atomically swaps in a newly-generated template and retires the previous one
+///   without waiting for readers. A retired template is released after a grace period,
+///   i.e., the next time that no reader could still be in the middle of obtaining a reference to it.
+pub struct TlsTemplateCell {
+    /// A pointer obtained from `Arc::into_raw()`, or null if nothing has been published yet.
+    current: AtomicPtr<TlsTemplate>,
+    /// The number of readers currently obtaining a reference to the `current` template.
+    readers: AtomicUsize,
+    /// Whether the `current` template is out of date with respect to its `TlsInitializer`.
+    stale: AtomicBool,
+    /// Previously-published templates that readers may still be obtaining a reference to.
+    retired: Mutex<Vec<Arc<TlsTemplate>>>,
+}
+impl TlsTemplateCell {
+    /// Creates a new cell with no published template.
+    pub const fn empty() -> TlsTemplateCell {
+        TlsTemplateCell {
+            current: AtomicPtr::new(core::ptr::null_mut()),
+            readers: AtomicUsize::new(0),
+            stale: AtomicBool::new(true),
+            retired: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Returns `true` if the TLS sections in the [`TlsInitializer`] that publishes to this cell
+    /// have changed since the current template was published.
+    pub fn is_stale(&self) -> bool {
+        self.stale.load(Ordering::Acquire)
+    }
+
+    fn mark_stale(&self) {
+        self.stale.store(true, Ordering::Release);
+    }
+
+    /// Returns a new copy of the most recently published TLS template without blocking,
+    /// or `None` if no template has been published yet.
+    ///
+    /// Note that the returned image may be [stale](Self::is_stale).
+    pub fn get_data(&self, hint: TlsAllocHint) -> Option<TlsDataImage> {
+        self.load().map(|template| template.instantiate(hint))
+    }
+
+    /// Like [`Self::get_data()`], but the inner result is an error if the new copy
+    /// cannot be allocated under memory pressure; see [`TlsTemplate::try_instantiate()`].
+    pub fn try_get_data(&self, hint: TlsAllocHint) -> Option<Result<TlsDataImage, &'static str>> {
+        self.load().map(|template| template.try_instantiate(hint))
+    }
+
+    /// Atomically obtains a reference to the currently-published template.
+    fn load(&self) -> Option<Arc<TlsTemplate>> {
+        self.readers.fetch_add(1, Ordering::SeqCst);
+        let ptr = self.current.load(Ordering::SeqCst);
+        let template = (!ptr.is_null()).then(|| {
+            // SAFETY: `ptr` came from `Arc::into_raw()` in `publish()`, and once it is retired,
+            //         it won't be released until `readers` has dropped to zero.
+            unsafe {
+                Arc::increment_strong_count(ptr);
+                Arc::from_raw(ptr)
+            }
+        });
+        if self.readers.fetch_sub(1, Ordering::SeqCst) == 1 {
+            // We were the last active reader, so release any retired templates unless a publisher is doing so.
+            if let Some(mut retired) = self.retired.try_lock() {
+                self.release_retired(&mut retired);
+            }
+        }
+        template
+    }
+
+    /// Publishes the given `template`, replacing the previous one.
+    ///
+    /// This never waits for readers; the previous template is retired instead.
+    fn publish(&self, template: Arc<TlsTemplate>) {
+        let new_ptr = Arc::into_raw(template) as *mut TlsTemplate;
+        let old_ptr = self.current.swap(new_ptr, Ordering::SeqCst);
+        self.stale.store(false, Ordering::Release);
+        let mut retired = self.retired.lock();
+        if !old_ptr.is_null() {
+            // SAFETY: `old_ptr` came from `Arc::into_raw()` in a prior call to `publish()`.
+            retired.push(unsafe { Arc::from_raw(old_ptr) });
+        }
+        self.release_retired(&mut retired);
+    }
+
+    /// Releases all `retired` templates if no reader is currently active.
+    ///
+    /// Every retired template was swapped out before it was retired, so any reader
+    /// that starts afterwards obtains a newer template. Thus, once no reader is active,
+    /// no reader can still be about to increment the reference count of a retired template.
+    fn release_retired(&self, retired: &mut Vec<Arc<TlsTemplate>>) {
+        if !retired.is_empty() && self.readers.load(Ordering::SeqCst) == 0 {
+            retired.clear();
+        }
+    }
+}
+impl core::fmt::Debug for TlsTemplateCell {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("TlsTemplateCell")
+            .field("published", &!self.current.load(Ordering::Relaxed).is_null())
+            .field("stale", &self.is_stale())
+            .finish_non_exhaustive()
+    }
+}
+
 /// An initialized TLS area data image ready to be used by a new task.
 /// 
 /// The data is opaque, but one can obtain a pointer to the TLS area.
@@ -278,20 +1543,447 @@ impl TlsInitializer {
 pub struct TlsDataImage {
     // The data is wrapped in an Option to avoid allocating an empty boxed slice
     // when there are no TLS data sections.
-    _data: Option<Box<[u8]>>,
+    _data: Option<ImageStorage>,
     ptr:   usize,
+    tcb_layout: TcbLayout,
+    /// The TLS sections that this image was generated from.
+    layout: Arc<TlsLayout>,
 }
 impl TlsDataImage {
-    /// Sets the current CPU's TLS register to point to this TLS data image.
+    /// Returns the memory node that this TLS data image was allocated on,
+    /// or `None` if it was allocated from the regular heap.
+    pub fn node(&self) -> Option<usize> {
+        self._data.as_ref().and_then(ImageStorage::node)
+    }
+
+    /// Moves this TLS data image's current contents into a new allocation
+    /// that is placed according to the given `hint`.
+    ///
+    /// This does nothing if the image is empty or already resides on the requested node.
+    /// Unlike [`TlsInitializer::get_data()`], this preserves the current values
+    /// of all TLS variables, and then re-calculates the TLS self pointer.
+    ///
+    /// # Safety
+    /// The task that owns this TLS data image must not be running,
+    /// and nothing may hold a reference into this TLS data image,
+    /// as its address changes after this function returns.
+    pub unsafe fn reallocate(&mut self, hint: TlsAllocHint) {
+        let Some(old_data) = self._data.as_ref() else { return };
+        let target_node = NODE_AWARE_ALLOCATOR.get().and_then(|alloc| hint.node(*alloc));
+        if target_node == old_data.node() {
+            return;
+        }
+        let self_ptr_offset = self.ptr - old_data.as_slice().as_ptr() as usize;
+        let mut new_data = ImageStorage::new_copy(old_data.as_slice(), hint);
+        self.ptr = new_data.write_self_ptr(self_ptr_offset, self.tcb_layout)
+            .expect("BUG: offset of TLS self pointer was out of bounds in the reallocated TLS data image");
+        self._data = Some(new_data);
+    }
+
+    /// Returns the starting address of this image's memory,
+    /// which is lower than its TLS self pointer if there are any static TLS sections.
+    ///
+    /// Returns `0` if this image is empty.
+    pub fn base_address(&self) -> usize {
+        self._data.as_ref().map_or(0, |data| data.as_slice().as_ptr() as usize)
+    }
+
+    /// Returns the size in bytes of this image's memory.
+    pub fn size(&self) -> usize {
+        self._data.as_ref().map_or(0, |data| data.as_slice().len())
+    }
+
+    /// Returns the value of this image's TLS self pointer,
+    /// which is the value that the TLS register holds when this image is active.
+    ///
+    /// Returns `0` if this image is empty.
+    pub fn self_pointer(&self) -> usize {
+        self.ptr
+    }
+
+    /// Creates a new TLS data image by copying the live contents of this TLS data image,
+    /// rather than copying the pristine contents of the TLS data sections.
+    ///
+    /// The new image's self pointer(s) are re-fixed to point to the new image.
+    /// The TCB header's other fields (e.g., the stack guard) and the `errno` slot are inherited,
+    /// but all other fixed per-task slots (the unwinding context, panic buffer,
+    /// heap cache, RNG state, profiling counters, and task-local storage root) are reset to zero.
+    /// Theseus has no dynamic thread vector (DTV), so there is nothing else to re-fix.
+    ///
+    /// # Safety
+    /// This image must be the current task's TLS area, or belong to a task that isn't running,
+    /// such that its contents aren't concurrently modified.
+    /// Also, the caller must ensure that the copied values of all TLS variables are valid
+    /// in the new image, e.g., that they don't own heap memory that would be freed twice.
+    pub unsafe fn duplicate(&self, hint: TlsAllocHint) -> TlsDataImage {
+        let Some(data) = self._data.as_ref() else {
+            return TlsDataImage { _data: None, ptr: 0, tcb_layout: self.tcb_layout, layout: self.layout.clone() };
+        };
+        let self_ptr_offset = self.ptr - data.as_slice().as_ptr() as usize;
+        let mut new_data = ImageStorage::new_copy(data.as_slice(), hint);
+        let ptr = new_data.write_self_ptr(self_ptr_offset, self.tcb_layout)
+            .expect("BUG: offset of TLS self pointer was out of bounds in the duplicated TLS data image");
+        let per_task_slots = (self_ptr_offset + UNWINDING_CONTEXT_OFFSET) .. (self_ptr_offset + RESERVED_AREA_SIZE);
+        new_data.as_mut_slice()[per_task_slots].fill(0);
+        TlsDataImage { _data: Some(new_data), ptr, tcb_layout: self.tcb_layout, layout: self.layout.clone() }
+    }
+
+    /// Overwrites the bytes at the given `offset` from the TLS self pointer in this TLS data image
+    /// with the given `bytes`, e.g., to set the initial value of a TLS variable.
+    ///
+    /// The `offset` is the same value that the local-exec TLS model uses to access a TLS variable,
+    /// i.e., negative for static TLS sections and positive for dynamic TLS sections.
+    ///
+    /// Returns an error if the range to be written is out of bounds of this image,
+    /// overlaps the TCB header or the fixed per-task slots after it, or overlaps a poisoned redzone.
+    pub fn write_at(&mut self, offset: isize, bytes: &[u8]) -> Result<(), &'static str> {
+        self.check_redzones(offset, bytes.len())?;
+        let data = self._data.as_mut().ok_or("cannot write to an empty TLS data image")?;
+        let self_ptr_index = self.ptr - data.as_slice().as_ptr() as usize;
+        let start = self_ptr_index.checked_add_signed(offset).ok_or("TLS offset is out of bounds")?;
+        let end = start.checked_add(bytes.len()).ok_or("TLS offset is out of bounds")?;
+        if start < self_ptr_index + RESERVED_AREA_SIZE && end > self_ptr_index {
+            return Err("cannot overwrite the TCB header or fixed per-task TLS slots");
+        }
+        data.as_mut_slice()
+            .get_mut(start .. end)
+            .ok_or("TLS offset and length are out of bounds of the TLS data image")?
+            .copy_from_slice(bytes);
+        Ok(())
+    }
+
+    /// Returns the `len` bytes at the given `offset` from the TLS self pointer in this TLS data image,
+    /// e.g., to read the current value of a TLS variable.
+    ///
+    /// The `offset` is interpreted in the same way as for [`write_at()`](Self::write_at).
+    /// The returned bytes may be concurrently modified by the task that owns this image.
+    ///
+    /// Returns an error if the range to be read is out of bounds of this image or overlaps a poisoned redzone.
+    pub fn read_at(&self, offset: isize, len: usize) -> Result<&[u8], &'static str> {
+        self.check_redzones(offset, len)?;
+        let data = self._data.as_ref().ok_or("cannot read from an empty TLS data image")?;
+        let self_ptr_index = self.ptr - data.as_slice().as_ptr() as usize;
+        let start = self_ptr_index.checked_add_signed(offset).ok_or("TLS offset is out of bounds")?;
+        let end = start.checked_add(len).ok_or("TLS offset is out of bounds")?;
+        data.as_slice()
+            .get(start .. end)
+            .ok_or("TLS offset and length are out of bounds of the TLS data image")
+    }
+
+    /// Returns a snapshot of the profiling counters in this TLS data image.
+    ///
+    /// The counters are read without synchronization, because they may be
+    /// concurrently updated by the task that owns this image;
+    /// thus, the returned values may be slightly out of date.
+    ///
+    /// Returns `None` if this image is empty.
+    pub fn counters(&self) -> Option<[u64; NUM_TLS_COUNTERS]> {
+        if self.ptr == 0 {
+            return None;
+        }
+        let counters = (self.ptr + TLS_COUNTERS_OFFSET) as *const u64;
+        let mut values = [0; NUM_TLS_COUNTERS];
+        for (i, value) in values.iter_mut().enumerate() {
+            // SAFE: every non-empty TLS data image reserves the block of counters at this offset.
+            *value = unsafe { counters.add(i).read_volatile() };
+        }
+        Some(values)
+    }
+
+    /// Sets the current CPU's TLS register to point to this TLS data image,
+    /// skipping the register write if it already points to this image.
+    ///
+    /// The last value written to each CPU's TLS register is cached, which avoids
+    /// a relatively expensive register write when switching back to the same task.
+    /// The given `cpu_id` must be the ID of the current CPU.
+    ///
+    /// If the TLS register may have been modified without going through this function,
+    /// use [`TlsDataImage::force_set_as_current_tls_base()`] instead.
+    pub fn set_as_current_tls_base(&self, cpu_id: u8) {
+        if CURRENT_TLS_BASES[cpu_id as usize].load(Ordering::Relaxed) != self.ptr {
+            self.force_set_as_current_tls_base(Some(cpu_id));
+        }
+    }
+
+    /// Sets the current CPU's TLS register to point to this TLS data image,
+    /// regardless of its current value.
     ///
     /// On x86_64, this writes to the `FsBase` MSR.
     /// On ARMv8, this writes to `TPIDR_EL0`.
-    pub fn set_as_current_tls_base(&self) {
-        #[cfg(target_arch = "x86_64")]
-        FsBase::write(VirtAddr::new_truncate(self.ptr as u64));
+    ///
+    /// If `cpu_id` is `Some`, it must be the ID of the current CPU,
+    /// whose cached TLS register value will be updated.
+    /// It can be `None` early in the boot process, before CPU IDs are available,
+    /// in which case the next TLS register update on this CPU must also be forced.
+    pub fn force_set_as_current_tls_base(&self, cpu_id: Option<u8>) {
+        write_tls_register(self.ptr, cpu_id);
+    }
+
+    /// Prepares this TLS data image for its task to run on another CPU
+    /// after having previously run on `from_cpu`.
+    ///
+    /// This should be invoked by the scheduler whenever it migrates a task across CPUs.
+    /// The cached TLS register value of `from_cpu` is forgotten if it refers to this image,
+    /// such that the thread pointer is re-installed the next time this task runs there,
+    /// even if a different image later reuses this image's address.
+    pub fn on_migrate(&self, from_cpu: u8) {
+        // This may race with `from_cpu` updating its own entry; see `CURRENT_TLS_BASES` for why relaxed is enough.
+        let _ = CURRENT_TLS_BASES[from_cpu as usize].compare_exchange(
+            self.ptr, 0, Ordering::Relaxed, Ordering::Relaxed,
+        );
+    }
+
+    /// Moves this TLS data image onto the memory node local to `to_cpu`
+    /// if [re-homing is enabled](set_rehome_on_migration),
+    /// preserving the current values of all TLS variables.
+    ///
+    /// Returns `true` if this image was moved, in which case its self pointer has changed.
+    ///
+    /// # Safety
+    /// The task that owns this TLS data image must never have run,
+    /// as a task that has run may hold references into its TLS data image at any point,
+    /// e.g., if it was preempted in the middle of a heap allocation.
+    /// It must not start running until this returns.
+    pub unsafe fn rehome(&mut self, to_cpu: u8) -> bool {
+        if !REHOME_ON_MIGRATION.load(Ordering::Relaxed) {
+            return false;
+        }
+        let old_ptr = self.ptr;
+        // SAFETY: ensured by the caller.
+        unsafe { self.reallocate(TlsAllocHint::Cpu(to_cpu)); }
+        self.ptr != old_ptr
+    }
+}
+
+/// Whether TLS data images are moved onto the memory node local to the destination CPU
+/// when their task migrates across CPUs.
+static REHOME_ON_MIGRATION: AtomicBool = AtomicBool::new(false);
+
+/// Sets whether [`TlsDataImage::rehome()`] moves a TLS data image onto
+/// the memory node local to the CPU that its task is migrating to.
+///
+/// Only the images of tasks that have never run can be re-homed, see [`TlsDataImage::rehome()`].
+/// This is disabled by default, as re-homing requires copying the entire image.
+/// It has no effect unless a [`NodeAwareAllocator`] has been registered.
+pub fn set_rehome_on_migration(enabled: bool) {
+    REHOME_ON_MIGRATION.store(enabled, Ordering::Relaxed);
+}
+
+/// Creates a minimal TLS data image for a CPU that is booting up
+/// and installs it into that CPU's TLS register.
+///
+/// This must be used by the boot code on every CPU before any TLS variables are accessed,
+/// i.e., before that CPU's bootstrap task (and its own TLS area) exists.
+/// The returned image must be kept alive until the bootstrap task's TLS area has been installed.
+///
+/// ## Arguments
+/// * `total_static_size`: the total size of all static TLS sections in the base kernel image,
+///    which is the offset of the TLS self pointer from the start of the image.
+/// * `tdata_template`: the initial contents of the static TLS sections, which are placed
+///    at the start of the image; any remaining bytes up to `total_static_size` are zeroed.
+/// * `cpu_id`: the ID of the current CPU, or `None` if CPU IDs are not yet available.
+///
+/// The image uses the default TCB layout and contains no dynamic TLS sections.
+pub fn init_bootstrap_tls(
+    total_static_size: usize,
+    tdata_template: &[u8],
+    cpu_id: Option<u8>,
+) -> Result<TlsDataImage, &'static str> {
+    if tdata_template.len() > total_static_size {
+        return Err("bootstrap TLS template is larger than the total static TLS size");
+    }
+    let tcb_layout = TcbLayout::SelfPointerOnly;
+    let mut data = vec![0u8; total_static_size + RESERVED_AREA_SIZE];
+    data[.. tdata_template.len()].copy_from_slice(tdata_template);
+    let hint = cpu_id.map_or(TlsAllocHint::Any, TlsAllocHint::Cpu);
+    let mut storage = ImageStorage::new_copy(&data, hint);
+    let ptr = storage.write_self_ptr(total_static_size, tcb_layout)
+        .ok_or("BUG: bootstrap TLS self pointer was out of bounds")?;
+    let image = TlsDataImage {
+        _data: Some(storage),
+        ptr,
+        tcb_layout,
+        layout: Arc::new(TlsLayout::empty()),
+    };
+    image.force_set_as_current_tls_base(cpu_id);
+    Ok(image)
+}
+
+/// Writes the given `value` into the current CPU's TLS register
+/// and records it in the per-CPU cache if `cpu_id` is `Some`.
+fn write_tls_register(value: usize, cpu_id: Option<u8>) {
+    #[cfg(target_arch = "x86_64")]
+    FsBase::write(VirtAddr::new_truncate(value as u64));
+
+    #[cfg(target_arch = "aarch64")]
+    TPIDR_EL0.set(value as u64);
+
+    if let Some(cpu_id) = cpu_id {
+        CURRENT_TLS_BASES[cpu_id as usize].store(value, Ordering::Relaxed);
+    }
+}
+
+/// Reads the current value of the current CPU's TLS register.
+fn read_tls_register() -> usize {
+    #[cfg(target_arch = "x86_64")]
+    let value = FsBase::read().as_u64() as usize;
+    #[cfg(target_arch = "aarch64")]
+    let value = TPIDR_EL0.get() as usize;
+    value
+}
+
+/// The maximum number of CPUs whose TLS register values are cached.
+const MAX_CPUS: usize = u8::MAX as usize + 1;
+
+/// The last value written to each CPU's TLS register, indexed by CPU ID.
+///
+/// Each entry is read and set by its own CPU, but another CPU may clear it
+/// via the compare-exchange in [`TlsDataImage::on_migrate()`].
+/// Relaxed ordering suffices for both, because an entry is only ever compared against
+/// or written into its own CPU's TLS register, never dereferenced by another CPU,
+/// and a cross-CPU clear only stores `0`, so no other memory must become visible along with it.
+/// All accesses to an entry are still totally ordered, so a clear cannot overwrite a newer value
+/// that the owning CPU stored; at worst, it forces that CPU's next TLS register write.
+static CURRENT_TLS_BASES: [AtomicUsize; MAX_CPUS] = {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const ZERO: AtomicUsize = AtomicUsize::new(0);
+    [ZERO; MAX_CPUS]
+};
+
+/// The memory that backs a [`TlsDataImage`].
+enum ImageStorage {
+    /// Memory allocated from the regular heap.
+    Heap(Box<[u8]>),
+    /// Memory allocated on a specific memory node by the registered [`NodeAwareAllocator`].
+    Node {
+        ptr:       NonNull<u8>,
+        len:       usize,
+        node:      usize,
+        allocator: &'static dyn NodeAwareAllocator,
+    },
+}
+// SAFETY: the `Node` variant exclusively owns its allocation, just like a `Box<[u8]>`.
+unsafe impl Send for ImageStorage { }
+unsafe impl Sync for ImageStorage { }
+
+impl ImageStorage {
+    /// Allocates new storage according to the given `hint` and copies `src` into it.
+    ///
+    /// Falls back to the regular heap if the `hint` cannot be satisfied.
+    /// If memory cannot be allocated even after reclaiming memory, this invokes
+    /// the global allocation error handler; see [`Self::try_new_copy()`].
+    fn new_copy(src: &[u8], hint: TlsAllocHint) -> ImageStorage {
+        Self::try_new_copy(src, hint).unwrap_or_else(|_| {
+            memory_pressure::image_allocated(src.len());
+            ImageStorage::Heap(src.into())
+        })
+    }
+
+    /// Like [`Self::new_copy()`], but returns an error if memory cannot be allocated
+    /// even after asking the memory-pressure subsystem to reclaim memory.
+    fn try_new_copy(src: &[u8], hint: TlsAllocHint) -> Result<ImageStorage, &'static str> {
+        let mut attempts = 0;
+        loop {
+            if let Some(storage) = Self::new_copy_on_node(src, hint).or_else(|| Self::try_new_copy_on_heap(src)) {
+                memory_pressure::image_allocated(src.len());
+                return Ok(storage);
+            }
+            attempts += 1;
+            if attempts > MAX_RECLAIM_ATTEMPTS || !memory_pressure::reclaim(src.len()) {
+                return Err("out of memory: couldn't allocate TLS data image");
+            }
+        }
+    }
+
+    fn try_new_copy_on_heap(src: &[u8]) -> Option<ImageStorage> {
+        let mut data = Vec::new();
+        data.try_reserve_exact(src.len()).ok()?;
+        data.extend_from_slice(src);
+        Some(ImageStorage::Heap(data.into_boxed_slice()))
+    }
+
+    fn new_copy_on_node(src: &[u8], hint: TlsAllocHint) -> Option<ImageStorage> {
+        let allocator = *NODE_AWARE_ALLOCATOR.get()?;
+        let node = hint.node(allocator)?;
+        let layout = Layout::from_size_align(src.len(), POINTER_SIZE).ok()?;
+        let ptr = allocator.allocate_on_node(layout, node)?;
+        // SAFETY: the allocator returned a new allocation of `src.len()` bytes,
+        //         which therefore cannot overlap with `src`.
+        unsafe { core::ptr::copy_nonoverlapping(src.as_ptr(), ptr.as_ptr(), src.len()); }
+        Some(ImageStorage::Node { ptr, len: src.len(), node, allocator })
+    }
+
+    fn node(&self) -> Option<usize> {
+        match self {
+            Self::Heap(_) => None,
+            Self::Node { node, .. } => Some(*node),
+        }
+    }
+
+    fn as_slice(&self) -> &[u8] {
+        match self {
+            Self::Heap(data) => data,
+            // SAFETY: `ptr` points to an owned allocation of `len` initialized bytes.
+            Self::Node { ptr, len, .. } => unsafe { core::slice::from_raw_parts(ptr.as_ptr(), *len) },
+        }
+    }
+
+    fn as_mut_slice(&mut self) -> &mut [u8] {
+        match self {
+            Self::Heap(data) => data,
+            // SAFETY: `ptr` points to an owned allocation of `len` initialized bytes.
+            Self::Node { ptr, len, .. } => unsafe { core::slice::from_raw_parts_mut(ptr.as_ptr(), *len) },
+        }
+    }
+
+    /// Writes the TLS self pointer at the given offset into this storage,
+    /// along with all other fields of the given TCB header that point to itself,
+    /// returning its value, or `None` if the offset was out of bounds.
+    fn write_self_ptr(&mut self, self_ptr_offset: usize, tcb_layout: TcbLayout) -> Option<usize> {
+        let tcb = self.as_mut_slice().get_mut(self_ptr_offset .. (self_ptr_offset + tcb_layout.size()))?;
+        let tls_self_ptr_value = tcb.as_ptr() as usize;
+        for &offset in tcb_layout.self_pointer_offsets() {
+            tcb[offset .. offset + POINTER_SIZE].copy_from_slice(&tls_self_ptr_value.to_ne_bytes());
+        }
+        Some(tls_self_ptr_value)
+    }
+
+    /// Writes fresh stack guard and pointer guard values into the TCB header
+    /// at the given offset, if the given TCB layout contains them.
+    fn write_tcb_guards(&mut self, self_ptr_offset: usize, tcb_layout: TcbLayout) {
+        let new_guard = || TCB_GUARD_SOURCE.get().map(|source| source()).unwrap_or(0);
+        let tcb = &mut self.as_mut_slice()[self_ptr_offset ..];
+        if let Some(offset) = tcb_layout.stack_guard_offset() {
+            // Like glibc and musl, we zero the lowest byte of the canary
+            // to prevent it from being leaked or overwritten by string functions.
+            let canary = new_guard() & !0xFF;
+            tcb[offset .. offset + POINTER_SIZE].copy_from_slice(&canary.to_ne_bytes());
+        }
+        if let Some(offset) = tcb_layout.pointer_guard_offset() {
+            tcb[offset .. offset + POINTER_SIZE].copy_from_slice(&new_guard().to_ne_bytes());
+        }
+    }
+}
+
+impl Drop for ImageStorage {
+    fn drop(&mut self) {
+        memory_pressure::image_freed(self.as_slice().len());
+        if let Self::Node { ptr, len, node, allocator } = self {
+            // SAFETY: this layout is identical to the one used in `new_copy_on_node()`.
+            unsafe {
+                let layout = Layout::from_size_align_unchecked(*len, POINTER_SIZE);
+                allocator.deallocate(*ptr, layout, *node);
+            }
+        }
+    }
+}
 
-        #[cfg(target_arch = "aarch64")]
-        TPIDR_EL0.set(self.ptr as u64);
+impl core::fmt::Debug for ImageStorage {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("ImageStorage")
+            .field("node", &self.node())
+            .field("len", &self.as_slice().len())
+            .finish_non_exhaustive()
     }
 }
 
@@ -302,6 +1994,25 @@ enum CacheStatus {
     Fresh,
     /// The cached data image is out of date and needs to be regenerated.
     Invalidated,
+    /// Only the given byte ranges of the cached data image are out of date
+    /// and need to be re-read from their TLS sections.
+    PartiallyDirty(Vec<Range<usize>>),
+}
+
+/// A TLS section that was moved to a different offset, either a dynamic TLS section
+/// by [`TlsInitializer::merge()`] or a static TLS section by [`TlsInitializer::replace_static_tls_sections()`].
+///
+/// The offsets of static TLS sections are negative, and are thus represented by their two's complement.
+#[derive(Debug, Clone)]
+pub struct TlsSectionRemapping {
+    /// The section that is still at the `old_offset`, e.g., in the merged-in `TlsInitializer`.
+    pub old_section: StrongSectionRef,
+    /// The offset of the `old_section` from the TLS self pointer.
+    pub old_offset: usize,
+    /// The section that replaces the `old_section` at the `new_offset`.
+    pub new_section: StrongSectionRef,
+    /// The offset of the `new_section` from the TLS self pointer.
+    pub new_offset: usize,
 }
 
 /// A wrapper around a `StrongSectionRef` that implements `PartialEq` and `Eq` 
@@ -0,0 +1,99 @@
+//! Integration of TLS data image allocation with a memory-pressure subsystem.
+//!
+//! This crate keeps track of how much memory is occupied by live TLS data images,
+//! which can be queried via [`tls_image_usage()`].
+//! A memory-pressure subsystem (or memory accountant) can register [`MemoryPressureHooks`]
+//! in order to be notified of changes to that occupancy and to be asked to reclaim memory,
+//! e.g., by releasing pooled or recycled TLS images, when a new TLS data image cannot be allocated.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::Once;
+
+/// The maximum number of times that a failed TLS data image allocation is retried
+/// after reclaiming memory.
+pub const MAX_RECLAIM_ATTEMPTS: usize = 3;
+
+/// The number of TLS data images that currently exist.
+static LIVE_IMAGES: AtomicUsize = AtomicUsize::new(0);
+/// The total size in bytes of all TLS data images that currently exist.
+static LIVE_BYTES: AtomicUsize = AtomicUsize::new(0);
+
+/// The occupancy of memory by TLS data images.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct TlsImageUsage {
+    /// The number of TLS data images that currently exist.
+    pub images: usize,
+    /// The total size in bytes of all TLS data images that currently exist.
+    pub bytes: usize,
+}
+
+/// Callbacks into a memory-pressure subsystem, which are invoked when allocating
+/// and freeing the backing memory of TLS data images.
+#[derive(Clone, Copy)]
+pub struct MemoryPressureHooks {
+    /// Invoked when a TLS data image of the given size in bytes could not be allocated.
+    ///
+    /// This should free up memory, e.g., by releasing pooled or recycled TLS images,
+    /// and return the number of bytes that were freed.
+    /// The allocation is retried as long as this frees some memory,
+    /// up to [`MAX_RECLAIM_ATTEMPTS`] times.
+    pub reclaim: fn(usize) -> usize,
+    /// Invoked with the new occupancy whenever a TLS data image is allocated or freed.
+    ///
+    /// This must not allocate or free TLS data images.
+    pub report_usage: fn(TlsImageUsage),
+}
+
+/// The callbacks registered by the memory-pressure subsystem.
+static MEMORY_PRESSURE_HOOKS: Once<MemoryPressureHooks> = Once::new();
+
+/// Registers the callbacks that are invoked upon TLS data image allocation failures
+/// and changes to the occupancy of TLS data images.
+///
+/// The current occupancy is reported immediately.
+/// Returns an error if hooks were already registered.
+pub fn set_memory_pressure_hooks(hooks: MemoryPressureHooks) -> Result<(), &'static str> {
+    let mut newly_set = false;
+    MEMORY_PRESSURE_HOOKS.call_once(|| { newly_set = true; hooks });
+    if newly_set {
+        (hooks.report_usage)(tls_image_usage());
+        Ok(())
+    } else {
+        Err("memory pressure hooks were already registered")
+    }
+}
+
+/// Returns the current occupancy of memory by TLS data images.
+pub fn tls_image_usage() -> TlsImageUsage {
+    TlsImageUsage {
+        images: LIVE_IMAGES.load(Ordering::Relaxed),
+        bytes: LIVE_BYTES.load(Ordering::Relaxed),
+    }
+}
+
+/// Records that a TLS data image of `size` bytes was allocated.
+pub(crate) fn image_allocated(size: usize) {
+    LIVE_IMAGES.fetch_add(1, Ordering::Relaxed);
+    LIVE_BYTES.fetch_add(size, Ordering::Relaxed);
+    report_usage();
+}
+
+/// Records that a TLS data image of `size` bytes was freed.
+pub(crate) fn image_freed(size: usize) {
+    LIVE_IMAGES.fetch_sub(1, Ordering::Relaxed);
+    LIVE_BYTES.fetch_sub(size, Ordering::Relaxed);
+    report_usage();
+}
+
+fn report_usage() {
+    if let Some(hooks) = MEMORY_PRESSURE_HOOKS.get() {
+        (hooks.report_usage)(tls_image_usage());
+    }
+}
+
+/// Asks the memory-pressure subsystem to reclaim memory for a TLS data image of `size` bytes.
+///
+/// Returns `true` if any memory was reclaimed, in which case the allocation should be retried.
+pub(crate) fn reclaim(size: usize) -> bool {
+    MEMORY_PRESSURE_HOOKS.get().map_or(false, |hooks| (hooks.reclaim)(size) > 0)
+}
@@ -0,0 +1,86 @@
+//! Hardened access to per-CPU state that doesn't depend on the TLS register.
+//!
+//! Fault handlers (e.g., double faults) may run while the TLS register holds a clobbered value,
+//! so they cannot use TLS variables or anything derived from the current task to find out
+//! which CPU they are running on. Instead, each CPU's ID is stored in a dedicated register
+//! that is never used for thread pointers: `IA32_TSC_AUX` on x86_64 (read via `rdtscp`)
+//! and `TPIDR_EL1` on aarch64.
+//! That CPU ID can then be used to index fixed-address per-CPU arrays,
+//! such as this crate's cache of each CPU's last-written TLS register value,
+//! which allows a fault handler to validate and repair the TLS register.
+
+use core::sync::atomic::Ordering;
+use super::{CURRENT_TLS_BASES, read_tls_register, write_tls_register};
+
+/// A marker stored alongside the CPU ID, which distinguishes an initialized
+/// hardened CPU ID from a register that was never written or was clobbered.
+const CPU_ID_MAGIC: u32 = 0x7C50_0000;
+/// The bits of the hardened CPU ID register that hold the marker.
+const CPU_ID_MAGIC_MASK: u32 = 0xFFFF_FF00;
+
+/// Records the given `cpu_id` as the current CPU's hardened CPU ID.
+///
+/// This must be invoked once on every CPU while it boots up.
+pub fn init_hardened_cpu_id(cpu_id: u8) {
+    let value = CPU_ID_MAGIC | cpu_id as u32;
+    #[cfg(target_arch = "x86_64")] {
+        const IA32_TSC_AUX: u32 = 0xC000_0103;
+        // SAFETY: `IA32_TSC_AUX` is only used to identify the current CPU.
+        unsafe { x86_64::registers::model_specific::Msr::new(IA32_TSC_AUX).write(value as u64); }
+    }
+    #[cfg(target_arch = "aarch64")]
+    // SAFETY: `TPIDR_EL1` is reserved for the kernel and otherwise unused in Theseus.
+    unsafe { core::arch::asm!("msr tpidr_el1, {}", in(reg) value as u64, options(nomem, nostack, preserves_flags)); }
+}
+
+/// Returns the ID of the current CPU without accessing the TLS register or memory,
+/// or `None` if [`init_hardened_cpu_id()`] hasn't been invoked on this CPU.
+pub fn hardened_cpu_id() -> Option<u8> {
+    let value: u32;
+    #[cfg(target_arch = "x86_64")]
+    // SAFETY: `rdtscp` only reads the timestamp counter and `IA32_TSC_AUX` into registers.
+    unsafe { core::arch::asm!("rdtscp", out("ecx") value, out("eax") _, out("edx") _, options(nomem, nostack, preserves_flags)); }
+    #[cfg(target_arch = "aarch64")] {
+        let raw: u64;
+        // SAFETY: reading `TPIDR_EL1` has no side effects.
+        unsafe { core::arch::asm!("mrs {}, tpidr_el1", out(reg) raw, options(nomem, nostack, preserves_flags)); }
+        value = raw as u32;
+    }
+    (value & CPU_ID_MAGIC_MASK == CPU_ID_MAGIC).then_some(value as u8)
+}
+
+/// Returns the current value of the TLS register if it matches the value that was
+/// last written to it via this crate, i.e., if it can be trusted to point to a valid TLS area.
+///
+/// Returns `None` if the TLS register has been clobbered or the current CPU is unknown.
+///
+/// This is safe to invoke from any context, including NMI and double fault handlers.
+/// Note that if an NMI interrupts a context switch in between the TLS register being written
+/// and its cached value being updated, this returns `None` even though the register is valid.
+pub fn verified_tls_base() -> Option<usize> {
+    let cpu_id = hardened_cpu_id()?;
+    let tls_base = read_tls_register();
+    (tls_base != 0 && tls_base == CURRENT_TLS_BASES[cpu_id as usize].load(Ordering::Relaxed))
+        .then_some(tls_base)
+}
+
+/// Restores the TLS register to the value that was last written to it via this crate,
+/// if it has been clobbered.
+///
+/// Returns `Ok(true)` if the TLS register was repaired, or `Ok(false)` if it was already valid.
+///
+/// This must only be invoked from fault handlers that will not return to
+/// the interrupted context, e.g., double fault handlers, because the interrupted
+/// context may be in the middle of intentionally changing the TLS register.
+pub fn repair_tls_register() -> Result<bool, &'static str> {
+    let cpu_id = hardened_cpu_id().ok_or("the current CPU's hardened CPU ID wasn't initialized")?;
+    if verified_tls_base().is_some() {
+        return Ok(false);
+    }
+    let last_written = CURRENT_TLS_BASES[cpu_id as usize].load(Ordering::Relaxed);
+    if last_written == 0 {
+        return Err("the current CPU's TLS register was never written");
+    }
+    write_tls_register(last_written, Some(cpu_id));
+    Ok(true)
+}
@@ -0,0 +1,173 @@
+//! Poisoned redzones between dynamic TLS sections, which catch TLS variables that overflow their section.
+//!
+//! Once enabled via [`TlsInitializer::set_redzones()`], every dynamic TLS section is placed
+//! at least [`TLS_REDZONE_SIZE`] bytes away from its neighbors and from the fixed per-task slots.
+//! All bytes in the dynamic part of a TLS data image that don't belong to a section,
+//! i.e., the redzones and any alignment padding, are filled with [`TLS_POISON_BYTE`]
+//! and marked in a shadow bitmap that is shared by all images generated from the same layout.
+//!
+//! The shadow bitmap is checked by the slow-path accessors [`TlsDataImage::read_at()`]
+//! and [`TlsDataImage::write_at()`], which reject accesses that touch a poisoned byte.
+//! Overflows by code that accesses TLS variables directly are caught by [`TlsDataImage::verify_redzones()`],
+//! which checks that every poisoned byte still holds the poison value
+//! and attributes a corrupted byte to the section right before it.
+
+use alloc::{string::String, vec::Vec};
+use core::{fmt, ops::Range};
+use super::{TlsDataImage, TlsInitializer};
+
+/// The minimum number of poisoned bytes between two dynamic TLS sections when redzones are enabled.
+pub const TLS_REDZONE_SIZE: usize = 16;
+
+/// The value of every poisoned byte in a TLS data image.
+pub const TLS_POISON_BYTE: u8 = 0xFC;
+
+/// The shadow bitmap of a TLS layout, in which each bit denotes whether a byte
+/// in the dynamic part of the TLS data image is poisoned.
+#[derive(Debug)]
+pub(crate) struct TlsShadow {
+    /// The range of offsets from the TLS self pointer that are covered by the bitmap.
+    covered: Range<usize>,
+    bits: Vec<u64>,
+}
+
+impl TlsShadow {
+    /// Returns whether the byte at the given offset from the TLS self pointer is poisoned.
+    fn is_poisoned(&self, offset: usize) -> bool {
+        if !self.covered.contains(&offset) {
+            return false;
+        }
+        let index = offset - self.covered.start;
+        self.bits[index / 64] & (1 << (index % 64)) != 0
+    }
+
+    /// Returns the first poisoned offset in the given range of offsets from the TLS self pointer, if any.
+    pub(crate) fn first_poisoned_in(&self, range: Range<isize>) -> Option<isize> {
+        let start = range.start.max(self.covered.start as isize);
+        let end = range.end.min(self.covered.end as isize);
+        (start .. end).find(|&offset| self.is_poisoned(offset as usize))
+    }
+
+    /// Returns an iterator over every poisoned offset from the TLS self pointer.
+    fn poisoned_offsets(&self) -> impl Iterator<Item = usize> + '_ {
+        self.covered.clone().filter(|&offset| self.is_poisoned(offset))
+    }
+
+    /// Fills every poisoned byte of the given data image with [`TLS_POISON_BYTE`].
+    pub(crate) fn poison(&self, data: &mut [u8], self_ptr_index: usize) {
+        for offset in self.poisoned_offsets() {
+            data[self_ptr_index + offset] = TLS_POISON_BYTE;
+        }
+    }
+}
+
+/// A poisoned byte in a TLS data image that no longer holds the poison value,
+/// which indicates that a TLS variable overflowed its section.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TlsRedzoneViolation {
+    /// The offset of the corrupted byte from the TLS self pointer.
+    pub offset: isize,
+    /// The value of the corrupted byte.
+    pub value: u8,
+    /// The name of the closest TLS section before the corrupted byte,
+    /// which is the most likely culprit, or `None` if there is no such section.
+    pub preceding_section: Option<String>,
+    /// The number of bytes between the end of the `preceding_section` and the corrupted byte.
+    pub distance: usize,
+}
+
+impl fmt::Display for TlsRedzoneViolation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "TLS redzone at offset {:#X} was overwritten with {:#04X}", self.offset, self.value)?;
+        match &self.preceding_section {
+            Some(name) => write!(f, ", {} bytes past the end of TLS section {:?}", self.distance, name),
+            None => Ok(()),
+        }
+    }
+}
+
+impl TlsInitializer {
+    /// Enables or disables poisoned redzones between the dynamic TLS sections added from now on.
+    ///
+    /// See the [`redzone`](self) module for more.
+    /// This must be set before any dynamic TLS sections have been added.
+    pub fn set_redzones(&mut self, enabled: bool) -> Result<(), &'static str> {
+        if enabled == self.redzones {
+            return Ok(());
+        }
+        if self.area.end_of_dynamic_sections() != 0 {
+            return Err("cannot enable or disable TLS redzones after dynamic TLS sections have been added");
+        }
+        self.redzones = enabled;
+        self.invalidate();
+        Ok(())
+    }
+
+    /// Returns whether dynamic TLS sections are separated by poisoned redzones.
+    pub fn has_redzones(&self) -> bool {
+        self.redzones
+    }
+
+    /// Returns the number of bytes to keep free around each dynamic TLS section.
+    pub(crate) fn redzone_size(&self) -> usize {
+        if self.redzones { TLS_REDZONE_SIZE } else { 0 }
+    }
+
+    /// Returns the shadow bitmap of the current TLS layout, or `None` if redzones aren't enabled.
+    pub(crate) fn shadow(&self) -> Option<TlsShadow> {
+        if !self.redzones {
+            return None;
+        }
+        let covered = self.area.reserved_size() .. self.area.end_of_dynamic_sections().max(self.area.reserved_size());
+        let len = covered.len();
+        let mut bits = vec![!0u64; len.div_ceil(64)];
+        for (range, _) in self.area.dynamic_sections().iter() {
+            for offset in range.clone() {
+                let index = offset - covered.start;
+                bits[index / 64] &= !(1 << (index % 64));
+            }
+        }
+        Some(TlsShadow { covered, bits })
+    }
+}
+
+impl TlsDataImage {
+    /// Returns an error if the `len` bytes at the given `offset` from the TLS self pointer
+    /// overlap a poisoned redzone.
+    pub(crate) fn check_redzones(&self, offset: isize, len: usize) -> Result<(), &'static str> {
+        let Some(shadow) = self.layout.shadow.as_ref() else { return Ok(()) };
+        let end = isize::try_from(len).ok()
+            .and_then(|len| offset.checked_add(len))
+            .ok_or("TLS offset is out of bounds")?;
+        match shadow.first_poisoned_in(offset .. end) {
+            Some(_) => Err("TLS access overlaps a poisoned redzone"),
+            None => Ok(()),
+        }
+    }
+
+    /// Checks that every poisoned redzone byte in this image still holds the poison value.
+    ///
+    /// Returns the first corrupted byte, attributed to the closest TLS section before it.
+    /// This always succeeds if the image was generated without redzones.
+    ///
+    /// The image may be concurrently modified by the task that owns it,
+    /// so a corruption may be detected later than it occurs.
+    pub fn verify_redzones(&self) -> Result<(), TlsRedzoneViolation> {
+        let (Some(shadow), Some(data)) = (self.layout.shadow.as_ref(), self._data.as_ref()) else {
+            return Ok(());
+        };
+        let data = data.as_slice();
+        let self_ptr_index = self.ptr - data.as_ptr() as usize;
+        let corrupted = shadow.poisoned_offsets()
+            .find(|&offset| data[self_ptr_index + offset] != TLS_POISON_BYTE);
+        let Some(offset) = corrupted else { return Ok(()) };
+        let offset = offset as isize;
+        let preceding = self.layout.section_before(offset);
+        Err(TlsRedzoneViolation {
+            offset,
+            value: data[self_ptr_index + offset as usize],
+            distance: preceding.map_or(0, |(_, end)| (offset - end) as usize),
+            preceding_section: preceding.map(|(name, _)| String::from(name)),
+        })
+    }
+}
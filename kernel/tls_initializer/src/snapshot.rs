@@ -0,0 +1,357 @@
+//! Checkpointing and restoring the contents of TLS data images.
+//!
+//! A [`TlsSnapshot`] records the current value of every TLS section in a stopped task's
+//! [`TlsDataImage`], along with the generation of the TLS layout it was taken against.
+//! Because crates may be loaded, unloaded, or swapped in between taking and restoring a snapshot,
+//! sections are matched by name rather than by offset when restoring,
+//! falling back to the name without its trailing hash if no section has the exact same name.
+//!
+//! A task's live [`TlsDataImage`] can also be carried over to a new set of TLS sections
+//! without going through a snapshot, via [`TlsInitializer::refresh_image()`],
+//! or to a new set of static TLS sections with different offsets, via [`TlsInitializer::rebase_image()`].
+
+use alloc::{string::String, vec::Vec};
+use crate_metadata::{LoadedSection, StrongSectionRef};
+use super::{TlsAllocHint, TlsDataImage, TlsInitializer, TlsSectionRemapping, TlsShadow, ERRNO_OFFSET, RESERVED_AREA_SIZE};
+
+/// The magic number at the start of a serialized [`TlsSnapshot`].
+const SNAPSHOT_MAGIC: [u8; 4] = *b"TLSS";
+
+/// The set of TLS sections in a TLS data image.
+#[derive(Debug)]
+pub(crate) struct TlsLayout {
+    generation: u64,
+    sections: Vec<TlsSectionInfo>,
+    /// The shadow bitmap of the poisoned redzones, if redzones are enabled.
+    pub(crate) shadow: Option<TlsShadow>,
+}
+
+/// The name and location of a single TLS section in a TLS data image.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct TlsSectionInfo {
+    name: String,
+    /// The offset of this section from the TLS self pointer.
+    offset: isize,
+    size: usize,
+}
+
+impl TlsLayout {
+    /// Returns a layout without any TLS sections.
+    pub(crate) fn empty() -> TlsLayout {
+        TlsLayout { generation: 0, sections: Vec::new(), shadow: None }
+    }
+
+    /// Returns the name and end offset of the TLS section that ends closest before the given offset, if any.
+    pub(crate) fn section_before(&self, offset: isize) -> Option<(&str, isize)> {
+        self.sections.iter()
+            .map(|sec| (sec.name.as_str(), sec.offset + sec.size as isize))
+            .filter(|&(_, end)| end <= offset)
+            .max_by_key(|&(_, end)| end)
+    }
+}
+
+impl TlsInitializer {
+    /// Returns a description of the TLS sections currently in this `TlsInitializer`.
+    pub(crate) fn current_layout(&self) -> TlsLayout {
+        let pointer_offset = self.area.pointer_offset();
+        let sections = self.area.image_ranges().map(|(image_range, sec)| TlsSectionInfo {
+            name: String::from(sec.name.as_str()),
+            offset: image_range.start as isize - pointer_offset as isize,
+            size: sec.size,
+        });
+        TlsLayout {
+            generation: self.generation,
+            sections: sections.collect(),
+            shadow: self.shadow(),
+        }
+    }
+
+    /// Creates a new TLS data image from the current set of TLS sections,
+    /// in which each section's contents are restored from the matching section in the given `snapshot`.
+    ///
+    /// Sections are matched by their full name, or if there is no such section,
+    /// by their name without the trailing hash, as long as that match is unambiguous.
+    /// Sections that don't exist in the `snapshot` keep their initial values,
+    /// and sections in the `snapshot` that no longer exist are ignored.
+    /// The fixed per-task slots are reset, except for the `errno` value.
+    ///
+    /// Returns an error if a matching section's size has changed.
+    pub fn restore_snapshot(&mut self, snapshot: &TlsSnapshot, hint: TlsAllocHint) -> Result<TlsDataImage, &'static str> {
+        let mut image = self.get_data(hint);
+        if image.ptr == 0 {
+            return Ok(image);
+        }
+        let layout = image.layout.clone();
+        for section in &layout.sections {
+            let Some(saved) = snapshot.find_match(section)? else { continue };
+            image.write_at(section.offset, &saved.1)?;
+        }
+        let data = image._data.as_mut().expect("BUG: non-empty TLS data image had no data");
+        let errno_index = image.ptr - data.as_slice().as_ptr() as usize + ERRNO_OFFSET;
+        data.as_mut_slice()[errno_index .. errno_index + 4].copy_from_slice(&snapshot.errno.to_ne_bytes());
+        Ok(image)
+    }
+
+    /// Creates a new TLS data image from the current set of TLS sections
+    /// that preserves the live contents of the given existing `image`, e.g., after swapping crates.
+    ///
+    /// The static TLS sections, the TCB header, and all fixed per-task slots are copied as-is,
+    /// as is every dynamic TLS section that still exists with the same name, offset, and size.
+    /// For each `(old, new)` pair in `replaced_sections`, the contents of the `old` section
+    /// in the given `image` are copied into the `new` section, even if its offset has changed.
+    /// All other sections, e.g., newly-added ones, keep their initial values.
+    ///
+    /// Returns an error if a replaced section's size has changed, or if the static TLS sections
+    /// or the TCB layout differ from those that the given `image` was generated from.
+    ///
+    /// # Safety
+    /// The task that owns the given `image` must not be running, and must not start running
+    /// until the returned image has replaced it, otherwise its modifications would be lost.
+    pub unsafe fn refresh_image(
+        &mut self,
+        image: &TlsDataImage,
+        replaced_sections: &[(StrongSectionRef, StrongSectionRef)],
+    ) -> Result<TlsDataImage, &'static str> {
+        let hint = image.node().map_or(TlsAllocHint::Any, TlsAllocHint::Node);
+        let mut new_image = self.get_data(hint);
+        let (Some(old_data), Some(new_data)) = (image._data.as_ref(), new_image._data.as_mut()) else {
+            return Ok(new_image);
+        };
+        let old_data = old_data.as_slice();
+        let self_ptr_index = image.ptr - old_data.as_ptr() as usize;
+        if new_image.ptr - new_data.as_slice().as_ptr() as usize != self_ptr_index || new_image.tcb_layout != image.tcb_layout {
+            return Err("static TLS sections or TCB layout differ from those of the refreshed TLS data image");
+        }
+        if replaced_sections.iter().any(|(old, new)| old.size != new.size) {
+            return Err("size of a replaced TLS section has changed");
+        }
+        // Carrying over the contents of an image whose TLS section overflowed would propagate the corruption.
+        if image.verify_redzones().is_err() {
+            return Err("a TLS redzone of the refreshed TLS data image was overwritten");
+        }
+
+        {
+            let new_slice = new_data.as_mut_slice();
+            let fixed = .. (self_ptr_index + RESERVED_AREA_SIZE);
+            new_slice[fixed].copy_from_slice(&old_data[fixed]);
+
+            let mut copy = |old_offset: isize, new_offset: isize, size: usize| {
+                let src = self_ptr_index.checked_add_signed(old_offset)
+                    .and_then(|start| old_data.get(start .. start + size));
+                let dest = self_ptr_index.checked_add_signed(new_offset)
+                    .and_then(|start| new_slice.get_mut(start .. start + size));
+                if let (Some(src), Some(dest)) = (src, dest) {
+                    dest.copy_from_slice(src);
+                }
+            };
+            for section in new_image.layout.sections.iter().filter(|sec| sec.offset >= 0) {
+                if image.layout.sections.contains(section) {
+                    copy(section.offset, section.offset, section.size);
+                }
+            }
+            for (old, new) in replaced_sections {
+                copy(old.virt_addr.value() as isize, new.virt_addr.value() as isize, new.size);
+            }
+        }
+
+        // The copied TCB header still points to the old image.
+        new_image.ptr = new_data.write_self_ptr(self_ptr_index, new_image.tcb_layout)
+            .expect("BUG: offset of TLS self pointer was out of bounds in the refreshed TLS data image");
+        Ok(new_image)
+    }
+
+    /// Creates a new TLS data image from the current set of TLS sections
+    /// that preserves the live contents of the given existing `image`,
+    /// after the static TLS sections have been replaced via [`replace_static_tls_sections()`].
+    ///
+    /// Unlike [`refresh_image()`](Self::refresh_image), the TLS self pointer may be at a different
+    /// offset in the new image than in the given `image`, because the total size of the static TLS sections may differ.
+    /// The TCB header, all fixed per-task slots, and every dynamic TLS section that still exists
+    /// with the same name, offset, and size are copied as-is.
+    /// For each of the `remappings`, the contents of the `old_section` in the given `image`
+    /// are copied into the `new_section`. All other sections keep their initial values.
+    ///
+    /// Returns an error if a remapped section's size has changed,
+    /// or if the TCB layout differs from the one that the given `image` was generated from.
+    ///
+    /// [`replace_static_tls_sections()`]: Self::replace_static_tls_sections
+    ///
+    /// # Safety
+    /// The task that owns the given `image` must not be running, and must not start running
+    /// until the returned image has replaced it, otherwise its modifications would be lost.
+    pub unsafe fn rebase_image(
+        &mut self,
+        image: &TlsDataImage,
+        remappings: &[TlsSectionRemapping],
+    ) -> Result<TlsDataImage, &'static str> {
+        let hint = image.node().map_or(TlsAllocHint::Any, TlsAllocHint::Node);
+        let mut new_image = self.get_data(hint);
+        let (Some(old_data), Some(new_data)) = (image._data.as_ref(), new_image._data.as_mut()) else {
+            return Ok(new_image);
+        };
+        if new_image.tcb_layout != image.tcb_layout {
+            return Err("TCB layout differs from that of the rebased TLS data image");
+        }
+        if remappings.iter().any(|r| r.old_section.size != r.new_section.size) {
+            return Err("size of a remapped TLS section has changed");
+        }
+        let old_data = old_data.as_slice();
+        let old_self_ptr_index = image.ptr - old_data.as_ptr() as usize;
+        let new_self_ptr_index = new_image.ptr - new_data.as_slice().as_ptr() as usize;
+
+        {
+            let new_slice = new_data.as_mut_slice();
+            let mut copy = |old_offset: isize, new_offset: isize, size: usize| {
+                let src = old_self_ptr_index.checked_add_signed(old_offset)
+                    .and_then(|start| old_data.get(start .. start + size));
+                let dest = new_self_ptr_index.checked_add_signed(new_offset)
+                    .and_then(|start| new_slice.get_mut(start .. start + size));
+                if let (Some(src), Some(dest)) = (src, dest) {
+                    dest.copy_from_slice(src);
+                }
+            };
+            copy(0, 0, RESERVED_AREA_SIZE);
+            for section in new_image.layout.sections.iter().filter(|sec| sec.offset >= 0) {
+                if image.layout.sections.contains(section) {
+                    copy(section.offset, section.offset, section.size);
+                }
+            }
+            for r in remappings {
+                copy(r.old_offset as isize, r.new_offset as isize, r.new_section.size);
+            }
+        }
+
+        // The copied TCB header still points to the old image.
+        new_image.ptr = new_data.write_self_ptr(new_self_ptr_index, new_image.tcb_layout)
+            .expect("BUG: offset of TLS self pointer was out of bounds in the rebased TLS data image");
+        Ok(new_image)
+    }
+}
+
+/// A saved copy of the contents of a [`TlsDataImage`],
+/// which can be restored via [`TlsInitializer::restore_snapshot()`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TlsSnapshot {
+    generation: u64,
+    errno: i32,
+    sections: Vec<(TlsSectionInfo, Vec<u8>)>,
+}
+
+impl TlsSnapshot {
+    /// Returns the generation of the TLS layout that this snapshot was taken against.
+    ///
+    /// If this matches the generation of a restored image, the TLS layout is unchanged.
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// Returns the number of TLS sections saved in this snapshot.
+    pub fn num_sections(&self) -> usize {
+        self.sections.len()
+    }
+
+    /// Returns the saved section that matches the given current `section`, if any.
+    fn find_match(&self, section: &TlsSectionInfo) -> Result<Option<&(TlsSectionInfo, Vec<u8>)>, &'static str> {
+        let saved = match self.sections.iter().find(|(info, _)| info.name == section.name) {
+            Some(exact) => Some(exact),
+            None => {
+                let hashless = LoadedSection::section_name_without_hash(&section.name);
+                let mut candidates = self.sections.iter()
+                    .filter(|(info, _)| LoadedSection::section_name_without_hash(&info.name) == hashless);
+                match (candidates.next(), candidates.next()) {
+                    (Some(only), None) => Some(only),
+                    _ => None,
+                }
+            }
+        };
+        match saved {
+            Some((info, _)) if info.size != section.size => Err("size of a TLS section in the snapshot has changed"),
+            other => Ok(other),
+        }
+    }
+
+    /// Serializes this snapshot into a byte vector, which can be parsed via [`from_bytes()`](Self::from_bytes).
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&SNAPSHOT_MAGIC);
+        bytes.extend_from_slice(&self.generation.to_le_bytes());
+        bytes.extend_from_slice(&self.errno.to_le_bytes());
+        bytes.extend_from_slice(&(self.sections.len() as u64).to_le_bytes());
+        for (info, data) in &self.sections {
+            bytes.extend_from_slice(&(info.name.len() as u64).to_le_bytes());
+            bytes.extend_from_slice(info.name.as_bytes());
+            bytes.extend_from_slice(&(info.offset as i64).to_le_bytes());
+            bytes.extend_from_slice(&(data.len() as u64).to_le_bytes());
+            bytes.extend_from_slice(data);
+        }
+        bytes
+    }
+
+    /// Parses a snapshot that was serialized via [`to_bytes()`](Self::to_bytes).
+    pub fn from_bytes(bytes: &[u8]) -> Result<TlsSnapshot, &'static str> {
+        let mut reader = Reader(bytes);
+        if reader.take(SNAPSHOT_MAGIC.len())? != SNAPSHOT_MAGIC {
+            return Err("TLS snapshot has an invalid magic number");
+        }
+        let generation = reader.u64()?;
+        let errno = i32::from_le_bytes(reader.take(4)?.try_into().unwrap());
+        let num_sections = reader.u64()?;
+        let mut sections = Vec::new();
+        for _ in 0..num_sections {
+            let name_len = reader.u64()? as usize;
+            let name = core::str::from_utf8(reader.take(name_len)?)
+                .map_err(|_| "TLS snapshot has an invalid section name")?;
+            let offset = reader.u64()? as i64 as isize;
+            let size = reader.u64()? as usize;
+            let data = reader.take(size)?.to_vec();
+            sections.push((TlsSectionInfo { name: String::from(name), offset, size }, data));
+        }
+        if !reader.0.is_empty() {
+            return Err("TLS snapshot has trailing bytes");
+        }
+        Ok(TlsSnapshot { generation, errno, sections })
+    }
+}
+
+/// A cursor over the bytes of a serialized [`TlsSnapshot`].
+struct Reader<'b>(&'b [u8]);
+impl<'b> Reader<'b> {
+    fn take(&mut self, len: usize) -> Result<&'b [u8], &'static str> {
+        if len > self.0.len() {
+            return Err("TLS snapshot is truncated");
+        }
+        let (taken, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(taken)
+    }
+
+    fn u64(&mut self) -> Result<u64, &'static str> {
+        self.take(8).map(|b| u64::from_le_bytes(b.try_into().unwrap()))
+    }
+}
+
+impl TlsDataImage {
+    /// Saves the current contents of every TLS section in this image into a new [`TlsSnapshot`].
+    ///
+    /// # Safety
+    /// The task that owns this TLS data image must not be running,
+    /// such that its contents aren't concurrently modified.
+    pub unsafe fn snapshot(&self) -> TlsSnapshot {
+        let Some(data) = self._data.as_ref() else {
+            return TlsSnapshot { generation: self.layout.generation, errno: 0, sections: Vec::new() };
+        };
+        let self_ptr_index = self.ptr - data.as_slice().as_ptr() as usize;
+        let sections = self.layout.sections.iter().map(|info| {
+            let start = (self_ptr_index as isize + info.offset) as usize;
+            (info.clone(), data.as_slice()[start .. start + info.size].to_vec())
+        }).collect();
+        let errno_index = self_ptr_index + ERRNO_OFFSET;
+        let errno = i32::from_ne_bytes(data.as_slice()[errno_index .. errno_index + 4].try_into().unwrap());
+        TlsSnapshot { generation: self.layout.generation, errno, sections }
+    }
+
+    /// Returns the generation of the TLS layout that this image was generated from.
+    pub fn layout_generation(&self) -> u64 {
+        self.layout.generation
+    }
+}
@@ -0,0 +1,253 @@
+//! Directories within a tmpfs instance.
+
+use alloc::{collections::BTreeMap, string::String, sync::{Arc, Weak}, vec::Vec};
+use fs_node::{DirRef, Directory, FileOrDir, FsNode, WeakDirRef};
+use spin::Mutex;
+use time::Duration;
+use crate::{file::{TmpFile, TmpFileRef}, timestamp, Metadata, NodeKind, TmpFs};
+
+/// A reference to a directory within a tmpfs instance.
+pub type TmpDirRef = Arc<Mutex<TmpDirectory>>;
+
+/// Returns an error if the given name cannot be used for an entry in a directory.
+pub(crate) fn validate_name(name: &str) -> Result<(), &'static str> {
+    if name.is_empty() || name.contains('/') || name == "." || name == ".." {
+        Err("invalid file name")
+    } else {
+        Ok(())
+    }
+}
+
+/// Returns whether the given `dir` is `descendant` itself or one of its ancestors.
+pub(crate) fn is_ancestor(dir: &DirRef, descendant: &TmpDirRef) -> bool {
+    let target = Arc::as_ptr(dir) as *const u8;
+    let mut current = Some(descendant.clone() as DirRef);
+    while let Some(node) = current {
+        if Arc::as_ptr(&node) as *const u8 == target {
+            return true;
+        }
+        current = node.lock().get_parent_dir();
+    }
+    false
+}
+
+/// An entry within a [`TmpDirectory`].
+///
+/// Nodes created by a tmpfs instance are kept with their concrete types, such that they can be renamed.
+/// Nodes from elsewhere in the VFS that were inserted into a tmpfs directory are kept as is.
+#[derive(Clone)]
+pub(crate) enum Entry {
+    File(TmpFileRef),
+    Dir(TmpDirRef),
+    Foreign(FileOrDir),
+}
+
+impl Entry {
+    fn to_node(&self) -> FileOrDir {
+        match self {
+            Entry::File(file) => FileOrDir::File(file.clone()),
+            Entry::Dir(dir) => FileOrDir::Dir(dir.clone()),
+            Entry::Foreign(node) => node.clone(),
+        }
+    }
+
+    fn is_dir(&self) -> bool {
+        self.to_node().is_dir()
+    }
+
+    /// Returns whether this is a directory that contains no entries.
+    fn is_empty_dir(&self) -> bool {
+        match self {
+            Entry::File(_) => false,
+            Entry::Dir(dir) => dir.lock().entries.is_empty(),
+            Entry::Foreign(FileOrDir::File(_)) => false,
+            Entry::Foreign(FileOrDir::Dir(dir)) => dir.lock().list().is_empty(),
+        }
+    }
+
+    /// Changes the name of this entry's node, which is only possible for nodes created by a tmpfs instance.
+    fn set_name(&self, name: &str) -> Result<(), &'static str> {
+        match self {
+            Entry::File(file) => file.lock().name = String::from(name),
+            Entry::Dir(dir) => dir.lock().name = String::from(name),
+            Entry::Foreign(node) if node.get_name() == name => {}
+            Entry::Foreign(_) => return Err("cannot rename a node that wasn't created in a tmpfs instance"),
+        }
+        Ok(())
+    }
+}
+
+/// A directory within a tmpfs instance.
+pub struct TmpDirectory {
+    fs: Arc<TmpFs>,
+    name: String,
+    parent: WeakDirRef,
+    /// A reference to this directory, which becomes the parent of its entries.
+    self_ref: Weak<Mutex<TmpDirectory>>,
+    entries: BTreeMap<String, Entry>,
+    created: Duration,
+    modified: Duration,
+}
+
+impl TmpDirectory {
+    pub(crate) fn new_ref(fs: Arc<TmpFs>, name: String, parent: WeakDirRef) -> TmpDirRef {
+        let time = timestamp();
+        Arc::new_cyclic(|self_ref| Mutex::new(TmpDirectory {
+            fs,
+            name,
+            parent,
+            self_ref: self_ref.clone(),
+            entries: BTreeMap::new(),
+            created: time,
+            modified: time,
+        }))
+    }
+
+    /// Returns the tmpfs instance that this directory belongs to.
+    pub fn fs(&self) -> &Arc<TmpFs> {
+        &self.fs
+    }
+
+    /// Returns information about this directory.
+    pub fn metadata(&self) -> Metadata {
+        Metadata {
+            kind: NodeKind::Directory,
+            len: self.entries.len(),
+            allocated_bytes: 0,
+            created: self.created,
+            modified: self.modified,
+        }
+    }
+
+    /// Creates a new, empty file with the given `name` in this directory.
+    pub fn create_file(&mut self, name: &str) -> Result<TmpFileRef, &'static str> {
+        self.check_new_name(name)?;
+        let file = TmpFile::new_ref(self.fs.clone(), String::from(name), self.weak_self());
+        self.add(name, Entry::File(file.clone()));
+        Ok(file)
+    }
+
+    /// Creates a new, empty directory with the given `name` in this directory.
+    pub fn create_dir(&mut self, name: &str) -> Result<TmpDirRef, &'static str> {
+        self.check_new_name(name)?;
+        let dir = TmpDirectory::new_ref(self.fs.clone(), String::from(name), self.weak_self());
+        self.add(name, Entry::Dir(dir.clone()));
+        Ok(dir)
+    }
+
+    /// Removes the entry with the given `name` from this directory and returns it.
+    ///
+    /// Unlike [`Directory::remove()`], directories can only be unlinked if they are empty.
+    /// A file's memory is freed once the last reference to it is dropped.
+    pub fn unlink(&mut self, name: &str) -> Result<FileOrDir, &'static str> {
+        let entry = self.entries.get(name).ok_or("file or directory not found")?;
+        if entry.is_dir() && !entry.is_empty_dir() {
+            return Err("directory is not empty");
+        }
+        Ok(self.take_entry(name)?.to_node())
+    }
+
+    /// Renames the entry named `old_name` in this directory to `new_name`.
+    ///
+    /// See [`rename()`](crate::rename) for moving entries between directories.
+    pub fn rename(&mut self, old_name: &str, new_name: &str) -> Result<(), &'static str> {
+        validate_name(new_name)?;
+        if old_name == new_name {
+            return self.entries.get(old_name).map(|_| ()).ok_or("file or directory not found");
+        }
+        let entry = self.take_entry(old_name)?;
+        if let Err((entry, e)) = self.put_entry(entry, new_name) {
+            let _ = self.put_entry(entry, old_name);
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// Removes and returns the entry with the given `name`, detaching it from this directory.
+    pub(crate) fn take_entry(&mut self, name: &str) -> Result<Entry, &'static str> {
+        let entry = self.entries.remove(name).ok_or("file or directory not found")?;
+        entry.to_node().set_parent_dir(Weak::<Mutex<TmpDirectory>>::new());
+        self.modified = timestamp();
+        Ok(entry)
+    }
+
+    /// Adds the given entry to this directory as `name`, replacing any existing entry of the same kind.
+    ///
+    /// Upon failure, the entry is returned along with the error.
+    pub(crate) fn put_entry(&mut self, entry: Entry, name: &str) -> Result<(), (Entry, &'static str)> {
+        if let Some(existing) = self.entries.get(name) {
+            if existing.is_dir() != entry.is_dir() {
+                return Err((entry, "cannot replace a file with a directory or vice versa"));
+            }
+            if existing.is_dir() && !existing.is_empty_dir() {
+                return Err((entry, "directory is not empty"));
+            }
+        }
+        if let Err(e) = entry.set_name(name) {
+            return Err((entry, e));
+        }
+        if let Some(existing) = self.entries.remove(name) {
+            existing.to_node().set_parent_dir(Weak::<Mutex<TmpDirectory>>::new());
+        }
+        entry.to_node().set_parent_dir(self.weak_self());
+        self.add(name, entry);
+        Ok(())
+    }
+
+    fn check_new_name(&self, name: &str) -> Result<(), &'static str> {
+        validate_name(name)?;
+        if self.entries.contains_key(name) {
+            return Err("a file or directory with that name already exists");
+        }
+        Ok(())
+    }
+
+    fn add(&mut self, name: &str, entry: Entry) {
+        self.entries.insert(String::from(name), entry);
+        self.modified = timestamp();
+    }
+
+    fn weak_self(&self) -> WeakDirRef {
+        self.self_ref.clone() as WeakDirRef
+    }
+}
+
+impl Directory for TmpDirectory {
+    fn insert(&mut self, node: FileOrDir) -> Result<Option<FileOrDir>, &'static str> {
+        let name = node.get_name();
+        validate_name(&name)?;
+        let old_entry = self.entries.insert(name, Entry::Foreign(node));
+        self.modified = timestamp();
+        Ok(old_entry.map(|old_entry| {
+            let mut old_node = old_entry.to_node();
+            old_node.set_parent_dir(Weak::<Mutex<TmpDirectory>>::new());
+            old_node
+        }))
+    }
+
+    fn get(&self, name: &str) -> Option<FileOrDir> {
+        self.entries.get(name).map(Entry::to_node)
+    }
+
+    fn list(&self) -> Vec<String> {
+        self.entries.keys().cloned().collect()
+    }
+
+    fn remove(&mut self, node: &FileOrDir) -> Option<FileOrDir> {
+        self.take_entry(&node.get_name()).ok().map(|entry| entry.to_node())
+    }
+}
+
+impl FsNode for TmpDirectory {
+    fn get_name(&self) -> String {
+        self.name.clone()
+    }
+
+    fn get_parent_dir(&self) -> Option<DirRef> {
+        self.parent.upgrade()
+    }
+
+    fn set_parent_dir(&mut self, new_parent: WeakDirRef) {
+        self.parent = new_parent;
+    }
+}
@@ -0,0 +1,215 @@
+//! Files within a tmpfs instance, whose contents are stored in memory-mappable pages.
+
+use alloc::{string::String, sync::Arc};
+use core::cmp::{max, min};
+use fs_node::{DirRef, File, FsNode, WeakDirRef};
+use io::{ByteReader, ByteWriter, IoError, KnownLength};
+use memory::{allocate_pages_by_bytes, get_kernel_mmi_ref, MappedPages, PteFlags, PAGE_SIZE};
+use spin::Mutex;
+use time::Duration;
+use crate::{timestamp, Metadata, NodeKind, TmpFs};
+
+/// A reference to a file within a tmpfs instance.
+pub type TmpFileRef = Arc<Mutex<TmpFile>>;
+
+/// A file within a tmpfs instance.
+///
+/// The file's contents are stored at the start of its pages, and all bytes beyond its length are zero,
+/// such that extending the file or mapping its pages never exposes stale data.
+pub struct TmpFile {
+    fs: Arc<TmpFs>,
+    pub(crate) name: String,
+    parent: WeakDirRef,
+    /// The length in bytes of the file, which is at most the size of its pages.
+    len: usize,
+    /// The pages holding the file's contents, all of which are charged to the tmpfs instance.
+    mp: MappedPages,
+    created: Duration,
+    modified: Duration,
+}
+
+impl TmpFile {
+    pub(crate) fn new_ref(fs: Arc<TmpFs>, name: String, parent: WeakDirRef) -> TmpFileRef {
+        let time = timestamp();
+        Arc::new(Mutex::new(TmpFile {
+            fs,
+            name,
+            parent,
+            len: 0,
+            mp: MappedPages::empty(),
+            created: time,
+            modified: time,
+        }))
+    }
+
+    /// Returns information about this file.
+    pub fn metadata(&self) -> Metadata {
+        Metadata {
+            kind: NodeKind::File,
+            len: self.len,
+            allocated_bytes: self.mp.size_in_bytes(),
+            created: self.created,
+            modified: self.modified,
+        }
+    }
+
+    /// Appends the given `buffer` to the end of this file.
+    pub fn append(&mut self, buffer: &[u8]) -> Result<usize, IoError> {
+        self.write_at(buffer, self.len)
+    }
+
+    /// Moves this file's contents into newly-allocated pages that can hold at least `capacity` bytes,
+    /// truncating the file if it's longer than that.
+    ///
+    /// The difference in size between the old and new pages is charged to or returned to the tmpfs instance.
+    fn set_capacity(&mut self, capacity: usize) -> Result<(), IoError> {
+        let old_size = self.mp.size_in_bytes();
+        let new_size = round_up_to_page(capacity);
+        if new_size > old_size {
+            self.fs.charge(new_size - old_size)?;
+        }
+        let new_mp = match map_zeroed_pages(new_size) {
+            Ok(new_mp) => new_mp,
+            Err(e) => {
+                if new_size > old_size {
+                    self.fs.uncharge(new_size - old_size);
+                }
+                return Err(e);
+            }
+        };
+        let old_mp = core::mem::replace(&mut self.mp, new_mp);
+        self.len = min(self.len, new_size);
+        if self.len > 0 {
+            self.mp.as_slice_mut::<u8>(0, self.len)?.copy_from_slice(old_mp.as_slice(0, self.len)?);
+        }
+        drop(old_mp);
+        if old_size > new_size {
+            self.fs.uncharge(old_size - new_size);
+        }
+        Ok(())
+    }
+}
+
+/// Rounds the given number of bytes up to a multiple of the page size.
+fn round_up_to_page(bytes: usize) -> usize {
+    (bytes + PAGE_SIZE - 1) / PAGE_SIZE * PAGE_SIZE
+}
+
+/// Allocates and maps `size` bytes of writable, zeroed pages.
+fn map_zeroed_pages(size: usize) -> Result<MappedPages, IoError> {
+    if size == 0 {
+        return Ok(MappedPages::empty());
+    }
+    let kernel_mmi_ref = get_kernel_mmi_ref().ok_or("KERNEL_MMI was not yet initialized!")?;
+    let pages = allocate_pages_by_bytes(size).ok_or("tmpfs: couldn't allocate pages")?;
+    let mut mp = kernel_mmi_ref.lock().page_table.map_allocated_pages(
+        pages,
+        PteFlags::new().valid(true).writable(true),
+    )?;
+    mp.as_slice_mut::<u8>(0, size)?.fill(0);
+    Ok(mp)
+}
+
+impl Drop for TmpFile {
+    /// Returns the memory occupied by this file to its tmpfs instance.
+    fn drop(&mut self) {
+        self.fs.uncharge(self.mp.size_in_bytes());
+    }
+}
+
+impl ByteReader for TmpFile {
+    fn read_at(&mut self, buffer: &mut [u8], offset: usize) -> Result<usize, IoError> {
+        if offset > self.len {
+            return Err(IoError::InvalidInput);
+        }
+        let count = min(self.len - offset, buffer.len());
+        if count > 0 {
+            buffer[..count].copy_from_slice(self.mp.as_slice(offset, count)?);
+        }
+        Ok(count)
+    }
+}
+
+impl ByteWriter for TmpFile {
+    /// Writes the given `buffer` at the given `offset`, extending the file if necessary.
+    /// Any gap between the previous end of the file and `offset` reads as zeros.
+    ///
+    /// Returns an error without writing anything if the file cannot grow
+    /// because its tmpfs instance has reached its size limit.
+    fn write_at(&mut self, buffer: &[u8], offset: usize) -> Result<usize, IoError> {
+        if buffer.is_empty() {
+            return Ok(0);
+        }
+        let end = offset.checked_add(buffer.len()).ok_or(IoError::InvalidInput)?;
+        let capacity = self.mp.size_in_bytes();
+        if end > capacity {
+            // Grow geometrically to amortize the cost of copying,
+            // but fall back to the exact size if the doubled size exceeds the size limit.
+            let doubled = max(end, capacity.saturating_mul(2));
+            if self.set_capacity(doubled).is_err() {
+                self.set_capacity(end)?;
+            }
+        }
+        self.mp.as_slice_mut::<u8>(offset, buffer.len())?.copy_from_slice(buffer);
+        self.len = max(self.len, end);
+        self.modified = timestamp();
+        Ok(buffer.len())
+    }
+
+    fn flush(&mut self) -> Result<(), IoError> {
+        Ok(())
+    }
+}
+
+impl KnownLength for TmpFile {
+    fn len(&self) -> usize {
+        self.len
+    }
+}
+
+impl File for TmpFile {
+    fn as_mapping(&self) -> Result<&MappedPages, &'static str> {
+        Ok(&self.mp)
+    }
+
+    /// Sets the length of this file, either truncating it or extending it with zeros.
+    ///
+    /// Truncating the file frees any pages beyond its new length.
+    fn set_len(&mut self, len: usize) -> Result<(), IoError> {
+        if len > self.mp.size_in_bytes() {
+            self.set_capacity(len)?;
+        } else if len < self.len {
+            self.mp.as_slice_mut::<u8>(len, self.len - len)?.fill(0);
+            if round_up_to_page(len) < self.mp.size_in_bytes() {
+                self.set_capacity(len)?;
+            }
+        }
+        self.len = len;
+        self.modified = timestamp();
+        Ok(())
+    }
+
+    /// Maps this file's pages copy-on-write, such that no contents are copied until they are modified.
+    /// A mapping that extends beyond this file's pages is copied instead.
+    fn mmap(&mut self, offset: usize, len: usize) -> Result<MappedPages, IoError> {
+        if offset.saturating_add(len) <= self.mp.size_in_bytes() {
+            Ok(fs_node::map_copy_on_write(&mut self.mp, offset, len)?)
+        } else {
+            fs_node::map_by_copying(self, offset, len)
+        }
+    }
+}
+
+impl FsNode for TmpFile {
+    fn get_name(&self) -> String {
+        self.name.clone()
+    }
+
+    fn get_parent_dir(&self) -> Option<DirRef> {
+        self.parent.upgrade()
+    }
+
+    fn set_parent_dir(&mut self, new_parent: WeakDirRef) {
+        self.parent = new_parent;
+    }
+}
@@ -0,0 +1,197 @@
+//! An in-memory filesystem whose size is limited per instance.
+//!
+//! [`mount()`] creates a new, empty tmpfs instance and inserts its root directory
+//! into a VFS directory as a [`TmpDirectory`].
+//! Files and directories can then be created, renamed, and unlinked within it,
+//! and each node's [`Metadata`] can be queried.
+//! The contents of each [`TmpFile`] are stored in [`MappedPages`](memory::MappedPages),
+//! so files can be memory mapped via [`File::as_mapping()`](fs_node::File::as_mapping).
+//!
+//! The pages backing each instance's files are charged against that instance's size limit,
+//! and writes that would exceed it fail.
+//! The usage of every instance is reported to the memory accountant via [`instances()`].
+//!
+//! [`init()`] mounts the default instance at [`TMP_DIRECTORY_PATH`].
+
+#![no_std]
+
+extern crate alloc;
+
+mod dir;
+mod file;
+
+pub use dir::{TmpDirRef, TmpDirectory};
+pub use file::{TmpFile, TmpFileRef};
+
+use alloc::{string::String, sync::{Arc, Weak}, vec::Vec};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use fs_node::{DirRef, Directory, FileOrDir};
+use io::IoError;
+use path::Path;
+use spin::Mutex;
+use time::{now, Duration, WallTime};
+
+/// The absolute path of the default tmpfs instance, which is mounted by [`init()`].
+pub const TMP_DIRECTORY_PATH: &str = "/tmp";
+
+/// The size limit in bytes of the default tmpfs instance.
+pub const DEFAULT_SIZE_LIMIT: usize = 64 * 1024 * 1024;
+
+/// All tmpfs instances, which are reported to the memory accountant.
+static INSTANCES: Mutex<Vec<Weak<TmpFs>>> = Mutex::new(Vec::new());
+
+/// Mounts the default tmpfs instance at [`TMP_DIRECTORY_PATH`]
+/// and registers it in the VFS mount table.
+pub fn init() -> Result<(), &'static str> {
+    let root = mount(String::from(&TMP_DIRECTORY_PATH[1..]), root::get_root(), DEFAULT_SIZE_LIMIT)?;
+    vfs::mount(&Path::new(String::from(TMP_DIRECTORY_PATH)), root as DirRef, "tmpfs")?;
+    Ok(())
+}
+
+/// Mounts a new, empty tmpfs instance as a directory with the given `name`
+/// within the given `parent` directory.
+///
+/// The files within the instance may occupy at most `size_limit` bytes of memory.
+/// Returns the root directory of the new instance.
+pub fn mount(name: String, parent: &DirRef, size_limit: usize) -> Result<TmpDirRef, &'static str> {
+    let fs = Arc::new(TmpFs {
+        name: name.clone(),
+        size_limit: AtomicUsize::new(size_limit),
+        used_bytes: AtomicUsize::new(0),
+    });
+    {
+        let mut instances = INSTANCES.lock();
+        instances.retain(|instance| instance.strong_count() > 0);
+        instances.push(Arc::downgrade(&fs));
+    }
+    let root = TmpDirectory::new_ref(fs, name, Arc::downgrade(parent));
+    parent.lock().insert(FileOrDir::Dir(root.clone()))?;
+    Ok(root)
+}
+
+/// Returns every tmpfs instance that is still in use.
+pub fn instances() -> Vec<Arc<TmpFs>> {
+    INSTANCES.lock().iter().filter_map(Weak::upgrade).collect()
+}
+
+/// A tmpfs instance, which tracks the memory occupied by its files against its size limit.
+pub struct TmpFs {
+    /// The name of the instance's root directory when it was mounted.
+    name: String,
+    size_limit: AtomicUsize,
+    used_bytes: AtomicUsize,
+}
+
+impl TmpFs {
+    /// Returns the name of this instance's root directory when it was mounted.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns the number of bytes of memory currently occupied by this instance's files.
+    pub fn used_bytes(&self) -> usize {
+        self.used_bytes.load(Ordering::Relaxed)
+    }
+
+    /// Returns the maximum number of bytes of memory that this instance's files may occupy.
+    pub fn size_limit(&self) -> usize {
+        self.size_limit.load(Ordering::Relaxed)
+    }
+
+    /// Changes the size limit of this instance.
+    ///
+    /// The new limit may be lower than the memory currently in use,
+    /// in which case files can only shrink until usage falls below it.
+    pub fn set_size_limit(&self, size_limit: usize) {
+        self.size_limit.store(size_limit, Ordering::Relaxed);
+    }
+
+    /// Charges `bytes` of memory against this instance's size limit,
+    /// or returns an error if that would exceed the limit.
+    fn charge(&self, bytes: usize) -> Result<(), IoError> {
+        let size_limit = self.size_limit();
+        self.used_bytes
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |used| {
+                used.checked_add(bytes).filter(|&new_used| new_used <= size_limit)
+            })
+            .map(|_| ())
+            .map_err(|_| IoError::Other("tmpfs size limit exceeded"))
+    }
+
+    /// Returns `bytes` of memory that were previously charged via [`TmpFs::charge()`].
+    fn uncharge(&self, bytes: usize) {
+        self.used_bytes.fetch_sub(bytes, Ordering::Relaxed);
+    }
+}
+
+/// The kind of a node within a tmpfs instance.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NodeKind {
+    File,
+    Directory,
+}
+
+/// Information about a file or directory within a tmpfs instance.
+#[derive(Debug, Clone, Copy)]
+pub struct Metadata {
+    pub kind: NodeKind,
+    /// The length in bytes of a file, or the number of entries in a directory.
+    pub len: usize,
+    /// The number of bytes of memory occupied by a file's contents, which is zero for directories.
+    pub allocated_bytes: usize,
+    /// The wall-clock time at which the node was created.
+    pub created: Duration,
+    /// The wall-clock time at which the node's contents were last modified.
+    pub modified: Duration,
+}
+
+/// Moves the entry named `old_name` in the `src` directory to the `dest` directory as `new_name`.
+///
+/// If `dest` already contains an entry named `new_name`, it is replaced,
+/// but only if it is the same kind of node as the moved one and, if it's a directory, it's empty.
+/// Both directories must belong to the same tmpfs instance,
+/// and a directory cannot be moved into itself or one of its subdirectories.
+pub fn rename(src: &TmpDirRef, old_name: &str, dest: &TmpDirRef, new_name: &str) -> Result<(), &'static str> {
+    if Arc::ptr_eq(src, dest) {
+        return src.lock().rename(old_name, new_name);
+    }
+    dir::validate_name(new_name)?;
+    // Check for a cycle before locking both directories, as this locks the ancestors of `dest`.
+    let moved = src.lock().get(old_name);
+    if let Some(FileOrDir::Dir(moved)) = moved {
+        if dir::is_ancestor(&moved, dest) {
+            return Err("cannot move a directory into itself");
+        }
+    }
+    let replaced = dest.lock().get(new_name);
+    if let Some(FileOrDir::Dir(replaced)) = replaced {
+        // `src` cannot be empty, as it contains the entry being moved.
+        if Arc::as_ptr(&replaced) as *const u8 == Arc::as_ptr(src) as *const u8 {
+            return Err("directory is not empty");
+        }
+    }
+
+    // Lock both directories in a consistent order, such that concurrent renames cannot deadlock.
+    let (mut src, mut dest) = if Arc::as_ptr(src) < Arc::as_ptr(dest) {
+        let src = src.lock();
+        (src, dest.lock())
+    } else {
+        let dest = dest.lock();
+        (src.lock(), dest)
+    };
+    if !Arc::ptr_eq(src.fs(), dest.fs()) {
+        return Err("cannot rename across tmpfs instances");
+    }
+    let node = src.take_entry(old_name)?;
+    if let Err((node, e)) = dest.put_entry(node, new_name) {
+        // Restore the entry, which cannot fail as it was just removed from `src`.
+        let _ = src.put_entry(node, old_name);
+        return Err(e);
+    }
+    Ok(())
+}
+
+/// Returns the current wall-clock time, which is used for node timestamps.
+fn timestamp() -> Duration {
+    now::<WallTime>()
+}
@@ -0,0 +1,215 @@
+//! Support for building "thread-pointer areas", i.e., areas of memory that are accessed
+//! via offsets from a pointer held in a dedicated register.
+//!
+//! Thread-Local Storage (TLS) areas (accessed via `FS` on x86_64 or `TPIDR_EL0` on aarch64)
+//! and per-CPU areas (accessed via `GS` on x86_64 or `TPIDR_EL1` on aarch64)
+//! are both thread-pointer areas: each consists of sections placed at fixed offsets
+//! from the area pointer, and each instance of the area is a copy of a single template data image.
+//!
+//! An [`AreaBuilder`] is the placement engine for such an area.
+//! It assigns offsets to sections and generates the area's template data image,
+//! which consists of three parts:
+//! 1. Fixed sections, whose offsets were determined at link time, e.g.,
+//!    TLS sections in the statically-linked base kernel image.
+//!    These are placed right before the area pointer, i.e., at negative offsets from it,
+//!    and can only exist in [`AreaVariant::FixedBelowPointer`] areas.
+//! 2. A reserved region that begins at the area pointer, e.g., for a self pointer,
+//!    a Thread Control Block (TCB) header, or other slots at well-known offsets.
+//! 3. Dynamic sections, whose offsets are assigned at runtime, e.g., when a crate is loaded.
+//!    Each is placed into the first gap after the reserved region that fits it.
+//!
+//! An `AreaBuilder` is generic over the type of its sections,
+//! which are opaque to it except for their ranges of offsets.
+
+#![no_std]
+
+#[macro_use] extern crate alloc;
+
+use alloc::vec::Vec;
+use core::{cmp::max, ops::Range};
+use rangemap::RangeMap;
+
+/// The layout variant of a thread-pointer area, which determines where its sections may be placed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AreaVariant {
+    /// Fixed sections are placed before the area pointer, and dynamic sections after the reserved region.
+    ///
+    /// This is used for TLS areas, in which the area pointer is the TLS self pointer.
+    FixedBelowPointer,
+    /// The area begins at the area pointer, and only dynamic sections exist after the reserved region.
+    ///
+    /// This is used for per-CPU areas.
+    PointerAtStart,
+}
+
+/// The placement engine for the sections of a thread-pointer area.
+///
+/// See the [crate-level documentation](crate) for the layout of an area.
+#[derive(Debug, Clone)]
+pub struct AreaBuilder<S> {
+    variant: AreaVariant,
+    /// The size of the reserved region that begins at the area pointer.
+    reserved_size: usize,
+    /// The fixed sections, keyed by their offsets from the start of the area.
+    fixed_sections: RangeMap<usize, S>,
+    /// The ending offset (an exclusive range end bound) of the last fixed section,
+    /// which is the offset of the area pointer from the start of the area.
+    end_of_fixed_sections: usize,
+    /// The dynamic sections, keyed by their offsets from the area pointer.
+    dynamic_sections: RangeMap<usize, S>,
+    /// The ending offset (an exclusive range end bound) of the last dynamic section.
+    end_of_dynamic_sections: usize,
+}
+
+impl<S: Clone + Eq> AreaBuilder<S> {
+    /// Creates a new area builder without any sections, in which the first `reserved_size` bytes
+    /// starting at the area pointer are reserved.
+    pub const fn new(variant: AreaVariant, reserved_size: usize) -> AreaBuilder<S> {
+        AreaBuilder {
+            variant,
+            reserved_size,
+            fixed_sections: RangeMap::new(),
+            end_of_fixed_sections: 0,
+            dynamic_sections: RangeMap::new(),
+            end_of_dynamic_sections: 0,
+        }
+    }
+
+    /// Returns the layout variant of this area.
+    pub fn variant(&self) -> AreaVariant {
+        self.variant
+    }
+
+    /// Returns the size of the reserved region that begins at the area pointer.
+    pub fn reserved_size(&self) -> usize {
+        self.reserved_size
+    }
+
+    /// Returns the fixed sections, keyed by their offsets from the start of the area.
+    pub fn fixed_sections(&self) -> &RangeMap<usize, S> {
+        &self.fixed_sections
+    }
+
+    /// Returns the dynamic sections, keyed by their offsets from the area pointer.
+    pub fn dynamic_sections(&self) -> &RangeMap<usize, S> {
+        &self.dynamic_sections
+    }
+
+    /// Returns the offset of the area pointer from the start of the area,
+    /// which is the total size of all fixed sections.
+    pub fn pointer_offset(&self) -> usize {
+        self.end_of_fixed_sections
+    }
+
+    /// Returns the ending offset (from the area pointer) of the last dynamic section,
+    /// or `0` if there are no dynamic sections.
+    pub fn end_of_dynamic_sections(&self) -> usize {
+        self.end_of_dynamic_sections
+    }
+
+    /// Returns `true` if this area has no sections at all.
+    pub fn is_empty(&self) -> bool {
+        self.end_of_fixed_sections == 0 && self.end_of_dynamic_sections == 0
+    }
+
+    /// Returns the size of this area's data image.
+    ///
+    /// A [`AreaVariant::FixedBelowPointer`] area without any sections has an empty image,
+    /// whereas a [`AreaVariant::PointerAtStart`] area always includes its reserved region.
+    pub fn image_size(&self) -> usize {
+        if self.is_empty() && self.variant == AreaVariant::FixedBelowPointer {
+            0
+        } else {
+            self.end_of_fixed_sections + max(self.reserved_size, self.end_of_dynamic_sections)
+        }
+    }
+
+    /// Inserts a fixed section at the given `range` of offsets from the start of the area.
+    ///
+    /// Returns an error if this area's variant doesn't support fixed sections
+    /// or if the `range` overlaps an existing fixed section.
+    pub fn insert_fixed(&mut self, range: Range<usize>, section: S) -> Result<(), &'static str> {
+        if self.variant != AreaVariant::FixedBelowPointer {
+            return Err("this thread-pointer area variant doesn't support fixed sections");
+        }
+        if self.fixed_sections.overlaps(&range) {
+            return Err("fixed section overlaps an existing fixed section");
+        }
+        self.end_of_fixed_sections = max(self.end_of_fixed_sections, range.end);
+        if !range.is_empty() {
+            self.fixed_sections.insert(range, section);
+        }
+        Ok(())
+    }
+
+    /// Returns the first offset (from the area pointer) after the reserved region at which
+    /// `size` bytes aligned to `alignment` fit between the existing dynamic sections.
+    pub fn find_gap(&self, size: usize, alignment: usize) -> Option<usize> {
+        let range_after_reserved = self.reserved_size .. usize::MAX;
+        self.dynamic_sections.gaps(&range_after_reserved)
+            .map(|gap| (gap.start.next_multiple_of(alignment.max(1)), gap.end))
+            .find(|&(aligned_start, gap_end)| aligned_start.checked_add(size).map_or(false, |end| end <= gap_end))
+            .map(|(aligned_start, _)| aligned_start)
+    }
+
+    /// Inserts a dynamic section at the given `range` of offsets from the area pointer,
+    /// overwriting any existing dynamic sections in that range.
+    ///
+    /// The `range` should usually be obtained from [`find_gap()`](Self::find_gap).
+    pub fn insert_dynamic(&mut self, range: Range<usize>, section: S) {
+        self.end_of_dynamic_sections = max(self.end_of_dynamic_sections, range.end);
+        if !range.is_empty() {
+            self.dynamic_sections.insert(range, section);
+        }
+    }
+
+    /// Removes the dynamic sections in the given `range` of offsets from the area pointer.
+    pub fn remove_dynamic(&mut self, range: Range<usize>) {
+        self.dynamic_sections.remove(range);
+        self.end_of_dynamic_sections = self.dynamic_sections.iter()
+            .map(|(range, _)| range.end)
+            .max()
+            .unwrap_or(0);
+    }
+
+    /// Returns the range of offsets of the first dynamic section for which `predicate` returns `true`.
+    pub fn find_dynamic(&self, predicate: impl Fn(&S) -> bool) -> Option<Range<usize>> {
+        self.dynamic_sections.iter()
+            .find(|(_, sec)| predicate(sec))
+            .map(|(range, _)| range.clone())
+    }
+
+    /// Returns the offset from the area pointer of the first section for which `predicate` returns `true`.
+    ///
+    /// Fixed sections have negative offsets, while dynamic sections have positive offsets.
+    pub fn offset_of(&self, predicate: impl Fn(&S) -> bool) -> Option<isize> {
+        self.fixed_sections.iter()
+            .find(|(_, sec)| predicate(sec))
+            .map(|(range, _)| range.start as isize - self.end_of_fixed_sections as isize)
+            .or_else(|| self.find_dynamic(predicate).map(|range| range.start as isize))
+    }
+
+    /// Returns an iterator over all sections and their ranges of bytes in this area's data image,
+    /// in which the area pointer is at [`pointer_offset()`](Self::pointer_offset).
+    pub fn image_ranges(&self) -> impl Iterator<Item = (Range<usize>, &S)> + '_ {
+        let pointer_offset = self.end_of_fixed_sections;
+        let fixed = self.fixed_sections.iter().map(|(range, sec)| (range.clone(), sec));
+        let dynamic = self.dynamic_sections.iter().map(move |(range, sec)|
+            ((pointer_offset + range.start) .. (pointer_offset + range.end), sec)
+        );
+        fixed.chain(dynamic)
+    }
+
+    /// Generates this area's data image, in which the reserved region and
+    /// all padding between sections are zeroed.
+    ///
+    /// The given `copy_section` function is invoked on each section to write its initial contents
+    /// into its part of the image, which is initially zeroed and exactly as large as the section.
+    pub fn build_image(&self, mut copy_section: impl FnMut(&S, &mut [u8])) -> Vec<u8> {
+        let mut image = vec![0u8; self.image_size()];
+        for (range, sec) in self.image_ranges() {
+            copy_section(sec, &mut image[range]);
+        }
+        image
+    }
+}
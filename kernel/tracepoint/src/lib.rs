@@ -0,0 +1,290 @@
+//! Low-overhead static tracepoints that record events into per-CPU ring buffers.
+//!
+//! Each [`Tracepoint`] is a fixed place in a kernel subsystem, e.g., spawning a task or handling a page fault,
+//! that invokes [`trace()`] with two arguments describing the event.
+//! Tracepoints are disabled by default, in which case [`trace()`] costs a single relaxed atomic load;
+//! they can be enabled and their events dumped at runtime, e.g., via the `trace` command.
+//!
+//! Each CPU records its events into its own fixed-size ring buffer, created by [`init()`],
+//! which overwrites its oldest events once it is full.
+//! Recording an event never blocks or allocates, so tracepoints can be placed in any context,
+//! including interrupt handlers and the context switch path.
+//!
+//! Timestamps are raw TSC ticks, which are obtained together with the current CPU's ID
+//! by a single `rdtscp` instruction, such that this crate has no dependencies.
+
+#![no_std]
+
+extern crate alloc;
+
+use alloc::{boxed::Box, vec::Vec};
+use core::{
+    fmt,
+    ptr,
+    str::FromStr,
+    sync::atomic::{fence, AtomicPtr, AtomicU32, AtomicU64, Ordering},
+};
+
+/// The maximum number of CPUs, one for each possible CPU ID.
+const MAX_CPUS: usize = u8::MAX as usize + 1;
+
+/// The number of events that each CPU's ring buffer can hold before overwriting its oldest events.
+pub const RING_CAPACITY: usize = 4096;
+
+/// A static tracepoint in a kernel subsystem.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[repr(u8)]
+pub enum Tracepoint {
+    /// A new task was spawned.
+    Spawn = 0,
+    /// A CPU switched from one task to another.
+    ContextSwitch = 1,
+    /// A `TlsInitializer` re-generated its TLS data image from scratch.
+    TlsImage = 2,
+    /// A page fault occurred.
+    PageFault = 3,
+    /// A crate was loaded into a namespace.
+    CrateLoad = 4,
+}
+
+impl Tracepoint {
+    /// All tracepoints.
+    pub const ALL: [Tracepoint; 5] = [
+        Tracepoint::Spawn,
+        Tracepoint::ContextSwitch,
+        Tracepoint::TlsImage,
+        Tracepoint::PageFault,
+        Tracepoint::CrateLoad,
+    ];
+
+    /// Returns the short name of this tracepoint, which is accepted by [`FromStr`].
+    pub fn name(self) -> &'static str {
+        match self {
+            Tracepoint::Spawn         => "spawn",
+            Tracepoint::ContextSwitch => "switch",
+            Tracepoint::TlsImage      => "tls",
+            Tracepoint::PageFault     => "pagefault",
+            Tracepoint::CrateLoad     => "crate",
+        }
+    }
+
+    /// Returns the names of the two arguments recorded by this tracepoint.
+    pub fn arg_names(self) -> [&'static str; 2] {
+        match self {
+            Tracepoint::Spawn         => ["task", "ticks"],
+            Tracepoint::ContextSwitch => ["prev", "next"],
+            Tracepoint::TlsImage      => ["generation", "size"],
+            Tracepoint::PageFault     => ["addr", "error"],
+            Tracepoint::CrateLoad     => ["sections", "ticks"],
+        }
+    }
+
+    fn from_u8(value: u8) -> Option<Tracepoint> {
+        Tracepoint::ALL.get(value as usize).copied()
+    }
+
+    fn mask(self) -> u32 {
+        1 << (self as u8)
+    }
+}
+
+impl fmt::Display for Tracepoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Tracepoint {
+    type Err = &'static str;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Tracepoint::ALL.into_iter()
+            .find(|point| point.name() == s)
+            .ok_or("unknown tracepoint")
+    }
+}
+
+/// An event recorded by a [`Tracepoint`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Event {
+    /// The TSC value when this event was recorded.
+    pub timestamp: u64,
+    /// The ID of the CPU that recorded this event.
+    pub cpu: u8,
+    /// The tracepoint that recorded this event.
+    pub point: Tracepoint,
+    /// The arguments of this event, whose meaning is given by [`Tracepoint::arg_names()`].
+    pub args: [u64; 2],
+}
+
+/// A single entry in a ring buffer.
+///
+/// Its `seq` is zero while the entry is being written, and otherwise is one more than
+/// the index of the event it holds, such that readers can detect torn or overwritten entries.
+struct Slot {
+    seq: AtomicU64,
+    timestamp: AtomicU64,
+    point: AtomicU64,
+    args: [AtomicU64; 2],
+}
+
+/// The ring buffer of events recorded on one CPU.
+struct Ring {
+    /// The index of the next event to be recorded; only ever increases.
+    head: AtomicU64,
+    /// Events with an index below this were discarded by [`clear()`].
+    start: AtomicU64,
+    slots: Box<[Slot]>,
+}
+
+/// The ring buffer of each CPU, or null if it hasn't been initialized.
+static RINGS: [AtomicPtr<Ring>; MAX_CPUS] = {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const NULL: AtomicPtr<Ring> = AtomicPtr::new(ptr::null_mut());
+    [NULL; MAX_CPUS]
+};
+
+/// A bitmask of the enabled tracepoints, indexed by their discriminant.
+static ENABLED: AtomicU32 = AtomicU32::new(0);
+
+/// Creates the ring buffer of the CPU with the given `cpu_id`.
+///
+/// This must be invoked once on every CPU while it boots up;
+/// events recorded on a CPU without a ring buffer are ignored.
+/// If that CPU already has a ring buffer, e.g., because it was previously brought online,
+/// its existing events are kept.
+pub fn init(cpu_id: u8) {
+    let slot = &RINGS[cpu_id as usize];
+    if !slot.load(Ordering::Acquire).is_null() {
+        return;
+    }
+    let ring = Box::into_raw(Box::new(Ring {
+        head: AtomicU64::new(0),
+        start: AtomicU64::new(0),
+        slots: (0 .. RING_CAPACITY).map(|_| Slot {
+            seq: AtomicU64::new(0),
+            timestamp: AtomicU64::new(0),
+            point: AtomicU64::new(0),
+            args: [AtomicU64::new(0), AtomicU64::new(0)],
+        }).collect(),
+    }));
+    if slot.compare_exchange(ptr::null_mut(), ring, Ordering::AcqRel, Ordering::Acquire).is_err() {
+        // SAFETY: `ring` was created above and never shared, because another CPU won the race.
+        drop(unsafe { Box::from_raw(ring) });
+    }
+}
+
+/// Returns the ring buffer of the CPU with the given `cpu_id`, if it has been initialized.
+fn ring(cpu_id: u8) -> Option<&'static Ring> {
+    let ring = RINGS[cpu_id as usize].load(Ordering::Acquire);
+    // SAFETY: ring buffers are never freed once they have been published.
+    (!ring.is_null()).then(|| unsafe { &*ring })
+}
+
+/// Returns the current TSC value and the ID of the current CPU.
+#[inline(always)]
+fn timestamp_and_cpu() -> (u64, u8) {
+    #[cfg(target_arch = "x86_64")] {
+        let mut aux = 0;
+        // SAFETY: `rdtscp` only reads the TSC and the `IA32_TSC_AUX` MSR, which holds the current CPU's ID.
+        let ticks = unsafe { core::arch::x86_64::__rdtscp(&mut aux) };
+        (ticks, aux as u8)
+    }
+    #[cfg(not(target_arch = "x86_64"))] {
+        (0, 0)
+    }
+}
+
+/// Returns the current TSC value, which can be used to measure how long a traced operation took.
+#[inline(always)]
+pub fn timestamp() -> u64 {
+    timestamp_and_cpu().0
+}
+
+/// Returns whether the given tracepoint is enabled.
+#[inline(always)]
+pub fn is_enabled(point: Tracepoint) -> bool {
+    ENABLED.load(Ordering::Relaxed) & point.mask() != 0
+}
+
+/// Enables or disables the given tracepoint.
+pub fn set_enabled(point: Tracepoint, enabled: bool) {
+    if enabled {
+        ENABLED.fetch_or(point.mask(), Ordering::Relaxed);
+    } else {
+        ENABLED.fetch_and(!point.mask(), Ordering::Relaxed);
+    }
+}
+
+/// Records an event with the given arguments at the given tracepoint, if it is enabled.
+///
+/// This never blocks or allocates, so it can be invoked from any context.
+#[inline(always)]
+pub fn trace(point: Tracepoint, arg0: u64, arg1: u64) {
+    if is_enabled(point) {
+        record(point, [arg0, arg1]);
+    }
+}
+
+#[cold]
+fn record(point: Tracepoint, args: [u64; 2]) {
+    let (timestamp, cpu_id) = timestamp_and_cpu();
+    let Some(ring) = ring(cpu_id) else { return };
+    // Events can be recorded by an interrupt handler while this CPU is recording another event,
+    // so each event claims its own index rather than assuming it is the only writer.
+    let index = ring.head.fetch_add(1, Ordering::Relaxed);
+    let slot = &ring.slots[index as usize % RING_CAPACITY];
+    slot.seq.store(0, Ordering::Relaxed);
+    fence(Ordering::Release);
+    slot.timestamp.store(timestamp, Ordering::Relaxed);
+    slot.point.store(point as u64, Ordering::Relaxed);
+    slot.args[0].store(args[0], Ordering::Relaxed);
+    slot.args[1].store(args[1], Ordering::Relaxed);
+    slot.seq.store(index + 1, Ordering::Release);
+}
+
+/// Returns the events currently held in the ring buffer of the CPU with the given `cpu_id`,
+/// from oldest to newest.
+///
+/// Events that are being recorded or overwritten while they are read are skipped.
+pub fn events(cpu_id: u8) -> Vec<Event> {
+    let Some(ring) = ring(cpu_id) else { return Vec::new() };
+    let head = ring.head.load(Ordering::Acquire);
+    let first = head.saturating_sub(RING_CAPACITY as u64).max(ring.start.load(Ordering::Acquire));
+    (first .. head).filter_map(|index| {
+        let slot = &ring.slots[index as usize % RING_CAPACITY];
+        if slot.seq.load(Ordering::Acquire) != index + 1 {
+            return None;
+        }
+        let timestamp = slot.timestamp.load(Ordering::Relaxed);
+        let point = slot.point.load(Ordering::Relaxed);
+        let args = [slot.args[0].load(Ordering::Relaxed), slot.args[1].load(Ordering::Relaxed)];
+        fence(Ordering::Acquire);
+        if slot.seq.load(Ordering::Relaxed) != index + 1 {
+            return None;
+        }
+        Some(Event { timestamp, cpu: cpu_id, point: Tracepoint::from_u8(point as u8)?, args })
+    }).collect()
+}
+
+/// Returns the events currently held in the ring buffers of all CPUs, ordered by their timestamps.
+pub fn all_events() -> Vec<Event> {
+    let mut events: Vec<Event> = (0 ..= u8::MAX).flat_map(events).collect();
+    events.sort_by_key(|event| event.timestamp);
+    events
+}
+
+/// Returns the number of events recorded on the CPU with the given `cpu_id`
+/// that have been overwritten before they were cleared.
+pub fn overwritten(cpu_id: u8) -> u64 {
+    ring(cpu_id).map_or(0, |ring| {
+        let head = ring.head.load(Ordering::Acquire);
+        head.saturating_sub(RING_CAPACITY as u64).saturating_sub(ring.start.load(Ordering::Acquire))
+    })
+}
+
+/// Discards all events currently held in the ring buffers of all CPUs.
+pub fn clear() {
+    for ring in (0 ..= u8::MAX).filter_map(ring) {
+        ring.start.store(ring.head.load(Ordering::Acquire), Ordering::Release);
+    }
+}
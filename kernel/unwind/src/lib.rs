@@ -12,6 +12,8 @@
 //!   (the knowledge for which comes from parsing the .eh_frame section).
 //! * `start_unwinding()` creates an unwinding context, which contains the stack frame iterator, 
 //!   the reason for the panic, and a reference to the current task being unwound.
+//!   A pointer to it is stored in the current task's TLS area (see [`mod_mgmt::unwinding_context()`]),
+//!   so unwinding a task never requires locking and is independent of unwinding other tasks.
 //!   It then skips the first several stack frames, which correspond to the panic and unwind handlers themselves.
 //!   Note that we cannot unwind those frames because they contain resources that we are currently using for unwinding purposes.
 //! * At any point hereafter, the unwinding context must be manually cleaned up.
@@ -25,7 +27,7 @@
 //! * `continue_unwinding()` continues iterating up the call stack. 
 //!   Once it reaches the end of the call stack (or an error occurs),
 //!   we invoke a finalizer routine called `cleanup_unwinding_context()`. 
-//! * In `cleanup_unwinding_context()`, the unwinding context pointer is recovered and all unwinding resources are freed.
+//! * In `cleanup_unwinding_context()`, the unwinding context pointer is taken from TLS and all unwinding resources are freed.
 //!   Finally, the task is marked as killed so it can no longer be scheduled in. 
 //! 
 //! 
@@ -87,8 +89,10 @@ use task::{TaskRef, KillReason};
 /// 
 /// Because those callbacks follow an extern "C" ABI, this structure is passed as a pointer 
 /// rather than directly by value or by reference.
+/// That pointer is also stored in the current task's TLS area, from which the unwinder reads it.
 /// Thus, it must be manually freed when unwinding is finished (or if it fails in the middle)
 /// in order to avoid leaking memory, e.g., not dropping reference counts. 
+/// Use [`take_unwinding_context()`] to recover ownership of it.
 pub struct UnwindingContext {
     /// The iterator over the current call stack, in which the "next" item in the iterator
     /// is the previous frame in the call stack (the caller frame).
@@ -728,7 +732,7 @@ pub fn start_unwinding(reason: KillReason, stack_frames_to_skip: usize) -> Resul
         let current_task = task::get_my_current_task().ok_or("couldn't get current task")?;
         let namespace = current_task.get_namespace();
 
-        Box::into_raw(Box::new(
+        let ptr = Box::into_raw(Box::new(
             UnwindingContext {
                 stack_frame_iter: StackFrameIter::new(
                     Arc::clone(namespace),
@@ -738,7 +742,12 @@ pub fn start_unwinding(reason: KillReason, stack_frames_to_skip: usize) -> Resul
                 cause: reason,
                 current_task,
             }
-        ))
+        ));
+        if mod_mgmt::unwinding_context() != 0 {
+            warn!("start_unwinding(): current task was already being unwound; its previous unwinding context will be leaked.");
+        }
+        mod_mgmt::set_unwinding_context(ptr as usize);
+        ptr
     };
 
     // IMPORTANT NOTE!!!!
@@ -764,7 +773,7 @@ pub fn start_unwinding(reason: KillReason, stack_frames_to_skip: usize) -> Resul
             }
         }
 
-        continue_unwinding(unwinding_context_ptr)
+        continue_unwinding()
     });
 
     match res {
@@ -775,20 +784,24 @@ pub fn start_unwinding(reason: KillReason, stack_frames_to_skip: usize) -> Resul
             error!("BUG: unwinding the first stack frame returned unexpectedly. Error: {}", e);
         }
     }
-    cleanup_unwinding_context(unwinding_context_ptr);
+    cleanup_unwinding_context();
 }
 
 
 /// Continues the unwinding process from the point it left off at, 
-/// which is defined by the given unwinding context.
+/// which is defined by the current task's unwinding context stored in TLS.
 /// 
 /// This returns an error upon failure, 
 /// and an `Ok(())` when it reaches the end of the stack and there are no more frames to unwind.
 /// When either value is returned (upon a return of any kind),
-/// **the caller is responsible for cleaning up** the given `UnwindingContext`.
+/// **the caller is responsible for cleaning up** the current `UnwindingContext`.
 /// 
 /// Upon successfully continuing to iterate up the call stack, this function will actually not return at all. 
-fn continue_unwinding(unwinding_context_ptr: *mut UnwindingContext) -> Result<(), &'static str> {
+fn continue_unwinding() -> Result<(), &'static str> {
+    let unwinding_context_ptr = mod_mgmt::unwinding_context() as *mut UnwindingContext;
+    if unwinding_context_ptr.is_null() {
+        return Err("continue_unwinding(): current task has no unwinding context");
+    }
     let stack_frame_iter = unsafe { &mut (*unwinding_context_ptr).stack_frame_iter };
     
     #[cfg(not(downtime_eval))]
@@ -860,7 +873,7 @@ fn continue_unwinding(unwinding_context_ptr: *mut UnwindingContext) -> Result<()
         } else {
             #[cfg(not(downtime_eval))]
             trace!("continue_unwinding(): stack frame has no LSDA");
-            return continue_unwinding(unwinding_context_ptr);
+            return continue_unwinding();
         }
     } else {
         #[cfg(not(downtime_eval))]
@@ -874,7 +887,7 @@ fn continue_unwinding(unwinding_context_ptr: *mut UnwindingContext) -> Result<()
         _ => {
             #[cfg(not(downtime_eval))]
             warn!("continue_unwinding(): stack frame has LSDA but no landing pad");
-            return continue_unwinding(unwinding_context_ptr);
+            return continue_unwinding();
         }
     };
 
@@ -886,7 +899,7 @@ fn continue_unwinding(unwinding_context_ptr: *mut UnwindingContext) -> Result<()
         warn!("Skipping exception/interrupt handler's landing pad (cleanup function) at {:#X}, which points to {:#X} (UD2: {})", 
             landing_pad_address, landing_pad_value, landing_pad_value == 0x0B0F,  // the `ud2` instruction
         );
-        return continue_unwinding(unwinding_context_ptr);
+        return continue_unwinding();
     }
 
     // Jump to the actual landing pad function, or rather, a function that will jump there after setting up register values properly.
@@ -913,9 +926,10 @@ fn continue_unwinding(unwinding_context_ptr: *mut UnwindingContext) -> Result<()
 #[doc(hidden)]
 pub fn unwind_resume(unwinding_context_ptr: usize) -> ! {
     // trace!("unwind_resume(): unwinding_context_ptr value: {:#X}", unwinding_context_ptr);
-    let unwinding_context_ptr = unwinding_context_ptr as *mut UnwindingContext;
+    // The unwinding context is read from TLS; the argument is the same pointer, passed through the landing pad.
+    debug_assert_eq!(unwinding_context_ptr, mod_mgmt::unwinding_context());
 
-    match continue_unwinding(unwinding_context_ptr) {
+    match continue_unwinding() {
         Ok(()) => {
             #[cfg(not(downtime_eval))]
             debug!("unwind_resume(): continue_unwinding() returned Ok(), meaning it's at the end of the call stack.");
@@ -925,16 +939,32 @@ pub fn unwind_resume(unwinding_context_ptr: usize) -> ! {
         }
     }
     // here, cleanup the unwinding state and kill the task
-    cleanup_unwinding_context(unwinding_context_ptr);
+    cleanup_unwinding_context();
 }
 
 
-/// This function should be invoked when the unwinding procedure is finished, or cannot be continued any further.
-/// It cleans up the `UnwindingContext` object pointed to by the given pointer and marks the current task as killed.
-fn cleanup_unwinding_context(unwinding_context_ptr: *mut UnwindingContext) -> ! {
-    // Recover ownership of the unwinding context from its pointer
+/// Takes ownership of the current task's `UnwindingContext` out of its TLS slot,
+/// leaving the current task with no unwinding context.
+///
+/// Returns `None` if the current task is not being unwound.
+pub fn take_unwinding_context() -> Option<UnwindingContext> {
+    let unwinding_context_ptr = mod_mgmt::unwinding_context() as *mut UnwindingContext;
+    if unwinding_context_ptr.is_null() {
+        return None;
+    }
+    mod_mgmt::set_unwinding_context(0);
+    // SAFE: the pointer in the TLS slot was created by `Box::into_raw()` in `start_unwinding()`.
     let unwinding_context_boxed = unsafe { Box::from_raw(unwinding_context_ptr) };
-    let unwinding_context = *unwinding_context_boxed;
+    Some(*unwinding_context_boxed)
+}
+
+
+/// This function should be invoked when the unwinding procedure is finished, or cannot be continued any further.
+/// It cleans up the current task's `UnwindingContext` and marks the current task as killed.
+fn cleanup_unwinding_context() -> ! {
+    // Recover ownership of the unwinding context from the current task's TLS area.
+    let unwinding_context = take_unwinding_context()
+        .expect("BUG: cleanup_unwinding_context(): current task had no unwinding context");
     let (stack_frame_iter, cause, current_task) = unwinding_context.into();
     drop(stack_frame_iter);
 
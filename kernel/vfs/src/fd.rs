@@ -0,0 +1,311 @@
+//! File descriptor tables, which map small integers to open files.
+//!
+//! Each task owns a [`FdTable`], which is copied from its parent when the task is spawned,
+//! such that both tasks share the same [`OpenFile`]s, including their offsets, like after `fork()`.
+//! Duplicated file descriptors within a table also share the same [`OpenFile`].
+
+use alloc::{sync::Arc, vec::Vec};
+use core2::io::SeekFrom;
+use fs_node::{DirRef, Directory, FileOrDir};
+use io::KnownLength;
+use memfs::MemFile;
+use path::Path;
+use spin::Mutex;
+use crate::{mmap, resolve, resolve_parent, Error, FileMapping, MapMode, Result};
+
+/// A file descriptor, which is an index into a [`FdTable`].
+pub type Fd = usize;
+
+/// The maximum number of file descriptors that can be open in a single table.
+pub const MAX_OPEN_FILES: usize = 1024;
+
+/// A reference to a file descriptor table, which is shared by all users of a task's table.
+pub type FdTableRef = Arc<Mutex<FdTable>>;
+
+/// A reference to an open file, which is shared by duplicated file descriptors.
+pub type OpenFileRef = Arc<Mutex<OpenFile>>;
+
+/// Options that determine how a file is opened, similar to the flags of POSIX `open()`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct OpenOptions {
+    read: bool,
+    write: bool,
+    append: bool,
+    truncate: bool,
+    create: bool,
+    create_new: bool,
+}
+
+impl OpenOptions {
+    /// Returns a new set of options with everything disabled.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Opens the file for reading.
+    pub fn read(mut self, read: bool) -> Self {
+        self.read = read;
+        self
+    }
+
+    /// Opens the file for writing.
+    pub fn write(mut self, write: bool) -> Self {
+        self.write = write;
+        self
+    }
+
+    /// Opens the file for writing, with every write going to the current end of the file.
+    pub fn append(mut self, append: bool) -> Self {
+        self.append = append;
+        self
+    }
+
+    /// Truncates the file to zero length if it already exists, which requires write access.
+    pub fn truncate(mut self, truncate: bool) -> Self {
+        self.truncate = truncate;
+        self
+    }
+
+    /// Creates the file if it doesn't already exist.
+    pub fn create(mut self, create: bool) -> Self {
+        self.create = create;
+        self
+    }
+
+    /// Creates the file, failing if it already exists.
+    pub fn create_new(mut self, create_new: bool) -> Self {
+        self.create_new = create_new;
+        self
+    }
+
+    fn is_writable(&self) -> bool {
+        self.write || self.append
+    }
+}
+
+/// An open file or directory, along with the current offset into it.
+pub struct OpenFile {
+    node: FileOrDir,
+    /// The canonical absolute path that the node was opened at.
+    path: Path,
+    offset: usize,
+    options: OpenOptions,
+}
+
+impl OpenFile {
+    /// Returns the open file or directory.
+    pub fn node(&self) -> &FileOrDir {
+        &self.node
+    }
+
+    /// Returns the canonical absolute path that the node was opened at.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Returns the current offset, at which the next read or write begins.
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    /// Reads from the current offset into the given `buffer`, advancing the offset.
+    ///
+    /// Returns the number of bytes read, which is zero at the end of the file.
+    pub fn read(&mut self, buffer: &mut [u8]) -> Result<usize> {
+        if !self.options.read {
+            return Err(Error::BadFileDescriptor);
+        }
+        let FileOrDir::File(file) = &self.node else {
+            return Err(Error::IsADirectory);
+        };
+        let mut file = file.lock();
+        if self.offset >= file.len() {
+            return Ok(0);
+        }
+        let count = file.read_at(buffer, self.offset)?;
+        self.offset += count;
+        Ok(count)
+    }
+
+    /// Writes the given `buffer` at the current offset, or at the end of the file if opened for appending,
+    /// and advances the offset past the written bytes.
+    pub fn write(&mut self, buffer: &[u8]) -> Result<usize> {
+        if !self.options.is_writable() {
+            return Err(Error::BadFileDescriptor);
+        }
+        let FileOrDir::File(file) = &self.node else {
+            return Err(Error::IsADirectory);
+        };
+        let mut file = file.lock();
+        if self.options.append {
+            self.offset = file.len();
+        }
+        let count = file.write_at(buffer, self.offset)?;
+        self.offset += count;
+        Ok(count)
+    }
+
+    /// Moves the offset to the given position, returning the new offset.
+    ///
+    /// The offset may be moved beyond the end of the file,
+    /// in which case a subsequent write extends the file.
+    pub fn seek(&mut self, position: SeekFrom) -> Result<usize> {
+        let (base, delta) = match position {
+            SeekFrom::Start(offset) => (0, offset as i64),
+            SeekFrom::Current(delta) => (self.offset, delta),
+            SeekFrom::End(delta) => (self.node.len(), delta),
+        };
+        let offset = (base as i64).checked_add(delta)
+            .filter(|&offset| offset >= 0)
+            .ok_or(Error::InvalidInput)?;
+        self.offset = offset as usize;
+        Ok(self.offset)
+    }
+}
+
+/// A table of file descriptors, each of which refers to an [`OpenFile`].
+#[derive(Default)]
+pub struct FdTable {
+    files: Vec<Option<OpenFileRef>>,
+}
+
+impl FdTable {
+    /// Returns a new, empty file descriptor table.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns a copy of this table, whose file descriptors refer to the same [`OpenFile`]s.
+    pub fn try_clone(&self) -> Self {
+        FdTable { files: self.files.clone() }
+    }
+
+    /// Opens the file or directory at the given `path`, which is either absolute or relative
+    /// to the directory `cwd`, and returns the lowest unused file descriptor that refers to it.
+    ///
+    /// Newly-created files are in-memory files; see the `memfs` crate.
+    pub fn open(&mut self, path: &Path, cwd: &DirRef, options: OpenOptions) -> Result<Fd> {
+        if !options.read && !options.is_writable() {
+            return Err(Error::InvalidInput);
+        }
+        if options.truncate && !options.is_writable() {
+            return Err(Error::InvalidInput);
+        }
+        let resolved = match resolve(path, cwd, true) {
+            Ok(_) if options.create_new => return Err(Error::AlreadyExists),
+            Ok(resolved) => resolved,
+            Err(Error::NotFound) if options.create || options.create_new => {
+                let (parent, name) = resolve_parent(path, cwd)?;
+                // Don't replace a dangling symbolic link.
+                if parent.lock().get(&name).is_some() {
+                    return Err(Error::NotFound);
+                }
+                MemFile::create(name, &parent).map_err(io::IoError::from)?;
+                // Resolve the path again, as some directories store a copy of the inserted file.
+                resolve(path, cwd, true)?
+            }
+            Err(e) => return Err(e),
+        };
+
+        match &resolved.node {
+            FileOrDir::Dir(_) if options.is_writable() => return Err(Error::IsADirectory),
+            FileOrDir::File(file) if options.truncate => file.lock().set_len(0)?,
+            _ => {}
+        }
+        self.insert(Arc::new(Mutex::new(OpenFile {
+            node: resolved.node,
+            path: resolved.path,
+            offset: 0,
+            options,
+        })))
+    }
+
+    /// Adds the given open file to this table at the lowest unused file descriptor, and returns it.
+    pub fn insert(&mut self, file: OpenFileRef) -> Result<Fd> {
+        let fd = self.lowest_unused();
+        if fd >= MAX_OPEN_FILES {
+            return Err(Error::TooManyOpenFiles);
+        }
+        self.set(fd, file);
+        Ok(fd)
+    }
+
+    /// Returns the open file that the given file descriptor refers to.
+    pub fn get(&self, fd: Fd) -> Result<OpenFileRef> {
+        self.files.get(fd).cloned().flatten().ok_or(Error::BadFileDescriptor)
+    }
+
+    /// Closes the given file descriptor.
+    ///
+    /// The open file is closed once no file descriptor in any table refers to it.
+    pub fn close(&mut self, fd: Fd) -> Result<()> {
+        let slot = self.files.get_mut(fd).ok_or(Error::BadFileDescriptor)?;
+        slot.take().ok_or(Error::BadFileDescriptor)?;
+        while let Some(None) = self.files.last() {
+            self.files.pop();
+        }
+        Ok(())
+    }
+
+    /// Returns a new file descriptor, the lowest unused one, that refers to the same open file as `fd`.
+    pub fn dup(&mut self, fd: Fd) -> Result<Fd> {
+        let file = self.get(fd)?;
+        self.insert(file)
+    }
+
+    /// Makes `new_fd` refer to the same open file as `old_fd`, closing `new_fd` first if it was open.
+    pub fn dup2(&mut self, old_fd: Fd, new_fd: Fd) -> Result<Fd> {
+        let file = self.get(old_fd)?;
+        if new_fd >= MAX_OPEN_FILES {
+            return Err(Error::BadFileDescriptor);
+        }
+        self.set(new_fd, file);
+        Ok(new_fd)
+    }
+
+    /// Reads from the open file that `fd` refers to. See [`OpenFile::read()`].
+    pub fn read(&self, fd: Fd, buffer: &mut [u8]) -> Result<usize> {
+        self.get(fd)?.lock().read(buffer)
+    }
+
+    /// Writes to the open file that `fd` refers to. See [`OpenFile::write()`].
+    pub fn write(&self, fd: Fd, buffer: &[u8]) -> Result<usize> {
+        self.get(fd)?.lock().write(buffer)
+    }
+
+    /// Moves the offset of the open file that `fd` refers to. See [`OpenFile::seek()`].
+    pub fn seek(&self, fd: Fd, position: SeekFrom) -> Result<usize> {
+        self.get(fd)?.lock().seek(position)
+    }
+
+    /// Maps `len` bytes of the file that `fd` refers to into memory, starting at `offset`. See [`mmap()`].
+    ///
+    /// The file must have been opened for reading, and for writing if the mapping is shared.
+    pub fn mmap(&self, fd: Fd, offset: usize, len: usize, mode: MapMode) -> Result<FileMapping> {
+        let open_file = self.get(fd)?;
+        let open_file = open_file.lock();
+        if !open_file.options.read || (mode == MapMode::Shared && !open_file.options.is_writable()) {
+            return Err(Error::BadFileDescriptor);
+        }
+        match &open_file.node {
+            FileOrDir::File(file) => mmap(file, offset, len, mode),
+            FileOrDir::Dir(_) => Err(Error::IsADirectory),
+        }
+    }
+
+    /// Returns an iterator over all open file descriptors and the open files they refer to.
+    pub fn iter(&self) -> impl Iterator<Item = (Fd, &OpenFileRef)> {
+        self.files.iter().enumerate().filter_map(|(fd, file)| Some((fd, file.as_ref()?)))
+    }
+
+    fn lowest_unused(&self) -> Fd {
+        self.files.iter().position(Option::is_none).unwrap_or(self.files.len())
+    }
+
+    fn set(&mut self, fd: Fd, file: OpenFileRef) {
+        if fd >= self.files.len() {
+            self.files.resize(fd + 1, None);
+        }
+        self.files[fd] = Some(file);
+    }
+}
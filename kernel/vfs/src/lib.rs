@@ -0,0 +1,286 @@
+//! The virtual filesystem layer, which unifies all filesystems into a single namespace.
+//!
+//! This crate builds on the [`fs_node`] traits with:
+//! * a [mount table](mount), which maps absolute paths to the root directories of filesystems,
+//! * canonical [path resolution](resolve()), which handles `.` and `..` components,
+//!   mount points, and [symbolic links](symlink),
+//! * [file descriptor tables](fd), which give ported programs POSIX-like `open`, `read`, `write`,
+//!   `lseek`, `dup`, and `close` semantics. Each task owns one, see `Task::fd_table()`.
+//! * [memory-mapped files](mmap), whose changes are optionally written back to the file.
+
+#![no_std]
+
+extern crate alloc;
+
+pub mod fd;
+pub mod mmap;
+pub mod mount;
+pub mod symlink;
+
+pub use fd::{Fd, FdTable, FdTableRef, OpenFile, OpenFileRef, OpenOptions};
+pub use mmap::{mmap, FileMapping, MapMode};
+pub use mount::{mount, mounts, unmount, MountInfo};
+pub use symlink::{symlink, Symlink};
+
+use alloc::{collections::VecDeque, string::{String, ToString}, sync::Arc, vec::Vec};
+use core::fmt;
+use fs_node::{DirRef, FileOrDir, FsNode};
+use io::IoError;
+use path::Path;
+
+/// The maximum number of symbolic links that are followed while resolving a single path,
+/// which prevents cycles of symbolic links from being followed forever.
+pub const MAX_SYMLINK_FOLLOWS: usize = 40;
+
+/// A specialized [`Result`] type for VFS operations.
+///
+/// [`Result`]: core::result::Result
+pub type Result<T> = core::result::Result<T, Error>;
+
+/// The error type for VFS operations.
+#[derive(Debug)]
+pub enum Error {
+    /// A filesystem node wasn't found.
+    NotFound,
+    /// A filesystem node was, unexpectedly, not a directory.
+    NotADirectory,
+    /// A filesystem node was, unexpectedly, a directory.
+    IsADirectory,
+    /// A filesystem node already exists.
+    AlreadyExists,
+    /// More than [`MAX_SYMLINK_FOLLOWS`] symbolic links were encountered while resolving a path.
+    TooManySymlinks,
+    /// A filesystem is already mounted at, or still mounted below, the given path.
+    Busy,
+    /// A file descriptor wasn't open, or wasn't open for the requested access.
+    BadFileDescriptor,
+    /// The file descriptor table has no free slots.
+    TooManyOpenFiles,
+    /// An argument was invalid.
+    InvalidInput,
+    /// An error occurred in the underlying file or directory.
+    Io(IoError),
+}
+
+impl Error {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Error::NotFound => "entity not found",
+            Error::NotADirectory => "not a directory",
+            Error::IsADirectory => "is a directory",
+            Error::AlreadyExists => "entity already exists",
+            Error::TooManySymlinks => "too many levels of symbolic links",
+            Error::Busy => "resource busy",
+            Error::BadFileDescriptor => "bad file descriptor",
+            Error::TooManyOpenFiles => "too many open files",
+            Error::InvalidInput | Error::Io(IoError::InvalidInput) => "invalid input",
+            Error::Io(IoError::TimedOut) => "timed out",
+            Error::Io(IoError::Other(s)) => *s,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl From<IoError> for Error {
+    fn from(e: IoError) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<Error> for &'static str {
+    fn from(e: Error) -> Self {
+        e.as_str()
+    }
+}
+
+/// A resolved path: the node it refers to and its canonical absolute path.
+#[derive(Debug, Clone)]
+pub struct Resolved {
+    pub node: FileOrDir,
+    /// The absolute path of `node`, which contains no `.` or `..` components,
+    /// and no symbolic links other than possibly the final component.
+    pub path: Path,
+}
+
+/// Resolves the given `path`, which is either absolute or relative to the directory `cwd`.
+///
+/// `.` and `..` components are resolved against the directories actually traversed,
+/// so `..` after a symbolic link refers to the parent of the link's target.
+/// At each directory, the mount table takes precedence over the directory's own entries.
+///
+/// Symbolic links are followed in all but the final component;
+/// the final one is only followed if `follow_last` is `true`.
+pub fn resolve(path: &Path, cwd: &DirRef, follow_last: bool) -> Result<Resolved> {
+    let mut walk = if path.is_absolute() {
+        Walk::from_root()
+    } else {
+        Walk::from_dir(cwd)
+    };
+    let mut remaining: VecDeque<String> = path.components().map(ToString::to_string).collect();
+    let mut symlinks_followed = 0;
+
+    while let Some(component) = remaining.pop_front() {
+        match component.as_str() {
+            "." => continue,
+            ".." => {
+                walk.pop();
+                continue;
+            }
+            _ => {}
+        }
+        let node = match mount::lookup(&walk.child_path(&component)) {
+            Some(mount_root) => FileOrDir::Dir(mount_root),
+            None => walk.current().lock().get(&component).ok_or(Error::NotFound)?,
+        };
+        match node {
+            FileOrDir::Dir(dir) => walk.push(component, dir),
+            FileOrDir::File(file) => {
+                let is_last = remaining.is_empty();
+                let target = file.lock().symlink_target();
+                match target {
+                    Some(target) if !is_last || follow_last => {
+                        symlinks_followed += 1;
+                        if symlinks_followed > MAX_SYMLINK_FOLLOWS {
+                            return Err(Error::TooManySymlinks);
+                        }
+                        let target = Path::new(target);
+                        if target.is_absolute() {
+                            walk = Walk::from_root();
+                        }
+                        for target_component in target.components().rev() {
+                            remaining.push_front(target_component.to_string());
+                        }
+                    }
+                    _ if is_last => {
+                        return Ok(Resolved {
+                            node: FileOrDir::File(file),
+                            path: Path::new(walk.child_path(&component)),
+                        });
+                    }
+                    _ => return Err(Error::NotADirectory),
+                }
+            }
+        }
+    }
+
+    Ok(Resolved {
+        node: FileOrDir::Dir(walk.current().clone()),
+        path: Path::new(walk.path()),
+    })
+}
+
+/// Resolves the given `path` like [`resolve()`], following all symbolic links,
+/// and returns the directory it refers to.
+pub fn resolve_dir(path: &Path, cwd: &DirRef) -> Result<DirRef> {
+    match resolve(path, cwd, true)?.node {
+        FileOrDir::Dir(dir) => Ok(dir),
+        FileOrDir::File(_) => Err(Error::NotADirectory),
+    }
+}
+
+/// Returns the canonical absolute form of the given `path`, which is either absolute
+/// or relative to the directory `cwd`, with all symbolic links resolved.
+pub fn canonicalize(path: &Path, cwd: &DirRef) -> Result<Path> {
+    resolve(path, cwd, true).map(|resolved| resolved.path)
+}
+
+/// Resolves the parent directory of the given `path`, following all symbolic links,
+/// and returns it along with the final component of `path`.
+///
+/// This is used to create, remove, or rename the final component.
+pub fn resolve_parent(path: &Path, cwd: &DirRef) -> Result<(DirRef, String)> {
+    let (parent, name) = split_parent(path)?;
+    Ok((resolve_dir(&parent, cwd)?, name))
+}
+
+/// Splits the given `path` into the path of its parent directory and its final component,
+/// which must not be `.` or `..`.
+fn split_parent(path: &Path) -> Result<(Path, String)> {
+    let name = path.components().last().ok_or(Error::InvalidInput)?;
+    if name == "." || name == ".." {
+        return Err(Error::InvalidInput);
+    }
+    let mut parent = String::from(path.as_str());
+    parent.truncate(parent.trim_end_matches(path::PATH_DELIMITER).len() - name.len());
+    if parent.is_empty() {
+        parent.push('.');
+    }
+    Ok((Path::new(parent), String::from(name)))
+}
+
+/// The directories traversed so far while resolving a path, starting at the root.
+struct Walk {
+    /// Each traversed directory along with its name, where the first one is the root directory.
+    dirs: Vec<(String, DirRef)>,
+}
+
+impl Walk {
+    fn from_root() -> Walk {
+        Walk { dirs: alloc::vec![(String::new(), root::get_root().clone())] }
+    }
+
+    /// Starts at the given directory, reconstructing its ancestors from its parent references.
+    fn from_dir(dir: &DirRef) -> Walk {
+        let mut dirs = Vec::new();
+        let mut current = Some(dir.clone());
+        while let Some(dir) = current {
+            let (name, parent) = {
+                let locked = dir.lock();
+                (locked.get_name(), locked.get_parent_dir())
+            };
+            dirs.push((name, dir));
+            current = parent;
+        }
+        dirs.reverse();
+        // The topmost ancestor acts as the root of the walk, even if it's detached from the root directory.
+        dirs[0].0 = String::new();
+        Walk { dirs }
+    }
+
+    fn current(&self) -> &DirRef {
+        &self.dirs.last().expect("BUG: a path walk always contains its root directory").1
+    }
+
+    fn push(&mut self, name: String, dir: DirRef) {
+        self.dirs.push((name, dir));
+    }
+
+    /// Moves to the parent directory, staying at the root if it's already there.
+    fn pop(&mut self) {
+        if self.dirs.len() > 1 {
+            self.dirs.pop();
+        }
+    }
+
+    /// Returns the absolute path of the current directory.
+    fn path(&self) -> String {
+        if self.dirs.len() == 1 {
+            return String::from(path::PATH_DELIMITER);
+        }
+        self.dirs[1..].iter().fold(String::new(), |mut path, (name, _)| {
+            path.push_str(path::PATH_DELIMITER);
+            path.push_str(name);
+            path
+        })
+    }
+
+    /// Returns the absolute path of the entry with the given `name` in the current directory.
+    fn child_path(&self, name: &str) -> String {
+        let mut path = self.path();
+        if self.dirs.len() > 1 {
+            path.push_str(path::PATH_DELIMITER);
+        }
+        path.push_str(name);
+        path
+    }
+}
+
+/// Returns whether the two directory references refer to the same directory.
+fn same_dir(a: &DirRef, b: &DirRef) -> bool {
+    Arc::as_ptr(a) as *const u8 == Arc::as_ptr(b) as *const u8
+}
@@ -0,0 +1,171 @@
+//! Memory-mapped files.
+//!
+//! A [`FileMapping`] is created from a file's [`File::mmap()`](fs_node::File::mmap) implementation,
+//! which either shares the file's own pages copy-on-write or copies the file's contents into new pages,
+//! e.g., from the page cache of an on-disk filesystem.
+//!
+//! A [`MapMode::Private`] mapping is simply those pages: writes to it never reach the file.
+//! A [`MapMode::Shared`] mapping additionally keeps a copy-on-write snapshot of its pages as of the last writeback.
+//! A page that has been written to no longer maps the same frame as its snapshot,
+//! so only those pages are written back to the file upon [`FileMapping::flush()`] or drop.
+//! Changes made to the file by others after a mapping was created are not reflected in the mapping.
+
+use alloc::vec::Vec;
+use core::{cmp::min, ops::Deref};
+use fs_node::FileRef;
+use io::IoError;
+use log::error;
+use memory::{MappedPages, PAGE_SIZE};
+use crate::{Error, Result};
+
+/// Whether the changes made to a [`FileMapping`] are written back to the file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MapMode {
+    /// Changes are written back to the file, like `MAP_SHARED`.
+    Shared,
+    /// Changes are private to the mapping, like `MAP_PRIVATE`.
+    Private,
+}
+
+/// Maps `len` bytes of the given `file` starting at `offset` into memory.
+///
+/// `offset` must be a multiple of the page size.
+/// The part of the mapping beyond the end of the file is zeroed, and is never written back to the file.
+pub fn mmap(file: &FileRef, offset: usize, len: usize, mode: MapMode) -> Result<FileMapping> {
+    let mut pages = file.lock().mmap(offset, len)?;
+    let snapshot = match mode {
+        MapMode::Shared => Some(take_snapshot(&mut pages)?),
+        MapMode::Private => None,
+    };
+    Ok(FileMapping { file: file.clone(), offset, len, mode, pages, snapshot })
+}
+
+/// A region of a file that is mapped into memory.
+pub struct FileMapping {
+    file: FileRef,
+    offset: usize,
+    len: usize,
+    mode: MapMode,
+    pages: MappedPages,
+    /// For shared mappings, a copy-on-write copy of `pages` as of the last writeback.
+    snapshot: Option<MappedPages>,
+}
+
+impl FileMapping {
+    /// Returns the offset into the file at which this mapping starts.
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    /// Returns the length in bytes of this mapping.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns `true` if this mapping has a length of zero.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Returns whether changes to this mapping are written back to the file.
+    pub fn mode(&self) -> MapMode {
+        self.mode
+    }
+
+    /// Returns the file that this mapping was created from.
+    pub fn file(&self) -> &FileRef {
+        &self.file
+    }
+
+    /// Returns the pages that hold this mapping's contents.
+    pub fn mapped_pages(&self) -> &MappedPages {
+        &self.pages
+    }
+
+    /// Returns the contents of this mapping.
+    pub fn as_slice(&self) -> Result<&[u8]> {
+        Ok(self.pages.as_slice(0, self.len).map_err(IoError::from)?)
+    }
+
+    /// Returns the contents of this mapping, which may be modified.
+    ///
+    /// The first write to each page gives it its own copy of that page,
+    /// so this mapping must not be written to while the kernel's page table is locked.
+    pub fn as_slice_mut(&mut self) -> Result<&mut [u8]> {
+        Ok(self.pages.as_slice_mut(0, self.len).map_err(IoError::from)?)
+    }
+
+    /// Writes the pages of a shared mapping that have been modified since the last writeback back to the file,
+    /// and then flushes the file, e.g., to write back its filesystem's page cache.
+    ///
+    /// This does nothing for private mappings.
+    pub fn flush(&mut self) -> Result<()> {
+        let Some(snapshot) = self.snapshot.take() else {
+            return Ok(());
+        };
+        let dirty = self.dirty_pages(&snapshot);
+        if let Err(e) = self.write_back(&dirty) {
+            // Keep the previous snapshot, such that the same pages are written back upon the next flush.
+            self.snapshot = Some(snapshot);
+            return Err(e);
+        }
+        drop(snapshot);
+        self.snapshot = Some(take_snapshot(&mut self.pages)?);
+        Ok(())
+    }
+
+    /// Writes the pages with the given indices back to the file, and then flushes the file.
+    fn write_back(&self, dirty: &[usize]) -> Result<()> {
+        let mut file = self.file.lock();
+        let file_len = file.len();
+        for &index in dirty {
+            let start = index * PAGE_SIZE;
+            // Bytes beyond the end of the file are not written back, as they don't extend the file.
+            let end = min(start + PAGE_SIZE, min(self.len, file_len.saturating_sub(self.offset)));
+            if start >= end {
+                continue;
+            }
+            let bytes = self.pages.as_slice::<u8>(start, end - start).map_err(IoError::from)?;
+            file.write_at(bytes, self.offset + start)?;
+        }
+        Ok(file.flush()?)
+    }
+
+    /// Returns the indices of the pages that no longer map the same frame as their snapshot,
+    /// i.e., the pages that have been written to since the snapshot was taken.
+    fn dirty_pages(&self, snapshot: &MappedPages) -> Vec<usize> {
+        let Some(kernel_mmi_ref) = memory::get_kernel_mmi_ref() else {
+            return Vec::new();
+        };
+        let kernel_mmi = kernel_mmi_ref.lock();
+        self.pages.deref().clone().into_iter().zip(snapshot.deref().clone())
+            .enumerate()
+            .filter(|(_, (page, snapshot_page))| {
+                kernel_mmi.page_table.translate_page(*page) != kernel_mmi.page_table.translate_page(*snapshot_page)
+            })
+            .map(|(index, _)| index)
+            .collect()
+    }
+}
+
+impl Drop for FileMapping {
+    fn drop(&mut self) {
+        let Some(snapshot) = self.snapshot.take() else {
+            return;
+        };
+        let dirty = self.dirty_pages(&snapshot);
+        if let Err(e) = self.write_back(&dirty) {
+            error!("FileMapping: failed to write back changes to {:?}: {}", self.file.lock().get_name(), e);
+        }
+    }
+}
+
+/// Returns a copy-on-write copy of the given `pages`, which share all of their frames.
+fn take_snapshot(pages: &mut MappedPages) -> Result<MappedPages> {
+    let kernel_mmi_ref = memory::get_kernel_mmi_ref().ok_or(Error::Io(IoError::Other("KERNEL_MMI was not yet initialized!")))?;
+    let new_pages = memory::allocate_pages(pages.size_in_pages())
+        .ok_or(Error::Io(IoError::Other("couldn't allocate pages for a shared file mapping")))?;
+    let snapshot = pages.share_copy_on_write(&mut kernel_mmi_ref.lock().page_table, new_pages)
+        .map_err(IoError::from)?;
+    Ok(snapshot)
+}
@@ -0,0 +1,103 @@
+//! The mount table, which maps absolute paths to the root directories of mounted filesystems.
+//!
+//! During [path resolution](crate::resolve()), a mount point takes precedence over
+//! any entry of the same name in its parent directory.
+//! Filesystems typically also insert their root directory into the parent directory
+//! (e.g., `fat_fs::mount()`), such that the mount point is listed there;
+//! [`unmount()`] removes that entry as well.
+
+use alloc::{string::String, vec::Vec};
+use fs_node::{DirRef, Directory, FileOrDir};
+use path::Path;
+use spin::Mutex;
+use crate::{canonicalize, same_dir, split_parent, Error, Result};
+
+/// A mounted filesystem.
+struct Mount {
+    /// The canonical absolute path at which the filesystem is mounted.
+    path: String,
+    root: DirRef,
+    fs_type: &'static str,
+}
+
+/// All mounted filesystems, in the order they were mounted.
+static MOUNT_TABLE: Mutex<Vec<Mount>> = Mutex::new(Vec::new());
+
+/// Information about a mounted filesystem.
+#[derive(Debug, Clone)]
+pub struct MountInfo {
+    /// The canonical absolute path at which the filesystem is mounted.
+    pub path: Path,
+    /// The type of the filesystem, e.g., `"tmpfs"`.
+    pub fs_type: &'static str,
+}
+
+/// Mounts the filesystem whose root directory is `root` at the given absolute `path`.
+///
+/// The parent directory of `path` must exist, but `path` itself need not;
+/// any existing entry at `path` is hidden until the filesystem is unmounted.
+/// Returns [`Error::Busy`] if a filesystem is already mounted at `path`.
+pub fn mount(path: &Path, root: DirRef, fs_type: &'static str) -> Result<()> {
+    if !path.is_absolute() {
+        return Err(Error::InvalidInput);
+    }
+    let (parent, name) = split_parent(path)?;
+    let mut mount_path = String::from(canonicalize(&parent, root::get_root())?.as_str());
+    if !mount_path.ends_with(path::PATH_DELIMITER) {
+        mount_path.push_str(path::PATH_DELIMITER);
+    }
+    mount_path.push_str(&name);
+
+    let mut table = MOUNT_TABLE.lock();
+    if table.iter().any(|mount| mount.path == mount_path) {
+        return Err(Error::Busy);
+    }
+    table.push(Mount { path: mount_path, root, fs_type });
+    Ok(())
+}
+
+/// Unmounts the filesystem mounted at the given absolute `path`, returning its root directory.
+///
+/// Returns [`Error::Busy`] if other filesystems are still mounted below `path`.
+pub fn unmount(path: &Path) -> Result<DirRef> {
+    let mount_path = canonicalize(path, root::get_root())?;
+    let root = {
+        let mut table = MOUNT_TABLE.lock();
+        let index = table.iter()
+            .position(|mount| mount.path == mount_path.as_str())
+            .ok_or(Error::InvalidInput)?;
+        let prefix = alloc::format!("{}{}", mount_path, path::PATH_DELIMITER);
+        if table.iter().any(|mount| mount.path.starts_with(&prefix)) {
+            return Err(Error::Busy);
+        }
+        table.remove(index).root
+    };
+
+    // Remove the root directory from the parent directory, if the filesystem had inserted it there.
+    let (parent, name) = split_parent(&mount_path)?;
+    if let Ok(parent) = crate::resolve_dir(&parent, root::get_root()) {
+        let mut parent = parent.lock();
+        if let Some(FileOrDir::Dir(entry)) = parent.get(&name) {
+            if same_dir(&entry, &root) {
+                parent.remove(&FileOrDir::Dir(entry));
+            }
+        }
+    }
+    Ok(root)
+}
+
+/// Returns information about all mounted filesystems, ordered by path.
+pub fn mounts() -> Vec<MountInfo> {
+    let mut mounts: Vec<MountInfo> = MOUNT_TABLE.lock().iter()
+        .map(|mount| MountInfo { path: Path::new(mount.path.clone()), fs_type: mount.fs_type })
+        .collect();
+    mounts.sort_by(|a, b| a.path.as_str().cmp(b.path.as_str()));
+    mounts
+}
+
+/// Returns the root directory of the filesystem mounted at the given canonical absolute path.
+pub(crate) fn lookup(path: &str) -> Option<DirRef> {
+    MOUNT_TABLE.lock().iter()
+        .find(|mount| mount.path == path)
+        .map(|mount| mount.root.clone())
+}
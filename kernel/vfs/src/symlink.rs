@@ -0,0 +1,94 @@
+//! Symbolic links, which are files that refer to another path.
+
+use alloc::{string::String, sync::Arc};
+use core::cmp::min;
+use fs_node::{DirRef, File, FileOrDir, FileRef, FsNode, WeakDirRef};
+use io::{ByteReader, ByteWriter, IoError, KnownLength};
+use memory::MappedPages;
+use path::Path;
+use spin::Mutex;
+use crate::{resolve_parent, Error, Result};
+
+/// Creates a symbolic link at the given `link` path that points to the given `target` path.
+///
+/// `link` is either absolute or relative to the directory `cwd`, and its parent directory must exist.
+/// `target` need not exist; if it is relative, it is resolved relative to the link's parent directory.
+///
+/// The link is inserted into its parent directory as a [`Symlink`] file,
+/// so it is only preserved by directories that store inserted nodes as is.
+/// Directories of on-disk filesystems store a copy of it as a regular file instead.
+pub fn symlink(target: &Path, link: &Path, cwd: &DirRef) -> Result<FileRef> {
+    if target.is_empty() {
+        return Err(Error::InvalidInput);
+    }
+    let (parent, name) = resolve_parent(link, cwd)?;
+    let mut parent_locked = parent.lock();
+    if parent_locked.get(&name).is_some() {
+        return Err(Error::AlreadyExists);
+    }
+    let symlink = Arc::new(Mutex::new(Symlink {
+        name,
+        target: String::from(target.as_str()),
+        parent: Arc::downgrade(&parent),
+    })) as FileRef;
+    parent_locked.insert(FileOrDir::File(symlink.clone())).map_err(IoError::from)?;
+    Ok(symlink)
+}
+
+/// A symbolic link, whose contents are the path it points to.
+pub struct Symlink {
+    name: String,
+    target: String,
+    parent: WeakDirRef,
+}
+
+impl ByteReader for Symlink {
+    fn read_at(&mut self, buffer: &mut [u8], offset: usize) -> core::result::Result<usize, IoError> {
+        if offset > self.target.len() {
+            return Err(IoError::InvalidInput);
+        }
+        let count = min(self.target.len() - offset, buffer.len());
+        buffer[..count].copy_from_slice(&self.target.as_bytes()[offset..][..count]);
+        Ok(count)
+    }
+}
+
+impl ByteWriter for Symlink {
+    fn write_at(&mut self, _buffer: &[u8], _offset: usize) -> core::result::Result<usize, IoError> {
+        Err(IoError::Other("symbolic links cannot be written to"))
+    }
+
+    fn flush(&mut self) -> core::result::Result<(), IoError> {
+        Ok(())
+    }
+}
+
+impl KnownLength for Symlink {
+    fn len(&self) -> usize {
+        self.target.len()
+    }
+}
+
+impl File for Symlink {
+    fn as_mapping(&self) -> core::result::Result<&MappedPages, &'static str> {
+        Err("symbolic links cannot be memory mapped")
+    }
+
+    fn symlink_target(&self) -> Option<String> {
+        Some(self.target.clone())
+    }
+}
+
+impl FsNode for Symlink {
+    fn get_name(&self) -> String {
+        self.name.clone()
+    }
+
+    fn get_parent_dir(&self) -> Option<DirRef> {
+        self.parent.upgrade()
+    }
+
+    fn set_parent_dir(&mut self, new_parent: WeakDirRef) {
+        self.parent = new_parent;
+    }
+}
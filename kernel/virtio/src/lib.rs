@@ -0,0 +1,41 @@
+//! Support for virtio devices that use the modern (virtio 1.x) PCI transport.
+//!
+//! This crate only contains the parts that are common to all virtio devices:
+//! * [`transport`]: discovering and accessing a device's configuration structures,
+//!   negotiating features, and allocating interrupts for its MSI-X vectors.
+//! * [`virtqueue`]: the split virtqueues through which buffers are exchanged with a device.
+//!
+//! Device-specific drivers, e.g., `virtio_net` and `virtio_blk`, are built atop these.
+//!
+//! See the [virtio 1.1 specification](https://docs.oasis-open.org/virtio/virtio/v1.1/virtio-v1.1.html).
+
+#![no_std]
+
+extern crate alloc;
+
+pub mod transport;
+pub mod virtqueue;
+
+use memory::{
+    allocate_frames_by_bytes_at, allocate_pages_by_bytes, get_kernel_mmi_ref, MappedPages,
+    PhysicalAddress, PteFlags,
+};
+
+/// The mapping flags used to map virtio device memory,
+/// i.e., configuration structures and virtqueues.
+pub const VIRTIO_MAPPING_FLAGS: PteFlags = PteFlags::from_bits_truncate(
+    PteFlags::new().bits()
+    | PteFlags::VALID.bits()
+    | PteFlags::WRITABLE.bits()
+    | PteFlags::DEVICE_MEMORY.bits()
+);
+
+/// Maps the given physical memory region of a virtio device, e.g., part of one of its BARs.
+fn map_device_memory(mem_base: PhysicalAddress, mem_size_in_bytes: usize) -> Result<MappedPages, &'static str> {
+    let pages = allocate_pages_by_bytes(mem_size_in_bytes)
+        .ok_or("virtio: couldn't allocate virtual pages for device memory")?;
+    let frames = allocate_frames_by_bytes_at(mem_base, mem_size_in_bytes)
+        .map_err(|_e| "virtio: couldn't allocate physical frames for device memory")?;
+    let kernel_mmi_ref = get_kernel_mmi_ref().ok_or("virtio: KERNEL_MMI was not yet initialized!")?;
+    kernel_mmi_ref.lock().page_table.map_allocated_pages_to(pages, frames, VIRTIO_MAPPING_FLAGS)
+}
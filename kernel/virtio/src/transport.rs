@@ -0,0 +1,267 @@
+//! The virtio 1.x ("modern") PCI transport, in which each of the device's configuration structures
+//! is located in one of its BARs, as described by a vendor-specific PCI capability.
+//!
+//! See section 4.1 of the virtio 1.1 specification.
+
+use alloc::{sync::Arc, vec::Vec};
+use irq_safety::MutexIrqSafe;
+use memory::MappedPages;
+use msi::{MsiVector, MsixCapability, MsixTable, MsixVectorEntry};
+use pci::PciDevice;
+use volatile::{ReadOnly, Volatile};
+use x86_64::structures::idt::HandlerFunc;
+use zerocopy::FromBytes;
+
+/// The ID of a vendor-specific PCI capability.
+const PCI_CAPABILITY_VENDOR: u8 = 0x09;
+
+/// The `cfg_type` of each virtio PCI capability.
+const CAP_COMMON_CFG: u8 = 1;
+const CAP_NOTIFY_CFG: u8 = 2;
+const CAP_ISR_CFG:    u8 = 3;
+const CAP_DEVICE_CFG: u8 = 4;
+
+/// The offsets of the fields within a virtio PCI capability.
+const CAP_CFG_TYPE:              u8 = 3;
+const CAP_BAR:                   u8 = 4;
+const CAP_OFFSET:                u8 = 8;
+const CAP_LENGTH:                u8 = 12;
+const CAP_NOTIFY_OFF_MULTIPLIER: u8 = 16;
+
+/// The value of an MSI-X vector field that disables interrupts for a queue or for configuration changes.
+pub const NO_VECTOR: u16 = 0xFFFF;
+
+/// The bits of the device status field.
+pub const STATUS_ACKNOWLEDGE: u8 = 1;
+pub const STATUS_DRIVER:      u8 = 2;
+pub const STATUS_DRIVER_OK:   u8 = 4;
+pub const STATUS_FEATURES_OK: u8 = 8;
+pub const STATUS_FAILED:      u8 = 128;
+
+/// The layout in memory of the common configuration structure (`virtio_pci_common_cfg`).
+///
+/// The 64-bit queue addresses are split into two 32-bit halves,
+/// because not all devices support 64-bit accesses.
+#[derive(FromBytes)]
+#[repr(C)]
+pub struct CommonCfg {
+    pub device_feature_select:  Volatile<u32>,   // 0x00
+    pub device_feature:         ReadOnly<u32>,   // 0x04
+    pub driver_feature_select:  Volatile<u32>,   // 0x08
+    pub driver_feature:         Volatile<u32>,   // 0x0C
+    pub msix_config:            Volatile<u16>,   // 0x10
+    pub num_queues:             ReadOnly<u16>,   // 0x12
+    pub device_status:          Volatile<u8>,    // 0x14
+    pub config_generation:      ReadOnly<u8>,    // 0x15
+    pub queue_select:           Volatile<u16>,   // 0x16
+    pub queue_size:             Volatile<u16>,   // 0x18
+    pub queue_msix_vector:      Volatile<u16>,   // 0x1A
+    pub queue_enable:           Volatile<u16>,   // 0x1C
+    pub queue_notify_off:       ReadOnly<u16>,   // 0x1E
+    pub queue_desc_lo:          Volatile<u32>,   // 0x20
+    pub queue_desc_hi:          Volatile<u32>,   // 0x24
+    pub queue_driver_lo:        Volatile<u32>,   // 0x28
+    pub queue_driver_hi:        Volatile<u32>,   // 0x2C
+    pub queue_device_lo:        Volatile<u32>,   // 0x30
+    pub queue_device_hi:        Volatile<u32>,   // 0x34
+}
+
+const _: () = assert!(core::mem::size_of::<CommonCfg>() == 0x38);
+
+/// A structure located within one of the device's BARs.
+#[derive(Clone, Copy, Debug)]
+struct Region {
+    bar: usize,
+    offset: usize,
+    length: usize,
+}
+
+/// The mapped configuration structures of a virtio PCI device.
+pub struct Transport {
+    /// The mapped BARs that hold at least one configuration structure, indexed by BAR number.
+    bars: [Option<MappedPages>; 6],
+    common: Region,
+    notify: Region,
+    notify_off_multiplier: u32,
+    isr: Region,
+    device: Region,
+    /// The MSI-X table, which points into one of the mapped BARs.
+    msix_table: Option<Arc<MutexIrqSafe<MsixTable>>>,
+    /// The interrupts allocated for MSI-X vectors, which are freed when the transport is dropped.
+    interrupt_nums: Vec<u8>,
+}
+
+impl Transport {
+    /// Finds and maps the configuration structures of the given virtio device.
+    pub fn new(dev: &PciDevice) -> Result<Transport, &'static str> {
+        let mut regions: [Option<Region>; 5] = [None; 5];
+        let mut notify_off_multiplier = 0;
+        for (id, cap) in dev.pci_capabilities() {
+            if id != PCI_CAPABILITY_VENDOR {
+                continue;
+            }
+            let cfg_type = dev.pci_read_8(cap + CAP_CFG_TYPE);
+            let region = Region {
+                bar: dev.pci_read_8(cap + CAP_BAR) as usize,
+                offset: dev.pci_read_32(cap + CAP_OFFSET) as usize,
+                length: dev.pci_read_32(cap + CAP_LENGTH) as usize,
+            };
+            // Skip unknown structures and reserved BARs; the first of each type is preferred.
+            if !(CAP_COMMON_CFG..=CAP_DEVICE_CFG).contains(&cfg_type)
+                || region.bar > 5
+                || regions[cfg_type as usize].is_some()
+            {
+                continue;
+            }
+            if cfg_type == CAP_NOTIFY_CFG {
+                notify_off_multiplier = dev.pci_read_32(cap + CAP_NOTIFY_OFF_MULTIPLIER);
+            }
+            regions[cfg_type as usize] = Some(region);
+        }
+
+        let mut transport = Transport {
+            bars: Default::default(),
+            common: regions[CAP_COMMON_CFG as usize].ok_or("virtio: device has no common configuration capability")?,
+            notify: regions[CAP_NOTIFY_CFG as usize].ok_or("virtio: device has no notification capability")?,
+            notify_off_multiplier,
+            isr: regions[CAP_ISR_CFG as usize].ok_or("virtio: device has no ISR status capability")?,
+            device: regions[CAP_DEVICE_CFG as usize].ok_or("virtio: device has no device configuration capability")?,
+            msix_table: None,
+            interrupt_nums: Vec::new(),
+        };
+        for region in [transport.common, transport.notify, transport.isr, transport.device] {
+            transport.map_region(dev, region)?;
+        }
+        Ok(transport)
+    }
+
+    /// Maps the BAR that contains the given region, if it isn't already mapped.
+    fn map_region(&mut self, dev: &PciDevice, region: Region) -> Result<(), &'static str> {
+        if self.bars[region.bar].is_none() {
+            let mem_base = dev.determine_mem_base(region.bar)?;
+            let mem_size = dev.determine_mem_size(region.bar) as usize;
+            self.bars[region.bar] = Some(crate::map_device_memory(mem_base, mem_size)?);
+        }
+        let mapped = self.bars[region.bar].as_ref().unwrap();
+        if region.offset + region.length > mapped.size_in_bytes() {
+            return Err("virtio: configuration structure extends beyond its BAR");
+        }
+        Ok(())
+    }
+
+    fn region_as<T: FromBytes>(&mut self, region: Region) -> &mut T {
+        self.bars[region.bar].as_mut()
+            .and_then(|mp| mp.as_type_mut(region.offset).ok())
+            .expect("BUG: virtio configuration structure wasn't mapped")
+    }
+
+    /// Returns the common configuration structure.
+    pub fn common(&mut self) -> &mut CommonCfg {
+        let region = self.common;
+        self.region_as(region)
+    }
+
+    /// Returns the device-specific configuration structure.
+    pub fn device_config<T: FromBytes>(&mut self) -> Result<&mut T, &'static str> {
+        let region = self.device;
+        if core::mem::size_of::<T>() > region.length {
+            return Err("virtio: device configuration structure is too small");
+        }
+        Ok(self.region_as(region))
+    }
+
+    /// Reads and clears the ISR status, which is only used with legacy interrupts.
+    pub fn read_isr(&mut self) -> u8 {
+        let region = self.isr;
+        self.region_as::<ReadOnly<u8>>(region).read()
+    }
+
+    /// Notifies the device that new buffers are available in the queue with the given index.
+    pub fn notify(&mut self, queue_index: u16, queue_notify_off: u16) {
+        let region = Region {
+            offset: self.notify.offset + queue_notify_off as usize * self.notify_off_multiplier as usize,
+            length: 2,
+            ..self.notify
+        };
+        self.region_as::<Volatile<u16>>(region).write(queue_index);
+    }
+
+    /// Resets the device, waiting until the reset has completed.
+    pub fn reset(&mut self) {
+        let common = self.common();
+        common.device_status.write(0);
+        while common.device_status.read() != 0 {
+            core::hint::spin_loop();
+        }
+    }
+
+    pub fn status(&mut self) -> u8 {
+        self.common().device_status.read()
+    }
+
+    /// Sets the given bits in the device status, keeping the existing ones.
+    pub fn add_status(&mut self, bits: u8) {
+        let common = self.common();
+        let status = common.device_status.read();
+        common.device_status.write(status | bits);
+    }
+
+    /// Returns the 64 feature bits offered by the device.
+    pub fn device_features(&mut self) -> u64 {
+        let common = self.common();
+        common.device_feature_select.write(0);
+        let low = common.device_feature.read() as u64;
+        common.device_feature_select.write(1);
+        let high = common.device_feature.read() as u64;
+        low | (high << 32)
+    }
+
+    /// Sets the 64 feature bits accepted by the driver.
+    pub fn set_driver_features(&mut self, features: u64) {
+        let common = self.common();
+        common.driver_feature_select.write(0);
+        common.driver_feature.write(features as u32);
+        common.driver_feature_select.write(1);
+        common.driver_feature.write((features >> 32) as u32);
+    }
+
+    /// Maps the device's MSI-X vector table and enables MSI-X, returning the number of vectors in the table.
+    pub fn map_msix_table(&mut self, dev: &PciDevice) -> Result<u16, &'static str> {
+        let capability = MsixCapability::find(dev).ok_or("virtio: device does not have MSI-X capability")?;
+        let num_vectors = capability.num_vectors();
+        let (bar, offset) = capability.table_location();
+        let region = Region {
+            bar,
+            offset,
+            length: num_vectors as usize * core::mem::size_of::<MsixVectorEntry>(),
+        };
+        // The table often shares a BAR with the configuration structures, so it can't be mapped separately.
+        self.map_region(dev, region)?;
+        let entries = self.region_as::<MsixVectorEntry>(region) as *mut MsixVectorEntry;
+        // SAFETY: the whole table was mapped above, and the BAR stays mapped until the transport is dropped,
+        //         which first frees every interrupt that refers to the table.
+        let table = unsafe { MsixTable::from_raw(capability, entries) };
+        table.enable();
+        self.msix_table = Some(Arc::new(MutexIrqSafe::new(table)));
+        Ok(num_vectors)
+    }
+
+    /// Allocates an interrupt for the given MSI-X `vector` that invokes `handler` on the given CPU,
+    /// or on the least-loaded CPU if `cpu` is `None`, leaving the vector masked.
+    ///
+    /// Returns the allocated interrupt number, which is passed to [`msi::unmask()`] to unmask the vector.
+    pub fn allocate_msix_vector(&mut self, vector: u16, handler: HandlerFunc, cpu: Option<u8>) -> Result<u8, &'static str> {
+        let table = self.msix_table.as_ref().ok_or("virtio: MSI-X table wasn't mapped")?;
+        let interrupt_num = msi::allocate(MsiVector::Msix(Arc::clone(table), vector), handler, cpu)?;
+        self.interrupt_nums.push(interrupt_num);
+        Ok(interrupt_num)
+    }
+}
+
+impl Drop for Transport {
+    fn drop(&mut self) {
+        for interrupt_num in self.interrupt_nums.drain(..) {
+            let _ = msi::free(interrupt_num);
+        }
+    }
+}
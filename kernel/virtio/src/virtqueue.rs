@@ -0,0 +1,255 @@
+//! Split virtqueues, the shared rings of buffers through which the driver and a virtio device communicate.
+//!
+//! See section 2.6 of the virtio 1.1 specification.
+
+use core::sync::atomic::{fence, Ordering};
+use memory::{create_contiguous_mapping, MappedPages, PhysicalAddress};
+use volatile::Volatile;
+use zerocopy::FromBytes;
+use crate::{transport::Transport, VIRTIO_MAPPING_FLAGS};
+
+/// This descriptor continues via the `next` field.
+const DESC_F_NEXT:  u16 = 1;
+/// This descriptor's buffer is write-only for the device (otherwise it is read-only).
+const DESC_F_WRITE: u16 = 2;
+
+/// The alignments of the three parts of a split virtqueue.
+const DESC_TABLE_ALIGN:  usize = 16;
+const AVAIL_RING_ALIGN:  usize = 2;
+const USED_RING_ALIGN:   usize = 4;
+
+/// A descriptor in the descriptor table, which describes a single buffer in a chain of buffers.
+#[derive(FromBytes)]
+#[repr(C)]
+struct Descriptor {
+    addr:  Volatile<u64>,
+    len:   Volatile<u32>,
+    flags: Volatile<u16>,
+    next:  Volatile<u16>,
+}
+
+/// An element of the used ring, which returns a chain of buffers to the driver.
+#[derive(FromBytes)]
+#[repr(C)]
+struct UsedElem {
+    /// The index of the head descriptor of the used chain.
+    id:  Volatile<u32>,
+    /// The number of bytes written by the device into the chain's device-writable buffers.
+    len: Volatile<u32>,
+}
+
+/// A buffer that is part of a chain given to the device.
+#[derive(Clone, Copy, Debug)]
+pub struct Buffer {
+    pub phys_addr: PhysicalAddress,
+    pub len: u32,
+    /// Whether the device writes into (rather than reads from) this buffer.
+    pub device_writable: bool,
+}
+
+/// A split virtqueue, whose descriptor table, available ring, and used ring
+/// are all located in a single physically-contiguous mapping.
+pub struct Virtqueue {
+    /// The index of this queue on its device.
+    index: u16,
+    /// The number of descriptors in this queue, which is a power of two.
+    size: u16,
+    /// The offset of this queue's notification register within the notification structure.
+    notify_off: u16,
+    mp: MappedPages,
+    avail_offset: usize,
+    used_offset: usize,
+    /// The head of the list of free descriptors, which are chained via their `next` field.
+    free_head: u16,
+    num_free: u16,
+    /// The next index that the driver will write to in the available ring.
+    avail_idx: u16,
+    /// The next index that the driver will read from in the used ring.
+    last_used_idx: u16,
+}
+
+const fn align_up(value: usize, align: usize) -> usize {
+    (value + align - 1) & !(align - 1)
+}
+
+impl Virtqueue {
+    /// Allocates and enables the queue with the given `index` on the device,
+    /// with at most `max_size` descriptors.
+    ///
+    /// Interrupts for this queue will be delivered on the given MSI-X vector,
+    /// or not at all if it is [`NO_VECTOR`](crate::transport::NO_VECTOR).
+    pub fn new(transport: &mut Transport, index: u16, max_size: u16, msix_vector: u16) -> Result<Virtqueue, &'static str> {
+        let common = transport.common();
+        common.queue_select.write(index);
+        let device_size = common.queue_size.read();
+        if device_size == 0 {
+            return Err("virtio: queue is not available");
+        }
+        if common.queue_enable.read() != 0 {
+            return Err("virtio: queue is already enabled");
+        }
+        // The queue size must be a power of two, which the device's maximum need not be.
+        let size = core::cmp::min(device_size, max_size);
+        let size = if size.is_power_of_two() { size } else { size.next_power_of_two() >> 1 };
+
+        let desc_table_size = 16 * size as usize;
+        let avail_offset = align_up(desc_table_size, AVAIL_RING_ALIGN);
+        let avail_ring_size = 6 + 2 * size as usize;
+        let used_offset = align_up(avail_offset + avail_ring_size, USED_RING_ALIGN);
+        let used_ring_size = 6 + 8 * size as usize;
+        let total_size = used_offset + used_ring_size;
+
+        let (mut mp, phys_addr) = create_contiguous_mapping(total_size, VIRTIO_MAPPING_FLAGS)?;
+        debug_assert!(phys_addr.value() % DESC_TABLE_ALIGN == 0);
+        mp.as_slice_mut::<u8>(0, total_size)?.fill(0);
+
+        let desc_addr = phys_addr.value() as u64;
+        let driver_addr = desc_addr + avail_offset as u64;
+        let device_addr = desc_addr + used_offset as u64;
+        common.queue_size.write(size);
+        common.queue_desc_lo.write(desc_addr as u32);
+        common.queue_desc_hi.write((desc_addr >> 32) as u32);
+        common.queue_driver_lo.write(driver_addr as u32);
+        common.queue_driver_hi.write((driver_addr >> 32) as u32);
+        common.queue_device_lo.write(device_addr as u32);
+        common.queue_device_hi.write((device_addr >> 32) as u32);
+        common.queue_msix_vector.write(msix_vector);
+        // The device reports a failure to allocate the vector by reading back `NO_VECTOR`.
+        if common.queue_msix_vector.read() != msix_vector {
+            return Err("virtio: device couldn't assign MSI-X vector to queue");
+        }
+        let notify_off = common.queue_notify_off.read();
+
+        let mut queue = Virtqueue {
+            index,
+            size,
+            notify_off,
+            mp,
+            avail_offset,
+            used_offset,
+            free_head: 0,
+            num_free: size,
+            avail_idx: 0,
+            last_used_idx: 0,
+        };
+        for i in 0..size {
+            queue.descriptor(i).next.write((i + 1) % size);
+        }
+
+        transport.common().queue_select.write(index);
+        transport.common().queue_enable.write(1);
+        Ok(queue)
+    }
+
+    pub fn index(&self) -> u16 {
+        self.index
+    }
+
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+
+    pub fn notify_off(&self) -> u16 {
+        self.notify_off
+    }
+
+    /// Returns the number of free descriptors in this queue.
+    pub fn num_free(&self) -> u16 {
+        self.num_free
+    }
+
+    /// Returns the index of the head descriptor of the next chain to be added, if any descriptors are free.
+    pub fn next_free(&self) -> Option<u16> {
+        (self.num_free > 0).then_some(self.free_head)
+    }
+
+    fn descriptor(&mut self, i: u16) -> &mut Descriptor {
+        self.mp.as_type_mut(16 * i as usize).expect("BUG: virtqueue descriptor out of bounds")
+    }
+
+    fn avail_field(&mut self, offset: usize) -> &mut Volatile<u16> {
+        let offset = self.avail_offset + offset;
+        self.mp.as_type_mut(offset).expect("BUG: virtqueue available ring out of bounds")
+    }
+
+    fn used_idx(&mut self) -> u16 {
+        let offset = self.used_offset + 2;
+        self.mp.as_type_mut::<Volatile<u16>>(offset).expect("BUG: virtqueue used ring out of bounds").read()
+    }
+
+    fn used_elem(&mut self, i: u16) -> &mut UsedElem {
+        let offset = self.used_offset + 4 + 8 * i as usize;
+        self.mp.as_type_mut(offset).expect("BUG: virtqueue used ring out of bounds")
+    }
+
+    /// Adds a chain of the given buffers to the available ring, returning the index of its head descriptor.
+    ///
+    /// The device must be notified separately via [`Transport::notify()`].
+    pub fn add(&mut self, buffers: &[Buffer]) -> Result<u16, &'static str> {
+        if buffers.is_empty() {
+            return Err("virtio: cannot add an empty chain to a queue");
+        }
+        if buffers.len() > self.num_free as usize {
+            return Err("virtio: not enough free descriptors in queue");
+        }
+        let head = self.free_head;
+        let mut last = head;
+        for (i, buffer) in buffers.iter().enumerate() {
+            let is_last = i == buffers.len() - 1;
+            let desc = self.descriptor(last);
+            desc.addr.write(buffer.phys_addr.value() as u64);
+            desc.len.write(buffer.len);
+            let mut flags = if buffer.device_writable { DESC_F_WRITE } else { 0 };
+            if !is_last {
+                flags |= DESC_F_NEXT;
+            }
+            desc.flags.write(flags);
+            let next = desc.next.read();
+            if is_last {
+                self.free_head = next;
+            } else {
+                last = next;
+            }
+        }
+        self.num_free -= buffers.len() as u16;
+
+        let slot = self.avail_idx % self.size;
+        self.avail_field(4 + 2 * slot as usize).write(head);
+        self.avail_idx = self.avail_idx.wrapping_add(1);
+        // The device must see the descriptors and ring entry before the new index.
+        fence(Ordering::Release);
+        let avail_idx = self.avail_idx;
+        self.avail_field(2).write(avail_idx);
+        Ok(head)
+    }
+
+    /// Removes the next chain that the device has returned, if any,
+    /// returning the index of its head descriptor and the number of bytes written into it.
+    ///
+    /// The chain's descriptors are freed.
+    pub fn pop_used(&mut self) -> Option<(u16, u32)> {
+        if self.used_idx() == self.last_used_idx {
+            return None;
+        }
+        // Don't read the used element before seeing the device's new index.
+        fence(Ordering::Acquire);
+        let slot = self.last_used_idx % self.size;
+        let elem = self.used_elem(slot);
+        let head = elem.id.read() as u16;
+        let len = elem.len.read();
+        self.last_used_idx = self.last_used_idx.wrapping_add(1);
+
+        // Return the chain to the front of the free list.
+        let mut last = head;
+        let mut count = 1;
+        while self.descriptor(last).flags.read() & DESC_F_NEXT != 0 {
+            last = self.descriptor(last).next.read();
+            count += 1;
+        }
+        let free_head = self.free_head;
+        self.descriptor(last).next.write(free_head);
+        self.free_head = head;
+        self.num_free += count;
+        Some((head, len))
+    }
+}
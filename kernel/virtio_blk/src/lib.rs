@@ -0,0 +1,430 @@
+//! A driver for virtio-blk block devices, as provided by QEMU/KVM and most cloud hypervisors.
+//!
+//! The device is exposed as a [`VirtioBlkDrive`], which implements both the blocking
+//! [`StorageDevice`] traits and the asynchronous [`BlockDevice`] trait.
+//! Requests are submitted on a single [`Virtqueue`] and completed by its MSI-X interrupt handler.
+//!
+//! Only the modern (virtio 1.x) PCI transport is supported, including for transitional devices.
+//! Only a single virtio-blk device is currently supported.
+
+#![no_std]
+#![feature(abi_x86_interrupt)]
+
+extern crate alloc;
+
+use alloc::{boxed::Box, sync::Arc, vec, vec::Vec};
+use core::iter;
+use interrupts::eoi;
+use io::{BlockIo, BlockReader, BlockWriter, IoError, KnownLength};
+use irq_safety::MutexIrqSafe;
+use log::{debug, error, info};
+use memory::{create_contiguous_mapping, MappedPages, PhysicalAddress};
+use pci::PciDevice;
+use spin::{Mutex, Once};
+use storage_device::{
+    new_block_request, BlockCompleter, BlockDevice, BlockRequest, BlockResult, StorageController,
+    StorageDevice, StorageDeviceRef,
+};
+use virtio::{transport::*, virtqueue::{Buffer, Virtqueue}, VIRTIO_MAPPING_FLAGS};
+use volatile::ReadOnly;
+use x86_64::structures::idt::InterruptStackFrame;
+use zerocopy::FromBytes;
+
+pub const VIRTIO_VENDOR_ID:                u16 = 0x1AF4;
+/// The device ID of a transitional virtio-blk device, which supports both the legacy and modern transports.
+pub const VIRTIO_BLK_TRANSITIONAL_DEV_ID:  u16 = 0x1001;
+/// The device ID of a modern-only virtio-blk device.
+pub const VIRTIO_BLK_DEV_ID:               u16 = 0x1042;
+
+/// virtio-blk devices are always addressed in 512-byte sectors, regardless of their physical block size.
+const SECTOR_SIZE_IN_BYTES: usize = 512;
+/// The maximum number of descriptors in the request queue.
+const QUEUE_SIZE: u16 = 128;
+/// The maximum number of sectors transferred by a single request.
+const MAX_SECTORS_PER_REQUEST: usize = 128;
+
+/// The feature bits used by this driver.
+const F_RO:        u64 = 1 << 5;
+const F_FLUSH:     u64 = 1 << 9;
+const F_DISCARD:   u64 = 1 << 13;
+const F_VERSION_1: u64 = 1 << 32;
+
+/// The types of requests.
+const T_IN:      u32 = 0;
+const T_OUT:     u32 = 1;
+const T_FLUSH:   u32 = 4;
+const T_DISCARD: u32 = 11;
+
+/// The status written by the device upon completing a request.
+const S_OK:     u8 = 0;
+const S_IOERR:  u8 = 1;
+const S_UNSUPP: u8 = 2;
+
+/// Each chain has a slot in the request area, indexed by its head descriptor,
+/// that holds its header, its discard segment, and its status.
+const REQUEST_SLOT_SIZE:    usize = 64;
+const SLOT_HEADER_OFFSET:   usize = 0;
+const SLOT_SEGMENT_OFFSET:  usize = 16;
+const SLOT_STATUS_OFFSET:   usize = 32;
+
+/// The device-specific configuration structure of a virtio-blk device,
+/// up to the fields used by this driver.
+#[derive(FromBytes)]
+#[repr(C)]
+struct VirtioBlkConfig {
+    /// The capacity in 512-byte sectors, split into two halves
+    /// because not all devices support 64-bit accesses.
+    capacity_lo: ReadOnly<u32>,
+    capacity_hi: ReadOnly<u32>,
+}
+
+/// The header that precedes each request.
+#[derive(FromBytes)]
+#[repr(C)]
+struct RequestHeader {
+    request_type: u32,
+    reserved: u32,
+    sector: u64,
+}
+
+/// The range of sectors to discard in a discard request.
+#[derive(FromBytes)]
+#[repr(C)]
+struct DiscardSegment {
+    sector: u64,
+    num_sectors: u32,
+    flags: u32,
+}
+
+/// The request queue of the single virtio-blk device, which is shared with its interrupt handler.
+static VIRTIO_BLK_QUEUE: Once<MutexIrqSafe<RequestQueue>> = Once::new();
+
+/// A request that has been submitted to the device but not yet completed.
+struct InFlight {
+    request_type: u32,
+    /// The bounce buffer that the device transfers data to or from.
+    dma_buffer: Option<MappedPages>,
+    /// The buffer returned to the requester upon completion.
+    buffer: Vec<u8>,
+    completer: BlockCompleter,
+}
+
+/// The device's request queue, along with the requests that are in flight on it.
+struct RequestQueue {
+    transport: Transport,
+    queue: Virtqueue,
+    requests: MappedPages,
+    requests_phys_addr: PhysicalAddress,
+    /// Indexed by the head descriptor of each request's chain.
+    in_flight: Vec<Option<InFlight>>,
+    /// The bounce buffers of completed requests, which are unmapped outside of interrupt context.
+    retired: Vec<MappedPages>,
+    interrupt_num: u8,
+}
+
+impl RequestQueue {
+    /// Submits a request of the given type.
+    ///
+    /// The data of a read request is transferred into a bounce buffer of `buffer.len()` bytes,
+    /// which is copied into `buffer` upon completion.
+    fn submit(
+        &mut self,
+        request_type: u32,
+        sector: u64,
+        buffer: Vec<u8>,
+        discard: Option<u32>,
+    ) -> Result<BlockRequest, IoError> {
+        self.retired.clear();
+        let num_descs = if buffer.is_empty() && discard.is_none() { 2 } else { 3 };
+        let head = match self.queue.next_free() {
+            Some(head) if self.queue.num_free() >= num_descs => head,
+            _ => return Err(IoError::Other("virtio_blk: request queue is full")),
+        };
+
+        let slot = head as usize * REQUEST_SLOT_SIZE;
+        let header: &mut RequestHeader = self.requests.as_type_mut(slot + SLOT_HEADER_OFFSET)?;
+        header.request_type = request_type;
+        header.reserved = 0;
+        header.sector = sector;
+        *self.requests.as_type_mut::<u8>(slot + SLOT_STATUS_OFFSET)? = u8::MAX;
+        let slot_phys_addr = self.requests_phys_addr + slot;
+
+        let mut buffers = [Buffer { phys_addr: slot_phys_addr + SLOT_HEADER_OFFSET, len: 16, device_writable: false }; 3];
+        let mut dma_buffer = None;
+        if let Some(num_sectors) = discard {
+            let segment: &mut DiscardSegment = self.requests.as_type_mut(slot + SLOT_SEGMENT_OFFSET)?;
+            segment.sector = sector;
+            segment.num_sectors = num_sectors;
+            segment.flags = 0;
+            buffers[1] = Buffer { phys_addr: slot_phys_addr + SLOT_SEGMENT_OFFSET, len: 16, device_writable: false };
+        } else if !buffer.is_empty() {
+            let (mut mp, phys_addr) = create_contiguous_mapping(buffer.len(), VIRTIO_MAPPING_FLAGS)?;
+            if request_type == T_OUT {
+                mp.as_slice_mut(0, buffer.len())?.copy_from_slice(&buffer);
+            }
+            buffers[1] = Buffer { phys_addr, len: buffer.len() as u32, device_writable: request_type == T_IN };
+            dma_buffer = Some(mp);
+        }
+        buffers[num_descs as usize - 1] = Buffer { phys_addr: slot_phys_addr + SLOT_STATUS_OFFSET, len: 1, device_writable: true };
+
+        self.queue.add(&buffers[..num_descs as usize])?;
+        let (request, completer) = new_block_request();
+        self.in_flight[head as usize] = Some(InFlight { request_type, dma_buffer, buffer, completer });
+        self.transport.notify(self.queue.index(), self.queue.notify_off());
+        Ok(request)
+    }
+
+    /// Completes all requests that the device has finished.
+    fn handle_completions(&mut self) {
+        while let Some((head, _len)) = self.queue.pop_used() {
+            let Some(in_flight) = self.in_flight[head as usize].take() else {
+                error!("virtio_blk: device completed unknown request {}", head);
+                continue;
+            };
+            let status = self.requests
+                .as_type::<u8>(head as usize * REQUEST_SLOT_SIZE + SLOT_STATUS_OFFSET)
+                .map(|status| *status)
+                .unwrap_or(S_IOERR);
+            let result = match status {
+                S_OK => Self::finish(in_flight.request_type, in_flight.dma_buffer.as_ref(), in_flight.buffer),
+                S_UNSUPP => Err(IoError::Other("virtio_blk: request is unsupported by the device")),
+                _ => Err(IoError::Other("virtio_blk: device reported an I/O error")),
+            };
+            in_flight.completer.complete(result);
+            // This never reallocates, as there can't be more retired buffers than descriptors.
+            self.retired.extend(in_flight.dma_buffer);
+        }
+    }
+
+    fn finish(request_type: u32, dma_buffer: Option<&MappedPages>, mut buffer: Vec<u8>) -> BlockResult {
+        if let (T_IN, Some(mp)) = (request_type, dma_buffer) {
+            let len = buffer.len();
+            buffer.copy_from_slice(mp.as_slice(0, len)?);
+        }
+        Ok(buffer)
+    }
+}
+
+/// A virtio-blk device.
+pub struct VirtioBlkDrive {
+    queue: &'static MutexIrqSafe<RequestQueue>,
+    /// The size of the device in 512-byte sectors.
+    capacity: usize,
+    features: u64,
+}
+
+impl VirtioBlkDrive {
+    /// Initializes the virtio-blk device that is connected as the given PciDevice.
+    pub fn init(virtio_pci_dev: &PciDevice) -> Result<VirtioBlkDrive, &'static str> {
+        if VIRTIO_BLK_QUEUE.get().is_some() {
+            return Err("virtio_blk: only a single virtio-blk device is currently supported");
+        }
+        let mut transport = Transport::new(virtio_pci_dev)?;
+
+        // set the bus mastering bit for this PciDevice, which allows it to use DMA
+        virtio_pci_dev.pci_set_command_bus_master_bit();
+        virtio_pci_dev.pci_set_interrupt_disable_bit();
+
+        // Follow the device initialization sequence in section 3.1.1 of the specification.
+        transport.reset();
+        transport.add_status(STATUS_ACKNOWLEDGE | STATUS_DRIVER);
+
+        let device_features = transport.device_features();
+        if device_features & F_VERSION_1 == 0 {
+            transport.add_status(STATUS_FAILED);
+            return Err("virtio_blk: device doesn't support virtio 1.x");
+        }
+        let features = F_VERSION_1 | (device_features & (F_RO | F_FLUSH | F_DISCARD));
+        transport.set_driver_features(features);
+        transport.add_status(STATUS_FEATURES_OK);
+        if transport.status() & STATUS_FEATURES_OK == 0 {
+            transport.add_status(STATUS_FAILED);
+            return Err("virtio_blk: device didn't accept the negotiated features");
+        }
+
+        let capacity = loop {
+            let generation = transport.common().config_generation.read();
+            let config = transport.device_config::<VirtioBlkConfig>()?;
+            let capacity = config.capacity_lo.read() as u64 | ((config.capacity_hi.read() as u64) << 32);
+            if transport.common().config_generation.read() == generation {
+                break capacity as usize;
+            }
+        };
+
+        // The request queue raises interrupts on MSI-X vector 0, which is unmasked once the device is ready.
+        transport.map_msix_table(virtio_pci_dev)?;
+        transport.common().msix_config.write(NO_VECTOR);
+        let interrupt_num = transport.allocate_msix_vector(0, virtio_blk_handler, Some(cpu::current_cpu()))?;
+
+        let queue = Virtqueue::new(&mut transport, 0, QUEUE_SIZE, 0)?;
+        let (requests, requests_phys_addr) = create_contiguous_mapping(
+            queue.size() as usize * REQUEST_SLOT_SIZE,
+            VIRTIO_MAPPING_FLAGS,
+        )?;
+        let in_flight = (0..queue.size()).map(|_| None).collect();
+        let retired = Vec::with_capacity(queue.size() as usize);
+
+        transport.add_status(STATUS_DRIVER_OK);
+
+        let request_queue = RequestQueue { transport, queue, requests, requests_phys_addr, in_flight, retired, interrupt_num };
+        let queue = VIRTIO_BLK_QUEUE.call_once(|| MutexIrqSafe::new(request_queue));
+        msi::unmask(interrupt_num)?;
+
+        info!("virtio_blk: initialized device with {} sectors (features {:#X})", capacity, features);
+        Ok(VirtioBlkDrive { queue, capacity, features })
+    }
+
+    /// Returns whether the device is read-only.
+    pub fn is_read_only(&self) -> bool {
+        self.features & F_RO != 0
+    }
+
+    fn check_bounds(&self, block_offset: usize, num_blocks: usize) -> Result<(), IoError> {
+        match block_offset.checked_add(num_blocks) {
+            Some(end) if end <= self.capacity => Ok(()),
+            _ => Err(IoError::InvalidInput),
+        }
+    }
+
+    /// Waits for the given request to complete.
+    ///
+    /// If the current task cannot block, e.g., during early initialization,
+    /// this polls the request queue instead of waiting for an interrupt.
+    fn wait(&self, mut request: BlockRequest) -> BlockResult {
+        if io_wait::can_block() {
+            return request.wait();
+        }
+        loop {
+            self.queue.lock().handle_completions();
+            if let Some(result) = request.try_take() {
+                return result;
+            }
+            core::hint::spin_loop();
+        }
+    }
+}
+
+impl BlockDevice for VirtioBlkDrive {
+    fn max_blocks_per_request(&self) -> usize {
+        MAX_SECTORS_PER_REQUEST
+    }
+
+    fn submit_read(&mut self, block_offset: usize, num_blocks: usize) -> Result<BlockRequest, IoError> {
+        self.check_bounds(block_offset, num_blocks)?;
+        if num_blocks == 0 || num_blocks > MAX_SECTORS_PER_REQUEST {
+            return Err(IoError::InvalidInput);
+        }
+        let buffer = vec![0; num_blocks * SECTOR_SIZE_IN_BYTES];
+        self.queue.lock().submit(T_IN, block_offset as u64, buffer, None)
+    }
+
+    fn submit_write(&mut self, buffer: Vec<u8>, block_offset: usize) -> Result<BlockRequest, IoError> {
+        if self.is_read_only() {
+            return Err(IoError::Other("virtio_blk: device is read-only"));
+        }
+        let num_blocks = buffer.len() / SECTOR_SIZE_IN_BYTES;
+        if buffer.len() % SECTOR_SIZE_IN_BYTES != 0 || num_blocks == 0 || num_blocks > MAX_SECTORS_PER_REQUEST {
+            return Err(IoError::InvalidInput);
+        }
+        self.check_bounds(block_offset, num_blocks)?;
+        self.queue.lock().submit(T_OUT, block_offset as u64, buffer, None)
+    }
+
+    fn submit_flush(&mut self) -> Result<BlockRequest, IoError> {
+        // Without the flush feature, the device has no volatile write cache to flush.
+        if self.features & F_FLUSH == 0 {
+            return Ok(BlockRequest::completed(Ok(Vec::new())));
+        }
+        self.queue.lock().submit(T_FLUSH, 0, Vec::new(), None)
+    }
+
+    fn submit_discard(&mut self, block_offset: usize, num_blocks: usize) -> Result<BlockRequest, IoError> {
+        if self.features & F_DISCARD == 0 {
+            return Err(IoError::Other("virtio_blk: device doesn't support discarding blocks"));
+        }
+        self.check_bounds(block_offset, num_blocks)?;
+        let num_sectors = u32::try_from(num_blocks).map_err(|_| IoError::InvalidInput)?;
+        self.queue.lock().submit(T_DISCARD, block_offset as u64, Vec::new(), Some(num_sectors))
+    }
+}
+
+impl StorageDevice for VirtioBlkDrive {
+    fn size_in_blocks(&self) -> usize {
+        self.capacity
+    }
+
+    fn as_block_device_mut(&mut self) -> Option<&mut dyn BlockDevice> {
+        Some(self)
+    }
+}
+impl BlockIo for VirtioBlkDrive {
+    fn block_size(&self) -> usize { SECTOR_SIZE_IN_BYTES }
+}
+impl KnownLength for VirtioBlkDrive {
+    fn len(&self) -> usize { self.block_size() * self.size_in_blocks() }
+}
+impl BlockReader for VirtioBlkDrive {
+    fn read_blocks(&mut self, buffer: &mut [u8], block_offset: usize) -> Result<usize, IoError> {
+        if buffer.len() % SECTOR_SIZE_IN_BYTES != 0 {
+            return Err(IoError::InvalidInput);
+        }
+        let mut offset = block_offset;
+        for chunk in buffer.chunks_mut(MAX_SECTORS_PER_REQUEST * SECTOR_SIZE_IN_BYTES) {
+            let num_blocks = chunk.len() / SECTOR_SIZE_IN_BYTES;
+            let request = self.submit_read(offset, num_blocks)?;
+            chunk.copy_from_slice(&self.wait(request)?);
+            offset += num_blocks;
+        }
+        Ok(offset - block_offset)
+    }
+}
+impl BlockWriter for VirtioBlkDrive {
+    fn write_blocks(&mut self, buffer: &[u8], block_offset: usize) -> Result<usize, IoError> {
+        if buffer.len() % SECTOR_SIZE_IN_BYTES != 0 {
+            return Err(IoError::InvalidInput);
+        }
+        let mut offset = block_offset;
+        for chunk in buffer.chunks(MAX_SECTORS_PER_REQUEST * SECTOR_SIZE_IN_BYTES) {
+            let request = self.submit_write(chunk.to_vec(), offset)?;
+            self.wait(request)?;
+            offset += chunk.len() / SECTOR_SIZE_IN_BYTES;
+        }
+        Ok(offset - block_offset)
+    }
+
+    fn flush(&mut self) -> Result<(), IoError> {
+        let request = self.submit_flush()?;
+        self.wait(request).map(|_| ())
+    }
+}
+
+/// A "controller" for a single virtio-blk device, since each virtio-blk device is its own PCI device.
+pub struct VirtioBlkController {
+    drive: Arc<Mutex<VirtioBlkDrive>>,
+}
+
+impl VirtioBlkController {
+    /// Initializes the virtio-blk device that is connected as the given PciDevice.
+    pub fn new(virtio_pci_dev: &PciDevice) -> Result<VirtioBlkController, &'static str> {
+        let drive = VirtioBlkDrive::init(virtio_pci_dev)?;
+        debug!("virtio_blk: device is {}", if drive.is_read_only() { "read-only" } else { "writable" });
+        Ok(VirtioBlkController { drive: Arc::new(Mutex::new(drive)) })
+    }
+}
+
+impl StorageController for VirtioBlkController {
+    fn devices<'c>(&'c self) -> Box<(dyn Iterator<Item = StorageDeviceRef> + 'c)> {
+        Box::new(iter::once(Arc::clone(&self.drive) as StorageDeviceRef))
+    }
+}
+
+extern "x86-interrupt" fn virtio_blk_handler(_stack_frame: InterruptStackFrame) {
+    if let Some(queue_ref) = VIRTIO_BLK_QUEUE.get() {
+        let mut queue = queue_ref.lock();
+        queue.handle_completions();
+        eoi(Some(queue.interrupt_num));
+    } else {
+        error!("BUG: virtio_blk_handler(): virtio-blk device hasn't yet been initialized!");
+        eoi(None);
+    }
+}
@@ -0,0 +1,482 @@
+//! A driver for virtio-net network devices, as provided by QEMU/KVM and most cloud hypervisors.
+//!
+//! Only the modern (virtio 1.x) PCI transport is supported, including for transitional devices.
+//! Each pair of receive and transmit queues is backed by its own split [`Virtqueue`],
+//! and each receive queue raises interrupts on its own MSI-X vector.
+//! If the device supports multiqueue, up to [`MAX_QUEUE_PAIRS`] queue pairs are used,
+//! and packets are transmitted on the queue pair corresponding to the current CPU.
+//!
+//! See the [virtio 1.1 specification](https://docs.oasis-open.org/virtio/virtio/v1.1/virtio-v1.1.html).
+
+#![no_std]
+#![allow(dead_code)] // to suppress warnings for unused register fields
+#![feature(abi_x86_interrupt)]
+
+extern crate alloc;
+
+use alloc::{collections::VecDeque, sync::Arc, vec, vec::Vec};
+use interrupts::eoi;
+use irq_safety::MutexIrqSafe;
+use log::{debug, error, info, warn};
+use memory::{create_contiguous_mapping, MappedPages, PhysicalAddress};
+use network_interface_card::NetworkInterfaceCard;
+use nic_buffers::{ReceiveBuffer, ReceivedFrame, TransmitBuffer, NIC_MAPPING_FLAGS};
+use pci::PciDevice;
+use spin::Once;
+use virtio::transport::*;
+use virtio::virtqueue::{Buffer, Virtqueue};
+use volatile::ReadOnly;
+use x86_64::structures::idt::{HandlerFunc, InterruptStackFrame};
+use zerocopy::FromBytes;
+
+pub const VIRTIO_VENDOR_ID:                u16 = 0x1AF4;
+/// The device ID of a transitional virtio-net device, which supports both the legacy and modern transports.
+pub const VIRTIO_NET_TRANSITIONAL_DEV_ID:  u16 = 0x1000;
+/// The device ID of a modern-only virtio-net device.
+pub const VIRTIO_NET_DEV_ID:               u16 = 0x1041;
+
+/// The maximum number of receive/transmit queue pairs used by this driver.
+pub const MAX_QUEUE_PAIRS: usize = 4;
+/// The maximum number of descriptors in each queue.
+const QUEUE_SIZE: u16 = 256;
+/// The size of each receive buffer, which must fit a full Ethernet frame
+/// because mergeable receive buffers are not negotiated.
+const RX_BUFFER_SIZE_IN_BYTES: u16 = 2048;
+
+/// The length of the `virtio_net_hdr` that precedes each packet, for a device with `VIRTIO_F_VERSION_1`.
+const NET_HDR_LEN: usize = 12;
+
+/// The feature bits used by this driver.
+const F_MAC:       u64 = 1 << 5;
+const F_CTRL_VQ:   u64 = 1 << 17;
+const F_MQ:        u64 = 1 << 22;
+const F_VERSION_1: u64 = 1 << 32;
+
+/// The control queue command that sets the number of queue pairs in use.
+const CTRL_MQ:                 u8 = 4;
+const CTRL_MQ_VQ_PAIRS_SET:    u8 = 0;
+/// The acknowledgement written by the device upon a successful control command.
+const CTRL_OK:                 u8 = 0;
+
+/// The device-specific configuration structure of a virtio-net device.
+#[derive(FromBytes)]
+#[repr(C)]
+struct VirtioNetConfig {
+    mac: [ReadOnly<u8>; 6],
+    status: ReadOnly<u16>,
+    /// Only valid if `VIRTIO_NET_F_MQ` is offered.
+    max_virtqueue_pairs: ReadOnly<u16>,
+}
+
+/// The single instance of the virtio-net NIC.
+static VIRTIO_NET_NIC: Once<MutexIrqSafe<VirtioNetNic>> = Once::new();
+
+/// Returns a reference to the VirtioNetNic wrapped in a MutexIrqSafe,
+/// if it exists and has been initialized.
+pub fn get_virtio_net_nic() -> Option<&'static MutexIrqSafe<VirtioNetNic>> {
+    VIRTIO_NET_NIC.get()
+}
+
+/// The pool of pre-allocated receive buffers that are used by the virtio-net NIC
+/// and temporarily given to higher layers in the networking stack.
+/// Its capacity depends on the number of receive queues, so it is created during initialization.
+static RX_BUFFER_POOL: Once<mpmc::Queue<ReceiveBuffer>> = Once::new();
+
+macro_rules! rx_interrupt_handlers {
+    ($($name:ident => $queue:expr),* $(,)?) => {
+        $(
+            extern "x86-interrupt" fn $name(_stack_frame: InterruptStackFrame) {
+                rx_interrupt_handler($queue);
+            }
+        )*
+        /// The interrupt handler for each receive queue, indexed by queue pair.
+        const RX_INTERRUPT_HANDLERS: [HandlerFunc; MAX_QUEUE_PAIRS] = [$($name),*];
+    };
+}
+
+rx_interrupt_handlers! {
+    virtio_net_rx_handler_0 => 0,
+    virtio_net_rx_handler_1 => 1,
+    virtio_net_rx_handler_2 => 2,
+    virtio_net_rx_handler_3 => 3,
+}
+
+/// A receive queue, in which each chain consists of a slot for the packet's header
+/// followed by a [`ReceiveBuffer`] for the packet itself.
+struct RxQueue {
+    queue: Virtqueue,
+    /// One header slot per descriptor, indexed by the head descriptor of each chain.
+    headers: MappedPages,
+    headers_phys_addr: PhysicalAddress,
+    /// The receive buffers currently owned by the device, indexed by the head descriptor of their chain.
+    bufs_in_use: Vec<Option<ReceiveBuffer>>,
+}
+
+/// A transmit queue, in which each chain consists of the shared zeroed header
+/// followed by a [`TransmitBuffer`] holding the packet.
+struct TxQueue {
+    queue: Virtqueue,
+    /// The transmit buffers currently owned by the device, indexed by the head descriptor of their chain.
+    bufs_in_use: Vec<Option<TransmitBuffer>>,
+}
+
+/// The control queue, used here only to enable multiqueue.
+struct CtrlQueue {
+    queue: Virtqueue,
+    /// Holds the command header (class and command), its data, and the device's acknowledgement.
+    command: MappedPages,
+    command_phys_addr: PhysicalAddress,
+}
+
+/// Struct representing a virtio-net network interface card.
+pub struct VirtioNetNic {
+    transport: Transport,
+    /// The MAC address provided by the device.
+    mac_hardware: [u8; 6],
+    /// The optional spoofed MAC address to use in place of `mac_hardware` when transmitting.
+    mac_spoofed: Option<[u8; 6]>,
+    rx_queues: Vec<RxQueue>,
+    tx_queues: Vec<TxQueue>,
+    /// A zeroed header that is shared by all transmitted packets, since no offloads are negotiated.
+    tx_header: MappedPages,
+    tx_header_phys_addr: PhysicalAddress,
+    /// The interrupt number of each receive queue's MSI-X vector, indexed by queue pair.
+    interrupt_nums: Vec<u8>,
+    received_frames: VecDeque<ReceivedFrame>,
+    deferred_task: Option<task::JoinableTaskRef>,
+}
+
+impl VirtioNetNic {
+    /// Initializes the new virtio-net network interface card that is connected as the given PciDevice.
+    ///
+    /// `init_interrupts` must be called after the NIC has been registered with the `net` subsystem.
+    pub fn init(virtio_pci_dev: &PciDevice) -> Result<&'static MutexIrqSafe<VirtioNetNic>, &'static str> {
+        let mut transport = Transport::new(virtio_pci_dev)?;
+
+        // set the bus mastering bit for this PciDevice, which allows it to use DMA
+        virtio_pci_dev.pci_set_command_bus_master_bit();
+        virtio_pci_dev.pci_set_interrupt_disable_bit();
+
+        // Follow the device initialization sequence in section 3.1.1 of the specification.
+        transport.reset();
+        transport.add_status(STATUS_ACKNOWLEDGE | STATUS_DRIVER);
+
+        let device_features = transport.device_features();
+        if device_features & F_VERSION_1 == 0 {
+            transport.add_status(STATUS_FAILED);
+            return Err("virtio_net: device doesn't support virtio 1.x");
+        }
+        let mut features = F_VERSION_1 | (device_features & F_MAC);
+        if device_features & (F_CTRL_VQ | F_MQ) == F_CTRL_VQ | F_MQ {
+            features |= F_CTRL_VQ | F_MQ;
+        }
+        transport.set_driver_features(features);
+        transport.add_status(STATUS_FEATURES_OK);
+        if transport.status() & STATUS_FEATURES_OK == 0 {
+            transport.add_status(STATUS_FAILED);
+            return Err("virtio_net: device didn't accept the negotiated features");
+        }
+        debug!("virtio_net: device features {:#X}, driver features {:#X}", device_features, features);
+
+        let mac_hardware = Self::read_mac_address(&mut transport, features)?;
+        let max_queue_pairs = if features & F_MQ != 0 {
+            transport.device_config::<VirtioNetConfig>()?.max_virtqueue_pairs.read().max(1)
+        } else {
+            1
+        };
+
+        // Each receive queue has its own MSI-X vector, which is left masked until `init_interrupts`.
+        // The vectors are spread across CPUs, so that each queue's packets are received on a different CPU.
+        let num_vectors = transport.map_msix_table(virtio_pci_dev)?;
+        let num_queue_pairs = [
+            max_queue_pairs as usize,
+            MAX_QUEUE_PAIRS,
+            num_vectors as usize,
+            cpu::cpu_count() as usize,
+        ].into_iter().min().unwrap_or(1).max(1);
+        transport.common().msix_config.write(NO_VECTOR);
+
+        let mut interrupt_nums = Vec::with_capacity(num_queue_pairs);
+        for (vector, handler) in RX_INTERRUPT_HANDLERS.iter().take(num_queue_pairs).enumerate() {
+            interrupt_nums.push(transport.allocate_msix_vector(vector as u16, *handler, None)?);
+        }
+
+        // initialize the buffer pool
+        let rx_buffer_pool_size = num_queue_pairs * QUEUE_SIZE as usize;
+        let rx_buffer_pool = RX_BUFFER_POOL.call_once(|| mpmc::Queue::with_capacity(rx_buffer_pool_size));
+        nic_initialization::init_rx_buf_pool(rx_buffer_pool_size, RX_BUFFER_SIZE_IN_BYTES, rx_buffer_pool)?;
+        let mut rx_queues = Vec::with_capacity(num_queue_pairs);
+        let mut tx_queues = Vec::with_capacity(num_queue_pairs);
+        for pair in 0..num_queue_pairs as u16 {
+            rx_queues.push(Self::rx_init(&mut transport, pair)?);
+            tx_queues.push(Self::tx_init(&mut transport, pair)?);
+        }
+        let mut ctrl_queue = if num_queue_pairs > 1 {
+            Some(Self::ctrl_init(&mut transport, max_queue_pairs)?)
+        } else {
+            None
+        };
+
+        let (mut tx_header, tx_header_phys_addr) = create_contiguous_mapping(NET_HDR_LEN, NIC_MAPPING_FLAGS)?;
+        tx_header.as_slice_mut::<u8>(0, NET_HDR_LEN)?.fill(0);
+
+        transport.add_status(STATUS_DRIVER_OK);
+        for rxq in &rx_queues {
+            transport.notify(rxq.queue.index(), rxq.queue.notify_off());
+        }
+        if let Some(ctrl_queue) = ctrl_queue.as_mut() {
+            Self::set_queue_pairs(&mut transport, ctrl_queue, num_queue_pairs as u16)?;
+        }
+        info!("virtio_net: initialized with MAC address {:02x?} and {} queue pair(s)", mac_hardware, num_queue_pairs);
+
+        let virtio_net_nic = VirtioNetNic {
+            transport,
+            mac_hardware,
+            mac_spoofed: None,
+            rx_queues,
+            tx_queues,
+            tx_header,
+            tx_header_phys_addr,
+            interrupt_nums,
+            received_frames: VecDeque::new(),
+            deferred_task: None,
+        };
+
+        let nic_ref = VIRTIO_NET_NIC.call_once(|| MutexIrqSafe::new(virtio_net_nic));
+        Ok(nic_ref)
+    }
+
+    /// Initializes the deferred interrupt task and enables interrupts for this virtio-net NIC.
+    ///
+    /// The provided `interface` must be the network interface associated with this virtio-net NIC.
+    /// This interface will be polled in a deferred task upon an interrupt being triggered
+    /// for a received packet on any receive queue.
+    pub fn init_interrupts(
+        &mut self,
+        interface: Arc<net::NetworkInterface>,
+    ) -> Result<(), &'static str> {
+        let deferred_task = deferred_interrupt_tasks::spawn_deferred_task(
+            poll_interface,
+            interface,
+            Some("virtio_net_deferred_task"),
+        )?;
+        self.deferred_task = Some(deferred_task);
+
+        for &interrupt_num in &self.interrupt_nums {
+            msi::unmask(interrupt_num)?;
+        }
+        Ok(())
+    }
+
+    pub fn spoof_mac(&mut self, spoofed_mac_addr: [u8; 6]) {
+        self.mac_spoofed = Some(spoofed_mac_addr);
+    }
+
+    /// Reads the MAC address from the device configuration,
+    /// retrying if the configuration changes while it is being read.
+    fn read_mac_address(transport: &mut Transport, features: u64) -> Result<[u8; 6], &'static str> {
+        if features & F_MAC == 0 {
+            return Err("virtio_net: device doesn't provide a MAC address");
+        }
+        loop {
+            let generation = transport.common().config_generation.read();
+            let config = transport.device_config::<VirtioNetConfig>()?;
+            let mac_addr = core::array::from_fn(|i| config.mac[i].read());
+            if transport.common().config_generation.read() == generation {
+                debug!("virtio_net: read hardware MAC address: {:02x?}", mac_addr);
+                return Ok(mac_addr);
+            }
+        }
+    }
+
+    /// Sets up the receive queue of the given queue pair and fills it with receive buffers.
+    fn rx_init(transport: &mut Transport, pair: u16) -> Result<RxQueue, &'static str> {
+        let queue = Virtqueue::new(transport, 2 * pair, QUEUE_SIZE, pair)?;
+        let size = queue.size() as usize;
+        let (headers, headers_phys_addr) = create_contiguous_mapping(size * NET_HDR_LEN, NIC_MAPPING_FLAGS)?;
+        let mut rxq = RxQueue {
+            queue,
+            headers,
+            headers_phys_addr,
+            bufs_in_use: (0..size).map(|_| None).collect(),
+        };
+        rxq.refill()?;
+        Ok(rxq)
+    }
+
+    /// Sets up the transmit queue of the given queue pair, which doesn't raise interrupts.
+    fn tx_init(transport: &mut Transport, pair: u16) -> Result<TxQueue, &'static str> {
+        let queue = Virtqueue::new(transport, 2 * pair + 1, QUEUE_SIZE, NO_VECTOR)?;
+        let size = queue.size() as usize;
+        Ok(TxQueue {
+            queue,
+            bufs_in_use: (0..size).map(|_| None).collect(),
+        })
+    }
+
+    /// Sets up the control queue, which follows the receive and transmit queues of all possible queue pairs.
+    fn ctrl_init(transport: &mut Transport, max_queue_pairs: u16) -> Result<CtrlQueue, &'static str> {
+        let queue = Virtqueue::new(transport, 2 * max_queue_pairs, QUEUE_SIZE, NO_VECTOR)?;
+        let (command, command_phys_addr) = create_contiguous_mapping(8, NIC_MAPPING_FLAGS)?;
+        Ok(CtrlQueue { queue, command, command_phys_addr })
+    }
+
+    /// Tells the device to use the given number of queue pairs, waiting for it to acknowledge the command.
+    fn set_queue_pairs(transport: &mut Transport, ctrl: &mut CtrlQueue, num_queue_pairs: u16) -> Result<(), &'static str> {
+        {
+            let command = ctrl.command.as_slice_mut::<u8>(0, 5)?;
+            command[0] = CTRL_MQ;
+            command[1] = CTRL_MQ_VQ_PAIRS_SET;
+            command[2..4].copy_from_slice(&num_queue_pairs.to_le_bytes());
+            command[4] = u8::MAX;
+        }
+        ctrl.queue.add(&[
+            Buffer { phys_addr: ctrl.command_phys_addr, len: 2, device_writable: false },
+            Buffer { phys_addr: ctrl.command_phys_addr + 2, len: 2, device_writable: false },
+            Buffer { phys_addr: ctrl.command_phys_addr + 4, len: 1, device_writable: true },
+        ])?;
+        transport.notify(ctrl.queue.index(), ctrl.queue.notify_off());
+        while ctrl.queue.pop_used().is_none() {
+            core::hint::spin_loop();
+        }
+        if ctrl.command.as_slice::<u8>(4, 1)?[0] != CTRL_OK {
+            return Err("virtio_net: device rejected the number of queue pairs");
+        }
+        Ok(())
+    }
+
+    /// Moves all packets received on the given queue pair's receive queue into `received_frames`,
+    /// and gives new receive buffers to the device in their place.
+    fn poll_rx_queue(&mut self, pair: usize) -> Result<(), &'static str> {
+        let rxq = self.rx_queues.get_mut(pair).ok_or("virtio_net: invalid receive queue")?;
+        while let Some((head, len)) = rxq.queue.pop_used() {
+            let Some(mut buffer) = rxq.bufs_in_use[head as usize].take() else {
+                error!("virtio_net: device returned unknown receive chain {}", head);
+                continue;
+            };
+            let frame_len = (len as usize).saturating_sub(NET_HDR_LEN);
+            buffer.set_length(frame_len as u16)?;
+            self.received_frames.push_back(ReceivedFrame(vec![buffer]));
+        }
+        if rxq.refill()? {
+            self.transport.notify(rxq.queue.index(), rxq.queue.notify_off());
+        }
+        Ok(())
+    }
+
+    /// Frees the transmit buffers that the device has finished sending on the given transmit queue.
+    fn reclaim_tx_buffers(txq: &mut TxQueue) {
+        while let Some((head, _len)) = txq.queue.pop_used() {
+            txq.bufs_in_use[head as usize] = None;
+        }
+    }
+}
+
+impl RxQueue {
+    /// Gives receive buffers to the device until the queue is full,
+    /// returning whether any were added.
+    fn refill(&mut self) -> Result<bool, &'static str> {
+        let mut added = false;
+        while let Some(head) = self.queue.next_free().filter(|_| self.queue.num_free() >= 2) {
+            let pool = RX_BUFFER_POOL.get().ok_or("BUG: virtio_net receive buffer pool wasn't initialized")?;
+            let mut buffer = match pool.pop() {
+                Some(buffer) => buffer,
+                None => {
+                    warn!("virtio_net: receive buffer pool is empty, allocating a new receive buffer");
+                    let (mp, phys_addr) = create_contiguous_mapping(RX_BUFFER_SIZE_IN_BYTES as usize, NIC_MAPPING_FLAGS)?;
+                    ReceiveBuffer::new(mp, phys_addr, RX_BUFFER_SIZE_IN_BYTES, pool)?
+                }
+            };
+            buffer.reset_length();
+            self.queue.add(&[
+                Buffer {
+                    phys_addr: self.headers_phys_addr + head as usize * NET_HDR_LEN,
+                    len: NET_HDR_LEN as u32,
+                    device_writable: true,
+                },
+                Buffer {
+                    phys_addr: buffer.phys_addr(),
+                    len: buffer.length() as u32,
+                    device_writable: true,
+                },
+            ])?;
+            self.bufs_in_use[head as usize] = Some(buffer);
+            added = true;
+        }
+        Ok(added)
+    }
+}
+
+impl NetworkInterfaceCard for VirtioNetNic {
+    fn send_packet(&mut self, transmit_buffer: TransmitBuffer) -> Result<(), &'static str> {
+        let pair = cpu::current_cpu() as usize % self.tx_queues.len();
+        let txq = &mut self.tx_queues[pair];
+        Self::reclaim_tx_buffers(txq);
+        let Some(head) = txq.queue.next_free().filter(|_| txq.queue.num_free() >= 2) else {
+            return Err("virtio_net: transmit queue is full");
+        };
+        txq.queue.add(&[
+            Buffer { phys_addr: self.tx_header_phys_addr, len: NET_HDR_LEN as u32, device_writable: false },
+            Buffer { phys_addr: transmit_buffer.phys_addr(), len: transmit_buffer.length() as u32, device_writable: false },
+        ])?;
+        txq.bufs_in_use[head as usize] = Some(transmit_buffer);
+        self.transport.notify(txq.queue.index(), txq.queue.notify_off());
+        Ok(())
+    }
+
+    fn get_received_frame(&mut self) -> Option<ReceivedFrame> {
+        self.received_frames.pop_front()
+    }
+
+    fn poll_receive(&mut self) -> Result<(), &'static str> {
+        for pair in 0..self.rx_queues.len() {
+            self.poll_rx_queue(pair)?;
+        }
+        Ok(())
+    }
+
+    fn mac_address(&self) -> [u8; 6] {
+        self.mac_spoofed.unwrap_or(self.mac_hardware)
+    }
+}
+
+impl net::NetworkDevice for VirtioNetNic {
+    fn send(&mut self, buf: &[u8]) -> net::Result<()> {
+        let mut transmit_buffer = TransmitBuffer::new(buf.len() as u16).map_err(|_| net::Error::Exhausted)?;
+        transmit_buffer.copy_from_slice(buf);
+        self.send_packet(transmit_buffer).map_err(|_| net::Error::Exhausted)
+    }
+
+    fn receive(&mut self) -> Option<ReceivedFrame> {
+        self.received_frames.pop_front()
+    }
+
+    /// Returns the MAC address.
+    fn mac_address(&self) -> [u8; 6] {
+        self.mac_spoofed.unwrap_or(self.mac_hardware)
+    }
+}
+
+/// The interrupt handler for the receive queue of the given queue pair.
+fn rx_interrupt_handler(pair: usize) {
+    let Some(nic_ref) = VIRTIO_NET_NIC.get() else {
+        error!("BUG: virtio_net_rx_handler_{}(): virtio-net NIC hasn't yet been initialized!", pair);
+        return;
+    };
+    let mut nic = nic_ref.lock();
+    if let Err(e) = nic.poll_rx_queue(pair) {
+        error!("virtio_net_rx_handler_{}(): error handling interrupt: {:?}", pair, e);
+    }
+    if let Some(ref deferred_task) = nic.deferred_task {
+        // The deferred task may already be running, in which case it will see the new frames.
+        let _ = deferred_task.unblock();
+    }
+    eoi(nic.interrupt_nums.get(pair).copied());
+}
+
+/// This function is used as a deferred interrupt task.
+///
+/// After processing the interrupt, the network interface associated with the virtio-net NIC will be polled to process the received data.
+fn poll_interface(interface: &Arc<net::NetworkInterface>) -> Result<(), net::Error> {
+    interface.poll()
+}
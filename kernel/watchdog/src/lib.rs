@@ -0,0 +1,389 @@
+//! A software watchdog that detects stalled CPUs and tasks that monopolize a CPU.
+//!
+//! Each CPU counts its scheduler ticks as [`Stat::Heartbeats`] in its per-CPU area,
+//! alongside its [`Stat::ContextSwitches`]. A monitor task, started by [`start()`],
+//! periodically reads those counters from every CPU and detects two kinds of lockups:
+//! * A *stalled CPU*, whose heartbeat hasn't advanced within [`WatchdogConfig::cpu_deadline`],
+//!   e.g., because it is spinning with interrupts disabled.
+//! * A *stuck task*, which has been running on a CPU without a context switch for longer than
+//!   [`WatchdogConfig::task_deadline`] while other tasks were waiting to run on that CPU,
+//!   e.g., because it is spinning with preemption disabled.
+//!
+//! Upon detecting a lockup, the monitor logs it along with a backtrace of the affected CPU,
+//! which is captured by that CPU itself in response to an NMI (see [`handle_nmi()`]),
+//! and then takes the configured [`Action`].
+//!
+//! The monitor only reads lock-free counters and never blocks on a lock that a stalled CPU may hold,
+//! except while symbolizing backtraces. It cannot detect a lockup of the CPU it is running on.
+
+#![no_std]
+
+extern crate alloc;
+
+use alloc::{boxed::Box, collections::BTreeMap, string::String};
+use apic::LapicIpiDestination;
+use core::{
+    fmt, ptr,
+    sync::atomic::{AtomicBool, AtomicPtr, AtomicUsize, Ordering},
+};
+use cpu_stats::Stat;
+use log::{error, info, warn};
+use memory::{VirtualAddress, PAGE_SIZE};
+use spin::{Mutex, Once};
+use task::{JoinableTaskRef, KillReason, TaskRef};
+use time::{Duration, Instant, Monotonic};
+
+/// The maximum number of CPUs, one for each possible CPU ID.
+const MAX_CPUS: usize = u8::MAX as usize + 1;
+
+/// The number of words copied from the top of a CPU's stack when it captures a backtrace.
+pub const SNAPSHOT_STACK_WORDS: usize = 32;
+
+/// How long the monitor waits for a CPU to respond to a backtrace request.
+const SNAPSHOT_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// What the watchdog does after logging a detected lockup.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Action {
+    /// Only log the lockup and a backtrace of the affected CPU.
+    Log,
+    /// Also kill the task running on the affected CPU,
+    /// which takes effect once that task is switched out.
+    KillTask,
+    /// Also halt all CPUs, like a kernel panic.
+    Panic,
+    /// Also power off the machine.
+    Shutdown,
+    /// Also reboot the machine.
+    Reboot,
+}
+
+/// The configuration of the watchdog, which can be changed at runtime via [`set_config()`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct WatchdogConfig {
+    /// How often the monitor checks every CPU.
+    pub period: Duration,
+    /// How long a CPU may go without a scheduler tick before it's considered stalled,
+    /// or `None` to not detect stalled CPUs.
+    pub cpu_deadline: Option<Duration>,
+    /// How long a task may run without being switched out while other tasks are waiting
+    /// before it's considered stuck, or `None` to not detect stuck tasks.
+    pub task_deadline: Option<Duration>,
+    /// What to do upon detecting a stalled CPU.
+    pub cpu_action: Action,
+    /// What to do upon detecting a stuck task.
+    pub task_action: Action,
+}
+
+impl Default for WatchdogConfig {
+    fn default() -> Self {
+        WatchdogConfig {
+            period: Duration::from_secs(1),
+            cpu_deadline: Some(Duration::from_secs(10)),
+            task_deadline: Some(Duration::from_secs(20)),
+            cpu_action: Action::Log,
+            task_action: Action::Log,
+        }
+    }
+}
+
+static CONFIG: Mutex<Option<WatchdogConfig>> = Mutex::new(None);
+
+/// The monitor task, once it has been started.
+static MONITOR: Once<JoinableTaskRef> = Once::new();
+
+/// Starts the watchdog's monitor task with the given `config`.
+///
+/// If the watchdog has already been started, its configuration is replaced instead.
+pub fn start(config: WatchdogConfig) -> Result<(), &'static str> {
+    set_config(config);
+    if MONITOR.is_completed() {
+        return Ok(());
+    }
+    let monitor = spawn::new_task_builder(monitor_loop, ())
+        .name(String::from("watchdog"))
+        .spawn()?;
+    MONITOR.call_once(|| monitor);
+    info!("watchdog: started with {:?}", config);
+    Ok(())
+}
+
+/// Replaces the watchdog's configuration, which takes effect upon the monitor's next check.
+pub fn set_config(config: WatchdogConfig) {
+    *CONFIG.lock() = Some(config);
+}
+
+/// Returns the watchdog's current configuration.
+pub fn config() -> WatchdogConfig {
+    CONFIG.lock().unwrap_or_default()
+}
+
+/// The entry point for the monitor task.
+fn monitor_loop(_: ()) {
+    let mut cpus = BTreeMap::new();
+    loop {
+        let config = config();
+        if sleep::sleep(config.period).is_err() {
+            error!("watchdog: couldn't sleep, exiting.");
+            return;
+        }
+        let now = time::now::<Monotonic>();
+        for (cpu, counters) in cpu_stats::aggregate().per_cpu {
+            // An offline CPU doesn't tick, so it starts afresh once it comes back online.
+            if cpu::is_offline(cpu) {
+                cpus.remove(&cpu);
+                continue;
+            }
+            let heartbeats = counters[Stat::Heartbeats as usize];
+            let switches = counters[Stat::ContextSwitches as usize];
+            cpus.entry(cpu)
+                .or_insert_with(|| CpuState::new(heartbeats, switches, now))
+                .check(cpu, heartbeats, switches, now, &config);
+        }
+    }
+}
+
+/// What the monitor knows about a CPU from its previous checks.
+struct CpuState {
+    heartbeats: u64,
+    last_heartbeat: Instant,
+    switches: u64,
+    last_switch: Instant,
+    /// Whether the current stall of this CPU was already reported.
+    stall_reported: bool,
+    /// Whether the task currently stuck on this CPU was already reported.
+    stuck_reported: bool,
+}
+
+impl CpuState {
+    fn new(heartbeats: u64, switches: u64, now: Instant) -> Self {
+        CpuState {
+            heartbeats,
+            last_heartbeat: now,
+            switches,
+            last_switch: now,
+            stall_reported: false,
+            stuck_reported: false,
+        }
+    }
+
+    fn check(&mut self, cpu: u8, heartbeats: u64, switches: u64, now: Instant, config: &WatchdogConfig) {
+        let ticking = heartbeats != self.heartbeats;
+        if ticking {
+            self.heartbeats = heartbeats;
+            self.last_heartbeat = now;
+            self.stall_reported = false;
+        }
+        if switches != self.switches {
+            self.switches = switches;
+            self.last_switch = now;
+            self.stuck_reported = false;
+        }
+
+        let stalled_for = now.duration_since(self.last_heartbeat);
+        if let Some(deadline) = config.cpu_deadline {
+            if stalled_for >= deadline && !self.stall_reported {
+                self.stall_reported = true;
+                error!("watchdog: CPU {} stalled: no scheduler tick for {:?}", cpu, stalled_for);
+                log_backtrace(cpu);
+                take_action(cpu, config.cpu_action, format_args!("CPU {} stalled", cpu));
+            }
+        }
+
+        // A stalled CPU doesn't switch tasks either, which was already reported above.
+        let running_for = now.duration_since(self.last_switch);
+        if let Some(deadline) = config.task_deadline {
+            if ticking && running_for >= deadline && !self.stuck_reported {
+                let Some(task) = running_task(cpu) else { return };
+                if task.is_an_idle_task || !has_waiting_tasks(cpu).unwrap_or(false) {
+                    return;
+                }
+                self.stuck_reported = true;
+                error!("watchdog: task {:?} (id {}) is stuck on CPU {}: not switched out for {:?} while other tasks are waiting",
+                    task.name, task.id, cpu, running_for,
+                );
+                log_backtrace(cpu);
+                take_action(cpu, config.task_action, format_args!("task {:?} is stuck on CPU {}", task.name, cpu));
+            }
+        }
+    }
+}
+
+/// Returns the task currently running on the given `cpu`,
+/// or `None` if it's unknown because the task list is locked.
+fn running_task(cpu: u8) -> Option<TaskRef> {
+    task::TASKLIST.try_lock()?
+        .values()
+        .find(|task| task.running_on_cpu() == Some(cpu))
+        .cloned()
+}
+
+/// Returns whether any task other than the idle task is waiting to run on the given `cpu`,
+/// or `None` if it's unknown because that CPU's runqueue is locked.
+fn has_waiting_tasks(cpu: u8) -> Option<bool> {
+    let runqueue = runqueue::get_runqueue(cpu)?.try_read()?;
+    Some(runqueue.iter().any(|t| !t.is_an_idle_task && t.is_runnable() && !t.is_running()))
+}
+
+fn take_action(cpu: u8, action: Action, reason: fmt::Arguments) {
+    match action {
+        Action::Log => {}
+        Action::KillTask => match running_task(cpu) {
+            // `Task::kill()` only supports killing a running task.
+            Some(task) if !task.is_an_idle_task && task.is_running() => {
+                warn!("watchdog: killing task {:?} (id {})", task.name, task.id);
+                if let Err(e) = task.kill(KillReason::Requested) {
+                    error!("watchdog: couldn't kill task {:?}: {}", task.name, e);
+                }
+            }
+            _ => warn!("watchdog: no task to kill on CPU {}", cpu),
+        },
+        Action::Panic => halt_system(reason),
+        Action::Shutdown => {
+            error!("watchdog: shutting down: {}", reason);
+            power::shutdown()
+        }
+        Action::Reboot => {
+            error!("watchdog: rebooting: {}", reason);
+            power::reboot()
+        }
+    }
+}
+
+/// Set once the watchdog halts the system, which makes every CPU halt upon its next NMI.
+static HALTING: AtomicBool = AtomicBool::new(false);
+
+/// Halts all CPUs, after logging the given `reason`.
+fn halt_system(reason: fmt::Arguments) -> ! {
+    error!("watchdog: halting the system: {}", reason);
+    HALTING.store(true, Ordering::Release);
+    if let Some(lapic) = apic::get_my_apic() {
+        lapic.write().send_nmi_ipi(LapicIpiDestination::AllButMe);
+    }
+    halt()
+}
+
+fn halt() -> ! {
+    loop {
+        // SAFETY: this CPU never resumes any other work.
+        unsafe { core::arch::asm!("cli", "hlt", options(nomem, nostack)) };
+    }
+}
+
+/// A snapshot of a CPU's registers and stack, captured by that CPU in response to an NMI.
+#[derive(Default)]
+struct Snapshot {
+    /// Set once the other fields have been written.
+    ready: AtomicBool,
+    instruction_pointer: AtomicUsize,
+    stack_pointer: AtomicUsize,
+    /// The number of valid words in `stack`.
+    len: AtomicUsize,
+    stack: [AtomicUsize; SNAPSHOT_STACK_WORDS],
+}
+
+/// The snapshot that each CPU should capture upon its next NMI, or null if none was requested.
+static SNAPSHOT_REQUESTS: [AtomicPtr<Snapshot>; MAX_CPUS] = {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const NULL: AtomicPtr<Snapshot> = AtomicPtr::new(ptr::null_mut());
+    [NULL; MAX_CPUS]
+};
+
+/// Handles an NMI sent by the watchdog, given the interrupted context's instruction and stack pointers.
+///
+/// Returns `true` if the watchdog expected this NMI, i.e., it requested a backtrace of the current CPU.
+/// If the watchdog is halting the system, this never returns.
+///
+/// This must be invoked by the NMI handler. It doesn't acquire any locks or allocate,
+/// and only reads the interrupted stack up to the end of the page containing `stack_pointer`.
+pub fn handle_nmi(instruction_pointer: usize, stack_pointer: usize) -> bool {
+    if HALTING.load(Ordering::Acquire) {
+        halt();
+    }
+    let Some(cpu) = tls_initializer::hardened_cpu_id() else {
+        return false;
+    };
+    let snapshot = SNAPSHOT_REQUESTS[cpu as usize].swap(ptr::null_mut(), Ordering::AcqRel);
+    if snapshot.is_null() {
+        return false;
+    }
+    // SAFETY: the requester doesn't free a snapshot that was taken until it's ready.
+    let snapshot = unsafe { &*snapshot };
+
+    let start = (stack_pointer + 7) & !7;
+    let page_end = (stack_pointer & !(PAGE_SIZE - 1)) + PAGE_SIZE;
+    let len = (page_end.saturating_sub(start) / core::mem::size_of::<usize>()).min(SNAPSHOT_STACK_WORDS);
+    for (i, word) in snapshot.stack[..len].iter().enumerate() {
+        // SAFETY: the page containing the stack pointer is mapped, as it was just in use.
+        let value = unsafe { ptr::read_volatile((start as *const usize).add(i)) };
+        word.store(value, Ordering::Relaxed);
+    }
+    snapshot.instruction_pointer.store(instruction_pointer, Ordering::Relaxed);
+    snapshot.stack_pointer.store(stack_pointer, Ordering::Relaxed);
+    snapshot.len.store(len, Ordering::Relaxed);
+    snapshot.ready.store(true, Ordering::Release);
+    true
+}
+
+/// Requests a snapshot from the given `cpu` via an NMI, and logs it as a backtrace.
+fn log_backtrace(cpu: u8) {
+    let request = &SNAPSHOT_REQUESTS[cpu as usize];
+    let snapshot = Box::into_raw(Box::<Snapshot>::default());
+    if request.compare_exchange(ptr::null_mut(), snapshot, Ordering::AcqRel, Ordering::Acquire).is_err() {
+        // SAFETY: `snapshot` was created above and never shared.
+        drop(unsafe { Box::from_raw(snapshot) });
+        error!("watchdog: CPU {} still hasn't responded to a previous NMI", cpu);
+        return;
+    }
+    match apic::get_my_apic() {
+        Some(lapic) => lapic.write().send_nmi_ipi(LapicIpiDestination::One(cpu)),
+        None => error!("watchdog: couldn't get the current CPU's local APIC to send an NMI"),
+    }
+
+    // SAFETY: `snapshot` is only freed below, once it's no longer accessible to the NMI handler.
+    let snapshot_ref = unsafe { &*snapshot };
+    let deadline = time::now::<Monotonic>() + SNAPSHOT_TIMEOUT;
+    while !snapshot_ref.ready.load(Ordering::Acquire) && time::now::<Monotonic>() < deadline {
+        let _ = sleep::sleep(Duration::from_millis(1));
+    }
+    if !snapshot_ref.ready.load(Ordering::Acquire) {
+        if request.compare_exchange(snapshot, ptr::null_mut(), Ordering::AcqRel, Ordering::Acquire).is_ok() {
+            // SAFETY: the request was withdrawn before the NMI handler took it.
+            drop(unsafe { Box::from_raw(snapshot) });
+            error!("watchdog: CPU {} didn't respond to an NMI", cpu);
+            return;
+        }
+        // The NMI handler took the request just now, and finishes it without blocking.
+        while !snapshot_ref.ready.load(Ordering::Acquire) {
+            core::hint::spin_loop();
+        }
+    }
+
+    let ip = snapshot_ref.instruction_pointer.load(Ordering::Relaxed);
+    let sp = snapshot_ref.stack_pointer.load(Ordering::Relaxed);
+    error!("watchdog: CPU {} was interrupted at {:#X} ({}), stack pointer {:#X}", cpu, ip, symbolize(ip), sp);
+    error!("watchdog: possible return addresses on its stack:");
+    let len = snapshot_ref.len.load(Ordering::Relaxed);
+    for word in &snapshot_ref.stack[..len] {
+        let value = word.load(Ordering::Relaxed);
+        if let Some(symbol) = try_symbolize(value) {
+            error!("  ? {:#X} {}", value, symbol);
+        }
+    }
+    // SAFETY: the NMI handler finished writing the snapshot and no longer accesses it.
+    drop(unsafe { Box::from_raw(snapshot) });
+}
+
+/// Returns the function containing the given code address, or `??` if it's unknown.
+fn symbolize(addr: usize) -> String {
+    try_symbolize(addr).unwrap_or_else(|| String::from("??"))
+}
+
+fn try_symbolize(addr: usize) -> Option<String> {
+    if addr == 0 {
+        return None;
+    }
+    let addr = VirtualAddress::new(addr)?;
+    let symbol = mod_mgmt::get_initial_kernel_namespace()?.symbolize_address(addr)?;
+    Some(alloc::format!("{}", symbol))
+}
@@ -0,0 +1,138 @@
+//! Watchpoints using the aarch64 watchpoint registers `DBGWVR<n>_EL1` and `DBGWCR<n>_EL1`.
+
+use alloc::vec::Vec;
+use core::arch::asm;
+use memory::VirtualAddress;
+use crate::{NUM_WATCHPOINTS, WatchKind, WatchLength, Watchpoint};
+
+/// The enable bit of `DBGWCR<n>_EL1`.
+const WCR_ENABLE: u64 = 1 << 0;
+/// The "privileged access control" field of `DBGWCR<n>_EL1`, set to match accesses from EL1 and EL0.
+const WCR_PAC_EL1_EL0: u64 = 0b11 << 1;
+/// The shift of the "load/store control" field of `DBGWCR<n>_EL1`.
+const WCR_LSC_SHIFT: u64 = 3;
+/// The shift of the "byte address select" field of `DBGWCR<n>_EL1`,
+/// which selects the watched bytes within the doubleword at the address in `DBGWVR<n>_EL1`.
+const WCR_BAS_SHIFT: u64 = 5;
+
+/// The "kernel debug enable" bit of `MDSCR_EL1`, which enables debug exceptions at EL1.
+const MDSCR_KDE: u64 = 1 << 13;
+/// The "monitor debug enable" bit of `MDSCR_EL1`, which enables watchpoint exceptions.
+const MDSCR_MDE: u64 = 1 << 15;
+
+macro_rules! read_sysreg {
+    ($index:expr, $reg0:literal, $reg1:literal, $reg2:literal, $reg3:literal) => {{
+        let value: u64;
+        unsafe {
+            match $index {
+                0 => asm!(concat!("mrs {}, ", $reg0), out(reg) value, options(nomem, nostack, preserves_flags)),
+                1 => asm!(concat!("mrs {}, ", $reg1), out(reg) value, options(nomem, nostack, preserves_flags)),
+                2 => asm!(concat!("mrs {}, ", $reg2), out(reg) value, options(nomem, nostack, preserves_flags)),
+                _ => asm!(concat!("mrs {}, ", $reg3), out(reg) value, options(nomem, nostack, preserves_flags)),
+            }
+        }
+        value
+    }};
+}
+
+macro_rules! write_sysreg {
+    ($index:expr, $value:expr, $reg0:literal, $reg1:literal, $reg2:literal, $reg3:literal) => {{
+        let value: u64 = $value;
+        match $index {
+            0 => asm!(concat!("msr ", $reg0, ", {}"), in(reg) value, options(nomem, nostack, preserves_flags)),
+            1 => asm!(concat!("msr ", $reg1, ", {}"), in(reg) value, options(nomem, nostack, preserves_flags)),
+            2 => asm!(concat!("msr ", $reg2, ", {}"), in(reg) value, options(nomem, nostack, preserves_flags)),
+            _ => asm!(concat!("msr ", $reg3, ", {}"), in(reg) value, options(nomem, nostack, preserves_flags)),
+        }
+        asm!("isb", options(nomem, nostack, preserves_flags));
+    }};
+}
+
+fn read_wvr(index: usize) -> u64 {
+    read_sysreg!(index, "dbgwvr0_el1", "dbgwvr1_el1", "dbgwvr2_el1", "dbgwvr3_el1")
+}
+
+fn read_wcr(index: usize) -> u64 {
+    read_sysreg!(index, "dbgwcr0_el1", "dbgwcr1_el1", "dbgwcr2_el1", "dbgwcr3_el1")
+}
+
+unsafe fn write_wvr(index: usize, value: u64) {
+    write_sysreg!(index, value, "dbgwvr0_el1", "dbgwvr1_el1", "dbgwvr2_el1", "dbgwvr3_el1")
+}
+
+unsafe fn write_wcr(index: usize, value: u64) {
+    write_sysreg!(index, value, "dbgwcr0_el1", "dbgwcr1_el1", "dbgwcr2_el1", "dbgwcr3_el1")
+}
+
+/// Enables watchpoint exceptions at EL1 on the current CPU.
+unsafe fn enable_debug_exceptions() {
+    let mut mdscr: u64;
+    asm!("mrs {}, mdscr_el1", out(reg) mdscr, options(nomem, nostack, preserves_flags));
+    mdscr |= MDSCR_KDE | MDSCR_MDE;
+    asm!(
+        // Unlock the OS lock, which otherwise suppresses debug exceptions.
+        "msr oslar_el1, xzr",
+        "msr mdscr_el1, {}",
+        // Unmask debug exceptions (PSTATE.D).
+        "msr daifclr, #8",
+        "isb",
+        in(reg) mdscr,
+        options(nomem, nostack, preserves_flags),
+    );
+}
+
+pub(crate) fn get(index: usize) -> Option<Watchpoint> {
+    let wcr = read_wcr(index);
+    if wcr & WCR_ENABLE == 0 {
+        return None;
+    }
+    let kind = match (wcr >> WCR_LSC_SHIFT) & 0b11 {
+        0b10 => WatchKind::Write,
+        0b11 => WatchKind::ReadWrite,
+        // Load-only watchpoints are never set by this crate.
+        _ => return None,
+    };
+    let bas = (wcr >> WCR_BAS_SHIFT) & 0xFF;
+    let length = match bas.count_ones() {
+        1 => WatchLength::One,
+        2 => WatchLength::Two,
+        4 => WatchLength::Four,
+        _ => WatchLength::Eight,
+    };
+    let address = read_wvr(index) as usize + bas.trailing_zeros() as usize;
+    Some(Watchpoint { index, address: VirtualAddress::new_canonical(address), length, kind })
+}
+
+pub(crate) unsafe fn enable(watchpoint: &Watchpoint) {
+    enable_debug_exceptions();
+    let lsc = match watchpoint.kind {
+        WatchKind::Write => 0b10,
+        WatchKind::ReadWrite => 0b11,
+    };
+    let address = watchpoint.address.value() as u64;
+    // The watched address must be doubleword-aligned, so sub-doubleword watchpoints select their bytes.
+    let bas = ((1u64 << watchpoint.length.bytes()) - 1) << (address & 0b111);
+    write_wcr(watchpoint.index, 0);
+    write_wvr(watchpoint.index, address & !0b111);
+    write_wcr(
+        watchpoint.index,
+        WCR_ENABLE | WCR_PAC_EL1_EL0 | (lsc << WCR_LSC_SHIFT) | (bas << WCR_BAS_SHIFT),
+    );
+}
+
+pub(crate) unsafe fn disable(index: usize) {
+    write_wcr(index, 0);
+    write_wvr(index, 0);
+}
+
+pub(crate) fn take_triggered() -> Vec<Watchpoint> {
+    let far: u64;
+    unsafe { asm!("mrs {}, far_el1", out(reg) far, options(nomem, nostack, preserves_flags)) };
+    (0..NUM_WATCHPOINTS)
+        .filter_map(get)
+        .filter(|w| {
+            let start = w.address.value() as u64;
+            start <= far && far < start + w.length.bytes() as u64
+        })
+        .collect()
+}
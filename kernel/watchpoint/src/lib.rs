@@ -0,0 +1,174 @@
+//! Hardware data watchpoints, which trap the exact instruction that accesses a given memory location.
+//!
+//! Watchpoints are implemented using the debug address registers `DR0`–`DR3` on x86_64
+//! and the first four watchpoint register pairs (`DBGWVR<n>_EL1` and `DBGWCR<n>_EL1`) on aarch64.
+//! They are useful for catching memory corruption, e.g., of a specific TLS slot
+//! or of the TLS self pointer; see [`watch_tls_offset()`] and [`watch_tls_self_pointer()`].
+//!
+//! # Per-CPU state
+//! Watchpoint registers are per-CPU and aren't saved or restored upon a context switch,
+//! so a watchpoint only traps accesses performed on the CPU that set it, by any task.
+//! To watch an address that's only accessed by a given task, that task should be pinned to that CPU.
+//! Likewise, a watchpoint can only be cleared on the CPU that set it.
+//!
+//! # Handling watchpoint exceptions
+//! When an access matches a watchpoint, a debug exception is raised:
+//! a `#DB` exception (vector 1) on x86_64, after the accessing instruction has completed,
+//! or a watchpoint exception on aarch64, before it completes.
+//! The exception handler can then use [`take_triggered()`] to determine which watchpoints were hit.
+
+#![no_std]
+
+extern crate alloc;
+
+#[cfg(target_arch = "x86_64")]
+#[path = "x86_64.rs"]
+mod arch;
+#[cfg(target_arch = "aarch64")]
+#[path = "aarch64.rs"]
+mod arch;
+
+use alloc::vec::Vec;
+use core::fmt;
+use memory::VirtualAddress;
+
+/// The number of watchpoints that can be set on each CPU.
+pub const NUM_WATCHPOINTS: usize = 4;
+
+/// The kind of memory access that triggers a watchpoint.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WatchKind {
+    /// Only writes to the watched memory trigger the watchpoint.
+    Write,
+    /// Both reads and writes of the watched memory trigger the watchpoint.
+    ReadWrite,
+}
+
+/// The number of bytes watched by a watchpoint.
+///
+/// The watched address must be aligned to this length.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WatchLength {
+    One   = 1,
+    Two   = 2,
+    Four  = 4,
+    Eight = 8,
+}
+
+impl WatchLength {
+    /// Returns the number of bytes watched.
+    pub fn bytes(self) -> usize {
+        self as usize
+    }
+}
+
+/// A watchpoint that has been set on the current CPU.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Watchpoint {
+    /// The index of the watchpoint register that holds this watchpoint, from `0` to `NUM_WATCHPOINTS - 1`.
+    pub index: usize,
+    pub address: VirtualAddress,
+    pub length: WatchLength,
+    pub kind: WatchKind,
+}
+
+impl fmt::Display for Watchpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let kind = match self.kind {
+            WatchKind::Write => "writes to",
+            WatchKind::ReadWrite => "accesses of",
+        };
+        write!(f, "watchpoint {} ({} {:#X}, {} bytes)", self.index, kind, self.address, self.length.bytes())
+    }
+}
+
+/// Sets a watchpoint on the current CPU that traps the given `kind` of access
+/// to the `length` bytes at the given `address`.
+///
+/// Returns an error if the `address` isn't aligned to the `length`
+/// or if all watchpoints on the current CPU are already in use.
+pub fn set(address: VirtualAddress, length: WatchLength, kind: WatchKind) -> Result<Watchpoint, &'static str> {
+    if address.value() % length.bytes() != 0 {
+        return Err("watchpoint address must be aligned to its length");
+    }
+    // Prevent another task on this CPU from claiming the same watchpoint register.
+    let _held_interrupts = irq_safety::hold_interrupts();
+    let index = (0..NUM_WATCHPOINTS)
+        .find(|&i| arch::get(i).is_none())
+        .ok_or("all watchpoints on this CPU are in use")?;
+    let watchpoint = Watchpoint { index, address, length, kind };
+    // SAFETY: the watchpoint register at `index` is unused, and a watchpoint doesn't affect memory safety.
+    unsafe { arch::enable(&watchpoint) };
+    Ok(watchpoint)
+}
+
+/// Clears the given watchpoint, which must have been set on the current CPU.
+///
+/// Returns an error if the current CPU doesn't hold the given watchpoint.
+pub fn clear(watchpoint: &Watchpoint) -> Result<(), &'static str> {
+    let _held_interrupts = irq_safety::hold_interrupts();
+    if get(watchpoint.index).as_ref() != Some(watchpoint) {
+        return Err("the given watchpoint isn't set on this CPU");
+    }
+    // SAFETY: the watchpoint register at `index` holds the given watchpoint.
+    unsafe { arch::disable(watchpoint.index) };
+    Ok(())
+}
+
+/// Clears all watchpoints on the current CPU.
+pub fn clear_all() {
+    let _held_interrupts = irq_safety::hold_interrupts();
+    for index in 0..NUM_WATCHPOINTS {
+        // SAFETY: clearing a watchpoint doesn't affect memory safety.
+        unsafe { arch::disable(index) };
+    }
+}
+
+/// Returns the watchpoint held by the watchpoint register at the given `index` on the current CPU, if any.
+pub fn get(index: usize) -> Option<Watchpoint> {
+    if index < NUM_WATCHPOINTS {
+        arch::get(index)
+    } else {
+        None
+    }
+}
+
+/// Returns all watchpoints that are set on the current CPU.
+pub fn all() -> Vec<Watchpoint> {
+    (0..NUM_WATCHPOINTS).filter_map(arch::get).collect()
+}
+
+/// Returns the watchpoints on the current CPU that caused the current debug exception.
+///
+/// This is only meaningful within a debug exception handler.
+/// On x86_64, this also clears the triggered status in `DR6`,
+/// so it returns the triggered watchpoints only once.
+/// On aarch64, this matches the faulting address in `FAR_EL1` against the current watchpoints.
+pub fn take_triggered() -> Vec<Watchpoint> {
+    arch::take_triggered()
+}
+
+/// Sets a watchpoint on the current CPU that traps the given `kind` of access to the `length` bytes
+/// at the given `offset` from the current task's TLS self pointer, e.g., a specific TLS slot.
+///
+/// The offset of a TLS variable can be obtained from the `TlsInitializer` or the debug info registry.
+pub fn watch_tls_offset(offset: isize, length: WatchLength, kind: WatchKind) -> Result<Watchpoint, &'static str> {
+    let self_pointer = current_tls_self_pointer()?;
+    let address = VirtualAddress::new(self_pointer.wrapping_add(offset as usize))
+        .ok_or("TLS offset yields an invalid virtual address")?;
+    set(address, length, kind)
+}
+
+/// Sets a watchpoint on the current CPU that traps writes to the current task's TLS self pointer,
+/// i.e., the first word of its TLS area that the TLS register points to.
+pub fn watch_tls_self_pointer() -> Result<Watchpoint, &'static str> {
+    watch_tls_offset(0, WatchLength::Eight, WatchKind::Write)
+}
+
+fn current_tls_self_pointer() -> Result<usize, &'static str> {
+    match task::with_current_task(|t| t.tls_self_pointer()) {
+        Ok(0) => Err("the current task has no TLS area"),
+        Ok(self_pointer) => Ok(self_pointer),
+        Err(_) => Err("couldn't get the current task"),
+    }
+}
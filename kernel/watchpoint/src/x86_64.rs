@@ -0,0 +1,126 @@
+//! Watchpoints using the x86_64 debug registers `DR0`–`DR3`, `DR6`, and `DR7`.
+
+use alloc::vec::Vec;
+use core::arch::asm;
+use memory::VirtualAddress;
+use crate::{NUM_WATCHPOINTS, WatchKind, WatchLength, Watchpoint};
+
+/// The "local exact breakpoint enable" and "global exact breakpoint enable" bits of `DR7`,
+/// which are recommended to be set whenever a data breakpoint is enabled.
+const DR7_EXACT: u64 = (1 << 8) | (1 << 9);
+/// The value of `DR6` with no status bits set; its reserved bits must be written as ones.
+const DR6_CLEAR: u64 = 0xFFFF_0FF0;
+
+/// The bit that locally enables the debug address register at `index`.
+const fn enable_bit(index: usize) -> u64 {
+    1 << (index * 2)
+}
+
+/// The shift of the 4-bit field of `DR7` that holds the `R/W` and `LEN` bits
+/// for the debug address register at `index`.
+const fn condition_shift(index: usize) -> usize {
+    16 + index * 4
+}
+
+fn read_dr7() -> u64 {
+    let value: u64;
+    unsafe { asm!("mov {}, dr7", out(reg) value, options(nomem, nostack, preserves_flags)) };
+    value
+}
+
+unsafe fn write_dr7(value: u64) {
+    asm!("mov dr7, {}", in(reg) value, options(nomem, nostack, preserves_flags));
+}
+
+fn read_dr6() -> u64 {
+    let value: u64;
+    unsafe { asm!("mov {}, dr6", out(reg) value, options(nomem, nostack, preserves_flags)) };
+    value
+}
+
+unsafe fn write_dr6(value: u64) {
+    asm!("mov dr6, {}", in(reg) value, options(nomem, nostack, preserves_flags));
+}
+
+fn read_address(index: usize) -> u64 {
+    let value: u64;
+    unsafe {
+        match index {
+            0 => asm!("mov {}, dr0", out(reg) value, options(nomem, nostack, preserves_flags)),
+            1 => asm!("mov {}, dr1", out(reg) value, options(nomem, nostack, preserves_flags)),
+            2 => asm!("mov {}, dr2", out(reg) value, options(nomem, nostack, preserves_flags)),
+            _ => asm!("mov {}, dr3", out(reg) value, options(nomem, nostack, preserves_flags)),
+        }
+    }
+    value
+}
+
+unsafe fn write_address(index: usize, value: u64) {
+    match index {
+        0 => asm!("mov dr0, {}", in(reg) value, options(nomem, nostack, preserves_flags)),
+        1 => asm!("mov dr1, {}", in(reg) value, options(nomem, nostack, preserves_flags)),
+        2 => asm!("mov dr2, {}", in(reg) value, options(nomem, nostack, preserves_flags)),
+        _ => asm!("mov dr3, {}", in(reg) value, options(nomem, nostack, preserves_flags)),
+    }
+}
+
+pub(crate) fn get(index: usize) -> Option<Watchpoint> {
+    let dr7 = read_dr7();
+    if dr7 & enable_bit(index) == 0 {
+        return None;
+    }
+    let condition = (dr7 >> condition_shift(index)) & 0b1111;
+    let kind = match condition & 0b11 {
+        0b01 => WatchKind::Write,
+        0b11 => WatchKind::ReadWrite,
+        // Instruction breakpoints and I/O breakpoints aren't watchpoints.
+        _ => return None,
+    };
+    let length = match condition >> 2 {
+        0b00 => WatchLength::One,
+        0b01 => WatchLength::Two,
+        0b11 => WatchLength::Four,
+        _    => WatchLength::Eight,
+    };
+    let address = VirtualAddress::new_canonical(read_address(index) as usize);
+    Some(Watchpoint { index, address, length, kind })
+}
+
+pub(crate) unsafe fn enable(watchpoint: &Watchpoint) {
+    let rw = match watchpoint.kind {
+        WatchKind::Write => 0b01,
+        WatchKind::ReadWrite => 0b11,
+    };
+    let len = match watchpoint.length {
+        WatchLength::One => 0b00,
+        WatchLength::Two => 0b01,
+        WatchLength::Four => 0b11,
+        WatchLength::Eight => 0b10,
+    };
+    let index = watchpoint.index;
+    write_address(index, watchpoint.address.value() as u64);
+    let mut dr7 = read_dr7() & !(0b1111 << condition_shift(index));
+    dr7 |= ((len << 2) | rw) << condition_shift(index);
+    dr7 |= enable_bit(index) | DR7_EXACT;
+    write_dr7(dr7);
+}
+
+pub(crate) unsafe fn disable(index: usize) {
+    let mut dr7 = read_dr7() & !enable_bit(index);
+    if (0..NUM_WATCHPOINTS).all(|i| dr7 & enable_bit(i) == 0) {
+        dr7 &= !DR7_EXACT;
+    }
+    write_dr7(dr7);
+    write_address(index, 0);
+}
+
+pub(crate) fn take_triggered() -> Vec<Watchpoint> {
+    let dr6 = read_dr6();
+    let triggered = (0..NUM_WATCHPOINTS)
+        .filter(|&i| dr6 & (1 << i) != 0)
+        .filter_map(get)
+        .collect();
+    // The processor never clears the status bits of `DR6` itself.
+    unsafe { write_dr6(DR6_CLEAR) };
+    triggered
+}
@@ -103,6 +103,14 @@ impl<T> CowArc<T> {
         Arc::strong_count(&self.arc.inner_arc) > 1
     }
 
+    /// Returns the number of references to this instance of `CowArc`,
+    /// including shallow clones created via [`CowArc::clone_shallow()`].
+    ///
+    /// This does not include other `Shared` instances; see [`CowArc::is_shared()`].
+    pub fn strong_count(this: &CowArc<T>) -> usize {
+        Arc::strong_count(&this.arc)
+    }
+
     /// Returns true if the two `CowArc`s point to the same value
     /// (not just values that compare as equal).
     pub fn ptr_eq(&self, other: &Self) -> bool {
@@ -160,6 +168,14 @@ pub struct CowWeak<T> {
     weak: InnerWeak<T>,
 }
 impl<T> CowWeak<T> {
+    /// Just like `Weak::new()`, creates a new `CowWeak` that doesn't point to any `CowArc`,
+    /// such that calling [`upgrade()`](Self::upgrade) on it always returns `None`.
+    pub fn new() -> CowWeak<T> {
+        CowWeak {
+            weak: InnerWeak { inner_weak: Weak::new() },
+        }
+    }
+
     /// Just like `Weak::upgrade()`, attempts to upgrade this `CowWeak`
     /// into a strong reference to the `CowArc` that it points to.
     pub fn upgrade(&self) -> Option<CowArc<T>> {
@@ -168,6 +184,11 @@ impl<T> CowWeak<T> {
         })
     }
 }
+impl<T> Default for CowWeak<T> {
+    fn default() -> CowWeak<T> {
+        CowWeak::new()
+    }
+}
 impl<T> Clone for CowWeak<T> {
     fn clone(&self) -> CowWeak<T> {
         CowWeak {
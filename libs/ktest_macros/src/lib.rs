@@ -0,0 +1,77 @@
+//! Provides the `#[ktest]` attribute, which marks a function as an in-kernel test case.
+//!
+//! A test function must take no arguments and return either `()` or `Result<(), &'static str>`.
+//! Test functions (and everything they use) are only compiled when the `ktest` cfg option is enabled,
+//! e.g., via `THESEUS_CONFIG += ktest`, so they never end up in a regular build.
+//!
+//! For each test function `foo` in module `my_crate::tests`, the attribute emits a wrapper function
+//! with the unmangled symbol name `__ktest::my_crate::tests::foo` and the signature
+//! `fn() -> Result<(), &'static str>`.
+//! The test harness finds every test at runtime by searching the symbol map for that prefix,
+//! so tests don't need to be registered anywhere.
+
+use proc_macro::TokenStream;
+use quote::{format_ident, quote, quote_spanned};
+use syn::{parse_macro_input, spanned::Spanned, ItemFn, ReturnType};
+
+/// The prefix of the symbol name of every test wrapper function.
+///
+/// This must match `ktest::SYMBOL_PREFIX`.
+const SYMBOL_PREFIX: &str = "__ktest::";
+
+/// Marks a function as an in-kernel test case that is run by the `ktest` harness.
+///
+/// A test passes if it returns `()` or `Ok(())`, and fails if it returns an `Err` or panics.
+///
+/// ```ignore
+/// #[ktest]
+/// fn offsets_are_aligned() -> Result<(), &'static str> {
+///     ...
+/// }
+/// ```
+#[proc_macro_attribute]
+pub fn ktest(attr: TokenStream, item: TokenStream) -> TokenStream {
+    if !attr.is_empty() {
+        let attr = proc_macro2::TokenStream::from(attr);
+        return quote_spanned! { attr.span() =>
+            compile_error!("#[ktest] does not accept any arguments");
+        }.into();
+    }
+
+    let func = parse_macro_input!(item as ItemFn);
+    let sig = &func.sig;
+    let error = if !sig.inputs.is_empty() {
+        Some((sig.inputs.span(), "a #[ktest] function must not take any arguments"))
+    } else if !sig.generics.params.is_empty() {
+        Some((sig.generics.span(), "a #[ktest] function must not be generic"))
+    } else if sig.asyncness.is_some() {
+        Some((sig.asyncness.span(), "a #[ktest] function must not be async"))
+    } else if sig.unsafety.is_some() {
+        Some((sig.unsafety.span(), "a #[ktest] function must not be unsafe"))
+    } else {
+        None
+    };
+    if let Some((span, msg)) = error {
+        return quote_spanned! { span => compile_error!(#msg); }.into();
+    }
+
+    let name = &sig.ident;
+    let wrapper_name = format_ident!("__ktest_{}", name);
+    // The return type is checked by the compiler against the wrapper's return type.
+    let body = match sig.output {
+        ReturnType::Default => quote! { #name(); Ok(()) },
+        ReturnType::Type(..) => quote! { #name() },
+    };
+
+    quote! {
+        #[cfg(ktest)]
+        #func
+
+        #[cfg(ktest)]
+        #[doc(hidden)]
+        #[export_name = concat!(#SYMBOL_PREFIX, module_path!(), "::", stringify!(#name))]
+        pub fn #wrapper_name() -> core::result::Result<(), &'static str> {
+            #body
+        }
+    }.into()
+}
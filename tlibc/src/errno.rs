@@ -2,9 +2,7 @@
 
 use libc::c_int;
 
-#[thread_local]
-#[no_mangle]
-pub static mut errno: c_int = 0;
+pub use tls_initializer::{errno, set_errno};
 
 
 #[no_mangle]
@@ -12,13 +10,15 @@ pub unsafe extern "C" fn __errno() -> *mut c_int {
     __errno_location()
 }
 
+/// Returns the address of the current task's `errno` value,
+/// which resides in the slot that Theseus reserves in each task's TLS area.
 #[no_mangle]
 pub unsafe extern "C" fn __errno_location() -> *mut c_int {
-    &mut errno
+    tls_initializer::errno_location()
 }
 
 pub fn errno_str() -> &'static str {
-    &STR_ERROR[ unsafe { errno } as usize ]
+    &STR_ERROR[ errno() as usize ]
 }
 
 
@@ -25,6 +25,7 @@ extern crate memchr;
 extern crate cbitset;
 extern crate memory;
 extern crate task;
+extern crate tls_initializer;
 extern crate cstr_core;
 extern crate core2;
 
@@ -17,13 +17,13 @@ pub unsafe extern "C" fn malloc(size: size_t) -> *mut c_void {
     let layout = match Layout::from_size_align(size, 1) {
         Ok(l)   => l,
         Err(_e) => {
-            errno = EINVAL;
+            set_errno(EINVAL);
             return core::ptr::null_mut();
         }
     };
     let ptr = alloc(layout);
     if ptr.is_null() {
-        errno = ENOMEM;
+        set_errno(ENOMEM);
     }
     POINTER_LAYOUTS.lock().insert(ptr as usize, layout);
     ptr as *mut c_void
@@ -213,7 +213,7 @@ pub unsafe extern "C" fn strndup(s1: *const c_char, size: size_t) -> *mut c_char
     // the "+ 1" is to account for the NUL byte
     let buffer = crate::stdlib::malloc(len + 1) as *mut c_char;
     if buffer.is_null() {
-        errno = ENOMEM;
+        set_errno(ENOMEM);
     } else {
         //memcpy(buffer, s1, len)
         for i in 0..len {